@@ -438,6 +438,7 @@ Options:
   --key <file>                TLS certificate key path [default: src/bin/cert.key]
   --root <dir>                Root directory [default: src/bin/root/]
   --index <name>              The file that will be used as index [default: index.html].
+  --dir-listing               List the directory contents when there is no index file.
   --name <str>                Name of the server [default: quic.tech]
   --max-data BYTES            Connection-wide flow control limit [default: 10000000].
   --max-window BYTES          Connection-wide max receiver window [default: 25165824].
@@ -462,6 +463,7 @@ Options:
   --qpack-max-table-capacity BYTES  Max capacity of QPACK dynamic table decoding. Any value other that 0 is currently unsupported.
   --qpack-blocked-streams STREAMS   Limit of streams that can be blocked while decoding. Any value other that 0 is currently unsupported.
   --disable-gso               Disable GSO (linux only).
+  --disable-gro               Disable GRO (linux only).
   --disable-pacing            Disable pacing (linux only).
   --initial-cwnd-packets PACKETS      The initial congestion window size in terms of packet count [default: 10].
   -h --help                   Show this screen.
@@ -473,9 +475,11 @@ pub struct ServerArgs {
     pub no_retry: bool,
     pub root: String,
     pub index: String,
+    pub dir_listing: bool,
     pub cert: String,
     pub key: String,
     pub disable_gso: bool,
+    pub disable_gro: bool,
     pub disable_pacing: bool,
 }
 
@@ -487,9 +491,11 @@ impl Args for ServerArgs {
         let no_retry = args.get_bool("--no-retry");
         let root = args.get_str("--root").to_string();
         let index = args.get_str("--index").to_string();
+        let dir_listing = args.get_bool("--dir-listing");
         let cert = args.get_str("--cert").to_string();
         let key = args.get_str("--key").to_string();
         let disable_gso = args.get_bool("--disable-gso");
+        let disable_gro = args.get_bool("--disable-gro");
         let disable_pacing = args.get_bool("--disable-pacing");
 
         ServerArgs {
@@ -497,9 +503,11 @@ impl Args for ServerArgs {
             no_retry,
             root,
             index,
+            dir_listing,
             cert,
             key,
             disable_gso,
+            disable_gro,
             disable_pacing,
         }
     }
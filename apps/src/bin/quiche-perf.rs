@@ -0,0 +1,672 @@
+// Copyright (C) 2023, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Measures throughput and latency using the QUIC perf protocol.
+//!
+//! The client opens bidirectional streams, and on each of them sends the
+//! number of bytes it wants to receive, encoded as a 64-bit big-endian
+//! integer, followed by the data to upload. Once the server receives the
+//! whole request, it sends back the requested number of bytes.
+
+#[macro_use]
+extern crate log;
+
+use std::collections::HashMap;
+
+use std::convert::TryInto;
+
+use std::net::SocketAddr;
+use std::net::ToSocketAddrs;
+
+use std::time;
+
+use ring::rand::*;
+
+const USAGE: &str = "Usage:
+  quiche-perf server [options]
+  quiche-perf client [options] ADDR
+  quiche-perf -h | --help
+
+Options:
+  --listen ADDR            Address the server listens on [default: 127.0.0.1:4433].
+  --cert FILE              TLS certificate path [default: src/bin/cert.crt].
+  --key FILE               TLS certificate key path [default: src/bin/cert.key].
+  --upload BYTES           Bytes the client sends on each stream [default: 0].
+  --download BYTES         Bytes the server sends on each stream [default: 10000000].
+  --streams NUM            Number of streams the client opens [default: 1].
+  --cc-algorithm NAME      Congestion control algorithm to use [default: cubic].
+  --idle-timeout TIMEOUT   Idle timeout in milliseconds [default: 30000].
+  --max-data BYTES         Connection-wide flow control limit [default: 100000000].
+  --max-stream-data BYTES  Per-stream flow control limit [default: 100000000].
+  --no-verify              Don't verify the server's certificate.
+  -h --help                Show this screen.
+";
+
+const MAX_DATAGRAM_SIZE: usize = 1350;
+
+const ALPN: &[u8] = b"perf";
+
+/// The length of the response size sent at the start of each request.
+const HEADER_LEN: usize = 8;
+
+/// Payload sent on perf streams.
+static PAYLOAD: [u8; 65536] = [0; 65536];
+
+fn main() {
+    env_logger::builder().format_timestamp_nanos().init();
+
+    let args = docopt::Docopt::new(USAGE)
+        .and_then(|dopt| dopt.parse())
+        .unwrap_or_else(|e| e.exit());
+
+    let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION).unwrap();
+
+    config.set_application_protos(&[ALPN]).unwrap();
+
+    config
+        .set_cc_algorithm_name(args.get_str("--cc-algorithm"))
+        .unwrap();
+
+    let max_data = args.get_str("--max-data").parse().unwrap();
+    let max_stream_data = args.get_str("--max-stream-data").parse().unwrap();
+
+    config.set_max_idle_timeout(args.get_str("--idle-timeout").parse().unwrap());
+    config.set_max_recv_udp_payload_size(MAX_DATAGRAM_SIZE);
+    config.set_max_send_udp_payload_size(MAX_DATAGRAM_SIZE);
+    config.set_initial_max_data(max_data);
+    config.set_initial_max_stream_data_bidi_local(max_stream_data);
+    config.set_initial_max_stream_data_bidi_remote(max_stream_data);
+    config.set_initial_max_streams_bidi(1000);
+    config.set_disable_active_migration(true);
+
+    if args.get_bool("server") {
+        config
+            .load_cert_chain_from_pem_file(args.get_str("--cert"))
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file(args.get_str("--key"))
+            .unwrap();
+
+        let listen = args.get_str("--listen").parse().unwrap();
+
+        run_server(listen, config);
+    } else {
+        config.verify_peer(!args.get_bool("--no-verify"));
+
+        let params = ClientParams {
+            addr: args.get_str("ADDR").to_string(),
+            upload: args.get_str("--upload").parse().unwrap(),
+            download: args.get_str("--download").parse().unwrap(),
+            streams: args.get_str("--streams").parse().unwrap(),
+        };
+
+        run_client(params, config);
+    }
+}
+
+/// Server-side state of a perf stream.
+#[derive(Default)]
+struct ServerStream {
+    /// The bytes of the response size received so far.
+    header: Vec<u8>,
+
+    /// The number of response bytes left to send, once the request has been
+    /// fully received.
+    left: Option<u64>,
+}
+
+struct Client {
+    conn: quiche::Connection,
+
+    streams: HashMap<u64, ServerStream>,
+}
+
+fn run_server(listen: SocketAddr, mut config: quiche::Config) {
+    let mut buf = [0; 65535];
+    let mut out = [0; MAX_DATAGRAM_SIZE];
+
+    let mut poll = mio::Poll::new().unwrap();
+    let mut events = mio::Events::with_capacity(1024);
+
+    let mut socket = mio::net::UdpSocket::bind(listen).unwrap();
+    poll.registry()
+        .register(&mut socket, mio::Token(0), mio::Interest::READABLE)
+        .unwrap();
+
+    let local_addr = socket.local_addr().unwrap();
+
+    info!("listening on {}", local_addr);
+
+    let rng = SystemRandom::new();
+    let conn_id_seed =
+        ring::hmac::Key::generate(ring::hmac::HMAC_SHA256, &rng).unwrap();
+
+    let mut clients: HashMap<quiche::ConnectionId<'static>, Client> =
+        HashMap::new();
+
+    loop {
+        let timeout = clients.values().filter_map(|c| c.conn.timeout()).min();
+
+        poll.poll(&mut events, timeout).unwrap();
+
+        'read: loop {
+            if events.is_empty() {
+                clients.values_mut().for_each(|c| c.conn.on_timeout());
+
+                break 'read;
+            }
+
+            let (len, from) = match socket.recv_from(&mut buf) {
+                Ok(v) => v,
+
+                Err(e) => {
+                    if e.kind() == std::io::ErrorKind::WouldBlock {
+                        break 'read;
+                    }
+
+                    panic!("recv() failed: {:?}", e);
+                },
+            };
+
+            let pkt_buf = &mut buf[..len];
+
+            let hdr = match quiche::Header::from_slice(
+                pkt_buf,
+                quiche::MAX_CONN_ID_LEN,
+            ) {
+                Ok(v) => v,
+
+                Err(e) => {
+                    error!("Parsing packet header failed: {:?}", e);
+                    continue 'read;
+                },
+            };
+
+            // Derive the connection ID from the client's one, so that
+            // retransmitted Initial packets map to the same connection.
+            let conn_id = ring::hmac::sign(&conn_id_seed, &hdr.dcid);
+            let conn_id = &conn_id.as_ref()[..quiche::MAX_CONN_ID_LEN];
+            let conn_id: quiche::ConnectionId<'static> = conn_id.to_vec().into();
+
+            let scid = if clients.contains_key(&hdr.dcid) {
+                hdr.dcid.clone().into_owned()
+            } else if clients.contains_key(&conn_id) {
+                conn_id
+            } else {
+                if hdr.ty != quiche::Type::Initial {
+                    error!("Packet is not Initial");
+                    continue 'read;
+                }
+
+                if !config.is_version_supported(hdr.version) {
+                    let len =
+                        quiche::negotiate_version(&hdr.scid, &hdr.dcid, &mut out)
+                            .unwrap();
+
+                    socket.send_to(&out[..len], from).ok();
+                    continue 'read;
+                }
+
+                let conn =
+                    quiche::accept(&conn_id, None, local_addr, from, &mut config)
+                        .unwrap();
+
+                info!("{} new connection from {}", conn.trace_id(), from);
+
+                clients.insert(conn_id.clone(), Client {
+                    conn,
+                    streams: HashMap::new(),
+                });
+
+                conn_id
+            };
+
+            let client = clients.get_mut(&scid).unwrap();
+
+            let recv_info = quiche::RecvInfo {
+                to: local_addr,
+                from,
+            };
+
+            if let Err(e) = client.conn.recv(pkt_buf, recv_info) {
+                error!("{} recv failed: {:?}", client.conn.trace_id(), e);
+                continue 'read;
+            }
+
+            if !client.conn.is_established() {
+                continue 'read;
+            }
+
+            for stream_id in client.conn.readable() {
+                while let Ok((read, fin)) =
+                    client.conn.stream_recv(stream_id, &mut buf)
+                {
+                    let stream = client.streams.entry(stream_id).or_default();
+
+                    let missing = HEADER_LEN - stream.header.len();
+                    let header_len = std::cmp::min(missing, read);
+                    stream.header.extend_from_slice(&buf[..header_len]);
+
+                    if !fin {
+                        continue;
+                    }
+
+                    let header: [u8; HEADER_LEN] =
+                        match stream.header[..].try_into() {
+                            Ok(v) => v,
+
+                            Err(_) => {
+                                error!(
+                                    "{} stream {} finished before response size",
+                                    client.conn.trace_id(),
+                                    stream_id
+                                );
+
+                                client.streams.remove(&stream_id);
+                                break;
+                            },
+                        };
+
+                    stream.left = Some(u64::from_be_bytes(header));
+
+                    send_response(client, stream_id);
+                }
+            }
+
+            for stream_id in client.conn.writable() {
+                send_response(client, stream_id);
+            }
+        }
+
+        for client in clients.values_mut() {
+            flush(&mut client.conn, &socket, &mut out);
+        }
+
+        clients.retain(|_, c| {
+            if c.conn.is_closed() {
+                info!(
+                    "{} connection collected {:?}",
+                    c.conn.trace_id(),
+                    c.conn.stats()
+                );
+            }
+
+            !c.conn.is_closed()
+        });
+    }
+}
+
+/// Sends as much of the response on the given stream as flow and congestion
+/// control allow.
+fn send_response(client: &mut Client, stream_id: u64) {
+    let stream = match client.streams.get_mut(&stream_id) {
+        Some(v) => v,
+
+        None => return,
+    };
+
+    let mut left = match stream.left {
+        Some(v) => v,
+
+        // The request is still being received.
+        None => return,
+    };
+
+    loop {
+        let len = std::cmp::min(left, PAYLOAD.len() as u64) as usize;
+        let fin = len as u64 == left;
+
+        let written =
+            match client.conn.stream_send(stream_id, &PAYLOAD[..len], fin) {
+                Ok(v) => v,
+
+                Err(quiche::Error::Done) => break,
+
+                Err(e) => {
+                    error!(
+                        "{} stream {} send failed: {:?}",
+                        client.conn.trace_id(),
+                        stream_id,
+                        e
+                    );
+
+                    client.streams.remove(&stream_id);
+                    return;
+                },
+            };
+
+        left -= written as u64;
+
+        if fin && written == len {
+            client.streams.remove(&stream_id);
+            return;
+        }
+
+        if written < len {
+            break;
+        }
+    }
+
+    stream.left = Some(left);
+}
+
+struct ClientParams {
+    addr: String,
+
+    upload: u64,
+
+    download: u64,
+
+    streams: u64,
+}
+
+/// Client-side state of a perf stream.
+struct ClientStream {
+    /// The bytes of the response size sent so far.
+    header_sent: usize,
+
+    /// The upload bytes left to send.
+    upload_left: u64,
+
+    /// The response bytes received so far.
+    received: u64,
+
+    /// Whether the whole response was received.
+    finished: bool,
+}
+
+fn run_client(params: ClientParams, mut config: quiche::Config) {
+    let mut buf = [0; 65535];
+    let mut out = [0; MAX_DATAGRAM_SIZE];
+
+    let peer_addr = params.addr.to_socket_addrs().unwrap().next().unwrap();
+
+    let server_name = params
+        .addr
+        .rsplit_once(':')
+        .map_or(params.addr.as_str(), |(host, _)| host)
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_string();
+
+    let bind_addr = match peer_addr {
+        SocketAddr::V4(_) => "0.0.0.0:0",
+        SocketAddr::V6(_) => "[::]:0",
+    };
+
+    let mut poll = mio::Poll::new().unwrap();
+    let mut events = mio::Events::with_capacity(1024);
+
+    let mut socket =
+        mio::net::UdpSocket::bind(bind_addr.parse().unwrap()).unwrap();
+    poll.registry()
+        .register(&mut socket, mio::Token(0), mio::Interest::READABLE)
+        .unwrap();
+
+    let local_addr = socket.local_addr().unwrap();
+
+    let mut scid = [0; quiche::MAX_CONN_ID_LEN];
+    SystemRandom::new().fill(&mut scid[..]).unwrap();
+    let scid = quiche::ConnectionId::from_ref(&scid);
+
+    let mut conn = quiche::connect(
+        Some(&server_name),
+        &scid,
+        local_addr,
+        peer_addr,
+        &mut config,
+    )
+    .unwrap();
+
+    let conn_start = time::Instant::now();
+
+    let mut streams: HashMap<u64, ClientStream> = HashMap::new();
+
+    let mut start = None;
+    let mut end = None;
+
+    let mut rtt_samples = Vec::new();
+
+    loop {
+        flush(&mut conn, &socket, &mut out);
+
+        if conn.is_closed() {
+            break;
+        }
+
+        poll.poll(&mut events, conn.timeout()).unwrap();
+
+        if events.is_empty() {
+            conn.on_timeout();
+        }
+
+        loop {
+            let (len, from) = match socket.recv_from(&mut buf) {
+                Ok(v) => v,
+
+                Err(e) => {
+                    if e.kind() == std::io::ErrorKind::WouldBlock {
+                        break;
+                    }
+
+                    panic!("recv() failed: {:?}", e);
+                },
+            };
+
+            let recv_info = quiche::RecvInfo {
+                to: local_addr,
+                from,
+            };
+
+            if let Err(e) = conn.recv(&mut buf[..len], recv_info) {
+                error!("recv failed: {:?}", e);
+            }
+        }
+
+        if let Some(path) = conn.path_stats().next() {
+            rtt_samples.push(path.rtt);
+        }
+
+        if !conn.is_established() {
+            continue;
+        }
+
+        // Open all the streams as soon as the handshake completes.
+        if start.is_none() {
+            info!("connection established in {:?}", conn_start.elapsed());
+
+            start = Some(time::Instant::now());
+
+            for i in 0..params.streams {
+                streams.insert(i * 4, ClientStream {
+                    header_sent: 0,
+                    upload_left: params.upload,
+                    received: 0,
+                    finished: false,
+                });
+            }
+        }
+
+        for (&stream_id, stream) in streams.iter_mut() {
+            send_request(&mut conn, stream_id, stream, params.download);
+        }
+
+        for stream_id in conn.readable() {
+            while let Ok((read, fin)) = conn.stream_recv(stream_id, &mut buf) {
+                if let Some(stream) = streams.get_mut(&stream_id) {
+                    stream.received += read as u64;
+                    stream.finished = fin;
+                }
+            }
+        }
+
+        if end.is_none() && streams.values().all(|s| s.finished) {
+            end = Some(time::Instant::now());
+
+            conn.close(true, 0x00, b"done").ok();
+        }
+    }
+
+    let start = match start {
+        Some(v) => v,
+
+        None => {
+            error!("connection closed before the handshake completed");
+            std::process::exit(-1);
+        },
+    };
+
+    let elapsed = end.unwrap_or_else(time::Instant::now) - start;
+
+    let uploaded = params.upload * params.streams;
+    let downloaded: u64 = streams.values().map(|s| s.received).sum();
+
+    let stats = conn.stats();
+
+    println!("duration: {:.3} s", elapsed.as_secs_f64());
+    println!(
+        "upload: {} bytes, {:.3} Mbit/s",
+        uploaded,
+        goodput(uploaded, elapsed)
+    );
+    println!(
+        "download: {} bytes, {:.3} Mbit/s",
+        downloaded,
+        goodput(downloaded, elapsed)
+    );
+
+    rtt_samples.sort();
+
+    println!(
+        "rtt: min {:.3} ms, p50 {:.3} ms, p90 {:.3} ms, p99 {:.3} ms, max {:.3} ms",
+        percentile_ms(&rtt_samples, 0.0),
+        percentile_ms(&rtt_samples, 0.5),
+        percentile_ms(&rtt_samples, 0.9),
+        percentile_ms(&rtt_samples, 0.99),
+        percentile_ms(&rtt_samples, 1.0),
+    );
+
+    println!(
+        "packets: sent {}, recv {}, lost {}, retrans {}",
+        stats.sent, stats.recv, stats.lost, stats.retrans
+    );
+
+    if end.is_none() {
+        error!("connection closed before all the responses were received");
+        std::process::exit(-2);
+    }
+}
+
+/// Sends as much of the request on the given stream as flow and congestion
+/// control allow.
+fn send_request(
+    conn: &mut quiche::Connection, stream_id: u64, stream: &mut ClientStream,
+    download: u64,
+) {
+    let header = download.to_be_bytes();
+
+    // The response size is sent first, followed by the upload payload.
+    while stream.header_sent < HEADER_LEN {
+        let fin = stream.upload_left == 0;
+
+        match conn.stream_send(stream_id, &header[stream.header_sent..], fin) {
+            Ok(v) => stream.header_sent += v,
+
+            Err(quiche::Error::Done) => return,
+
+            Err(e) => {
+                error!("stream {} send failed: {:?}", stream_id, e);
+                return;
+            },
+        }
+    }
+
+    while stream.upload_left > 0 {
+        let len = std::cmp::min(stream.upload_left, PAYLOAD.len() as u64);
+        let fin = len == stream.upload_left;
+
+        match conn.stream_send(stream_id, &PAYLOAD[..len as usize], fin) {
+            Ok(v) => stream.upload_left -= v as u64,
+
+            Err(quiche::Error::Done) => return,
+
+            Err(e) => {
+                error!("stream {} send failed: {:?}", stream_id, e);
+                return;
+            },
+        }
+    }
+}
+
+/// Sends all the packets that quiche generated.
+fn flush(
+    conn: &mut quiche::Connection, socket: &mio::net::UdpSocket, out: &mut [u8],
+) {
+    loop {
+        let (write, send_info) = match conn.send(out) {
+            Ok(v) => v,
+
+            Err(quiche::Error::Done) => break,
+
+            Err(e) => {
+                error!("{} send failed: {:?}", conn.trace_id(), e);
+
+                conn.close(false, 0x1, b"fail").ok();
+                break;
+            },
+        };
+
+        if let Err(e) = socket.send_to(&out[..write], send_info.to) {
+            if e.kind() == std::io::ErrorKind::WouldBlock {
+                break;
+            }
+
+            panic!("send() failed: {:?}", e);
+        }
+    }
+}
+
+/// Returns the goodput in Mbit/s.
+fn goodput(bytes: u64, elapsed: time::Duration) -> f64 {
+    let secs = elapsed.as_secs_f64();
+
+    if secs == 0.0 {
+        return 0.0;
+    }
+
+    bytes as f64 * 8.0 / secs / 1_000_000.0
+}
+
+/// Returns the given percentile of sorted RTT samples, in milliseconds.
+fn percentile_ms(samples: &[time::Duration], p: f64) -> f64 {
+    if samples.is_empty() {
+        return 0.0;
+    }
+
+    let i = ((samples.len() - 1) as f64 * p).round() as usize;
+
+    samples[i].as_secs_f64() * 1000.0
+}
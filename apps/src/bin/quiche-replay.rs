@@ -0,0 +1,83 @@
+// Copyright (C) 2020, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Replays the packets of a qlog trace through quiche's loss recovery and
+//! congestion control, and prints the resulting state after every event.
+
+const USAGE: &str = "Usage:
+  quiche-replay [options] FILE
+  quiche-replay -h | --help
+
+Options:
+  --cc-algorithm NAME      Specify which congestion control algorithm to use [default: cubic].
+  --disable-hystart        Disable HyStart++.
+  -h --help                Show this screen.
+";
+
+fn main() {
+    env_logger::builder().format_timestamp_nanos().init();
+
+    let args = docopt::Docopt::new(USAGE)
+        .and_then(|dopt| dopt.parse())
+        .unwrap_or_else(|e| e.exit());
+
+    let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION).unwrap();
+
+    config
+        .set_cc_algorithm_name(args.get_str("--cc-algorithm"))
+        .unwrap();
+
+    config.enable_hystart(!args.get_bool("--disable-hystart"));
+
+    let file = std::fs::File::open(args.get_str("FILE")).unwrap();
+    let reader = Box::new(std::io::BufReader::new(file));
+
+    let snapshots = quiche::replay_seq(reader, &config).unwrap();
+
+    println!(
+        "time_ms event pkt_num cwnd bytes_in_flight ssthresh rtt_ms pto_count lost recorded_cwnd"
+    );
+
+    for s in snapshots {
+        println!(
+            "{:.3} {:?} {} {} {} {} {:.3} {} {} {}",
+            s.time.as_secs_f64() * 1000.0,
+            s.trigger,
+            opt_to_string(s.pkt_num),
+            s.congestion_window,
+            s.bytes_in_flight,
+            opt_to_string(s.ssthresh),
+            s.rtt.as_secs_f64() * 1000.0,
+            s.pto_count,
+            s.lost_count,
+            opt_to_string(s.recorded_congestion_window),
+        );
+    }
+}
+
+fn opt_to_string<T: ToString>(v: Option<T>) -> String {
+    v.map_or_else(|| "-".to_string(), |v| v.to_string())
+}
@@ -100,6 +100,14 @@ fn main() {
 
     trace!("GSO detected: {}", enable_gso);
 
+    let enable_gro = if args.disable_gro {
+        false
+    } else {
+        detect_gro(&socket)
+    };
+
+    trace!("GRO detected: {}", enable_gro);
+
     // Create the configuration for the QUIC connections.
     let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION).unwrap();
 
@@ -202,20 +210,21 @@ fn main() {
                 break 'read;
             }
 
-            let (len, from) = match socket.recv_from(&mut buf) {
-                Ok(v) => v,
+            let (len, from, segment_size) =
+                match recv_from(&socket, &mut buf, enable_gro) {
+                    Ok(v) => v,
 
-                Err(e) => {
-                    // There are no more UDP packets to read, so end the read
-                    // loop.
-                    if e.kind() == std::io::ErrorKind::WouldBlock {
-                        trace!("recv() would block");
-                        break 'read;
-                    }
+                    Err(e) => {
+                        // There are no more UDP packets to read, so end the read
+                        // loop.
+                        if e.kind() == std::io::ErrorKind::WouldBlock {
+                            trace!("recv() would block");
+                            break 'read;
+                        }
 
-                    panic!("recv() failed: {:?}", e);
-                },
-            };
+                        panic!("recv() failed: {:?}", e);
+                    },
+                };
 
             trace!("got {} bytes", len);
 
@@ -261,7 +270,7 @@ fn main() {
                     continue 'read;
                 }
 
-                if !quiche::version_is_supported(hdr.version) {
+                if !config.is_version_supported(hdr.version) {
                     warn!("Doing version negotiation");
 
                     let len =
@@ -409,15 +418,17 @@ fn main() {
                 from,
             };
 
-            // Process potentially coalesced packets.
-            let read = match client.conn.recv(pkt_buf, recv_info) {
-                Ok(v) => v,
+            // Process potentially coalesced packets, and datagrams coalesced
+            // by GRO.
+            let read =
+                match client.conn.recv_batch(pkt_buf, segment_size, recv_info) {
+                    Ok(v) => v,
 
-                Err(e) => {
-                    error!("{} recv failed: {:?}", client.conn.trace_id(), e);
-                    continue 'read;
-                },
-            };
+                    Err(e) => {
+                        error!("{} recv failed: {:?}", client.conn.trace_id(), e);
+                        continue 'read;
+                    },
+                };
 
             trace!("{} processed {} bytes", client.conn.trace_id(), read);
 
@@ -493,6 +504,7 @@ fn main() {
                         partial_responses,
                         &args.root,
                         &args.index,
+                        args.dir_listing,
                         &mut buf,
                     )
                     .is_err()
@@ -536,52 +548,40 @@ fn main() {
                 client.loss_rate = loss_rate;
             }
 
-            let max_send_burst =
-                client.conn.send_quantum().min(client.max_send_burst) /
-                    client.max_datagram_size *
-                    client.max_datagram_size;
-            let mut total_write = 0;
-            let mut dst_info = None;
-
-            while total_write < max_send_burst {
-                let (write, send_info) = match client
-                    .conn
-                    .send(&mut out[total_write..max_send_burst])
-                {
-                    Ok(v) => v,
-
-                    Err(quiche::Error::Done) => {
-                        trace!("{} done writing", client.conn.trace_id());
-                        break;
-                    },
-
-                    Err(e) => {
-                        error!("{} send failed: {:?}", client.conn.trace_id(), e);
-
-                        client.conn.close(false, 0x1, b"fail").ok();
-                        break;
-                    },
-                };
+            // send_batch() also limits the batch to the send budget.
+            let max_send_burst = client.max_send_burst / client.max_datagram_size *
+                client.max_datagram_size;
+            let (total_write, send_info) = match client
+                .conn
+                .send_batch(&mut out[..max_send_burst], client.max_datagram_size)
+            {
+                Ok(v) => v,
 
-                total_write += write;
+                Err(quiche::Error::Done) => {
+                    trace!("{} done writing", client.conn.trace_id());
+                    break;
+                },
 
-                // Use the first packet time to send, not the last.
-                let _ = dst_info.get_or_insert(send_info);
+                // Packets written before the error were returned by the
+                // previous call, and already sent.
+                Err(e) => {
+                    error!("{} send failed: {:?}", client.conn.trace_id(), e);
 
-                if write < client.max_datagram_size {
-                    continue_write = true;
+                    client.conn.close(false, 0x1, b"fail").ok();
                     break;
-                }
-            }
+                },
+            };
 
-            if total_write == 0 || dst_info.is_none() {
-                break;
+            // The batch ended early because of a short packet, there might be
+            // more to send.
+            if total_write % client.max_datagram_size != 0 {
+                continue_write = true;
             }
 
             if let Err(e) = send_to(
                 &socket,
                 &out[..total_write],
-                &dst_info.unwrap(),
+                &send_info,
                 client.max_datagram_size,
                 pacing,
                 enable_gso,
@@ -27,6 +27,7 @@
 use crate::args::*;
 use crate::common::*;
 
+use std::net::SocketAddr;
 use std::net::ToSocketAddrs;
 
 use std::io::prelude::*;
@@ -50,54 +51,9 @@ pub fn connect(
     args: ClientArgs, conn_args: CommonArgs,
     output_sink: impl FnMut(String) + 'static,
 ) -> Result<(), ClientError> {
-    let mut buf = [0; 65535];
-    let mut out = [0; MAX_DATAGRAM_SIZE];
-
     let output_sink =
         Rc::new(RefCell::new(output_sink)) as Rc<RefCell<dyn FnMut(_)>>;
 
-    // Setup the event loop.
-    let mut poll = mio::Poll::new().unwrap();
-    let mut events = mio::Events::with_capacity(1024);
-
-    // We'll only connect to the first server provided in URL list.
-    let connect_url = &args.urls[0];
-
-    // Resolve server address.
-    let peer_addr = if let Some(addr) = &args.connect_to {
-        addr.parse().expect("--connect-to is expected to be a string containing an IPv4 or IPv6 address with a port. E.g. 192.0.2.0:443")
-    } else {
-        connect_url.to_socket_addrs().unwrap().next().unwrap()
-    };
-
-    // Bind to INADDR_ANY or IN6ADDR_ANY depending on the IP family of the
-    // server address. This is needed on macOS and BSD variants that don't
-    // support binding to IN6ADDR_ANY for both v4 and v6.
-    let bind_addr = match peer_addr {
-        std::net::SocketAddr::V4(_) => format!("0.0.0.0:{}", args.source_port),
-        std::net::SocketAddr::V6(_) => format!("[::]:{}", args.source_port),
-    };
-
-    // Create the UDP socket backing the QUIC connection, and register it with
-    // the event loop.
-    let mut socket =
-        mio::net::UdpSocket::bind(bind_addr.parse().unwrap()).unwrap();
-    poll.registry()
-        .register(&mut socket, mio::Token(0), mio::Interest::READABLE)
-        .unwrap();
-
-    let migrate_socket = if args.perform_migration {
-        let mut socket =
-            mio::net::UdpSocket::bind(bind_addr.parse().unwrap()).unwrap();
-        poll.registry()
-            .register(&mut socket, mio::Token(1), mio::Interest::READABLE)
-            .unwrap();
-
-        Some(socket)
-    } else {
-        None
-    };
-
     // Create the configuration for the QUIC connection.
     let mut config = quiche::Config::new(args.version).unwrap();
 
@@ -165,89 +121,169 @@ pub fn connect(
         config.enable_dgram(true, 1000, 1000);
     }
 
-    let mut http_conn: Option<Box<dyn HttpConn>> = None;
+    // Group URLs by origin, so that all the requests to the same origin are
+    // sent on a single connection, in the order they were given.
+    let mut origins: Vec<(url::Origin, Vec<url::Url>)> = Vec::new();
 
-    let mut app_proto_selected = false;
+    for url in &args.urls {
+        match origins
+            .iter_mut()
+            .find(|(origin, _)| *origin == url.origin())
+        {
+            Some((_, urls)) => urls.push(url.clone()),
+
+            None => origins.push((url.origin(), vec![url.clone()])),
+        }
+    }
+
+    for (_, urls) in &origins {
+        connect_origin(
+            &args,
+            &conn_args,
+            &mut config,
+            &keylog,
+            urls,
+            &output_sink,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Connects to the origin of `urls` and performs all the requests over a
+/// single connection.
+fn connect_origin(
+    args: &ClientArgs, conn_args: &CommonArgs, config: &mut quiche::Config,
+    keylog: &Option<std::fs::File>, urls: &[url::Url],
+    output_sink: &Rc<RefCell<dyn FnMut(String)>>,
+) -> Result<(), ClientError> {
+    let mut buf = [0; 65535];
+    let mut out = [0; MAX_DATAGRAM_SIZE];
+
+    // Setup the event loop.
+    let mut poll = mio::Poll::new().unwrap();
+    let mut events = mio::Events::with_capacity(1024);
+
+    let connect_url = &urls[0];
+
+    // Resolve server addresses.
+    let peer_addrs = if let Some(addr) = &args.connect_to {
+        vec![addr.parse().expect("--connect-to is expected to be a string containing an IPv4 or IPv6 address with a port. E.g. 192.0.2.0:443")]
+    } else {
+        let addrs = connect_url.to_socket_addrs().map_err(|e| {
+            ClientError::Other(format!("failed to resolve {connect_url}: {e}"))
+        })?;
+
+        sort_addrs(addrs.collect())
+    };
 
-    // Generate a random source connection ID for the connection.
-    let mut scid = [0; quiche::MAX_CONN_ID_LEN];
     let rng = SystemRandom::new();
-    rng.fill(&mut scid[..]).unwrap();
 
-    let scid = quiche::ConnectionId::from_ref(&scid);
+    let mut pkt_count = 0;
 
-    let local_addr = socket.local_addr().unwrap();
+    let app_data_start = std::time::Instant::now();
 
-    // Create a QUIC connection and initiate handshake.
-    let mut conn = quiche::connect(
-        connect_url.domain(),
-        &scid,
-        local_addr,
-        peer_addr,
-        &mut config,
-    )
-    .unwrap();
+    // Create a QUIC connection to the given address and initiate handshake.
+    let mut new_conn = |local_addr: SocketAddr, peer_addr: SocketAddr| {
+        // Generate a random source connection ID for the connection.
+        let mut scid = [0; quiche::MAX_CONN_ID_LEN];
+        rng.fill(&mut scid[..]).unwrap();
+
+        let scid = quiche::ConnectionId::from_ref(&scid);
+
+        let mut conn = quiche::connect(
+            connect_url.domain(),
+            &scid,
+            local_addr,
+            peer_addr,
+            config,
+        )
+        .unwrap();
 
-    if let Some(keylog) = &mut keylog {
-        if let Ok(keylog) = keylog.try_clone() {
-            conn.set_keylog(Box::new(keylog));
+        if let Some(keylog) = keylog {
+            if let Ok(keylog) = keylog.try_clone() {
+                conn.set_keylog(Box::new(keylog));
+            }
         }
-    }
 
-    // Only bother with qlog if the user specified it.
-    #[cfg(feature = "qlog")]
-    {
-        if let Some(dir) = std::env::var_os("QLOGDIR") {
-            let id = format!("{scid:?}");
-            let writer = make_qlog_writer(&dir, "client", &id);
-
-            conn.set_qlog(
-                std::boxed::Box::new(writer),
-                "quiche-client qlog".to_string(),
-                format!("{} id={}", "quiche-client qlog", id),
-            );
+        // Only bother with qlog if the user specified it.
+        #[cfg(feature = "qlog")]
+        {
+            if let Some(dir) = std::env::var_os("QLOGDIR") {
+                let id = format!("{scid:?}");
+                let writer = make_qlog_writer(&dir, "client", &id);
+
+                conn.set_qlog(
+                    std::boxed::Box::new(writer),
+                    "quiche-client qlog".to_string(),
+                    format!("{} id={}", "quiche-client qlog", id),
+                );
+            }
         }
-    }
 
-    if let Some(session_file) = &args.session_file {
-        if let Ok(session) = std::fs::read(session_file) {
-            conn.set_session(&session).ok();
+        if let Some(session_file) = &args.session_file {
+            if let Ok(session) = std::fs::read(session_file) {
+                conn.set_session(&session).ok();
+            }
         }
-    }
 
-    info!(
-        "connecting to {:} from {:} with scid {:?}",
-        peer_addr,
-        socket.local_addr().unwrap(),
-        scid,
-    );
-
-    let (write, send_info) = conn.send(&mut out).expect("initial send failed");
-
-    while let Err(e) = socket.send_to(&out[..write], send_info.to) {
-        if e.kind() == std::io::ErrorKind::WouldBlock {
-            trace!(
-                "{} -> {}: send() would block",
-                socket.local_addr().unwrap(),
-                send_info.to
-            );
-            continue;
-        }
+        info!(
+            "connecting to {:} from {:} with scid {:?}",
+            peer_addr, local_addr, scid,
+        );
 
-        return Err(ClientError::Other(format!("send() failed: {e:?}")));
-    }
+        conn
+    };
 
-    trace!("written {}", write);
+    let (mut socket, mut conn, peer_addr) = race_connections(
+        &mut poll,
+        &peer_addrs,
+        args.source_port,
+        &mut new_conn,
+        conn_args,
+        &mut pkt_count,
+        &mut buf,
+        &mut out,
+    )?;
 
-    let app_data_start = std::time::Instant::now();
+    poll.registry()
+        .reregister(&mut socket, mio::Token(0), mio::Interest::READABLE)
+        .unwrap();
 
-    let mut pkt_count = 0;
+    let migrate_socket = if args.perform_migration {
+        let mut socket =
+            mio::net::UdpSocket::bind(bind_addr(&peer_addr, args.source_port))
+                .unwrap();
+        poll.registry()
+            .register(&mut socket, mio::Token(1), mio::Interest::READABLE)
+            .unwrap();
+
+        Some(socket)
+    } else {
+        None
+    };
+
+    let mut http_conn: Option<Box<dyn HttpConn>> = None;
+
+    let mut app_proto_selected = false;
+
+    let mut handshake_completed = false;
 
     let mut scid_sent = false;
     let mut new_path_probed = false;
     let mut migrated = false;
 
     loop {
+        if conn.is_established() && !handshake_completed {
+            info!(
+                "handshake with {} completed in {:?}",
+                peer_addr,
+                app_data_start.elapsed()
+            );
+
+            handshake_completed = true;
+        }
+
         if !conn.is_in_early_data() || app_proto_selected {
             poll.poll(&mut events, conn.timeout()).unwrap();
         }
@@ -293,16 +329,7 @@ pub fn connect(
 
                 trace!("{}: got {} bytes", local_addr, len);
 
-                if let Some(target_path) = conn_args.dump_packet_path.as_ref() {
-                    let path = format!("{target_path}/{pkt_count}.pkt");
-
-                    if let Ok(f) = std::fs::File::create(path) {
-                        let mut f = std::io::BufWriter::new(f);
-                        f.write_all(&buf[..len]).ok();
-                    }
-                }
-
-                pkt_count += 1;
+                dump_packet(conn_args, &mut pkt_count, &buf[..len]);
 
                 let recv_info = quiche::RecvInfo {
                     to: local_addr,
@@ -374,9 +401,9 @@ pub fn connect(
 
             if alpns::HTTP_09.contains(&app_proto) {
                 http_conn = Some(Http09Conn::with_urls(
-                    &args.urls,
+                    urls,
                     args.reqs_cardinal,
-                    Rc::clone(&output_sink),
+                    Rc::clone(output_sink),
                 ));
 
                 app_proto_selected = true;
@@ -393,7 +420,7 @@ pub fn connect(
 
                 http_conn = Some(Http3Conn::with_urls(
                     &mut conn,
-                    &args.urls,
+                    urls,
                     args.reqs_cardinal,
                     &args.req_headers,
                     &args.body,
@@ -404,7 +431,7 @@ pub fn connect(
                     conn_args.qpack_blocked_streams,
                     args.dump_json,
                     dgram_sender,
-                    Rc::clone(&output_sink),
+                    Rc::clone(output_sink),
                 ));
 
                 app_proto_selected = true;
@@ -588,3 +615,269 @@ pub fn connect(
 
     Ok(())
 }
+
+/// Delay between starting successive connection attempts, as recommended by
+/// RFC 8305.
+const CONNECTION_ATTEMPT_DELAY: std::time::Duration =
+    std::time::Duration::from_millis(250);
+
+/// A connection attempt started while racing the resolved addresses.
+struct Attempt {
+    conn: quiche::Connection,
+    peer_addr: SocketAddr,
+}
+
+/// Orders addresses as described in RFC 8305, interleaving address families
+/// starting with IPv6, while otherwise preserving the resolver's order.
+fn sort_addrs(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let (mut v6, mut v4): (Vec<_>, Vec<_>) =
+        addrs.into_iter().partition(|a| a.is_ipv6());
+
+    let mut sorted = Vec::with_capacity(v6.len() + v4.len());
+
+    v6.reverse();
+    v4.reverse();
+
+    loop {
+        match (v6.pop(), v4.pop()) {
+            (None, None) => break,
+
+            (a, b) => sorted.extend(a.into_iter().chain(b)),
+        }
+    }
+
+    sorted
+}
+
+/// Returns the address to bind to for talking to `peer_addr`.
+fn bind_addr(peer_addr: &SocketAddr, source_port: u16) -> SocketAddr {
+    // Bind to INADDR_ANY or IN6ADDR_ANY depending on the IP family of the
+    // server address. This is needed on macOS and BSD variants that don't
+    // support binding to IN6ADDR_ANY for both v4 and v6.
+    let bind_addr = match peer_addr {
+        SocketAddr::V4(_) => format!("0.0.0.0:{source_port}"),
+        SocketAddr::V6(_) => format!("[::]:{source_port}"),
+    };
+
+    bind_addr.parse().unwrap()
+}
+
+/// Writes a received packet to the directory given with --dump-packets.
+fn dump_packet(conn_args: &CommonArgs, pkt_count: &mut u64, pkt: &[u8]) {
+    if let Some(target_path) = conn_args.dump_packet_path.as_ref() {
+        let path = format!("{target_path}/{pkt_count}.pkt");
+
+        if let Ok(f) = std::fs::File::create(path) {
+            let mut f = std::io::BufWriter::new(f);
+            f.write_all(pkt).ok();
+        }
+    }
+
+    *pkt_count += 1;
+}
+
+/// Races connection attempts to the given addresses ("Happy Eyeballs"),
+/// starting a new one every CONNECTION_ATTEMPT_DELAY, or as soon as the
+/// previous ones failed.
+///
+/// Returns the first connection that completes the handshake, or that can
+/// send early data, along with the socket it uses and the address it is
+/// connected to. The other attempts are abandoned.
+#[allow(clippy::too_many_arguments)]
+fn race_connections(
+    poll: &mut mio::Poll, peer_addrs: &[SocketAddr], source_port: u16,
+    new_conn: &mut impl FnMut(SocketAddr, SocketAddr) -> quiche::Connection,
+    conn_args: &CommonArgs, pkt_count: &mut u64, buf: &mut [u8], out: &mut [u8],
+) -> Result<(mio::net::UdpSocket, quiche::Connection, SocketAddr), ClientError> {
+    let mut events = mio::Events::with_capacity(1024);
+
+    // One socket per address family, indexed by the mio token they are
+    // registered with.
+    let mut sockets: [Option<mio::net::UdpSocket>; 2] = [None, None];
+
+    let mut attempts: Vec<Attempt> = Vec::new();
+
+    let mut next_addr = 0;
+    let mut next_attempt = std::time::Instant::now();
+
+    let start = std::time::Instant::now();
+
+    loop {
+        // Start the next attempt, if it's time to.
+        if next_addr < peer_addrs.len() &&
+            (attempts.is_empty() || next_attempt <= std::time::Instant::now())
+        {
+            let peer_addr = peer_addrs[next_addr];
+            let family = peer_addr.is_ipv4() as usize;
+
+            next_addr += 1;
+
+            if sockets[family].is_none() {
+                match mio::net::UdpSocket::bind(bind_addr(
+                    &peer_addr,
+                    source_port,
+                )) {
+                    Ok(mut socket) => {
+                        poll.registry()
+                            .register(
+                                &mut socket,
+                                mio::Token(family),
+                                mio::Interest::READABLE,
+                            )
+                            .unwrap();
+
+                        sockets[family] = Some(socket);
+                    },
+
+                    Err(e) => {
+                        error!(
+                            "failed to bind socket for {}: {:?}",
+                            peer_addr, e
+                        );
+                        continue;
+                    },
+                }
+            }
+
+            let local_addr =
+                sockets[family].as_ref().unwrap().local_addr().unwrap();
+
+            attempts.push(Attempt {
+                conn: new_conn(local_addr, peer_addr),
+                peer_addr,
+            });
+
+            next_attempt = std::time::Instant::now() + CONNECTION_ATTEMPT_DELAY;
+        }
+
+        // Generate outgoing QUIC packets for all attempts.
+        for attempt in &mut attempts {
+            let family = attempt.peer_addr.is_ipv4() as usize;
+            let socket = sockets[family].as_ref().unwrap();
+
+            loop {
+                let (write, send_info) = match attempt.conn.send(out) {
+                    Ok(v) => v,
+
+                    Err(quiche::Error::Done) => break,
+
+                    Err(e) => {
+                        error!("{}: send failed: {:?}", attempt.peer_addr, e);
+
+                        attempt.conn.close(false, 0x1, b"fail").ok();
+                        break;
+                    },
+                };
+
+                if let Err(e) = socket.send_to(&out[..write], send_info.to) {
+                    if e.kind() == std::io::ErrorKind::WouldBlock {
+                        trace!("{}: send() would block", send_info.to);
+                        break;
+                    }
+
+                    return Err(ClientError::Other(format!(
+                        "{}: send() failed: {:?}",
+                        send_info.to, e
+                    )));
+                }
+
+                trace!("{}: written {}", send_info.to, write);
+            }
+        }
+
+        if let Some(i) = attempts
+            .iter()
+            .position(|a| a.conn.is_established() || a.conn.is_in_early_data())
+        {
+            let winner = attempts.swap_remove(i);
+            let family = winner.peer_addr.is_ipv4() as usize;
+
+            for attempt in attempts {
+                info!("abandoning connection attempt to {}", attempt.peer_addr);
+            }
+
+            let socket = sockets[family].take().unwrap();
+
+            return Ok((socket, winner.conn, winner.peer_addr));
+        }
+
+        attempts.retain(|a| {
+            if a.conn.is_closed() {
+                info!("connection attempt to {} failed", a.peer_addr);
+            }
+
+            !a.conn.is_closed()
+        });
+
+        if attempts.is_empty() {
+            if next_addr < peer_addrs.len() {
+                continue;
+            }
+
+            error!("connection timed out after {:?}", start.elapsed());
+
+            return Err(ClientError::HandshakeFail);
+        }
+
+        let mut timeout = attempts.iter().filter_map(|a| a.conn.timeout()).min();
+
+        if next_addr < peer_addrs.len() {
+            let delay =
+                next_attempt.saturating_duration_since(std::time::Instant::now());
+
+            timeout = Some(timeout.map_or(delay, |t| t.min(delay)));
+        }
+
+        poll.poll(&mut events, timeout).unwrap();
+
+        if events.is_empty() {
+            trace!("timed out");
+
+            for attempt in &mut attempts {
+                attempt.conn.on_timeout();
+            }
+        }
+
+        for event in &events {
+            let socket = sockets[event.token().0].as_ref().unwrap();
+            let local_addr = socket.local_addr().unwrap();
+
+            'read: loop {
+                let (len, from) = match socket.recv_from(buf) {
+                    Ok(v) => v,
+
+                    Err(e) => {
+                        if e.kind() == std::io::ErrorKind::WouldBlock {
+                            trace!("{}: recv() would block", local_addr);
+                            break 'read;
+                        }
+
+                        return Err(ClientError::Other(format!(
+                            "{local_addr}: recv() failed: {e:?}"
+                        )));
+                    },
+                };
+
+                trace!("{}: got {} bytes", local_addr, len);
+
+                dump_packet(conn_args, pkt_count, &buf[..len]);
+
+                let attempt =
+                    match attempts.iter_mut().find(|a| a.peer_addr == from) {
+                        Some(v) => v,
+
+                        None => continue 'read,
+                    };
+
+                let recv_info = quiche::RecvInfo {
+                    to: local_addr,
+                    from,
+                };
+
+                if let Err(e) = attempt.conn.recv(&mut buf[..len], recv_info) {
+                    error!("{}: recv failed: {:?}", local_addr, e);
+                }
+            }
+        }
+    }
+}
@@ -146,6 +146,265 @@ fn autoindex(path: path::PathBuf, index: &str) -> path::PathBuf {
     path
 }
 
+/// The conditions of a request for a file.
+#[derive(Default)]
+struct FileRequest {
+    range: Option<String>,
+
+    if_modified_since: Option<String>,
+
+    if_none_match: Option<String>,
+}
+
+/// Builds the response for a file, honoring conditional and range requests.
+///
+/// Returns `None` if the file can't be read.
+fn build_file_response(
+    file_path: &path::Path, req: &FileRequest,
+) -> Option<(u16, Vec<quiche::h3::Header>, Vec<u8>)> {
+    let data = std::fs::read(file_path).ok()?;
+
+    let modified = std::fs::metadata(file_path)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|d| d.as_secs());
+
+    let etag = format!("\"{:x}-{:x}\"", data.len(), modified.unwrap_or(0));
+
+    let mut headers = vec![
+        quiche::h3::Header::new(b"accept-ranges", b"bytes"),
+        quiche::h3::Header::new(b"etag", etag.as_bytes()),
+    ];
+
+    if let Some(modified) = modified {
+        headers.push(quiche::h3::Header::new(
+            b"last-modified",
+            http_date(modified).as_bytes(),
+        ));
+    }
+
+    // If-None-Match takes precedence over If-Modified-Since.
+    let not_modified = match (&req.if_none_match, &req.if_modified_since) {
+        (Some(tags), _) => tags.split(',').any(|t| {
+            let t = t.trim();
+            t == "*" || t.trim_start_matches("W/") == etag
+        }),
+
+        (None, Some(since)) => match (parse_http_date(since), modified) {
+            (Some(since), Some(modified)) => modified <= since,
+
+            _ => false,
+        },
+
+        (None, None) => false,
+    };
+
+    if not_modified {
+        return Some((304, headers, Vec::new()));
+    }
+
+    let len = data.len() as u64;
+
+    match req.range.as_deref().and_then(|r| parse_range(r, len)) {
+        Some(Some((start, end))) => {
+            headers.push(quiche::h3::Header::new(
+                b"content-range",
+                format!("bytes {start}-{end}/{len}").as_bytes(),
+            ));
+
+            let body = data[start as usize..=end as usize].to_vec();
+
+            Some((206, headers, body))
+        },
+
+        Some(None) => {
+            headers.push(quiche::h3::Header::new(
+                b"content-range",
+                format!("bytes */{len}").as_bytes(),
+            ));
+
+            Some((416, headers, Vec::new()))
+        },
+
+        None => Some((200, headers, data)),
+    }
+}
+
+/// Parses the value of a Range header for a file of `len` bytes.
+///
+/// Returns `None` if the header should be ignored, e.g. because it is
+/// invalid or has multiple ranges, which are not supported. Otherwise returns
+/// the inclusive byte range to send, or `None` if it can't be satisfied.
+fn parse_range(value: &str, len: u64) -> Option<Option<(u64, u64)>> {
+    let spec = value.trim().strip_prefix("bytes=")?;
+
+    if spec.contains(',') {
+        return None;
+    }
+
+    let (start, end) = spec.split_once('-')?;
+
+    let range = match (start.trim(), end.trim()) {
+        ("", "") => return None,
+
+        // A suffix range, with the last bytes of the file.
+        ("", suffix) => {
+            let suffix: u64 = suffix.parse().ok()?;
+
+            if suffix == 0 || len == 0 {
+                None
+            } else {
+                Some((len.saturating_sub(suffix), len - 1))
+            }
+        },
+
+        (start, end) => {
+            let start: u64 = start.parse().ok()?;
+
+            let end = match end {
+                "" => u64::MAX,
+
+                _ => end.parse().ok()?,
+            };
+
+            if end < start {
+                return None;
+            }
+
+            if start >= len {
+                None
+            } else {
+                Some((start, std::cmp::min(end, len - 1)))
+            }
+        },
+    };
+
+    Some(range)
+}
+
+/// Builds an HTML page listing the contents of a directory.
+fn build_dir_listing(dir: &path::Path, url_path: &str) -> Option<Vec<u8>> {
+    let mut entries: Vec<String> = std::fs::read_dir(dir)
+        .ok()?
+        .filter_map(|e| e.ok())
+        .map(|e| {
+            let mut name = e.file_name().to_string_lossy().into_owned();
+
+            if e.path().is_dir() {
+                name.push('/');
+            }
+
+            name
+        })
+        .collect();
+
+    entries.sort();
+
+    let title = html_escape(url_path);
+
+    let mut body = format!(
+        "<!DOCTYPE html>\n<html><head><title>Index of {title}</title></head>\n\
+         <body><h1>Index of {title}</h1><ul>\n"
+    );
+
+    for name in entries {
+        let name = html_escape(&name);
+        writeln!(body, "<li><a href=\"{name}\">{name}</a></li>").ok();
+    }
+
+    body.push_str("</ul></body></html>\n");
+
+    Some(body.into_bytes())
+}
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov",
+    "Dec",
+];
+
+/// Formats a UNIX timestamp as an HTTP date (RFC 9110 Section 5.6.7).
+fn http_date(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+
+    let (year, month, day) = civil_from_days(days);
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[(days % 7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+/// Parses an HTTP date in the IMF-fixdate format, e.g.
+/// "Sun, 06 Nov 1994 08:49:37 GMT", into a UNIX timestamp.
+fn parse_http_date(date: &str) -> Option<u64> {
+    let mut parts = date.split_whitespace().skip(1);
+
+    let day: i64 = parts.next()?.parse().ok()?;
+
+    let month = parts.next()?;
+    let month = MONTHS.iter().position(|&m| m == month)? as i64 + 1;
+
+    let year: i64 = parts.next()?.parse().ok()?;
+
+    let mut time = parts.next()?.split(':');
+    let hour: i64 = time.next()?.parse().ok()?;
+    let min: i64 = time.next()?.parse().ok()?;
+    let sec: i64 = time.next()?.parse().ok()?;
+
+    if parts.next()? != "GMT" {
+        return None;
+    }
+
+    let days = days_from_civil(year, month, day);
+
+    u64::try_from(days * 86400 + hour * 3600 + min * 60 + sec).ok()
+}
+
+// Conversions between days since the UNIX epoch and civil dates, from
+// http://howardhinnant.github.io/date_algorithms.html
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    (year, month, day)
+}
+
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+
+    era * 146097 + doe - 719468
+}
+
 /// Makes a buffered writer for a qlog.
 pub fn make_qlog_writer(
     dir: &std::ffi::OsStr, role: &str, id: &str,
@@ -345,11 +604,12 @@ pub trait HttpConn {
 
     fn report_incomplete(&self, start: &std::time::Instant) -> bool;
 
+    #[allow(clippy::too_many_arguments)]
     fn handle_requests(
         &mut self, conn: &mut quiche::Connection,
         partial_requests: &mut HashMap<u64, PartialRequest>,
         partial_responses: &mut HashMap<u64, PartialResponse>, root: &str,
-        index: &str, buf: &mut [u8],
+        index: &str, dir_listing: bool, buf: &mut [u8],
     ) -> quiche::h3::Result<()>;
 
     fn handle_writable(
@@ -365,6 +625,8 @@ pub struct Http09Request {
     request_line: String,
     stream_id: Option<u64>,
     response_writer: Option<std::io::BufWriter<std::fs::File>>,
+    sent_at: Option<std::time::Instant>,
+    ttfb: Option<std::time::Duration>,
 }
 
 /// Represents an HTTP/3 formatted request.
@@ -378,6 +640,25 @@ struct Http3Request {
     response_body: Vec<u8>,
     response_body_max: usize,
     response_writer: Option<std::io::BufWriter<std::fs::File>>,
+    sent_at: Option<std::time::Instant>,
+    ttfb: Option<std::time::Duration>,
+}
+
+/// Logs how long a request took to get the first byte of its response
+/// (TTFB) and to complete, both measured from when it was sent.
+fn log_request_timing(
+    url: &url::Url, stream_id: u64, sent_at: Option<std::time::Instant>,
+    ttfb: Option<std::time::Duration>,
+) {
+    if let Some(sent_at) = sent_at {
+        info!(
+            "{} on stream {}: ttfb {:?}, completed in {:?}",
+            url,
+            stream_id,
+            ttfb.unwrap_or_default(),
+            sent_at.elapsed()
+        );
+    }
 }
 
 type Http3ResponseBuilderResult = std::result::Result<
@@ -420,6 +701,8 @@ impl Http09Conn {
                     request_line,
                     stream_id: None,
                     response_writer: None,
+                    sent_at: None,
+                    ttfb: None,
                 });
             }
         }
@@ -466,6 +749,7 @@ impl HttpConn for Http09Conn {
             req.stream_id = Some(self.stream_id);
             req.response_writer =
                 make_resource_writer(&req.url, target_path, req.cardinal);
+            req.sent_at = Some(std::time::Instant::now());
 
             self.stream_id += 4;
 
@@ -499,6 +783,10 @@ impl HttpConn for Http09Conn {
                     .find(|r| r.stream_id == Some(s))
                     .unwrap();
 
+                if req.ttfb.is_none() {
+                    req.ttfb = req.sent_at.map(|t| t.elapsed());
+                }
+
                 match &mut req.response_writer {
                     Some(rw) => {
                         rw.write_all(&buf[..read]).ok();
@@ -517,6 +805,8 @@ impl HttpConn for Http09Conn {
                 // we got the full response. If all responses are received
                 // then close the connection.
                 if &s % 4 == 0 && fin {
+                    log_request_timing(&req.url, s, req.sent_at, req.ttfb);
+
                     self.reqs_complete += 1;
                     let reqs_count = self.reqs.len();
 
@@ -566,7 +856,7 @@ impl HttpConn for Http09Conn {
         &mut self, conn: &mut quiche::Connection,
         partial_requests: &mut HashMap<u64, PartialRequest>,
         partial_responses: &mut HashMap<u64, PartialResponse>, root: &str,
-        index: &str, buf: &mut [u8],
+        index: &str, _dir_listing: bool, buf: &mut [u8],
     ) -> quiche::h3::Result<()> {
         // Process all readable streams.
         for s in conn.readable() {
@@ -828,6 +1118,8 @@ impl Http3Conn {
                     response_body_max: dump_json.unwrap_or_default(),
                     stream_id: None,
                     response_writer: None,
+                    sent_at: None,
+                    ttfb: None,
                 });
             }
         }
@@ -889,7 +1181,8 @@ impl Http3Conn {
 
     /// Builds an HTTP/3 response given a request.
     fn build_h3_response(
-        root: &str, index: &str, request: &[quiche::h3::Header],
+        root: &str, index: &str, dir_listing: bool,
+        request: &[quiche::h3::Header],
     ) -> Http3ResponseBuilderResult {
         let mut file_path = path::PathBuf::from(root);
         let mut scheme = None;
@@ -898,6 +1191,7 @@ impl Http3Conn {
         let mut path = None;
         let mut method = None;
         let mut priority = vec![];
+        let mut conditions = FileRequest::default();
 
         // Parse some of the request headers.
         for hdr in request {
@@ -957,6 +1251,18 @@ impl Http3Conn {
 
                 b"host" => host = Some(std::str::from_utf8(hdr.value()).unwrap()),
 
+                b"range" =>
+                    conditions.range =
+                        Some(String::from_utf8_lossy(hdr.value()).into_owned()),
+
+                b"if-modified-since" =>
+                    conditions.if_modified_since =
+                        Some(String::from_utf8_lossy(hdr.value()).into_owned()),
+
+                b"if-none-match" =>
+                    conditions.if_none_match =
+                        Some(String::from_utf8_lossy(hdr.value()).into_owned()),
+
                 _ => (),
             }
         }
@@ -1081,7 +1387,7 @@ impl Http3Conn {
             priority = p.as_bytes().to_vec();
         }
 
-        let (status, body) = match decided_method {
+        let (status, extra_headers, body) = match decided_method {
             "GET" => {
                 for c in pathbuf.components() {
                     if let path::Component::Normal(v) = c {
@@ -1089,24 +1395,44 @@ impl Http3Conn {
                     }
                 }
 
-                match std::fs::read(file_path.as_path()) {
-                    Ok(data) => (200, data),
+                let listing = || {
+                    // Only list directories whose index file is missing.
+                    if !dir_listing || !url.path().ends_with('/') {
+                        return None;
+                    }
 
-                    Err(_) => (404, b"Not Found!".to_vec()),
-                }
+                    let body =
+                        build_dir_listing(file_path.parent()?, url.path())?;
+
+                    let headers = vec![quiche::h3::Header::new(
+                        b"content-type",
+                        b"text/html",
+                    )];
+
+                    Some((200, headers, body))
+                };
+
+                build_file_response(&file_path, &conditions)
+                    .or_else(listing)
+                    .unwrap_or_else(|| (404, vec![], b"Not Found!".to_vec()))
             },
 
-            _ => (405, Vec::new()),
+            _ => (405, vec![], Vec::new()),
         };
 
-        let headers = vec![
+        let mut headers = vec![
             quiche::h3::Header::new(b":status", status.to_string().as_bytes()),
             quiche::h3::Header::new(b"server", b"quiche"),
-            quiche::h3::Header::new(
+        ];
+
+        if status != 304 {
+            headers.push(quiche::h3::Header::new(
                 b"content-length",
                 body.len().to_string().as_bytes(),
-            ),
-        ];
+            ));
+        }
+
+        headers.extend(extra_headers);
 
         Ok((headers, body, priority))
     }
@@ -1157,6 +1483,7 @@ impl HttpConn for Http3Conn {
             req.stream_id = Some(s);
             req.response_writer =
                 make_resource_writer(&req.url, target_path, req.cardinal);
+            req.sent_at = Some(std::time::Instant::now());
             self.sent_body_bytes.insert(s, 0);
 
             reqs_done += 1;
@@ -1234,6 +1561,7 @@ impl HttpConn for Http3Conn {
                         .unwrap();
 
                     req.response_hdrs = list;
+                    req.ttfb = req.sent_at.map(|t| t.elapsed());
                 },
 
                 Ok((stream_id, quiche::h3::Event::Data)) => {
@@ -1274,7 +1602,18 @@ impl HttpConn for Http3Conn {
                     }
                 },
 
-                Ok((_stream_id, quiche::h3::Event::Finished)) => {
+                Ok((stream_id, quiche::h3::Event::Finished)) => {
+                    if let Some(req) =
+                        self.reqs.iter().find(|r| r.stream_id == Some(stream_id))
+                    {
+                        log_request_timing(
+                            &req.url,
+                            stream_id,
+                            req.sent_at,
+                            req.ttfb,
+                        );
+                    }
+
                     self.reqs_complete += 1;
                     let reqs_count = self.reqs.len();
 
@@ -1322,6 +1661,35 @@ impl HttpConn for Http3Conn {
                     break;
                 },
 
+                Ok((_stream_id, quiche::h3::Event::RequestCancelled)) => {
+                    error!("request was cancelled by peer, closing...");
+
+                    match conn.close(true, 0x100, b"kthxbye") {
+                        // Already closed.
+                        Ok(_) | Err(quiche::Error::Done) => (),
+
+                        Err(e) => panic!("error closing conn: {:?}", e),
+                    }
+
+                    break;
+                },
+
+                Ok((_stream_id, quiche::h3::Event::MessageError(cause))) => {
+                    error!(
+                        "malformed response received: {:?}, closing...",
+                        cause
+                    );
+
+                    match conn.close(true, 0x100, b"kthxbye") {
+                        // Already closed.
+                        Ok(_) | Err(quiche::Error::Done) => (),
+
+                        Err(e) => panic!("error closing conn: {:?}", e),
+                    }
+
+                    break;
+                },
+
                 Ok((
                     prioritized_element_id,
                     quiche::h3::Event::PriorityUpdate,
@@ -1341,6 +1709,28 @@ impl HttpConn for Http3Conn {
                     );
                 },
 
+                Ok((stream_id, quiche::h3::Event::RequestRejected)) => {
+                    info!("request on stream id {} was rejected", stream_id);
+                },
+
+                Ok((_goaway_id, quiche::h3::Event::Drained)) => (),
+
+                Ok((stream_id, quiche::h3::Event::InterimHeaders { list })) => {
+                    debug!(
+                        "got interim response headers {:?} on stream id {}",
+                        hdrs_to_strings(&list),
+                        stream_id
+                    );
+                },
+
+                Ok((stream_id, quiche::h3::Event::Trailers { list })) => {
+                    debug!(
+                        "got response trailers {:?} on stream id {}",
+                        hdrs_to_strings(&list),
+                        stream_id
+                    );
+                },
+
                 Err(quiche::h3::Error::Done) => {
                     break;
                 },
@@ -1390,7 +1780,7 @@ impl HttpConn for Http3Conn {
         &mut self, conn: &mut quiche::Connection,
         _partial_requests: &mut HashMap<u64, PartialRequest>,
         partial_responses: &mut HashMap<u64, PartialResponse>, root: &str,
-        index: &str, buf: &mut [u8],
+        index: &str, dir_listing: bool, buf: &mut [u8],
     ) -> quiche::h3::Result<()> {
         // Process HTTP stream-related events.
         loop {
@@ -1414,7 +1804,12 @@ impl HttpConn for Http3Conn {
                         .unwrap();
 
                     let (mut headers, body, mut priority) =
-                        match Http3Conn::build_h3_response(root, index, &list) {
+                        match Http3Conn::build_h3_response(
+                            root,
+                            index,
+                            dir_listing,
+                            &list,
+                        ) {
                             Ok(v) => v,
 
                             Err((error_code, _)) => {
@@ -1517,6 +1912,10 @@ impl HttpConn for Http3Conn {
 
                 Ok((_stream_id, quiche::h3::Event::Reset { .. })) => (),
 
+                Ok((_stream_id, quiche::h3::Event::MessageError(_))) => (),
+
+                Ok((_stream_id, quiche::h3::Event::RequestCancelled)) => (),
+
                 Ok((
                     prioritized_element_id,
                     quiche::h3::Event::PriorityUpdate,
@@ -1538,6 +1937,20 @@ impl HttpConn for Http3Conn {
                         .send_goaway(conn, self.largest_processed_request)?;
                 },
 
+                Ok((stream_id, quiche::h3::Event::RequestRejected)) => {
+                    info!(
+                        "{} rejected request on stream {}",
+                        conn.trace_id(),
+                        stream_id
+                    );
+                },
+
+                Ok((_goaway_id, quiche::h3::Event::Drained)) => (),
+
+                Ok((_stream_id, quiche::h3::Event::InterimHeaders { .. })) => (),
+
+                Ok((_stream_id, quiche::h3::Event::Trailers { .. })) => (),
+
                 Err(quiche::h3::Error::Done) => {
                     break;
                 },
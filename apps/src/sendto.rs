@@ -47,6 +47,96 @@ pub fn detect_gso(_socket: &mio::net::UdpSocket, _segment_size: usize) -> bool {
     false
 }
 
+/// For Linux, try to enable GRO on the socket.
+#[cfg(target_os = "linux")]
+pub fn detect_gro(socket: &mio::net::UdpSocket) -> bool {
+    use nix::sys::socket::setsockopt;
+    use nix::sys::socket::sockopt::UdpGroSegment;
+    use std::os::unix::io::AsRawFd;
+
+    // mio::net::UdpSocket doesn't implement AsFd (yet?).
+    let fd = unsafe { std::os::fd::BorrowedFd::borrow_raw(socket.as_raw_fd()) };
+
+    setsockopt(&fd, UdpGroSegment, &true).is_ok()
+}
+
+/// For non-Linux, there is no GRO support.
+#[cfg(not(target_os = "linux"))]
+pub fn detect_gro(_socket: &mio::net::UdpSocket) -> bool {
+    false
+}
+
+/// Receive packets using recvmsg() with GRO.
+#[cfg(target_os = "linux")]
+fn recv_from_gro(
+    socket: &mio::net::UdpSocket, buf: &mut [u8],
+) -> io::Result<(usize, std::net::SocketAddr, usize)> {
+    use nix::sys::socket::recvmsg;
+    use nix::sys::socket::ControlMessageOwned;
+    use nix::sys::socket::MsgFlags;
+    use nix::sys::socket::SockaddrStorage;
+    use std::io::IoSliceMut;
+    use std::os::unix::io::AsRawFd;
+
+    let mut iov = [IoSliceMut::new(buf)];
+    let mut cmsg_buf = nix::cmsg_space!(u16);
+
+    let msg = recvmsg::<SockaddrStorage>(
+        socket.as_raw_fd(),
+        &mut iov,
+        Some(&mut cmsg_buf),
+        MsgFlags::empty(),
+    )?;
+
+    // GRO option.
+    let mut segment_size = 0;
+
+    for cmsg in msg.cmsgs() {
+        if let ControlMessageOwned::UdpGroSegments(v) = cmsg {
+            segment_size = v as usize;
+        }
+    }
+
+    let from = match msg.address {
+        Some(addr) => match (addr.as_sockaddr_in(), addr.as_sockaddr_in6()) {
+            (Some(v4), _) => std::net::SocketAddr::V4((*v4).into()),
+
+            (_, Some(v6)) => std::net::SocketAddr::V6((*v6).into()),
+
+            _ => return Err(io::ErrorKind::InvalidData.into()),
+        },
+
+        None => return Err(io::ErrorKind::InvalidData.into()),
+    };
+
+    Ok((msg.bytes, from, segment_size))
+}
+
+/// For non-Linux platforms.
+#[cfg(not(target_os = "linux"))]
+fn recv_from_gro(
+    _socket: &mio::net::UdpSocket, _buf: &mut [u8],
+) -> io::Result<(usize, std::net::SocketAddr, usize)> {
+    panic!("recv_from_gro() should not be called on non-linux platforms");
+}
+
+/// A wrapper function of recv_from().
+///
+/// When GRO is enabled, packets are received using recv_from_gro(), which also
+/// returns the size of the coalesced datagrams. Otherwise, a single packet is
+/// received using socket.recv_from(), with a segment size of 0.
+pub fn recv_from(
+    socket: &mio::net::UdpSocket, buf: &mut [u8], enable_gro: bool,
+) -> io::Result<(usize, std::net::SocketAddr, usize)> {
+    if enable_gro {
+        return recv_from_gro(socket, buf);
+    }
+
+    let (len, from) = socket.recv_from(buf)?;
+
+    Ok((len, from, 0))
+}
+
 /// Send packets using sendmsg() with GSO.
 #[cfg(target_os = "linux")]
 fn send_to_gso_pacing(
@@ -62,6 +62,7 @@ pub enum ConnectivityEventType {
     SpinBitUpdated,
     ConnectionStateUpdated,
     MtuUpdated,
+    AmplificationLimited,
 }
 
 #[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
@@ -145,3 +146,10 @@ pub struct MtuUpdated {
     pub new: u16,
     pub done: Option<bool>,
 }
+
+#[serde_with::skip_serializing_none]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct AmplificationLimited {
+    pub remaining: u64,
+    pub received: Option<u64>,
+}
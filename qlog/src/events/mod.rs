@@ -214,6 +214,9 @@ impl From<EventType> for EventImportance {
             EventType::ConnectivityEventType(
                 ConnectivityEventType::MtuUpdated,
             ) => EventImportance::Extra,
+            EventType::ConnectivityEventType(
+                ConnectivityEventType::AmplificationLimited,
+            ) => EventImportance::Extra,
 
             EventType::SecurityEventType(SecurityEventType::KeyUpdated) =>
                 EventImportance::Base,
@@ -257,6 +260,9 @@ impl From<EventType> for EventImportance {
             ) => EventImportance::Extra,
             EventType::TransportEventType(TransportEventType::DataMoved) =>
                 EventImportance::Base,
+            EventType::TransportEventType(
+                TransportEventType::FlowControlUpdated,
+            ) => EventImportance::Extra,
 
             EventType::RecoveryEventType(RecoveryEventType::ParametersSet) =>
                 EventImportance::Base,
@@ -393,6 +399,10 @@ impl From<&EventData> for EventType {
             EventData::MtuUpdated { .. } => EventType::ConnectivityEventType(
                 ConnectivityEventType::MtuUpdated,
             ),
+            EventData::AmplificationLimited { .. } =>
+                EventType::ConnectivityEventType(
+                    ConnectivityEventType::AmplificationLimited,
+                ),
 
             EventData::KeyUpdated { .. } =>
                 EventType::SecurityEventType(SecurityEventType::KeyUpdated),
@@ -436,6 +446,10 @@ impl From<&EventData> for EventType {
                 EventType::TransportEventType(TransportEventType::FramesProcessed),
             EventData::DataMoved { .. } =>
                 EventType::TransportEventType(TransportEventType::DataMoved),
+            EventData::FlowControlUpdated { .. } =>
+                EventType::TransportEventType(
+                    TransportEventType::FlowControlUpdated,
+                ),
 
             EventData::RecoveryParametersSet { .. } =>
                 EventType::RecoveryEventType(RecoveryEventType::ParametersSet),
@@ -544,6 +558,9 @@ pub enum EventData {
     #[serde(rename = "connectivity:mtu_updated")]
     MtuUpdated(connectivity::MtuUpdated),
 
+    #[serde(rename = "connectivity:amplification_limited")]
+    AmplificationLimited(connectivity::AmplificationLimited),
+
     // Security
     #[serde(rename = "security:key_updated")]
     KeyUpdated(security::KeyUpdated),
@@ -597,6 +614,9 @@ pub enum EventData {
     #[serde(rename = "transport:data_moved")]
     DataMoved(quic::DataMoved),
 
+    #[serde(rename = "transport:flow_control_updated")]
+    FlowControlUpdated(quic::FlowControlUpdated),
+
     // Recovery
     #[serde(rename = "recovery:parameters_set")]
     RecoveryParametersSet(quic::RecoveryParametersSet),
@@ -255,6 +255,8 @@ pub enum TransportEventType {
     StreamStateUpdated,
 
     DataMoved,
+
+    FlowControlUpdated,
 }
 
 #[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
@@ -715,6 +717,20 @@ pub struct DataMoved {
     pub raw: Option<RawInfo>,
 }
 
+#[serde_with::skip_serializing_none]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct FlowControlUpdated {
+    /// The stream the receive window belongs to, or `None` for the connection
+    /// window.
+    pub stream_id: Option<u64>,
+
+    pub max_data: Option<u64>,
+    pub window: Option<u64>,
+
+    /// The rate at which received data is consumed, in bytes per second.
+    pub consumption_rate: Option<u64>,
+}
+
 #[serde_with::skip_serializing_none]
 #[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
 pub struct RecoveryParametersSet {
@@ -749,6 +765,11 @@ pub struct MetricsUpdated {
     pub packets_in_flight: Option<u64>,
 
     pub pacing_rate: Option<u64>,
+
+    // CUBIC specific, absent for other congestion controllers. W_max is in
+    // bytes, and K in milliseconds.
+    pub cubic_w_max: Option<u64>,
+    pub cubic_k: Option<f32>,
 }
 
 #[serde_with::skip_serializing_none]
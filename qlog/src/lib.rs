@@ -251,7 +251,8 @@
 //! Create an object with the [`Write`] trait:
 //!
 //! ```
-//! let mut file = std::fs::File::create("foo.sqlog").unwrap();
+//! let path = std::env::temp_dir().join("foo.sqlog");
+//! let mut file = std::fs::File::create(path).unwrap();
 //! ```
 //!
 //! Create a [`QlogStreamer`] and start serialization to foo.sqlog
@@ -272,7 +273,8 @@
 //! #    }),
 //! #    None,
 //! # );
-//! # let mut file = std::fs::File::create("foo.sqlog").unwrap();
+//! # let path = std::env::temp_dir().join("foo.sqlog");
+//! # let mut file = std::fs::File::create(path).unwrap();
 //! let mut streamer = qlog::streamer::QlogStreamer::new(
 //!     qlog::QLOG_VERSION.to_string(),
 //!     Some("Example qlog".to_string()),
@@ -309,7 +311,7 @@
 //! #    }),
 //! #    None,
 //! # );
-//! # let mut file = std::fs::File::create("foo.qlog").unwrap();
+//! # let file: Vec<u8> = Vec::new();
 //! # let mut streamer = qlog::streamer::QlogStreamer::new(
 //! #     qlog::QLOG_VERSION.to_string(),
 //! #     Some("Example qlog".to_string()),
@@ -372,7 +374,7 @@
 //! #    }),
 //! #    None,
 //! # );
-//! # let mut file = std::fs::File::create("foo.qlog").unwrap();
+//! # let file: Vec<u8> = Vec::new();
 //! # let mut streamer = qlog::streamer::QlogStreamer::new(
 //! #     qlog::QLOG_VERSION.to_string(),
 //! #     Some("Example qlog".to_string()),
@@ -969,3 +971,5 @@ mod tests {
 pub mod events;
 pub mod reader;
 pub mod streamer;
+
+pub use crate::streamer::StreamingSink;
@@ -259,6 +259,96 @@ impl Drop for QlogStreamer {
     }
 }
 
+/// A destination for qlog records streamed as they are generated.
+///
+/// Unlike a plain [`Write`], a sink is handed each JSON-SEQ record as a whole,
+/// so records can be forwarded as individual messages, for example to feed a
+/// live dashboard. Implementations are provided for [`SyncSender`] channels
+/// and connected [`UdpSocket`]s.
+///
+/// A sink is turned into a writer for [QlogStreamer] with [SinkWriter].
+///
+/// [`Write`]: https://doc.rust-lang.org/std/io/trait.Write.html
+/// [`SyncSender`]: https://doc.rust-lang.org/std/sync/mpsc/struct.SyncSender.html
+/// [`UdpSocket`]: https://doc.rust-lang.org/std/net/struct.UdpSocket.html
+pub trait StreamingSink: Send + Sync {
+    /// Handles a single serialized record.
+    ///
+    /// The record is framed as in the JSON-SEQ format, so it starts with the
+    /// record separator character and ends with a newline.
+    fn write_record(&mut self, record: &[u8]) -> std::io::Result<()>;
+
+    /// Flushes records buffered by the sink, if any.
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Records are sent without blocking, and dropped when the channel is full,
+/// so a slow consumer can't stall the connection.
+impl StreamingSink for std::sync::mpsc::SyncSender<Vec<u8>> {
+    fn write_record(&mut self, record: &[u8]) -> std::io::Result<()> {
+        self.try_send(record.to_vec()).map_err(|e| match e {
+            std::sync::mpsc::TrySendError::Full(_) =>
+                std::io::ErrorKind::WouldBlock.into(),
+
+            std::sync::mpsc::TrySendError::Disconnected(_) =>
+                std::io::ErrorKind::BrokenPipe.into(),
+        })
+    }
+}
+
+/// Each record is sent as a single datagram to the socket's connected peer.
+impl StreamingSink for std::net::UdpSocket {
+    fn write_record(&mut self, record: &[u8]) -> std::io::Result<()> {
+        self.send(record).map(|_| ())
+    }
+}
+
+/// A [`Write`] implementation that splits the JSON-SEQ output of
+/// [QlogStreamer] into records, and hands them to a [StreamingSink].
+///
+/// [`Write`]: https://doc.rust-lang.org/std/io/trait.Write.html
+pub struct SinkWriter {
+    sink: Box<dyn StreamingSink>,
+    buf: Vec<u8>,
+}
+
+impl SinkWriter {
+    /// Creates a writer forwarding records to `sink`.
+    pub fn new(sink: Box<dyn StreamingSink>) -> Self {
+        SinkWriter {
+            sink,
+            buf: Vec::new(),
+        }
+    }
+}
+
+impl std::io::Write for SinkWriter {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        // Serialized JSON can't contain raw newlines, so they always mark the
+        // end of a record.
+        let mut rest = data;
+
+        while let Some(pos) = rest.iter().position(|&b| b == b'\n') {
+            self.buf.extend_from_slice(&rest[..=pos]);
+            rest = &rest[pos + 1..];
+
+            let res = self.sink.write_record(&self.buf);
+            self.buf.clear();
+            res?;
+        }
+
+        self.buf.extend_from_slice(rest);
+
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.sink.flush()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::collections::BTreeMap;
@@ -541,4 +631,60 @@ mod tests {
 
         assert_eq!(log_string, written_string);
     }
+
+    #[test]
+    fn streaming_sink() {
+        let (tx, rx) = std::sync::mpsc::sync_channel(2);
+        let writer = Box::new(SinkWriter::new(Box::new(tx)));
+
+        let trace = make_trace_seq();
+        let pkt_hdr = make_pkt_hdr(quic::PacketType::Handshake);
+
+        let event_data = EventData::PacketSent(quic::PacketSent {
+            header: pkt_hdr,
+            frames: None,
+            is_coalesced: None,
+            retry_token: None,
+            stateless_reset_token: None,
+            supported_versions: None,
+            raw: None,
+            datagram_id: None,
+            send_at_time: None,
+            trigger: None,
+        });
+
+        let ev = Event::with_time(0.0, event_data);
+
+        let mut s = streamer::QlogStreamer::new(
+            "version".to_string(),
+            None,
+            None,
+            None,
+            std::time::Instant::now(),
+            trace,
+            EventImportance::Base,
+            writer,
+        );
+
+        assert!(matches!(s.start_log(), Ok(())));
+
+        // Each record is delivered as soon as it's written.
+        let record = rx.try_recv().unwrap();
+        assert!(record.starts_with(b"\x1e{\"qlog_version\":\"version\""));
+        assert!(record.ends_with(b"}\n"));
+
+        assert!(matches!(s.add_event(ev.clone()), Ok(())));
+
+        let record = rx.try_recv().unwrap();
+        assert!(record.starts_with(b"\x1e{\"time\":0.0"));
+        assert!(record.ends_with(b"}\n"));
+        assert!(rx.try_recv().is_err());
+
+        // Records are dropped when the channel is full.
+        assert!(matches!(s.add_event(ev.clone()), Ok(())));
+        assert!(matches!(s.add_event(ev.clone()), Ok(())));
+        assert!(matches!(s.add_event(ev), Err(Error::IoError(_))));
+
+        assert_eq!(rx.try_iter().count(), 2);
+    }
 }
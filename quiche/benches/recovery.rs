@@ -0,0 +1,274 @@
+// Copyright (C) 2023, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Benchmarks sent packets bookkeeping with a large number of packets in
+//! flight, as on high-BDP links, and the cost of sending packets in the
+//! steady state.
+
+use std::alloc::GlobalAlloc;
+use std::alloc::Layout;
+use std::alloc::System;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use std::time::Instant;
+
+use criterion::criterion_group;
+use criterion::criterion_main;
+use criterion::BatchSize;
+use criterion::Criterion;
+use criterion::Throughput;
+
+use quiche::frame::Frame;
+use quiche::packet::Epoch;
+use quiche::ranges::RangeSet;
+use quiche::recovery::HandshakeStatus;
+use quiche::recovery::Recovery;
+use quiche::recovery::Sent;
+
+const OUTSTANDING_PACKETS: u64 = 1_000_000;
+
+// The number of packets newly acknowledged by each ACK frame.
+const ACK_STEP: u64 = 1_000;
+
+// The number of packets in flight in the steady state benchmarks.
+const IN_FLIGHT_PACKETS: u64 = 32;
+
+// The number of rounds of sending and acknowledging packets in each
+// iteration of the steady state benchmarks.
+const ROUNDS: u64 = 100;
+
+/// Counts heap allocations, so that benchmarks can report how many happen.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+fn handshake_status() -> HandshakeStatus {
+    HandshakeStatus {
+        has_handshake_keys: true,
+
+        peer_verified_address: true,
+
+        completed: true,
+    }
+}
+
+fn recovery_with_outstanding_packets(now: Instant) -> Recovery {
+    let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION).unwrap();
+    config.set_initial_congestion_window_packets(OUTSTANDING_PACKETS as usize);
+
+    let mut r = Recovery::new(&config);
+
+    for pkt_num in 0..OUTSTANDING_PACKETS {
+        let pkt = Sent {
+            pkt_num,
+            frames: Default::default(),
+            time_sent: now,
+            time_acked: None,
+            time_lost: None,
+            size: 1200,
+            ack_eliciting: true,
+            in_flight: true,
+            delivered: 0,
+            delivered_time: now,
+            first_sent_time: now,
+            is_app_limited: false,
+            tx_in_flight: 0,
+            lost: 0,
+            has_data: false,
+        };
+
+        r.on_packet_sent(pkt, Epoch::Application, handshake_status(), now, "");
+    }
+
+    r
+}
+
+/// Acknowledges all outstanding packets but the first one, so none of them
+/// can be removed from the sent packets list, with every ACK frame repeating
+/// the previously acknowledged range.
+fn ack_with_gap(c: &mut Criterion) {
+    let now = Instant::now();
+
+    c.bench_function("ack_1m_outstanding_with_gap", |b| {
+        b.iter_batched(
+            || recovery_with_outstanding_packets(now),
+            |mut r| {
+                let mut newly_acked = Vec::new();
+                let mut ranges = RangeSet::default();
+
+                let mut largest = 1;
+
+                while largest < OUTSTANDING_PACKETS {
+                    largest = (largest + ACK_STEP).min(OUTSTANDING_PACKETS);
+
+                    ranges.insert(1..largest);
+
+                    newly_acked.clear();
+
+                    r.on_ack_received(
+                        &ranges,
+                        0,
+                        Epoch::Application,
+                        handshake_status(),
+                        now + Duration::from_millis(10),
+                        "",
+                        &mut newly_acked,
+                    )
+                    .unwrap();
+                }
+
+                r
+            },
+            BatchSize::PerIteration,
+        )
+    });
+}
+
+/// Sends `IN_FLIGHT_PACKETS` packets with two frames each, and acknowledges
+/// all of them.
+fn send_and_ack(
+    r: &mut Recovery, pkt_num: &mut u64, now: &mut Instant,
+    newly_acked: &mut Vec<quiche::recovery::Acked>,
+) {
+    let first = *pkt_num;
+
+    for _ in 0..IN_FLIGHT_PACKETS {
+        let mut frames = r.frames_buf();
+        frames.push(Frame::Ping { mtu_probe: None });
+        frames.push(Frame::Padding { len: 1000 });
+
+        let pkt = Sent {
+            pkt_num: *pkt_num,
+            frames,
+            time_sent: *now,
+            time_acked: None,
+            time_lost: None,
+            size: 1200,
+            ack_eliciting: true,
+            in_flight: true,
+            delivered: 0,
+            delivered_time: *now,
+            first_sent_time: *now,
+            is_app_limited: false,
+            tx_in_flight: 0,
+            lost: 0,
+            has_data: false,
+        };
+
+        r.on_packet_sent(pkt, Epoch::Application, handshake_status(), *now, "");
+
+        *pkt_num += 1;
+    }
+
+    *now += Duration::from_millis(10);
+
+    let mut ranges = RangeSet::default();
+    ranges.insert(first..*pkt_num);
+
+    newly_acked.clear();
+
+    r.on_ack_received(
+        &ranges,
+        0,
+        Epoch::Application,
+        handshake_status(),
+        *now,
+        "",
+        newly_acked,
+    )
+    .unwrap();
+}
+
+/// Sends and acknowledges packets in the steady state, with and without
+/// reusing the frame lists of acknowledged packets, and reports how many
+/// allocations each round of packets needs.
+fn steady_state(c: &mut Criterion) {
+    let mut group = c.benchmark_group("steady_state");
+    group.throughput(Throughput::Elements(IN_FLIGHT_PACKETS * ROUNDS));
+
+    for pool_size in [0, 64] {
+        let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION).unwrap();
+        config.set_packet_pool_size(pool_size);
+
+        let mut r = Recovery::new(&config);
+
+        let mut pkt_num = 0;
+        let mut now = Instant::now();
+        let mut newly_acked = Vec::new();
+
+        // Fill the pool first, then count the allocations of a single round.
+        send_and_ack(&mut r, &mut pkt_num, &mut now, &mut newly_acked);
+
+        let before = ALLOCATIONS.load(Ordering::Relaxed);
+
+        send_and_ack(&mut r, &mut pkt_num, &mut now, &mut newly_acked);
+
+        println!(
+            "steady_state/packet_pool_{}: {} allocations per {} packets",
+            pool_size,
+            ALLOCATIONS.load(Ordering::Relaxed) - before,
+            IN_FLIGHT_PACKETS
+        );
+
+        group.bench_function(format!("packet_pool_{}", pool_size), |b| {
+            b.iter(|| {
+                for _ in 0..ROUNDS {
+                    send_and_ack(
+                        &mut r,
+                        &mut pkt_num,
+                        &mut now,
+                        &mut newly_acked,
+                    );
+                }
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(10);
+    targets = ack_with_gap, steady_state
+}
+criterion_main!(benches);
@@ -278,12 +278,37 @@ fn main() {
                         conn.close(true, 0x100, b"kthxbye").unwrap();
                     },
 
+                    Ok((_stream_id, quiche::h3::Event::MessageError(cause))) => {
+                        error!(
+                            "malformed response received: {:?}, closing...",
+                            cause
+                        );
+
+                        conn.close(true, 0x100, b"kthxbye").unwrap();
+                    },
+
+                    Ok((_stream_id, quiche::h3::Event::RequestCancelled)) => {
+                        error!("request was cancelled by peer, closing...");
+
+                        conn.close(true, 0x100, b"kthxbye").unwrap();
+                    },
+
                     Ok((_, quiche::h3::Event::PriorityUpdate)) => unreachable!(),
 
                     Ok((goaway_id, quiche::h3::Event::GoAway)) => {
                         info!("GOAWAY id={}", goaway_id);
                     },
 
+                    Ok((stream_id, quiche::h3::Event::RequestRejected)) => {
+                        info!("request on stream id {} was rejected", stream_id);
+                    },
+
+                    Ok((_, quiche::h3::Event::Drained)) => (),
+
+                    Ok((_, quiche::h3::Event::InterimHeaders { .. })) => (),
+
+                    Ok((_, quiche::h3::Event::Trailers { .. })) => (),
+
                     Err(quiche::h3::Error::Done) => {
                         break;
                     },
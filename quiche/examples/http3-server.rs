@@ -186,7 +186,7 @@ fn main() {
                     continue 'read;
                 }
 
-                if !quiche::version_is_supported(hdr.version) {
+                if !config.is_version_supported(hdr.version) {
                     warn!("Doing version negotiation");
 
                     let len =
@@ -367,6 +367,12 @@ fn main() {
 
                         Ok((_stream_id, quiche::h3::Event::Reset { .. })) => (),
 
+                        Ok((_stream_id, quiche::h3::Event::MessageError(_))) =>
+                            (),
+
+                        Ok((_stream_id, quiche::h3::Event::RequestCancelled)) =>
+                            (),
+
                         Ok((
                             _prioritized_element_id,
                             quiche::h3::Event::PriorityUpdate,
@@ -374,6 +380,15 @@ fn main() {
 
                         Ok((_goaway_id, quiche::h3::Event::GoAway)) => (),
 
+                        Ok((_stream_id, quiche::h3::Event::RequestRejected)) =>
+                            (),
+
+                        Ok((_goaway_id, quiche::h3::Event::Drained)) => (),
+
+                        Ok((_, quiche::h3::Event::InterimHeaders { .. })) => (),
+
+                        Ok((_, quiche::h3::Event::Trailers { .. })) => (),
+
                         Err(quiche::h3::Error::Done) => {
                             break;
                         },
@@ -27,12 +27,14 @@
 #[macro_use]
 extern crate log;
 
-use std::net;
-
 use std::collections::HashMap;
 
+use std::sync::Arc;
+
 use ring::rand::*;
 
+use quiche::TokenProvider;
+
 const MAX_DATAGRAM_SIZE: usize = 1350;
 
 struct PartialResponse {
@@ -110,6 +112,14 @@ fn main() {
     let conn_id_seed =
         ring::hmac::Key::generate(ring::hmac::HMAC_SHA256, &rng).unwrap();
 
+    // Address validation tokens are protected with a random secret, so they
+    // can't be used after a restart.
+    let mut token_secret = [0; 32];
+    rng.fill(&mut token_secret).unwrap();
+
+    let token_provider = Arc::new(quiche::AeadTokenProvider::new(&token_secret));
+    config.set_token_provider(token_provider.clone());
+
     let mut clients = ClientMap::new();
 
     let local_addr = socket.local_addr().unwrap();
@@ -184,7 +194,7 @@ fn main() {
                     continue 'read;
                 }
 
-                if !quiche::version_is_supported(hdr.version) {
+                if !config.is_version_supported(hdr.version) {
                     warn!("Doing version negotiation");
 
                     let len =
@@ -212,52 +222,60 @@ fn main() {
                 // Token is always present in Initial packets.
                 let token = hdr.token.as_ref().unwrap();
 
-                // Do stateless retry if the client didn't send a token.
-                if token.is_empty() {
-                    warn!("Doing stateless retry");
-
-                    let new_token = mint_token(&hdr, &from);
-
-                    let len = quiche::retry(
-                        &hdr.scid,
-                        &hdr.dcid,
-                        &scid,
-                        &new_token,
-                        hdr.version,
-                        &mut out,
-                    )
-                    .unwrap();
-
-                    let out = &out[..len];
+                let validated = if token.is_empty() {
+                    None
+                } else {
+                    token_provider.validate_token(&from, &hdr.dcid, token)
+                };
 
-                    if let Err(e) = socket.send_to(out, from) {
-                        if e.kind() == std::io::ErrorKind::WouldBlock {
-                            debug!("send() would block");
-                            break;
+                let (scid, odcid) = match validated {
+                    Some(quiche::ValidatedToken::Retry(odcid)) => {
+                        if scid.len() != hdr.dcid.len() {
+                            error!("Invalid destination connection ID");
+                            continue 'read;
                         }
 
-                        panic!("send() failed: {:?}", e);
-                    }
-                    continue 'read;
-                }
-
-                let odcid = validate_token(&from, token);
-
-                // The token was not valid, meaning the retry failed, so
-                // drop the packet.
-                if odcid.is_none() {
-                    error!("Invalid address validation token");
-                    continue 'read;
-                }
-
-                if scid.len() != hdr.dcid.len() {
-                    error!("Invalid destination connection ID");
-                    continue 'read;
-                }
+                        // Reuse the source connection ID we sent in the Retry
+                        // packet, instead of changing it again.
+                        (hdr.dcid.clone(), Some(odcid))
+                    },
 
-                // Reuse the source connection ID we sent in the Retry packet,
-                // instead of changing it again.
-                let scid = hdr.dcid.clone();
+                    // The client was already validated on a previous
+                    // connection, so there is no need to do a retry.
+                    Some(quiche::ValidatedToken::NewToken) =>
+                        (scid.into_owned(), None),
+
+                    // Do stateless retry if the client didn't send a valid
+                    // token.
+                    None => {
+                        warn!("Doing stateless retry");
+
+                        let new_token =
+                            token_provider.mint_retry_token(&from, &hdr.dcid);
+
+                        let len = quiche::retry(
+                            &hdr.scid,
+                            &hdr.dcid,
+                            &scid,
+                            &new_token,
+                            hdr.version,
+                            &mut out,
+                        )
+                        .unwrap();
+
+                        let out = &out[..len];
+
+                        if let Err(e) = socket.send_to(out, from) {
+                            if e.kind() == std::io::ErrorKind::WouldBlock {
+                                debug!("send() would block");
+                                break;
+                            }
+
+                            panic!("send() failed: {:?}", e);
+                        }
+                        continue 'read;
+                    },
+                };
 
                 debug!("New connection: dcid={:?} scid={:?}", hdr.dcid, scid);
 
@@ -387,62 +405,6 @@ fn main() {
     }
 }
 
-/// Generate a stateless retry token.
-///
-/// The token includes the static string `"quiche"` followed by the IP address
-/// of the client and by the original destination connection ID generated by the
-/// client.
-///
-/// Note that this function is only an example and doesn't do any cryptographic
-/// authenticate of the token. *It should not be used in production system*.
-fn mint_token(hdr: &quiche::Header, src: &net::SocketAddr) -> Vec<u8> {
-    let mut token = Vec::new();
-
-    token.extend_from_slice(b"quiche");
-
-    let addr = match src.ip() {
-        std::net::IpAddr::V4(a) => a.octets().to_vec(),
-        std::net::IpAddr::V6(a) => a.octets().to_vec(),
-    };
-
-    token.extend_from_slice(&addr);
-    token.extend_from_slice(&hdr.dcid);
-
-    token
-}
-
-/// Validates a stateless retry token.
-///
-/// This checks that the ticket includes the `"quiche"` static string, and that
-/// the client IP address matches the address stored in the ticket.
-///
-/// Note that this function is only an example and doesn't do any cryptographic
-/// authenticate of the token. *It should not be used in production system*.
-fn validate_token<'a>(
-    src: &net::SocketAddr, token: &'a [u8],
-) -> Option<quiche::ConnectionId<'a>> {
-    if token.len() < 6 {
-        return None;
-    }
-
-    if &token[..6] != b"quiche" {
-        return None;
-    }
-
-    let token = &token[6..];
-
-    let addr = match src.ip() {
-        std::net::IpAddr::V4(a) => a.octets().to_vec(),
-        std::net::IpAddr::V6(a) => a.octets().to_vec(),
-    };
-
-    if token.len() < addr.len() || &token[..addr.len()] != addr.as_slice() {
-        return None;
-    }
-
-    Some(quiche::ConnectionId::from_ref(&token[addr.len()..]))
-}
-
 /// Handles incoming HTTP/0.9 requests.
 fn handle_stream(client: &mut Client, stream_id: u64, buf: &[u8], root: &str) {
     let conn = &mut client.conn;
@@ -58,6 +58,9 @@ extern "C" {
 // The current QUIC wire version.
 #define QUICHE_PROTOCOL_VERSION 0x00000001
 
+// QUIC version 2.
+#define QUICHE_PROTOCOL_VERSION_V2 0x6b3343cf
+
 // The maximum length of a connection ID.
 #define QUICHE_MAX_CONN_ID_LEN 20
 
@@ -124,6 +127,9 @@ enum quiche_error {
 
     // Error in key update.
     QUICHE_ERR_KEY_UPDATE = -19,
+
+    // The peer's version negotiation is inconsistent with the version in use.
+    QUICHE_ERR_VERSION_NEGOTIATION = -20,
 };
 
 // Returns a human readable string with the quiche version number.
@@ -155,12 +161,41 @@ int quiche_config_load_verify_locations_from_file(quiche_config *config,
 int quiche_config_load_verify_locations_from_directory(quiche_config *config,
                                                        const char *path);
 
+// Configures the TLS key exchange groups, as a colon-separated list.
+int quiche_config_set_key_exchange_groups(quiche_config *config,
+                                          const char *groups);
+
+// Restricts the TLS 1.3 cipher suites that can be negotiated, given as IANA
+// code points (e.g. 0x1302 for TLS_AES_256_GCM_SHA384). Only AES-GCM, or
+// AES-256-GCM alone, are supported.
+int quiche_config_set_cipher_suites(quiche_config *config,
+                                    const uint16_t *suites, size_t suites_len);
+
+// Configures the TLS signature algorithms, as a colon-separated list.
+int quiche_config_set_signature_algorithms(quiche_config *config,
+                                           const char *sigalgs);
+
+// Configures the application settings (ALPS) to send for the given protocol.
+void quiche_config_set_application_settings(quiche_config *config,
+                                            const uint8_t *proto, size_t proto_len,
+                                            const uint8_t *settings, size_t settings_len);
+
 // Configures whether to verify the peer's certificate.
 void quiche_config_verify_peer(quiche_config *config, bool v);
 
 // Configures whether to send GREASE.
 void quiche_config_grease(quiche_config *config, bool v);
 
+// Sets the fraction of connections that send GREASE values.
+void quiche_config_set_grease_rate(quiche_config *config, double v);
+
+// Configures whether to support QUIC version 2.
+void quiche_config_enable_quic_v2(quiche_config *config, bool v);
+
+// Configures whether servers require clients to present a certificate.
+void quiche_config_verify_peer_certificate_required(quiche_config *config,
+                                                    bool v);
+
 // Enables logging of secrets.
 void quiche_config_log_keys(quiche_config *config);
 
@@ -172,10 +207,20 @@ int quiche_config_set_application_protos(quiche_config *config,
                                          const uint8_t *protos,
                                          size_t protos_len);
 
+// Adds a custom transport parameter sent to the peer.
+int quiche_config_add_custom_transport_param(quiche_config *config,
+                                             uint64_t id,
+                                             const uint8_t *value,
+                                             size_t value_len);
+
 // Sets the `max_idle_timeout` transport parameter, in milliseconds, default is
 // no timeout.
 void quiche_config_set_max_idle_timeout(quiche_config *config, uint64_t v);
 
+// Sets the interval after which a PING is sent on an idle connection, in
+// milliseconds, default is no keepalive.
+void quiche_config_set_keepalive_interval(quiche_config *config, uint64_t v);
+
 // Sets the `max_udp_payload_size transport` parameter.
 void quiche_config_set_max_recv_udp_payload_size(quiche_config *config, size_t v);
 
@@ -206,15 +251,35 @@ void quiche_config_set_ack_delay_exponent(quiche_config *config, uint64_t v);
 // Sets the `max_ack_delay` transport parameter.
 void quiche_config_set_max_ack_delay(quiche_config *config, uint64_t v);
 
+// Sets the number of ack-eliciting packets received before an ACK is sent.
+void quiche_config_set_ack_eliciting_threshold(quiche_config *config, size_t v);
+
+// Configures whether out-of-order packets are acknowledged right away.
+void quiche_config_enable_ack_on_reordering(quiche_config *config, bool v);
+
+// Configures whether every packet is acknowledged right away until the
+// handshake is confirmed.
+void quiche_config_enable_immediate_ack_during_handshake(quiche_config *config,
+                                                         bool v);
+
 // Sets the `disable_active_migration` transport parameter.
 void quiche_config_set_disable_active_migration(quiche_config *config, bool v);
 
+// Sets the addresses advertised in the `preferred_address` transport
+// parameter. Either address can be NULL.
+void quiche_config_set_preferred_address(quiche_config *config,
+                                         const struct sockaddr *v4, socklen_t v4_len,
+                                         const struct sockaddr *v6, socklen_t v6_len);
+
 // Sets the congestion control algorithm used by string.
 int quiche_config_set_cc_algorithm_name(quiche_config *config, const char *algo);
 
 // Sets the initial cwnd for the connection in terms of packet count.
 void quiche_config_set_initial_congestion_window_packets(quiche_config *config, size_t packets);
 
+// Sets the minimum cwnd for the connection in terms of packet count.
+int quiche_config_set_minimum_congestion_window_packets(quiche_config *config, size_t packets);
+
 enum quiche_cc_algorithm {
     QUICHE_CC_RENO = 0,
     QUICHE_CC_CUBIC = 1,
@@ -228,23 +293,93 @@ void quiche_config_set_cc_algorithm(quiche_config *config, enum quiche_cc_algori
 // Configures whether to use HyStart++.
 void quiche_config_enable_hystart(quiche_config *config, bool v);
 
+// Configures whether CUBIC uses fast convergence (enabled by default).
+void quiche_config_enable_cubic_fast_convergence(quiche_config *config, bool v);
+
 // Configures whether to enable pacing (enabled by default).
 void quiche_config_enable_pacing(quiche_config *config, bool v);
 
+enum quiche_pacing_mode {
+    QUICHE_PACING_DISABLED = 0,
+    QUICHE_PACING_AUTO = 1,
+    QUICHE_PACING_CONGESTION_WINDOW = 2,
+    QUICHE_PACING_DELIVERY_RATE = 3,
+};
+
+// Sets how the pacing rate is computed.
+void quiche_config_set_pacing_mode(quiche_config *config, enum quiche_pacing_mode v);
+
 // Configures max pacing rate to be used.
 void quiche_config_set_max_pacing_rate(quiche_config *config, uint64_t v);
 
+// Sets how far ahead of their pacing release time packets can be written.
+void quiche_config_set_pacing_horizon(quiche_config *config, uint64_t v);
+
+// Sets the RTT used before any RTT sample is taken, in milliseconds.
+void quiche_config_set_initial_rtt(quiche_config *config, uint64_t v);
+
+// Sets the maximum number of times the PTO is doubled on consecutive PTOs.
+void quiche_config_set_max_pto_backoff(quiche_config *config, uint32_t v);
+
+// Sets the number of consecutive PTOs after which the connection is closed.
+void quiche_config_set_max_pto_count(quiche_config *config, uint32_t v);
+
+// Configures whether PTO probes retransmit the most recently sent data.
+void quiche_config_enable_tail_loss_probe(quiche_config *config, bool v);
+
+enum quiche_pto_retransmit_policy {
+    QUICHE_PTO_RETRANSMIT_OLDEST = 0,
+    QUICHE_PTO_RETRANSMIT_PRIORITIZED = 1,
+};
+
+// Sets which frames are retransmitted by the probes sent on PTO.
+void quiche_config_set_pto_retransmit_policy(quiche_config *config,
+                                             enum quiche_pto_retransmit_policy v);
+
+// Sets the number of sent packet records each path keeps for reuse.
+void quiche_config_set_packet_pool_size(quiche_config *config, size_t v);
+
+// Sets the number of data buffers each connection keeps for reuse.
+void quiche_config_set_buffer_pool_size(quiche_config *config, size_t v);
+
+// Configures whether to enable Path MTU Discovery.
+void quiche_config_discover_pmtu(quiche_config *config, bool v);
+
 // Configures whether to enable receiving DATAGRAM frames.
 void quiche_config_enable_dgram(quiche_config *config, bool enabled,
                                 size_t recv_queue_len,
                                 size_t send_queue_len);
 
+// Configures whether to enable experimental packet-level FEC.
+void quiche_config_enable_fec(quiche_config *config, bool v);
+
+// Sets the number of packets protected by a single FEC repair symbol.
+void quiche_config_set_fec_group_size(quiche_config *config, size_t v);
+
+// Configures whether to enable support for the RESET_STREAM_AT frame.
+void quiche_config_enable_reset_stream_at(quiche_config *config, bool v);
+
+// Configures whether to accept packets with the QUIC bit cleared.
+void quiche_config_enable_grease_quic_bit(quiche_config *config, bool v);
+
 // Sets the maximum connection window.
 void quiche_config_set_max_connection_window(quiche_config *config, uint64_t v);
 
 // Sets the maximum stream window.
 void quiche_config_set_max_stream_window(quiche_config *config, uint64_t v);
 
+// Configures whether receive windows are autotuned based on the measured
+// consumption rate.
+void quiche_config_enable_rate_window_autotuning(quiche_config *config, bool v);
+
+// Sets the low and high send buffer watermarks of new streams.
+void quiche_config_set_stream_send_watermarks(quiche_config *config,
+                                              size_t low, size_t high);
+
+// Sets the maximum amount of unacknowledged stream data per connection.
+void quiche_config_set_send_buffer_high_watermark(quiche_config *config,
+                                                  size_t v);
+
 // Sets the limit of active connection IDs.
 void quiche_config_set_active_connection_id_limit(quiche_config *config, uint64_t v);
 
@@ -254,6 +389,64 @@ void quiche_config_set_stateless_reset_token(quiche_config *config, const uint8_
 // Sets whether the QUIC connection should avoid reusing DCIDs over different paths.
 void quiche_config_set_disable_dcid_reuse(quiche_config *config, bool v);
 
+// Sets the interval, in milliseconds, after which the destination Connection
+// ID is rotated.
+void quiche_config_set_dcid_rotation_interval(quiche_config *config, uint64_t v);
+
+// Sets the number of bytes sent after which the destination Connection ID is
+// rotated.
+void quiche_config_set_dcid_rotation_bytes(quiche_config *config, uint64_t v);
+
+// Configures whether rotating the destination Connection ID also requests the
+// peer to retire the source Connection IDs it uses.
+void quiche_config_enable_dcid_rotation_retire_prior_to(quiche_config *config,
+                                                        bool v);
+
+// Sets the number of packets sent after which keys are updated.
+void quiche_config_set_key_update_packets(quiche_config *config, uint64_t v);
+
+// Sets the anti-amplification factor applied to unvalidated paths.
+void quiche_config_set_max_amplification_factor(quiche_config *config, size_t v);
+
+enum quiche_new_path_policy {
+    QUICHE_NEW_PATH_VALIDATE = 0,
+    QUICHE_NEW_PATH_DEFER = 1,
+    QUICHE_NEW_PATH_DROP = 2,
+};
+
+// Sets how a server handles packets received from a new peer address.
+void quiche_config_set_new_path_policy(quiche_config *config,
+                                       enum quiche_new_path_policy v);
+
+enum quiche_migration_policy {
+    QUICHE_MIGRATION_IMMEDIATE = 0,
+    QUICHE_MIGRATION_VALIDATE_FIRST = 1,
+    QUICHE_MIGRATION_MAKE_BEFORE_BREAK = 2,
+};
+
+// Sets how a client switches to a new path when migrating.
+void quiche_config_set_migration_policy(quiche_config *config,
+                                        enum quiche_migration_policy v);
+
+enum quiche_coalescing_policy {
+    QUICHE_COALESCING_ALL = 0,
+    QUICHE_COALESCING_LONG_HEADER_ONLY = 1,
+    QUICHE_COALESCING_DISABLED = 2,
+};
+
+// Sets which packets can be coalesced into the same UDP datagram.
+void quiche_config_set_coalescing_policy(quiche_config *config,
+                                         enum quiche_coalescing_policy v);
+
+enum quiche_initial_padding_policy {
+    QUICHE_INITIAL_PADDING_ALL = 0,
+    QUICHE_INITIAL_PADDING_FIRST_ONLY = 1,
+};
+
+// Sets which UDP datagrams containing an Initial packet are padded.
+void quiche_config_set_initial_padding_policy(quiche_config *config,
+                                              enum quiche_initial_padding_policy v);
+
 // Configures the session ticket key material.
 int quiche_config_set_ticket_key(quiche_config *config, const uint8_t *key, size_t key_len);
 
@@ -300,6 +493,13 @@ ssize_t quiche_retry(const uint8_t *scid, size_t scid_len,
 // Returns true if the given protocol version is supported.
 bool quiche_version_is_supported(uint32_t version);
 
+// Returns true if a server using the given configuration accepts connections
+// with the given protocol version.
+bool quiche_config_is_version_supported(const quiche_config *config,
+                                        uint32_t version);
+
+// Creates a connection using the given BoringSSL `SSL` object. Not available
+// when quiche is built with the `rustls` feature.
 quiche_conn *quiche_conn_new_with_tls(const uint8_t *scid, size_t scid_len,
                                       const uint8_t *odcid, size_t odcid_len,
                                       const struct sockaddr *local, socklen_t local_len,
@@ -313,6 +513,23 @@ bool quiche_conn_set_keylog_path(quiche_conn *conn, const char *path);
 // Enables keylog to the specified file descriptor. Unix only.
 void quiche_conn_set_keylog_fd(quiche_conn *conn, int fd);
 
+enum quiche_qlog_category {
+    QUICHE_QLOG_CONNECTIVITY = 0,
+    QUICHE_QLOG_TRANSPORT = 1,
+    QUICHE_QLOG_SECURITY = 2,
+    QUICHE_QLOG_RECOVERY = 3,
+    QUICHE_QLOG_HTTP3 = 4,
+    QUICHE_QLOG_QPACK = 5,
+    QUICHE_QLOG_GENERIC = 6,
+};
+
+// Restricts qlog output to the given event categories (or all of them if
+// empty), and to one in `sampling_rate` connections.
+void quiche_config_set_qlog_filter(quiche_config *config,
+                                   const enum quiche_qlog_category *categories,
+                                   size_t categories_len,
+                                   uint64_t sampling_rate);
+
 // Enables qlog to the specified file path. Returns true on success.
 bool quiche_conn_set_qlog_path(quiche_conn *conn, const char *path,
                           const char *log_title, const char *log_desc);
@@ -338,6 +555,10 @@ typedef struct {
 ssize_t quiche_conn_recv(quiche_conn *conn, uint8_t *buf, size_t buf_len,
                          const quiche_recv_info *info);
 
+// Processes a batch of UDP datagrams of `segment_size` bytes (e.g. from GRO).
+ssize_t quiche_conn_recv_batch(quiche_conn *conn, uint8_t *buf, size_t buf_len,
+                               size_t segment_size, const quiche_recv_info *info);
+
 typedef struct {
     // The local address the packet should be sent from.
     struct sockaddr_storage from;
@@ -355,9 +576,17 @@ typedef struct {
 ssize_t quiche_conn_send(quiche_conn *conn, uint8_t *out, size_t out_len,
                          quiche_send_info *out_info);
 
+// Writes multiple QUIC packets to be sent to the peer as a single UDP GSO
+// batch, with the given segment size.
+ssize_t quiche_conn_send_batch(quiche_conn *conn, uint8_t *out, size_t out_len,
+                               size_t segment_size, quiche_send_info *out_info);
+
 // Returns the size of the send quantum, in bytes.
 size_t quiche_conn_send_quantum(const quiche_conn *conn);
 
+// Returns how many bytes can be sent in the next burst, in bytes.
+size_t quiche_conn_send_budget(const quiche_conn *conn);
+
 // Writes a single QUIC packet to be sent to the peer from the specified
 // local address "from" to the destination address "to".
 ssize_t quiche_conn_send_on_path(quiche_conn *conn, uint8_t *out, size_t out_len,
@@ -393,6 +622,11 @@ int quiche_conn_stream_priority(quiche_conn *conn, uint64_t stream_id,
 int quiche_conn_stream_shutdown(quiche_conn *conn, uint64_t stream_id,
                                 enum quiche_shutdown direction, uint64_t err);
 
+// Resets the sending side of a stream, but still delivers its first
+// `reliable_size` bytes.
+int quiche_conn_stream_reset_at(quiche_conn *conn, uint64_t stream_id,
+                                uint64_t err, uint64_t reliable_size);
+
 // Returns the stream's send capacity in bytes.
 ssize_t quiche_conn_stream_capacity(const quiche_conn *conn, uint64_t stream_id);
 
@@ -408,6 +642,10 @@ int64_t quiche_conn_stream_readable_next(quiche_conn *conn);
 // On error a value lower than 0 is returned.
 int quiche_conn_stream_writable(quiche_conn *conn, uint64_t stream_id, size_t len);
 
+// Sets the low and high send buffer watermarks of a stream.
+int quiche_conn_stream_send_watermarks(quiche_conn *conn, uint64_t stream_id,
+                                       size_t low, size_t high);
+
 // Returns the next stream that can be written to, or -1 if no such stream is
 // available.
 int64_t quiche_conn_stream_writable_next(quiche_conn *conn);
@@ -439,6 +677,11 @@ void quiche_conn_on_timeout(quiche_conn *conn);
 int quiche_conn_close(quiche_conn *conn, bool app, uint64_t err,
                       const uint8_t *reason, size_t reason_len);
 
+// Closes the connection, optionally skipping the draining period.
+int quiche_conn_close_with_drain(quiche_conn *conn, bool app, uint64_t err,
+                                 const uint8_t *reason, size_t reason_len,
+                                 bool drain);
+
 // Returns a string uniquely representing the connection.
 void quiche_conn_trace_id(const quiche_conn *conn, const uint8_t **out, size_t *out_len);
 
@@ -464,6 +707,13 @@ void quiche_conn_destination_id(const quiche_conn *conn, const uint8_t **out, si
 void quiche_conn_application_proto(const quiche_conn *conn, const uint8_t **out,
                                    size_t *out_len);
 
+// Returns the application settings (ALPS) sent by the peer, if negotiated.
+bool quiche_conn_peer_application_settings(const quiche_conn *conn,
+                                           const uint8_t **out, size_t *out_len);
+
+// Returns the IANA code point of the negotiated cipher suite, or 0.
+uint16_t quiche_conn_cipher_suite(const quiche_conn *conn);
+
 // Returns the peer's leaf certificate (if any) as a DER-encoded buffer.
 void quiche_conn_peer_cert(const quiche_conn *conn, const uint8_t **out, size_t *out_len);
 
@@ -480,12 +730,30 @@ bool quiche_conn_is_resumed(const quiche_conn *conn);
 // enough to send or receive early data.
 bool quiche_conn_is_in_early_data(const quiche_conn *conn);
 
+// Derives `out_len` bytes of keying material from the TLS session, using
+// the given label and context (RFC 8446 Section 7.5).
+int quiche_conn_export_keying_material(const quiche_conn *conn,
+                                       const uint8_t *label, size_t label_len,
+                                       const uint8_t *context,
+                                       size_t context_len,
+                                       uint8_t *out, size_t out_len);
+
+// Returns true if early data was rejected.
+bool quiche_conn_is_early_data_rejected(const quiche_conn *conn);
+
+// Enables or disables early data for the connection. Servers need to call
+// this before the first packet is received.
+int quiche_conn_set_early_data_enabled(quiche_conn *conn, bool v);
+
 // Returns whether there is stream or DATAGRAM data available to read.
 bool quiche_conn_is_readable(const quiche_conn *conn);
 
 // Returns true if the connection is draining.
 bool quiche_conn_is_draining(const quiche_conn *conn);
 
+// Returns true if the connection is being closed locally.
+bool quiche_conn_is_closing(const quiche_conn *conn);
+
 // Returns the number of bidirectional streams that can be created
 // before the peer's stream count limit is reached.
 uint64_t quiche_conn_peer_streams_left_bidi(const quiche_conn *conn);
@@ -494,6 +762,14 @@ uint64_t quiche_conn_peer_streams_left_bidi(const quiche_conn *conn);
 // before the peer's stream count limit is reached.
 uint64_t quiche_conn_peer_streams_left_uni(const quiche_conn *conn);
 
+// Returns the number of bidirectional streams the peer can create before
+// the local stream count limit is reached.
+uint64_t quiche_conn_local_streams_left_bidi(const quiche_conn *conn);
+
+// Returns the number of unidirectional streams the peer can create before
+// the local stream count limit is reached.
+uint64_t quiche_conn_local_streams_left_uni(const quiche_conn *conn);
+
 // Returns true if the connection is closed.
 bool quiche_conn_is_closed(const quiche_conn *conn);
 
@@ -606,12 +882,23 @@ typedef struct {
 
     // DATAGRAM frame extension parameter, if any.
     ssize_t peer_max_datagram_frame_size;
+
+    // Whether the RESET_STREAM_AT frame is supported.
+    bool peer_reset_stream_at;
+
+    // Whether packets with the QUIC bit cleared are accepted.
+    bool peer_grease_quic_bit;
 } quiche_transport_params;
 
 // Returns the peer's transport parameters in |out|. Returns false if we have
 // not yet processed the peer's transport parameters.
 bool quiche_conn_peer_transport_params(const quiche_conn *conn, quiche_transport_params *out);
 
+// Returns the raw value of a transport parameter sent by the peer that is not
+// handled by quiche. Returns false if the peer didn't send it.
+bool quiche_conn_peer_custom_transport_param(const quiche_conn *conn, uint64_t id,
+                                             const uint8_t **out, size_t *out_len);
+
 typedef struct {
     // The local address used by this path.
     struct sockaddr_storage local_addr;
@@ -700,6 +987,12 @@ ssize_t quiche_conn_dgram_recv(quiche_conn *conn, uint8_t *buf,
 ssize_t quiche_conn_dgram_send(quiche_conn *conn, const uint8_t *buf,
                                size_t buf_len);
 
+// Sends data in a DATAGRAM frame with the given urgency, dropping it if not
+// sent within `ttl_ms` milliseconds (0 means no expiry).
+ssize_t quiche_conn_dgram_send_with_ttl(quiche_conn *conn, const uint8_t *buf,
+                                        size_t buf_len, uint8_t urgency,
+                                        uint64_t ttl_ms);
+
 // Purges queued outgoing DATAGRAMs matching the predicate.
 void quiche_conn_dgram_purge_outgoing(quiche_conn *conn,
                                       bool (*f)(uint8_t *, size_t));
@@ -718,6 +1011,15 @@ ssize_t quiche_conn_send_ack_eliciting_on_path(quiche_conn *conn,
                            const struct sockaddr *local, socklen_t local_len,
                            const struct sockaddr *peer, socklen_t peer_len);
 
+// Enables or disables keepalives on the connection.
+void quiche_conn_enable_keepalive(quiche_conn *conn, bool v);
+
+// Fills the congestion window with padding packets for the given duration.
+void quiche_conn_probe_bandwidth(quiche_conn *conn, uint64_t duration_ms);
+
+// Returns true if the connection is currently probing for bandwidth.
+bool quiche_conn_is_probing_bandwidth(const quiche_conn *conn);
+
 // Returns true if there are retired source connection ids and fill the parameters
 bool quiche_conn_retired_scid_next(const quiche_conn *conn, const uint8_t **out, size_t *out_len);
 
@@ -742,6 +1044,12 @@ int quiche_conn_new_scid(quiche_conn *conn,
                            const uint8_t *scid, size_t scid_len,
                            const uint8_t *reset_token, bool retire_if_needed, uint64_t *scid_seq);
 
+// Provides the Connection ID advertised in the `preferred_address` transport
+// parameter. Must be called by servers before the first packet is received.
+int quiche_conn_set_preferred_address_cid(quiche_conn *conn,
+                                          const uint8_t *scid, size_t scid_len,
+                                          const uint8_t *reset_token);
+
 // Requests the stack to perform path validation of the proposed 4-tuple.
 int quiche_conn_probe_path(quiche_conn *conn,
                                 const struct sockaddr *local, socklen_t local_len,
@@ -757,6 +1065,20 @@ int quiche_conn_migrate(quiche_conn *conn,
                              const struct sockaddr *peer, socklen_t peer_len,
                              uint64_t *seq);
 
+// Migrates the connection over the given network path between "local"
+// and "peer" once that path has been validated.
+int quiche_conn_migrate_after_validation(quiche_conn *conn,
+                             const struct sockaddr *local, socklen_t local_len,
+                             const struct sockaddr *peer, socklen_t peer_len,
+                             uint64_t *seq);
+
+// Migrates the connection over the given network path between "local"
+// and "peer", following the configured migration policy.
+int quiche_conn_migrate_with_policy(quiche_conn *conn,
+                             const struct sockaddr *local, socklen_t local_len,
+                             const struct sockaddr *peer, socklen_t peer_len,
+                             uint64_t *seq);
+
 enum quiche_path_event_type {
     QUICHE_PATH_EVENT_NEW,
     QUICHE_PATH_EVENT_VALIDATED,
@@ -809,6 +1131,13 @@ void quiche_path_event_free(quiche_path_event *ev);
 // host to reach its peer.
 int quiche_conn_retire_dcid(quiche_conn *conn, uint64_t dcid_seq);
 
+// Switches the active path to a new destination Connection ID, and retires
+// the previous one. Writes the new sequence number to "dcid_seq".
+int quiche_conn_rotate_dcid(quiche_conn *conn, uint64_t *dcid_seq);
+
+// Initiates a key update.
+int quiche_conn_initiate_key_update(quiche_conn *conn);
+
 typedef struct quiche_socket_addr_iter quiche_socket_addr_iter;
 
 // Returns an iterator over destination `SockAddr`s whose association
@@ -913,6 +1242,12 @@ enum quiche_h3_error {
     // over HTTP/1.1.
     QUICHE_H3_ERR_VERSION_FALLBACK = -20,
 
+    // An HTTP Datagram or capsule was malformed.
+    QUICHE_H3_ERR_DATAGRAM_ERROR = -21,
+
+    // The request can't be sent in early data because it is not replay-safe.
+    QUICHE_H3_ERR_UNSAFE_EARLY_REQUEST = -22,
+
     // The following QUICHE_H3_TRANSPORT_ERR_* errors are propagated
     // from the QUIC transport layer.
 
@@ -972,6 +1307,9 @@ enum quiche_h3_error {
 
     // See QUICHE_ERR_KEY_UPDATE.
     QUICHE_H3_TRANSPORT_ERR_KEY_UPDATE = QUICHE_ERR_KEY_UPDATE - 1000,
+
+    // See QUICHE_ERR_VERSION_NEGOTIATION.
+    QUICHE_H3_TRANSPORT_ERR_VERSION_NEGOTIATION = QUICHE_ERR_VERSION_NEGOTIATION - 1000,
 };
 
 // Stores configuration shared between multiple connections.
@@ -983,6 +1321,15 @@ quiche_h3_config *quiche_h3_config_new(void);
 // Sets the `SETTINGS_MAX_FIELD_SECTION_SIZE` setting.
 void quiche_h3_config_set_max_field_section_size(quiche_h3_config *config, uint64_t v);
 
+// Sets the maximum number of fields in a received header section.
+void quiche_h3_config_set_max_header_count(quiche_h3_config *config, uint64_t v);
+
+// Sets the maximum size of a single field in a received header section.
+void quiche_h3_config_set_max_field_size(quiche_h3_config *config, uint64_t v);
+
+// Configures whether servers strictly validate received requests.
+void quiche_h3_config_enable_strict_validation(quiche_h3_config *config, bool v);
+
 // Sets the `SETTINGS_QPACK_MAX_TABLE_CAPACITY` setting.
 void quiche_h3_config_set_qpack_max_table_capacity(quiche_h3_config *config, uint64_t v);
 
@@ -992,6 +1339,15 @@ void quiche_h3_config_set_qpack_blocked_streams(quiche_h3_config *config, uint64
 // Sets the `SETTINGS_ENABLE_CONNECT_PROTOCOL` setting.
 void quiche_h3_config_enable_extended_connect(quiche_h3_config *config, bool enabled);
 
+// Sets the maximum number of concurrent requests a server will process.
+void quiche_h3_config_set_max_concurrent_requests(quiche_h3_config *config, uint64_t v);
+
+// Sets the amount of request body data a server can read for each request.
+void quiche_h3_config_set_initial_body_credit(quiche_h3_config *config, uint64_t v);
+
+// Configures whether servers apply request priorities to streams automatically.
+void quiche_h3_config_enable_auto_priority(quiche_h3_config *config, bool v);
+
 // Frees the HTTP/3 config object.
 void quiche_h3_config_free(quiche_h3_config *config);
 
@@ -1009,6 +1365,12 @@ enum quiche_h3_event_type {
     QUICHE_H3_EVENT_GOAWAY,
     QUICHE_H3_EVENT_RESET,
     QUICHE_H3_EVENT_PRIORITY_UPDATE,
+    QUICHE_H3_EVENT_REQUEST_REJECTED,
+    QUICHE_H3_EVENT_INTERIM_HEADERS,
+    QUICHE_H3_EVENT_TRAILERS,
+    QUICHE_H3_EVENT_DRAINED,
+    QUICHE_H3_EVENT_MESSAGE_ERROR,
+    QUICHE_H3_EVENT_REQUEST_CANCELLED,
 };
 
 typedef struct quiche_h3_event quiche_h3_event;
@@ -1081,6 +1443,17 @@ int quiche_h3_send_response_with_priority(quiche_h3_conn *conn,
                             const quiche_h3_header *headers, size_t headers_len,
                             quiche_h3_priority *priority, bool fin);
 
+// Sends an HTTP/3 interim (1xx) response on the specified stream.
+int quiche_h3_send_interim_response(quiche_h3_conn *conn, quiche_conn *quic_conn,
+                                    uint64_t stream_id,
+                                    const quiche_h3_header *headers,
+                                    size_t headers_len);
+
+// Sends a trailer section on the specified stream, terminating it.
+int quiche_h3_send_trailers(quiche_h3_conn *conn, quiche_conn *quic_conn,
+                            uint64_t stream_id, const quiche_h3_header *headers,
+                            size_t headers_len);
+
 // Sends an HTTP/3 body chunk on the given stream.
 ssize_t quiche_h3_send_body(quiche_h3_conn *conn, quiche_conn *quic_conn,
                             uint64_t stream_id, const uint8_t *body, size_t body_len,
@@ -1090,10 +1463,24 @@ ssize_t quiche_h3_send_body(quiche_h3_conn *conn, quiche_conn *quic_conn,
 ssize_t quiche_h3_recv_body(quiche_h3_conn *conn, quiche_conn *quic_conn,
                             uint64_t stream_id, uint8_t *out, size_t out_len);
 
+// Allows the application to read more of a request's body.
+int quiche_h3_grant_body_credit(quiche_h3_conn *conn, quiche_conn *quic_conn,
+                                uint64_t stream_id, uint64_t credit);
+
+// Sends a final response without reading the rest of the request body.
+int quiche_h3_reject_request_body(quiche_h3_conn *conn, quiche_conn *quic_conn,
+                                  uint64_t stream_id,
+                                  const quiche_h3_header *headers,
+                                  size_t headers_len);
+
 // Sends a GOAWAY frame to initiate graceful connection closure.
 int quiche_h3_send_goaway(quiche_h3_conn *conn, quiche_conn *quic_conn,
                           uint64_t id);
 
+// Cancels a request, resetting its stream with H3_REQUEST_CANCELLED.
+int quiche_h3_cancel_request(quiche_h3_conn *conn, quiche_conn *quic_conn,
+                             uint64_t stream_id);
+
 // Try to parse an Extensible Priority field value.
 int quiche_h3_parse_extensible_priority(uint8_t *priority,
                                         size_t priority_len,
@@ -1122,6 +1509,17 @@ int quiche_h3_take_last_priority_update(quiche_h3_conn *conn,
 bool quiche_h3_dgram_enabled_by_peer(quiche_h3_conn *conn,
                                      quiche_conn *quic_conn);
 
+// Sends an HTTP Datagram associated with the given request stream.
+int quiche_h3_send_dgram(quiche_h3_conn *conn, quiche_conn *quic_conn,
+                         uint64_t stream_id, const uint8_t *buf,
+                         size_t buf_len);
+
+// Reads the next HTTP Datagram, and returns the ID of its request stream and
+// the offset of its payload in the output buffer.
+ssize_t quiche_h3_recv_dgram(quiche_h3_conn *conn, quiche_conn *quic_conn,
+                             uint64_t *stream_id, size_t *payload_off,
+                             uint8_t *out, size_t out_len);
+
 // Frees the HTTP/3 connection object.
 void quiche_h3_conn_free(quiche_h3_conn *conn);
 
@@ -31,6 +31,42 @@ use crate::frame;
 use crate::packet::ConnectionId;
 
 use std::collections::VecDeque;
+use std::time;
+
+/// A source of new Connection IDs.
+///
+/// Implementations can encode routing information into the Connection IDs they
+/// produce, such as the QUIC-LB [`Generator`]. They need to be `Send`, so
+/// that a server's listener can be driven from any thread.
+///
+/// [`Generator`]: quic_lb/struct.Generator.html
+pub trait ConnectionIdGenerator: Send {
+    /// Generates a new Connection ID.
+    fn generate(&mut self) -> ConnectionId<'static>;
+}
+
+/// When both endpoints switch to fresh Connection IDs on an established path.
+///
+/// On each rotation the host moves to a spare destination Connection ID, and
+/// requests the peer to do the same by raising the Retire Prior To field of
+/// its NEW_CONNECTION_ID frames. This makes it harder for on-path observers to
+/// link the connection's traffic over time.
+///
+/// This enum is set with [`set_cid_rotation()`].
+///
+/// [`set_cid_rotation()`]: struct.Config.html#method.set_cid_rotation
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CidRotation {
+    /// Connection IDs are never rotated (default).
+    #[default]
+    Disabled,
+
+    /// Connection IDs are rotated once per the given interval.
+    Interval(time::Duration),
+
+    /// Connection IDs are rotated once the given number of bytes was sent.
+    Bytes(u64),
+}
 
 /// A structure holding a `ConnectionId` and all its related metadata.
 #[derive(Debug, Default)]
@@ -392,6 +428,11 @@ impl ConnectionIdentifiers {
         Ok(seq)
     }
 
+    /// Returns the sequence number that the next source identifier will get.
+    pub fn next_scid_seq(&self) -> u64 {
+        self.next_scid_seq
+    }
+
     /// Sets the initial destination identifier.
     pub fn set_initial_dcid(
         &mut self, cid: ConnectionId<'static>, reset_token: Option<u128>,
@@ -425,6 +466,8 @@ impl ConnectionIdentifiers {
         }
 
         let mut retired_path_ids = Vec::new();
+        let mut path_id = None;
+
         // If an endpoint receives a NEW_CONNECTION_ID frame that repeats a
         // previously issued connection ID with a different Stateless Reset
         // Token field value or a different Sequence Number field value, or if a
@@ -437,8 +480,14 @@ impl ConnectionIdentifiers {
             {
                 return Err(Error::InvalidFrame);
             }
-            // The identifier is already there, nothing to do.
-            return Ok(retired_path_ids);
+
+            // The identifier is already there, but the frame may still raise
+            // the Retire Prior To value.
+            if retire_prior_to <= self.largest_peer_retire_prior_to {
+                return Ok(retired_path_ids);
+            }
+
+            path_id = e.path_id;
         }
 
         // The value in the Retire Prior To field MUST be less than or equal to
@@ -470,7 +519,7 @@ impl ConnectionIdentifiers {
             cid: cid.clone(),
             seq,
             reset_token: Some(reset_token),
-            path_id: None,
+            path_id,
         };
 
         // A receiver MUST ignore any Retire Prior To fields that do not
@@ -539,6 +588,41 @@ impl ConnectionIdentifiers {
         Ok(pid)
     }
 
+    /// Requests the peer to retire all the source Connection IDs but the
+    /// newest one.
+    ///
+    /// The Retire Prior To value is raised to the newest sequence number,
+    /// which is advertised again so that the peer learns about it.
+    ///
+    /// Returns the new Retire Prior To value, or an [`OutOfIdentifiers`] if
+    /// no Connection ID newer than the lowest usable one is available.
+    ///
+    /// [`OutOfIdentifiers`]: enum.Error.html#OutOfIdentifiers
+    pub fn retire_scids_prior_to_newest(&mut self) -> Result<u64> {
+        if self.zero_length_scid {
+            return Err(Error::InvalidState);
+        }
+
+        let newest_seq = self
+            .scids
+            .iter()
+            .map(|e| e.seq)
+            .max()
+            .ok_or(Error::InvalidState)?;
+
+        if newest_seq <= self.lowest_usable_scid_seq()? {
+            return Err(Error::OutOfIdentifiers);
+        }
+
+        self.retire_prior_to = newest_seq;
+
+        if !self.advertise_new_scid_seqs.contains(&newest_seq) {
+            self.mark_advertise_new_scid_seq(newest_seq, true);
+        }
+
+        Ok(newest_seq)
+    }
+
     /// Retires the Destination Connection ID having the provided sequence
     /// number.
     ///
@@ -785,6 +869,135 @@ impl ConnectionIdentifiers {
     pub fn pop_retired_scid(&mut self) -> Option<ConnectionId<'static>> {
         self.retired_scids.pop_front()
     }
+
+    /// Serializes the identifiers, for freezing a connection.
+    ///
+    /// Pending NEW_CONNECTION_ID and RETIRE_CONNECTION_ID frames are not
+    /// serialized, so the caller must make sure there are none.
+    pub fn freeze(&self, b: &mut octets::OctetsMut) -> Result<()> {
+        freeze_cid_entries(&self.scids, b)?;
+        freeze_cid_entries(&self.dcids, b)?;
+
+        b.put_varint(self.retired_scids.len() as u64)?;
+
+        for cid in &self.retired_scids {
+            b.put_u8(cid.len() as u8)?;
+            b.put_bytes(cid.as_ref())?;
+        }
+
+        b.put_varint(self.largest_peer_retire_prior_to)?;
+        b.put_varint(self.largest_destination_seq)?;
+        b.put_varint(self.next_scid_seq)?;
+        b.put_varint(self.retire_prior_to)?;
+        b.put_varint(self.source_conn_id_limit as u64)?;
+        b.put_u8(self.zero_length_scid as u8)?;
+        b.put_u8(self.zero_length_dcid as u8)?;
+
+        Ok(())
+    }
+
+    /// Restores identifiers serialized by [`freeze()`], linking the ones that
+    /// were in use to the provided path.
+    ///
+    /// [`freeze()`]: struct.ConnectionIdentifiers.html#method.freeze
+    pub fn thaw(
+        b: &mut octets::Octets, path_id: usize,
+    ) -> Result<ConnectionIdentifiers> {
+        let scids = thaw_cid_entries(b, path_id)?;
+        let dcids = thaw_cid_entries(b, path_id)?;
+
+        let retired_len = b.get_varint()?;
+        let mut retired_scids = VecDeque::new();
+
+        for _ in 0..retired_len {
+            let cid = b.get_bytes_with_u8_length()?;
+            retired_scids.push_back(ConnectionId::from_vec(cid.to_vec()));
+        }
+
+        Ok(ConnectionIdentifiers {
+            scids,
+            dcids,
+            retired_scids,
+            largest_peer_retire_prior_to: b.get_varint()?,
+            largest_destination_seq: b.get_varint()?,
+            next_scid_seq: b.get_varint()?,
+            retire_prior_to: b.get_varint()?,
+            source_conn_id_limit: b.get_varint()? as usize,
+            zero_length_scid: b.get_u8()? != 0,
+            zero_length_dcid: b.get_u8()? != 0,
+            ..Default::default()
+        })
+    }
+}
+
+fn freeze_cid_entries(
+    cids: &BoundedNonEmptyConnectionIdVecDeque, b: &mut octets::OctetsMut,
+) -> Result<()> {
+    b.put_varint(cids.capacity as u64)?;
+    b.put_varint(cids.len() as u64)?;
+
+    for e in cids.iter() {
+        b.put_varint(e.seq)?;
+        b.put_u8(e.cid.len() as u8)?;
+        b.put_bytes(e.cid.as_ref())?;
+
+        match e.reset_token {
+            Some(token) => {
+                b.put_u8(1)?;
+                b.put_bytes(&token.to_be_bytes())?;
+            },
+
+            None => {
+                b.put_u8(0)?;
+            },
+        }
+
+        b.put_u8(e.path_id.is_some() as u8)?;
+    }
+
+    Ok(())
+}
+
+fn thaw_cid_entries(
+    b: &mut octets::Octets, path_id: usize,
+) -> Result<BoundedNonEmptyConnectionIdVecDeque> {
+    let capacity = b.get_varint()? as usize;
+    let len = b.get_varint()?;
+
+    if len == 0 || len > capacity as u64 {
+        return Err(Error::InvalidState);
+    }
+
+    let mut inner = VecDeque::with_capacity(len as usize);
+
+    for _ in 0..len {
+        let seq = b.get_varint()?;
+        let cid = ConnectionId::from_vec(b.get_bytes_with_u8_length()?.to_vec());
+
+        let reset_token = if b.get_u8()? != 0 {
+            let mut token = [0; 16];
+            token.copy_from_slice(b.get_bytes(16)?.as_ref());
+
+            Some(u128::from_be_bytes(token))
+        } else {
+            None
+        };
+
+        let path_id = if b.get_u8()? != 0 {
+            Some(path_id)
+        } else {
+            None
+        };
+
+        inner.push_back(ConnectionIdEntry {
+            cid,
+            seq,
+            reset_token,
+            path_id,
+        });
+    }
+
+    Ok(BoundedNonEmptyConnectionIdVecDeque { inner, capacity })
 }
 
 #[cfg(test)]
@@ -952,4 +1165,86 @@ mod tests {
         assert_eq!(ids.pop_retired_scid(), Some(scid2));
         assert_eq!(ids.pop_retired_scid(), None);
     }
+
+    #[test]
+    fn retire_scids_prior_to_newest() {
+        let (scid, _) = create_cid_and_reset_token(16);
+        let (dcid, _) = create_cid_and_reset_token(16);
+
+        let mut ids = ConnectionIdentifiers::new(3, &scid, 0, None);
+        ids.set_initial_dcid(dcid, None, Some(0));
+        ids.set_source_conn_id_limit(3);
+
+        // Nothing to rotate to.
+        assert_eq!(
+            ids.retire_scids_prior_to_newest(),
+            Err(Error::OutOfIdentifiers)
+        );
+
+        let (scid2, rt2) = create_cid_and_reset_token(16);
+        let (scid3, rt3) = create_cid_and_reset_token(16);
+
+        assert_eq!(ids.new_scid(scid2, Some(rt2), true, None, false), Ok(1));
+        assert_eq!(ids.new_scid(scid3, Some(rt3), true, None, false), Ok(2));
+
+        // Fake NEW_CONNECTION_ID sending.
+        ids.mark_advertise_new_scid_seq(1, false);
+        ids.mark_advertise_new_scid_seq(2, false);
+        assert!(!ids.has_new_scids());
+
+        assert_eq!(ids.retire_scids_prior_to_newest(), Ok(2));
+        assert_eq!(ids.lowest_usable_scid_seq(), Ok(2));
+        assert_eq!(ids.next_advertise_new_scid_seq(), Some(2));
+
+        match ids.get_new_connection_id_frame_for(2) {
+            Ok(frame::Frame::NewConnectionId {
+                seq_num,
+                retire_prior_to,
+                ..
+            }) => {
+                assert_eq!(seq_num, 2);
+                assert_eq!(retire_prior_to, 2);
+            },
+
+            _ => panic!("unexpected frame"),
+        }
+
+        // Only the newest Connection ID is left.
+        assert_eq!(
+            ids.retire_scids_prior_to_newest(),
+            Err(Error::OutOfIdentifiers)
+        );
+    }
+
+    #[test]
+    fn new_dcid_repeated_with_higher_retire_prior_to() {
+        let (scid, _) = create_cid_and_reset_token(16);
+        let (dcid, _) = create_cid_and_reset_token(16);
+
+        let mut ids = ConnectionIdentifiers::new(3, &scid, 0, None);
+        ids.set_initial_dcid(dcid, None, Some(0));
+
+        let (dcid2, rt2) = create_cid_and_reset_token(16);
+
+        assert_eq!(
+            ids.new_dcid(dcid2.clone(), 1, rt2, 0),
+            Ok(Vec::<(u64, usize)>::new()),
+        );
+        ids.link_dcid_to_path_id(1, 1).unwrap();
+
+        // Repeating the same frame is a no-op.
+        assert_eq!(
+            ids.new_dcid(dcid2.clone(), 1, rt2, 0),
+            Ok(Vec::<(u64, usize)>::new()),
+        );
+        assert_eq!(ids.dcids.len(), 2);
+
+        // But a higher Retire Prior To is still honoured.
+        assert_eq!(ids.new_dcid(dcid2, 1, rt2, 1), Ok(vec![(0, 0)]));
+        assert_eq!(ids.dcids.len(), 1);
+        assert_eq!(ids.next_retire_dcid_seq(), Some(0));
+
+        // The repeated identifier is still linked to its path.
+        assert_eq!(ids.get_dcid(1).unwrap().path_id, Some(1));
+    }
 }
@@ -24,23 +24,28 @@
 // NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
 // SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
 
+#[cfg(not(feature = "rustls"))]
 use std::mem::MaybeUninit;
 
 use ring::aead;
 use ring::hkdf;
 
+#[cfg(not(feature = "rustls"))]
 use libc::c_int;
+#[cfg(not(feature = "rustls"))]
 use libc::c_void;
 
 use crate::Error;
 use crate::Result;
 
+use crate::offload;
 use crate::packet;
 
 #[repr(C)]
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Level {
     Initial   = 0,
+    #[cfg_attr(feature = "rustls", allow(dead_code))]
     ZeroRTT   = 1,
     Handshake = 2,
     OneRTT    = 3,
@@ -71,6 +76,7 @@ pub enum Algorithm {
 }
 
 impl Algorithm {
+    #[cfg(not(feature = "rustls"))]
     fn get_evp_aead(self) -> *const EVP_AEAD {
         match self {
             Algorithm::AES128_GCM => unsafe { EVP_aead_aes_128_gcm() },
@@ -81,6 +87,15 @@ impl Algorithm {
         }
     }
 
+    #[cfg(feature = "rustls")]
+    fn get_ring_aead(self) -> &'static aead::Algorithm {
+        match self {
+            Algorithm::AES128_GCM => &aead::AES_128_GCM,
+            Algorithm::AES256_GCM => &aead::AES_256_GCM,
+            Algorithm::ChaCha20_Poly1305 => &aead::CHACHA20_POLY1305,
+        }
+    }
+
     fn get_ring_hp(self) -> &'static aead::quic::Algorithm {
         match self {
             Algorithm::AES128_GCM => &aead::quic::AES_128,
@@ -124,11 +139,23 @@ impl Algorithm {
             Algorithm::ChaCha20_Poly1305 => 12,
         }
     }
+
+    /// Returns the maximum number of packets that can be protected with a
+    /// single key, as per RFC 9001 Section 6.6.
+    pub fn confidentiality_limit(self) -> u64 {
+        match self {
+            Algorithm::AES128_GCM => 1 << 23,
+            Algorithm::AES256_GCM => 1 << 23,
+            Algorithm::ChaCha20_Poly1305 => 1 << 62,
+        }
+    }
 }
 
 pub struct Open {
     alg: Algorithm,
 
+    version: u32,
+
     secret: Vec<u8>,
 
     header: HeaderProtectionKey,
@@ -139,11 +166,13 @@ pub struct Open {
 impl Open {
     pub fn new(
         alg: Algorithm, key: Vec<u8>, iv: Vec<u8>, hp_key: Vec<u8>,
-        secret: Vec<u8>,
+        secret: Vec<u8>, version: u32,
     ) -> Result<Open> {
         Ok(Open {
             alg,
 
+            version,
+
             header: HeaderProtectionKey::new(alg, hp_key)?,
 
             packet: PacketKey::new(alg, key, iv)?,
@@ -152,18 +181,23 @@ impl Open {
         })
     }
 
-    pub fn from_secret(aead: Algorithm, secret: Vec<u8>) -> Result<Open> {
+    pub fn from_secret(
+        aead: Algorithm, secret: Vec<u8>, version: u32,
+    ) -> Result<Open> {
         Ok(Open {
             alg: aead,
 
-            header: HeaderProtectionKey::from_secret(aead, &secret)?,
+            version,
+
+            header: HeaderProtectionKey::from_secret(aead, &secret, version)?,
 
-            packet: PacketKey::from_secret(aead, &secret)?,
+            packet: PacketKey::from_secret(aead, &secret, version)?,
 
             secret,
         })
     }
 
+    #[cfg(not(feature = "rustls"))]
     pub fn open_with_u64_counter(
         &self, counter: u64, ad: &[u8], buf: &mut [u8],
     ) -> Result<usize> {
@@ -204,6 +238,29 @@ impl Open {
         Ok(out_len)
     }
 
+    #[cfg(feature = "rustls")]
+    pub fn open_with_u64_counter(
+        &self, counter: u64, ad: &[u8], buf: &mut [u8],
+    ) -> Result<usize> {
+        if cfg!(feature = "fuzzing") {
+            return Ok(buf.len());
+        }
+
+        let nonce = make_nonce(&self.packet.nonce, counter);
+
+        let plaintext = self
+            .packet
+            .ctx
+            .open_in_place(
+                aead::Nonce::assume_unique_for_key(nonce),
+                aead::Aad::from(ad),
+                buf,
+            )
+            .map_err(|_| Error::CryptoFail)?;
+
+        Ok(plaintext.len())
+    }
+
     pub fn new_mask(&self, sample: &[u8]) -> Result<[u8; 5]> {
         if cfg!(feature = "fuzzing") {
             return Ok(<[u8; 5]>::default());
@@ -222,14 +279,22 @@ impl Open {
         self.alg
     }
 
+    pub fn secret(&self) -> &[u8] {
+        &self.secret
+    }
+
     pub fn derive_next_packet_key(&self) -> Result<Open> {
-        let next_secret = derive_next_secret(self.alg, &self.secret)?;
+        let next_secret =
+            derive_next_secret(self.alg, self.version, &self.secret)?;
 
-        let next_packet_key = PacketKey::from_secret(self.alg, &next_secret)?;
+        let next_packet_key =
+            PacketKey::from_secret(self.alg, &next_secret, self.version)?;
 
         Ok(Open {
             alg: self.alg,
 
+            version: self.version,
+
             secret: next_secret,
 
             header: HeaderProtectionKey::new(
@@ -245,6 +310,8 @@ impl Open {
 pub struct Seal {
     alg: Algorithm,
 
+    version: u32,
+
     secret: Vec<u8>,
 
     header: HeaderProtectionKey,
@@ -255,11 +322,13 @@ pub struct Seal {
 impl Seal {
     pub fn new(
         alg: Algorithm, key: Vec<u8>, iv: Vec<u8>, hp_key: Vec<u8>,
-        secret: Vec<u8>,
+        secret: Vec<u8>, version: u32,
     ) -> Result<Seal> {
         Ok(Seal {
             alg,
 
+            version,
+
             header: HeaderProtectionKey::new(alg, hp_key)?,
 
             packet: PacketKey::new(alg, key, iv)?,
@@ -268,18 +337,23 @@ impl Seal {
         })
     }
 
-    pub fn from_secret(aead: Algorithm, secret: Vec<u8>) -> Result<Seal> {
+    pub fn from_secret(
+        aead: Algorithm, secret: Vec<u8>, version: u32,
+    ) -> Result<Seal> {
         Ok(Seal {
             alg: aead,
 
-            header: HeaderProtectionKey::from_secret(aead, &secret)?,
+            version,
 
-            packet: PacketKey::from_secret(aead, &secret)?,
+            header: HeaderProtectionKey::from_secret(aead, &secret, version)?,
+
+            packet: PacketKey::from_secret(aead, &secret, version)?,
 
             secret,
         })
     }
 
+    #[cfg(not(feature = "rustls"))]
     pub fn seal_with_u64_counter(
         &self, counter: u64, ad: &[u8], buf: &mut [u8], in_len: usize,
         extra_in: Option<&[u8]>,
@@ -335,6 +409,47 @@ impl Seal {
         Ok(in_len + out_tag_len)
     }
 
+    #[cfg(feature = "rustls")]
+    pub fn seal_with_u64_counter(
+        &self, counter: u64, ad: &[u8], buf: &mut [u8], in_len: usize,
+        extra_in: Option<&[u8]>,
+    ) -> Result<usize> {
+        let extra_in = extra_in.unwrap_or_default();
+
+        if cfg!(feature = "fuzzing") {
+            buf[in_len..in_len + extra_in.len()].copy_from_slice(extra_in);
+            return Ok(in_len + extra_in.len());
+        }
+
+        let tag_len = self.alg().tag_len();
+
+        // Make sure all the outputs combined fit in the buffer.
+        if in_len + tag_len + extra_in.len() > buf.len() {
+            return Err(Error::CryptoFail);
+        }
+
+        // The extra input is encrypted as if it followed the plaintext, so
+        // the output matches EVP_AEAD_CTX_seal_scatter().
+        let in_len = in_len + extra_in.len();
+        buf[in_len - extra_in.len()..in_len].copy_from_slice(extra_in);
+
+        let nonce = make_nonce(&self.packet.nonce, counter);
+
+        let tag = self
+            .packet
+            .ctx
+            .seal_in_place_separate_tag(
+                aead::Nonce::assume_unique_for_key(nonce),
+                aead::Aad::from(ad),
+                &mut buf[..in_len],
+            )
+            .map_err(|_| Error::CryptoFail)?;
+
+        buf[in_len..in_len + tag_len].copy_from_slice(tag.as_ref());
+
+        Ok(in_len + tag_len)
+    }
+
     pub fn new_mask(&self, sample: &[u8]) -> Result<[u8; 5]> {
         if cfg!(feature = "fuzzing") {
             return Ok(<[u8; 5]>::default());
@@ -353,14 +468,35 @@ impl Seal {
         self.alg
     }
 
+    pub fn secret(&self) -> &[u8] {
+        &self.secret
+    }
+
+    /// Returns the raw key material, for offloading packet protection.
+    pub fn packet_keys(&self) -> offload::PacketKeys<'_> {
+        offload::PacketKeys {
+            cipher_suite: self.alg.into(),
+
+            key: &self.packet.key,
+
+            iv: &self.packet.nonce,
+
+            hp_key: &self.header.hp_key,
+        }
+    }
+
     pub fn derive_next_packet_key(&self) -> Result<Seal> {
-        let next_secret = derive_next_secret(self.alg, &self.secret)?;
+        let next_secret =
+            derive_next_secret(self.alg, self.version, &self.secret)?;
 
-        let next_packet_key = PacketKey::from_secret(self.alg, &next_secret)?;
+        let next_packet_key =
+            PacketKey::from_secret(self.alg, &next_secret, self.version)?;
 
         Ok(Seal {
             alg: self.alg,
 
+            version: self.version,
+
             secret: next_secret,
 
             header: HeaderProtectionKey::new(
@@ -386,20 +522,28 @@ impl HeaderProtectionKey {
             .map_err(|_| Error::CryptoFail)
     }
 
-    pub fn from_secret(aead: Algorithm, secret: &[u8]) -> Result<Self> {
+    pub fn from_secret(
+        aead: Algorithm, secret: &[u8], version: u32,
+    ) -> Result<Self> {
         let key_len = aead.key_len();
 
         let mut hp_key = vec![0; key_len];
 
-        derive_hdr_key(aead, secret, &mut hp_key)?;
+        derive_hdr_key(aead, version, secret, &mut hp_key)?;
 
         Self::new(aead, hp_key)
     }
 }
 
 pub struct PacketKey {
+    #[cfg(not(feature = "rustls"))]
     ctx: EVP_AEAD_CTX,
 
+    #[cfg(feature = "rustls")]
+    ctx: aead::LessSafeKey,
+
+    key: Vec<u8>,
+
     nonce: Vec<u8>,
 }
 
@@ -408,19 +552,23 @@ impl PacketKey {
         Ok(Self {
             ctx: make_aead_ctx(alg, &key)?,
 
+            key,
+
             nonce: iv,
         })
     }
 
-    pub fn from_secret(aead: Algorithm, secret: &[u8]) -> Result<Self> {
+    pub fn from_secret(
+        aead: Algorithm, secret: &[u8], version: u32,
+    ) -> Result<Self> {
         let key_len = aead.key_len();
         let nonce_len = aead.nonce_len();
 
         let mut key = vec![0; key_len];
         let mut iv = vec![0; nonce_len];
 
-        derive_pkt_key(aead, secret, &mut key)?;
-        derive_pkt_iv(aead, secret, &mut iv)?;
+        derive_pkt_key(aead, version, secret, &mut key)?;
+        derive_pkt_iv(aead, version, secret, &mut iv)?;
 
         Self::new(aead, key, iv)
     }
@@ -445,9 +593,9 @@ pub fn derive_initial_key_material(
     let mut client_hp_key = vec![0; key_len];
 
     derive_client_initial_secret(&initial_secret, &mut client_secret)?;
-    derive_pkt_key(aead, &client_secret, &mut client_key)?;
-    derive_pkt_iv(aead, &client_secret, &mut client_iv)?;
-    derive_hdr_key(aead, &client_secret, &mut client_hp_key)?;
+    derive_pkt_key(aead, version, &client_secret, &mut client_key)?;
+    derive_pkt_iv(aead, version, &client_secret, &mut client_iv)?;
+    derive_hdr_key(aead, version, &client_secret, &mut client_hp_key)?;
 
     // Server.
     let mut server_key = vec![0; key_len];
@@ -455,9 +603,9 @@ pub fn derive_initial_key_material(
     let mut server_hp_key = vec![0; key_len];
 
     derive_server_initial_secret(&initial_secret, &mut server_secret)?;
-    derive_pkt_key(aead, &server_secret, &mut server_key)?;
-    derive_pkt_iv(aead, &server_secret, &mut server_iv)?;
-    derive_hdr_key(aead, &server_secret, &mut server_hp_key)?;
+    derive_pkt_key(aead, version, &server_secret, &mut server_key)?;
+    derive_pkt_iv(aead, version, &server_secret, &mut server_iv)?;
+    derive_hdr_key(aead, version, &server_secret, &mut server_hp_key)?;
 
     let (open, seal) = if is_server {
         (
@@ -467,6 +615,7 @@ pub fn derive_initial_key_material(
                 client_iv,
                 client_hp_key,
                 client_secret.to_vec(),
+                version,
             )?,
             Seal::new(
                 aead,
@@ -474,6 +623,7 @@ pub fn derive_initial_key_material(
                 server_iv,
                 server_hp_key,
                 server_secret.to_vec(),
+                version,
             )?,
         )
     } else {
@@ -484,6 +634,7 @@ pub fn derive_initial_key_material(
                 server_iv,
                 server_hp_key,
                 server_secret.to_vec(),
+                version,
             )?,
             Seal::new(
                 aead,
@@ -491,6 +642,7 @@ pub fn derive_initial_key_material(
                 client_iv,
                 client_hp_key,
                 client_secret.to_vec(),
+                version,
             )?,
         )
     };
@@ -504,9 +656,16 @@ fn derive_initial_secret(secret: &[u8], version: u32) -> hkdf::Prk {
         0xa4, 0xc8, 0x0c, 0xad, 0xcc, 0xbb, 0x7f, 0x0a,
     ];
 
+    const INITIAL_SALT_V2: [u8; 20] = [
+        0x0d, 0xed, 0xe3, 0xde, 0xf7, 0x00, 0xa6, 0xdb, 0x81, 0x93, 0x81, 0xbe,
+        0x6e, 0x26, 0x9d, 0xcb, 0xf9, 0xbd, 0x2e, 0xd9,
+    ];
+
     let salt = match version {
         crate::PROTOCOL_VERSION_V1 => &INITIAL_SALT_V1,
 
+        crate::PROTOCOL_VERSION_V2 => &INITIAL_SALT_V2,
+
         _ => &INITIAL_SALT_V1,
     };
 
@@ -524,21 +683,31 @@ fn derive_server_initial_secret(prk: &hkdf::Prk, out: &mut [u8]) -> Result<()> {
     hkdf_expand_label(prk, LABEL, out)
 }
 
-fn derive_next_secret(aead: Algorithm, secret: &[u8]) -> Result<Vec<u8>> {
-    const LABEL: &[u8] = b"quic ku";
+fn derive_next_secret(
+    aead: Algorithm, version: u32, secret: &[u8],
+) -> Result<Vec<u8>> {
+    let label: &[u8] = match version {
+        crate::PROTOCOL_VERSION_V2 => b"quicv2 ku",
+
+        _ => b"quic ku",
+    };
 
     let mut next_secret = vec![0; secret.len()];
 
     let secret_prk = hkdf::Prk::new_less_safe(aead.get_ring_digest(), secret);
-    hkdf_expand_label(&secret_prk, LABEL, &mut next_secret)?;
+    hkdf_expand_label(&secret_prk, label, &mut next_secret)?;
 
     Ok(next_secret)
 }
 
 pub fn derive_hdr_key(
-    aead: Algorithm, secret: &[u8], out: &mut [u8],
+    aead: Algorithm, version: u32, secret: &[u8], out: &mut [u8],
 ) -> Result<()> {
-    const LABEL: &[u8] = b"quic hp";
+    let label: &[u8] = match version {
+        crate::PROTOCOL_VERSION_V2 => b"quicv2 hp",
+
+        _ => b"quic hp",
+    };
 
     let key_len = aead.key_len();
 
@@ -547,13 +716,17 @@ pub fn derive_hdr_key(
     }
 
     let secret = hkdf::Prk::new_less_safe(aead.get_ring_digest(), secret);
-    hkdf_expand_label(&secret, LABEL, &mut out[..key_len])
+    hkdf_expand_label(&secret, label, &mut out[..key_len])
 }
 
 pub fn derive_pkt_key(
-    aead: Algorithm, secret: &[u8], out: &mut [u8],
+    aead: Algorithm, version: u32, secret: &[u8], out: &mut [u8],
 ) -> Result<()> {
-    const LABEL: &[u8] = b"quic key";
+    let label: &[u8] = match version {
+        crate::PROTOCOL_VERSION_V2 => b"quicv2 key",
+
+        _ => b"quic key",
+    };
 
     let key_len = aead.key_len();
 
@@ -562,13 +735,17 @@ pub fn derive_pkt_key(
     }
 
     let secret = hkdf::Prk::new_less_safe(aead.get_ring_digest(), secret);
-    hkdf_expand_label(&secret, LABEL, &mut out[..key_len])
+    hkdf_expand_label(&secret, label, &mut out[..key_len])
 }
 
 pub fn derive_pkt_iv(
-    aead: Algorithm, secret: &[u8], out: &mut [u8],
+    aead: Algorithm, version: u32, secret: &[u8], out: &mut [u8],
 ) -> Result<()> {
-    const LABEL: &[u8] = b"quic iv";
+    let label: &[u8] = match version {
+        crate::PROTOCOL_VERSION_V2 => b"quicv2 iv",
+
+        _ => b"quic iv",
+    };
 
     let nonce_len = aead.nonce_len();
 
@@ -577,9 +754,10 @@ pub fn derive_pkt_iv(
     }
 
     let secret = hkdf::Prk::new_less_safe(aead.get_ring_digest(), secret);
-    hkdf_expand_label(&secret, LABEL, &mut out[..nonce_len])
+    hkdf_expand_label(&secret, label, &mut out[..nonce_len])
 }
 
+#[cfg(not(feature = "rustls"))]
 fn make_aead_ctx(alg: Algorithm, key: &[u8]) -> Result<EVP_AEAD_CTX> {
     let mut ctx = MaybeUninit::uninit();
 
@@ -605,6 +783,13 @@ fn make_aead_ctx(alg: Algorithm, key: &[u8]) -> Result<EVP_AEAD_CTX> {
     Ok(ctx)
 }
 
+#[cfg(feature = "rustls")]
+fn make_aead_ctx(alg: Algorithm, key: &[u8]) -> Result<aead::LessSafeKey> {
+    aead::UnboundKey::new(alg.get_ring_aead(), key)
+        .map(aead::LessSafeKey::new)
+        .map_err(|_| Error::CryptoFail)
+}
+
 fn hkdf_expand_label(
     prk: &hkdf::Prk, label: &[u8], out: &mut [u8],
 ) -> Result<()> {
@@ -636,6 +821,91 @@ fn make_nonce(iv: &[u8], counter: u64) -> [u8; aead::NONCE_LEN] {
     nonce
 }
 
+/// A raw AES-128 block cipher, used to build other constructions (e.g.
+/// QUIC-LB Connection IDs) that are not AEADs.
+#[cfg(not(feature = "rustls"))]
+pub struct Aes128Ecb {
+    enc: AES_KEY,
+    dec: AES_KEY,
+}
+
+#[cfg(not(feature = "rustls"))]
+impl Aes128Ecb {
+    pub fn new(key: &[u8; 16]) -> Result<Aes128Ecb> {
+        let mut enc = MaybeUninit::<AES_KEY>::uninit();
+        let mut dec = MaybeUninit::<AES_KEY>::uninit();
+
+        let rc =
+            unsafe { AES_set_encrypt_key(key.as_ptr(), 128, enc.as_mut_ptr()) };
+
+        if rc != 0 {
+            return Err(Error::CryptoFail);
+        }
+
+        let rc =
+            unsafe { AES_set_decrypt_key(key.as_ptr(), 128, dec.as_mut_ptr()) };
+
+        if rc != 0 {
+            return Err(Error::CryptoFail);
+        }
+
+        Ok(Aes128Ecb {
+            enc: unsafe { enc.assume_init() },
+            dec: unsafe { dec.assume_init() },
+        })
+    }
+
+    pub fn encrypt_block(&self, block: &[u8; 16]) -> [u8; 16] {
+        let mut out = [0; 16];
+
+        unsafe { AES_encrypt(block.as_ptr(), out.as_mut_ptr(), &self.enc) };
+
+        out
+    }
+
+    pub fn decrypt_block(&self, block: &[u8; 16]) -> [u8; 16] {
+        let mut out = [0; 16];
+
+        unsafe { AES_decrypt(block.as_ptr(), out.as_mut_ptr(), &self.dec) };
+
+        out
+    }
+}
+
+/// A raw AES-128 block cipher, used to build other constructions (e.g.
+/// QUIC-LB Connection IDs) that are not AEADs.
+#[cfg(feature = "rustls")]
+pub struct Aes128Ecb(aes::Aes128);
+
+#[cfg(feature = "rustls")]
+impl Aes128Ecb {
+    pub fn new(key: &[u8; 16]) -> Result<Aes128Ecb> {
+        use aes::cipher::KeyInit;
+
+        Ok(Aes128Ecb(aes::Aes128::new(key.into())))
+    }
+
+    pub fn encrypt_block(&self, block: &[u8; 16]) -> [u8; 16] {
+        use aes::cipher::BlockEncrypt;
+
+        let mut out = aes::Block::from(*block);
+
+        self.0.encrypt_block(&mut out);
+
+        out.into()
+    }
+
+    pub fn decrypt_block(&self, block: &[u8; 16]) -> [u8; 16] {
+        use aes::cipher::BlockDecrypt;
+
+        let mut out = aes::Block::from(*block);
+
+        self.0.decrypt_block(&mut out);
+
+        out.into()
+    }
+}
+
 // The ring HKDF expand() API does not accept an arbitrary output length, so we
 // need to hide the `usize` length as part of a type that implements the trait
 // `ring::hkdf::KeyType` in order to trick ring into accepting it.
@@ -647,6 +917,7 @@ impl hkdf::KeyType for ArbitraryOutputLen {
     }
 }
 
+#[cfg(not(feature = "rustls"))]
 #[allow(non_camel_case_types)]
 #[repr(transparent)]
 struct EVP_AEAD {
@@ -656,6 +927,7 @@ struct EVP_AEAD {
 // NOTE: This structure is copied from <openssl/aead.h> in order to be able to
 // statically allocate it. While it is not often modified upstream, it needs to
 // be kept in sync.
+#[cfg(not(feature = "rustls"))]
 #[repr(C)]
 struct EVP_AEAD_CTX {
     aead: libc::uintptr_t,
@@ -664,7 +936,31 @@ struct EVP_AEAD_CTX {
     tag_len: u8,
 }
 
+// NOTE: This structure is copied from <openssl/aes.h> in order to be able to
+// statically allocate it.
+#[cfg(not(feature = "rustls"))]
+#[allow(non_camel_case_types)]
+#[repr(C)]
+struct AES_KEY {
+    rd_key: [u32; 60],
+    rounds: libc::c_uint,
+}
+
+#[cfg(not(feature = "rustls"))]
 extern {
+    // AES
+    fn AES_set_encrypt_key(
+        key: *const u8, bits: libc::c_uint, aeskey: *mut AES_KEY,
+    ) -> c_int;
+
+    fn AES_set_decrypt_key(
+        key: *const u8, bits: libc::c_uint, aeskey: *mut AES_KEY,
+    ) -> c_int;
+
+    fn AES_encrypt(inp: *const u8, out: *mut u8, key: *const AES_KEY);
+
+    fn AES_decrypt(inp: *const u8, out: *mut u8, key: *const AES_KEY);
+
     // EVP_AEAD
     fn EVP_aead_aes_128_gcm() -> *const EVP_AEAD;
 
@@ -721,21 +1017,39 @@ mod tests {
         ];
         assert_eq!(&secret, &expected_client_initial_secret);
 
-        assert!(derive_pkt_key(aead, &secret, &mut pkt_key).is_ok());
+        assert!(derive_pkt_key(
+            aead,
+            crate::PROTOCOL_VERSION_V1,
+            &secret,
+            &mut pkt_key
+        )
+        .is_ok());
         let expected_client_pkt_key = [
             0x1f, 0x36, 0x96, 0x13, 0xdd, 0x76, 0xd5, 0x46, 0x77, 0x30, 0xef,
             0xcb, 0xe3, 0xb1, 0xa2, 0x2d,
         ];
         assert_eq!(&pkt_key, &expected_client_pkt_key);
 
-        assert!(derive_pkt_iv(aead, &secret, &mut pkt_iv).is_ok());
+        assert!(derive_pkt_iv(
+            aead,
+            crate::PROTOCOL_VERSION_V1,
+            &secret,
+            &mut pkt_iv
+        )
+        .is_ok());
         let expected_client_pkt_iv = [
             0xfa, 0x04, 0x4b, 0x2f, 0x42, 0xa3, 0xfd, 0x3b, 0x46, 0xfb, 0x25,
             0x5c,
         ];
         assert_eq!(&pkt_iv, &expected_client_pkt_iv);
 
-        assert!(derive_hdr_key(aead, &secret, &mut hdr_key).is_ok());
+        assert!(derive_hdr_key(
+            aead,
+            crate::PROTOCOL_VERSION_V1,
+            &secret,
+            &mut hdr_key
+        )
+        .is_ok());
         let expected_client_hdr_key = [
             0x9f, 0x50, 0x44, 0x9e, 0x04, 0xa0, 0xe8, 0x10, 0x28, 0x3a, 0x1e,
             0x99, 0x33, 0xad, 0xed, 0xd2,
@@ -753,21 +1067,39 @@ mod tests {
         ];
         assert_eq!(&secret, &expected_server_initial_secret);
 
-        assert!(derive_pkt_key(aead, &secret, &mut pkt_key).is_ok());
+        assert!(derive_pkt_key(
+            aead,
+            crate::PROTOCOL_VERSION_V1,
+            &secret,
+            &mut pkt_key
+        )
+        .is_ok());
         let expected_server_pkt_key = [
             0xcf, 0x3a, 0x53, 0x31, 0x65, 0x3c, 0x36, 0x4c, 0x88, 0xf0, 0xf3,
             0x79, 0xb6, 0x06, 0x7e, 0x37,
         ];
         assert_eq!(&pkt_key, &expected_server_pkt_key);
 
-        assert!(derive_pkt_iv(aead, &secret, &mut pkt_iv).is_ok());
+        assert!(derive_pkt_iv(
+            aead,
+            crate::PROTOCOL_VERSION_V1,
+            &secret,
+            &mut pkt_iv
+        )
+        .is_ok());
         let expected_server_pkt_iv = [
             0x0a, 0xc1, 0x49, 0x3c, 0xa1, 0x90, 0x58, 0x53, 0xb0, 0xbb, 0xa0,
             0x3e,
         ];
         assert_eq!(&pkt_iv, &expected_server_pkt_iv);
 
-        assert!(derive_hdr_key(aead, &secret, &mut hdr_key).is_ok());
+        assert!(derive_hdr_key(
+            aead,
+            crate::PROTOCOL_VERSION_V1,
+            &secret,
+            &mut hdr_key
+        )
+        .is_ok());
         let expected_server_hdr_key = [
             0xc2, 0x06, 0xb8, 0xd9, 0xb9, 0xf0, 0xf3, 0x76, 0x44, 0x43, 0x0b,
             0x49, 0x0e, 0xea, 0xa3, 0x14,
@@ -775,6 +1107,86 @@ mod tests {
         assert_eq!(&hdr_key, &expected_server_hdr_key);
     }
 
+    #[test]
+    fn derive_initial_secrets_v2() {
+        let dcid = [0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08];
+
+        let mut secret = [0; 32];
+        let mut pkt_key = [0; 16];
+        let mut pkt_iv = [0; 12];
+        let mut hdr_key = [0; 16];
+
+        let aead = Algorithm::AES128_GCM;
+
+        let version = crate::PROTOCOL_VERSION_V2;
+
+        let initial_secret = derive_initial_secret(&dcid, version);
+
+        // Client.
+        assert!(
+            derive_client_initial_secret(&initial_secret, &mut secret).is_ok()
+        );
+        let expected_client_initial_secret = [
+            0x14, 0xec, 0x9d, 0x6e, 0xb9, 0xfd, 0x7a, 0xf8, 0x3b, 0xf5, 0xa6,
+            0x68, 0xbc, 0x17, 0xa7, 0xe2, 0x83, 0x76, 0x6a, 0xad, 0xe7, 0xec,
+            0xd0, 0x89, 0x1f, 0x70, 0xf9, 0xff, 0x7f, 0x4b, 0xf4, 0x7b,
+        ];
+        assert_eq!(&secret, &expected_client_initial_secret);
+
+        assert!(derive_pkt_key(aead, version, &secret, &mut pkt_key).is_ok());
+        let expected_client_pkt_key = [
+            0x8b, 0x1a, 0x0b, 0xc1, 0x21, 0x28, 0x42, 0x90, 0xa2, 0x9e, 0x09,
+            0x71, 0xb5, 0xcd, 0x04, 0x5d,
+        ];
+        assert_eq!(&pkt_key, &expected_client_pkt_key);
+
+        assert!(derive_pkt_iv(aead, version, &secret, &mut pkt_iv).is_ok());
+        let expected_client_pkt_iv = [
+            0x91, 0xf7, 0x3e, 0x23, 0x51, 0xd8, 0xfa, 0x91, 0x66, 0x0e, 0x90,
+            0x9f,
+        ];
+        assert_eq!(&pkt_iv, &expected_client_pkt_iv);
+
+        assert!(derive_hdr_key(aead, version, &secret, &mut hdr_key).is_ok());
+        let expected_client_hdr_key = [
+            0x45, 0xb9, 0x5e, 0x15, 0x23, 0x5d, 0x6f, 0x45, 0xa6, 0xb1, 0x9c,
+            0xbc, 0xb0, 0x29, 0x4b, 0xa9,
+        ];
+        assert_eq!(&hdr_key, &expected_client_hdr_key);
+
+        // Server.
+        assert!(
+            derive_server_initial_secret(&initial_secret, &mut secret).is_ok()
+        );
+        let expected_server_initial_secret = [
+            0x02, 0x63, 0xdb, 0x17, 0x82, 0x73, 0x1b, 0xf4, 0x58, 0x8e, 0x7e,
+            0x4d, 0x93, 0xb7, 0x46, 0x39, 0x07, 0xcb, 0x8c, 0xd8, 0x20, 0x0b,
+            0x5d, 0xa5, 0x5a, 0x8b, 0xd4, 0x88, 0xea, 0xfc, 0x37, 0xc1,
+        ];
+        assert_eq!(&secret, &expected_server_initial_secret);
+
+        assert!(derive_pkt_key(aead, version, &secret, &mut pkt_key).is_ok());
+        let expected_server_pkt_key = [
+            0x82, 0xdb, 0x63, 0x78, 0x61, 0xd5, 0x5e, 0x1d, 0x01, 0x1f, 0x19,
+            0xea, 0x71, 0xd5, 0xd2, 0xa7,
+        ];
+        assert_eq!(&pkt_key, &expected_server_pkt_key);
+
+        assert!(derive_pkt_iv(aead, version, &secret, &mut pkt_iv).is_ok());
+        let expected_server_pkt_iv = [
+            0xdd, 0x13, 0xc2, 0x76, 0x49, 0x9c, 0x02, 0x49, 0xd3, 0x31, 0x06,
+            0x52,
+        ];
+        assert_eq!(&pkt_iv, &expected_server_pkt_iv);
+
+        assert!(derive_hdr_key(aead, version, &secret, &mut hdr_key).is_ok());
+        let expected_server_hdr_key = [
+            0xed, 0xf6, 0xd0, 0x5c, 0x83, 0x12, 0x12, 0x01, 0xb4, 0x36, 0xe1,
+            0x68, 0x77, 0x59, 0x3c, 0x3a,
+        ];
+        assert_eq!(&hdr_key, &expected_server_hdr_key);
+    }
+
     #[test]
     fn derive_chacha20_secrets() {
         let secret = [
@@ -789,7 +1201,13 @@ mod tests {
         let mut pkt_iv = [0; 12];
         let mut hdr_key = [0; 32];
 
-        assert!(derive_pkt_key(aead, &secret, &mut pkt_key).is_ok());
+        assert!(derive_pkt_key(
+            aead,
+            crate::PROTOCOL_VERSION_V1,
+            &secret,
+            &mut pkt_key
+        )
+        .is_ok());
         let expected_pkt_key = [
             0xc6, 0xd9, 0x8f, 0xf3, 0x44, 0x1c, 0x3f, 0xe1, 0xb2, 0x18, 0x20,
             0x94, 0xf6, 0x9c, 0xaa, 0x2e, 0xd4, 0xb7, 0x16, 0xb6, 0x54, 0x88,
@@ -797,14 +1215,26 @@ mod tests {
         ];
         assert_eq!(&pkt_key, &expected_pkt_key);
 
-        assert!(derive_pkt_iv(aead, &secret, &mut pkt_iv).is_ok());
+        assert!(derive_pkt_iv(
+            aead,
+            crate::PROTOCOL_VERSION_V1,
+            &secret,
+            &mut pkt_iv
+        )
+        .is_ok());
         let expected_pkt_iv = [
             0xe0, 0x45, 0x9b, 0x34, 0x74, 0xbd, 0xd0, 0xe4, 0x4a, 0x41, 0xc1,
             0x44,
         ];
         assert_eq!(&pkt_iv, &expected_pkt_iv);
 
-        assert!(derive_hdr_key(aead, &secret, &mut hdr_key).is_ok());
+        assert!(derive_hdr_key(
+            aead,
+            crate::PROTOCOL_VERSION_V1,
+            &secret,
+            &mut hdr_key
+        )
+        .is_ok());
         let expected_hdr_key = [
             0x25, 0xa2, 0x82, 0xb9, 0xe8, 0x2f, 0x06, 0xf2, 0x1f, 0x48, 0x89,
             0x17, 0xa4, 0xfc, 0x8f, 0x1b, 0x73, 0x57, 0x36, 0x85, 0x60, 0x85,
@@ -28,13 +28,38 @@ use crate::Error;
 use crate::Result;
 
 use std::collections::VecDeque;
+use std::time;
+
+/// The urgency of DATAGRAMs queued without an explicit one, matching the
+/// default urgency of streams.
+const DEFAULT_URGENCY: u8 = 127;
+
+/// A queued DATAGRAM payload and its scheduling metadata.
+struct Datagram {
+    data: Vec<u8>,
+
+    /// Lower values are sent first.
+    urgency: u8,
+
+    /// When the DATAGRAM is dropped if it wasn't sent yet.
+    expiry: Option<time::Instant>,
+}
 
 /// Keeps track of DATAGRAM frames.
+///
+/// DATAGRAMs are kept ordered by urgency, and in insertion order among those
+/// with the same urgency.
 #[derive(Default)]
 pub struct DatagramQueue {
-    queue: Option<VecDeque<Vec<u8>>>,
+    queue: Option<VecDeque<Datagram>>,
     queue_max_len: usize,
     queue_bytes_size: usize,
+
+    /// The largest number of DATAGRAMs queued at once.
+    peak_len: usize,
+
+    /// The number of DATAGRAMs dropped because they expired.
+    expired_count: usize,
 }
 
 impl DatagramQueue {
@@ -43,35 +68,60 @@ impl DatagramQueue {
             queue: None,
             queue_bytes_size: 0,
             queue_max_len,
+            peak_len: 0,
+            expired_count: 0,
         }
     }
 
     pub fn push(&mut self, data: Vec<u8>) -> Result<()> {
+        self.push_with(data, DEFAULT_URGENCY, None)
+    }
+
+    /// Queues a DATAGRAM with the given urgency, that is dropped if it is
+    /// still queued at `expiry`.
+    pub fn push_with(
+        &mut self, data: Vec<u8>, urgency: u8, expiry: Option<time::Instant>,
+    ) -> Result<()> {
         if self.is_full() {
             return Err(Error::Done);
         }
 
         self.queue_bytes_size += data.len();
-        self.queue
-            .get_or_insert_with(Default::default)
-            .push_back(data);
+
+        let queue = self.queue.get_or_insert_with(Default::default);
+
+        // Insert after the last DATAGRAM that is at least as urgent.
+        let index = queue
+            .iter()
+            .rposition(|d| d.urgency <= urgency)
+            .map_or(0, |i| i + 1);
+
+        queue.insert(index, Datagram {
+            data,
+            urgency,
+            expiry,
+        });
+
+        self.peak_len = std::cmp::max(self.peak_len, queue.len());
 
         Ok(())
     }
 
     pub fn peek_front_len(&self) -> Option<usize> {
-        self.queue.as_ref().and_then(|q| q.front().map(|d| d.len()))
+        self.queue
+            .as_ref()
+            .and_then(|q| q.front().map(|d| d.data.len()))
     }
 
     pub fn peek_front_bytes(&self, buf: &mut [u8], len: usize) -> Result<usize> {
         match self.queue.as_ref().and_then(|q| q.front()) {
             Some(d) => {
-                let len = std::cmp::min(len, d.len());
+                let len = std::cmp::min(len, d.data.len());
                 if buf.len() < len {
                     return Err(Error::BufferTooShort);
                 }
 
-                buf[..len].copy_from_slice(&d[..len]);
+                buf[..len].copy_from_slice(&d.data[..len]);
                 Ok(len)
             },
 
@@ -81,8 +131,9 @@ impl DatagramQueue {
 
     pub fn pop(&mut self) -> Option<Vec<u8>> {
         if let Some(d) = self.queue.as_mut().and_then(|q| q.pop_front()) {
-            self.queue_bytes_size = self.queue_bytes_size.saturating_sub(d.len());
-            return Some(d);
+            self.queue_bytes_size =
+                self.queue_bytes_size.saturating_sub(d.data.len());
+            return Some(d.data);
         }
 
         None
@@ -94,8 +145,24 @@ impl DatagramQueue {
 
     pub fn purge<F: Fn(&[u8]) -> bool>(&mut self, f: F) {
         if let Some(q) = self.queue.as_mut() {
-            q.retain(|d| !f(d));
-            self.queue_bytes_size = q.iter().fold(0, |total, d| total + d.len());
+            q.retain(|d| !f(&d.data));
+            self.queue_bytes_size =
+                q.iter().fold(0, |total, d| total + d.data.len());
+        }
+    }
+
+    /// Drops the DATAGRAMs that expired at `now`.
+    pub fn purge_expired(&mut self, now: time::Instant) {
+        if let Some(q) = self.queue.as_mut() {
+            let len = q.len();
+
+            q.retain(|d| d.expiry.map_or(true, |e| now < e));
+
+            if q.len() != len {
+                self.expired_count += len - q.len();
+                self.queue_bytes_size =
+                    q.iter().fold(0, |total, d| total + d.data.len());
+            }
         }
     }
 
@@ -114,4 +181,12 @@ impl DatagramQueue {
     pub fn byte_size(&self) -> usize {
         self.queue_bytes_size
     }
+
+    pub fn peak_len(&self) -> usize {
+        self.peak_len
+    }
+
+    pub fn expired_count(&self) -> usize {
+        self.expired_count
+    }
 }
@@ -0,0 +1,306 @@
+// Copyright (C) 2023, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Experimental packet-level forward error correction.
+//!
+//! The sender groups consecutive 1-RTT packets and, once a group is complete,
+//! emits a REPAIR frame carrying the XOR of the plaintext payloads of all the
+//! packets in the group. The receiver keeps a window of recently received
+//! payloads, so that when exactly one packet of a group is lost its payload
+//! can be rebuilt from the repair symbol and the other packets.
+//!
+//! Payloads of different lengths are zero-extended before being combined.
+//! Trailing zeroes in a recovered payload decode as PADDING frames, so the
+//! original length doesn't need to be transmitted.
+
+use std::collections::VecDeque;
+
+use crate::frame;
+
+/// The default number of packets protected by a single repair symbol.
+pub const DEFAULT_GROUP_SIZE: usize = 8;
+
+/// The maximum number of repair symbols waiting to be sent.
+const MAX_PENDING_REPAIRS: usize = 4;
+
+/// The number of received payloads kept around for recovery.
+const RECV_WINDOW: usize = 128;
+
+fn xor_into(dst: &mut Vec<u8>, src: &[u8]) {
+    if dst.len() < src.len() {
+        dst.resize(src.len(), 0);
+    }
+
+    for (d, s) in dst.iter_mut().zip(src) {
+        *d ^= s;
+    }
+}
+
+/// Sender-side FEC state.
+pub struct Encoder {
+    group_size: u64,
+
+    first_pkt_num: u64,
+
+    count: u64,
+
+    data: Vec<u8>,
+
+    ready: VecDeque<frame::Frame>,
+}
+
+impl Encoder {
+    pub fn new(group_size: usize) -> Encoder {
+        Encoder {
+            group_size: group_size.max(2) as u64,
+
+            first_pkt_num: 0,
+
+            count: 0,
+
+            data: Vec::new(),
+
+            ready: VecDeque::new(),
+        }
+    }
+
+    /// Adds the payload of packet `pkt_num` to the current group.
+    ///
+    /// Groups only cover consecutive packet numbers, so a gap closes the
+    /// current group and starts a new one.
+    pub fn protect(&mut self, pkt_num: u64, payload: &[u8]) {
+        if self.count > 0 && pkt_num != self.first_pkt_num + self.count {
+            self.flush();
+        }
+
+        if self.count == 0 {
+            self.first_pkt_num = pkt_num;
+        }
+
+        xor_into(&mut self.data, payload);
+
+        self.count += 1;
+
+        if self.count >= self.group_size {
+            self.flush();
+        }
+    }
+
+    /// Closes the current group and queues its repair symbol.
+    ///
+    /// Groups of a single packet are dropped, as their repair symbol would
+    /// just be a copy of the packet.
+    pub fn flush(&mut self) {
+        if self.count >= 2 {
+            if self.ready.len() >= MAX_PENDING_REPAIRS {
+                self.ready.pop_front();
+            }
+
+            self.ready.push_back(frame::Frame::Repair {
+                first_pkt_num: self.first_pkt_num,
+                count: self.count,
+                data: std::mem::take(&mut self.data),
+            });
+        }
+
+        self.count = 0;
+        self.data.clear();
+    }
+
+    /// Returns true if the current group is not empty.
+    pub fn has_open_group(&self) -> bool {
+        self.count > 0
+    }
+
+    /// Returns true if there are repair symbols waiting to be sent.
+    pub fn has_pending(&self) -> bool {
+        !self.ready.is_empty()
+    }
+
+    /// Returns the wire length of the next REPAIR frame to send.
+    pub fn pending_len(&self) -> Option<usize> {
+        self.ready.front().map(|f| f.wire_len())
+    }
+
+    /// Returns the next REPAIR frame to send.
+    pub fn pop(&mut self) -> Option<frame::Frame> {
+        self.ready.pop_front()
+    }
+}
+
+/// Receiver-side FEC state.
+#[derive(Default)]
+pub struct Decoder {
+    recv: VecDeque<(u64, Vec<u8>)>,
+}
+
+impl Decoder {
+    /// Stores the decrypted payload of packet `pkt_num`.
+    pub fn store(&mut self, pkt_num: u64, payload: &[u8]) {
+        if self.recv.len() >= RECV_WINDOW {
+            self.recv.pop_front();
+        }
+
+        self.recv.push_back((pkt_num, payload.to_vec()));
+    }
+
+    /// Attempts to rebuild the single missing packet of a group.
+    ///
+    /// On success the packet number and payload of the recovered packet are
+    /// returned. Nothing is returned if all the packets of the group were
+    /// received, or if more than one of them is missing.
+    pub fn recover(
+        &mut self, first_pkt_num: u64, count: u64, data: &[u8],
+    ) -> Option<(u64, Vec<u8>)> {
+        if count == 0 || count > RECV_WINDOW as u64 {
+            return None;
+        }
+
+        let last_pkt_num = first_pkt_num.checked_add(count - 1)?;
+
+        let mut payload = data.to_vec();
+        let mut found = 0;
+
+        for (pn, p) in &self.recv {
+            if (first_pkt_num..=last_pkt_num).contains(pn) {
+                xor_into(&mut payload, p);
+
+                found += 1;
+            }
+        }
+
+        if found + 1 != count {
+            return None;
+        }
+
+        let missing = (first_pkt_num..=last_pkt_num)
+            .find(|pn| !self.recv.iter().any(|(p, _)| p == pn))?;
+
+        self.store(missing, &payload);
+
+        Some((missing, payload))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn repair_of(f: frame::Frame) -> (u64, u64, Vec<u8>) {
+        match f {
+            frame::Frame::Repair {
+                first_pkt_num,
+                count,
+                data,
+            } => (first_pkt_num, count, data),
+
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn encode_group() {
+        let mut enc = Encoder::new(3);
+
+        enc.protect(10, &[1, 2, 3]);
+        enc.protect(11, &[4, 5]);
+        assert!(!enc.has_pending());
+
+        enc.protect(12, &[6, 7, 8, 9]);
+        assert!(enc.has_pending());
+        assert!(!enc.has_open_group());
+
+        let (first, count, data) = repair_of(enc.pop().unwrap());
+        assert_eq!(first, 10);
+        assert_eq!(count, 3);
+        assert_eq!(data, vec![1 ^ 4 ^ 6, 2 ^ 5 ^ 7, 3 ^ 8, 9]);
+
+        assert_eq!(enc.pop(), None);
+    }
+
+    #[test]
+    fn encode_gap() {
+        let mut enc = Encoder::new(8);
+
+        enc.protect(0, &[1]);
+        enc.protect(1, &[2]);
+
+        // Packet 2 is not protected, so the group is closed.
+        enc.protect(3, &[3]);
+
+        let (first, count, data) = repair_of(enc.pop().unwrap());
+        assert_eq!(first, 0);
+        assert_eq!(count, 2);
+        assert_eq!(data, vec![1 ^ 2]);
+
+        // Single-packet groups don't generate repair symbols.
+        enc.flush();
+        assert!(!enc.has_pending());
+    }
+
+    #[test]
+    fn recover_single_loss() {
+        let mut enc = Encoder::new(4);
+        let mut dec = Decoder::default();
+
+        let payloads: [&[u8]; 4] = [&[1, 2, 3, 4], &[5, 6], &[7, 8, 9], &[10]];
+
+        for (i, p) in payloads.iter().enumerate() {
+            enc.protect(i as u64, p);
+
+            if i != 2 {
+                dec.store(i as u64, p);
+            }
+        }
+
+        let (first, count, data) = repair_of(enc.pop().unwrap());
+
+        let (pn, payload) = dec.recover(first, count, &data).unwrap();
+        assert_eq!(pn, 2);
+        assert_eq!(payload, vec![7, 8, 9, 0]);
+
+        // Nothing left to recover.
+        assert_eq!(dec.recover(first, count, &data), None);
+    }
+
+    #[test]
+    fn recover_multiple_losses() {
+        let mut enc = Encoder::new(4);
+        let mut dec = Decoder::default();
+
+        for i in 0..4 {
+            enc.protect(i, &[i as u8; 10]);
+
+            if i < 2 {
+                dec.store(i, &[i as u8; 10]);
+            }
+        }
+
+        let (first, count, data) = repair_of(enc.pop().unwrap());
+
+        assert_eq!(dec.recover(first, count, &data), None);
+    }
+}
@@ -201,6 +201,72 @@ pub extern fn quiche_config_load_verify_locations_from_directory(
     }
 }
 
+#[no_mangle]
+pub extern fn quiche_config_set_key_exchange_groups(
+    config: &mut Config, groups: *const c_char,
+) -> c_int {
+    let groups = unsafe { ffi::CStr::from_ptr(groups).to_str().unwrap() };
+    let groups: Vec<&str> = groups.split(':').collect();
+
+    match config.set_key_exchange_groups(&groups) {
+        Ok(_) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_config_set_cipher_suites(
+    config: &mut Config, suites: *const u16, suites_len: size_t,
+) -> c_int {
+    let suites = unsafe { slice::from_raw_parts(suites, suites_len) };
+
+    let mut v = Vec::with_capacity(suites.len());
+
+    for s in suites {
+        v.push(match s {
+            0x1301 => CipherSuite::Aes128GcmSha256,
+
+            0x1302 => CipherSuite::Aes256GcmSha384,
+
+            0x1303 => CipherSuite::ChaCha20Poly1305Sha256,
+
+            _ => return Error::TlsFail.to_c() as c_int,
+        });
+    }
+
+    match config.set_cipher_suites(&v) {
+        Ok(_) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_config_set_signature_algorithms(
+    config: &mut Config, sigalgs: *const c_char,
+) -> c_int {
+    let sigalgs = unsafe { ffi::CStr::from_ptr(sigalgs).to_str().unwrap() };
+    let sigalgs: Vec<&str> = sigalgs.split(':').collect();
+
+    match config.set_signature_algorithms(&sigalgs) {
+        Ok(_) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_config_set_application_settings(
+    config: &mut Config, proto: *const u8, proto_len: size_t,
+    settings: *const u8, settings_len: size_t,
+) {
+    let proto = unsafe { slice::from_raw_parts(proto, proto_len) };
+    let settings = unsafe { slice::from_raw_parts(settings, settings_len) };
+
+    config.set_application_settings(proto, settings);
+}
+
 #[no_mangle]
 pub extern fn quiche_config_verify_peer(config: &mut Config, v: bool) {
     config.verify_peer(v);
@@ -211,6 +277,23 @@ pub extern fn quiche_config_grease(config: &mut Config, v: bool) {
     config.grease(v);
 }
 
+#[no_mangle]
+pub extern fn quiche_config_set_grease_rate(config: &mut Config, v: f64) {
+    config.set_grease_rate(v);
+}
+
+#[no_mangle]
+pub extern fn quiche_config_enable_quic_v2(config: &mut Config, v: bool) {
+    config.enable_quic_v2(v);
+}
+
+#[no_mangle]
+pub extern fn quiche_config_verify_peer_certificate_required(
+    config: &mut Config, v: bool,
+) {
+    config.verify_peer_certificate_required(v);
+}
+
 #[no_mangle]
 pub extern fn quiche_config_log_keys(config: &mut Config) {
     config.log_keys();
@@ -236,11 +319,29 @@ pub extern fn quiche_config_set_application_protos(
     }
 }
 
+#[no_mangle]
+pub extern fn quiche_config_add_custom_transport_param(
+    config: &mut Config, id: u64, value: *const u8, value_len: size_t,
+) -> c_int {
+    let value = unsafe { slice::from_raw_parts(value, value_len) };
+
+    match config.add_custom_transport_param(id, value) {
+        Ok(_) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
 #[no_mangle]
 pub extern fn quiche_config_set_max_idle_timeout(config: &mut Config, v: u64) {
     config.set_max_idle_timeout(v);
 }
 
+#[no_mangle]
+pub extern fn quiche_config_set_keepalive_interval(config: &mut Config, v: u64) {
+    config.set_keepalive_interval(std::time::Duration::from_millis(v));
+}
+
 #[no_mangle]
 pub extern fn quiche_config_set_max_recv_udp_payload_size(
     config: &mut Config, v: size_t,
@@ -298,6 +399,27 @@ pub extern fn quiche_config_set_max_ack_delay(config: &mut Config, v: u64) {
     config.set_max_ack_delay(v);
 }
 
+#[no_mangle]
+pub extern fn quiche_config_set_ack_eliciting_threshold(
+    config: &mut Config, v: size_t,
+) {
+    config.set_ack_eliciting_threshold(v);
+}
+
+#[no_mangle]
+pub extern fn quiche_config_enable_ack_on_reordering(
+    config: &mut Config, v: bool,
+) {
+    config.enable_ack_on_reordering(v);
+}
+
+#[no_mangle]
+pub extern fn quiche_config_enable_immediate_ack_during_handshake(
+    config: &mut Config, v: bool,
+) {
+    config.enable_immediate_ack_during_handshake(v);
+}
+
 #[no_mangle]
 pub extern fn quiche_config_set_disable_active_migration(
     config: &mut Config, v: bool,
@@ -305,6 +427,32 @@ pub extern fn quiche_config_set_disable_active_migration(
     config.set_disable_active_migration(v);
 }
 
+#[no_mangle]
+pub extern fn quiche_config_set_preferred_address(
+    config: &mut Config, v4: *const sockaddr, v4_len: socklen_t,
+    v6: *const sockaddr, v6_len: socklen_t,
+) {
+    let ipv4 = if v4.is_null() {
+        None
+    } else {
+        match std_addr_from_c(unsafe { &*v4 }, v4_len) {
+            SocketAddr::V4(addr) => Some(addr),
+            SocketAddr::V6(_) => None,
+        }
+    };
+
+    let ipv6 = if v6.is_null() {
+        None
+    } else {
+        match std_addr_from_c(unsafe { &*v6 }, v6_len) {
+            SocketAddr::V4(_) => None,
+            SocketAddr::V6(addr) => Some(addr),
+        }
+    };
+
+    config.set_preferred_address(ipv4, ipv6);
+}
+
 #[no_mangle]
 pub extern fn quiche_config_set_cc_algorithm_name(
     config: &mut Config, name: *const c_char,
@@ -331,21 +479,91 @@ pub extern fn quiche_config_set_initial_congestion_window_packets(
     config.set_initial_congestion_window_packets(packets);
 }
 
+#[no_mangle]
+pub extern fn quiche_config_set_minimum_congestion_window_packets(
+    config: &mut Config, packets: size_t,
+) -> c_int {
+    match config.set_minimum_congestion_window_packets(packets) {
+        Ok(_) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
 #[no_mangle]
 pub extern fn quiche_config_enable_hystart(config: &mut Config, v: bool) {
     config.enable_hystart(v);
 }
 
+#[no_mangle]
+pub extern fn quiche_config_enable_cubic_fast_convergence(
+    config: &mut Config, v: bool,
+) {
+    config.enable_cubic_fast_convergence(v);
+}
+
 #[no_mangle]
 pub extern fn quiche_config_enable_pacing(config: &mut Config, v: bool) {
     config.enable_pacing(v);
 }
 
+#[no_mangle]
+pub extern fn quiche_config_set_pacing_mode(config: &mut Config, v: PacingMode) {
+    config.set_pacing_mode(v);
+}
+
 #[no_mangle]
 pub extern fn quiche_config_set_max_pacing_rate(config: &mut Config, v: u64) {
     config.set_max_pacing_rate(v);
 }
 
+#[no_mangle]
+pub extern fn quiche_config_set_pacing_horizon(config: &mut Config, v: u64) {
+    config.set_pacing_horizon(v);
+}
+
+#[no_mangle]
+pub extern fn quiche_config_set_initial_rtt(config: &mut Config, v: u64) {
+    config.set_initial_rtt(v);
+}
+
+#[no_mangle]
+pub extern fn quiche_config_set_max_pto_backoff(config: &mut Config, v: u32) {
+    config.set_max_pto_backoff(v);
+}
+
+#[no_mangle]
+pub extern fn quiche_config_set_max_pto_count(config: &mut Config, v: u32) {
+    config.set_max_pto_count(v);
+}
+
+#[no_mangle]
+pub extern fn quiche_config_enable_tail_loss_probe(config: &mut Config, v: bool) {
+    config.enable_tail_loss_probe(v);
+}
+
+#[no_mangle]
+pub extern fn quiche_config_set_pto_retransmit_policy(
+    config: &mut Config, v: PtoRetransmitPolicy,
+) {
+    config.set_pto_retransmit_policy(v);
+}
+
+#[no_mangle]
+pub extern fn quiche_config_set_packet_pool_size(config: &mut Config, v: size_t) {
+    config.set_packet_pool_size(v);
+}
+
+#[no_mangle]
+pub extern fn quiche_config_set_buffer_pool_size(config: &mut Config, v: size_t) {
+    config.set_buffer_pool_size(v);
+}
+
+#[no_mangle]
+pub extern fn quiche_config_discover_pmtu(config: &mut Config, v: bool) {
+    config.discover_pmtu(v);
+}
+
 #[no_mangle]
 pub extern fn quiche_config_enable_dgram(
     config: &mut Config, enabled: bool, recv_queue_len: size_t,
@@ -354,6 +572,26 @@ pub extern fn quiche_config_enable_dgram(
     config.enable_dgram(enabled, recv_queue_len, send_queue_len);
 }
 
+#[no_mangle]
+pub extern fn quiche_config_enable_fec(config: &mut Config, v: bool) {
+    config.enable_fec(v);
+}
+
+#[no_mangle]
+pub extern fn quiche_config_set_fec_group_size(config: &mut Config, v: size_t) {
+    config.set_fec_group_size(v);
+}
+
+#[no_mangle]
+pub extern fn quiche_config_enable_reset_stream_at(config: &mut Config, v: bool) {
+    config.enable_reset_stream_at(v);
+}
+
+#[no_mangle]
+pub extern fn quiche_config_enable_grease_quic_bit(config: &mut Config, v: bool) {
+    config.enable_grease_quic_bit(v);
+}
+
 #[no_mangle]
 pub extern fn quiche_config_set_max_send_udp_payload_size(
     config: &mut Config, v: size_t,
@@ -373,6 +611,27 @@ pub extern fn quiche_config_set_max_stream_window(config: &mut Config, v: u64) {
     config.set_max_stream_window(v);
 }
 
+#[no_mangle]
+pub extern fn quiche_config_enable_rate_window_autotuning(
+    config: &mut Config, v: bool,
+) {
+    config.enable_rate_window_autotuning(v);
+}
+
+#[no_mangle]
+pub extern fn quiche_config_set_stream_send_watermarks(
+    config: &mut Config, low: size_t, high: size_t,
+) {
+    config.set_stream_send_watermarks(low, high);
+}
+
+#[no_mangle]
+pub extern fn quiche_config_set_send_buffer_high_watermark(
+    config: &mut Config, v: size_t,
+) {
+    config.set_send_buffer_high_watermark(v);
+}
+
 #[no_mangle]
 pub extern fn quiche_config_set_active_connection_id_limit(
     config: &mut Config, v: u64,
@@ -398,6 +657,65 @@ pub extern fn quiche_config_set_disable_dcid_reuse(config: &mut Config, v: bool)
     config.set_disable_dcid_reuse(v);
 }
 
+#[no_mangle]
+pub extern fn quiche_config_set_dcid_rotation_interval(
+    config: &mut Config, v: u64,
+) {
+    config.set_dcid_rotation_interval(v);
+}
+
+#[no_mangle]
+pub extern fn quiche_config_set_dcid_rotation_bytes(config: &mut Config, v: u64) {
+    config.set_dcid_rotation_bytes(v);
+}
+
+#[no_mangle]
+pub extern fn quiche_config_enable_dcid_rotation_retire_prior_to(
+    config: &mut Config, v: bool,
+) {
+    config.enable_dcid_rotation_retire_prior_to(v);
+}
+
+#[no_mangle]
+pub extern fn quiche_config_set_key_update_packets(config: &mut Config, v: u64) {
+    config.set_key_update_packets(v);
+}
+
+#[no_mangle]
+pub extern fn quiche_config_set_max_amplification_factor(
+    config: &mut Config, v: size_t,
+) {
+    config.set_max_amplification_factor(v);
+}
+
+#[no_mangle]
+pub extern fn quiche_config_set_new_path_policy(
+    config: &mut Config, v: NewPathPolicy,
+) {
+    config.set_new_path_policy(v);
+}
+
+#[no_mangle]
+pub extern fn quiche_config_set_migration_policy(
+    config: &mut Config, v: MigrationPolicy,
+) {
+    config.set_migration_policy(v);
+}
+
+#[no_mangle]
+pub extern fn quiche_config_set_coalescing_policy(
+    config: &mut Config, v: CoalescingPolicy,
+) {
+    config.set_coalescing_policy(v);
+}
+
+#[no_mangle]
+pub extern fn quiche_config_set_initial_padding_policy(
+    config: &mut Config, v: InitialPaddingPolicy,
+) {
+    config.set_initial_padding_policy(v);
+}
+
 #[no_mangle]
 pub extern fn quiche_config_set_ticket_key(
     config: &mut Config, key: *const u8, key_len: size_t,
@@ -558,6 +876,13 @@ pub extern fn quiche_version_is_supported(version: u32) -> bool {
     version_is_supported(version)
 }
 
+#[no_mangle]
+pub extern fn quiche_config_is_version_supported(
+    config: &Config, version: u32,
+) -> bool {
+    config.is_version_supported(version)
+}
+
 #[no_mangle]
 pub extern fn quiche_retry(
     scid: *const u8, scid_len: size_t, dcid: *const u8, dcid_len: size_t,
@@ -584,6 +909,7 @@ pub extern fn quiche_retry(
 }
 
 #[no_mangle]
+#[cfg(not(feature = "rustls"))]
 pub extern fn quiche_conn_new_with_tls(
     scid: *const u8, scid_len: size_t, odcid: *const u8, odcid_len: size_t,
     local: &sockaddr, local_len: socklen_t, peer: &sockaddr, peer_len: socklen_t,
@@ -651,6 +977,21 @@ pub extern fn quiche_conn_set_keylog_fd(conn: &mut Connection, fd: c_int) {
     conn.set_keylog(Box::new(writer));
 }
 
+#[no_mangle]
+#[cfg(feature = "qlog")]
+pub extern fn quiche_config_set_qlog_filter(
+    config: &mut Config, categories: *const QlogCategory, categories_len: size_t,
+    sampling_rate: u64,
+) {
+    let categories = if categories_len == 0 {
+        &[]
+    } else {
+        unsafe { slice::from_raw_parts(categories, categories_len) }
+    };
+
+    config.set_qlog_filter(categories, sampling_rate);
+}
+
 #[no_mangle]
 #[cfg(feature = "qlog")]
 pub extern fn quiche_conn_set_qlog_path(
@@ -748,6 +1089,24 @@ pub extern fn quiche_conn_recv(
     }
 }
 
+#[no_mangle]
+pub extern fn quiche_conn_recv_batch(
+    conn: &mut Connection, buf: *mut u8, buf_len: size_t, segment_size: size_t,
+    info: &RecvInfo,
+) -> ssize_t {
+    if buf_len > ssize_t::MAX as usize {
+        panic!("The provided buffer is too large");
+    }
+
+    let buf = unsafe { slice::from_raw_parts_mut(buf, buf_len) };
+
+    match conn.recv_batch(buf, segment_size, info.into()) {
+        Ok(v) => v as ssize_t,
+
+        Err(e) => e.to_c(),
+    }
+}
+
 #[repr(C)]
 pub struct SendInfo {
     from: sockaddr_storage,
@@ -782,6 +1141,31 @@ pub extern fn quiche_conn_send(
     }
 }
 
+#[no_mangle]
+pub extern fn quiche_conn_send_batch(
+    conn: &mut Connection, out: *mut u8, out_len: size_t, segment_size: size_t,
+    out_info: &mut SendInfo,
+) -> ssize_t {
+    if out_len > ssize_t::MAX as usize {
+        panic!("The provided buffer is too large");
+    }
+
+    let out = unsafe { slice::from_raw_parts_mut(out, out_len) };
+
+    match conn.send_batch(out, segment_size) {
+        Ok((v, info)) => {
+            out_info.from_len = std_addr_to_c(&info.from, &mut out_info.from);
+            out_info.to_len = std_addr_to_c(&info.to, &mut out_info.to);
+
+            std_time_to_c(&info.at, &mut out_info.at);
+
+            v as ssize_t
+        },
+
+        Err(e) => e.to_c(),
+    }
+}
+
 #[no_mangle]
 pub extern fn quiche_conn_send_on_path(
     conn: &mut Connection, out: *mut u8, out_len: size_t, from: *const sockaddr,
@@ -872,6 +1256,17 @@ pub extern fn quiche_conn_stream_shutdown(
     }
 }
 
+#[no_mangle]
+pub extern fn quiche_conn_stream_reset_at(
+    conn: &mut Connection, stream_id: u64, err: u64, reliable_size: u64,
+) -> c_int {
+    match conn.stream_reset_at(stream_id, err, reliable_size) {
+        Ok(_) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
 #[no_mangle]
 pub extern fn quiche_conn_stream_capacity(
     conn: &Connection, stream_id: u64,
@@ -908,6 +1303,17 @@ pub extern fn quiche_conn_stream_writable(
     }
 }
 
+#[no_mangle]
+pub extern fn quiche_conn_stream_send_watermarks(
+    conn: &mut Connection, stream_id: u64, low: size_t, high: size_t,
+) -> c_int {
+    match conn.stream_send_watermarks(stream_id, low, high) {
+        Ok(_) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
 #[no_mangle]
 pub extern fn quiche_conn_stream_writable_next(conn: &mut Connection) -> i64 {
     conn.stream_writable_next().map(|v| v as i64).unwrap_or(-1)
@@ -954,6 +1360,20 @@ pub extern fn quiche_conn_close(
     }
 }
 
+#[no_mangle]
+pub extern fn quiche_conn_close_with_drain(
+    conn: &mut Connection, app: bool, err: u64, reason: *const u8,
+    reason_len: size_t, drain: bool,
+) -> c_int {
+    let reason = unsafe { slice::from_raw_parts(reason, reason_len) };
+
+    match conn.close_with_drain(app, err, reason, drain) {
+        Ok(_) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
 #[no_mangle]
 pub extern fn quiche_conn_timeout_as_nanos(conn: &Connection) -> u64 {
     match conn.timeout() {
@@ -1064,6 +1484,31 @@ pub extern fn quiche_conn_application_proto(
     *out_len = proto.len();
 }
 
+#[no_mangle]
+pub extern fn quiche_conn_peer_application_settings(
+    conn: &Connection, out: &mut *const u8, out_len: &mut size_t,
+) -> bool {
+    match conn.peer_application_settings() {
+        Some(settings) => {
+            *out = settings.as_ptr();
+            *out_len = settings.len();
+
+            true
+        },
+
+        None => false,
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_cipher_suite(conn: &Connection) -> u16 {
+    match conn.handshake_info().cipher_suite {
+        Some(suite) => suite as u16,
+
+        None => 0,
+    }
+}
+
 #[no_mangle]
 pub extern fn quiche_conn_peer_cert(
     conn: &Connection, out: &mut *const u8, out_len: &mut size_t,
@@ -1107,11 +1552,51 @@ pub extern fn quiche_conn_is_in_early_data(conn: &Connection) -> bool {
     conn.is_in_early_data()
 }
 
+#[no_mangle]
+pub extern fn quiche_conn_export_keying_material(
+    conn: &Connection, label: *const u8, label_len: size_t, context: *const u8,
+    context_len: size_t, out: *mut u8, out_len: size_t,
+) -> c_int {
+    let label = unsafe { slice::from_raw_parts(label, label_len) };
+    let context = unsafe { slice::from_raw_parts(context, context_len) };
+    let out = unsafe { slice::from_raw_parts_mut(out, out_len) };
+
+    match conn.export_keying_material(label, context, out_len) {
+        Ok(v) => {
+            out.copy_from_slice(&v);
+            0
+        },
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_is_early_data_rejected(conn: &Connection) -> bool {
+    conn.is_early_data_rejected()
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_set_early_data_enabled(
+    conn: &mut Connection, v: bool,
+) -> c_int {
+    match conn.set_early_data_enabled(v) {
+        Ok(_) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
 #[no_mangle]
 pub extern fn quiche_conn_is_draining(conn: &Connection) -> bool {
     conn.is_draining()
 }
 
+#[no_mangle]
+pub extern fn quiche_conn_is_closing(conn: &Connection) -> bool {
+    conn.is_closing()
+}
+
 #[no_mangle]
 pub extern fn quiche_conn_is_closed(conn: &Connection) -> bool {
     conn.is_closed()
@@ -1208,6 +1693,8 @@ pub struct TransportParams {
     disable_active_migration: bool,
     active_conn_id_limit: u64,
     max_datagram_frame_size: ssize_t,
+    reset_stream_at: bool,
+    grease_quic_bit: bool,
 }
 
 #[no_mangle]
@@ -1257,6 +1744,26 @@ pub extern fn quiche_conn_peer_transport_params(
 
         Some(v) => v as ssize_t,
     };
+    out.reset_stream_at = tps.reset_stream_at;
+    out.grease_quic_bit = tps.grease_quic_bit;
+
+    true
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_peer_custom_transport_param(
+    conn: &Connection, id: u64, out: &mut *const u8, out_len: &mut size_t,
+) -> bool {
+    let value = match conn
+        .peer_transport_params()
+        .and_then(|t| t.custom_param(id))
+    {
+        Some(v) => v,
+        None => return false,
+    };
+
+    *out = value.as_ptr();
+    *out_len = value.len();
 
     true
 }
@@ -1376,6 +1883,29 @@ pub extern fn quiche_conn_dgram_send(
     }
 }
 
+#[no_mangle]
+pub extern fn quiche_conn_dgram_send_with_ttl(
+    conn: &mut Connection, buf: *const u8, buf_len: size_t, urgency: u8,
+    ttl_ms: u64,
+) -> ssize_t {
+    if buf_len > ssize_t::MAX as usize {
+        panic!("The provided buffer is too large");
+    }
+
+    let buf = unsafe { slice::from_raw_parts(buf, buf_len) };
+
+    let ttl = match ttl_ms {
+        0 => None,
+        v => Some(std::time::Duration::from_millis(v)),
+    };
+
+    match conn.dgram_send_with_ttl(buf, urgency, ttl) {
+        Ok(_) => buf_len as ssize_t,
+
+        Err(e) => e.to_c(),
+    }
+}
+
 #[no_mangle]
 pub extern fn quiche_conn_dgram_recv(
     conn: &mut Connection, out: *mut u8, out_len: size_t,
@@ -1438,6 +1968,23 @@ pub extern fn quiche_conn_send_ack_eliciting_on_path(
     }
 }
 
+#[no_mangle]
+pub extern fn quiche_conn_enable_keepalive(conn: &mut Connection, v: bool) {
+    conn.enable_keepalive(v);
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_probe_bandwidth(
+    conn: &mut Connection, duration_ms: u64,
+) {
+    conn.probe_bandwidth(std::time::Duration::from_millis(duration_ms));
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_is_probing_bandwidth(conn: &Connection) -> bool {
+    conn.is_probing_bandwidth()
+}
+
 #[no_mangle]
 pub extern fn quiche_conn_free(conn: *mut Connection) {
     drop(unsafe { Box::from_raw(conn) });
@@ -1453,11 +2000,26 @@ pub extern fn quiche_conn_peer_streams_left_uni(conn: &Connection) -> u64 {
     conn.peer_streams_left_uni()
 }
 
+#[no_mangle]
+pub extern fn quiche_conn_local_streams_left_bidi(conn: &Connection) -> u64 {
+    conn.local_streams_left_bidi()
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_local_streams_left_uni(conn: &Connection) -> u64 {
+    conn.local_streams_left_uni()
+}
+
 #[no_mangle]
 pub extern fn quiche_conn_send_quantum(conn: &Connection) -> size_t {
     conn.send_quantum() as size_t
 }
 
+#[no_mangle]
+pub extern fn quiche_conn_send_budget(conn: &Connection) -> size_t {
+    conn.send_budget() as size_t
+}
+
 #[no_mangle]
 pub extern fn quiche_conn_active_scids(conn: &Connection) -> size_t {
     conn.active_scids() as size_t
@@ -1492,6 +2054,27 @@ pub extern fn quiche_conn_new_scid(
     }
 }
 
+#[no_mangle]
+pub extern fn quiche_conn_set_preferred_address_cid(
+    conn: &mut Connection, scid: *const u8, scid_len: size_t,
+    reset_token: *const u8,
+) -> c_int {
+    let scid = unsafe { slice::from_raw_parts(scid, scid_len) };
+    let scid = ConnectionId::from_ref(scid);
+
+    let reset_token = unsafe { slice::from_raw_parts(reset_token, 16) };
+    let reset_token = match reset_token.try_into() {
+        Ok(rt) => rt,
+        Err(_) => unreachable!(),
+    };
+    let reset_token = u128::from_be_bytes(reset_token);
+
+    match conn.set_preferred_address_cid(&scid, reset_token) {
+        Ok(_) => 0,
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
 #[no_mangle]
 pub extern fn quiche_conn_retire_dcid(
     conn: &mut Connection, dcid_seq: u64,
@@ -1502,6 +2085,27 @@ pub extern fn quiche_conn_retire_dcid(
     }
 }
 
+#[no_mangle]
+pub extern fn quiche_conn_rotate_dcid(
+    conn: &mut Connection, dcid_seq: *mut u64,
+) -> c_int {
+    match conn.rotate_dcid() {
+        Ok(v) => {
+            unsafe { *dcid_seq = v }
+            0
+        },
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_initiate_key_update(conn: &mut Connection) -> c_int {
+    match conn.initiate_key_update() {
+        Ok(_) => 0,
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
 #[no_mangle]
 pub extern fn quiche_conn_available_dcids(conn: &Connection) -> size_t {
     conn.available_dcids() as size_t
@@ -1625,6 +2229,38 @@ pub extern fn quiche_conn_migrate(
     }
 }
 
+#[no_mangle]
+pub extern fn quiche_conn_migrate_after_validation(
+    conn: &mut Connection, local: &sockaddr, local_len: socklen_t,
+    peer: &sockaddr, peer_len: socklen_t, seq: *mut u64,
+) -> c_int {
+    let local = std_addr_from_c(local, local_len);
+    let peer = std_addr_from_c(peer, peer_len);
+    match conn.migrate_after_validation(local, peer) {
+        Ok(v) => {
+            unsafe { *seq = v }
+            0
+        },
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_migrate_with_policy(
+    conn: &mut Connection, local: &sockaddr, local_len: socklen_t,
+    peer: &sockaddr, peer_len: socklen_t, seq: *mut u64,
+) -> c_int {
+    let local = std_addr_from_c(local, local_len);
+    let peer = std_addr_from_c(peer, peer_len);
+    match conn.migrate_with_policy(local, peer) {
+        Ok(v) => {
+            unsafe { *seq = v }
+            0
+        },
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
 #[no_mangle]
 pub extern fn quiche_conn_path_event_next(
     conn: &mut Connection,
@@ -52,6 +52,12 @@ pub struct FlowControl {
 
     /// Last update time of max_data for autotuning the window.
     last_update: Option<Instant>,
+
+    /// Start time and consumed bytes of the current rate sample.
+    rate_sample: Option<(Instant, u64)>,
+
+    /// The latest measured consumption rate, in bytes per second.
+    consumption_rate: u64,
 }
 
 impl FlowControl {
@@ -77,6 +83,11 @@ impl FlowControl {
         self.max_data
     }
 
+    /// Returns the total consumed bytes.
+    pub fn consumed(&self) -> u64 {
+        self.consumed
+    }
+
     /// Update consumed bytes.
     pub fn add_consumed(&mut self, consumed: u64) {
         self.consumed += consumed;
@@ -117,6 +128,44 @@ impl FlowControl {
         }
     }
 
+    /// Autotune the window size based on the rate at which data is
+    /// consumed. Once every RTT the rate is sampled, and the window is
+    /// grown to twice the resulting bandwidth-delay product, capped by
+    /// max_window. The window never shrinks.
+    pub fn autotune_window_rate(&mut self, now: Instant, rtt: Duration) {
+        let (start, consumed) = match self.rate_sample {
+            Some(v) => v,
+
+            None => {
+                self.rate_sample = Some((now, self.consumed));
+                return;
+            },
+        };
+
+        let elapsed = now.saturating_duration_since(start);
+
+        if elapsed.is_zero() || elapsed < rtt {
+            return;
+        }
+
+        self.consumption_rate =
+            ((self.consumed - consumed) as f64 / elapsed.as_secs_f64()) as u64;
+
+        self.rate_sample = Some((now, self.consumed));
+
+        let bdp = (self.consumption_rate as f64 * rtt.as_secs_f64()) as u64;
+
+        self.window = std::cmp::min(
+            std::cmp::max(self.window, bdp * WINDOW_INCREASE_FACTOR),
+            self.max_window,
+        );
+    }
+
+    /// Returns the latest measured consumption rate, in bytes per second.
+    pub fn consumption_rate(&self) -> u64 {
+        self.consumption_rate
+    }
+
     /// Make sure the lower bound of the window is same to
     /// the current window.
     pub fn ensure_window_lower_bound(&mut self, min_window: u64) {
@@ -204,6 +253,41 @@ mod tests {
         assert_eq!(max_data_next, consumed + consumed_inc + w);
     }
 
+    #[test]
+    fn autotune_window_rate() {
+        let mut fc = FlowControl::new(100, 20, 1000);
+
+        let now = Instant::now();
+        let rtt = Duration::from_millis(100);
+
+        // The first call only starts a sample.
+        fc.autotune_window_rate(now, rtt);
+        assert_eq!(fc.window(), 20);
+
+        fc.add_consumed(50);
+
+        // Not enough time elapsed to take a sample.
+        fc.autotune_window_rate(now + rtt / 2, rtt);
+        assert_eq!(fc.window(), 20);
+        assert_eq!(fc.consumption_rate(), 0);
+
+        // 50 bytes in 100ms, so the BDP is 50 bytes.
+        fc.autotune_window_rate(now + rtt, rtt);
+        assert_eq!(fc.consumption_rate(), 500);
+        assert_eq!(fc.window(), 100);
+
+        // The window doesn't shrink when the rate drops.
+        fc.add_consumed(10);
+        fc.autotune_window_rate(now + rtt * 2, rtt);
+        assert_eq!(fc.consumption_rate(), 100);
+        assert_eq!(fc.window(), 100);
+
+        // The window is capped by max_window.
+        fc.add_consumed(5000);
+        fc.autotune_window_rate(now + rtt * 3, rtt);
+        assert_eq!(fc.window(), 1000);
+    }
+
     #[test]
     fn ensure_window_lower_bound() {
         let w = 20;
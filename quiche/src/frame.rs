@@ -46,6 +46,10 @@ pub const MAX_CRYPTO_OVERHEAD: usize = 8;
 pub const MAX_DGRAM_OVERHEAD: usize = 2;
 pub const MAX_STREAM_OVERHEAD: usize = 12;
 pub const MAX_STREAM_SIZE: u64 = 1 << 62;
+pub const MAX_REPAIR_OVERHEAD: usize = 20;
+
+/// Experimental frame type used for FEC repair symbols.
+pub const REPAIR_FRAME_TYPE: u64 = 0xfec0;
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct EcnCounts {
@@ -60,7 +64,12 @@ pub enum Frame {
         len: usize,
     },
 
-    Ping,
+    Ping {
+        // Attach metadata to the Ping frame. This doesn't appear on the wire,
+        // but does tell us if this frame was part of a PMTUD probe and how
+        // large the probe was.
+        mtu_probe: Option<usize>,
+    },
 
     ACK {
         ack_delay: u64,
@@ -74,6 +83,13 @@ pub enum Frame {
         final_size: u64,
     },
 
+    ResetStreamAt {
+        stream_id: u64,
+        error_code: u64,
+        final_size: u64,
+        reliable_size: u64,
+    },
+
     StopSending {
         stream_id: u64,
         error_code: u64,
@@ -177,11 +193,27 @@ pub enum Frame {
     DatagramHeader {
         length: usize,
     },
+
+    Repair {
+        first_pkt_num: u64,
+        count: u64,
+        data: Vec<u8>,
+    },
 }
 
 impl Frame {
     pub fn from_bytes(
         b: &mut octets::Octets, pkt: packet::Type,
+    ) -> Result<Frame> {
+        Frame::from_bytes_pooled(b, pkt, &mut stream::BufPool::default())
+    }
+
+    /// Parses a frame like [`from_bytes()`], taking the memory holding the
+    /// data of STREAM and CRYPTO frames from `pool`.
+    ///
+    /// [`from_bytes()`]: enum.Frame.html#method.from_bytes
+    pub fn from_bytes_pooled(
+        b: &mut octets::Octets, pkt: packet::Type, pool: &mut stream::BufPool,
     ) -> Result<Frame> {
         let frame_type = b.get_varint()?;
 
@@ -198,7 +230,7 @@ impl Frame {
                 Frame::Padding { len }
             },
 
-            0x01 => Frame::Ping,
+            0x01 => Frame::Ping { mtu_probe: None },
 
             0x02..=0x03 => parse_ack_frame(frame_type, b)?,
 
@@ -216,7 +248,7 @@ impl Frame {
             0x06 => {
                 let offset = b.get_varint()?;
                 let data = b.get_bytes_with_varint_length()?;
-                let data = stream::RangeBuf::from(data.as_ref(), offset, false);
+                let data = pool.range_buf(data.as_ref(), offset, false);
 
                 Frame::Crypto { data }
             },
@@ -232,7 +264,7 @@ impl Frame {
                 }
             },
 
-            0x08..=0x0f => parse_stream_frame(frame_type, b)?,
+            0x08..=0x0f => parse_stream_frame(frame_type, b, pool)?,
 
             0x10 => Frame::MaxData {
                 max: b.get_varint()?,
@@ -322,8 +354,16 @@ impl Frame {
 
             0x1e => Frame::HandshakeDone,
 
+            0x24 => parse_reset_stream_at_frame(b)?,
+
             0x30 | 0x31 => parse_datagram_frame(frame_type, b)?,
 
+            REPAIR_FRAME_TYPE => Frame::Repair {
+                first_pkt_num: b.get_varint()?,
+                count: b.get_varint()?,
+                data: b.get_bytes_with_varint_length()?.to_vec(),
+            },
+
             _ => return Err(Error::InvalidFrame),
         };
 
@@ -341,6 +381,9 @@ impl Frame {
             (packet::Type::ZeroRTT, Frame::RetireConnectionId { .. }) => false,
             (packet::Type::ZeroRTT, Frame::ConnectionClose { .. }) => false,
 
+            // REPAIR frames only protect 1-RTT packets.
+            (packet::Type::ZeroRTT, Frame::Repair { .. }) => false,
+
             // ACK, CRYPTO and CONNECTION_CLOSE can be sent on all other packet
             // types.
             (_, Frame::ACK { .. }) => true,
@@ -376,7 +419,7 @@ impl Frame {
                 }
             },
 
-            Frame::Ping => {
+            Frame::Ping { .. } => {
                 b.put_varint(0x01)?;
             },
 
@@ -432,6 +475,20 @@ impl Frame {
                 b.put_varint(*final_size)?;
             },
 
+            Frame::ResetStreamAt {
+                stream_id,
+                error_code,
+                final_size,
+                reliable_size,
+            } => {
+                b.put_varint(0x24)?;
+
+                b.put_varint(*stream_id)?;
+                b.put_varint(*error_code)?;
+                b.put_varint(*final_size)?;
+                b.put_varint(*reliable_size)?;
+            },
+
             Frame::StopSending {
                 stream_id,
                 error_code,
@@ -586,6 +643,19 @@ impl Frame {
             },
 
             Frame::DatagramHeader { .. } => (),
+
+            Frame::Repair {
+                first_pkt_num,
+                count,
+                data,
+            } => {
+                b.put_varint(REPAIR_FRAME_TYPE)?;
+
+                b.put_varint(*first_pkt_num)?;
+                b.put_varint(*count)?;
+                b.put_varint(data.len() as u64)?;
+                b.put_bytes(data.as_ref())?;
+            },
         }
 
         Ok(before - b.cap())
@@ -595,7 +665,7 @@ impl Frame {
         match self {
             Frame::Padding { len } => *len,
 
-            Frame::Ping => 1,
+            Frame::Ping { .. } => 1,
 
             Frame::ACK {
                 ack_delay,
@@ -645,6 +715,19 @@ impl Frame {
                 octets::varint_len(*final_size) // final_size
             },
 
+            Frame::ResetStreamAt {
+                stream_id,
+                error_code,
+                final_size,
+                reliable_size,
+            } => {
+                1 + // frame type
+                octets::varint_len(*stream_id) + // stream_id
+                octets::varint_len(*error_code) + // error_code
+                octets::varint_len(*final_size) + // final_size
+                octets::varint_len(*reliable_size) // reliable_size
+            },
+
             Frame::StopSending {
                 stream_id,
                 error_code,
@@ -801,6 +884,18 @@ impl Frame {
                 2 + // length, always encode as 2-byte varint
                 *length // data
             },
+
+            Frame::Repair {
+                first_pkt_num,
+                count,
+                data,
+            } => {
+                octets::varint_len(REPAIR_FRAME_TYPE) + // frame type
+                octets::varint_len(*first_pkt_num) + // first_pkt_num
+                octets::varint_len(*count) + // count
+                octets::varint_len(data.len() as u64) + // length
+                data.len() // data
+            },
         }
     }
 
@@ -870,6 +965,12 @@ impl Frame {
                 final_size: *final_size,
             },
 
+            Frame::ResetStreamAt { .. } => QuicFrame::Unknown {
+                raw_frame_type: 0x24,
+                frame_type_value: None,
+                raw: None,
+            },
+
             Frame::StopSending {
                 stream_id,
                 error_code,
@@ -1013,6 +1114,12 @@ impl Frame {
                 length: *length as u64,
                 raw: None,
             },
+
+            Frame::Repair { .. } => QuicFrame::Unknown {
+                raw_frame_type: REPAIR_FRAME_TYPE,
+                frame_type_value: None,
+                raw: None,
+            },
         }
     }
 }
@@ -1024,8 +1131,8 @@ impl std::fmt::Debug for Frame {
                 write!(f, "PADDING len={len}")?;
             },
 
-            Frame::Ping => {
-                write!(f, "PING")?;
+            Frame::Ping { mtu_probe } => {
+                write!(f, "PING mtu_probe={mtu_probe:?}")?;
             },
 
             Frame::ACK {
@@ -1050,6 +1157,18 @@ impl std::fmt::Debug for Frame {
                 )?;
             },
 
+            Frame::ResetStreamAt {
+                stream_id,
+                error_code,
+                final_size,
+                reliable_size,
+            } => {
+                write!(
+                    f,
+                    "RESET_STREAM_AT stream={stream_id} err={error_code:x} size={final_size} reliable_size={reliable_size}"
+                )?;
+            },
+
             Frame::StopSending {
                 stream_id,
                 error_code,
@@ -1180,6 +1299,18 @@ impl std::fmt::Debug for Frame {
             Frame::DatagramHeader { length } => {
                 write!(f, "DATAGRAM len={length}")?;
             },
+
+            Frame::Repair {
+                first_pkt_num,
+                count,
+                data,
+            } => {
+                write!(
+                    f,
+                    "REPAIR first_pn={first_pkt_num} count={count} len={}",
+                    data.len()
+                )?;
+            },
         }
 
         Ok(())
@@ -1296,7 +1427,9 @@ pub fn encode_dgram_header(length: u64, b: &mut octets::OctetsMut) -> Result<()>
     Ok(())
 }
 
-fn parse_stream_frame(ty: u64, b: &mut octets::Octets) -> Result<Frame> {
+fn parse_stream_frame(
+    ty: u64, b: &mut octets::Octets, pool: &mut stream::BufPool,
+) -> Result<Frame> {
     let first = ty as u8;
 
     let stream_id = b.get_varint()?;
@@ -1320,7 +1453,7 @@ fn parse_stream_frame(ty: u64, b: &mut octets::Octets) -> Result<Frame> {
     let fin = first & 0x01 != 0;
 
     let data = b.get_bytes(len)?;
-    let data = stream::RangeBuf::from(data.as_ref(), offset, fin);
+    let data = pool.range_buf(data.as_ref(), offset, fin);
 
     Ok(Frame::Stream { stream_id, data })
 }
@@ -1341,6 +1474,24 @@ fn parse_datagram_frame(ty: u64, b: &mut octets::Octets) -> Result<Frame> {
     })
 }
 
+fn parse_reset_stream_at_frame(b: &mut octets::Octets) -> Result<Frame> {
+    let stream_id = b.get_varint()?;
+    let error_code = b.get_varint()?;
+    let final_size = b.get_varint()?;
+    let reliable_size = b.get_varint()?;
+
+    if reliable_size > final_size {
+        return Err(Error::InvalidFrame);
+    }
+
+    Ok(Frame::ResetStreamAt {
+        stream_id,
+        error_code,
+        final_size,
+        reliable_size,
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -1375,7 +1526,7 @@ mod tests {
     fn ping() {
         let mut d = [42; 128];
 
-        let frame = Frame::Ping;
+        let frame = Frame::Ping { mtu_probe: None };
 
         let wire_len = {
             let mut b = octets::OctetsMut::with_slice(&mut d);
@@ -1506,6 +1657,57 @@ mod tests {
         assert!(Frame::from_bytes(&mut b, packet::Type::Handshake).is_err());
     }
 
+    #[test]
+    fn reset_stream_at() {
+        let mut d = [42; 128];
+
+        let frame = Frame::ResetStreamAt {
+            stream_id: 123_213,
+            error_code: 21_123_767,
+            final_size: 21_123_767,
+            reliable_size: 1_000,
+        };
+
+        let wire_len = {
+            let mut b = octets::OctetsMut::with_slice(&mut d);
+            frame.to_bytes(&mut b).unwrap()
+        };
+
+        assert_eq!(wire_len, 15);
+        assert_eq!(wire_len, frame.wire_len());
+
+        let mut b = octets::Octets::with_slice(&d);
+        assert_eq!(Frame::from_bytes(&mut b, packet::Type::Short), Ok(frame));
+
+        let mut b = octets::Octets::with_slice(&d);
+        assert!(Frame::from_bytes(&mut b, packet::Type::ZeroRTT).is_ok());
+
+        let mut b = octets::Octets::with_slice(&d);
+        assert!(Frame::from_bytes(&mut b, packet::Type::Initial).is_err());
+
+        let mut b = octets::Octets::with_slice(&d);
+        assert!(Frame::from_bytes(&mut b, packet::Type::Handshake).is_err());
+
+        // The reliable size can't be larger than the final size.
+        let frame = Frame::ResetStreamAt {
+            stream_id: 4,
+            error_code: 0,
+            final_size: 10,
+            reliable_size: 11,
+        };
+
+        let wire_len = {
+            let mut b = octets::OctetsMut::with_slice(&mut d);
+            frame.to_bytes(&mut b).unwrap()
+        };
+
+        let mut b = octets::Octets::with_slice(&d[..wire_len]);
+        assert_eq!(
+            Frame::from_bytes(&mut b, packet::Type::Short),
+            Err(Error::InvalidFrame)
+        );
+    }
+
     #[test]
     fn stop_sending() {
         let mut d = [42; 128];
@@ -2099,4 +2301,35 @@ mod tests {
 
         assert_eq!(frame_data, data);
     }
+
+    #[test]
+    fn repair() {
+        let mut d = [42; 128];
+
+        let frame = Frame::Repair {
+            first_pkt_num: 1234,
+            count: 8,
+            data: vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12],
+        };
+
+        let wire_len = {
+            let mut b = octets::OctetsMut::with_slice(&mut d);
+            frame.to_bytes(&mut b).unwrap()
+        };
+
+        assert_eq!(wire_len, 20);
+        assert_eq!(wire_len, frame.wire_len());
+
+        let mut b = octets::Octets::with_slice(&d);
+        assert_eq!(Frame::from_bytes(&mut b, packet::Type::Short), Ok(frame));
+
+        let mut b = octets::Octets::with_slice(&d);
+        assert!(Frame::from_bytes(&mut b, packet::Type::Initial).is_err());
+
+        let mut b = octets::Octets::with_slice(&d);
+        assert!(Frame::from_bytes(&mut b, packet::Type::ZeroRTT).is_err());
+
+        let mut b = octets::Octets::with_slice(&d);
+        assert!(Frame::from_bytes(&mut b, packet::Type::Handshake).is_err());
+    }
 }
@@ -0,0 +1,253 @@
+// Copyright (C) 2023, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! HTTP capsules (RFC 9297 Section 3).
+//!
+//! Capsules are carried on the DATA stream of a request once it has been
+//! upgraded, e.g. by an extended CONNECT request. Applications read the
+//! request body with [`recv_body()`] and feed it to a [`CapsuleDecoder`].
+//!
+//! [`recv_body()`]: ../struct.Connection.html#method.recv_body
+//! [`CapsuleDecoder`]: struct.CapsuleDecoder.html
+
+use super::Error;
+use super::Result;
+
+/// The type of the DATAGRAM capsule.
+pub const DATAGRAM_CAPSULE_TYPE_ID: u64 = 0x00;
+
+/// The default limit to the size of a single capsule's payload.
+const DEFAULT_MAX_CAPSULE_SIZE: usize = 65536;
+
+/// An HTTP capsule.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Capsule {
+    /// A DATAGRAM capsule, carrying an HTTP Datagram on the request stream.
+    Datagram {
+        /// The datagram's payload.
+        payload: Vec<u8>,
+    },
+
+    /// A capsule of a type unknown to quiche, which applications can
+    /// process or ignore.
+    Unknown {
+        /// The capsule's type.
+        ty: u64,
+
+        /// The capsule's payload.
+        payload: Vec<u8>,
+    },
+}
+
+impl Capsule {
+    /// Parses a capsule's payload of the given type.
+    pub fn from_bytes(ty: u64, payload: &[u8]) -> Capsule {
+        match ty {
+            DATAGRAM_CAPSULE_TYPE_ID => Capsule::Datagram {
+                payload: payload.to_vec(),
+            },
+
+            _ => Capsule::Unknown {
+                ty,
+                payload: payload.to_vec(),
+            },
+        }
+    }
+
+    /// Returns the capsule's type.
+    pub fn ty(&self) -> u64 {
+        match self {
+            Capsule::Datagram { .. } => DATAGRAM_CAPSULE_TYPE_ID,
+
+            Capsule::Unknown { ty, .. } => *ty,
+        }
+    }
+
+    fn payload(&self) -> &[u8] {
+        match self {
+            Capsule::Datagram { payload } => payload,
+
+            Capsule::Unknown { payload, .. } => payload,
+        }
+    }
+
+    /// Returns the length of the encoded capsule.
+    pub fn wire_len(&self) -> usize {
+        let len = self.payload().len();
+
+        octets::varint_len(self.ty()) + octets::varint_len(len as u64) + len
+    }
+
+    /// Encodes the capsule into the provided buffer.
+    ///
+    /// On success the number of bytes written is returned.
+    pub fn to_bytes(&self, out: &mut [u8]) -> Result<usize> {
+        let payload = self.payload();
+
+        let mut b = octets::OctetsMut::with_slice(out);
+
+        b.put_varint(self.ty())?;
+        b.put_varint(payload.len() as u64)?;
+        b.put_bytes(payload)?;
+
+        Ok(b.off())
+    }
+}
+
+/// Reassembles capsules from the bytes received on a request stream.
+pub struct CapsuleDecoder {
+    buf: Vec<u8>,
+
+    max_capsule_size: usize,
+}
+
+impl CapsuleDecoder {
+    /// Creates a new decoder.
+    pub fn new() -> CapsuleDecoder {
+        CapsuleDecoder {
+            buf: Vec::new(),
+
+            max_capsule_size: DEFAULT_MAX_CAPSULE_SIZE,
+        }
+    }
+
+    /// Sets the maximum size of a single capsule's payload.
+    ///
+    /// Larger capsules cause [`decode()`] to fail with [`DatagramError`].
+    ///
+    /// The default value is `65536`.
+    ///
+    /// [`decode()`]: struct.CapsuleDecoder.html#method.decode
+    /// [`DatagramError`]: ../enum.Error.html#variant.DatagramError
+    pub fn set_max_capsule_size(&mut self, v: usize) {
+        self.max_capsule_size = v;
+    }
+
+    /// Buffers data read from the request stream.
+    pub fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Decodes the next buffered capsule.
+    ///
+    /// [`Done`] is returned when a full capsule hasn't been received yet.
+    ///
+    /// [`Done`]: ../enum.Error.html#variant.Done
+    pub fn decode(&mut self) -> Result<Capsule> {
+        let mut b = octets::Octets::with_slice(&self.buf);
+
+        let ty = b.get_varint().map_err(|_| Error::Done)?;
+        let len = b.get_varint().map_err(|_| Error::Done)?;
+
+        if len > self.max_capsule_size as u64 {
+            return Err(Error::DatagramError);
+        }
+
+        let payload = b.get_bytes(len as usize).map_err(|_| Error::Done)?;
+
+        let capsule = Capsule::from_bytes(ty, payload.buf());
+
+        let off = b.off();
+        self.buf.drain(..off);
+
+        Ok(capsule)
+    }
+}
+
+impl Default for CapsuleDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn datagram_capsule() {
+        let capsule = Capsule::Datagram {
+            payload: vec![1, 2, 3, 4],
+        };
+
+        let mut d = [0; 16];
+        let len = capsule.to_bytes(&mut d).unwrap();
+        assert_eq!(len, capsule.wire_len());
+        assert_eq!(&d[..len], &[0x00, 0x04, 1, 2, 3, 4]);
+
+        let mut decoder = CapsuleDecoder::new();
+
+        // Partial capsules are buffered.
+        decoder.push(&d[..3]);
+        assert_eq!(decoder.decode(), Err(Error::Done));
+
+        decoder.push(&d[3..len]);
+        assert_eq!(decoder.decode(), Ok(capsule));
+        assert_eq!(decoder.decode(), Err(Error::Done));
+    }
+
+    #[test]
+    fn unknown_capsule() {
+        let unknown = Capsule::Unknown {
+            ty: 0x2a,
+            payload: vec![0xba; 10],
+        };
+
+        let dgram = Capsule::Datagram { payload: vec![] };
+
+        let mut d = [0; 32];
+        let mut len = unknown.to_bytes(&mut d).unwrap();
+        len += dgram.to_bytes(&mut d[len..]).unwrap();
+
+        let mut decoder = CapsuleDecoder::new();
+        decoder.push(&d[..len]);
+
+        assert_eq!(decoder.decode(), Ok(unknown));
+        assert_eq!(decoder.decode(), Ok(dgram));
+        assert_eq!(decoder.decode(), Err(Error::Done));
+    }
+
+    #[test]
+    fn capsule_too_large() {
+        let capsule = Capsule::Datagram {
+            payload: vec![0; 100],
+        };
+
+        let mut d = [0; 128];
+        let len = capsule.to_bytes(&mut d).unwrap();
+
+        let mut decoder = CapsuleDecoder::new();
+        decoder.set_max_capsule_size(99);
+        decoder.push(&d[..3]);
+
+        assert_eq!(decoder.decode(), Err(Error::DatagramError));
+
+        let mut decoder = CapsuleDecoder::new();
+        decoder.push(&d[..len]);
+
+        assert_eq!(decoder.decode(), Ok(capsule));
+    }
+}
@@ -56,6 +56,27 @@ pub extern fn quiche_h3_config_set_max_field_section_size(
     config.set_max_field_section_size(v);
 }
 
+#[no_mangle]
+pub extern fn quiche_h3_config_set_max_header_count(
+    config: &mut h3::Config, v: u64,
+) {
+    config.set_max_header_count(v);
+}
+
+#[no_mangle]
+pub extern fn quiche_h3_config_set_max_field_size(
+    config: &mut h3::Config, v: u64,
+) {
+    config.set_max_field_size(v);
+}
+
+#[no_mangle]
+pub extern fn quiche_h3_config_enable_strict_validation(
+    config: &mut h3::Config, v: bool,
+) {
+    config.enable_strict_validation(v);
+}
+
 #[no_mangle]
 pub extern fn quiche_h3_config_set_qpack_max_table_capacity(
     config: &mut h3::Config, v: u64,
@@ -77,6 +98,28 @@ pub extern fn quiche_h3_config_enable_extended_connect(
     config.enable_extended_connect(enabled);
 }
 
+#[no_mangle]
+pub extern fn quiche_h3_config_set_max_concurrent_requests(
+    config: &mut h3::Config, v: u64,
+) {
+    config.set_max_concurrent_requests(v);
+}
+
+#[no_mangle]
+pub extern fn quiche_h3_config_set_initial_body_credit(
+    config: &mut h3::Config, v: u64,
+) {
+    config.set_initial_body_credit(v);
+}
+
+#[no_mangle]
+#[cfg(feature = "sfv")]
+pub extern fn quiche_h3_config_enable_auto_priority(
+    config: &mut h3::Config, v: bool,
+) {
+    config.enable_auto_priority(v);
+}
+
 #[no_mangle]
 pub extern fn quiche_h3_config_free(config: *mut h3::Config) {
     drop(unsafe { Box::from_raw(config) });
@@ -148,6 +191,18 @@ pub extern fn quiche_h3_event_type(ev: &h3::Event) -> u32 {
         h3::Event::Reset { .. } => 4,
 
         h3::Event::PriorityUpdate { .. } => 5,
+
+        h3::Event::RequestRejected => 6,
+
+        h3::Event::InterimHeaders { .. } => 7,
+
+        h3::Event::Trailers { .. } => 8,
+
+        h3::Event::Drained => 9,
+
+        h3::Event::MessageError(_) => 10,
+
+        h3::Event::RequestCancelled => 11,
     }
 }
 
@@ -166,7 +221,9 @@ pub extern fn quiche_h3_event_for_each_header(
     argp: *mut c_void,
 ) -> c_int {
     match ev {
-        h3::Event::Headers { list, .. } =>
+        h3::Event::Headers { list, .. } |
+        h3::Event::InterimHeaders { list } |
+        h3::Event::Trailers { list } =>
             for h in list {
                 let rc = cb(
                     h.name().as_ptr(),
@@ -265,6 +322,34 @@ pub extern fn quiche_h3_send_response_with_priority(
     }
 }
 
+#[no_mangle]
+pub extern fn quiche_h3_send_interim_response(
+    conn: &mut h3::Connection, quic_conn: &mut Connection, stream_id: u64,
+    headers: *const Header, headers_len: size_t,
+) -> c_int {
+    let resp_headers = headers_from_ptr(headers, headers_len);
+
+    match conn.send_interim_response(quic_conn, stream_id, &resp_headers) {
+        Ok(_) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_h3_send_trailers(
+    conn: &mut h3::Connection, quic_conn: &mut Connection, stream_id: u64,
+    headers: *const Header, headers_len: size_t,
+) -> c_int {
+    let trailers = headers_from_ptr(headers, headers_len);
+
+    match conn.send_trailers(quic_conn, stream_id, &trailers) {
+        Ok(_) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
 #[no_mangle]
 pub extern fn quiche_h3_send_body(
     conn: &mut h3::Connection, quic_conn: &mut Connection, stream_id: u64,
@@ -301,6 +386,32 @@ pub extern fn quiche_h3_recv_body(
     }
 }
 
+#[no_mangle]
+pub extern fn quiche_h3_grant_body_credit(
+    conn: &mut h3::Connection, quic_conn: &mut Connection, stream_id: u64,
+    credit: u64,
+) -> c_int {
+    match conn.grant_body_credit(quic_conn, stream_id, credit) {
+        Ok(()) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_h3_reject_request_body(
+    conn: &mut h3::Connection, quic_conn: &mut Connection, stream_id: u64,
+    headers: *const Header, headers_len: size_t,
+) -> c_int {
+    let resp_headers = headers_from_ptr(headers, headers_len);
+
+    match conn.reject_request_body(quic_conn, stream_id, &resp_headers) {
+        Ok(()) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
 #[no_mangle]
 pub extern fn quiche_h3_send_goaway(
     conn: &mut h3::Connection, quic_conn: &mut Connection, id: u64,
@@ -312,6 +423,17 @@ pub extern fn quiche_h3_send_goaway(
     }
 }
 
+#[no_mangle]
+pub extern fn quiche_h3_cancel_request(
+    conn: &mut h3::Connection, quic_conn: &mut Connection, stream_id: u64,
+) -> c_int {
+    match conn.cancel_request(quic_conn, stream_id) {
+        Ok(()) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
 #[no_mangle]
 #[cfg(feature = "sfv")]
 pub extern fn quiche_h3_parse_extensible_priority(
@@ -374,6 +496,43 @@ pub extern fn quiche_h3_dgram_enabled_by_peer(
     conn.dgram_enabled_by_peer(quic_conn)
 }
 
+#[no_mangle]
+pub extern fn quiche_h3_send_dgram(
+    conn: &mut h3::Connection, quic_conn: &mut Connection, stream_id: u64,
+    buf: *const u8, buf_len: size_t,
+) -> c_int {
+    let buf = unsafe { slice::from_raw_parts(buf, buf_len) };
+
+    match conn.send_dgram(quic_conn, stream_id, buf) {
+        Ok(()) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_h3_recv_dgram(
+    conn: &mut h3::Connection, quic_conn: &mut Connection, stream_id: *mut u64,
+    payload_off: *mut size_t, out: *mut u8, out_len: size_t,
+) -> ssize_t {
+    if out_len > <ssize_t>::MAX as usize {
+        panic!("The provided buffer is too large");
+    }
+
+    let out = unsafe { slice::from_raw_parts_mut(out, out_len) };
+
+    match conn.recv_dgram(quic_conn, out) {
+        Ok((len, id, off)) => {
+            unsafe { *stream_id = id };
+            unsafe { *payload_off = off };
+
+            len as ssize_t
+        },
+
+        Err(e) => e.to_c(),
+    }
+}
+
 #[no_mangle]
 pub extern fn quiche_h3_conn_free(conn: *mut h3::Connection) {
     drop(unsafe { Box::from_raw(conn) });
@@ -35,6 +35,7 @@ pub const CANCEL_PUSH_FRAME_TYPE_ID: u64 = 0x3;
 pub const SETTINGS_FRAME_TYPE_ID: u64 = 0x4;
 pub const PUSH_PROMISE_FRAME_TYPE_ID: u64 = 0x5;
 pub const GOAWAY_FRAME_TYPE_ID: u64 = 0x7;
+pub const ORIGIN_FRAME_TYPE_ID: u64 = 0xC;
 pub const MAX_PUSH_FRAME_TYPE_ID: u64 = 0xD;
 pub const PRIORITY_UPDATE_FRAME_REQUEST_TYPE_ID: u64 = 0xF0700;
 pub const PRIORITY_UPDATE_FRAME_PUSH_TYPE_ID: u64 = 0xF0701;
@@ -86,6 +87,10 @@ pub enum Frame {
         push_id: u64,
     },
 
+    Origin {
+        origins: Vec<Vec<u8>>,
+    },
+
     PriorityUpdateRequest {
         prioritized_element_id: u64,
         priority_field_value: Vec<u8>,
@@ -136,6 +141,8 @@ impl Frame {
                 push_id: b.get_varint()?,
             },
 
+            ORIGIN_FRAME_TYPE_ID => parse_origin(payload_length, &mut b)?,
+
             PRIORITY_UPDATE_FRAME_REQUEST_TYPE_ID |
             PRIORITY_UPDATE_FRAME_PUSH_TYPE_ID =>
                 parse_priority_update(frame_type, payload_length, &mut b)?,
@@ -279,6 +286,18 @@ impl Frame {
                 b.put_varint(*push_id)?;
             },
 
+            Frame::Origin { origins } => {
+                let len: usize = origins.iter().map(|o| 2 + o.len()).sum();
+
+                b.put_varint(ORIGIN_FRAME_TYPE_ID)?;
+                b.put_varint(len as u64)?;
+
+                for origin in origins {
+                    b.put_u16(origin.len() as u16)?;
+                    b.put_bytes(origin)?;
+                }
+            },
+
             Frame::PriorityUpdateRequest {
                 prioritized_element_id,
                 priority_field_value,
@@ -397,6 +416,12 @@ impl Frame {
             Frame::MaxPushId { push_id } =>
                 Http3Frame::MaxPushId { push_id: *push_id },
 
+            // Qlog doesn't define the ORIGIN frame.
+            Frame::Origin { .. } => Http3Frame::Unknown {
+                frame_type_value: ORIGIN_FRAME_TYPE_ID,
+                raw: None,
+            },
+
             Frame::PriorityUpdateRequest {
                 prioritized_element_id,
                 priority_field_value,
@@ -483,6 +508,10 @@ impl std::fmt::Debug for Frame {
                 write!(f, "MAX_PUSH_ID push_id={push_id}")?;
             },
 
+            Frame::Origin { origins } => {
+                write!(f, "ORIGIN count={}", origins.len())?;
+            },
+
             Frame::PriorityUpdateRequest {
                 prioritized_element_id,
                 priority_field_value,
@@ -601,6 +630,20 @@ fn parse_push_promise(
     })
 }
 
+fn parse_origin(payload_length: u64, b: &mut octets::Octets) -> Result<Frame> {
+    let mut payload = b.get_bytes(payload_length as usize)?;
+
+    let mut origins = Vec::new();
+
+    while payload.cap() > 0 {
+        let len = payload.get_u16()?;
+
+        origins.push(payload.get_bytes(len as usize)?.to_vec());
+    }
+
+    Ok(Frame::Origin { origins })
+}
+
 fn parse_priority_update(
     frame_type: u64, payload_length: u64, b: &mut octets::Octets,
 ) -> Result<Frame> {
@@ -1187,6 +1230,46 @@ mod tests {
         );
     }
 
+    #[test]
+    fn origin() {
+        let mut d = [42; 128];
+
+        let frame = Frame::Origin {
+            origins: vec![
+                b"https://quic.tech".to_vec(),
+                b"https://cloudflare-quic.com:4433".to_vec(),
+            ],
+        };
+
+        let frame_payload_len = 2 + 17 + 2 + 32;
+        let frame_header_len = 2;
+
+        let wire_len = {
+            let mut b = octets::OctetsMut::with_slice(&mut d);
+            frame.to_bytes(&mut b).unwrap()
+        };
+
+        assert_eq!(wire_len, frame_header_len + frame_payload_len);
+
+        assert_eq!(
+            Frame::from_bytes(
+                ORIGIN_FRAME_TYPE_ID,
+                frame_payload_len as u64,
+                &d[frame_header_len..]
+            )
+            .unwrap(),
+            frame
+        );
+
+        // Truncated entries are rejected.
+        assert!(Frame::from_bytes(
+            ORIGIN_FRAME_TYPE_ID,
+            (frame_payload_len - 1) as u64,
+            &d[frame_header_len..]
+        )
+        .is_err());
+    }
+
     #[test]
     fn max_push_id() {
         let mut d = [42; 128];
@@ -68,6 +68,10 @@
 //! # Ok::<(), quiche::h3::Error>(())
 //! ```
 //!
+//! The QUIC connection is accessed through the [`Transport`] trait, so HTTP/3
+//! can also be run on top of QUIC implementations other than quiche's, by
+//! implementing the trait for them.
+//!
 //! ## Sending a request
 //!
 //! An HTTP/3 client can send a request by using the connection's
@@ -172,12 +176,24 @@
 //!             // Peer reset the stream, handle it.
 //!         },
 //!
+//!         Ok((stream_id, quiche::h3::Event::MessageError(cause))) => {
+//!             // Malformed message received, the stream was reset.
+//!         },
+//!
+//!         Ok((stream_id, quiche::h3::Event::RequestCancelled)) => {
+//!             // Peer cancelled the request, handle it.
+//!         },
+//!
 //!         Ok((_flow_id, quiche::h3::Event::PriorityUpdate)) => (),
 //!
 //!         Ok((goaway_id, quiche::h3::Event::GoAway)) => {
 //!              // Peer signalled it is going away, handle it.
 //!         },
 //!
+//!         Ok((_stream_id, quiche::h3::Event::RequestRejected)) => (),
+//!
+//!         Ok((_goaway_id, quiche::h3::Event::Drained)) => (),
+//!
 //!         Err(quiche::h3::Error::Done) => {
 //!             // Done reading.
 //!             break;
@@ -233,12 +249,24 @@
 //!             // Peer reset the stream, handle it.
 //!         },
 //!
+//!         Ok((stream_id, quiche::h3::Event::MessageError(cause))) => {
+//!             // Malformed message received, the stream was reset.
+//!         },
+//!
+//!         Ok((stream_id, quiche::h3::Event::RequestCancelled)) => {
+//!             // Peer cancelled the request, handle it.
+//!         },
+//!
 //!         Ok((_prioritized_element_id, quiche::h3::Event::PriorityUpdate)) => (),
 //!
 //!         Ok((goaway_id, quiche::h3::Event::GoAway)) => {
 //!              // Peer signalled it is going away, handle it.
 //!         },
 //!
+//!         Ok((_stream_id, quiche::h3::Event::RequestRejected)) => (),
+//!
+//!         Ok((_goaway_id, quiche::h3::Event::Drained)) => (),
+//!
 //!         Err(quiche::h3::Error::Done) => {
 //!             // Done reading.
 //!             break;
@@ -275,6 +303,7 @@
 //! [sending]: ../index.html#generating-outgoing-packets
 //! [receiving]: ../index.html#handling-incoming-packets
 //! [`with_transport()`]: struct.Connection.html#method.with_transport
+//! [`Transport`]: trait.Transport.html
 //! [`poll()`]: struct.Connection.html#method.poll
 //! [`Event`]: enum.Event.html
 //! [`Error`]: enum.Error.html
@@ -282,9 +311,10 @@
 //! [`send_response()`]: struct.Connection.html#method.send_response
 //! [`send_body()`]: struct.Connection.html#method.send_body
 
+use std::cmp;
+
 use std::collections::VecDeque;
 
-#[cfg(feature = "sfv")]
 use std::convert::TryFrom;
 use std::fmt;
 use std::fmt::Write;
@@ -308,10 +338,22 @@ use qlog::events::h3::Http3Frame;
 #[cfg(feature = "qlog")]
 use qlog::events::EventData;
 #[cfg(feature = "qlog")]
-use qlog::events::EventImportance;
-#[cfg(feature = "qlog")]
 use qlog::events::EventType;
 
+pub use transport::Transport;
+
+// Like `qlog_with_type!`, but gets the qlog streamer from the transport.
+macro_rules! qlog_with_transport {
+    ($ty:expr, $conn:expr, $qlog_streamer_ref:ident, $body:block) => {{
+        #[cfg(feature = "qlog")]
+        {
+            if let Some($qlog_streamer_ref) = $conn.qlog_streamer($ty) {
+                $body
+            }
+        }
+    }};
+}
+
 /// List of ALPN tokens of supported HTTP/3 versions.
 ///
 /// This can be passed directly to the [`Config::set_application_protos()`]
@@ -332,6 +374,13 @@ const PRIORITY_URGENCY_UPPER_BOUND: u8 = 7;
 const PRIORITY_URGENCY_DEFAULT: u8 = 3;
 const PRIORITY_INCREMENTAL_DEFAULT: bool = false;
 
+// Quarter Stream IDs larger than this can't map to a valid stream ID, see
+// RFC 9297 Section 2.1.
+const MAX_QUARTER_STREAM_ID: u64 = 1 << 60;
+
+// The maximum amount of body data read from a body source at once.
+const MAX_BODY_CHUNK_LEN: usize = 64 * 1024;
+
 #[cfg(feature = "qlog")]
 const QLOG_FRAME_CREATED: EventType =
     EventType::Http3EventType(Http3EventType::FrameCreated);
@@ -420,6 +469,12 @@ pub enum Error {
     /// The requested operation cannot be served over HTTP/3. Peer should retry
     /// over HTTP/1.1.
     VersionFallback,
+
+    /// An HTTP Datagram or capsule was malformed.
+    DatagramError,
+
+    /// The request can't be sent in early data because it is not replay-safe.
+    UnsafeEarlyRequest,
 }
 
 impl Error {
@@ -445,6 +500,8 @@ impl Error {
             Error::MessageError => 0x10E,
             Error::ConnectError => 0x10F,
             Error::VersionFallback => 0x110,
+            Error::DatagramError => 0x33,
+            Error::UnsafeEarlyRequest => 0xFF,
         }
     }
 
@@ -471,6 +528,8 @@ impl Error {
             Error::MessageError => -18,
             Error::ConnectError => -19,
             Error::VersionFallback => -20,
+            Error::DatagramError => -21,
+            Error::UnsafeEarlyRequest => -22,
 
             Error::TransportError(quic_error) => quic_error.to_c() - 1000,
         }
@@ -505,12 +564,165 @@ impl std::convert::From<octets::BufferTooShortError> for Error {
     }
 }
 
+/// HTTP/3 application error codes.
+///
+/// These are carried by application `CONNECTION_CLOSE`, `RESET_STREAM` and
+/// `STOP_SENDING` frames. Error codes received from the peer, for example in
+/// [`ConnectionError::error_code`], can be converted with `TryFrom`, and this
+/// can be converted into `u64` to close a connection or stream with a given
+/// code.
+///
+/// [`ConnectionError::error_code`]:
+/// ../struct.ConnectionError.html#structfield.error_code
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WireErrorCode {
+    /// No error, used when the connection or stream needs to be closed.
+    NoError,
+
+    /// Peer violated protocol requirements in a way that does not match a
+    /// more specific error code.
+    GeneralProtocolError,
+
+    /// An internal error has occurred in the HTTP stack.
+    InternalError,
+
+    /// The endpoint detected that its peer created a stream that it will not
+    /// accept.
+    StreamCreationError,
+
+    /// A stream required by the HTTP/3 connection was closed or reset.
+    ClosedCriticalStream,
+
+    /// A frame was received that is not permitted in the current state or on
+    /// the current stream.
+    FrameUnexpected,
+
+    /// A frame that fails to satisfy layout requirements or with an invalid
+    /// size was received.
+    FrameError,
+
+    /// The endpoint detected that its peer is exhibiting a behavior that might
+    /// be generating excessive load.
+    ExcessiveLoad,
+
+    /// A stream ID or push ID was used incorrectly.
+    IdError,
+
+    /// An endpoint detected an error in the payload of a SETTINGS frame.
+    SettingsError,
+
+    /// No SETTINGS frame was received at the beginning of the control stream.
+    MissingSettings,
+
+    /// A server rejected a request without performing any application
+    /// processing.
+    RequestRejected,
+
+    /// The request or its response is cancelled.
+    RequestCancelled,
+
+    /// The client's stream terminated without containing a fully formed
+    /// request.
+    RequestIncomplete,
+
+    /// An HTTP message was malformed and cannot be processed.
+    MessageError,
+
+    /// The TCP connection established in response to a CONNECT request was
+    /// reset or abnormally closed.
+    ConnectError,
+
+    /// The requested operation cannot be served over HTTP/3.
+    VersionFallback,
+
+    /// The decoder failed to interpret an encoded field section.
+    QpackDecompressionFailed,
+
+    /// The decoder failed to interpret an encoder instruction.
+    QpackEncoderStreamError,
+
+    /// The encoder failed to interpret a decoder instruction.
+    QpackDecoderStreamError,
+
+    /// An HTTP Datagram or capsule was malformed.
+    DatagramError,
+}
+
+impl TryFrom<u64> for WireErrorCode {
+    type Error = u64;
+
+    fn try_from(code: u64) -> std::result::Result<Self, u64> {
+        let code = match code {
+            0x100 => WireErrorCode::NoError,
+            0x101 => WireErrorCode::GeneralProtocolError,
+            0x102 => WireErrorCode::InternalError,
+            0x103 => WireErrorCode::StreamCreationError,
+            0x104 => WireErrorCode::ClosedCriticalStream,
+            0x105 => WireErrorCode::FrameUnexpected,
+            0x106 => WireErrorCode::FrameError,
+            0x107 => WireErrorCode::ExcessiveLoad,
+            0x108 => WireErrorCode::IdError,
+            0x109 => WireErrorCode::SettingsError,
+            0x10a => WireErrorCode::MissingSettings,
+            0x10b => WireErrorCode::RequestRejected,
+            0x10c => WireErrorCode::RequestCancelled,
+            0x10d => WireErrorCode::RequestIncomplete,
+            0x10e => WireErrorCode::MessageError,
+            0x10f => WireErrorCode::ConnectError,
+            0x110 => WireErrorCode::VersionFallback,
+            0x200 => WireErrorCode::QpackDecompressionFailed,
+            0x201 => WireErrorCode::QpackEncoderStreamError,
+            0x202 => WireErrorCode::QpackDecoderStreamError,
+            0x33 => WireErrorCode::DatagramError,
+            _ => return Err(code),
+        };
+
+        Ok(code)
+    }
+}
+
+impl From<WireErrorCode> for u64 {
+    fn from(code: WireErrorCode) -> u64 {
+        match code {
+            WireErrorCode::NoError => 0x100,
+            WireErrorCode::GeneralProtocolError => 0x101,
+            WireErrorCode::InternalError => 0x102,
+            WireErrorCode::StreamCreationError => 0x103,
+            WireErrorCode::ClosedCriticalStream => 0x104,
+            WireErrorCode::FrameUnexpected => 0x105,
+            WireErrorCode::FrameError => 0x106,
+            WireErrorCode::ExcessiveLoad => 0x107,
+            WireErrorCode::IdError => 0x108,
+            WireErrorCode::SettingsError => 0x109,
+            WireErrorCode::MissingSettings => 0x10a,
+            WireErrorCode::RequestRejected => 0x10b,
+            WireErrorCode::RequestCancelled => 0x10c,
+            WireErrorCode::RequestIncomplete => 0x10d,
+            WireErrorCode::MessageError => 0x10e,
+            WireErrorCode::ConnectError => 0x10f,
+            WireErrorCode::VersionFallback => 0x110,
+            WireErrorCode::QpackDecompressionFailed => 0x200,
+            WireErrorCode::QpackEncoderStreamError => 0x201,
+            WireErrorCode::QpackDecoderStreamError => 0x202,
+            WireErrorCode::DatagramError => 0x33,
+        }
+    }
+}
+
 /// An HTTP/3 configuration.
 pub struct Config {
     max_field_section_size: Option<u64>,
+    max_header_count: Option<u64>,
+    max_field_size: Option<u64>,
     qpack_max_table_capacity: Option<u64>,
     qpack_blocked_streams: Option<u64>,
     connect_protocol_enabled: Option<u64>,
+    max_concurrent_requests: Option<u64>,
+    initial_body_credit: Option<u64>,
+    non_idempotent_early_requests: bool,
+    strict_validation: bool,
+    #[cfg(feature = "sfv")]
+    auto_priority: bool,
 }
 
 impl Config {
@@ -518,25 +730,56 @@ impl Config {
     pub const fn new() -> Result<Config> {
         Ok(Config {
             max_field_section_size: None,
+            max_header_count: None,
+            max_field_size: None,
             qpack_max_table_capacity: None,
             qpack_blocked_streams: None,
             connect_protocol_enabled: None,
+            max_concurrent_requests: None,
+            initial_body_credit: None,
+            non_idempotent_early_requests: false,
+            strict_validation: false,
+            #[cfg(feature = "sfv")]
+            auto_priority: false,
         })
     }
 
     /// Sets the `SETTINGS_MAX_FIELD_SECTION_SIZE` setting.
     ///
-    /// By default no limit is enforced. When a request whose headers exceed
-    /// the limit set by the application is received, the call to the [`poll()`]
-    /// method will return the [`Error::ExcessiveLoad`] error, and the
-    /// connection will be closed.
+    /// By default no limit is enforced. When a message whose headers exceed
+    /// the limit set by the application is received, the stream is reset with
+    /// the `H3_MESSAGE_ERROR` error code, and the call to the [`poll()`] method
+    /// will return a [`Reset`] event for it.
     ///
     /// [`poll()`]: struct.Connection.html#method.poll
-    /// [`Error::ExcessiveLoad`]: enum.Error.html#variant.ExcessiveLoad
+    /// [`Reset`]: enum.Event.html#variant.Reset
     pub fn set_max_field_section_size(&mut self, v: u64) {
         self.max_field_section_size = Some(v);
     }
 
+    /// Sets the maximum number of fields in a received header section.
+    ///
+    /// Messages with more fields are handled like the ones exceeding the
+    /// [`set_max_field_section_size()`] limit. By default no limit is
+    /// enforced.
+    ///
+    /// [`set_max_field_section_size()`]: struct.Config.html#method.set_max_field_section_size
+    pub fn set_max_header_count(&mut self, v: u64) {
+        self.max_header_count = Some(v);
+    }
+
+    /// Sets the maximum size of a single field in a received header section,
+    /// calculated as the length of the name plus the length of the value.
+    ///
+    /// Messages with larger fields are handled like the ones exceeding the
+    /// [`set_max_field_section_size()`] limit. By default no limit is
+    /// enforced.
+    ///
+    /// [`set_max_field_section_size()`]: struct.Config.html#method.set_max_field_section_size
+    pub fn set_max_field_size(&mut self, v: u64) {
+        self.max_field_size = Some(v);
+    }
+
     /// Sets the `SETTINGS_QPACK_MAX_TABLE_CAPACITY` setting.
     ///
     /// The default value is `0`.
@@ -561,6 +804,98 @@ impl Config {
             self.connect_protocol_enabled = None;
         }
     }
+
+    /// Sets the maximum number of concurrent requests a server will process.
+    ///
+    /// When a client opens a new request stream while this many requests are
+    /// already in progress, the stream is refused with the
+    /// `H3_REQUEST_REJECTED` error code and [`poll()`] returns a
+    /// [`RequestRejected`] event for it. A request stops counting towards the
+    /// limit once its underlying QUIC stream is fully closed.
+    ///
+    /// This has no effect for clients. By default no limit is enforced.
+    ///
+    /// [`poll()`]: struct.Connection.html#method.poll
+    /// [`RequestRejected`]: enum.Event.html#variant.RequestRejected
+    pub fn set_max_concurrent_requests(&mut self, v: u64) {
+        self.max_concurrent_requests = Some(v);
+    }
+
+    /// Sets the amount of request body data a server application can read
+    /// for each request, before granting more explicitly.
+    ///
+    /// Once a request's credit is exhausted, [`recv_body()`] returns
+    /// [`Done`] and the request body is left unread, so the underlying QUIC
+    /// stream's flow control window is not extended and the client stops
+    /// sending. Applications grant more credit using [`grant_body_credit()`].
+    ///
+    /// This has no effect for clients. By default request bodies are not
+    /// limited.
+    ///
+    /// [`recv_body()`]: struct.Connection.html#method.recv_body
+    /// [`Done`]: enum.Error.html#variant.Done
+    /// [`grant_body_credit()`]: struct.Connection.html#method.grant_body_credit
+    pub fn set_initial_body_credit(&mut self, v: u64) {
+        self.initial_body_credit = Some(v);
+    }
+
+    /// Configures whether requests with non-idempotent methods can be sent in
+    /// early data.
+    ///
+    /// Early data can be replayed by an attacker, so by default
+    /// [`send_request_early()`] only allows idempotent methods, such as `GET`
+    /// or `PUT`. Applications that protect against replays themselves can
+    /// lift this restriction.
+    ///
+    /// The default value is `false`.
+    ///
+    /// [`send_request_early()`]: struct.Connection.html#method.send_request_early
+    pub fn enable_non_idempotent_early_requests(&mut self, v: bool) {
+        self.non_idempotent_early_requests = v;
+    }
+
+    /// Configures whether servers strictly validate received requests.
+    ///
+    /// When enabled, requests are rejected as malformed if:
+    ///
+    /// * pseudo-header fields follow regular fields,
+    /// * connection-specific fields, such as `connection` or
+    ///   `transfer-encoding`, are present,
+    /// * the `content-length` field is invalid, or doesn't match the length of
+    ///   the received body,
+    /// * the host in the `:authority` field doesn't match the server name
+    ///   requested by the client during the handshake.
+    ///
+    /// Malformed requests are reset with the `H3_MESSAGE_ERROR` error code,
+    /// and reported to the application with the [`MessageError`] event.
+    ///
+    /// The default value is `false`.
+    ///
+    /// [`MessageError`]: enum.Event.html#variant.MessageError
+    pub fn enable_strict_validation(&mut self, v: bool) {
+        self.strict_validation = v;
+    }
+
+    /// Configures whether servers apply request priorities automatically.
+    ///
+    /// When enabled, the [Extensible Priority] of a request, as signaled by
+    /// its `priority` header field or by PRIORITY_UPDATE frames, is applied
+    /// to the underlying QUIC stream with [`stream_priority()`], so that
+    /// responses are scheduled accordingly. A PRIORITY_UPDATE frame received
+    /// before the request headers takes precedence over the header field.
+    ///
+    /// [`PriorityUpdate`] events are still reported to the application.
+    ///
+    /// This has no effect for clients. The default value is `false`.
+    ///
+    /// [Extensible Priority]: https://www.rfc-editor.org/rfc/rfc9218.html#section-4.
+    /// [`stream_priority()`]: ../struct.Connection.html#method.stream_priority
+    /// [`PriorityUpdate`]: enum.Event.html#variant.PriorityUpdate
+    #[cfg(feature = "sfv")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "sfv")))]
+    pub fn enable_auto_priority(&mut self, v: bool) {
+        self.auto_priority = v;
+    }
 }
 
 /// A trait for types with associated string name and value.
@@ -660,6 +995,27 @@ pub enum Event {
         has_body: bool,
     },
 
+    /// Interim (1xx) response headers were received.
+    ///
+    /// This is only reported to clients, and can happen any number of times
+    /// before the final response's [`Headers`] event, e.g. for a 103 (Early
+    /// Hints) response.
+    ///
+    /// [`Headers`]: enum.Event.html#variant.Headers
+    InterimHeaders {
+        /// The list of received header fields.
+        list: Vec<Header>,
+    },
+
+    /// A trailer section was received.
+    ///
+    /// Trailers are sent after the headers and body, if any, so no other
+    /// data will be reported for the stream.
+    Trailers {
+        /// The list of received trailer fields.
+        list: Vec<Header>,
+    },
+
     /// Data was received.
     ///
     /// This indicates that the application can use the [`recv_body()`] method
@@ -681,6 +1037,24 @@ pub enum Event {
     /// The associated data represents the error code sent by the peer.
     Reset(u64),
 
+    /// A malformed message was received.
+    ///
+    /// The stream was reset with the `H3_MESSAGE_ERROR` error code, and no
+    /// further events will be reported for it. The associated data describes
+    /// why the message was rejected.
+    MessageError(MessageErrorCause),
+
+    /// The peer cancelled a request.
+    ///
+    /// The stream was reset with the `H3_REQUEST_CANCELLED` error code, for
+    /// example using [`cancel_request()`], and no further data will be
+    /// received on it. This is reported instead of a [`Reset`] event with
+    /// that error code.
+    ///
+    /// [`cancel_request()`]: struct.Connection.html#method.cancel_request
+    /// [`Reset`]: enum.Event.html#variant.Reset
+    RequestCancelled,
+
     /// PRIORITY_UPDATE was received.
     ///
     /// This indicates that the application can use the
@@ -698,6 +1072,64 @@ pub enum Event {
 
     /// GOAWAY was received.
     GoAway,
+
+    /// A request was rejected without being processed.
+    ///
+    /// At servers, this happens when the maximum number of concurrent
+    /// requests was reached, or when the request was received after a GOAWAY
+    /// was sent with a lower ID. The request stream was reset with the
+    /// `H3_REQUEST_REJECTED` error code.
+    ///
+    /// At clients, this happens when a GOAWAY is received from the server
+    /// with an ID lower than or equal to that of an outstanding request. The
+    /// request stream was cancelled.
+    ///
+    /// In both cases no further events will be reported for the stream, and
+    /// the client can safely retry the request on a new connection.
+    ///
+    /// See [`Config::set_max_concurrent_requests()`] and [`send_goaway()`].
+    ///
+    /// [`Config::set_max_concurrent_requests()`]: struct.Config.html#method.set_max_concurrent_requests
+    /// [`send_goaway()`]: struct.Connection.html#method.send_goaway
+    RequestRejected,
+
+    /// All the requests in progress when a GOAWAY was sent or received have
+    /// completed.
+    ///
+    /// No new requests will be processed on the connection, which can now be
+    /// closed using the transport's [`close()`] method. This event is only
+    /// reported once.
+    ///
+    /// [`close()`]: ../struct.Connection.html#method.close
+    Drained,
+}
+
+/// The reason why a received message was considered malformed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageErrorCause {
+    /// The field section exceeded the configured maximum size.
+    FieldSectionTooLarge,
+
+    /// The field section had more fields than the configured maximum.
+    TooManyFields,
+
+    /// A field exceeded the configured maximum size.
+    FieldTooLarge,
+
+    /// A pseudo-header field followed a regular field.
+    PseudoHeaderOrder,
+
+    /// A connection-specific field was present.
+    ConnectionSpecificField,
+
+    /// The `content-length` field was not a valid length.
+    InvalidContentLength,
+
+    /// The length of the body didn't match the `content-length` field.
+    ContentLengthMismatch,
+
+    /// The `:authority` host didn't match the requested server name.
+    AuthorityMismatch,
 }
 
 /// Extensible Priorities parameters.
@@ -812,6 +1244,73 @@ struct QpackStreams {
     pub decoder_stream_id: Option<u64>,
 }
 
+/// A source of HTTP/3 body data, pulled on demand.
+///
+/// Sources are attached to request streams with [`set_body_source()`], and
+/// are read by [`send_body_from_sources()`] as the streams gain capacity, so
+/// applications don't need to buffer the body themselves.
+///
+/// The trait is implemented for closures with the same signature as
+/// [`read_body()`], and for [`std::io::Read`] implementations wrapped in a
+/// [`ReadBodySource`].
+///
+/// [`set_body_source()`]: struct.Connection.html#method.set_body_source
+/// [`send_body_from_sources()`]: struct.Connection.html#method.send_body_from_sources
+/// [`read_body()`]: trait.BodySource.html#tymethod.read_body
+/// [`ReadBodySource`]: struct.ReadBodySource.html
+pub trait BodySource: Send + Sync {
+    /// Writes the next chunk of the body into `buf`.
+    ///
+    /// On success it returns the number of bytes written, and whether the end
+    /// of the body was reached. [`Done`] is returned when no data is available
+    /// yet, in which case the source is read again on the next call to
+    /// [`send_body_from_sources()`].
+    ///
+    /// [`Done`]: enum.Error.html#variant.Done
+    /// [`send_body_from_sources()`]: struct.Connection.html#method.send_body_from_sources
+    fn read_body(&mut self, buf: &mut [u8]) -> Result<(usize, bool)>;
+}
+
+impl<F> BodySource for F
+where
+    F: FnMut(&mut [u8]) -> Result<(usize, bool)> + Send + Sync,
+{
+    fn read_body(&mut self, buf: &mut [u8]) -> Result<(usize, bool)> {
+        self(buf)
+    }
+}
+
+/// A [`BodySource`] reading the body from a [`std::io::Read`] implementation,
+/// such as a file.
+///
+/// The end of the body is reached when the reader returns no data.
+///
+/// [`BodySource`]: trait.BodySource.html
+pub struct ReadBodySource<R>(pub R);
+
+impl<R: std::io::Read + Send + Sync> BodySource for ReadBodySource<R> {
+    fn read_body(&mut self, buf: &mut [u8]) -> Result<(usize, bool)> {
+        match self.0.read(buf) {
+            Ok(0) => Ok((0, true)),
+
+            Ok(v) => Ok((v, false)),
+
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock =>
+                Err(Error::Done),
+
+            Err(_) => Err(Error::InternalError),
+        }
+    }
+}
+
+/// A body source attached to a stream, with the data read from it that
+/// wasn't sent yet.
+struct PendingBody {
+    source: Box<dyn BodySource>,
+    buf: Vec<u8>,
+    fin: bool,
+}
+
 /// An HTTP/3 connection.
 pub struct Connection {
     is_server: bool,
@@ -837,10 +1336,39 @@ pub struct Connection {
 
     finished_streams: VecDeque<u64>,
 
+    readable_streams: Vec<u64>,
+
     frames_greased: bool,
 
     local_goaway_id: Option<u64>,
     peer_goaway_id: Option<u64>,
+
+    peer_origins: Option<Vec<Vec<u8>>>,
+
+    rejected_requests: VecDeque<u64>,
+
+    drained: bool,
+
+    max_concurrent_requests: Option<u64>,
+
+    initial_body_credit: Option<u64>,
+
+    max_header_count: Option<u64>,
+
+    max_field_size: Option<u64>,
+
+    non_idempotent_early_requests: bool,
+
+    strict_validation: bool,
+
+    early_requests: Vec<u64>,
+
+    early_rejection_cb: Option<Box<dyn FnMut(u64) -> bool + Send + Sync>>,
+
+    body_sources: crate::stream::StreamIdHashMap<PendingBody>,
+
+    #[cfg(feature = "sfv")]
+    auto_priority: bool,
 }
 
 impl Connection {
@@ -897,10 +1425,39 @@ impl Connection {
 
             finished_streams: VecDeque::new(),
 
+            readable_streams: Vec::new(),
+
             frames_greased: false,
 
             local_goaway_id: None,
             peer_goaway_id: None,
+
+            peer_origins: None,
+
+            rejected_requests: VecDeque::new(),
+
+            drained: false,
+
+            max_concurrent_requests: config.max_concurrent_requests,
+
+            initial_body_credit: config.initial_body_credit,
+
+            max_header_count: config.max_header_count,
+
+            max_field_size: config.max_field_size,
+
+            non_idempotent_early_requests: config.non_idempotent_early_requests,
+
+            strict_validation: config.strict_validation,
+
+            early_requests: Vec::new(),
+
+            early_rejection_cb: None,
+
+            body_sources: Default::default(),
+
+            #[cfg(feature = "sfv")]
+            auto_priority: config.auto_priority,
         })
     }
 
@@ -921,16 +1478,16 @@ impl Connection {
     /// [`StreamLimit`]: ../enum.Error.html#variant.StreamLimit
     /// [`InternalError`]: ../enum.Error.html#variant.InternalError
     pub fn with_transport(
-        conn: &mut super::Connection, config: &Config,
+        conn: &mut impl Transport, config: &Config,
     ) -> Result<Connection> {
-        let is_client = !conn.is_server;
+        let is_client = !conn.is_server();
         if is_client && !(conn.is_established() || conn.is_in_early_data()) {
             trace!("{} QUIC connection must be established or in early data before creating an HTTP/3 connection", conn.trace_id());
             return Err(Error::InternalError);
         }
 
         let mut http3_conn =
-            Connection::new(config, conn.is_server, conn.dgram_enabled())?;
+            Connection::new(config, conn.is_server(), conn.dgram_enabled())?;
 
         match http3_conn.send_settings(conn) {
             Ok(_) => (),
@@ -946,7 +1503,7 @@ impl Connection {
         http3_conn.open_qpack_encoder_stream(conn).ok();
         http3_conn.open_qpack_decoder_stream(conn).ok();
 
-        if conn.grease {
+        if conn.grease() {
             // Try opening a GREASE stream, but ignore errors since it's not
             // critical.
             http3_conn.open_grease_stream(conn).ok();
@@ -972,7 +1529,7 @@ impl Connection {
     /// [`send_body()`]: struct.Connection.html#method.send_body
     /// [`StreamBlocked`]: enum.Error.html#variant.StreamBlocked
     pub fn send_request<T: NameValue>(
-        &mut self, conn: &mut super::Connection, headers: &[T], fin: bool,
+        &mut self, conn: &mut impl Transport, headers: &[T], fin: bool,
     ) -> Result<u64> {
         // If we received a GOAWAY from the peer, MUST NOT initiate new
         // requests.
@@ -1011,48 +1568,159 @@ impl Connection {
         Ok(stream_id)
     }
 
-    /// Sends an HTTP/3 response on the specified stream with default priority.
+    /// Sends an HTTP/3 request in early data.
     ///
-    /// This method sends the provided `headers` without a body. To include a
-    /// body, set `fin` as `false` and subsequently call [`send_body()`] with
-    /// the same `conn` and `stream_id`.
+    /// This works like [`send_request()`], but can be used while the
+    /// transport is still performing the handshake and
+    /// [`is_in_early_data()`] returns `true`. In that case, since early data
+    /// can be replayed, only requests with idempotent methods are allowed,
+    /// unless [`Config::enable_non_idempotent_early_requests()`] was called,
+    /// and [`UnsafeEarlyRequest`] is returned otherwise.
     ///
-    /// The [`StreamBlocked`] error is returned when the underlying QUIC stream
-    /// doesn't have enough capacity for the operation to complete. When this
-    /// happens the application should retry the operation once the stream is
-    /// reported as writable again.
+    /// If the server rejects early data, the request is sent again after the
+    /// handshake completes. The callback set with
+    /// [`set_early_rejection_callback()`] can be used to decide which
+    /// requests are resubmitted.
     ///
-    /// [`send_body()`]: struct.Connection.html#method.send_body
-    /// [`StreamBlocked`]: enum.Error.html#variant.StreamBlocked
-    pub fn send_response<T: NameValue>(
-        &mut self, conn: &mut super::Connection, stream_id: u64, headers: &[T],
-        fin: bool,
-    ) -> Result<()> {
-        let priority = Default::default();
+    /// Once the handshake is completed this is the same as calling
+    /// [`send_request()`].
+    ///
+    /// [`send_request()`]: struct.Connection.html#method.send_request
+    /// [`is_in_early_data()`]: ../struct.Connection.html#method.is_in_early_data
+    /// [`Config::enable_non_idempotent_early_requests()`]: struct.Config.html#method.enable_non_idempotent_early_requests
+    /// [`UnsafeEarlyRequest`]: enum.Error.html#variant.UnsafeEarlyRequest
+    /// [`set_early_rejection_callback()`]: struct.Connection.html#method.set_early_rejection_callback
+    pub fn send_request_early<T: NameValue>(
+        &mut self, conn: &mut impl Transport, headers: &[T], fin: bool,
+    ) -> Result<u64> {
+        let is_early = !conn.is_established();
 
-        self.send_response_with_priority(
-            conn, stream_id, headers, &priority, fin,
-        )?;
+        if is_early {
+            if !conn.is_in_early_data() {
+                return Err(Error::Done);
+            }
 
-        Ok(())
+            if !self.non_idempotent_early_requests &&
+                !is_idempotent_request(headers)
+            {
+                return Err(Error::UnsafeEarlyRequest);
+            }
+        }
+
+        let stream_id = self.send_request(conn, headers, fin)?;
+
+        if is_early {
+            self.early_requests.push(stream_id);
+        }
+
+        Ok(stream_id)
     }
 
-    /// Sends an HTTP/3 response on the specified stream with specified
-    /// priority.
+    /// Sets the callback invoked when the server rejects early data.
     ///
-    /// The `priority` parameter represents [Extensible Priority]
-    /// parameters. If the urgency is outside the range 0-7, it will be clamped
-    /// to 7.
+    /// The callback is called once the handshake completes, with the stream
+    /// ID of each request sent with [`send_request_early()`] while in early
+    /// data. When it returns `true` the request is resubmitted, otherwise it
+    /// is cancelled with the `H3_REQUEST_CANCELLED` error code, and no further
+    /// events are reported for it.
     ///
-    /// The [`StreamBlocked`] error is returned when the underlying QUIC stream
-    /// doesn't have enough capacity for the operation to complete. When this
-    /// happens the application should retry the operation once the stream is
-    /// reported as writable again.
+    /// By default all the requests are resubmitted.
     ///
-    /// [`StreamBlocked`]: enum.Error.html#variant.StreamBlocked
+    /// [`send_request_early()`]: struct.Connection.html#method.send_request_early
+    pub fn set_early_rejection_callback<F>(&mut self, cb: F)
+    where
+        F: FnMut(u64) -> bool + Send + Sync + 'static,
+    {
+        self.early_rejection_cb = Some(Box::new(cb));
+    }
+
+    /// Processes the requests sent in early data once the handshake
+    /// completes.
+    fn process_early_requests(&mut self, conn: &mut impl Transport) {
+        if self.early_requests.is_empty() || !conn.is_established() {
+            return;
+        }
+
+        let early_requests = std::mem::take(&mut self.early_requests);
+
+        // Stream data lost with the early data is retransmitted by the
+        // transport, so there's nothing to do unless the application wants to
+        // cancel some of the requests.
+        if !conn.is_early_data_rejected() {
+            return;
+        }
+
+        trace!(
+            "{} early data rejected, resubmitting {} requests",
+            conn.trace_id(),
+            early_requests.len()
+        );
+
+        let cb = match self.early_rejection_cb.as_mut() {
+            Some(v) => v,
+
+            None => return,
+        };
+
+        let err = Error::RequestCancelled.to_wire();
+
+        for stream_id in early_requests {
+            if !self.streams.contains_key(&stream_id) || cb(stream_id) {
+                continue;
+            }
+
+            self.streams.remove(&stream_id);
+
+            // The stream might have been already closed, so ignore errors.
+            conn.stream_shutdown(stream_id, crate::Shutdown::Read, err)
+                .ok();
+            conn.stream_shutdown(stream_id, crate::Shutdown::Write, err)
+                .ok();
+        }
+    }
+
+    /// Sends an HTTP/3 response on the specified stream with default priority.
+    ///
+    /// This method sends the provided `headers` without a body. To include a
+    /// body, set `fin` as `false` and subsequently call [`send_body()`] with
+    /// the same `conn` and `stream_id`.
+    ///
+    /// The [`StreamBlocked`] error is returned when the underlying QUIC stream
+    /// doesn't have enough capacity for the operation to complete. When this
+    /// happens the application should retry the operation once the stream is
+    /// reported as writable again.
+    ///
+    /// [`send_body()`]: struct.Connection.html#method.send_body
+    /// [`StreamBlocked`]: enum.Error.html#variant.StreamBlocked
+    pub fn send_response<T: NameValue>(
+        &mut self, conn: &mut impl Transport, stream_id: u64, headers: &[T],
+        fin: bool,
+    ) -> Result<()> {
+        let priority = Default::default();
+
+        self.send_response_with_priority(
+            conn, stream_id, headers, &priority, fin,
+        )?;
+
+        Ok(())
+    }
+
+    /// Sends an HTTP/3 response on the specified stream with specified
+    /// priority.
+    ///
+    /// The `priority` parameter represents [Extensible Priority]
+    /// parameters. If the urgency is outside the range 0-7, it will be clamped
+    /// to 7.
+    ///
+    /// The [`StreamBlocked`] error is returned when the underlying QUIC stream
+    /// doesn't have enough capacity for the operation to complete. When this
+    /// happens the application should retry the operation once the stream is
+    /// reported as writable again.
+    ///
+    /// [`StreamBlocked`]: enum.Error.html#variant.StreamBlocked
     /// [Extensible Priority]: https://www.rfc-editor.org/rfc/rfc9218.html#section-4.
     pub fn send_response_with_priority<T: NameValue>(
-        &mut self, conn: &mut super::Connection, stream_id: u64, headers: &[T],
+        &mut self, conn: &mut impl Transport, stream_id: u64, headers: &[T],
         priority: &Priority, fin: bool,
     ) -> Result<()> {
         if !self.streams.contains_key(&stream_id) {
@@ -1072,6 +1740,86 @@ impl Connection {
         Ok(())
     }
 
+    /// Sends an HTTP/3 interim response on the specified stream.
+    ///
+    /// The `:status` pseudo-header in `headers` must be an informational
+    /// (1xx) status code other than 101, such as 103 (Early Hints). Any number
+    /// of interim responses can be sent before the final response is sent
+    /// with [`send_response()`].
+    ///
+    /// The [`FrameUnexpected`] error is returned when called by a client, or
+    /// after the final response was already sent, and [`MessageError`] is
+    /// returned when the status code is not a valid informational one.
+    ///
+    /// The [`StreamBlocked`] error is returned when the underlying QUIC stream
+    /// doesn't have enough capacity for the operation to complete. When this
+    /// happens the application should retry the operation once the stream is
+    /// reported as writable again.
+    ///
+    /// [`send_response()`]: struct.Connection.html#method.send_response
+    /// [`FrameUnexpected`]: enum.Error.html#variant.FrameUnexpected
+    /// [`MessageError`]: enum.Error.html#variant.MessageError
+    /// [`StreamBlocked`]: enum.Error.html#variant.StreamBlocked
+    pub fn send_interim_response<T: NameValue>(
+        &mut self, conn: &mut impl Transport, stream_id: u64, headers: &[T],
+    ) -> Result<()> {
+        if !self.is_server {
+            return Err(Error::FrameUnexpected);
+        }
+
+        match self.streams.get(&stream_id) {
+            Some(s) if !s.local_initialized() => (),
+
+            _ => return Err(Error::FrameUnexpected),
+        }
+
+        match status_code(headers) {
+            Some(status) if (100..200).contains(&status) && status != 101 => (),
+
+            _ => return Err(Error::MessageError),
+        }
+
+        self.send_header_block(conn, stream_id, headers, false)
+    }
+
+    /// Sends a trailer section on the specified stream.
+    ///
+    /// Trailers can only be sent after the request or response headers, and
+    /// they terminate the stream, so no more body data can be sent after
+    /// them. Trailers must not include pseudo-headers.
+    ///
+    /// The [`FrameUnexpected`] error is returned when the headers weren't sent
+    /// yet, or when trailers were already sent on the stream.
+    ///
+    /// The [`StreamBlocked`] error is returned when the underlying QUIC stream
+    /// doesn't have enough capacity for the operation to complete. When this
+    /// happens the application should retry the operation once the stream is
+    /// reported as writable again.
+    ///
+    /// [`FrameUnexpected`]: enum.Error.html#variant.FrameUnexpected
+    /// [`StreamBlocked`]: enum.Error.html#variant.StreamBlocked
+    pub fn send_trailers<T: NameValue>(
+        &mut self, conn: &mut impl Transport, stream_id: u64, headers: &[T],
+    ) -> Result<()> {
+        match self.streams.get(&stream_id) {
+            Some(s) if s.local_initialized() && !s.trailers_sent() => (),
+
+            _ => return Err(Error::FrameUnexpected),
+        }
+
+        if headers.iter().any(|h| h.name().starts_with(b":")) {
+            return Err(Error::MessageError);
+        }
+
+        self.send_header_block(conn, stream_id, headers, true)?;
+
+        if let Some(s) = self.streams.get_mut(&stream_id) {
+            s.set_trailers_sent();
+        }
+
+        Ok(())
+    }
+
     fn encode_header_block<T: NameValue>(
         &mut self, headers: &[T],
     ) -> Result<Vec<u8>> {
@@ -1091,13 +1839,32 @@ impl Connection {
     }
 
     fn send_headers<T: NameValue>(
-        &mut self, conn: &mut super::Connection, stream_id: u64, headers: &[T],
+        &mut self, conn: &mut impl Transport, stream_id: u64, headers: &[T],
+        fin: bool,
+    ) -> Result<()> {
+        if let Some(s) = self.streams.get(&stream_id) {
+            if s.trailers_sent() {
+                return Err(Error::FrameUnexpected);
+            }
+        }
+
+        self.send_header_block(conn, stream_id, headers, fin)?;
+
+        if let Some(s) = self.streams.get_mut(&stream_id) {
+            s.initialize_local();
+        }
+
+        Ok(())
+    }
+
+    fn send_header_block<T: NameValue>(
+        &mut self, conn: &mut impl Transport, stream_id: u64, headers: &[T],
         fin: bool,
     ) -> Result<()> {
         let mut d = [42; 10];
         let mut b = octets::OctetsMut::with_slice(&mut d);
 
-        if !self.frames_greased && conn.grease {
+        if !self.frames_greased && conn.grease() {
             self.send_grease_frames(conn, stream_id)?;
             self.frames_greased = true;
         }
@@ -1139,7 +1906,7 @@ impl Connection {
             fin
         );
 
-        qlog_with_type!(QLOG_FRAME_CREATED, conn.qlog, q, {
+        qlog_with_transport!(QLOG_FRAME_CREATED, conn, q, {
             let qlog_headers = headers
                 .iter()
                 .map(|h| qlog::events::h3::HttpHeader {
@@ -1161,10 +1928,6 @@ impl Connection {
             q.add_event_data_now(ev_data).ok();
         });
 
-        if let Some(s) = self.streams.get_mut(&stream_id) {
-            s.initialize_local();
-        }
-
         if fin && conn.stream_finished(stream_id) {
             self.streams.remove(&stream_id);
         }
@@ -1187,7 +1950,7 @@ impl Connection {
     ///
     /// [`Done`]: enum.Error.html#variant.Done
     pub fn send_body(
-        &mut self, conn: &mut super::Connection, stream_id: u64, body: &[u8],
+        &mut self, conn: &mut impl Transport, stream_id: u64, body: &[u8],
         fin: bool,
     ) -> Result<usize> {
         let mut d = [42; 10];
@@ -1265,7 +2028,7 @@ impl Connection {
             fin
         );
 
-        qlog_with_type!(QLOG_FRAME_CREATED, conn.qlog, q, {
+        qlog_with_transport!(QLOG_FRAME_CREATED, conn, q, {
             let frame = Http3Frame::Data { raw: None };
             let ev_data = EventData::H3FrameCreated(H3FrameCreated {
                 stream_id,
@@ -1294,6 +2057,136 @@ impl Connection {
         Ok(written)
     }
 
+    /// Attaches a body source to the given stream.
+    ///
+    /// The body is then sent by [`send_body_from_sources()`] as the stream
+    /// gains capacity, instead of the application calling [`send_body()`]
+    /// itself. Attaching a new source replaces the previous one, and the
+    /// source is dropped once the end of the body is sent.
+    ///
+    /// The [`FrameUnexpected`] error is returned when the stream can't carry
+    /// a body, e.g. because headers were not sent on it yet.
+    ///
+    /// [`send_body_from_sources()`]: struct.Connection.html#method.send_body_from_sources
+    /// [`send_body()`]: struct.Connection.html#method.send_body
+    /// [`FrameUnexpected`]: enum.Error.html#variant.FrameUnexpected
+    pub fn set_body_source<S: BodySource + 'static>(
+        &mut self, stream_id: u64, source: S,
+    ) -> Result<()> {
+        if stream_id % 4 != 0 {
+            return Err(Error::FrameUnexpected);
+        }
+
+        match self.streams.get(&stream_id) {
+            Some(s) if s.local_initialized() => (),
+
+            _ => return Err(Error::FrameUnexpected),
+        }
+
+        self.body_sources.insert(stream_id, PendingBody {
+            source: Box::new(source),
+            buf: Vec::new(),
+            fin: false,
+        });
+
+        Ok(())
+    }
+
+    /// Sends body data pulled from the sources attached with
+    /// [`set_body_source()`], as far as stream capacity allows.
+    ///
+    /// This should be called whenever streams become writable, e.g. after
+    /// processing incoming packets. Data is only read from a source when it
+    /// can be sent right away.
+    ///
+    /// On success the total number of body bytes written is returned. When a
+    /// source fails, it is detached from its stream and the error is
+    /// returned.
+    ///
+    /// [`set_body_source()`]: struct.Connection.html#method.set_body_source
+    pub fn send_body_from_sources(
+        &mut self, conn: &mut impl Transport,
+    ) -> Result<usize> {
+        let mut done = 0;
+
+        let stream_ids: Vec<u64> = self.body_sources.keys().copied().collect();
+
+        for stream_id in stream_ids {
+            let mut body = match self.body_sources.remove(&stream_id) {
+                Some(v) => v,
+
+                None => continue,
+            };
+
+            loop {
+                if body.buf.is_empty() && !body.fin {
+                    let cap = match conn.stream_capacity(stream_id) {
+                        Ok(v) => v,
+
+                        // The stream is gone, so drop its source.
+                        Err(_) => break,
+                    };
+
+                    // Leave room for the DATA frame header.
+                    let overhead = octets::varint_len(frame::DATA_FRAME_TYPE_ID) +
+                        octets::varint_len(cap as u64);
+
+                    if cap <= overhead {
+                        let _ = conn.stream_writable(stream_id, overhead + 1);
+                        self.body_sources.insert(stream_id, body);
+                        break;
+                    }
+
+                    let len = std::cmp::min(cap - overhead, MAX_BODY_CHUNK_LEN);
+
+                    body.buf.resize(len, 0);
+
+                    match body.source.read_body(&mut body.buf) {
+                        Ok((len, fin)) => {
+                            body.buf.truncate(len);
+                            body.fin = fin;
+                        },
+
+                        Err(Error::Done) => {
+                            body.buf.clear();
+                            self.body_sources.insert(stream_id, body);
+                            break;
+                        },
+
+                        Err(e) => return Err(e),
+                    }
+                }
+
+                let written =
+                    match self.send_body(conn, stream_id, &body.buf, body.fin) {
+                        Ok(v) => v,
+
+                        Err(Error::Done) => {
+                            self.body_sources.insert(stream_id, body);
+                            break;
+                        },
+
+                        Err(e) => return Err(e),
+                    };
+
+                done += written;
+
+                body.buf.drain(..written);
+
+                if body.buf.is_empty() && body.fin {
+                    break;
+                }
+
+                if !body.buf.is_empty() {
+                    self.body_sources.insert(stream_id, body);
+                    break;
+                }
+            }
+        }
+
+        Ok(done)
+    }
+
     /// Returns whether the peer enabled HTTP/3 DATAGRAM frame support.
     ///
     /// Support is signalled by the peer's SETTINGS, so this method always
@@ -1301,11 +2194,78 @@ impl Connection {
     /// method.
     ///
     /// [`poll()`]: struct.Connection.html#method.poll
-    pub fn dgram_enabled_by_peer(&self, conn: &super::Connection) -> bool {
+    pub fn dgram_enabled_by_peer(&self, conn: &impl Transport) -> bool {
         self.peer_settings.h3_datagram == Some(1) &&
             conn.dgram_max_writable_len().is_some()
     }
 
+    /// Sends an HTTP Datagram associated with the given request stream.
+    ///
+    /// The datagram is prefixed with the Quarter Stream ID of the request
+    /// stream (RFC 9297 Section 2.1), and sent using the underlying QUIC
+    /// connection's [`dgram_send()`] method.
+    ///
+    /// The [`DatagramError`] error is returned if the peer didn't enable
+    /// HTTP/3 datagrams, or if `stream_id` is not a client-initiated
+    /// bidirectional stream.
+    ///
+    /// [`dgram_send()`]: ../struct.Connection.html#method.dgram_send
+    /// [`DatagramError`]: enum.Error.html#variant.DatagramError
+    pub fn send_dgram(
+        &mut self, conn: &mut impl Transport, stream_id: u64, payload: &[u8],
+    ) -> Result<()> {
+        if !self.dgram_enabled_by_peer(conn) || stream_id % 4 != 0 {
+            return Err(Error::DatagramError);
+        }
+
+        let quarter_id = stream_id / 4;
+
+        let len = octets::varint_len(quarter_id) + payload.len();
+        let mut d = vec![0; len];
+        let mut b = octets::OctetsMut::with_slice(&mut d);
+
+        b.put_varint(quarter_id)?;
+        b.put_bytes(payload)?;
+
+        conn.dgram_send_vec(d)?;
+
+        Ok(())
+    }
+
+    /// Reads the next HTTP Datagram from the underlying QUIC connection.
+    ///
+    /// On success the datagram is written into `buf`, and a tuple of the
+    /// total length, the ID of the associated request stream, and the
+    /// offset in `buf` at which the payload starts is returned.
+    ///
+    /// Datagrams with an invalid Quarter Stream ID cause the connection to be
+    /// closed with the [`DatagramError`] error.
+    ///
+    /// [`DatagramError`]: enum.Error.html#variant.DatagramError
+    pub fn recv_dgram(
+        &mut self, conn: &mut impl Transport, buf: &mut [u8],
+    ) -> Result<(usize, u64, usize)> {
+        let len = conn.dgram_recv(buf)?;
+
+        let mut b = octets::Octets::with_slice(&buf[..len]);
+
+        let quarter_id = match b.get_varint() {
+            Ok(v) if v < MAX_QUARTER_STREAM_ID => v,
+
+            _ => {
+                conn.close(
+                    true,
+                    Error::DatagramError.to_wire(),
+                    b"Invalid Quarter Stream ID",
+                )?;
+
+                return Err(Error::DatagramError);
+            },
+        };
+
+        Ok((len, quarter_id * 4, b.off()))
+    }
+
     /// Returns whether the peer enabled extended CONNECT support.
     ///
     /// Support is signalled by the peer's SETTINGS, so this method always
@@ -1329,7 +2289,7 @@ impl Connection {
     /// [`Data`]: enum.Event.html#variant.Data
     /// [`Done`]: enum.Error.html#variant.Done
     pub fn recv_body(
-        &mut self, conn: &mut super::Connection, stream_id: u64, out: &mut [u8],
+        &mut self, conn: &mut impl Transport, stream_id: u64, out: &mut [u8],
     ) -> Result<usize> {
         let mut total = 0;
 
@@ -1342,14 +2302,25 @@ impl Connection {
                 break;
             }
 
-            let (read, fin) =
-                match stream.try_consume_data(conn, &mut out[total..]) {
-                    Ok(v) => v,
+            let max = match stream.body_credit() {
+                Some(0) => break,
 
-                    Err(Error::Done) => break,
+                Some(v) => cmp::min(v, (out.len() - total) as u64) as usize,
 
-                    Err(e) => return Err(e),
-                };
+                None => out.len() - total,
+            };
+
+            let (read, fin) = match stream
+                .try_consume_data(conn, &mut out[total..total + max])
+            {
+                Ok(v) => v,
+
+                Err(Error::Done) => break,
+
+                Err(e) => return Err(e),
+            };
+
+            stream.consume_body_credit(read);
 
             total += read;
 
@@ -1388,6 +2359,88 @@ impl Connection {
         Ok(total)
     }
 
+    /// Allows the application to read `credit` more bytes of a request's
+    /// body.
+    ///
+    /// This only has an effect when request bodies are limited using
+    /// [`Config::set_initial_body_credit()`]. If the request included an
+    /// `Expect: 100-continue` header, a 100 (Continue) interim response is
+    /// sent first, to let the client know the body will be accepted.
+    ///
+    /// The [`Data`] event is re-armed, so that it's reported again if body
+    /// data is still buffered.
+    ///
+    /// The [`FrameUnexpected`] error is returned when called by a client, or
+    /// on an unknown stream. The [`StreamBlocked`] error is returned when the
+    /// 100 (Continue) response can't be sent, in which case the application
+    /// should retry once the stream is reported as writable again.
+    ///
+    /// [`Config::set_initial_body_credit()`]: struct.Config.html#method.set_initial_body_credit
+    /// [`Data`]: enum.Event.html#variant.Data
+    /// [`FrameUnexpected`]: enum.Error.html#variant.FrameUnexpected
+    /// [`StreamBlocked`]: enum.Error.html#variant.StreamBlocked
+    pub fn grant_body_credit(
+        &mut self, conn: &mut impl Transport, stream_id: u64, credit: u64,
+    ) -> Result<()> {
+        if !self.is_server {
+            return Err(Error::FrameUnexpected);
+        }
+
+        let expect_continue = self
+            .streams
+            .get(&stream_id)
+            .ok_or(Error::FrameUnexpected)?
+            .expect_continue();
+
+        if expect_continue {
+            let headers = [Header::new(b":status", b"100")];
+
+            self.send_interim_response(conn, stream_id, &headers)?;
+        }
+
+        if let Some(stream) = self.streams.get_mut(&stream_id) {
+            stream.set_expect_continue(false);
+            stream.add_body_credit(credit);
+            stream.reset_data_event();
+        }
+
+        Ok(())
+    }
+
+    /// Sends a final response without reading the rest of the request body.
+    ///
+    /// This is used by servers to refuse a request before its body is
+    /// received, e.g. with a 413 (Content Too Large) or a 417 (Expectation
+    /// Failed) status when the client is waiting for a 100 (Continue)
+    /// response. The response is sent with [`send_response()`], and the
+    /// client is then asked to stop sending with the `H3_NO_ERROR` error
+    /// code.
+    ///
+    /// The [`FrameUnexpected`] error is returned when called by a client.
+    ///
+    /// [`send_response()`]: struct.Connection.html#method.send_response
+    /// [`FrameUnexpected`]: enum.Error.html#variant.FrameUnexpected
+    pub fn reject_request_body<T: NameValue>(
+        &mut self, conn: &mut impl Transport, stream_id: u64, headers: &[T],
+    ) -> Result<()> {
+        if !self.is_server {
+            return Err(Error::FrameUnexpected);
+        }
+
+        self.send_response(conn, stream_id, headers, true)?;
+
+        if let Some(stream) = self.streams.get_mut(&stream_id) {
+            stream.set_expect_continue(false);
+        }
+
+        // H3_NO_ERROR
+        match conn.stream_shutdown(stream_id, crate::Shutdown::Read, 0x100) {
+            Ok(()) | Err(super::Error::Done) => Ok(()),
+
+            Err(e) => Err(e.into()),
+        }
+    }
+
     /// Sends a PRIORITY_UPDATE frame on the control stream with specified
     /// request stream ID and priority.
     ///
@@ -1403,8 +2456,7 @@ impl Connection {
     /// [`StreamBlocked`]: enum.Error.html#variant.StreamBlocked
     /// [Extensible Priority]: https://www.rfc-editor.org/rfc/rfc9218.html#section-4.
     pub fn send_priority_update_for_request(
-        &mut self, conn: &mut super::Connection, stream_id: u64,
-        priority: &Priority,
+        &mut self, conn: &mut impl Transport, stream_id: u64, priority: &Priority,
     ) -> Result<()> {
         let mut d = [42; 20];
         let mut b = octets::OctetsMut::with_slice(&mut d);
@@ -1470,7 +2522,7 @@ impl Connection {
             field_value,
         );
 
-        qlog_with_type!(QLOG_FRAME_CREATED, conn.qlog, q, {
+        qlog_with_transport!(QLOG_FRAME_CREATED, conn, q, {
             let frame = Http3Frame::PriorityUpdate {
                 target_stream_type: H3PriorityTargetStreamType::Request,
                 prioritized_element_id: stream_id,
@@ -1535,6 +2587,14 @@ impl Connection {
     /// prioritized element ID that is used in the method
     /// [`take_last_priority_update()`], which rearms the event for that ID.
     ///
+    /// The event [`RequestRejected`] returns the ID of a request stream that
+    /// was refused by the server, either because of the concurrent requests
+    /// limit or because of a GOAWAY, and requires no further action from the
+    /// server application. Clients can retry the request on a new connection.
+    ///
+    /// The event [`Drained`] returns the ID of the last GOAWAY sent or
+    /// received, once all the requests allowed by it have completed.
+    ///
     /// If an error occurs while processing data, the connection is closed with
     /// the appropriate error code, using the transport's [`close()`] method.
     ///
@@ -1545,20 +2605,24 @@ impl Connection {
     /// [`Finished`]: enum.Event.html#variant.Finished
     /// [`GoAway`]: enum.Event.html#variant.GoAWay
     /// [`PriorityUpdate`]: enum.Event.html#variant.PriorityUpdate
+    /// [`RequestRejected`]: enum.Event.html#variant.RequestRejected
+    /// [`Drained`]: enum.Event.html#variant.Drained
     /// [`recv_body()`]: struct.Connection.html#method.recv_body
     /// [`send_response()`]: struct.Connection.html#method.send_response
     /// [`send_body()`]: struct.Connection.html#method.send_body
     /// [`recv_dgram()`]: struct.Connection.html#method.recv_dgram
     /// [`take_last_priority_update()`]: struct.Connection.html#method.take_last_priority_update
     /// [`close()`]: ../struct.Connection.html#method.close
-    pub fn poll(&mut self, conn: &mut super::Connection) -> Result<(u64, Event)> {
+    pub fn poll(&mut self, conn: &mut impl Transport) -> Result<(u64, Event)> {
         // When connection close is initiated by the local application (e.g. due
         // to a protocol error), the connection itself might be in a broken
         // state, so return early.
-        if conn.local_error.is_some() {
+        if conn.local_error().is_some() {
             return Err(Error::Done);
         }
 
+        self.process_early_requests(conn);
+
         // Process control streams first.
         if let Some(stream_id) = self.peer_control_stream_id {
             match self.process_control_stream(conn, stream_id) {
@@ -1590,13 +2654,53 @@ impl Connection {
             };
         }
 
+        // Report requests that the peer won't process.
+        if let Some(rejected) = self.rejected_requests.pop_front() {
+            return Ok((rejected, Event::RequestRejected));
+        }
+
         // Process finished streams list.
         if let Some(finished) = self.finished_streams.pop_front() {
-            return Ok((finished, Event::Finished));
+            return self.finished_event(conn, finished);
+        }
+
+        // Process HTTP/3 data from readable streams. The list is kept around to
+        // avoid allocating it on every call.
+        let mut readable = std::mem::take(&mut self.readable_streams);
+
+        readable.clear();
+        conn.readable(&mut readable);
+
+        let res = self.process_readable_streams(conn, &readable);
+
+        self.readable_streams = readable;
+
+        if let Some(ev) = res? {
+            return Ok(ev);
+        }
+
+        // Process finished streams list once again, to make sure `Finished`
+        // events are returned when receiving empty stream frames with the fin
+        // flag set.
+        if let Some(finished) = self.finished_streams.pop_front() {
+            return self.finished_event(conn, finished);
+        }
+
+        if let Some(id) = self.drained_goaway_id(conn) {
+            self.drained = true;
+
+            return Ok((id, Event::Drained));
         }
 
-        // Process HTTP/3 data from readable streams.
-        for s in conn.readable() {
+        Err(Error::Done)
+    }
+
+    /// Processes HTTP/3 data from the given readable streams, until an event
+    /// is found.
+    fn process_readable_streams(
+        &mut self, conn: &mut impl Transport, readable: &[u64],
+    ) -> Result<Option<(u64, Event)>> {
+        for &s in readable {
             trace!("{} stream id {} is readable", conn.trace_id(), s);
 
             let ev = match self.process_readable_stream(conn, s, true) {
@@ -1606,8 +2710,12 @@ impl Connection {
 
                 // Return early if the stream was reset, to avoid returning
                 // a Finished event later as well.
+                Err(Error::TransportError(crate::Error::StreamReset(e)))
+                    if e == Error::RequestCancelled.to_wire() =>
+                    return Ok(Some((s, Event::RequestCancelled))),
+
                 Err(Error::TransportError(crate::Error::StreamReset(e))) =>
-                    return Ok((s, Event::Reset(e))),
+                    return Ok(Some((s, Event::Reset(e)))),
 
                 Err(e) => return Err(e),
             };
@@ -1617,19 +2725,54 @@ impl Connection {
             }
 
             // TODO: check if stream is completed so it can be freed
-            if let Some(ev) = ev {
+            if ev.is_some() {
                 return Ok(ev);
             }
         }
 
-        // Process finished streams list once again, to make sure `Finished`
-        // events are returned when receiving empty stream frames with the fin
-        // flag set.
-        if let Some(finished) = self.finished_streams.pop_front() {
-            return Ok((finished, Event::Finished));
+        Ok(None)
+    }
+
+    /// Cancels a request, or the response to it at servers.
+    ///
+    /// Both directions of the request stream are reset with the
+    /// `H3_REQUEST_CANCELLED` error code, which the peer reports with the
+    /// [`RequestCancelled`] event, and all the state kept for the stream is
+    /// discarded, including any pending body source. No further events are
+    /// reported for the stream.
+    ///
+    /// The [`FrameUnexpected`] error is returned if `stream_id` isn't a
+    /// request stream in progress.
+    ///
+    /// [`RequestCancelled`]: enum.Event.html#variant.RequestCancelled
+    /// [`FrameUnexpected`]: enum.Error.html#variant.FrameUnexpected
+    pub fn cancel_request(
+        &mut self, conn: &mut impl Transport, stream_id: u64,
+    ) -> Result<()> {
+        if !crate::stream::is_bidi(stream_id) ||
+            self.streams.remove(&stream_id).is_none()
+        {
+            return Err(Error::FrameUnexpected);
         }
 
-        Err(Error::Done)
+        trace!(
+            "{} cancelling request stream {}",
+            conn.trace_id(),
+            stream_id
+        );
+
+        self.body_sources.remove(&stream_id);
+        self.finished_streams.retain(|&id| id != stream_id);
+
+        let err = Error::RequestCancelled.to_wire();
+
+        // The stream might have been already closed, so ignore errors.
+        conn.stream_shutdown(stream_id, crate::Shutdown::Read, err)
+            .ok();
+        conn.stream_shutdown(stream_id, crate::Shutdown::Write, err)
+            .ok();
+
+        Ok(())
     }
 
     /// Sends a GOAWAY frame to initiate graceful connection closure.
@@ -1639,12 +2782,19 @@ impl Connection {
     /// and 2^62-4. However, the ID cannot be increased. Failure to satisfy
     /// these conditions will return an error.
     ///
+    /// Once a GOAWAY is sent, the server rejects new requests on streams with
+    /// an ID greater than or equal to `id`, and reports them with the
+    /// [`RequestRejected`] event, while requests with lower IDs are allowed
+    /// to complete. The [`Drained`] event is reported once they have.
+    ///
     /// This method does not close the QUIC connection. Applications are
     /// required to call [`close()`] themselves.
     ///
+    /// [`RequestRejected`]: enum.Event.html#variant.RequestRejected
+    /// [`Drained`]: enum.Event.html#variant.Drained
     /// [`close()`]: ../struct.Connection.html#method.close
     pub fn send_goaway(
-        &mut self, conn: &mut super::Connection, id: u64,
+        &mut self, conn: &mut impl Transport, id: u64,
     ) -> Result<()> {
         let mut id = id;
 
@@ -1680,7 +2830,7 @@ impl Connection {
 
             trace!("{} tx frm {:?}", conn.trace_id(), frame);
 
-            qlog_with_type!(QLOG_FRAME_CREATED, conn.qlog, q, {
+            qlog_with_transport!(QLOG_FRAME_CREATED, conn, q, {
                 let ev_data = EventData::H3FrameCreated(H3FrameCreated {
                     stream_id,
                     length: Some(octets::varint_len(id) as u64),
@@ -1700,6 +2850,123 @@ impl Connection {
         Ok(())
     }
 
+    /// Sends an ORIGIN frame to advertise the origins the server is
+    /// authoritative for (RFC 9412).
+    ///
+    /// Each origin is serialized as in `https://example.com`, with an optional
+    /// port. Clients add the origins to the connection's origin set, which is
+    /// used by [`is_authoritative()`].
+    ///
+    /// The [`FrameUnexpected`] error is returned when called by a client, and
+    /// [`StreamBlocked`] is returned when the control stream doesn't have
+    /// enough capacity for the frame.
+    ///
+    /// [`is_authoritative()`]: struct.Connection.html#method.is_authoritative
+    /// [`FrameUnexpected`]: enum.Error.html#variant.FrameUnexpected
+    /// [`StreamBlocked`]: enum.Error.html#variant.StreamBlocked
+    pub fn send_origin(
+        &mut self, conn: &mut impl Transport, origins: &[&[u8]],
+    ) -> Result<()> {
+        if !self.is_server {
+            return Err(Error::FrameUnexpected);
+        }
+
+        if origins.iter().any(|o| o.len() > u16::MAX as usize) {
+            return Err(Error::MessageError);
+        }
+
+        let stream_id = self.control_stream_id.ok_or(Error::FrameUnexpected)?;
+
+        let frame = frame::Frame::Origin {
+            origins: origins.iter().map(|o| o.to_vec()).collect(),
+        };
+
+        let payload_len: usize = origins.iter().map(|o| 2 + o.len()).sum();
+        let len = octets::varint_len(frame::ORIGIN_FRAME_TYPE_ID) +
+            octets::varint_len(payload_len as u64) +
+            payload_len;
+
+        let mut d = vec![0; len];
+        let mut b = octets::OctetsMut::with_slice(&mut d);
+
+        let wire_len = frame.to_bytes(&mut b)?;
+        let stream_cap = conn.stream_capacity(stream_id)?;
+
+        if stream_cap < wire_len {
+            return Err(Error::StreamBlocked);
+        }
+
+        trace!("{} tx frm {:?}", conn.trace_id(), frame);
+
+        qlog_with_transport!(QLOG_FRAME_CREATED, conn, q, {
+            let ev_data = EventData::H3FrameCreated(H3FrameCreated {
+                stream_id,
+                length: Some(payload_len as u64),
+                frame: frame.to_qlog(),
+                raw: None,
+            });
+
+            q.add_event_data_now(ev_data).ok();
+        });
+
+        conn.stream_send(stream_id, &d[..wire_len], false)?;
+
+        Ok(())
+    }
+
+    /// Returns whether the server is authoritative for `authority`, and
+    /// requests for it can be sent on this connection.
+    ///
+    /// The `authority` is a host name with an optional port, e.g. the value
+    /// of the `:authority` pseudo-header. The server's certificate must be
+    /// valid for the host. If the server sent ORIGIN frames, the origin must
+    /// also be part of the connection's origin set, which includes the
+    /// origin of the connection's SNI and the ones advertised by the server.
+    ///
+    /// This assumes that the server's certificate was verified during the
+    /// handshake, and always returns false before the handshake completes.
+    pub fn is_authoritative(
+        &self, conn: &impl Transport, authority: &str,
+    ) -> bool {
+        if !conn.is_established() {
+            return false;
+        }
+
+        let (host, port) = match split_host_port(authority.as_bytes()) {
+            Some(v) => v,
+
+            None => return false,
+        };
+
+        if let Some(origins) = &self.peer_origins {
+            let initial = conn.server_name().map(|sni| {
+                let port = conn.peer_addr().map_or(443, |a| a.port());
+
+                (sni.as_bytes(), port)
+            });
+
+            let same_origin =
+                |(h, p): (&[u8], u16)| h.eq_ignore_ascii_case(host) && p == port;
+
+            let in_origin_set = initial.map_or(false, same_origin) ||
+                origins
+                    .iter()
+                    .filter_map(|o| o.strip_prefix(b"https://"))
+                    .filter_map(split_host_port)
+                    .any(same_origin);
+
+            if !in_origin_set {
+                return false;
+            }
+        }
+
+        match std::str::from_utf8(host) {
+            Ok(host) => conn.peer_cert_covers_host(host),
+
+            Err(_) => false,
+        }
+    }
+
     /// Gets the raw settings from peer including unknown and reserved types.
     ///
     /// The order of settings is the same as received in the SETTINGS frame.
@@ -1708,7 +2975,7 @@ impl Connection {
     }
 
     fn open_uni_stream(
-        &mut self, conn: &mut super::Connection, ty: u64,
+        &mut self, conn: &mut impl Transport, ty: u64,
     ) -> Result<u64> {
         let stream_id = self.next_uni_stream_id;
 
@@ -1745,14 +3012,14 @@ impl Connection {
     }
 
     fn open_qpack_encoder_stream(
-        &mut self, conn: &mut super::Connection,
+        &mut self, conn: &mut impl Transport,
     ) -> Result<()> {
         let stream_id =
             self.open_uni_stream(conn, stream::QPACK_ENCODER_STREAM_TYPE_ID)?;
 
         self.local_qpack_streams.encoder_stream_id = Some(stream_id);
 
-        qlog_with_type!(QLOG_STREAM_TYPE_SET, conn.qlog, q, {
+        qlog_with_transport!(QLOG_STREAM_TYPE_SET, conn, q, {
             let ev_data = EventData::H3StreamTypeSet(H3StreamTypeSet {
                 stream_id,
                 owner: Some(H3Owner::Local),
@@ -1767,14 +3034,14 @@ impl Connection {
     }
 
     fn open_qpack_decoder_stream(
-        &mut self, conn: &mut super::Connection,
+        &mut self, conn: &mut impl Transport,
     ) -> Result<()> {
         let stream_id =
             self.open_uni_stream(conn, stream::QPACK_DECODER_STREAM_TYPE_ID)?;
 
         self.local_qpack_streams.decoder_stream_id = Some(stream_id);
 
-        qlog_with_type!(QLOG_STREAM_TYPE_SET, conn.qlog, q, {
+        qlog_with_transport!(QLOG_STREAM_TYPE_SET, conn, q, {
             let ev_data = EventData::H3StreamTypeSet(H3StreamTypeSet {
                 stream_id,
                 owner: Some(H3Owner::Local),
@@ -1790,7 +3057,7 @@ impl Connection {
 
     /// Send GREASE frames on the provided stream ID.
     fn send_grease_frames(
-        &mut self, conn: &mut super::Connection, stream_id: u64,
+        &mut self, conn: &mut impl Transport, stream_id: u64,
     ) -> Result<()> {
         let mut d = [0; 8];
 
@@ -1835,7 +3102,7 @@ impl Connection {
             stream_id
         );
 
-        qlog_with_type!(QLOG_FRAME_CREATED, conn.qlog, q, {
+        qlog_with_transport!(QLOG_FRAME_CREATED, conn, q, {
             let frame = Http3Frame::Reserved { length: Some(0) };
             let ev_data = EventData::H3FrameCreated(H3FrameCreated {
                 stream_id,
@@ -1863,7 +3130,7 @@ impl Connection {
             grease_payload.len()
         );
 
-        qlog_with_type!(QLOG_FRAME_CREATED, conn.qlog, q, {
+        qlog_with_transport!(QLOG_FRAME_CREATED, conn, q, {
             let frame = Http3Frame::Reserved {
                 length: Some(grease_payload.len() as u64),
             };
@@ -1882,14 +3149,14 @@ impl Connection {
 
     /// Opens a new unidirectional stream with a GREASE type and sends some
     /// unframed payload.
-    fn open_grease_stream(&mut self, conn: &mut super::Connection) -> Result<()> {
+    fn open_grease_stream(&mut self, conn: &mut impl Transport) -> Result<()> {
         match self.open_uni_stream(conn, grease_value()) {
             Ok(stream_id) => {
                 conn.stream_send(stream_id, b"GREASE is the word", true)?;
 
                 trace!("{} open GREASE stream {}", conn.trace_id(), stream_id);
 
-                qlog_with_type!(QLOG_STREAM_TYPE_SET, conn.qlog, q, {
+                qlog_with_transport!(QLOG_STREAM_TYPE_SET, conn, q, {
                     let ev_data = EventData::H3StreamTypeSet(H3StreamTypeSet {
                         stream_id,
                         owner: Some(H3Owner::Local),
@@ -1914,7 +3181,7 @@ impl Connection {
     }
 
     /// Sends SETTINGS frame based on HTTP/3 configuration.
-    fn send_settings(&mut self, conn: &mut super::Connection) -> Result<()> {
+    fn send_settings(&mut self, conn: &mut impl Transport) -> Result<()> {
         let stream_id = match self
             .open_uni_stream(conn, stream::HTTP3_CONTROL_STREAM_TYPE_ID)
         {
@@ -1933,7 +3200,7 @@ impl Connection {
 
         self.control_stream_id = Some(stream_id);
 
-        qlog_with_type!(QLOG_STREAM_TYPE_SET, conn.qlog, q, {
+        qlog_with_transport!(QLOG_STREAM_TYPE_SET, conn, q, {
             let ev_data = EventData::H3StreamTypeSet(H3StreamTypeSet {
                 stream_id,
                 owner: Some(H3Owner::Local),
@@ -1944,7 +3211,7 @@ impl Connection {
             q.add_event_data_now(ev_data).ok();
         });
 
-        let grease = if conn.grease {
+        let grease = if conn.grease() {
             Some((grease_value(), grease_value()))
         } else {
             None
@@ -1981,7 +3248,7 @@ impl Connection {
                 off
             );
 
-            qlog_with_type!(QLOG_FRAME_CREATED, conn.qlog, q, {
+            qlog_with_transport!(QLOG_FRAME_CREATED, conn, q, {
                 let frame = frame.to_qlog();
                 let ev_data = EventData::H3FrameCreated(H3FrameCreated {
                     stream_id: id,
@@ -1998,7 +3265,7 @@ impl Connection {
     }
 
     fn process_control_stream(
-        &mut self, conn: &mut super::Connection, stream_id: u64,
+        &mut self, conn: &mut impl Transport, stream_id: u64,
     ) -> Result<(u64, Event)> {
         if conn.stream_finished(stream_id) {
             conn.close(
@@ -2031,9 +3298,179 @@ impl Connection {
         Err(Error::Done)
     }
 
+    /// Returns the number of peer-initiated requests that are still in
+    /// progress, not counting `new_stream_id`.
+    fn active_requests(&self, conn: &impl Transport, new_stream_id: u64) -> u64 {
+        self.streams
+            .keys()
+            .filter(|&&id| {
+                id != new_stream_id &&
+                    crate::stream::is_bidi(id) &&
+                    !crate::stream::is_local(id, self.is_server) &&
+                    conn.stream_exists(id)
+            })
+            .count() as u64
+    }
+
+    /// Returns the ID of the GOAWAY that was sent or received, if all the
+    /// requests in progress have completed and the [`Drained`] event wasn't
+    /// reported yet.
+    ///
+    /// [`Drained`]: enum.Event.html#variant.Drained
+    fn drained_goaway_id(&self, conn: &impl Transport) -> Option<u64> {
+        if self.drained {
+            return None;
+        }
+
+        let id = self.local_goaway_id.or(self.peer_goaway_id)?;
+
+        let in_progress = self
+            .streams
+            .keys()
+            .any(|&id| crate::stream::is_bidi(id) && conn.stream_exists(id));
+
+        if in_progress {
+            return None;
+        }
+
+        Some(id)
+    }
+
+    /// Cancels the local requests that a GOAWAY received from the server
+    /// with the given ID indicates won't be processed.
+    fn cancel_rejected_requests(
+        &mut self, conn: &mut impl Transport, goaway_id: u64,
+    ) {
+        let mut rejected: Vec<u64> = self
+            .streams
+            .keys()
+            .filter(|&&id| {
+                id >= goaway_id &&
+                    crate::stream::is_bidi(id) &&
+                    crate::stream::is_local(id, self.is_server)
+            })
+            .copied()
+            .collect();
+
+        rejected.sort_unstable();
+
+        let err = Error::RequestCancelled.to_wire();
+
+        for stream_id in rejected {
+            self.streams.remove(&stream_id);
+
+            // The stream might have been already closed, so ignore errors.
+            conn.stream_shutdown(stream_id, crate::Shutdown::Read, err)
+                .ok();
+            conn.stream_shutdown(stream_id, crate::Shutdown::Write, err)
+                .ok();
+
+            self.rejected_requests.push_back(stream_id);
+        }
+    }
+
+    /// Resets a stream on which a malformed message was received with the
+    /// `H3_MESSAGE_ERROR` error code.
+    ///
+    /// No further events are reported for the stream.
+    fn reset_malformed_message(
+        &mut self, conn: &mut impl Transport, stream_id: u64,
+        cause: MessageErrorCause,
+    ) -> Result<(u64, Event)> {
+        trace!(
+            "{} malformed message on stream {}: {:?}",
+            conn.trace_id(),
+            stream_id,
+            cause
+        );
+
+        let err = Error::MessageError.to_wire();
+
+        // The stream might have been already closed, so ignore errors.
+        conn.stream_shutdown(stream_id, crate::Shutdown::Read, err)
+            .ok();
+        conn.stream_shutdown(stream_id, crate::Shutdown::Write, err)
+            .ok();
+
+        if let Some(stream) = self.streams.get_mut(&stream_id) {
+            stream.finished();
+        }
+
+        Ok((stream_id, Event::MessageError(cause)))
+    }
+
+    /// Returns the event to report for a stream that was finished.
+    fn finished_event(
+        &mut self, conn: &mut impl Transport, stream_id: u64,
+    ) -> Result<(u64, Event)> {
+        let mismatch = self
+            .streams
+            .get(&stream_id)
+            .map_or(false, |s| s.content_length_mismatch(true));
+
+        if mismatch {
+            return self.reset_malformed_message(
+                conn,
+                stream_id,
+                MessageErrorCause::ContentLengthMismatch,
+            );
+        }
+
+        Ok((stream_id, Event::Finished))
+    }
+
+    /// Refuses a new request stream with the `H3_REQUEST_REJECTED` error code.
+    fn reject_request(
+        &mut self, conn: &mut impl Transport, stream_id: u64, reason: &str,
+    ) -> Result<()> {
+        trace!(
+            "{} rejecting request stream {}: {}",
+            conn.trace_id(),
+            stream_id,
+            reason
+        );
+
+        let err = Error::RequestRejected.to_wire();
+
+        conn.stream_shutdown(stream_id, crate::Shutdown::Read, err)?;
+        conn.stream_shutdown(stream_id, crate::Shutdown::Write, err)?;
+
+        Ok(())
+    }
+
     fn process_readable_stream(
-        &mut self, conn: &mut super::Connection, stream_id: u64, polling: bool,
+        &mut self, conn: &mut impl Transport, stream_id: u64, polling: bool,
     ) -> Result<(u64, Event)> {
+        // Enforce the concurrent requests limit on new request streams.
+        if let Some(max) = self.max_concurrent_requests {
+            if self.is_server &&
+                crate::stream::is_bidi(stream_id) &&
+                !self.streams.contains_key(&stream_id) &&
+                self.active_requests(conn, stream_id) >= max
+            {
+                self.reject_request(
+                    conn,
+                    stream_id,
+                    "too many concurrent requests",
+                )?;
+
+                return Ok((stream_id, Event::RequestRejected));
+            }
+        }
+
+        // Refuse new requests after GOAWAY was sent.
+        if let Some(goaway_id) = self.local_goaway_id {
+            if self.is_server &&
+                crate::stream::is_bidi(stream_id) &&
+                stream_id >= goaway_id &&
+                !self.streams.contains_key(&stream_id)
+            {
+                self.reject_request(conn, stream_id, "GOAWAY sent")?;
+
+                return Ok((stream_id, Event::RequestRejected));
+            }
+        }
+
         self.streams
             .entry(stream_id)
             .or_insert_with(|| stream::Stream::new(stream_id, false));
@@ -2060,7 +3497,7 @@ impl Connection {
                         return Err(e);
                     }
 
-                    qlog_with_type!(QLOG_STREAM_TYPE_SET, conn.qlog, q, {
+                    qlog_with_transport!(QLOG_STREAM_TYPE_SET, conn, q, {
                         let ev_data =
                             EventData::H3StreamTypeSet(H3StreamTypeSet {
                                 stream_id,
@@ -2220,7 +3657,7 @@ impl Connection {
                             payload_len
                         );
 
-                        qlog_with_type!(QLOG_FRAME_PARSED, conn.qlog, q, {
+                        qlog_with_transport!(QLOG_FRAME_PARSED, conn, q, {
                             let frame = Http3Frame::Data { raw: None };
 
                             let ev_data =
@@ -2239,6 +3676,18 @@ impl Connection {
                         conn.close(true, e.to_wire(), b"")?;
                         return Err(e);
                     }
+
+                    if Some(frame::DATA_FRAME_TYPE_ID) == stream.frame_type() {
+                        stream.add_data_len(payload_len);
+
+                        if stream.content_length_mismatch(false) {
+                            return self.reset_malformed_message(
+                                conn,
+                                stream_id,
+                                MessageErrorCause::ContentLengthMismatch,
+                            );
+                        }
+                    }
                 },
 
                 stream::State::FramePayload => {
@@ -2345,8 +3794,32 @@ impl Connection {
         };
     }
 
+    /// Applies the priority of a request whose headers were just received
+    /// to the underlying transport stream.
+    #[cfg(feature = "sfv")]
+    fn apply_request_priority(
+        &mut self, conn: &mut impl Transport, stream_id: u64, headers: &[Header],
+    ) -> Result<()> {
+        let update = self
+            .streams
+            .get(&stream_id)
+            .and_then(|s| s.last_priority_update());
+
+        let field_value = match update {
+            Some(v) => v,
+
+            None => match headers.iter().find(|h| h.name() == b"priority") {
+                Some(h) => h.value(),
+
+                None => return Ok(()),
+            },
+        };
+
+        set_stream_priority(conn, stream_id, field_value)
+    }
+
     fn process_frame(
-        &mut self, conn: &mut super::Connection, stream_id: u64,
+        &mut self, conn: &mut impl Transport, stream_id: u64,
         frame: frame::Frame, payload_len: u64,
     ) -> Result<(u64, Event)> {
         trace!(
@@ -2357,7 +3830,7 @@ impl Connection {
             payload_len
         );
 
-        qlog_with_type!(QLOG_FRAME_PARSED, conn.qlog, q, {
+        qlog_with_transport!(QLOG_FRAME_PARSED, conn, q, {
             // HEADERS frames are special case and will be logged below.
             if !matches!(frame, frame::Frame::Headers { .. }) {
                 let frame = frame.to_qlog();
@@ -2429,13 +3902,15 @@ impl Connection {
                 {
                     Ok(v) => v,
 
-                    Err(e) => {
-                        let e = match e {
-                            qpack::Error::HeaderListTooLarge =>
-                                Error::ExcessiveLoad,
+                    Err(qpack::Error::HeaderListTooLarge) =>
+                        return self.reset_malformed_message(
+                            conn,
+                            stream_id,
+                            MessageErrorCause::FieldSectionTooLarge,
+                        ),
 
-                            _ => Error::QpackDecompressionFailed,
-                        };
+                    Err(_) => {
+                        let e = Error::QpackDecompressionFailed;
 
                         conn.close(true, e.to_wire(), b"Error parsing headers.")?;
 
@@ -2443,7 +3918,47 @@ impl Connection {
                     },
                 };
 
-                qlog_with_type!(QLOG_FRAME_PARSED, conn.qlog, q, {
+                if let Some(max) = self.max_header_count {
+                    if headers.len() as u64 > max {
+                        return self.reset_malformed_message(
+                            conn,
+                            stream_id,
+                            MessageErrorCause::TooManyFields,
+                        );
+                    }
+                }
+
+                if let Some(max) = self.max_field_size {
+                    if headers
+                        .iter()
+                        .any(|h| (h.name().len() + h.value().len()) as u64 > max)
+                    {
+                        return self.reset_malformed_message(
+                            conn,
+                            stream_id,
+                            MessageErrorCause::FieldTooLarge,
+                        );
+                    }
+                }
+
+                let is_request = self.is_server &&
+                    self.streams
+                        .get(&stream_id)
+                        .map_or(false, |s| !s.headers_received());
+
+                let content_length = if self.strict_validation && is_request {
+                    match validate_request(&headers, conn.server_name()) {
+                        Ok(v) => v,
+
+                        Err(cause) =>
+                            return self
+                                .reset_malformed_message(conn, stream_id, cause),
+                    }
+                } else {
+                    None
+                };
+
+                qlog_with_transport!(QLOG_FRAME_PARSED, conn, q, {
                     let qlog_headers = headers
                         .iter()
                         .map(|h| qlog::events::h3::HttpHeader {
@@ -2457,15 +3972,62 @@ impl Connection {
                         headers: qlog_headers,
                     };
 
-                    let ev_data = EventData::H3FrameParsed(H3FrameParsed {
-                        stream_id,
-                        length: Some(payload_len),
-                        frame,
-                        raw: None,
-                    });
+                    let ev_data = EventData::H3FrameParsed(H3FrameParsed {
+                        stream_id,
+                        length: Some(payload_len),
+                        frame,
+                        raw: None,
+                    });
+
+                    q.add_event_data_now(ev_data).ok();
+                });
+
+                let is_interim = !self.is_server && is_interim_response(&headers);
+
+                let (body_credit, expect_continue) = if self.is_server {
+                    (self.initial_body_credit, is_expect_continue(&headers))
+                } else {
+                    (None, false)
+                };
+
+                match self.streams.get_mut(&stream_id) {
+                    Some(s) if s.trailers_received() => {
+                        conn.close(
+                            true,
+                            Error::FrameUnexpected.to_wire(),
+                            b"HEADERS received after trailers",
+                        )?;
+
+                        return Err(Error::FrameUnexpected);
+                    },
+
+                    Some(s) if s.headers_received() => {
+                        s.set_trailers_received();
+
+                        return Ok((stream_id, Event::Trailers {
+                            list: headers,
+                        }));
+                    },
+
+                    Some(_) if is_interim =>
+                        return Ok((stream_id, Event::InterimHeaders {
+                            list: headers,
+                        })),
+
+                    Some(s) => {
+                        s.set_headers_received();
+                        s.set_body_credit(body_credit);
+                        s.set_expect_continue(expect_continue);
+                        s.set_content_length(content_length);
+                    },
+
+                    None => (),
+                }
 
-                    q.add_event_data_now(ev_data).ok();
-                });
+                #[cfg(feature = "sfv")]
+                if self.auto_priority && self.is_server {
+                    self.apply_request_priority(conn, stream_id, &headers)?;
+                }
 
                 let has_body = !conn.stream_finished(stream_id);
 
@@ -2524,6 +4086,10 @@ impl Connection {
 
                 self.peer_goaway_id = Some(id);
 
+                if !self.is_server {
+                    self.cancel_rejected_requests(conn, id);
+                }
+
                 return Ok((id, Event::GoAway));
             },
 
@@ -2633,7 +4199,7 @@ impl Connection {
                     return Err(Error::FrameUnexpected);
                 }
 
-                if prioritized_element_id > conn.streams.max_streams_bidi() * 4 {
+                if prioritized_element_id > conn.max_streams_bidi() * 4 {
                     conn.close(
                         true,
                         Error::IdError.to_wire(),
@@ -2649,7 +4215,7 @@ impl Connection {
                 // been opened and that's OK because it's within our concurrency
                 // limit. However, we discard PRIORITY_UPDATE that refers to
                 // streams that we know have been collected.
-                if conn.streams.is_collected(prioritized_element_id) {
+                if conn.stream_is_collected(prioritized_element_id) {
                     return Err(Error::Done);
                 }
 
@@ -2660,6 +4226,19 @@ impl Connection {
                     );
 
                 let had_priority_update = stream.has_last_priority_update();
+
+                // Requests that are already open are reprioritized right
+                // away, otherwise the value is applied when the headers are
+                // received.
+                #[cfg(feature = "sfv")]
+                if self.auto_priority && stream.headers_received() {
+                    set_stream_priority(
+                        conn,
+                        prioritized_element_id,
+                        &priority_field_value,
+                    )?;
+                }
+
                 stream.set_last_priority_update(Some(priority_field_value));
 
                 // Only trigger the event when there wasn't already a stored
@@ -2708,6 +4287,18 @@ impl Connection {
                 // TODO: we only implement this if we implement server push
             },
 
+            frame::Frame::Origin { origins } => {
+                // ORIGIN frames are only meaningful on the server's control
+                // stream, and are ignored anywhere else.
+                if !self.is_server &&
+                    Some(stream_id) == self.peer_control_stream_id
+                {
+                    self.peer_origins
+                        .get_or_insert_with(Vec::new)
+                        .extend(origins);
+                }
+            },
+
             frame::Frame::Unknown { .. } => (),
         }
 
@@ -2721,6 +4312,136 @@ pub fn grease_value() -> u64 {
     31 * n + 33
 }
 
+/// Applies a Priority field value to a transport stream.
+///
+/// Invalid field values are ignored, and the default priority is used.
+#[cfg(feature = "sfv")]
+fn set_stream_priority(
+    conn: &mut impl Transport, stream_id: u64, field_value: &[u8],
+) -> Result<()> {
+    let priority = Priority::try_from(field_value).unwrap_or_default();
+
+    let urgency = priority
+        .urgency
+        .clamp(PRIORITY_URGENCY_LOWER_BOUND, PRIORITY_URGENCY_UPPER_BOUND) +
+        PRIORITY_URGENCY_OFFSET;
+
+    conn.stream_priority(stream_id, urgency, priority.incremental)?;
+
+    Ok(())
+}
+
+/// Returns the value of the `:status` pseudo-header, if present and valid.
+fn status_code<T: NameValue>(headers: &[T]) -> Option<u16> {
+    let status = headers.iter().find(|h| h.name() == b":status")?;
+
+    std::str::from_utf8(status.value()).ok()?.parse().ok()
+}
+
+/// Returns true if the headers are those of an interim (1xx) response.
+fn is_interim_response<T: NameValue>(headers: &[T]) -> bool {
+    matches!(status_code(headers), Some(100..=199))
+}
+
+/// Splits an authority into its host and port, which defaults to 443.
+fn split_host_port(authority: &[u8]) -> Option<(&[u8], u16)> {
+    let (host, port) = match authority.iter().rposition(|&c| c == b':') {
+        Some(i) => {
+            let port = std::str::from_utf8(&authority[i + 1..]).ok()?;
+
+            (&authority[..i], port.parse().ok()?)
+        },
+
+        None => (authority, 443),
+    };
+
+    if host.is_empty() {
+        return None;
+    }
+
+    Some((host, port))
+}
+
+/// Returns whether a request's method is idempotent (RFC 9110 Section 9.2.2),
+/// so the request can be safely replayed.
+fn is_idempotent_request<T: NameValue>(headers: &[T]) -> bool {
+    headers.iter().any(|h| {
+        h.name() == b":method" &&
+            matches!(
+                h.value(),
+                b"GET" | b"HEAD" | b"OPTIONS" | b"TRACE" | b"PUT" | b"DELETE"
+            )
+    })
+}
+
+/// Validates the headers of a request received in strict mode.
+///
+/// On success the value of the `content-length` field, if any, is returned.
+fn validate_request(
+    headers: &[Header], server_name: Option<&str>,
+) -> std::result::Result<Option<u64>, MessageErrorCause> {
+    let mut regular_seen = false;
+    let mut content_length = None;
+
+    for h in headers {
+        if h.name().starts_with(b":") {
+            if regular_seen {
+                return Err(MessageErrorCause::PseudoHeaderOrder);
+            }
+
+            if h.name() == b":authority" {
+                let host = split_host_port(h.value()).map(|(host, _)| host);
+
+                if let (Some(host), Some(sni)) = (host, server_name) {
+                    if !host.eq_ignore_ascii_case(sni.as_bytes()) {
+                        return Err(MessageErrorCause::AuthorityMismatch);
+                    }
+                }
+            }
+
+            continue;
+        }
+
+        regular_seen = true;
+
+        match h.name() {
+            b"connection" | b"keep-alive" | b"proxy-connection" |
+            b"transfer-encoding" | b"upgrade" =>
+                return Err(MessageErrorCause::ConnectionSpecificField),
+
+            b"te" if h.value() != b"trailers" =>
+                return Err(MessageErrorCause::ConnectionSpecificField),
+
+            b"content-length" => {
+                let len = std::str::from_utf8(h.value())
+                    .ok()
+                    .filter(|v| v.bytes().all(|c| c.is_ascii_digit()))
+                    .and_then(|v| v.parse::<u64>().ok())
+                    .ok_or(MessageErrorCause::InvalidContentLength)?;
+
+                // Repeated fields must all have the same value.
+                if content_length.map_or(false, |v| v != len) {
+                    return Err(MessageErrorCause::InvalidContentLength);
+                }
+
+                content_length = Some(len);
+            },
+
+            _ => (),
+        }
+    }
+
+    Ok(content_length)
+}
+
+/// Returns whether a request is waiting for a 100 (Continue) response before
+/// sending its body.
+fn is_expect_continue<T: NameValue>(headers: &[T]) -> bool {
+    headers.iter().any(|h| {
+        h.name() == b"expect" && h.value().eq_ignore_ascii_case(b"100-continue")
+    })
+}
+
 #[doc(hidden)]
 pub mod testing {
     use super::*;
@@ -3077,6 +4798,227 @@ mod tests {
         assert!(grease_value() < 2u64.pow(62) - 1);
     }
 
+    /// A transport that records sent data, and returns data queued by the
+    /// test when read.
+    #[derive(Default)]
+    struct MockTransport {
+        sent: std::collections::BTreeMap<u64, Vec<u8>>,
+
+        recv: std::collections::BTreeMap<u64, Vec<u8>>,
+    }
+
+    impl Transport for MockTransport {
+        fn trace_id(&self) -> &str {
+            "mock"
+        }
+
+        fn is_server(&self) -> bool {
+            false
+        }
+
+        fn is_established(&self) -> bool {
+            true
+        }
+
+        fn close(
+            &mut self, _app: bool, _err: u64, _reason: &[u8],
+        ) -> crate::Result<()> {
+            Ok(())
+        }
+
+        fn stream_send(
+            &mut self, stream_id: u64, buf: &[u8], _fin: bool,
+        ) -> crate::Result<usize> {
+            self.sent
+                .entry(stream_id)
+                .or_default()
+                .extend_from_slice(buf);
+
+            Ok(buf.len())
+        }
+
+        fn stream_recv(
+            &mut self, stream_id: u64, out: &mut [u8],
+        ) -> crate::Result<(usize, bool)> {
+            let buf = self.recv.get_mut(&stream_id).ok_or(crate::Error::Done)?;
+
+            if buf.is_empty() {
+                return Err(crate::Error::Done);
+            }
+
+            let len = std::cmp::min(out.len(), buf.len());
+            out[..len].copy_from_slice(&buf[..len]);
+            buf.drain(..len);
+
+            Ok((len, false))
+        }
+
+        fn stream_shutdown(
+            &mut self, _stream_id: u64, _direction: crate::Shutdown, _err: u64,
+        ) -> crate::Result<()> {
+            Ok(())
+        }
+
+        fn stream_capacity(&self, _stream_id: u64) -> crate::Result<usize> {
+            Ok(usize::MAX)
+        }
+
+        fn stream_writable(
+            &mut self, _stream_id: u64, _len: usize,
+        ) -> crate::Result<bool> {
+            Ok(true)
+        }
+
+        fn stream_finished(&self, _stream_id: u64) -> bool {
+            false
+        }
+
+        fn stream_readable(&self, stream_id: u64) -> bool {
+            self.recv.get(&stream_id).map_or(false, |b| !b.is_empty())
+        }
+
+        fn stream_exists(&self, stream_id: u64) -> bool {
+            self.sent.contains_key(&stream_id) ||
+                self.recv.contains_key(&stream_id)
+        }
+
+        fn max_streams_bidi(&self) -> u64 {
+            100
+        }
+
+        fn readable(&self, out: &mut Vec<u64>) {
+            out.extend(
+                self.recv
+                    .iter()
+                    .filter(|(_, b)| !b.is_empty())
+                    .map(|(&id, _)| id),
+            );
+        }
+    }
+
+    #[test]
+    /// Tests driving a connection over a transport other than quiche's.
+    fn mock_transport() {
+        let mut transport = MockTransport::default();
+
+        let config = Config::new().unwrap();
+
+        let mut h3_conn =
+            Connection::with_transport(&mut transport, &config).unwrap();
+
+        // Control stream with a SETTINGS frame, and QPACK streams.
+        assert_eq!(transport.sent[&2][..2], [
+            stream::HTTP3_CONTROL_STREAM_TYPE_ID as u8,
+            frame::SETTINGS_FRAME_TYPE_ID as u8
+        ]);
+        assert_eq!(transport.sent[&6], [
+            stream::QPACK_ENCODER_STREAM_TYPE_ID as u8
+        ]);
+        assert_eq!(transport.sent[&10], [
+            stream::QPACK_DECODER_STREAM_TYPE_ID as u8
+        ]);
+
+        // The peer's control stream with an empty SETTINGS frame.
+        transport.recv.insert(3, vec![
+            stream::HTTP3_CONTROL_STREAM_TYPE_ID as u8,
+            frame::SETTINGS_FRAME_TYPE_ID as u8,
+            0,
+        ]);
+
+        assert_eq!(h3_conn.poll(&mut transport), Err(Error::Done));
+        assert_eq!(h3_conn.peer_settings_raw(), Some(&[][..]));
+
+        let req = vec![
+            Header::new(b":method", b"GET"),
+            Header::new(b":scheme", b"https"),
+            Header::new(b":authority", b"quic.tech"),
+            Header::new(b":path", b"/test"),
+        ];
+
+        assert_eq!(h3_conn.send_request(&mut transport, &req, true), Ok(0));
+        assert_eq!(transport.sent[&0][0], frame::HEADERS_FRAME_TYPE_ID as u8);
+    }
+
+    #[test]
+    /// Tests sending requests in early data that the server rejects.
+    fn send_request_early_rejected() {
+        let mut config = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config.set_application_protos(&[b"h3"]).unwrap();
+        config.set_initial_max_data(1500);
+        config.set_initial_max_stream_data_bidi_local(150);
+        config.set_initial_max_stream_data_bidi_remote(150);
+        config.set_initial_max_stream_data_uni(150);
+        config.set_initial_max_streams_bidi(5);
+        config.set_initial_max_streams_uni(5);
+        config.enable_early_data();
+        config.verify_peer(false);
+
+        let h3_config = Config::new().unwrap();
+
+        // Perform initial handshake.
+        let mut pipe = crate::testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let session = pipe.client.session().unwrap();
+
+        let mut pipe = crate::testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.client.set_session(session), Ok(()));
+
+        // Server doesn't accept early data on this connection.
+        assert_eq!(pipe.server.set_early_data_enabled(false), Ok(()));
+
+        let mut flight = crate::testing::emit_flight(&mut pipe.client).unwrap();
+        assert!(pipe.client.is_in_early_data());
+
+        let mut h3_conn =
+            Connection::with_transport(&mut pipe.client, &h3_config).unwrap();
+
+        let mut req = vec![
+            Header::new(b":method", b"POST"),
+            Header::new(b":scheme", b"https"),
+            Header::new(b":authority", b"quic.tech"),
+            Header::new(b":path", b"/test"),
+        ];
+
+        // Non-idempotent requests are not replay-safe.
+        assert_eq!(
+            h3_conn.send_request_early(&mut pipe.client, &req, true),
+            Err(Error::UnsafeEarlyRequest)
+        );
+
+        req[0] = Header::new(b":method", b"GET");
+
+        let cancelled = h3_conn
+            .send_request_early(&mut pipe.client, &req, true)
+            .unwrap();
+        let resubmitted = h3_conn
+            .send_request_early(&mut pipe.client, &req, true)
+            .unwrap();
+
+        h3_conn.set_early_rejection_callback(move |id| id != cancelled);
+
+        flight
+            .append(&mut crate::testing::emit_flight(&mut pipe.client).unwrap());
+        crate::testing::process_flight(&mut pipe.server, flight).unwrap();
+
+        assert!(pipe.server.is_early_data_rejected());
+
+        assert_eq!(pipe.advance(), Ok(()));
+        assert!(pipe.client.is_early_data_rejected());
+
+        assert_eq!(h3_conn.poll(&mut pipe.client), Err(Error::Done));
+
+        assert!(!h3_conn.streams.contains_key(&cancelled));
+        assert!(h3_conn.streams.contains_key(&resubmitted));
+        assert!(h3_conn.early_requests.is_empty());
+    }
+
     #[test]
     fn h3_handshake_0rtt() {
         let mut buf = [0; 65535];
@@ -3160,18 +5102,137 @@ mod tests {
 
         let (stream, req) = s.send_request(true).unwrap();
 
-        assert_eq!(stream, 0);
+        assert_eq!(stream, 0);
+
+        let ev_headers = Event::Headers {
+            list: req,
+            has_body: false,
+        };
+
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+        assert_eq!(s.poll_server(), Ok((stream, Event::Finished)));
+
+        let resp = s.send_response(stream, true).unwrap();
+
+        let ev_headers = Event::Headers {
+            list: resp,
+            has_body: false,
+        };
+
+        assert_eq!(s.poll_client(), Ok((stream, ev_headers)));
+        assert_eq!(s.poll_client(), Ok((stream, Event::Finished)));
+        assert_eq!(s.poll_client(), Err(Error::Done));
+    }
+
+    #[test]
+    /// Send a request with no body, get a response with one DATA frame.
+    fn request_no_body_response_one_chunk() {
+        let mut s = Session::new().unwrap();
+        s.handshake().unwrap();
+
+        let (stream, req) = s.send_request(true).unwrap();
+        assert_eq!(stream, 0);
+
+        let ev_headers = Event::Headers {
+            list: req,
+            has_body: false,
+        };
+
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+
+        assert_eq!(s.poll_server(), Ok((stream, Event::Finished)));
+
+        let resp = s.send_response(stream, false).unwrap();
+
+        let body = s.send_body_server(stream, true).unwrap();
+
+        let mut recv_buf = vec![0; body.len()];
+
+        let ev_headers = Event::Headers {
+            list: resp,
+            has_body: true,
+        };
+
+        assert_eq!(s.poll_client(), Ok((stream, ev_headers)));
+
+        assert_eq!(s.poll_client(), Ok((stream, Event::Data)));
+        assert_eq!(s.recv_body_client(stream, &mut recv_buf), Ok(body.len()));
+
+        assert_eq!(s.poll_client(), Ok((stream, Event::Finished)));
+        assert_eq!(s.poll_client(), Err(Error::Done));
+    }
+
+    #[test]
+    /// Send a request with no body, get interim responses before the final
+    /// one.
+    fn interim_responses() {
+        let mut s = Session::new().unwrap();
+        s.handshake().unwrap();
+
+        let (stream, req) = s.send_request(true).unwrap();
+
+        let ev_headers = Event::Headers {
+            list: req,
+            has_body: false,
+        };
+
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+        assert_eq!(s.poll_server(), Ok((stream, Event::Finished)));
+
+        let hints = vec![
+            Header::new(b":status", b"103"),
+            Header::new(b"link", b"</style.css>; rel=preload; as=style"),
+        ];
+
+        // Interim responses can't be sent by clients, nor have a final
+        // status code.
+        assert_eq!(
+            s.client
+                .send_interim_response(&mut s.pipe.client, stream, &hints),
+            Err(Error::FrameUnexpected)
+        );
+
+        let not_interim = vec![Header::new(b":status", b"101")];
+        assert_eq!(
+            s.server.send_interim_response(
+                &mut s.pipe.server,
+                stream,
+                &not_interim
+            ),
+            Err(Error::MessageError)
+        );
 
-        let ev_headers = Event::Headers {
-            list: req,
-            has_body: false,
-        };
+        let cont = vec![Header::new(b":status", b"100")];
 
-        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
-        assert_eq!(s.poll_server(), Ok((stream, Event::Finished)));
+        assert_eq!(
+            s.server
+                .send_interim_response(&mut s.pipe.server, stream, &cont),
+            Ok(())
+        );
+        assert_eq!(
+            s.server
+                .send_interim_response(&mut s.pipe.server, stream, &hints),
+            Ok(())
+        );
 
         let resp = s.send_response(stream, true).unwrap();
 
+        // No more interim responses after the final one.
+        assert_eq!(
+            s.server
+                .send_interim_response(&mut s.pipe.server, stream, &hints),
+            Err(Error::FrameUnexpected)
+        );
+
+        assert_eq!(
+            s.poll_client(),
+            Ok((stream, Event::InterimHeaders { list: cont }))
+        );
+        assert_eq!(
+            s.poll_client(),
+            Ok((stream, Event::InterimHeaders { list: hints }))
+        );
+
         let ev_headers = Event::Headers {
             list: resp,
             has_body: false,
@@ -3183,28 +5244,75 @@ mod tests {
     }
 
     #[test]
-    /// Send a request with no body, get a response with one DATA frame.
-    fn request_no_body_response_one_chunk() {
+    /// Send a request with a body and trailers, get a response with a body and
+    /// trailers.
+    fn request_response_trailers() {
         let mut s = Session::new().unwrap();
         s.handshake().unwrap();
 
-        let (stream, req) = s.send_request(true).unwrap();
-        assert_eq!(stream, 0);
+        let (stream, req) = s.send_request(false).unwrap();
+
+        // Trailers can't contain pseudo-headers.
+        let bad_trailers = vec![Header::new(b":status", b"200")];
+        assert_eq!(
+            s.client
+                .send_trailers(&mut s.pipe.client, stream, &bad_trailers),
+            Err(Error::MessageError)
+        );
+
+        let body = s.send_body_client(stream, false).unwrap();
+
+        let req_trailers = vec![Header::new(b"checksum", b"1234")];
+        assert_eq!(
+            s.client
+                .send_trailers(&mut s.pipe.client, stream, &req_trailers),
+            Ok(())
+        );
+
+        // Trailers terminate the stream.
+        assert_eq!(
+            s.client
+                .send_trailers(&mut s.pipe.client, stream, &req_trailers),
+            Err(Error::FrameUnexpected)
+        );
+
+        s.advance().ok();
+
+        let mut recv_buf = vec![0; body.len()];
 
         let ev_headers = Event::Headers {
             list: req,
-            has_body: false,
+            has_body: true,
         };
 
         assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+        assert_eq!(s.poll_server(), Ok((stream, Event::Data)));
+        assert_eq!(s.recv_body_server(stream, &mut recv_buf), Ok(body.len()));
 
+        assert_eq!(
+            s.poll_server(),
+            Ok((stream, Event::Trailers { list: req_trailers }))
+        );
         assert_eq!(s.poll_server(), Ok((stream, Event::Finished)));
 
+        // Trailers can't be sent before the response.
+        let resp_trailers = vec![Header::new(b"server-timing", b"total;dur=1")];
+        assert_eq!(
+            s.server
+                .send_trailers(&mut s.pipe.server, stream, &resp_trailers),
+            Err(Error::FrameUnexpected)
+        );
+
         let resp = s.send_response(stream, false).unwrap();
+        let body = s.send_body_server(stream, false).unwrap();
 
-        let body = s.send_body_server(stream, true).unwrap();
+        assert_eq!(
+            s.server
+                .send_trailers(&mut s.pipe.server, stream, &resp_trailers),
+            Ok(())
+        );
 
-        let mut recv_buf = vec![0; body.len()];
+        s.advance().ok();
 
         let ev_headers = Event::Headers {
             list: resp,
@@ -3212,10 +5320,15 @@ mod tests {
         };
 
         assert_eq!(s.poll_client(), Ok((stream, ev_headers)));
-
         assert_eq!(s.poll_client(), Ok((stream, Event::Data)));
         assert_eq!(s.recv_body_client(stream, &mut recv_buf), Ok(body.len()));
 
+        assert_eq!(
+            s.poll_client(),
+            Ok((stream, Event::Trailers {
+                list: resp_trailers
+            }))
+        );
         assert_eq!(s.poll_client(), Ok((stream, Event::Finished)));
         assert_eq!(s.poll_client(), Err(Error::Done));
     }
@@ -3530,6 +5643,69 @@ mod tests {
         assert_eq!(s.poll_client(), Err(Error::Done));
     }
 
+    #[test]
+    /// Send a response body pulled from a body source.
+    fn send_body_from_source() {
+        let mut s = Session::new().unwrap();
+        s.handshake().unwrap();
+
+        let (stream, req) = s.send_request(true).unwrap();
+
+        let ev_headers = Event::Headers {
+            list: req,
+            has_body: false,
+        };
+
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+        assert_eq!(s.poll_server(), Ok((stream, Event::Finished)));
+
+        // A source can't be attached before the headers are sent.
+        assert_eq!(
+            s.server
+                .set_body_source(stream, ReadBodySource(std::io::empty())),
+            Err(Error::FrameUnexpected)
+        );
+
+        let resp = s.send_response(stream, false).unwrap();
+
+        let body = vec![7; 300];
+        let source = ReadBodySource(std::io::Cursor::new(body.clone()));
+        assert_eq!(s.server.set_body_source(stream, source), Ok(()));
+
+        let ev_headers = Event::Headers {
+            list: resp,
+            has_body: true,
+        };
+
+        assert_eq!(s.poll_client(), Ok((stream, ev_headers)));
+
+        let mut recv_buf = [0; 1000];
+        let mut recv = Vec::new();
+
+        // The body is larger than the stream's flow control window, so it
+        // takes multiple rounds to send.
+        loop {
+            s.server.send_body_from_sources(&mut s.pipe.server).unwrap();
+            s.advance().ok();
+
+            match s.poll_client() {
+                Ok((_, Event::Data)) =>
+                    while let Ok(len) = s.recv_body_client(stream, &mut recv_buf)
+                    {
+                        recv.extend_from_slice(&recv_buf[..len]);
+                    },
+
+                Ok((_, Event::Finished)) => break,
+
+                Err(Error::Done) => (),
+
+                ev => panic!("unexpected event {:?}", ev),
+            }
+        }
+
+        assert_eq!(recv, body);
+    }
+
     #[test]
     /// Try to send DATA frames before HEADERS.
     fn body_response_before_headers() {
@@ -3909,6 +6085,91 @@ mod tests {
         assert_eq!(s.poll_client(), Err(Error::IdError));
     }
 
+    #[test]
+    /// Requests in progress complete after GOAWAY, while later ones are
+    /// rejected at both ends.
+    fn goaway_graceful_shutdown() {
+        let mut s = Session::new().unwrap();
+        s.handshake().unwrap();
+
+        let (stream, req) = s.send_request(true).unwrap();
+
+        let ev_headers = Event::Headers {
+            list: req,
+            has_body: false,
+        };
+
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+        assert_eq!(s.poll_server(), Ok((stream, Event::Finished)));
+        assert_eq!(s.poll_server(), Err(Error::Done));
+
+        s.server
+            .send_goaway(&mut s.pipe.server, stream + 4)
+            .unwrap();
+
+        // The client sends another request before receiving the GOAWAY.
+        let (rejected, _) = s.send_request(true).unwrap();
+        assert_eq!(rejected, stream + 4);
+
+        assert_eq!(s.poll_client(), Ok((stream + 4, Event::GoAway)));
+        assert_eq!(s.poll_client(), Ok((rejected, Event::RequestRejected)));
+        assert_eq!(s.poll_client(), Err(Error::Done));
+
+        assert_eq!(s.poll_server(), Ok((rejected, Event::RequestRejected)));
+        assert_eq!(s.poll_server(), Err(Error::Done));
+
+        // The request in progress is allowed to complete.
+        let resp = s.send_response(stream, true).unwrap();
+
+        let ev_headers = Event::Headers {
+            list: resp,
+            has_body: false,
+        };
+
+        assert_eq!(s.poll_client(), Ok((stream, ev_headers)));
+        assert_eq!(s.poll_client(), Ok((stream, Event::Finished)));
+
+        assert_eq!(s.poll_server(), Ok((stream + 4, Event::Drained)));
+        assert_eq!(s.poll_server(), Err(Error::Done));
+    }
+
+    #[test]
+    /// Tests the ORIGIN frame and the client's origin set.
+    fn origin_frame() {
+        let mut s = Session::new().unwrap();
+        s.handshake().unwrap();
+
+        // Before any ORIGIN frame, hosts covered by the certificate are fine.
+        assert!(s.client.is_authoritative(&s.pipe.client, "quic.tech"));
+        assert!(s.client.is_authoritative(&s.pipe.client, "quic.tech:8443"));
+        assert!(!s.client.is_authoritative(&s.pipe.client, "example.com"));
+        assert!(!s.client.is_authoritative(&s.pipe.client, "quic.tech:x"));
+
+        assert_eq!(
+            s.client
+                .send_origin(&mut s.pipe.client, &[b"https://quic.tech"]),
+            Err(Error::FrameUnexpected)
+        );
+
+        s.server
+            .send_origin(&mut s.pipe.server, &[
+                b"https://quic.tech:8443",
+                b"https://example.com",
+            ])
+            .unwrap();
+        s.advance().ok();
+
+        assert_eq!(s.poll_client(), Err(Error::Done));
+
+        // Only the SNI origin and the advertised ones are now allowed.
+        assert!(s.client.is_authoritative(&s.pipe.client, "quic.tech:4321"));
+        assert!(s.client.is_authoritative(&s.pipe.client, "QUIC.tech:8443"));
+        assert!(!s.client.is_authoritative(&s.pipe.client, "quic.tech"));
+
+        // The certificate must still cover the host.
+        assert!(!s.client.is_authoritative(&s.pipe.client, "example.com"));
+    }
+
     #[test]
     #[cfg(feature = "sfv")]
     fn parse_priority_field_value() {
@@ -3970,47 +6231,139 @@ mod tests {
             Priority::try_from(b"u=7;croeso, i;gymru".as_slice())
         );
 
-        assert_eq!(
-            Ok(Priority::new(0, true)),
-            Priority::try_from(b"u=0, i, spinaltap=11".as_slice())
-        );
+        assert_eq!(
+            Ok(Priority::new(0, true)),
+            Priority::try_from(b"u=0, i, spinaltap=11".as_slice())
+        );
+
+        // Illegal formats
+        assert_eq!(Err(Error::Done), Priority::try_from(b"0".as_slice()));
+        assert_eq!(
+            Ok(Priority::new(7, false)),
+            Priority::try_from(b"u=-1".as_slice())
+        );
+        assert_eq!(Err(Error::Done), Priority::try_from(b"u=0.2".as_slice()));
+        assert_eq!(
+            Ok(Priority::new(7, false)),
+            Priority::try_from(b"u=100".as_slice())
+        );
+        assert_eq!(
+            Err(Error::Done),
+            Priority::try_from(b"u=3, i=true".as_slice())
+        );
+
+        // Trailing comma in dict is malformed
+        assert_eq!(Err(Error::Done), Priority::try_from(b"u=7, ".as_slice()));
+    }
+
+    #[test]
+    /// Send a PRIORITY_UPDATE for request stream from the client.
+    fn priority_update_request() {
+        let mut s = Session::new().unwrap();
+        s.handshake().unwrap();
+
+        s.client
+            .send_priority_update_for_request(&mut s.pipe.client, 0, &Priority {
+                urgency: 3,
+                incremental: false,
+            })
+            .unwrap();
+        s.advance().ok();
+
+        assert_eq!(s.poll_server(), Ok((0, Event::PriorityUpdate)));
+        assert_eq!(s.poll_server(), Err(Error::Done));
+    }
+
+    #[test]
+    #[cfg(feature = "sfv")]
+    /// Request priorities are applied to transport streams automatically.
+    fn auto_priority() {
+        let mut config = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config.set_application_protos(&[b"h3"]).unwrap();
+        config.set_initial_max_data(1500);
+        config.set_initial_max_stream_data_bidi_local(150);
+        config.set_initial_max_stream_data_bidi_remote(150);
+        config.set_initial_max_stream_data_uni(150);
+        config.set_initial_max_streams_bidi(5);
+        config.set_initial_max_streams_uni(5);
+        config.verify_peer(false);
+
+        let mut h3_config = Config::new().unwrap();
+        h3_config.enable_auto_priority(true);
+
+        let mut s = Session::with_configs(&mut config, &h3_config).unwrap();
+        s.handshake().unwrap();
+
+        let req = vec![
+            Header::new(b":method", b"GET"),
+            Header::new(b":scheme", b"https"),
+            Header::new(b":authority", b"quic.tech"),
+            Header::new(b":path", b"/test"),
+            Header::new(b"priority", b"u=1, i"),
+        ];
+
+        // The priority header field is applied.
+        let stream = s
+            .client
+            .send_request(&mut s.pipe.client, &req, true)
+            .unwrap();
+        s.advance().ok();
+
+        assert!(matches!(s.poll_server(), Ok((0, Event::Headers { .. }))));
+
+        let st = s.pipe.server.streams.get(stream).unwrap();
+        assert_eq!(st.urgency, 1 + PRIORITY_URGENCY_OFFSET);
+        assert!(st.incremental);
+
+        // Later updates are applied right away.
+        s.client
+            .send_priority_update_for_request(
+                &mut s.pipe.client,
+                stream,
+                &Priority::new(5, false),
+            )
+            .unwrap();
+        s.advance().ok();
 
-        // Illegal formats
-        assert_eq!(Err(Error::Done), Priority::try_from(b"0".as_slice()));
-        assert_eq!(
-            Ok(Priority::new(7, false)),
-            Priority::try_from(b"u=-1".as_slice())
-        );
-        assert_eq!(Err(Error::Done), Priority::try_from(b"u=0.2".as_slice()));
-        assert_eq!(
-            Ok(Priority::new(7, false)),
-            Priority::try_from(b"u=100".as_slice())
-        );
-        assert_eq!(
-            Err(Error::Done),
-            Priority::try_from(b"u=3, i=true".as_slice())
-        );
+        assert_eq!(s.poll_server(), Ok((stream, Event::PriorityUpdate)));
 
-        // Trailing comma in dict is malformed
-        assert_eq!(Err(Error::Done), Priority::try_from(b"u=7, ".as_slice()));
-    }
+        let st = s.pipe.server.streams.get(stream).unwrap();
+        assert_eq!(st.urgency, 5 + PRIORITY_URGENCY_OFFSET);
+        assert!(!st.incremental);
 
-    #[test]
-    /// Send a PRIORITY_UPDATE for request stream from the client.
-    fn priority_update_request() {
-        let mut s = Session::new().unwrap();
-        s.handshake().unwrap();
+        assert_eq!(s.poll_server(), Ok((stream, Event::Finished)));
 
+        // An update received before the request takes precedence over the
+        // header field.
         s.client
-            .send_priority_update_for_request(&mut s.pipe.client, 0, &Priority {
-                urgency: 3,
-                incremental: false,
-            })
+            .send_priority_update_for_request(
+                &mut s.pipe.client,
+                4,
+                &Priority::new(2, false),
+            )
             .unwrap();
         s.advance().ok();
 
-        assert_eq!(s.poll_server(), Ok((0, Event::PriorityUpdate)));
-        assert_eq!(s.poll_server(), Err(Error::Done));
+        assert_eq!(s.poll_server(), Ok((4, Event::PriorityUpdate)));
+
+        let stream = s
+            .client
+            .send_request(&mut s.pipe.client, &req, true)
+            .unwrap();
+        assert_eq!(stream, 4);
+        s.advance().ok();
+
+        assert!(matches!(s.poll_server(), Ok((4, Event::Headers { .. }))));
+
+        let st = s.pipe.server.streams.get(stream).unwrap();
+        assert_eq!(st.urgency, 2 + PRIORITY_URGENCY_OFFSET);
+        assert!(!st.incremental);
     }
 
     #[test]
@@ -4210,6 +6563,53 @@ mod tests {
         assert_eq!(s.poll_server(), Err(Error::Done));
     }
 
+    #[test]
+    /// A cancelled request is reset at both ends, and reported to the peer.
+    fn cancel_request() {
+        let mut s = Session::new().unwrap();
+        s.handshake().unwrap();
+
+        let (stream, req) = s.send_request(false).unwrap();
+
+        let ev_headers = Event::Headers {
+            list: req,
+            has_body: true,
+        };
+
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+        assert_eq!(s.poll_server(), Err(Error::Done));
+
+        assert_eq!(s.client.cancel_request(&mut s.pipe.client, stream), Ok(()));
+        assert_eq!(
+            s.client.cancel_request(&mut s.pipe.client, stream),
+            Err(Error::FrameUnexpected)
+        );
+
+        // Control streams can't be cancelled.
+        assert_eq!(
+            s.client.cancel_request(&mut s.pipe.client, 2),
+            Err(Error::FrameUnexpected)
+        );
+
+        assert!(s.send_body_client(stream, true).is_err());
+
+        s.advance().ok();
+
+        assert_eq!(s.poll_server(), Ok((stream, Event::RequestCancelled)));
+        assert_eq!(s.poll_server(), Err(Error::Done));
+
+        let resp = vec![Header::new(b":status", b"200")];
+
+        assert!(s
+            .server
+            .send_response(&mut s.pipe.server, stream, &resp, true)
+            .is_err());
+
+        s.advance().ok();
+
+        assert_eq!(s.poll_client(), Err(Error::Done));
+    }
+
     #[test]
     /// Send a PRIORITY_UPDATE for a request stream, before and after the stream
     /// has been stopped.
@@ -4645,36 +7045,330 @@ mod tests {
         config.set_initial_max_streams_uni(5);
         config.verify_peer(false);
 
-        let mut h3_config = Config::new().unwrap();
-        h3_config.set_max_field_section_size(65);
+        let mut h3_config = Config::new().unwrap();
+        h3_config.set_max_field_section_size(65);
+
+        let mut s = Session::with_configs(&mut config, &h3_config).unwrap();
+
+        s.handshake().unwrap();
+
+        let req = vec![
+            Header::new(b":method", b"GET"),
+            Header::new(b":scheme", b"https"),
+            Header::new(b":authority", b"quic.tech"),
+            Header::new(b":path", b"/test"),
+            Header::new(b"aaaaaaa", b"aaaaaaaa"),
+        ];
+
+        let stream = s
+            .client
+            .send_request(&mut s.pipe.client, &req, true)
+            .unwrap();
+
+        s.advance().ok();
+
+        assert_eq!(stream, 0);
+
+        // The stream is reset, but the connection stays open.
+        assert_eq!(
+            s.poll_server(),
+            Ok((
+                0,
+                Event::MessageError(MessageErrorCause::FieldSectionTooLarge)
+            ))
+        );
+        assert_eq!(s.poll_server(), Err(Error::Done));
+
+        assert_eq!(s.pipe.server.local_error, None);
+
+        s.advance().ok();
+
+        assert_eq!(
+            s.poll_client(),
+            Ok((0, Event::Reset(Error::MessageError.to_wire())))
+        );
+    }
+
+    #[test]
+    /// Tests that messages exceeding the header count and field size limits
+    /// are rejected.
+    fn header_limits() {
+        let mut config = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config.set_application_protos(&[b"h3"]).unwrap();
+        config.set_initial_max_data(1500);
+        config.set_initial_max_stream_data_bidi_local(150);
+        config.set_initial_max_stream_data_bidi_remote(150);
+        config.set_initial_max_stream_data_uni(150);
+        config.set_initial_max_streams_bidi(5);
+        config.set_initial_max_streams_uni(5);
+        config.verify_peer(false);
+
+        let mut h3_config = Config::new().unwrap();
+        h3_config.set_max_header_count(5);
+        h3_config.set_max_field_size(20);
+
+        let mut s = Session::with_configs(&mut config, &h3_config).unwrap();
+
+        s.handshake().unwrap();
+
+        let mut req = vec![
+            Header::new(b":method", b"GET"),
+            Header::new(b":scheme", b"https"),
+            Header::new(b":authority", b"quic.tech"),
+            Header::new(b":path", b"/test"),
+            Header::new(b"a", b"b"),
+        ];
+
+        let stream = s
+            .client
+            .send_request(&mut s.pipe.client, &req, true)
+            .unwrap();
+        s.advance().ok();
+
+        let ev_headers = Event::Headers {
+            list: req.clone(),
+            has_body: false,
+        };
+
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+
+        // Too many fields.
+        req.push(Header::new(b"c", b"d"));
+
+        let stream = s
+            .client
+            .send_request(&mut s.pipe.client, &req, true)
+            .unwrap();
+        s.advance().ok();
+
+        assert_eq!(
+            s.poll_server(),
+            Ok((
+                stream,
+                Event::MessageError(MessageErrorCause::TooManyFields)
+            ))
+        );
+
+        // Field too large.
+        req.truncate(4);
+        req.push(Header::new(b"user-agent", b"quiche-test-agent"));
+
+        let stream = s
+            .client
+            .send_request(&mut s.pipe.client, &req, true)
+            .unwrap();
+        s.advance().ok();
+
+        assert_eq!(
+            s.poll_server(),
+            Ok((
+                stream,
+                Event::MessageError(MessageErrorCause::FieldTooLarge)
+            ))
+        );
+        assert_eq!(s.poll_server(), Err(Error::Done));
+
+        assert_eq!(s.pipe.server.local_error, None);
+    }
+
+    #[test]
+    /// Tests that malformed requests are rejected in strict mode.
+    fn strict_validation() {
+        let mut config = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config.set_application_protos(&[b"h3"]).unwrap();
+        config.set_initial_max_data(1500);
+        config.set_initial_max_stream_data_bidi_local(150);
+        config.set_initial_max_stream_data_bidi_remote(150);
+        config.set_initial_max_stream_data_uni(150);
+        config.set_initial_max_streams_bidi(10);
+        config.set_initial_max_streams_uni(5);
+        config.verify_peer(false);
+
+        let mut h3_config = Config::new().unwrap();
+        h3_config.enable_strict_validation(true);
+
+        let mut s = Session::with_configs(&mut config, &h3_config).unwrap();
+
+        s.handshake().unwrap();
+
+        let base = [
+            Header::new(b":method", b"POST"),
+            Header::new(b":scheme", b"https"),
+            Header::new(b":authority", b"QUIC.tech:443"),
+            Header::new(b":path", b"/test"),
+        ];
+
+        let cases: &[(&[Header], MessageErrorCause)] = &[
+            (
+                &[
+                    Header::new(b":method", b"GET"),
+                    Header::new(b"user-agent", b"quiche-test"),
+                    Header::new(b":path", b"/test"),
+                ],
+                MessageErrorCause::PseudoHeaderOrder,
+            ),
+            (
+                &[Header::new(b"connection", b"keep-alive")],
+                MessageErrorCause::ConnectionSpecificField,
+            ),
+            (
+                &[Header::new(b"te", b"gzip")],
+                MessageErrorCause::ConnectionSpecificField,
+            ),
+            (
+                &[Header::new(b"content-length", b"+1")],
+                MessageErrorCause::InvalidContentLength,
+            ),
+            (
+                &[
+                    Header::new(b"content-length", b"1"),
+                    Header::new(b"content-length", b"2"),
+                ],
+                MessageErrorCause::InvalidContentLength,
+            ),
+        ];
+
+        for (extra, cause) in cases {
+            let mut req = base.to_vec();
+            req.extend_from_slice(extra);
+
+            let stream = s
+                .client
+                .send_request(&mut s.pipe.client, &req, true)
+                .unwrap();
+            s.advance().ok();
+
+            assert_eq!(
+                s.poll_server(),
+                Ok((stream, Event::MessageError(*cause)))
+            );
+        }
+
+        // The authority doesn't match the server name.
+        let mut req = base.to_vec();
+        req[2] = Header::new(b":authority", b"example.com");
+
+        let stream = s
+            .client
+            .send_request(&mut s.pipe.client, &req, true)
+            .unwrap();
+        s.advance().ok();
+
+        assert_eq!(
+            s.poll_server(),
+            Ok((
+                stream,
+                Event::MessageError(MessageErrorCause::AuthorityMismatch)
+            ))
+        );
+
+        // The body is shorter than announced.
+        let mut req = base.to_vec();
+        req.push(Header::new(b"content-length", b"10"));
+
+        let stream = s
+            .client
+            .send_request(&mut s.pipe.client, &req, false)
+            .unwrap();
+        s.client
+            .send_body(&mut s.pipe.client, stream, b"hello", true)
+            .unwrap();
+        s.advance().ok();
+
+        let ev_headers = Event::Headers {
+            list: req,
+            has_body: true,
+        };
+
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+        assert_eq!(s.poll_server(), Ok((stream, Event::Data)));
+
+        let mut buf = [0; 10];
+        assert_eq!(s.recv_body_server(stream, &mut buf), Ok(5));
+
+        assert_eq!(
+            s.poll_server(),
+            Ok((
+                stream,
+                Event::MessageError(MessageErrorCause::ContentLengthMismatch)
+            ))
+        );
+
+        // The body is longer than announced.
+        let mut req = base.to_vec();
+        req.push(Header::new(b"content-length", b"2"));
 
-        let mut s = Session::with_configs(&mut config, &h3_config).unwrap();
+        let stream = s
+            .client
+            .send_request(&mut s.pipe.client, &req, false)
+            .unwrap();
+        s.client
+            .send_body(&mut s.pipe.client, stream, b"hello", true)
+            .unwrap();
+        s.advance().ok();
 
-        s.handshake().unwrap();
+        let ev_headers = Event::Headers {
+            list: req.clone(),
+            has_body: true,
+        };
 
-        let req = vec![
-            Header::new(b":method", b"GET"),
-            Header::new(b":scheme", b"https"),
-            Header::new(b":authority", b"quic.tech"),
-            Header::new(b":path", b"/test"),
-            Header::new(b"aaaaaaa", b"aaaaaaaa"),
-        ];
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+        assert_eq!(
+            s.poll_server(),
+            Ok((
+                stream,
+                Event::MessageError(MessageErrorCause::ContentLengthMismatch)
+            ))
+        );
+
+        // A valid request.
+        req.pop();
+        req.push(Header::new(b"content-length", b"5"));
+        req.push(Header::new(b"te", b"trailers"));
 
         let stream = s
             .client
-            .send_request(&mut s.pipe.client, &req, true)
+            .send_request(&mut s.pipe.client, &req, false)
+            .unwrap();
+        s.client
+            .send_body(&mut s.pipe.client, stream, b"hello", true)
             .unwrap();
-
         s.advance().ok();
 
-        assert_eq!(stream, 0);
+        let ev_headers = Event::Headers {
+            list: req,
+            has_body: true,
+        };
+
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+        assert_eq!(s.poll_server(), Ok((stream, Event::Data)));
+        assert_eq!(s.recv_body_server(stream, &mut buf), Ok(5));
+        assert_eq!(s.poll_server(), Ok((stream, Event::Finished)));
 
-        assert_eq!(s.poll_server(), Err(Error::ExcessiveLoad));
+        assert_eq!(s.pipe.server.local_error, None);
+    }
 
+    #[test]
+    /// Tests the mapping between HTTP/3 error codes and WireErrorCode.
+    fn wire_error_code() {
         assert_eq!(
-            s.pipe.server.local_error.as_ref().unwrap().error_code,
-            Error::to_wire(Error::ExcessiveLoad)
+            WireErrorCode::try_from(0x10c),
+            Ok(WireErrorCode::RequestCancelled)
         );
+        assert_eq!(u64::from(WireErrorCode::RequestCancelled), 0x10c);
+        assert_eq!(WireErrorCode::try_from(0x1234), Err(0x1234));
     }
 
     #[test]
@@ -5930,10 +8624,7 @@ mod tests {
             .send_headers(&mut s.pipe.client, stream, &trailers, false)
             .unwrap();
 
-        let ev_trailers = Event::Headers {
-            list: trailers,
-            has_body: true,
-        };
+        let ev_trailers = Event::Trailers { list: trailers };
 
         s.advance().ok();
 
@@ -6018,6 +8709,73 @@ mod tests {
         );
     }
 
+    #[test]
+    /// Tests sending and receiving HTTP Datagrams using Quarter Stream IDs.
+    fn dgram_quarter_stream_id() {
+        let mut buf = [0; 65535];
+
+        let mut config = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config.set_application_protos(&[b"h3"]).unwrap();
+        config.set_initial_max_data(1500);
+        config.set_initial_max_stream_data_bidi_local(150);
+        config.set_initial_max_stream_data_bidi_remote(150);
+        config.set_initial_max_stream_data_uni(150);
+        config.set_initial_max_streams_bidi(100);
+        config.set_initial_max_streams_uni(5);
+        config.verify_peer(false);
+        config.enable_dgram(true, 100, 100);
+
+        let h3_config = Config::new().unwrap();
+        let mut s = Session::with_configs(&mut config, &h3_config).unwrap();
+        s.handshake().unwrap();
+
+        // Only client-initiated bidirectional streams can carry datagrams.
+        assert_eq!(
+            s.client.send_dgram(&mut s.pipe.client, 2, b"hello"),
+            Err(Error::DatagramError)
+        );
+
+        s.client
+            .send_dgram(&mut s.pipe.client, 4, b"hello")
+            .unwrap();
+        s.server
+            .send_dgram(&mut s.pipe.server, 8, b"world")
+            .unwrap();
+        s.advance().ok();
+
+        assert_eq!(
+            s.server.recv_dgram(&mut s.pipe.server, &mut buf),
+            Ok((6, 4, 1))
+        );
+        assert_eq!(&buf[1..6], b"hello");
+
+        assert_eq!(
+            s.client.recv_dgram(&mut s.pipe.client, &mut buf),
+            Ok((6, 8, 1))
+        );
+        assert_eq!(&buf[1..6], b"world");
+
+        assert_eq!(
+            s.server.recv_dgram(&mut s.pipe.server, &mut buf),
+            Err(Error::Done)
+        );
+
+        // An out of range Quarter Stream ID closes the connection.
+        s.send_dgram_client(MAX_QUARTER_STREAM_ID).unwrap();
+
+        assert_eq!(
+            s.server.recv_dgram(&mut s.pipe.server, &mut buf),
+            Err(Error::DatagramError)
+        );
+        assert!(s.pipe.server.local_error().is_some());
+    }
+
     #[test]
     /// Tests that the Datagram event is properly re-armed.
     fn dgram_event_rearm() {
@@ -6273,8 +9031,215 @@ mod tests {
         assert_eq!(s.poll_client(), Ok((stream, Event::Finished)));
         assert_eq!(s.poll_client(), Err(Error::Done));
     }
+
+    #[test]
+    /// Server rejects requests above the concurrent requests limit.
+    fn max_concurrent_requests() {
+        let mut config = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config.set_application_protos(&[b"h3"]).unwrap();
+        config.set_initial_max_data(1500);
+        config.set_initial_max_stream_data_bidi_local(150);
+        config.set_initial_max_stream_data_bidi_remote(150);
+        config.set_initial_max_stream_data_uni(150);
+        config.set_initial_max_streams_bidi(5);
+        config.set_initial_max_streams_uni(5);
+        config.verify_peer(false);
+
+        let mut h3_config = Config::new().unwrap();
+        h3_config.set_max_concurrent_requests(1);
+
+        let mut s = Session::with_configs(&mut config, &h3_config).unwrap();
+        s.handshake().unwrap();
+
+        let (stream, req) = s.send_request(true).unwrap();
+
+        let ev_headers = Event::Headers {
+            list: req,
+            has_body: false,
+        };
+
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+        assert_eq!(s.poll_server(), Ok((stream, Event::Finished)));
+        assert_eq!(s.poll_server(), Err(Error::Done));
+
+        // The first request is still in progress, so a second one is refused.
+        let (rejected, _) = s.send_request(true).unwrap();
+
+        assert_eq!(s.poll_server(), Ok((rejected, Event::RequestRejected)));
+        assert_eq!(s.poll_server(), Err(Error::Done));
+
+        assert_eq!(
+            s.poll_client(),
+            Ok((rejected, Event::Reset(Error::RequestRejected.to_wire())))
+        );
+
+        // Completing the first request frees up room for a new one.
+        s.send_response(stream, true).unwrap();
+
+        let (stream, req) = s.send_request(true).unwrap();
+
+        let ev_headers = Event::Headers {
+            list: req,
+            has_body: false,
+        };
+
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+    }
+
+    #[test]
+    /// Tests that request bodies are only read up to the granted credit.
+    fn body_credit() {
+        let mut config = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config.set_application_protos(&[b"h3"]).unwrap();
+        config.set_initial_max_data(1500);
+        config.set_initial_max_stream_data_bidi_local(150);
+        config.set_initial_max_stream_data_bidi_remote(150);
+        config.set_initial_max_stream_data_uni(150);
+        config.set_initial_max_streams_bidi(5);
+        config.set_initial_max_streams_uni(5);
+        config.verify_peer(false);
+
+        let mut h3_config = Config::new().unwrap();
+        h3_config.set_initial_body_credit(5);
+
+        let mut s = Session::with_configs(&mut config, &h3_config).unwrap();
+        s.handshake().unwrap();
+
+        let (stream, req) = s.send_request(false).unwrap();
+
+        let body = s.send_body_client(stream, true).unwrap();
+
+        let mut recv_buf = vec![0; body.len()];
+
+        let ev_headers = Event::Headers {
+            list: req,
+            has_body: true,
+        };
+
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+        assert_eq!(s.poll_server(), Ok((stream, Event::Data)));
+
+        // Only part of the body can be read.
+        assert_eq!(s.recv_body_server(stream, &mut recv_buf), Ok(5));
+        assert_eq!(s.recv_body_server(stream, &mut recv_buf), Err(Error::Done));
+        assert_eq!(s.poll_server(), Err(Error::Done));
+
+        // Granting more credit re-arms the Data event.
+        assert_eq!(
+            s.client.grant_body_credit(&mut s.pipe.client, stream, 10),
+            Err(Error::FrameUnexpected)
+        );
+        assert_eq!(
+            s.server.grant_body_credit(&mut s.pipe.server, stream, 10),
+            Ok(())
+        );
+
+        assert_eq!(s.poll_server(), Ok((stream, Event::Data)));
+        assert_eq!(s.recv_body_server(stream, &mut recv_buf[5..]), Ok(5));
+        assert_eq!(recv_buf, body);
+
+        assert_eq!(s.poll_server(), Ok((stream, Event::Finished)));
+        assert_eq!(s.poll_server(), Err(Error::Done));
+    }
+
+    #[test]
+    /// Tests accepting and rejecting requests that expect a 100 (Continue)
+    /// response.
+    fn expect_continue() {
+        let mut s = Session::new().unwrap();
+        s.handshake().unwrap();
+
+        let req = vec![
+            Header::new(b":method", b"POST"),
+            Header::new(b":scheme", b"https"),
+            Header::new(b":authority", b"quic.tech"),
+            Header::new(b":path", b"/upload"),
+            Header::new(b"expect", b"100-continue"),
+        ];
+
+        let stream = s
+            .client
+            .send_request(&mut s.pipe.client, &req, false)
+            .unwrap();
+        s.advance().ok();
+
+        let ev_headers = Event::Headers {
+            list: req.clone(),
+            has_body: true,
+        };
+
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+
+        // Accepting the body sends a 100 (Continue) response.
+        s.server
+            .grant_body_credit(&mut s.pipe.server, stream, 100)
+            .unwrap();
+        s.advance().ok();
+
+        let ev_interim = Event::InterimHeaders {
+            list: vec![Header::new(b":status", b"100")],
+        };
+
+        assert_eq!(s.poll_client(), Ok((stream, ev_interim)));
+        assert_eq!(s.poll_client(), Err(Error::Done));
+
+        let body = s.send_body_client(stream, true).unwrap();
+
+        let mut recv_buf = vec![0; body.len()];
+
+        assert_eq!(s.poll_server(), Ok((stream, Event::Data)));
+        assert_eq!(s.recv_body_server(stream, &mut recv_buf), Ok(body.len()));
+        assert_eq!(s.poll_server(), Ok((stream, Event::Finished)));
+
+        // A second upload is rejected before its body is sent.
+        let stream = s
+            .client
+            .send_request(&mut s.pipe.client, &req, false)
+            .unwrap();
+        s.advance().ok();
+
+        let ev_headers = Event::Headers {
+            list: req,
+            has_body: true,
+        };
+
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+
+        let resp = vec![Header::new(b":status", b"417")];
+
+        s.server
+            .reject_request_body(&mut s.pipe.server, stream, &resp)
+            .unwrap();
+        s.advance().ok();
+
+        let ev_headers = Event::Headers {
+            list: resp,
+            has_body: false,
+        };
+
+        assert_eq!(s.poll_client(), Ok((stream, ev_headers)));
+        assert_eq!(s.poll_client(), Ok((stream, Event::Finished)));
+
+        assert_eq!(
+            s.send_body_client(stream, true),
+            Err(Error::TransportError(crate::Error::StreamStopped(0x100)))
+        );
+    }
 }
 
+pub mod capsule;
 #[cfg(feature = "ffi")]
 mod ffi;
 #[cfg(feature = "internal")]
@@ -6285,3 +9250,4 @@ mod frame;
 #[doc(hidden)]
 pub mod qpack;
 mod stream;
+mod transport;
@@ -26,6 +26,7 @@
 
 use super::Error;
 use super::Result;
+use super::Transport;
 
 use super::frame;
 
@@ -151,9 +152,31 @@ pub struct Stream {
     /// Whether the stream has been locally initialized.
     local_initialized: bool,
 
+    /// Whether the final, non-interim, header section has been received.
+    headers_received: bool,
+
+    /// Whether a trailer section has been received.
+    trailers_received: bool,
+
+    /// Whether a trailer section has been sent.
+    trailers_sent: bool,
+
     /// Whether a `Data` event has been triggered for this stream.
     data_event_triggered: bool,
 
+    /// The amount of body data the application can still read, if limited.
+    body_credit: Option<u64>,
+
+    /// Whether the peer is waiting for a 100 (Continue) response before
+    /// sending the request body.
+    expect_continue: bool,
+
+    /// The value of the peer's `content-length` field, if enforced.
+    content_length: Option<u64>,
+
+    /// The total payload length of the DATA frames received so far.
+    data_len: u64,
+
     /// The last `PRIORITY_UPDATE` frame encoded field value, if any.
     last_priority_update: Option<Vec<u8>>,
 }
@@ -193,8 +216,18 @@ impl Stream {
             remote_initialized: false,
             local_initialized: false,
 
+            headers_received: false,
+            trailers_received: false,
+            trailers_sent: false,
+
             data_event_triggered: false,
 
+            body_credit: None,
+            expect_continue: false,
+
+            content_length: None,
+            data_len: 0,
+
             last_priority_update: None,
         }
     }
@@ -389,9 +422,7 @@ impl Stream {
     ///
     /// When not enough data can be read to complete the state, this returns
     /// `Error::Done`.
-    pub fn try_fill_buffer(
-        &mut self, conn: &mut crate::Connection,
-    ) -> Result<()> {
+    pub fn try_fill_buffer(&mut self, conn: &mut impl Transport) -> Result<()> {
         // If no bytes are required to be read, return early.
         if self.state_buffer_complete() {
             return Ok(());
@@ -440,6 +471,96 @@ impl Stream {
         self.local_initialized
     }
 
+    /// Records the reception of the final header section.
+    pub fn set_headers_received(&mut self) {
+        self.headers_received = true;
+    }
+
+    /// Whether the final header section has been received.
+    pub fn headers_received(&self) -> bool {
+        self.headers_received
+    }
+
+    /// Records the reception of a trailer section.
+    pub fn set_trailers_received(&mut self) {
+        self.trailers_received = true;
+    }
+
+    /// Whether a trailer section has been received.
+    pub fn trailers_received(&self) -> bool {
+        self.trailers_received
+    }
+
+    /// Records that a trailer section was sent.
+    pub fn set_trailers_sent(&mut self) {
+        self.trailers_sent = true;
+    }
+
+    /// Whether a trailer section has been sent.
+    pub fn trailers_sent(&self) -> bool {
+        self.trailers_sent
+    }
+
+    /// Sets the amount of body data the application can read, or `None` if
+    /// unlimited.
+    pub fn set_body_credit(&mut self, credit: Option<u64>) {
+        self.body_credit = credit;
+    }
+
+    /// Returns the amount of body data the application can still read, or
+    /// `None` if unlimited.
+    pub fn body_credit(&self) -> Option<u64> {
+        self.body_credit
+    }
+
+    /// Increases the amount of body data the application can read.
+    pub fn add_body_credit(&mut self, credit: u64) {
+        if let Some(v) = self.body_credit.as_mut() {
+            *v = v.saturating_add(credit);
+        }
+    }
+
+    /// Records that `len` bytes of body data were read by the application.
+    pub fn consume_body_credit(&mut self, len: usize) {
+        if let Some(v) = self.body_credit.as_mut() {
+            *v = v.saturating_sub(len as u64);
+        }
+    }
+
+    /// Sets whether the peer is waiting for a 100 (Continue) response.
+    pub fn set_expect_continue(&mut self, v: bool) {
+        self.expect_continue = v;
+    }
+
+    /// Whether the peer is waiting for a 100 (Continue) response.
+    pub fn expect_continue(&self) -> bool {
+        self.expect_continue
+    }
+
+    /// Sets the body length announced by the peer that is enforced.
+    pub fn set_content_length(&mut self, len: Option<u64>) {
+        self.content_length = len;
+    }
+
+    /// Records the payload length of a received DATA frame.
+    pub fn add_data_len(&mut self, len: u64) {
+        self.data_len = self.data_len.saturating_add(len);
+    }
+
+    /// Whether the received DATA doesn't match the enforced body length.
+    ///
+    /// Before the stream is `finished`, only receiving more data than
+    /// announced is a mismatch.
+    pub fn content_length_mismatch(&self, finished: bool) -> bool {
+        match self.content_length {
+            Some(len) if finished => self.data_len != len,
+
+            Some(len) => self.data_len > len,
+
+            None => false,
+        }
+    }
+
     /// Tries to fill the state buffer by reading data from the given cursor.
     ///
     /// This is intended to replace `try_fill_buffer()` in tests, in order to
@@ -507,7 +628,7 @@ impl Stream {
 
     /// Tries to read DATA payload from the transport stream.
     pub fn try_consume_data(
-        &mut self, conn: &mut crate::Connection, out: &mut [u8],
+        &mut self, conn: &mut impl Transport, out: &mut [u8],
     ) -> Result<(usize, bool)> {
         let left = std::cmp::min(out.len(), self.state_len - self.state_off);
 
@@ -579,7 +700,7 @@ impl Stream {
     }
 
     /// Resets the data triggered state.
-    fn reset_data_event(&mut self) {
+    pub fn reset_data_event(&mut self) {
         self.data_event_triggered = false;
     }
 
@@ -593,6 +714,11 @@ impl Stream {
         self.last_priority_update.take()
     }
 
+    /// Returns the last priority update, if any, without taking it.
+    pub fn last_priority_update(&self) -> Option<&[u8]> {
+        self.last_priority_update.as_deref()
+    }
+
     /// Returns `true` if there is a priority update.
     pub fn has_last_priority_update(&self) -> bool {
         self.last_priority_update.is_some()
@@ -0,0 +1,306 @@
+// Copyright (C) 2023, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! The QUIC transport HTTP/3 runs on top of.
+
+use std::net::SocketAddr;
+
+use crate::ConnectionError;
+use crate::Error;
+use crate::Result;
+use crate::Shutdown;
+
+use crate::tls::TlsHandshake;
+
+/// The operations an HTTP/3 connection needs from the underlying QUIC
+/// connection.
+///
+/// It is implemented by quiche's own [`Connection`], but it can also be
+/// implemented by other QUIC stacks, or by in-process pipes for testing.
+///
+/// Methods mirror the ones of [`Connection`] with the same name, and the
+/// ones with a default implementation cover optional features, such as
+/// datagrams or early data, that transports are not required to support.
+///
+/// [`Connection`]: ../struct.Connection.html
+pub trait Transport {
+    /// Returns a string uniquely representing the connection, for logging.
+    fn trace_id(&self) -> &str;
+
+    /// Returns true if the connection is a server.
+    fn is_server(&self) -> bool;
+
+    /// Returns true if the connection handshake is complete.
+    fn is_established(&self) -> bool;
+
+    /// Closes the connection with the given error and reason.
+    fn close(&mut self, app: bool, err: u64, reason: &[u8]) -> Result<()>;
+
+    /// Writes data to a stream, returning the number of bytes written.
+    fn stream_send(
+        &mut self, stream_id: u64, buf: &[u8], fin: bool,
+    ) -> Result<usize>;
+
+    /// Reads contiguous data from a stream into the provided slice.
+    fn stream_recv(
+        &mut self, stream_id: u64, out: &mut [u8],
+    ) -> Result<(usize, bool)>;
+
+    /// Shuts down reading or writing from/to the specified stream.
+    fn stream_shutdown(
+        &mut self, stream_id: u64, direction: Shutdown, err: u64,
+    ) -> Result<()>;
+
+    /// Sets the priority for a stream.
+    fn stream_priority(
+        &mut self, _stream_id: u64, _urgency: u8, _incremental: bool,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    /// Returns the stream's send capacity in bytes.
+    fn stream_capacity(&self, stream_id: u64) -> Result<usize>;
+
+    /// Returns true if the stream has enough send capacity for `len` bytes.
+    fn stream_writable(&mut self, stream_id: u64, len: usize) -> Result<bool>;
+
+    /// Returns true if all the data has been read from the specified stream.
+    fn stream_finished(&self, stream_id: u64) -> bool;
+
+    /// Returns true if the stream has data that can be read.
+    fn stream_readable(&self, stream_id: u64) -> bool;
+
+    /// Returns true if the stream was opened and not yet collected.
+    fn stream_exists(&self, stream_id: u64) -> bool;
+
+    /// Returns true if the stream was already collected.
+    fn stream_is_collected(&self, _stream_id: u64) -> bool {
+        false
+    }
+
+    /// Returns the maximum number of bidirectional streams the peer is
+    /// allowed to open.
+    fn max_streams_bidi(&self) -> u64;
+
+    /// Appends the streams that have outstanding data to read to `out`.
+    ///
+    /// The caller reuses the same vector across calls, so that polling the
+    /// connection doesn't allocate.
+    fn readable(&self, out: &mut Vec<u64>);
+
+    /// Returns the error that the connection was closed with locally, if any.
+    fn local_error(&self) -> Option<&ConnectionError> {
+        None
+    }
+
+    /// Returns true if the connection is resumed and in the early data phase.
+    fn is_in_early_data(&self) -> bool {
+        false
+    }
+
+    /// Returns true if the early data sent by the client was rejected.
+    fn is_early_data_rejected(&self) -> bool {
+        false
+    }
+
+    /// Returns the server name requested by the client.
+    fn server_name(&self) -> Option<&str> {
+        None
+    }
+
+    /// Returns the address of the peer on the active path.
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    /// Returns true if the certificate presented by the peer is valid for
+    /// `host`.
+    fn peer_cert_covers_host(&self, _host: &str) -> bool {
+        false
+    }
+
+    /// Returns true if GREASE should be sent.
+    fn grease(&self) -> bool {
+        false
+    }
+
+    /// Returns true if datagrams were enabled locally.
+    fn dgram_enabled(&self) -> bool {
+        false
+    }
+
+    /// Returns the maximum datagram payload that can be sent.
+    fn dgram_max_writable_len(&self) -> Option<usize> {
+        None
+    }
+
+    /// Reads the first received datagram.
+    fn dgram_recv(&mut self, _buf: &mut [u8]) -> Result<usize> {
+        Err(Error::Done)
+    }
+
+    /// Sends data in a datagram.
+    fn dgram_send_vec(&mut self, _buf: Vec<u8>) -> Result<()> {
+        Err(Error::InvalidState)
+    }
+
+    /// Returns the qlog streamer, if events of the given type are logged.
+    #[cfg(feature = "qlog")]
+    fn qlog_streamer(
+        &mut self, _ty: qlog::events::EventType,
+    ) -> Option<&mut qlog::streamer::QlogStreamer> {
+        None
+    }
+}
+
+impl Transport for crate::Connection {
+    fn trace_id(&self) -> &str {
+        crate::Connection::trace_id(self)
+    }
+
+    fn is_server(&self) -> bool {
+        self.is_server
+    }
+
+    fn is_established(&self) -> bool {
+        crate::Connection::is_established(self)
+    }
+
+    fn close(&mut self, app: bool, err: u64, reason: &[u8]) -> Result<()> {
+        crate::Connection::close(self, app, err, reason)
+    }
+
+    fn stream_send(
+        &mut self, stream_id: u64, buf: &[u8], fin: bool,
+    ) -> Result<usize> {
+        crate::Connection::stream_send(self, stream_id, buf, fin)
+    }
+
+    fn stream_recv(
+        &mut self, stream_id: u64, out: &mut [u8],
+    ) -> Result<(usize, bool)> {
+        crate::Connection::stream_recv(self, stream_id, out)
+    }
+
+    fn stream_shutdown(
+        &mut self, stream_id: u64, direction: Shutdown, err: u64,
+    ) -> Result<()> {
+        crate::Connection::stream_shutdown(self, stream_id, direction, err)
+    }
+
+    fn stream_priority(
+        &mut self, stream_id: u64, urgency: u8, incremental: bool,
+    ) -> Result<()> {
+        crate::Connection::stream_priority(self, stream_id, urgency, incremental)
+    }
+
+    fn stream_capacity(&self, stream_id: u64) -> Result<usize> {
+        crate::Connection::stream_capacity(self, stream_id)
+    }
+
+    fn stream_writable(&mut self, stream_id: u64, len: usize) -> Result<bool> {
+        crate::Connection::stream_writable(self, stream_id, len)
+    }
+
+    fn stream_finished(&self, stream_id: u64) -> bool {
+        crate::Connection::stream_finished(self, stream_id)
+    }
+
+    fn stream_readable(&self, stream_id: u64) -> bool {
+        crate::Connection::stream_readable(self, stream_id)
+    }
+
+    fn stream_exists(&self, stream_id: u64) -> bool {
+        self.streams.get(stream_id).is_some()
+    }
+
+    fn stream_is_collected(&self, stream_id: u64) -> bool {
+        self.streams.is_collected(stream_id)
+    }
+
+    fn max_streams_bidi(&self) -> u64 {
+        self.streams.max_streams_bidi()
+    }
+
+    fn readable(&self, out: &mut Vec<u64>) {
+        out.extend(crate::Connection::readable(self));
+    }
+
+    fn local_error(&self) -> Option<&ConnectionError> {
+        crate::Connection::local_error(self)
+    }
+
+    fn is_in_early_data(&self) -> bool {
+        crate::Connection::is_in_early_data(self)
+    }
+
+    fn is_early_data_rejected(&self) -> bool {
+        crate::Connection::is_early_data_rejected(self)
+    }
+
+    fn server_name(&self) -> Option<&str> {
+        crate::Connection::server_name(self)
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.paths.get_active().ok().map(|p| p.peer_addr())
+    }
+
+    fn peer_cert_covers_host(&self, host: &str) -> bool {
+        self.handshake.peer_cert_covers_host(host)
+    }
+
+    fn grease(&self) -> bool {
+        self.grease
+    }
+
+    fn dgram_enabled(&self) -> bool {
+        crate::Connection::dgram_enabled(self)
+    }
+
+    fn dgram_max_writable_len(&self) -> Option<usize> {
+        crate::Connection::dgram_max_writable_len(self)
+    }
+
+    fn dgram_recv(&mut self, buf: &mut [u8]) -> Result<usize> {
+        crate::Connection::dgram_recv(self, buf)
+    }
+
+    fn dgram_send_vec(&mut self, buf: Vec<u8>) -> Result<()> {
+        crate::Connection::dgram_send_vec(self, buf)
+    }
+
+    #[cfg(feature = "qlog")]
+    fn qlog_streamer(
+        &mut self, ty: qlog::events::EventType,
+    ) -> Option<&mut qlog::streamer::QlogStreamer> {
+        if !self.qlog.allows(ty) {
+            return None;
+        }
+
+        self.qlog.streamer.as_mut()
+    }
+}
@@ -365,15 +365,33 @@
 //!   [boring] crate. It takes precedence over `boringssl-vendored` if both
 //!   features are enabled.
 //!
+//! * `rustls`: Use [rustls] for TLS, and [ring] for packet protection, instead
+//!   of BoringSSL. Session resumption, 0-RTT, application settings, ticket keys
+//!   and signature algorithm preferences are not supported, and neither is
+//!   `quiche_conn_new_with_tls()` in the FFI API. It can't be combined with
+//!   `boringssl-boring-crate`.
+//!
 //! * `pkg-config-meta`: Generate pkg-config metadata file for libquiche.
 //!
 //! * `ffi`: Build and expose the FFI API.
 //!
 //! * `qlog`: Enable support for the [qlog] logging format.
 //!
+//! * `tracing`: Instrument connections with [tracing] spans and events, in
+//!   addition to the existing [log] messages.
+//!
+//! * `tokio`: Expose the [tokio module], which drives connections on the
+//!   [Tokio] runtime.
+//!
 //! [feature flags]: https://doc.rust-lang.org/cargo/reference/manifest.html#the-features-section
 //! [boring]: https://crates.io/crates/boring
+//! [rustls]: https://crates.io/crates/rustls
+//! [ring]: https://crates.io/crates/ring
 //! [qlog]: https://datatracker.ietf.org/doc/html/draft-ietf-quic-qlog-main-schema
+//! [tracing]: https://crates.io/crates/tracing
+//! [tokio module]: tokio/index.html
+//! [Tokio]: https://tokio.rs/
+//! [log]: https://crates.io/crates/log
 
 #![allow(clippy::upper_case_acronyms)]
 #![warn(missing_docs)]
@@ -382,6 +400,13 @@
 #[macro_use]
 extern crate log;
 
+#[cfg(all(feature = "rustls", feature = "boringssl-boring-crate"))]
+compile_error!(
+    "the `rustls` and `boringssl-boring-crate` features are mutually exclusive"
+);
+
+#[cfg(feature = "qlog")]
+use qlog::events::connectivity::ConnectivityEventType;
 #[cfg(feature = "qlog")]
 use qlog::events::connectivity::TransportOwner;
 #[cfg(feature = "qlog")]
@@ -401,14 +426,21 @@ use qlog::events::EventType;
 #[cfg(feature = "qlog")]
 use qlog::events::RawInfo;
 use stream::StreamPriorityKey;
+use tls::TlsContext;
+use tls::TlsHandshake;
 
 use std::cmp;
+use std::convert::TryFrom;
 use std::convert::TryInto;
 use std::time;
 
 use std::sync::Arc;
 
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
 use std::net::SocketAddr;
+use std::net::SocketAddrV4;
+use std::net::SocketAddrV6;
 
 use std::str::FromStr;
 
@@ -420,8 +452,18 @@ use smallvec::SmallVec;
 /// The current QUIC wire version.
 pub const PROTOCOL_VERSION: u32 = PROTOCOL_VERSION_V1;
 
-/// Supported QUIC versions.
-const PROTOCOL_VERSION_V1: u32 = 0x0000_0001;
+/// QUIC version 1, as defined in [RFC 9000].
+///
+/// [RFC 9000]: https://www.rfc-editor.org/rfc/rfc9000
+pub const PROTOCOL_VERSION_V1: u32 = 0x0000_0001;
+
+/// QUIC version 2, as defined in [RFC 9369].
+///
+/// Version 2 must be enabled with [`enable_quic_v2()`] to be used.
+///
+/// [RFC 9369]: https://www.rfc-editor.org/rfc/rfc9369
+/// [`enable_quic_v2()`]: struct.Config.html#method.enable_quic_v2
+pub const PROTOCOL_VERSION_V2: u32 = 0x6b33_43cf;
 
 /// The maximum length of a connection ID.
 pub const MAX_CONN_ID_LEN: usize = crate::packet::MAX_CID_LEN as usize;
@@ -489,6 +531,33 @@ const MAX_PROBING_TIMEOUTS: usize = 3;
 // The default initial congestion window size in terms of packet count.
 const DEFAULT_INITIAL_CONGESTION_WINDOW_PACKETS: usize = 10;
 
+// The default minimum congestion window size in terms of packet count.
+const DEFAULT_MINIMUM_CONGESTION_WINDOW_PACKETS: usize = 2;
+
+// The default number of sent packet records kept for reuse by each path.
+const DEFAULT_PACKET_POOL_SIZE: usize = 64;
+
+// The default number of stream and crypto data buffers kept for reuse by each
+// connection.
+const DEFAULT_BUFFER_POOL_SIZE: usize = 16;
+
+// The experimental transport parameter used to negotiate FEC support.
+const FEC_TRANSPORT_PARAM: u64 = 0xfec0;
+
+// The transport parameter used to negotiate support for RESET_STREAM_AT.
+const RESET_STREAM_AT_TRANSPORT_PARAM: u64 = 0x17f7586d2cb571;
+
+// The transport parameter used to advertise that packets with the QUIC bit
+// cleared are accepted (RFC 9287).
+const GREASE_QUIC_BIT_TRANSPORT_PARAM: u64 = 0x2ab2;
+
+// The keepalive interval is reduced by a random amount of up to this fraction,
+// so that connections created together don't send their PINGs in lockstep.
+const KEEPALIVE_JITTER_DIVISOR: u32 = 8;
+
+// The version of the serialization format used by `Connection::freeze()`.
+const FREEZE_FORMAT_VERSION: u8 = 1;
+
 /// A specialized [`Result`] type for quiche operations.
 ///
 /// This type is used throughout quiche's public API for any operation that
@@ -567,21 +636,28 @@ pub enum Error {
 
     /// Error in key update.
     KeyUpdate,
+
+    /// The peer's version negotiation is inconsistent with the version in use.
+    VersionNegotiation,
 }
 
 impl Error {
     fn to_wire(self) -> u64 {
-        match self {
-            Error::Done => 0x0,
-            Error::InvalidFrame => 0x7,
-            Error::InvalidStreamState(..) => 0x5,
-            Error::InvalidTransportParam => 0x8,
-            Error::FlowControl => 0x3,
-            Error::StreamLimit => 0x4,
-            Error::FinalSize => 0x6,
-            Error::KeyUpdate => 0xe,
-            _ => 0xa,
-        }
+        let code = match self {
+            Error::Done => WireErrorCode::NoError,
+            Error::InvalidFrame => WireErrorCode::FrameEncodingError,
+            Error::InvalidStreamState(..) => WireErrorCode::StreamStateError,
+            Error::InvalidTransportParam =>
+                WireErrorCode::TransportParameterError,
+            Error::FlowControl => WireErrorCode::FlowControlError,
+            Error::StreamLimit => WireErrorCode::StreamLimitError,
+            Error::FinalSize => WireErrorCode::FinalSizeError,
+            Error::KeyUpdate => WireErrorCode::KeyUpdateError,
+            Error::VersionNegotiation => WireErrorCode::VersionNegotiationError,
+            _ => WireErrorCode::ProtocolViolation,
+        };
+
+        u64::from(code)
     }
 
     #[cfg(feature = "ffi")]
@@ -606,6 +682,7 @@ impl Error {
             Error::IdLimit => -17,
             Error::OutOfIdentifiers => -18,
             Error::KeyUpdate => -19,
+            Error::VersionNegotiation => -20,
         }
     }
 }
@@ -655,6 +732,139 @@ pub struct SendInfo {
     pub at: time::Instant,
 }
 
+/// Which QUIC packets can be coalesced into the same UDP datagram.
+///
+/// This enum is set with [`set_coalescing_policy()`].
+///
+/// [`set_coalescing_policy()`]: struct.Config.html#method.set_coalescing_policy
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(C)]
+pub enum CoalescingPolicy {
+    /// Packets of any type are coalesced (default).
+    All            = 0,
+
+    /// Initial and Handshake packets are coalesced together, but 1-RTT packets
+    /// are always sent in their own UDP datagram.
+    LongHeaderOnly = 1,
+
+    /// Each QUIC packet is sent in its own UDP datagram.
+    Disabled       = 2,
+}
+
+/// Which UDP datagrams containing a QUIC Initial packet are padded.
+///
+/// This enum is set with [`set_initial_padding_policy()`].
+///
+/// [`set_initial_padding_policy()`]: struct.Config.html#method.set_initial_padding_policy
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(C)]
+pub enum InitialPaddingPolicy {
+    /// All datagrams containing an Initial packet are padded to at least
+    /// 1200 bytes, as required by [RFC 9000] (default).
+    ///
+    /// [RFC 9000]: https://www.rfc-editor.org/rfc/rfc9000#section-14.1
+    All       = 0,
+
+    /// Only the first datagram containing an Initial packet is padded.
+    ///
+    /// Note that peers are allowed to discard Initial packets carried in
+    /// smaller datagrams, so this should only be used towards peers known to
+    /// accept them.
+    FirstOnly = 1,
+}
+
+/// The reason why [`send()`] didn't write any packet.
+///
+/// This can be retrieved using [`send_blocked_reason()`] after [`send()`]
+/// returned [`Done`], so that applications can decide when it's worth calling
+/// [`send()`] again, instead of polling.
+///
+/// Note that by default pacing never prevents packets from being written. The
+/// time at which a packet should be sent is provided by [`SendInfo.at`]
+/// instead, unless a horizon is set with [`set_pacing_horizon()`].
+///
+/// [`send()`]: struct.Connection.html#method.send
+/// [`send_blocked_reason()`]: struct.Connection.html#method.send_blocked_reason
+/// [`Done`]: enum.Error.html#variant.Done
+/// [`SendInfo.at`]: struct.SendInfo.html#structfield.at
+/// [`set_pacing_horizon()`]: struct.Config.html#method.set_pacing_horizon
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SendBlockedReason {
+    /// The server reached the anti-amplification limit, and needs to receive
+    /// more data from the client (or validate its address) before sending
+    /// more.
+    AmplificationLimit,
+
+    /// The congestion window is full, sending will resume once in-flight data
+    /// is acknowledged or declared lost.
+    CongestionWindow,
+
+    /// There is application data to send, but the handshake hasn't progressed
+    /// enough for it to be sent.
+    Handshake,
+
+    /// The next packet is held back by pacing until the given time, as
+    /// configured with [`set_pacing_horizon()`].
+    ///
+    /// [`set_pacing_horizon()`]: struct.Config.html#method.set_pacing_horizon
+    Pacing(time::Instant),
+}
+
+/// The timer responsible for the next timeout event.
+///
+/// This is returned by [`next_timeout_reason()`], alongside the time returned
+/// by [`timeout_instant()`].
+///
+/// Note that pacing and acknowledgements don't use timers: the time at which
+/// a packet should be sent is provided by [`SendInfo.at`], and ACKs are sent
+/// with the next packet.
+///
+/// [`next_timeout_reason()`]: struct.Connection.html#method.next_timeout_reason
+/// [`timeout_instant()`]: struct.Connection.html#method.timeout_instant
+/// [`SendInfo.at`]: struct.SendInfo.html#structfield.at
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimeoutReason {
+    /// The connection is draining, and will be closed when the timer expires.
+    Draining,
+
+    /// No packet was exchanged with the peer for the idle timeout period.
+    Idle,
+
+    /// The loss detection or probe timeout (PTO) timer of a path, which
+    /// also covers path validation probes.
+    LossDetection,
+
+    /// The keys of the previous key phase can be discarded.
+    KeyUpdate,
+
+    /// The deadline of a stream, as set by [`stream_set_deadline()`].
+    ///
+    /// [`stream_set_deadline()`]:
+    /// struct.Connection.html#method.stream_set_deadline
+    StreamDeadline,
+
+    /// Stream count credit can be given back to the peer, as delayed by
+    /// [`MaxStreamsPolicy::RateLimited`].
+    ///
+    /// [`MaxStreamsPolicy::RateLimited`]:
+    /// enum.MaxStreamsPolicy.html#variant.RateLimited
+    MaxStreams,
+
+    /// A PING frame needs to be sent to keep the connection alive, as
+    /// configured with [`set_keepalive_interval()`].
+    ///
+    /// [`set_keepalive_interval()`]:
+    /// struct.Config.html#method.set_keepalive_interval
+    Keepalive,
+
+    /// A delayed ACK needs to be sent, as configured with
+    /// [`set_ack_eliciting_threshold()`].
+    ///
+    /// [`set_ack_eliciting_threshold()`]:
+    /// struct.Config.html#method.set_ack_eliciting_threshold
+    AckDelay,
+}
+
 /// Represents information carried by `CONNECTION_CLOSE` frames.
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct ConnectionError {
@@ -668,6 +878,154 @@ pub struct ConnectionError {
     pub reason: Vec<u8>,
 }
 
+impl ConnectionError {
+    /// Returns the transport error code, if this is a transport error with
+    /// one of the codes defined by QUIC.
+    pub fn wire_error_code(&self) -> Option<WireErrorCode> {
+        if self.is_app {
+            return None;
+        }
+
+        WireErrorCode::try_from(self.error_code).ok()
+    }
+
+    /// Returns the TLS alert that caused the connection to fail, if this is a
+    /// transport error in the `CRYPTO_ERROR` range.
+    pub fn tls_alert(&self) -> Option<u8> {
+        if self.is_app || !(0x100..=0x1ff).contains(&self.error_code) {
+            return None;
+        }
+
+        Some((self.error_code - 0x100) as u8)
+    }
+}
+
+/// QUIC transport error codes, as carried by `CONNECTION_CLOSE` frames.
+///
+/// The `CRYPTO_ERROR` range, which carries TLS alerts, isn't represented
+/// here. See [`ConnectionError::tls_alert()`] instead.
+///
+/// [`ConnectionError::tls_alert()`]:
+/// struct.ConnectionError.html#method.tls_alert
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WireErrorCode {
+    /// The connection is being closed abruptly in the absence of any error.
+    NoError,
+
+    /// The endpoint encountered an internal error and cannot continue.
+    InternalError,
+
+    /// The server refused to accept a new connection.
+    ConnectionRefused,
+
+    /// An endpoint received more data than it permitted in its advertised
+    /// data limits.
+    FlowControlError,
+
+    /// An endpoint received a frame for a stream identifier that exceeded its
+    /// advertised stream limit.
+    StreamLimitError,
+
+    /// An endpoint received a frame for a stream that was not in a state that
+    /// permitted that frame.
+    StreamStateError,
+
+    /// An endpoint received data inconsistent with a stream's final size.
+    FinalSizeError,
+
+    /// An endpoint received a frame that was badly formatted.
+    FrameEncodingError,
+
+    /// An endpoint received transport parameters that were badly formatted,
+    /// invalid or missing.
+    TransportParameterError,
+
+    /// The peer provided more connection IDs than allowed.
+    ConnectionIdLimitError,
+
+    /// An endpoint detected a protocol compliance error not covered by a more
+    /// specific code.
+    ProtocolViolation,
+
+    /// A server received a client Initial that contained an invalid token.
+    InvalidToken,
+
+    /// The application or application protocol caused the connection to be
+    /// closed.
+    ApplicationError,
+
+    /// An endpoint received more data in CRYPTO frames than it can buffer.
+    CryptoBufferExceeded,
+
+    /// An endpoint detected errors in performing key updates.
+    KeyUpdateError,
+
+    /// An endpoint reached the confidentiality or integrity limit for the
+    /// AEAD algorithm.
+    AeadLimitReached,
+
+    /// No network path is able to support the connection.
+    NoViablePath,
+
+    /// The peer's version negotiation is inconsistent with the version in use.
+    VersionNegotiationError,
+}
+
+impl std::convert::TryFrom<u64> for WireErrorCode {
+    type Error = u64;
+
+    fn try_from(code: u64) -> std::result::Result<Self, u64> {
+        let code = match code {
+            0x0 => WireErrorCode::NoError,
+            0x1 => WireErrorCode::InternalError,
+            0x2 => WireErrorCode::ConnectionRefused,
+            0x3 => WireErrorCode::FlowControlError,
+            0x4 => WireErrorCode::StreamLimitError,
+            0x5 => WireErrorCode::StreamStateError,
+            0x6 => WireErrorCode::FinalSizeError,
+            0x7 => WireErrorCode::FrameEncodingError,
+            0x8 => WireErrorCode::TransportParameterError,
+            0x9 => WireErrorCode::ConnectionIdLimitError,
+            0xa => WireErrorCode::ProtocolViolation,
+            0xb => WireErrorCode::InvalidToken,
+            0xc => WireErrorCode::ApplicationError,
+            0xd => WireErrorCode::CryptoBufferExceeded,
+            0xe => WireErrorCode::KeyUpdateError,
+            0xf => WireErrorCode::AeadLimitReached,
+            0x10 => WireErrorCode::NoViablePath,
+            0x11 => WireErrorCode::VersionNegotiationError,
+            _ => return Err(code),
+        };
+
+        Ok(code)
+    }
+}
+
+impl From<WireErrorCode> for u64 {
+    fn from(code: WireErrorCode) -> u64 {
+        match code {
+            WireErrorCode::NoError => 0x0,
+            WireErrorCode::InternalError => 0x1,
+            WireErrorCode::ConnectionRefused => 0x2,
+            WireErrorCode::FlowControlError => 0x3,
+            WireErrorCode::StreamLimitError => 0x4,
+            WireErrorCode::StreamStateError => 0x5,
+            WireErrorCode::FinalSizeError => 0x6,
+            WireErrorCode::FrameEncodingError => 0x7,
+            WireErrorCode::TransportParameterError => 0x8,
+            WireErrorCode::ConnectionIdLimitError => 0x9,
+            WireErrorCode::ProtocolViolation => 0xa,
+            WireErrorCode::InvalidToken => 0xb,
+            WireErrorCode::ApplicationError => 0xc,
+            WireErrorCode::CryptoBufferExceeded => 0xd,
+            WireErrorCode::KeyUpdateError => 0xe,
+            WireErrorCode::AeadLimitReached => 0xf,
+            WireErrorCode::NoViablePath => 0x10,
+            WireErrorCode::VersionNegotiationError => 0x11,
+        }
+    }
+}
+
 /// The side of the stream to be shut down.
 ///
 /// This should be used when calling [`stream_shutdown()`].
@@ -698,37 +1056,345 @@ pub enum QlogLevel {
     Extra = 2,
 }
 
+/// Qlog event category, used to filter qlog output.
+///
+/// See [`set_qlog_filter()`].
+///
+/// [`set_qlog_filter()`]: struct.Config.html#method.set_qlog_filter
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg(feature = "qlog")]
+#[cfg_attr(docsrs, doc(cfg(feature = "qlog")))]
+pub enum QlogCategory {
+    /// Connectivity events, e.g. connection state changes.
+    Connectivity = 0,
+
+    /// Transport events, e.g. packets sent and received.
+    Transport    = 1,
+
+    /// Security events, e.g. key updates.
+    Security     = 2,
+
+    /// Recovery events, e.g. congestion control and loss detection state.
+    Recovery     = 3,
+
+    /// HTTP/3 events.
+    Http3        = 4,
+
+    /// QPACK events.
+    Qpack        = 5,
+
+    /// Generic events.
+    Generic      = 6,
+}
+
+#[cfg(feature = "qlog")]
+impl QlogCategory {
+    fn from_event_type(ty: EventType) -> QlogCategory {
+        match ty {
+            EventType::ConnectivityEventType(_) => QlogCategory::Connectivity,
+
+            EventType::TransportEventType(_) => QlogCategory::Transport,
+
+            EventType::SecurityEventType(_) => QlogCategory::Security,
+
+            EventType::RecoveryEventType(_) => QlogCategory::Recovery,
+
+            EventType::Http3EventType(_) => QlogCategory::Http3,
+
+            EventType::QpackEventType(_) => QlogCategory::Qpack,
+
+            EventType::GenericEventType(_) | EventType::None =>
+                QlogCategory::Generic,
+        }
+    }
+}
+
+/// Detects replayed 0-RTT connection attempts on the server.
+///
+/// Early data is not protected against replay by TLS, so servers that accept
+/// it can install an implementation of this trait with
+/// [`set_early_data_replay_filter()`] to only accept early data once for any
+/// given connection attempt.
+///
+/// [`set_early_data_replay_filter()`]: struct.Config.html#method.set_early_data_replay_filter
+pub trait EarlyDataReplayFilter: Send + Sync {
+    /// Records the connection attempt identified by `odcid` (the original
+    /// Destination Connection ID chosen by the client), and returns true if
+    /// it was already seen before.
+    ///
+    /// When this returns true early data is rejected, and the client will
+    /// have to retransmit it after the handshake completes.
+    fn is_replay(&self, odcid: &[u8]) -> bool;
+}
+
+/// Persists TLS sessions on the client, so they can be resumed by later
+/// connections to the same server.
+///
+/// When a store is configured with [`set_session_store()`], sessions
+/// received from the server are saved automatically, and new connections
+/// created with [`connect()`] look up a previous session for their server
+/// name, so applications don't need to call [`session()`] and
+/// [`set_session()`] themselves.
+///
+/// Sessions are opaque blobs in the same format used by [`session()`],
+/// which also includes the server's transport parameters.
+///
+/// [`set_session_store()`]: struct.Config.html#method.set_session_store
+/// [`connect()`]: fn.connect.html
+/// [`session()`]: struct.Connection.html#method.session
+/// [`set_session()`]: struct.Connection.html#method.set_session
+pub trait SessionStore: Send + Sync {
+    /// Returns the latest session stored for `server_name`, if any.
+    fn get(&self, server_name: &str) -> Option<Vec<u8>>;
+
+    /// Stores a new session for `server_name`, replacing any previous one.
+    fn put(&self, server_name: &str, session: &[u8]);
+
+    /// Returns the latest address validation token received from
+    /// `server_name` in a NEW_TOKEN frame, if any.
+    ///
+    /// The token is sent in the Initial packets of new connections, so that
+    /// the server can skip address validation. By default no token is
+    /// returned.
+    fn get_token(&self, _server_name: &str) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Stores an address validation token received from `server_name`,
+    /// replacing any previous one.
+    ///
+    /// By default tokens are discarded.
+    fn put_token(&self, _server_name: &str, _token: &[u8]) {}
+}
+
+/// The direction of a packet reported to a [`PacketCapture`].
+///
+/// [`PacketCapture`]: trait.PacketCapture.html
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PacketDirection {
+    /// The packet was sent by the connection.
+    Sent,
+
+    /// The packet was received by the connection.
+    Received,
+}
+
+/// A QUIC packet reported to a [`PacketCapture`].
+///
+/// [`PacketCapture`]: trait.PacketCapture.html
+#[derive(Clone, Copy, Debug)]
+pub struct CapturedPacket<'a> {
+    /// Whether the packet was sent or received.
+    pub direction: PacketDirection,
+
+    /// The type of the packet.
+    pub ty: Type,
+
+    /// The packet number.
+    pub pkt_num: u64,
+
+    /// The local or remote address the packet was sent from.
+    pub from: SocketAddr,
+
+    /// The local or remote address the packet was sent to.
+    pub to: SocketAddr,
+
+    /// The time the packet was sent or received.
+    pub time: time::Instant,
+
+    /// The protected packet, as sent or received on the wire.
+    ///
+    /// When multiple packets are coalesced in a single UDP datagram, each of
+    /// them is reported separately.
+    pub raw: &'a [u8],
+
+    /// The decrypted payload of the packet, containing its frames.
+    pub payload: &'a [u8],
+}
+
+/// Receives the packets sent and received by a connection, for example to
+/// write them in a capture file or to feed a live debugging tool, without
+/// having to decrypt a network capture with a keylog.
+///
+/// A capture is installed with [`set_packet_capture()`].
+///
+/// [`set_packet_capture()`]: struct.Connection.html#method.set_packet_capture
+pub trait PacketCapture: Send + Sync {
+    /// Called for every packet successfully sent or received.
+    ///
+    /// Received packets are reported once decrypted, so packets that are
+    /// dropped before that are not.
+    fn on_packet(&mut self, pkt: &CapturedPacket);
+}
+
+/// Application callback used to verify the peer's certificate chain, see
+/// [`set_verify_callback()`].
+///
+/// [`set_verify_callback()`]: struct.Config.html#method.set_verify_callback
+pub type VerifyCallback = dyn Fn(&[&[u8]]) -> bool + Send + Sync;
+
+/// Application callback invoked with the ID and value of each unknown
+/// transport parameter received from the peer, see
+/// [`set_custom_transport_param_callback()`].
+///
+/// [`set_custom_transport_param_callback()`]:
+/// struct.Config.html#method.set_custom_transport_param_callback
+pub type TransportParamCallback = dyn Fn(u64, &[u8]) + Send + Sync;
+
+/// A TLS 1.3 cipher suite.
+///
+/// The discriminants are the suites' IANA code points.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CipherSuite {
+    /// TLS_AES_128_GCM_SHA256.
+    Aes128GcmSha256 = 0x1301,
+
+    /// TLS_AES_256_GCM_SHA384.
+    Aes256GcmSha384 = 0x1302,
+
+    /// TLS_CHACHA20_POLY1305_SHA256.
+    ChaCha20Poly1305Sha256 = 0x1303,
+}
+
+impl CipherSuite {
+    fn algorithm(self) -> crypto::Algorithm {
+        match self {
+            CipherSuite::Aes128GcmSha256 => crypto::Algorithm::AES128_GCM,
+
+            CipherSuite::Aes256GcmSha384 => crypto::Algorithm::AES256_GCM,
+
+            CipherSuite::ChaCha20Poly1305Sha256 =>
+                crypto::Algorithm::ChaCha20_Poly1305,
+        }
+    }
+}
+
+impl From<crypto::Algorithm> for CipherSuite {
+    fn from(alg: crypto::Algorithm) -> Self {
+        match alg {
+            crypto::Algorithm::AES128_GCM => CipherSuite::Aes128GcmSha256,
+
+            crypto::Algorithm::AES256_GCM => CipherSuite::Aes256GcmSha384,
+
+            crypto::Algorithm::ChaCha20_Poly1305 =>
+                CipherSuite::ChaCha20Poly1305Sha256,
+        }
+    }
+}
+
 /// Stores configuration shared between multiple connections.
 pub struct Config {
     local_transport_params: TransportParams,
 
     version: u32,
 
+    quic_v2: bool,
+
     tls_ctx: tls::Context,
 
     application_protos: Vec<Vec<u8>>,
 
     grease: bool,
+    grease_rate: f64,
 
     cc_algorithm: CongestionControlAlgorithm,
     initial_congestion_window_packets: usize,
+    minimum_congestion_window_packets: usize,
 
     hystart: bool,
 
+    cubic_fast_convergence: bool,
+
     pacing: bool,
+    pacing_mode: PacingMode,
     max_pacing_rate: Option<u64>,
+    pacing_horizon: Option<time::Duration>,
+
+    initial_rtt: time::Duration,
+    max_pto_backoff: Option<u32>,
+    max_pto_count: Option<u32>,
+    tail_loss_probe: bool,
+    pto_retransmit_policy: PtoRetransmitPolicy,
+    packet_events: bool,
+    packet_pool_size: usize,
+    buffer_pool_size: usize,
 
     dgram_recv_max_queue_len: usize,
     dgram_send_max_queue_len: usize,
 
+    fec_group_size: usize,
+
     path_challenge_recv_max_queue_len: usize,
 
     max_send_udp_payload_size: usize,
 
+    pmtud: bool,
+
     max_connection_window: u64,
     max_stream_window: u64,
 
+    rate_window_autotuning: bool,
+
+    stream_send_lowat: usize,
+    stream_send_max_buffered: Option<usize>,
+    send_max_buffered: Option<usize>,
+    max_streams_policy: MaxStreamsPolicy,
+
+    max_reassembly_buffer: Option<u64>,
+    reassembly_stop_error: Option<u64>,
+
+    keepalive_interval: Option<time::Duration>,
+
+    ack_eliciting_threshold: usize,
+    ack_on_reordering: bool,
+    immediate_ack_during_handshake: bool,
+
+    metrics: Option<Arc<metrics::Registry>>,
+
+    #[cfg(feature = "qlog")]
+    qlog_categories: u8,
+
+    #[cfg(feature = "qlog")]
+    qlog_sampling_rate: u64,
+
     disable_dcid_reuse: bool,
+
+    preferred_address_v4: Option<SocketAddrV4>,
+    preferred_address_v6: Option<SocketAddrV6>,
+
+    dcid_rotation_interval: Option<time::Duration>,
+    dcid_rotation_bytes: Option<u64>,
+    dcid_rotation_retire_prior_to: bool,
+
+    key_update_packets: Option<u64>,
+
+    max_amplification_factor: usize,
+    new_path_policy: NewPathPolicy,
+
+    migration_policy: MigrationPolicy,
+
+    coalescing_policy: CoalescingPolicy,
+    initial_padding_policy: InitialPaddingPolicy,
+
+    early_data_replay_filter: Option<Arc<dyn EarlyDataReplayFilter>>,
+
+    session_store: Option<Arc<dyn SessionStore>>,
+
+    keylog: Option<Arc<std::sync::Mutex<Box<dyn std::io::Write + Send>>>>,
+
+    verify_callback: Option<Arc<VerifyCallback>>,
+
+    custom_transport_param_callback: Option<Arc<TransportParamCallback>>,
+
+    token_provider: Option<Arc<dyn TokenProvider>>,
+
+    cipher_suites: Vec<crypto::Algorithm>,
+
+    application_settings: Vec<(Vec<u8>, Vec<u8>)>,
+
+    packet_protector: Option<Arc<dyn PacketProtector>>,
 }
 
 // See https://quicwg.org/base-drafts/rfc9000.html#section-15
@@ -772,28 +1438,107 @@ impl Config {
         Ok(Config {
             local_transport_params: TransportParams::default(),
             version,
+            quic_v2: false,
             tls_ctx,
             application_protos: Vec::new(),
             grease: true,
+            grease_rate: 1.0,
             cc_algorithm: CongestionControlAlgorithm::CUBIC,
             initial_congestion_window_packets:
                 DEFAULT_INITIAL_CONGESTION_WINDOW_PACKETS,
+            minimum_congestion_window_packets:
+                DEFAULT_MINIMUM_CONGESTION_WINDOW_PACKETS,
             hystart: true,
+            cubic_fast_convergence: true,
             pacing: true,
+            pacing_mode: PacingMode::Auto,
             max_pacing_rate: None,
+            pacing_horizon: None,
+
+            initial_rtt: recovery::INITIAL_RTT,
+            max_pto_backoff: None,
+            tail_loss_probe: false,
+            pto_retransmit_policy: PtoRetransmitPolicy::Oldest,
+            packet_events: false,
+            packet_pool_size: DEFAULT_PACKET_POOL_SIZE,
+            buffer_pool_size: DEFAULT_BUFFER_POOL_SIZE,
+            max_pto_count: None,
 
             dgram_recv_max_queue_len: DEFAULT_MAX_DGRAM_QUEUE_LEN,
             dgram_send_max_queue_len: DEFAULT_MAX_DGRAM_QUEUE_LEN,
 
+            fec_group_size: fec::DEFAULT_GROUP_SIZE,
+
             path_challenge_recv_max_queue_len:
                 DEFAULT_MAX_PATH_CHALLENGE_RX_QUEUE_LEN,
 
             max_send_udp_payload_size: MAX_SEND_UDP_PAYLOAD_SIZE,
 
+            pmtud: false,
+
             max_connection_window: MAX_CONNECTION_WINDOW,
             max_stream_window: stream::MAX_STREAM_WINDOW,
 
+            rate_window_autotuning: false,
+
+            stream_send_lowat: 1,
+            stream_send_max_buffered: None,
+            send_max_buffered: None,
+            max_streams_policy: MaxStreamsPolicy::default(),
+
+            max_reassembly_buffer: None,
+            reassembly_stop_error: None,
+
+            keepalive_interval: None,
+
+            ack_eliciting_threshold: 1,
+            ack_on_reordering: true,
+            immediate_ack_during_handshake: true,
+
+            metrics: None,
+
+            #[cfg(feature = "qlog")]
+            qlog_categories: 0,
+
+            #[cfg(feature = "qlog")]
+            qlog_sampling_rate: 1,
+
             disable_dcid_reuse: false,
+
+            preferred_address_v4: None,
+            preferred_address_v6: None,
+
+            dcid_rotation_interval: None,
+            dcid_rotation_bytes: None,
+            dcid_rotation_retire_prior_to: false,
+
+            key_update_packets: None,
+
+            max_amplification_factor: MAX_AMPLIFICATION_FACTOR,
+            new_path_policy: NewPathPolicy::Validate,
+
+            migration_policy: MigrationPolicy::Immediate,
+
+            coalescing_policy: CoalescingPolicy::All,
+            initial_padding_policy: InitialPaddingPolicy::All,
+
+            early_data_replay_filter: None,
+
+            session_store: None,
+
+            keylog: None,
+
+            verify_callback: None,
+
+            custom_transport_param_callback: None,
+
+            token_provider: None,
+
+            cipher_suites: Vec::new(),
+
+            application_settings: Vec::new(),
+
+            packet_protector: None,
         })
     }
 
@@ -878,11 +1623,166 @@ impl Config {
         self.tls_ctx.set_verify(verify);
     }
 
-    /// Configures whether to send GREASE values.
+    /// Configures whether servers require clients to present a certificate.
     ///
-    /// The default value is `true`.
-    pub fn grease(&mut self, grease: bool) {
-        self.grease = grease;
+    /// When enabled, peer verification is also enabled as with
+    /// [`verify_peer()`], and the handshake fails if the client doesn't
+    /// present a certificate, instead of allowing anonymous clients.
+    ///
+    /// The default value is `false`.
+    ///
+    /// [`verify_peer()`]: struct.Config.html#method.verify_peer
+    pub fn verify_peer_certificate_required(&mut self, required: bool) {
+        self.tls_ctx.set_verify_required(required);
+    }
+
+    /// Sets a callback used to perform additional verification of the peer's
+    /// certificate chain, e.g. to pin certificates or check custom
+    /// extensions.
+    ///
+    /// The callback receives the DER-encoded chain, with the peer's leaf
+    /// certificate at index 0, only after the chain was successfully verified
+    /// against the configured CAs. Returning `false` fails the handshake.
+    ///
+    /// The callback is only invoked when peer verification is enabled with
+    /// [`verify_peer()`].
+    ///
+    /// [`verify_peer()`]: struct.Config.html#method.verify_peer
+    pub fn set_verify_callback<F>(&mut self, cb: F)
+    where
+        F: Fn(&[&[u8]]) -> bool + Send + Sync + 'static,
+    {
+        self.tls_ctx.enable_verify_callback();
+
+        self.verify_callback = Some(Arc::new(cb));
+    }
+
+    /// Adds a custom transport parameter sent to the peer, e.g. to prototype
+    /// protocol extensions.
+    ///
+    /// The `id` must not be one of the transport parameters handled by
+    /// quiche, a reserved (GREASE) one, or one that was already added,
+    /// otherwise [`InvalidTransportParam`] is returned.
+    ///
+    /// [`InvalidTransportParam`]: enum.Error.html#variant.InvalidTransportParam
+    ///
+    /// ## Examples:
+    ///
+    /// ```
+    /// # let mut config = quiche::Config::new(0xbabababa)?;
+    /// config.add_custom_transport_param(0x7a3b, b"experiment")?;
+    /// # Ok::<(), quiche::Error>(())
+    /// ```
+    pub fn add_custom_transport_param(
+        &mut self, id: u64, value: &[u8],
+    ) -> Result<()> {
+        let custom_params = &mut self.local_transport_params.custom_params;
+
+        if id >= 1 << 62 ||
+            TransportParams::is_known_param(id) ||
+            TransportParams::is_grease_param(id) ||
+            custom_params.iter().any(|(v, _)| *v == id)
+        {
+            return Err(Error::InvalidTransportParam);
+        }
+
+        custom_params.push((id, value.to_vec()));
+
+        Ok(())
+    }
+
+    /// Sets a callback invoked with each unknown transport parameter received
+    /// from the peer, including the ones added with
+    /// [`add_custom_transport_param()`] by the peer.
+    ///
+    /// The callback is invoked once the peer's transport parameters are
+    /// validated, during the handshake. Reserved (GREASE) transport
+    /// parameters are ignored. The parameters are also available from the
+    /// connection's [`peer_transport_params()`].
+    ///
+    /// [`add_custom_transport_param()`]:
+    /// struct.Config.html#method.add_custom_transport_param
+    /// [`peer_transport_params()`]:
+    /// struct.Connection.html#method.peer_transport_params
+    pub fn set_custom_transport_param_callback<F>(&mut self, cb: F)
+    where
+        F: Fn(u64, &[u8]) + Send + Sync + 'static,
+    {
+        self.custom_transport_param_callback = Some(Arc::new(cb));
+    }
+
+    /// Configures whether to send GREASE values.
+    ///
+    /// The default value is `true`.
+    pub fn grease(&mut self, grease: bool) {
+        self.grease = grease;
+    }
+
+    /// Sets the fraction of connections that send GREASE values.
+    ///
+    /// Each new connection decides once, when created, whether to send
+    /// reserved transport parameters and versions, as well as reserved
+    /// HTTP/3 frames, stream types and settings. The value is clamped to the
+    /// `[0.0, 1.0]` range, and has no effect when GREASE is disabled with
+    /// [`grease()`].
+    ///
+    /// The default value is `1.0`.
+    ///
+    /// [`grease()`]: struct.Config.html#method.grease
+    pub fn set_grease_rate(&mut self, v: f64) {
+        self.grease_rate = v.clamp(0.0, 1.0);
+    }
+
+    /// Configures whether to support QUIC version 2.
+    ///
+    /// When enabled, both QUIC v1 and v2 are advertised to the peer as per
+    /// [RFC 9368], preferring the version the config was created with. A
+    /// client still starts the handshake with that version, but follows the
+    /// server if it switches to the other one, and a server switches clients
+    /// to its preferred version when they offer it.
+    ///
+    /// The default value is `false`.
+    ///
+    /// [RFC 9368]: https://www.rfc-editor.org/rfc/rfc9368
+    pub fn enable_quic_v2(&mut self, v: bool) {
+        self.quic_v2 = v;
+    }
+
+    /// Returns true if a server using this configuration accepts connections
+    /// with the given protocol version.
+    ///
+    /// Unlike [`version_is_supported()`], this only returns `true` for QUIC v2
+    /// when it is enabled with [`enable_quic_v2()`]. Servers should use it to
+    /// decide whether to send a Version Negotiation packet in response to a
+    /// client's Initial packet.
+    ///
+    /// [`version_is_supported()`]: fn.version_is_supported.html
+    /// [`enable_quic_v2()`]: struct.Config.html#method.enable_quic_v2
+    pub fn is_version_supported(&self, version: u32) -> bool {
+        match version {
+            PROTOCOL_VERSION_V1 => true,
+
+            PROTOCOL_VERSION_V2 => self.quic_v2,
+
+            _ => false,
+        }
+    }
+
+    /// Returns the versions that can be negotiated compatibly, in order of
+    /// preference.
+    ///
+    /// The list is empty if compatible version negotiation is disabled.
+    fn compatible_versions(&self) -> Vec<u32> {
+        if !self.quic_v2 {
+            return Vec::new();
+        }
+
+        let mut versions = vec![PROTOCOL_VERSION_V1, PROTOCOL_VERSION_V2];
+
+        // Prefer the version the config was created with.
+        versions.sort_by_key(|&v| v != self.version);
+
+        versions
     }
 
     /// Enables logging of secrets.
@@ -897,6 +1797,23 @@ impl Config {
         self.tls_ctx.enable_keylog();
     }
 
+    /// Enables logging of secrets to the given [`Writer`], shared by all the
+    /// connections created with this configuration.
+    ///
+    /// Secrets are written in the [keylog] format (e.g. for the file pointed
+    /// to by `SSLKEYLOGFILE`), which can be used by tools like Wireshark to
+    /// decrypt captured traffic. Connections can still override the writer
+    /// by calling [`set_keylog()`].
+    ///
+    /// [`Writer`]: https://doc.rust-lang.org/std/io/trait.Write.html
+    /// [keylog]: https://developer.mozilla.org/en-US/docs/Mozilla/Projects/NSS/Key_Log_Format
+    /// [`set_keylog()`]: struct.Connection.html#method.set_keylog
+    pub fn set_keylog_writer(&mut self, writer: Box<dyn std::io::Write + Send>) {
+        self.tls_ctx.enable_keylog();
+
+        self.keylog = Some(Arc::new(std::sync::Mutex::new(writer)));
+    }
+
     /// Configures the session ticket key material.
     ///
     /// On the server this key will be used to encrypt and decrypt session
@@ -916,6 +1833,174 @@ impl Config {
         self.tls_ctx.set_early_data_enabled(true);
     }
 
+    /// Sets the filter used by servers to reject replayed early data.
+    ///
+    /// The filter is shared by all the connections created with this
+    /// configuration. By default no filter is used, and early data is
+    /// accepted whenever it is enabled.
+    pub fn set_early_data_replay_filter(
+        &mut self, filter: Arc<dyn EarlyDataReplayFilter>,
+    ) {
+        self.early_data_replay_filter = Some(filter);
+    }
+
+    /// Sets the provider used by servers to validate address validation
+    /// tokens, and to mint new ones.
+    ///
+    /// When a provider is set, servers send a NEW_TOKEN frame to clients
+    /// once the handshake completes, and clients that present a valid token
+    /// minted this way are considered validated, lifting the
+    /// anti-amplification limit from the start of the connection.
+    ///
+    /// Tokens received in Initial packets are also validated by the
+    /// application before calling [`accept()`], as described in
+    /// [`TokenProvider`]. By default no provider is used.
+    ///
+    /// [`accept()`]: fn.accept.html
+    /// [`TokenProvider`]: trait.TokenProvider.html
+    pub fn set_token_provider(&mut self, provider: Arc<dyn TokenProvider>) {
+        self.token_provider = Some(provider);
+    }
+
+    /// Sets the engine used to protect outgoing 1-RTT packets.
+    ///
+    /// The protector is handed all the 1-RTT packets written by a single
+    /// [`send_batch()`] call at once, or a single packet at a time when
+    /// [`send()`] is used. By default packets are protected internally.
+    ///
+    /// [`send_batch()`]: struct.Connection.html#method.send_batch
+    /// [`send()`]: struct.Connection.html#method.send
+    pub fn set_packet_protector(&mut self, protector: Arc<dyn PacketProtector>) {
+        self.packet_protector = Some(protector);
+    }
+
+    /// Sets the store used by clients to save and resume TLS sessions.
+    ///
+    /// Sessions are only stored and looked up for connections created with a
+    /// server name. By default no store is used.
+    pub fn set_session_store(&mut self, store: Arc<dyn SessionStore>) {
+        self.session_store = Some(store);
+    }
+
+    /// Configures the TLS key exchange groups, in order of preference.
+    ///
+    /// Group names follow BoringSSL's naming, e.g. `X25519`, `P-256` or the
+    /// post-quantum hybrids `X25519Kyber768Draft00` and `X25519MLKEM768`.
+    /// The larger key shares of hybrid groups make the client's Initial
+    /// flight span multiple packets.
+    ///
+    /// If any of the groups is not supported, [`TlsFail`] is returned. By
+    /// default BoringSSL's list of groups is used.
+    ///
+    /// ## Examples:
+    ///
+    /// ```
+    /// # let mut config = quiche::Config::new(0xbabababa)?;
+    /// config.set_key_exchange_groups(&["X25519Kyber768Draft00", "X25519"])?;
+    /// # Ok::<(), quiche::Error>(())
+    /// ```
+    ///
+    /// [`TlsFail`]: enum.Error.html#variant.TlsFail
+    pub fn set_key_exchange_groups(&mut self, groups: &[&str]) -> Result<()> {
+        self.tls_ctx.set_groups_list(&groups.join(":"))
+    }
+
+    /// Restricts the TLS 1.3 cipher suites that can be negotiated.
+    ///
+    /// Clients only offer, and servers only pick, the given suites, so the
+    /// handshake succeeds as long as the peer supports one of them.
+    ///
+    /// BoringSSL doesn't allow configuring the TLS 1.3 suites directly, and
+    /// restricts them with its compliance policies instead, so only two sets
+    /// of suites are supported:
+    ///
+    /// * AES-128-GCM and AES-256-GCM. This also limits key exchange to P-256
+    ///   and P-384, and signatures to RSA and to ECDSA with P-256 or P-384.
+    ///
+    /// * AES-256-GCM alone. This also limits key exchange to P-384, and
+    ///   signatures to RSA with SHA-384 or SHA-512, and to ECDSA with P-384.
+    ///
+    /// These limits replace the ones set with [`set_key_exchange_groups()`]
+    /// and [`set_signature_algorithms()`]. For other sets [`TlsFail`] is
+    /// returned. Note that BoringSSL already prefers ChaCha20-Poly1305 when
+    /// the CPU doesn't have AES instructions, as is common on mobile devices.
+    ///
+    /// With the `rustls` feature any set of suites can be used, and the other
+    /// settings are left alone.
+    ///
+    /// By default, or if `suites` is empty, all the suites are allowed.
+    ///
+    /// ## Examples:
+    ///
+    /// ```
+    /// # let mut config = quiche::Config::new(0xbabababa)?;
+    /// config.set_cipher_suites(&[
+    ///     quiche::CipherSuite::Aes128GcmSha256,
+    ///     quiche::CipherSuite::Aes256GcmSha384,
+    /// ])?;
+    /// # Ok::<(), quiche::Error>(())
+    /// ```
+    ///
+    /// [`set_key_exchange_groups()`]: struct.Config.html#method.set_key_exchange_groups
+    /// [`set_signature_algorithms()`]: struct.Config.html#method.set_signature_algorithms
+    /// [`TlsFail`]: enum.Error.html#variant.TlsFail
+    pub fn set_cipher_suites(&mut self, suites: &[CipherSuite]) -> Result<()> {
+        let suites: Vec<crypto::Algorithm> =
+            suites.iter().map(|s| s.algorithm()).collect();
+
+        tls::check_cipher_suites(&suites)?;
+
+        self.cipher_suites = suites;
+
+        Ok(())
+    }
+
+    /// Configures the TLS signature algorithms, in order of preference.
+    ///
+    /// The list applies both to the signatures generated locally, and to the
+    /// ones accepted from the peer. Algorithm names are the ones from the TLS
+    /// registry, e.g. `ecdsa_secp256r1_sha256`, `rsa_pss_rsae_sha256` or
+    /// `ed25519`.
+    ///
+    /// If any of the algorithms is not supported, [`TlsFail`] is returned. By
+    /// default BoringSSL's list of algorithms is used. With the `rustls`
+    /// feature [`TlsFail`] is always returned, as rustls doesn't allow
+    /// configuring them.
+    ///
+    /// ## Examples:
+    ///
+    /// ```
+    /// # let mut config = quiche::Config::new(0xbabababa)?;
+    /// config.set_signature_algorithms(&[
+    ///     "ecdsa_secp256r1_sha256",
+    ///     "rsa_pss_rsae_sha256",
+    /// ])?;
+    /// # Ok::<(), quiche::Error>(())
+    /// ```
+    ///
+    /// [`TlsFail`]: enum.Error.html#variant.TlsFail
+    pub fn set_signature_algorithms(&mut self, sigalgs: &[&str]) -> Result<()> {
+        self.tls_ctx.set_sigalgs_list(&sigalgs.join(":"))
+    }
+
+    /// Configures the application settings (ALPS) to send to the peer when
+    /// the `proto` application protocol is negotiated.
+    ///
+    /// Application settings are only exchanged if both endpoints configure
+    /// them for the negotiated protocol. The peer's settings can be retrieved
+    /// with [`peer_application_settings()`] once the handshake completes.
+    ///
+    /// Calling this again for the same protocol replaces its settings.
+    ///
+    /// [`peer_application_settings()`]:
+    /// struct.Connection.html#method.peer_application_settings
+    pub fn set_application_settings(&mut self, proto: &[u8], settings: &[u8]) {
+        self.application_settings.retain(|(p, _)| p != proto);
+
+        self.application_settings
+            .push((proto.to_vec(), settings.to_vec()));
+    }
+
     /// Configures the list of supported application protocols.
     ///
     /// On the client this configures the list of protocols to send to the
@@ -979,6 +2064,58 @@ impl Config {
         self.local_transport_params.max_idle_timeout = v;
     }
 
+    /// Sets the interval after which a PING frame is sent on an idle
+    /// connection.
+    ///
+    /// Once no ack-eliciting packet was sent or received for this long, a PING
+    /// frame is sent to keep the connection from hitting the idle timeout.
+    /// The interval is capped at half the idle timeout, and reduced by a
+    /// small random amount so that PINGs of different connections are spread
+    /// out. Keepalives can also be disabled on individual connections with
+    /// [`enable_keepalive()`].
+    ///
+    /// The default value is zero, that is, no keepalive is sent.
+    ///
+    /// [`enable_keepalive()`]: struct.Connection.html#method.enable_keepalive
+    pub fn set_keepalive_interval(&mut self, v: time::Duration) {
+        self.keepalive_interval = if v.is_zero() { None } else { Some(v) };
+    }
+
+    /// Sets the metrics registry updated by connections.
+    ///
+    /// The same registry can be shared by multiple configurations, to
+    /// aggregate metrics across all connections. See the [`metrics`] module
+    /// for more details.
+    ///
+    /// [`metrics`]: metrics/index.html
+    pub fn set_metrics_registry(&mut self, v: Arc<metrics::Registry>) {
+        self.metrics = Some(v);
+    }
+
+    /// Restricts qlog output to some event categories and connections.
+    ///
+    /// Only events belonging to one of the given `categories` are logged, or
+    /// all of them if the list is empty. Events that are filtered out are
+    /// never serialized, so they have no cost beyond the check itself.
+    ///
+    /// Additionally, qlog is only enabled for one in `sampling_rate`
+    /// connections, chosen at random when each connection is created. On the
+    /// other connections [`set_qlog()`] has no effect. A value of 0 or 1
+    /// enables qlog on all connections.
+    ///
+    /// By default all categories are logged, on all connections.
+    ///
+    /// [`set_qlog()`]: struct.Connection.html#method.set_qlog
+    #[cfg(feature = "qlog")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "qlog")))]
+    pub fn set_qlog_filter(
+        &mut self, categories: &[QlogCategory], sampling_rate: u64,
+    ) {
+        self.qlog_categories =
+            categories.iter().fold(0, |mask, &c| mask | (1 << c as u8));
+        self.qlog_sampling_rate = cmp::max(sampling_rate, 1);
+    }
+
     /// Sets the `max_udp_payload_size transport` parameter.
     ///
     /// The default value is `65527`.
@@ -993,6 +2130,27 @@ impl Config {
         self.max_send_udp_payload_size = cmp::max(v, MAX_SEND_UDP_PAYLOAD_SIZE);
     }
 
+    /// Configures whether to enable Datagram Packetization Layer Path MTU
+    /// Discovery ([RFC 8899]).
+    ///
+    /// When enabled, packets start at the minimum size of 1200 bytes, and the
+    /// connection sends padded PING probes once the handshake is confirmed to
+    /// find the largest size supported by each path. The size set with
+    /// [`set_max_send_udp_payload_size()`] is then used as the upper bound of
+    /// the search, rather than as the actual size of outgoing packets.
+    ///
+    /// The current PMTU of each path is reported by [`path_stats()`].
+    ///
+    /// The default value is `false`.
+    ///
+    /// [RFC 8899]: https://www.rfc-editor.org/rfc/rfc8899
+    /// [`set_max_send_udp_payload_size()`]:
+    ///     struct.Config.html#method.set_max_send_udp_payload_size
+    /// [`path_stats()`]: struct.Connection.html#method.path_stats
+    pub fn discover_pmtu(&mut self, v: bool) {
+        self.pmtud = v;
+    }
+
     /// Sets the `initial_max_data` transport parameter.
     ///
     /// When set to a non-zero value quiche will only allow at most `v` bytes of
@@ -1110,11 +2268,56 @@ impl Config {
 
     /// Sets the `max_ack_delay` transport parameter.
     ///
+    /// This is also the longest time acknowledgements are delayed for, see
+    /// [`set_ack_eliciting_threshold()`].
+    ///
     /// The default value is `25`.
+    ///
+    /// [`set_ack_eliciting_threshold()`]:
+    /// struct.Config.html#method.set_ack_eliciting_threshold
     pub fn set_max_ack_delay(&mut self, v: u64) {
         self.local_transport_params.max_ack_delay = v;
     }
 
+    /// Sets the number of ack-eliciting packets received before an ACK is
+    /// sent right away.
+    ///
+    /// Until the threshold is reached, ACKs for 1-RTT packets are delayed by
+    /// up to `max_ack_delay`, unless they can be bundled with other frames.
+    /// Initial and Handshake packets are always acknowledged right away.
+    ///
+    /// The default value is `1`, that is, every ack-eliciting packet is
+    /// acknowledged without delay. Lower values are treated as `1`.
+    pub fn set_ack_eliciting_threshold(&mut self, v: usize) {
+        self.ack_eliciting_threshold = cmp::max(v, 1);
+    }
+
+    /// Configures whether out-of-order packets are acknowledged right away,
+    /// regardless of [`set_ack_eliciting_threshold()`].
+    ///
+    /// A packet is out-of-order when its number is lower than the largest
+    /// received one, or when it leaves a gap after it.
+    ///
+    /// The default value is `true`.
+    ///
+    /// [`set_ack_eliciting_threshold()`]:
+    /// struct.Config.html#method.set_ack_eliciting_threshold
+    pub fn enable_ack_on_reordering(&mut self, v: bool) {
+        self.ack_on_reordering = v;
+    }
+
+    /// Configures whether every 1-RTT packet is acknowledged right away until
+    /// the handshake is confirmed, regardless of
+    /// [`set_ack_eliciting_threshold()`].
+    ///
+    /// The default value is `true`.
+    ///
+    /// [`set_ack_eliciting_threshold()`]:
+    /// struct.Config.html#method.set_ack_eliciting_threshold
+    pub fn enable_immediate_ack_during_handshake(&mut self, v: bool) {
+        self.immediate_ack_during_handshake = v;
+    }
+
     /// Sets the `active_connection_id_limit` transport parameter.
     ///
     /// The default value is `2`. Lower values will be ignored.
@@ -1131,6 +2334,28 @@ impl Config {
         self.local_transport_params.disable_active_migration = v;
     }
 
+    /// Sets the addresses advertised in the `preferred_address` transport
+    /// parameter.
+    ///
+    /// This only applies to servers. As the parameter also carries a
+    /// Connection ID that is specific to each connection, it is only sent once
+    /// that Connection ID is provided with
+    /// [`set_preferred_address_cid()`].
+    ///
+    /// Clients that receive the parameter automatically validate the
+    /// preferred address once the handshake is confirmed, and migrate to it.
+    ///
+    /// The default value is `None` for both addresses.
+    ///
+    /// [`set_preferred_address_cid()`]:
+    ///     struct.Connection.html#method.set_preferred_address_cid
+    pub fn set_preferred_address(
+        &mut self, ipv4: Option<SocketAddrV4>, ipv6: Option<SocketAddrV6>,
+    ) {
+        self.preferred_address_v4 = ipv4;
+        self.preferred_address_v6 = ipv6;
+    }
+
     /// Sets the congestion control algorithm used by string.
     ///
     /// The default value is `cubic`. On error `Error::CongestionControl`
@@ -1151,11 +2376,39 @@ impl Config {
 
     /// Sets initial congestion window size in terms of packet count.
     ///
+    /// If the value is smaller than the minimum congestion window (see
+    /// [`set_minimum_congestion_window_packets()`]), the minimum is used
+    /// instead.
+    ///
     /// The default value is 10.
+    ///
+    /// [`set_minimum_congestion_window_packets()`]:
+    /// struct.Config.html#method.set_minimum_congestion_window_packets
     pub fn set_initial_congestion_window_packets(&mut self, packets: usize) {
         self.initial_congestion_window_packets = packets;
     }
 
+    /// Sets the minimum congestion window size in terms of packet count.
+    ///
+    /// The congestion window is never reduced below this value, e.g. after a
+    /// loss event or when persistent congestion is detected. If `packets` is
+    /// 0, [`Error::CongestionControl`] is returned.
+    ///
+    /// The default value is 2.
+    ///
+    /// [`Error::CongestionControl`]: enum.Error.html#variant.CongestionControl
+    pub fn set_minimum_congestion_window_packets(
+        &mut self, packets: usize,
+    ) -> Result<()> {
+        if packets == 0 {
+            return Err(Error::CongestionControl);
+        }
+
+        self.minimum_congestion_window_packets = packets;
+
+        Ok(())
+    }
+
     /// Sets the congestion control algorithm used.
     ///
     /// The default value is `CongestionControlAlgorithm::CUBIC`.
@@ -1170,6 +2423,20 @@ impl Config {
         self.hystart = v;
     }
 
+    /// Configures whether CUBIC uses fast convergence.
+    ///
+    /// When enabled, W_max is further reduced after a congestion event that
+    /// happens before the previous W_max was reached, releasing bandwidth to
+    /// new flows more quickly ([RFC 9438 Section 4.7]). This only has effect
+    /// when the CUBIC congestion control algorithm is used.
+    ///
+    /// The default value is `true`.
+    ///
+    /// [RFC 9438 Section 4.7]: https://www.rfc-editor.org/rfc/rfc9438#section-4.7
+    pub fn enable_cubic_fast_convergence(&mut self, v: bool) {
+        self.cubic_fast_convergence = v;
+    }
+
     /// Configures whether to enable pacing.
     ///
     /// The default value is `true`.
@@ -1177,6 +2444,20 @@ impl Config {
         self.pacing = v;
     }
 
+    /// Sets how the pacing rate is computed.
+    ///
+    /// See [`PacingMode`] for the available options. Pacing still needs to be
+    /// enabled with [`enable_pacing()`].
+    ///
+    /// The default value is [`PacingMode::Auto`].
+    ///
+    /// [`PacingMode`]: enum.PacingMode.html
+    /// [`PacingMode::Auto`]: enum.PacingMode.html#variant.Auto
+    /// [`enable_pacing()`]: struct.Config.html#method.enable_pacing
+    pub fn set_pacing_mode(&mut self, v: PacingMode) {
+        self.pacing_mode = v;
+    }
+
     /// Sets the max value for pacing rate.
     ///
     /// By default pacing rate is not limited.
@@ -1184,56 +2465,343 @@ impl Config {
         self.max_pacing_rate = Some(v);
     }
 
-    /// Configures whether to enable receiving DATAGRAM frames.
+    /// Sets how far ahead of their pacing release time packets carrying
+    /// application data can be written, in milliseconds.
     ///
-    /// When enabled, the `max_datagram_frame_size` transport parameter is set
-    /// to 65536 as recommended by draft-ietf-quic-datagram-01.
+    /// By default packets are always written, and the time at which they
+    /// should be sent is provided by [`SendInfo.at`]. When this is set,
+    /// [`send()`] instead returns [`Done`] while the next packet would be
+    /// released later than this, and [`send_blocked_reason()`] returns
+    /// [`SendBlockedReason::Pacing`] with the release time, so that
+    /// applications that don't schedule packets themselves can wait until
+    /// then.
     ///
-    /// The default is `false`.
-    pub fn enable_dgram(
-        &mut self, enabled: bool, recv_queue_len: usize, send_queue_len: usize,
-    ) {
-        self.local_transport_params.max_datagram_frame_size = if enabled {
-            Some(MAX_DGRAM_FRAME_SIZE)
-        } else {
-            None
+    /// The default value is `0`, meaning that packets are never held back.
+    ///
+    /// [`SendInfo.at`]: struct.SendInfo.html#structfield.at
+    /// [`send()`]: struct.Connection.html#method.send
+    /// [`Done`]: enum.Error.html#variant.Done
+    /// [`send_blocked_reason()`]:
+    ///     struct.Connection.html#method.send_blocked_reason
+    /// [`SendBlockedReason::Pacing`]:
+    ///     enum.SendBlockedReason.html#variant.Pacing
+    pub fn set_pacing_horizon(&mut self, v: u64) {
+        self.pacing_horizon = match v {
+            0 => None,
+            v => Some(time::Duration::from_millis(v)),
         };
-        self.dgram_recv_max_queue_len = recv_queue_len;
-        self.dgram_send_max_queue_len = send_queue_len;
     }
 
-    /// Configures the max number of queued received PATH_CHALLENGE frames.
+    /// Sets the RTT used before any RTT sample is taken, in milliseconds.
     ///
-    /// When an endpoint receives a PATH_CHALLENGE frame and the queue is full,
-    /// the frame is discarded.
+    /// This also determines the initial probe timeout (PTO), which is three
+    /// times this value. Links with very high latency (e.g. satellite) may
+    /// want to raise it to avoid spurious retransmissions during the
+    /// handshake.
     ///
-    /// The default is 3.
-    pub fn set_path_challenge_recv_max_queue_len(&mut self, queue_len: usize) {
-        self.path_challenge_recv_max_queue_len = queue_len;
+    /// The default value is `333`.
+    pub fn set_initial_rtt(&mut self, v: u64) {
+        self.initial_rtt = time::Duration::from_millis(v);
     }
 
-    /// Sets the maximum size of the connection window.
+    /// Sets the maximum number of times the probe timeout (PTO) is doubled
+    /// when consecutive PTOs expire.
     ///
-    /// The default value is MAX_CONNECTION_WINDOW (24MBytes).
-    pub fn set_max_connection_window(&mut self, v: u64) {
-        self.max_connection_window = v;
+    /// Once the limit is reached, further PTOs keep using the same timeout
+    /// instead of backing off exponentially.
+    ///
+    /// By default the backoff is not limited.
+    pub fn set_max_pto_backoff(&mut self, v: u32) {
+        self.max_pto_backoff = Some(v);
     }
 
-    /// Sets the maximum size of the stream window.
+    /// Sets the maximum number of consecutive probe timeouts (PTO) after
+    /// which the connection is closed.
     ///
-    /// The default value is MAX_STREAM_WINDOW (16MBytes).
-    pub fn set_max_stream_window(&mut self, v: u64) {
-        self.max_stream_window = v;
+    /// The connection is closed silently, as if the idle timeout had expired,
+    /// and [`is_timed_out()`] will return `true`.
+    ///
+    /// By default the connection is only closed by the idle timeout.
+    ///
+    /// [`is_timed_out()`]: struct.Connection.html#method.is_timed_out
+    pub fn set_max_pto_count(&mut self, v: u32) {
+        self.max_pto_count = Some(v);
     }
 
-    /// Sets the initial stateless reset token.
+    /// Configures whether probe timeouts (PTO) send tail loss probes.
     ///
-    /// This value is only advertised by servers. Setting a stateless retry
-    /// token as a client has no effect on the connection.
+    /// When enabled, the probe packets sent in the application packet number
+    /// space retransmit the most recently sent unacknowledged data, rather
+    /// than the oldest, as done by RACK-TLP ([RFC 8985 Section 7.3]). This
+    /// reduces the latency of short transfers whose last packets are lost.
     ///
-    /// The default value is `None`.
-    pub fn set_stateless_reset_token(&mut self, v: Option<u128>) {
-        self.local_transport_params.stateless_reset_token = v;
+    /// The default value is `false`.
+    ///
+    /// [RFC 8985 Section 7.3]: https://www.rfc-editor.org/rfc/rfc8985#section-7.3
+    pub fn enable_tail_loss_probe(&mut self, v: bool) {
+        self.tail_loss_probe = v;
+    }
+
+    /// Sets which frames are retransmitted by the probes sent when a PTO
+    /// fires.
+    ///
+    /// With [`PtoRetransmitPolicy::Prioritized`], CRYPTO data and control
+    /// frames are retransmitted ahead of stream data, and the data of the
+    /// most urgent streams ahead of the others, which reduces the latency of
+    /// the handshake and of important streams under loss.
+    ///
+    /// The default value is [`PtoRetransmitPolicy::Oldest`].
+    ///
+    /// [`PtoRetransmitPolicy::Prioritized`]: enum.PtoRetransmitPolicy.html#variant.Prioritized
+    /// [`PtoRetransmitPolicy::Oldest`]: enum.PtoRetransmitPolicy.html#variant.Oldest
+    pub fn set_pto_retransmit_policy(&mut self, v: PtoRetransmitPolicy) {
+        self.pto_retransmit_policy = v;
+    }
+
+    /// Configures whether to record packet-level transport events.
+    ///
+    /// When enabled, the connection queues an event every time a 0-RTT or
+    /// 1-RTT packet is sent, acknowledged or declared lost, which can be
+    /// retrieved with [`next_transport_event()`]. This allows applications to
+    /// compute custom congestion metrics without having to parse qlog output.
+    ///
+    /// Initial and Handshake packets are not reported, as they use separate
+    /// packet number spaces, which events don't distinguish. See
+    /// [`TransportEvent`].
+    ///
+    /// The default value is `false`.
+    ///
+    /// [`next_transport_event()`]: struct.Connection.html#method.next_transport_event
+    /// [`TransportEvent`]: enum.TransportEvent.html
+    pub fn enable_packet_events(&mut self, v: bool) {
+        self.packet_events = v;
+    }
+
+    /// Sets the number of sent packet records whose memory each path keeps
+    /// for reuse.
+    ///
+    /// Once a packet is acknowledged or declared lost, the memory holding the
+    /// list of its frames is recycled for packets sent later, so that the
+    /// send path doesn't allocate in the steady state. Setting the value to 0
+    /// disables the pool.
+    ///
+    /// The default value is 64.
+    pub fn set_packet_pool_size(&mut self, v: usize) {
+        self.packet_pool_size = v;
+    }
+
+    /// Sets the number of data buffers whose memory each connection keeps
+    /// for reuse.
+    ///
+    /// The data of received STREAM and CRYPTO frames, and the handshake data
+    /// generated by TLS, are copied into buffers that are recycled once the
+    /// data was read by the application or acknowledged by the peer. Setting
+    /// the value to 0 disables the pool.
+    ///
+    /// The default value is 16.
+    pub fn set_buffer_pool_size(&mut self, v: usize) {
+        self.buffer_pool_size = v;
+    }
+
+    /// Configures whether to enable receiving DATAGRAM frames.
+    ///
+    /// When enabled, the `max_datagram_frame_size` transport parameter is set
+    /// to 65536 as recommended by draft-ietf-quic-datagram-01.
+    ///
+    /// The default is `false`.
+    pub fn enable_dgram(
+        &mut self, enabled: bool, recv_queue_len: usize, send_queue_len: usize,
+    ) {
+        self.local_transport_params.max_datagram_frame_size = if enabled {
+            Some(MAX_DGRAM_FRAME_SIZE)
+        } else {
+            None
+        };
+        self.dgram_recv_max_queue_len = recv_queue_len;
+        self.dgram_send_max_queue_len = send_queue_len;
+    }
+
+    /// Configures whether to enable experimental packet-level forward error
+    /// correction (FEC).
+    ///
+    /// When both endpoints enable it, 1-RTT packets are protected in groups
+    /// and a REPAIR frame is sent for each group, allowing the receiver to
+    /// rebuild a single lost packet per group without waiting for a
+    /// retransmission. This trades bandwidth for latency on lossy links.
+    ///
+    /// The default is `false`.
+    pub fn enable_fec(&mut self, v: bool) {
+        self.local_transport_params.enable_fec = v;
+    }
+
+    /// Sets the number of packets protected by a single FEC repair symbol.
+    ///
+    /// Smaller groups recover from more losses, at the cost of sending more
+    /// repair data. Values lower than 2 are treated as 2.
+    ///
+    /// The default value is `8`.
+    pub fn set_fec_group_size(&mut self, v: usize) {
+        self.fec_group_size = v;
+    }
+
+    /// Configures whether to enable support for the RESET_STREAM_AT frame.
+    ///
+    /// When the peer enables it, streams can be reset while still guaranteeing
+    /// the delivery of the start of their data, see [`stream_reset_at()`].
+    ///
+    /// The default is `false`.
+    ///
+    /// [`stream_reset_at()`]: struct.Connection.html#method.stream_reset_at
+    pub fn enable_reset_stream_at(&mut self, v: bool) {
+        self.local_transport_params.reset_stream_at = v;
+    }
+
+    /// Configures whether to send the `grease_quic_bit` transport parameter.
+    ///
+    /// When enabled, the peer is told that packets with the QUIC bit (aka
+    /// fixed bit) cleared are accepted, as per [RFC 9287]. Otherwise such
+    /// packets are discarded. Independently of this setting, the QUIC bit of
+    /// sent packets is randomized when the peer sends the parameter, to
+    /// prevent middleboxes from ossifying on it.
+    ///
+    /// The default is `false`.
+    ///
+    /// [RFC 9287]: https://www.rfc-editor.org/rfc/rfc9287
+    pub fn enable_grease_quic_bit(&mut self, v: bool) {
+        self.local_transport_params.grease_quic_bit = v;
+    }
+
+    /// Configures the max number of queued received PATH_CHALLENGE frames.
+    ///
+    /// When an endpoint receives a PATH_CHALLENGE frame and the queue is full,
+    /// the frame is discarded.
+    ///
+    /// The default is 3.
+    pub fn set_path_challenge_recv_max_queue_len(&mut self, queue_len: usize) {
+        self.path_challenge_recv_max_queue_len = queue_len;
+    }
+
+    /// Sets the maximum size of the connection window.
+    ///
+    /// The default value is MAX_CONNECTION_WINDOW (24MBytes).
+    pub fn set_max_connection_window(&mut self, v: u64) {
+        self.max_connection_window = v;
+    }
+
+    /// Sets the maximum size of the stream window.
+    ///
+    /// The default value is MAX_STREAM_WINDOW (16MBytes).
+    pub fn set_max_stream_window(&mut self, v: u64) {
+        self.max_stream_window = v;
+    }
+
+    /// Configures whether receive windows are autotuned based on the
+    /// measured consumption rate.
+    ///
+    /// By default the connection and stream windows are doubled whenever
+    /// they need to be updated more than once within 2 RTTs. When this is
+    /// enabled, the rate at which the application reads data is sampled
+    /// every RTT instead, and the windows are grown to twice the resulting
+    /// bandwidth-delay product, so that high-BDP paths are not limited by
+    /// flow control. Windows are still capped by
+    /// [`set_max_connection_window()`] and [`set_max_stream_window()`].
+    ///
+    /// The default value is `false`.
+    ///
+    /// [`set_max_connection_window()`]: struct.Config.html#method.set_max_connection_window
+    /// [`set_max_stream_window()`]: struct.Config.html#method.set_max_stream_window
+    pub fn enable_rate_window_autotuning(&mut self, v: bool) {
+        self.rate_window_autotuning = v;
+    }
+
+    /// Sets the send buffer watermarks of new streams.
+    ///
+    /// Streams are only reported as writable once at least `low` bytes can
+    /// be written to them, and [`stream_send()`] doesn't buffer more than
+    /// `high` bytes of data that wasn't sent yet on any single stream. This
+    /// lets applications apply backpressure based on [`writable()`] or
+    /// [`writable_detailed()`], instead of retrying writes that return
+    /// [`Done`].
+    ///
+    /// The watermarks of a single stream can be changed with
+    /// [`stream_send_watermarks()`]. By default the low watermark is 1 byte,
+    /// and there is no high watermark.
+    ///
+    /// [`stream_send()`]: struct.Connection.html#method.stream_send
+    /// [`writable()`]: struct.Connection.html#method.writable
+    /// [`writable_detailed()`]: struct.Connection.html#method.writable_detailed
+    /// [`Done`]: enum.Error.html#variant.Done
+    /// [`stream_send_watermarks()`]: struct.Connection.html#method.stream_send_watermarks
+    pub fn set_stream_send_watermarks(&mut self, low: usize, high: usize) {
+        self.stream_send_lowat = cmp::max(1, low);
+        self.stream_send_max_buffered = Some(cmp::max(low, high));
+    }
+
+    /// Sets the maximum amount of stream data buffered by a connection that
+    /// wasn't acknowledged by the peer yet.
+    ///
+    /// Once this much data is buffered across all streams, no stream is
+    /// writable until some of it is acknowledged. By default the amount of
+    /// buffered data is only limited by flow and congestion control.
+    pub fn set_send_buffer_high_watermark(&mut self, v: usize) {
+        self.send_max_buffered = Some(v);
+    }
+
+    /// Sets how stream count credit is given back to the peer.
+    ///
+    /// When streams opened by the peer complete, new MAX_STREAMS frames can
+    /// be sent to allow the peer to open more. See [`MaxStreamsPolicy`] for
+    /// the available options.
+    ///
+    /// The default value is [`MaxStreamsPolicy::Batched`].
+    ///
+    /// [`MaxStreamsPolicy`]: enum.MaxStreamsPolicy.html
+    /// [`MaxStreamsPolicy::Batched`]: enum.MaxStreamsPolicy.html#variant.Batched
+    pub fn set_max_streams_policy(&mut self, v: MaxStreamsPolicy) {
+        self.max_streams_policy = v;
+    }
+
+    /// Sets the maximum number of bytes buffered out of order across all
+    /// streams of a connection.
+    ///
+    /// Stream data received after a gap can't be read by the application
+    /// until the gap is filled, so a peer could make the receiver buffer up to
+    /// the flow control windows of all its streams. When a stream pushes the
+    /// connection over this limit, it is reported by
+    /// [`reassembly_pressure_next()`], and stopped if enabled with
+    /// [`set_reassembly_stop_sending()`].
+    ///
+    /// The default value is unlimited.
+    ///
+    /// [`reassembly_pressure_next()`]: struct.Connection.html#method.reassembly_pressure_next
+    /// [`set_reassembly_stop_sending()`]: struct.Config.html#method.set_reassembly_stop_sending
+    pub fn set_max_reassembly_buffer(&mut self, v: u64) {
+        self.max_reassembly_buffer = Some(v);
+    }
+
+    /// Stops streams exceeding the limit set with
+    /// [`set_max_reassembly_buffer()`], using a STOP_SENDING frame with the
+    /// given error code.
+    ///
+    /// The data buffered by stopped streams is discarded, as if the
+    /// application called [`stream_shutdown()`] itself.
+    ///
+    /// By default, such streams are only reported.
+    ///
+    /// [`set_max_reassembly_buffer()`]: struct.Config.html#method.set_max_reassembly_buffer
+    /// [`stream_shutdown()`]: struct.Connection.html#method.stream_shutdown
+    pub fn set_reassembly_stop_sending(&mut self, error_code: u64) {
+        self.reassembly_stop_error = Some(error_code);
+    }
+
+    /// Sets the initial stateless reset token.
+    ///
+    /// This value is only advertised by servers. Setting a stateless retry
+    /// token as a client has no effect on the connection.
+    ///
+    /// The default value is `None`.
+    pub fn set_stateless_reset_token(&mut self, v: Option<u128>) {
+        self.local_transport_params.stateless_reset_token = v;
     }
 
     /// Sets whether the QUIC connection should avoid reusing DCIDs over
@@ -1249,6 +2817,211 @@ impl Config {
     pub fn set_disable_dcid_reuse(&mut self, v: bool) {
         self.disable_dcid_reuse = v;
     }
+
+    /// Sets the interval, in milliseconds, after which the destination
+    /// Connection ID of the active path is rotated.
+    ///
+    /// Periodically switching to a fresh Connection ID makes it harder for
+    /// on-path observers to correlate the connection's traffic over time. The
+    /// previous Connection ID is retired, so the peer is expected to provide
+    /// new ones. The rotation is skipped while no spare Connection ID is
+    /// available. See [`enable_dcid_rotation_retire_prior_to()`] to rotate the
+    /// peer's Connection ID as well.
+    ///
+    /// The default value is `0`, meaning that rotation is disabled.
+    ///
+    /// [`enable_dcid_rotation_retire_prior_to()`]:
+    ///     struct.Config.html#method.enable_dcid_rotation_retire_prior_to
+    pub fn set_dcid_rotation_interval(&mut self, v: u64) {
+        self.dcid_rotation_interval = match v {
+            0 => None,
+            v => Some(time::Duration::from_millis(v)),
+        };
+    }
+
+    /// Sets the number of bytes sent after which the destination Connection ID
+    /// of the active path is rotated.
+    ///
+    /// See [`set_dcid_rotation_interval()`] for details. When both thresholds
+    /// are set, the Connection ID is rotated as soon as either is reached.
+    ///
+    /// The default value is `0`, meaning that rotation is disabled.
+    ///
+    /// [`set_dcid_rotation_interval()`]:
+    ///     struct.Config.html#method.set_dcid_rotation_interval
+    pub fn set_dcid_rotation_bytes(&mut self, v: u64) {
+        self.dcid_rotation_bytes = match v {
+            0 => None,
+            v => Some(v),
+        };
+    }
+
+    /// Configures whether rotating the destination Connection ID also
+    /// requests the peer to switch to a fresh one.
+    ///
+    /// When enabled, each rotation raises the Retire Prior To field of
+    /// NEW_CONNECTION_ID frames, so that the peer stops using all but the
+    /// newest source Connection ID. Rotating both directions together
+    /// prevents an observer from linking the old and new Connection IDs using
+    /// the unchanged one. Rotations are then skipped while paths are being
+    /// validated, so that they don't interfere with connection migration, and
+    /// while no spare Connection ID is available in either direction.
+    ///
+    /// This applies both to [`rotate_dcid()`] and to the automatic rotation
+    /// configured with [`set_dcid_rotation_interval()`] and
+    /// [`set_dcid_rotation_bytes()`].
+    ///
+    /// The default value is `false`.
+    ///
+    /// [`rotate_dcid()`]: struct.Connection.html#method.rotate_dcid
+    /// [`set_dcid_rotation_interval()`]:
+    ///     struct.Config.html#method.set_dcid_rotation_interval
+    /// [`set_dcid_rotation_bytes()`]:
+    ///     struct.Config.html#method.set_dcid_rotation_bytes
+    pub fn enable_dcid_rotation_retire_prior_to(&mut self, v: bool) {
+        self.dcid_rotation_retire_prior_to = v;
+    }
+
+    /// Sets the policy for rotating Connection IDs in both directions.
+    ///
+    /// This is a shorthand that configures the rotation thresholds and
+    /// enables Retire Prior To, as [`set_dcid_rotation_interval()`],
+    /// [`set_dcid_rotation_bytes()`] and
+    /// [`enable_dcid_rotation_retire_prior_to()`] would. It replaces any
+    /// threshold previously set with those methods.
+    ///
+    /// The default value is [`CidRotation::Disabled`].
+    ///
+    /// [`set_dcid_rotation_interval()`]:
+    ///     struct.Config.html#method.set_dcid_rotation_interval
+    /// [`set_dcid_rotation_bytes()`]:
+    ///     struct.Config.html#method.set_dcid_rotation_bytes
+    /// [`enable_dcid_rotation_retire_prior_to()`]:
+    ///     struct.Config.html#method.enable_dcid_rotation_retire_prior_to
+    /// [`CidRotation::Disabled`]: enum.CidRotation.html#variant.Disabled
+    pub fn set_cid_rotation(&mut self, v: CidRotation) {
+        let (interval, bytes) = match v {
+            CidRotation::Disabled => (None, None),
+            CidRotation::Interval(i) => (Some(i).filter(|i| !i.is_zero()), None),
+            CidRotation::Bytes(b) => (None, Some(b).filter(|b| *b > 0)),
+        };
+
+        self.dcid_rotation_interval = interval;
+        self.dcid_rotation_bytes = bytes;
+        self.dcid_rotation_retire_prior_to = v != CidRotation::Disabled;
+    }
+
+    /// Sets the number of packets sent after which a key update is initiated
+    /// automatically.
+    ///
+    /// The default value is `0`, meaning that keys are updated before
+    /// reaching the confidentiality limit of the negotiated AEAD, as
+    /// described in [RFC 9001].
+    ///
+    /// [RFC 9001]: https://www.rfc-editor.org/rfc/rfc9001#section-6.6
+    pub fn set_key_update_packets(&mut self, v: u64) {
+        self.key_update_packets = match v {
+            0 => None,
+            v => Some(v),
+        };
+    }
+
+    /// Sets the anti-amplification factor, i.e. how many bytes a server can
+    /// send on a path for each byte received, until the peer's address is
+    /// validated.
+    ///
+    /// [RFC 9000] mandates a factor of 3, so this should only be changed for
+    /// testing purposes. Values lower than `1` are ignored.
+    ///
+    /// The default value is `3`.
+    ///
+    /// [RFC 9000]: https://www.rfc-editor.org/rfc/rfc9000#section-8
+    pub fn set_max_amplification_factor(&mut self, v: usize) {
+        if v >= 1 {
+            self.max_amplification_factor = v;
+        }
+    }
+
+    /// Sets how a server handles packets received from a new peer address,
+    /// e.g. after a NAT rebinding or when the client migrates.
+    ///
+    /// Packets received on new paths are counted in
+    /// [`Stats::new_path_dropped`] when they are dropped.
+    ///
+    /// The default value is [`NewPathPolicy::Validate`].
+    ///
+    /// [`Stats::new_path_dropped`]: struct.Stats.html#structfield.new_path_dropped
+    /// [`NewPathPolicy::Validate`]: enum.NewPathPolicy.html#variant.Validate
+    pub fn set_new_path_policy(&mut self, v: NewPathPolicy) {
+        self.new_path_policy = v;
+    }
+
+    /// Sets how a client switches to a new path when migrating with
+    /// [`migrate_with_policy()`].
+    ///
+    /// On unreliable networks, validating a path before switching to it, and
+    /// keeping the previous path around until the new one has proven itself,
+    /// avoids stalling the connection on a path that doesn't work.
+    ///
+    /// The default value is [`MigrationPolicy::Immediate`].
+    ///
+    /// [`migrate_with_policy()`]: struct.Connection.html#method.migrate_with_policy
+    /// [`MigrationPolicy::Immediate`]: enum.MigrationPolicy.html#variant.Immediate
+    pub fn set_migration_policy(&mut self, v: MigrationPolicy) {
+        self.migration_policy = v;
+    }
+
+    /// Sets which QUIC packets can be coalesced into the same UDP datagram.
+    ///
+    /// Some middleboxes mishandle datagrams carrying multiple QUIC packets,
+    /// in which case coalescing can be restricted or disabled, at the cost of
+    /// sending more datagrams during the handshake.
+    ///
+    /// The default value is [`CoalescingPolicy::All`].
+    ///
+    /// [`CoalescingPolicy::All`]: enum.CoalescingPolicy.html#variant.All
+    pub fn set_coalescing_policy(&mut self, v: CoalescingPolicy) {
+        self.coalescing_policy = v;
+    }
+
+    /// Sets which UDP datagrams containing a QUIC Initial packet are padded.
+    ///
+    /// The default value is [`InitialPaddingPolicy::All`].
+    ///
+    /// [`InitialPaddingPolicy::All`]: enum.InitialPaddingPolicy.html#variant.All
+    pub fn set_initial_padding_policy(&mut self, v: InitialPaddingPolicy) {
+        self.initial_padding_policy = v;
+    }
+}
+
+/// Keylog writer shared between all the connections of a [`Config`].
+///
+/// Each line is written while holding the lock, so that lines logged by
+/// different connections are not interleaved.
+struct SharedKeylog(Arc<std::sync::Mutex<Box<dyn std::io::Write + Send>>>);
+
+impl std::io::Write for SharedKeylog {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.write_all(buf)?;
+
+        Ok(buf.len())
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        match self.0.lock() {
+            Ok(mut w) => w.write_all(buf),
+
+            Err(_) => Err(std::io::ErrorKind::Other.into()),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self.0.lock() {
+            Ok(mut w) => w.flush(),
+
+            Err(_) => Err(std::io::ErrorKind::Other.into()),
+        }
+    }
 }
 
 /// A QUIC connection.
@@ -1262,6 +3035,10 @@ pub struct Connection {
     /// Unique opaque ID for the connection that can be used for logging.
     trace_id: String,
 
+    /// Span covering all the work done on behalf of the connection.
+    #[cfg(feature = "tracing")]
+    tracing_span: tracing::Span,
+
     /// Packet number spaces.
     pkt_num_spaces: [packet::PktNumSpace; packet::Epoch::count()],
 
@@ -1292,6 +3069,15 @@ pub struct Connection {
     /// Total number of received PATH_CHALLENGE frames.
     path_challenge_rx_count: u64,
 
+    /// The reason why the last call to `send()` didn't write any packet.
+    send_blocked_reason: Option<SendBlockedReason>,
+
+    /// How far ahead of their pacing release time packets can be written.
+    pacing_horizon: Option<time::Duration>,
+
+    /// Until when padding packets are sent to fill the congestion window.
+    bandwidth_probe_until: Option<time::Instant>,
+
     /// List of supported application protocols.
     application_protos: Vec<Vec<u8>>,
 
@@ -1322,6 +3108,9 @@ pub struct Connection {
     // Number of bytes buffered in the send buffer.
     tx_buffered: usize,
 
+    /// The maximum number of bytes that can be buffered in the send buffer.
+    tx_max_buffered: Option<usize>,
+
     /// Total number of bytes sent to the peer.
     tx_data: u64,
 
@@ -1347,6 +3136,9 @@ pub struct Connection {
     /// Streams map, indexed by stream ID.
     streams: stream::StreamMap,
 
+    /// File regions still to be written to streams, indexed by stream ID.
+    file_sources: stream::StreamIdHashMap<stream::FileSource>,
+
     /// Peer's original destination connection ID. Used by the client to
     /// validate the server's transport parameter.
     odcid: Option<ConnectionId<'static>>,
@@ -1375,6 +3167,42 @@ pub struct Connection {
     /// Draining timeout expiration time.
     draining_timer: Option<time::Instant>,
 
+    /// The interval after which a PING frame is sent on an idle connection.
+    keepalive_interval: Option<time::Duration>,
+
+    /// Whether keepalives are enabled.
+    keepalive_enabled: bool,
+
+    /// The number of ack-eliciting packets received before ACKing them.
+    ack_eliciting_threshold: usize,
+
+    /// Whether to ACK out-of-order packets right away.
+    ack_on_reordering: bool,
+
+    /// Whether to ACK every packet right away until handshake confirmation.
+    immediate_ack_during_handshake: bool,
+
+    /// Keepalive expiration time.
+    keepalive_timer: Option<time::Instant>,
+
+    /// The connection's contribution to a metrics registry.
+    metrics: Option<metrics::ConnectionMetrics>,
+
+    /// Whether the connection waits for the draining period after sending a
+    /// CONNECTION_CLOSE frame, rather than being closed right away.
+    close_drain: bool,
+
+    /// Whether the CONNECTION_CLOSE frame needs to be sent again, in response
+    /// to a packet received in the closing state.
+    close_resend: bool,
+
+    /// The number of packets received in the closing state.
+    closing_recv_count: u64,
+
+    /// The number of packets received in the closing state after which the
+    /// CONNECTION_CLOSE frame is sent again.
+    closing_resend_at: u64,
+
     /// List of raw packets that were received before they could be decrypted.
     undecryptable_pkts: VecDeque<(Vec<u8>, RecvInfo)>,
 
@@ -1391,6 +3219,16 @@ pub struct Connection {
     /// relevant for client connections.
     did_version_negotiation: bool,
 
+    /// The versions that can be negotiated compatibly, in order of preference.
+    compatible_versions: Vec<u32>,
+
+    /// Whether the connection switched to a compatible version during the
+    /// handshake.
+    did_version_upgrade: bool,
+
+    /// The Connection ID the Initial keys are derived from.
+    initial_keys_cid: ConnectionId<'static>,
+
     /// Whether stateless retry has been performed.
     did_retry: bool,
 
@@ -1434,6 +3272,9 @@ pub struct Connection {
     /// TLS keylog writer.
     keylog: Option<Box<dyn std::io::Write + Send + Sync>>,
 
+    /// Receives the packets sent and received, if set.
+    packet_capture: Option<Box<dyn PacketCapture>>,
+
     #[cfg(feature = "qlog")]
     qlog: QlogInfo,
 
@@ -1444,10 +3285,142 @@ pub struct Connection {
     /// Whether to emit DATAGRAM frames in the next packet.
     emit_dgram: bool,
 
+    /// FEC repair symbol generation state.
+    fec_encoder: fec::Encoder,
+
+    /// FEC recovery state.
+    fec_decoder: fec::Decoder,
+
+    /// Total number of REPAIR frames sent.
+    fec_repair_sent_count: usize,
+
+    /// Total number of packets recovered using REPAIR frames.
+    fec_recovered_count: usize,
+
     /// Whether the connection should prevent from reusing destination
     /// Connection IDs when the peer migrates.
     disable_dcid_reuse: bool,
 
+    /// Whether receive windows are autotuned based on the consumption rate.
+    rate_window_autotuning: bool,
+
+    /// The addresses to advertise in the `preferred_address` transport
+    /// parameter.
+    preferred_address_v4: Option<SocketAddrV4>,
+    preferred_address_v6: Option<SocketAddrV6>,
+
+    /// Whether the client still needs to migrate to the server's preferred
+    /// address.
+    migrate_to_preferred_address: bool,
+
+    /// The thresholds after which the destination Connection ID of the active
+    /// path is rotated.
+    dcid_rotation_interval: Option<time::Duration>,
+    dcid_rotation_bytes: Option<u64>,
+
+    /// When the destination Connection ID was last rotated, and the number of
+    /// bytes sent at that time.
+    dcid_rotation_time: time::Instant,
+    dcid_rotation_sent_bytes: u64,
+
+    /// The number of destination Connection IDs rotated.
+    dcid_rotation_count: usize,
+
+    /// Whether rotating the destination Connection ID also requests the peer
+    /// to retire the source Connection IDs it uses.
+    dcid_rotation_retire_prior_to: bool,
+
+    /// The number of packets sent after which keys are updated, if
+    /// configured.
+    key_update_packets: Option<u64>,
+
+    /// The total number of packets sent when keys were last updated.
+    key_update_sent_count: usize,
+
+    /// The number of key updates initiated locally and by the peer.
+    key_update_local_count: usize,
+    key_update_remote_count: usize,
+
+    /// Timestamps of the handshake milestones.
+    handshake_timeline: HandshakeTimeline,
+
+    /// The anti-amplification factor applied to unvalidated paths.
+    max_amplification_factor: usize,
+
+    /// How packets from new peer addresses are handled.
+    new_path_policy: NewPathPolicy,
+
+    /// The number of packets dropped because of `new_path_policy`.
+    new_path_dropped_count: u64,
+
+    /// How to switch to a new path when migrating.
+    migration_policy: MigrationPolicy,
+
+    /// Which packets can be coalesced into the same datagram.
+    coalescing_policy: CoalescingPolicy,
+
+    /// Which datagrams containing an Initial packet are padded.
+    initial_padding_policy: InitialPaddingPolicy,
+
+    /// Whether a datagram containing an Initial packet was already padded.
+    initial_padded: bool,
+
+    /// The number of UDP datagrams sent.
+    sent_dgram_count: usize,
+
+    /// The number of UDP datagrams sent containing more than one packet.
+    coalesced_dgram_count: usize,
+
+    /// The number of 0-RTT packets rejected by the peer.
+    early_data_rejected_count: usize,
+
+    /// The number of bytes in 0-RTT packets rejected by the peer.
+    early_data_rejected_bytes: u64,
+
+    /// Store used by the client to save TLS sessions.
+    session_store: Option<Arc<dyn SessionStore>>,
+
+    /// Application callback used to verify the peer's certificate chain.
+    verify_callback: Option<Arc<VerifyCallback>>,
+
+    custom_transport_param_callback: Option<Arc<TransportParamCallback>>,
+
+    /// Provider used by the server to validate and mint address validation
+    /// tokens.
+    token_provider: Option<Arc<dyn TokenProvider>>,
+
+    /// Buffers reused to hold received stream data and crypto data.
+    buf_pool: stream::BufPool,
+
+    /// Engine used to protect outgoing 1-RTT packets.
+    packet_protector: Option<Arc<dyn PacketProtector>>,
+
+    /// Whether the protection of 1-RTT packets is deferred until the end of
+    /// the current batch.
+    defer_seal: bool,
+
+    /// The 1-RTT packets of the current batch that still need protecting.
+    pending_seals: Vec<packet::PendingSeal>,
+
+    /// An error hit while writing a batch, to be returned once the packets
+    /// already in the batch were handed to the application.
+    batch_error: Option<Error>,
+
+    /// Whether the NEW_TOKEN frame has been sent.
+    new_token_sent: bool,
+
+    /// Whether the NEW_TOKEN frame has been acked.
+    new_token_acked: bool,
+
+    /// Filter used by the server to detect replayed early data.
+    early_data_replay_filter: Option<Arc<dyn EarlyDataReplayFilter>>,
+
+    /// Whether early data was rejected.
+    early_data_rejected: bool,
+
+    /// The number of consecutive PTOs after which the connection is closed.
+    max_pto_count: Option<u32>,
+
     /// A resusable buffer used by Recovery
     newly_acked: Vec<recovery::Acked>,
 
@@ -1462,10 +3435,24 @@ pub struct Connection {
 
     /// The number of streams stopped by remote.
     stopped_stream_remote_count: u64,
-}
 
-/// Creates a new server-side connection.
-///
+    /// Whether the connection was restored with `Connection::thaw()`, and
+    /// thus has no TLS state.
+    thawed: bool,
+
+    /// The type of the received frame that couldn't be processed, and the
+    /// number of the packet carrying it, while the packet is processed.
+    frame_error: Option<(u64, u64)>,
+
+    /// The frame type and packet number that triggered `local_error`.
+    local_error_frame: Option<(u64, u64)>,
+
+    /// The frame type carried by the peer's transport `CONNECTION_CLOSE`.
+    peer_error_frame_type: Option<u64>,
+}
+
+/// Creates a new server-side connection.
+///
 /// The `scid` parameter represents the server's source connection ID, while
 /// the optional `odcid` parameter represents the original destination ID the
 /// client sent before a stateless retry (this is only required when using
@@ -1520,6 +3507,23 @@ pub fn connect(
 
     if let Some(server_name) = server_name {
         conn.handshake.set_host_name(server_name)?;
+
+        if let Some(session) = config
+            .session_store
+            .as_ref()
+            .and_then(|s| s.get(server_name))
+        {
+            // A stale or corrupted session only prevents resumption, so fall
+            // back to a full handshake.
+            if let Err(e) = conn.set_session(&session) {
+                trace!("{} failed to resume session: {:?}", conn.trace_id, e);
+            }
+        }
+
+        conn.token = config
+            .session_store
+            .as_ref()
+            .and_then(|s| s.get_token(server_name));
     }
 
     Ok(conn)
@@ -1623,9 +3627,37 @@ pub fn retry(
 }
 
 /// Returns true if the given protocol version is supported.
+///
+/// This includes QUIC v2 even when it's not enabled by the configuration, so
+/// servers should use [`Config::is_version_supported()`] instead to decide
+/// whether to negotiate the version.
+///
+/// [`Config::is_version_supported()`]: struct.Config.html#method.is_version_supported
 #[inline]
 pub fn version_is_supported(version: u32) -> bool {
-    matches!(version, PROTOCOL_VERSION_V1)
+    matches!(version, PROTOCOL_VERSION_V1 | PROTOCOL_VERSION_V2)
+}
+
+/// Returns true if a connection should send GREASE values, given the
+/// configured rate.
+fn grease_sampled(rate: f64) -> bool {
+    if rate >= 1.0 {
+        return true;
+    }
+
+    (rand::rand_u64() as f64 / u64::MAX as f64) < rate
+}
+
+/// Returns a random reserved version, as per RFC 9000 Section 15.
+fn grease_version() -> u32 {
+    (rand::rand_u64() as u32 & 0xf0f0_f0f0) | 0x0a0a_0a0a
+}
+
+/// Returns a random reserved transport parameter ID, as per RFC 9000 Section
+/// 18.1.
+fn grease_transport_param_id() -> u64 {
+    let n = rand::rand_u64_uniform(148_764_065_110_560_899);
+    31 * n + 27
 }
 
 /// Pushes a frame to the output packet if there is enough space.
@@ -1651,12 +3683,12 @@ macro_rules! push_frame_to_pkt {
 
 /// Executes the provided body if the qlog feature is enabled, quiche has been
 /// configured with a log writer, the event's importance is within the
-/// configured level.
+/// configured level, and its category is not filtered out.
 macro_rules! qlog_with_type {
     ($ty:expr, $qlog:expr, $qlog_streamer_ref:ident, $body:block) => {{
         #[cfg(feature = "qlog")]
         {
-            if EventImportance::from($ty).is_contained_in(&$qlog.level) {
+            if $qlog.allows($ty) {
                 if let Some($qlog_streamer_ref) = &mut $qlog.streamer {
                     $body
                 }
@@ -1681,15 +3713,47 @@ const QLOG_PACKET_TX: EventType =
 const QLOG_DATA_MV: EventType =
     EventType::TransportEventType(TransportEventType::DataMoved);
 
+#[cfg(feature = "qlog")]
+const QLOG_FLOW_CONTROL: EventType =
+    EventType::TransportEventType(TransportEventType::FlowControlUpdated);
+
 #[cfg(feature = "qlog")]
 const QLOG_METRICS: EventType =
     EventType::RecoveryEventType(RecoveryEventType::MetricsUpdated);
 
+#[cfg(feature = "qlog")]
+const QLOG_CONGESTION_STATE: EventType =
+    EventType::RecoveryEventType(RecoveryEventType::CongestionStateUpdated);
+
+#[cfg(feature = "qlog")]
+const QLOG_AMPLIFICATION_LIMITED: EventType =
+    EventType::ConnectivityEventType(ConnectivityEventType::AmplificationLimited);
+
 #[cfg(feature = "qlog")]
 struct QlogInfo {
     streamer: Option<qlog::streamer::QlogStreamer>,
     logged_peer_params: bool,
     level: EventImportance,
+
+    /// Bitmask of the logged event categories, or 0 to log all of them.
+    categories: u8,
+
+    /// Whether the connection was picked for logging by sampling.
+    sampled: bool,
+}
+
+#[cfg(feature = "qlog")]
+impl QlogInfo {
+    /// Returns true if events of the given type need to be logged.
+    fn allows(&self, ty: EventType) -> bool {
+        if !EventImportance::from(ty).is_contained_in(&self.level) {
+            return false;
+        }
+
+        let category = QlogCategory::from_event_type(ty) as u8;
+
+        self.categories == 0 || self.categories & (1 << category) != 0
+    }
 }
 
 #[cfg(feature = "qlog")]
@@ -1699,6 +3763,8 @@ impl Default for QlogInfo {
             streamer: None,
             logged_peer_params: false,
             level: EventImportance::Base,
+            categories: 0,
+            sampled: true,
         }
     }
 }
@@ -1785,6 +3851,12 @@ impl Connection {
                 .path_challenge_recv_max_queue_len,
             path_challenge_rx_count: 0,
 
+            send_blocked_reason: None,
+
+            pacing_horizon: config.pacing_horizon,
+
+            bandwidth_probe_until: None,
+
             application_protos: config.application_protos.clone(),
 
             recv_count: 0,
@@ -1807,6 +3879,8 @@ impl Connection {
 
             tx_buffered: 0,
 
+            tx_max_buffered: config.send_max_buffered,
+
             tx_data: 0,
             max_tx_data: 0,
             last_tx_data: 0,
@@ -1819,6 +3893,8 @@ impl Connection {
                 config.max_stream_window,
             ),
 
+            file_sources: Default::default(),
+
             odcid: None,
 
             rscid: None,
@@ -1835,6 +3911,35 @@ impl Connection {
 
             draining_timer: None,
 
+            keepalive_interval: config.keepalive_interval,
+
+            keepalive_enabled: true,
+
+            keepalive_timer: None,
+
+            ack_eliciting_threshold: config.ack_eliciting_threshold,
+
+            ack_on_reordering: config.ack_on_reordering,
+
+            immediate_ack_during_handshake: config.immediate_ack_during_handshake,
+
+            metrics: config.metrics.clone().map(metrics::ConnectionMetrics::new),
+
+            #[cfg(feature = "tracing")]
+            tracing_span: tracing::debug_span!(
+                "quic_conn",
+                trace_id = %scid_as_hex.join(""),
+                is_server,
+            ),
+
+            close_drain: true,
+
+            close_resend: false,
+
+            closing_recv_count: 0,
+
+            closing_resend_at: 1,
+
             undecryptable_pkts: VecDeque::new(),
 
             alpn: Vec::new(),
@@ -1845,6 +3950,12 @@ impl Connection {
 
             did_version_negotiation: false,
 
+            compatible_versions: config.compatible_versions(),
+
+            did_version_upgrade: false,
+
+            initial_keys_cid: ConnectionId::default(),
+
             did_retry: false,
 
             got_peer_conn_id: false,
@@ -1869,12 +3980,22 @@ impl Connection {
 
             timed_out: false,
 
-            grease: config.grease,
+            grease: config.grease && grease_sampled(config.grease_rate),
 
-            keylog: None,
+            keylog: config.keylog.as_ref().map(|w| {
+                Box::new(SharedKeylog(Arc::clone(w)))
+                    as Box<dyn std::io::Write + Send + Sync>
+            }),
+
+            packet_capture: None,
 
             #[cfg(feature = "qlog")]
-            qlog: Default::default(),
+            qlog: QlogInfo {
+                categories: config.qlog_categories,
+                sampled: config.qlog_sampling_rate <= 1 ||
+                    rand::rand_u64_uniform(config.qlog_sampling_rate) == 0,
+                ..Default::default()
+            },
 
             dgram_recv_queue: dgram::DatagramQueue::new(
                 config.dgram_recv_max_queue_len,
@@ -1886,14 +4007,116 @@ impl Connection {
 
             emit_dgram: true,
 
+            fec_encoder: fec::Encoder::new(config.fec_group_size),
+
+            fec_decoder: fec::Decoder::default(),
+
+            fec_repair_sent_count: 0,
+
+            fec_recovered_count: 0,
+
             disable_dcid_reuse: config.disable_dcid_reuse,
 
+            rate_window_autotuning: config.rate_window_autotuning,
+
+            preferred_address_v4: config.preferred_address_v4,
+            preferred_address_v6: config.preferred_address_v6,
+
+            migrate_to_preferred_address: false,
+
+            dcid_rotation_interval: config.dcid_rotation_interval,
+            dcid_rotation_bytes: config.dcid_rotation_bytes,
+
+            dcid_rotation_time: time::Instant::now(),
+            dcid_rotation_sent_bytes: 0,
+
+            dcid_rotation_count: 0,
+
+            dcid_rotation_retire_prior_to: config.dcid_rotation_retire_prior_to,
+
+            key_update_packets: config.key_update_packets,
+            key_update_sent_count: 0,
+            key_update_local_count: 0,
+            key_update_remote_count: 0,
+
+            handshake_timeline: HandshakeTimeline {
+                created: Some(time::Instant::now()),
+                ..Default::default()
+            },
+
+            max_amplification_factor: config.max_amplification_factor,
+
+            new_path_policy: config.new_path_policy,
+
+            new_path_dropped_count: 0,
+
+            migration_policy: config.migration_policy,
+
+            coalescing_policy: config.coalescing_policy,
+
+            initial_padding_policy: config.initial_padding_policy,
+
+            initial_padded: false,
+
+            sent_dgram_count: 0,
+
+            coalesced_dgram_count: 0,
+
+            early_data_rejected_count: 0,
+
+            early_data_rejected_bytes: 0,
+
+            session_store: if is_server {
+                None
+            } else {
+                config.session_store.clone()
+            },
+
+            verify_callback: config.verify_callback.clone(),
+
+            custom_transport_param_callback: config
+                .custom_transport_param_callback
+                .clone(),
+
+            token_provider: if is_server {
+                config.token_provider.clone()
+            } else {
+                None
+            },
+
+            buf_pool: stream::BufPool::new(config.buffer_pool_size),
+
+            packet_protector: config.packet_protector.clone(),
+
+            defer_seal: false,
+
+            pending_seals: Vec::new(),
+
+            batch_error: None,
+
+            new_token_sent: false,
+            new_token_acked: false,
+
+            early_data_replay_filter: config.early_data_replay_filter.clone(),
+
+            early_data_rejected: false,
+
+            max_pto_count: config.max_pto_count,
+
             newly_acked: Vec::new(),
 
             reset_stream_local_count: 0,
             stopped_stream_local_count: 0,
             reset_stream_remote_count: 0,
             stopped_stream_remote_count: 0,
+
+            thawed: false,
+
+            frame_error: None,
+
+            local_error_frame: None,
+
+            peer_error_frame_type: None,
         };
 
         if let Some(odcid) = odcid {
@@ -1909,10 +4132,29 @@ impl Connection {
         conn.local_transport_params.initial_source_connection_id =
             Some(conn.ids.get_scid(0)?.cid.to_vec().into());
 
+        conn.streams.set_send_watermarks(
+            config.stream_send_lowat,
+            config.stream_send_max_buffered,
+        );
+
+        conn.streams
+            .set_max_streams_policy(config.max_streams_policy);
+
+        conn.streams.set_reassembly_limit(
+            config.max_reassembly_buffer,
+            config.reassembly_stop_error,
+        );
+
         conn.handshake.init(is_server)?;
 
         conn.handshake
-            .use_legacy_codepoint(config.version != PROTOCOL_VERSION_V1);
+            .use_legacy_codepoint(!version_is_supported(config.version));
+
+        conn.handshake.set_cipher_suites(&config.cipher_suites)?;
+
+        for (proto, settings) in &config.application_settings {
+            conn.handshake.add_application_settings(proto, settings)?;
+        }
 
         conn.encode_transport_params()?;
 
@@ -1928,6 +4170,8 @@ impl Connection {
                 conn.is_server,
             )?;
 
+            conn.initial_keys_cid = dcid.to_vec().into();
+
             let reset_token = conn.peer_transport_params.stateless_reset_token;
             conn.set_initial_dcid(
                 dcid.to_vec().into(),
@@ -1940,6 +4184,8 @@ impl Connection {
             conn.pkt_num_spaces[packet::Epoch::Initial].crypto_seal =
                 Some(aead_seal);
 
+            conn.handshake_timeline.initial_keys = Some(time::Instant::now());
+
             conn.derived_initial_secrets = true;
         }
 
@@ -1959,6 +4205,31 @@ impl Connection {
         self.keylog = Some(writer);
     }
 
+    /// Sets the [`PacketCapture`] receiving all the packets sent and received
+    /// by the connection, along with their decrypted payload.
+    ///
+    /// Note that this copies every packet, so it should only be used for
+    /// debugging purposes.
+    ///
+    /// [`PacketCapture`]: trait.PacketCapture.html
+    pub fn set_packet_capture(&mut self, capture: Box<dyn PacketCapture>) {
+        self.packet_capture = Some(capture);
+    }
+
+    /// Sets the metrics registry updated by this connection.
+    ///
+    /// This replaces the registry set with [`Config::set_metrics_registry()`],
+    /// if any. All of the connection's activity is accounted for in the new
+    /// registry, including what happened before this call.
+    ///
+    /// [`Config::set_metrics_registry()`]:
+    /// struct.Config.html#method.set_metrics_registry
+    pub fn set_metrics_registry(&mut self, registry: Arc<metrics::Registry>) {
+        self.metrics = Some(metrics::ConnectionMetrics::new(registry));
+
+        self.update_metrics();
+    }
+
     /// Sets qlog output to the designated [`Writer`].
     ///
     /// Only events included in `QlogLevel::Base` are written. The serialization
@@ -1992,6 +4263,10 @@ impl Connection {
         &mut self, writer: Box<dyn std::io::Write + Send + Sync>, title: String,
         description: String, qlog_level: QlogLevel,
     ) {
+        if !self.qlog.sampled {
+            return;
+        }
+
         let vp = if self.is_server {
             qlog::VantagePointType::Server
         } else {
@@ -2036,16 +4311,38 @@ impl Connection {
 
         streamer.start_log().ok();
 
-        let ev_data = self
-            .local_transport_params
-            .to_qlog(TransportOwner::Local, self.handshake.cipher());
+        if self.qlog.allows(QLOG_PARAMS_SET) {
+            let ev_data = self
+                .local_transport_params
+                .to_qlog(TransportOwner::Local, self.handshake.cipher());
 
-        // This event occurs very early, so just mark the relative time as 0.0.
-        streamer.add_event(Event::with_time(0.0, ev_data)).ok();
+            // This event occurs very early, so just mark the relative time as
+            // 0.0.
+            streamer.add_event(Event::with_time(0.0, ev_data)).ok();
+        }
 
         self.qlog.streamer = Some(streamer);
     }
 
+    /// Streams qlog events to the designated [`StreamingSink`] as they happen.
+    ///
+    /// This works like [`set_qlog_with_level()`], except that each JSON-SEQ
+    /// record is handed to the sink individually, so it can be forwarded over
+    /// a socket or a channel, e.g. to a live dashboard.
+    ///
+    /// [`StreamingSink`]: https://docs.rs/qlog/latest/qlog/streamer/trait.StreamingSink.html
+    /// [`set_qlog_with_level()`]: struct.Connection.html#method.set_qlog_with_level
+    #[cfg(feature = "qlog")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "qlog")))]
+    pub fn set_qlog_sink(
+        &mut self, sink: Box<dyn qlog::StreamingSink>, title: String,
+        description: String, qlog_level: QlogLevel,
+    ) {
+        let writer = qlog::streamer::SinkWriter::new(sink);
+
+        self.set_qlog_with_level(Box::new(writer), title, description, qlog_level)
+    }
+
     /// Returns a mutable reference to the QlogStreamer, if it exists.
     #[cfg(feature = "qlog")]
     #[cfg_attr(docsrs, doc(cfg(feature = "qlog")))]
@@ -2082,6 +4379,305 @@ impl Connection {
         Ok(())
     }
 
+    /// Serializes the state of an idle connection, so that it can be restored
+    /// later with [`thaw()`], possibly by another process.
+    ///
+    /// The connection must be quiescent: the handshake must be confirmed, a
+    /// single path must be in use, all sent data must have been acked, and no
+    /// stream or DATAGRAM data can be waiting to be read or sent. Otherwise
+    /// [`InvalidState`] is returned and the connection is left untouched.
+    ///
+    /// On success the connection is marked as closed without notifying the
+    /// peer, as sending any further packet from this instance would reuse
+    /// packet numbers and keys with the restored one. The returned buffer
+    /// contains the connection's keys and must be stored securely.
+    ///
+    /// Congestion control and RTT state are not preserved, and neither is the
+    /// TLS state, so the restored connection can't provide the peer
+    /// certificate, a new session or exported keying material.
+    ///
+    /// [`thaw()`]: struct.Connection.html#method.thaw
+    /// [`InvalidState`]: enum.Error.html#variant.InvalidState
+    pub fn freeze(&mut self) -> Result<Vec<u8>> {
+        let mut out = vec![0; 4096];
+
+        let len = loop {
+            let mut b = octets::OctetsMut::with_slice(&mut out);
+
+            match self.freeze_state(&mut b) {
+                Ok(()) => break b.off(),
+
+                Err(Error::BufferTooShort) => {
+                    let new_len = out.len() * 2;
+                    out.resize(new_len, 0);
+                },
+
+                Err(e) => return Err(e),
+            }
+        };
+
+        out.truncate(len);
+
+        trace!("{} frozen in {} bytes", self.trace_id, len);
+
+        self.mark_closed();
+
+        Ok(out)
+    }
+
+    /// Restores a connection serialized by [`freeze()`].
+    ///
+    /// The `local` and `peer` addresses are the ones of the path the
+    /// connection was using. The `config` must be equivalent to the one the
+    /// connection was originally created with, as the local transport
+    /// parameters are not serialized.
+    ///
+    /// Post-handshake TLS messages received by the restored connection are
+    /// discarded.
+    ///
+    /// [`freeze()`]: struct.Connection.html#method.freeze
+    pub fn thaw(
+        state: &[u8], local: SocketAddr, peer: SocketAddr, config: &mut Config,
+    ) -> Result<Connection> {
+        let mut b = octets::Octets::with_slice(state);
+
+        if b.get_u8()? != FREEZE_FORMAT_VERSION {
+            return Err(Error::InvalidState);
+        }
+
+        let version = b.get_u32()?;
+        let is_server = b.get_u8()? != 0;
+        let scid = ConnectionId::from_vec(b.get_bytes_with_u8_length()?.to_vec());
+
+        let mut conn =
+            Connection::new(&scid, None, local, peer, config, is_server)?;
+
+        let now = time::Instant::now();
+
+        conn.version = version;
+        conn.alpn = b.get_bytes_with_u8_length()?.to_vec();
+
+        let raw_params_len = b.get_varint()? as usize;
+        let peer_params = TransportParams::decode(
+            b.get_bytes(raw_params_len)?.as_ref(),
+            is_server,
+        )?;
+
+        conn.process_peer_transport_params(peer_params)?;
+
+        // The CIDs are restored as a whole, so a preferred address received
+        // before freezing must not be acted on again.
+        conn.migrate_to_preferred_address = false;
+
+        let active_path_id = conn.paths.get_active_path_id()?;
+
+        conn.ids = cid::ConnectionIdentifiers::thaw(&mut b, active_path_id)?;
+
+        let active_scid_seq = b.get_varint()?;
+        let active_dcid_seq = b.get_varint()?;
+
+        conn.rx_data = b.get_varint()?;
+
+        let consumed = b.get_varint()?;
+        let max_data = b.get_varint()?;
+        let window = b.get_varint()?;
+
+        conn.flow_control = flowcontrol::FlowControl::new(
+            max_data,
+            window,
+            config.max_connection_window,
+        );
+        conn.flow_control.add_consumed(consumed);
+
+        conn.tx_data = b.get_varint()?;
+        conn.max_tx_data = b.get_varint()?;
+
+        conn.streams.thaw(&mut b)?;
+
+        conn.drop_epoch_state(packet::Epoch::Initial, now);
+        conn.drop_epoch_state(packet::Epoch::Handshake, now);
+
+        let pkt_space = &mut conn.pkt_num_spaces[packet::Epoch::Application];
+
+        pkt_space.next_pkt_num = b.get_varint()?;
+        pkt_space.largest_rx_pkt_num = b.get_varint()?;
+        pkt_space.largest_rx_non_probing_pkt_num = pkt_space.largest_rx_pkt_num;
+        pkt_space.recv_pkt_num =
+            packet::PktNumWindow::from_largest(pkt_space.largest_rx_pkt_num);
+
+        for _ in 0..b.get_varint()? {
+            let start = b.get_varint()?;
+            let end = b.get_varint()?;
+
+            pkt_space.recv_pkt_need_ack.insert(start..end);
+        }
+
+        pkt_space.ack_elicited = b.get_u8()? != 0;
+
+        let alg = match b.get_u16()? {
+            0x1301 => crypto::Algorithm::AES128_GCM,
+            0x1302 => crypto::Algorithm::AES256_GCM,
+            0x1303 => crypto::Algorithm::ChaCha20_Poly1305,
+            _ => return Err(Error::InvalidState),
+        };
+
+        let open_secret = b.get_bytes_with_u8_length()?.to_vec();
+        let seal_secret = b.get_bytes_with_u8_length()?.to_vec();
+
+        pkt_space.crypto_open =
+            Some(crypto::Open::from_secret(alg, open_secret, version)?);
+        pkt_space.crypto_seal =
+            Some(crypto::Seal::from_secret(alg, seal_secret, version)?);
+
+        pkt_space
+            .crypto_stream
+            .recv
+            .thaw(&mut b, stream::MAX_STREAM_WINDOW)?;
+        pkt_space.crypto_stream.send.thaw(&mut b)?;
+
+        conn.key_phase = b.get_u8()? != 0;
+        conn.key_update_sent_count = b.get_varint()? as usize;
+        conn.new_token_sent = b.get_u8()? != 0;
+        conn.new_token_acked = conn.new_token_sent;
+
+        conn.derived_initial_secrets = true;
+        conn.got_peer_conn_id = true;
+        conn.peer_verified_initial_address = true;
+        conn.parsed_peer_transport_params = true;
+        conn.handshake_completed = true;
+        conn.handshake_confirmed = true;
+        conn.handshake_done_sent = true;
+        conn.handshake_done_acked = true;
+        conn.thawed = true;
+
+        let path = conn.paths.get_mut(active_path_id)?;
+
+        path.active_scid_seq = Some(active_scid_seq);
+        path.active_dcid_seq = Some(active_dcid_seq);
+        path.verified_peer_address = true;
+        path.peer_verified_local_address = true;
+
+        conn.update_tx_cap();
+
+        if let Some(idle_timeout) = conn.idle_timeout() {
+            conn.idle_timer = Some(now + idle_timeout);
+        }
+
+        conn.restart_keepalive_timer(now);
+
+        trace!("{} thawed", conn.trace_id);
+
+        Ok(conn)
+    }
+
+    /// Writes the connection state for [`freeze()`], checking that the
+    /// connection is quiescent along the way.
+    ///
+    /// [`freeze()`]: struct.Connection.html#method.freeze
+    fn freeze_state(&self, b: &mut octets::OctetsMut) -> Result<()> {
+        let pkt_space = &self.pkt_num_spaces[packet::Epoch::Application];
+
+        let key_update_pending = pkt_space
+            .key_update
+            .as_ref()
+            .map_or(false, |ku| !ku.update_acked);
+
+        if !self.handshake_confirmed ||
+            self.is_closed() ||
+            self.is_draining() ||
+            self.local_error.is_some() ||
+            self.paths.len() != 1 ||
+            self.paths.get_active()?.recovery.bytes_in_flight() > 0 ||
+            !self.dgram_send_queue.is_empty() ||
+            !self.dgram_recv_queue.is_empty() ||
+            self.ids.has_new_scids() ||
+            self.ids.has_retire_dcids() ||
+            !self.pending_seals.is_empty() ||
+            self.almost_full ||
+            key_update_pending
+        {
+            return Err(Error::InvalidState);
+        }
+
+        let (open, seal) = match (&pkt_space.crypto_open, &pkt_space.crypto_seal)
+        {
+            (Some(open), Some(seal)) => (open, seal),
+
+            _ => return Err(Error::InvalidState),
+        };
+
+        let path = self.paths.get_active()?;
+
+        let (active_scid_seq, active_dcid_seq) =
+            match (path.active_scid_seq, path.active_dcid_seq) {
+                (Some(scid_seq), Some(dcid_seq)) => (scid_seq, dcid_seq),
+
+                _ => return Err(Error::InvalidState),
+            };
+
+        let scid = self.source_id();
+
+        b.put_u8(FREEZE_FORMAT_VERSION)?;
+        b.put_u32(self.version)?;
+        b.put_u8(self.is_server as u8)?;
+        b.put_u8(scid.len() as u8)?;
+        b.put_bytes(scid.as_ref())?;
+        b.put_u8(self.alpn.len() as u8)?;
+        b.put_bytes(&self.alpn)?;
+
+        let mut raw_params =
+            vec![0; self.peer_transport_params.max_encoded_len()];
+        let raw_params = TransportParams::encode(
+            &self.peer_transport_params,
+            !self.is_server,
+            &mut raw_params,
+        )?;
+
+        b.put_varint(raw_params.len() as u64)?;
+        b.put_bytes(raw_params)?;
+
+        self.ids.freeze(b)?;
+
+        b.put_varint(active_scid_seq)?;
+        b.put_varint(active_dcid_seq)?;
+
+        b.put_varint(self.rx_data)?;
+        b.put_varint(self.flow_control.consumed())?;
+        b.put_varint(self.flow_control.max_data())?;
+        b.put_varint(self.flow_control.window())?;
+        b.put_varint(self.tx_data)?;
+        b.put_varint(self.max_tx_data)?;
+
+        self.streams.freeze(b)?;
+
+        b.put_varint(pkt_space.next_pkt_num)?;
+        b.put_varint(pkt_space.largest_rx_pkt_num)?;
+
+        b.put_varint(pkt_space.recv_pkt_need_ack.len() as u64)?;
+
+        for r in pkt_space.recv_pkt_need_ack.iter() {
+            b.put_varint(r.start)?;
+            b.put_varint(r.end)?;
+        }
+
+        b.put_u8(pkt_space.ack_elicited as u8)?;
+
+        b.put_u16(CipherSuite::from(seal.alg()) as u16)?;
+        b.put_u8(open.secret().len() as u8)?;
+        b.put_bytes(open.secret())?;
+        b.put_u8(seal.secret().len() as u8)?;
+        b.put_bytes(seal.secret())?;
+
+        pkt_space.crypto_stream.recv.freeze(b)?;
+        pkt_space.crypto_stream.send.freeze(b)?;
+
+        b.put_u8(self.key_phase as u8)?;
+        b.put_varint(self.key_update_sent_count as u64)?;
+        b.put_u8(self.new_token_sent as u8)?;
+
+        Ok(())
+    }
+
     /// Processes QUIC packets received from the peer.
     ///
     /// On success the number of bytes processed from the input buffer is
@@ -2125,6 +4721,9 @@ impl Connection {
     /// # Ok::<(), quiche::Error>(())
     /// ```
     pub fn recv(&mut self, buf: &mut [u8], info: RecvInfo) -> Result<usize> {
+        #[cfg(feature = "tracing")]
+        let _span = self.tracing_span.clone().entered();
+
         let len = buf.len();
 
         if len == 0 {
@@ -2148,7 +4747,7 @@ impl Connection {
             // Note that we also need to limit the number of bytes we sent on a
             // path if we are not the host that initiated its usage.
             if self.is_server && !recv_path.verified_peer_address {
-                recv_path.max_send_bytes += len * MAX_AMPLIFICATION_FACTOR;
+                recv_path.max_send_bytes += len * self.max_amplification_factor;
             }
         } else if !self.is_server {
             // If a client receives packets from an unknown server address,
@@ -2159,6 +4758,16 @@ impl Connection {
                 info,
             );
 
+            return Ok(len);
+        } else if self.new_path_policy == NewPathPolicy::Drop {
+            trace!(
+                "{} server received packet from new address {:?}, dropping",
+                self.trace_id,
+                info,
+            );
+
+            self.new_path_dropped_count += 1;
+
             return Ok(len);
         }
 
@@ -2167,11 +4776,13 @@ impl Connection {
 
         // Process coalesced packets.
         while left > 0 {
-            let read = match self.recv_single(
-                &mut buf[len - left..len],
-                &info,
-                recv_pid,
-            ) {
+            let read =
+                self.recv_single(&mut buf[len - left..len], &info, recv_pid);
+
+            // Only keep the frame that triggered the error while handling it.
+            let frame_error = self.frame_error.take();
+
+            let read = match read {
                 Ok(v) => v,
 
                 Err(Error::Done) => {
@@ -2189,7 +4800,13 @@ impl Connection {
                 Err(e) => {
                     // In case of error processing the incoming packet, close
                     // the connection.
-                    self.close(false, e.to_wire(), b"").ok();
+                    //
+                    // Record which frame triggered the error, if any, unless
+                    // the connection was already being closed.
+                    if self.close(false, e.to_wire(), b"").is_ok() {
+                        self.local_error_frame = frame_error;
+                    }
+
                     return Err(e);
                 },
             };
@@ -2203,6 +4820,37 @@ impl Connection {
         // from the `recv()` method.
         self.process_undecrypted_0rtt_packets()?;
 
+        self.update_metrics();
+
+        Ok(done)
+    }
+
+    /// Processes a batch of UDP datagrams received from the peer.
+    ///
+    /// This is meant to be used with UDP Generic Receive Offload (GRO), where
+    /// the kernel coalesces multiple datagrams of the same flow into a single
+    /// buffer. `buf` is split into datagrams of `segment_size` bytes, except
+    /// for the last one that can be shorter, and each of them is processed as
+    /// with [`recv()`]. A `segment_size` of 0 means that `buf` contains a
+    /// single datagram.
+    ///
+    /// On success the total number of bytes processed is returned. On error
+    /// the datagrams following the failing one are not processed.
+    ///
+    /// [`recv()`]: struct.Connection.html#method.recv
+    pub fn recv_batch(
+        &mut self, buf: &mut [u8], segment_size: usize, info: RecvInfo,
+    ) -> Result<usize> {
+        if segment_size == 0 {
+            return self.recv(buf, info);
+        }
+
+        let mut done = 0;
+
+        for datagram in buf.chunks_mut(segment_size) {
+            done += self.recv(datagram, info)?;
+        }
+
         Ok(done)
     }
 
@@ -2268,10 +4916,23 @@ impl Connection {
     ) -> Result<usize> {
         let now = time::Instant::now();
 
+        #[cfg(feature = "tracing")]
+        let _span = tracing::trace_span!(
+            "recv_packet",
+            len = buf.len(),
+            ty = tracing::field::Empty,
+            pn = tracing::field::Empty,
+        )
+        .entered();
+
         if buf.is_empty() {
             return Err(Error::Done);
         }
 
+        if self.is_closing() && self.is_draining() {
+            self.on_closing_pkt_recv();
+        }
+
         if self.is_closed() || self.is_draining() {
             return Err(Error::Done);
         }
@@ -2284,6 +4945,8 @@ impl Connection {
 
         let buf_len = buf.len();
 
+        let first = buf[0];
+
         let mut b = octets::OctetsMut::with_slice(buf);
 
         let mut hdr = Header::from_bytes(&mut b, self.source_id().len())
@@ -2331,24 +4994,20 @@ impl Connection {
                 return Err(Error::Done);
             }
 
-            let supported_versions =
-                versions.iter().filter(|&&v| version_is_supported(v));
-
-            let mut found_version = false;
-
-            for &v in supported_versions {
-                found_version = true;
-
-                // The final version takes precedence over draft ones.
-                if v == PROTOCOL_VERSION_V1 {
-                    self.version = v;
-                    break;
-                }
+            // Pick the most preferred version among the ones offered by the
+            // server.
+            let local_versions = if self.compatible_versions.is_empty() {
+                vec![PROTOCOL_VERSION_V1]
+            } else {
+                self.compatible_versions.clone()
+            };
 
-                self.version = cmp::max(self.version, v);
-            }
+            let found_version =
+                local_versions.iter().find(|v| versions.contains(v));
 
-            if !found_version {
+            if let Some(&v) = found_version {
+                self.version = v;
+            } else {
                 // We don't support any of the versions offered.
                 //
                 // While a man-in-the-middle attacker might be able to
@@ -2368,6 +5027,8 @@ impl Connection {
                 self.is_server,
             )?;
 
+            self.initial_keys_cid = self.destination_id().into_owned();
+
             // Reset connection state to force sending another Initial packet.
             self.drop_epoch_state(packet::Epoch::Initial, now);
             self.got_peer_conn_id = false;
@@ -2379,7 +5040,7 @@ impl Connection {
                 Some(aead_seal);
 
             self.handshake
-                .use_legacy_codepoint(self.version != PROTOCOL_VERSION_V1);
+                .use_legacy_codepoint(!version_is_supported(self.version));
 
             // Encode transport parameters again, as the new version might be
             // using a different format.
@@ -2388,6 +5049,16 @@ impl Connection {
             return Err(Error::Done);
         }
 
+        // Packets with the QUIC bit cleared are only valid if the peer was
+        // told they are accepted (RFC 9287).
+        if !Header::quic_bit(first) &&
+            !self.local_transport_params.grease_quic_bit
+        {
+            trace!("{} dropped packet with QUIC bit cleared", self.trace_id);
+
+            return Err(Error::Done);
+        }
+
         if hdr.ty == packet::Type::Retry {
             // Retry packets can only be sent by the server.
             if self.is_server {
@@ -2433,6 +5104,8 @@ impl Connection {
                 self.is_server,
             )?;
 
+            self.initial_keys_cid = hdr.scid.clone().into_owned();
+
             // Reset connection state to force sending another Initial packet.
             self.drop_epoch_state(packet::Epoch::Initial, now);
             self.got_peer_conn_id = false;
@@ -2447,7 +5120,10 @@ impl Connection {
         }
 
         if self.is_server && !self.did_version_negotiation {
-            if !version_is_supported(hdr.version) {
+            // QUIC v2 is only accepted when enabled.
+            if hdr.version != PROTOCOL_VERSION_V1 &&
+                !self.compatible_versions.contains(&hdr.version)
+            {
                 return Err(Error::UnknownVersion);
             }
 
@@ -2455,13 +5131,28 @@ impl Connection {
             self.did_version_negotiation = true;
 
             self.handshake
-                .use_legacy_codepoint(self.version != PROTOCOL_VERSION_V1);
+                .use_legacy_codepoint(!version_is_supported(self.version));
 
             // Encode transport parameters again, as the new version might be
             // using a different format.
             self.encode_transport_params()?;
         }
 
+        // The server switches to a compatible version with its first Initial
+        // packet, and the client follows as long as it offered that version.
+        if !self.is_server &&
+            hdr.ty == packet::Type::Initial &&
+            hdr.version != self.version &&
+            self.compatible_versions.contains(&hdr.version) &&
+            self.pkt_num_spaces[packet::Epoch::Handshake]
+                .crypto_open
+                .is_none()
+        {
+            self.version = hdr.version;
+
+            self.on_version_upgrade()?;
+        }
+
         if hdr.ty != packet::Type::Short && hdr.version != self.version {
             // At this point version negotiation was already performed, so
             // ignore packets that don't match the connection's version.
@@ -2502,12 +5193,16 @@ impl Connection {
                 self.is_server,
             )?;
 
+            self.initial_keys_cid = hdr.dcid.clone().into_owned();
+
             self.pkt_num_spaces[packet::Epoch::Initial].crypto_open =
                 Some(aead_open);
             self.pkt_num_spaces[packet::Epoch::Initial].crypto_seal =
                 Some(aead_seal);
 
             self.derived_initial_secrets = true;
+
+            self.handshake_timeline.initial_keys = Some(now);
         }
 
         // Select packet number space epoch based on the received packet's type.
@@ -2556,6 +5251,12 @@ impl Connection {
 
         let aead_tag_len = aead.alg().tag_len();
 
+        // Keep a copy of the protected packet, as it's decrypted in place.
+        let captured_raw = self
+            .packet_capture
+            .as_ref()
+            .map(|_| b.buf()[..b.off() + payload_len].to_vec());
+
         packet::decrypt_hdr(&mut b, &mut hdr, aead).map_err(|e| {
             drop_pkt_on_err(e, self.recv_count, self.is_server, &self.trace_id)
         })?;
@@ -2568,6 +5269,12 @@ impl Connection {
 
         let pn_len = hdr.pkt_num_len;
 
+        #[cfg(feature = "tracing")]
+        {
+            _span.record("ty", tracing::field::debug(hdr.ty));
+            _span.record("pn", pn);
+        }
+
         trace!(
             "{} rx pkt {:?} len={} pn={} {}",
             self.trace_id,
@@ -2634,11 +5341,51 @@ impl Connection {
             return Err(Error::Done);
         }
 
+        if let (Some(capture), Some(raw)) =
+            (self.packet_capture.as_mut(), captured_raw)
+        {
+            capture.on_packet(&CapturedPacket {
+                direction: PacketDirection::Received,
+                ty: hdr.ty,
+                pkt_num: pn,
+                from: info.from,
+                to: info.to,
+                time: now,
+                raw: &raw,
+                payload: payload.as_ref(),
+            });
+        }
+
+        // The first packet received with the new keys after a locally
+        // initiated key update marks where the peer switched keys. Older keys
+        // are only needed for a few more PTOs from now on.
+        if hdr.ty == Type::Short && hdr.key_phase == self.key_phase {
+            let pto = self.paths.get_active()?.recovery.pto();
+
+            if let Some(key_update) = self.pkt_num_spaces[epoch]
+                .key_update
+                .as_mut()
+                .filter(|key_update| key_update.pn_on_update == u64::MAX)
+            {
+                trace!("{} key update confirmed by peer", self.trace_id);
+
+                key_update.pn_on_update = pn;
+                key_update.timer = now + (pto * 3);
+            }
+        }
+
         // Packets with no frames are invalid.
         if payload.cap() == 0 {
             return Err(Error::InvalidPacket);
         }
 
+        // Keep the payload around in case the peer sends a repair symbol for
+        // another packet of the same FEC group.
+        if hdr.ty == packet::Type::Short && self.local_transport_params.enable_fec
+        {
+            self.fec_decoder.store(pn, payload.as_ref());
+        }
+
         // Now that we decrypted the packet, let's see if we can map it to an
         // existing path.
         let recv_pid = if hdr.ty == packet::Type::Short && self.got_peer_conn_id {
@@ -2681,6 +5428,9 @@ impl Connection {
 
             self.key_phase = !self.key_phase;
 
+            self.key_update_sent_count = self.sent_count;
+            self.key_update_remote_count += 1;
+
             qlog_with_type!(QLOG_PACKET_RX, self.qlog, q, {
                 let trigger = Some(
                     qlog::events::security::KeyUpdateOrRetiredTrigger::RemoteUpdate,
@@ -2737,8 +5487,17 @@ impl Connection {
                     Some(hdr.dcid.to_vec().into());
 
                 self.encode_transport_params()?;
+
+                self.check_new_token(
+                    hdr.token.as_deref(),
+                    &hdr.dcid,
+                    recv_pid,
+                    info.from,
+                )?;
             }
 
+            self.check_early_data_replay();
+
             self.got_peer_conn_id = true;
         }
 
@@ -2757,7 +5516,20 @@ impl Connection {
 
         // Process packet payload.
         while payload.cap() > 0 {
-            let frame = frame::Frame::from_bytes(&mut payload, hdr.ty)?;
+            let frame_type = payload.peek_bytes(payload.cap())?.get_varint()?;
+
+            let frame = match frame::Frame::from_bytes_pooled(
+                &mut payload,
+                hdr.ty,
+                &mut self.buf_pool,
+            ) {
+                Ok(v) => v,
+
+                Err(e) => {
+                    self.frame_error = Some((frame_type, pn));
+                    return Err(e);
+                },
+            };
 
             qlog_with_type!(QLOG_PACKET_RX, self.qlog, _q, {
                 qlog_frames.push(frame.to_qlog());
@@ -2773,6 +5545,7 @@ impl Connection {
 
             if let Err(e) = self.process_frame(frame, &hdr, recv_pid, epoch, now)
             {
+                self.frame_error = Some((frame_type, pn));
                 frame_processing_err = Some(e);
                 break;
             }
@@ -2818,6 +5591,16 @@ impl Connection {
             }
         });
 
+        qlog_with_type!(QLOG_CONGESTION_STATE, self.qlog, q, {
+            for (_, p) in self.paths.iter_mut() {
+                if let Some(ev_data) =
+                    p.recovery.maybe_qlog_persistent_congestion()
+                {
+                    q.add_event_data_with_instant(ev_data, now).ok();
+                }
+            }
+        });
+
         if let Some(e) = frame_processing_err {
             // Any frame error is terminal, so now just return.
             return Err(e);
@@ -2843,8 +5626,17 @@ impl Connection {
         // Process acked frames. Note that several packets from several paths
         // might have been acked by the received packet.
         for (_, p) in self.paths.iter_mut() {
+            let mut new_pmtu = None;
+
             for acked in p.recovery.acked[epoch].drain(..) {
                 match acked {
+                    frame::Frame::Ping {
+                        mtu_probe: Some(size),
+                    } =>
+                        if let Some(pmtud) = p.pmtud.as_mut() {
+                            new_pmtu = pmtud.on_probe_acked(size).or(new_pmtu);
+                        },
+
                     frame::Frame::ACK { ranges, .. } => {
                         // Stop acknowledging packets less than or equal to the
                         // largest acknowledged in the sent ACK frame that, in
@@ -2860,7 +5652,11 @@ impl Connection {
                         self.pkt_num_spaces[epoch]
                             .crypto_stream
                             .send
-                            .ack_and_drop(offset, length);
+                            .ack_and_drop_pooled(
+                                offset,
+                                length,
+                                &mut self.buf_pool,
+                            );
                     },
 
                     frame::Frame::StreamHeader {
@@ -2912,7 +5708,14 @@ impl Connection {
                         self.handshake_done_acked = true;
                     },
 
-                    frame::Frame::ResetStream { stream_id, .. } => {
+                    frame::Frame::NewToken { .. } => {
+                        self.new_token_sent = true;
+
+                        self.new_token_acked = true;
+                    },
+
+                    frame::Frame::ResetStream { stream_id, .. } |
+                    frame::Frame::ResetStreamAt { stream_id, .. } => {
                         let stream = match self.streams.get_mut(stream_id) {
                             Some(v) => v,
 
@@ -2931,6 +5734,12 @@ impl Connection {
                     _ => (),
                 }
             }
+
+            if let Some(pmtu) = new_pmtu {
+                trace!("{} pmtu updated to {}", self.trace_id, pmtu);
+
+                p.recovery.pmtud_update_max_datagram_size(pmtu);
+            }
         }
 
         // Now that we processed all the frames, if there is a path that has no
@@ -2962,12 +5771,15 @@ impl Connection {
             self.pkt_num_spaces[epoch].largest_rx_pkt_time = now;
         }
 
+        let reordered = self.pkt_num_spaces[epoch].is_reordered(pn);
+
         self.pkt_num_spaces[epoch].recv_pkt_num.insert(pn);
 
         self.pkt_num_spaces[epoch].recv_pkt_need_ack.push_item(pn);
 
-        self.pkt_num_spaces[epoch].ack_elicited =
-            cmp::max(self.pkt_num_spaces[epoch].ack_elicited, ack_elicited);
+        if ack_elicited {
+            self.on_ack_eliciting_pkt_received(epoch, reordered, now);
+        }
 
         self.pkt_num_spaces[epoch].largest_rx_pkt_num =
             cmp::max(self.pkt_num_spaces[epoch].largest_rx_pkt_num, pn);
@@ -2993,12 +5805,18 @@ impl Connection {
             self.idle_timer = Some(now + idle_timeout);
         }
 
+        self.restart_keepalive_timer(now);
+
         // Update send capacity.
         self.update_tx_cap();
 
         self.recv_count += 1;
         self.paths.get_mut(recv_pid)?.recv_count += 1;
 
+        if hdr.ty == packet::Type::Short {
+            self.handshake_timeline.first_1rtt_recv.get_or_insert(now);
+        }
+
         let read = b.off() + aead_tag_len;
 
         self.recv_bytes += read as u64;
@@ -3083,6 +5901,19 @@ impl Connection {
         self.send_on_path(out, None, None)
     }
 
+    /// Returns the reason why the last call to [`send()`] or
+    /// [`send_on_path()`] returned [`Done`].
+    ///
+    /// `None` is returned if there simply was nothing to send, or if the last
+    /// call wrote a packet.
+    ///
+    /// [`send()`]: struct.Connection.html#method.send
+    /// [`send_on_path()`]: struct.Connection.html#method.send_on_path
+    /// [`Done`]: enum.Error.html#variant.Done
+    pub fn send_blocked_reason(&self) -> Option<SendBlockedReason> {
+        self.send_blocked_reason
+    }
+
     /// Writes a single QUIC packet to be sent to the peer from the specified
     /// local address `from` to the destination address `to`.
     ///
@@ -3170,20 +6001,41 @@ impl Connection {
         &mut self, out: &mut [u8], from: Option<SocketAddr>,
         to: Option<SocketAddr>,
     ) -> Result<(usize, SendInfo)> {
+        #[cfg(feature = "tracing")]
+        let _span = self.tracing_span.clone().entered();
+
         if out.is_empty() {
             return Err(Error::BufferTooShort);
         }
 
-        if self.is_closed() || self.is_draining() {
+        if self.is_closed() || (self.is_draining() && !self.close_resend) {
             return Err(Error::Done);
         }
 
         let now = time::Instant::now();
 
+        self.send_blocked_reason = None;
+
+        if self.bandwidth_probe_until.map_or(false, |t| t <= now) {
+            self.bandwidth_probe_until = None;
+        }
+
+        // Close the current FEC group once the application has nothing left
+        // to send, so that the tail of a burst is protected as well.
+        if self.fec_enabled() &&
+            self.fec_encoder.has_open_group() &&
+            !self.streams.has_flushable() &&
+            !self.dgram_send_queue.has_pending()
+        {
+            self.fec_encoder.flush();
+        }
+
         if self.local_error.is_none() {
             self.do_handshake(now)?;
         }
 
+        self.send_file_data();
+
         // Forwarding the error value here could confuse
         // applications, as they may not expect getting a `recv()`
         // error when calling `send()`.
@@ -3192,6 +6044,22 @@ impl Connection {
         // take care of terminating the connection as needed.
         let _ = self.process_undecrypted_0rtt_packets();
 
+        if self.migrate_to_preferred_address && self.handshake_confirmed {
+            self.start_preferred_address_migration();
+        }
+
+        if self.handshake_confirmed && self.dcid_rotation_due(now) {
+            // Without spare Connection IDs, or while paths are being
+            // validated, try again later.
+            let _ = self.rotate_dcid();
+        }
+
+        if self.handshake_confirmed && self.key_update_due() {
+            // A previous key update might still be unconfirmed, in which case
+            // try again later.
+            let _ = self.initiate_key_update();
+        }
+
         // There's no point in trying to send a packet if the Initial secrets
         // have not been derived yet, so return early.
         if !self.derived_initial_secrets {
@@ -3200,6 +6068,15 @@ impl Connection {
 
         let mut has_initial = false;
 
+        // Whether a datagram containing an Initial packet needs to be padded.
+        let pad_initial = match self.initial_padding_policy {
+            InitialPaddingPolicy::All => true,
+
+            InitialPaddingPolicy::FirstOnly => !self.initial_padded,
+        };
+
+        let mut pkt_count = 0;
+
         let mut done = 0;
 
         // Limit output packet size to respect the sender and receiver's
@@ -3217,18 +6094,67 @@ impl Connection {
 
         let send_path = self.paths.get_mut(send_pid)?;
 
+        let mut amplification_limited = false;
+
+        // A PMTU probe is allowed to exceed the current maximum datagram size.
+        // Whether it is actually sent is decided when building the packet.
+        if let Some(pmtud) = &send_path.pmtud {
+            if self.handshake_confirmed && pmtud.should_probe() {
+                left = cmp::max(left, cmp::min(out.len(), pmtud.probe_size()));
+            }
+        }
+
         // Limit data sent by the server based on the amount of data received
         // from the client before its address is validated.
         if !send_path.verified_peer_address && self.is_server {
+            amplification_limited = send_path.max_send_bytes < left;
+
             left = cmp::min(left, send_path.max_send_bytes);
         }
 
+        if let Some(horizon) = self.pacing_horizon {
+            let release = send_path.recovery.pacing_release_time(now);
+
+            let app_space = &self.pkt_num_spaces[packet::Epoch::Application];
+
+            let ack_needed =
+                !app_space.recv_pkt_need_ack.is_empty() && app_space.ack_elicited;
+
+            let has_app_data =
+                self.streams.has_flushable() || !self.dgram_send_queue.is_empty();
+
+            // Hold back application data that pacing would only release past
+            // the horizon, unless an ACK needs to be sent right away.
+            if release > now + horizon &&
+                self.handshake_confirmed &&
+                self.local_error.is_none() &&
+                has_app_data &&
+                !ack_needed
+            {
+                self.send_blocked_reason =
+                    Some(SendBlockedReason::Pacing(release));
+
+                return Err(Error::Done);
+            }
+        }
+
         // Generate coalesced packets.
         while left > 0 {
+            // Don't coalesce a 1-RTT packet after long header ones, when the
+            // policy doesn't allow it.
+            if pkt_count > 0 &&
+                self.coalescing_policy == CoalescingPolicy::LongHeaderOnly &&
+                self.write_pkt_type(send_pid, now) == Ok(packet::Type::Short)
+            {
+                break;
+            }
+
+            let pending = self.pending_seals.len();
+
             let (ty, written) = match self.send_single(
                 &mut out[done..done + left],
                 send_pid,
-                has_initial,
+                has_initial && pad_initial,
                 now,
             ) {
                 Ok(v) => v,
@@ -3238,8 +6164,13 @@ impl Connection {
                 Err(e) => return Err(e),
             };
 
+            for p in &mut self.pending_seals[pending..] {
+                p.offset += done;
+            }
+
             done += written;
             left -= written;
+            pkt_count += 1;
 
             match ty {
                 packet::Type::Initial => has_initial = true,
@@ -3250,6 +6181,10 @@ impl Connection {
                 _ => (),
             };
 
+            if self.coalescing_policy == CoalescingPolicy::Disabled {
+                break;
+            }
+
             // When sending multiple PTO probes, don't coalesce them together,
             // so they are sent on separate UDP datagrams.
             if let Ok(epoch) = ty.to_epoch() {
@@ -3269,12 +6204,37 @@ impl Connection {
         if done == 0 {
             self.last_tx_data = self.tx_data;
 
+            let reason =
+                self.compute_send_blocked_reason(send_pid, amplification_limited);
+
+            if reason == Some(SendBlockedReason::AmplificationLimit) &&
+                self.send_blocked_reason != reason
+            {
+                self.handshake_timeline.amplification_limited += 1;
+
+                qlog_with_type!(QLOG_AMPLIFICATION_LIMITED, self.qlog, q, {
+                    let path = self.paths.get(send_pid)?;
+
+                    let ev_data = EventData::AmplificationLimited(
+                        qlog::events::connectivity::AmplificationLimited {
+                            remaining: path.max_send_bytes as u64,
+                            received: Some(path.recv_bytes),
+                        },
+                    );
+
+                    q.add_event_data_with_instant(ev_data, now).ok();
+                });
+            }
+
+            self.send_blocked_reason = reason;
+
             return Err(Error::Done);
         }
 
         // Pad UDP datagram if it contains a QUIC Initial packet.
         #[cfg(not(feature = "fuzzing"))]
-        if has_initial && left > 0 && done < MIN_CLIENT_INITIAL_LEN {
+        if has_initial && pad_initial && left > 0 && done < MIN_CLIENT_INITIAL_LEN
+        {
             let pad_len = cmp::min(left, MIN_CLIENT_INITIAL_LEN - done);
 
             // Fill padding area with null bytes, to avoid leaking information
@@ -3284,6 +6244,24 @@ impl Connection {
             done += pad_len;
         }
 
+        if has_initial {
+            self.initial_padded = true;
+        }
+
+        self.sent_dgram_count += 1;
+
+        if pkt_count > 1 {
+            self.coalesced_dgram_count += 1;
+        }
+
+        // Without a draining period, the connection is closed as soon as the
+        // CONNECTION_CLOSE frame is sent.
+        if !self.close_drain && self.is_closing() && self.is_draining() {
+            self.mark_closed();
+        }
+
+        self.update_metrics();
+
         let send_path = self.paths.get(send_pid)?;
 
         let info = SendInfo {
@@ -3296,58 +6274,258 @@ impl Connection {
         Ok((done, info))
     }
 
-    fn send_single(
-        &mut self, out: &mut [u8], send_pid: usize, has_initial: bool,
-        now: time::Instant,
-    ) -> Result<(packet::Type, usize)> {
-        if out.is_empty() {
+    /// Writes multiple QUIC packets to be sent to the peer as a single batch
+    /// suitable for UDP Generic Segmentation Offload (GSO).
+    ///
+    /// Packets are written to `out` in slots of `segment_size` bytes, so that
+    /// the buffer can be handed to the kernel with a `UDP_SEGMENT` of
+    /// `segment_size` and split back into individual datagrams. Since only
+    /// the last datagram of a GSO batch can be shorter than the segment size,
+    /// the batch ends with the first packet that doesn't fill its slot. All
+    /// the packets of a batch are sent on the same path.
+    ///
+    /// The batch is also limited to the path's [`send_budget()`], rounded
+    /// down to a whole number of segments, so the buffer can be larger than
+    /// what is currently allowed to be sent.
+    ///
+    /// On success the total number of bytes written and the [`SendInfo`] of
+    /// the first packet are returned, or [`Done`] if there is nothing to send.
+    ///
+    /// If an error occurs after some packets were already written, the
+    /// partial batch is returned, and the error is returned by the next call
+    /// instead, so that the packets aren't lost.
+    ///
+    /// [`send_budget()`]: struct.Connection.html#method.send_budget
+    /// [`SendInfo`]: struct.SendInfo.html
+    /// [`Done`]: enum.Error.html#variant.Done
+    ///
+    /// ## Examples:
+    ///
+    /// ```no_run
+    /// # let mut out = [0; 65535];
+    /// # let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    /// # let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION)?;
+    /// # let scid = quiche::ConnectionId::from_ref(&[0xba; 16]);
+    /// # let peer = "127.0.0.1:1234".parse().unwrap();
+    /// # let local = socket.local_addr().unwrap();
+    /// # let mut conn = quiche::accept(&scid, None, local, peer, &mut config)?;
+    /// # let segment_size = 1200;
+    /// loop {
+    ///     let (write, send_info) = match conn.send_batch(&mut out, segment_size) {
+    ///         Ok(v) => v,
+    ///
+    ///         Err(quiche::Error::Done) => {
+    ///             // Done writing.
+    ///             break;
+    ///         },
+    ///
+    ///         Err(e) => {
+    ///             // An error occurred, handle it.
+    ///             break;
+    ///         },
+    ///     };
+    ///
+    ///     // Send `out[..write]` with a `UDP_SEGMENT` of `segment_size`.
+    /// }
+    /// # Ok::<(), quiche::Error>(())
+    /// ```
+    pub fn send_batch(
+        &mut self, out: &mut [u8], segment_size: usize,
+    ) -> Result<(usize, SendInfo)> {
+        if segment_size == 0 {
             return Err(Error::BufferTooShort);
         }
 
-        if self.is_draining() {
-            return Err(Error::Done);
+        if let Some(e) = self.batch_error.take() {
+            return Err(e);
         }
 
-        let is_closing = self.local_error.is_some();
-
-        let mut b = octets::OctetsMut::with_slice(out);
-
-        let pkt_type = self.write_pkt_type(send_pid)?;
+        // Don't write more than the send quantum of the path, so that the
+        // whole batch can be sent at once without bursting past the pacer or
+        // the congestion window.
+        let max_len = match self
+            .get_send_path_id(None, None)
+            .and_then(|pid| self.paths.get(pid))
+        {
+            Ok(p) =>
+                cmp::max(p.recovery.send_budget() / segment_size, 1) *
+                    segment_size,
 
-        let max_dgram_len = if !self.dgram_send_queue.is_empty() {
-            self.dgram_max_writable_len()
-        } else {
-            None
+            Err(_) => out.len(),
         };
 
-        let epoch = pkt_type.to_epoch()?;
-        let pkt_space = &mut self.pkt_num_spaces[epoch];
+        let out_len = cmp::min(out.len(), max_len);
+        let out = &mut out[..out_len];
 
-        // Process lost frames. There might be several paths having lost frames.
-        for (_, p) in self.paths.iter_mut() {
-            for lost in p.recovery.lost[epoch].drain(..) {
-                match lost {
-                    frame::Frame::CryptoHeader { offset, length } => {
-                        pkt_space.crypto_stream.send.retransmit(offset, length);
+        let mut done = 0;
+        let mut batch_info: Option<SendInfo> = None;
 
-                        self.stream_retrans_bytes += length as u64;
-                        p.stream_retrans_bytes += length as u64;
+        // Protect all the 1-RTT packets of the batch together at the end.
+        self.defer_seal = true;
+        self.pending_seals.clear();
 
-                        self.retrans_count += 1;
-                        p.retrans_count += 1;
-                    },
+        while done < out.len() {
+            let end = cmp::min(done + segment_size, out.len());
 
-                    frame::Frame::StreamHeader {
-                        stream_id,
-                        offset,
-                        length,
-                        fin,
-                    } => {
-                        let stream = match self.streams.get_mut(stream_id) {
-                            Some(v) => v,
+            let (from, to) = match batch_info {
+                Some(info) => (Some(info.from), Some(info.to)),
 
-                            None => continue,
-                        };
+                None => (None, None),
+            };
+
+            let pending = self.pending_seals.len();
+
+            let (written, info) =
+                match self.send_on_path(&mut out[done..end], from, to) {
+                    Ok(v) => v,
+
+                    Err(Error::Done) => break,
+
+                    // Hand out the packets already written first.
+                    Err(e) if done > 0 => {
+                        self.pending_seals.truncate(pending);
+                        self.batch_error = Some(e);
+
+                        break;
+                    },
+
+                    Err(e) => {
+                        self.defer_seal = false;
+                        self.pending_seals.clear();
+
+                        return Err(e);
+                    },
+                };
+
+            for p in &mut self.pending_seals[pending..] {
+                p.offset += done;
+            }
+
+            batch_info.get_or_insert(info);
+
+            done += written;
+
+            if written < segment_size {
+                break;
+            }
+        }
+
+        self.defer_seal = false;
+
+        if !self.pending_seals.is_empty() {
+            let pending = std::mem::take(&mut self.pending_seals);
+
+            let aead = match self.pkt_num_spaces[packet::Epoch::Application]
+                .crypto_seal
+            {
+                Some(ref v) => v,
+                None => return Err(Error::InvalidState),
+            };
+
+            let res = packet::encrypt_pkts(
+                &mut out[..done],
+                &pending,
+                aead,
+                self.packet_protector.as_deref(),
+            );
+
+            // Keep the allocation around for the next batch.
+            self.pending_seals = pending;
+            self.pending_seals.clear();
+
+            res?;
+        }
+
+        match batch_info {
+            Some(info) => Ok((done, info)),
+
+            None => Err(Error::Done),
+        }
+    }
+
+    /// Returns why nothing could be sent on the given path.
+    fn compute_send_blocked_reason(
+        &self, send_pid: usize, amplification_limited: bool,
+    ) -> Option<SendBlockedReason> {
+        if amplification_limited {
+            return Some(SendBlockedReason::AmplificationLimit);
+        }
+
+        let has_app_data =
+            self.streams.has_flushable() || !self.dgram_send_queue.is_empty();
+
+        if has_app_data && !self.is_established() && !self.is_in_early_data() {
+            return Some(SendBlockedReason::Handshake);
+        }
+
+        let path = self.paths.get(send_pid).ok()?;
+
+        // Note that stream data is only buffered up to the available cwnd
+        // (see `stream_capacity()`), so a full cwnd is reported even when no
+        // data is currently buffered.
+        if path.recovery.cwnd_available() < path.recovery.max_datagram_size() {
+            return Some(SendBlockedReason::CongestionWindow);
+        }
+
+        None
+    }
+
+    fn send_single(
+        &mut self, out: &mut [u8], send_pid: usize, has_initial: bool,
+        now: time::Instant,
+    ) -> Result<(packet::Type, usize)> {
+        if out.is_empty() {
+            return Err(Error::BufferTooShort);
+        }
+
+        if self.is_draining() && !self.close_resend {
+            return Err(Error::Done);
+        }
+
+        // Don't spend the congestion window on DATAGRAMs that are too old to
+        // be useful.
+        self.dgram_send_queue.purge_expired(now);
+
+        let is_closing = self.local_error.is_some();
+
+        let mut b = octets::OctetsMut::with_slice(out);
+
+        let pkt_type = self.write_pkt_type(send_pid, now)?;
+
+        let max_dgram_len = if !self.dgram_send_queue.is_empty() {
+            self.dgram_max_writable_len()
+        } else {
+            None
+        };
+
+        let epoch = pkt_type.to_epoch()?;
+        let pkt_space = &mut self.pkt_num_spaces[epoch];
+
+        // Process lost frames. There might be several paths having lost frames.
+        for (_, p) in self.paths.iter_mut() {
+            for lost in p.recovery.lost[epoch].drain(..) {
+                match lost {
+                    frame::Frame::CryptoHeader { offset, length } => {
+                        pkt_space.crypto_stream.send.retransmit(offset, length);
+
+                        self.stream_retrans_bytes += length as u64;
+                        p.stream_retrans_bytes += length as u64;
+
+                        self.retrans_count += 1;
+                        p.retrans_count += 1;
+                    },
+
+                    frame::Frame::StreamHeader {
+                        stream_id,
+                        offset,
+                        length,
+                        fin,
+                    } => {
+                        let stream = match self.streams.get_mut(stream_id) {
+                            Some(v) => v,
+
+                            None => continue,
+                        };
 
                         let was_flushable = stream.is_flushable();
 
@@ -3379,22 +6557,49 @@ impl Connection {
                         pkt_space.ack_elicited = true;
                     },
 
+                    frame::Frame::Ping {
+                        mtu_probe: Some(size),
+                    } =>
+                        if let Some(pmtud) = &mut p.pmtud {
+                            pmtud.on_probe_lost(size);
+                        },
+
                     frame::Frame::ResetStream {
                         stream_id,
                         error_code,
                         final_size,
                     } =>
                         if self.streams.get(stream_id).is_some() {
-                            self.streams
-                                .insert_reset(stream_id, error_code, final_size);
+                            self.streams.insert_reset(
+                                stream_id, error_code, final_size, 0,
+                            );
                         },
 
+                    frame::Frame::ResetStreamAt {
+                        stream_id,
+                        error_code,
+                        final_size,
+                        reliable_size,
+                    } if self.streams.get(stream_id).is_some() => {
+                        self.streams.insert_reset(
+                            stream_id,
+                            error_code,
+                            final_size,
+                            reliable_size,
+                        );
+                    },
+
                     // Retransmit HANDSHAKE_DONE only if it hasn't been acked at
                     // least once already.
                     frame::Frame::HandshakeDone if !self.handshake_done_acked => {
                         self.handshake_done_sent = false;
                     },
 
+                    // A new token is minted when the frame is retransmitted.
+                    frame::Frame::NewToken { .. } if !self.new_token_acked => {
+                        self.new_token_sent = false;
+                    },
+
                     frame::Frame::MaxStreamData { stream_id, .. } => {
                         if self.streams.get(stream_id).is_some() {
                             self.streams.insert_almost_full(stream_id);
@@ -3418,7 +6623,12 @@ impl Connection {
             }
         }
 
-        let is_app_limited = self.delivery_rate_check_if_app_limited();
+        // Probe packets fill the congestion window on behalf of the
+        // application, so delivery rate samples are not app-limited.
+        let is_app_limited = self.delivery_rate_check_if_app_limited() &&
+            self.bandwidth_probe_until.is_none();
+        let fec_enabled = self.fec_enabled();
+        let max_send_udp_payload_size = self.max_send_udp_payload_size();
         let n_paths = self.paths.len();
         let path = self.paths.get_mut(send_pid)?;
         let flow_control = &mut self.flow_control;
@@ -3426,9 +6636,41 @@ impl Connection {
 
         let mut left = b.cap();
 
+        // Only PMTU probes are allowed to exceed the current maximum datagram
+        // size. Since a probe is padded to its full size, it also needs to fit
+        // in the congestion window.
+        let pmtu_probe_size = match &path.pmtud {
+            Some(pmtud)
+                if pkt_type == packet::Type::Short &&
+                    self.handshake_confirmed &&
+                    !is_closing &&
+                    path.active() &&
+                    pmtud.should_probe() &&
+                    left >= pmtud.probe_size() &&
+                    path.recovery.cwnd_available() >= pmtud.probe_size() =>
+                Some(pmtud.probe_size()),
+
+            _ => None,
+        };
+
+        left = match pmtu_probe_size {
+            Some(probe_size) => cmp::min(left, probe_size),
+
+            None => cmp::min(left, max_send_udp_payload_size),
+        };
+
         let pn = pkt_space.next_pkt_num;
         let pn_len = packet::pkt_num_len(pn)?;
 
+        #[cfg(feature = "tracing")]
+        let _span = tracing::trace_span!(
+            "send_packet",
+            ty = ?pkt_type,
+            pn,
+            path_id = send_pid,
+        )
+        .entered();
+
         // The AEAD overhead at the current encryption level.
         let crypto_overhead = pkt_space.crypto_overhead().ok_or(Error::Done)?;
 
@@ -3469,7 +6711,11 @@ impl Connection {
             key_phase: self.key_phase,
         };
 
-        hdr.to_bytes(&mut b)?;
+        // Randomize the QUIC bit if the peer accepts it being cleared.
+        let quic_bit = !self.peer_transport_params.grease_quic_bit ||
+            rand::rand_u8() & 1 == 1;
+
+        hdr.to_bytes_with_quic_bit(&mut b, quic_bit)?;
 
         let hdr_trace = if log::max_level() == log::LevelFilter::Trace {
             Some(format!("{hdr:?}"))
@@ -3480,15 +6726,18 @@ impl Connection {
         let hdr_ty = hdr.ty;
 
         #[cfg(feature = "qlog")]
-        let qlog_pkt_hdr = self.qlog.streamer.as_ref().map(|_q| {
-            qlog::events::quic::PacketHeader::with_type(
-                hdr.ty.to_qlog(),
-                Some(pn),
-                Some(hdr.version),
-                Some(&hdr.scid),
-                Some(&hdr.dcid),
-            )
-        });
+        let qlog_pkt_hdr =
+            if self.qlog.streamer.is_some() && self.qlog.allows(QLOG_PACKET_TX) {
+                Some(qlog::events::quic::PacketHeader::with_type(
+                    hdr.ty.to_qlog(),
+                    Some(pn),
+                    Some(hdr.version),
+                    Some(&hdr.scid),
+                    Some(&hdr.dcid),
+                ))
+            } else {
+                None
+            };
 
         // Calculate the space required for the packet, including the header
         // the payload length, the packet number and the AEAD overhead.
@@ -3522,7 +6771,7 @@ impl Connection {
             return Err(Error::Done);
         }
 
-        let mut frames: SmallVec<[frame::Frame; 1]> = SmallVec::new();
+        let mut frames = path.recovery.frames_buf();
 
         let mut ack_eliciting = false;
         let mut in_flight = false;
@@ -3546,9 +6795,48 @@ impl Connection {
 
         let payload_offset = b.off();
 
-        let cwnd_available =
+        let mut cwnd_available =
             path.recovery.cwnd_available().saturating_sub(overhead);
 
+        // Whether the packet payload should be added to the current FEC group.
+        let mut fec_protect = false;
+
+        // Create REPAIR frame.
+        //
+        // Packets carrying a repair symbol are not protected themselves. All
+        // other 1-RTT packets leave room for the REPAIR frame overhead, so that
+        // a repair symbol always fits in a packet of the same size.
+        if fec_enabled &&
+            pkt_type == packet::Type::Short &&
+            pmtu_probe_size.is_none()
+        {
+            fec_protect = true;
+
+            if let Some(len) = self.fec_encoder.pending_len() {
+                if len <= left &&
+                    len <= cwnd_available &&
+                    path.active() &&
+                    !is_closing
+                {
+                    if let Some(frame) = self.fec_encoder.pop() {
+                        if push_frame_to_pkt!(b, frames, frame, left) {
+                            cwnd_available -= len;
+
+                            ack_eliciting = true;
+                            in_flight = true;
+                            fec_protect = false;
+
+                            self.fec_repair_sent_count += 1;
+                        }
+                    }
+                }
+            }
+
+            if fec_protect {
+                left = left.saturating_sub(frame::MAX_REPAIR_OVERHEAD);
+            }
+        }
+
         let left_before_packing_ack_frame = left;
 
         // Create ACK frame.
@@ -3557,9 +6845,15 @@ impl Connection {
         // generate an ACK (if there's anything to ACK) since we're going to
         // send a packet with PING anyways, even if we haven't received anything
         // ACK eliciting.
-        if pkt_space.recv_pkt_need_ack.len() > 0 &&
-            (pkt_space.ack_elicited || ack_elicit_required) &&
+        //
+        // When closing without a draining period, pending ACKs are flushed
+        // with the CONNECTION_CLOSE frame, as there won't be another chance.
+        if !pkt_space.recv_pkt_need_ack.is_empty() &&
+            (pkt_space.ack_elicited ||
+                pkt_space.ack_timer.is_some() ||
+                ack_elicit_required) &&
             (!is_closing ||
+                !self.close_drain ||
                 (pkt_type == Type::Handshake &&
                     self.local_error
                         .as_ref()
@@ -3588,7 +6882,39 @@ impl Connection {
                 // available cwnd.
                 if push_frame_to_pkt!(b, frames, frame, left) {
                     pkt_space.ack_elicited = false;
+                    pkt_space.ack_eliciting_unacked = 0;
+                    pkt_space.ack_timer = None;
+                }
+            }
+        }
+
+        // Create PMTU probe.
+        //
+        // The probe is padded up to the probe size, so no other frame can be
+        // bundled with it, except for ACK.
+        if let Some(probe_size) = pmtu_probe_size {
+            let frame = frame::Frame::Ping {
+                mtu_probe: Some(probe_size),
+            };
+
+            if push_frame_to_pkt!(b, frames, frame, left) {
+                let frame = frame::Frame::Padding { len: left };
+
+                push_frame_to_pkt!(b, frames, frame, left);
+
+                ack_eliciting = true;
+                in_flight = true;
+
+                if let Some(pmtud) = &mut path.pmtud {
+                    pmtud.on_probe_sent();
                 }
+
+                trace!(
+                    "{} sending pmtu probe pn={} size={}",
+                    self.trace_id,
+                    pn,
+                    probe_size
+                );
             }
         }
 
@@ -3600,6 +6926,7 @@ impl Connection {
         );
 
         let mut challenge_data = None;
+        let mut quality_probe_data = None;
 
         if pkt_type == packet::Type::Short {
             // Create PATH_RESPONSE frame if needed.
@@ -3633,6 +6960,20 @@ impl Connection {
                 }
             }
 
+            // Create PATH_CHALLENGE frame for a path quality probe.
+            if path.quality_probe_requested() {
+                let data = rand::rand_u64().to_be_bytes();
+
+                let frame = frame::Frame::PathChallenge { data };
+
+                if push_frame_to_pkt!(b, frames, frame, left) {
+                    quality_probe_data = Some(data);
+
+                    ack_eliciting = true;
+                    in_flight = true;
+                }
+            }
+
             if let Some(key_update) = pkt_space.key_update.as_mut() {
                 key_update.update_acked = true;
             }
@@ -3671,14 +7012,30 @@ impl Connection {
                 }
             }
 
+            // Create NEW_TOKEN frame.
+            if self.handshake_confirmed && !self.new_token_sent {
+                if let Some(provider) = &self.token_provider {
+                    let frame = frame::Frame::NewToken {
+                        token: provider.mint_new_token(&path.peer_addr()),
+                    };
+
+                    if push_frame_to_pkt!(b, frames, frame, left) {
+                        self.new_token_sent = true;
+
+                        ack_eliciting = true;
+                        in_flight = true;
+                    }
+                }
+            }
+
             // Create MAX_STREAMS_BIDI frame.
-            if self.streams.should_update_max_streams_bidi() {
+            if self.streams.should_update_max_streams_bidi(now) {
                 let frame = frame::Frame::MaxStreamsBidi {
                     max: self.streams.max_streams_bidi_next(),
                 };
 
                 if push_frame_to_pkt!(b, frames, frame, left) {
-                    self.streams.update_max_streams_bidi();
+                    self.streams.update_max_streams_bidi(now);
 
                     ack_eliciting = true;
                     in_flight = true;
@@ -3686,13 +7043,13 @@ impl Connection {
             }
 
             // Create MAX_STREAMS_UNI frame.
-            if self.streams.should_update_max_streams_uni() {
+            if self.streams.should_update_max_streams_uni(now) {
                 let frame = frame::Frame::MaxStreamsUni {
                     max: self.streams.max_streams_uni_next(),
                 };
 
                 if push_frame_to_pkt!(b, frames, frame, left) {
-                    self.streams.update_max_streams_uni();
+                    self.streams.update_max_streams_uni(now);
 
                     ack_eliciting = true;
                     in_flight = true;
@@ -3725,7 +7082,11 @@ impl Connection {
                 };
 
                 // Autotune the stream window size.
-                stream.recv.autotune_window(now, path.recovery.rtt());
+                if self.rate_window_autotuning {
+                    stream.recv.autotune_window_rate(now, path.recovery.rtt());
+                } else {
+                    stream.recv.autotune_window(now, path.recovery.rtt());
+                }
 
                 let frame = frame::Frame::MaxStreamData {
                     stream_id,
@@ -3737,6 +7098,21 @@ impl Connection {
 
                     stream.recv.update_max_data(now);
 
+                    qlog_with_type!(QLOG_FLOW_CONTROL, self.qlog, q, {
+                        let ev_data = EventData::FlowControlUpdated(
+                            qlog::events::quic::FlowControlUpdated {
+                                stream_id: Some(stream_id),
+                                max_data: Some(stream.recv.max_data()),
+                                window: Some(recv_win),
+                                consumption_rate: Some(
+                                    stream.recv.consumption_rate(),
+                                ),
+                            },
+                        );
+
+                        q.add_event_data_with_instant(ev_data, now).ok();
+                    });
+
                     self.streams.remove_almost_full(stream_id);
 
                     ack_eliciting = true;
@@ -3759,7 +7135,11 @@ impl Connection {
                 flow_control.max_data() < flow_control.max_data_next()
             {
                 // Autotune the connection window size.
-                flow_control.autotune_window(now, path.recovery.rtt());
+                if self.rate_window_autotuning {
+                    flow_control.autotune_window_rate(now, path.recovery.rtt());
+                } else {
+                    flow_control.autotune_window(now, path.recovery.rtt());
+                }
 
                 let frame = frame::Frame::MaxData {
                     max: flow_control.max_data_next(),
@@ -3771,6 +7151,21 @@ impl Connection {
                     // Commits the new max_rx_data limit.
                     flow_control.update_max_data(now);
 
+                    qlog_with_type!(QLOG_FLOW_CONTROL, self.qlog, q, {
+                        let ev_data = EventData::FlowControlUpdated(
+                            qlog::events::quic::FlowControlUpdated {
+                                stream_id: None,
+                                max_data: Some(flow_control.max_data()),
+                                window: Some(flow_control.window()),
+                                consumption_rate: Some(
+                                    flow_control.consumption_rate(),
+                                ),
+                            },
+                        );
+
+                        q.add_event_data_with_instant(ev_data, now).ok();
+                    });
+
                     ack_eliciting = true;
                     in_flight = true;
                 }
@@ -3796,17 +7191,26 @@ impl Connection {
                 }
             }
 
-            // Create RESET_STREAM frames as needed.
-            for (stream_id, (error_code, final_size)) in self
+            // Create RESET_STREAM and RESET_STREAM_AT frames as needed.
+            for (stream_id, (error_code, final_size, reliable_size)) in self
                 .streams
                 .reset()
                 .map(|(&k, &v)| (k, v))
-                .collect::<Vec<(u64, (u64, u64))>>()
+                .collect::<Vec<(u64, (u64, u64, u64))>>()
             {
-                let frame = frame::Frame::ResetStream {
-                    stream_id,
-                    error_code,
-                    final_size,
+                let frame = if reliable_size > 0 {
+                    frame::Frame::ResetStreamAt {
+                        stream_id,
+                        error_code,
+                        final_size,
+                        reliable_size,
+                    }
+                } else {
+                    frame::Frame::ResetStream {
+                        stream_id,
+                        error_code,
+                        final_size,
+                    }
                 };
 
                 if push_frame_to_pkt!(b, frames, frame, left) {
@@ -3858,6 +7262,8 @@ impl Connection {
             }
         }
 
+        let mut close_sent = false;
+
         // Create CONNECTION_CLOSE frame. Try to send this only on the active
         // path, unless it is the last one available.
         if path.active() || n_paths == 1 {
@@ -3871,8 +7277,7 @@ impl Connection {
                         };
 
                         if push_frame_to_pkt!(b, frames, frame, left) {
-                            let pto = path.recovery.pto();
-                            self.draining_timer = Some(now + (pto * 3));
+                            close_sent = true;
 
                             ack_eliciting = true;
                             in_flight = true;
@@ -3882,13 +7287,14 @@ impl Connection {
                     // Create ConnectionClose frame.
                     let frame = frame::Frame::ConnectionClose {
                         error_code: conn_err.error_code,
-                        frame_type: 0,
+                        frame_type: self
+                            .local_error_frame
+                            .map_or(0, |(frame_type, _)| frame_type),
                         reason: conn_err.reason.clone(),
                     };
 
                     if push_frame_to_pkt!(b, frames, frame, left) {
-                        let pto = path.recovery.pto();
-                        self.draining_timer = Some(now + (pto * 3));
+                        close_sent = true;
 
                         ack_eliciting = true;
                         in_flight = true;
@@ -3897,6 +7303,21 @@ impl Connection {
             }
         }
 
+        if close_sent {
+            self.close_resend = false;
+
+            // Frames sent again don't extend the closing period.
+            if self.draining_timer.is_none() {
+                let pto = path.recovery.pto();
+
+                self.draining_timer = Some(if self.close_drain {
+                    now + (pto * 3)
+                } else {
+                    now
+                });
+            }
+        }
+
         // Create CRYPTO frame.
         if pkt_space.crypto_stream.is_flushable() &&
             left > frame::MAX_CRYPTO_OVERHEAD &&
@@ -4099,19 +7520,19 @@ impl Connection {
                     octets::varint_len(stream_off) + // offset
                     2; // length, always encode as 2-byte varint
 
+                // If the frame header doesn't fit, leave the stream queued so
+                // its data is sent in the next packet. Removing it would
+                // starve it until more data is written to it.
                 let max_len = match left.checked_sub(hdr_len) {
                     Some(v) => v,
-                    None => {
-                        let priority_key = Arc::clone(&stream.priority_key);
-                        self.streams.remove_flushable(&priority_key);
-
-                        continue;
-                    },
+                    None => break,
                 };
 
                 let (mut stream_hdr, mut stream_payload) =
                     b.split_at(hdr_off + hdr_len)?;
 
+                let was_writable = stream.is_writable();
+
                 // Write stream data into the packet buffer.
                 let (len, fin) =
                     stream.send.emit(&mut stream_payload.as_mut()[..max_len])?;
@@ -4149,6 +7570,11 @@ impl Connection {
                 }
 
                 let priority_key = Arc::clone(&stream.priority_key);
+
+                // Sending data might bring the stream back below its high
+                // watermark.
+                let now_writable = !was_writable && stream.is_writable();
+
                 // If the stream is no longer flushable, remove it from the queue
                 if !stream.is_flushable() {
                     self.streams.remove_flushable(&priority_key);
@@ -4159,6 +7585,12 @@ impl Connection {
                     self.streams.insert_flushable(&priority_key);
                 }
 
+                if now_writable {
+                    self.streams.insert_writable(&priority_key);
+                }
+
+                self.streams.on_group_data_sent(priority_key.group, len);
+
                 break;
             }
         }
@@ -4176,12 +7608,38 @@ impl Connection {
             left >= 1 &&
             !is_closing
         {
-            let frame = frame::Frame::Ping;
+            let frame = frame::Frame::Ping { mtu_probe: None };
+
+            if push_frame_to_pkt!(b, frames, frame, left) {
+                ack_eliciting = true;
+                in_flight = true;
+            }
+        }
+
+        // When probing for bandwidth and nothing else is being sent, fill the
+        // packet with PING and PADDING frames, up to the available cwnd.
+        if self.bandwidth_probe_until.is_some() &&
+            pkt_type == packet::Type::Short &&
+            !ack_eliciting &&
+            left >= 1 &&
+            !is_closing
+        {
+            let frame = frame::Frame::Ping { mtu_probe: None };
 
             if push_frame_to_pkt!(b, frames, frame, left) {
                 ack_eliciting = true;
                 in_flight = true;
             }
+
+            let payload_len = b.off() - payload_offset;
+            let padding_len =
+                cmp::min(left, cwnd_available.saturating_sub(payload_len));
+
+            if padding_len > 0 {
+                let frame = frame::Frame::Padding { len: padding_len };
+
+                push_frame_to_pkt!(b, frames, frame, left);
+            }
         }
 
         if ack_eliciting {
@@ -4205,7 +7663,9 @@ impl Connection {
         // as Initial always requires padding.
         //
         // 2) this is a probing packet towards an unvalidated peer address.
-        if (has_initial || !path.validated()) &&
+        //
+        // 3) this is a path quality probe, which is always full-sized.
+        if (has_initial || !path.validated() || quality_probe_data.is_some()) &&
             pkt_type == packet::Type::Short &&
             left >= 1
         {
@@ -4302,15 +7762,70 @@ impl Connection {
             None => return Err(Error::InvalidState),
         };
 
-        let written = packet::encrypt_pkt(
-            &mut b,
-            pn,
-            pn_len,
-            payload_len,
-            payload_offset,
-            None,
-            aead,
-        )?;
+        if fec_protect {
+            self.fec_encoder.protect(
+                pn,
+                &b.buf()[payload_offset..payload_offset + payload_len],
+            );
+        }
+
+        // Keep a copy of the payload, as it's encrypted in place.
+        let captured_payload = self.packet_capture.as_ref().map(|_| {
+            b.buf()[payload_offset..payload_offset + payload_len].to_vec()
+        });
+
+        let seal_later = pkt_type == packet::Type::Short &&
+            (self.defer_seal || self.packet_protector.is_some()) &&
+            !cfg!(feature = "fuzzing");
+
+        let written = if seal_later {
+            let pending = packet::PendingSeal {
+                offset: 0,
+                pn,
+                pn_len,
+                payload_offset,
+                payload_len,
+            };
+
+            // Captured packets need to be protected right away.
+            if self.defer_seal && self.packet_capture.is_none() {
+                self.pending_seals.push(pending);
+            } else {
+                packet::encrypt_pkts(
+                    out,
+                    &[pending],
+                    aead,
+                    self.packet_protector.as_deref(),
+                )?;
+            }
+
+            payload_offset + payload_len + aead.alg().tag_len()
+        } else {
+            packet::encrypt_pkt(
+                &mut b,
+                pn,
+                pn_len,
+                payload_len,
+                payload_offset,
+                None,
+                aead,
+            )?
+        };
+
+        if let (Some(capture), Some(payload)) =
+            (self.packet_capture.as_mut(), captured_payload)
+        {
+            capture.on_packet(&CapturedPacket {
+                direction: PacketDirection::Sent,
+                ty: pkt_type,
+                pkt_num: pn,
+                from: path.local_addr(),
+                to: path.peer_addr(),
+                time: now,
+                raw: &out[..written],
+                payload: &payload,
+            });
+        }
 
         let sent_pkt = recovery::Sent {
             pkt_num: pn,
@@ -4362,6 +7877,16 @@ impl Connection {
             path.add_challenge_sent(data, written, now);
         }
 
+        if let Some(data) = quality_probe_data {
+            path.on_quality_probe_sent(data, now);
+        }
+
+        self.handshake_timeline.first_packet_sent.get_or_insert(now);
+
+        if pkt_type == packet::Type::Short {
+            self.handshake_timeline.first_1rtt_sent.get_or_insert(now);
+        }
+
         self.sent_count += 1;
         self.sent_bytes += written as u64;
         path.sent_count += 1;
@@ -4384,6 +7909,8 @@ impl Connection {
             if let Some(idle_timeout) = self.idle_timeout() {
                 self.idle_timer = Some(now + idle_timeout);
             }
+
+            self.restart_keepalive_timer(now);
         }
 
         if ack_eliciting {
@@ -4430,6 +7957,53 @@ impl Connection {
             .unwrap_or(0)
     }
 
+    /// Returns how many bytes can be sent in the next burst on the active
+    /// path.
+    ///
+    /// This is the [`send_quantum()`] capped by the congestion window still
+    /// available. It is never smaller than a single datagram, so that packets
+    /// that are not congestion controlled (e.g. ACKs) can always be sent.
+    ///
+    /// [`send_batch()`] never writes more than this.
+    ///
+    /// [`send_quantum()`]: struct.Connection.html#method.send_quantum
+    /// [`send_batch()`]: struct.Connection.html#method.send_batch
+    #[inline]
+    pub fn send_budget(&self) -> usize {
+        match self.paths.get_active() {
+            Ok(p) => p.recovery.send_budget(),
+            _ => 0,
+        }
+    }
+
+    /// Returns the time at which the congestion controller and the pacer
+    /// will next allow sending data on the active path.
+    ///
+    /// The returned time is the pacing hint of the next packet (see the
+    /// [`at`] field of [`SendInfo`]), and can be in the past if data can be
+    /// sent right away.
+    ///
+    /// If the congestion window is full `None` is returned, since no more
+    /// data can be sent until an acknowledgement is received or the timer
+    /// returned by [`timeout_instant()`] expires. In that case event loops
+    /// should wait for incoming packets or the timeout rather than calling
+    /// [`send()`] again, though a single packet (e.g. carrying ACKs) can still
+    /// be written.
+    ///
+    /// [`at`]: struct.SendInfo.html#structfield.at
+    /// [`SendInfo`]: struct.SendInfo.html
+    /// [`timeout_instant()`]: struct.Connection.html#method.timeout_instant
+    /// [`send()`]: struct.Connection.html#method.send
+    pub fn send_ready_at(&self) -> Option<time::Instant> {
+        let path = self.paths.get_active().ok()?;
+
+        if path.recovery.cwnd_available() < path.recovery.max_datagram_size() {
+            return None;
+        }
+
+        Some(path.recovery.get_packet_send_time())
+    }
+
     /// Reads contiguous data from a stream into the provided slice.
     ///
     /// The slice must be sized by the caller and will be populated up to its
@@ -4463,6 +8037,67 @@ impl Connection {
     pub fn stream_recv(
         &mut self, stream_id: u64, out: &mut [u8],
     ) -> Result<(usize, bool)> {
+        self.stream_recv_with(stream_id, |stream, pool| {
+            let (read, fin) = stream.recv.emit_pooled(out, pool)?;
+
+            Ok((read, read, fin))
+        })
+    }
+
+    /// Reads the next chunk of contiguous data from a stream, without copying
+    /// it.
+    ///
+    /// This works like [`stream_recv()`], but instead of copying data into a
+    /// slice provided by the application, it returns a [`StreamBuf`] that
+    /// shares its memory with the stream's receive buffer. At most `max_len`
+    /// bytes are returned, which can be fewer than the amount of data that is
+    /// readable, so this should be called in a loop until [`Done`] is
+    /// returned.
+    ///
+    /// On success the data and a flag indicating the fin state is returned as
+    /// a tuple.
+    ///
+    /// [`stream_recv()`]: struct.Connection.html#method.stream_recv
+    /// [`StreamBuf`]: struct.StreamBuf.html
+    /// [`Done`]: enum.Error.html#variant.Done
+    ///
+    /// ## Examples:
+    ///
+    /// ```no_run
+    /// # let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    /// # let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION)?;
+    /// # let scid = quiche::ConnectionId::from_ref(&[0xba; 16]);
+    /// # let peer = "127.0.0.1:1234".parse().unwrap();
+    /// # let local = socket.local_addr().unwrap();
+    /// # let mut conn = quiche::accept(&scid, None, local, peer, &mut config)?;
+    /// # let stream_id = 0;
+    /// while let Ok((buf, fin)) = conn.stream_recv_buf(stream_id, usize::MAX) {
+    ///     println!("Got {} bytes at offset {}", buf.len(), buf.off());
+    /// }
+    /// # Ok::<(), quiche::Error>(())
+    /// ```
+    pub fn stream_recv_buf(
+        &mut self, stream_id: u64, max_len: usize,
+    ) -> Result<(StreamBuf, bool)> {
+        self.stream_recv_with(stream_id, |stream, _| {
+            let (buf, fin) = stream.recv.emit_buf(max_len)?;
+            let read = buf.len();
+
+            Ok((buf.into(), read, fin))
+        })
+    }
+
+    /// Reads data from a stream using the given `emit` function, which
+    /// returns the data read, its length and the fin state.
+    fn stream_recv_with<T, F>(
+        &mut self, stream_id: u64, emit: F,
+    ) -> Result<(T, bool)>
+    where
+        F: FnOnce(
+            &mut stream::Stream,
+            &mut stream::BufPool,
+        ) -> Result<(T, usize, bool)>,
+    {
         // We can't read on our own unidirectional streams.
         if !stream::is_bidi(stream_id) &&
             stream::is_local(stream_id, self.is_server)
@@ -4485,7 +8120,7 @@ impl Connection {
         #[cfg(feature = "qlog")]
         let offset = stream.recv.off_front();
 
-        let (read, fin) = match stream.recv.emit(out) {
+        let (data, read, fin) = match emit(stream, &mut self.buf_pool) {
             Ok(v) => v,
 
             Err(e) => {
@@ -4544,7 +8179,7 @@ impl Connection {
             self.streams.insert_readable(&priority_key);
         }
 
-        Ok((read, fin))
+        Ok((data, fin))
     }
 
     /// Writes data to a stream.
@@ -4597,6 +8232,205 @@ impl Connection {
     pub fn stream_send(
         &mut self, stream_id: u64, buf: &[u8], fin: bool,
     ) -> Result<usize> {
+        // Data can't be interleaved with a file region being sent.
+        if self.file_sources.contains_key(&stream_id) {
+            return Err(Error::InvalidStreamState(stream_id));
+        }
+
+        self.stream_send_with(stream_id, buf.len(), fin, |stream, len, fin| {
+            stream.send.write(&buf[..len], fin)
+        })
+    }
+
+    /// Writes data from multiple buffers to a stream.
+    ///
+    /// This behaves like [`stream_send()`] called with the concatenation of
+    /// `bufs`, so the `fin` flag only takes effect if all the data could be
+    /// written. Small buffers are coalesced when copied into the stream's
+    /// send buffer.
+    ///
+    /// [`stream_send()`]: struct.Connection.html#method.stream_send
+    pub fn stream_send_vectored(
+        &mut self, stream_id: u64, bufs: &[std::io::IoSlice], fin: bool,
+    ) -> Result<usize> {
+        let len = bufs.iter().map(|b| b.len()).sum();
+
+        self.stream_send_with(stream_id, len, fin, |stream, len, fin| {
+            let mut data = Vec::with_capacity(len);
+
+            for b in bufs {
+                let left = len - data.len();
+
+                data.extend_from_slice(&b[..cmp::min(b.len(), left)]);
+            }
+
+            stream
+                .send
+                .write_owned(&stream::RangeBuf::from_vec(data), fin)
+        })
+    }
+
+    /// Writes data to a stream without copying it.
+    ///
+    /// Unlike [`stream_send()`], the data is not copied into the stream's
+    /// send buffer. Instead the connection holds a reference to the memory
+    /// of `buf` until the data is acknowledged by the peer, so the same
+    /// buffer can be sent on many streams or connections at once, e.g. when
+    /// serving large objects from a cache. A [`StreamBuf`] can be created
+    /// from a `Vec<u8>` or an `Arc<Vec<u8>>`, and buffers returned by
+    /// [`stream_recv_buf()`] can be forwarded as well.
+    ///
+    /// The written bytes are consumed from `buf`, so in case of a partial
+    /// write the same buffer can be passed again once the stream is writable,
+    /// to write the rest of the data. Otherwise this behaves like
+    /// [`stream_send()`].
+    ///
+    /// [`stream_send()`]: struct.Connection.html#method.stream_send
+    /// [`StreamBuf`]: struct.StreamBuf.html
+    /// [`stream_recv_buf()`]: struct.Connection.html#method.stream_recv_buf
+    pub fn stream_send_owned(
+        &mut self, stream_id: u64, buf: &mut StreamBuf, fin: bool,
+    ) -> Result<usize> {
+        let written = self.stream_send_with(
+            stream_id,
+            buf.len(),
+            fin,
+            |stream, len, fin| stream.send.write_owned(&buf.slice(len), fin),
+        )?;
+
+        buf.consume(written);
+
+        Ok(written)
+    }
+
+    /// Writes a region of a file to a stream.
+    ///
+    /// Instead of buffering the whole region at once, `len` bytes starting
+    /// at `offset` are read from `file` lazily, as the stream's flow control
+    /// and the connection's congestion window allow, when packets are
+    /// generated by [`send()`]. This lets large static content be served
+    /// without keeping it in memory.
+    ///
+    /// Once the whole region has been written, the stream is finished if
+    /// `fin` is true. Until then, [`stream_send()`] and this method return
+    /// [`InvalidStreamState`] for the stream. If reading the file fails,
+    /// e.g. because it is shorter than expected, the stream is reset.
+    ///
+    /// [`send()`]: struct.Connection.html#method.send
+    /// [`stream_send()`]: struct.Connection.html#method.stream_send
+    /// [`InvalidStreamState`]: enum.Error.html#variant.InvalidStreamState
+    pub fn stream_send_file(
+        &mut self, stream_id: u64, file: std::fs::File, offset: u64, len: u64,
+        fin: bool,
+    ) -> Result<()> {
+        // We can't write on the peer's unidirectional streams.
+        if !stream::is_bidi(stream_id) &&
+            !stream::is_local(stream_id, self.is_server)
+        {
+            return Err(Error::InvalidStreamState(stream_id));
+        }
+
+        if self.file_sources.contains_key(&stream_id) {
+            return Err(Error::InvalidStreamState(stream_id));
+        }
+
+        let stream = self.get_or_create_stream(stream_id, true)?;
+
+        if stream.send.is_fin() || stream.send.is_shutdown() {
+            return Err(Error::InvalidStreamState(stream_id));
+        }
+
+        self.file_sources
+            .insert(stream_id, stream::FileSource::new(file, offset, len, fin));
+
+        Ok(())
+    }
+
+    /// Writes pending file regions to their streams, up to the streams'
+    /// current send capacity.
+    fn send_file_data(&mut self) {
+        if self.file_sources.is_empty() {
+            return;
+        }
+
+        let ids: Vec<u64> = self.file_sources.keys().copied().collect();
+
+        for stream_id in ids {
+            let mut src = match self.file_sources.remove(&stream_id) {
+                Some(v) => v,
+                None => continue,
+            };
+
+            let cap = match self.stream_capacity(stream_id) {
+                Ok(v) => v,
+
+                // The stream is gone or was stopped by the peer, so there is
+                // no point in reading the rest of the file.
+                Err(_) => continue,
+            };
+
+            let remaining = src.remaining();
+
+            if cap == 0 && remaining > 0 {
+                self.file_sources.insert(stream_id, src);
+                continue;
+            }
+
+            let data = match src.read(cap) {
+                Ok(v) => v,
+
+                Err(e) => {
+                    trace!(
+                        "{} failed to read file for stream {}: {:?}",
+                        self.trace_id,
+                        stream_id,
+                        e
+                    );
+
+                    let _ = self.stream_shutdown(stream_id, Shutdown::Write, 0);
+                    continue;
+                },
+            };
+
+            let len = data.len();
+
+            let fin = src.fin() && len as u64 == remaining;
+
+            let written =
+                self.stream_send_with(stream_id, len, fin, |stream, len, fin| {
+                    let mut data = data;
+                    data.truncate(len);
+
+                    stream
+                        .send
+                        .write_owned(&stream::RangeBuf::from_vec(data), fin)
+                });
+
+            match written {
+                Ok(v) => src.advance(v),
+
+                Err(Error::Done) => (),
+
+                Err(_) => continue,
+            }
+
+            if src.remaining() > 0 {
+                self.file_sources.insert(stream_id, src);
+            }
+        }
+    }
+
+    /// Buffers up to `len` bytes of data on a stream.
+    ///
+    /// The `write` closure is called with the stream, and the
+    /// amount of data it is allowed to write, after stream and connection
+    /// limits are applied.
+    fn stream_send_with<F>(
+        &mut self, stream_id: u64, len: usize, fin: bool, write: F,
+    ) -> Result<usize>
+    where
+        F: FnOnce(&mut stream::Stream, usize, bool) -> Result<usize>,
+    {
         // We can't write on the peer's unidirectional streams.
         if !stream::is_bidi(stream_id) &&
             !stream::is_local(stream_id, self.is_server)
@@ -4609,15 +8443,24 @@ impl Connection {
         //
         // Note that this is separate from "send capacity" as that also takes
         // congestion control into consideration.
-        if self.max_tx_data - self.tx_data < buf.len() as u64 {
+        if self.max_tx_data - self.tx_data < len as u64 {
             self.blocked_limit = Some(self.max_tx_data);
         }
 
         let cap = self.tx_cap;
 
+        let group_cap = self.streams.group_cap(stream_id);
+
         // Get existing stream or create a new one.
         let stream = self.get_or_create_stream(stream_id, true)?;
 
+        // Don't buffer more unsent data than the stream's high watermark.
+        let cap = cmp::min(cap, stream.send.buffer_cap());
+
+        // Don't buffer more data than the stream's group budget allows.
+        let blocked_by_group = group_cap < cmp::min(cap, len);
+        let cap = cmp::min(cap, group_cap);
+
         #[cfg(feature = "qlog")]
         let offset = stream.send.off_back();
 
@@ -4632,8 +8475,12 @@ impl Connection {
         //
         // When the cap is zero, the method returns Ok(0) *only* when the passed
         // buffer is empty. We return Error::Done otherwise.
-        if cap == 0 && !buf.is_empty() {
-            if was_writable {
+        if cap == 0 && len > 0 {
+            if blocked_by_group {
+                // The stream is reported as writable again once the group's
+                // budget is increased.
+                self.streams.remove_writable(&priority_key);
+            } else if was_writable {
                 // When `stream_writable_next()` returns a stream, the writable
                 // mark is removed, but because the stream is blocked by the
                 // connection-level send capacity it won't be marked as writable
@@ -4646,13 +8493,13 @@ impl Connection {
             return Err(Error::Done);
         }
 
-        let (buf, fin, blocked_by_cap) = if cap < buf.len() {
-            (&buf[..cap], false, true)
+        let (len, fin, blocked_by_cap) = if cap < len {
+            (cap, false, true)
         } else {
-            (buf, fin, false)
+            (len, fin, false)
         };
 
-        let sent = match stream.send.write(buf, fin) {
+        let sent = match write(stream, len, fin) {
             Ok(v) => v,
 
             Err(e) => {
@@ -4668,9 +8515,9 @@ impl Connection {
 
         let writable = stream.is_writable();
 
-        let empty_fin = buf.is_empty() && fin;
+        let empty_fin = len == 0 && fin;
 
-        if sent < buf.len() {
+        if sent < len {
             let max_off = stream.send.max_off();
 
             if stream.send.blocked_at() != Some(max_off) {
@@ -4691,7 +8538,7 @@ impl Connection {
             self.streams.insert_flushable(&priority_key);
         }
 
-        if !writable {
+        if !writable || blocked_by_group {
             self.streams.remove_writable(&priority_key);
         } else if was_writable && blocked_by_cap {
             // When `stream_writable_next()` returns a stream, the writable
@@ -4707,6 +8554,8 @@ impl Connection {
 
         self.tx_data += sent as u64;
 
+        self.streams.on_group_data_written(priority_key.group, sent);
+
         self.tx_buffered += sent;
 
         qlog_with_type!(QLOG_DATA_MV, self.qlog, q, {
@@ -4723,11 +8572,11 @@ impl Connection {
             q.add_event_data_with_instant(ev_data, now).ok();
         });
 
-        if sent == 0 && !buf.is_empty() {
+        if sent == 0 && len > 0 {
             return Err(Error::Done);
         }
 
-        if incremental && writable {
+        if incremental && writable && !blocked_by_group {
             // Shuffle the incremental stream to the back of the the queue.
             self.streams.remove_writable(&priority_key);
             self.streams.insert_writable(&priority_key);
@@ -4742,6 +8591,17 @@ impl Connection {
     /// on the wire (streams with lower priority are sent first). Streams are
     /// created with a default priority of `127`.
     ///
+    /// The `urgency` and `incremental` parameters follow the semantics of
+    /// the HTTP Extensible Priorities (RFC 9218):
+    ///
+    ///   * Data of streams with a lower urgency is always sent first.
+    ///
+    ///   * Among streams with the same urgency, non-incremental streams are
+    ///     sent one at a time in stream ID order, before incremental ones.
+    ///
+    ///   * Incremental streams with the same urgency take turns, one packet at
+    ///     a time, so none of them is starved by the others.
+    ///
     /// The target stream is created if it did not exist before calling this
     /// method.
     pub fn stream_priority(
@@ -4767,6 +8627,7 @@ impl Connection {
         let new_priority_key = Arc::new(StreamPriorityKey {
             urgency: stream.urgency,
             incremental: stream.incremental,
+            group: stream.priority_key.group,
             id: stream_id,
             ..Default::default()
         });
@@ -4780,71 +8641,182 @@ impl Connection {
         Ok(())
     }
 
-    /// Shuts down reading or writing from/to the specified stream.
+    /// Sets whether lost data of a stream is retransmitted before new data.
     ///
-    /// When the `direction` argument is set to [`Shutdown::Read`], outstanding
-    /// data in the stream's receive buffer is dropped, and no additional data
-    /// is added to it. Data received after calling this method is still
-    /// validated and acked but not stored, and [`stream_recv()`] will not
-    /// return it to the application. In addition, a `STOP_SENDING` frame will
-    /// be sent to the peer to signal it to stop sending data.
-    ///
-    /// When the `direction` argument is set to [`Shutdown::Write`], outstanding
-    /// data in the stream's send buffer is dropped, and no additional data is
-    /// added to it. Data passed to [`stream_send()`] after calling this method
-    /// will be ignored. In addition, a `RESET_STREAM` frame will be sent to the
-    /// peer to signal the reset.
-    ///
-    /// Locally-initiated unidirectional streams can only be closed in the
-    /// [`Shutdown::Write`] direction. Remotely-initiated unidirectional streams
-    /// can only be closed in the [`Shutdown::Read`] direction. Using an
-    /// incorrect direction will return [`InvalidStreamState`].
+    /// By default, when stream data is declared lost it is sent again before
+    /// any data that was never sent on that stream. When `retransmit_first`
+    /// is `false`, new data is sent first instead, and lost data is only
+    /// retransmitted once there is no new data left to send. This is useful
+    /// for applications like live streaming, where the latest data matters
+    /// more than older data.
     ///
-    /// [`Shutdown::Read`]: enum.Shutdown.html#variant.Read
-    /// [`Shutdown::Write`]: enum.Shutdown.html#variant.Write
-    /// [`stream_recv()`]: struct.Connection.html#method.stream_recv
-    /// [`stream_send()`]: struct.Connection.html#method.stream_send
-    /// [`InvalidStreamState`]: enum.Error.html#variant.InvalidStreamState
-    pub fn stream_shutdown(
-        &mut self, stream_id: u64, direction: Shutdown, err: u64,
+    /// The target stream is created if it did not exist before calling this
+    /// method.
+    pub fn stream_set_retransmission_priority(
+        &mut self, stream_id: u64, retransmit_first: bool,
     ) -> Result<()> {
-        // Don't try to stop a local unidirectional stream.
-        if direction == Shutdown::Read &&
-            stream::is_local(stream_id, self.is_server) &&
-            !stream::is_bidi(stream_id)
-        {
-            return Err(Error::InvalidStreamState(stream_id));
-        }
-
-        // Dont' try to reset a remote unidirectional stream.
-        if direction == Shutdown::Write &&
-            !stream::is_local(stream_id, self.is_server) &&
-            !stream::is_bidi(stream_id)
+        // We can't write on the peer's unidirectional streams.
+        if !stream::is_bidi(stream_id) &&
+            !stream::is_local(stream_id, self.is_server)
         {
             return Err(Error::InvalidStreamState(stream_id));
         }
 
-        // Get existing stream.
-        let stream = self.streams.get_mut(stream_id).ok_or(Error::Done)?;
-
-        let priority_key = Arc::clone(&stream.priority_key);
+        let stream = match self.get_or_create_stream(stream_id, true) {
+            Ok(v) => v,
 
-        match direction {
-            Shutdown::Read => {
-                stream.recv.shutdown()?;
+            Err(Error::Done) => return Ok(()),
 
-                if !stream.recv.is_fin() {
-                    self.streams.insert_stopped(stream_id, err);
-                }
+            Err(e) => return Err(e),
+        };
 
-                // Once shutdown, the stream is guaranteed to be non-readable.
-                self.streams.remove_readable(&priority_key);
+        stream.send.set_new_data_first(!retransmit_first);
 
-                self.stopped_stream_local_count =
-                    self.stopped_stream_local_count.saturating_add(1);
-            },
+        Ok(())
+    }
 
-            Shutdown::Write => {
+    /// Assigns a stream to a group.
+    ///
+    /// Stream groups let applications isolate sets of streams from each
+    /// other, for example to separate control streams from bulk transfers,
+    /// or streams belonging to different tenants. Groups share the
+    /// connection's bandwidth using weighted fair queuing, based on the
+    /// weights set with [`set_stream_group_params()`], and stream priorities
+    /// (see [`stream_priority()`]) only apply among streams of the same group.
+    ///
+    /// Streams belong to group `0` by default. Groups don't need to be
+    /// created before assigning streams to them.
+    ///
+    /// Data that was already written to the stream is still accounted to the
+    /// stream's previous group budget.
+    ///
+    /// The target stream is created if it did not exist before calling this
+    /// method.
+    ///
+    /// [`set_stream_group_params()`]: struct.Connection.html#method.set_stream_group_params
+    /// [`stream_priority()`]: struct.Connection.html#method.stream_priority
+    pub fn stream_set_group(
+        &mut self, stream_id: u64, group_id: u64,
+    ) -> Result<()> {
+        // Get existing stream or create a new one, but if the stream
+        // has already been closed and collected, ignore the grouping.
+        let stream = match self.get_or_create_stream(stream_id, true) {
+            Ok(v) => v,
+
+            Err(Error::Done) => return Ok(()),
+
+            Err(e) => return Err(e),
+        };
+
+        if stream.priority_key.group == group_id {
+            return Ok(());
+        }
+
+        let new_priority_key = Arc::new(StreamPriorityKey {
+            urgency: stream.urgency,
+            incremental: stream.incremental,
+            group: group_id,
+            id: stream_id,
+            ..Default::default()
+        });
+
+        let old_priority_key =
+            std::mem::replace(&mut stream.priority_key, new_priority_key.clone());
+
+        self.streams
+            .update_priority(&old_priority_key, &new_priority_key);
+
+        Ok(())
+    }
+
+    /// Sets the scheduling weight and send budget of a stream group.
+    ///
+    /// When several groups have data to send, each of them gets a share of
+    /// the connection's bandwidth proportional to its `weight`. A weight of
+    /// zero is treated as `1`. Groups have a default weight of `16`.
+    ///
+    /// The `max_data` argument limits the total amount of data that can be
+    /// written to the group's streams over the lifetime of the connection,
+    /// with `None` meaning no limit. Once the budget is used up,
+    /// [`stream_send()`] returns [`Done`] and the group's streams are no
+    /// longer reported as writable, until the budget is increased by calling
+    /// this method again.
+    ///
+    /// [`stream_send()`]: struct.Connection.html#method.stream_send
+    /// [`Done`]: enum.Error.html#variant.Done
+    pub fn set_stream_group_params(
+        &mut self, group_id: u64, weight: u8, max_data: Option<u64>,
+    ) {
+        self.streams.set_group_params(group_id, weight, max_data);
+    }
+
+    /// Shuts down reading or writing from/to the specified stream.
+    ///
+    /// When the `direction` argument is set to [`Shutdown::Read`], outstanding
+    /// data in the stream's receive buffer is dropped, and no additional data
+    /// is added to it. Data received after calling this method is still
+    /// validated and acked but not stored, and [`stream_recv()`] will not
+    /// return it to the application. In addition, a `STOP_SENDING` frame will
+    /// be sent to the peer to signal it to stop sending data.
+    ///
+    /// When the `direction` argument is set to [`Shutdown::Write`], outstanding
+    /// data in the stream's send buffer is dropped, and no additional data is
+    /// added to it. Data passed to [`stream_send()`] after calling this method
+    /// will be ignored. In addition, a `RESET_STREAM` frame will be sent to the
+    /// peer to signal the reset.
+    ///
+    /// Locally-initiated unidirectional streams can only be closed in the
+    /// [`Shutdown::Write`] direction. Remotely-initiated unidirectional streams
+    /// can only be closed in the [`Shutdown::Read`] direction. Using an
+    /// incorrect direction will return [`InvalidStreamState`].
+    ///
+    /// [`Shutdown::Read`]: enum.Shutdown.html#variant.Read
+    /// [`Shutdown::Write`]: enum.Shutdown.html#variant.Write
+    /// [`stream_recv()`]: struct.Connection.html#method.stream_recv
+    /// [`stream_send()`]: struct.Connection.html#method.stream_send
+    /// [`InvalidStreamState`]: enum.Error.html#variant.InvalidStreamState
+    pub fn stream_shutdown(
+        &mut self, stream_id: u64, direction: Shutdown, err: u64,
+    ) -> Result<()> {
+        // Don't try to stop a local unidirectional stream.
+        if direction == Shutdown::Read &&
+            stream::is_local(stream_id, self.is_server) &&
+            !stream::is_bidi(stream_id)
+        {
+            return Err(Error::InvalidStreamState(stream_id));
+        }
+
+        // Dont' try to reset a remote unidirectional stream.
+        if direction == Shutdown::Write &&
+            !stream::is_local(stream_id, self.is_server) &&
+            !stream::is_bidi(stream_id)
+        {
+            return Err(Error::InvalidStreamState(stream_id));
+        }
+
+        // Get existing stream.
+        let stream = self.streams.get_mut(stream_id).ok_or(Error::Done)?;
+
+        let priority_key = Arc::clone(&stream.priority_key);
+
+        match direction {
+            Shutdown::Read => {
+                stream.recv.shutdown()?;
+
+                if !stream.recv.is_fin() {
+                    self.streams.insert_stopped(stream_id, err);
+                }
+
+                self.streams.update_reassembly(stream_id, 0);
+
+                // Once shutdown, the stream is guaranteed to be non-readable.
+                self.streams.remove_readable(&priority_key);
+
+                self.stopped_stream_local_count =
+                    self.stopped_stream_local_count.saturating_add(1);
+            },
+
+            Shutdown::Write => {
                 let (final_size, unsent) = stream.send.shutdown()?;
 
                 // Claw back some flow control allowance from data that was
@@ -4857,11 +8829,15 @@ impl Connection {
                 // Update send capacity.
                 self.update_tx_cap();
 
-                self.streams.insert_reset(stream_id, err, final_size);
+                self.streams.insert_reset(stream_id, err, final_size, 0);
 
                 // Once shutdown, the stream is guaranteed to be non-writable.
                 self.streams.remove_writable(&priority_key);
 
+                self.streams.remove_deadline(stream_id);
+
+                self.file_sources.remove(&stream_id);
+
                 self.reset_stream_local_count =
                     self.reset_stream_local_count.saturating_add(1);
             },
@@ -4870,6 +8846,115 @@ impl Connection {
         Ok(())
     }
 
+    /// Resets the send side of a stream, but still delivers the first
+    /// `reliable_size` bytes of its data to the peer.
+    ///
+    /// This works like [`stream_shutdown()`] with [`Shutdown::Write`], except
+    /// that data below the reliable size is kept and retransmitted as needed,
+    /// and the peer's application can still read it before being notified
+    /// that the stream was reset. Data past the reliable size is dropped, and
+    /// won't be retransmitted if lost. The reliable size is capped to the
+    /// amount of data written to the stream.
+    ///
+    /// A non-zero reliable size requires the peer to support RESET_STREAM_AT
+    /// (see [`enable_reset_stream_at()`]), otherwise the [`InvalidState`] error
+    /// is returned. With a reliable size of zero this is the same as
+    /// [`stream_shutdown()`].
+    ///
+    /// [`stream_shutdown()`]: struct.Connection.html#method.stream_shutdown
+    /// [`Shutdown::Write`]: enum.Shutdown.html#variant.Write
+    /// [`enable_reset_stream_at()`]:
+    /// struct.Config.html#method.enable_reset_stream_at
+    /// [`InvalidState`]: enum.Error.html#variant.InvalidState
+    pub fn stream_reset_at(
+        &mut self, stream_id: u64, err: u64, reliable_size: u64,
+    ) -> Result<()> {
+        // Dont' try to reset a remote unidirectional stream.
+        if !stream::is_local(stream_id, self.is_server) &&
+            !stream::is_bidi(stream_id)
+        {
+            return Err(Error::InvalidStreamState(stream_id));
+        }
+
+        if reliable_size > 0 && !self.peer_transport_params.reset_stream_at {
+            return Err(Error::InvalidState);
+        }
+
+        // Get existing stream.
+        let stream = self.streams.get_mut(stream_id).ok_or(Error::Done)?;
+
+        let priority_key = Arc::clone(&stream.priority_key);
+
+        let reliable_size = cmp::min(reliable_size, stream.send.off_back());
+
+        let (final_size, unsent) = stream.send.reset_at(reliable_size)?;
+
+        // Claw back some flow control allowance from data that was buffered
+        // but won't be sent.
+        self.tx_data = self.tx_data.saturating_sub(unsent);
+
+        self.tx_buffered = self.tx_buffered.saturating_sub(unsent as usize);
+
+        // Update send capacity.
+        self.update_tx_cap();
+
+        self.streams
+            .insert_reset(stream_id, err, final_size, reliable_size);
+
+        // Once reset, the stream is guaranteed to be non-writable.
+        self.streams.remove_writable(&priority_key);
+
+        self.streams.remove_deadline(stream_id);
+
+        self.reset_stream_local_count =
+            self.reset_stream_local_count.saturating_add(1);
+
+        Ok(())
+    }
+
+    /// Sets a deadline for the data written to a stream.
+    ///
+    /// When the deadline is reached, the stream is reset as if
+    /// [`stream_reset_at()`] was called with the given `reliable_size` and
+    /// `err` values, so that data that is no longer useful (e.g. a stale
+    /// media frame) stops being sent and retransmitted. If the peer doesn't
+    /// support RESET_STREAM_AT, the whole stream is reset instead.
+    ///
+    /// Nothing happens if the stream completes before the deadline. Setting a
+    /// new deadline replaces the previous one.
+    ///
+    /// Deadlines are taken into account by [`timeout()`], and processed by
+    /// [`on_timeout()`].
+    ///
+    /// [`stream_reset_at()`]: struct.Connection.html#method.stream_reset_at
+    /// [`timeout()`]: struct.Connection.html#method.timeout
+    /// [`on_timeout()`]: struct.Connection.html#method.on_timeout
+    pub fn stream_set_deadline(
+        &mut self, stream_id: u64, deadline: time::Instant, reliable_size: u64,
+        err: u64,
+    ) -> Result<()> {
+        // Dont' try to reset a remote unidirectional stream.
+        if !stream::is_local(stream_id, self.is_server) &&
+            !stream::is_bidi(stream_id)
+        {
+            return Err(Error::InvalidStreamState(stream_id));
+        }
+
+        let stream = self
+            .streams
+            .get(stream_id)
+            .ok_or(Error::InvalidStreamState(stream_id))?;
+
+        if stream.send.is_shutdown() || stream.send.is_stopped() {
+            return Err(Error::Done);
+        }
+
+        self.streams
+            .insert_deadline(stream_id, deadline, reliable_size, err);
+
+        Ok(())
+    }
+
     /// Returns the stream's send capacity in bytes.
     ///
     /// If the specified stream doesn't exist (including when it has already
@@ -4886,7 +8971,8 @@ impl Connection {
     pub fn stream_capacity(&self, stream_id: u64) -> Result<usize> {
         if let Some(stream) = self.streams.get(stream_id) {
             let cap = cmp::min(self.tx_cap, stream.send.cap()?);
-            return Ok(cap);
+            let cap = cmp::min(cap, self.streams.group_cap(stream_id));
+            return Ok(cmp::min(cap, stream.send.buffer_cap()));
         };
 
         Err(Error::InvalidStreamState(stream_id))
@@ -4923,6 +9009,37 @@ impl Connection {
         stream.is_readable()
     }
 
+    /// Returns the receive-side offsets of a stream.
+    ///
+    /// This reports the offset up to which the stream's data was received
+    /// without gaps, the largest offset received, and the ranges of data
+    /// missing in between. Applications that can tolerate missing data can use
+    /// this to decide to skip ahead, or to ask for the data to be sent again at
+    /// the application layer.
+    ///
+    /// Data that was already read by the application is considered received.
+    ///
+    /// The [`InvalidStreamState`] error is returned if the stream doesn't
+    /// exist, or if it is a local unidirectional stream.
+    ///
+    /// [`InvalidStreamState`]: enum.Error.html#variant.InvalidStreamState
+    pub fn stream_recv_offsets(
+        &self, stream_id: u64,
+    ) -> Result<StreamRecvOffsets> {
+        if !stream::is_bidi(stream_id) &&
+            stream::is_local(stream_id, self.is_server)
+        {
+            return Err(Error::InvalidStreamState(stream_id));
+        }
+
+        let stream = self
+            .streams
+            .get(stream_id)
+            .ok_or(Error::InvalidStreamState(stream_id))?;
+
+        Ok(stream.recv.offsets())
+    }
+
     /// Returns the next stream that can be written to.
     ///
     /// Note that once returned by this method, a stream ID will not be returned
@@ -4962,6 +9079,8 @@ impl Connection {
                         },
                 };
 
+                let cap = cmp::min(cap, stream.send.buffer_cap());
+
                 if cmp::min(self.tx_cap, cap) >= stream.send_lowat {
                     self.streams.remove_writable(&priority_key);
                     return Some(priority_key.id);
@@ -5078,6 +9197,73 @@ impl Connection {
         self.streams.peer_streams_left_uni()
     }
 
+    /// Returns the number of bidirectional streams the peer can create before
+    /// the local stream count limit is reached.
+    ///
+    /// This only accounts for the credit that was already advertised to the
+    /// peer, not for credit waiting to be sent in MAX_STREAMS frames.
+    #[inline]
+    pub fn local_streams_left_bidi(&self) -> u64 {
+        self.streams.local_streams_left_bidi()
+    }
+
+    /// Returns the number of unidirectional streams the peer can create
+    /// before the local stream count limit is reached.
+    ///
+    /// This only accounts for the credit that was already advertised to the
+    /// peer, not for credit waiting to be sent in MAX_STREAMS frames.
+    #[inline]
+    pub fn local_streams_left_uni(&self) -> u64 {
+        self.streams.local_streams_left_uni()
+    }
+
+    /// Returns the next STREAMS_BLOCKED event, if any.
+    ///
+    /// An event is generated when the peer reports that it can't open new
+    /// streams because of the current local stream count limit. Only one
+    /// event is generated per limit and stream direction.
+    ///
+    /// ## Examples:
+    ///
+    /// ```no_run
+    /// # let mut buf = [0; 512];
+    /// # let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    /// # let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION)?;
+    /// # let scid = quiche::ConnectionId::from_ref(&[0xba; 16]);
+    /// # let peer = "127.0.0.1:1234".parse().unwrap();
+    /// # let local = socket.local_addr().unwrap();
+    /// # let mut conn = quiche::accept(&scid, None, local, peer, &mut config)?;
+    /// while let Some(ev) = conn.streams_blocked_next() {
+    ///     println!("peer blocked at {} streams (bidi: {})", ev.limit, ev.bidi);
+    /// }
+    /// # Ok::<(), quiche::Error>(())
+    /// ```
+    #[inline]
+    pub fn streams_blocked_next(&mut self) -> Option<StreamsBlocked> {
+        self.streams.pop_blocked_event()
+    }
+
+    /// Returns the next stream that pushed the connection over its reassembly
+    /// memory limit, if any.
+    ///
+    /// Streams are reported once, until they no longer buffer data out of
+    /// order. The limit is set with [`set_max_reassembly_buffer()`].
+    ///
+    /// [`set_max_reassembly_buffer()`]: struct.Config.html#method.set_max_reassembly_buffer
+    #[inline]
+    pub fn reassembly_pressure_next(&mut self) -> Option<ReassemblyPressure> {
+        self.streams.pop_reassembly_event()
+    }
+
+    /// Returns the number of bytes buffered out of order across all streams.
+    ///
+    /// This data can't be read by the application until the missing data
+    /// preceding it is received.
+    #[inline]
+    pub fn reassembly_buffered(&self) -> u64 {
+        self.streams.reassembly_len()
+    }
+
     /// Returns an iterator over streams that have outstanding data to read.
     ///
     /// Note that the iterator will only include streams that were readable at
@@ -5158,38 +9344,118 @@ impl Connection {
         self.streams.writable()
     }
 
-    /// Returns the maximum possible size of egress UDP payloads.
+    /// Returns an iterator over streams that can be written to, along with
+    /// how many bytes each of them can accept.
     ///
-    /// This is the maximum size of UDP payloads that can be sent, and depends
-    /// on both the configured maximum send payload size of the local endpoint
-    /// (as configured with [`set_max_send_udp_payload_size()`]), as well as
-    /// the transport parameter advertised by the remote peer.
+    /// This is similar to [`writable()`], but also reports the capacity of
+    /// each stream, taking into account flow control, the connection's send
+    /// capacity and the stream's send watermarks. Streams that can't accept
+    /// at least their low watermark are not included. A stream that was
+    /// stopped by the peer is reported with a capacity of zero, and writing
+    /// to it returns the [`StreamStopped`] error.
     ///
-    /// Note that this value can change during the lifetime of the connection,
-    /// but should remain stable across consecutive calls to [`send()`].
+    /// Note that the capacity is shared by all the streams, so writing to one
+    /// stream can reduce the capacity of the others.
     ///
-    /// [`set_max_send_udp_payload_size()`]:
-    ///     struct.Config.html#method.set_max_send_udp_payload_size
-    /// [`send()`]: struct.Connection.html#method.send
-    pub fn max_send_udp_payload_size(&self) -> usize {
-        let max_datagram_size = self
-            .paths
-            .get_active()
-            .ok()
-            .map(|p| p.recovery.max_datagram_size());
-
-        if let Some(max_datagram_size) = max_datagram_size {
-            if self.is_established() {
-                // We cap the maximum packet size to 16KB or so, so that it can be
-                // always encoded with a 2-byte varint.
-                return cmp::min(16383, max_datagram_size);
-            }
-        }
-
-        // Allow for 1200 bytes (minimum QUIC packet size) during the
-        // handshake.
-        MIN_CLIENT_INITIAL_LEN
-    }
+    /// ## Examples:
+    ///
+    /// ```no_run
+    /// # let buf = [0; 512];
+    /// # let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    /// # let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION)?;
+    /// # let scid = quiche::ConnectionId::from_ref(&[0xba; 16]);
+    /// # let local = socket.local_addr().unwrap();
+    /// # let peer = "127.0.0.1:1234".parse().unwrap();
+    /// # let mut conn = quiche::accept(&scid, None, local, peer, &mut config)?;
+    /// for (stream_id, cap) in conn.writable_detailed() {
+    ///     let len = std::cmp::min(cap, buf.len());
+    ///
+    ///     if let Ok(written) = conn.stream_send(stream_id, &buf[..len], false) {
+    ///         println!("Written {} bytes on stream {}", written, stream_id);
+    ///     }
+    /// }
+    /// # Ok::<(), quiche::Error>(())
+    /// ```
+    ///
+    /// [`writable()`]: struct.Connection.html#method.writable
+    /// [`StreamStopped`]: enum.Error.html#variant.StreamStopped
+    #[inline]
+    pub fn writable_detailed(&self) -> StreamCapacityIter {
+        if self.tx_cap == 0 {
+            return StreamCapacityIter::default();
+        }
+
+        self.streams.writable_capacity(self.tx_cap)
+    }
+
+    /// Sets the send buffer watermarks of a stream.
+    ///
+    /// This overrides the watermarks set with
+    /// [`set_stream_send_watermarks()`] for a single stream. The stream is
+    /// only reported as writable when at least `low` bytes can be written to
+    /// it, and no more than `high` bytes of unsent data are buffered for it.
+    ///
+    /// If the specified stream doesn't exist (including when it has already
+    /// been completed and closed), the [`InvalidStreamState`] error will be
+    /// returned.
+    ///
+    /// [`set_stream_send_watermarks()`]: struct.Config.html#method.set_stream_send_watermarks
+    /// [`InvalidStreamState`]: enum.Error.html#variant.InvalidStreamState
+    pub fn stream_send_watermarks(
+        &mut self, stream_id: u64, low: usize, high: usize,
+    ) -> Result<()> {
+        let stream = match self.streams.get_mut(stream_id) {
+            Some(v) => v,
+
+            None => return Err(Error::InvalidStreamState(stream_id)),
+        };
+
+        stream.send_lowat = cmp::max(1, low);
+        stream.send.set_max_buffered(Some(cmp::max(low, high)));
+
+        let priority_key = Arc::clone(&stream.priority_key);
+
+        if stream.is_writable() {
+            self.streams.insert_writable(&priority_key);
+        } else {
+            self.streams.remove_writable(&priority_key);
+        }
+
+        Ok(())
+    }
+
+    /// Returns the maximum possible size of egress UDP payloads.
+    ///
+    /// This is the maximum size of UDP payloads that can be sent, and depends
+    /// on both the configured maximum send payload size of the local endpoint
+    /// (as configured with [`set_max_send_udp_payload_size()`]), as well as
+    /// the transport parameter advertised by the remote peer.
+    ///
+    /// Note that this value can change during the lifetime of the connection,
+    /// but should remain stable across consecutive calls to [`send()`].
+    ///
+    /// [`set_max_send_udp_payload_size()`]:
+    ///     struct.Config.html#method.set_max_send_udp_payload_size
+    /// [`send()`]: struct.Connection.html#method.send
+    pub fn max_send_udp_payload_size(&self) -> usize {
+        let max_datagram_size = self
+            .paths
+            .get_active()
+            .ok()
+            .map(|p| p.recovery.max_datagram_size());
+
+        if let Some(max_datagram_size) = max_datagram_size {
+            if self.is_established() {
+                // We cap the maximum packet size to 16KB or so, so that it can be
+                // always encoded with a 2-byte varint.
+                return cmp::min(16383, max_datagram_size);
+            }
+        }
+
+        // Allow for 1200 bytes (minimum QUIC packet size) during the
+        // handshake.
+        MIN_CLIENT_INITIAL_LEN
+    }
 
     /// Schedule an ack-eliciting packet on the active path.
     ///
@@ -5231,6 +9497,56 @@ impl Connection {
         Ok(())
     }
 
+    /// Enables or disables keepalives on this connection.
+    ///
+    /// Keepalives are enabled by default, but are only sent when an interval
+    /// was configured with [`set_keepalive_interval()`].
+    ///
+    /// [`set_keepalive_interval()`]:
+    /// struct.Config.html#method.set_keepalive_interval
+    pub fn enable_keepalive(&mut self, v: bool) {
+        self.keepalive_enabled = v;
+
+        self.restart_keepalive_timer(time::Instant::now());
+    }
+
+    /// Starts probing the available bandwidth for the given duration.
+    ///
+    /// While probing, if the application doesn't provide enough data to fill
+    /// the congestion window, [`send()`] generates packets made of PING and
+    /// PADDING frames to keep the bytes in flight close to the congestion
+    /// window. This allows the congestion controller and the delivery rate
+    /// estimator to observe the path's capacity even when the application is
+    /// bursty (e.g. video conferencing).
+    ///
+    /// Probe packets don't carry any application data and are not
+    /// retransmitted when lost, but they are subject to congestion control.
+    /// Since probe packets are only generated by [`send()`], the application
+    /// should call it after this method, as it normally would after queueing
+    /// new data.
+    ///
+    /// Calling this method again replaces the previous probing deadline. A
+    /// zero `duration` stops probing.
+    ///
+    /// [`send()`]: struct.Connection.html#method.send
+    pub fn probe_bandwidth(&mut self, duration: time::Duration) {
+        self.bandwidth_probe_until = if duration.is_zero() {
+            None
+        } else {
+            Some(time::Instant::now() + duration)
+        };
+    }
+
+    /// Returns true if the connection is currently probing for bandwidth.
+    ///
+    /// See [`probe_bandwidth()`].
+    ///
+    /// [`probe_bandwidth()`]: struct.Connection.html#method.probe_bandwidth
+    pub fn is_probing_bandwidth(&self) -> bool {
+        self.bandwidth_probe_until
+            .map_or(false, |t| time::Instant::now() < t)
+    }
+
     /// Reads the first received DATAGRAM.
     ///
     /// On success the DATAGRAM's data is returned along with its size.
@@ -5436,6 +9752,63 @@ impl Connection {
         Ok(())
     }
 
+    /// Sends data in a DATAGRAM frame, with the given urgency and lifetime.
+    ///
+    /// This is the same as [`dgram_send()`], but queued DATAGRAMs with a lower
+    /// `urgency` are sent first, with DATAGRAMs of the same urgency sent in
+    /// the order they were queued. DATAGRAMs queued with [`dgram_send()`] have
+    /// an urgency of 127.
+    ///
+    /// If `ttl` is set and the DATAGRAM couldn't be sent within that time,
+    /// for example because the congestion window was exhausted, it is dropped
+    /// rather than sending stale data. Expired DATAGRAMs are counted in
+    /// [`dgram_send_expired`].
+    ///
+    /// [`dgram_send()`]: struct.Connection.html#method.dgram_send
+    /// [`dgram_send_expired`]: struct.Stats.html#structfield.dgram_send_expired
+    ///
+    /// ## Examples:
+    ///
+    /// ```no_run
+    /// # let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    /// # let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION)?;
+    /// # let scid = quiche::ConnectionId::from_ref(&[0xba; 16]);
+    /// # let peer = "127.0.0.1:1234".parse().unwrap();
+    /// # let local = socket.local_addr().unwrap();
+    /// # let mut conn = quiche::accept(&scid, None, local, peer, &mut config)?;
+    /// let ttl = std::time::Duration::from_millis(50);
+    /// conn.dgram_send_with_ttl(b"hello", 0, Some(ttl))?;
+    /// # Ok::<(), quiche::Error>(())
+    /// ```
+    pub fn dgram_send_with_ttl(
+        &mut self, buf: &[u8], urgency: u8, ttl: Option<time::Duration>,
+    ) -> Result<()> {
+        let max_payload_len = match self.dgram_max_writable_len() {
+            Some(v) => v,
+
+            None => return Err(Error::InvalidState),
+        };
+
+        if buf.len() > max_payload_len {
+            return Err(Error::BufferTooShort);
+        }
+
+        let expiry = ttl.map(|ttl| time::Instant::now() + ttl);
+
+        self.dgram_send_queue
+            .push_with(buf.to_vec(), urgency, expiry)?;
+
+        let active_path = self.paths.get_active_mut()?;
+
+        if self.dgram_send_queue.byte_size() >
+            active_path.recovery.cwnd_available()
+        {
+            active_path.recovery.update_app_limited(false);
+        }
+
+        Ok(())
+    }
+
     /// Purges queued outgoing DATAGRAMs matching the predicate.
     ///
     /// In other words, remove all elements `e` such that `f(&e)` returns true.
@@ -5512,6 +9885,12 @@ impl Connection {
             .is_some()
     }
 
+    /// Returns true if FEC was negotiated by both endpoints.
+    fn fec_enabled(&self) -> bool {
+        self.local_transport_params.enable_fec &&
+            self.peer_transport_params.enable_fec
+    }
+
     /// Returns when the next timeout event will occur.
     ///
     /// Once the timeout Instant has been reached, the [`on_timeout()`] method
@@ -5520,6 +9899,27 @@ impl Connection {
     ///
     /// [`on_timeout()`]: struct.Connection.html#method.on_timeout
     pub fn timeout_instant(&self) -> Option<time::Instant> {
+        self.next_timeout().map(|(timeout, _)| timeout)
+    }
+
+    /// Returns which timer is responsible for the next timeout event.
+    ///
+    /// This matches the time returned by [`timeout_instant()`], and can be
+    /// used by applications to decide how precisely the timeout needs to be
+    /// handled. For example, servers with many connections can handle
+    /// [`Idle`] timeouts with a coarse timer wheel, since the idle timer is
+    /// pushed back every time a packet is received. Calling [`on_timeout()`]
+    /// before the timer expires is harmless.
+    ///
+    /// [`timeout_instant()`]: struct.Connection.html#method.timeout_instant
+    /// [`Idle`]: enum.TimeoutReason.html#variant.Idle
+    /// [`on_timeout()`]: struct.Connection.html#method.on_timeout
+    pub fn next_timeout_reason(&self) -> Option<TimeoutReason> {
+        self.next_timeout().map(|(_, reason)| reason)
+    }
+
+    /// Returns the time and reason of the next timeout event.
+    fn next_timeout(&self) -> Option<(time::Instant, TimeoutReason)> {
         if self.is_closed() {
             return None;
         }
@@ -5528,28 +9928,46 @@ impl Connection {
             // Draining timer takes precedence over all other timers. If it is
             // set it means the connection is closing so there's no point in
             // processing the other timers.
-            self.draining_timer
-        } else {
-            // Use the lowest timer value (i.e. "sooner") among idle and loss
-            // detection timers. If they are both unset (i.e. `None`) then the
-            // result is `None`, but if at least one of them is set then a
-            // `Some(...)` value is returned.
-            let path_timer = self
-                .paths
-                .iter()
-                .filter_map(|(_, p)| p.recovery.loss_detection_timer())
-                .min();
+            return self
+                .draining_timer
+                .map(|timer| (timer, TimeoutReason::Draining));
+        }
 
-            let key_update_timer = self.pkt_num_spaces
-                [packet::Epoch::Application]
-                .key_update
-                .as_ref()
-                .map(|key_update| key_update.timer);
+        // Use the lowest timer value (i.e. "sooner") among idle, loss
+        // detection, key update and stream deadline timers. If they are all unset
+        // (i.e. `None`) then the result is `None`, but if at least one of
+        // them is set then a `Some(...)` value is returned.
+        let path_timer = self
+            .paths
+            .iter()
+            .filter_map(|(_, p)| p.recovery.loss_detection_timer())
+            .min();
 
-            let timers = [self.idle_timer, path_timer, key_update_timer];
+        // Previous keys are kept until the peer responds to a locally
+        // initiated key update, regardless of the timer.
+        let key_update_timer = self.pkt_num_spaces[packet::Epoch::Application]
+            .key_update
+            .as_ref()
+            .filter(|key_update| key_update.pn_on_update != u64::MAX)
+            .map(|key_update| key_update.timer);
+
+        let timers = [
+            (self.idle_timer, TimeoutReason::Idle),
+            (path_timer, TimeoutReason::LossDetection),
+            (key_update_timer, TimeoutReason::KeyUpdate),
+            (self.streams.next_deadline(), TimeoutReason::StreamDeadline),
+            (self.streams.max_streams_timer(), TimeoutReason::MaxStreams),
+            (self.keepalive_timer, TimeoutReason::Keepalive),
+            (
+                self.pkt_num_spaces[packet::Epoch::Application].ack_timer,
+                TimeoutReason::AckDelay,
+            ),
+        ];
 
-            timers.iter().filter_map(|&x| x).min()
-        }
+        timers
+            .iter()
+            .filter_map(|&(timer, reason)| timer.map(|t| (t, reason)))
+            .min_by_key(|&(t, _)| t)
     }
 
     /// Returns the amount of time until the next timeout event.
@@ -5574,6 +9992,9 @@ impl Connection {
     ///
     /// If no timeout has occurred it does nothing.
     pub fn on_timeout(&mut self) {
+        #[cfg(feature = "tracing")]
+        let _span = self.tracing_span.clone().entered();
+
         let now = time::Instant::now();
 
         if let Some(draining_timer) = self.draining_timer {
@@ -5602,6 +10023,7 @@ impl Connection {
         if let Some(timer) = self.pkt_num_spaces[packet::Epoch::Application]
             .key_update
             .as_ref()
+            .filter(|key_update| key_update.pn_on_update != u64::MAX)
             .map(|key_update| key_update.timer)
         {
             if timer <= now {
@@ -5612,9 +10034,51 @@ impl Connection {
             }
         }
 
+        if let Some(timer) = self.keepalive_timer {
+            if timer <= now {
+                trace!("{} keepalive timeout expired", self.trace_id);
+
+                self.keepalive_timer = None;
+
+                if let Ok(path) = self.paths.get_active_mut() {
+                    path.needs_ack_eliciting = true;
+                }
+            }
+        }
+
+        let pkt_space = &mut self.pkt_num_spaces[packet::Epoch::Application];
+
+        if let Some(timer) = pkt_space.ack_timer {
+            if timer <= now {
+                trace!("{} ack delay expired", self.trace_id);
+
+                pkt_space.ack_timer = None;
+                pkt_space.ack_elicited = true;
+            }
+        }
+
+        for (stream_id, reliable_size, err) in self.streams.expired_deadlines(now)
+        {
+            trace!("{} stream {} deadline expired", self.trace_id, stream_id);
+
+            let reliable_size = if self.peer_transport_params.reset_stream_at {
+                reliable_size
+            } else {
+                0
+            };
+
+            self.stream_reset_at(stream_id, err, reliable_size).ok();
+        }
+
         let handshake_status = self.handshake_status();
 
+        let mut pto_limit_reached = false;
+
+        let streams = &self.streams;
+
         for (_, p) in self.paths.iter_mut() {
+            p.detect_lost_quality_probes(now);
+
             if let Some(timer) = p.recovery.loss_detection_timer() {
                 if timer <= now {
                     trace!("{} loss detection timeout expired", self.trace_id);
@@ -5624,6 +10088,7 @@ impl Connection {
                         now,
                         self.is_server,
                         &self.trace_id,
+                        |id| streams.get(id).map_or(u8::MAX, |s| s.urgency),
                     );
 
                     self.lost_count += lost_packets;
@@ -5634,10 +10099,32 @@ impl Connection {
                             q.add_event_data_with_instant(ev_data, now).ok();
                         }
                     });
+
+                    qlog_with_type!(QLOG_CONGESTION_STATE, self.qlog, q, {
+                        if let Some(ev_data) =
+                            p.recovery.maybe_qlog_persistent_congestion()
+                        {
+                            q.add_event_data_with_instant(ev_data, now).ok();
+                        }
+                    });
+
+                    if let Some(max_pto_count) = self.max_pto_count {
+                        if p.recovery.pto_count() >= max_pto_count {
+                            pto_limit_reached = true;
+                        }
+                    }
                 }
             }
         }
 
+        if pto_limit_reached {
+            trace!("{} maximum PTO count reached", self.trace_id);
+
+            self.mark_closed();
+            self.timed_out = true;
+            return;
+        }
+
         // Notify timeout events to the application.
         self.paths.notify_failed_validations();
 
@@ -5656,6 +10143,8 @@ impl Connection {
                 },
             }
         }
+
+        self.update_metrics();
     }
 
     /// Requests the stack to perform path validation of the proposed 4-tuple.
@@ -5703,6 +10192,50 @@ impl Connection {
         path.active_dcid_seq.ok_or(Error::InvalidState)
     }
 
+    /// Sends a path quality probe on the validated path between `local_addr`
+    /// and `peer_addr`.
+    ///
+    /// The probe is a full-sized packet carrying a PATH_CHALLENGE frame, so it
+    /// doesn't cause the peer to migrate when sent on a standby path, and is
+    /// invisible to the peer's application. Its outcome is reported in the
+    /// path's [`PathStats`]: the round-trip time measured by the latest
+    /// answered probe, and the number of probes sent, answered and lost. A
+    /// probe is declared lost if it isn't answered within three probe
+    /// timeouts.
+    ///
+    /// Unlike [`probe_path()`], answered probes don't affect the path's
+    /// validation state.
+    ///
+    /// Calling this method several times before calling [`send()`] or
+    /// [`send_on_path()`] results in a single probe being generated.
+    ///
+    /// [`InvalidState`] is returned if there is no record of the path, or if
+    /// it is not validated.
+    ///
+    /// [`PathStats`]: struct.PathStats.html
+    /// [`probe_path()`]: struct.Connection.html#method.probe_path
+    /// [`send()`]: struct.Connection.html#method.send
+    /// [`send_on_path()`]: struct.Connection.html#method.send_on_path
+    /// [`InvalidState`]: enum.Error.html#variant.InvalidState
+    pub fn probe_path_quality(
+        &mut self, local_addr: SocketAddr, peer_addr: SocketAddr,
+    ) -> Result<()> {
+        let pid = self
+            .paths
+            .path_id_from_addrs(&(local_addr, peer_addr))
+            .ok_or(Error::InvalidState)?;
+
+        let path = self.paths.get_mut(pid)?;
+
+        if !path.validated() {
+            return Err(Error::InvalidState);
+        }
+
+        path.request_quality_probe();
+
+        Ok(())
+    }
+
     /// Migrates the connection to a new local address `local_addr`.
     ///
     /// The behavior is similar to [`migrate()`], with the nuance that the
@@ -5792,37 +10325,195 @@ impl Connection {
         Ok(dcid_seq)
     }
 
-    /// Provides additional source Connection IDs that the peer can use to reach
-    /// this host.
-    ///
-    /// This triggers sending NEW_CONNECTION_ID frames if the provided Source
-    /// Connection ID is not already present. In the case the caller tries to
-    /// reuse a Connection ID with a different reset token, this raises an
-    /// `InvalidState`.
-    ///
-    /// At any time, the peer cannot have more Destination Connection IDs than
-    /// the maximum number of active Connection IDs it negotiated. In such case
-    /// (i.e., when [`scids_left()`] returns 0), if the host agrees to
-    /// request the removal of previous connection IDs, it sets the
-    /// `retire_if_needed` parameter. Otherwise, an [`IdLimit`] is returned.
+    /// Migrates the connection over the given network path between `local_addr`
+    /// and `peer_addr`, once that path has been validated.
     ///
-    /// Note that setting `retire_if_needed` does not prevent this function from
-    /// returning an [`IdLimit`] in the case the caller wants to retire still
-    /// unannounced Connection IDs.
+    /// Unlike [`migrate()`], the active path is not changed immediately.
+    /// Instead, a path validation is started on the new path (unless it is
+    /// already validated), and non-probing packets keep being sent on the
+    /// current active path until the validation succeeds. The connection then
+    /// switches to the new path and retires the Destination Connection ID used
+    /// on the previous one, so that the two paths can't be linked by an
+    /// observer.
     ///
-    /// The caller is responsible from ensuring that the provided `scid` is not
-    /// repeated several times over the connection. quiche ensures that as long
-    /// as the provided Connection ID is still in use (i.e., not retired), it
-    /// does not assign a different sequence number.
+    /// If the validation fails, the connection stays on the current path and a
+    /// [`PathEvent::FailedValidation`] is reported.
     ///
-    /// Note that if the host uses zero-length Source Connection IDs, it cannot
-    /// advertise Source Connection IDs and calling this method returns an
-    /// [`InvalidState`].
+    /// The same requirements as [`migrate()`] apply: only clients can migrate,
+    /// otherwise [`InvalidState`] is returned, and spare Connection IDs are
+    /// needed at both sides, otherwise [`OutOfIdentifiers`] is returned.
     ///
-    /// Returns the sequence number associated to the provided Connection ID.
+    /// Returns the Destination Connection ID sequence number associated to the
+    /// new path.
     ///
-    /// [`scids_left()`]: struct.Connection.html#method.scids_left
-    /// [`IdLimit`]: enum.Error.html#IdLimit
+    /// [`migrate()`]: struct.Connection.html#method.migrate
+    /// [`PathEvent::FailedValidation`]: enum.PathEvent.html#variant.FailedValidation
+    /// [`OutOfIdentifiers`]: enum.Error.html#OutOfIdentifiers
+    /// [`InvalidState`]: enum.Error.html#InvalidState
+    pub fn migrate_after_validation(
+        &mut self, local_addr: SocketAddr, peer_addr: SocketAddr,
+    ) -> Result<u64> {
+        if self.is_server {
+            return Err(Error::InvalidState);
+        }
+
+        if self
+            .paths
+            .path_id_from_addrs(&(local_addr, peer_addr))
+            .is_none()
+        {
+            self.create_path_on_client(local_addr, peer_addr)?;
+        }
+
+        let pid = self
+            .paths
+            .path_id_from_addrs(&(local_addr, peer_addr))
+            .ok_or(Error::InvalidState)?;
+
+        let path = self.paths.get_mut(pid)?;
+
+        if path.active() {
+            return path.active_dcid_seq.ok_or(Error::OutOfIdentifiers);
+        }
+
+        if path.validated() {
+            let old_dcid_seq = self.paths.get_active()?.active_dcid_seq;
+
+            let dcid_seq = self.migrate(local_addr, peer_addr)?;
+
+            self.retire_migrated_dcid(old_dcid_seq);
+
+            return Ok(dcid_seq);
+        }
+
+        path.migrate_on_validation = true;
+        path.keep_previous_path = false;
+
+        if !path.under_validation() {
+            path.request_validation();
+        }
+
+        path.active_dcid_seq.ok_or(Error::InvalidState)
+    }
+
+    /// Migrates the connection over the given network path between `local_addr`
+    /// and `peer_addr`, following the configured migration policy.
+    ///
+    /// Depending on the policy set with [`set_migration_policy()`], this
+    /// behaves like [`migrate()`] or [`migrate_after_validation()`]. With
+    /// [`MigrationPolicy::MakeBeforeBreak`], the previous path is not retired
+    /// once the connection switches to the new one, so that both paths stay
+    /// usable and [`migrate()`] can move the connection back, e.g. if
+    /// [`compare_path_rtt()`] shows that the new path is slower.
+    ///
+    /// The same requirements as [`migrate()`] apply.
+    ///
+    /// Returns the Destination Connection ID sequence number associated to the
+    /// new path.
+    ///
+    /// [`set_migration_policy()`]: struct.Config.html#method.set_migration_policy
+    /// [`migrate()`]: struct.Connection.html#method.migrate
+    /// [`migrate_after_validation()`]: struct.Connection.html#method.migrate_after_validation
+    /// [`MigrationPolicy::MakeBeforeBreak`]: enum.MigrationPolicy.html#variant.MakeBeforeBreak
+    /// [`compare_path_rtt()`]: struct.Connection.html#method.compare_path_rtt
+    pub fn migrate_with_policy(
+        &mut self, local_addr: SocketAddr, peer_addr: SocketAddr,
+    ) -> Result<u64> {
+        match self.migration_policy {
+            MigrationPolicy::Immediate => self.migrate(local_addr, peer_addr),
+
+            MigrationPolicy::ValidateFirst =>
+                self.migrate_after_validation(local_addr, peer_addr),
+
+            MigrationPolicy::MakeBeforeBreak => {
+                let validated = self
+                    .paths
+                    .path_id_from_addrs(&(local_addr, peer_addr))
+                    .and_then(|pid| self.paths.get(pid).ok())
+                    .map_or(false, |p| p.validated());
+
+                // The previous path is kept, so there is nothing to retire.
+                if validated {
+                    return self.migrate(local_addr, peer_addr);
+                }
+
+                let dcid_seq =
+                    self.migrate_after_validation(local_addr, peer_addr)?;
+
+                if let Some(pid) =
+                    self.paths.path_id_from_addrs(&(local_addr, peer_addr))
+                {
+                    let path = self.paths.get_mut(pid)?;
+
+                    if path.migrate_on_validation {
+                        path.keep_previous_path = true;
+                    }
+                }
+
+                Ok(dcid_seq)
+            },
+        }
+    }
+
+    /// Compares the round-trip time of the active path with the one of the
+    /// path between `local_addr` and `peer_addr`.
+    ///
+    /// The candidate path's round-trip time is only known once packets were
+    /// acknowledged on it, or once a quality probe sent with
+    /// [`probe_path_quality()`] was answered. This can be used to decide
+    /// whether to migrate to a standby path, or to move back to the previous
+    /// path after a migration.
+    ///
+    /// [`InvalidState`] is returned if there is no record of the path.
+    ///
+    /// [`probe_path_quality()`]: struct.Connection.html#method.probe_path_quality
+    /// [`InvalidState`]: enum.Error.html#variant.InvalidState
+    pub fn compare_path_rtt(
+        &self, local_addr: SocketAddr, peer_addr: SocketAddr,
+    ) -> Result<PathRttComparison> {
+        let pid = self
+            .paths
+            .path_id_from_addrs(&(local_addr, peer_addr))
+            .ok_or(Error::InvalidState)?;
+
+        Ok(PathRttComparison {
+            active_rtt: self.paths.get_active()?.recovery.rtt(),
+
+            candidate_rtt: self.paths.get(pid)?.rtt_sample(),
+        })
+    }
+
+    /// Provides additional source Connection IDs that the peer can use to reach
+    /// this host.
+    ///
+    /// This triggers sending NEW_CONNECTION_ID frames if the provided Source
+    /// Connection ID is not already present. In the case the caller tries to
+    /// reuse a Connection ID with a different reset token, this raises an
+    /// `InvalidState`.
+    ///
+    /// At any time, the peer cannot have more Destination Connection IDs than
+    /// the maximum number of active Connection IDs it negotiated. In such case
+    /// (i.e., when [`scids_left()`] returns 0), if the host agrees to
+    /// request the removal of previous connection IDs, it sets the
+    /// `retire_if_needed` parameter. Otherwise, an [`IdLimit`] is returned.
+    ///
+    /// Note that setting `retire_if_needed` does not prevent this function from
+    /// returning an [`IdLimit`] in the case the caller wants to retire still
+    /// unannounced Connection IDs.
+    ///
+    /// The caller is responsible from ensuring that the provided `scid` is not
+    /// repeated several times over the connection. quiche ensures that as long
+    /// as the provided Connection ID is still in use (i.e., not retired), it
+    /// does not assign a different sequence number.
+    ///
+    /// Note that if the host uses zero-length Source Connection IDs, it cannot
+    /// advertise Source Connection IDs and calling this method returns an
+    /// [`InvalidState`].
+    ///
+    /// Returns the sequence number associated to the provided Connection ID.
+    ///
+    /// [`scids_left()`]: struct.Connection.html#method.scids_left
+    /// [`IdLimit`]: enum.Error.html#IdLimit
     /// [`InvalidState`]: enum.Error.html#InvalidState
     pub fn new_scid(
         &mut self, scid: &ConnectionId, reset_token: u128, retire_if_needed: bool,
@@ -5836,6 +10527,256 @@ impl Connection {
         )
     }
 
+    /// Switches the active path to a new destination Connection ID, and
+    /// retires the one it used so far.
+    ///
+    /// This can be used to prevent on-path observers from correlating the
+    /// traffic of the connection over time. See also
+    /// [`set_dcid_rotation_interval()`] and [`set_dcid_rotation_bytes()`] to
+    /// rotate Connection IDs automatically.
+    ///
+    /// If [`enable_dcid_rotation_retire_prior_to()`] was set, the peer is also
+    /// requested to retire all but the newest source Connection ID, so that
+    /// both directions are rotated together.
+    ///
+    /// If there is no spare destination Connection ID, or no spare source
+    /// Connection ID when rotating both directions, this method returns an
+    /// [`OutOfIdentifiers`]. If the host uses zero-length Connection IDs, or
+    /// a path is being validated when rotating both directions, it returns an
+    /// [`InvalidState`].
+    ///
+    /// Returns the sequence number of the new destination Connection ID.
+    ///
+    /// [`enable_dcid_rotation_retire_prior_to()`]:
+    ///     struct.Config.html#method.enable_dcid_rotation_retire_prior_to
+    /// [`set_dcid_rotation_interval()`]:
+    ///     struct.Config.html#method.set_dcid_rotation_interval
+    /// [`set_dcid_rotation_bytes()`]:
+    ///     struct.Config.html#method.set_dcid_rotation_bytes
+    /// [`OutOfIdentifiers`]: enum.Error.html#OutOfIdentifiers
+    /// [`InvalidState`]: enum.Error.html#InvalidState
+    pub fn rotate_dcid(&mut self) -> Result<u64> {
+        if self.ids.zero_length_dcid() {
+            return Err(Error::InvalidState);
+        }
+
+        if self.ids.lowest_available_dcid_seq().is_none() {
+            return Err(Error::OutOfIdentifiers);
+        }
+
+        let dcid_seq = self
+            .paths
+            .get_active()?
+            .active_dcid_seq
+            .ok_or(Error::InvalidState)?;
+
+        if self.dcid_rotation_retire_prior_to {
+            if self.ids.zero_length_scid() {
+                return Err(Error::InvalidState);
+            }
+
+            // The peer may still need the old Connection IDs to complete the
+            // validation.
+            if self
+                .paths
+                .iter()
+                .any(|(_, p)| p.under_validation() || p.validation_requested())
+            {
+                return Err(Error::InvalidState);
+            }
+
+            // Check there is a source Connection ID to move the peer to before
+            // switching the destination one.
+            self.ids.retire_scids_prior_to_newest()?;
+        }
+
+        self.retire_dcid(dcid_seq)?;
+
+        self.dcid_rotation_time = time::Instant::now();
+        self.dcid_rotation_sent_bytes = self.sent_bytes;
+        self.dcid_rotation_count += 1;
+
+        self.paths
+            .get_active()?
+            .active_dcid_seq
+            .ok_or(Error::OutOfIdentifiers)
+    }
+
+    /// Initiates a key update.
+    ///
+    /// New packet protection keys are derived and used for all subsequent
+    /// short header packets, while the previous ones are retained for a while
+    /// to decrypt delayed packets. See also [`set_key_update_packets()`] to
+    /// update keys automatically.
+    ///
+    /// If the handshake is not confirmed yet, or if the peer has not
+    /// responded to a previous key update, this method returns an
+    /// [`InvalidState`].
+    ///
+    /// [`set_key_update_packets()`]:
+    ///     struct.Config.html#method.set_key_update_packets
+    /// [`InvalidState`]: enum.Error.html#InvalidState
+    pub fn initiate_key_update(&mut self) -> Result<()> {
+        if !self.handshake_confirmed {
+            return Err(Error::InvalidState);
+        }
+
+        let now = time::Instant::now();
+
+        let pto = self.paths.get_active()?.recovery.pto();
+
+        let space = &mut self.pkt_num_spaces[packet::Epoch::Application];
+
+        // Wait for the previous key update to complete.
+        if space.key_update.as_ref().map_or(false, |prev| {
+            prev.pn_on_update == u64::MAX || !prev.update_acked
+        }) {
+            return Err(Error::InvalidState);
+        }
+
+        let open_next = space
+            .crypto_open
+            .as_ref()
+            .ok_or(Error::InvalidState)?
+            .derive_next_packet_key()?;
+
+        let seal_next = space
+            .crypto_seal
+            .as_ref()
+            .ok_or(Error::InvalidState)?
+            .derive_next_packet_key()?;
+
+        let _ = space.crypto_seal.replace(seal_next);
+
+        let open_prev = space.crypto_open.replace(open_next).unwrap();
+
+        // Until the peer responds, all packets received with the previous key
+        // phase are decrypted with the previous keys.
+        space.key_update = Some(packet::KeyUpdate {
+            crypto_open: open_prev,
+            pn_on_update: u64::MAX,
+            update_acked: true,
+            timer: now + (pto * 3),
+        });
+
+        self.key_phase = !self.key_phase;
+
+        self.key_update_sent_count = self.sent_count;
+        self.key_update_local_count += 1;
+
+        trace!("{} initiated key update", self.trace_id);
+
+        qlog_with_type!(QLOG_PACKET_TX, self.qlog, q, {
+            let trigger = Some(
+                qlog::events::security::KeyUpdateOrRetiredTrigger::LocalUpdate,
+            );
+
+            let ev_data_client =
+                EventData::KeyUpdated(qlog::events::security::KeyUpdated {
+                    key_type: qlog::events::security::KeyType::Client1RttSecret,
+                    old: None,
+                    new: String::new(),
+                    generation: None,
+                    trigger: trigger.clone(),
+                });
+
+            q.add_event_data_with_instant(ev_data_client, now).ok();
+
+            let ev_data_server =
+                EventData::KeyUpdated(qlog::events::security::KeyUpdated {
+                    key_type: qlog::events::security::KeyType::Server1RttSecret,
+                    old: None,
+                    new: String::new(),
+                    generation: None,
+                    trigger,
+                });
+
+            q.add_event_data_with_instant(ev_data_server, now).ok();
+        });
+
+        Ok(())
+    }
+
+    /// Provides the Connection ID that the client should use to reach the
+    /// server on its preferred address.
+    ///
+    /// The Connection ID is advertised, together with the addresses set with
+    /// [`set_preferred_address()`], in the `preferred_address` transport
+    /// parameter, and it is always assigned the sequence number 1. It must
+    /// hence be provided before any other additional Source Connection ID, and
+    /// before the first packet from the client is processed with [`recv()`].
+    ///
+    /// Calling this method as a client, after the first packet was received,
+    /// or without preferred addresses configured returns an [`InvalidState`].
+    ///
+    /// [`set_preferred_address()`]:
+    ///     struct.Config.html#method.set_preferred_address
+    /// [`recv()`]: struct.Connection.html#method.recv
+    /// [`InvalidState`]: enum.Error.html#InvalidState
+    pub fn set_preferred_address_cid(
+        &mut self, scid: &ConnectionId, reset_token: u128,
+    ) -> Result<()> {
+        if !self.is_server || self.got_peer_conn_id {
+            return Err(Error::InvalidState);
+        }
+
+        if self.preferred_address_v4.is_none() &&
+            self.preferred_address_v6.is_none()
+        {
+            return Err(Error::InvalidState);
+        }
+
+        if self.ids.zero_length_scid() || self.ids.next_scid_seq() != 1 {
+            return Err(Error::InvalidState);
+        }
+
+        self.ids.new_scid(
+            scid.to_vec().into(),
+            Some(reset_token),
+            false,
+            None,
+            false,
+        )?;
+
+        self.local_transport_params.preferred_address = Some(PreferredAddress {
+            ipv4: self.preferred_address_v4,
+            ipv6: self.preferred_address_v6,
+            connection_id: scid.to_vec().into(),
+            stateless_reset_token: reset_token,
+        });
+
+        self.encode_transport_params()
+    }
+
+    /// Provides new source Connection IDs obtained from `generator`, until
+    /// the limit advertised by the peer is reached.
+    ///
+    /// Each Connection ID is assigned a random stateless reset token and is
+    /// advertised to the peer as with [`new_scid()`].
+    ///
+    /// Returns the Connection IDs that were added, so that the application can
+    /// route packets using them to this connection.
+    ///
+    /// [`new_scid()`]: struct.Connection.html#method.new_scid
+    pub fn provide_scids(
+        &mut self, generator: &mut dyn ConnectionIdGenerator,
+    ) -> Result<Vec<ConnectionId<'static>>> {
+        let mut scids = Vec::new();
+
+        while self.scids_left() > 0 {
+            let scid = generator.generate();
+
+            let mut reset_token = [0; 16];
+            rand::rand_bytes(&mut reset_token);
+
+            self.new_scid(&scid, u128::from_be_bytes(reset_token), false)?;
+
+            scids.push(scid);
+        }
+
+        Ok(scids)
+    }
+
     /// Returns the number of source Connection IDs that are active. This is
     /// only meaningful if the host uses non-zero length Source Connection IDs.
     pub fn active_scids(&self) -> usize {
@@ -5936,6 +10877,27 @@ impl Connection {
         self.paths.pop_event()
     }
 
+    /// Returns the next packet-level transport event.
+    ///
+    /// Events are returned in the order they occurred, across all paths, or
+    /// `None` when there are no more events to report. Events are only
+    /// recorded for 0-RTT and 1-RTT packets, when enabled with
+    /// [`enable_packet_events()`]. If they are not retrieved frequently enough,
+    /// the oldest ones are dropped.
+    ///
+    /// [`enable_packet_events()`]: struct.Config.html#method.enable_packet_events
+    pub fn next_transport_event(&mut self) -> Option<TransportEvent> {
+        let (_, path) = self
+            .paths
+            .iter_mut()
+            .filter(|(_, p)| p.recovery.peek_packet_event().is_some())
+            .min_by_key(|(_, p)| {
+                p.recovery.peek_packet_event().map(|e| e.time())
+            })?;
+
+        path.recovery.pop_packet_event()
+    }
+
     /// Returns the number of source Connection IDs that are retired.
     pub fn retired_scids(&self) -> usize {
         self.ids.retired_source_cids()
@@ -6050,6 +11012,32 @@ impl Connection {
     /// [`on_timeout()`]: struct.Connection.html#method.on_timeout
     /// [`is_closed()`]: struct.Connection.html#method.is_closed
     pub fn close(&mut self, app: bool, err: u64, reason: &[u8]) -> Result<()> {
+        self.close_with_drain(app, err, reason, true)
+    }
+
+    /// Closes the connection with the given error and reason, optionally
+    /// skipping the draining period.
+    ///
+    /// This behaves like [`close()`] when `drain` is `true`. Otherwise, the
+    /// connection is closed as soon as the packet carrying the
+    /// CONNECTION_CLOSE frame is generated by [`send()`], together with any
+    /// pending ACK frames, so [`is_closed()`] returns `true` right after that
+    /// packet is sent, instead of after three times the PTO.
+    ///
+    /// Skipping the draining period means that packets still in flight from
+    /// the peer won't be answered, so this should only be used when the
+    /// connection's resources need to be released quickly, e.g. on server
+    /// shutdown.
+    ///
+    /// Returns [`Done`] if the connection had already been closed.
+    ///
+    /// [`close()`]: struct.Connection.html#method.close
+    /// [`send()`]: struct.Connection.html#method.send
+    /// [`is_closed()`]: struct.Connection.html#method.is_closed
+    /// [`Done`]: enum.Error.html#variant.Done
+    pub fn close_with_drain(
+        &mut self, app: bool, err: u64, reason: &[u8], drain: bool,
+    ) -> Result<()> {
         if self.is_closed() || self.is_draining() {
             return Err(Error::Done);
         }
@@ -6076,6 +11064,11 @@ impl Connection {
             });
         }
 
+        // The new error wasn't triggered by a received frame.
+        self.local_error_frame = None;
+
+        self.close_drain = drain;
+
         // When no packet was successfully processed close connection immediately.
         if self.recv_count == 0 {
             self.mark_closed();
@@ -6101,6 +11094,30 @@ impl Connection {
         self.alpn.as_ref()
     }
 
+    /// Derives `len` bytes of keying material from the TLS session, as
+    /// described in [RFC 8446 Section 7.5].
+    ///
+    /// The `label` and `context` values are defined by the application
+    /// protocol using the exported material. If the handshake is not
+    /// completed yet, [`InvalidState`] is returned.
+    ///
+    /// [RFC 8446 Section 7.5]: https://www.rfc-editor.org/rfc/rfc8446#section-7.5
+    /// [`InvalidState`]: enum.Error.html#variant.InvalidState
+    pub fn export_keying_material(
+        &self, label: &[u8], context: &[u8], len: usize,
+    ) -> Result<Vec<u8>> {
+        if !self.handshake_completed {
+            return Err(Error::InvalidState);
+        }
+
+        let mut out = vec![0; len];
+
+        self.handshake
+            .export_keying_material(label, context, &mut out)?;
+
+        Ok(out)
+    }
+
     /// Returns the server name requested by the client.
     #[inline]
     pub fn server_name(&self) -> Option<&str> {
@@ -6113,21 +11130,54 @@ impl Connection {
         self.handshake.peer_cert()
     }
 
-    /// Returns the peer's certificate chain (if any) as a vector of DER-encoded
-    /// buffers.
+    /// Returns the application settings (ALPS) sent by the peer.
     ///
-    /// The certificate at index 0 is the peer's leaf certificate, the other
-    /// certificates (if any) are the chain certificate authorities used to
-    /// sign the leaf certificate.
+    /// `None` is returned if application settings were not negotiated, see
+    /// [`set_application_settings()`].
+    ///
+    /// [`set_application_settings()`]:
+    /// struct.Config.html#method.set_application_settings
     #[inline]
-    pub fn peer_cert_chain(&self) -> Option<Vec<&[u8]>> {
-        self.handshake.peer_cert_chain()
+    pub fn peer_application_settings(&self) -> Option<&[u8]> {
+        self.handshake.peer_application_settings()
     }
 
-    /// Returns the serialized cryptographic session for the connection.
+    /// Returns the parameters negotiated during the TLS handshake.
     ///
-    /// This can be used by a client to cache a connection's session, and resume
-    /// it later using the [`set_session()`] method.
+    /// Fields that haven't been negotiated yet are empty.
+    pub fn handshake_info(&self) -> HandshakeInfo {
+        HandshakeInfo {
+            cipher_suite: self.handshake.cipher().map(CipherSuite::from),
+
+            key_exchange_group: self.handshake.curve(),
+
+            peer_signature_algorithm: self.handshake.sigalg(),
+
+            application_proto: self.alpn.clone(),
+
+            resumed: self.handshake.is_resumed(),
+
+            peer_application_settings: self
+                .peer_application_settings()
+                .map(|s| s.to_vec()),
+        }
+    }
+
+    /// Returns the peer's certificate chain (if any) as a vector of DER-encoded
+    /// buffers.
+    ///
+    /// The certificate at index 0 is the peer's leaf certificate, the other
+    /// certificates (if any) are the chain certificate authorities used to
+    /// sign the leaf certificate.
+    #[inline]
+    pub fn peer_cert_chain(&self) -> Option<Vec<&[u8]>> {
+        self.handshake.peer_cert_chain()
+    }
+
+    /// Returns the serialized cryptographic session for the connection.
+    ///
+    /// This can be used by a client to cache a connection's session, and resume
+    /// it later using the [`set_session()`] method.
     ///
     /// [`set_session()`]: struct.Connection.html#method.set_session
     #[inline]
@@ -6202,6 +11252,42 @@ impl Connection {
         self.handshake.is_in_early_data()
     }
 
+    /// Returns true if early data was rejected.
+    ///
+    /// On the client this means that the server didn't accept the 0-RTT
+    /// data, which is then automatically retransmitted once the handshake
+    /// completes. On the server this means that early data was disabled with
+    /// [`set_early_data_enabled()`] or that the connection attempt was
+    /// detected as a replay.
+    ///
+    /// [`set_early_data_enabled()`]: struct.Connection.html#method.set_early_data_enabled
+    #[inline]
+    pub fn is_early_data_rejected(&self) -> bool {
+        self.early_data_rejected
+    }
+
+    /// Enables or disables early data for this connection, overriding the
+    /// value set with [`enable_early_data()`].
+    ///
+    /// This can be used by servers to decide whether to accept 0-RTT data on
+    /// a per-connection basis, e.g. depending on the load or on the client's
+    /// address. It needs to be called before the first packet is received,
+    /// otherwise [`InvalidState`] is returned.
+    ///
+    /// [`enable_early_data()`]: struct.Config.html#method.enable_early_data
+    /// [`InvalidState`]: enum.Error.html#variant.InvalidState
+    pub fn set_early_data_enabled(&mut self, enabled: bool) -> Result<()> {
+        if !self.is_server || self.got_peer_conn_id {
+            return Err(Error::InvalidState);
+        }
+
+        self.handshake.set_early_data_enabled(enabled);
+
+        self.early_data_rejected = !enabled;
+
+        Ok(())
+    }
+
     /// Returns whether there is stream or DATAGRAM data available to read.
     #[inline]
     pub fn is_readable(&self) -> bool {
@@ -6226,6 +11312,76 @@ impl Connection {
         Ok(self.paths.get(pid)?.validated())
     }
 
+    /// Limits the size of UDP payloads sent on the network path with local
+    /// address `from` and remote address `to`.
+    ///
+    /// This lowers the maximum datagram size of the path, which is also used
+    /// by congestion control to convert between bytes and packets, as well as
+    /// the upper bound of PMTU discovery on the path when enabled with
+    /// [`discover_pmtu()`]. The size can't be lower than `1200`, and it can't
+    /// be raised again afterwards.
+    ///
+    /// The current maximum datagram size of each path is reported by
+    /// [`path_stats()`].
+    ///
+    /// If the 4-tuple does not exist over the connection, returns an
+    /// [`InvalidState`].
+    ///
+    /// [`discover_pmtu()`]: struct.Config.html#method.discover_pmtu
+    /// [`path_stats()`]: struct.Connection.html#method.path_stats
+    /// [`InvalidState`]: enum.Error.html#variant.InvalidState
+    pub fn set_path_max_send_udp_payload_size(
+        &mut self, from: SocketAddr, to: SocketAddr, v: usize,
+    ) -> Result<()> {
+        let pid = self
+            .paths
+            .path_id_from_addrs(&(from, to))
+            .ok_or(Error::InvalidState)?;
+
+        self.paths.get_mut(pid)?.clamp_max_datagram_size(v);
+
+        Ok(())
+    }
+
+    /// Returns the number of bytes the server can still send on the active
+    /// path before hitting the anti-amplification limit.
+    ///
+    /// `None` is returned on the client, or once the client's address has been
+    /// validated, as the limit doesn't apply anymore.
+    pub fn amplification_budget(&self) -> Option<usize> {
+        if !self.is_server {
+            return None;
+        }
+
+        let path = self.paths.get_active().ok()?;
+
+        if path.verified_peer_address {
+            return None;
+        }
+
+        Some(path.max_send_bytes)
+    }
+
+    /// Marks the client's address on the active path as validated.
+    ///
+    /// Servers can call this when they have out-of-band evidence that the
+    /// client owns its address, such as a NEW_TOKEN token previously issued to
+    /// it, in order to lift the anti-amplification limit before the handshake
+    /// validates the address.
+    ///
+    /// Returns [`InvalidState`] when called on the client.
+    ///
+    /// [`InvalidState`]: enum.Error.html#variant.InvalidState
+    pub fn validate_peer_address(&mut self) -> Result<()> {
+        if !self.is_server {
+            return Err(Error::InvalidState);
+        }
+
+        self.paths.get_active_mut()?.verified_peer_address = true;
+
+        Ok(())
+    }
+
     /// Returns true if the connection is draining.
     ///
     /// If this returns `true`, the connection object cannot yet be dropped, but
@@ -6234,18 +11390,49 @@ impl Connection {
     /// methods as normal, until the [`is_closed()`] method returns `true`.
     ///
     /// In contrast, once `is_draining()` returns `true`, calling [`send()`]
-    /// is not required because no new outgoing packets will be generated.
+    /// is only required when [`is_closing()`] also returns `true`, as the
+    /// CONNECTION_CLOSE frame is sent again in response to packets received
+    /// from the peer. Otherwise no new outgoing packets will be generated.
     ///
     /// [`recv()`]: struct.Connection.html#method.recv
     /// [`send()`]: struct.Connection.html#method.send
     /// [`timeout()`]: struct.Connection.html#method.timeout
     /// [`on_timeout()`]: struct.Connection.html#method.on_timeout
     /// [`is_closed()`]: struct.Connection.html#method.is_closed
+    /// [`is_closing()`]: struct.Connection.html#method.is_closing
     #[inline]
     pub fn is_draining(&self) -> bool {
         self.draining_timer.is_some()
     }
 
+    /// Returns true if the connection is being closed locally.
+    ///
+    /// This is the case once [`close()`] was called, or an error was
+    /// detected, until the connection is closed. The CONNECTION_CLOSE frame
+    /// is sent by [`send()`], after which [`is_draining()`] also returns
+    /// `true`.
+    ///
+    /// [`close()`]: struct.Connection.html#method.close
+    /// [`send()`]: struct.Connection.html#method.send
+    /// [`is_draining()`]: struct.Connection.html#method.is_draining
+    #[inline]
+    pub fn is_closing(&self) -> bool {
+        self.local_error.is_some() && !self.closed
+    }
+
+    /// Returns when the connection will be closed, once it's draining.
+    ///
+    /// This is the end of the closing or draining period, which lasts three
+    /// times the PTO after the CONNECTION_CLOSE frame was sent or received.
+    #[inline]
+    pub fn draining_deadline(&self) -> Option<time::Instant> {
+        if self.closed {
+            return None;
+        }
+
+        self.draining_timer
+    }
+
     /// Returns true if the connection is closed.
     ///
     /// If this returns true, the connection object can be dropped.
@@ -6254,7 +11441,8 @@ impl Connection {
         self.closed
     }
 
-    /// Returns true if the connection was closed due to the idle timeout.
+    /// Returns true if the connection was closed due to the idle timeout, or
+    /// because the maximum number of consecutive PTOs was reached.
     #[inline]
     pub fn is_timed_out(&self) -> bool {
         self.timed_out
@@ -6286,6 +11474,29 @@ impl Connection {
         self.local_error.as_ref()
     }
 
+    /// Returns the type of the frame carried by the peer's transport
+    /// `CONNECTION_CLOSE` frame, if known.
+    ///
+    /// This is `None` if the peer closed the connection with an application
+    /// error, or didn't indicate which frame triggered the error.
+    #[inline]
+    pub fn peer_error_frame_type(&self) -> Option<u64> {
+        self.peer_error_frame_type
+    }
+
+    /// Returns the type of the received frame that triggered the
+    /// [`local_error()`], and the number of the packet carrying it, if known.
+    ///
+    /// This is only set for transport errors detected while processing a
+    /// received frame. The frame type is also sent to the peer in the
+    /// `CONNECTION_CLOSE` frame.
+    ///
+    /// [`local_error()`]: struct.Connection.html#method.local_error
+    #[inline]
+    pub fn local_error_frame(&self) -> Option<(u64, u64)> {
+        self.local_error_frame
+    }
+
     /// Collects and returns statistics about the connection.
     #[inline]
     pub fn stats(&self) -> Stats {
@@ -6304,11 +11515,56 @@ impl Connection {
             reset_stream_count_remote: self.reset_stream_remote_count,
             stopped_stream_count_remote: self.stopped_stream_remote_count,
             path_challenge_rx_count: self.path_challenge_rx_count,
+            path_validated_count: self.paths.validated_count(),
+            path_failed_validation_count: self.paths.failed_validation_count(),
+            new_path_dropped: self.new_path_dropped_count,
+            persistent_congestion_count: self
+                .paths
+                .iter()
+                .map(|(_, p)| p.recovery.persistent_congestion_count)
+                .sum(),
+            pto_count_initial: self.pto_count_sum(packet::Epoch::Initial),
+            pto_count_handshake: self.pto_count_sum(packet::Epoch::Handshake),
+            pto_count_application: self.pto_count_sum(packet::Epoch::Application),
+            longest_pto_backoff: self
+                .paths
+                .iter()
+                .map(|(_, p)| p.recovery.longest_pto_backoff)
+                .max()
+                .unwrap_or(0),
+            fec_repair_sent: self.fec_repair_sent_count,
+            fec_recovered: self.fec_recovered_count,
+            dcid_rotated: self.dcid_rotation_count,
+            key_updates_local: self.key_update_local_count,
+            key_updates_remote: self.key_update_remote_count,
+            dgram_send_expired: self.dgram_send_queue.expired_count(),
+            dgram_send_queue_peak_len: self.dgram_send_queue.peak_len(),
+            dgram_recv_queue_peak_len: self.dgram_recv_queue.peak_len(),
+            sent_dgrams: self.sent_dgram_count,
+            coalesced_dgrams: self.coalesced_dgram_count,
+            early_data_rejected: self.early_data_rejected_count,
+            early_data_rejected_bytes: self.early_data_rejected_bytes,
+            handshake_timeline: self.handshake_timeline,
         }
     }
 
+    fn pto_count_sum(&self, epoch: packet::Epoch) -> usize {
+        self.paths
+            .iter()
+            .map(|(_, p)| p.recovery.pto_expirations[epoch])
+            .sum()
+    }
+
     /// Returns reference to peer's transport parameters. Returns `None` if we
     /// have not yet processed the peer's transport parameters.
+    ///
+    /// The values are the ones advertised by the peer, not the ones resulting
+    /// from the negotiation. For example, the size of the datagrams actually
+    /// sent is returned by [`max_send_udp_payload_size()`]. Parameters not
+    /// handled by quiche are available as raw bytes in [`custom_params`].
+    ///
+    /// [`max_send_udp_payload_size()`]: struct.Connection.html#method.max_send_udp_payload_size
+    /// [`custom_params`]: struct.TransportParams.html#structfield.custom_params
     pub fn peer_transport_params(&self) -> Option<&TransportParams> {
         if !self.parsed_peer_transport_params {
             return None;
@@ -6323,21 +11579,101 @@ impl Connection {
         self.paths.iter().map(|(_, p)| p.stats())
     }
 
+    /// Collects and returns statistics about each known path for the
+    /// connection, restricted to the current interval.
+    ///
+    /// Unlike [`path_stats()`], which returns cumulative counters since the
+    /// path was created, the counters returned here only account for the
+    /// events that happened since the last call to [`reset_path_stats()`].
+    /// This makes it easy for long-lived connections to report per-interval
+    /// loss rate and RTT.
+    ///
+    /// [`path_stats()`]: struct.Connection.html#method.path_stats
+    /// [`reset_path_stats()`]: struct.Connection.html#method.reset_path_stats
+    pub fn path_interval_stats(
+        &self,
+    ) -> impl Iterator<Item = PathIntervalStats> + '_ {
+        let now = time::Instant::now();
+
+        self.paths.iter().map(move |(_, p)| p.interval_stats(now))
+    }
+
+    /// Starts a new statistics interval on each known path.
+    ///
+    /// Counters returned by [`path_interval_stats()`] are reset, while the
+    /// cumulative ones returned by [`path_stats()`] are not affected.
+    ///
+    /// [`path_interval_stats()`]: struct.Connection.html#method.path_interval_stats
+    /// [`path_stats()`]: struct.Connection.html#method.path_stats
+    pub fn reset_path_stats(&mut self) {
+        let now = time::Instant::now();
+
+        for (_, p) in self.paths.iter_mut() {
+            p.reset_interval_stats(now);
+        }
+    }
+
     /// Returns whether or not this is a server-side connection.
     pub fn is_server(&self) -> bool {
         self.is_server
     }
 
     fn encode_transport_params(&mut self) -> Result<()> {
-        let mut raw_params = [0; 128];
+        if !self.compatible_versions.is_empty() {
+            let mut available_versions = self.compatible_versions.clone();
 
-        let raw_params = TransportParams::encode(
+            if self.grease {
+                available_versions.push(grease_version());
+            }
+
+            self.local_transport_params.version_information =
+                Some(VersionInformation {
+                    chosen_version: self.version,
+                    available_versions,
+                });
+        }
+
+        let mut raw_params =
+            vec![0; self.local_transport_params.max_encoded_len()];
+
+        let mut len = TransportParams::encode(
             &self.local_transport_params,
             self.is_server,
             &mut raw_params,
+        )?
+        .len();
+
+        // Note that QUIC v1 doesn't reserve any frame types, so frames are
+        // only greased by HTTP/3.
+        if self.grease {
+            len += TransportParams::encode_grease(&mut raw_params[len..])?;
+        }
+
+        self.handshake
+            .set_quic_transport_params(&raw_params[..len])?;
+
+        Ok(())
+    }
+
+    /// Derives the Initial keys for the compatible version the connection
+    /// switched to.
+    fn on_version_upgrade(&mut self) -> Result<()> {
+        trace!(
+            "{} switched to compatible version {:x}",
+            self.trace_id,
+            self.version
+        );
+
+        let (aead_open, aead_seal) = crypto::derive_initial_key_material(
+            &self.initial_keys_cid,
+            self.version,
+            self.is_server,
         )?;
 
-        self.handshake.set_quic_transport_params(raw_params)?;
+        self.pkt_num_spaces[packet::Epoch::Initial].crypto_open = Some(aead_open);
+        self.pkt_num_spaces[packet::Epoch::Initial].crypto_seal = Some(aead_seal);
+
+        self.did_version_upgrade = true;
 
         Ok(())
     }
@@ -6345,6 +11681,26 @@ impl Connection {
     fn parse_peer_transport_params(
         &mut self, peer_params: TransportParams,
     ) -> Result<()> {
+        // Validate version_information, as per RFC 9368.
+        if !self.compatible_versions.is_empty() {
+            match &peer_params.version_information {
+                // On the server, the client's chosen version was already
+                // checked when switching to a compatible version.
+                Some(info)
+                    if info.chosen_version != self.version &&
+                        !(self.is_server && self.did_version_upgrade) =>
+                    return Err(Error::VersionNegotiation),
+
+                Some(_) => (),
+
+                // A server switching version must confirm it.
+                None if !self.is_server && self.did_version_upgrade =>
+                    return Err(Error::VersionNegotiation),
+
+                None => (),
+            }
+        }
+
         // Validate initial_source_connection_id.
         match &peer_params.initial_source_connection_id {
             Some(v) if v != &self.destination_id() =>
@@ -6388,6 +11744,12 @@ impl Connection {
             }
         }
 
+        if let Some(cb) = &self.custom_transport_param_callback {
+            for (id, value) in &peer_params.custom_params {
+                cb(*id, value);
+            }
+        }
+
         self.process_peer_transport_params(peer_params)?;
 
         self.parsed_peer_transport_params = true;
@@ -6413,6 +11775,14 @@ impl Connection {
 
         self.recovery_config.max_ack_delay = max_ack_delay;
 
+        // PMTU probes can't exceed the peer's limit, nor the largest size that
+        // fits in a 2-byte varint.
+        let pmtud_max = cmp::min(peer_params.max_udp_payload_size, 16383);
+
+        if let Some(max) = &mut self.recovery_config.pmtud_max_datagram_size {
+            *max = cmp::min(*max, pmtud_max as usize);
+        }
+
         let active_path = self.paths.get_active_mut()?;
 
         active_path.recovery.max_ack_delay = max_ack_delay;
@@ -6421,10 +11791,30 @@ impl Connection {
             .recovery
             .update_max_datagram_size(peer_params.max_udp_payload_size as usize);
 
+        if let Some(pmtud) = &mut active_path.pmtud {
+            pmtud.set_max(pmtud_max as usize);
+        }
+
         // Record the max_active_conn_id parameter advertised by the peer.
         self.ids
             .set_source_conn_id_limit(peer_params.active_conn_id_limit);
 
+        // The Connection ID of the preferred address has sequence number 1.
+        if let Some(pa) = &peer_params.preferred_address {
+            if !self.is_server {
+                self.ids
+                    .new_dcid(
+                        pa.connection_id.clone(),
+                        1,
+                        pa.stateless_reset_token,
+                        0,
+                    )
+                    .map_err(|_| Error::InvalidTransportParam)?;
+
+                self.migrate_to_preferred_address = true;
+            }
+        }
+
         self.peer_transport_params = peer_params;
 
         Ok(())
@@ -6441,20 +11831,59 @@ impl Connection {
 
             session: &mut self.session,
 
+            session_store: self.session_store.as_deref(),
+
+            verify_callback: self.verify_callback.as_deref(),
+
             local_error: &mut self.local_error,
 
+            buf_pool: &mut self.buf_pool,
+
             keylog: self.keylog.as_mut(),
 
             trace_id: &self.trace_id,
 
             is_server: self.is_server,
+
+            version: &mut self.version,
+
+            compatible_versions: &self.compatible_versions,
+
+            grease: self.grease,
+
+            local_transport_params: &mut self.local_transport_params,
         };
 
         if self.handshake_completed {
+            if self.thawed {
+                return Ok(());
+            }
+
             return self.handshake.process_post_handshake(&mut ex_data);
         }
 
-        match self.handshake.do_handshake(&mut ex_data) {
+        let version = *ex_data.version;
+
+        let res = self.handshake.do_handshake(&mut ex_data);
+
+        // The server might have switched to a compatible version while
+        // processing the ClientHello.
+        if self.version != version {
+            self.on_version_upgrade()?;
+        }
+
+        if !self.is_server &&
+            !self.early_data_rejected &&
+            self.handshake.is_early_data_rejected()
+        {
+            self.on_early_data_rejected(now);
+        }
+
+        if self.pkt_num_spaces[packet::Epoch::Handshake].has_keys() {
+            self.handshake_timeline.handshake_keys.get_or_insert(now);
+        }
+
+        match res {
             Ok(_) => (),
 
             Err(Error::Done) => {
@@ -6493,11 +11922,16 @@ impl Connection {
         }
 
         if self.handshake_completed {
+            self.handshake_timeline.handshake_completed = Some(now);
+            self.handshake_timeline.hello_retry_request =
+                self.handshake.used_hello_retry_request();
+
             // The handshake is considered confirmed at the server when the
             // handshake completes, at which point we can also drop the
             // handshake epoch.
             if self.is_server {
                 self.handshake_confirmed = true;
+                self.handshake_timeline.handshake_confirmed = Some(now);
 
                 self.drop_epoch_state(packet::Epoch::Handshake, now);
             }
@@ -6519,8 +11953,78 @@ impl Connection {
         Ok(())
     }
 
+    /// Considers the client's address validated if it presented a token that
+    /// was previously sent in a NEW_TOKEN frame.
+    fn check_new_token(
+        &mut self, token: Option<&[u8]>, dcid: &[u8], pid: usize,
+        from: SocketAddr,
+    ) -> Result<()> {
+        let (provider, token) = match (&self.token_provider, token) {
+            (Some(provider), Some(token)) if !token.is_empty() =>
+                (provider, token),
+
+            _ => return Ok(()),
+        };
+
+        if provider.validate_token(&from, dcid, token) ==
+            Some(ValidatedToken::NewToken)
+        {
+            trace!("{} peer address validated by token", self.trace_id);
+
+            self.paths.get_mut(pid)?.verified_peer_address = true;
+        }
+
+        Ok(())
+    }
+
+    /// Rejects early data on the server if the connection attempt was already
+    /// seen by the configured replay filter.
+    fn check_early_data_replay(&mut self) {
+        let filter = match &self.early_data_replay_filter {
+            Some(v) => v,
+
+            None => return,
+        };
+
+        let odcid = match &self
+            .local_transport_params
+            .original_destination_connection_id
+        {
+            Some(v) => v,
+
+            None => return,
+        };
+
+        if filter.is_replay(odcid) {
+            trace!("{} rejecting replayed early data", self.trace_id);
+
+            self.handshake.set_early_data_enabled(false);
+
+            self.early_data_rejected = true;
+        }
+    }
+
+    /// Schedules the retransmission of the 0-RTT data rejected by the server.
+    fn on_early_data_rejected(&mut self, now: time::Instant) {
+        trace!("{} early data rejected by peer", self.trace_id);
+
+        self.early_data_rejected = true;
+
+        // The peer never processed these packets, so they are not counted
+        // as lost.
+        for (_, p) in self.paths.iter_mut() {
+            let (rejected_packets, rejected_bytes) =
+                p.recovery.on_early_data_rejected(now, &self.trace_id);
+
+            self.early_data_rejected_count += rejected_packets;
+            self.early_data_rejected_bytes += rejected_bytes as u64;
+        }
+    }
+
     /// Selects the packet type for the next outgoing packet.
-    fn write_pkt_type(&self, send_pid: usize) -> Result<packet::Type> {
+    fn write_pkt_type(
+        &self, send_pid: usize, now: time::Instant,
+    ) -> Result<packet::Type> {
         // On error send packet in the latest epoch available, but only send
         // 1-RTT ones when the handshake is completed.
         if self
@@ -6528,7 +12032,14 @@ impl Connection {
             .as_ref()
             .map_or(false, |conn_err| !conn_err.is_app)
         {
-            let epoch = match self.handshake.write_level() {
+            // A thawed connection only has 1-RTT keys.
+            let level = if self.thawed {
+                crypto::Level::OneRTT
+            } else {
+                self.handshake.write_level()
+            };
+
+            let epoch = match level {
                 crypto::Level::Initial => packet::Epoch::Initial,
                 crypto::Level::ZeroRTT => unreachable!(),
                 crypto::Level::Handshake => packet::Epoch::Handshake,
@@ -6587,14 +12098,15 @@ impl Connection {
         let send_path = self.paths.get(send_pid)?;
         if (self.is_established() || self.is_in_early_data()) &&
             (self.should_send_handshake_done() ||
+                self.should_send_new_token() ||
                 self.almost_full ||
                 self.blocked_limit.is_some() ||
                 self.dgram_send_queue.has_pending() ||
                 self.local_error
                     .as_ref()
                     .map_or(false, |conn_err| conn_err.is_app) ||
-                self.streams.should_update_max_streams_bidi() ||
-                self.streams.should_update_max_streams_uni() ||
+                self.streams.should_update_max_streams_bidi(now) ||
+                self.streams.should_update_max_streams_uni(now) ||
                 self.streams.has_flushable() ||
                 self.streams.has_almost_full() ||
                 self.streams.has_blocked() ||
@@ -6603,7 +12115,9 @@ impl Connection {
                 self.ids.has_new_scids() ||
                 self.ids.has_retire_dcids() ||
                 send_path.needs_ack_eliciting ||
-                send_path.probing_required())
+                send_path.probing_required() ||
+                self.bandwidth_probe_required(send_path) ||
+                (self.fec_enabled() && self.fec_encoder.has_pending()))
         {
             // Only clients can send 0-RTT packets.
             if !self.is_server && self.is_in_early_data() {
@@ -6616,6 +12130,45 @@ impl Connection {
         Err(Error::Done)
     }
 
+    /// Returns whether a bandwidth probe packet should be sent on the path.
+    fn bandwidth_probe_required(&self, path: &path::Path) -> bool {
+        self.bandwidth_probe_until.is_some() &&
+            path.active() &&
+            path.recovery.cwnd_available() >= path.recovery.max_datagram_size()
+    }
+
+    /// Records the receipt of an ack-eliciting packet, and decides whether it
+    /// needs to be acknowledged right away or can be delayed, as per the
+    /// configured ACK policy.
+    fn on_ack_eliciting_pkt_received(
+        &mut self, epoch: packet::Epoch, reordered: bool, now: time::Instant,
+    ) {
+        let max_ack_delay = time::Duration::from_millis(
+            self.local_transport_params.max_ack_delay,
+        );
+
+        let can_delay = epoch == packet::Epoch::Application &&
+            !max_ack_delay.is_zero() &&
+            (self.handshake_confirmed || !self.immediate_ack_during_handshake) &&
+            !(reordered && self.ack_on_reordering);
+
+        let pkt_space = &mut self.pkt_num_spaces[epoch];
+
+        pkt_space.ack_eliciting_unacked += 1;
+
+        if !can_delay ||
+            pkt_space.ack_eliciting_unacked >= self.ack_eliciting_threshold
+        {
+            pkt_space.ack_elicited = true;
+            pkt_space.ack_timer = None;
+            return;
+        }
+
+        if pkt_space.ack_timer.is_none() && !pkt_space.ack_elicited {
+            pkt_space.ack_timer = Some(now + max_ack_delay);
+        }
+    }
+
     /// Returns the mutable stream with the given ID if it exists, or creates
     /// a new one otherwise.
     fn get_or_create_stream(
@@ -6640,7 +12193,7 @@ impl Connection {
         match frame {
             frame::Frame::Padding { .. } => (),
 
-            frame::Frame::Ping => (),
+            frame::Frame::Ping { .. } => (),
 
             frame::Frame::ACK {
                 ranges, ack_delay, ..
@@ -6686,53 +12239,28 @@ impl Connection {
                 stream_id,
                 error_code,
                 final_size,
+            } =>
+                self.process_reset_stream(stream_id, error_code, final_size, 0)?,
+
+            frame::Frame::ResetStreamAt {
+                stream_id,
+                error_code,
+                final_size,
+                reliable_size,
             } => {
-                // Peer can't send on our unidirectional streams.
-                if !stream::is_bidi(stream_id) &&
-                    stream::is_local(stream_id, self.is_server)
-                {
-                    return Err(Error::InvalidStreamState(stream_id));
+                // Treat the frame as unknown if support for it wasn't
+                // advertised.
+                if !self.local_transport_params.reset_stream_at {
+                    return Err(Error::InvalidFrame);
                 }
 
-                let max_rx_data_left = self.max_rx_data() - self.rx_data;
-
-                // Get existing stream or create a new one, but if the stream
-                // has already been closed and collected, ignore the frame.
-                //
-                // This can happen if e.g. an ACK frame is lost, and the peer
-                // retransmits another frame before it realizes that the stream
-                // is gone.
-                //
-                // Note that it makes it impossible to check if the frame is
-                // illegal, since we have no state, but since we ignore the
-                // frame, it should be fine.
-                let stream = match self.get_or_create_stream(stream_id, false) {
-                    Ok(v) => v,
-
-                    Err(Error::Done) => return Ok(()),
-
-                    Err(e) => return Err(e),
-                };
-
-                let was_readable = stream.is_readable();
-                let priority_key = Arc::clone(&stream.priority_key);
-
-                let max_off_delta =
-                    stream.recv.reset(error_code, final_size)? as u64;
-
-                if max_off_delta > max_rx_data_left {
-                    return Err(Error::FlowControl);
-                }
-
-                if !was_readable && stream.is_readable() {
-                    self.streams.insert_readable(&priority_key);
-                }
-
-                self.rx_data += max_off_delta;
-
-                self.reset_stream_remote_count =
-                    self.reset_stream_remote_count.saturating_add(1);
-            },
+                self.process_reset_stream(
+                    stream_id,
+                    error_code,
+                    final_size,
+                    reliable_size,
+                )?;
+            },
 
             frame::Frame::StopSending {
                 stream_id,
@@ -6780,7 +12308,10 @@ impl Connection {
                     self.tx_buffered =
                         self.tx_buffered.saturating_sub(unsent as usize);
 
-                    self.streams.insert_reset(stream_id, error_code, final_size);
+                    self.streams
+                        .insert_reset(stream_id, error_code, final_size, 0);
+
+                    self.streams.remove_deadline(stream_id);
 
                     if !was_writable {
                         self.streams.insert_writable(&priority_key);
@@ -6805,7 +12336,21 @@ impl Connection {
 
                 let stream = &mut self.pkt_num_spaces[epoch].crypto_stream;
 
-                while let Ok((read, _)) = stream.recv.emit(&mut crypto_buf) {
+                // A thawed connection has no TLS state to process
+                // post-handshake messages with, so they are discarded.
+                if self.thawed {
+                    while stream
+                        .recv
+                        .emit_pooled(&mut crypto_buf, &mut self.buf_pool)
+                        .is_ok()
+                    {}
+
+                    return Ok(());
+                }
+
+                while let Ok((read, _)) =
+                    stream.recv.emit_pooled(&mut crypto_buf, &mut self.buf_pool)
+                {
                     let recv_buf = &crypto_buf[..read];
                     self.handshake.provide_data(level, recv_buf)?;
                 }
@@ -6815,11 +12360,17 @@ impl Connection {
 
             frame::Frame::CryptoHeader { .. } => unreachable!(),
 
-            // TODO: implement stateless retry
-            frame::Frame::NewToken { .. } =>
+            frame::Frame::NewToken { token } => {
                 if self.is_server {
                     return Err(Error::InvalidPacket);
-                },
+                }
+
+                if let (Some(store), Some(server_name)) =
+                    (&self.session_store, self.handshake.server_name())
+                {
+                    store.put_token(server_name, &token);
+                }
+            },
 
             frame::Frame::Stream { stream_id, data } => {
                 // Peer can't send on our unidirectional streams.
@@ -6864,6 +12415,8 @@ impl Connection {
 
                 stream.recv.write(data)?;
 
+                let reassembly_len = stream.recv.reassembly_len();
+
                 if !was_readable && stream.is_readable() {
                     self.streams.insert_readable(&priority_key);
                 }
@@ -6881,6 +12434,14 @@ impl Connection {
                         self.almost_full = true;
                     }
                 }
+
+                // Stop the stream if it pushed the connection over the
+                // reassembly limit, when configured to.
+                if let Some(err) =
+                    self.streams.update_reassembly(stream_id, reassembly_len)
+                {
+                    self.stream_shutdown(stream_id, Shutdown::Read, err)?;
+                }
             },
 
             frame::Frame::StreamHeader { .. } => unreachable!(),
@@ -6955,15 +12516,21 @@ impl Connection {
 
             frame::Frame::StreamDataBlocked { .. } => (),
 
-            frame::Frame::StreamsBlockedBidi { limit } =>
+            frame::Frame::StreamsBlockedBidi { limit } => {
                 if limit > MAX_STREAM_ID {
                     return Err(Error::InvalidFrame);
-                },
+                }
 
-            frame::Frame::StreamsBlockedUni { limit } =>
+                self.streams.on_peer_streams_blocked(true, limit);
+            },
+
+            frame::Frame::StreamsBlockedUni { limit } => {
                 if limit > MAX_STREAM_ID {
                     return Err(Error::InvalidFrame);
-                },
+                }
+
+                self.streams.on_peer_streams_blocked(false, limit);
+            },
 
             frame::Frame::NewConnectionId {
                 seq_num,
@@ -7040,11 +12607,40 @@ impl Connection {
             },
 
             frame::Frame::PathResponse { data } => {
+                // Answers to quality probes don't affect path validation.
+                if self.paths.on_quality_probe_response(data, now) {
+                    return Ok(());
+                }
+
                 self.paths.on_response_received(data)?;
+
+                // Complete any migration that was waiting for this path to
+                // be validated.
+                let pending = self
+                    .paths
+                    .iter_mut()
+                    .find(|(_, p)| p.migrate_on_validation && p.validated());
+
+                if let Some((pid, p)) = pending {
+                    p.migrate_on_validation = false;
+
+                    let keep_previous_path = p.keep_previous_path;
+                    p.keep_previous_path = false;
+
+                    let old_dcid_seq = self.paths.get_active()?.active_dcid_seq;
+
+                    self.set_active_path(pid, now)?;
+
+                    if !keep_previous_path {
+                        self.retire_migrated_dcid(old_dcid_seq);
+                    }
+                }
             },
 
             frame::Frame::ConnectionClose {
-                error_code, reason, ..
+                error_code,
+                frame_type,
+                reason,
             } => {
                 self.peer_error = Some(ConnectionError {
                     is_app: false,
@@ -7052,6 +12648,10 @@ impl Connection {
                     reason,
                 });
 
+                // A frame type of 0 means the type is unknown.
+                self.peer_error_frame_type =
+                    Some(frame_type).filter(|&ty| ty != 0);
+
                 let path = self.paths.get_active()?;
                 self.draining_timer = Some(now + (path.recovery.pto() * 3));
             },
@@ -7063,6 +12663,8 @@ impl Connection {
                     reason,
                 });
 
+                self.peer_error_frame_type = None;
+
                 let path = self.paths.get_active()?;
                 self.draining_timer = Some(now + (path.recovery.pto() * 3));
             },
@@ -7075,6 +12677,9 @@ impl Connection {
                 self.peer_verified_initial_address = true;
 
                 self.handshake_confirmed = true;
+                self.handshake_timeline
+                    .handshake_confirmed
+                    .get_or_insert(now);
 
                 // Once the handshake is confirmed, we can drop Handshake keys.
                 self.drop_epoch_state(packet::Epoch::Handshake, now);
@@ -7098,12 +12703,145 @@ impl Connection {
             },
 
             frame::Frame::DatagramHeader { .. } => unreachable!(),
+
+            frame::Frame::Repair {
+                first_pkt_num,
+                count,
+                data,
+            } => {
+                // Receiving a frame that wasn't negotiated is a protocol
+                // violation.
+                if !self.local_transport_params.enable_fec {
+                    return Err(Error::InvalidFrame);
+                }
+
+                let (pn, payload) =
+                    match self.fec_decoder.recover(first_pkt_num, count, &data) {
+                        Some(v) => v,
+
+                        None => return Ok(()),
+                    };
+
+                // The packet might have been received already, but dropped
+                // from the recovery window since.
+                if self.pkt_num_spaces[epoch].recv_pkt_num.contains(pn) {
+                    return Ok(());
+                }
+
+                trace!("{} recovered pkt pn={}", self.trace_id, pn);
+
+                let mut b = octets::Octets::with_slice(&payload);
+
+                let mut ack_elicited = false;
+
+                while b.cap() > 0 {
+                    let frame = frame::Frame::from_bytes(&mut b, hdr.ty)?;
+
+                    // Packets carrying REPAIR frames are never protected.
+                    if let frame::Frame::Repair { .. } = frame {
+                        return Err(Error::InvalidFrame);
+                    }
+
+                    if frame.ack_eliciting() {
+                        ack_elicited = true;
+                    }
+
+                    self.process_frame(frame, hdr, recv_path_id, epoch, now)?;
+                }
+
+                // Acknowledge the recovered packet, so the peer doesn't
+                // retransmit its contents.
+                let pkt_space = &mut self.pkt_num_spaces[epoch];
+
+                if pkt_space.recv_pkt_need_ack.last() < Some(pn) {
+                    pkt_space.largest_rx_pkt_time = now;
+                }
+
+                let reordered = pkt_space.is_reordered(pn);
+
+                pkt_space.recv_pkt_num.insert(pn);
+
+                pkt_space.recv_pkt_need_ack.push_item(pn);
+
+                pkt_space.largest_rx_pkt_num =
+                    cmp::max(pkt_space.largest_rx_pkt_num, pn);
+
+                if ack_elicited {
+                    self.on_ack_eliciting_pkt_received(epoch, reordered, now);
+                }
+
+                self.fec_recovered_count += 1;
+            },
         }
 
         Ok(())
     }
 
     /// Drops the keys and recovery state for the given epoch.
+    /// Processes a RESET_STREAM or RESET_STREAM_AT frame.
+    fn process_reset_stream(
+        &mut self, stream_id: u64, error_code: u64, final_size: u64,
+        reliable_size: u64,
+    ) -> Result<()> {
+        // Peer can't send on our unidirectional streams.
+        if !stream::is_bidi(stream_id) &&
+            stream::is_local(stream_id, self.is_server)
+        {
+            return Err(Error::InvalidStreamState(stream_id));
+        }
+
+        let max_rx_data_left = self.max_rx_data() - self.rx_data;
+
+        // Get existing stream or create a new one, but if the stream
+        // has already been closed and collected, ignore the frame.
+        //
+        // This can happen if e.g. an ACK frame is lost, and the peer
+        // retransmits another frame before it realizes that the stream
+        // is gone.
+        //
+        // Note that it makes it impossible to check if the frame is
+        // illegal, since we have no state, but since we ignore the
+        // frame, it should be fine.
+        let stream = match self.get_or_create_stream(stream_id, false) {
+            Ok(v) => v,
+
+            Err(Error::Done) => return Ok(()),
+
+            Err(e) => return Err(e),
+        };
+
+        let was_readable = stream.is_readable();
+        let priority_key = Arc::clone(&stream.priority_key);
+
+        let max_off_delta = if reliable_size > 0 {
+            stream
+                .recv
+                .reset_at(error_code, final_size, reliable_size)?
+                as u64
+        } else {
+            stream.recv.reset(error_code, final_size)? as u64
+        };
+
+        if max_off_delta > max_rx_data_left {
+            return Err(Error::FlowControl);
+        }
+
+        let reassembly_len = stream.recv.reassembly_len();
+
+        if !was_readable && stream.is_readable() {
+            self.streams.insert_readable(&priority_key);
+        }
+
+        self.streams.update_reassembly(stream_id, reassembly_len);
+
+        self.rx_data += max_off_delta;
+
+        self.reset_stream_remote_count =
+            self.reset_stream_remote_count.saturating_add(1);
+
+        Ok(())
+    }
+
     fn drop_epoch_state(&mut self, epoch: packet::Epoch, now: time::Instant) {
         if self.pkt_num_spaces[epoch].crypto_open.is_none() {
             return;
@@ -7140,6 +12878,38 @@ impl Connection {
         self.is_established() && !self.handshake_done_sent && self.is_server
     }
 
+    /// Returns true if the server should send a NEW_TOKEN frame.
+    fn should_send_new_token(&self) -> bool {
+        self.handshake_confirmed &&
+            !self.new_token_sent &&
+            self.token_provider.is_some()
+    }
+
+    /// Restarts the keepalive timer after activity on the connection.
+    fn restart_keepalive_timer(&mut self, now: time::Instant) {
+        self.keepalive_timer = None;
+
+        if !self.keepalive_enabled || !self.is_established() {
+            return;
+        }
+
+        let mut interval = match self.keepalive_interval {
+            Some(v) => v,
+
+            None => return,
+        };
+
+        if let Some(idle_timeout) = self.idle_timeout() {
+            interval = cmp::min(interval, idle_timeout / 2);
+        }
+
+        let jitter = (interval / KEEPALIVE_JITTER_DIVISOR).as_nanos() as u64;
+        let jitter =
+            time::Duration::from_nanos(rand::rand_u64_uniform(jitter + 1));
+
+        self.keepalive_timer = Some(now + interval - jitter);
+    }
+
     /// Returns the idle timeout value.
     ///
     /// `None` is returned if both end-points disabled the idle timeout.
@@ -7198,6 +12968,11 @@ impl Connection {
 
         self.tx_cap =
             cmp::min(cwin_available, self.max_tx_data - self.tx_data) as usize;
+
+        if let Some(max) = self.tx_max_buffered {
+            self.tx_cap =
+                cmp::min(self.tx_cap, max.saturating_sub(self.tx_buffered));
+        }
     }
 
     fn delivery_rate_check_if_app_limited(&self) -> bool {
@@ -7334,11 +13109,14 @@ impl Connection {
             false,
         );
 
-        path.max_send_bytes = buf_len * MAX_AMPLIFICATION_FACTOR;
+        path.max_send_bytes = buf_len * self.max_amplification_factor;
         path.active_scid_seq = Some(in_scid_seq);
 
-        // Automatically probes the new path.
-        path.request_validation();
+        // Automatically probes the new path, unless the application wants to
+        // decide by itself.
+        if self.new_path_policy == NewPathPolicy::Validate {
+            path.request_validation();
+        }
 
         let pid = self.paths.insert_path(path, self.is_server)?;
 
@@ -7406,6 +13184,128 @@ impl Connection {
         self.paths.set_active_path(path_id)
     }
 
+    /// Retires the Destination Connection ID `old_dcid_seq` that was used by
+    /// the previously active path, unless the new active path still uses it.
+    fn retire_migrated_dcid(&mut self, old_dcid_seq: Option<u64>) {
+        if self.ids.zero_length_dcid() {
+            return;
+        }
+
+        let old_dcid_seq = match old_dcid_seq {
+            Some(v) => v,
+
+            None => return,
+        };
+
+        let new_dcid_seq =
+            self.paths.get_active().ok().and_then(|p| p.active_dcid_seq);
+
+        if new_dcid_seq == Some(old_dcid_seq) {
+            return;
+        }
+
+        if let Err(e) = self.retire_dcid(old_dcid_seq) {
+            trace!(
+                "{} failed to retire dcid seq={} after migration: {:?}",
+                self.trace_id,
+                old_dcid_seq,
+                e
+            );
+        }
+    }
+
+    /// Returns true if the destination Connection ID of the active path should
+    /// be rotated.
+    fn dcid_rotation_due(&self, now: time::Instant) -> bool {
+        let time_due = self
+            .dcid_rotation_interval
+            .map_or(false, |i| now >= self.dcid_rotation_time + i);
+
+        let bytes_due = self.dcid_rotation_bytes.map_or(false, |b| {
+            self.sent_bytes - self.dcid_rotation_sent_bytes >= b
+        });
+
+        time_due || bytes_due
+    }
+
+    /// Returns true if enough packets were sent with the current keys that
+    /// they should be updated.
+    fn key_update_due(&self) -> bool {
+        let limit = match self.key_update_packets {
+            Some(v) => v,
+
+            None => match self.pkt_num_spaces[packet::Epoch::Application]
+                .crypto_seal
+                .as_ref()
+            {
+                // Leave some headroom below the confidentiality limit, so
+                // that the update completes before it is reached.
+                Some(seal) => seal.alg().confidentiality_limit() / 2,
+
+                None => return false,
+            },
+        };
+
+        (self.sent_count - self.key_update_sent_count) as u64 >= limit
+    }
+
+    /// Starts migrating to the server's preferred address, if it is reachable
+    /// with the address family of the current path.
+    fn start_preferred_address_migration(&mut self) {
+        let pa = match &self.peer_transport_params.preferred_address {
+            Some(v) => v,
+
+            None => return,
+        };
+
+        let (local_addr, peer_addr) = match self.paths.get_active() {
+            Ok(p) => (p.local_addr(), p.peer_addr()),
+
+            Err(_) => return,
+        };
+
+        let preferred_addr = match peer_addr {
+            SocketAddr::V4(_) => pa.ipv4.map(SocketAddr::V4),
+
+            SocketAddr::V6(_) => pa.ipv6.map(SocketAddr::V6),
+        };
+
+        let preferred_addr = match preferred_addr {
+            Some(v) => v,
+
+            None => {
+                self.migrate_to_preferred_address = false;
+                return;
+            },
+        };
+
+        match self.migrate_after_validation(local_addr, preferred_addr) {
+            Ok(_) => {
+                trace!(
+                    "{} migrating to preferred address {}",
+                    self.trace_id,
+                    preferred_addr
+                );
+
+                self.migrate_to_preferred_address = false;
+            },
+
+            // Try again once spare Connection IDs are available.
+            Err(Error::OutOfIdentifiers) => (),
+
+            Err(e) => {
+                trace!(
+                    "{} failed to migrate to preferred address {}: {:?}",
+                    self.trace_id,
+                    preferred_addr,
+                    e
+                );
+
+                self.migrate_to_preferred_address = false;
+            },
+        }
+    }
+
     /// Handles potential connection migration.
     fn on_peer_migrated(
         &mut self, new_pid: usize, disable_dcid_reuse: bool, now: time::Instant,
@@ -7472,6 +13372,44 @@ impl Connection {
         Ok(pid)
     }
 
+    /// Processes a packet received in the closing state.
+    ///
+    /// The CONNECTION_CLOSE frame is sent again in response, though less and
+    /// less often, so that a peer flooding the connection can't use it to
+    /// amplify its traffic (RFC 9000 Section 10.2.1).
+    fn on_closing_pkt_recv(&mut self) {
+        self.closing_recv_count += 1;
+
+        if self.closing_recv_count >= self.closing_resend_at {
+            self.closing_resend_at = self.closing_resend_at.saturating_mul(2);
+            self.close_resend = true;
+        }
+    }
+
+    /// Reports the connection's state to the metrics registry, if any.
+    fn update_metrics(&mut self) {
+        let metrics = match self.metrics.as_mut() {
+            Some(v) => v,
+
+            None => return,
+        };
+
+        let congestion_window = self
+            .paths
+            .get_active()
+            .map_or(0, |p| p.recovery.cwnd() as u64);
+
+        metrics.update(&metrics::ConnectionState {
+            handshake_completed: self.handshake_completed,
+            packets_sent: self.sent_count as u64,
+            packets_received: self.recv_count as u64,
+            packets_lost: self.lost_count as u64,
+            bytes_sent: self.sent_bytes,
+            bytes_lost: self.lost_bytes,
+            congestion_window,
+        });
+    }
+
     // Marks the connection as closed and does any related tidyup.
     fn mark_closed(&mut self) {
         #[cfg(feature = "qlog")]
@@ -7538,6 +13476,32 @@ impl std::fmt::Display for AddrTupleFmt {
     }
 }
 
+/// Parameters negotiated during the TLS handshake.
+///
+/// They can be collected using the [`handshake_info()`] method.
+///
+/// [`handshake_info()`]: struct.Connection.html#method.handshake_info
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HandshakeInfo {
+    /// The negotiated cipher suite.
+    pub cipher_suite: Option<CipherSuite>,
+
+    /// The negotiated key exchange group.
+    pub key_exchange_group: Option<String>,
+
+    /// The signature algorithm used by the peer to authenticate itself.
+    pub peer_signature_algorithm: Option<String>,
+
+    /// The negotiated ALPN protocol.
+    pub application_proto: Vec<u8>,
+
+    /// Whether the session was resumed.
+    pub resumed: bool,
+
+    /// The application settings (ALPS) sent by the peer.
+    pub peer_application_settings: Option<Vec<u8>>,
+}
+
 /// Statistics about the connection.
 ///
 /// A connection's statistics can be collected using the [`stats()`] method.
@@ -7586,27 +13550,179 @@ pub struct Stats {
 
     /// The total number of PATH_CHALLENGE frames that were received.
     pub path_challenge_rx_count: u64,
-}
 
-impl std::fmt::Debug for Stats {
-    #[inline]
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(
-            f,
-            "recv={} sent={} lost={} retrans={}",
-            self.recv, self.sent, self.lost, self.retrans,
-        )?;
+    /// The number of paths that were successfully validated.
+    pub path_validated_count: u64,
 
-        write!(
-            f,
-            " sent_bytes={} recv_bytes={} lost_bytes={}",
-            self.sent_bytes, self.recv_bytes, self.lost_bytes,
+    /// The number of paths that failed to be validated.
+    pub path_failed_validation_count: u64,
+
+    /// The number of packets dropped because they were received from a new
+    /// peer address, as configured with [`set_new_path_policy()`].
+    ///
+    /// [`set_new_path_policy()`]: struct.Config.html#method.set_new_path_policy
+    pub new_path_dropped: u64,
+
+    /// The number of times persistent congestion was established across all
+    /// known paths.
+    pub persistent_congestion_count: usize,
+
+    /// The number of probe timeouts (PTO) that expired in the Initial packet
+    /// number space.
+    pub pto_count_initial: usize,
+
+    /// The number of probe timeouts (PTO) that expired in the Handshake
+    /// packet number space.
+    pub pto_count_handshake: usize,
+
+    /// The number of probe timeouts (PTO) that expired in the Application
+    /// Data packet number space.
+    pub pto_count_application: usize,
+
+    /// The largest number of consecutive probe timeouts (PTO) across all known
+    /// paths, i.e. the longest exponential backoff that was reached.
+    pub longest_pto_backoff: u32,
+
+    /// The number of REPAIR frames sent, when FEC is enabled.
+    pub fec_repair_sent: usize,
+
+    /// The number of lost packets recovered using REPAIR frames received from
+    /// the peer, when FEC is enabled.
+    pub fec_recovered: usize,
+
+    /// The number of times the destination Connection ID of the active path
+    /// was rotated.
+    pub dcid_rotated: usize,
+
+    /// The number of key updates initiated by the local endpoint.
+    pub key_updates_local: usize,
+
+    /// The number of key updates initiated by the peer.
+    pub key_updates_remote: usize,
+
+    /// The number of outgoing DATAGRAMs dropped because they were not sent
+    /// before their TTL expired.
+    pub dgram_send_expired: usize,
+
+    /// The largest number of outgoing DATAGRAMs queued at once.
+    pub dgram_send_queue_peak_len: usize,
+
+    /// The largest number of incoming DATAGRAMs queued at once.
+    pub dgram_recv_queue_peak_len: usize,
+
+    /// The number of UDP datagrams sent.
+    pub sent_dgrams: usize,
+
+    /// The number of sent UDP datagrams that contained more than one QUIC
+    /// packet, as allowed by [`set_coalescing_policy()`].
+    ///
+    /// [`set_coalescing_policy()`]: struct.Config.html#method.set_coalescing_policy
+    pub coalesced_dgrams: usize,
+
+    /// The number of 0-RTT packets that had to be retransmitted because the
+    /// peer rejected early data. These are not counted as lost.
+    pub early_data_rejected: usize,
+
+    /// The number of bytes in 0-RTT packets rejected by the peer.
+    pub early_data_rejected_bytes: u64,
+
+    /// The timestamps of the handshake milestones.
+    pub handshake_timeline: HandshakeTimeline,
+}
+
+impl std::fmt::Debug for Stats {
+    #[inline]
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "recv={} sent={} lost={} retrans={}",
+            self.recv, self.sent, self.lost, self.retrans,
+        )?;
+
+        write!(
+            f,
+            " sent_bytes={} recv_bytes={} lost_bytes={}",
+            self.sent_bytes, self.recv_bytes, self.lost_bytes,
         )?;
 
         Ok(())
     }
 }
 
+/// Timestamps of the connection's handshake milestones.
+///
+/// Each field is `None` until the corresponding event happens. This is part
+/// of the connection's [`Stats`], and can help to find out which step of the
+/// handshake is slow, e.g. on lossy paths.
+///
+/// [`Stats`]: struct.Stats.html
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HandshakeTimeline {
+    /// When the connection was created.
+    pub created: Option<time::Instant>,
+
+    /// When the first packet was sent.
+    pub first_packet_sent: Option<time::Instant>,
+
+    /// When Initial keys became available.
+    pub initial_keys: Option<time::Instant>,
+
+    /// When Handshake keys became available.
+    pub handshake_keys: Option<time::Instant>,
+
+    /// When the handshake completed.
+    pub handshake_completed: Option<time::Instant>,
+
+    /// When the handshake was confirmed.
+    pub handshake_confirmed: Option<time::Instant>,
+
+    /// When the first 1-RTT packet was sent.
+    pub first_1rtt_sent: Option<time::Instant>,
+
+    /// When the first 1-RTT packet was received.
+    pub first_1rtt_recv: Option<time::Instant>,
+
+    /// Whether the server sent a HelloRetryRequest.
+    pub hello_retry_request: bool,
+
+    /// The number of times sending was stalled by the server's
+    /// anti-amplification limit.
+    pub amplification_limited: usize,
+}
+
+/// A server's preferred address, as carried by the `preferred_address`
+/// transport parameter.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PreferredAddress {
+    /// The IPv4 address and port, if any.
+    pub ipv4: Option<SocketAddrV4>,
+    /// The IPv6 address and port, if any.
+    pub ipv6: Option<SocketAddrV6>,
+    /// The Connection ID to use on the preferred address.
+    pub connection_id: ConnectionId<'static>,
+    /// The stateless reset token associated with the Connection ID.
+    pub stateless_reset_token: u128,
+}
+
+/// The versions an endpoint supports, as carried by the `version_information`
+/// transport parameter.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VersionInformation {
+    /// The version the endpoint is using for the connection.
+    pub chosen_version: u32,
+    /// The versions the endpoint supports, in order of preference.
+    pub available_versions: Vec<u32>,
+}
+
+impl PreferredAddress {
+    fn wire_len(&self) -> usize {
+        4 + 2 + // IPv4 address and port
+        16 + 2 + // IPv6 address and port
+        1 + self.connection_id.len() +
+        16 // stateless reset token
+    }
+}
+
 /// QUIC Transport Parameters
 #[derive(Clone, Debug, PartialEq)]
 pub struct TransportParams {
@@ -7646,7 +13762,22 @@ pub struct TransportParams {
     pub retry_source_connection_id: Option<ConnectionId<'static>>,
     /// DATAGRAM frame extension parameter, if any.
     pub max_datagram_frame_size: Option<u64>,
-    // pub preferred_address: ...,
+    /// Whether the experimental FEC extension is supported.
+    pub enable_fec: bool,
+    /// Whether the RESET_STREAM_AT frame is supported.
+    pub reset_stream_at: bool,
+    /// Whether packets with the QUIC bit cleared are accepted.
+    pub grease_quic_bit: bool,
+    /// The server's preferred address, if any.
+    pub preferred_address: Option<PreferredAddress>,
+    /// The versions used for compatible version negotiation, if any.
+    pub version_information: Option<VersionInformation>,
+    /// Transport parameters not handled by quiche, as added with
+    /// [`add_custom_transport_param()`] or received from the peer.
+    ///
+    /// [`add_custom_transport_param()`]:
+    /// struct.Config.html#method.add_custom_transport_param
+    pub custom_params: Vec<(u64, Vec<u8>)>,
 }
 
 impl Default for TransportParams {
@@ -7669,11 +13800,30 @@ impl Default for TransportParams {
             initial_source_connection_id: None,
             retry_source_connection_id: None,
             max_datagram_frame_size: None,
+            enable_fec: false,
+            reset_stream_at: false,
+            grease_quic_bit: false,
+            preferred_address: None,
+            version_information: None,
+            custom_params: Vec::new(),
         }
     }
 }
 
 impl TransportParams {
+    /// Returns the raw value of the transport parameter `id` not handled by
+    /// quiche, if present.
+    ///
+    /// See [`custom_params`] for the list of all such parameters.
+    ///
+    /// [`custom_params`]: struct.TransportParams.html#structfield.custom_params
+    pub fn custom_param(&self, id: u64) -> Option<&[u8]> {
+        self.custom_params
+            .iter()
+            .find(|(v, _)| *v == id)
+            .map(|(_, value)| value.as_slice())
+    }
+
     fn decode(buf: &[u8], is_server: bool) -> Result<TransportParams> {
         let mut params = octets::Octets::with_slice(buf);
         let mut seen_params = HashSet::new();
@@ -7790,7 +13940,57 @@ impl TransportParams {
                         return Err(Error::InvalidTransportParam);
                     }
 
-                    // TODO: decode preferred_address
+                    let ip: [u8; 4] = val
+                        .get_bytes(4)?
+                        .buf()
+                        .try_into()
+                        .map_err(|_| Error::BufferTooShort)?;
+                    let ip = Ipv4Addr::from(ip);
+                    let port = val.get_u16()?;
+
+                    let ipv4 = if ip.is_unspecified() && port == 0 {
+                        None
+                    } else {
+                        Some(SocketAddrV4::new(ip, port))
+                    };
+
+                    let ip: [u8; 16] = val
+                        .get_bytes(16)?
+                        .buf()
+                        .try_into()
+                        .map_err(|_| Error::BufferTooShort)?;
+                    let ip = Ipv6Addr::from(ip);
+                    let port = val.get_u16()?;
+
+                    let ipv6 = if ip.is_unspecified() && port == 0 {
+                        None
+                    } else {
+                        Some(SocketAddrV6::new(ip, port, 0, 0))
+                    };
+
+                    // The preferred address can't use a zero-length
+                    // Connection ID.
+                    let cid_len = val.get_u8()? as usize;
+
+                    if cid_len == 0 || cid_len > MAX_CONN_ID_LEN {
+                        return Err(Error::InvalidTransportParam);
+                    }
+
+                    let connection_id = val.get_bytes(cid_len)?.to_vec().into();
+
+                    let stateless_reset_token = u128::from_be_bytes(
+                        val.get_bytes(16)?
+                            .to_vec()
+                            .try_into()
+                            .map_err(|_| Error::BufferTooShort)?,
+                    );
+
+                    tp.preferred_address = Some(PreferredAddress {
+                        ipv4,
+                        ipv6,
+                        connection_id,
+                        stateless_reset_token,
+                    });
                 },
 
                 0x000e => {
@@ -7819,14 +14019,79 @@ impl TransportParams {
                     tp.max_datagram_frame_size = Some(val.get_varint()?);
                 },
 
-                // Ignore unknown parameters.
-                _ => (),
+                FEC_TRANSPORT_PARAM => {
+                    tp.enable_fec = true;
+                },
+
+                RESET_STREAM_AT_TRANSPORT_PARAM => {
+                    tp.reset_stream_at = true;
+                },
+
+                GREASE_QUIC_BIT_TRANSPORT_PARAM => {
+                    tp.grease_quic_bit = true;
+                },
+
+                0x0011 => {
+                    let chosen_version = val.get_u32()?;
+
+                    if chosen_version == 0 || val.cap() % 4 != 0 {
+                        return Err(Error::InvalidTransportParam);
+                    }
+
+                    let mut available_versions = Vec::new();
+
+                    while val.cap() > 0 {
+                        available_versions.push(val.get_u32()?);
+                    }
+
+                    tp.version_information = Some(VersionInformation {
+                        chosen_version,
+                        available_versions,
+                    });
+                },
+
+                // Ignore reserved parameters.
+                _ if TransportParams::is_grease_param(id) => (),
+
+                // Keep unknown parameters for the application.
+                _ => {
+                    tp.custom_params.push((id, val.to_vec()));
+                },
             }
         }
 
         Ok(tp)
     }
 
+    /// Returns true if the given transport parameter is handled by quiche.
+    fn is_known_param(id: u64) -> bool {
+        matches!(
+            id,
+            0x0000..=
+                0x0011 |
+                    0x0020 |
+                    FEC_TRANSPORT_PARAM |
+                    RESET_STREAM_AT_TRANSPORT_PARAM |
+                    GREASE_QUIC_BIT_TRANSPORT_PARAM
+        )
+    }
+
+    /// Returns true if the given transport parameter is reserved, as per
+    /// RFC 9000 Section 18.1.
+    fn is_grease_param(id: u64) -> bool {
+        id >= 27 && (id - 27) % 31 == 0
+    }
+
+    /// Returns the size of the buffer needed to encode the parameters,
+    /// including a reserved one.
+    fn max_encoded_len(&self) -> usize {
+        self.custom_params
+            .iter()
+            .map(|(_, v)| 16 + v.len())
+            .sum::<usize>() +
+            512
+    }
+
     fn encode_param(
         b: &mut octets::OctetsMut, ty: u64, len: usize,
     ) -> Result<()> {
@@ -7836,6 +14101,25 @@ impl TransportParams {
         Ok(())
     }
 
+    /// Appends a reserved transport parameter with a random payload, which
+    /// the peer must ignore. Returns the number of bytes written.
+    fn encode_grease(out: &mut [u8]) -> Result<usize> {
+        let mut payload = [0; 16];
+        let payload_len = rand::rand_u64_uniform(payload.len() as u64) as usize;
+        rand::rand_bytes(&mut payload[..payload_len]);
+
+        let mut b = octets::OctetsMut::with_slice(out);
+
+        TransportParams::encode_param(
+            &mut b,
+            grease_transport_param_id(),
+            payload_len,
+        )?;
+        b.put_bytes(&payload[..payload_len])?;
+
+        Ok(b.off())
+    }
+
     fn encode<'a>(
         tp: &TransportParams, is_server: bool, out: &'a mut [u8],
     ) -> Result<&'a mut [u8]> {
@@ -7949,7 +14233,28 @@ impl TransportParams {
             TransportParams::encode_param(&mut b, 0x000c, 0)?;
         }
 
-        // TODO: encode preferred_address
+        if is_server {
+            if let Some(pa) = &tp.preferred_address {
+                TransportParams::encode_param(&mut b, 0x000d, pa.wire_len())?;
+
+                let ipv4 = pa.ipv4.unwrap_or_else(|| {
+                    SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)
+                });
+                b.put_bytes(&ipv4.ip().octets())?;
+                b.put_u16(ipv4.port())?;
+
+                let ipv6 = pa.ipv6.unwrap_or_else(|| {
+                    SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0)
+                });
+                b.put_bytes(&ipv6.ip().octets())?;
+                b.put_u16(ipv6.port())?;
+
+                b.put_u8(pa.connection_id.len() as u8)?;
+                b.put_bytes(&pa.connection_id)?;
+
+                b.put_bytes(&pa.stateless_reset_token.to_be_bytes())?;
+            }
+        }
 
         if tp.active_conn_id_limit != 2 {
             TransportParams::encode_param(
@@ -7981,6 +14286,44 @@ impl TransportParams {
             b.put_varint(max_datagram_frame_size)?;
         }
 
+        if tp.enable_fec {
+            TransportParams::encode_param(&mut b, FEC_TRANSPORT_PARAM, 0)?;
+        }
+
+        if tp.reset_stream_at {
+            TransportParams::encode_param(
+                &mut b,
+                RESET_STREAM_AT_TRANSPORT_PARAM,
+                0,
+            )?;
+        }
+
+        if tp.grease_quic_bit {
+            TransportParams::encode_param(
+                &mut b,
+                GREASE_QUIC_BIT_TRANSPORT_PARAM,
+                0,
+            )?;
+        }
+
+        if let Some(info) = &tp.version_information {
+            TransportParams::encode_param(
+                &mut b,
+                0x0011,
+                4 + 4 * info.available_versions.len(),
+            )?;
+            b.put_u32(info.chosen_version)?;
+
+            for v in &info.available_versions {
+                b.put_u32(*v)?;
+            }
+        }
+
+        for (id, value) in &tp.custom_params {
+            TransportParams::encode_param(&mut b, *id, value.len())?;
+            b.put_bytes(value)?;
+        }
+
         let out_len = b.off();
 
         Ok(&mut out[..out_len])
@@ -8032,113 +14375,550 @@ impl TransportParams {
                 initial_max_streams_bidi: Some(self.initial_max_streams_bidi),
                 initial_max_streams_uni: Some(self.initial_max_streams_uni),
 
-                preferred_address: None,
+                preferred_address: self.preferred_address.as_ref().map(|pa| {
+                    qlog::events::quic::PreferredAddress {
+                        ip_v4: pa
+                            .ipv4
+                            .map(|a| a.ip().to_string())
+                            .unwrap_or_default(),
+                        ip_v6: pa
+                            .ipv6
+                            .map(|a| a.ip().to_string())
+                            .unwrap_or_default(),
+                        port_v4: pa.ipv4.map(|a| a.port()).unwrap_or_default(),
+                        port_v6: pa.ipv6.map(|a| a.port()).unwrap_or_default(),
+                        connection_id: format!(
+                            "{}",
+                            qlog::HexSlice::new(&pa.connection_id)
+                        ),
+                        stateless_reset_token: format!(
+                            "{}",
+                            qlog::HexSlice::new(
+                                &pa.stateless_reset_token.to_be_bytes()
+                            )
+                        ),
+                    }
+                }),
             },
         )
     }
 }
 
-#[doc(hidden)]
+/// A socket-free harness for testing applications built on quiche.
+///
+/// A [`Pipe`] holds a client and a server connection, and moves packets
+/// between them in memory. The packets sent in each direction go through a
+/// [`Link`], which can drop, delay and reorder them.
+///
+/// [`Pipe`]: struct.Pipe.html
+/// [`Link`]: struct.Link.html
 pub mod testing {
     use super::*;
 
-    pub struct Pipe {
-        pub client: Connection,
-        pub server: Connection,
+    /// A model of the network a [`Link`] emulates.
+    ///
+    /// The model decides the fate of each packet sent through the link. This
+    /// can be implemented to emulate specific network conditions, in addition
+    /// to the ones provided by [`EmulatedLink`].
+    ///
+    /// [`Link`]: struct.Link.html
+    /// [`EmulatedLink`]: struct.EmulatedLink.html
+    pub trait LinkModel: Send {
+        /// Called for each packet of `len` bytes sent at `now`.
+        ///
+        /// Returns when the packet is delivered, or `None` if it is dropped.
+        /// Packets are delivered in the order of the returned times, so
+        /// varying delays reorder packets.
+        fn on_packet(
+            &mut self, len: usize, now: time::Instant,
+        ) -> Option<time::Instant>;
     }
 
-    impl Pipe {
-        pub fn new() -> Result<Pipe> {
-            let mut config = Config::new(crate::PROTOCOL_VERSION)?;
-            config.load_cert_chain_from_pem_file("examples/cert.crt")?;
-            config.load_priv_key_from_pem_file("examples/cert.key")?;
-            config.set_application_protos(&[b"proto1", b"proto2"])?;
-            config.set_initial_max_data(30);
-            config.set_initial_max_stream_data_bidi_local(15);
-            config.set_initial_max_stream_data_bidi_remote(15);
-            config.set_initial_max_stream_data_uni(10);
-            config.set_initial_max_streams_bidi(3);
-            config.set_initial_max_streams_uni(3);
-            config.set_max_idle_timeout(180_000);
-            config.verify_peer(false);
-            config.set_ack_delay_exponent(8);
+    /// A seedable pseudo-random generator, so impairments are reproducible.
+    ///
+    /// This is SplitMix64, which is good enough to pick packets and doesn't
+    /// need any state beyond the seed.
+    #[derive(Clone, Copy, Default)]
+    struct Rng(u64);
 
-            Pipe::with_config(&mut config)
+    impl Rng {
+        fn next_u64(&mut self) -> u64 {
+            self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+
+            let mut z = self.0;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            z ^ (z >> 31)
         }
 
-        pub fn client_addr() -> SocketAddr {
-            "127.0.0.1:1234".parse().unwrap()
+        /// Returns a number uniformly distributed in `[0, 1)`.
+        fn next_f64(&mut self) -> f64 {
+            (self.next_u64() >> 11) as f64 / (1_u64 << 53) as f64
         }
 
-        pub fn server_addr() -> SocketAddr {
-            "127.0.0.1:4321".parse().unwrap()
+        /// Returns true with the given probability.
+        fn chance(&mut self, p: f64) -> bool {
+            p > 0.0 && self.next_f64() < p
         }
+    }
 
-        pub fn with_config(config: &mut Config) -> Result<Pipe> {
-            let mut client_scid = [0; 16];
-            rand::rand_bytes(&mut client_scid[..]);
-            let client_scid = ConnectionId::from_ref(&client_scid);
-            let client_addr = Pipe::client_addr();
+    /// A [`LinkModel`] emulating a bottleneck link, with a limited bandwidth
+    /// and queue, random and burst losses, and delay jitter.
+    ///
+    /// All the parameters are disabled by default. Losses and jitter are
+    /// picked by a pseudo-random generator seeded on creation.
+    ///
+    /// [`LinkModel`]: trait.LinkModel.html
+    pub struct EmulatedLink {
+        bandwidth: u64,
 
-            let mut server_scid = [0; 16];
-            rand::rand_bytes(&mut server_scid[..]);
-            let server_scid = ConnectionId::from_ref(&server_scid);
-            let server_addr = Pipe::server_addr();
+        queue_size: usize,
 
-            Ok(Pipe {
-                client: connect(
-                    Some("quic.tech"),
-                    &client_scid,
-                    client_addr,
-                    server_addr,
-                    config,
-                )?,
-                server: accept(
-                    &server_scid,
-                    None,
-                    server_addr,
-                    client_addr,
-                    config,
-                )?,
-            })
+        loss: f64,
+
+        burst_enter: f64,
+
+        burst_exit: f64,
+
+        in_burst: bool,
+
+        delay: time::Duration,
+
+        jitter: time::Duration,
+
+        busy_until: Option<time::Instant>,
+
+        rng: Rng,
+    }
+
+    impl EmulatedLink {
+        /// Creates a new link without any impairment, whose random decisions
+        /// are derived from `seed`.
+        pub fn new(seed: u64) -> EmulatedLink {
+            EmulatedLink {
+                bandwidth: 0,
+                queue_size: 0,
+                loss: 0.0,
+                burst_enter: 0.0,
+                burst_exit: 1.0,
+                in_burst: false,
+                delay: time::Duration::ZERO,
+                jitter: time::Duration::ZERO,
+                busy_until: None,
+                rng: Rng(seed),
+            }
         }
 
-        pub fn with_config_and_scid_lengths(
-            config: &mut Config, client_scid_len: usize, server_scid_len: usize,
-        ) -> Result<Pipe> {
-            let mut client_scid = vec![0; client_scid_len];
-            rand::rand_bytes(&mut client_scid[..]);
-            let client_scid = ConnectionId::from_ref(&client_scid);
-            let client_addr = Pipe::client_addr();
+        /// Creates a link resembling a geostationary satellite path.
+        pub fn satellite(seed: u64) -> EmulatedLink {
+            let mut link = EmulatedLink::new(seed);
+            link.set_bandwidth(20_000_000);
+            link.set_queue_size(1_000_000);
+            link.set_delay(time::Duration::from_millis(300));
+            link.set_jitter(time::Duration::from_millis(10));
+            link.set_loss(0.001);
+            link
+        }
 
-            let mut server_scid = vec![0; server_scid_len];
-            rand::rand_bytes(&mut server_scid[..]);
-            let server_scid = ConnectionId::from_ref(&server_scid);
-            let server_addr = Pipe::server_addr();
+        /// Creates a link resembling a cellular LTE path.
+        pub fn lte(seed: u64) -> EmulatedLink {
+            let mut link = EmulatedLink::new(seed);
+            link.set_bandwidth(30_000_000);
+            link.set_queue_size(250_000);
+            link.set_delay(time::Duration::from_millis(35));
+            link.set_jitter(time::Duration::from_millis(20));
+            link.set_loss(0.001);
+            link.set_burst_loss(0.002, 0.3);
+            link
+        }
 
-            Ok(Pipe {
-                client: connect(
-                    Some("quic.tech"),
-                    &client_scid,
-                    client_addr,
-                    server_addr,
-                    config,
-                )?,
-                server: accept(
-                    &server_scid,
-                    None,
-                    server_addr,
-                    client_addr,
-                    config,
-                )?,
-            })
+        /// Sets the link's bandwidth, in bits per second.
+        ///
+        /// The value of 0 means unlimited bandwidth.
+        pub fn set_bandwidth(&mut self, bps: u64) {
+            self.bandwidth = bps;
         }
 
-        pub fn with_client_config(client_config: &mut Config) -> Result<Pipe> {
-            let mut client_scid = [0; 16];
-            rand::rand_bytes(&mut client_scid[..]);
-            let client_scid = ConnectionId::from_ref(&client_scid);
-            let client_addr = Pipe::client_addr();
+        /// Sets the size of the link's queue, in bytes. Packets that don't fit
+        /// in the queue are dropped.
+        ///
+        /// The value of 0 means an unlimited queue. The queue is only used when
+        /// the bandwidth is limited.
+        pub fn set_queue_size(&mut self, bytes: usize) {
+            self.queue_size = bytes;
+        }
+
+        /// Sets the probability, between 0 and 1, that a packet is dropped.
+        pub fn set_loss(&mut self, p: f64) {
+            self.loss = p;
+        }
+
+        /// Sets the probabilities, between 0 and 1, that a loss burst starts
+        /// and ends with each packet. All packets are dropped during a burst.
+        pub fn set_burst_loss(&mut self, enter: f64, exit: f64) {
+            self.burst_enter = enter;
+            self.burst_exit = exit;
+        }
+
+        /// Sets the one-way propagation delay.
+        pub fn set_delay(&mut self, delay: time::Duration) {
+            self.delay = delay;
+        }
+
+        /// Sets the maximum random delay added to each packet.
+        pub fn set_jitter(&mut self, jitter: time::Duration) {
+            self.jitter = jitter;
+        }
+    }
+
+    impl LinkModel for EmulatedLink {
+        fn on_packet(
+            &mut self, len: usize, now: time::Instant,
+        ) -> Option<time::Instant> {
+            // Gilbert-Elliott model: switch state first, then drop everything
+            // while in a burst.
+            self.in_burst = if self.in_burst {
+                !self.rng.chance(self.burst_exit)
+            } else {
+                self.rng.chance(self.burst_enter)
+            };
+
+            if self.in_burst || self.rng.chance(self.loss) {
+                return None;
+            }
+
+            let mut sent = now;
+
+            if self.bandwidth > 0 {
+                let busy_until = self.busy_until.map_or(now, |t| t.max(now));
+
+                let queued = (busy_until - now).as_secs_f64() *
+                    self.bandwidth as f64 /
+                    8.0;
+
+                if self.queue_size > 0 && queued as usize + len > self.queue_size
+                {
+                    return None;
+                }
+
+                sent = busy_until +
+                    time::Duration::from_secs_f64(
+                        (len * 8) as f64 / self.bandwidth as f64,
+                    );
+
+                self.busy_until = Some(sent);
+            }
+
+            let jitter = self.jitter.mul_f64(self.rng.next_f64());
+
+            Some(sent + self.delay + jitter)
+        }
+    }
+
+    /// Network impairments applied to the packets sent in one direction of a
+    /// [`Pipe`].
+    ///
+    /// The packets to drop and reorder are picked by a pseudo-random generator
+    /// seeded with [`set_seed()`], so the same configuration always impairs the
+    /// same packets. A [`LinkModel`] can be set for more elaborate network
+    /// conditions.
+    ///
+    /// [`Pipe`]: struct.Pipe.html
+    /// [`set_seed()`]: struct.Link.html#method.set_seed
+    /// [`LinkModel`]: trait.LinkModel.html
+    #[derive(Default)]
+    pub struct Link {
+        loss: u8,
+
+        delay: time::Duration,
+
+        reorder: u8,
+
+        rng: Rng,
+
+        model: Option<Box<dyn LinkModel>>,
+
+        queue: VecDeque<(time::Instant, Vec<u8>, SendInfo)>,
+
+        held: Option<(time::Instant, Vec<u8>, SendInfo)>,
+
+        dropped: usize,
+
+        reordered: usize,
+    }
+
+    impl Link {
+        /// Sets the percentage of packets that are dropped.
+        ///
+        /// Values higher than 100 are treated as 100.
+        pub fn set_loss(&mut self, percent: u8) {
+            self.loss = cmp::min(percent, 100);
+        }
+
+        /// Sets the one-way delay added to every packet.
+        pub fn set_delay(&mut self, delay: time::Duration) {
+            self.delay = delay;
+        }
+
+        /// Sets the percentage of packets that are delivered after the packet
+        /// that follows them.
+        ///
+        /// Values higher than 100 are treated as 100.
+        pub fn set_reorder(&mut self, percent: u8) {
+            self.reorder = cmp::min(percent, 100);
+        }
+
+        /// Sets the seed of the generator that picks the packets to drop and
+        /// reorder.
+        pub fn set_seed(&mut self, seed: u64) {
+            self.rng = Rng(seed);
+        }
+
+        /// Sets the model deciding when packets are delivered, or whether
+        /// they are dropped.
+        ///
+        /// The model's decisions apply before the link's own loss, delay and
+        /// reordering.
+        pub fn set_model(&mut self, model: Box<dyn LinkModel>) {
+            self.model = Some(model);
+        }
+
+        /// Returns the number of packets dropped so far.
+        pub fn dropped(&self) -> usize {
+            self.dropped
+        }
+
+        /// Returns the number of packets reordered so far.
+        ///
+        /// This doesn't account for packets reordered by the link's model.
+        pub fn reordered(&self) -> usize {
+            self.reordered
+        }
+
+        /// Returns true if there are packets that have yet to be delivered.
+        pub fn has_pending(&self) -> bool {
+            !self.queue.is_empty() || self.held.is_some()
+        }
+
+        /// Returns true with the given probability, in percent.
+        fn roll(&mut self, percent: u8) -> bool {
+            percent > 0 && self.rng.next_u64() % 100 < percent as u64
+        }
+
+        /// Queues a packet, keeping the queue ordered by delivery time.
+        fn enqueue(&mut self, pkt: (time::Instant, Vec<u8>, SendInfo)) {
+            let pos = self
+                .queue
+                .iter()
+                .rposition(|(release, ..)| *release <= pkt.0);
+
+            match pos {
+                Some(pos) => self.queue.insert(pos + 1, pkt),
+
+                None => self.queue.push_front(pkt),
+            }
+        }
+
+        /// Queues the given packets for delivery, applying the impairments.
+        fn send(&mut self, flight: Vec<(Vec<u8>, SendInfo)>) {
+            let now = time::Instant::now();
+
+            for (pkt, info) in flight {
+                let release = match &mut self.model {
+                    Some(model) => match model.on_packet(pkt.len(), now) {
+                        Some(release) => release,
+
+                        None => {
+                            self.dropped += 1;
+                            continue;
+                        },
+                    },
+
+                    None => now,
+                };
+
+                let release = release + self.delay;
+
+                if self.roll(self.loss) {
+                    self.dropped += 1;
+                    continue;
+                }
+
+                if let Some(held) = self.held.take() {
+                    self.enqueue((release, pkt, info));
+                    self.enqueue((cmp::max(held.0, release), held.1, held.2));
+                } else if self.roll(self.reorder) {
+                    self.reordered += 1;
+                    self.held = Some((release, pkt, info));
+                } else {
+                    self.enqueue((release, pkt, info));
+                }
+            }
+        }
+
+        /// Returns the packets whose delay has elapsed.
+        fn deliver(&mut self, now: time::Instant) -> Vec<(Vec<u8>, SendInfo)> {
+            let mut flight = Vec::new();
+
+            while let Some((release, ..)) = self.queue.front() {
+                if *release > now {
+                    break;
+                }
+
+                if let Some((_, pkt, info)) = self.queue.pop_front() {
+                    flight.push((pkt, info));
+                }
+            }
+
+            flight
+        }
+
+        /// Returns when the next packet is due for delivery.
+        ///
+        /// A reordered packet that no other packet followed is released
+        /// once nothing else is pending.
+        fn next_release(&mut self) -> Option<time::Instant> {
+            if self.queue.is_empty() {
+                if let Some(held) = self.held.take() {
+                    self.queue.push_back(held);
+                }
+            }
+
+            self.queue.front().map(|(release, ..)| *release)
+        }
+    }
+
+    /// A client and a server connection exchanging packets in memory.
+    pub struct Pipe {
+        /// The client connection.
+        pub client: Connection,
+
+        /// The server connection.
+        pub server: Connection,
+
+        /// The impairments applied to packets sent by the client.
+        pub client_link: Link,
+
+        /// The impairments applied to packets sent by the server.
+        pub server_link: Link,
+    }
+
+    impl Pipe {
+        /// Creates a new pipe with a default configuration.
+        ///
+        /// The certificate and key are loaded from the `examples` directory,
+        /// so this only works when run from the quiche crate's directory. Use
+        /// [`with_config()`] otherwise.
+        ///
+        /// [`with_config()`]: struct.Pipe.html#method.with_config
+        pub fn new() -> Result<Pipe> {
+            let mut config = Config::new(crate::PROTOCOL_VERSION)?;
+            config.load_cert_chain_from_pem_file("examples/cert.crt")?;
+            config.load_priv_key_from_pem_file("examples/cert.key")?;
+            config.set_application_protos(&[b"proto1", b"proto2"])?;
+            config.set_initial_max_data(30);
+            config.set_initial_max_stream_data_bidi_local(15);
+            config.set_initial_max_stream_data_bidi_remote(15);
+            config.set_initial_max_stream_data_uni(10);
+            config.set_initial_max_streams_bidi(3);
+            config.set_initial_max_streams_uni(3);
+            config.set_max_idle_timeout(180_000);
+            config.verify_peer(false);
+            config.set_ack_delay_exponent(8);
+
+            Pipe::with_config(&mut config)
+        }
+
+        /// Returns the address used by the client.
+        pub fn client_addr() -> SocketAddr {
+            "127.0.0.1:1234".parse().unwrap()
+        }
+
+        /// Returns the address used by the server.
+        pub fn server_addr() -> SocketAddr {
+            "127.0.0.1:4321".parse().unwrap()
+        }
+
+        /// Creates a new pipe where both connections use the given
+        /// configuration.
+        pub fn with_config(config: &mut Config) -> Result<Pipe> {
+            let mut client_scid = [0; 16];
+            rand::rand_bytes(&mut client_scid[..]);
+            let client_scid = ConnectionId::from_ref(&client_scid);
+            let client_addr = Pipe::client_addr();
+
+            let mut server_scid = [0; 16];
+            rand::rand_bytes(&mut server_scid[..]);
+            let server_scid = ConnectionId::from_ref(&server_scid);
+            let server_addr = Pipe::server_addr();
+
+            Ok(Pipe {
+                client: connect(
+                    Some("quic.tech"),
+                    &client_scid,
+                    client_addr,
+                    server_addr,
+                    config,
+                )?,
+                server: accept(
+                    &server_scid,
+                    None,
+                    server_addr,
+                    client_addr,
+                    config,
+                )?,
+                client_link: Default::default(),
+                server_link: Default::default(),
+            })
+        }
+
+        /// Creates a new pipe where both connections use the given
+        /// configuration, and source connection IDs of the given lengths.
+        pub fn with_config_and_scid_lengths(
+            config: &mut Config, client_scid_len: usize, server_scid_len: usize,
+        ) -> Result<Pipe> {
+            let mut client_scid = vec![0; client_scid_len];
+            rand::rand_bytes(&mut client_scid[..]);
+            let client_scid = ConnectionId::from_ref(&client_scid);
+            let client_addr = Pipe::client_addr();
+
+            let mut server_scid = vec![0; server_scid_len];
+            rand::rand_bytes(&mut server_scid[..]);
+            let server_scid = ConnectionId::from_ref(&server_scid);
+            let server_addr = Pipe::server_addr();
+
+            Ok(Pipe {
+                client: connect(
+                    Some("quic.tech"),
+                    &client_scid,
+                    client_addr,
+                    server_addr,
+                    config,
+                )?,
+                server: accept(
+                    &server_scid,
+                    None,
+                    server_addr,
+                    client_addr,
+                    config,
+                )?,
+                client_link: Default::default(),
+                server_link: Default::default(),
+            })
+        }
+
+        /// Creates a new pipe with the given client configuration, and a
+        /// default server configuration.
+        ///
+        /// Like [`new()`], this loads the server certificate from the
+        /// `examples` directory.
+        ///
+        /// [`new()`]: struct.Pipe.html#method.new
+        pub fn with_client_config(client_config: &mut Config) -> Result<Pipe> {
+            let mut client_scid = [0; 16];
+            rand::rand_bytes(&mut client_scid[..]);
+            let client_scid = ConnectionId::from_ref(&client_scid);
+            let client_addr = Pipe::client_addr();
 
             let mut server_scid = [0; 16];
             rand::rand_bytes(&mut server_scid[..]);
@@ -8171,9 +14951,13 @@ pub mod testing {
                     client_addr,
                     &mut config,
                 )?,
+                client_link: Default::default(),
+                server_link: Default::default(),
             })
         }
 
+        /// Creates a new pipe with the given server configuration, and a
+        /// default client configuration.
         pub fn with_server_config(server_config: &mut Config) -> Result<Pipe> {
             let mut client_scid = [0; 16];
             rand::rand_bytes(&mut client_scid[..]);
@@ -8209,9 +14993,12 @@ pub mod testing {
                     client_addr,
                     server_config,
                 )?,
+                client_link: Default::default(),
+                server_link: Default::default(),
             })
         }
 
+        /// Creates a new pipe with the given client and server configurations.
         pub fn with_client_and_server_config(
             client_config: &mut Config, server_config: &mut Config,
         ) -> Result<Pipe> {
@@ -8240,9 +15027,14 @@ pub mod testing {
                     client_addr,
                     server_config,
                 )?,
+                client_link: Default::default(),
+                server_link: Default::default(),
             })
         }
 
+        /// Exchanges packets until both connections are established.
+        ///
+        /// Packets are not impaired during the handshake.
         pub fn handshake(&mut self) -> Result<()> {
             while !self.client.is_established() || !self.server.is_established() {
                 let flight = emit_flight(&mut self.client)?;
@@ -8255,31 +15047,92 @@ pub mod testing {
             Ok(())
         }
 
+        /// Exchanges packets until neither connection has anything to send,
+        /// and all the packets in flight have been delivered.
+        ///
+        /// Packets go through the client and server links, so this blocks
+        /// while delayed packets are in flight. Timers are not processed, see
+        /// [`on_timeout()`].
+        ///
+        /// [`on_timeout()`]: struct.Pipe.html#method.on_timeout
         pub fn advance(&mut self) -> Result<()> {
             let mut client_done = false;
             let mut server_done = false;
 
-            while !client_done || !server_done {
-                match emit_flight(&mut self.client) {
-                    Ok(flight) => process_flight(&mut self.server, flight)?,
+            loop {
+                while !client_done || !server_done {
+                    match emit_flight(&mut self.client) {
+                        Ok(flight) => self.client_link.send(flight),
 
-                    Err(Error::Done) => client_done = true,
+                        Err(Error::Done) => client_done = true,
 
-                    Err(e) => return Err(e),
-                };
+                        Err(e) => return Err(e),
+                    };
 
-                match emit_flight(&mut self.server) {
-                    Ok(flight) => process_flight(&mut self.client, flight)?,
+                    let flight = self.client_link.deliver(time::Instant::now());
+                    process_flight(&mut self.server, flight)?;
 
-                    Err(Error::Done) => server_done = true,
+                    match emit_flight(&mut self.server) {
+                        Ok(flight) => self.server_link.send(flight),
 
-                    Err(e) => return Err(e),
-                };
+                        Err(Error::Done) => server_done = true,
+
+                        Err(e) => return Err(e),
+                    };
+
+                    let flight = self.server_link.deliver(time::Instant::now());
+                    process_flight(&mut self.client, flight)?;
+                }
+
+                let next_release = [
+                    self.client_link.next_release(),
+                    self.server_link.next_release(),
+                ]
+                .iter()
+                .flatten()
+                .min()
+                .copied();
+
+                match next_release {
+                    Some(release) => {
+                        let now = time::Instant::now();
+
+                        if release > now {
+                            std::thread::sleep(release - now);
+                        }
+
+                        client_done = false;
+                        server_done = false;
+                    },
+
+                    None => return Ok(()),
+                }
             }
+        }
+
+        /// Waits for the earliest timer of either connection to expire, and
+        /// processes it.
+        ///
+        /// Returns [`Done`] if neither connection has a timer armed.
+        ///
+        /// [`Done`]: ../enum.Error.html#variant.Done
+        pub fn on_timeout(&mut self) -> Result<()> {
+            let timeout = [self.client.timeout(), self.server.timeout()]
+                .iter()
+                .flatten()
+                .min()
+                .copied()
+                .ok_or(Error::Done)?;
+
+            std::thread::sleep(timeout);
+
+            self.client.on_timeout();
+            self.server.on_timeout();
 
             Ok(())
         }
 
+        /// Processes the given packet on the client, as if sent by the server.
         pub fn client_recv(&mut self, buf: &mut [u8]) -> Result<usize> {
             let server_path = &self.server.paths.get_active().unwrap();
             let info = RecvInfo {
@@ -8290,6 +15143,7 @@ pub mod testing {
             self.client.recv(buf, info)
         }
 
+        /// Processes the given packet on the server, as if sent by the client.
         pub fn server_recv(&mut self, buf: &mut [u8]) -> Result<usize> {
             let client_path = &self.client.paths.get_active().unwrap();
             let info = RecvInfo {
@@ -8300,6 +15154,11 @@ pub mod testing {
             self.server.recv(buf, info)
         }
 
+        /// Encodes a packet with the given frames as the client, and processes
+        /// it on the server.
+        ///
+        /// The server's response, if any, is written to `buf` and its length
+        /// returned.
         pub fn send_pkt_to_server(
             &mut self, pkt_type: packet::Type, frames: &[frame::Frame],
             buf: &mut [u8],
@@ -8308,6 +15167,7 @@ pub mod testing {
             recv_send(&mut self.server, buf, written)
         }
 
+        /// Makes the client initiate a key update.
         pub fn client_update_key(&mut self) -> Result<()> {
             let space =
                 &mut self.client.pkt_num_spaces[packet::Epoch::Application];
@@ -8341,6 +15201,8 @@ pub mod testing {
         }
     }
 
+    /// Processes the packet in `buf` on the given connection, and writes its
+    /// response, if any, in the same buffer.
     pub fn recv_send(
         conn: &mut Connection, buf: &mut [u8], len: usize,
     ) -> Result<usize> {
@@ -8365,6 +15227,7 @@ pub mod testing {
         Ok(off)
     }
 
+    /// Processes a flight of packets on the given connection.
     pub fn process_flight(
         conn: &mut Connection, flight: Vec<(Vec<u8>, SendInfo)>,
     ) -> Result<()> {
@@ -8380,6 +15243,12 @@ pub mod testing {
         Ok(())
     }
 
+    /// Collects all the packets the given connection has to send on the given
+    /// path, each at most `out_size` bytes long.
+    ///
+    /// Returns [`Done`] if there is nothing to send.
+    ///
+    /// [`Done`]: ../enum.Error.html#variant.Done
     pub fn emit_flight_with_max_buffer(
         conn: &mut Connection, out_size: usize, from: Option<SocketAddr>,
         to: Option<SocketAddr>,
@@ -8410,18 +15279,23 @@ pub mod testing {
         Ok(flight)
     }
 
+    /// Collects all the packets the given connection has to send on the given
+    /// path.
     pub fn emit_flight_on_path(
         conn: &mut Connection, from: Option<SocketAddr>, to: Option<SocketAddr>,
     ) -> Result<Vec<(Vec<u8>, SendInfo)>> {
         emit_flight_with_max_buffer(conn, 65535, from, to)
     }
 
+    /// Collects all the packets the given connection has to send.
     pub fn emit_flight(
         conn: &mut Connection,
     ) -> Result<Vec<(Vec<u8>, SendInfo)>> {
         emit_flight_on_path(conn, None, None)
     }
 
+    /// Encodes and protects a packet of the given type containing the given
+    /// frames, as sent by the given connection.
     pub fn encode_pkt(
         conn: &mut Connection, pkt_type: packet::Type, frames: &[frame::Frame],
         buf: &mut [u8],
@@ -8500,6 +15374,7 @@ pub mod testing {
         Ok(written)
     }
 
+    /// Decrypts a packet sent to the given connection, and returns its frames.
     pub fn decode_pkt(
         conn: &mut Connection, buf: &mut [u8],
     ) -> Result<Vec<frame::Frame>> {
@@ -8535,6 +15410,8 @@ pub mod testing {
         Ok(frames)
     }
 
+    /// Generates a random connection ID of the given length, with a random
+    /// stateless reset token.
     pub fn create_cid_and_reset_token(
         cid_len: usize,
     ) -> (ConnectionId<'static>, u128) {
@@ -8575,12 +15452,29 @@ mod tests {
             initial_source_connection_id: Some(b"woot woot".to_vec().into()),
             retry_source_connection_id: Some(b"retry".to_vec().into()),
             max_datagram_frame_size: Some(32),
+            enable_fec: false,
+            reset_stream_at: true,
+            grease_quic_bit: true,
+            preferred_address: Some(PreferredAddress {
+                ipv4: Some("127.0.0.1:4433".parse().unwrap()),
+                ipv6: None,
+                connection_id: b"prefcid!".to_vec().into(),
+                stateless_reset_token: u128::from_be_bytes([0xab; 16]),
+            }),
+            version_information: Some(VersionInformation {
+                chosen_version: PROTOCOL_VERSION_V2,
+                available_versions: vec![
+                    PROTOCOL_VERSION_V2,
+                    PROTOCOL_VERSION_V1,
+                ],
+            }),
+            custom_params: vec![],
         };
 
         let mut raw_params = [42; 256];
         let raw_params =
             TransportParams::encode(&tp, true, &mut raw_params).unwrap();
-        assert_eq!(raw_params.len(), 94);
+        assert_eq!(raw_params.len(), 171);
 
         let new_tp = TransportParams::decode(raw_params, false).unwrap();
 
@@ -8605,12 +15499,18 @@ mod tests {
             initial_source_connection_id: Some(b"woot woot".to_vec().into()),
             retry_source_connection_id: None,
             max_datagram_frame_size: Some(32),
+            enable_fec: false,
+            reset_stream_at: false,
+            grease_quic_bit: false,
+            preferred_address: None,
+            version_information: None,
+            custom_params: vec![(0x1234, b"hello".to_vec())],
         };
 
         let mut raw_params = [42; 256];
         let raw_params =
             TransportParams::encode(&tp, false, &mut raw_params).unwrap();
-        assert_eq!(raw_params.len(), 69);
+        assert_eq!(raw_params.len(), 77);
 
         let new_tp = TransportParams::decode(raw_params, true).unwrap();
 
@@ -8618,36 +15518,122 @@ mod tests {
     }
 
     #[test]
-    fn transport_params_forbid_duplicates() {
-        // Given an encoded param.
-        let initial_source_connection_id = b"id";
-        let initial_source_connection_id_raw = [
-            15,
-            initial_source_connection_id.len() as u8,
-            initial_source_connection_id[0],
-            initial_source_connection_id[1],
-        ];
+    fn custom_transport_params() {
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.verify_peer(false);
 
-        // No error when decoding the param.
-        let tp = TransportParams::decode(
-            initial_source_connection_id_raw.as_slice(),
-            true,
-        )
-        .unwrap();
+        assert_eq!(config.add_custom_transport_param(0x1234, b"hello"), Ok(()));
 
+        // Duplicate, known and reserved parameters are rejected.
         assert_eq!(
-            tp.initial_source_connection_id,
-            Some(initial_source_connection_id.to_vec().into())
+            config.add_custom_transport_param(0x1234, b"again"),
+            Err(Error::InvalidTransportParam)
         );
-
-        // Duplicate the param.
-        let mut raw_params = Vec::new();
-        raw_params.append(&mut initial_source_connection_id_raw.to_vec());
-        raw_params.append(&mut initial_source_connection_id_raw.to_vec());
-
-        // Decoding fails.
         assert_eq!(
-            TransportParams::decode(raw_params.as_slice(), true),
+            config.add_custom_transport_param(0x0004, b"max data"),
+            Err(Error::InvalidTransportParam)
+        );
+        assert_eq!(
+            config.add_custom_transport_param(31 * 5 + 27, b"grease"),
+            Err(Error::InvalidTransportParam)
+        );
+
+        let received = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let received_cb = received.clone();
+
+        let mut server_config = Config::new(PROTOCOL_VERSION).unwrap();
+        server_config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        server_config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        server_config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        server_config.set_custom_transport_param_callback(move |id, value| {
+            received_cb.lock().unwrap().push((id, value.to_vec()));
+        });
+
+        let mut pipe = testing::Pipe::with_client_and_server_config(
+            &mut config,
+            &mut server_config,
+        )
+        .unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        // The GREASE parameter sent by the client is not reported.
+        assert_eq!(*received.lock().unwrap(), vec![(0x1234, b"hello".to_vec())]);
+        assert_eq!(
+            pipe.server.peer_transport_params().unwrap().custom_params,
+            vec![(0x1234, b"hello".to_vec())]
+        );
+
+        let tp = pipe.server.peer_transport_params().unwrap();
+        assert_eq!(tp.custom_param(0x1234), Some(&b"hello"[..]));
+        assert_eq!(tp.custom_param(0x1235), None);
+    }
+
+    #[test]
+    fn peer_transport_params() {
+        let mut pipe = testing::Pipe::new().unwrap();
+
+        assert!(pipe.client.peer_transport_params().is_none());
+        assert!(pipe.server.peer_transport_params().is_none());
+
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let tp = pipe.client.peer_transport_params().unwrap();
+        assert_eq!(tp.max_idle_timeout, 180_000);
+        assert_eq!(tp.initial_max_data, 30);
+        assert_eq!(tp.initial_max_stream_data_bidi_local, 15);
+        assert_eq!(tp.initial_max_stream_data_uni, 10);
+        assert_eq!(tp.initial_max_streams_uni, 3);
+        assert_eq!(tp.ack_delay_exponent, 8);
+        assert!(tp.custom_params.is_empty());
+
+        // Only the server sends the original destination CID.
+        assert!(tp.original_destination_connection_id.is_some());
+
+        let tp = pipe.server.peer_transport_params().unwrap();
+        assert_eq!(tp.max_idle_timeout, 180_000);
+        assert!(tp.original_destination_connection_id.is_none());
+    }
+
+    #[test]
+    fn transport_params_forbid_duplicates() {
+        // Given an encoded param.
+        let initial_source_connection_id = b"id";
+        let initial_source_connection_id_raw = [
+            15,
+            initial_source_connection_id.len() as u8,
+            initial_source_connection_id[0],
+            initial_source_connection_id[1],
+        ];
+
+        // No error when decoding the param.
+        let tp = TransportParams::decode(
+            initial_source_connection_id_raw.as_slice(),
+            true,
+        )
+        .unwrap();
+
+        assert_eq!(
+            tp.initial_source_connection_id,
+            Some(initial_source_connection_id.to_vec().into())
+        );
+
+        // Duplicate the param.
+        let mut raw_params = Vec::new();
+        raw_params.append(&mut initial_source_connection_id_raw.to_vec());
+        raw_params.append(&mut initial_source_connection_id_raw.to_vec());
+
+        // Decoding fails.
+        assert_eq!(
+            TransportParams::decode(raw_params.as_slice(), true),
             Err(Error::InvalidTransportParam)
         );
     }
@@ -8704,235 +15690,219 @@ mod tests {
     }
 
     #[test]
-    fn verify_custom_root() {
-        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
-        config.verify_peer(true);
-        config
-            .load_verify_locations_from_file("examples/rootca.crt")
-            .unwrap();
-        config
-            .set_application_protos(&[b"proto1", b"proto2"])
-            .unwrap();
+    fn compatible_version_negotiation() {
+        let new_config = |version, quic_v2| {
+            let mut config = Config::new(version).unwrap();
+            config
+                .load_cert_chain_from_pem_file("examples/cert.crt")
+                .unwrap();
+            config
+                .load_priv_key_from_pem_file("examples/cert.key")
+                .unwrap();
+            config
+                .set_application_protos(&[b"proto1", b"proto2"])
+                .unwrap();
+            config.set_initial_max_data(30);
+            config.set_initial_max_stream_data_bidi_local(15);
+            config.set_initial_max_stream_data_bidi_remote(15);
+            config.set_initial_max_streams_bidi(3);
+            config.verify_peer(false);
+            config.enable_quic_v2(quic_v2);
+            config
+        };
 
-        let mut pipe = testing::Pipe::with_client_config(&mut config).unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
-    }
+        // Client version and QUIC v2 support, server version and QUIC v2
+        // support, and the version eventually used.
+        let cases = [
+            (PROTOCOL_VERSION_V1, true, PROTOCOL_VERSION_V2, true, true),
+            (PROTOCOL_VERSION_V1, true, PROTOCOL_VERSION_V1, true, false),
+            (PROTOCOL_VERSION_V2, true, PROTOCOL_VERSION_V1, true, false),
+            (PROTOCOL_VERSION_V2, true, PROTOCOL_VERSION_V2, true, true),
+            (PROTOCOL_VERSION_V1, true, PROTOCOL_VERSION_V2, false, false),
+            (PROTOCOL_VERSION_V1, false, PROTOCOL_VERSION_V2, true, false),
+        ];
 
-    #[test]
-    fn verify_client_invalid() {
-        let mut server_config = Config::new(crate::PROTOCOL_VERSION).unwrap();
-        server_config
-            .load_cert_chain_from_pem_file("examples/cert.crt")
-            .unwrap();
-        server_config
-            .load_priv_key_from_pem_file("examples/cert.key")
-            .unwrap();
-        server_config
-            .set_application_protos(&[b"proto1", b"proto2"])
+        for (client_version, client_v2, server_version, server_v2, v2) in cases {
+            let mut client_config = new_config(client_version, client_v2);
+            let mut server_config = new_config(server_version, server_v2);
+
+            let mut pipe = testing::Pipe::with_client_and_server_config(
+                &mut client_config,
+                &mut server_config,
+            )
             .unwrap();
-        server_config.set_initial_max_data(30);
-        server_config.set_initial_max_stream_data_bidi_local(15);
-        server_config.set_initial_max_stream_data_bidi_remote(15);
-        server_config.set_initial_max_streams_bidi(3);
+            assert_eq!(pipe.handshake(), Ok(()));
 
-        // The server shouldn't be able to verify the client's certificate due
-        // to missing CA.
-        server_config.verify_peer(true);
+            let expected = if v2 {
+                PROTOCOL_VERSION_V2
+            } else {
+                PROTOCOL_VERSION_V1
+            };
 
-        let mut client_config = Config::new(crate::PROTOCOL_VERSION).unwrap();
-        client_config
-            .load_cert_chain_from_pem_file("examples/cert.crt")
-            .unwrap();
-        client_config
-            .load_priv_key_from_pem_file("examples/cert.key")
-            .unwrap();
-        client_config
-            .set_application_protos(&[b"proto1", b"proto2"])
-            .unwrap();
-        client_config.set_initial_max_data(30);
-        client_config.set_initial_max_stream_data_bidi_local(15);
-        client_config.set_initial_max_stream_data_bidi_remote(15);
-        client_config.set_initial_max_streams_bidi(3);
+            assert_eq!(pipe.client.version, expected);
+            assert_eq!(pipe.server.version, expected);
 
-        // The client is able to verify the server's certificate with the
-        // appropriate CA.
-        client_config
-            .load_verify_locations_from_file("examples/rootca.crt")
-            .unwrap();
-        client_config.verify_peer(true);
+            assert_eq!(
+                pipe.client.did_version_upgrade,
+                expected != client_version
+            );
+            assert_eq!(
+                pipe.server.did_version_upgrade,
+                expected != client_version
+            );
 
-        let mut pipe = testing::Pipe::with_client_and_server_config(
-            &mut client_config,
-            &mut server_config,
-        )
-        .unwrap();
-        assert_eq!(pipe.handshake(), Err(Error::TlsFail));
+            // Exchange some data with the negotiated version.
+            assert_eq!(pipe.client.stream_send(0, b"hello", true), Ok(5));
+            assert_eq!(pipe.advance(), Ok(()));
 
-        // Client did send a certificate.
-        assert!(pipe.server.peer_cert().is_some());
+            let mut b = [0; 5];
+            assert_eq!(pipe.server.stream_recv(0, &mut b), Ok((5, true)));
+        }
     }
 
     #[test]
-    fn verify_client_anonymous() {
-        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
-        config
-            .load_cert_chain_from_pem_file("examples/cert.crt")
-            .unwrap();
-        config
-            .load_priv_key_from_pem_file("examples/cert.key")
-            .unwrap();
+    fn quic_v2_disabled() {
+        let mut config = Config::new(PROTOCOL_VERSION_V2).unwrap();
         config
             .set_application_protos(&[b"proto1", b"proto2"])
             .unwrap();
-        config.set_initial_max_data(30);
-        config.set_initial_max_stream_data_bidi_local(15);
-        config.set_initial_max_stream_data_bidi_remote(15);
-        config.set_initial_max_streams_bidi(3);
+        config.verify_peer(false);
+        config.enable_quic_v2(true);
 
-        // Try to validate client certificate.
-        config.verify_peer(true);
+        let mut pipe = testing::Pipe::with_client_config(&mut config).unwrap();
 
-        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+        let mut buf = [0; 65535];
 
-        // Client didn't send a certificate.
-        assert!(pipe.server.peer_cert().is_none());
-    }
+        let (mut len, _) = pipe.client.send(&mut buf).unwrap();
 
-    #[test]
-    fn missing_initial_source_connection_id() {
-        let mut buf = [0; 65535];
+        let hdr = packet::Header::from_slice(&mut buf[..len], 0).unwrap();
+        assert_eq!(hdr.version, PROTOCOL_VERSION_V2);
 
-        let mut pipe = testing::Pipe::new().unwrap();
+        // The server doesn't have QUIC v2 enabled, so it negotiates the
+        // version instead of accepting the connection.
+        let server_config = Config::new(PROTOCOL_VERSION).unwrap();
+        assert!(!server_config.is_version_supported(hdr.version));
 
-        // Reset initial_source_connection_id.
-        pipe.client
-            .local_transport_params
-            .initial_source_connection_id = None;
-        assert_eq!(pipe.client.encode_transport_params(), Ok(()));
+        len = crate::negotiate_version(&hdr.scid, &hdr.dcid, &mut buf).unwrap();
 
-        // Client sends initial flight.
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(pipe.client_recv(&mut buf[..len]), Ok(len));
 
-        // Server rejects transport parameters.
-        assert_eq!(
-            pipe.server_recv(&mut buf[..len]),
-            Err(Error::InvalidTransportParam)
-        );
+        // The client falls back to QUIC v1.
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(pipe.client.version, PROTOCOL_VERSION_V1);
+        assert_eq!(pipe.server.version, PROTOCOL_VERSION_V1);
     }
 
     #[test]
-    fn invalid_initial_source_connection_id() {
-        let mut buf = [0; 65535];
-
-        let mut pipe = testing::Pipe::new().unwrap();
+    fn config_is_version_supported() {
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
 
-        // Scramble initial_source_connection_id.
-        pipe.client
-            .local_transport_params
-            .initial_source_connection_id = Some(b"bogus value".to_vec().into());
-        assert_eq!(pipe.client.encode_transport_params(), Ok(()));
+        assert!(config.is_version_supported(PROTOCOL_VERSION_V1));
+        assert!(!config.is_version_supported(PROTOCOL_VERSION_V2));
+        assert!(!config.is_version_supported(0xbabababa));
 
-        // Client sends initial flight.
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        config.enable_quic_v2(true);
 
-        // Server rejects transport parameters.
-        assert_eq!(
-            pipe.server_recv(&mut buf[..len]),
-            Err(Error::InvalidTransportParam)
-        );
+        assert!(config.is_version_supported(PROTOCOL_VERSION_V2));
     }
 
     #[test]
-    fn handshake() {
+    fn grease_transport_params() {
         let mut pipe = testing::Pipe::new().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        assert_eq!(
-            pipe.client.application_proto(),
-            pipe.server.application_proto()
+        // The server received and ignored the client's reserved parameter.
+        let mut b = octets::Octets::with_slice(
+            pipe.server.handshake.quic_transport_params(),
         );
 
-        assert_eq!(pipe.server.server_name(), Some("quic.tech"));
-    }
-
-    #[test]
-    fn handshake_done() {
-        let mut pipe = testing::Pipe::new().unwrap();
+        let mut found = false;
 
-        // Disable session tickets on the server (SSL_OP_NO_TICKET) to avoid
-        // triggering 1-RTT packet send with a CRYPTO frame.
-        pipe.server.handshake.set_options(0x0000_4000);
+        while b.cap() > 0 {
+            let id = b.get_varint().unwrap();
+            b.get_bytes_with_varint_length().unwrap();
 
-        assert_eq!(pipe.handshake(), Ok(()));
+            found |= id >= 27 && (id - 27) % 31 == 0;
+        }
 
-        assert!(pipe.server.handshake_done_sent);
+        assert!(found);
     }
 
     #[test]
-    fn handshake_confirmation() {
-        let mut pipe = testing::Pipe::new().unwrap();
-
-        // Client sends initial flight.
-        let flight = testing::emit_flight(&mut pipe.client).unwrap();
-        testing::process_flight(&mut pipe.server, flight).unwrap();
-
-        // Server sends initial flight.
-        let flight = testing::emit_flight(&mut pipe.server).unwrap();
-
-        assert!(!pipe.client.is_established());
-        assert!(!pipe.client.handshake_confirmed);
+    fn grease_rate() {
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.verify_peer(false);
+        config.set_grease_rate(0.0);
 
-        assert!(!pipe.server.is_established());
-        assert!(!pipe.server.handshake_confirmed);
+        let mut pipe = testing::Pipe::with_client_config(&mut config).unwrap();
+        assert!(!pipe.client.grease);
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        testing::process_flight(&mut pipe.client, flight).unwrap();
+        config.set_grease_rate(2.0);
 
-        // Client sends Handshake packet and completes handshake.
-        let flight = testing::emit_flight(&mut pipe.client).unwrap();
+        let pipe = testing::Pipe::with_client_config(&mut config).unwrap();
+        assert!(pipe.client.grease);
 
-        assert!(pipe.client.is_established());
-        assert!(!pipe.client.handshake_confirmed);
+        config.grease(false);
 
-        assert!(!pipe.server.is_established());
-        assert!(!pipe.server.handshake_confirmed);
+        let pipe = testing::Pipe::with_client_config(&mut config).unwrap();
+        assert!(!pipe.client.grease);
+    }
 
-        testing::process_flight(&mut pipe.server, flight).unwrap();
-
-        // Server completes and confirms handshake, and sends HANDSHAKE_DONE.
-        let flight = testing::emit_flight(&mut pipe.server).unwrap();
+    #[test]
+    fn verify_custom_root() {
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        config.verify_peer(true);
+        config
+            .load_verify_locations_from_file("examples/rootca.crt")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
 
-        assert!(pipe.client.is_established());
-        assert!(!pipe.client.handshake_confirmed);
+        let mut pipe = testing::Pipe::with_client_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+    }
 
-        assert!(pipe.server.is_established());
-        assert!(pipe.server.handshake_confirmed);
+    #[test]
+    fn verify_callback() {
+        let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
 
-        testing::process_flight(&mut pipe.client, flight).unwrap();
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        config.verify_peer(true);
+        config
+            .load_verify_locations_from_file("examples/rootca.crt")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
 
-        // Client acks 1-RTT packet, and confirms handshake.
-        let flight = testing::emit_flight(&mut pipe.client).unwrap();
+        let cb_calls = calls.clone();
+        config.set_verify_callback(move |chain| {
+            cb_calls.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
 
-        assert!(pipe.client.is_established());
-        assert!(pipe.client.handshake_confirmed);
+            !chain.is_empty()
+        });
 
-        assert!(pipe.server.is_established());
-        assert!(pipe.server.handshake_confirmed);
+        let mut pipe = testing::Pipe::with_client_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        testing::process_flight(&mut pipe.server, flight).unwrap();
+        assert_eq!(calls.load(std::sync::atomic::Ordering::Relaxed), 1);
 
-        assert!(pipe.client.is_established());
-        assert!(pipe.client.handshake_confirmed);
+        // The application rejects the server's certificate.
+        config.set_verify_callback(|_| false);
 
-        assert!(pipe.server.is_established());
-        assert!(pipe.server.handshake_confirmed);
+        let mut pipe = testing::Pipe::with_client_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Err(Error::TlsFail));
     }
 
     #[test]
-    fn handshake_resumption() {
-        const SESSION_TICKET_KEY: [u8; 48] = [0xa; 48];
-
-        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+    fn handshake_pq_key_exchange() {
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
         config
             .load_cert_chain_from_pem_file("examples/cert.crt")
             .unwrap();
@@ -8942,83 +15912,96 @@ mod tests {
         config
             .set_application_protos(&[b"proto1", b"proto2"])
             .unwrap();
-        config.set_initial_max_data(30);
-        config.set_initial_max_stream_data_bidi_local(15);
-        config.set_initial_max_stream_data_bidi_remote(15);
-        config.set_initial_max_streams_bidi(3);
-        config.set_ticket_key(&SESSION_TICKET_KEY).unwrap();
+        config.verify_peer(false);
 
-        // Perform initial handshake.
-        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+        assert_eq!(
+            config.set_key_exchange_groups(&["X25519", "nope"]),
+            Err(Error::TlsFail)
+        );
 
-        assert!(pipe.client.is_established());
-        assert!(pipe.server.is_established());
+        assert_eq!(
+            config.set_key_exchange_groups(&["X25519Kyber768Draft00"]),
+            Ok(())
+        );
 
-        assert!(!pipe.client.is_resumed());
-        assert!(!pipe.server.is_resumed());
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
 
-        // Extract session,
-        let session = pipe.client.session().unwrap();
+        // The ClientHello doesn't fit in a single Initial packet.
+        let flight = testing::emit_flight(&mut pipe.client).unwrap();
+        assert!(flight.len() > 1);
+        assert!(flight
+            .iter()
+            .all(|(p, _)| p.len() >= MIN_CLIENT_INITIAL_LEN));
 
-        // Configure session on new connection and perform handshake.
-        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
-        config
-            .load_cert_chain_from_pem_file("examples/cert.crt")
-            .unwrap();
-        config
-            .load_priv_key_from_pem_file("examples/cert.key")
-            .unwrap();
-        config
-            .set_application_protos(&[b"proto1", b"proto2"])
-            .unwrap();
-        config.set_initial_max_data(30);
-        config.set_initial_max_stream_data_bidi_local(15);
-        config.set_initial_max_stream_data_bidi_remote(15);
-        config.set_initial_max_streams_bidi(3);
-        config.set_ticket_key(&SESSION_TICKET_KEY).unwrap();
+        testing::process_flight(&mut pipe.server, flight).unwrap();
 
-        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+    }
+
+    #[test]
+    fn handshake_info() {
+        let mut pipe = testing::Pipe::new().unwrap();
+
+        let info = pipe.client.handshake_info();
+        assert_eq!(info.cipher_suite, None);
+        assert!(info.application_proto.is_empty());
 
-        assert_eq!(pipe.client.set_session(session), Ok(()));
         assert_eq!(pipe.handshake(), Ok(()));
 
-        assert!(pipe.client.is_established());
-        assert!(pipe.server.is_established());
+        let info = pipe.client.handshake_info();
+        assert!(info.cipher_suite.is_some());
+        assert!(info.key_exchange_group.is_some());
+        assert!(info.peer_signature_algorithm.is_some());
+        assert_eq!(info.application_proto, b"proto1");
+        assert!(!info.resumed);
+        assert_eq!(info.peer_application_settings, None);
 
-        assert!(pipe.client.is_resumed());
-        assert!(pipe.server.is_resumed());
+        assert_eq!(pipe.server.handshake_info().cipher_suite, info.cipher_suite);
     }
 
     #[test]
-    fn handshake_alpn_mismatch() {
-        let mut buf = [0; 65535];
-
+    fn cipher_suites() {
         let mut config = Config::new(PROTOCOL_VERSION).unwrap();
         config
-            .set_application_protos(&[b"proto3\x06proto4"])
+            .set_application_protos(&[b"proto1", b"proto2"])
             .unwrap();
         config.verify_peer(false);
 
-        let mut pipe = testing::Pipe::with_client_config(&mut config).unwrap();
-        assert_eq!(pipe.handshake(), Err(Error::TlsFail));
+        // Only the sets of suites matching a compliance policy are supported.
+        assert_eq!(
+            config.set_cipher_suites(&[CipherSuite::ChaCha20Poly1305Sha256]),
+            Err(Error::TlsFail)
+        );
+        assert_eq!(
+            config.set_cipher_suites(&[CipherSuite::Aes128GcmSha256]),
+            Err(Error::TlsFail)
+        );
 
-        assert_eq!(pipe.client.application_proto(), b"");
-        assert_eq!(pipe.server.application_proto(), b"");
+        let restrictions: [&[CipherSuite]; 2] = [
+            &[CipherSuite::Aes128GcmSha256, CipherSuite::Aes256GcmSha384],
+            &[CipherSuite::Aes256GcmSha384],
+        ];
 
-        // Server should only send one packet in response to ALPN mismatch.
-        let (len, _) = pipe.server.send(&mut buf).unwrap();
-        assert_eq!(len, 1200);
+        for suites in restrictions {
+            assert_eq!(config.set_cipher_suites(suites), Ok(()));
 
-        assert_eq!(pipe.server.send(&mut buf), Err(Error::Done));
-        assert_eq!(pipe.server.sent_count, 1);
+            let mut pipe =
+                testing::Pipe::with_client_config(&mut config).unwrap();
+            assert_eq!(pipe.handshake(), Ok(()));
+
+            let negotiated = pipe.client.handshake_info().cipher_suite.unwrap();
+            assert!(suites.contains(&negotiated));
+
+            assert_eq!(
+                pipe.server.handshake_info().cipher_suite,
+                Some(negotiated)
+            );
+        }
     }
 
     #[test]
-    fn handshake_0rtt() {
-        let mut buf = [0; 65535];
-
-        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+    fn cipher_suites_server() {
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
         config
             .load_cert_chain_from_pem_file("examples/cert.crt")
             .unwrap();
@@ -9028,58 +16011,34 @@ mod tests {
         config
             .set_application_protos(&[b"proto1", b"proto2"])
             .unwrap();
-        config.set_initial_max_data(30);
-        config.set_initial_max_stream_data_bidi_local(15);
-        config.set_initial_max_stream_data_bidi_remote(15);
-        config.set_initial_max_streams_bidi(3);
-        config.enable_early_data();
-        config.verify_peer(false);
-
-        // Perform initial handshake.
-        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
-
-        // Extract session,
-        let session = pipe.client.session().unwrap();
-
-        // Configure session on new connection.
-        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
-        assert_eq!(pipe.client.set_session(session), Ok(()));
-
-        // Client sends initial flight.
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
-        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
-
-        // Client sends 0-RTT packet.
-        let pkt_type = packet::Type::ZeroRTT;
 
-        let frames = [frame::Frame::Stream {
-            stream_id: 4,
-            data: stream::RangeBuf::from(b"aaaaa", 0, true),
-        }];
+        let restrictions: [&[CipherSuite]; 2] = [
+            &[CipherSuite::Aes128GcmSha256, CipherSuite::Aes256GcmSha384],
+            &[CipherSuite::Aes256GcmSha384],
+        ];
 
-        assert_eq!(
-            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
-            Ok(1200)
-        );
+        // The client offers all the suites, and the server picks one of the
+        // ones it allows.
+        for suites in restrictions {
+            assert_eq!(config.set_cipher_suites(suites), Ok(()));
 
-        assert_eq!(pipe.server.undecryptable_pkts.len(), 0);
+            let mut pipe =
+                testing::Pipe::with_server_config(&mut config).unwrap();
+            assert_eq!(pipe.handshake(), Ok(()));
 
-        // 0-RTT stream data is readable.
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), Some(4));
-        assert_eq!(r.next(), None);
+            let negotiated = pipe.server.handshake_info().cipher_suite.unwrap();
+            assert!(suites.contains(&negotiated));
 
-        let mut b = [0; 15];
-        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((5, true)));
-        assert_eq!(&b[..5], b"aaaaa");
+            assert_eq!(
+                pipe.client.handshake_info().cipher_suite,
+                Some(negotiated)
+            );
+        }
     }
 
     #[test]
-    fn handshake_0rtt_reordered() {
-        let mut buf = [0; 65535];
-
-        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+    fn signature_algorithms() {
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
         config
             .load_cert_chain_from_pem_file("examples/cert.crt")
             .unwrap();
@@ -9089,171 +16048,129 @@ mod tests {
         config
             .set_application_protos(&[b"proto1", b"proto2"])
             .unwrap();
-        config.set_initial_max_data(30);
-        config.set_initial_max_stream_data_bidi_local(15);
-        config.set_initial_max_stream_data_bidi_remote(15);
-        config.set_initial_max_streams_bidi(3);
-        config.enable_early_data();
-        config.verify_peer(false);
-
-        // Perform initial handshake.
-        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
-
-        // Extract session,
-        let session = pipe.client.session().unwrap();
-
-        // Configure session on new connection.
-        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
-        assert_eq!(pipe.client.set_session(session), Ok(()));
-
-        // Client sends initial flight.
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
-        let mut initial = buf[..len].to_vec();
-
-        // Client sends 0-RTT packet.
-        let pkt_type = packet::Type::ZeroRTT;
-
-        let frames = [frame::Frame::Stream {
-            stream_id: 4,
-            data: stream::RangeBuf::from(b"aaaaa", 0, true),
-        }];
-
-        let len =
-            testing::encode_pkt(&mut pipe.client, pkt_type, &frames, &mut buf)
-                .unwrap();
-        let mut zrtt = buf[..len].to_vec();
-
-        // 0-RTT packet is received before the Initial one.
-        assert_eq!(pipe.server_recv(&mut zrtt), Ok(zrtt.len()));
-
-        assert_eq!(pipe.server.undecryptable_pkts.len(), 1);
-        assert_eq!(pipe.server.undecryptable_pkts[0].0.len(), zrtt.len());
 
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), None);
+        assert_eq!(
+            config.set_signature_algorithms(&["nope"]),
+            Err(Error::TlsFail)
+        );
 
-        // Initial packet is also received.
-        assert_eq!(pipe.server_recv(&mut initial), Ok(initial.len()));
+        assert_eq!(
+            config.set_signature_algorithms(&["rsa_pss_rsae_sha384"]),
+            Ok(())
+        );
 
-        // 0-RTT stream data is readable.
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), Some(4));
-        assert_eq!(r.next(), None);
+        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        let mut b = [0; 15];
-        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((5, true)));
-        assert_eq!(&b[..5], b"aaaaa");
+        assert_eq!(
+            pipe.client.handshake_info().peer_signature_algorithm,
+            Some("rsa_pss_rsae_sha384".to_string())
+        );
     }
 
     #[test]
-    fn handshake_0rtt_truncated() {
-        let mut buf = [0; 65535];
+    fn application_settings() {
+        let mut client_config = Config::new(PROTOCOL_VERSION).unwrap();
+        client_config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        client_config.verify_peer(false);
+        client_config.set_application_settings(b"proto1", b"client");
 
-        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
-        config
+        let mut server_config = Config::new(PROTOCOL_VERSION).unwrap();
+        server_config
             .load_cert_chain_from_pem_file("examples/cert.crt")
             .unwrap();
-        config
+        server_config
             .load_priv_key_from_pem_file("examples/cert.key")
             .unwrap();
-        config
+        server_config
             .set_application_protos(&[b"proto1", b"proto2"])
             .unwrap();
-        config.set_initial_max_data(30);
-        config.set_initial_max_stream_data_bidi_local(15);
-        config.set_initial_max_stream_data_bidi_remote(15);
-        config.set_initial_max_streams_bidi(3);
-        config.enable_early_data();
-        config.verify_peer(false);
+        server_config.set_application_settings(b"proto1", b"stale");
+        server_config.set_application_settings(b"proto1", b"server");
 
-        // Perform initial handshake.
-        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        let mut pipe = testing::Pipe::with_client_and_server_config(
+            &mut client_config,
+            &mut server_config,
+        )
+        .unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        // Extract session,
-        let session = pipe.client.session().unwrap();
-
-        // Configure session on new connection.
-        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
-        assert_eq!(pipe.client.set_session(session), Ok(()));
-
-        // Client sends initial flight.
-        pipe.client.send(&mut buf).unwrap();
-
-        // Client sends 0-RTT packet.
-        let pkt_type = packet::Type::ZeroRTT;
-
-        let frames = [frame::Frame::Stream {
-            stream_id: 4,
-            data: stream::RangeBuf::from(b"aaaaa", 0, true),
-        }];
-
-        let len =
-            testing::encode_pkt(&mut pipe.client, pkt_type, &frames, &mut buf)
-                .unwrap();
-
-        // Simulate a truncated packet by sending one byte less.
-        let mut zrtt = buf[..len - 1].to_vec();
-
-        // 0-RTT packet is received before the Initial one.
-        assert_eq!(pipe.server_recv(&mut zrtt), Err(Error::InvalidPacket));
+        assert_eq!(
+            pipe.client.peer_application_settings(),
+            Some(&b"server"[..])
+        );
+        assert_eq!(
+            pipe.server.peer_application_settings(),
+            Some(&b"client"[..])
+        );
 
-        assert_eq!(pipe.server.undecryptable_pkts.len(), 0);
+        // Settings are not exchanged if the peer doesn't configure them.
+        let mut pipe =
+            testing::Pipe::with_client_config(&mut client_config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        assert!(pipe.server.is_closed());
+        assert_eq!(pipe.client.peer_application_settings(), None);
+        assert_eq!(pipe.server.peer_application_settings(), None);
     }
 
     #[test]
-    fn limit_handshake_data() {
-        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
-        config
-            .load_cert_chain_from_pem_file("examples/cert-big.crt")
+    fn verify_client_invalid() {
+        let mut server_config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        server_config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
             .unwrap();
-        config
+        server_config
             .load_priv_key_from_pem_file("examples/cert.key")
             .unwrap();
-        config
+        server_config
             .set_application_protos(&[b"proto1", b"proto2"])
             .unwrap();
+        server_config.set_initial_max_data(30);
+        server_config.set_initial_max_stream_data_bidi_local(15);
+        server_config.set_initial_max_stream_data_bidi_remote(15);
+        server_config.set_initial_max_streams_bidi(3);
 
-        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
-
-        let flight = testing::emit_flight(&mut pipe.client).unwrap();
-        let client_sent = flight.iter().fold(0, |out, p| out + p.0.len());
-        testing::process_flight(&mut pipe.server, flight).unwrap();
-
-        let flight = testing::emit_flight(&mut pipe.server).unwrap();
-        let server_sent = flight.iter().fold(0, |out, p| out + p.0.len());
-
-        assert_eq!(server_sent, client_sent * MAX_AMPLIFICATION_FACTOR);
-    }
-
-    #[test]
-    fn stream() {
-        let mut pipe = testing::Pipe::new().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
-
-        assert_eq!(pipe.client.stream_send(4, b"hello, world", true), Ok(12));
-        assert_eq!(pipe.advance(), Ok(()));
+        // The server shouldn't be able to verify the client's certificate due
+        // to missing CA.
+        server_config.verify_peer(true);
 
-        assert!(!pipe.server.stream_finished(4));
+        let mut client_config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        client_config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        client_config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        client_config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        client_config.set_initial_max_data(30);
+        client_config.set_initial_max_stream_data_bidi_local(15);
+        client_config.set_initial_max_stream_data_bidi_remote(15);
+        client_config.set_initial_max_streams_bidi(3);
 
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), Some(4));
-        assert_eq!(r.next(), None);
+        // The client is able to verify the server's certificate with the
+        // appropriate CA.
+        client_config
+            .load_verify_locations_from_file("examples/rootca.crt")
+            .unwrap();
+        client_config.verify_peer(true);
 
-        let mut b = [0; 15];
-        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((12, true)));
-        assert_eq!(&b[..12], b"hello, world");
+        let mut pipe = testing::Pipe::with_client_and_server_config(
+            &mut client_config,
+            &mut server_config,
+        )
+        .unwrap();
+        assert_eq!(pipe.handshake(), Err(Error::TlsFail));
 
-        assert!(pipe.server.stream_finished(4));
+        // Client did send a certificate.
+        assert!(pipe.server.peer_cert().is_some());
     }
 
     #[test]
-    fn zero_rtt() {
-        let mut buf = [0; 65535];
-
+    fn verify_client_anonymous() {
         let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
         config
             .load_cert_chain_from_pem_file("examples/cert.crt")
@@ -9268,50 +16185,19 @@ mod tests {
         config.set_initial_max_stream_data_bidi_local(15);
         config.set_initial_max_stream_data_bidi_remote(15);
         config.set_initial_max_streams_bidi(3);
-        config.enable_early_data();
-        config.verify_peer(false);
-
-        // Perform initial handshake.
-        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
-
-        // Extract session,
-        let session = pipe.client.session().unwrap();
-
-        // Configure session on new connection.
-        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
-        assert_eq!(pipe.client.set_session(session), Ok(()));
-
-        // Client sends initial flight.
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
-        let mut initial = buf[..len].to_vec();
-
-        assert!(pipe.client.is_in_early_data());
-
-        // Client sends 0-RTT data.
-        assert_eq!(pipe.client.stream_send(4, b"hello, world", true), Ok(12));
-
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
-        let mut zrtt = buf[..len].to_vec();
-
-        // Server receives packets.
-        assert_eq!(pipe.server_recv(&mut initial), Ok(initial.len()));
-        assert!(pipe.server.is_in_early_data());
 
-        assert_eq!(pipe.server_recv(&mut zrtt), Ok(zrtt.len()));
+        // Try to validate client certificate.
+        config.verify_peer(true);
 
-        // 0-RTT stream data is readable.
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), Some(4));
-        assert_eq!(r.next(), None);
+        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        let mut b = [0; 15];
-        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((12, true)));
-        assert_eq!(&b[..12], b"hello, world");
+        // Client didn't send a certificate.
+        assert!(pipe.server.peer_cert().is_none());
     }
 
     #[test]
-    fn stream_send_on_32bit_arch() {
+    fn verify_client_required() {
         let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
         config
             .load_cert_chain_from_pem_file("examples/cert.crt")
@@ -9322,1468 +16208,1269 @@ mod tests {
         config
             .set_application_protos(&[b"proto1", b"proto2"])
             .unwrap();
-        config.set_initial_max_data(2_u64.pow(32) + 5);
+        config.set_initial_max_data(30);
         config.set_initial_max_stream_data_bidi_local(15);
         config.set_initial_max_stream_data_bidi_remote(15);
-        config.set_initial_max_stream_data_uni(10);
         config.set_initial_max_streams_bidi(3);
-        config.set_initial_max_streams_uni(0);
-        config.verify_peer(false);
-
-        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
 
-        // In 32bit arch, send_capacity() should be min(2^32+5, cwnd),
-        // not min(5, cwnd)
-        assert_eq!(pipe.client.stream_send(4, b"hello, world", true), Ok(12));
+        // Anonymous clients are not allowed.
+        config.verify_peer_certificate_required(true);
 
-        assert_eq!(pipe.advance(), Ok(()));
+        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Err(Error::TlsFail));
 
-        assert!(!pipe.server.stream_finished(4));
+        assert!(pipe.server.peer_cert().is_none());
     }
 
     #[test]
-    fn empty_stream_frame() {
+    fn missing_initial_source_connection_id() {
         let mut buf = [0; 65535];
 
         let mut pipe = testing::Pipe::new().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
-
-        let frames = [frame::Frame::Stream {
-            stream_id: 4,
-            data: stream::RangeBuf::from(b"aaaaa", 0, false),
-        }];
 
-        let pkt_type = packet::Type::Short;
-        assert_eq!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf), Ok(39));
-
-        let mut readable = pipe.server.readable();
-        assert_eq!(readable.next(), Some(4));
+        // Reset initial_source_connection_id.
+        pipe.client
+            .local_transport_params
+            .initial_source_connection_id = None;
+        assert_eq!(pipe.client.encode_transport_params(), Ok(()));
 
-        assert_eq!(pipe.server.stream_recv(4, &mut buf), Ok((5, false)));
+        // Client sends initial flight.
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
 
-        let frames = [frame::Frame::Stream {
-            stream_id: 4,
-            data: stream::RangeBuf::from(b"", 5, true),
-        }];
+        // Server rejects transport parameters.
+        assert_eq!(
+            pipe.server_recv(&mut buf[..len]),
+            Err(Error::InvalidTransportParam)
+        );
+    }
 
-        let pkt_type = packet::Type::Short;
-        assert_eq!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf), Ok(39));
+    #[test]
+    fn invalid_initial_source_connection_id() {
+        let mut buf = [0; 65535];
 
-        let mut readable = pipe.server.readable();
-        assert_eq!(readable.next(), Some(4));
+        let mut pipe = testing::Pipe::new().unwrap();
 
-        assert_eq!(pipe.server.stream_recv(4, &mut buf), Ok((0, true)));
+        // Scramble initial_source_connection_id.
+        pipe.client
+            .local_transport_params
+            .initial_source_connection_id = Some(b"bogus value".to_vec().into());
+        assert_eq!(pipe.client.encode_transport_params(), Ok(()));
 
-        let frames = [frame::Frame::Stream {
-            stream_id: 4,
-            data: stream::RangeBuf::from(b"", 15, true),
-        }];
+        // Client sends initial flight.
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
 
-        let pkt_type = packet::Type::Short;
+        // Server rejects transport parameters.
         assert_eq!(
-            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
-            Err(Error::FinalSize)
+            pipe.server_recv(&mut buf[..len]),
+            Err(Error::InvalidTransportParam)
         );
     }
 
     #[test]
-    fn update_key_request() {
-        let mut b = [0; 15];
-
+    fn handshake() {
         let mut pipe = testing::Pipe::new().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
-        assert_eq!(pipe.advance(), Ok(()));
-
-        // Client sends message with key update request.
-        assert_eq!(pipe.client_update_key(), Ok(()));
-        assert_eq!(pipe.client.stream_send(4, b"hello", false), Ok(5));
-        assert_eq!(pipe.advance(), Ok(()));
 
-        // Ensure server updates key and it correctly decrypts the message.
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), Some(4));
-        assert_eq!(r.next(), None);
-        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((5, false)));
-        assert_eq!(&b[..5], b"hello");
-
-        // Ensure ACK for key update.
-        assert!(
-            pipe.server.pkt_num_spaces[packet::Epoch::Application]
-                .key_update
-                .as_ref()
-                .unwrap()
-                .update_acked
+        assert_eq!(
+            pipe.client.application_proto(),
+            pipe.server.application_proto()
         );
 
-        // Server sends message with the new key.
-        assert_eq!(pipe.server.stream_send(4, b"world", true), Ok(5));
-        assert_eq!(pipe.advance(), Ok(()));
-
-        // Ensure update key is completed and client can decrypt packet.
-        let mut r = pipe.client.readable();
-        assert_eq!(r.next(), Some(4));
-        assert_eq!(r.next(), None);
-        assert_eq!(pipe.client.stream_recv(4, &mut b), Ok((5, true)));
-        assert_eq!(&b[..5], b"world");
+        assert_eq!(pipe.server.server_name(), Some("quic.tech"));
     }
 
     #[test]
-    fn update_key_request_twice_error() {
-        let mut buf = [0; 65535];
-
+    fn handshake_done() {
         let mut pipe = testing::Pipe::new().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
-        assert_eq!(pipe.advance(), Ok(()));
-
-        let frames = [frame::Frame::Stream {
-            stream_id: 4,
-            data: stream::RangeBuf::from(b"hello", 0, false),
-        }];
-
-        // Client sends stream frame with key update request.
-        assert_eq!(pipe.client_update_key(), Ok(()));
-        let written = testing::encode_pkt(
-            &mut pipe.client,
-            packet::Type::Short,
-            &frames,
-            &mut buf,
-        )
-        .unwrap();
 
-        // Server correctly decode with new key.
-        assert_eq!(pipe.server_recv(&mut buf[..written]), Ok(written));
+        // Disable session tickets on the server (SSL_OP_NO_TICKET) to avoid
+        // triggering 1-RTT packet send with a CRYPTO frame. The rustls backend
+        // never sends them.
+        #[cfg(not(feature = "rustls"))]
+        pipe.server.handshake.set_options(0x0000_4000);
 
-        // Client sends stream frame with another key update request before server
-        // ACK.
-        assert_eq!(pipe.client_update_key(), Ok(()));
-        let written = testing::encode_pkt(
-            &mut pipe.client,
-            packet::Type::Short,
-            &frames,
-            &mut buf,
-        )
-        .unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        // Check server correctly closes the connection with a key update error
-        // for the peer.
-        assert_eq!(pipe.server_recv(&mut buf[..written]), Err(Error::KeyUpdate));
+        assert!(pipe.server.handshake_done_sent);
     }
 
     #[test]
-    /// Tests that receiving a MAX_STREAM_DATA frame for a receive-only
-    /// unidirectional stream is forbidden.
-    fn max_stream_data_receive_uni() {
-        let mut buf = [0; 65535];
-
+    fn handshake_timeline() {
         let mut pipe = testing::Pipe::new().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
-
-        // Client opens unidirectional stream.
-        assert_eq!(pipe.client.stream_send(2, b"hello", false), Ok(5));
         assert_eq!(pipe.advance(), Ok(()));
 
-        // Client sends MAX_STREAM_DATA on local unidirectional stream.
-        let frames = [frame::Frame::MaxStreamData {
-            stream_id: 2,
-            max: 1024,
-        }];
+        for conn in [&pipe.client, &pipe.server] {
+            let timeline = conn.stats().handshake_timeline;
 
-        let pkt_type = packet::Type::Short;
-        assert_eq!(
-            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
-            Err(Error::InvalidStreamState(2)),
-        );
-    }
+            let created = timeline.created.unwrap();
+            let initial_keys = timeline.initial_keys.unwrap();
+            let handshake_keys = timeline.handshake_keys.unwrap();
+            let completed = timeline.handshake_completed.unwrap();
+            let confirmed = timeline.handshake_confirmed.unwrap();
 
-    #[test]
-    fn empty_payload() {
-        let mut buf = [0; 65535];
+            assert!(created <= initial_keys);
+            assert!(initial_keys <= timeline.first_packet_sent.unwrap());
+            assert!(initial_keys <= handshake_keys);
+            assert!(handshake_keys <= completed);
+            assert!(completed <= confirmed);
 
-        let mut pipe = testing::Pipe::new().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+            assert!(timeline.first_1rtt_sent.is_some());
+            assert!(timeline.first_1rtt_recv.is_some());
 
-        // Send a packet with no frames.
-        let pkt_type = packet::Type::Short;
-        assert_eq!(
-            pipe.send_pkt_to_server(pkt_type, &[], &mut buf),
-            Err(Error::InvalidPacket)
-        );
+            assert!(!timeline.hello_retry_request);
+            assert_eq!(timeline.amplification_limited, 0);
+        }
     }
 
     #[test]
-    fn min_payload() {
-        let mut buf = [0; 65535];
-
+    fn handshake_confirmation() {
         let mut pipe = testing::Pipe::new().unwrap();
 
-        // Send a non-ack-eliciting packet.
-        let frames = [frame::Frame::Padding { len: 4 }];
+        // Client sends initial flight.
+        let flight = testing::emit_flight(&mut pipe.client).unwrap();
+        testing::process_flight(&mut pipe.server, flight).unwrap();
 
-        let pkt_type = packet::Type::Initial;
-        let written =
-            testing::encode_pkt(&mut pipe.client, pkt_type, &frames, &mut buf)
-                .unwrap();
-        assert_eq!(pipe.server_recv(&mut buf[..written]), Ok(written));
+        // Server sends initial flight.
+        let flight = testing::emit_flight(&mut pipe.server).unwrap();
 
-        let initial_path = pipe
-            .server
-            .paths
-            .get_active()
-            .expect("initial path not found");
+        assert!(!pipe.client.is_established());
+        assert!(!pipe.client.handshake_confirmed);
 
-        assert_eq!(initial_path.max_send_bytes, 195);
+        assert!(!pipe.server.is_established());
+        assert!(!pipe.server.handshake_confirmed);
 
-        // Force server to send a single PING frame.
-        pipe.server
-            .paths
-            .get_active_mut()
-            .expect("no active path")
-            .recovery
-            .loss_probes[packet::Epoch::Initial] = 1;
+        testing::process_flight(&mut pipe.client, flight).unwrap();
 
-        let initial_path = pipe
-            .server
-            .paths
-            .get_active_mut()
-            .expect("initial path not found");
+        // Client sends Handshake packet and completes handshake.
+        let flight = testing::emit_flight(&mut pipe.client).unwrap();
 
-        // Artificially limit the amount of bytes the server can send.
-        initial_path.max_send_bytes = 60;
+        assert!(pipe.client.is_established());
+        assert!(!pipe.client.handshake_confirmed);
 
-        assert_eq!(pipe.server.send(&mut buf), Err(Error::Done));
-    }
+        assert!(!pipe.server.is_established());
+        assert!(!pipe.server.handshake_confirmed);
 
-    #[test]
-    fn flow_control_limit() {
-        let mut buf = [0; 65535];
+        testing::process_flight(&mut pipe.server, flight).unwrap();
 
-        let mut pipe = testing::Pipe::new().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+        // Server completes and confirms handshake, and sends HANDSHAKE_DONE.
+        let flight = testing::emit_flight(&mut pipe.server).unwrap();
 
-        let frames = [
-            frame::Frame::Stream {
-                stream_id: 0,
-                data: stream::RangeBuf::from(b"aaaaaaaaaaaaaaa", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 4,
-                data: stream::RangeBuf::from(b"aaaaaaaaaaaaaaa", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 8,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-        ];
+        assert!(pipe.client.is_established());
+        assert!(!pipe.client.handshake_confirmed);
 
-        let pkt_type = packet::Type::Short;
-        assert_eq!(
-            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
-            Err(Error::FlowControl),
-        );
-    }
+        assert!(pipe.server.is_established());
+        assert!(pipe.server.handshake_confirmed);
 
-    #[test]
-    fn flow_control_limit_dup() {
-        let mut buf = [0; 65535];
+        testing::process_flight(&mut pipe.client, flight).unwrap();
 
-        let mut pipe = testing::Pipe::new().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+        // Client acks 1-RTT packet, and confirms handshake.
+        let flight = testing::emit_flight(&mut pipe.client).unwrap();
 
-        let frames = [
-            // One byte less than stream limit.
-            frame::Frame::Stream {
-                stream_id: 0,
-                data: stream::RangeBuf::from(b"aaaaaaaaaaaaaa", 0, false),
-            },
-            // Same stream, but one byte more.
-            frame::Frame::Stream {
-                stream_id: 0,
-                data: stream::RangeBuf::from(b"aaaaaaaaaaaaaaa", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 8,
-                data: stream::RangeBuf::from(b"aaaaaaaaaaaaaaa", 0, false),
-            },
-        ];
+        assert!(pipe.client.is_established());
+        assert!(pipe.client.handshake_confirmed);
 
-        let pkt_type = packet::Type::Short;
-        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
-    }
+        assert!(pipe.server.is_established());
+        assert!(pipe.server.handshake_confirmed);
 
-    #[test]
-    fn flow_control_update() {
-        let mut buf = [0; 65535];
+        testing::process_flight(&mut pipe.server, flight).unwrap();
 
-        let mut pipe = testing::Pipe::new().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+        assert!(pipe.client.is_established());
+        assert!(pipe.client.handshake_confirmed);
 
-        let frames = [
-            frame::Frame::Stream {
-                stream_id: 0,
-                data: stream::RangeBuf::from(b"aaaaaaaaaaaaaaa", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 4,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-        ];
+        assert!(pipe.server.is_established());
+        assert!(pipe.server.handshake_confirmed);
+    }
 
-        let pkt_type = packet::Type::Short;
+    #[test]
+    fn handshake_resumption_session_store() {
+        #[derive(Default)]
+        struct Store(
+            std::sync::Mutex<std::collections::HashMap<String, Vec<u8>>>,
+        );
 
-        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
+        impl SessionStore for Store {
+            fn get(&self, server_name: &str) -> Option<Vec<u8>> {
+                self.0.lock().unwrap().get(server_name).cloned()
+            }
 
-        pipe.server.stream_recv(0, &mut buf).unwrap();
-        pipe.server.stream_recv(4, &mut buf).unwrap();
+            fn put(&self, server_name: &str, session: &[u8]) {
+                self.0
+                    .lock()
+                    .unwrap()
+                    .insert(server_name.to_string(), session.to_vec());
+            }
+        }
 
-        let frames = [frame::Frame::Stream {
-            stream_id: 4,
-            data: stream::RangeBuf::from(b"a", 1, false),
-        }];
+        let store = Arc::new(Store::default());
 
-        let len = pipe
-            .send_pkt_to_server(pkt_type, &frames, &mut buf)
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
             .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_streams_bidi(3);
+        config.verify_peer(false);
+        config.set_session_store(store.clone());
 
-        assert!(len > 0);
-
-        let frames =
-            testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
-        let mut iter = frames.iter();
-
-        // Ignore ACK.
-        iter.next().unwrap();
-
-        assert_eq!(
-            iter.next(),
-            Some(&frame::Frame::MaxStreamData {
-                stream_id: 0,
-                max: 30
-            })
-        );
-        assert_eq!(iter.next(), Some(&frame::Frame::MaxData { max: 61 }));
+        // Perform initial handshake, the session is stored automatically.
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert!(!pipe.client.is_resumed());
+
+        assert_eq!(store.get("quic.tech").as_deref(), pipe.client.session());
+
+        // The stored session is used by the next connection.
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert!(pipe.client.is_resumed());
+        assert!(pipe.server.is_resumed());
     }
 
     #[test]
-    /// Tests that flow control is properly updated even when a stream is shut
-    /// down.
-    fn flow_control_drain() {
-        let mut pipe = testing::Pipe::new().unwrap();
+    fn handshake_resumption() {
+        const SESSION_TICKET_KEY: [u8; 48] = [0xa; 48];
+
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_streams_bidi(3);
+        config.set_ticket_key(&SESSION_TICKET_KEY).unwrap();
+
+        // Perform initial handshake.
+        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        // Client opens a stream and sends some data.
-        assert_eq!(pipe.client.stream_send(4, b"aaaaa", false), Ok(5));
-        assert_eq!(pipe.advance(), Ok(()));
+        assert!(pipe.client.is_established());
+        assert!(pipe.server.is_established());
 
-        // Server receives data, without reading it.
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), Some(4));
-        assert_eq!(r.next(), None);
+        assert!(!pipe.client.is_resumed());
+        assert!(!pipe.server.is_resumed());
 
-        // In the meantime, client sends more data.
-        assert_eq!(pipe.client.stream_send(4, b"aaaaa", false), Ok(5));
-        assert_eq!(pipe.client.stream_send(4, b"aaaaa", true), Ok(5));
+        // Extract session,
+        let session = pipe.client.session().unwrap();
 
-        assert_eq!(pipe.client.stream_send(8, b"aaaaa", false), Ok(5));
-        assert_eq!(pipe.client.stream_send(8, b"aaaaa", false), Ok(5));
-        assert_eq!(pipe.client.stream_send(8, b"aaaaa", true), Ok(5));
+        // Configure session on new connection and perform handshake.
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_streams_bidi(3);
+        config.set_ticket_key(&SESSION_TICKET_KEY).unwrap();
 
-        // Server shuts down one stream.
-        assert_eq!(pipe.server.stream_shutdown(4, Shutdown::Read, 42), Ok(()));
+        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
 
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), None);
+        assert_eq!(pipe.client.set_session(session), Ok(()));
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        // Flush connection.
-        assert_eq!(pipe.advance(), Ok(()));
+        assert!(pipe.client.is_established());
+        assert!(pipe.server.is_established());
+
+        assert!(pipe.client.is_resumed());
+        assert!(pipe.server.is_resumed());
     }
 
     #[test]
-    fn stream_flow_control_limit_bidi() {
-        let mut buf = [0; 65535];
-
+    fn export_keying_material() {
         let mut pipe = testing::Pipe::new().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
-
-        let frames = [frame::Frame::Stream {
-            stream_id: 4,
-            data: stream::RangeBuf::from(b"aaaaaaaaaaaaaaaa", 0, true),
-        }];
 
-        let pkt_type = packet::Type::Short;
         assert_eq!(
-            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
-            Err(Error::FlowControl),
+            pipe.client
+                .export_keying_material(b"EXPORTER-test", b"", 32),
+            Err(Error::InvalidState)
         );
-    }
-
-    #[test]
-    fn stream_flow_control_limit_uni() {
-        let mut buf = [0; 65535];
 
-        let mut pipe = testing::Pipe::new().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        let frames = [frame::Frame::Stream {
-            stream_id: 2,
-            data: stream::RangeBuf::from(b"aaaaaaaaaaa", 0, true),
-        }];
+        let client = pipe
+            .client
+            .export_keying_material(b"EXPORTER-test", b"ctx", 32)
+            .unwrap();
+        let server = pipe
+            .server
+            .export_keying_material(b"EXPORTER-test", b"ctx", 32)
+            .unwrap();
 
-        let pkt_type = packet::Type::Short;
-        assert_eq!(
-            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
-            Err(Error::FlowControl),
+        assert_eq!(client.len(), 32);
+        assert_eq!(client, server);
+
+        // Different contexts derive different keys.
+        assert_ne!(
+            pipe.client
+                .export_keying_material(b"EXPORTER-test", b"other", 32)
+                .unwrap(),
+            client
         );
     }
 
     #[test]
-    fn stream_flow_control_update() {
-        let mut buf = [0; 65535];
+    fn packet_capture() {
+        type Packets = Vec<(PacketDirection, Type, u64, usize, Vec<u8>)>;
+
+        #[derive(Clone, Default)]
+        struct Capture(Arc<std::sync::Mutex<Packets>>);
+
+        impl PacketCapture for Capture {
+            fn on_packet(&mut self, pkt: &CapturedPacket) {
+                self.0.lock().unwrap().push((
+                    pkt.direction,
+                    pkt.ty,
+                    pkt.pkt_num,
+                    pkt.raw.len(),
+                    pkt.payload.to_vec(),
+                ));
+            }
+        }
+
+        let capture = Capture::default();
 
         let mut pipe = testing::Pipe::new().unwrap();
+        pipe.client.set_packet_capture(Box::new(capture.clone()));
         assert_eq!(pipe.handshake(), Ok(()));
 
-        let frames = [frame::Frame::Stream {
-            stream_id: 4,
-            data: stream::RangeBuf::from(b"aaaaaaaaa", 0, false),
-        }];
+        {
+            let pkts = capture.0.lock().unwrap();
+
+            // The first packet is the client's padded Initial.
+            let (direction, ty, pn, raw_len, payload) = &pkts[0];
+            assert_eq!(*direction, PacketDirection::Sent);
+            assert_eq!(*ty, Type::Initial);
+            assert_eq!(*pn, 0);
+            assert!(*raw_len >= MIN_CLIENT_INITIAL_LEN);
+            assert!(payload.len() < *raw_len);
+
+            assert!(pkts.iter().any(|(direction, ty, ..)| {
+                *direction == PacketDirection::Received && *ty == Type::Handshake
+            }));
+        }
 
-        let pkt_type = packet::Type::Short;
+        // Stream data can be found in the decrypted payload.
+        assert_eq!(pipe.client.stream_send(0, b"hello, world", true), Ok(12));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
+        let pkts = capture.0.lock().unwrap();
 
-        pipe.server.stream_recv(4, &mut buf).unwrap();
+        assert!(pkts.iter().any(|(direction, ty, _, _, payload)| {
+            *direction == PacketDirection::Sent &&
+                *ty == Type::Short &&
+                payload.windows(12).any(|w| w == b"hello, world")
+        }));
+    }
 
-        let frames = [frame::Frame::Stream {
-            stream_id: 4,
-            data: stream::RangeBuf::from(b"a", 9, false),
-        }];
+    #[test]
+    fn keylog_writer() {
+        #[derive(Clone, Default)]
+        struct Writer(Arc<std::sync::Mutex<Vec<u8>>>);
 
-        let len = pipe
-            .send_pkt_to_server(pkt_type, &frames, &mut buf)
-            .unwrap();
+        impl std::io::Write for Writer {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
 
-        assert!(len > 0);
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
 
-        let frames =
-            testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
-        let mut iter = frames.iter();
+        let writer = Writer::default();
 
-        // Ignore ACK.
-        iter.next().unwrap();
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.verify_peer(false);
+        config.set_keylog_writer(Box::new(writer.clone()));
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
+        let log = String::from_utf8(writer.0.lock().unwrap().clone()).unwrap();
+
+        // Secrets of both endpoints are logged.
         assert_eq!(
-            iter.next(),
-            Some(&frame::Frame::MaxStreamData {
-                stream_id: 4,
-                max: 24,
-            })
+            log.lines()
+                .filter(|l| l.starts_with("CLIENT_TRAFFIC_SECRET_0 "))
+                .count(),
+            2
         );
+        assert!(log.lines().all(|l| l.split(' ').count() == 3));
     }
 
     #[test]
-    fn stream_left_bidi() {
+    fn handshake_alpn_mismatch() {
         let mut buf = [0; 65535];
 
-        let mut pipe = testing::Pipe::new().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
-
-        assert_eq!(3, pipe.client.peer_streams_left_bidi());
-        assert_eq!(3, pipe.server.peer_streams_left_bidi());
-
-        pipe.server.stream_send(1, b"a", false).ok();
-        assert_eq!(2, pipe.server.peer_streams_left_bidi());
-        pipe.server.stream_send(5, b"a", false).ok();
-        assert_eq!(1, pipe.server.peer_streams_left_bidi());
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        config
+            .set_application_protos(&[b"proto3\x06proto4"])
+            .unwrap();
+        config.verify_peer(false);
 
-        pipe.server.stream_send(9, b"a", false).ok();
-        assert_eq!(0, pipe.server.peer_streams_left_bidi());
+        let mut pipe = testing::Pipe::with_client_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Err(Error::TlsFail));
 
-        let frames = [frame::Frame::MaxStreamsBidi { max: MAX_STREAM_ID }];
+        assert_eq!(pipe.client.application_proto(), b"");
+        assert_eq!(pipe.server.application_proto(), b"");
 
-        let pkt_type = packet::Type::Short;
-        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
+        // Server should only send one packet in response to ALPN mismatch.
+        let (len, _) = pipe.server.send(&mut buf).unwrap();
+        assert_eq!(len, 1200);
 
-        assert_eq!(MAX_STREAM_ID - 3, pipe.server.peer_streams_left_bidi());
+        assert_eq!(pipe.server.send(&mut buf), Err(Error::Done));
+        assert_eq!(pipe.server.sent_count, 1);
     }
 
     #[test]
-    fn stream_left_uni() {
+    fn handshake_0rtt() {
         let mut buf = [0; 65535];
 
-        let mut pipe = testing::Pipe::new().unwrap();
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_streams_bidi(3);
+        config.enable_early_data();
+        config.verify_peer(false);
+
+        // Perform initial handshake.
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        assert_eq!(3, pipe.client.peer_streams_left_uni());
-        assert_eq!(3, pipe.server.peer_streams_left_uni());
+        // Extract session,
+        let session = pipe.client.session().unwrap();
 
-        pipe.server.stream_send(3, b"a", false).ok();
-        assert_eq!(2, pipe.server.peer_streams_left_uni());
-        pipe.server.stream_send(7, b"a", false).ok();
-        assert_eq!(1, pipe.server.peer_streams_left_uni());
-
-        pipe.server.stream_send(11, b"a", false).ok();
-        assert_eq!(0, pipe.server.peer_streams_left_uni());
-
-        let frames = [frame::Frame::MaxStreamsUni { max: MAX_STREAM_ID }];
-
-        let pkt_type = packet::Type::Short;
-        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
-
-        assert_eq!(MAX_STREAM_ID - 3, pipe.server.peer_streams_left_uni());
-    }
+        // Configure session on new connection.
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.client.set_session(session), Ok(()));
 
-    #[test]
-    fn stream_limit_bidi() {
-        let mut buf = [0; 65535];
+        // Client sends initial flight.
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
 
-        let mut pipe = testing::Pipe::new().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+        // Client sends 0-RTT packet.
+        let pkt_type = packet::Type::ZeroRTT;
 
-        let frames = [
-            frame::Frame::Stream {
-                stream_id: 4,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 8,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 12,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 16,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 20,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 24,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 28,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-        ];
+        let frames = [frame::Frame::Stream {
+            stream_id: 4,
+            data: stream::RangeBuf::from(b"aaaaa", 0, true),
+        }];
 
-        let pkt_type = packet::Type::Short;
         assert_eq!(
             pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
-            Err(Error::StreamLimit),
+            Ok(1200)
         );
+
+        assert_eq!(pipe.server.undecryptable_pkts.len(), 0);
+
+        // 0-RTT stream data is readable.
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(4));
+        assert_eq!(r.next(), None);
+
+        let mut b = [0; 15];
+        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((5, true)));
+        assert_eq!(&b[..5], b"aaaaa");
     }
 
     #[test]
-    fn stream_limit_max_bidi() {
+    fn handshake_0rtt_reordered() {
         let mut buf = [0; 65535];
 
-        let mut pipe = testing::Pipe::new().unwrap();
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_streams_bidi(3);
+        config.enable_early_data();
+        config.verify_peer(false);
+
+        // Perform initial handshake.
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        let frames = [frame::Frame::MaxStreamsBidi { max: MAX_STREAM_ID }];
+        // Extract session,
+        let session = pipe.client.session().unwrap();
 
-        let pkt_type = packet::Type::Short;
-        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
+        // Configure session on new connection.
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.client.set_session(session), Ok(()));
 
-        let frames = [frame::Frame::MaxStreamsBidi {
-            max: MAX_STREAM_ID + 1,
+        // Client sends initial flight.
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        let mut initial = buf[..len].to_vec();
+
+        // Client sends 0-RTT packet.
+        let pkt_type = packet::Type::ZeroRTT;
+
+        let frames = [frame::Frame::Stream {
+            stream_id: 4,
+            data: stream::RangeBuf::from(b"aaaaa", 0, true),
         }];
 
-        let pkt_type = packet::Type::Short;
-        assert_eq!(
-            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
-            Err(Error::InvalidFrame),
-        );
-    }
+        let len =
+            testing::encode_pkt(&mut pipe.client, pkt_type, &frames, &mut buf)
+                .unwrap();
+        let mut zrtt = buf[..len].to_vec();
 
-    #[test]
-    fn stream_limit_uni() {
-        let mut buf = [0; 65535];
+        // 0-RTT packet is received before the Initial one.
+        assert_eq!(pipe.server_recv(&mut zrtt), Ok(zrtt.len()));
 
-        let mut pipe = testing::Pipe::new().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+        assert_eq!(pipe.server.undecryptable_pkts.len(), 1);
+        assert_eq!(pipe.server.undecryptable_pkts[0].0.len(), zrtt.len());
 
-        let frames = [
-            frame::Frame::Stream {
-                stream_id: 2,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 6,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 10,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 14,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 18,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 22,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 26,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-        ];
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), None);
 
-        let pkt_type = packet::Type::Short;
-        assert_eq!(
-            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
-            Err(Error::StreamLimit),
-        );
+        // Initial packet is also received.
+        assert_eq!(pipe.server_recv(&mut initial), Ok(initial.len()));
+
+        // 0-RTT stream data is readable.
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(4));
+        assert_eq!(r.next(), None);
+
+        let mut b = [0; 15];
+        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((5, true)));
+        assert_eq!(&b[..5], b"aaaaa");
     }
 
     #[test]
-    fn stream_limit_max_uni() {
+    fn handshake_0rtt_truncated() {
         let mut buf = [0; 65535];
 
-        let mut pipe = testing::Pipe::new().unwrap();
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_streams_bidi(3);
+        config.enable_early_data();
+        config.verify_peer(false);
+
+        // Perform initial handshake.
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        let frames = [frame::Frame::MaxStreamsUni { max: MAX_STREAM_ID }];
+        // Extract session,
+        let session = pipe.client.session().unwrap();
 
-        let pkt_type = packet::Type::Short;
-        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
+        // Configure session on new connection.
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.client.set_session(session), Ok(()));
 
-        let frames = [frame::Frame::MaxStreamsUni {
-            max: MAX_STREAM_ID + 1,
-        }];
+        // Client sends initial flight.
+        pipe.client.send(&mut buf).unwrap();
 
-        let pkt_type = packet::Type::Short;
-        assert_eq!(
-            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
-            Err(Error::InvalidFrame),
-        );
-    }
+        // Client sends 0-RTT packet.
+        let pkt_type = packet::Type::ZeroRTT;
 
-    #[test]
-    fn stream_left_reset_bidi() {
-        let mut buf = [0; 65535];
+        let frames = [frame::Frame::Stream {
+            stream_id: 4,
+            data: stream::RangeBuf::from(b"aaaaa", 0, true),
+        }];
 
-        let mut pipe = testing::Pipe::new().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+        let len =
+            testing::encode_pkt(&mut pipe.client, pkt_type, &frames, &mut buf)
+                .unwrap();
 
-        assert_eq!(3, pipe.client.peer_streams_left_bidi());
-        assert_eq!(3, pipe.server.peer_streams_left_bidi());
+        // Simulate a truncated packet by sending one byte less.
+        let mut zrtt = buf[..len - 1].to_vec();
 
-        pipe.client.stream_send(0, b"a", false).ok();
-        assert_eq!(2, pipe.client.peer_streams_left_bidi());
-        pipe.client.stream_send(4, b"a", false).ok();
-        assert_eq!(1, pipe.client.peer_streams_left_bidi());
-        pipe.client.stream_send(8, b"a", false).ok();
-        assert_eq!(0, pipe.client.peer_streams_left_bidi());
+        // 0-RTT packet is received before the Initial one.
+        assert_eq!(pipe.server_recv(&mut zrtt), Err(Error::InvalidPacket));
 
-        // Client resets the stream.
-        pipe.client
-            .stream_shutdown(0, Shutdown::Write, 1001)
-            .unwrap();
-        pipe.advance().unwrap();
+        assert_eq!(pipe.server.undecryptable_pkts.len(), 0);
 
-        assert_eq!(0, pipe.client.peer_streams_left_bidi());
-        let mut r = pipe.server.readable();
-        assert_eq!(Some(0), r.next());
-        assert_eq!(Some(4), r.next());
-        assert_eq!(Some(8), r.next());
-        assert_eq!(None, r.next());
+        assert!(pipe.server.is_closed());
+    }
 
-        assert_eq!(
-            pipe.server.stream_recv(0, &mut buf),
-            Err(Error::StreamReset(1001))
-        );
+    fn early_data_config() -> Config {
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_streams_bidi(3);
+        config.enable_early_data();
+        config.verify_peer(false);
+        config
+    }
 
-        let mut r = pipe.server.readable();
-        assert_eq!(Some(4), r.next());
-        assert_eq!(Some(8), r.next());
-        assert_eq!(None, r.next());
+    #[test]
+    fn handshake_0rtt_rejected() {
+        let mut config = early_data_config();
 
-        // Server resets the stream in reaction.
-        pipe.server
-            .stream_shutdown(0, Shutdown::Write, 1001)
-            .unwrap();
-        pipe.advance().unwrap();
+        // Perform initial handshake.
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        assert_eq!(1, pipe.client.peer_streams_left_bidi());
+        let session = pipe.client.session().unwrap();
 
-        // Repeat for the other 2 streams
-        pipe.client
-            .stream_shutdown(4, Shutdown::Write, 1001)
-            .unwrap();
-        pipe.client
-            .stream_shutdown(8, Shutdown::Write, 1001)
-            .unwrap();
-        pipe.advance().unwrap();
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.client.set_session(session), Ok(()));
 
-        let mut r = pipe.server.readable();
-        assert_eq!(Some(4), r.next());
-        assert_eq!(Some(8), r.next());
-        assert_eq!(None, r.next());
+        // Server doesn't accept early data on this connection.
+        assert_eq!(pipe.server.set_early_data_enabled(false), Ok(()));
 
-        assert_eq!(
-            pipe.server.stream_recv(4, &mut buf),
-            Err(Error::StreamReset(1001))
-        );
+        // Client sends initial flight and 0-RTT data.
+        let mut flight = testing::emit_flight(&mut pipe.client).unwrap();
+        assert!(pipe.client.is_in_early_data());
 
-        assert_eq!(
-            pipe.server.stream_recv(8, &mut buf),
-            Err(Error::StreamReset(1001))
-        );
+        assert_eq!(pipe.client.stream_send(4, b"aaaaa", true), Ok(5));
+        flight.append(&mut testing::emit_flight(&mut pipe.client).unwrap());
+
+        testing::process_flight(&mut pipe.server, flight).unwrap();
+
+        assert!(pipe.server.is_early_data_rejected());
 
         let mut r = pipe.server.readable();
-        assert_eq!(None, r.next());
+        assert_eq!(r.next(), None);
 
-        pipe.server
-            .stream_shutdown(4, Shutdown::Write, 1001)
-            .unwrap();
-        pipe.server
-            .stream_shutdown(8, Shutdown::Write, 1001)
-            .unwrap();
-        pipe.advance().unwrap();
+        // Rejected data is retransmitted once the handshake completes.
+        assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(3, pipe.client.peer_streams_left_bidi());
+        assert!(pipe.client.is_established());
+        assert!(pipe.client.is_early_data_rejected());
+
+        let mut b = [0; 15];
+        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((5, true)));
+        assert_eq!(&b[..5], b"aaaaa");
+
+        // Rejected 0-RTT packets are not counted as lost.
+        let stats = pipe.client.stats();
+        assert!(stats.early_data_rejected > 0);
+        assert!(stats.early_data_rejected_bytes > 0);
+        assert_eq!(stats.lost, 0);
+        assert_eq!(stats.lost_bytes, 0);
+
+        // Too late to change early data settings.
+        assert_eq!(
+            pipe.server.set_early_data_enabled(true),
+            Err(Error::InvalidState)
+        );
     }
 
     #[test]
-    fn stream_reset_counts() {
-        let mut pipe = testing::Pipe::new().unwrap();
+    fn handshake_0rtt_replay_filter() {
+        #[derive(Default)]
+        struct Filter(std::sync::Mutex<HashSet<Vec<u8>>>);
+
+        impl EarlyDataReplayFilter for Filter {
+            fn is_replay(&self, odcid: &[u8]) -> bool {
+                !self.0.lock().unwrap().insert(odcid.to_vec())
+            }
+        }
+
+        let mut config = early_data_config();
+        config.set_early_data_replay_filter(Arc::new(Filter::default()));
+
+        // Perform initial handshake.
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        pipe.client.stream_send(0, b"a", false).ok();
-        pipe.client.stream_send(2, b"a", false).ok();
-        pipe.client.stream_send(4, b"a", false).ok();
-        pipe.client.stream_send(8, b"a", false).ok();
-        pipe.advance().unwrap();
+        let session = pipe.client.session().unwrap();
 
-        let stats = pipe.client.stats();
-        assert_eq!(stats.reset_stream_count_local, 0);
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.client.set_session(session), Ok(()));
 
-        // Client resets the stream.
-        pipe.client
-            .stream_shutdown(0, Shutdown::Write, 1001)
-            .unwrap();
-        pipe.advance().unwrap();
+        let mut flight = testing::emit_flight(&mut pipe.client).unwrap();
+        assert_eq!(pipe.client.stream_send(4, b"aaaaa", true), Ok(5));
+        flight.append(&mut testing::emit_flight(&mut pipe.client).unwrap());
 
-        let stats = pipe.client.stats();
-        assert_eq!(stats.reset_stream_count_local, 1);
-        assert_eq!(stats.reset_stream_count_remote, 0);
-        let stats = pipe.server.stats();
-        assert_eq!(stats.reset_stream_count_local, 0);
-        assert_eq!(stats.reset_stream_count_remote, 1);
+        // Early data is accepted the first time.
+        testing::process_flight(&mut pipe.server, flight.clone()).unwrap();
+        assert!(!pipe.server.is_early_data_rejected());
 
-        // Server resets the stream in reaction.
-        pipe.server
-            .stream_shutdown(0, Shutdown::Write, 1001)
-            .unwrap();
-        pipe.advance().unwrap();
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(4));
+        assert_eq!(r.next(), None);
 
-        let stats = pipe.client.stats();
-        assert_eq!(stats.reset_stream_count_local, 1);
-        assert_eq!(stats.reset_stream_count_remote, 1);
-        let stats = pipe.server.stats();
-        assert_eq!(stats.reset_stream_count_local, 1);
-        assert_eq!(stats.reset_stream_count_remote, 1);
+        // The same flight is replayed to another server.
+        let mut replay = testing::Pipe::with_config(&mut config).unwrap();
+        testing::process_flight(&mut replay.server, flight).unwrap();
+        assert!(replay.server.is_early_data_rejected());
 
-        // Repeat for the other streams
-        pipe.client
-            .stream_shutdown(2, Shutdown::Write, 1001)
+        let mut r = replay.server.readable();
+        assert_eq!(r.next(), None);
+    }
+
+    #[test]
+    fn limit_handshake_data() {
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert-big.crt")
             .unwrap();
-        pipe.client
-            .stream_shutdown(4, Shutdown::Write, 1001)
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
             .unwrap();
-        pipe.client
-            .stream_shutdown(8, Shutdown::Write, 1001)
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
             .unwrap();
-        pipe.advance().unwrap();
 
-        pipe.server
-            .stream_shutdown(4, Shutdown::Write, 1001)
-            .unwrap();
-        pipe.server
-            .stream_shutdown(8, Shutdown::Write, 1001)
-            .unwrap();
-        pipe.advance().unwrap();
+        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
 
-        let stats = pipe.client.stats();
-        assert_eq!(stats.reset_stream_count_local, 4);
-        assert_eq!(stats.reset_stream_count_remote, 3);
-        let stats = pipe.server.stats();
-        assert_eq!(stats.reset_stream_count_local, 3);
-        assert_eq!(stats.reset_stream_count_remote, 4);
+        let flight = testing::emit_flight(&mut pipe.client).unwrap();
+        let client_sent = flight.iter().fold(0, |out, p| out + p.0.len());
+        testing::process_flight(&mut pipe.server, flight).unwrap();
+
+        let flight = testing::emit_flight(&mut pipe.server).unwrap();
+        let server_sent = flight.iter().fold(0, |out, p| out + p.0.len());
+
+        assert_eq!(server_sent, client_sent * MAX_AMPLIFICATION_FACTOR);
+
+        assert_eq!(
+            pipe.server.send_blocked_reason(),
+            Some(SendBlockedReason::AmplificationLimit)
+        );
     }
 
     #[test]
-    fn stream_stop_counts() {
+    fn coalescing_policy() {
         let mut pipe = testing::Pipe::new().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        pipe.client.stream_send(0, b"a", false).ok();
-        pipe.client.stream_send(2, b"a", false).ok();
-        pipe.client.stream_send(4, b"a", false).ok();
-        pipe.client.stream_send(8, b"a", false).ok();
-        pipe.advance().unwrap();
-
-        let stats = pipe.client.stats();
-        assert_eq!(stats.reset_stream_count_local, 0);
-
-        // Server stops the stream and client automatically resets.
-        pipe.server
-            .stream_shutdown(0, Shutdown::Read, 1001)
-            .unwrap();
-        pipe.advance().unwrap();
-
-        let stats = pipe.client.stats();
-        assert_eq!(stats.stopped_stream_count_local, 0);
-        assert_eq!(stats.stopped_stream_count_remote, 1);
-        assert_eq!(stats.reset_stream_count_local, 1);
-        assert_eq!(stats.reset_stream_count_remote, 0);
-
-        let stats = pipe.server.stats();
-        assert_eq!(stats.stopped_stream_count_local, 1);
-        assert_eq!(stats.stopped_stream_count_remote, 0);
-        assert_eq!(stats.reset_stream_count_local, 0);
-        assert_eq!(stats.reset_stream_count_remote, 1);
+        assert!(pipe.server.stats().coalesced_dgrams > 0);
 
-        // Repeat for the other streams
-        pipe.server
-            .stream_shutdown(2, Shutdown::Read, 1001)
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
             .unwrap();
-        pipe.server
-            .stream_shutdown(4, Shutdown::Read, 1001)
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
             .unwrap();
-        pipe.server
-            .stream_shutdown(8, Shutdown::Read, 1001)
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
             .unwrap();
-        pipe.advance().unwrap();
+        config.set_coalescing_policy(CoalescingPolicy::Disabled);
 
-        let stats = pipe.client.stats();
-        assert_eq!(stats.stopped_stream_count_local, 0);
-        assert_eq!(stats.stopped_stream_count_remote, 4);
-        assert_eq!(stats.reset_stream_count_local, 4);
-        assert_eq!(stats.reset_stream_count_remote, 0);
+        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
         let stats = pipe.server.stats();
-        assert_eq!(stats.stopped_stream_count_local, 4);
-        assert_eq!(stats.stopped_stream_count_remote, 0);
-        assert_eq!(stats.reset_stream_count_local, 0);
-        assert_eq!(stats.reset_stream_count_remote, 4);
+        assert_eq!(stats.coalesced_dgrams, 0);
+        assert_eq!(stats.sent_dgrams, stats.sent);
     }
 
     #[test]
-    fn streams_blocked_max_bidi() {
-        let mut buf = [0; 65535];
+    fn initial_padding_policy() {
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.verify_peer(false);
+        config.set_initial_padding_policy(InitialPaddingPolicy::FirstOnly);
 
-        let mut pipe = testing::Pipe::new().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+        let mut pipe = testing::Pipe::with_client_config(&mut config).unwrap();
 
-        let frames = [frame::Frame::StreamsBlockedBidi {
-            limit: MAX_STREAM_ID,
-        }];
+        let flight = testing::emit_flight(&mut pipe.client).unwrap();
+        assert!(flight[0].0.len() >= MIN_CLIENT_INITIAL_LEN);
+        testing::process_flight(&mut pipe.server, flight).unwrap();
 
-        let pkt_type = packet::Type::Short;
-        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
+        let flight = testing::emit_flight(&mut pipe.server).unwrap();
+        testing::process_flight(&mut pipe.client, flight).unwrap();
 
-        let frames = [frame::Frame::StreamsBlockedBidi {
-            limit: MAX_STREAM_ID + 1,
-        }];
+        // The client's second Initial packet is not padded anymore.
+        let flight = testing::emit_flight(&mut pipe.client).unwrap();
+        assert!(flight[0].0.len() < MIN_CLIENT_INITIAL_LEN);
+        testing::process_flight(&mut pipe.server, flight).unwrap();
 
-        let pkt_type = packet::Type::Short;
-        assert_eq!(
-            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
-            Err(Error::InvalidFrame),
-        );
+        assert_eq!(pipe.handshake(), Ok(()));
     }
 
     #[test]
-    fn streams_blocked_max_uni() {
-        let mut buf = [0; 65535];
-
-        let mut pipe = testing::Pipe::new().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+    fn validate_peer_address_early() {
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert-big.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
 
-        let frames = [frame::Frame::StreamsBlockedUni {
-            limit: MAX_STREAM_ID,
-        }];
+        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
 
-        let pkt_type = packet::Type::Short;
-        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
+        assert_eq!(pipe.client.amplification_budget(), None);
+        assert_eq!(
+            pipe.client.validate_peer_address(),
+            Err(Error::InvalidState)
+        );
 
-        let frames = [frame::Frame::StreamsBlockedUni {
-            limit: MAX_STREAM_ID + 1,
-        }];
+        let flight = testing::emit_flight(&mut pipe.client).unwrap();
+        let client_sent = flight.iter().fold(0, |out, p| out + p.0.len());
+        testing::process_flight(&mut pipe.server, flight).unwrap();
 
-        let pkt_type = packet::Type::Short;
         assert_eq!(
-            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
-            Err(Error::InvalidFrame),
+            pipe.server.amplification_budget(),
+            Some(client_sent * MAX_AMPLIFICATION_FACTOR)
         );
-    }
-
-    #[test]
-    fn stream_data_overlap() {
-        let mut buf = [0; 65535];
 
-        let mut pipe = testing::Pipe::new().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+        let flight = testing::emit_flight(&mut pipe.server).unwrap();
+        let server_sent = flight.iter().fold(0, |out, p| out + p.0.len());
 
-        let frames = [
-            frame::Frame::Stream {
-                stream_id: 0,
-                data: stream::RangeBuf::from(b"aaaaa", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 0,
-                data: stream::RangeBuf::from(b"bbbbb", 3, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 0,
-                data: stream::RangeBuf::from(b"ccccc", 6, false),
-            },
-        ];
+        assert_eq!(server_sent, client_sent * MAX_AMPLIFICATION_FACTOR);
+        assert_eq!(pipe.server.amplification_budget(), Some(0));
 
-        let pkt_type = packet::Type::Short;
-        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
+        // Validating the address out-of-band lifts the limit.
+        assert_eq!(pipe.server.validate_peer_address(), Ok(()));
+        assert_eq!(pipe.server.amplification_budget(), None);
 
-        let mut b = [0; 15];
-        assert_eq!(pipe.server.stream_recv(0, &mut b), Ok((11, false)));
-        assert_eq!(&b[..11], b"aaaaabbbccc");
+        let flight = testing::emit_flight(&mut pipe.server).unwrap();
+        assert!(!flight.is_empty());
+        assert_eq!(pipe.server.send_blocked_reason(), None);
     }
 
     #[test]
-    fn stream_data_overlap_with_reordering() {
-        let mut buf = [0; 65535];
+    fn limit_handshake_data_custom_amplification_factor() {
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert-big.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.set_max_amplification_factor(2);
 
-        let mut pipe = testing::Pipe::new().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
 
-        let frames = [
-            frame::Frame::Stream {
-                stream_id: 0,
-                data: stream::RangeBuf::from(b"aaaaa", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 0,
-                data: stream::RangeBuf::from(b"ccccc", 6, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 0,
-                data: stream::RangeBuf::from(b"bbbbb", 3, false),
-            },
-        ];
+        let flight = testing::emit_flight(&mut pipe.client).unwrap();
+        let client_sent = flight.iter().fold(0, |out, p| out + p.0.len());
+        testing::process_flight(&mut pipe.server, flight).unwrap();
 
-        let pkt_type = packet::Type::Short;
-        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
+        let flight = testing::emit_flight(&mut pipe.server).unwrap();
+        let server_sent = flight.iter().fold(0, |out, p| out + p.0.len());
 
-        let mut b = [0; 15];
-        assert_eq!(pipe.server.stream_recv(0, &mut b), Ok((11, false)));
-        assert_eq!(&b[..11], b"aaaaabccccc");
+        assert_eq!(server_sent, client_sent * 2);
     }
 
     #[test]
-    /// Tests that receiving a valid RESET_STREAM frame when all data has
-    /// already been read, notifies the application.
-    fn reset_stream_data_recvd() {
-        let mut b = [0; 15];
-        let mut buf = [0; 65535];
+    fn send_blocked_by_cwnd() {
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.set_initial_max_data(50000);
+        config.set_initial_max_stream_data_bidi_local(50000);
+        config.set_initial_max_stream_data_bidi_remote(50000);
+        config.set_initial_max_streams_bidi(3);
+        config.set_initial_max_streams_uni(3);
+        config.set_max_recv_udp_payload_size(1200);
+        config.verify_peer(false);
 
-        let mut pipe = testing::Pipe::new().unwrap();
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        // Client sends some data.
-        assert_eq!(pipe.client.stream_send(0, b"hello", false), Ok(5));
-        assert_eq!(pipe.advance(), Ok(()));
+        let mut buf = [0; 2000];
 
-        // Server gets data and sends data back, closing stream.
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), Some(0));
-        assert_eq!(r.next(), None);
+        // Nothing to send.
+        assert_eq!(pipe.client.send(&mut buf), Err(Error::Done));
+        assert_eq!(pipe.client.send_blocked_reason(), None);
 
-        assert_eq!(pipe.server.stream_recv(0, &mut b), Ok((5, false)));
-        assert!(!pipe.server.stream_finished(0));
+        // Client sends stream data bigger than cwnd.
+        let send_buf = [0; 20000];
+        assert_eq!(pipe.client.stream_send(0, &send_buf, false), Ok(12000));
 
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), None);
+        testing::emit_flight(&mut pipe.client).unwrap();
 
-        assert_eq!(pipe.server.stream_send(0, b"", true), Ok(0));
-        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(pipe.client.send(&mut buf), Err(Error::Done));
+        assert_eq!(
+            pipe.client.send_blocked_reason(),
+            Some(SendBlockedReason::CongestionWindow)
+        );
+    }
 
-        let mut r = pipe.client.readable();
-        assert_eq!(r.next(), Some(0));
-        assert_eq!(r.next(), None);
+    #[test]
+    fn send_blocked_by_pacing() {
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.set_initial_max_data(1_000_000);
+        config.set_initial_max_stream_data_bidi_local(1_000_000);
+        config.set_initial_max_stream_data_bidi_remote(1_000_000);
+        config.set_initial_max_streams_bidi(3);
+        config.set_max_recv_udp_payload_size(1200);
+        config.set_max_pacing_rate(12_000);
+        config.set_pacing_horizon(1);
+        config.verify_peer(false);
 
-        assert_eq!(pipe.client.stream_recv(0, &mut b), Ok((0, true)));
-        assert!(pipe.client.stream_finished(0));
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        // Client sends RESET_STREAM, closing stream.
-        let frames = [frame::Frame::ResetStream {
-            stream_id: 0,
-            error_code: 42,
-            final_size: 5,
-        }];
+        let send_buf = [0; 100_000];
 
-        let pkt_type = packet::Type::Short;
-        assert_eq!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf), Ok(39));
+        // Once the initial window was sent, each burst is paced to 1 second at
+        // 12000 bytes per second, which is past the horizon.
+        let mut release = None;
 
-        // Server is notified of stream readability, due to reset.
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), Some(0));
-        assert_eq!(r.next(), None);
+        for _ in 0..10 {
+            let _ = pipe.client.stream_send(0, &send_buf, false);
+            assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(
-            pipe.server.stream_recv(0, &mut b),
-            Err(Error::StreamReset(42))
-        );
+            if let Some(SendBlockedReason::Pacing(t)) =
+                pipe.client.send_blocked_reason()
+            {
+                release = Some(t);
+                break;
+            }
+        }
 
-        assert!(pipe.server.stream_finished(0));
+        let release = release.expect("not blocked by pacing");
+        assert!(release > time::Instant::now());
 
-        // Sending RESET_STREAM again shouldn't make stream readable again.
-        pipe.send_pkt_to_server(pkt_type, &frames, &mut buf)
-            .unwrap();
+        let mut buf = [0; 2000];
 
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), None);
+        assert_eq!(pipe.client.send(&mut buf), Err(Error::Done));
+        assert!(matches!(
+            pipe.client.send_blocked_reason(),
+            Some(SendBlockedReason::Pacing(t)) if t >= release
+        ));
     }
 
     #[test]
-    /// Tests that receiving a valid RESET_STREAM frame when all data has _not_
-    /// been read, discards all buffered data and notifies the application.
-    fn reset_stream_data_not_recvd() {
-        let mut b = [0; 15];
-        let mut buf = [0; 65535];
-
+    fn stream() {
         let mut pipe = testing::Pipe::new().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        // Client sends some data.
-        assert_eq!(pipe.client.stream_send(0, b"h", false), Ok(1));
+        assert_eq!(pipe.client.stream_send(4, b"hello, world", true), Ok(12));
         assert_eq!(pipe.advance(), Ok(()));
 
-        // Server gets data and sends data back, closing stream.
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), Some(0));
-        assert_eq!(r.next(), None);
-
-        assert_eq!(pipe.server.stream_recv(0, &mut b), Ok((1, false)));
-        assert!(!pipe.server.stream_finished(0));
+        assert!(!pipe.server.stream_finished(4));
 
         let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(4));
         assert_eq!(r.next(), None);
 
-        assert_eq!(pipe.server.stream_send(0, b"", true), Ok(0));
-        assert_eq!(pipe.advance(), Ok(()));
-
-        let mut r = pipe.client.readable();
-        assert_eq!(r.next(), Some(0));
-        assert_eq!(r.next(), None);
-
-        assert_eq!(pipe.client.stream_recv(0, &mut b), Ok((0, true)));
-        assert!(pipe.client.stream_finished(0));
+        let mut b = [0; 15];
+        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((12, true)));
+        assert_eq!(&b[..12], b"hello, world");
 
-        // Client sends RESET_STREAM, closing stream.
-        let frames = [frame::Frame::ResetStream {
-            stream_id: 0,
-            error_code: 42,
-            final_size: 5,
-        }];
+        assert!(pipe.server.stream_finished(4));
+    }
 
-        let pkt_type = packet::Type::Short;
-        assert_eq!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf), Ok(39));
+    #[test]
+    fn zero_rtt() {
+        let mut buf = [0; 65535];
 
-        // Server is notified of stream readability, due to reset.
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), Some(0));
-        assert_eq!(r.next(), None);
-
-        assert_eq!(
-            pipe.server.stream_recv(0, &mut b),
-            Err(Error::StreamReset(42))
-        );
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_streams_bidi(3);
+        config.enable_early_data();
+        config.verify_peer(false);
 
-        assert!(pipe.server.stream_finished(0));
+        // Perform initial handshake.
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        // Sending RESET_STREAM again shouldn't make stream readable again.
-        assert_eq!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf), Ok(39));
+        // Extract session,
+        let session = pipe.client.session().unwrap();
 
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), None);
-    }
+        // Configure session on new connection.
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.client.set_session(session), Ok(()));
 
-    #[test]
-    /// Tests that RESET_STREAM frames exceeding the connection-level flow
-    /// control limit cause an error.
-    fn reset_stream_flow_control() {
-        let mut buf = [0; 65535];
+        // Client sends initial flight.
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        let mut initial = buf[..len].to_vec();
 
-        let mut pipe = testing::Pipe::new().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+        assert!(pipe.client.is_in_early_data());
 
-        let frames = [
-            frame::Frame::Stream {
-                stream_id: 0,
-                data: stream::RangeBuf::from(b"aaaaaaaaaaaaaaa", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 4,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-            frame::Frame::ResetStream {
-                stream_id: 4,
-                error_code: 0,
-                final_size: 15,
-            },
-            frame::Frame::Stream {
-                stream_id: 8,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-        ];
+        // Client sends 0-RTT data.
+        assert_eq!(pipe.client.stream_send(4, b"hello, world", true), Ok(12));
 
-        let pkt_type = packet::Type::Short;
-        assert_eq!(
-            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
-            Err(Error::FlowControl),
-        );
-    }
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        let mut zrtt = buf[..len].to_vec();
 
-    #[test]
-    /// Tests that RESET_STREAM frames exceeding the stream-level flow control
-    /// limit cause an error.
-    fn reset_stream_flow_control_stream() {
-        let mut buf = [0; 65535];
+        // Server receives packets.
+        assert_eq!(pipe.server_recv(&mut initial), Ok(initial.len()));
+        assert!(pipe.server.is_in_early_data());
 
-        let mut pipe = testing::Pipe::new().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+        assert_eq!(pipe.server_recv(&mut zrtt), Ok(zrtt.len()));
 
-        let frames = [
-            frame::Frame::Stream {
-                stream_id: 4,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-            frame::Frame::ResetStream {
-                stream_id: 4,
-                error_code: 0,
-                final_size: 16, // Past stream's flow control limit.
-            },
-        ];
+        // 0-RTT stream data is readable.
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(4));
+        assert_eq!(r.next(), None);
 
-        let pkt_type = packet::Type::Short;
-        assert_eq!(
-            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
-            Err(Error::FlowControl),
-        );
+        let mut b = [0; 15];
+        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((12, true)));
+        assert_eq!(&b[..12], b"hello, world");
     }
 
     #[test]
-    fn path_challenge() {
-        let mut buf = [0; 65535];
-
-        let mut pipe = testing::Pipe::new().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
-
-        let frames = [frame::Frame::PathChallenge { data: [0xba; 8] }];
-
-        let pkt_type = packet::Type::Short;
-
-        let len = pipe
-            .send_pkt_to_server(pkt_type, &frames, &mut buf)
+    fn stream_send_on_32bit_arch() {
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
             .unwrap();
+        config.set_initial_max_data(2_u64.pow(32) + 5);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_stream_data_uni(10);
+        config.set_initial_max_streams_bidi(3);
+        config.set_initial_max_streams_uni(0);
+        config.verify_peer(false);
 
-        assert!(len > 0);
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        let frames =
-            testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
-        let mut iter = frames.iter();
+        // In 32bit arch, send_capacity() should be min(2^32+5, cwnd),
+        // not min(5, cwnd)
+        assert_eq!(pipe.client.stream_send(4, b"hello, world", true), Ok(12));
 
-        // Ignore ACK.
-        iter.next().unwrap();
+        assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(
-            iter.next(),
-            Some(&frame::Frame::PathResponse { data: [0xba; 8] })
-        );
+        assert!(!pipe.server.stream_finished(4));
     }
 
     #[test]
-    /// Simulates reception of an early 1-RTT packet on the server, by
-    /// delaying the client's Handshake packet that completes the handshake.
-    fn early_1rtt_packet() {
+    fn empty_stream_frame() {
         let mut buf = [0; 65535];
 
         let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        // Client sends initial flight
-        let flight = testing::emit_flight(&mut pipe.client).unwrap();
-        testing::process_flight(&mut pipe.server, flight).unwrap();
-
-        // Server sends initial flight.
-        let flight = testing::emit_flight(&mut pipe.server).unwrap();
-        testing::process_flight(&mut pipe.client, flight).unwrap();
-
-        // Client sends Handshake packet.
-        let flight = testing::emit_flight(&mut pipe.client).unwrap();
-
-        // Emulate handshake packet delay by not making server process client
-        // packet.
-        let delayed = flight;
-
-        testing::emit_flight(&mut pipe.server).ok();
-
-        assert!(pipe.client.is_established());
-
-        // Send 1-RTT packet #0.
         let frames = [frame::Frame::Stream {
-            stream_id: 0,
-            data: stream::RangeBuf::from(b"hello, world", 0, true),
+            stream_id: 4,
+            data: stream::RangeBuf::from(b"aaaaa", 0, false),
         }];
 
         let pkt_type = packet::Type::Short;
-        let written =
-            testing::encode_pkt(&mut pipe.client, pkt_type, &frames, &mut buf)
-                .unwrap();
+        assert_eq!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf), Ok(39));
 
-        assert_eq!(pipe.server_recv(&mut buf[..written]), Ok(written));
+        let mut readable = pipe.server.readable();
+        assert_eq!(readable.next(), Some(4));
+
+        assert_eq!(pipe.server.stream_recv(4, &mut buf), Ok((5, false)));
 
-        // Send 1-RTT packet #1.
         let frames = [frame::Frame::Stream {
             stream_id: 4,
-            data: stream::RangeBuf::from(b"hello, world", 0, true),
+            data: stream::RangeBuf::from(b"", 5, true),
         }];
 
-        let written =
-            testing::encode_pkt(&mut pipe.client, pkt_type, &frames, &mut buf)
-                .unwrap();
-
-        assert_eq!(pipe.server_recv(&mut buf[..written]), Ok(written));
-
-        assert!(!pipe.server.is_established());
+        let pkt_type = packet::Type::Short;
+        assert_eq!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf), Ok(39));
 
-        // Client sent 1-RTT packets 0 and 1, but server hasn't received them.
-        //
-        // Note that `largest_rx_pkt_num` is initialized to 0, so we need to
-        // send another 1-RTT packet to make this check meaningful.
-        assert_eq!(
-            pipe.server.pkt_num_spaces[packet::Epoch::Application]
-                .largest_rx_pkt_num,
-            0
-        );
+        let mut readable = pipe.server.readable();
+        assert_eq!(readable.next(), Some(4));
 
-        // Process delayed packet.
-        testing::process_flight(&mut pipe.server, delayed).unwrap();
+        assert_eq!(pipe.server.stream_recv(4, &mut buf), Ok((0, true)));
 
-        assert!(pipe.server.is_established());
+        let frames = [frame::Frame::Stream {
+            stream_id: 4,
+            data: stream::RangeBuf::from(b"", 15, true),
+        }];
 
+        let pkt_type = packet::Type::Short;
         assert_eq!(
-            pipe.server.pkt_num_spaces[packet::Epoch::Application]
-                .largest_rx_pkt_num,
-            0
+            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
+            Err(Error::FinalSize)
         );
     }
 
     #[test]
-    fn stop_sending() {
+    fn update_key_request() {
         let mut b = [0; 15];
 
-        let mut buf = [0; 65535];
-
         let mut pipe = testing::Pipe::new().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
-
-        // Client sends some data, and closes stream.
-        assert_eq!(pipe.client.stream_send(0, b"hello", true), Ok(5));
         assert_eq!(pipe.advance(), Ok(()));
 
-        // Server gets data.
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), Some(0));
-        assert_eq!(r.next(), None);
-
-        assert_eq!(pipe.server.stream_recv(0, &mut b), Ok((5, true)));
-        assert!(pipe.server.stream_finished(0));
+        // Client sends message with key update request.
+        assert_eq!(pipe.client_update_key(), Ok(()));
+        assert_eq!(pipe.client.stream_send(4, b"hello", false), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
 
+        // Ensure server updates key and it correctly decrypts the message.
         let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(4));
         assert_eq!(r.next(), None);
+        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((5, false)));
+        assert_eq!(&b[..5], b"hello");
 
-        // Server sends data, until blocked.
-        let mut r = pipe.server.writable();
-        assert_eq!(r.next(), Some(0));
-        assert_eq!(r.next(), None);
-
-        loop {
-            if pipe.server.stream_send(0, b"world", false) == Err(Error::Done) {
-                break;
-            }
-
-            assert_eq!(pipe.advance(), Ok(()));
-        }
-
-        let mut r = pipe.server.writable();
-        assert_eq!(r.next(), None);
-
-        // Client sends STOP_SENDING.
-        let frames = [frame::Frame::StopSending {
-            stream_id: 0,
-            error_code: 42,
-        }];
-
-        let pkt_type = packet::Type::Short;
-        let len = pipe
-            .send_pkt_to_server(pkt_type, &frames, &mut buf)
-            .unwrap();
-
-        // Server sent a RESET_STREAM frame in response.
-        let frames =
-            testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
-
-        let mut iter = frames.iter();
-
-        // Skip ACK frame.
-        iter.next();
-
-        assert_eq!(
-            iter.next(),
-            Some(&frame::Frame::ResetStream {
-                stream_id: 0,
-                error_code: 42,
-                final_size: 15,
-            })
-        );
-
-        // Stream is writable, but writing returns an error.
-        let mut r = pipe.server.writable();
-        assert_eq!(r.next(), Some(0));
-        assert_eq!(r.next(), None);
-
-        assert_eq!(
-            pipe.server.stream_send(0, b"world", true),
-            Err(Error::StreamStopped(42)),
+        // Ensure ACK for key update.
+        assert!(
+            pipe.server.pkt_num_spaces[packet::Epoch::Application]
+                .key_update
+                .as_ref()
+                .unwrap()
+                .update_acked
         );
 
-        assert_eq!(pipe.server.streams.len(), 1);
-
-        // Client acks RESET_STREAM frame.
-        let mut ranges = ranges::RangeSet::default();
-        ranges.insert(0..6);
-
-        let frames = [frame::Frame::ACK {
-            ack_delay: 15,
-            ranges,
-            ecn_counts: None,
-        }];
-
-        assert_eq!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf), Ok(0));
-
-        // Stream is collected on the server after RESET_STREAM is acked.
-        assert_eq!(pipe.server.streams.len(), 0);
-
-        // Sending STOP_SENDING again shouldn't trigger RESET_STREAM again.
-        let frames = [frame::Frame::StopSending {
-            stream_id: 0,
-            error_code: 42,
-        }];
-
-        let len = pipe
-            .send_pkt_to_server(pkt_type, &frames, &mut buf)
-            .unwrap();
-
-        let frames =
-            testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
-
-        assert_eq!(frames.len(), 1);
-
-        match frames.first() {
-            Some(frame::Frame::ACK { .. }) => (),
-
-            f => panic!("expected ACK frame, got {:?}", f),
-        };
+        // Server sends message with the new key.
+        assert_eq!(pipe.server.stream_send(4, b"world", true), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let mut r = pipe.server.writable();
+        // Ensure update key is completed and client can decrypt packet.
+        let mut r = pipe.client.readable();
+        assert_eq!(r.next(), Some(4));
         assert_eq!(r.next(), None);
+        assert_eq!(pipe.client.stream_recv(4, &mut b), Ok((5, true)));
+        assert_eq!(&b[..5], b"world");
     }
 
     #[test]
-    fn stop_sending_fin() {
+    fn initiate_key_update() {
         let mut b = [0; 15];
 
-        let mut buf = [0; 65535];
-
         let mut pipe = testing::Pipe::new().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
-
-        // Client sends some data, and closes stream.
-        assert_eq!(pipe.client.stream_send(4, b"hello", true), Ok(5));
         assert_eq!(pipe.advance(), Ok(()));
 
-        // Server gets data.
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), Some(4));
-        assert_eq!(r.next(), None);
-
-        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((5, true)));
-        assert!(pipe.server.stream_finished(4));
-
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), None);
+        assert_eq!(pipe.client.initiate_key_update(), Ok(()));
 
-        // Server sends data...
-        let mut r = pipe.server.writable();
-        assert_eq!(r.next(), Some(4));
-        assert_eq!(r.next(), None);
+        // The peer has not responded yet.
+        assert_eq!(pipe.client.initiate_key_update(), Err(Error::InvalidState));
 
-        assert_eq!(pipe.server.stream_send(4, b"world", false), Ok(5));
+        assert_eq!(pipe.client.stream_send(4, b"hello", false), Ok(5));
         assert_eq!(pipe.advance(), Ok(()));
 
-        // ...and buffers more, and closes stream.
-        assert_eq!(pipe.server.stream_send(4, b"world", true), Ok(5));
+        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((5, false)));
+        assert_eq!(&b[..5], b"hello");
 
-        // Client sends STOP_SENDING before server flushes stream.
-        let frames = [frame::Frame::StopSending {
-            stream_id: 4,
-            error_code: 42,
-        }];
+        assert_eq!(pipe.server.stream_send(4, b"world", true), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let pkt_type = packet::Type::Short;
-        let len = pipe
-            .send_pkt_to_server(pkt_type, &frames, &mut buf)
-            .unwrap();
+        assert_eq!(pipe.client.stream_recv(4, &mut b), Ok((5, true)));
+        assert_eq!(&b[..5], b"world");
 
-        // Server sent a RESET_STREAM frame in response.
-        let frames =
-            testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
+        assert_ne!(
+            pipe.client.pkt_num_spaces[packet::Epoch::Application]
+                .key_update
+                .as_ref()
+                .unwrap()
+                .pn_on_update,
+            u64::MAX
+        );
 
-        let mut iter = frames.iter();
+        // The previous update is complete, so keys can be updated again.
+        assert_eq!(pipe.client.initiate_key_update(), Ok(()));
+        assert_eq!(pipe.client.stream_send(8, b"again", true), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        // Skip ACK frame.
-        iter.next();
+        assert_eq!(pipe.server.stream_recv(8, &mut b), Ok((5, true)));
+        assert_eq!(&b[..5], b"again");
 
-        assert_eq!(
-            iter.next(),
-            Some(&frame::Frame::ResetStream {
-                stream_id: 4,
-                error_code: 42,
-                final_size: 5,
-            })
-        );
+        assert_eq!(pipe.client.stats().key_updates_local, 2);
+        assert_eq!(pipe.client.stats().key_updates_remote, 0);
+        assert_eq!(pipe.server.stats().key_updates_local, 0);
+        assert_eq!(pipe.server.stats().key_updates_remote, 2);
+    }
 
-        // No more frames are sent by the server.
-        assert_eq!(iter.next(), None);
+    #[test]
+    fn initiate_key_update_before_handshake() {
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.client.initiate_key_update(), Err(Error::InvalidState));
     }
 
     #[test]
-    /// Tests that resetting a stream restores flow control for unsent data.
-    fn stop_sending_unsent_tx_cap() {
+    fn key_update_packets() {
         let mut buf = [0; 65535];
 
-        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
         config
             .load_cert_chain_from_pem_file("examples/cert.crt")
             .unwrap();
@@ -10793,892 +17480,974 @@ mod tests {
         config
             .set_application_protos(&[b"proto1", b"proto2"])
             .unwrap();
-        config.set_initial_max_data(15);
-        config.set_initial_max_stream_data_bidi_local(30);
-        config.set_initial_max_stream_data_bidi_remote(30);
-        config.set_initial_max_stream_data_uni(30);
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
         config.set_initial_max_streams_bidi(3);
-        config.set_initial_max_streams_uni(0);
         config.verify_peer(false);
+        config.set_key_update_packets(5);
 
         let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        // Client sends some data.
-        assert_eq!(pipe.client.stream_send(4, b"hello", true), Ok(5));
-        assert_eq!(pipe.advance(), Ok(()));
-
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), Some(4));
-        assert_eq!(r.next(), None);
-
-        let mut b = [0; 15];
-        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((5, true)));
+        for i in 0..3 {
+            assert_eq!(pipe.client.stream_send(i * 4, b"a", true), Ok(1));
+            assert_eq!(pipe.advance(), Ok(()));
 
-        // Server sends some data.
-        assert_eq!(pipe.server.stream_send(4, b"hello", false), Ok(5));
-        assert_eq!(pipe.advance(), Ok(()));
+            for s in pipe.server.readable() {
+                assert_eq!(pipe.server.stream_recv(s, &mut buf), Ok((1, true)));
+            }
+        }
 
-        // Server buffers some data, until send capacity limit reached.
-        assert_eq!(pipe.server.stream_send(4, b"hello", false), Ok(5));
-        assert_eq!(pipe.server.stream_send(4, b"hello", false), Ok(5));
+        assert!(pipe.client.stats().key_updates_local > 0);
         assert_eq!(
-            pipe.server.stream_send(4, b"hello", false),
-            Err(Error::Done)
+            pipe.server.stats().key_updates_remote,
+            pipe.client.stats().key_updates_local
         );
-
-        // Client sends STOP_SENDING.
-        let frames = [frame::Frame::StopSending {
-            stream_id: 4,
-            error_code: 42,
-        }];
-
-        let pkt_type = packet::Type::Short;
-        pipe.send_pkt_to_server(pkt_type, &frames, &mut buf)
-            .unwrap();
-
-        // Server can now send more data (on a different stream).
-        assert_eq!(pipe.client.stream_send(8, b"hello", true), Ok(5));
-        assert_eq!(pipe.advance(), Ok(()));
-
-        assert_eq!(pipe.server.stream_send(8, b"hello", false), Ok(5));
-        assert_eq!(pipe.server.stream_send(8, b"hello", false), Ok(5));
         assert_eq!(
-            pipe.server.stream_send(8, b"hello", false),
-            Err(Error::Done)
+            pipe.client.stats().key_updates_remote,
+            pipe.server.stats().key_updates_local
         );
-        assert_eq!(pipe.advance(), Ok(()));
     }
 
     #[test]
-    fn stream_shutdown_read() {
+    fn update_key_request_twice_error() {
         let mut buf = [0; 65535];
 
         let mut pipe = testing::Pipe::new().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
-
-        // Client sends some data.
-        assert_eq!(pipe.client.stream_send(4, b"hello, world", false), Ok(12));
         assert_eq!(pipe.advance(), Ok(()));
 
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), Some(4));
-        assert_eq!(r.next(), None);
-
-        assert_eq!(pipe.client.streams.len(), 1);
-        assert_eq!(pipe.server.streams.len(), 1);
-
-        // Server shuts down stream.
-        assert_eq!(pipe.server.stream_shutdown(4, Shutdown::Read, 42), Ok(()));
-
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), None);
+        let frames = [frame::Frame::Stream {
+            stream_id: 4,
+            data: stream::RangeBuf::from(b"hello", 0, false),
+        }];
 
-        let (len, _) = pipe.server.send(&mut buf).unwrap();
+        // Client sends stream frame with key update request.
+        assert_eq!(pipe.client_update_key(), Ok(()));
+        let written = testing::encode_pkt(
+            &mut pipe.client,
+            packet::Type::Short,
+            &frames,
+            &mut buf,
+        )
+        .unwrap();
 
-        let mut dummy = buf[..len].to_vec();
+        // Server correctly decode with new key.
+        assert_eq!(pipe.server_recv(&mut buf[..written]), Ok(written));
 
-        let frames =
-            testing::decode_pkt(&mut pipe.client, &mut dummy[..len]).unwrap();
-        let mut iter = frames.iter();
-
-        assert_eq!(
-            iter.next(),
-            Some(&frame::Frame::StopSending {
-                stream_id: 4,
-                error_code: 42,
-            })
-        );
-
-        assert_eq!(pipe.client_recv(&mut buf[..len]), Ok(len));
+        // Client sends stream frame with another key update request before server
+        // ACK.
+        assert_eq!(pipe.client_update_key(), Ok(()));
+        let written = testing::encode_pkt(
+            &mut pipe.client,
+            packet::Type::Short,
+            &frames,
+            &mut buf,
+        )
+        .unwrap();
 
-        assert_eq!(pipe.advance(), Ok(()));
+        // Check server correctly closes the connection with a key update error
+        // for the peer.
+        assert_eq!(pipe.server_recv(&mut buf[..written]), Err(Error::KeyUpdate));
+    }
 
-        // Sending more data is forbidden.
-        let mut r = pipe.client.writable();
-        assert_eq!(r.next(), Some(4));
-        assert_eq!(r.next(), None);
+    #[test]
+    /// Tests that receiving a MAX_STREAM_DATA frame for a receive-only
+    /// unidirectional stream is forbidden.
+    fn max_stream_data_receive_uni() {
+        let mut buf = [0; 65535];
 
-        assert_eq!(
-            pipe.client.stream_send(4, b"bye", false),
-            Err(Error::StreamStopped(42))
-        );
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        // Server sends some data, without reading the incoming data, and closes
-        // the stream.
-        assert_eq!(pipe.server.stream_send(4, b"hello, world", true), Ok(12));
+        // Client opens unidirectional stream.
+        assert_eq!(pipe.client.stream_send(2, b"hello", false), Ok(5));
         assert_eq!(pipe.advance(), Ok(()));
 
-        // Client reads the data.
-        let mut r = pipe.client.readable();
-        assert_eq!(r.next(), Some(4));
-        assert_eq!(r.next(), None);
-
-        assert_eq!(pipe.client.stream_recv(4, &mut buf), Ok((12, true)));
-
-        // Stream is collected on both sides.
-        assert_eq!(pipe.client.streams.len(), 0);
-        assert_eq!(pipe.server.streams.len(), 0);
+        // Client sends MAX_STREAM_DATA on local unidirectional stream.
+        let frames = [frame::Frame::MaxStreamData {
+            stream_id: 2,
+            max: 1024,
+        }];
 
+        let pkt_type = packet::Type::Short;
         assert_eq!(
-            pipe.server.stream_shutdown(4, Shutdown::Read, 0),
-            Err(Error::Done)
+            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
+            Err(Error::InvalidStreamState(2)),
         );
     }
 
     #[test]
-    fn stream_shutdown_read_after_fin() {
+    fn empty_payload() {
         let mut buf = [0; 65535];
 
         let mut pipe = testing::Pipe::new().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        // Client sends some data.
-        assert_eq!(pipe.client.stream_send(4, b"hello, world", true), Ok(12));
-        assert_eq!(pipe.advance(), Ok(()));
-
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), Some(4));
-        assert_eq!(r.next(), None);
+        // Send a packet with no frames.
+        let pkt_type = packet::Type::Short;
+        assert_eq!(
+            pipe.send_pkt_to_server(pkt_type, &[], &mut buf),
+            Err(Error::InvalidPacket)
+        );
+    }
 
-        assert_eq!(pipe.client.streams.len(), 1);
-        assert_eq!(pipe.server.streams.len(), 1);
+    #[test]
+    fn min_payload() {
+        let mut buf = [0; 65535];
 
-        // Server shuts down stream.
-        assert_eq!(pipe.server.stream_shutdown(4, Shutdown::Read, 42), Ok(()));
+        let mut pipe = testing::Pipe::new().unwrap();
 
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), None);
+        // Send a non-ack-eliciting packet.
+        let frames = [frame::Frame::Padding { len: 4 }];
 
-        // Server has nothing to send.
-        assert_eq!(pipe.server.send(&mut buf), Err(Error::Done));
+        let pkt_type = packet::Type::Initial;
+        let written =
+            testing::encode_pkt(&mut pipe.client, pkt_type, &frames, &mut buf)
+                .unwrap();
+        assert_eq!(pipe.server_recv(&mut buf[..written]), Ok(written));
 
-        assert_eq!(pipe.advance(), Ok(()));
+        let initial_path = pipe
+            .server
+            .paths
+            .get_active()
+            .expect("initial path not found");
 
-        // Server sends some data, without reading the incoming data, and closes
-        // the stream.
-        assert_eq!(pipe.server.stream_send(4, b"hello, world", true), Ok(12));
-        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(initial_path.max_send_bytes, 195);
 
-        // Client reads the data.
-        let mut r = pipe.client.readable();
-        assert_eq!(r.next(), Some(4));
-        assert_eq!(r.next(), None);
+        // Force server to send a single PING frame.
+        pipe.server
+            .paths
+            .get_active_mut()
+            .expect("no active path")
+            .recovery
+            .loss_probes[packet::Epoch::Initial] = 1;
 
-        assert_eq!(pipe.client.stream_recv(4, &mut buf), Ok((12, true)));
+        let initial_path = pipe
+            .server
+            .paths
+            .get_active_mut()
+            .expect("initial path not found");
 
-        // Stream is collected on both sides.
-        assert_eq!(pipe.client.streams.len(), 0);
-        assert_eq!(pipe.server.streams.len(), 0);
+        // Artificially limit the amount of bytes the server can send.
+        initial_path.max_send_bytes = 60;
 
-        assert_eq!(
-            pipe.server.stream_shutdown(4, Shutdown::Read, 0),
-            Err(Error::Done)
-        );
+        assert_eq!(pipe.server.send(&mut buf), Err(Error::Done));
     }
 
     #[test]
-    fn stream_shutdown_read_update_max_data() {
+    fn flow_control_limit() {
         let mut buf = [0; 65535];
 
-        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
-        config
-            .load_cert_chain_from_pem_file("examples/cert.crt")
-            .unwrap();
-        config
-            .load_priv_key_from_pem_file("examples/cert.key")
-            .unwrap();
-        config
-            .set_application_protos(&[b"proto1", b"proto2"])
-            .unwrap();
-        config.set_initial_max_data(30);
-        config.set_initial_max_stream_data_bidi_local(10000);
-        config.set_initial_max_stream_data_bidi_remote(10000);
-        config.set_initial_max_streams_bidi(10);
-        config.verify_peer(false);
-
-        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        let mut pipe = testing::Pipe::new().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        assert_eq!(pipe.client.stream_send(0, b"a", false), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
-
-        assert_eq!(pipe.server.stream_recv(0, &mut buf), Ok((1, false)));
-        assert_eq!(pipe.server.stream_shutdown(0, Shutdown::Read, 123), Ok(()));
-
-        assert_eq!(pipe.server.rx_data, 1);
-        assert_eq!(pipe.client.tx_data, 1);
-        assert_eq!(pipe.client.max_tx_data, 30);
+        let frames = [
+            frame::Frame::Stream {
+                stream_id: 0,
+                data: stream::RangeBuf::from(b"aaaaaaaaaaaaaaa", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 4,
+                data: stream::RangeBuf::from(b"aaaaaaaaaaaaaaa", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 8,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+        ];
 
+        let pkt_type = packet::Type::Short;
         assert_eq!(
-            pipe.client
-                .stream_send(0, &buf[..pipe.client.tx_cap], false),
-            Ok(29)
+            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
+            Err(Error::FlowControl),
         );
-        assert_eq!(pipe.advance(), Ok(()));
-
-        assert!(!pipe.server.stream_readable(0)); // nothing can be consumed
-
-        // The client has increased its tx_data, and server has received it, so
-        // it increases flow control accordingly.
-        assert_eq!(pipe.client.tx_data, 30);
-        assert_eq!(pipe.server.rx_data, 30);
-        assert_eq!(pipe.client.tx_cap, 45);
     }
 
     #[test]
-    fn stream_shutdown_uni() {
+    fn flow_control_limit_dup() {
+        let mut buf = [0; 65535];
+
         let mut pipe = testing::Pipe::new().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        // Exchange some data on uni streams.
-        assert_eq!(pipe.client.stream_send(2, b"hello, world", false), Ok(10));
-        assert_eq!(pipe.server.stream_send(3, b"hello, world", false), Ok(10));
-        assert_eq!(pipe.advance(), Ok(()));
-
-        // Test local and remote shutdown.
-        assert_eq!(pipe.client.stream_shutdown(2, Shutdown::Write, 42), Ok(()));
-        assert_eq!(
-            pipe.client.stream_shutdown(2, Shutdown::Read, 42),
-            Err(Error::InvalidStreamState(2))
-        );
+        let frames = [
+            // One byte less than stream limit.
+            frame::Frame::Stream {
+                stream_id: 0,
+                data: stream::RangeBuf::from(b"aaaaaaaaaaaaaa", 0, false),
+            },
+            // Same stream, but one byte more.
+            frame::Frame::Stream {
+                stream_id: 0,
+                data: stream::RangeBuf::from(b"aaaaaaaaaaaaaaa", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 8,
+                data: stream::RangeBuf::from(b"aaaaaaaaaaaaaaa", 0, false),
+            },
+        ];
 
-        assert_eq!(
-            pipe.client.stream_shutdown(3, Shutdown::Write, 42),
-            Err(Error::InvalidStreamState(3))
-        );
-        assert_eq!(pipe.client.stream_shutdown(3, Shutdown::Read, 42), Ok(()));
+        let pkt_type = packet::Type::Short;
+        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
     }
 
     #[test]
-    fn stream_shutdown_write() {
+    fn flow_control_update() {
         let mut buf = [0; 65535];
 
         let mut pipe = testing::Pipe::new().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        // Client sends some data.
-        assert_eq!(pipe.client.stream_send(4, b"hello, world", false), Ok(12));
-        assert_eq!(pipe.advance(), Ok(()));
+        let frames = [
+            frame::Frame::Stream {
+                stream_id: 0,
+                data: stream::RangeBuf::from(b"aaaaaaaaaaaaaaa", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 4,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+        ];
 
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), Some(4));
-        assert_eq!(r.next(), None);
+        let pkt_type = packet::Type::Short;
 
-        let mut r = pipe.server.writable();
-        assert_eq!(r.next(), Some(4));
-        assert_eq!(r.next(), None);
-
-        assert_eq!(pipe.client.streams.len(), 1);
-        assert_eq!(pipe.server.streams.len(), 1);
-
-        // Server sends some data.
-        assert_eq!(pipe.server.stream_send(4, b"goodbye, world", false), Ok(14));
-        assert_eq!(pipe.advance(), Ok(()));
+        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
 
-        // Server shuts down stream.
-        assert_eq!(pipe.server.stream_shutdown(4, Shutdown::Write, 42), Ok(()));
+        pipe.server.stream_recv(0, &mut buf).unwrap();
+        pipe.server.stream_recv(4, &mut buf).unwrap();
 
-        let mut r = pipe.server.writable();
-        assert_eq!(r.next(), None);
+        let frames = [frame::Frame::Stream {
+            stream_id: 4,
+            data: stream::RangeBuf::from(b"a", 1, false),
+        }];
 
-        let (len, _) = pipe.server.send(&mut buf).unwrap();
+        let len = pipe
+            .send_pkt_to_server(pkt_type, &frames, &mut buf)
+            .unwrap();
 
-        let mut dummy = buf[..len].to_vec();
+        assert!(len > 0);
 
         let frames =
-            testing::decode_pkt(&mut pipe.client, &mut dummy[..len]).unwrap();
+            testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
         let mut iter = frames.iter();
 
+        // Ignore ACK.
+        iter.next().unwrap();
+
         assert_eq!(
             iter.next(),
-            Some(&frame::Frame::ResetStream {
-                stream_id: 4,
-                error_code: 42,
-                final_size: 14,
+            Some(&frame::Frame::MaxStreamData {
+                stream_id: 0,
+                max: 30
             })
         );
+        assert_eq!(iter.next(), Some(&frame::Frame::MaxData { max: 61 }));
+    }
 
-        assert_eq!(pipe.client_recv(&mut buf[..len]), Ok(len));
-
-        assert_eq!(pipe.advance(), Ok(()));
-
-        // Sending more data is forbidden.
-        assert_eq!(
-            pipe.server.stream_send(4, b"bye", false),
-            Err(Error::FinalSize)
-        );
+    #[test]
+    /// Tests that flow control is properly updated even when a stream is shut
+    /// down.
+    fn flow_control_drain() {
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        // Client sends some data and closes the stream.
-        assert_eq!(pipe.client.stream_send(4, b"bye", true), Ok(3));
+        // Client opens a stream and sends some data.
+        assert_eq!(pipe.client.stream_send(4, b"aaaaa", false), Ok(5));
         assert_eq!(pipe.advance(), Ok(()));
 
-        // Server reads the data.
+        // Server receives data, without reading it.
         let mut r = pipe.server.readable();
         assert_eq!(r.next(), Some(4));
         assert_eq!(r.next(), None);
 
-        assert_eq!(pipe.server.stream_recv(4, &mut buf), Ok((15, true)));
+        // In the meantime, client sends more data.
+        assert_eq!(pipe.client.stream_send(4, b"aaaaa", false), Ok(5));
+        assert_eq!(pipe.client.stream_send(4, b"aaaaa", true), Ok(5));
 
-        // Client processes readable streams.
-        let mut r = pipe.client.readable();
-        assert_eq!(r.next(), Some(4));
-        assert_eq!(r.next(), None);
+        assert_eq!(pipe.client.stream_send(8, b"aaaaa", false), Ok(5));
+        assert_eq!(pipe.client.stream_send(8, b"aaaaa", false), Ok(5));
+        assert_eq!(pipe.client.stream_send(8, b"aaaaa", true), Ok(5));
 
-        assert_eq!(
-            pipe.client.stream_recv(4, &mut buf),
-            Err(Error::StreamReset(42))
-        );
+        // Server shuts down one stream.
+        assert_eq!(pipe.server.stream_shutdown(4, Shutdown::Read, 42), Ok(()));
 
-        // Stream is collected on both sides.
-        assert_eq!(pipe.client.streams.len(), 0);
-        assert_eq!(pipe.server.streams.len(), 0);
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), None);
 
-        assert_eq!(
-            pipe.server.stream_shutdown(4, Shutdown::Write, 0),
-            Err(Error::Done)
-        );
+        // Flush connection.
+        assert_eq!(pipe.advance(), Ok(()));
     }
 
     #[test]
-    /// Tests that shutting down a stream restores flow control for unsent data.
-    fn stream_shutdown_write_unsent_tx_cap() {
-        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
-        config
-            .load_cert_chain_from_pem_file("examples/cert.crt")
-            .unwrap();
-        config
-            .load_priv_key_from_pem_file("examples/cert.key")
-            .unwrap();
-        config
-            .set_application_protos(&[b"proto1", b"proto2"])
-            .unwrap();
-        config.set_initial_max_data(15);
-        config.set_initial_max_stream_data_bidi_local(30);
-        config.set_initial_max_stream_data_bidi_remote(30);
-        config.set_initial_max_stream_data_uni(30);
-        config.set_initial_max_streams_bidi(3);
-        config.set_initial_max_streams_uni(0);
-        config.verify_peer(false);
+    fn stream_flow_control_limit_bidi() {
+        let mut buf = [0; 65535];
 
-        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        let mut pipe = testing::Pipe::new().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        // Client sends some data.
-        assert_eq!(pipe.client.stream_send(4, b"hello", true), Ok(5));
-        assert_eq!(pipe.advance(), Ok(()));
-
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), Some(4));
-        assert_eq!(r.next(), None);
-
-        let mut b = [0; 15];
-        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((5, true)));
-
-        // Server sends some data.
-        assert_eq!(pipe.server.stream_send(4, b"hello", false), Ok(5));
-        assert_eq!(pipe.advance(), Ok(()));
+        let frames = [frame::Frame::Stream {
+            stream_id: 4,
+            data: stream::RangeBuf::from(b"aaaaaaaaaaaaaaaa", 0, true),
+        }];
 
-        // Server buffers some data, until send capacity limit reached.
-        assert_eq!(pipe.server.stream_send(4, b"hello", false), Ok(5));
-        assert_eq!(pipe.server.stream_send(4, b"hello", false), Ok(5));
+        let pkt_type = packet::Type::Short;
         assert_eq!(
-            pipe.server.stream_send(4, b"hello", false),
-            Err(Error::Done)
+            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
+            Err(Error::FlowControl),
         );
+    }
 
-        // Client shouldn't update flow control.
-        assert!(!pipe.client.should_update_max_data());
+    #[test]
+    fn stream_flow_control_limit_uni() {
+        let mut buf = [0; 65535];
 
-        // Server shuts down stream.
-        assert_eq!(pipe.server.stream_shutdown(4, Shutdown::Write, 42), Ok(()));
-        assert_eq!(pipe.advance(), Ok(()));
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        // Server can now send more data (on a different stream).
-        assert_eq!(pipe.client.stream_send(8, b"hello", true), Ok(5));
-        assert_eq!(pipe.advance(), Ok(()));
+        let frames = [frame::Frame::Stream {
+            stream_id: 2,
+            data: stream::RangeBuf::from(b"aaaaaaaaaaa", 0, true),
+        }];
 
-        assert_eq!(pipe.server.stream_send(8, b"hello", false), Ok(5));
-        assert_eq!(pipe.server.stream_send(8, b"hello", false), Ok(5));
+        let pkt_type = packet::Type::Short;
         assert_eq!(
-            pipe.server.stream_send(8, b"hello", false),
-            Err(Error::Done)
+            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
+            Err(Error::FlowControl),
         );
-        assert_eq!(pipe.advance(), Ok(()));
     }
 
     #[test]
-    /// Tests that the order of flushable streams scheduled on the wire is the
-    /// same as the order of `stream_send()` calls done by the application.
-    fn stream_round_robin() {
+    fn stream_flow_control_update() {
         let mut buf = [0; 65535];
 
         let mut pipe = testing::Pipe::new().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        assert_eq!(pipe.client.stream_send(8, b"aaaaa", false), Ok(5));
-        assert_eq!(pipe.client.stream_send(0, b"aaaaa", false), Ok(5));
-        assert_eq!(pipe.client.stream_send(4, b"aaaaa", false), Ok(5));
+        let frames = [frame::Frame::Stream {
+            stream_id: 4,
+            data: stream::RangeBuf::from(b"aaaaaaaaa", 0, false),
+        }];
 
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        let pkt_type = packet::Type::Short;
 
-        let frames =
-            testing::decode_pkt(&mut pipe.server, &mut buf[..len]).unwrap();
+        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
 
-        let mut iter = frames.iter();
+        pipe.server.stream_recv(4, &mut buf).unwrap();
 
-        // Skip ACK frame.
-        iter.next();
+        let frames = [frame::Frame::Stream {
+            stream_id: 4,
+            data: stream::RangeBuf::from(b"a", 9, false),
+        }];
 
-        assert_eq!(
-            iter.next(),
-            Some(&frame::Frame::Stream {
-                stream_id: 8,
-                data: stream::RangeBuf::from(b"aaaaa", 0, false),
-            })
-        );
+        let len = pipe
+            .send_pkt_to_server(pkt_type, &frames, &mut buf)
+            .unwrap();
 
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert!(len > 0);
 
         let frames =
-            testing::decode_pkt(&mut pipe.server, &mut buf[..len]).unwrap();
-
-        assert_eq!(
-            frames.first(),
-            Some(&frame::Frame::Stream {
-                stream_id: 0,
-                data: stream::RangeBuf::from(b"aaaaa", 0, false),
-            })
-        );
-
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
+            testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
+        let mut iter = frames.iter();
 
-        let frames =
-            testing::decode_pkt(&mut pipe.server, &mut buf[..len]).unwrap();
+        // Ignore ACK.
+        iter.next().unwrap();
 
         assert_eq!(
-            frames.first(),
-            Some(&frame::Frame::Stream {
+            iter.next(),
+            Some(&frame::Frame::MaxStreamData {
                 stream_id: 4,
-                data: stream::RangeBuf::from(b"aaaaa", 0, false),
+                max: 24,
             })
         );
     }
 
     #[test]
-    /// Tests the readable iterator.
-    fn stream_readable() {
+    fn stream_left_bidi() {
+        let mut buf = [0; 65535];
+
         let mut pipe = testing::Pipe::new().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        // No readable streams.
-        let mut r = pipe.client.readable();
-        assert_eq!(r.next(), None);
+        assert_eq!(3, pipe.client.peer_streams_left_bidi());
+        assert_eq!(3, pipe.server.peer_streams_left_bidi());
 
-        assert_eq!(pipe.client.stream_send(0, b"aaaaa", false), Ok(5));
+        pipe.server.stream_send(1, b"a", false).ok();
+        assert_eq!(2, pipe.server.peer_streams_left_bidi());
+        pipe.server.stream_send(5, b"a", false).ok();
+        assert_eq!(1, pipe.server.peer_streams_left_bidi());
 
-        let mut r = pipe.client.readable();
-        assert_eq!(r.next(), None);
+        pipe.server.stream_send(9, b"a", false).ok();
+        assert_eq!(0, pipe.server.peer_streams_left_bidi());
 
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), None);
+        let frames = [frame::Frame::MaxStreamsBidi { max: MAX_STREAM_ID }];
 
-        assert_eq!(pipe.advance(), Ok(()));
+        let pkt_type = packet::Type::Short;
+        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
 
-        // Server received stream.
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), Some(0));
-        assert_eq!(r.next(), None);
+        assert_eq!(MAX_STREAM_ID - 3, pipe.server.peer_streams_left_bidi());
+    }
 
-        assert_eq!(
-            pipe.server.stream_send(0, b"aaaaaaaaaaaaaaa", false),
-            Ok(15)
-        );
-        assert_eq!(pipe.advance(), Ok(()));
+    #[test]
+    fn stream_left_uni() {
+        let mut buf = [0; 65535];
 
-        let mut r = pipe.client.readable();
-        assert_eq!(r.next(), Some(0));
-        assert_eq!(r.next(), None);
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        // Client drains stream.
-        let mut b = [0; 15];
-        pipe.client.stream_recv(0, &mut b).unwrap();
-        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(3, pipe.client.peer_streams_left_uni());
+        assert_eq!(3, pipe.server.peer_streams_left_uni());
 
-        let mut r = pipe.client.readable();
-        assert_eq!(r.next(), None);
+        pipe.server.stream_send(3, b"a", false).ok();
+        assert_eq!(2, pipe.server.peer_streams_left_uni());
+        pipe.server.stream_send(7, b"a", false).ok();
+        assert_eq!(1, pipe.server.peer_streams_left_uni());
 
-        // Server shuts down stream.
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), Some(0));
-        assert_eq!(r.next(), None);
+        pipe.server.stream_send(11, b"a", false).ok();
+        assert_eq!(0, pipe.server.peer_streams_left_uni());
 
-        assert_eq!(pipe.server.stream_shutdown(0, Shutdown::Read, 0), Ok(()));
+        let frames = [frame::Frame::MaxStreamsUni { max: MAX_STREAM_ID }];
 
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), None);
+        let pkt_type = packet::Type::Short;
+        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
 
-        // Client creates multiple streams.
-        assert_eq!(pipe.client.stream_send(4, b"aaaaa", false), Ok(5));
-        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(MAX_STREAM_ID - 3, pipe.server.peer_streams_left_uni());
+    }
 
-        assert_eq!(pipe.client.stream_send(8, b"aaaaa", false), Ok(5));
-        assert_eq!(pipe.advance(), Ok(()));
+    #[test]
+    fn stream_limit_bidi() {
+        let mut buf = [0; 65535];
 
-        let mut r = pipe.server.readable();
-        assert_eq!(r.len(), 2);
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        assert!(r.next().is_some());
-        assert!(r.next().is_some());
-        assert!(r.next().is_none());
+        let frames = [
+            frame::Frame::Stream {
+                stream_id: 4,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 8,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 12,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 16,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 20,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 24,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 28,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+        ];
 
-        assert_eq!(r.len(), 0);
+        let pkt_type = packet::Type::Short;
+        assert_eq!(
+            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
+            Err(Error::StreamLimit),
+        );
     }
 
     #[test]
-    /// Tests the writable iterator.
-    fn stream_writable() {
+    fn stream_limit_max_bidi() {
+        let mut buf = [0; 65535];
+
         let mut pipe = testing::Pipe::new().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        // No writable streams.
-        let mut w = pipe.client.writable();
-        assert_eq!(w.next(), None);
-
-        assert_eq!(pipe.client.stream_send(0, b"aaaaa", false), Ok(5));
-
-        // Client created stream.
-        let mut w = pipe.client.writable();
-        assert_eq!(w.next(), Some(0));
-        assert_eq!(w.next(), None);
+        let frames = [frame::Frame::MaxStreamsBidi { max: MAX_STREAM_ID }];
 
-        assert_eq!(pipe.advance(), Ok(()));
+        let pkt_type = packet::Type::Short;
+        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
 
-        // Server created stream.
-        let mut w = pipe.server.writable();
-        assert_eq!(w.next(), Some(0));
-        assert_eq!(w.next(), None);
+        let frames = [frame::Frame::MaxStreamsBidi {
+            max: MAX_STREAM_ID + 1,
+        }];
 
+        let pkt_type = packet::Type::Short;
         assert_eq!(
-            pipe.server.stream_send(0, b"aaaaaaaaaaaaaaa", false),
-            Ok(15)
+            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
+            Err(Error::InvalidFrame),
         );
+    }
 
-        // Server stream is full.
-        let mut w = pipe.server.writable();
-        assert_eq!(w.next(), None);
-
-        assert_eq!(pipe.advance(), Ok(()));
-
-        // Client drains stream.
-        let mut b = [0; 15];
-        pipe.client.stream_recv(0, &mut b).unwrap();
-        assert_eq!(pipe.advance(), Ok(()));
-
-        // Server stream is writable again.
-        let mut w = pipe.server.writable();
-        assert_eq!(w.next(), Some(0));
-        assert_eq!(w.next(), None);
+    #[test]
+    fn stream_limit_uni() {
+        let mut buf = [0; 65535];
 
-        // Server shuts down stream.
-        assert_eq!(pipe.server.stream_shutdown(0, Shutdown::Write, 0), Ok(()));
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        let mut w = pipe.server.writable();
-        assert_eq!(w.next(), None);
+        let frames = [
+            frame::Frame::Stream {
+                stream_id: 2,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 6,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 10,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 14,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 18,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 22,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 26,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+        ];
 
-        // Client creates multiple streams.
-        assert_eq!(pipe.client.stream_send(4, b"aaaaa", false), Ok(5));
-        assert_eq!(pipe.advance(), Ok(()));
+        let pkt_type = packet::Type::Short;
+        assert_eq!(
+            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
+            Err(Error::StreamLimit),
+        );
+    }
 
-        assert_eq!(pipe.client.stream_send(8, b"aaaaa", false), Ok(5));
-        assert_eq!(pipe.advance(), Ok(()));
+    #[test]
+    fn stream_limit_max_uni() {
+        let mut buf = [0; 65535];
 
-        let mut w = pipe.server.writable();
-        assert_eq!(w.len(), 2);
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        assert!(w.next().is_some());
-        assert!(w.next().is_some());
-        assert!(w.next().is_none());
+        let frames = [frame::Frame::MaxStreamsUni { max: MAX_STREAM_ID }];
 
-        assert_eq!(w.len(), 0);
+        let pkt_type = packet::Type::Short;
+        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
 
-        // Server finishes stream.
-        assert_eq!(pipe.server.stream_send(8, b"aaaaa", true), Ok(5));
+        let frames = [frame::Frame::MaxStreamsUni {
+            max: MAX_STREAM_ID + 1,
+        }];
 
-        let mut w = pipe.server.writable();
-        assert_eq!(w.next(), Some(4));
-        assert_eq!(w.next(), None);
+        let pkt_type = packet::Type::Short;
+        assert_eq!(
+            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
+            Err(Error::InvalidFrame),
+        );
     }
 
     #[test]
-    fn stream_writable_blocked() {
-        let mut config = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
-        config
-            .load_cert_chain_from_pem_file("examples/cert.crt")
-            .unwrap();
-        config
-            .load_priv_key_from_pem_file("examples/cert.key")
-            .unwrap();
-        config.set_application_protos(&[b"h3"]).unwrap();
-        config.set_initial_max_data(70);
-        config.set_initial_max_stream_data_bidi_local(150000);
-        config.set_initial_max_stream_data_bidi_remote(150000);
-        config.set_initial_max_stream_data_uni(150000);
-        config.set_initial_max_streams_bidi(100);
-        config.set_initial_max_streams_uni(5);
-        config.verify_peer(false);
+    fn stream_left_reset_bidi() {
+        let mut buf = [0; 65535];
 
-        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        let mut pipe = testing::Pipe::new().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        // Client creates stream and sends some data.
-        let send_buf = [0; 35];
-        assert_eq!(pipe.client.stream_send(0, &send_buf, false), Ok(35));
-
-        // Stream is still writable as it still has capacity.
-        assert_eq!(pipe.client.stream_writable_next(), Some(0));
-        assert_eq!(pipe.client.stream_writable_next(), None);
+        assert_eq!(3, pipe.client.peer_streams_left_bidi());
+        assert_eq!(3, pipe.server.peer_streams_left_bidi());
 
-        // Client fills stream, which becomes unwritable due to connection
-        // capacity.
-        let send_buf = [0; 36];
-        assert_eq!(pipe.client.stream_send(0, &send_buf, false), Ok(35));
+        pipe.client.stream_send(0, b"a", false).ok();
+        assert_eq!(2, pipe.client.peer_streams_left_bidi());
+        pipe.client.stream_send(4, b"a", false).ok();
+        assert_eq!(1, pipe.client.peer_streams_left_bidi());
+        pipe.client.stream_send(8, b"a", false).ok();
+        assert_eq!(0, pipe.client.peer_streams_left_bidi());
 
-        assert_eq!(pipe.client.stream_writable_next(), None);
+        // Client resets the stream.
+        pipe.client
+            .stream_shutdown(0, Shutdown::Write, 1001)
+            .unwrap();
+        pipe.advance().unwrap();
 
-        assert_eq!(pipe.client.tx_cap, 0);
+        assert_eq!(0, pipe.client.peer_streams_left_bidi());
+        let mut r = pipe.server.readable();
+        assert_eq!(Some(0), r.next());
+        assert_eq!(Some(4), r.next());
+        assert_eq!(Some(8), r.next());
+        assert_eq!(None, r.next());
 
-        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(
+            pipe.server.stream_recv(0, &mut buf),
+            Err(Error::StreamReset(1001))
+        );
 
-        let mut b = [0; 70];
-        pipe.server.stream_recv(0, &mut b).unwrap();
+        let mut r = pipe.server.readable();
+        assert_eq!(Some(4), r.next());
+        assert_eq!(Some(8), r.next());
+        assert_eq!(None, r.next());
 
-        assert_eq!(pipe.advance(), Ok(()));
+        // Server resets the stream in reaction.
+        pipe.server
+            .stream_shutdown(0, Shutdown::Write, 1001)
+            .unwrap();
+        pipe.advance().unwrap();
 
-        // The connection capacity has increased and the stream is now writable
-        // again.
-        assert_ne!(pipe.client.tx_cap, 0);
+        assert_eq!(1, pipe.client.peer_streams_left_bidi());
 
-        assert_eq!(pipe.client.stream_writable_next(), Some(0));
-        assert_eq!(pipe.client.stream_writable_next(), None);
-    }
+        // Repeat for the other 2 streams
+        pipe.client
+            .stream_shutdown(4, Shutdown::Write, 1001)
+            .unwrap();
+        pipe.client
+            .stream_shutdown(8, Shutdown::Write, 1001)
+            .unwrap();
+        pipe.advance().unwrap();
 
-    #[test]
-    /// Tests that we don't exceed the per-connection flow control limit set by
-    /// the peer.
-    fn flow_control_limit_send() {
-        let mut pipe = testing::Pipe::new().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+        let mut r = pipe.server.readable();
+        assert_eq!(Some(4), r.next());
+        assert_eq!(Some(8), r.next());
+        assert_eq!(None, r.next());
 
         assert_eq!(
-            pipe.client.stream_send(0, b"aaaaaaaaaaaaaaa", false),
-            Ok(15)
+            pipe.server.stream_recv(4, &mut buf),
+            Err(Error::StreamReset(1001))
         );
-        assert_eq!(pipe.advance(), Ok(()));
+
         assert_eq!(
-            pipe.client.stream_send(4, b"aaaaaaaaaaaaaaa", false),
-            Ok(15)
+            pipe.server.stream_recv(8, &mut buf),
+            Err(Error::StreamReset(1001))
         );
-        assert_eq!(pipe.advance(), Ok(()));
-        assert_eq!(pipe.client.stream_send(8, b"a", false), Err(Error::Done));
-        assert_eq!(pipe.advance(), Ok(()));
 
         let mut r = pipe.server.readable();
-        assert!(r.next().is_some());
-        assert!(r.next().is_some());
-        assert!(r.next().is_none());
+        assert_eq!(None, r.next());
+
+        pipe.server
+            .stream_shutdown(4, Shutdown::Write, 1001)
+            .unwrap();
+        pipe.server
+            .stream_shutdown(8, Shutdown::Write, 1001)
+            .unwrap();
+        pipe.advance().unwrap();
+
+        assert_eq!(3, pipe.client.peer_streams_left_bidi());
     }
 
     #[test]
-    /// Tests that invalid packets received before any other valid ones cause
-    /// the server to close the connection immediately.
-    fn invalid_initial_server() {
-        let mut buf = [0; 65535];
+    fn stream_reset_counts() {
         let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        let frames = [frame::Frame::Padding { len: 10 }];
+        pipe.client.stream_send(0, b"a", false).ok();
+        pipe.client.stream_send(2, b"a", false).ok();
+        pipe.client.stream_send(4, b"a", false).ok();
+        pipe.client.stream_send(8, b"a", false).ok();
+        pipe.advance().unwrap();
 
-        let written = testing::encode_pkt(
-            &mut pipe.client,
-            packet::Type::Initial,
-            &frames,
-            &mut buf,
-        )
-        .unwrap();
+        let stats = pipe.client.stats();
+        assert_eq!(stats.reset_stream_count_local, 0);
 
-        // Corrupt the packets's last byte to make decryption fail (the last
-        // byte is part of the AEAD tag, so changing it means that the packet
-        // cannot be authenticated during decryption).
-        buf[written - 1] = !buf[written - 1];
+        // Client resets the stream.
+        pipe.client
+            .stream_shutdown(0, Shutdown::Write, 1001)
+            .unwrap();
+        pipe.advance().unwrap();
 
-        assert_eq!(pipe.server.timeout(), None);
+        let stats = pipe.client.stats();
+        assert_eq!(stats.reset_stream_count_local, 1);
+        assert_eq!(stats.reset_stream_count_remote, 0);
+        let stats = pipe.server.stats();
+        assert_eq!(stats.reset_stream_count_local, 0);
+        assert_eq!(stats.reset_stream_count_remote, 1);
 
-        assert_eq!(
-            pipe.server_recv(&mut buf[..written]),
-            Err(Error::CryptoFail)
-        );
+        // Server resets the stream in reaction.
+        pipe.server
+            .stream_shutdown(0, Shutdown::Write, 1001)
+            .unwrap();
+        pipe.advance().unwrap();
 
-        assert!(pipe.server.is_closed());
+        let stats = pipe.client.stats();
+        assert_eq!(stats.reset_stream_count_local, 1);
+        assert_eq!(stats.reset_stream_count_remote, 1);
+        let stats = pipe.server.stats();
+        assert_eq!(stats.reset_stream_count_local, 1);
+        assert_eq!(stats.reset_stream_count_remote, 1);
+
+        // Repeat for the other streams
+        pipe.client
+            .stream_shutdown(2, Shutdown::Write, 1001)
+            .unwrap();
+        pipe.client
+            .stream_shutdown(4, Shutdown::Write, 1001)
+            .unwrap();
+        pipe.client
+            .stream_shutdown(8, Shutdown::Write, 1001)
+            .unwrap();
+        pipe.advance().unwrap();
+
+        pipe.server
+            .stream_shutdown(4, Shutdown::Write, 1001)
+            .unwrap();
+        pipe.server
+            .stream_shutdown(8, Shutdown::Write, 1001)
+            .unwrap();
+        pipe.advance().unwrap();
+
+        let stats = pipe.client.stats();
+        assert_eq!(stats.reset_stream_count_local, 4);
+        assert_eq!(stats.reset_stream_count_remote, 3);
+        let stats = pipe.server.stats();
+        assert_eq!(stats.reset_stream_count_local, 3);
+        assert_eq!(stats.reset_stream_count_remote, 4);
     }
 
     #[test]
-    /// Tests that invalid Initial packets received to cause
-    /// the client to close the connection immediately.
-    fn invalid_initial_client() {
-        let mut buf = [0; 65535];
+    fn stream_stop_counts() {
         let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        // Client sends initial flight.
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        pipe.client.stream_send(0, b"a", false).ok();
+        pipe.client.stream_send(2, b"a", false).ok();
+        pipe.client.stream_send(4, b"a", false).ok();
+        pipe.client.stream_send(8, b"a", false).ok();
+        pipe.advance().unwrap();
 
-        // Server sends initial flight.
-        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(1200));
+        let stats = pipe.client.stats();
+        assert_eq!(stats.reset_stream_count_local, 0);
 
-        let frames = [frame::Frame::Padding { len: 10 }];
+        // Server stops the stream and client automatically resets.
+        pipe.server
+            .stream_shutdown(0, Shutdown::Read, 1001)
+            .unwrap();
+        pipe.advance().unwrap();
 
-        let written = testing::encode_pkt(
-            &mut pipe.server,
-            packet::Type::Initial,
-            &frames,
-            &mut buf,
-        )
-        .unwrap();
+        let stats = pipe.client.stats();
+        assert_eq!(stats.stopped_stream_count_local, 0);
+        assert_eq!(stats.stopped_stream_count_remote, 1);
+        assert_eq!(stats.reset_stream_count_local, 1);
+        assert_eq!(stats.reset_stream_count_remote, 0);
 
-        // Corrupt the packets's last byte to make decryption fail (the last
-        // byte is part of the AEAD tag, so changing it means that the packet
-        // cannot be authenticated during decryption).
-        buf[written - 1] = !buf[written - 1];
+        let stats = pipe.server.stats();
+        assert_eq!(stats.stopped_stream_count_local, 1);
+        assert_eq!(stats.stopped_stream_count_remote, 0);
+        assert_eq!(stats.reset_stream_count_local, 0);
+        assert_eq!(stats.reset_stream_count_remote, 1);
 
-        // Client will ignore invalid packet.
-        assert_eq!(pipe.client_recv(&mut buf[..written]), Ok(71));
+        // Repeat for the other streams
+        pipe.server
+            .stream_shutdown(2, Shutdown::Read, 1001)
+            .unwrap();
+        pipe.server
+            .stream_shutdown(4, Shutdown::Read, 1001)
+            .unwrap();
+        pipe.server
+            .stream_shutdown(8, Shutdown::Read, 1001)
+            .unwrap();
+        pipe.advance().unwrap();
 
-        // The connection should be alive...
-        assert!(!pipe.client.is_closed());
+        let stats = pipe.client.stats();
+        assert_eq!(stats.stopped_stream_count_local, 0);
+        assert_eq!(stats.stopped_stream_count_remote, 4);
+        assert_eq!(stats.reset_stream_count_local, 4);
+        assert_eq!(stats.reset_stream_count_remote, 0);
 
-        // ...and the idle timeout should be armed.
-        assert!(pipe.client.idle_timer.is_some());
+        let stats = pipe.server.stats();
+        assert_eq!(stats.stopped_stream_count_local, 4);
+        assert_eq!(stats.stopped_stream_count_remote, 0);
+        assert_eq!(stats.reset_stream_count_local, 0);
+        assert_eq!(stats.reset_stream_count_remote, 4);
     }
 
     #[test]
-    /// Tests that packets with invalid payload length received before any other
-    /// valid packet cause the server to close the connection immediately.
-    fn invalid_initial_payload() {
+    fn streams_blocked_max_bidi() {
         let mut buf = [0; 65535];
+
         let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        let mut b = octets::OctetsMut::with_slice(&mut buf);
+        let frames = [frame::Frame::StreamsBlockedBidi {
+            limit: MAX_STREAM_ID,
+        }];
 
-        let epoch = packet::Type::Initial.to_epoch().unwrap();
+        let pkt_type = packet::Type::Short;
+        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
 
-        let pn = 0;
-        let pn_len = packet::pkt_num_len(pn).unwrap();
+        let frames = [frame::Frame::StreamsBlockedBidi {
+            limit: MAX_STREAM_ID + 1,
+        }];
 
-        let dcid = pipe.client.destination_id();
-        let scid = pipe.client.source_id();
+        let pkt_type = packet::Type::Short;
+        assert_eq!(
+            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
+            Err(Error::InvalidFrame),
+        );
+    }
 
-        let hdr = Header {
-            ty: packet::Type::Initial,
-            version: pipe.client.version,
-            dcid: ConnectionId::from_ref(&dcid),
-            scid: ConnectionId::from_ref(&scid),
-            pkt_num: 0,
-            pkt_num_len: pn_len,
-            token: pipe.client.token.clone(),
-            versions: None,
-            key_phase: false,
-        };
+    #[test]
+    fn streams_blocked_max_uni() {
+        let mut buf = [0; 65535];
 
-        hdr.to_bytes(&mut b).unwrap();
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        // Payload length is invalid!!!
-        let payload_len = 4096;
+        let frames = [frame::Frame::StreamsBlockedUni {
+            limit: MAX_STREAM_ID,
+        }];
 
-        let len = pn_len + payload_len;
-        b.put_varint(len as u64).unwrap();
+        let pkt_type = packet::Type::Short;
+        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
 
-        packet::encode_pkt_num(pn, &mut b).unwrap();
+        let frames = [frame::Frame::StreamsBlockedUni {
+            limit: MAX_STREAM_ID + 1,
+        }];
 
-        let payload_offset = b.off();
+        let pkt_type = packet::Type::Short;
+        assert_eq!(
+            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
+            Err(Error::InvalidFrame),
+        );
+    }
 
-        let frames = [frame::Frame::Padding { len: 10 }];
+    #[test]
+    fn streams_blocked_event() {
+        let mut buf = [0; 65535];
 
-        for frame in &frames {
-            frame.to_bytes(&mut b).unwrap();
-        }
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        let space = &mut pipe.client.pkt_num_spaces[epoch];
+        assert_eq!(pipe.server.local_streams_left_bidi(), 3);
+        assert_eq!(pipe.server.local_streams_left_uni(), 3);
 
-        // Use correct payload length when encrypting the packet.
-        let payload_len = frames.iter().fold(0, |acc, x| acc + x.wire_len());
+        // Stale limits don't generate events.
+        let frames = [frame::Frame::StreamsBlockedBidi { limit: 2 }];
 
-        let aead = space.crypto_seal.as_ref().unwrap();
+        let pkt_type = packet::Type::Short;
+        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
+        assert_eq!(pipe.server.streams_blocked_next(), None);
 
-        let written = packet::encrypt_pkt(
-            &mut b,
-            pn,
-            pn_len,
-            payload_len,
-            payload_offset,
-            None,
-            aead,
-        )
-        .unwrap();
+        let frames = [
+            frame::Frame::StreamsBlockedBidi { limit: 3 },
+            frame::Frame::StreamsBlockedUni { limit: 3 },
+            frame::Frame::StreamsBlockedUni { limit: 3 },
+        ];
 
-        assert_eq!(pipe.server.timeout(), None);
+        let pkt_type = packet::Type::Short;
+        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
 
         assert_eq!(
-            pipe.server_recv(&mut buf[..written]),
-            Err(Error::InvalidPacket)
+            pipe.server.streams_blocked_next(),
+            Some(StreamsBlocked {
+                bidi: true,
+                limit: 3
+            })
         );
-
-        assert!(pipe.server.is_closed());
+        assert_eq!(
+            pipe.server.streams_blocked_next(),
+            Some(StreamsBlocked {
+                bidi: false,
+                limit: 3
+            })
+        );
+        assert_eq!(pipe.server.streams_blocked_next(), None);
     }
 
     #[test]
-    /// Tests that invalid packets don't cause the connection to be closed.
-    fn invalid_packet() {
+    fn stream_data_overlap() {
         let mut buf = [0; 65535];
 
         let mut pipe = testing::Pipe::new().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        let frames = [frame::Frame::Padding { len: 10 }];
-
-        let written = testing::encode_pkt(
-            &mut pipe.client,
-            packet::Type::Short,
-            &frames,
-            &mut buf,
-        )
-        .unwrap();
-
-        // Corrupt the packets's last byte to make decryption fail (the last
-        // byte is part of the AEAD tag, so changing it means that the packet
-        // cannot be authenticated during decryption).
-        buf[written - 1] = !buf[written - 1];
-
-        assert_eq!(pipe.server_recv(&mut buf[..written]), Ok(written));
+        let frames = [
+            frame::Frame::Stream {
+                stream_id: 0,
+                data: stream::RangeBuf::from(b"aaaaa", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 0,
+                data: stream::RangeBuf::from(b"bbbbb", 3, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 0,
+                data: stream::RangeBuf::from(b"ccccc", 6, false),
+            },
+        ];
 
-        // Corrupt the packets's first byte to make the header fail decoding.
-        buf[0] = 255;
+        let pkt_type = packet::Type::Short;
+        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
 
-        assert_eq!(pipe.server_recv(&mut buf[..written]), Ok(written));
+        let mut b = [0; 15];
+        assert_eq!(pipe.server.stream_recv(0, &mut b), Ok((11, false)));
+        assert_eq!(&b[..11], b"aaaaabbbccc");
     }
 
     #[test]
-    fn recv_empty_buffer() {
+    fn stream_recv_offsets() {
         let mut buf = [0; 65535];
 
         let mut pipe = testing::Pipe::new().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        assert_eq!(pipe.server_recv(&mut buf[..0]), Err(Error::BufferTooShort));
+        assert_eq!(
+            pipe.server.stream_recv_offsets(0),
+            Err(Error::InvalidStreamState(0))
+        );
+
+        // The server can't receive on its own unidirectional streams.
+        assert_eq!(
+            pipe.server.stream_recv_offsets(3),
+            Err(Error::InvalidStreamState(3))
+        );
+
+        let frames = [
+            frame::Frame::Stream {
+                stream_id: 0,
+                data: stream::RangeBuf::from(b"aaaaa", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 0,
+                data: stream::RangeBuf::from(b"ccccc", 10, false),
+            },
+        ];
+
+        let pkt_type = packet::Type::Short;
+        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
+
+        let mut b = [0; 15];
+        assert_eq!(pipe.server.stream_recv(0, &mut b), Ok((5, false)));
+
+        let offsets = pipe.server.stream_recv_offsets(0).unwrap();
+        assert_eq!(offsets.contiguous, 5);
+        assert_eq!(offsets.highest, 15);
+        assert_eq!(offsets.gaps.len(), 1);
+        assert_eq!(offsets.gaps[0], 5..10);
+
+        let frames = [frame::Frame::Stream {
+            stream_id: 0,
+            data: stream::RangeBuf::from(b"bbbbb", 5, false),
+        }];
+
+        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
+
+        assert_eq!(
+            pipe.server.stream_recv_offsets(0),
+            Ok(StreamRecvOffsets {
+                contiguous: 15,
+                highest: 15,
+                gaps: vec![],
+            })
+        );
     }
 
     #[test]
-    /// Tests that the MAX_STREAMS frame is sent for bidirectional streams.
-    fn stream_limit_update_bidi() {
-        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+    fn reassembly_limit() {
+        let mut buf = [0; 65535];
+
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
         config
             .load_cert_chain_from_pem_file("examples/cert.crt")
             .unwrap();
@@ -11689,71 +18458,135 @@ mod tests {
             .set_application_protos(&[b"proto1", b"proto2"])
             .unwrap();
         config.set_initial_max_data(30);
-        config.set_initial_max_stream_data_bidi_local(15);
         config.set_initial_max_stream_data_bidi_remote(15);
-        config.set_initial_max_stream_data_uni(10);
         config.set_initial_max_streams_bidi(3);
-        config.set_initial_max_streams_uni(0);
-        config.verify_peer(false);
+        config.set_max_reassembly_buffer(8);
+        config.set_reassembly_stop_sending(42);
 
-        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        // Client sends stream data.
-        assert_eq!(pipe.client.stream_send(0, b"a", false), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
+        let frames = [frame::Frame::Stream {
+            stream_id: 0,
+            data: stream::RangeBuf::from(b"aaaaa", 5, false),
+        }];
 
-        assert_eq!(pipe.client.stream_send(4, b"a", false), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
+        let pkt_type = packet::Type::Short;
+        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
 
-        assert_eq!(pipe.client.stream_send(4, b"b", true), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(pipe.server.reassembly_buffered(), 5);
+        assert_eq!(pipe.server.reassembly_pressure_next(), None);
 
-        assert_eq!(pipe.client.stream_send(0, b"b", true), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
+        // A second stream pushes the connection over the limit, so it's
+        // stopped.
+        let frames = [frame::Frame::Stream {
+            stream_id: 4,
+            data: stream::RangeBuf::from(b"bbbbb", 5, false),
+        }];
+
+        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
+
+        assert_eq!(
+            pipe.server.reassembly_pressure_next(),
+            Some(ReassemblyPressure {
+                stream_id: 4,
+                buffered: 5,
+                stopped: true,
+            })
+        );
+        assert_eq!(pipe.server.reassembly_pressure_next(), None);
+        assert_eq!(pipe.server.reassembly_buffered(), 5);
+
+        // Filling the gap releases the reassembly memory.
+        let frames = [frame::Frame::Stream {
+            stream_id: 0,
+            data: stream::RangeBuf::from(b"aaaaa", 0, false),
+        }];
+
+        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
+
+        assert_eq!(pipe.server.reassembly_buffered(), 0);
 
-        // Server reads stream data.
         let mut b = [0; 15];
-        pipe.server.stream_recv(0, &mut b).unwrap();
-        pipe.server.stream_recv(4, &mut b).unwrap();
-        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(pipe.server.stream_recv(0, &mut b), Ok((10, false)));
+    }
 
-        // Server sends stream data, with fin.
-        assert_eq!(pipe.server.stream_send(0, b"a", false), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
+    #[test]
+    fn stream_recv_buf() {
+        let mut buf = [0; 65535];
 
-        assert_eq!(pipe.server.stream_send(4, b"a", false), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        assert_eq!(pipe.server.stream_send(4, b"b", true), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
+        let frames = [
+            frame::Frame::Stream {
+                stream_id: 0,
+                data: stream::RangeBuf::from(b"aaaaa", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 0,
+                data: stream::RangeBuf::from(b"bbbbb", 5, true),
+            },
+        ];
 
-        assert_eq!(pipe.server.stream_send(0, b"b", true), Ok(1));
+        let pkt_type = packet::Type::Short;
+        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
 
-        // Server sends MAX_STREAMS.
-        assert_eq!(pipe.advance(), Ok(()));
+        let (data, fin) = pipe.server.stream_recv_buf(0, 8).unwrap();
+        assert_eq!(&data[..], b"aaaaa");
+        assert_eq!(data.off(), 0);
+        assert!(!fin);
 
-        // Client tries to create new streams.
-        assert_eq!(pipe.client.stream_send(8, b"a", false), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
+        let (data, fin) = pipe.server.stream_recv_buf(0, 3).unwrap();
+        assert_eq!(&data[..], b"bbb");
+        assert_eq!(data.off(), 5);
+        assert!(!fin);
 
-        assert_eq!(pipe.client.stream_send(12, b"a", false), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
+        assert!(pipe.server.stream_readable(0));
 
-        assert_eq!(pipe.client.stream_send(16, b"a", false), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
+        let (data, fin) = pipe.server.stream_recv_buf(0, 8).unwrap();
+        assert_eq!(&data[..], b"bb");
+        assert!(fin);
 
+        assert_eq!(pipe.server.stream_recv_buf(0, 8).err(), Some(Error::Done));
+        assert!(!pipe.server.stream_readable(0));
+
+        // Own unidirectional streams can't be read.
         assert_eq!(
-            pipe.client.stream_send(20, b"a", false),
-            Err(Error::StreamLimit)
+            pipe.client.stream_recv_buf(2, 8).err(),
+            Some(Error::InvalidStreamState(2))
         );
-
-        assert_eq!(pipe.server.readable().len(), 3);
     }
 
     #[test]
-    /// Tests that the MAX_STREAMS frame is sent for unidirectional streams.
-    fn stream_limit_update_uni() {
+    fn buffer_pool() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        // The buffers holding the handshake data were recycled.
+        let pooled = pipe.server.buf_pool.len();
+        assert!(pooled > 0);
+
+        let frames = [frame::Frame::Stream {
+            stream_id: 0,
+            data: stream::RangeBuf::from(b"aaaaa", 0, false),
+        }];
+
+        let pkt_type = packet::Type::Short;
+        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
+
+        // The received data is held in a pooled buffer until it is read.
+        assert_eq!(pipe.server.buf_pool.len(), pooled - 1);
+
+        let mut b = [0; 5];
+        assert_eq!(pipe.server.stream_recv(0, &mut b), Ok((5, false)));
+        assert_eq!(&b, b"aaaaa");
+
+        assert_eq!(pipe.server.buf_pool.len(), pooled);
+
+        // Nothing is kept when the pool is disabled.
         let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
         config
             .load_cert_chain_from_pem_file("examples/cert.crt")
@@ -11764,613 +18597,516 @@ mod tests {
         config
             .set_application_protos(&[b"proto1", b"proto2"])
             .unwrap();
-        config.set_initial_max_data(30);
-        config.set_initial_max_stream_data_bidi_local(15);
-        config.set_initial_max_stream_data_bidi_remote(15);
-        config.set_initial_max_stream_data_uni(10);
-        config.set_initial_max_streams_bidi(0);
-        config.set_initial_max_streams_uni(3);
-        config.verify_peer(false);
+        config.set_buffer_pool_size(0);
 
-        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        // Client sends stream data.
-        assert_eq!(pipe.client.stream_send(2, b"a", false), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
-
-        assert_eq!(pipe.client.stream_send(6, b"a", false), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(pipe.server.buf_pool.len(), 0);
+    }
 
-        assert_eq!(pipe.client.stream_send(6, b"b", true), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
+    #[test]
+    fn stream_send_vectored() {
+        let mut buf = [0; 65535];
 
-        assert_eq!(pipe.client.stream_send(2, b"b", true), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        // Server reads stream data.
-        let mut b = [0; 15];
-        pipe.server.stream_recv(2, &mut b).unwrap();
-        pipe.server.stream_recv(6, &mut b).unwrap();
+        let bufs = [
+            std::io::IoSlice::new(b"hello"),
+            std::io::IoSlice::new(b", "),
+            std::io::IoSlice::new(b"world"),
+        ];
 
-        // Server sends MAX_STREAMS.
+        assert_eq!(pipe.client.stream_send_vectored(0, &bufs, true), Ok(12));
         assert_eq!(pipe.advance(), Ok(()));
 
-        // Client tries to create new streams.
-        assert_eq!(pipe.client.stream_send(10, b"a", false), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(pipe.server.stream_recv(0, &mut buf), Ok((12, true)));
+        assert_eq!(&buf[..12], b"hello, world");
 
-        assert_eq!(pipe.client.stream_send(14, b"a", false), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
+        // The write is truncated to the stream's capacity, so the fin flag is
+        // not set.
+        let bufs = [
+            std::io::IoSlice::new(b"aaaaaaaaaa"),
+            std::io::IoSlice::new(b"bbbbbbbbbb"),
+        ];
 
-        assert_eq!(pipe.client.stream_send(18, b"a", false), Ok(1));
+        assert_eq!(pipe.client.stream_send_vectored(4, &bufs, true), Ok(15));
         assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(
-            pipe.client.stream_send(22, b"a", false),
-            Err(Error::StreamLimit)
-        );
-
-        assert_eq!(pipe.server.readable().len(), 3);
+        assert_eq!(pipe.server.stream_recv(4, &mut buf), Ok((15, false)));
+        assert_eq!(&buf[..15], b"aaaaaaaaaabbbbb");
     }
 
     #[test]
-    /// Tests that the stream's fin flag is properly flushed even if there's no
-    /// data in the buffer, and that the buffer becomes readable on the other
-    /// side.
-    fn stream_zero_length_fin() {
+    fn stream_send_owned() {
+        let mut buf = [0; 65535];
+
         let mut pipe = testing::Pipe::new().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        assert_eq!(
-            pipe.client.stream_send(0, b"aaaaaaaaaaaaaaa", false),
-            Ok(15)
-        );
-        assert_eq!(pipe.advance(), Ok(()));
-
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), Some(0));
-        assert!(r.next().is_none());
-
-        let mut b = [0; 15];
-        pipe.server.stream_recv(0, &mut b).unwrap();
-        assert_eq!(pipe.advance(), Ok(()));
+        let data = Arc::new(b"aaaaaaaaaabbbbbbbbbb".to_vec());
+        let mut owned = StreamBuf::from(data.clone());
 
-        // Client sends zero-length frame.
-        assert_eq!(pipe.client.stream_send(0, b"", true), Ok(0));
+        // Only part of the data fits in the stream's capacity, the rest is
+        // left in the buffer.
+        assert_eq!(pipe.client.stream_send_owned(0, &mut owned, true), Ok(15));
+        assert_eq!(&owned[..], b"bbbbb");
         assert_eq!(pipe.advance(), Ok(()));
 
-        // Stream should be readable on the server after receiving empty fin.
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), Some(0));
-        assert!(r.next().is_none());
-
-        let mut b = [0; 15];
-        pipe.server.stream_recv(0, &mut b).unwrap();
+        assert_eq!(pipe.server.stream_recv(0, &mut buf), Ok((15, false)));
+        assert_eq!(&buf[..15], b"aaaaaaaaaabbbbb");
         assert_eq!(pipe.advance(), Ok(()));
 
-        // Client sends zero-length frame (again).
-        assert_eq!(pipe.client.stream_send(0, b"", true), Ok(0));
+        assert_eq!(pipe.client.stream_send_owned(0, &mut owned, true), Ok(5));
+        assert!(owned.is_empty());
         assert_eq!(pipe.advance(), Ok(()));
 
-        // Stream should _not_ be readable on the server after receiving empty
-        // fin, because it was already finished.
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), None);
+        assert_eq!(pipe.server.stream_recv(0, &mut buf), Ok((5, true)));
+        assert_eq!(&buf[..5], b"bbbbb");
     }
 
     #[test]
-    /// Tests that the stream's fin flag is properly flushed even if there's no
-    /// data in the buffer, that the buffer becomes readable on the other
-    /// side and stays readable even if the stream is fin'd locally.
-    fn stream_zero_length_fin_deferred_collection() {
+    fn stream_send_file() {
+        let mut buf = [0; 65535];
+
+        let data = std::fs::read("examples/cert.crt").unwrap();
+
         let mut pipe = testing::Pipe::new().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
+        let file = std::fs::File::open("examples/cert.crt").unwrap();
+        assert_eq!(pipe.client.stream_send_file(0, file, 10, 40, true), Ok(()));
+
+        // Nothing else can be written to the stream until the whole region
+        // has been sent.
         assert_eq!(
-            pipe.client.stream_send(0, b"aaaaaaaaaaaaaaa", false),
-            Ok(15)
+            pipe.client.stream_send(0, b"a", false),
+            Err(Error::InvalidStreamState(0))
         );
-        assert_eq!(pipe.advance(), Ok(()));
-
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), Some(0));
-        assert!(r.next().is_none());
-
-        let mut b = [0; 15];
-        pipe.server.stream_recv(0, &mut b).unwrap();
-        assert_eq!(pipe.advance(), Ok(()));
 
-        // Client sends zero-length frame.
-        assert_eq!(pipe.client.stream_send(0, b"", true), Ok(0));
-        assert_eq!(pipe.advance(), Ok(()));
+        // The region is larger than the stream's flow control window, so it
+        // is sent over multiple rounds as credit is returned.
+        let mut recv = Vec::new();
+        let mut fin = false;
 
-        // Server sends zero-length frame.
-        assert_eq!(pipe.server.stream_send(0, b"", true), Ok(0));
-        assert_eq!(pipe.advance(), Ok(()));
+        for _ in 0..10 {
+            assert_eq!(pipe.advance(), Ok(()));
 
-        // Stream should be readable on the server after receiving empty fin.
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), Some(0));
-        assert!(r.next().is_none());
+            match pipe.server.stream_recv(0, &mut buf) {
+                Ok((len, f)) => {
+                    recv.extend_from_slice(&buf[..len]);
+                    fin = f;
+                },
 
-        let mut b = [0; 15];
-        pipe.server.stream_recv(0, &mut b).unwrap();
-        assert_eq!(pipe.advance(), Ok(()));
+                Err(Error::Done) => (),
 
-        // Client sends zero-length frame (again).
-        assert_eq!(pipe.client.stream_send(0, b"", true), Ok(0));
-        assert_eq!(pipe.advance(), Ok(()));
+                Err(e) => panic!("unexpected error {:?}", e),
+            }
 
-        // Stream should _not_ be readable on the server after receiving empty
-        // fin, because it was already finished.
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), None);
+            if fin {
+                break;
+            }
+        }
 
-        // Stream _is_readable on the client side.
-        let mut r = pipe.client.readable();
-        assert_eq!(r.next(), Some(0));
+        assert!(fin);
+        assert_eq!(&recv[..], &data[10..50]);
 
-        pipe.client.stream_recv(0, &mut b).unwrap();
+        // Reading past the end of the file resets the stream.
+        let file = std::fs::File::open("examples/cert.crt").unwrap();
+        let off = data.len() as u64 - 5;
+        assert_eq!(pipe.client.stream_send_file(4, file, off, 10, true), Ok(()));
         assert_eq!(pipe.advance(), Ok(()));
 
-        // Stream is completed and _is not_ readable.
-        let mut r = pipe.client.readable();
-        assert_eq!(r.next(), None);
+        assert_eq!(
+            pipe.server.stream_recv(4, &mut buf),
+            Err(Error::StreamReset(0))
+        );
     }
 
     #[test]
-    /// Tests that the stream gets created with stream_send() even if there's
-    /// no data in the buffer and the fin flag is not set.
-    fn stream_zero_length_non_fin() {
+    fn stream_data_overlap_with_reordering() {
+        let mut buf = [0; 65535];
+
         let mut pipe = testing::Pipe::new().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        assert_eq!(pipe.client.stream_send(0, b"", false), Ok(0));
+        let frames = [
+            frame::Frame::Stream {
+                stream_id: 0,
+                data: stream::RangeBuf::from(b"aaaaa", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 0,
+                data: stream::RangeBuf::from(b"ccccc", 6, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 0,
+                data: stream::RangeBuf::from(b"bbbbb", 3, false),
+            },
+        ];
 
-        // The stream now should have been created.
-        assert_eq!(pipe.client.streams.len(), 1);
-        assert_eq!(pipe.advance(), Ok(()));
+        let pkt_type = packet::Type::Short;
+        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
 
-        // Sending an empty non-fin should not change any stream state on the
-        // other side.
-        let mut r = pipe.server.readable();
-        assert!(r.next().is_none());
+        let mut b = [0; 15];
+        assert_eq!(pipe.server.stream_recv(0, &mut b), Ok((11, false)));
+        assert_eq!(&b[..11], b"aaaaabccccc");
     }
 
     #[test]
-    /// Tests that completed streams are garbage collected.
-    fn collect_streams() {
+    /// Tests that receiving a valid RESET_STREAM frame when all data has
+    /// already been read, notifies the application.
+    fn reset_stream_data_recvd() {
+        let mut b = [0; 15];
         let mut buf = [0; 65535];
 
         let mut pipe = testing::Pipe::new().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        assert_eq!(pipe.client.streams.len(), 0);
-        assert_eq!(pipe.server.streams.len(), 0);
-
-        assert_eq!(pipe.client.stream_send(0, b"aaaaa", true), Ok(5));
-        assert_eq!(pipe.advance(), Ok(()));
-
-        assert!(!pipe.client.stream_finished(0));
-        assert!(!pipe.server.stream_finished(0));
-
-        assert_eq!(pipe.client.streams.len(), 1);
-        assert_eq!(pipe.server.streams.len(), 1);
-
-        let mut b = [0; 5];
-        pipe.server.stream_recv(0, &mut b).unwrap();
+        // Client sends some data.
+        assert_eq!(pipe.client.stream_send(0, b"hello", false), Ok(5));
         assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(pipe.server.stream_send(0, b"aaaaa", true), Ok(5));
-        assert_eq!(pipe.advance(), Ok(()));
+        // Server gets data and sends data back, closing stream.
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(0));
+        assert_eq!(r.next(), None);
 
-        assert!(!pipe.client.stream_finished(0));
-        assert!(pipe.server.stream_finished(0));
+        assert_eq!(pipe.server.stream_recv(0, &mut b), Ok((5, false)));
+        assert!(!pipe.server.stream_finished(0));
 
-        assert_eq!(pipe.client.streams.len(), 1);
-        assert_eq!(pipe.server.streams.len(), 0);
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), None);
 
-        let mut b = [0; 5];
-        pipe.client.stream_recv(0, &mut b).unwrap();
+        assert_eq!(pipe.server.stream_send(0, b"", true), Ok(0));
         assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(pipe.client.streams.len(), 0);
-        assert_eq!(pipe.server.streams.len(), 0);
+        let mut r = pipe.client.readable();
+        assert_eq!(r.next(), Some(0));
+        assert_eq!(r.next(), None);
 
+        assert_eq!(pipe.client.stream_recv(0, &mut b), Ok((0, true)));
         assert!(pipe.client.stream_finished(0));
-        assert!(pipe.server.stream_finished(0));
 
-        assert_eq!(pipe.client.stream_send(0, b"", true), Err(Error::Done));
-
-        let frames = [frame::Frame::Stream {
+        // Client sends RESET_STREAM, closing stream.
+        let frames = [frame::Frame::ResetStream {
             stream_id: 0,
-            data: stream::RangeBuf::from(b"aa", 0, false),
+            error_code: 42,
+            final_size: 5,
         }];
 
         let pkt_type = packet::Type::Short;
         assert_eq!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf), Ok(39));
-    }
-
-    #[test]
-    fn config_set_cc_algorithm_name() {
-        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
 
-        assert_eq!(config.set_cc_algorithm_name("reno"), Ok(()));
+        // Server is notified of stream readability, due to reset.
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(0));
+        assert_eq!(r.next(), None);
 
-        // Unknown name.
         assert_eq!(
-            config.set_cc_algorithm_name("???"),
-            Err(Error::CongestionControl)
+            pipe.server.stream_recv(0, &mut b),
+            Err(Error::StreamReset(42))
         );
+
+        assert!(pipe.server.stream_finished(0));
+
+        // Sending RESET_STREAM again shouldn't make stream readable again.
+        pipe.send_pkt_to_server(pkt_type, &frames, &mut buf)
+            .unwrap();
+
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), None);
     }
 
     #[test]
-    fn peer_cert() {
+    /// Tests that receiving a valid RESET_STREAM frame when all data has _not_
+    /// been read, discards all buffered data and notifies the application.
+    fn reset_stream_data_not_recvd() {
+        let mut b = [0; 15];
+        let mut buf = [0; 65535];
+
         let mut pipe = testing::Pipe::new().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        match pipe.client.peer_cert() {
-            Some(c) => assert_eq!(c.len(), 753),
+        // Client sends some data.
+        assert_eq!(pipe.client.stream_send(0, b"h", false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-            None => panic!("missing server certificate"),
-        }
-    }
-
-    #[test]
-    fn peer_cert_chain() {
-        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
-        config
-            .load_cert_chain_from_pem_file("examples/cert-big.crt")
-            .unwrap();
-        config
-            .load_priv_key_from_pem_file("examples/cert.key")
-            .unwrap();
-        config
-            .set_application_protos(&[b"proto1", b"proto2"])
-            .unwrap();
-
-        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
-
-        match pipe.client.peer_cert_chain() {
-            Some(c) => assert_eq!(c.len(), 5),
+        // Server gets data and sends data back, closing stream.
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(0));
+        assert_eq!(r.next(), None);
 
-            None => panic!("missing server certificate chain"),
-        }
-    }
+        assert_eq!(pipe.server.stream_recv(0, &mut b), Ok((1, false)));
+        assert!(!pipe.server.stream_finished(0));
 
-    #[test]
-    fn retry() {
-        let mut buf = [0; 65535];
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), None);
 
-        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
-        config
-            .load_cert_chain_from_pem_file("examples/cert.crt")
-            .unwrap();
-        config
-            .load_priv_key_from_pem_file("examples/cert.key")
-            .unwrap();
-        config
-            .set_application_protos(&[b"proto1", b"proto2"])
-            .unwrap();
+        assert_eq!(pipe.server.stream_send(0, b"", true), Ok(0));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
+        let mut r = pipe.client.readable();
+        assert_eq!(r.next(), Some(0));
+        assert_eq!(r.next(), None);
 
-        // Client sends initial flight.
-        let (mut len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(pipe.client.stream_recv(0, &mut b), Ok((0, true)));
+        assert!(pipe.client.stream_finished(0));
 
-        // Server sends Retry packet.
-        let hdr = Header::from_slice(&mut buf[..len], MAX_CONN_ID_LEN).unwrap();
+        // Client sends RESET_STREAM, closing stream.
+        let frames = [frame::Frame::ResetStream {
+            stream_id: 0,
+            error_code: 42,
+            final_size: 5,
+        }];
 
-        let odcid = hdr.dcid.clone();
+        let pkt_type = packet::Type::Short;
+        assert_eq!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf), Ok(39));
 
-        let mut scid = [0; MAX_CONN_ID_LEN];
-        rand::rand_bytes(&mut scid[..]);
-        let scid = ConnectionId::from_ref(&scid);
+        // Server is notified of stream readability, due to reset.
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(0));
+        assert_eq!(r.next(), None);
 
-        let token = b"quiche test retry token";
+        assert_eq!(
+            pipe.server.stream_recv(0, &mut b),
+            Err(Error::StreamReset(42))
+        );
 
-        len = packet::retry(
-            &hdr.scid,
-            &hdr.dcid,
-            &scid,
-            token,
-            hdr.version,
-            &mut buf,
-        )
-        .unwrap();
+        assert!(pipe.server.stream_finished(0));
 
-        // Client receives Retry and sends new Initial.
-        assert_eq!(pipe.client_recv(&mut buf[..len]), Ok(len));
+        // Sending RESET_STREAM again shouldn't make stream readable again.
+        assert_eq!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf), Ok(39));
 
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), None);
+    }
 
-        let hdr = Header::from_slice(&mut buf[..len], MAX_CONN_ID_LEN).unwrap();
-        assert_eq!(&hdr.token.unwrap(), token);
+    #[test]
+    /// Tests that RESET_STREAM frames exceeding the connection-level flow
+    /// control limit cause an error.
+    fn reset_stream_flow_control() {
+        let mut buf = [0; 65535];
 
-        // Server accepts connection.
-        let from = "127.0.0.1:1234".parse().unwrap();
-        pipe.server = accept(
-            &scid,
-            Some(&odcid),
-            testing::Pipe::server_addr(),
-            from,
-            &mut config,
-        )
-        .unwrap();
-        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        assert_eq!(pipe.advance(), Ok(()));
+        let frames = [
+            frame::Frame::Stream {
+                stream_id: 0,
+                data: stream::RangeBuf::from(b"aaaaaaaaaaaaaaa", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 4,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+            frame::Frame::ResetStream {
+                stream_id: 4,
+                error_code: 0,
+                final_size: 15,
+            },
+            frame::Frame::Stream {
+                stream_id: 8,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+        ];
 
-        assert!(pipe.client.is_established());
-        assert!(pipe.server.is_established());
+        let pkt_type = packet::Type::Short;
+        assert_eq!(
+            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
+            Err(Error::FlowControl),
+        );
     }
 
     #[test]
-    fn missing_retry_source_connection_id() {
+    /// Tests that RESET_STREAM frames exceeding the stream-level flow control
+    /// limit cause an error.
+    fn reset_stream_flow_control_stream() {
         let mut buf = [0; 65535];
 
-        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
-        config
-            .load_cert_chain_from_pem_file("examples/cert.crt")
-            .unwrap();
-        config
-            .load_priv_key_from_pem_file("examples/cert.key")
-            .unwrap();
-        config
-            .set_application_protos(&[b"proto1", b"proto2"])
-            .unwrap();
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
+        let frames = [
+            frame::Frame::Stream {
+                stream_id: 4,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+            frame::Frame::ResetStream {
+                stream_id: 4,
+                error_code: 0,
+                final_size: 16, // Past stream's flow control limit.
+            },
+        ];
 
-        // Client sends initial flight.
-        let (mut len, _) = pipe.client.send(&mut buf).unwrap();
+        let pkt_type = packet::Type::Short;
+        assert_eq!(
+            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
+            Err(Error::FlowControl),
+        );
+    }
 
-        // Server sends Retry packet.
-        let hdr = Header::from_slice(&mut buf[..len], MAX_CONN_ID_LEN).unwrap();
+    #[test]
+    fn path_challenge() {
+        let mut buf = [0; 65535];
 
-        let mut scid = [0; MAX_CONN_ID_LEN];
-        rand::rand_bytes(&mut scid[..]);
-        let scid = ConnectionId::from_ref(&scid);
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        let token = b"quiche test retry token";
+        let frames = [frame::Frame::PathChallenge { data: [0xba; 8] }];
 
-        len = packet::retry(
-            &hdr.scid,
-            &hdr.dcid,
-            &scid,
-            token,
-            hdr.version,
-            &mut buf,
-        )
-        .unwrap();
+        let pkt_type = packet::Type::Short;
 
-        // Client receives Retry and sends new Initial.
-        assert_eq!(pipe.client_recv(&mut buf[..len]), Ok(len));
+        let len = pipe
+            .send_pkt_to_server(pkt_type, &frames, &mut buf)
+            .unwrap();
 
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert!(len > 0);
 
-        // Server accepts connection and send first flight. But original
-        // destination connection ID is ignored.
-        let from = "127.0.0.1:1234".parse().unwrap();
-        pipe.server =
-            accept(&scid, None, testing::Pipe::server_addr(), from, &mut config)
-                .unwrap();
-        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+        let frames =
+            testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
+        let mut iter = frames.iter();
 
-        let flight = testing::emit_flight(&mut pipe.server).unwrap();
+        // Ignore ACK.
+        iter.next().unwrap();
 
         assert_eq!(
-            testing::process_flight(&mut pipe.client, flight),
-            Err(Error::InvalidTransportParam)
+            iter.next(),
+            Some(&frame::Frame::PathResponse { data: [0xba; 8] })
         );
     }
 
     #[test]
-    fn invalid_retry_source_connection_id() {
+    /// Simulates reception of an early 1-RTT packet on the server, by
+    /// delaying the client's Handshake packet that completes the handshake.
+    fn early_1rtt_packet() {
         let mut buf = [0; 65535];
 
-        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
-        config
-            .load_cert_chain_from_pem_file("examples/cert.crt")
-            .unwrap();
-        config
-            .load_priv_key_from_pem_file("examples/cert.key")
-            .unwrap();
-        config
-            .set_application_protos(&[b"proto1", b"proto2"])
-            .unwrap();
+        let mut pipe = testing::Pipe::new().unwrap();
 
-        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
+        // Client sends initial flight
+        let flight = testing::emit_flight(&mut pipe.client).unwrap();
+        testing::process_flight(&mut pipe.server, flight).unwrap();
 
-        // Client sends initial flight.
-        let (mut len, _) = pipe.client.send(&mut buf).unwrap();
+        // Server sends initial flight.
+        let flight = testing::emit_flight(&mut pipe.server).unwrap();
+        testing::process_flight(&mut pipe.client, flight).unwrap();
 
-        // Server sends Retry packet.
-        let hdr = Header::from_slice(&mut buf[..len], MAX_CONN_ID_LEN).unwrap();
+        // Client sends Handshake packet.
+        let flight = testing::emit_flight(&mut pipe.client).unwrap();
 
-        let mut scid = [0; MAX_CONN_ID_LEN];
-        rand::rand_bytes(&mut scid[..]);
-        let scid = ConnectionId::from_ref(&scid);
-
-        let token = b"quiche test retry token";
-
-        len = packet::retry(
-            &hdr.scid,
-            &hdr.dcid,
-            &scid,
-            token,
-            hdr.version,
-            &mut buf,
-        )
-        .unwrap();
-
-        // Client receives Retry and sends new Initial.
-        assert_eq!(pipe.client_recv(&mut buf[..len]), Ok(len));
-
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
-
-        // Server accepts connection and send first flight. But original
-        // destination connection ID is invalid.
-        let from = "127.0.0.1:1234".parse().unwrap();
-        let odcid = ConnectionId::from_ref(b"bogus value");
-        pipe.server = accept(
-            &scid,
-            Some(&odcid),
-            testing::Pipe::server_addr(),
-            from,
-            &mut config,
-        )
-        .unwrap();
-        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
-
-        let flight = testing::emit_flight(&mut pipe.server).unwrap();
-
-        assert_eq!(
-            testing::process_flight(&mut pipe.client, flight),
-            Err(Error::InvalidTransportParam)
-        );
-    }
-
-    #[test]
-    /// Tests that a zero-length NEW_TOKEN frame is detected as an error.
-    fn zero_length_new_token() {
-        let mut buf = [0; 65535];
+        // Emulate handshake packet delay by not making server process client
+        // packet.
+        let delayed = flight;
 
-        let mut pipe = testing::Pipe::new().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+        testing::emit_flight(&mut pipe.server).ok();
 
-        let mut frames = Vec::new();
+        assert!(pipe.client.is_established());
 
-        frames.push(frame::Frame::NewToken { token: vec![] });
+        // Send 1-RTT packet #0.
+        let frames = [frame::Frame::Stream {
+            stream_id: 0,
+            data: stream::RangeBuf::from(b"hello, world", 0, true),
+        }];
 
         let pkt_type = packet::Type::Short;
-
         let written =
-            testing::encode_pkt(&mut pipe.server, pkt_type, &frames, &mut buf)
+            testing::encode_pkt(&mut pipe.client, pkt_type, &frames, &mut buf)
                 .unwrap();
 
-        assert_eq!(
-            pipe.client_recv(&mut buf[..written]),
-            Err(Error::InvalidFrame)
-        );
-    }
-
-    #[test]
-    /// Tests that a NEW_TOKEN frame sent by client is detected as an error.
-    fn client_sent_new_token() {
-        let mut buf = [0; 65535];
-
-        let mut pipe = testing::Pipe::new().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
-
-        let mut frames = Vec::new();
-
-        frames.push(frame::Frame::NewToken {
-            token: vec![1, 2, 3],
-        });
+        assert_eq!(pipe.server_recv(&mut buf[..written]), Ok(written));
 
-        let pkt_type = packet::Type::Short;
+        // Send 1-RTT packet #1.
+        let frames = [frame::Frame::Stream {
+            stream_id: 4,
+            data: stream::RangeBuf::from(b"hello, world", 0, true),
+        }];
 
         let written =
             testing::encode_pkt(&mut pipe.client, pkt_type, &frames, &mut buf)
                 .unwrap();
 
-        assert_eq!(
-            pipe.server_recv(&mut buf[..written]),
-            Err(Error::InvalidPacket)
-        );
-    }
+        assert_eq!(pipe.server_recv(&mut buf[..written]), Ok(written));
 
-    fn check_send(_: &mut impl Send) {}
+        assert!(!pipe.server.is_established());
 
-    #[test]
-    fn config_must_be_send() {
-        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
-        check_send(&mut config);
-    }
+        // Client sent 1-RTT packets 0 and 1, but server hasn't received them.
+        //
+        // Note that `largest_rx_pkt_num` is initialized to 0, so we need to
+        // send another 1-RTT packet to make this check meaningful.
+        assert_eq!(
+            pipe.server.pkt_num_spaces[packet::Epoch::Application]
+                .largest_rx_pkt_num,
+            0
+        );
 
-    #[test]
-    fn connection_must_be_send() {
-        let mut pipe = testing::Pipe::new().unwrap();
-        check_send(&mut pipe.client);
-    }
+        // Process delayed packet.
+        testing::process_flight(&mut pipe.server, delayed).unwrap();
 
-    fn check_sync(_: &mut impl Sync) {}
+        assert!(pipe.server.is_established());
 
-    #[test]
-    fn config_must_be_sync() {
-        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
-        check_sync(&mut config);
+        assert_eq!(
+            pipe.server.pkt_num_spaces[packet::Epoch::Application]
+                .largest_rx_pkt_num,
+            0
+        );
     }
 
     #[test]
-    fn connection_must_be_sync() {
-        let mut pipe = testing::Pipe::new().unwrap();
-        check_sync(&mut pipe.client);
-    }
+    fn stop_sending() {
+        let mut b = [0; 15];
 
-    #[test]
-    fn data_blocked() {
         let mut buf = [0; 65535];
 
         let mut pipe = testing::Pipe::new().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        assert_eq!(pipe.client.stream_send(0, b"aaaaaaaaaa", false), Ok(10));
-        assert_eq!(pipe.client.blocked_limit, None);
-        assert_eq!(pipe.advance(), Ok(()));
-
-        assert_eq!(pipe.client.stream_send(4, b"aaaaaaaaaa", false), Ok(10));
-        assert_eq!(pipe.client.blocked_limit, None);
+        // Client sends some data, and closes stream.
+        assert_eq!(pipe.client.stream_send(0, b"hello", true), Ok(5));
         assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(pipe.client.stream_send(8, b"aaaaaaaaaaa", false), Ok(10));
-        assert_eq!(pipe.client.blocked_limit, Some(30));
-
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
-        assert_eq!(pipe.client.blocked_limit, None);
-
-        let frames =
-            testing::decode_pkt(&mut pipe.server, &mut buf[..len]).unwrap();
-
-        let mut iter = frames.iter();
-
-        assert_eq!(iter.next(), Some(&frame::Frame::DataBlocked { limit: 30 }));
+        // Server gets data.
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(0));
+        assert_eq!(r.next(), None);
 
-        assert_eq!(
-            iter.next(),
-            Some(&frame::Frame::Stream {
-                stream_id: 8,
-                data: stream::RangeBuf::from(b"aaaaaaaaaa", 0, false),
-            })
-        );
+        assert_eq!(pipe.server.stream_recv(0, &mut b), Ok((5, true)));
+        assert!(pipe.server.stream_finished(0));
 
-        assert_eq!(iter.next(), None);
-    }
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), None);
 
-    #[test]
-    fn stream_data_blocked() {
-        let mut buf = [0; 65535];
+        // Server sends data, until blocked.
+        let mut r = pipe.server.writable();
+        assert_eq!(r.next(), Some(0));
+        assert_eq!(r.next(), None);
 
-        let mut pipe = testing::Pipe::new().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+        loop {
+            if pipe.server.stream_send(0, b"world", false) == Err(Error::Done) {
+                break;
+            }
 
-        assert_eq!(pipe.client.stream_send(0, b"aaaaa", false), Ok(5));
-        assert_eq!(pipe.client.streams.blocked().len(), 0);
+            assert_eq!(pipe.advance(), Ok(()));
+        }
 
-        assert_eq!(pipe.client.stream_send(0, b"aaaaa", false), Ok(5));
-        assert_eq!(pipe.client.streams.blocked().len(), 0);
+        let mut r = pipe.server.writable();
+        assert_eq!(r.next(), None);
 
-        assert_eq!(pipe.client.stream_send(0, b"aaaaaa", false), Ok(5));
-        assert_eq!(pipe.client.streams.blocked().len(), 1);
+        // Client sends STOP_SENDING.
+        let frames = [frame::Frame::StopSending {
+            stream_id: 0,
+            error_code: 42,
+        }];
 
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
-        assert_eq!(pipe.client.streams.blocked().len(), 0);
+        let pkt_type = packet::Type::Short;
+        let len = pipe
+            .send_pkt_to_server(pkt_type, &frames, &mut buf)
+            .unwrap();
 
+        // Server sent a RESET_STREAM frame in response.
         let frames =
-            testing::decode_pkt(&mut pipe.server, &mut buf[..len]).unwrap();
+            testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
 
         let mut iter = frames.iter();
 
@@ -12379,524 +19115,497 @@ mod tests {
 
         assert_eq!(
             iter.next(),
-            Some(&frame::Frame::StreamDataBlocked {
+            Some(&frame::Frame::ResetStream {
                 stream_id: 0,
-                limit: 15,
+                error_code: 42,
+                final_size: 15,
             })
         );
 
+        // Stream is writable, but writing returns an error.
+        let mut r = pipe.server.writable();
+        assert_eq!(r.next(), Some(0));
+        assert_eq!(r.next(), None);
+
         assert_eq!(
-            iter.next(),
-            Some(&frame::Frame::Stream {
-                stream_id: 0,
-                data: stream::RangeBuf::from(b"aaaaaaaaaaaaaaa", 0, false),
-            })
+            pipe.server.stream_send(0, b"world", true),
+            Err(Error::StreamStopped(42)),
         );
 
-        assert_eq!(iter.next(), None);
-
-        // Send from another stream, make sure we don't send STREAM_DATA_BLOCKED
-        // again.
-        assert_eq!(pipe.client.stream_send(4, b"a", false), Ok(1));
+        assert_eq!(pipe.server.streams.len(), 1);
 
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
-        assert_eq!(pipe.client.streams.blocked().len(), 0);
+        // Client acks RESET_STREAM frame.
+        let mut ranges = ranges::RangeSet::default();
+        ranges.insert(0..6);
 
-        let frames =
-            testing::decode_pkt(&mut pipe.server, &mut buf[..len]).unwrap();
+        let frames = [frame::Frame::ACK {
+            ack_delay: 15,
+            ranges,
+            ecn_counts: None,
+        }];
 
-        let mut iter = frames.iter();
+        assert_eq!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf), Ok(0));
 
-        assert_eq!(
-            iter.next(),
-            Some(&frame::Frame::Stream {
-                stream_id: 4,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            })
-        );
+        // Stream is collected on the server after RESET_STREAM is acked.
+        assert_eq!(pipe.server.streams.len(), 0);
 
-        assert_eq!(iter.next(), None);
+        // Sending STOP_SENDING again shouldn't trigger RESET_STREAM again.
+        let frames = [frame::Frame::StopSending {
+            stream_id: 0,
+            error_code: 42,
+        }];
 
-        // Send again from blocked stream and make sure it is not marked as
-        // blocked again.
-        assert_eq!(
-            pipe.client.stream_send(0, b"aaaaaa", false),
-            Err(Error::Done)
-        );
-        assert_eq!(pipe.client.streams.blocked().len(), 0);
-        assert_eq!(pipe.client.send(&mut buf), Err(Error::Done));
+        let len = pipe
+            .send_pkt_to_server(pkt_type, &frames, &mut buf)
+            .unwrap();
+
+        let frames =
+            testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
+
+        assert_eq!(frames.len(), 1);
+
+        match frames.first() {
+            Some(frame::Frame::ACK { .. }) => (),
+
+            f => panic!("expected ACK frame, got {:?}", f),
+        };
+
+        let mut r = pipe.server.writable();
+        assert_eq!(r.next(), None);
     }
 
     #[test]
-    fn stream_data_blocked_unblocked_flow_control() {
+    fn stop_sending_fin() {
+        let mut b = [0; 15];
+
         let mut buf = [0; 65535];
+
         let mut pipe = testing::Pipe::new().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        assert_eq!(
-            pipe.client.stream_send(0, b"aaaaaaaaaaaaaaah", false),
-            Ok(15)
-        );
-        assert_eq!(pipe.client.streams.blocked().len(), 1);
+        // Client sends some data, and closes stream.
+        assert_eq!(pipe.client.stream_send(4, b"hello", true), Ok(5));
         assert_eq!(pipe.advance(), Ok(()));
-        assert_eq!(pipe.client.streams.blocked().len(), 0);
-
-        // Send again on blocked stream. It's blocked at the same offset as
-        // previously, so it should not be marked as blocked again.
-        assert_eq!(pipe.client.stream_send(0, b"h", false), Err(Error::Done));
-        assert_eq!(pipe.client.streams.blocked().len(), 0);
-
-        // No matter how many times we try to write stream data tried, no
-        // packets containing STREAM_BLOCKED should be emitted.
-        assert_eq!(pipe.client.stream_send(0, b"h", false), Err(Error::Done));
-        assert_eq!(pipe.client.send(&mut buf), Err(Error::Done));
 
-        assert_eq!(pipe.client.stream_send(0, b"h", false), Err(Error::Done));
-        assert_eq!(pipe.client.send(&mut buf), Err(Error::Done));
+        // Server gets data.
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(4));
+        assert_eq!(r.next(), None);
 
-        assert_eq!(pipe.client.stream_send(0, b"h", false), Err(Error::Done));
-        assert_eq!(pipe.client.send(&mut buf), Err(Error::Done));
+        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((5, true)));
+        assert!(pipe.server.stream_finished(4));
 
-        // Now read some data at the server to release flow control.
         let mut r = pipe.server.readable();
-        assert_eq!(r.next(), Some(0));
         assert_eq!(r.next(), None);
 
-        let mut b = [0; 10];
-        assert_eq!(pipe.server.stream_recv(0, &mut b), Ok((10, false)));
-        assert_eq!(&b[..10], b"aaaaaaaaaa");
+        // Server sends data...
+        let mut r = pipe.server.writable();
+        assert_eq!(r.next(), Some(4));
+        assert_eq!(r.next(), None);
+
+        assert_eq!(pipe.server.stream_send(4, b"world", false), Ok(5));
         assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(pipe.client.stream_send(0, b"hhhhhhhhhh!", false), Ok(10));
-        assert_eq!(pipe.client.streams.blocked().len(), 1);
+        // ...and buffers more, and closes stream.
+        assert_eq!(pipe.server.stream_send(4, b"world", true), Ok(5));
 
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
-        assert_eq!(pipe.client.streams.blocked().len(), 0);
+        // Client sends STOP_SENDING before server flushes stream.
+        let frames = [frame::Frame::StopSending {
+            stream_id: 4,
+            error_code: 42,
+        }];
+
+        let pkt_type = packet::Type::Short;
+        let len = pipe
+            .send_pkt_to_server(pkt_type, &frames, &mut buf)
+            .unwrap();
 
+        // Server sent a RESET_STREAM frame in response.
         let frames =
-            testing::decode_pkt(&mut pipe.server, &mut buf[..len]).unwrap();
+            testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
 
         let mut iter = frames.iter();
 
+        // Skip ACK frame.
+        iter.next();
+
         assert_eq!(
             iter.next(),
-            Some(&frame::Frame::StreamDataBlocked {
-                stream_id: 0,
-                limit: 25,
+            Some(&frame::Frame::ResetStream {
+                stream_id: 4,
+                error_code: 42,
+                final_size: 5,
             })
         );
 
-        // don't care about remaining received frames
-
-        assert_eq!(pipe.client.stream_send(0, b"!", false), Err(Error::Done));
-        assert_eq!(pipe.client.streams.blocked().len(), 0);
-        assert_eq!(pipe.client.send(&mut buf), Err(Error::Done));
+        // No more frames are sent by the server.
+        assert_eq!(iter.next(), None);
     }
 
     #[test]
-    fn app_limited_true() {
-        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+    /// Tests that resetting a stream restores flow control for unsent data.
+    fn stop_sending_unsent_tx_cap() {
+        let mut buf = [0; 65535];
+
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
         config
             .set_application_protos(&[b"proto1", b"proto2"])
             .unwrap();
-        config.set_initial_max_data(50000);
-        config.set_initial_max_stream_data_bidi_local(50000);
-        config.set_initial_max_stream_data_bidi_remote(50000);
-        config.set_max_recv_udp_payload_size(1200);
+        config.set_initial_max_data(15);
+        config.set_initial_max_stream_data_bidi_local(30);
+        config.set_initial_max_stream_data_bidi_remote(30);
+        config.set_initial_max_stream_data_uni(30);
+        config.set_initial_max_streams_bidi(3);
+        config.set_initial_max_streams_uni(0);
         config.verify_peer(false);
 
-        let mut pipe = testing::Pipe::with_client_config(&mut config).unwrap();
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        // Client sends stream data.
-        assert_eq!(pipe.client.stream_send(0, b"a", true), Ok(1));
+        // Client sends some data.
+        assert_eq!(pipe.client.stream_send(4, b"hello", true), Ok(5));
         assert_eq!(pipe.advance(), Ok(()));
 
-        // Server reads stream data.
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(4));
+        assert_eq!(r.next(), None);
+
         let mut b = [0; 15];
-        pipe.server.stream_recv(0, &mut b).unwrap();
+        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((5, true)));
+
+        // Server sends some data.
+        assert_eq!(pipe.server.stream_send(4, b"hello", false), Ok(5));
         assert_eq!(pipe.advance(), Ok(()));
 
-        // Server sends stream data smaller than cwnd.
-        let send_buf = [0; 10000];
-        assert_eq!(pipe.server.stream_send(0, &send_buf, false), Ok(10000));
+        // Server buffers some data, until send capacity limit reached.
+        assert_eq!(pipe.server.stream_send(4, b"hello", false), Ok(5));
+        assert_eq!(pipe.server.stream_send(4, b"hello", false), Ok(5));
+        assert_eq!(
+            pipe.server.stream_send(4, b"hello", false),
+            Err(Error::Done)
+        );
+
+        // Client sends STOP_SENDING.
+        let frames = [frame::Frame::StopSending {
+            stream_id: 4,
+            error_code: 42,
+        }];
+
+        let pkt_type = packet::Type::Short;
+        pipe.send_pkt_to_server(pkt_type, &frames, &mut buf)
+            .unwrap();
+
+        // Server can now send more data (on a different stream).
+        assert_eq!(pipe.client.stream_send(8, b"hello", true), Ok(5));
         assert_eq!(pipe.advance(), Ok(()));
 
-        // app_limited should be true because we send less than cwnd.
-        assert!(pipe
-            .server
-            .paths
-            .get_active()
-            .expect("no active")
-            .recovery
-            .app_limited());
+        assert_eq!(pipe.server.stream_send(8, b"hello", false), Ok(5));
+        assert_eq!(pipe.server.stream_send(8, b"hello", false), Ok(5));
+        assert_eq!(
+            pipe.server.stream_send(8, b"hello", false),
+            Err(Error::Done)
+        );
+        assert_eq!(pipe.advance(), Ok(()));
     }
 
     #[test]
-    fn app_limited_false() {
-        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
-        config
-            .set_application_protos(&[b"proto1", b"proto2"])
-            .unwrap();
-        config.set_initial_max_data(50000);
-        config.set_initial_max_stream_data_bidi_local(50000);
-        config.set_initial_max_stream_data_bidi_remote(50000);
-        config.set_max_recv_udp_payload_size(1200);
-        config.verify_peer(false);
+    fn stream_shutdown_read() {
+        let mut buf = [0; 65535];
 
-        let mut pipe = testing::Pipe::with_client_config(&mut config).unwrap();
+        let mut pipe = testing::Pipe::new().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        // Client sends stream data.
-        assert_eq!(pipe.client.stream_send(0, b"a", true), Ok(1));
+        // Client sends some data.
+        assert_eq!(pipe.client.stream_send(4, b"hello, world", false), Ok(12));
         assert_eq!(pipe.advance(), Ok(()));
 
-        // Server reads stream data.
-        let mut b = [0; 15];
-        pipe.server.stream_recv(0, &mut b).unwrap();
-        assert_eq!(pipe.advance(), Ok(()));
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(4));
+        assert_eq!(r.next(), None);
 
-        // Server sends stream data bigger than cwnd.
-        let send_buf1 = [0; 20000];
-        assert_eq!(pipe.server.stream_send(0, &send_buf1, false), Ok(12000));
+        assert_eq!(pipe.client.streams.len(), 1);
+        assert_eq!(pipe.server.streams.len(), 1);
 
-        testing::emit_flight(&mut pipe.server).ok();
+        // Server shuts down stream.
+        assert_eq!(pipe.server.stream_shutdown(4, Shutdown::Read, 42), Ok(()));
 
-        // We can't create a new packet header because there is no room by cwnd.
-        // app_limited should be false because we can't send more by cwnd.
-        assert!(!pipe
-            .server
-            .paths
-            .get_active()
-            .expect("no active")
-            .recovery
-            .app_limited());
-    }
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), None);
 
-    #[test]
-    fn sends_ack_only_pkt_when_full_cwnd_and_ack_elicited() {
-        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
-        config
-            .load_cert_chain_from_pem_file("examples/cert.crt")
-            .unwrap();
-        config
-            .load_priv_key_from_pem_file("examples/cert.key")
-            .unwrap();
-        config
-            .set_application_protos(&[b"proto1", b"proto2"])
-            .unwrap();
-        config.set_initial_max_data(50000);
-        config.set_initial_max_stream_data_bidi_local(50000);
-        config.set_initial_max_stream_data_bidi_remote(50000);
-        config.set_initial_max_streams_bidi(3);
-        config.set_initial_max_streams_uni(3);
-        config.set_max_recv_udp_payload_size(1200);
-        config.verify_peer(false);
+        let (len, _) = pipe.server.send(&mut buf).unwrap();
 
-        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+        let mut dummy = buf[..len].to_vec();
 
-        // Client sends stream data bigger than cwnd (it will never arrive to the
-        // server).
-        let send_buf1 = [0; 20000];
-        assert_eq!(pipe.client.stream_send(0, &send_buf1, false), Ok(12000));
-
-        testing::emit_flight(&mut pipe.client).ok();
+        let frames =
+            testing::decode_pkt(&mut pipe.client, &mut dummy[..len]).unwrap();
+        let mut iter = frames.iter();
 
-        // Server sends some stream data that will need ACKs.
         assert_eq!(
-            pipe.server.stream_send(1, &send_buf1[..500], false),
-            Ok(500)
+            iter.next(),
+            Some(&frame::Frame::StopSending {
+                stream_id: 4,
+                error_code: 42,
+            })
         );
 
-        testing::process_flight(
-            &mut pipe.client,
-            testing::emit_flight(&mut pipe.server).unwrap(),
-        )
-        .unwrap();
+        assert_eq!(pipe.client_recv(&mut buf[..len]), Ok(len));
 
-        let mut buf = [0; 2000];
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let ret = pipe.client.send(&mut buf);
+        // Sending more data is forbidden.
+        let mut r = pipe.client.writable();
+        assert_eq!(r.next(), Some(4));
+        assert_eq!(r.next(), None);
 
-        assert_eq!(pipe.client.tx_cap, 0);
+        assert_eq!(
+            pipe.client.stream_send(4, b"bye", false),
+            Err(Error::StreamStopped(42))
+        );
 
-        assert!(matches!(ret, Ok((_, _))), "the client should at least send one packet to acknowledge the newly received data");
+        // Server sends some data, without reading the incoming data, and closes
+        // the stream.
+        assert_eq!(pipe.server.stream_send(4, b"hello, world", true), Ok(12));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let (sent, _) = ret.unwrap();
+        // Client reads the data.
+        let mut r = pipe.client.readable();
+        assert_eq!(r.next(), Some(4));
+        assert_eq!(r.next(), None);
 
-        assert_ne!(sent, 0, "the client should at least send a pure ACK packet");
+        assert_eq!(pipe.client.stream_recv(4, &mut buf), Ok((12, true)));
 
-        let frames =
-            testing::decode_pkt(&mut pipe.server, &mut buf[..sent]).unwrap();
-        assert_eq!(1, frames.len());
-        assert!(
-            matches!(frames[0], frame::Frame::ACK { .. }),
-            "the packet sent by the client must be an ACK only packet"
+        // Stream is collected on both sides.
+        assert_eq!(pipe.client.streams.len(), 0);
+        assert_eq!(pipe.server.streams.len(), 0);
+
+        assert_eq!(
+            pipe.server.stream_shutdown(4, Shutdown::Read, 0),
+            Err(Error::Done)
         );
     }
 
-    /// Like sends_ack_only_pkt_when_full_cwnd_and_ack_elicited, but when
-    /// ack_eliciting is explicitly requested.
     #[test]
-    fn sends_ack_only_pkt_when_full_cwnd_and_ack_elicited_despite_max_unacknowledging(
-    ) {
-        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
-        config
-            .load_cert_chain_from_pem_file("examples/cert.crt")
-            .unwrap();
-        config
-            .load_priv_key_from_pem_file("examples/cert.key")
-            .unwrap();
-        config
-            .set_application_protos(&[b"proto1", b"proto2"])
-            .unwrap();
-        config.set_initial_max_data(50000);
-        config.set_initial_max_stream_data_bidi_local(50000);
-        config.set_initial_max_stream_data_bidi_remote(50000);
-        config.set_initial_max_streams_bidi(3);
-        config.set_initial_max_streams_uni(3);
-        config.set_max_recv_udp_payload_size(1200);
-        config.verify_peer(false);
+    fn stream_shutdown_read_after_fin() {
+        let mut buf = [0; 65535];
 
-        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        let mut pipe = testing::Pipe::new().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        // Client sends stream data bigger than cwnd (it will never arrive to the
-        // server). This exhausts the congestion window.
-        let send_buf1 = [0; 20000];
-        assert_eq!(pipe.client.stream_send(0, &send_buf1, false), Ok(12000));
+        // Client sends some data.
+        assert_eq!(pipe.client.stream_send(4, b"hello, world", true), Ok(12));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        testing::emit_flight(&mut pipe.client).ok();
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(4));
+        assert_eq!(r.next(), None);
 
-        // Client gets PING frames from server, which elicit ACK
-        let mut buf = [0; 2000];
-        for _ in 0..recovery::MAX_OUTSTANDING_NON_ACK_ELICITING {
-            let written = testing::encode_pkt(
-                &mut pipe.server,
-                packet::Type::Short,
-                &[frame::Frame::Ping],
-                &mut buf,
-            )
-            .unwrap();
+        assert_eq!(pipe.client.streams.len(), 1);
+        assert_eq!(pipe.server.streams.len(), 1);
 
-            pipe.client_recv(&mut buf[..written])
-                .expect("client recv ping");
+        // Server shuts down stream.
+        assert_eq!(pipe.server.stream_shutdown(4, Shutdown::Read, 42), Ok(()));
 
-            // Client acknowledges despite a full congestion window
-            let ret = pipe.client.send(&mut buf);
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), None);
 
-            assert!(matches!(ret, Ok((_, _))), "the client should at least send one packet to acknowledge the newly received data");
+        // Server has nothing to send.
+        assert_eq!(pipe.server.send(&mut buf), Err(Error::Done));
 
-            let (sent, _) = ret.unwrap();
+        assert_eq!(pipe.advance(), Ok(()));
 
-            assert_ne!(
-                sent, 0,
-                "the client should at least send a pure ACK packet"
-            );
+        // Server sends some data, without reading the incoming data, and closes
+        // the stream.
+        assert_eq!(pipe.server.stream_send(4, b"hello, world", true), Ok(12));
+        assert_eq!(pipe.advance(), Ok(()));
 
-            let frames =
-                testing::decode_pkt(&mut pipe.server, &mut buf[..sent]).unwrap();
+        // Client reads the data.
+        let mut r = pipe.client.readable();
+        assert_eq!(r.next(), Some(4));
+        assert_eq!(r.next(), None);
 
-            assert_eq!(1, frames.len());
+        assert_eq!(pipe.client.stream_recv(4, &mut buf), Ok((12, true)));
 
-            assert!(
-                matches!(frames[0], frame::Frame::ACK { .. }),
-                "the packet sent by the client must be an ACK only packet"
-            );
-        }
+        // Stream is collected on both sides.
+        assert_eq!(pipe.client.streams.len(), 0);
+        assert_eq!(pipe.server.streams.len(), 0);
 
-        // The client shouldn't need to send any more packets after the ACK only
-        // packet it just sent.
         assert_eq!(
-            pipe.client.send(&mut buf),
-            Err(Error::Done),
-            "nothing for client to send after ACK-only packet"
+            pipe.server.stream_shutdown(4, Shutdown::Read, 0),
+            Err(Error::Done)
         );
     }
 
     #[test]
-    fn app_limited_false_no_frame() {
-        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+    fn stream_shutdown_read_update_max_data() {
+        let mut buf = [0; 65535];
+
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
         config
             .set_application_protos(&[b"proto1", b"proto2"])
             .unwrap();
-        config.set_initial_max_data(50000);
-        config.set_initial_max_stream_data_bidi_local(50000);
-        config.set_initial_max_stream_data_bidi_remote(50000);
-        config.set_max_recv_udp_payload_size(1405);
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(10000);
+        config.set_initial_max_stream_data_bidi_remote(10000);
+        config.set_initial_max_streams_bidi(10);
         config.verify_peer(false);
 
-        let mut pipe = testing::Pipe::with_client_config(&mut config).unwrap();
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        // Client sends stream data.
-        assert_eq!(pipe.client.stream_send(0, b"a", true), Ok(1));
+        assert_eq!(pipe.client.stream_send(0, b"a", false), Ok(1));
         assert_eq!(pipe.advance(), Ok(()));
 
-        // Server reads stream data.
-        let mut b = [0; 15];
-        pipe.server.stream_recv(0, &mut b).unwrap();
-        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(pipe.server.stream_recv(0, &mut buf), Ok((1, false)));
+        assert_eq!(pipe.server.stream_shutdown(0, Shutdown::Read, 123), Ok(()));
 
-        // Server sends stream data bigger than cwnd.
-        let send_buf1 = [0; 20000];
-        assert_eq!(pipe.server.stream_send(0, &send_buf1, false), Ok(12000));
+        assert_eq!(pipe.server.rx_data, 1);
+        assert_eq!(pipe.client.tx_data, 1);
+        assert_eq!(pipe.client.max_tx_data, 30);
 
-        testing::emit_flight(&mut pipe.server).ok();
+        assert_eq!(
+            pipe.client
+                .stream_send(0, &buf[..pipe.client.tx_cap], false),
+            Ok(29)
+        );
+        assert_eq!(pipe.advance(), Ok(()));
 
-        // We can't create a new packet header because there is no room by cwnd.
-        // app_limited should be false because we can't send more by cwnd.
-        assert!(!pipe
-            .server
-            .paths
-            .get_active()
-            .expect("no active")
-            .recovery
-            .app_limited());
+        assert!(!pipe.server.stream_readable(0)); // nothing can be consumed
+
+        // The client has increased its tx_data, and server has received it, so
+        // it increases flow control accordingly.
+        assert_eq!(pipe.client.tx_data, 30);
+        assert_eq!(pipe.server.rx_data, 30);
+        assert_eq!(pipe.client.tx_cap, 45);
     }
 
     #[test]
-    fn app_limited_false_no_header() {
-        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
-        config
-            .set_application_protos(&[b"proto1", b"proto2"])
-            .unwrap();
-        config.set_initial_max_data(50000);
-        config.set_initial_max_stream_data_bidi_local(50000);
-        config.set_initial_max_stream_data_bidi_remote(50000);
-        config.set_max_recv_udp_payload_size(1406);
-        config.verify_peer(false);
-
-        let mut pipe = testing::Pipe::with_client_config(&mut config).unwrap();
+    fn stream_shutdown_uni() {
+        let mut pipe = testing::Pipe::new().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        // Client sends stream data.
-        assert_eq!(pipe.client.stream_send(0, b"a", true), Ok(1));
+        // Exchange some data on uni streams.
+        assert_eq!(pipe.client.stream_send(2, b"hello, world", false), Ok(10));
+        assert_eq!(pipe.server.stream_send(3, b"hello, world", false), Ok(10));
         assert_eq!(pipe.advance(), Ok(()));
 
-        // Server reads stream data.
-        let mut b = [0; 15];
-        pipe.server.stream_recv(0, &mut b).unwrap();
-        assert_eq!(pipe.advance(), Ok(()));
+        // Test local and remote shutdown.
+        assert_eq!(pipe.client.stream_shutdown(2, Shutdown::Write, 42), Ok(()));
+        assert_eq!(
+            pipe.client.stream_shutdown(2, Shutdown::Read, 42),
+            Err(Error::InvalidStreamState(2))
+        );
 
-        // Server sends stream data bigger than cwnd.
-        let send_buf1 = [0; 20000];
-        assert_eq!(pipe.server.stream_send(0, &send_buf1, false), Ok(12000));
+        assert_eq!(
+            pipe.client.stream_shutdown(3, Shutdown::Write, 42),
+            Err(Error::InvalidStreamState(3))
+        );
+        assert_eq!(pipe.client.stream_shutdown(3, Shutdown::Read, 42), Ok(()));
+    }
 
-        testing::emit_flight(&mut pipe.server).ok();
+    #[test]
+    fn stream_shutdown_write() {
+        let mut buf = [0; 65535];
 
-        // We can't create a new frame because there is no room by cwnd.
-        // app_limited should be false because we can't send more by cwnd.
-        assert!(!pipe
-            .server
-            .paths
-            .get_active()
-            .expect("no active")
-            .recovery
-            .app_limited());
-    }
-
-    #[test]
-    fn app_limited_not_changed_on_no_new_frames() {
-        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
-        config
-            .set_application_protos(&[b"proto1", b"proto2"])
-            .unwrap();
-        config.set_initial_max_data(50000);
-        config.set_initial_max_stream_data_bidi_local(50000);
-        config.set_initial_max_stream_data_bidi_remote(50000);
-        config.set_max_recv_udp_payload_size(1200);
-        config.verify_peer(false);
-
-        let mut pipe = testing::Pipe::with_client_config(&mut config).unwrap();
+        let mut pipe = testing::Pipe::new().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        // Client sends stream data.
-        assert_eq!(pipe.client.stream_send(0, b"a", true), Ok(1));
+        // Client sends some data.
+        assert_eq!(pipe.client.stream_send(4, b"hello, world", false), Ok(12));
         assert_eq!(pipe.advance(), Ok(()));
 
-        // Server reads stream data.
-        let mut b = [0; 15];
-        pipe.server.stream_recv(0, &mut b).unwrap();
-        assert_eq!(pipe.advance(), Ok(()));
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(4));
+        assert_eq!(r.next(), None);
 
-        // Client's app_limited is true because its bytes-in-flight
-        // is much smaller than the current cwnd.
-        assert!(pipe
-            .client
-            .paths
-            .get_active()
-            .expect("no active")
-            .recovery
-            .app_limited());
+        let mut r = pipe.server.writable();
+        assert_eq!(r.next(), Some(4));
+        assert_eq!(r.next(), None);
 
-        // Client has no new frames to send - returns Done.
-        assert_eq!(testing::emit_flight(&mut pipe.client), Err(Error::Done));
+        assert_eq!(pipe.client.streams.len(), 1);
+        assert_eq!(pipe.server.streams.len(), 1);
 
-        // Client's app_limited should remain the same.
-        assert!(pipe
-            .client
-            .paths
-            .get_active()
-            .expect("no active")
-            .recovery
-            .app_limited());
-    }
+        // Server sends some data.
+        assert_eq!(pipe.server.stream_send(4, b"goodbye, world", false), Ok(14));
+        assert_eq!(pipe.advance(), Ok(()));
 
-    #[test]
-    fn limit_ack_ranges() {
-        let mut buf = [0; 65535];
+        // Server shuts down stream.
+        assert_eq!(pipe.server.stream_shutdown(4, Shutdown::Write, 42), Ok(()));
 
-        let mut pipe = testing::Pipe::new().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+        let mut r = pipe.server.writable();
+        assert_eq!(r.next(), None);
 
-        let epoch = packet::Epoch::Application;
+        let (len, _) = pipe.server.send(&mut buf).unwrap();
 
-        assert_eq!(pipe.server.pkt_num_spaces[epoch].recv_pkt_need_ack.len(), 0);
+        let mut dummy = buf[..len].to_vec();
 
-        let frames = [frame::Frame::Ping, frame::Frame::Padding { len: 3 }];
+        let frames =
+            testing::decode_pkt(&mut pipe.client, &mut dummy[..len]).unwrap();
+        let mut iter = frames.iter();
 
-        let pkt_type = packet::Type::Short;
+        assert_eq!(
+            iter.next(),
+            Some(&frame::Frame::ResetStream {
+                stream_id: 4,
+                error_code: 42,
+                final_size: 14,
+            })
+        );
 
-        let mut last_packet_sent = 0;
+        assert_eq!(pipe.client_recv(&mut buf[..len]), Ok(len));
 
-        for _ in 0..512 {
-            let recv_count = pipe.server.recv_count;
+        assert_eq!(pipe.advance(), Ok(()));
 
-            last_packet_sent = pipe.client.pkt_num_spaces[epoch].next_pkt_num;
+        // Sending more data is forbidden.
+        assert_eq!(
+            pipe.server.stream_send(4, b"bye", false),
+            Err(Error::FinalSize)
+        );
 
-            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf)
-                .unwrap();
+        // Client sends some data and closes the stream.
+        assert_eq!(pipe.client.stream_send(4, b"bye", true), Ok(3));
+        assert_eq!(pipe.advance(), Ok(()));
 
-            assert_eq!(pipe.server.recv_count, recv_count + 1);
+        // Server reads the data.
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(4));
+        assert_eq!(r.next(), None);
 
-            // Skip packet number.
-            pipe.client.pkt_num_spaces[epoch].next_pkt_num += 1;
-        }
+        assert_eq!(pipe.server.stream_recv(4, &mut buf), Ok((15, true)));
 
-        assert_eq!(
-            pipe.server.pkt_num_spaces[epoch].recv_pkt_need_ack.len(),
-            MAX_ACK_RANGES
-        );
+        // Client processes readable streams.
+        let mut r = pipe.client.readable();
+        assert_eq!(r.next(), Some(4));
+        assert_eq!(r.next(), None);
 
         assert_eq!(
-            pipe.server.pkt_num_spaces[epoch].recv_pkt_need_ack.first(),
-            Some(last_packet_sent - ((MAX_ACK_RANGES as u64) - 1) * 2)
+            pipe.client.stream_recv(4, &mut buf),
+            Err(Error::StreamReset(42))
         );
 
+        // Stream is collected on both sides.
+        assert_eq!(pipe.client.streams.len(), 0);
+        assert_eq!(pipe.server.streams.len(), 0);
+
         assert_eq!(
-            pipe.server.pkt_num_spaces[epoch].recv_pkt_need_ack.last(),
-            Some(last_packet_sent)
+            pipe.server.stream_shutdown(4, Shutdown::Write, 0),
+            Err(Error::Done)
         );
     }
 
     #[test]
-    /// Tests that streams are correctly scheduled based on their priority.
-    fn stream_priority() {
-        // Limit 1-RTT packet size to avoid congestion control interference.
-        const MAX_TEST_PACKET_SIZE: usize = 540;
-
+    fn stream_reset_at() {
         let mut buf = [0; 65535];
 
         let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
@@ -12909,219 +19618,166 @@ mod tests {
         config
             .set_application_protos(&[b"proto1", b"proto2"])
             .unwrap();
-        config.set_initial_max_data(1_000_000);
-        config.set_initial_max_stream_data_bidi_local(1_000_000);
-        config.set_initial_max_stream_data_bidi_remote(1_000_000);
-        config.set_initial_max_stream_data_uni(0);
-        config.set_initial_max_streams_bidi(100);
-        config.set_initial_max_streams_uni(0);
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_streams_bidi(3);
+        config.enable_reset_stream_at(true);
         config.verify_peer(false);
 
         let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        assert_eq!(pipe.client.stream_send(0, b"a", false), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(pipe.client.stream_send(0, b"hello, world", false), Ok(12));
+        assert_eq!(pipe.client.stream_reset_at(0, 42, 5), Ok(()));
 
-        assert_eq!(pipe.client.stream_send(4, b"a", false), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
+        // Unsent data past the reliable size doesn't count against flow
+        // control anymore.
+        assert_eq!(pipe.client.tx_data, 5);
 
-        assert_eq!(pipe.client.stream_send(8, b"a", false), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(pipe.client.writable().next(), None);
+        assert_eq!(pipe.client.stream_reset_at(0, 42, 5), Err(Error::Done));
 
-        assert_eq!(pipe.client.stream_send(12, b"a", false), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
 
-        assert_eq!(pipe.client.stream_send(16, b"a", false), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
+        let mut dummy = buf[..len].to_vec();
 
-        assert_eq!(pipe.client.stream_send(20, b"a", false), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
+        let frames =
+            testing::decode_pkt(&mut pipe.server, &mut dummy[..len]).unwrap();
 
-        let mut b = [0; 1];
+        assert!(frames.contains(&frame::Frame::ResetStreamAt {
+            stream_id: 0,
+            error_code: 42,
+            final_size: 5,
+            reliable_size: 5,
+        }));
 
-        let out = [b'b'; 500];
+        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
 
-        // Server prioritizes streams as follows:
-        //  * Stream 8 and 16 have the same priority but are non-incremental.
-        //  * Stream 4, 12 and 20 have the same priority but 20 is non-incremental
-        //    and 4 and 12 are incremental.
-        //  * Stream 0 is on its own.
+        // The server reads the reliable data before the error.
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(0));
+        assert_eq!(r.next(), None);
 
-        pipe.server.stream_recv(0, &mut b).unwrap();
-        assert_eq!(pipe.server.stream_priority(0, 255, true), Ok(()));
-        pipe.server.stream_send(0, &out, false).unwrap();
-        pipe.server.stream_send(0, &out, false).unwrap();
-        pipe.server.stream_send(0, &out, false).unwrap();
+        assert_eq!(pipe.server.stream_recv(0, &mut buf), Ok((5, false)));
+        assert_eq!(&buf[..5], b"hello");
 
-        pipe.server.stream_recv(12, &mut b).unwrap();
-        assert_eq!(pipe.server.stream_priority(12, 42, true), Ok(()));
-        pipe.server.stream_send(12, &out, false).unwrap();
-        pipe.server.stream_send(12, &out, false).unwrap();
-        pipe.server.stream_send(12, &out, false).unwrap();
+        assert_eq!(
+            pipe.server.stream_recv(0, &mut buf),
+            Err(Error::StreamReset(42))
+        );
 
-        pipe.server.stream_recv(16, &mut b).unwrap();
-        assert_eq!(pipe.server.stream_priority(16, 10, false), Ok(()));
-        pipe.server.stream_send(16, &out, false).unwrap();
-        pipe.server.stream_send(16, &out, false).unwrap();
-        pipe.server.stream_send(16, &out, false).unwrap();
+        assert_eq!(pipe.advance(), Ok(()));
+    }
 
-        pipe.server.stream_recv(4, &mut b).unwrap();
-        assert_eq!(pipe.server.stream_priority(4, 42, true), Ok(()));
-        pipe.server.stream_send(4, &out, false).unwrap();
-        pipe.server.stream_send(4, &out, false).unwrap();
-        pipe.server.stream_send(4, &out, false).unwrap();
+    #[test]
+    fn stream_reset_at_unsupported() {
+        let mut buf = [0; 65535];
 
-        pipe.server.stream_recv(8, &mut b).unwrap();
-        assert_eq!(pipe.server.stream_priority(8, 10, false), Ok(()));
-        pipe.server.stream_send(8, &out, false).unwrap();
-        pipe.server.stream_send(8, &out, false).unwrap();
-        pipe.server.stream_send(8, &out, false).unwrap();
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        pipe.server.stream_recv(20, &mut b).unwrap();
-        assert_eq!(pipe.server.stream_priority(20, 42, false), Ok(()));
-        pipe.server.stream_send(20, &out, false).unwrap();
-        pipe.server.stream_send(20, &out, false).unwrap();
-        pipe.server.stream_send(20, &out, false).unwrap();
+        assert_eq!(pipe.client.stream_send(0, b"hello, world", false), Ok(12));
 
-        // First is stream 8.
-        let mut off = 0;
+        assert_eq!(
+            pipe.client.stream_reset_at(0, 42, 5),
+            Err(Error::InvalidState)
+        );
 
-        for _ in 1..=3 {
-            let (len, _) =
-                pipe.server.send(&mut buf[..MAX_TEST_PACKET_SIZE]).unwrap();
+        // Without reliable data this is the same as RESET_STREAM.
+        assert_eq!(pipe.client.stream_reset_at(0, 42, 0), Ok(()));
+        assert_eq!(pipe.client.tx_data, 0);
 
-            let frames =
-                testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
-            let stream = frames.first().unwrap();
+        assert_eq!(pipe.advance(), Ok(()));
 
-            assert_eq!(stream, &frame::Frame::Stream {
-                stream_id: 8,
-                data: stream::RangeBuf::from(&out, off, false),
-            });
+        assert_eq!(
+            pipe.server.stream_recv(0, &mut buf),
+            Err(Error::StreamReset(42))
+        );
 
-            off = match stream {
-                frame::Frame::Stream { data, .. } => data.max_off(),
+        // RESET_STREAM_AT is rejected if support wasn't advertised.
+        let frames = [frame::Frame::ResetStreamAt {
+            stream_id: 4,
+            error_code: 42,
+            final_size: 5,
+            reliable_size: 5,
+        }];
 
-                _ => unreachable!(),
-            };
-        }
+        let pkt_type = packet::Type::Short;
+        assert_eq!(
+            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
+            Err(Error::InvalidFrame)
+        );
+    }
 
-        // Then is stream 16.
-        let mut off = 0;
+    #[test]
+    fn stream_deadline() {
+        let mut buf = [0; 65535];
 
-        for _ in 1..=3 {
-            let (len, _) =
-                pipe.server.send(&mut buf[..MAX_TEST_PACKET_SIZE]).unwrap();
-
-            let frames =
-                testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
-            let stream = frames.first().unwrap();
-
-            assert_eq!(stream, &frame::Frame::Stream {
-                stream_id: 16,
-                data: stream::RangeBuf::from(&out, off, false),
-            });
-
-            off = match stream {
-                frame::Frame::Stream { data, .. } => data.max_off(),
-
-                _ => unreachable!(),
-            };
-        }
-
-        // Then is stream 20.
-        let mut off = 0;
-
-        for _ in 1..=3 {
-            let (len, _) =
-                pipe.server.send(&mut buf[..MAX_TEST_PACKET_SIZE]).unwrap();
-
-            let frames =
-                testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
-            let stream = frames.first().unwrap();
-
-            assert_eq!(stream, &frame::Frame::Stream {
-                stream_id: 20,
-                data: stream::RangeBuf::from(&out, off, false),
-            });
-
-            off = match stream {
-                frame::Frame::Stream { data, .. } => data.max_off(),
-
-                _ => unreachable!(),
-            };
-        }
-
-        // Then are stream 12 and 4, with the same priority, incrementally.
-        let mut off = 0;
-
-        for _ in 1..=3 {
-            let (len, _) =
-                pipe.server.send(&mut buf[..MAX_TEST_PACKET_SIZE]).unwrap();
-
-            let frames =
-                testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
-
-            assert_eq!(
-                frames.first(),
-                Some(&frame::Frame::Stream {
-                    stream_id: 12,
-                    data: stream::RangeBuf::from(&out, off, false),
-                })
-            );
-
-            let (len, _) =
-                pipe.server.send(&mut buf[..MAX_TEST_PACKET_SIZE]).unwrap();
-
-            let frames =
-                testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
-
-            let stream = frames.first().unwrap();
-
-            assert_eq!(stream, &frame::Frame::Stream {
-                stream_id: 4,
-                data: stream::RangeBuf::from(&out, off, false),
-            });
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_streams_bidi(3);
+        config.enable_reset_stream_at(true);
+        config.verify_peer(false);
 
-            off = match stream {
-                frame::Frame::Stream { data, .. } => data.max_off(),
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-                _ => unreachable!(),
-            };
-        }
+        assert_eq!(pipe.client.stream_send(0, b"hello, world", false), Ok(12));
 
-        // Final is stream 0.
-        let mut off = 0;
+        assert_eq!(
+            pipe.client
+                .stream_set_deadline(4, time::Instant::now(), 3, 7),
+            Err(Error::InvalidStreamState(4))
+        );
+        assert_eq!(
+            pipe.client
+                .stream_set_deadline(0, time::Instant::now(), 3, 7),
+            Ok(())
+        );
 
-        for _ in 1..=3 {
-            let (len, _) =
-                pipe.server.send(&mut buf[..MAX_TEST_PACKET_SIZE]).unwrap();
+        assert_eq!(
+            pipe.client.next_timeout_reason(),
+            Some(TimeoutReason::StreamDeadline)
+        );
 
-            let frames =
-                testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
-            let stream = frames.first().unwrap();
+        pipe.client.on_timeout();
 
-            assert_eq!(stream, &frame::Frame::Stream {
-                stream_id: 0,
-                data: stream::RangeBuf::from(&out, off, false),
-            });
+        assert_ne!(
+            pipe.client.next_timeout_reason(),
+            Some(TimeoutReason::StreamDeadline)
+        );
+        assert_eq!(
+            pipe.client
+                .stream_set_deadline(0, time::Instant::now(), 3, 7),
+            Err(Error::Done)
+        );
 
-            off = match stream {
-                frame::Frame::Stream { data, .. } => data.max_off(),
+        assert_eq!(pipe.advance(), Ok(()));
 
-                _ => unreachable!(),
-            };
-        }
+        assert_eq!(pipe.server.stream_recv(0, &mut buf), Ok((3, false)));
+        assert_eq!(&buf[..3], b"hel");
 
-        assert_eq!(pipe.server.send(&mut buf), Err(Error::Done));
+        assert_eq!(
+            pipe.server.stream_recv(0, &mut buf),
+            Err(Error::StreamReset(7))
+        );
     }
 
     #[test]
-    /// Tests that changing a stream's priority is correctly propagated.
-    fn stream_reprioritize() {
-        let mut buf = [0; 65535];
-
+    /// Tests that shutting down a stream restores flow control for unsent data.
+    fn stream_shutdown_write_unsent_tx_cap() {
         let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
         config
             .load_cert_chain_from_pem_file("examples/cert.crt")
@@ -13132,536 +19788,596 @@ mod tests {
         config
             .set_application_protos(&[b"proto1", b"proto2"])
             .unwrap();
-        config.set_initial_max_data(30);
-        config.set_initial_max_stream_data_bidi_local(15);
-        config.set_initial_max_stream_data_bidi_remote(15);
-        config.set_initial_max_stream_data_uni(0);
-        config.set_initial_max_streams_bidi(5);
+        config.set_initial_max_data(15);
+        config.set_initial_max_stream_data_bidi_local(30);
+        config.set_initial_max_stream_data_bidi_remote(30);
+        config.set_initial_max_stream_data_uni(30);
+        config.set_initial_max_streams_bidi(3);
         config.set_initial_max_streams_uni(0);
         config.verify_peer(false);
 
         let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        assert_eq!(pipe.client.stream_send(0, b"a", false), Ok(1));
+        // Client sends some data.
+        assert_eq!(pipe.client.stream_send(4, b"hello", true), Ok(5));
         assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(pipe.client.stream_send(4, b"a", false), Ok(1));
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(4));
+        assert_eq!(r.next(), None);
+
+        let mut b = [0; 15];
+        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((5, true)));
+
+        // Server sends some data.
+        assert_eq!(pipe.server.stream_send(4, b"hello", false), Ok(5));
         assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(pipe.client.stream_send(8, b"a", false), Ok(1));
+        // Server buffers some data, until send capacity limit reached.
+        assert_eq!(pipe.server.stream_send(4, b"hello", false), Ok(5));
+        assert_eq!(pipe.server.stream_send(4, b"hello", false), Ok(5));
+        assert_eq!(
+            pipe.server.stream_send(4, b"hello", false),
+            Err(Error::Done)
+        );
+
+        // Client shouldn't update flow control.
+        assert!(!pipe.client.should_update_max_data());
+
+        // Server shuts down stream.
+        assert_eq!(pipe.server.stream_shutdown(4, Shutdown::Write, 42), Ok(()));
         assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(pipe.client.stream_send(12, b"a", false), Ok(1));
+        // Server can now send more data (on a different stream).
+        assert_eq!(pipe.client.stream_send(8, b"hello", true), Ok(5));
         assert_eq!(pipe.advance(), Ok(()));
 
-        let mut b = [0; 1];
+        assert_eq!(pipe.server.stream_send(8, b"hello", false), Ok(5));
+        assert_eq!(pipe.server.stream_send(8, b"hello", false), Ok(5));
+        assert_eq!(
+            pipe.server.stream_send(8, b"hello", false),
+            Err(Error::Done)
+        );
+        assert_eq!(pipe.advance(), Ok(()));
+    }
 
-        pipe.server.stream_recv(0, &mut b).unwrap();
-        assert_eq!(pipe.server.stream_priority(0, 255, true), Ok(()));
-        pipe.server.stream_send(0, b"b", false).unwrap();
+    #[test]
+    /// Tests that the order of flushable streams scheduled on the wire is the
+    /// same as the order of `stream_send()` calls done by the application.
+    fn stream_round_robin() {
+        let mut buf = [0; 65535];
 
-        pipe.server.stream_recv(12, &mut b).unwrap();
-        assert_eq!(pipe.server.stream_priority(12, 42, true), Ok(()));
-        pipe.server.stream_send(12, b"b", false).unwrap();
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        pipe.server.stream_recv(8, &mut b).unwrap();
-        assert_eq!(pipe.server.stream_priority(8, 10, true), Ok(()));
-        pipe.server.stream_send(8, b"b", false).unwrap();
+        assert_eq!(pipe.client.stream_send(8, b"aaaaa", false), Ok(5));
+        assert_eq!(pipe.client.stream_send(0, b"aaaaa", false), Ok(5));
+        assert_eq!(pipe.client.stream_send(4, b"aaaaa", false), Ok(5));
 
-        pipe.server.stream_recv(4, &mut b).unwrap();
-        assert_eq!(pipe.server.stream_priority(4, 42, true), Ok(()));
-        pipe.server.stream_send(4, b"b", false).unwrap();
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
 
-        // Stream 0 is re-prioritized!!!
-        assert_eq!(pipe.server.stream_priority(0, 20, true), Ok(()));
+        let frames =
+            testing::decode_pkt(&mut pipe.server, &mut buf[..len]).unwrap();
 
-        // First is stream 8.
-        let (len, _) = pipe.server.send(&mut buf).unwrap();
+        let mut iter = frames.iter();
 
-        let frames =
-            testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
+        // Skip ACK frame.
+        iter.next();
 
         assert_eq!(
-            frames.first(),
+            iter.next(),
             Some(&frame::Frame::Stream {
                 stream_id: 8,
-                data: stream::RangeBuf::from(b"b", 0, false),
+                data: stream::RangeBuf::from(b"aaaaa", 0, false),
             })
         );
 
-        // Then is stream 0.
-        let (len, _) = pipe.server.send(&mut buf).unwrap();
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
 
         let frames =
-            testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
+            testing::decode_pkt(&mut pipe.server, &mut buf[..len]).unwrap();
 
         assert_eq!(
             frames.first(),
             Some(&frame::Frame::Stream {
                 stream_id: 0,
-                data: stream::RangeBuf::from(b"b", 0, false),
-            })
-        );
-
-        // Then are stream 12 and 4, with the same priority.
-        let (len, _) = pipe.server.send(&mut buf).unwrap();
-
-        let frames =
-            testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
-
-        assert_eq!(
-            frames.first(),
-            Some(&frame::Frame::Stream {
-                stream_id: 12,
-                data: stream::RangeBuf::from(b"b", 0, false),
+                data: stream::RangeBuf::from(b"aaaaa", 0, false),
             })
         );
 
-        let (len, _) = pipe.server.send(&mut buf).unwrap();
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
 
         let frames =
-            testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
+            testing::decode_pkt(&mut pipe.server, &mut buf[..len]).unwrap();
 
         assert_eq!(
             frames.first(),
             Some(&frame::Frame::Stream {
                 stream_id: 4,
-                data: stream::RangeBuf::from(b"b", 0, false),
+                data: stream::RangeBuf::from(b"aaaaa", 0, false),
             })
         );
-
-        assert_eq!(pipe.server.send(&mut buf), Err(Error::Done));
     }
 
     #[test]
-    /// Tests that streams and datagrams are correctly scheduled.
-    fn stream_datagram_priority() {
-        // Limit 1-RTT packet size to avoid congestion control interference.
-        const MAX_TEST_PACKET_SIZE: usize = 540;
+    /// Tests the readable iterator.
+    fn stream_readable() {
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        let mut buf = [0; 65535];
+        // No readable streams.
+        let mut r = pipe.client.readable();
+        assert_eq!(r.next(), None);
 
-        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
-        config
-            .load_cert_chain_from_pem_file("examples/cert.crt")
-            .unwrap();
-        config
-            .load_priv_key_from_pem_file("examples/cert.key")
-            .unwrap();
-        config
-            .set_application_protos(&[b"proto1", b"proto2"])
-            .unwrap();
-        config.set_initial_max_data(1_000_000);
-        config.set_initial_max_stream_data_bidi_local(1_000_000);
-        config.set_initial_max_stream_data_bidi_remote(1_000_000);
-        config.set_initial_max_stream_data_uni(0);
-        config.set_initial_max_streams_bidi(100);
-        config.set_initial_max_streams_uni(0);
-        config.enable_dgram(true, 10, 10);
-        config.verify_peer(false);
+        assert_eq!(pipe.client.stream_send(0, b"aaaaa", false), Ok(5));
 
-        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+        let mut r = pipe.client.readable();
+        assert_eq!(r.next(), None);
+
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), None);
 
-        assert_eq!(pipe.client.stream_send(0, b"a", false), Ok(1));
         assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(pipe.client.stream_send(4, b"a", false), Ok(1));
+        // Server received stream.
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(0));
+        assert_eq!(r.next(), None);
+
+        assert_eq!(
+            pipe.server.stream_send(0, b"aaaaaaaaaaaaaaa", false),
+            Ok(15)
+        );
         assert_eq!(pipe.advance(), Ok(()));
 
-        let mut b = [0; 1];
+        let mut r = pipe.client.readable();
+        assert_eq!(r.next(), Some(0));
+        assert_eq!(r.next(), None);
 
-        let out = [b'b'; 500];
+        // Client drains stream.
+        let mut b = [0; 15];
+        pipe.client.stream_recv(0, &mut b).unwrap();
+        assert_eq!(pipe.advance(), Ok(()));
 
-        // Server prioritizes Stream 0 and 4 with the same urgency with
-        // incremental, meaning the frames should be sent in round-robin
-        // fashion. It also sends DATAGRAMS which are always interleaved with
-        // STREAM frames. So we'll expect a mix of frame types regardless
-        // of the order that the application writes things in.
+        let mut r = pipe.client.readable();
+        assert_eq!(r.next(), None);
 
-        pipe.server.stream_recv(0, &mut b).unwrap();
-        assert_eq!(pipe.server.stream_priority(0, 255, true), Ok(()));
-        pipe.server.stream_send(0, &out, false).unwrap();
-        pipe.server.stream_send(0, &out, false).unwrap();
-        pipe.server.stream_send(0, &out, false).unwrap();
+        // Server shuts down stream.
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(0));
+        assert_eq!(r.next(), None);
 
-        assert_eq!(pipe.server.stream_priority(4, 255, true), Ok(()));
-        pipe.server.stream_send(4, &out, false).unwrap();
-        pipe.server.stream_send(4, &out, false).unwrap();
-        pipe.server.stream_send(4, &out, false).unwrap();
+        assert_eq!(pipe.server.stream_shutdown(0, Shutdown::Read, 0), Ok(()));
 
-        for _ in 1..=6 {
-            assert_eq!(pipe.server.dgram_send(&out), Ok(()));
-        }
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), None);
 
-        let mut off_0 = 0;
-        let mut off_4 = 0;
+        // Client creates multiple streams.
+        assert_eq!(pipe.client.stream_send(4, b"aaaaa", false), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        for _ in 1..=3 {
-            // DATAGRAM
-            let (len, _) =
-                pipe.server.send(&mut buf[..MAX_TEST_PACKET_SIZE]).unwrap();
+        assert_eq!(pipe.client.stream_send(8, b"aaaaa", false), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
 
-            let frames =
-                testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
-            let mut frame_iter = frames.iter();
+        let mut r = pipe.server.readable();
+        assert_eq!(r.len(), 2);
 
-            assert_eq!(frame_iter.next().unwrap(), &frame::Frame::Datagram {
-                data: out.into(),
-            });
-            assert_eq!(frame_iter.next(), None);
+        assert!(r.next().is_some());
+        assert!(r.next().is_some());
+        assert!(r.next().is_none());
 
-            // STREAM 0
-            let (len, _) =
-                pipe.server.send(&mut buf[..MAX_TEST_PACKET_SIZE]).unwrap();
+        assert_eq!(r.len(), 0);
+    }
 
-            let frames =
-                testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
-            let mut frame_iter = frames.iter();
-            let stream = frame_iter.next().unwrap();
+    #[test]
+    /// Tests the writable iterator.
+    fn stream_writable() {
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-            assert_eq!(stream, &frame::Frame::Stream {
-                stream_id: 0,
-                data: stream::RangeBuf::from(&out, off_0, false),
-            });
+        // No writable streams.
+        let mut w = pipe.client.writable();
+        assert_eq!(w.next(), None);
 
-            off_0 = match stream {
-                frame::Frame::Stream { data, .. } => data.max_off(),
+        assert_eq!(pipe.client.stream_send(0, b"aaaaa", false), Ok(5));
 
-                _ => unreachable!(),
-            };
-            assert_eq!(frame_iter.next(), None);
+        // Client created stream.
+        let mut w = pipe.client.writable();
+        assert_eq!(w.next(), Some(0));
+        assert_eq!(w.next(), None);
 
-            // DATAGRAM
-            let (len, _) =
-                pipe.server.send(&mut buf[..MAX_TEST_PACKET_SIZE]).unwrap();
+        assert_eq!(pipe.advance(), Ok(()));
 
-            let frames =
-                testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
-            let mut frame_iter = frames.iter();
+        // Server created stream.
+        let mut w = pipe.server.writable();
+        assert_eq!(w.next(), Some(0));
+        assert_eq!(w.next(), None);
 
-            assert_eq!(frame_iter.next().unwrap(), &frame::Frame::Datagram {
-                data: out.into(),
-            });
-            assert_eq!(frame_iter.next(), None);
+        assert_eq!(
+            pipe.server.stream_send(0, b"aaaaaaaaaaaaaaa", false),
+            Ok(15)
+        );
 
-            // STREAM 4
-            let (len, _) =
-                pipe.server.send(&mut buf[..MAX_TEST_PACKET_SIZE]).unwrap();
+        // Server stream is full.
+        let mut w = pipe.server.writable();
+        assert_eq!(w.next(), None);
 
-            let frames =
-                testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
-            let mut frame_iter = frames.iter();
-            let stream = frame_iter.next().unwrap();
+        assert_eq!(pipe.advance(), Ok(()));
 
-            assert_eq!(stream, &frame::Frame::Stream {
-                stream_id: 4,
-                data: stream::RangeBuf::from(&out, off_4, false),
-            });
+        // Client drains stream.
+        let mut b = [0; 15];
+        pipe.client.stream_recv(0, &mut b).unwrap();
+        assert_eq!(pipe.advance(), Ok(()));
 
-            off_4 = match stream {
-                frame::Frame::Stream { data, .. } => data.max_off(),
+        // Server stream is writable again.
+        let mut w = pipe.server.writable();
+        assert_eq!(w.next(), Some(0));
+        assert_eq!(w.next(), None);
 
-                _ => unreachable!(),
-            };
-            assert_eq!(frame_iter.next(), None);
-        }
-    }
+        // Server shuts down stream.
+        assert_eq!(pipe.server.stream_shutdown(0, Shutdown::Write, 0), Ok(()));
 
-    #[test]
-    /// Tests that old data is retransmitted on PTO.
-    fn early_retransmit() {
-        let mut buf = [0; 65535];
+        let mut w = pipe.server.writable();
+        assert_eq!(w.next(), None);
 
-        let mut pipe = testing::Pipe::new().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+        // Client creates multiple streams.
+        assert_eq!(pipe.client.stream_send(4, b"aaaaa", false), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        // Client sends stream data.
-        assert_eq!(pipe.client.stream_send(0, b"a", false), Ok(1));
+        assert_eq!(pipe.client.stream_send(8, b"aaaaa", false), Ok(5));
         assert_eq!(pipe.advance(), Ok(()));
 
-        // Client sends more stream data, but packet is lost
-        assert_eq!(pipe.client.stream_send(4, b"b", false), Ok(1));
-        assert!(pipe.client.send(&mut buf).is_ok());
+        let mut w = pipe.server.writable();
+        assert_eq!(w.len(), 2);
 
-        // Wait until PTO expires. Since the RTT is very low, wait a bit more.
-        let timer = pipe.client.timeout().unwrap();
-        std::thread::sleep(timer + time::Duration::from_millis(1));
+        assert!(w.next().is_some());
+        assert!(w.next().is_some());
+        assert!(w.next().is_none());
 
-        pipe.client.on_timeout();
+        assert_eq!(w.len(), 0);
 
-        let epoch = packet::Epoch::Application;
-        assert_eq!(
-            pipe.client
-                .paths
-                .get_active()
-                .expect("no active")
-                .recovery
-                .loss_probes[epoch],
-            1,
-        );
+        // Server finishes stream.
+        assert_eq!(pipe.server.stream_send(8, b"aaaaa", true), Ok(5));
+
+        let mut w = pipe.server.writable();
+        assert_eq!(w.next(), Some(4));
+        assert_eq!(w.next(), None);
+    }
+
+    #[test]
+    fn stream_send_watermarks() {
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        // Client retransmits stream data in PTO probe.
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
         assert_eq!(
-            pipe.client
-                .paths
-                .get_active()
-                .expect("no active")
-                .recovery
-                .loss_probes[epoch],
-            0,
+            pipe.client.stream_send_watermarks(0, 2, 5),
+            Err(Error::InvalidStreamState(0))
         );
 
-        let frames =
-            testing::decode_pkt(&mut pipe.server, &mut buf[..len]).unwrap();
+        assert_eq!(pipe.client.stream_send(0, b"a", false), Ok(1));
+        assert_eq!(pipe.client.stream_send_watermarks(0, 2, 5), Ok(()));
 
-        let mut iter = frames.iter();
+        // Only 4 more bytes can be buffered.
+        let mut w = pipe.client.writable_detailed();
+        assert_eq!(w.len(), 1);
+        assert_eq!(w.next(), Some((0, 4)));
+        assert_eq!(w.next(), None);
 
-        // Skip ACK frame.
-        iter.next();
+        assert_eq!(pipe.client.stream_capacity(0), Ok(4));
 
-        assert_eq!(
-            iter.next(),
-            Some(&frame::Frame::Stream {
-                stream_id: 4,
-                data: stream::RangeBuf::from(b"b", 0, false),
-            })
-        );
-        assert_eq!(pipe.client.stats().retrans, 1);
-    }
+        assert_eq!(pipe.client.stream_send(0, b"bbbbbbbb", false), Ok(4));
 
-    #[test]
-    /// Tests that PTO probe packets are not coalesced together.
-    fn dont_coalesce_probes() {
-        let mut buf = [0; 65535];
+        // The stream is full until its data is sent.
+        assert_eq!(pipe.client.writable_detailed().next(), None);
+        assert_eq!(pipe.client.writable().next(), None);
+        assert_eq!(pipe.client.stream_send(0, b"c", false), Err(Error::Done));
 
-        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.advance(), Ok(()));
 
-        // Client sends Initial packet.
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
-        assert_eq!(len, 1200);
+        assert_eq!(pipe.client.writable_detailed().next(), Some((0, 5)));
+        assert_eq!(pipe.client.stream_writable_next(), Some(0));
+    }
 
-        // Wait for PTO to expire.
-        let timer = pipe.client.timeout().unwrap();
-        std::thread::sleep(timer + time::Duration::from_millis(1));
+    #[test]
+    fn send_buffer_high_watermark() {
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_streams_bidi(3);
+        config.verify_peer(false);
+        config.set_send_buffer_high_watermark(10);
 
-        pipe.client.on_timeout();
+        let mut pipe = testing::Pipe::with_client_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        let epoch = packet::Epoch::Initial;
         assert_eq!(
-            pipe.client
-                .paths
-                .get_active()
-                .expect("no active")
-                .recovery
-                .loss_probes[epoch],
-            1,
+            pipe.client.stream_send(0, b"aaaaaaaaaaaaaaa", false),
+            Ok(10)
         );
+        assert_eq!(pipe.client.stream_send(4, b"a", false), Err(Error::Done));
+
+        // Once the data is acknowledged more can be buffered.
+        assert_eq!(pipe.advance(), Ok(()));
 
-        // Client sends PTO probe.
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
-        assert_eq!(len, 1200);
         assert_eq!(
-            pipe.client
-                .paths
-                .get_active()
-                .expect("no active")
-                .recovery
-                .loss_probes[epoch],
-            0,
+            pipe.client.stream_send(4, b"aaaaaaaaaaaaaaa", false),
+            Ok(10)
         );
+    }
 
-        // Wait for PTO to expire.
-        let timer = pipe.client.timeout().unwrap();
-        std::thread::sleep(timer + time::Duration::from_millis(1));
-
-        pipe.client.on_timeout();
+    #[test]
+    fn stream_writable_blocked() {
+        let mut config = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config.set_application_protos(&[b"h3"]).unwrap();
+        config.set_initial_max_data(70);
+        config.set_initial_max_stream_data_bidi_local(150000);
+        config.set_initial_max_stream_data_bidi_remote(150000);
+        config.set_initial_max_stream_data_uni(150000);
+        config.set_initial_max_streams_bidi(100);
+        config.set_initial_max_streams_uni(5);
+        config.verify_peer(false);
 
-        assert_eq!(
-            pipe.client
-                .paths
-                .get_active()
-                .expect("no active")
-                .recovery
-                .loss_probes[epoch],
-            2,
-        );
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        // Client sends first PTO probe.
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
-        assert_eq!(len, 1200);
-        assert_eq!(
-            pipe.client
-                .paths
-                .get_active()
-                .expect("no active")
-                .recovery
-                .loss_probes[epoch],
-            1,
-        );
+        // Client creates stream and sends some data.
+        let send_buf = [0; 35];
+        assert_eq!(pipe.client.stream_send(0, &send_buf, false), Ok(35));
 
-        // Client sends second PTO probe.
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
-        assert_eq!(len, 1200);
-        assert_eq!(
-            pipe.client
-                .paths
-                .get_active()
-                .expect("no active")
-                .recovery
-                .loss_probes[epoch],
-            0,
-        );
-    }
+        // Stream is still writable as it still has capacity.
+        assert_eq!(pipe.client.stream_writable_next(), Some(0));
+        assert_eq!(pipe.client.stream_writable_next(), None);
 
-    #[test]
-    fn coalesce_padding_short() {
-        let mut buf = [0; 65535];
+        // Client fills stream, which becomes unwritable due to connection
+        // capacity.
+        let send_buf = [0; 36];
+        assert_eq!(pipe.client.stream_send(0, &send_buf, false), Ok(35));
 
-        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.client.stream_writable_next(), None);
 
-        // Client sends first flight.
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
-        assert_eq!(len, MIN_CLIENT_INITIAL_LEN);
-        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+        assert_eq!(pipe.client.tx_cap, 0);
 
-        // Server sends first flight.
-        let (len, _) = pipe.server.send(&mut buf).unwrap();
-        assert_eq!(len, MIN_CLIENT_INITIAL_LEN);
-        assert_eq!(pipe.client_recv(&mut buf[..len]), Ok(len));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let (len, _) = pipe.server.send(&mut buf).unwrap();
-        assert_eq!(pipe.client_recv(&mut buf[..len]), Ok(len));
+        let mut b = [0; 70];
+        pipe.server.stream_recv(0, &mut b).unwrap();
 
-        // Client sends stream data.
-        assert!(pipe.client.is_established());
-        assert_eq!(pipe.client.stream_send(4, b"hello", true), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        // Client sends second flight.
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
-        assert_eq!(len, MIN_CLIENT_INITIAL_LEN);
-        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+        // The connection capacity has increased and the stream is now writable
+        // again.
+        assert_ne!(pipe.client.tx_cap, 0);
 
-        // None of the sent packets should have been dropped.
-        assert_eq!(pipe.client.sent_count, pipe.server.recv_count);
-        assert_eq!(pipe.server.sent_count, pipe.client.recv_count);
+        assert_eq!(pipe.client.stream_writable_next(), Some(0));
+        assert_eq!(pipe.client.stream_writable_next(), None);
     }
 
     #[test]
-    /// Tests that client avoids handshake deadlock by arming PTO.
-    fn handshake_anti_deadlock() {
-        let mut buf = [0; 65535];
+    /// Tests that we don't exceed the per-connection flow control limit set by
+    /// the peer.
+    fn flow_control_limit_send() {
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
-        config
-            .load_cert_chain_from_pem_file("examples/cert-big.crt")
-            .unwrap();
-        config
-            .load_priv_key_from_pem_file("examples/cert.key")
-            .unwrap();
-        config
-            .set_application_protos(&[b"proto1", b"proto2"])
-            .unwrap();
+        assert_eq!(
+            pipe.client.stream_send(0, b"aaaaaaaaaaaaaaa", false),
+            Ok(15)
+        );
+        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(
+            pipe.client.stream_send(4, b"aaaaaaaaaaaaaaa", false),
+            Ok(15)
+        );
+        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(pipe.client.stream_send(8, b"a", false), Err(Error::Done));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
+        let mut r = pipe.server.readable();
+        assert!(r.next().is_some());
+        assert!(r.next().is_some());
+        assert!(r.next().is_none());
+    }
 
-        assert!(!pipe.client.handshake_status().has_handshake_keys);
-        assert!(!pipe.client.handshake_status().peer_verified_address);
-        assert!(!pipe.server.handshake_status().has_handshake_keys);
-        assert!(pipe.server.handshake_status().peer_verified_address);
+    #[test]
+    /// Tests that invalid packets received before any other valid ones cause
+    /// the server to close the connection immediately.
+    fn invalid_initial_server() {
+        let mut buf = [0; 65535];
+        let mut pipe = testing::Pipe::new().unwrap();
 
-        // Client sends padded Initial.
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
-        assert_eq!(len, 1200);
+        let frames = [frame::Frame::Padding { len: 10 }];
 
-        // Server receives client's Initial and sends own Initial and Handshake
-        // until it's blocked by the anti-amplification limit.
-        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
-        let flight = testing::emit_flight(&mut pipe.server).unwrap();
+        let written = testing::encode_pkt(
+            &mut pipe.client,
+            packet::Type::Initial,
+            &frames,
+            &mut buf,
+        )
+        .unwrap();
 
-        assert!(!pipe.client.handshake_status().has_handshake_keys);
-        assert!(!pipe.client.handshake_status().peer_verified_address);
-        assert!(pipe.server.handshake_status().has_handshake_keys);
-        assert!(pipe.server.handshake_status().peer_verified_address);
+        // Corrupt the packets's last byte to make decryption fail (the last
+        // byte is part of the AEAD tag, so changing it means that the packet
+        // cannot be authenticated during decryption).
+        buf[written - 1] = !buf[written - 1];
 
-        // Client receives the server flight and sends Handshake ACK, but it is
-        // lost.
-        testing::process_flight(&mut pipe.client, flight).unwrap();
-        testing::emit_flight(&mut pipe.client).unwrap();
+        assert_eq!(pipe.server.timeout(), None);
 
-        assert!(pipe.client.handshake_status().has_handshake_keys);
-        assert!(!pipe.client.handshake_status().peer_verified_address);
-        assert!(pipe.server.handshake_status().has_handshake_keys);
-        assert!(pipe.server.handshake_status().peer_verified_address);
+        assert_eq!(
+            pipe.server_recv(&mut buf[..written]),
+            Err(Error::CryptoFail)
+        );
 
-        // Make sure client's PTO timer is armed.
-        assert!(pipe.client.timeout().is_some());
+        assert!(pipe.server.is_closed());
     }
 
     #[test]
-    /// Tests that packets with corrupted type (from Handshake to Initial) are
-    /// properly ignored.
-    fn handshake_packet_type_corruption() {
+    /// Tests that invalid Initial packets received to cause
+    /// the client to close the connection immediately.
+    fn invalid_initial_client() {
         let mut buf = [0; 65535];
-
         let mut pipe = testing::Pipe::new().unwrap();
 
-        // Client sends padded Initial.
+        // Client sends initial flight.
         let (len, _) = pipe.client.send(&mut buf).unwrap();
-        assert_eq!(len, 1200);
 
-        // Server receives client's Initial and sends own Initial and Handshake.
-        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+        // Server sends initial flight.
+        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(1200));
 
-        let flight = testing::emit_flight(&mut pipe.server).unwrap();
-        testing::process_flight(&mut pipe.client, flight).unwrap();
-
-        // Client sends Initial packet with ACK.
-        let active_pid =
-            pipe.client.paths.get_active_path_id().expect("no active");
-        let (ty, len) = pipe
-            .client
-            .send_single(&mut buf, active_pid, false, time::Instant::now())
-            .unwrap();
-        assert_eq!(ty, Type::Initial);
+        let frames = [frame::Frame::Padding { len: 10 }];
 
-        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+        let written = testing::encode_pkt(
+            &mut pipe.server,
+            packet::Type::Initial,
+            &frames,
+            &mut buf,
+        )
+        .unwrap();
 
-        // Client sends Handshake packet.
-        let (ty, len) = pipe
-            .client
-            .send_single(&mut buf, active_pid, false, time::Instant::now())
-            .unwrap();
-        assert_eq!(ty, Type::Handshake);
+        // Corrupt the packets's last byte to make decryption fail (the last
+        // byte is part of the AEAD tag, so changing it means that the packet
+        // cannot be authenticated during decryption).
+        buf[written - 1] = !buf[written - 1];
 
-        // Packet type is corrupted to Initial.
-        buf[0] &= !(0x20);
+        // Client will ignore invalid packet.
+        assert_eq!(pipe.client_recv(&mut buf[..written]), Ok(71));
 
-        let hdr = Header::from_slice(&mut buf[..len], 0).unwrap();
-        assert_eq!(hdr.ty, Type::Initial);
+        // The connection should be alive...
+        assert!(!pipe.client.is_closed());
 
-        // Server receives corrupted packet without returning an error.
-        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+        // ...and the idle timeout should be armed.
+        assert!(pipe.client.idle_timer.is_some());
     }
 
     #[test]
-    fn dgram_send_fails_invalidstate() {
+    /// Tests that packets with invalid payload length received before any other
+    /// valid packet cause the server to close the connection immediately.
+    fn invalid_initial_payload() {
+        let mut buf = [0; 65535];
         let mut pipe = testing::Pipe::new().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+
+        let mut b = octets::OctetsMut::with_slice(&mut buf);
+
+        let epoch = packet::Type::Initial.to_epoch().unwrap();
+
+        let pn = 0;
+        let pn_len = packet::pkt_num_len(pn).unwrap();
+
+        let dcid = pipe.client.destination_id();
+        let scid = pipe.client.source_id();
+
+        let hdr = Header {
+            ty: packet::Type::Initial,
+            version: pipe.client.version,
+            dcid: ConnectionId::from_ref(&dcid),
+            scid: ConnectionId::from_ref(&scid),
+            pkt_num: 0,
+            pkt_num_len: pn_len,
+            token: pipe.client.token.clone(),
+            versions: None,
+            key_phase: false,
+        };
+
+        hdr.to_bytes(&mut b).unwrap();
+
+        // Payload length is invalid!!!
+        let payload_len = 4096;
+
+        let len = pn_len + payload_len;
+        b.put_varint(len as u64).unwrap();
+
+        packet::encode_pkt_num(pn, &mut b).unwrap();
+
+        let payload_offset = b.off();
+
+        let frames = [frame::Frame::Padding { len: 10 }];
+
+        for frame in &frames {
+            frame.to_bytes(&mut b).unwrap();
+        }
+
+        let space = &mut pipe.client.pkt_num_spaces[epoch];
+
+        // Use correct payload length when encrypting the packet.
+        let payload_len = frames.iter().fold(0, |acc, x| acc + x.wire_len());
+
+        let aead = space.crypto_seal.as_ref().unwrap();
+
+        let written = packet::encrypt_pkt(
+            &mut b,
+            pn,
+            pn_len,
+            payload_len,
+            payload_offset,
+            None,
+            aead,
+        )
+        .unwrap();
+
+        assert_eq!(pipe.server.timeout(), None);
 
         assert_eq!(
-            pipe.client.dgram_send(b"hello, world"),
-            Err(Error::InvalidState)
+            pipe.server_recv(&mut buf[..written]),
+            Err(Error::InvalidPacket)
         );
+
+        assert!(pipe.server.is_closed());
     }
 
     #[test]
-    fn dgram_send_app_limited() {
+    /// Tests that invalid packets don't cause the connection to be closed.
+    fn invalid_packet() {
         let mut buf = [0; 65535];
-        let send_buf = [0xcf; 1000];
 
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let frames = [frame::Frame::Padding { len: 10 }];
+
+        let written = testing::encode_pkt(
+            &mut pipe.client,
+            packet::Type::Short,
+            &frames,
+            &mut buf,
+        )
+        .unwrap();
+
+        // Corrupt the packets's last byte to make decryption fail (the last
+        // byte is part of the AEAD tag, so changing it means that the packet
+        // cannot be authenticated during decryption).
+        buf[written - 1] = !buf[written - 1];
+
+        assert_eq!(pipe.server_recv(&mut buf[..written]), Ok(written));
+
+        // Corrupt the packets's first byte to make the header fail decoding.
+        buf[0] = 255;
+
+        assert_eq!(pipe.server_recv(&mut buf[..written]), Ok(written));
+    }
+
+    #[test]
+    fn recv_empty_buffer() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(pipe.server_recv(&mut buf[..0]), Err(Error::BufferTooShort));
+    }
+
+    #[test]
+    /// Tests that the MAX_STREAMS frame is sent for bidirectional streams.
+    fn stream_limit_update_bidi() {
         let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
         config
             .load_cert_chain_from_pem_file("examples/cert.crt")
@@ -13677,63 +20393,67 @@ mod tests {
         config.set_initial_max_stream_data_bidi_remote(15);
         config.set_initial_max_stream_data_uni(10);
         config.set_initial_max_streams_bidi(3);
-        config.set_initial_max_streams_uni(3);
-        config.enable_dgram(true, 1000, 1000);
-        config.set_max_recv_udp_payload_size(1200);
+        config.set_initial_max_streams_uni(0);
         config.verify_peer(false);
 
         let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        for _ in 0..1000 {
-            assert_eq!(pipe.client.dgram_send(&send_buf), Ok(()));
-        }
+        // Client sends stream data.
+        assert_eq!(pipe.client.stream_send(0, b"a", false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        assert!(!pipe
-            .client
-            .paths
-            .get_active()
-            .expect("no active")
-            .recovery
-            .app_limited());
-        assert_eq!(pipe.client.dgram_send_queue.byte_size(), 1_000_000);
+        assert_eq!(pipe.client.stream_send(4, b"a", false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(pipe.client.stream_send(4, b"b", true), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        assert_ne!(pipe.client.dgram_send_queue.byte_size(), 0);
-        assert_ne!(pipe.client.dgram_send_queue.byte_size(), 1_000_000);
-        assert!(!pipe
-            .client
-            .paths
-            .get_active()
-            .expect("no active")
-            .recovery
-            .app_limited());
+        assert_eq!(pipe.client.stream_send(0, b"b", true), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+        // Server reads stream data.
+        let mut b = [0; 15];
+        pipe.server.stream_recv(0, &mut b).unwrap();
+        pipe.server.stream_recv(4, &mut b).unwrap();
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let flight = testing::emit_flight(&mut pipe.client).unwrap();
-        testing::process_flight(&mut pipe.server, flight).unwrap();
+        // Server sends stream data, with fin.
+        assert_eq!(pipe.server.stream_send(0, b"a", false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let flight = testing::emit_flight(&mut pipe.server).unwrap();
-        testing::process_flight(&mut pipe.client, flight).unwrap();
+        assert_eq!(pipe.server.stream_send(4, b"a", false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        assert_ne!(pipe.client.dgram_send_queue.byte_size(), 0);
-        assert_ne!(pipe.client.dgram_send_queue.byte_size(), 1_000_000);
+        assert_eq!(pipe.server.stream_send(4, b"b", true), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        assert!(!pipe
-            .client
-            .paths
-            .get_active()
-            .expect("no active")
-            .recovery
-            .app_limited());
+        assert_eq!(pipe.server.stream_send(0, b"b", true), Ok(1));
+
+        // Server sends MAX_STREAMS.
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // Client tries to create new streams.
+        assert_eq!(pipe.client.stream_send(8, b"a", false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.client.stream_send(12, b"a", false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.client.stream_send(16, b"a", false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(
+            pipe.client.stream_send(20, b"a", false),
+            Err(Error::StreamLimit)
+        );
+
+        assert_eq!(pipe.server.readable().len(), 3);
     }
 
     #[test]
-    fn dgram_single_datagram() {
-        let mut buf = [0; 65535];
-
+    /// Tests that the MAX_STREAMS frame is sent for unidirectional streams.
+    fn stream_limit_update_uni() {
         let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
         config
             .load_cert_chain_from_pem_file("examples/cert.crt")
@@ -13748,250 +20468,260 @@ mod tests {
         config.set_initial_max_stream_data_bidi_local(15);
         config.set_initial_max_stream_data_bidi_remote(15);
         config.set_initial_max_stream_data_uni(10);
-        config.set_initial_max_streams_bidi(3);
+        config.set_initial_max_streams_bidi(0);
         config.set_initial_max_streams_uni(3);
-        config.enable_dgram(true, 10, 10);
         config.verify_peer(false);
 
         let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        assert_eq!(pipe.client.dgram_send(b"hello, world"), Ok(()));
+        // Client sends stream data.
+        assert_eq!(pipe.client.stream_send(2, b"a", false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
+        assert_eq!(pipe.client.stream_send(6, b"a", false), Ok(1));
         assert_eq!(pipe.advance(), Ok(()));
 
-        let result1 = pipe.server.dgram_recv(&mut buf);
-        assert_eq!(result1, Ok(12));
+        assert_eq!(pipe.client.stream_send(6, b"b", true), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let result2 = pipe.server.dgram_recv(&mut buf);
-        assert_eq!(result2, Err(Error::Done));
-    }
-
-    #[test]
-    fn dgram_multiple_datagrams() {
-        let mut buf = [0; 65535];
+        assert_eq!(pipe.client.stream_send(2, b"b", true), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
-        config
-            .load_cert_chain_from_pem_file("examples/cert.crt")
-            .unwrap();
-        config
-            .load_priv_key_from_pem_file("examples/cert.key")
-            .unwrap();
-        config
-            .set_application_protos(&[b"proto1", b"proto2"])
-            .unwrap();
-        config.set_initial_max_data(30);
-        config.set_initial_max_stream_data_bidi_local(15);
-        config.set_initial_max_stream_data_bidi_remote(15);
-        config.set_initial_max_stream_data_uni(10);
-        config.set_initial_max_streams_bidi(3);
-        config.set_initial_max_streams_uni(3);
-        config.enable_dgram(true, 2, 3);
-        config.verify_peer(false);
+        // Server reads stream data.
+        let mut b = [0; 15];
+        pipe.server.stream_recv(2, &mut b).unwrap();
+        pipe.server.stream_recv(6, &mut b).unwrap();
 
-        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+        // Server sends MAX_STREAMS.
+        assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(pipe.client.dgram_send_queue_len(), 0);
-        assert_eq!(pipe.client.dgram_send_queue_byte_size(), 0);
+        // Client tries to create new streams.
+        assert_eq!(pipe.client.stream_send(10, b"a", false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(pipe.client.dgram_send(b"hello, world"), Ok(()));
-        assert_eq!(pipe.client.dgram_send(b"ciao, mondo"), Ok(()));
-        assert_eq!(pipe.client.dgram_send(b"hola, mundo"), Ok(()));
-        assert!(pipe.client.is_dgram_send_queue_full());
+        assert_eq!(pipe.client.stream_send(14, b"a", false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(pipe.client.dgram_send_queue_byte_size(), 34);
+        assert_eq!(pipe.client.stream_send(18, b"a", false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        pipe.client
-            .dgram_purge_outgoing(|d: &[u8]| -> bool { d[0] == b'c' });
+        assert_eq!(
+            pipe.client.stream_send(22, b"a", false),
+            Err(Error::StreamLimit)
+        );
 
-        assert_eq!(pipe.client.dgram_send_queue_len(), 2);
-        assert_eq!(pipe.client.dgram_send_queue_byte_size(), 23);
-        assert!(!pipe.client.is_dgram_send_queue_full());
+        assert_eq!(pipe.server.readable().len(), 3);
+    }
 
-        // Before packets exchanged, no dgrams on server receive side.
-        assert_eq!(pipe.server.dgram_recv_queue_len(), 0);
+    #[test]
+    /// Tests that the stream's fin flag is properly flushed even if there's no
+    /// data in the buffer, and that the buffer becomes readable on the other
+    /// side.
+    fn stream_zero_length_fin() {
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
+        assert_eq!(
+            pipe.client.stream_send(0, b"aaaaaaaaaaaaaaa", false),
+            Ok(15)
+        );
         assert_eq!(pipe.advance(), Ok(()));
 
-        // After packets exchanged, no dgrams on client send side.
-        assert_eq!(pipe.client.dgram_send_queue_len(), 0);
-        assert_eq!(pipe.client.dgram_send_queue_byte_size(), 0);
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(0));
+        assert!(r.next().is_none());
 
-        assert_eq!(pipe.server.dgram_recv_queue_len(), 2);
-        assert_eq!(pipe.server.dgram_recv_queue_byte_size(), 23);
-        assert!(pipe.server.is_dgram_recv_queue_full());
+        let mut b = [0; 15];
+        pipe.server.stream_recv(0, &mut b).unwrap();
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let result1 = pipe.server.dgram_recv(&mut buf);
-        assert_eq!(result1, Ok(12));
-        assert_eq!(buf[0], b'h');
-        assert_eq!(buf[1], b'e');
-        assert!(!pipe.server.is_dgram_recv_queue_full());
+        // Client sends zero-length frame.
+        assert_eq!(pipe.client.stream_send(0, b"", true), Ok(0));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let result2 = pipe.server.dgram_recv(&mut buf);
-        assert_eq!(result2, Ok(11));
-        assert_eq!(buf[0], b'h');
-        assert_eq!(buf[1], b'o');
+        // Stream should be readable on the server after receiving empty fin.
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(0));
+        assert!(r.next().is_none());
 
-        let result3 = pipe.server.dgram_recv(&mut buf);
-        assert_eq!(result3, Err(Error::Done));
+        let mut b = [0; 15];
+        pipe.server.stream_recv(0, &mut b).unwrap();
+        assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(pipe.server.dgram_recv_queue_len(), 0);
-        assert_eq!(pipe.server.dgram_recv_queue_byte_size(), 0);
+        // Client sends zero-length frame (again).
+        assert_eq!(pipe.client.stream_send(0, b"", true), Ok(0));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // Stream should _not_ be readable on the server after receiving empty
+        // fin, because it was already finished.
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), None);
     }
 
     #[test]
-    fn dgram_send_queue_overflow() {
-        let mut buf = [0; 65535];
-
-        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
-        config
-            .load_cert_chain_from_pem_file("examples/cert.crt")
-            .unwrap();
-        config
-            .load_priv_key_from_pem_file("examples/cert.key")
-            .unwrap();
-        config
-            .set_application_protos(&[b"proto1", b"proto2"])
-            .unwrap();
-        config.set_initial_max_data(30);
-        config.set_initial_max_stream_data_bidi_local(15);
-        config.set_initial_max_stream_data_bidi_remote(15);
-        config.set_initial_max_stream_data_uni(10);
-        config.set_initial_max_streams_bidi(3);
-        config.set_initial_max_streams_uni(3);
-        config.enable_dgram(true, 10, 2);
-        config.verify_peer(false);
-
-        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+    /// Tests that the stream's fin flag is properly flushed even if there's no
+    /// data in the buffer, that the buffer becomes readable on the other
+    /// side and stays readable even if the stream is fin'd locally.
+    fn stream_zero_length_fin_deferred_collection() {
+        let mut pipe = testing::Pipe::new().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        assert_eq!(pipe.client.dgram_send(b"hello, world"), Ok(()));
-        assert_eq!(pipe.client.dgram_send(b"ciao, mondo"), Ok(()));
-        assert_eq!(pipe.client.dgram_send(b"hola, mundo"), Err(Error::Done));
+        assert_eq!(
+            pipe.client.stream_send(0, b"aaaaaaaaaaaaaaa", false),
+            Ok(15)
+        );
+        assert_eq!(pipe.advance(), Ok(()));
+
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(0));
+        assert!(r.next().is_none());
 
+        let mut b = [0; 15];
+        pipe.server.stream_recv(0, &mut b).unwrap();
         assert_eq!(pipe.advance(), Ok(()));
 
-        let result1 = pipe.server.dgram_recv(&mut buf);
-        assert_eq!(result1, Ok(12));
-        assert_eq!(buf[0], b'h');
-        assert_eq!(buf[1], b'e');
+        // Client sends zero-length frame.
+        assert_eq!(pipe.client.stream_send(0, b"", true), Ok(0));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let result2 = pipe.server.dgram_recv(&mut buf);
-        assert_eq!(result2, Ok(11));
-        assert_eq!(buf[0], b'c');
-        assert_eq!(buf[1], b'i');
+        // Server sends zero-length frame.
+        assert_eq!(pipe.server.stream_send(0, b"", true), Ok(0));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let result3 = pipe.server.dgram_recv(&mut buf);
-        assert_eq!(result3, Err(Error::Done));
-    }
+        // Stream should be readable on the server after receiving empty fin.
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(0));
+        assert!(r.next().is_none());
 
-    #[test]
-    fn dgram_recv_queue_overflow() {
-        let mut buf = [0; 65535];
+        let mut b = [0; 15];
+        pipe.server.stream_recv(0, &mut b).unwrap();
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
-        config
-            .load_cert_chain_from_pem_file("examples/cert.crt")
-            .unwrap();
-        config
-            .load_priv_key_from_pem_file("examples/cert.key")
-            .unwrap();
-        config
-            .set_application_protos(&[b"proto1", b"proto2"])
-            .unwrap();
-        config.set_initial_max_data(30);
-        config.set_initial_max_stream_data_bidi_local(15);
-        config.set_initial_max_stream_data_bidi_remote(15);
-        config.set_initial_max_stream_data_uni(10);
-        config.set_initial_max_streams_bidi(3);
-        config.set_initial_max_streams_uni(3);
-        config.enable_dgram(true, 2, 10);
-        config.set_max_recv_udp_payload_size(1200);
-        config.verify_peer(false);
+        // Client sends zero-length frame (again).
+        assert_eq!(pipe.client.stream_send(0, b"", true), Ok(0));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+        // Stream should _not_ be readable on the server after receiving empty
+        // fin, because it was already finished.
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), None);
 
-        assert_eq!(pipe.client.dgram_send(b"hello, world"), Ok(()));
-        assert_eq!(pipe.client.dgram_send(b"ciao, mondo"), Ok(()));
-        assert_eq!(pipe.client.dgram_send(b"hola, mundo"), Ok(()));
+        // Stream _is_readable on the client side.
+        let mut r = pipe.client.readable();
+        assert_eq!(r.next(), Some(0));
 
+        pipe.client.stream_recv(0, &mut b).unwrap();
         assert_eq!(pipe.advance(), Ok(()));
 
-        let result1 = pipe.server.dgram_recv(&mut buf);
-        assert_eq!(result1, Ok(11));
-        assert_eq!(buf[0], b'c');
-        assert_eq!(buf[1], b'i');
+        // Stream is completed and _is not_ readable.
+        let mut r = pipe.client.readable();
+        assert_eq!(r.next(), None);
+    }
 
-        let result2 = pipe.server.dgram_recv(&mut buf);
-        assert_eq!(result2, Ok(11));
-        assert_eq!(buf[0], b'h');
-        assert_eq!(buf[1], b'o');
+    #[test]
+    /// Tests that the stream gets created with stream_send() even if there's
+    /// no data in the buffer and the fin flag is not set.
+    fn stream_zero_length_non_fin() {
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        let result3 = pipe.server.dgram_recv(&mut buf);
-        assert_eq!(result3, Err(Error::Done));
+        assert_eq!(pipe.client.stream_send(0, b"", false), Ok(0));
+
+        // The stream now should have been created.
+        assert_eq!(pipe.client.streams.len(), 1);
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // Sending an empty non-fin should not change any stream state on the
+        // other side.
+        let mut r = pipe.server.readable();
+        assert!(r.next().is_none());
     }
 
     #[test]
-    fn dgram_send_max_size() {
-        let mut buf = [0; MAX_DGRAM_FRAME_SIZE as usize];
+    /// Tests that completed streams are garbage collected.
+    fn collect_streams() {
+        let mut buf = [0; 65535];
 
-        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
-        config
-            .load_cert_chain_from_pem_file("examples/cert.crt")
-            .unwrap();
-        config
-            .load_priv_key_from_pem_file("examples/cert.key")
-            .unwrap();
-        config
-            .set_application_protos(&[b"proto1", b"proto2"])
-            .unwrap();
-        config.set_initial_max_data(30);
-        config.set_initial_max_stream_data_bidi_local(15);
-        config.set_initial_max_stream_data_bidi_remote(15);
-        config.set_initial_max_stream_data_uni(10);
-        config.set_initial_max_streams_bidi(3);
-        config.set_initial_max_streams_uni(3);
-        config.enable_dgram(true, 10, 10);
-        config.set_max_recv_udp_payload_size(1452);
-        config.verify_peer(false);
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.client.streams.len(), 0);
+        assert_eq!(pipe.server.streams.len(), 0);
 
-        // Before handshake (before peer settings) we don't know max dgram size
-        assert_eq!(pipe.client.dgram_max_writable_len(), None);
+        assert_eq!(pipe.client.stream_send(0, b"aaaaa", true), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(pipe.handshake(), Ok(()));
+        assert!(!pipe.client.stream_finished(0));
+        assert!(!pipe.server.stream_finished(0));
 
-        let max_dgram_size = pipe.client.dgram_max_writable_len().unwrap();
+        assert_eq!(pipe.client.streams.len(), 1);
+        assert_eq!(pipe.server.streams.len(), 1);
 
-        // Tests use a 16-byte connection ID, so the max datagram frame payload
-        // size is (1200 byte-long packet - 40 bytes overhead)
-        assert_eq!(max_dgram_size, 1160);
+        let mut b = [0; 5];
+        pipe.server.stream_recv(0, &mut b).unwrap();
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let dgram_packet: Vec<u8> = vec![42; max_dgram_size];
+        assert_eq!(pipe.server.stream_send(0, b"aaaaa", true), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(pipe.client.dgram_send(&dgram_packet), Ok(()));
+        assert!(!pipe.client.stream_finished(0));
+        assert!(pipe.server.stream_finished(0));
+
+        assert_eq!(pipe.client.streams.len(), 1);
+        assert_eq!(pipe.server.streams.len(), 0);
 
+        let mut b = [0; 5];
+        pipe.client.stream_recv(0, &mut b).unwrap();
         assert_eq!(pipe.advance(), Ok(()));
 
-        let result1 = pipe.server.dgram_recv(&mut buf);
-        assert_eq!(result1, Ok(max_dgram_size));
+        assert_eq!(pipe.client.streams.len(), 0);
+        assert_eq!(pipe.server.streams.len(), 0);
 
-        let result2 = pipe.server.dgram_recv(&mut buf);
-        assert_eq!(result2, Err(Error::Done));
+        assert!(pipe.client.stream_finished(0));
+        assert!(pipe.server.stream_finished(0));
+
+        assert_eq!(pipe.client.stream_send(0, b"", true), Err(Error::Done));
+
+        let frames = [frame::Frame::Stream {
+            stream_id: 0,
+            data: stream::RangeBuf::from(b"aa", 0, false),
+        }];
+
+        let pkt_type = packet::Type::Short;
+        assert_eq!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf), Ok(39));
     }
 
     #[test]
-    /// Tests is_readable check.
-    fn is_readable() {
-        let mut buf = [0; 65535];
+    fn config_set_cc_algorithm_name() {
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
 
-        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        assert_eq!(config.set_cc_algorithm_name("reno"), Ok(()));
+
+        // Unknown name.
+        assert_eq!(
+            config.set_cc_algorithm_name("???"),
+            Err(Error::CongestionControl)
+        );
+    }
+
+    #[test]
+    fn peer_cert() {
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        match pipe.client.peer_cert() {
+            Some(c) => assert_eq!(c.len(), 753),
+
+            None => panic!("missing server certificate"),
+        }
+    }
+
+    #[test]
+    fn peer_cert_chain() {
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
         config
-            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .load_cert_chain_from_pem_file("examples/cert-big.crt")
             .unwrap();
         config
             .load_priv_key_from_pem_file("examples/cert.key")
@@ -13999,636 +20729,643 @@ mod tests {
         config
             .set_application_protos(&[b"proto1", b"proto2"])
             .unwrap();
-        config.set_initial_max_data(30);
-        config.set_initial_max_stream_data_bidi_local(15);
-        config.set_initial_max_stream_data_bidi_remote(15);
-        config.set_initial_max_stream_data_uni(10);
-        config.set_initial_max_streams_bidi(3);
-        config.set_initial_max_streams_uni(3);
-        config.enable_dgram(true, 10, 10);
-        config.set_max_recv_udp_payload_size(1452);
-        config.verify_peer(false);
 
-        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        // No readable data.
-        assert!(!pipe.client.is_readable());
-        assert!(!pipe.server.is_readable());
-
-        assert_eq!(pipe.client.stream_send(4, b"aaaaa", false), Ok(5));
-        assert_eq!(pipe.advance(), Ok(()));
-
-        // Server received stream.
-        assert!(!pipe.client.is_readable());
-        assert!(pipe.server.is_readable());
-
-        assert_eq!(
-            pipe.server.stream_send(4, b"aaaaaaaaaaaaaaa", false),
-            Ok(15)
-        );
-        assert_eq!(pipe.advance(), Ok(()));
-
-        // Client received stream.
-        assert!(pipe.client.is_readable());
-        assert!(pipe.server.is_readable());
+        match pipe.client.peer_cert_chain() {
+            Some(c) => assert_eq!(c.len(), 5),
 
-        // Client drains stream.
-        let mut b = [0; 15];
-        pipe.client.stream_recv(4, &mut b).unwrap();
-        assert_eq!(pipe.advance(), Ok(()));
+            None => panic!("missing server certificate chain"),
+        }
+    }
 
-        assert!(!pipe.client.is_readable());
-        assert!(pipe.server.is_readable());
+    #[test]
+    fn retry() {
+        let mut buf = [0; 65535];
 
-        // Server shuts down stream.
-        assert_eq!(pipe.server.stream_shutdown(4, Shutdown::Read, 0), Ok(()));
-        assert!(!pipe.server.is_readable());
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
 
-        // Server received dgram.
-        assert_eq!(pipe.client.dgram_send(b"dddddddddddddd"), Ok(()));
-        assert_eq!(pipe.advance(), Ok(()));
+        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
 
-        assert!(!pipe.client.is_readable());
-        assert!(pipe.server.is_readable());
+        // Client sends initial flight.
+        let (mut len, _) = pipe.client.send(&mut buf).unwrap();
 
-        // Client received dgram.
-        assert_eq!(pipe.server.dgram_send(b"dddddddddddddd"), Ok(()));
-        assert_eq!(pipe.advance(), Ok(()));
+        // Server sends Retry packet.
+        let hdr = Header::from_slice(&mut buf[..len], MAX_CONN_ID_LEN).unwrap();
 
-        assert!(pipe.client.is_readable());
-        assert!(pipe.server.is_readable());
+        let odcid = hdr.dcid.clone();
 
-        // Drain the dgram queues.
-        let r = pipe.server.dgram_recv(&mut buf);
-        assert_eq!(r, Ok(14));
-        assert!(!pipe.server.is_readable());
+        let mut scid = [0; MAX_CONN_ID_LEN];
+        rand::rand_bytes(&mut scid[..]);
+        let scid = ConnectionId::from_ref(&scid);
 
-        let r = pipe.client.dgram_recv(&mut buf);
-        assert_eq!(r, Ok(14));
-        assert!(!pipe.client.is_readable());
-    }
+        let token = b"quiche test retry token";
 
-    #[test]
-    fn close() {
-        let mut buf = [0; 65535];
+        len = packet::retry(
+            &hdr.scid,
+            &hdr.dcid,
+            &scid,
+            token,
+            hdr.version,
+            &mut buf,
+        )
+        .unwrap();
 
-        let mut pipe = testing::Pipe::new().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+        // Client receives Retry and sends new Initial.
+        assert_eq!(pipe.client_recv(&mut buf[..len]), Ok(len));
 
-        assert_eq!(pipe.client.close(false, 0x1234, b"hello?"), Ok(()));
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
 
-        assert_eq!(
-            pipe.client.close(false, 0x4321, b"hello?"),
-            Err(Error::Done)
-        );
+        let hdr = Header::from_slice(&mut buf[..len], MAX_CONN_ID_LEN).unwrap();
+        assert_eq!(&hdr.token.unwrap(), token);
 
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        // Server accepts connection.
+        let from = "127.0.0.1:1234".parse().unwrap();
+        pipe.server = accept(
+            &scid,
+            Some(&odcid),
+            testing::Pipe::server_addr(),
+            from,
+            &mut config,
+        )
+        .unwrap();
+        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
 
-        let frames =
-            testing::decode_pkt(&mut pipe.server, &mut buf[..len]).unwrap();
+        assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(
-            frames.first(),
-            Some(&frame::Frame::ConnectionClose {
-                error_code: 0x1234,
-                frame_type: 0,
-                reason: b"hello?".to_vec(),
-            })
-        );
+        assert!(pipe.client.is_established());
+        assert!(pipe.server.is_established());
     }
 
     #[test]
-    fn app_close_by_client() {
+    fn missing_retry_source_connection_id() {
         let mut buf = [0; 65535];
 
-        let mut pipe = testing::Pipe::new().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
 
-        assert_eq!(pipe.client.close(true, 0x1234, b"hello!"), Ok(()));
+        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
 
-        assert_eq!(pipe.client.close(true, 0x4321, b"hello!"), Err(Error::Done));
+        // Client sends initial flight.
+        let (mut len, _) = pipe.client.send(&mut buf).unwrap();
 
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        // Server sends Retry packet.
+        let hdr = Header::from_slice(&mut buf[..len], MAX_CONN_ID_LEN).unwrap();
 
-        let frames =
-            testing::decode_pkt(&mut pipe.server, &mut buf[..len]).unwrap();
+        let mut scid = [0; MAX_CONN_ID_LEN];
+        rand::rand_bytes(&mut scid[..]);
+        let scid = ConnectionId::from_ref(&scid);
+
+        let token = b"quiche test retry token";
+
+        len = packet::retry(
+            &hdr.scid,
+            &hdr.dcid,
+            &scid,
+            token,
+            hdr.version,
+            &mut buf,
+        )
+        .unwrap();
+
+        // Client receives Retry and sends new Initial.
+        assert_eq!(pipe.client_recv(&mut buf[..len]), Ok(len));
+
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+
+        // Server accepts connection and send first flight. But original
+        // destination connection ID is ignored.
+        let from = "127.0.0.1:1234".parse().unwrap();
+        pipe.server =
+            accept(&scid, None, testing::Pipe::server_addr(), from, &mut config)
+                .unwrap();
+        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+
+        let flight = testing::emit_flight(&mut pipe.server).unwrap();
 
         assert_eq!(
-            frames.first(),
-            Some(&frame::Frame::ApplicationClose {
-                error_code: 0x1234,
-                reason: b"hello!".to_vec(),
-            })
+            testing::process_flight(&mut pipe.client, flight),
+            Err(Error::InvalidTransportParam)
         );
     }
 
     #[test]
-    fn app_close_by_server_during_handshake_private_key_failure() {
-        let mut pipe = testing::Pipe::new().unwrap();
-        pipe.server.handshake.set_failing_private_key_method();
+    fn invalid_retry_source_connection_id() {
+        let mut buf = [0; 65535];
+
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+
+        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
 
         // Client sends initial flight.
-        let flight = testing::emit_flight(&mut pipe.client).unwrap();
-        assert_eq!(
-            testing::process_flight(&mut pipe.server, flight),
-            Err(Error::TlsFail)
-        );
+        let (mut len, _) = pipe.client.send(&mut buf).unwrap();
 
-        let flight = testing::emit_flight(&mut pipe.server).unwrap();
+        // Server sends Retry packet.
+        let hdr = Header::from_slice(&mut buf[..len], MAX_CONN_ID_LEN).unwrap();
 
-        // Both connections are not established.
-        assert!(!pipe.server.is_established());
-        assert!(!pipe.client.is_established());
+        let mut scid = [0; MAX_CONN_ID_LEN];
+        rand::rand_bytes(&mut scid[..]);
+        let scid = ConnectionId::from_ref(&scid);
 
-        // Connection should already be closed due the failure during key signing.
-        assert_eq!(
-            pipe.server.close(true, 123, b"fail whale"),
-            Err(Error::Done)
-        );
+        let token = b"quiche test retry token";
 
-        testing::process_flight(&mut pipe.client, flight).unwrap();
+        len = packet::retry(
+            &hdr.scid,
+            &hdr.dcid,
+            &scid,
+            token,
+            hdr.version,
+            &mut buf,
+        )
+        .unwrap();
 
-        // Connection should already be closed due the failure during key signing.
-        assert_eq!(
-            pipe.client.close(true, 123, b"fail whale"),
-            Err(Error::Done)
-        );
+        // Client receives Retry and sends new Initial.
+        assert_eq!(pipe.client_recv(&mut buf[..len]), Ok(len));
 
-        // Connection is not established on the server / client (and never
-        // will be)
-        assert!(!pipe.server.is_established());
-        assert!(!pipe.client.is_established());
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
 
-        assert_eq!(pipe.advance(), Ok(()));
+        // Server accepts connection and send first flight. But original
+        // destination connection ID is invalid.
+        let from = "127.0.0.1:1234".parse().unwrap();
+        let odcid = ConnectionId::from_ref(b"bogus value");
+        pipe.server = accept(
+            &scid,
+            Some(&odcid),
+            testing::Pipe::server_addr(),
+            from,
+            &mut config,
+        )
+        .unwrap();
+        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+
+        let flight = testing::emit_flight(&mut pipe.server).unwrap();
 
         assert_eq!(
-            pipe.server.local_error(),
-            Some(&ConnectionError {
-                is_app: false,
-                error_code: 0x01,
-                reason: vec![],
-            })
-        );
-        assert_eq!(
-            pipe.client.peer_error(),
-            Some(&ConnectionError {
-                is_app: false,
-                error_code: 0x01,
-                reason: vec![],
-            })
+            testing::process_flight(&mut pipe.client, flight),
+            Err(Error::InvalidTransportParam)
         );
     }
 
     #[test]
-    fn app_close_by_server_during_handshake_not_established() {
+    /// Tests that a zero-length NEW_TOKEN frame is detected as an error.
+    fn zero_length_new_token() {
+        let mut buf = [0; 65535];
+
         let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        // Client sends initial flight.
-        let flight = testing::emit_flight(&mut pipe.client).unwrap();
-        testing::process_flight(&mut pipe.server, flight).unwrap();
+        let mut frames = Vec::new();
 
-        let flight = testing::emit_flight(&mut pipe.server).unwrap();
+        frames.push(frame::Frame::NewToken { token: vec![] });
 
-        // Both connections are not established.
-        assert!(!pipe.client.is_established() && !pipe.server.is_established());
+        let pkt_type = packet::Type::Short;
 
-        // Server closes before connection is established.
-        pipe.server.close(true, 123, b"fail whale").unwrap();
+        let written =
+            testing::encode_pkt(&mut pipe.server, pkt_type, &frames, &mut buf)
+                .unwrap();
 
-        testing::process_flight(&mut pipe.client, flight).unwrap();
+        assert_eq!(
+            pipe.client_recv(&mut buf[..written]),
+            Err(Error::InvalidFrame)
+        );
+    }
 
-        // Connection is established on the client.
-        assert!(pipe.client.is_established());
+    #[test]
+    fn new_token() {
+        #[derive(Default)]
+        struct Store(std::sync::Mutex<Option<Vec<u8>>>);
 
-        // Client sends after connection is established.
-        pipe.client.stream_send(0, b"badauthtoken", true).unwrap();
+        impl SessionStore for Store {
+            fn get(&self, _server_name: &str) -> Option<Vec<u8>> {
+                None
+            }
 
-        let flight = testing::emit_flight(&mut pipe.client).unwrap();
-        testing::process_flight(&mut pipe.server, flight).unwrap();
+            fn put(&self, _server_name: &str, _session: &[u8]) {}
 
-        // Connection is not established on the server (and never will be)
-        assert!(!pipe.server.is_established());
+            fn get_token(&self, _server_name: &str) -> Option<Vec<u8>> {
+                self.0.lock().unwrap().clone()
+            }
+
+            fn put_token(&self, _server_name: &str, token: &[u8]) {
+                *self.0.lock().unwrap() = Some(token.to_vec());
+            }
+        }
+
+        let store = Arc::new(Store::default());
+
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.verify_peer(false);
+        config.set_token_provider(Arc::new(AeadTokenProvider::new(b"secret")));
+        config.set_session_store(store.clone());
 
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
         assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(
-            pipe.server.local_error(),
-            Some(&ConnectionError {
-                is_app: false,
-                error_code: 0x0c,
-                reason: vec![],
-            })
-        );
-        assert_eq!(
-            pipe.client.peer_error(),
-            Some(&ConnectionError {
-                is_app: false,
-                error_code: 0x0c,
-                reason: vec![],
-            })
-        );
-    }
+        // The server sent a token once the handshake was confirmed.
+        let token = store.get_token("quic.tech").unwrap();
+        assert!(!pipe.server.should_send_new_token());
 
-    #[test]
-    fn app_close_by_server_during_handshake_established() {
-        let mut pipe = testing::Pipe::new().unwrap();
+        // The token is used by the next connection to the same server.
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.client.token, Some(token.clone()));
+
+        assert!(
+            !pipe
+                .server
+                .paths
+                .get_active()
+                .unwrap()
+                .verified_peer_address
+        );
 
-        // Client sends initial flight.
         let flight = testing::emit_flight(&mut pipe.client).unwrap();
         testing::process_flight(&mut pipe.server, flight).unwrap();
 
-        let flight = testing::emit_flight(&mut pipe.server).unwrap();
-
-        // Both connections are not established.
-        assert!(!pipe.client.is_established() && !pipe.server.is_established());
-
-        testing::process_flight(&mut pipe.client, flight).unwrap();
+        assert!(
+            pipe.server
+                .paths
+                .get_active()
+                .unwrap()
+                .verified_peer_address
+        );
 
-        // Connection is established on the client.
-        assert!(pipe.client.is_established());
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        // Client sends after connection is established.
-        pipe.client.stream_send(0, b"badauthtoken", true).unwrap();
+        // The same token can't be used again by another connection.
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        pipe.client.token = Some(token);
 
         let flight = testing::emit_flight(&mut pipe.client).unwrap();
         testing::process_flight(&mut pipe.server, flight).unwrap();
 
-        // Connection is established on the server but the Handshake ACK has not
-        // been sent yet.
-        assert!(pipe.server.is_established());
+        assert!(
+            !pipe
+                .server
+                .paths
+                .get_active()
+                .unwrap()
+                .verified_peer_address
+        );
 
-        // Server closes after connection is established.
-        pipe.server
-            .close(true, 123, b"Invalid authentication")
-            .unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+    }
 
-        // Server sends Handshake ACK and then 1RTT CONNECTION_CLOSE.
-        assert_eq!(pipe.advance(), Ok(()));
+    #[test]
+    /// Tests that a NEW_TOKEN frame sent by client is detected as an error.
+    fn client_sent_new_token() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let mut frames = Vec::new();
+
+        frames.push(frame::Frame::NewToken {
+            token: vec![1, 2, 3],
+        });
+
+        let pkt_type = packet::Type::Short;
+
+        let written =
+            testing::encode_pkt(&mut pipe.client, pkt_type, &frames, &mut buf)
+                .unwrap();
 
         assert_eq!(
-            pipe.server.local_error(),
-            Some(&ConnectionError {
-                is_app: true,
-                error_code: 123,
-                reason: b"Invalid authentication".to_vec()
-            })
-        );
-        assert_eq!(
-            pipe.client.peer_error(),
-            Some(&ConnectionError {
-                is_app: true,
-                error_code: 123,
-                reason: b"Invalid authentication".to_vec()
-            })
+            pipe.server_recv(&mut buf[..written]),
+            Err(Error::InvalidPacket)
         );
     }
 
+    fn check_send(_: &mut impl Send) {}
+
     #[test]
-    fn peer_error() {
+    fn config_must_be_send() {
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        check_send(&mut config);
+    }
+
+    #[test]
+    fn connection_must_be_send() {
         let mut pipe = testing::Pipe::new().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+        check_send(&mut pipe.client);
+    }
 
-        assert_eq!(pipe.server.close(false, 0x1234, b"hello?"), Ok(()));
-        assert_eq!(pipe.advance(), Ok(()));
+    fn check_sync(_: &mut impl Sync) {}
 
-        assert_eq!(
-            pipe.client.peer_error(),
-            Some(&ConnectionError {
-                is_app: false,
-                error_code: 0x1234u64,
-                reason: b"hello?".to_vec()
-            })
-        );
+    #[test]
+    fn config_must_be_sync() {
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        check_sync(&mut config);
     }
 
     #[test]
-    fn app_peer_error() {
+    fn connection_must_be_sync() {
+        let mut pipe = testing::Pipe::new().unwrap();
+        check_sync(&mut pipe.client);
+    }
+
+    #[test]
+    fn data_blocked() {
+        let mut buf = [0; 65535];
+
         let mut pipe = testing::Pipe::new().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        assert_eq!(pipe.server.close(true, 0x1234, b"hello!"), Ok(()));
+        assert_eq!(pipe.client.stream_send(0, b"aaaaaaaaaa", false), Ok(10));
+        assert_eq!(pipe.client.blocked_limit, None);
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.client.stream_send(4, b"aaaaaaaaaa", false), Ok(10));
+        assert_eq!(pipe.client.blocked_limit, None);
         assert_eq!(pipe.advance(), Ok(()));
 
+        assert_eq!(pipe.client.stream_send(8, b"aaaaaaaaaaa", false), Ok(10));
+        assert_eq!(pipe.client.blocked_limit, Some(30));
+
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(pipe.client.blocked_limit, None);
+
+        let frames =
+            testing::decode_pkt(&mut pipe.server, &mut buf[..len]).unwrap();
+
+        let mut iter = frames.iter();
+
+        assert_eq!(iter.next(), Some(&frame::Frame::DataBlocked { limit: 30 }));
+
         assert_eq!(
-            pipe.client.peer_error(),
-            Some(&ConnectionError {
-                is_app: true,
-                error_code: 0x1234u64,
-                reason: b"hello!".to_vec()
+            iter.next(),
+            Some(&frame::Frame::Stream {
+                stream_id: 8,
+                data: stream::RangeBuf::from(b"aaaaaaaaaa", 0, false),
             })
         );
+
+        assert_eq!(iter.next(), None);
     }
 
     #[test]
-    fn local_error() {
+    fn stream_data_blocked() {
+        let mut buf = [0; 65535];
+
         let mut pipe = testing::Pipe::new().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        assert_eq!(pipe.server.local_error(), None);
+        assert_eq!(pipe.client.stream_send(0, b"aaaaa", false), Ok(5));
+        assert_eq!(pipe.client.streams.blocked().len(), 0);
 
-        assert_eq!(pipe.server.close(true, 0x1234, b"hello!"), Ok(()));
+        assert_eq!(pipe.client.stream_send(0, b"aaaaa", false), Ok(5));
+        assert_eq!(pipe.client.streams.blocked().len(), 0);
+
+        assert_eq!(pipe.client.stream_send(0, b"aaaaaa", false), Ok(5));
+        assert_eq!(pipe.client.streams.blocked().len(), 1);
+
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(pipe.client.streams.blocked().len(), 0);
+
+        let frames =
+            testing::decode_pkt(&mut pipe.server, &mut buf[..len]).unwrap();
+
+        let mut iter = frames.iter();
+
+        // Skip ACK frame.
+        iter.next();
 
         assert_eq!(
-            pipe.server.local_error(),
-            Some(&ConnectionError {
-                is_app: true,
-                error_code: 0x1234u64,
-                reason: b"hello!".to_vec()
+            iter.next(),
+            Some(&frame::Frame::StreamDataBlocked {
+                stream_id: 0,
+                limit: 15,
             })
         );
-    }
 
-    #[test]
-    fn update_max_datagram_size() {
-        let mut client_scid = [0; 16];
-        rand::rand_bytes(&mut client_scid[..]);
-        let client_scid = ConnectionId::from_ref(&client_scid);
-        let client_addr = "127.0.0.1:1234".parse().unwrap();
+        assert_eq!(
+            iter.next(),
+            Some(&frame::Frame::Stream {
+                stream_id: 0,
+                data: stream::RangeBuf::from(b"aaaaaaaaaaaaaaa", 0, false),
+            })
+        );
 
-        let mut server_scid = [0; 16];
-        rand::rand_bytes(&mut server_scid[..]);
-        let server_scid = ConnectionId::from_ref(&server_scid);
-        let server_addr = "127.0.0.1:4321".parse().unwrap();
+        assert_eq!(iter.next(), None);
 
-        let mut client_config = Config::new(crate::PROTOCOL_VERSION).unwrap();
-        client_config
-            .set_application_protos(&[b"proto1", b"proto2"])
-            .unwrap();
-        client_config.set_max_recv_udp_payload_size(1200);
+        // Send from another stream, make sure we don't send STREAM_DATA_BLOCKED
+        // again.
+        assert_eq!(pipe.client.stream_send(4, b"a", false), Ok(1));
 
-        let mut server_config = Config::new(crate::PROTOCOL_VERSION).unwrap();
-        server_config
-            .load_cert_chain_from_pem_file("examples/cert.crt")
-            .unwrap();
-        server_config
-            .load_priv_key_from_pem_file("examples/cert.key")
-            .unwrap();
-        server_config
-            .set_application_protos(&[b"proto1", b"proto2"])
-            .unwrap();
-        server_config.verify_peer(false);
-        server_config
-            .set_application_protos(&[b"proto1", b"proto2"])
-            .unwrap();
-        // Larger than the client
-        server_config.set_max_send_udp_payload_size(1500);
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(pipe.client.streams.blocked().len(), 0);
 
-        let mut pipe = testing::Pipe {
-            client: connect(
-                Some("quic.tech"),
-                &client_scid,
-                client_addr,
-                server_addr,
-                &mut client_config,
-            )
-            .unwrap(),
-            server: accept(
-                &server_scid,
-                None,
-                server_addr,
-                client_addr,
-                &mut server_config,
-            )
-            .unwrap(),
-        };
+        let frames =
+            testing::decode_pkt(&mut pipe.server, &mut buf[..len]).unwrap();
+
+        let mut iter = frames.iter();
 
-        // Before handshake
         assert_eq!(
-            pipe.server
-                .paths
-                .get_active()
-                .expect("no active")
-                .recovery
-                .max_datagram_size(),
-            1500,
+            iter.next(),
+            Some(&frame::Frame::Stream {
+                stream_id: 4,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            })
         );
 
-        assert_eq!(pipe.handshake(), Ok(()));
+        assert_eq!(iter.next(), None);
 
-        // After handshake, max_datagram_size should match to client's
-        // max_recv_udp_payload_size which is smaller
-        assert_eq!(
-            pipe.server
-                .paths
-                .get_active()
-                .expect("no active")
-                .recovery
-                .max_datagram_size(),
-            1200,
-        );
+        // Send again from blocked stream and make sure it is not marked as
+        // blocked again.
         assert_eq!(
-            pipe.server
-                .paths
-                .get_active()
-                .expect("no active")
-                .recovery
-                .cwnd(),
-            12000,
+            pipe.client.stream_send(0, b"aaaaaa", false),
+            Err(Error::Done)
         );
+        assert_eq!(pipe.client.streams.blocked().len(), 0);
+        assert_eq!(pipe.client.send(&mut buf), Err(Error::Done));
     }
 
     #[test]
-    /// Tests that connection-level send capacity decreases as more stream data
-    /// is buffered.
-    fn send_capacity() {
+    fn stream_data_blocked_unblocked_flow_control() {
         let mut buf = [0; 65535];
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
-        config
-            .load_cert_chain_from_pem_file("examples/cert.crt")
-            .unwrap();
-        config
-            .load_priv_key_from_pem_file("examples/cert.key")
-            .unwrap();
-        config
-            .set_application_protos(&[b"proto1", b"proto2"])
-            .unwrap();
-        config.set_initial_max_data(100000);
-        config.set_initial_max_stream_data_bidi_local(10000);
-        config.set_initial_max_stream_data_bidi_remote(10000);
-        config.set_initial_max_streams_bidi(10);
-        config.verify_peer(false);
-
-        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
-
-        assert_eq!(pipe.client.stream_send(0, b"hello!", true), Ok(6));
+        assert_eq!(
+            pipe.client.stream_send(0, b"aaaaaaaaaaaaaaah", false),
+            Ok(15)
+        );
+        assert_eq!(pipe.client.streams.blocked().len(), 1);
         assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(pipe.client.streams.blocked().len(), 0);
 
-        assert_eq!(pipe.client.stream_send(4, b"hello!", true), Ok(6));
-        assert_eq!(pipe.advance(), Ok(()));
+        // Send again on blocked stream. It's blocked at the same offset as
+        // previously, so it should not be marked as blocked again.
+        assert_eq!(pipe.client.stream_send(0, b"h", false), Err(Error::Done));
+        assert_eq!(pipe.client.streams.blocked().len(), 0);
 
-        assert_eq!(pipe.client.stream_send(8, b"hello!", true), Ok(6));
-        assert_eq!(pipe.advance(), Ok(()));
+        // No matter how many times we try to write stream data tried, no
+        // packets containing STREAM_BLOCKED should be emitted.
+        assert_eq!(pipe.client.stream_send(0, b"h", false), Err(Error::Done));
+        assert_eq!(pipe.client.send(&mut buf), Err(Error::Done));
 
-        assert_eq!(pipe.client.stream_send(12, b"hello!", true), Ok(6));
-        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(pipe.client.stream_send(0, b"h", false), Err(Error::Done));
+        assert_eq!(pipe.client.send(&mut buf), Err(Error::Done));
 
-        let mut r = pipe.server.readable().collect::<Vec<u64>>();
-        assert_eq!(r.len(), 4);
+        assert_eq!(pipe.client.stream_send(0, b"h", false), Err(Error::Done));
+        assert_eq!(pipe.client.send(&mut buf), Err(Error::Done));
 
-        r.sort();
+        // Now read some data at the server to release flow control.
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(0));
+        assert_eq!(r.next(), None);
 
-        assert_eq!(r, [0, 4, 8, 12]);
+        let mut b = [0; 10];
+        assert_eq!(pipe.server.stream_recv(0, &mut b), Ok((10, false)));
+        assert_eq!(&b[..10], b"aaaaaaaaaa");
+        assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(pipe.server.stream_recv(0, &mut buf), Ok((6, true)));
-        assert_eq!(pipe.server.stream_recv(4, &mut buf), Ok((6, true)));
-        assert_eq!(pipe.server.stream_recv(8, &mut buf), Ok((6, true)));
-        assert_eq!(pipe.server.stream_recv(12, &mut buf), Ok((6, true)));
+        assert_eq!(pipe.client.stream_send(0, b"hhhhhhhhhh!", false), Ok(10));
+        assert_eq!(pipe.client.streams.blocked().len(), 1);
 
-        assert_eq!(pipe.server.tx_cap, 12000);
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(pipe.client.streams.blocked().len(), 0);
 
-        assert_eq!(pipe.server.stream_send(0, &buf[..5000], false), Ok(5000));
-        assert_eq!(pipe.server.stream_send(4, &buf[..5000], false), Ok(5000));
-        assert_eq!(pipe.server.stream_send(8, &buf[..5000], false), Ok(2000));
+        let frames =
+            testing::decode_pkt(&mut pipe.server, &mut buf[..len]).unwrap();
+
+        let mut iter = frames.iter();
 
-        // No more connection send capacity.
         assert_eq!(
-            pipe.server.stream_send(12, &buf[..5000], false),
-            Err(Error::Done)
+            iter.next(),
+            Some(&frame::Frame::StreamDataBlocked {
+                stream_id: 0,
+                limit: 25,
+            })
         );
-        assert_eq!(pipe.server.tx_cap, 0);
 
-        assert_eq!(pipe.advance(), Ok(()));
+        // don't care about remaining received frames
+
+        assert_eq!(pipe.client.stream_send(0, b"!", false), Err(Error::Done));
+        assert_eq!(pipe.client.streams.blocked().len(), 0);
+        assert_eq!(pipe.client.send(&mut buf), Err(Error::Done));
     }
 
-    #[cfg(feature = "boringssl-boring-crate")]
     #[test]
-    fn user_provided_boring_ctx() -> Result<()> {
-        // Manually construct boring ssl ctx for server
-        let mut server_tls_ctx_builder =
-            boring::ssl::SslContextBuilder::new(boring::ssl::SslMethod::tls())
-                .unwrap();
-        server_tls_ctx_builder
-            .set_certificate_chain_file("examples/cert.crt")
-            .unwrap();
-        server_tls_ctx_builder
-            .set_private_key_file(
-                "examples/cert.key",
-                boring::ssl::SslFiletype::PEM,
-            )
+    fn app_limited_true() {
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
             .unwrap();
+        config.set_initial_max_data(50000);
+        config.set_initial_max_stream_data_bidi_local(50000);
+        config.set_initial_max_stream_data_bidi_remote(50000);
+        config.set_max_recv_udp_payload_size(1200);
+        config.verify_peer(false);
 
-        let mut server_config = Config::with_boring_ssl_ctx_builder(
-            crate::PROTOCOL_VERSION,
-            server_tls_ctx_builder,
-        )?;
-        let mut client_config = Config::new(crate::PROTOCOL_VERSION)?;
-        client_config.load_cert_chain_from_pem_file("examples/cert.crt")?;
-        client_config.load_priv_key_from_pem_file("examples/cert.key")?;
-
-        for config in [&mut client_config, &mut server_config] {
-            config.set_application_protos(&[b"proto1", b"proto2"])?;
-            config.set_initial_max_data(30);
-            config.set_initial_max_stream_data_bidi_local(15);
-            config.set_initial_max_stream_data_bidi_remote(15);
-            config.set_initial_max_stream_data_uni(10);
-            config.set_initial_max_streams_bidi(3);
-            config.set_initial_max_streams_uni(3);
-            config.set_max_idle_timeout(180_000);
-            config.verify_peer(false);
-            config.set_ack_delay_exponent(8);
-        }
-
-        let mut client_scid = [0; 16];
-        rand::rand_bytes(&mut client_scid[..]);
-        let client_scid = ConnectionId::from_ref(&client_scid);
-        let client_addr = "127.0.0.1:1234".parse().unwrap();
+        let mut pipe = testing::Pipe::with_client_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        let mut server_scid = [0; 16];
-        rand::rand_bytes(&mut server_scid[..]);
-        let server_scid = ConnectionId::from_ref(&server_scid);
-        let server_addr = "127.0.0.1:4321".parse().unwrap();
+        // Client sends stream data.
+        assert_eq!(pipe.client.stream_send(0, b"a", true), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let mut pipe = testing::Pipe {
-            client: connect(
-                Some("quic.tech"),
-                &client_scid,
-                client_addr,
-                server_addr,
-                &mut client_config,
-            )?,
-            server: accept(
-                &server_scid,
-                None,
-                server_addr,
-                client_addr,
-                &mut server_config,
-            )?,
-        };
+        // Server reads stream data.
+        let mut b = [0; 15];
+        pipe.server.stream_recv(0, &mut b).unwrap();
+        assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(pipe.handshake(), Ok(()));
+        // Server sends stream data smaller than cwnd.
+        let send_buf = [0; 10000];
+        assert_eq!(pipe.server.stream_send(0, &send_buf, false), Ok(10000));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        Ok(())
+        // app_limited should be true because we send less than cwnd.
+        assert!(pipe
+            .server
+            .paths
+            .get_active()
+            .expect("no active")
+            .recovery
+            .app_limited());
     }
 
     #[test]
-    /// Tests that resetting a stream restores flow control for unsent data.
-    fn last_tx_data_larger_than_tx_data() {
+    fn app_limited_false() {
         let mut config = Config::new(PROTOCOL_VERSION).unwrap();
         config
             .set_application_protos(&[b"proto1", b"proto2"])
             .unwrap();
-        config.set_initial_max_data(12000);
-        config.set_initial_max_stream_data_bidi_local(20000);
-        config.set_initial_max_stream_data_bidi_remote(20000);
+        config.set_initial_max_data(50000);
+        config.set_initial_max_stream_data_bidi_local(50000);
+        config.set_initial_max_stream_data_bidi_remote(50000);
         config.set_max_recv_udp_payload_size(1200);
         config.verify_peer(false);
 
         let mut pipe = testing::Pipe::with_client_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        // Client opens stream 4 and 8.
-        assert_eq!(pipe.client.stream_send(4, b"a", true), Ok(1));
-        assert_eq!(pipe.client.stream_send(8, b"b", true), Ok(1));
+        // Client sends stream data.
+        assert_eq!(pipe.client.stream_send(0, b"a", true), Ok(1));
         assert_eq!(pipe.advance(), Ok(()));
 
         // Server reads stream data.
         let mut b = [0; 15];
-        pipe.server.stream_recv(4, &mut b).unwrap();
-
-        // Server sends stream data close to cwnd (12000).
-        let buf = [0; 10000];
-        assert_eq!(pipe.server.stream_send(4, &buf, false), Ok(10000));
-
-        testing::emit_flight(&mut pipe.server).unwrap();
-
-        // Server buffers some data, until send capacity limit reached.
-        let mut buf = [0; 1200];
-        assert_eq!(pipe.server.stream_send(4, &buf, false), Ok(1200));
-        assert_eq!(pipe.server.stream_send(8, &buf, false), Ok(800));
-        assert_eq!(pipe.server.stream_send(4, &buf, false), Err(Error::Done));
-
-        // Wait for PTO to expire.
-        let timer = pipe.server.timeout().unwrap();
-        std::thread::sleep(timer + time::Duration::from_millis(1));
-
-        pipe.server.on_timeout();
+        pipe.server.stream_recv(0, &mut b).unwrap();
+        assert_eq!(pipe.advance(), Ok(()));
 
-        // Server sends PTO probe (not limited to cwnd),
-        // to update last_tx_data.
-        let (len, _) = pipe.server.send(&mut buf).unwrap();
-        assert_eq!(len, 1200);
+        // Server sends stream data bigger than cwnd.
+        let send_buf1 = [0; 20000];
+        assert_eq!(pipe.server.stream_send(0, &send_buf1, false), Ok(12000));
 
-        // Client sends STOP_SENDING to decrease tx_data
-        // by unsent data. It will make last_tx_data > tx_data
-        // and trigger #1232 bug.
-        let frames = [frame::Frame::StopSending {
-            stream_id: 4,
-            error_code: 42,
-        }];
+        testing::emit_flight(&mut pipe.server).ok();
 
-        let pkt_type = packet::Type::Short;
-        pipe.send_pkt_to_server(pkt_type, &frames, &mut buf)
-            .unwrap();
+        // We can't create a new packet header because there is no room by cwnd.
+        // app_limited should be false because we can't send more by cwnd.
+        assert!(!pipe
+            .server
+            .paths
+            .get_active()
+            .expect("no active")
+            .recovery
+            .app_limited());
     }
 
-    /// Tests that when the client provides a new ConnectionId, it eventually
-    /// reaches the server and notifies the application.
     #[test]
-    fn send_connection_ids() {
-        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+    fn sends_ack_only_pkt_when_full_cwnd_and_ack_elicited() {
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
         config
             .load_cert_chain_from_pem_file("examples/cert.crt")
             .unwrap();
@@ -14638,57 +21375,63 @@ mod tests {
         config
             .set_application_protos(&[b"proto1", b"proto2"])
             .unwrap();
+        config.set_initial_max_data(50000);
+        config.set_initial_max_stream_data_bidi_local(50000);
+        config.set_initial_max_stream_data_bidi_remote(50000);
+        config.set_initial_max_streams_bidi(3);
+        config.set_initial_max_streams_uni(3);
+        config.set_max_recv_udp_payload_size(1200);
         config.verify_peer(false);
-        config.set_active_connection_id_limit(3);
 
         let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        // So far, there should not have any QUIC event.
-        assert_eq!(pipe.client.path_event_next(), None);
-        assert_eq!(pipe.server.path_event_next(), None);
-        assert_eq!(pipe.client.scids_left(), 2);
+        // Client sends stream data bigger than cwnd (it will never arrive to the
+        // server).
+        let send_buf1 = [0; 20000];
+        assert_eq!(pipe.client.stream_send(0, &send_buf1, false), Ok(12000));
 
-        let (scid, reset_token) = testing::create_cid_and_reset_token(16);
-        assert_eq!(pipe.client.new_scid(&scid, reset_token, false), Ok(1));
+        testing::emit_flight(&mut pipe.client).ok();
 
-        // Let exchange packets over the connection.
-        assert_eq!(pipe.advance(), Ok(()));
+        // Server sends some stream data that will need ACKs.
+        assert_eq!(
+            pipe.server.stream_send(1, &send_buf1[..500], false),
+            Ok(500)
+        );
 
-        // At this point, the server should be notified that it has a new CID.
-        assert_eq!(pipe.server.available_dcids(), 1);
-        assert_eq!(pipe.server.path_event_next(), None);
-        assert_eq!(pipe.client.path_event_next(), None);
-        assert_eq!(pipe.client.scids_left(), 1);
+        testing::process_flight(
+            &mut pipe.client,
+            testing::emit_flight(&mut pipe.server).unwrap(),
+        )
+        .unwrap();
 
-        // Now, a second CID can be provided.
-        let (scid, reset_token) = testing::create_cid_and_reset_token(16);
-        assert_eq!(pipe.client.new_scid(&scid, reset_token, false), Ok(2));
+        let mut buf = [0; 2000];
 
-        // Let exchange packets over the connection.
-        assert_eq!(pipe.advance(), Ok(()));
+        let ret = pipe.client.send(&mut buf);
 
-        // At this point, the server should be notified that it has a new CID.
-        assert_eq!(pipe.server.available_dcids(), 2);
-        assert_eq!(pipe.server.path_event_next(), None);
-        assert_eq!(pipe.client.path_event_next(), None);
-        assert_eq!(pipe.client.scids_left(), 0);
+        assert_eq!(pipe.client.tx_cap, 0);
 
-        // If now the client tries to send another CID, it reports an error
-        // since it exceeds the limit of active CIDs.
-        let (scid, reset_token) = testing::create_cid_and_reset_token(16);
-        assert_eq!(
-            pipe.client.new_scid(&scid, reset_token, false),
-            Err(Error::IdLimit),
+        assert!(matches!(ret, Ok((_, _))), "the client should at least send one packet to acknowledge the newly received data");
+
+        let (sent, _) = ret.unwrap();
+
+        assert_ne!(sent, 0, "the client should at least send a pure ACK packet");
+
+        let frames =
+            testing::decode_pkt(&mut pipe.server, &mut buf[..sent]).unwrap();
+        assert_eq!(1, frames.len());
+        assert!(
+            matches!(frames[0], frame::Frame::ACK { .. }),
+            "the packet sent by the client must be an ACK only packet"
         );
     }
 
+    /// Like sends_ack_only_pkt_when_full_cwnd_and_ack_elicited, but when
+    /// ack_eliciting is explicitly requested.
     #[test]
-    /// Tests that NEW_CONNECTION_ID with zero-length CID are rejected.
-    fn connection_id_zero() {
-        let mut buf = [0; 65535];
-
-        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+    fn sends_ack_only_pkt_when_full_cwnd_and_ack_elicited_despite_max_unacknowledging(
+    ) {
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
         config
             .load_cert_chain_from_pem_file("examples/cert.crt")
             .unwrap();
@@ -14698,278 +21441,257 @@ mod tests {
         config
             .set_application_protos(&[b"proto1", b"proto2"])
             .unwrap();
+        config.set_initial_max_data(50000);
+        config.set_initial_max_stream_data_bidi_local(50000);
+        config.set_initial_max_stream_data_bidi_remote(50000);
+        config.set_initial_max_streams_bidi(3);
+        config.set_initial_max_streams_uni(3);
+        config.set_max_recv_udp_payload_size(1200);
         config.verify_peer(false);
-        config.set_active_connection_id_limit(2);
 
         let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        let mut frames = Vec::new();
+        // Client sends stream data bigger than cwnd (it will never arrive to the
+        // server). This exhausts the congestion window.
+        let send_buf1 = [0; 20000];
+        assert_eq!(pipe.client.stream_send(0, &send_buf1, false), Ok(12000));
 
-        // Client adds a CID that is too short.
-        let (scid, reset_token) = testing::create_cid_and_reset_token(0);
+        testing::emit_flight(&mut pipe.client).ok();
 
-        frames.push(frame::Frame::NewConnectionId {
-            seq_num: 1,
-            retire_prior_to: 0,
-            conn_id: scid.to_vec(),
-            reset_token: reset_token.to_be_bytes(),
-        });
+        // Client gets PING frames from server, which elicit ACK
+        let mut buf = [0; 2000];
+        for _ in 0..recovery::MAX_OUTSTANDING_NON_ACK_ELICITING {
+            let written = testing::encode_pkt(
+                &mut pipe.server,
+                packet::Type::Short,
+                &[frame::Frame::Ping { mtu_probe: None }],
+                &mut buf,
+            )
+            .unwrap();
 
-        let pkt_type = packet::Type::Short;
+            pipe.client_recv(&mut buf[..written])
+                .expect("client recv ping");
 
-        let written =
-            testing::encode_pkt(&mut pipe.client, pkt_type, &frames, &mut buf)
-                .unwrap();
+            // Client acknowledges despite a full congestion window
+            let ret = pipe.client.send(&mut buf);
 
-        let active_path = pipe.server.paths.get_active().unwrap();
-        let info = RecvInfo {
-            to: active_path.local_addr(),
-            from: active_path.peer_addr(),
-        };
+            assert!(matches!(ret, Ok((_, _))), "the client should at least send one packet to acknowledge the newly received data");
 
-        assert_eq!(
-            pipe.server.recv(&mut buf[..written], info),
-            Err(Error::InvalidFrame)
-        );
+            let (sent, _) = ret.unwrap();
 
-        let written = match pipe.server.send(&mut buf) {
-            Ok((write, _)) => write,
+            assert_ne!(
+                sent, 0,
+                "the client should at least send a pure ACK packet"
+            );
 
-            Err(_) => unreachable!(),
-        };
+            let frames =
+                testing::decode_pkt(&mut pipe.server, &mut buf[..sent]).unwrap();
 
-        let frames =
-            testing::decode_pkt(&mut pipe.client, &mut buf[..written]).unwrap();
-        let mut iter = frames.iter();
+            assert_eq!(1, frames.len());
+
+            assert!(
+                matches!(frames[0], frame::Frame::ACK { .. }),
+                "the packet sent by the client must be an ACK only packet"
+            );
+        }
 
+        // The client shouldn't need to send any more packets after the ACK only
+        // packet it just sent.
         assert_eq!(
-            iter.next(),
-            Some(&frame::Frame::ConnectionClose {
-                error_code: 0x7,
-                frame_type: 0,
-                reason: Vec::new(),
-            })
+            pipe.client.send(&mut buf),
+            Err(Error::Done),
+            "nothing for client to send after ACK-only packet"
         );
     }
 
     #[test]
-    /// Tests that NEW_CONNECTION_ID with too long CID are rejected.
-    fn connection_id_invalid_max_len() {
-        let mut buf = [0; 65535];
-
-        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
-        config
-            .load_cert_chain_from_pem_file("examples/cert.crt")
-            .unwrap();
-        config
-            .load_priv_key_from_pem_file("examples/cert.key")
-            .unwrap();
+    fn app_limited_false_no_frame() {
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
         config
             .set_application_protos(&[b"proto1", b"proto2"])
             .unwrap();
+        config.set_initial_max_data(50000);
+        config.set_initial_max_stream_data_bidi_local(50000);
+        config.set_initial_max_stream_data_bidi_remote(50000);
+        config.set_max_recv_udp_payload_size(1405);
         config.verify_peer(false);
-        config.set_active_connection_id_limit(2);
 
-        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        let mut pipe = testing::Pipe::with_client_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        let mut frames = Vec::new();
-
-        // Client adds a CID that is too long.
-        let (scid, reset_token) =
-            testing::create_cid_and_reset_token(MAX_CONN_ID_LEN + 1);
-
-        frames.push(frame::Frame::NewConnectionId {
-            seq_num: 1,
-            retire_prior_to: 0,
-            conn_id: scid.to_vec(),
-            reset_token: reset_token.to_be_bytes(),
-        });
-
-        let pkt_type = packet::Type::Short;
-
-        let written =
-            testing::encode_pkt(&mut pipe.client, pkt_type, &frames, &mut buf)
-                .unwrap();
-
-        let active_path = pipe.server.paths.get_active().unwrap();
-        let info = RecvInfo {
-            to: active_path.local_addr(),
-            from: active_path.peer_addr(),
-        };
-
-        assert_eq!(
-            pipe.server.recv(&mut buf[..written], info),
-            Err(Error::InvalidFrame)
-        );
+        // Client sends stream data.
+        assert_eq!(pipe.client.stream_send(0, b"a", true), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let written = match pipe.server.send(&mut buf) {
-            Ok((write, _)) => write,
+        // Server reads stream data.
+        let mut b = [0; 15];
+        pipe.server.stream_recv(0, &mut b).unwrap();
+        assert_eq!(pipe.advance(), Ok(()));
 
-            Err(_) => unreachable!(),
-        };
+        // Server sends stream data bigger than cwnd.
+        let send_buf1 = [0; 20000];
+        assert_eq!(pipe.server.stream_send(0, &send_buf1, false), Ok(12000));
 
-        let frames =
-            testing::decode_pkt(&mut pipe.client, &mut buf[..written]).unwrap();
-        let mut iter = frames.iter();
+        testing::emit_flight(&mut pipe.server).ok();
 
-        assert_eq!(
-            iter.next(),
-            Some(&frame::Frame::ConnectionClose {
-                error_code: 0x7,
-                frame_type: 0,
-                reason: Vec::new(),
-            })
-        );
+        // We can't create a new packet header because there is no room by cwnd.
+        // app_limited should be false because we can't send more by cwnd.
+        assert!(!pipe
+            .server
+            .paths
+            .get_active()
+            .expect("no active")
+            .recovery
+            .app_limited());
     }
 
     #[test]
-    /// Exercises the handling of NEW_CONNECTION_ID and RETIRE_CONNECTION_ID
-    /// frames.
-    fn connection_id_handling() {
-        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
-        config
-            .load_cert_chain_from_pem_file("examples/cert.crt")
-            .unwrap();
-        config
-            .load_priv_key_from_pem_file("examples/cert.key")
-            .unwrap();
+    fn app_limited_false_no_header() {
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
         config
             .set_application_protos(&[b"proto1", b"proto2"])
             .unwrap();
+        config.set_initial_max_data(50000);
+        config.set_initial_max_stream_data_bidi_local(50000);
+        config.set_initial_max_stream_data_bidi_remote(50000);
+        config.set_max_recv_udp_payload_size(1406);
         config.verify_peer(false);
-        config.set_active_connection_id_limit(2);
 
-        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        let mut pipe = testing::Pipe::with_client_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        // So far, there should not have any QUIC event.
-        assert_eq!(pipe.client.path_event_next(), None);
-        assert_eq!(pipe.server.path_event_next(), None);
-        assert_eq!(pipe.client.scids_left(), 1);
+        // Client sends stream data.
+        assert_eq!(pipe.client.stream_send(0, b"a", true), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let scid = pipe.client.source_id().into_owned();
+        // Server reads stream data.
+        let mut b = [0; 15];
+        pipe.server.stream_recv(0, &mut b).unwrap();
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let (scid_1, reset_token_1) = testing::create_cid_and_reset_token(16);
-        assert_eq!(pipe.client.new_scid(&scid_1, reset_token_1, false), Ok(1));
+        // Server sends stream data bigger than cwnd.
+        let send_buf1 = [0; 20000];
+        assert_eq!(pipe.server.stream_send(0, &send_buf1, false), Ok(12000));
 
-        // Let exchange packets over the connection.
-        assert_eq!(pipe.advance(), Ok(()));
+        testing::emit_flight(&mut pipe.server).ok();
 
-        // At this point, the server should be notified that it has a new CID.
-        assert_eq!(pipe.server.available_dcids(), 1);
-        assert_eq!(pipe.server.path_event_next(), None);
-        assert_eq!(pipe.client.path_event_next(), None);
-        assert_eq!(pipe.client.scids_left(), 0);
+        // We can't create a new frame because there is no room by cwnd.
+        // app_limited should be false because we can't send more by cwnd.
+        assert!(!pipe
+            .server
+            .paths
+            .get_active()
+            .expect("no active")
+            .recovery
+            .app_limited());
+    }
 
-        // Now we assume that the client wants to advertise more source
-        // Connection IDs than the advertised limit. This is valid if it
-        // requests its peer to retire enough Connection IDs to fit within the
-        // limits.
+    #[test]
+    fn app_limited_not_changed_on_no_new_frames() {
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.set_initial_max_data(50000);
+        config.set_initial_max_stream_data_bidi_local(50000);
+        config.set_initial_max_stream_data_bidi_remote(50000);
+        config.set_max_recv_udp_payload_size(1200);
+        config.verify_peer(false);
 
-        let (scid_2, reset_token_2) = testing::create_cid_and_reset_token(16);
-        assert_eq!(pipe.client.new_scid(&scid_2, reset_token_2, true), Ok(2));
+        let mut pipe = testing::Pipe::with_client_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        // Let exchange packets over the connection.
+        // Client sends stream data.
+        assert_eq!(pipe.client.stream_send(0, b"a", true), Ok(1));
         assert_eq!(pipe.advance(), Ok(()));
 
-        // At this point, the server still have a spare DCID.
-        assert_eq!(pipe.server.available_dcids(), 1);
-        assert_eq!(pipe.server.path_event_next(), None);
-
-        // Client should have received a retired notification.
-        assert_eq!(pipe.client.retired_scid_next(), Some(scid));
-        assert_eq!(pipe.client.retired_scid_next(), None);
-
-        assert_eq!(pipe.client.path_event_next(), None);
-        assert_eq!(pipe.client.scids_left(), 0);
-
-        // The active Destination Connection ID of the server should now be the
-        // one with sequence number 1.
-        assert_eq!(pipe.server.destination_id(), scid_1);
-
-        // Now tries to experience CID retirement. If the server tries to remove
-        // non-existing DCIDs, it fails.
-        assert_eq!(pipe.server.retire_dcid(0), Err(Error::InvalidState));
-        assert_eq!(pipe.server.retire_dcid(3), Err(Error::InvalidState));
-
-        // Now it removes DCID with sequence 1.
-        assert_eq!(pipe.server.retire_dcid(1), Ok(()));
-
-        // Let exchange packets over the connection.
+        // Server reads stream data.
+        let mut b = [0; 15];
+        pipe.server.stream_recv(0, &mut b).unwrap();
         assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(pipe.server.path_event_next(), None);
-        assert_eq!(pipe.client.retired_scid_next(), Some(scid_1));
-        assert_eq!(pipe.client.retired_scid_next(), None);
+        // Client's app_limited is true because its bytes-in-flight
+        // is much smaller than the current cwnd.
+        assert!(pipe
+            .client
+            .paths
+            .get_active()
+            .expect("no active")
+            .recovery
+            .app_limited());
 
-        assert_eq!(pipe.server.destination_id(), scid_2);
-        assert_eq!(pipe.server.available_dcids(), 0);
+        // Client has no new frames to send - returns Done.
+        assert_eq!(testing::emit_flight(&mut pipe.client), Err(Error::Done));
 
-        // Trying to remove the last DCID triggers an error.
-        assert_eq!(pipe.server.retire_dcid(2), Err(Error::OutOfIdentifiers));
+        // Client's app_limited should remain the same.
+        assert!(pipe
+            .client
+            .paths
+            .get_active()
+            .expect("no active")
+            .recovery
+            .app_limited());
     }
 
     #[test]
-    fn lost_connection_id_frames() {
-        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
-        config
-            .load_cert_chain_from_pem_file("examples/cert.crt")
-            .unwrap();
-        config
-            .load_priv_key_from_pem_file("examples/cert.key")
-            .unwrap();
-        config
-            .set_application_protos(&[b"proto1", b"proto2"])
-            .unwrap();
-        config.verify_peer(false);
-        config.set_active_connection_id_limit(2);
+    fn limit_ack_ranges() {
+        let mut buf = [0; 65535];
 
-        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        let mut pipe = testing::Pipe::new().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        let scid = pipe.client.source_id().into_owned();
+        let epoch = packet::Epoch::Application;
 
-        let (scid_1, reset_token_1) = testing::create_cid_and_reset_token(16);
-        assert_eq!(pipe.client.new_scid(&scid_1, reset_token_1, false), Ok(1));
+        assert_eq!(pipe.server.pkt_num_spaces[epoch].recv_pkt_need_ack.len(), 0);
 
-        // Packets are sent, but never received.
-        testing::emit_flight(&mut pipe.client).unwrap();
+        let frames = [
+            frame::Frame::Ping { mtu_probe: None },
+            frame::Frame::Padding { len: 3 },
+        ];
 
-        // Wait until timer expires. Since the RTT is very low, wait a bit more.
-        let timer = pipe.client.timeout().unwrap();
-        std::thread::sleep(timer + time::Duration::from_millis(1));
+        let pkt_type = packet::Type::Short;
 
-        pipe.client.on_timeout();
+        let mut last_packet_sent = 0;
 
-        // Let exchange packets over the connection.
-        assert_eq!(pipe.advance(), Ok(()));
+        for _ in 0..512 {
+            let recv_count = pipe.server.recv_count;
 
-        // At this point, the server should be notified that it has a new CID.
-        assert_eq!(pipe.server.available_dcids(), 1);
+            last_packet_sent = pipe.client.pkt_num_spaces[epoch].next_pkt_num;
 
-        // Now the server retires the first Destination CID.
-        assert_eq!(pipe.server.retire_dcid(0), Ok(()));
+            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf)
+                .unwrap();
 
-        // But the packet never reaches the client.
-        testing::emit_flight(&mut pipe.server).unwrap();
+            assert_eq!(pipe.server.recv_count, recv_count + 1);
 
-        // Wait until timer expires. Since the RTT is very low, wait a bit more.
-        let timer = pipe.server.timeout().unwrap();
-        std::thread::sleep(timer + time::Duration::from_millis(1));
+            // Skip packet number.
+            pipe.client.pkt_num_spaces[epoch].next_pkt_num += 1;
+        }
 
-        pipe.server.on_timeout();
+        assert_eq!(
+            pipe.server.pkt_num_spaces[epoch].recv_pkt_need_ack.len(),
+            MAX_ACK_RANGES
+        );
 
-        // Let exchange packets over the connection.
-        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(
+            pipe.server.pkt_num_spaces[epoch].recv_pkt_need_ack.first(),
+            Some(last_packet_sent - ((MAX_ACK_RANGES as u64) - 1) * 2)
+        );
 
-        assert_eq!(pipe.client.retired_scid_next(), Some(scid));
-        assert_eq!(pipe.client.retired_scid_next(), None);
+        assert_eq!(
+            pipe.server.pkt_num_spaces[epoch].recv_pkt_need_ack.last(),
+            Some(last_packet_sent)
+        );
     }
 
     #[test]
-    fn sending_duplicate_scids() {
+    /// Tests that streams are correctly scheduled based on their priority.
+    fn stream_priority() {
+        // Limit 1-RTT packet size to avoid congestion control interference.
+        const MAX_TEST_PACKET_SIZE: usize = 540;
+
+        let mut buf = [0; 65535];
+
         let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
         config
             .load_cert_chain_from_pem_file("examples/cert.crt")
@@ -14980,100 +21702,223 @@ mod tests {
         config
             .set_application_protos(&[b"proto1", b"proto2"])
             .unwrap();
+        config.set_initial_max_data(1_000_000);
+        config.set_initial_max_stream_data_bidi_local(1_000_000);
+        config.set_initial_max_stream_data_bidi_remote(1_000_000);
+        config.set_initial_max_stream_data_uni(0);
+        config.set_initial_max_streams_bidi(100);
+        config.set_initial_max_streams_uni(0);
         config.verify_peer(false);
-        config.set_active_connection_id_limit(3);
 
         let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        let (scid_1, reset_token_1) = testing::create_cid_and_reset_token(16);
-        assert_eq!(pipe.client.new_scid(&scid_1, reset_token_1, false), Ok(1));
+        assert_eq!(pipe.client.stream_send(0, b"a", false), Ok(1));
         assert_eq!(pipe.advance(), Ok(()));
 
-        // Trying to send the same CID with a different reset token raises an
-        // InvalidState error.
-        let reset_token_2 = reset_token_1.wrapping_add(1);
-        assert_eq!(
-            pipe.client.new_scid(&scid_1, reset_token_2, false),
-            Err(Error::InvalidState),
-        );
-
-        // Retrying to send the exact same CID with the same token returns the
-        // previously assigned CID seq, but without sending anything.
-        assert_eq!(pipe.client.new_scid(&scid_1, reset_token_1, false), Ok(1));
-        assert!(!pipe.client.ids.has_new_scids());
+        assert_eq!(pipe.client.stream_send(4, b"a", false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        // Now retire this new CID.
-        assert_eq!(pipe.server.retire_dcid(1), Ok(()));
+        assert_eq!(pipe.client.stream_send(8, b"a", false), Ok(1));
         assert_eq!(pipe.advance(), Ok(()));
 
-        // It is up to the application to ensure that a given SCID is not reused
-        // later.
-        assert_eq!(pipe.client.new_scid(&scid_1, reset_token_1, false), Ok(2));
-    }
+        assert_eq!(pipe.client.stream_send(12, b"a", false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-    // Utility function.
-    fn pipe_with_exchanged_cids(
-        config: &mut Config, client_scid_len: usize, server_scid_len: usize,
-        additional_cids: usize,
-    ) -> testing::Pipe {
-        let mut pipe = testing::Pipe::with_config_and_scid_lengths(
-            config,
-            client_scid_len,
-            server_scid_len,
-        )
-        .unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+        assert_eq!(pipe.client.stream_send(16, b"a", false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let mut c_cids = Vec::new();
-        let mut c_reset_tokens = Vec::new();
-        let mut s_cids = Vec::new();
-        let mut s_reset_tokens = Vec::new();
+        assert_eq!(pipe.client.stream_send(20, b"a", false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        for i in 0..additional_cids {
-            if client_scid_len > 0 {
-                let (c_cid, c_reset_token) =
-                    testing::create_cid_and_reset_token(client_scid_len);
-                c_cids.push(c_cid);
-                c_reset_tokens.push(c_reset_token);
+        let mut b = [0; 1];
 
-                assert_eq!(
-                    pipe.client.new_scid(&c_cids[i], c_reset_tokens[i], true),
-                    Ok(i as u64 + 1)
-                );
-            }
+        let out = [b'b'; 500];
 
-            if server_scid_len > 0 {
-                let (s_cid, s_reset_token) =
-                    testing::create_cid_and_reset_token(server_scid_len);
-                s_cids.push(s_cid);
-                s_reset_tokens.push(s_reset_token);
-                assert_eq!(
-                    pipe.server.new_scid(&s_cids[i], s_reset_tokens[i], true),
-                    Ok(i as u64 + 1)
-                );
-            }
-        }
+        // Server prioritizes streams as follows:
+        //  * Stream 8 and 16 have the same priority but are non-incremental.
+        //  * Stream 4, 12 and 20 have the same priority but 20 is non-incremental
+        //    and 4 and 12 are incremental.
+        //  * Stream 0 is on its own.
 
-        // Let exchange packets over the connection.
-        assert_eq!(pipe.advance(), Ok(()));
+        pipe.server.stream_recv(0, &mut b).unwrap();
+        assert_eq!(pipe.server.stream_priority(0, 255, true), Ok(()));
+        pipe.server.stream_send(0, &out, false).unwrap();
+        pipe.server.stream_send(0, &out, false).unwrap();
+        pipe.server.stream_send(0, &out, false).unwrap();
 
-        if client_scid_len > 0 {
-            assert_eq!(pipe.server.available_dcids(), additional_cids);
+        pipe.server.stream_recv(12, &mut b).unwrap();
+        assert_eq!(pipe.server.stream_priority(12, 42, true), Ok(()));
+        pipe.server.stream_send(12, &out, false).unwrap();
+        pipe.server.stream_send(12, &out, false).unwrap();
+        pipe.server.stream_send(12, &out, false).unwrap();
+
+        pipe.server.stream_recv(16, &mut b).unwrap();
+        assert_eq!(pipe.server.stream_priority(16, 10, false), Ok(()));
+        pipe.server.stream_send(16, &out, false).unwrap();
+        pipe.server.stream_send(16, &out, false).unwrap();
+        pipe.server.stream_send(16, &out, false).unwrap();
+
+        pipe.server.stream_recv(4, &mut b).unwrap();
+        assert_eq!(pipe.server.stream_priority(4, 42, true), Ok(()));
+        pipe.server.stream_send(4, &out, false).unwrap();
+        pipe.server.stream_send(4, &out, false).unwrap();
+        pipe.server.stream_send(4, &out, false).unwrap();
+
+        pipe.server.stream_recv(8, &mut b).unwrap();
+        assert_eq!(pipe.server.stream_priority(8, 10, false), Ok(()));
+        pipe.server.stream_send(8, &out, false).unwrap();
+        pipe.server.stream_send(8, &out, false).unwrap();
+        pipe.server.stream_send(8, &out, false).unwrap();
+
+        pipe.server.stream_recv(20, &mut b).unwrap();
+        assert_eq!(pipe.server.stream_priority(20, 42, false), Ok(()));
+        pipe.server.stream_send(20, &out, false).unwrap();
+        pipe.server.stream_send(20, &out, false).unwrap();
+        pipe.server.stream_send(20, &out, false).unwrap();
+
+        // First is stream 8.
+        let mut off = 0;
+
+        for _ in 1..=3 {
+            let (len, _) =
+                pipe.server.send(&mut buf[..MAX_TEST_PACKET_SIZE]).unwrap();
+
+            let frames =
+                testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
+            let stream = frames.first().unwrap();
+
+            assert_eq!(stream, &frame::Frame::Stream {
+                stream_id: 8,
+                data: stream::RangeBuf::from(&out, off, false),
+            });
+
+            off = match stream {
+                frame::Frame::Stream { data, .. } => data.max_off(),
+
+                _ => unreachable!(),
+            };
         }
 
-        if server_scid_len > 0 {
-            assert_eq!(pipe.client.available_dcids(), additional_cids);
+        // Then is stream 16.
+        let mut off = 0;
+
+        for _ in 1..=3 {
+            let (len, _) =
+                pipe.server.send(&mut buf[..MAX_TEST_PACKET_SIZE]).unwrap();
+
+            let frames =
+                testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
+            let stream = frames.first().unwrap();
+
+            assert_eq!(stream, &frame::Frame::Stream {
+                stream_id: 16,
+                data: stream::RangeBuf::from(&out, off, false),
+            });
+
+            off = match stream {
+                frame::Frame::Stream { data, .. } => data.max_off(),
+
+                _ => unreachable!(),
+            };
         }
 
-        assert_eq!(pipe.server.path_event_next(), None);
-        assert_eq!(pipe.client.path_event_next(), None);
+        // Then is stream 20.
+        let mut off = 0;
 
-        pipe
+        for _ in 1..=3 {
+            let (len, _) =
+                pipe.server.send(&mut buf[..MAX_TEST_PACKET_SIZE]).unwrap();
+
+            let frames =
+                testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
+            let stream = frames.first().unwrap();
+
+            assert_eq!(stream, &frame::Frame::Stream {
+                stream_id: 20,
+                data: stream::RangeBuf::from(&out, off, false),
+            });
+
+            off = match stream {
+                frame::Frame::Stream { data, .. } => data.max_off(),
+
+                _ => unreachable!(),
+            };
+        }
+
+        // Then are stream 12 and 4, with the same priority, incrementally.
+        let mut off = 0;
+
+        for _ in 1..=3 {
+            let (len, _) =
+                pipe.server.send(&mut buf[..MAX_TEST_PACKET_SIZE]).unwrap();
+
+            let frames =
+                testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
+
+            assert_eq!(
+                frames.first(),
+                Some(&frame::Frame::Stream {
+                    stream_id: 12,
+                    data: stream::RangeBuf::from(&out, off, false),
+                })
+            );
+
+            let (len, _) =
+                pipe.server.send(&mut buf[..MAX_TEST_PACKET_SIZE]).unwrap();
+
+            let frames =
+                testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
+
+            let stream = frames.first().unwrap();
+
+            assert_eq!(stream, &frame::Frame::Stream {
+                stream_id: 4,
+                data: stream::RangeBuf::from(&out, off, false),
+            });
+
+            off = match stream {
+                frame::Frame::Stream { data, .. } => data.max_off(),
+
+                _ => unreachable!(),
+            };
+        }
+
+        // Final is stream 0.
+        let mut off = 0;
+
+        for _ in 1..=3 {
+            let (len, _) =
+                pipe.server.send(&mut buf[..MAX_TEST_PACKET_SIZE]).unwrap();
+
+            let frames =
+                testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
+            let stream = frames.first().unwrap();
+
+            assert_eq!(stream, &frame::Frame::Stream {
+                stream_id: 0,
+                data: stream::RangeBuf::from(&out, off, false),
+            });
+
+            off = match stream {
+                frame::Frame::Stream { data, .. } => data.max_off(),
+
+                _ => unreachable!(),
+            };
+        }
+
+        assert_eq!(pipe.server.send(&mut buf), Err(Error::Done));
     }
 
     #[test]
-    fn path_validation() {
+    /// Tests that incremental streams with the same urgency are not starved,
+    /// even when the application keeps writing to one of them.
+    fn stream_priority_incremental_fairness() {
+        // Limit 1-RTT packet size to avoid congestion control interference.
+        const MAX_TEST_PACKET_SIZE: usize = 540;
+
+        let mut buf = [0; 65535];
+
         let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
         config
             .load_cert_chain_from_pem_file("examples/cert.crt")
@@ -15084,81 +21929,99 @@ mod tests {
         config
             .set_application_protos(&[b"proto1", b"proto2"])
             .unwrap();
+        config.set_initial_max_data(1_000_000);
+        config.set_initial_max_stream_data_bidi_local(1_000_000);
+        config.set_initial_max_stream_data_bidi_remote(1_000_000);
+        config.set_initial_max_streams_bidi(100);
         config.verify_peer(false);
-        config.set_active_connection_id_limit(2);
 
         let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        let server_addr = testing::Pipe::server_addr();
-        let client_addr_2 = "127.0.0.1:5678".parse().unwrap();
+        for id in [0, 4, 8, 12] {
+            assert_eq!(pipe.client.stream_send(id, b"a", false), Ok(1));
+            assert_eq!(pipe.advance(), Ok(()));
+        }
 
-        // We cannot probe a new path if there are not enough identifiers.
-        assert_eq!(
-            pipe.client.probe_path(client_addr_2, server_addr),
-            Err(Error::OutOfIdentifiers)
-        );
+        let mut b = [0; 1];
 
-        let (c_cid, c_reset_token) = testing::create_cid_and_reset_token(16);
+        let out = [b'b'; 1000];
 
-        assert_eq!(pipe.client.new_scid(&c_cid, c_reset_token, true), Ok(1));
+        for (id, urgency) in [(0, 3), (4, 3), (8, 3), (12, 5)] {
+            pipe.server.stream_recv(id, &mut b).unwrap();
+            assert_eq!(pipe.server.stream_priority(id, urgency, true), Ok(()));
+            assert_eq!(pipe.server.stream_send(id, &out, false), Ok(1000));
+        }
 
-        let (s_cid, s_reset_token) = testing::create_cid_and_reset_token(16);
-        assert_eq!(pipe.server.new_scid(&s_cid, s_reset_token, true), Ok(1));
+        let mut sent = Vec::new();
 
-        // We need to exchange the CIDs first.
-        assert_eq!(
-            pipe.client.probe_path(client_addr_2, server_addr),
-            Err(Error::OutOfIdentifiers)
-        );
+        loop {
+            let len = match pipe.server.send(&mut buf[..MAX_TEST_PACKET_SIZE]) {
+                Ok((len, _)) => len,
 
-        // Let exchange packets over the connection.
-        assert_eq!(pipe.advance(), Ok(()));
+                Err(Error::Done) => break,
 
-        assert_eq!(pipe.server.available_dcids(), 1);
-        assert_eq!(pipe.server.path_event_next(), None);
-        assert_eq!(pipe.client.available_dcids(), 1);
-        assert_eq!(pipe.client.path_event_next(), None);
+                Err(e) => panic!("{:?}", e),
+            };
 
-        // Now the path probing can work.
-        assert_eq!(pipe.client.probe_path(client_addr_2, server_addr), Ok(1));
+            let frames =
+                testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
 
-        // But the server cannot probe a yet-unseen path.
-        assert_eq!(
-            pipe.server.probe_path(server_addr, client_addr_2),
-            Err(Error::InvalidState),
-        );
+            for f in frames {
+                if let frame::Frame::Stream { stream_id, .. } = f {
+                    sent.push(stream_id);
+                }
+            }
+        }
 
-        assert_eq!(pipe.advance(), Ok(()));
+        // The streams with the same urgency take turns...
+        let urgent = sent.iter().take_while(|&&id| id != 12).count();
+        assert!(urgent > 3);
+        assert_eq!(urgent % 3, 0);
 
-        // The path should be validated at some point.
-        assert_eq!(
-            pipe.client.path_event_next(),
-            Some(PathEvent::Validated(client_addr_2, server_addr)),
-        );
-        assert_eq!(pipe.client.path_event_next(), None);
+        for turn in sent[..urgent].chunks(3) {
+            let mut turn = turn.to_vec();
+            turn.sort_unstable();
 
-        // The server should be notified of this new path.
-        assert_eq!(
-            pipe.server.path_event_next(),
-            Some(PathEvent::New(server_addr, client_addr_2)),
-        );
-        assert_eq!(
-            pipe.server.path_event_next(),
-            Some(PathEvent::Validated(server_addr, client_addr_2)),
-        );
-        assert_eq!(pipe.server.path_event_next(), None);
+            assert_eq!(turn, vec![0, 4, 8]);
+        }
 
-        // The server can later probe the path again.
-        assert_eq!(pipe.server.probe_path(server_addr, client_addr_2), Ok(1));
+        // ...and the less urgent stream is sent once they are done.
+        assert!(sent[urgent..].iter().all(|&id| id == 12));
+        assert!(sent.len() > urgent);
 
-        // This should not trigger any event at client side.
-        assert_eq!(pipe.client.path_event_next(), None);
-        assert_eq!(pipe.server.path_event_next(), None);
+        // Writing more data to an incremental stream after each packet
+        // doesn't prevent the other stream from being sent.
+        assert_eq!(pipe.server.stream_send(0, &out, false), Ok(1000));
+        assert_eq!(pipe.server.stream_send(4, &out, false), Ok(1000));
+
+        let mut sent = Vec::new();
+
+        for _ in 0..4 {
+            let (len, _) =
+                pipe.server.send(&mut buf[..MAX_TEST_PACKET_SIZE]).unwrap();
+
+            let frames =
+                testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
+
+            for f in frames {
+                if let frame::Frame::Stream { stream_id, .. } = f {
+                    sent.push(stream_id);
+                }
+            }
+
+            assert_eq!(pipe.server.stream_send(0, &out[..100], false), Ok(100));
+        }
+
+        assert_eq!(sent.iter().filter(|&&id| id == 4).count(), 2);
+        assert_eq!(sent.iter().filter(|&&id| id == 0).count(), 2);
     }
 
     #[test]
-    fn losing_probing_packets() {
+    /// Tests that changing a stream's priority is correctly propagated.
+    fn stream_reprioritize() {
+        let mut buf = [0; 65535];
+
         let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
         config
             .load_cert_chain_from_pem_file("examples/cert.crt")
@@ -15169,113 +22032,173 @@ mod tests {
         config
             .set_application_protos(&[b"proto1", b"proto2"])
             .unwrap();
-        config.verify_peer(false);
-        config.set_active_connection_id_limit(2);
-
-        let mut pipe = pipe_with_exchanged_cids(&mut config, 16, 16, 1);
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_stream_data_uni(0);
+        config.set_initial_max_streams_bidi(5);
+        config.set_initial_max_streams_uni(0);
+        config.verify_peer(false);
 
-        let server_addr = testing::Pipe::server_addr();
-        let client_addr_2 = "127.0.0.1:5678".parse().unwrap();
-        assert_eq!(pipe.client.probe_path(client_addr_2, server_addr), Ok(1));
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        // The client creates the PATH CHALLENGE, but it is lost.
-        testing::emit_flight(&mut pipe.client).unwrap();
+        assert_eq!(pipe.client.stream_send(0, b"a", false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        // Wait until probing timer expires. Since the RTT is very low,
-        // wait a bit more.
-        let probed_pid = pipe
-            .client
-            .paths
-            .path_id_from_addrs(&(client_addr_2, server_addr))
-            .unwrap();
-        let probe_instant = pipe
-            .client
-            .paths
-            .get(probed_pid)
-            .unwrap()
-            .recovery
-            .loss_detection_timer()
-            .unwrap();
-        let timer = probe_instant.duration_since(time::Instant::now());
-        std::thread::sleep(timer + time::Duration::from_millis(1));
+        assert_eq!(pipe.client.stream_send(4, b"a", false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        pipe.client.on_timeout();
+        assert_eq!(pipe.client.stream_send(8, b"a", false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
+        assert_eq!(pipe.client.stream_send(12, b"a", false), Ok(1));
         assert_eq!(pipe.advance(), Ok(()));
 
-        // The path should be validated at some point.
+        let mut b = [0; 1];
+
+        pipe.server.stream_recv(0, &mut b).unwrap();
+        assert_eq!(pipe.server.stream_priority(0, 255, true), Ok(()));
+        pipe.server.stream_send(0, b"b", false).unwrap();
+
+        pipe.server.stream_recv(12, &mut b).unwrap();
+        assert_eq!(pipe.server.stream_priority(12, 42, true), Ok(()));
+        pipe.server.stream_send(12, b"b", false).unwrap();
+
+        pipe.server.stream_recv(8, &mut b).unwrap();
+        assert_eq!(pipe.server.stream_priority(8, 10, true), Ok(()));
+        pipe.server.stream_send(8, b"b", false).unwrap();
+
+        pipe.server.stream_recv(4, &mut b).unwrap();
+        assert_eq!(pipe.server.stream_priority(4, 42, true), Ok(()));
+        pipe.server.stream_send(4, b"b", false).unwrap();
+
+        // Stream 0 is re-prioritized!!!
+        assert_eq!(pipe.server.stream_priority(0, 20, true), Ok(()));
+
+        // First is stream 8.
+        let (len, _) = pipe.server.send(&mut buf).unwrap();
+
+        let frames =
+            testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
+
         assert_eq!(
-            pipe.client.path_event_next(),
-            Some(PathEvent::Validated(client_addr_2, server_addr))
+            frames.first(),
+            Some(&frame::Frame::Stream {
+                stream_id: 8,
+                data: stream::RangeBuf::from(b"b", 0, false),
+            })
         );
-        assert_eq!(pipe.client.path_event_next(), None);
+
+        // Then is stream 0.
+        let (len, _) = pipe.server.send(&mut buf).unwrap();
+
+        let frames =
+            testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
 
         assert_eq!(
-            pipe.server.path_event_next(),
-            Some(PathEvent::New(server_addr, client_addr_2))
+            frames.first(),
+            Some(&frame::Frame::Stream {
+                stream_id: 0,
+                data: stream::RangeBuf::from(b"b", 0, false),
+            })
         );
-        // The path should be validated at some point.
+
+        // Then are stream 12 and 4, with the same priority.
+        let (len, _) = pipe.server.send(&mut buf).unwrap();
+
+        let frames =
+            testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
+
         assert_eq!(
-            pipe.server.path_event_next(),
-            Some(PathEvent::Validated(server_addr, client_addr_2))
+            frames.first(),
+            Some(&frame::Frame::Stream {
+                stream_id: 12,
+                data: stream::RangeBuf::from(b"b", 0, false),
+            })
         );
-        assert_eq!(pipe.server.path_event_next(), None);
+
+        let (len, _) = pipe.server.send(&mut buf).unwrap();
+
+        let frames =
+            testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
+
+        assert_eq!(
+            frames.first(),
+            Some(&frame::Frame::Stream {
+                stream_id: 4,
+                data: stream::RangeBuf::from(b"b", 0, false),
+            })
+        );
+
+        assert_eq!(pipe.server.send(&mut buf), Err(Error::Done));
     }
 
     #[test]
-    fn failed_path_validation() {
-        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
-        config
-            .load_cert_chain_from_pem_file("examples/cert.crt")
-            .unwrap();
-        config
-            .load_priv_key_from_pem_file("examples/cert.key")
-            .unwrap();
-        config
-            .set_application_protos(&[b"proto1", b"proto2"])
-            .unwrap();
-        config.verify_peer(false);
-        config.set_active_connection_id_limit(2);
+    fn stream_retransmission_priority() {
+        let mut buf = [0; 65535];
 
-        let mut pipe = pipe_with_exchanged_cids(&mut config, 16, 16, 1);
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        let server_addr = testing::Pipe::server_addr();
-        let client_addr_2 = "127.0.0.1:5678".parse().unwrap();
-        assert_eq!(pipe.client.probe_path(client_addr_2, server_addr), Ok(1));
+        assert_eq!(
+            pipe.client.stream_set_retransmission_priority(3, false),
+            Err(Error::InvalidStreamState(3))
+        );
 
-        for _ in 0..MAX_PROBING_TIMEOUTS {
-            // The client creates the PATH CHALLENGE, but it is always lost.
-            testing::emit_flight(&mut pipe.client).unwrap();
+        assert_eq!(
+            pipe.client.stream_set_retransmission_priority(0, false),
+            Ok(())
+        );
 
-            // Wait until probing timer expires. Since the RTT is very low,
-            // wait a bit more.
-            let probed_pid = pipe
-                .client
-                .paths
-                .path_id_from_addrs(&(client_addr_2, server_addr))
-                .unwrap();
-            let probe_instant = pipe
-                .client
-                .paths
-                .get(probed_pid)
-                .unwrap()
-                .recovery
-                .loss_detection_timer()
-                .unwrap();
-            let timer = probe_instant.duration_since(time::Instant::now());
-            std::thread::sleep(timer + time::Duration::from_millis(1));
+        assert_eq!(pipe.client.stream_send(0, b"aaaaa", false), Ok(5));
 
-            pipe.client.on_timeout();
-        }
+        // The packet carrying the data is lost.
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert!(len > 0);
+
+        assert_eq!(pipe.client.stream_send(0, b"bbbbb", false), Ok(5));
+
+        pipe.client
+            .streams
+            .get_mut(0)
+            .unwrap()
+            .send
+            .retransmit(0, 5);
+
+        // New data is sent first.
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+
+        let frames =
+            testing::decode_pkt(&mut pipe.server, &mut buf[..len]).unwrap();
 
         assert_eq!(
-            pipe.client.path_event_next(),
-            Some(PathEvent::FailedValidation(client_addr_2, server_addr)),
+            frames.first(),
+            Some(&frame::Frame::Stream {
+                stream_id: 0,
+                data: stream::RangeBuf::from(b"bbbbb", 5, false),
+            })
+        );
+
+        // Then lost data is retransmitted.
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+
+        let frames =
+            testing::decode_pkt(&mut pipe.server, &mut buf[..len]).unwrap();
+
+        assert_eq!(
+            frames.first(),
+            Some(&frame::Frame::Stream {
+                stream_id: 0,
+                data: stream::RangeBuf::from(b"aaaaa", 0, false),
+            })
         );
     }
 
     #[test]
-    fn client_discard_unknown_address() {
+    fn stream_group_budget() {
+        let mut buf = [0; 65535];
+
         let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
         config
             .load_cert_chain_from_pem_file("examples/cert.crt")
@@ -15286,29 +22209,54 @@ mod tests {
         config
             .set_application_protos(&[b"proto1", b"proto2"])
             .unwrap();
+        config.set_initial_max_data(1_000_000);
+        config.set_initial_max_stream_data_bidi_local(1_000_000);
+        config.set_initial_max_stream_data_bidi_remote(1_000_000);
+        config.set_initial_max_streams_bidi(100);
         config.verify_peer(false);
-        config.set_initial_max_data(30);
-        config.set_initial_max_stream_data_uni(10);
-        config.set_initial_max_streams_uni(3);
 
         let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        // Server sends stream data.
-        assert_eq!(pipe.server.stream_send(3, b"a", true), Ok(1));
+        pipe.client.set_stream_group_params(1, 8, Some(10));
 
-        let mut flight =
-            testing::emit_flight(&mut pipe.server).expect("no packet");
-        // Let's change the address info.
-        flight
-            .iter_mut()
-            .for_each(|(_, si)| si.from = "127.0.0.1:9292".parse().unwrap());
-        assert_eq!(testing::process_flight(&mut pipe.client, flight), Ok(()));
-        assert_eq!(pipe.client.paths.len(), 1);
+        assert_eq!(pipe.client.stream_set_group(0, 1), Ok(()));
+        assert_eq!(pipe.client.stream_set_group(4, 1), Ok(()));
+
+        // The group's budget is shared by its streams.
+        assert_eq!(pipe.client.stream_send(0, b"aaaaaaaa", false), Ok(8));
+        assert_eq!(pipe.client.stream_send(4, b"bbbbb", false), Ok(2));
+        assert_eq!(pipe.client.stream_send(4, b"bbb", false), Err(Error::Done));
+        assert_eq!(pipe.client.stream_capacity(4), Ok(0));
+
+        let writable: Vec<u64> = pipe.client.writable().collect();
+        assert!(!writable.contains(&4));
+
+        // Streams in other groups are not affected.
+        assert_eq!(pipe.client.stream_send(8, b"hello", false), Ok(5));
+
+        // Increasing the budget makes the group's streams writable again.
+        pipe.client.set_stream_group_params(1, 8, Some(20));
+
+        let writable: Vec<u64> = pipe.client.writable().collect();
+        assert!(writable.contains(&4));
+
+        assert_eq!(pipe.client.stream_send(4, b"bbb", false), Ok(3));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.server.stream_recv(0, &mut buf), Ok((8, false)));
+        assert_eq!(pipe.server.stream_recv(4, &mut buf), Ok((5, false)));
+        assert_eq!(pipe.server.stream_recv(8, &mut buf), Ok((5, false)));
     }
 
     #[test]
-    fn path_validation_limited_mtu() {
+    /// Tests that streams and datagrams are correctly scheduled.
+    fn stream_datagram_priority() {
+        // Limit 1-RTT packet size to avoid congestion control interference.
+        const MAX_TEST_PACKET_SIZE: usize = 540;
+
+        let mut buf = [0; 65535];
+
         let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
         config
             .load_cert_chain_from_pem_file("examples/cert.crt")
@@ -15319,49 +22267,126 @@ mod tests {
         config
             .set_application_protos(&[b"proto1", b"proto2"])
             .unwrap();
+        config.set_initial_max_data(1_000_000);
+        config.set_initial_max_stream_data_bidi_local(1_000_000);
+        config.set_initial_max_stream_data_bidi_remote(1_000_000);
+        config.set_initial_max_stream_data_uni(0);
+        config.set_initial_max_streams_bidi(100);
+        config.set_initial_max_streams_uni(0);
+        config.enable_dgram(true, 10, 10);
         config.verify_peer(false);
-        config.set_active_connection_id_limit(2);
 
-        let mut pipe = pipe_with_exchanged_cids(&mut config, 16, 16, 1);
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        let server_addr = testing::Pipe::server_addr();
-        let client_addr_2 = "127.0.0.1:5678".parse().unwrap();
-        assert_eq!(pipe.client.probe_path(client_addr_2, server_addr), Ok(1));
-        // Limited MTU of 1199 bytes for some reason.
-        testing::process_flight(
-            &mut pipe.server,
-            testing::emit_flight_with_max_buffer(
-                &mut pipe.client,
-                1199,
-                None,
-                None,
-            )
-            .expect("no packet"),
-        )
-        .expect("error when processing client packets");
-        testing::process_flight(
-            &mut pipe.client,
-            testing::emit_flight(&mut pipe.server).expect("no packet"),
-        )
-        .expect("error when processing client packets");
-        let probed_pid = pipe
-            .client
-            .paths
-            .path_id_from_addrs(&(client_addr_2, server_addr))
-            .unwrap();
-        assert!(!pipe.client.paths.get(probed_pid).unwrap().validated(),);
-        assert_eq!(pipe.client.path_event_next(), None);
-        // Now let the client probe at its MTU.
+        assert_eq!(pipe.client.stream_send(0, b"a", false), Ok(1));
         assert_eq!(pipe.advance(), Ok(()));
-        assert!(pipe.client.paths.get(probed_pid).unwrap().validated());
-        assert_eq!(
-            pipe.client.path_event_next(),
-            Some(PathEvent::Validated(client_addr_2, server_addr))
-        );
+
+        assert_eq!(pipe.client.stream_send(4, b"a", false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        let mut b = [0; 1];
+
+        let out = [b'b'; 500];
+
+        // Server prioritizes Stream 0 and 4 with the same urgency with
+        // incremental, meaning the frames should be sent in round-robin
+        // fashion. It also sends DATAGRAMS which are always interleaved with
+        // STREAM frames. So we'll expect a mix of frame types regardless
+        // of the order that the application writes things in.
+
+        pipe.server.stream_recv(0, &mut b).unwrap();
+        assert_eq!(pipe.server.stream_priority(0, 255, true), Ok(()));
+        pipe.server.stream_send(0, &out, false).unwrap();
+        pipe.server.stream_send(0, &out, false).unwrap();
+        pipe.server.stream_send(0, &out, false).unwrap();
+
+        assert_eq!(pipe.server.stream_priority(4, 255, true), Ok(()));
+        pipe.server.stream_send(4, &out, false).unwrap();
+        pipe.server.stream_send(4, &out, false).unwrap();
+        pipe.server.stream_send(4, &out, false).unwrap();
+
+        for _ in 1..=6 {
+            assert_eq!(pipe.server.dgram_send(&out), Ok(()));
+        }
+
+        let mut off_0 = 0;
+        let mut off_4 = 0;
+
+        for _ in 1..=3 {
+            // DATAGRAM
+            let (len, _) =
+                pipe.server.send(&mut buf[..MAX_TEST_PACKET_SIZE]).unwrap();
+
+            let frames =
+                testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
+            let mut frame_iter = frames.iter();
+
+            assert_eq!(frame_iter.next().unwrap(), &frame::Frame::Datagram {
+                data: out.into(),
+            });
+            assert_eq!(frame_iter.next(), None);
+
+            // STREAM 0
+            let (len, _) =
+                pipe.server.send(&mut buf[..MAX_TEST_PACKET_SIZE]).unwrap();
+
+            let frames =
+                testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
+            let mut frame_iter = frames.iter();
+            let stream = frame_iter.next().unwrap();
+
+            assert_eq!(stream, &frame::Frame::Stream {
+                stream_id: 0,
+                data: stream::RangeBuf::from(&out, off_0, false),
+            });
+
+            off_0 = match stream {
+                frame::Frame::Stream { data, .. } => data.max_off(),
+
+                _ => unreachable!(),
+            };
+            assert_eq!(frame_iter.next(), None);
+
+            // DATAGRAM
+            let (len, _) =
+                pipe.server.send(&mut buf[..MAX_TEST_PACKET_SIZE]).unwrap();
+
+            let frames =
+                testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
+            let mut frame_iter = frames.iter();
+
+            assert_eq!(frame_iter.next().unwrap(), &frame::Frame::Datagram {
+                data: out.into(),
+            });
+            assert_eq!(frame_iter.next(), None);
+
+            // STREAM 4
+            let (len, _) =
+                pipe.server.send(&mut buf[..MAX_TEST_PACKET_SIZE]).unwrap();
+
+            let frames =
+                testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
+            let mut frame_iter = frames.iter();
+            let stream = frame_iter.next().unwrap();
+
+            assert_eq!(stream, &frame::Frame::Stream {
+                stream_id: 4,
+                data: stream::RangeBuf::from(&out, off_4, false),
+            });
+
+            off_4 = match stream {
+                frame::Frame::Stream { data, .. } => data.max_off(),
+
+                _ => unreachable!(),
+            };
+            assert_eq!(frame_iter.next(), None);
+        }
     }
 
     #[test]
-    fn path_probing_dos() {
+    #[cfg(feature = "qlog")]
+    fn qlog_filter() {
         let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
         config
             .load_cert_chain_from_pem_file("examples/cert.crt")
@@ -15372,62 +22397,40 @@ mod tests {
         config
             .set_application_protos(&[b"proto1", b"proto2"])
             .unwrap();
+        config.set_qlog_filter(&[QlogCategory::Recovery], 0);
         config.verify_peer(false);
-        config.set_active_connection_id_limit(2);
-
-        let mut pipe = pipe_with_exchanged_cids(&mut config, 16, 16, 1);
 
-        let server_addr = testing::Pipe::server_addr();
-        let client_addr_2 = "127.0.0.1:5678".parse().unwrap();
-        assert_eq!(pipe.client.probe_path(client_addr_2, server_addr), Ok(1));
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
 
-        assert_eq!(pipe.advance(), Ok(()));
+        assert!(pipe.client.qlog.allows(QLOG_METRICS));
+        assert!(!pipe.client.qlog.allows(QLOG_PACKET_TX));
 
-        // The path should be validated at some point.
-        assert_eq!(
-            pipe.client.path_event_next(),
-            Some(PathEvent::Validated(client_addr_2, server_addr))
+        pipe.client.set_qlog(
+            Box::new(std::io::sink()),
+            "title".to_string(),
+            "description".to_string(),
         );
-        assert_eq!(pipe.client.path_event_next(), None);
+        assert!(pipe.client.qlog_streamer().is_some());
 
-        // The server should be notified of this new path.
-        assert_eq!(
-            pipe.server.path_event_next(),
-            Some(PathEvent::New(server_addr, client_addr_2))
-        );
-        assert_eq!(
-            pipe.server.path_event_next(),
-            Some(PathEvent::Validated(server_addr, client_addr_2))
-        );
-        assert_eq!(pipe.server.path_event_next(), None);
+        // Connections that are not sampled are not logged.
+        config.set_qlog_filter(&[], u64::MAX);
 
-        assert_eq!(pipe.server.paths.len(), 2);
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
 
-        // Now forge a packet reusing the unverified path's CID over another
-        // 4-tuple.
-        assert_eq!(pipe.client.probe_path(client_addr_2, server_addr), Ok(1));
-        let client_addr_3 = "127.0.0.1:9012".parse().unwrap();
-        let mut flight =
-            testing::emit_flight(&mut pipe.client).expect("no generated packet");
-        flight
-            .iter_mut()
-            .for_each(|(_, si)| si.from = client_addr_3);
-        testing::process_flight(&mut pipe.server, flight)
-            .expect("failed to process");
-        assert_eq!(pipe.server.paths.len(), 2);
-        assert_eq!(
-            pipe.server.path_event_next(),
-            Some(PathEvent::ReusedSourceConnectionId(
-                1,
-                (server_addr, client_addr_2),
-                (server_addr, client_addr_3)
-            ))
+        assert!(pipe.client.qlog.allows(QLOG_PACKET_TX));
+
+        pipe.client.set_qlog(
+            Box::new(std::io::sink()),
+            "title".to_string(),
+            "description".to_string(),
         );
-        assert_eq!(pipe.server.path_event_next(), None);
+        assert!(pipe.client.qlog_streamer().is_none());
     }
 
     #[test]
-    fn retiring_active_path_dcid() {
+    fn metrics_registry() {
+        let registry = Arc::new(metrics::Registry::new());
+
         let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
         config
             .load_cert_chain_from_pem_file("examples/cert.crt")
@@ -15438,19 +22441,37 @@ mod tests {
         config
             .set_application_protos(&[b"proto1", b"proto2"])
             .unwrap();
+        config.set_metrics_registry(registry.clone());
         config.verify_peer(false);
-        config.set_active_connection_id_limit(2);
 
-        let mut pipe = pipe_with_exchanged_cids(&mut config, 16, 16, 1);
-        let server_addr = testing::Pipe::server_addr();
-        let client_addr_2 = "127.0.0.1:5678".parse().unwrap();
-        assert_eq!(pipe.client.probe_path(client_addr_2, server_addr), Ok(1));
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(registry.snapshot().connections_active, 2);
 
-        assert_eq!(pipe.client.retire_dcid(0), Err(Error::OutOfIdentifiers));
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let s = registry.snapshot();
+        assert_eq!(s.handshakes_completed, 2);
+        assert_eq!(
+            s.packets_sent,
+            (pipe.client.sent_count + pipe.server.sent_count) as u64
+        );
+        assert!(s.packets_received > 0);
+        assert!(s.congestion_window > 0);
+
+        drop(pipe);
+
+        let s = registry.snapshot();
+        assert_eq!(s.handshakes_completed, 2);
+        assert_eq!(s.connections_active, 0);
+        assert_eq!(s.congestion_window, 0);
     }
 
     #[test]
-    fn send_on_path_test() {
+    fn keepalive() {
+        let mut buf = [0; 65535];
+
+        let interval = time::Duration::from_millis(20);
+
         let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
         config
             .load_cert_chain_from_pem_file("examples/cert.crt")
@@ -15461,184 +22482,125 @@ mod tests {
         config
             .set_application_protos(&[b"proto1", b"proto2"])
             .unwrap();
+        config.set_max_idle_timeout(1000);
+        config.set_keepalive_interval(interval);
         config.verify_peer(false);
-        config.set_initial_max_data(100000);
-        config.set_initial_max_stream_data_bidi_local(100000);
-        config.set_initial_max_stream_data_bidi_remote(100000);
-        config.set_initial_max_streams_bidi(2);
-        config.set_active_connection_id_limit(4);
 
-        let mut pipe = pipe_with_exchanged_cids(&mut config, 16, 16, 3);
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let server_addr = testing::Pipe::server_addr();
-        let client_addr = testing::Pipe::client_addr();
-        let client_addr_2 = "127.0.0.1:5678".parse().unwrap();
-        assert_eq!(pipe.client.probe_path(client_addr_2, server_addr), Ok(1));
+        let timer = pipe.client.keepalive_timer.unwrap();
+        assert!(timer <= time::Instant::now() + interval);
+
+        // Keepalives can be disabled per connection.
+        pipe.client.enable_keepalive(false);
+        assert_eq!(pipe.client.keepalive_timer, None);
+
+        pipe.client.enable_keepalive(true);
+        let timer = pipe.client.keepalive_timer.unwrap();
 
-        let mut buf = [0; 65535];
-        // There is nothing to send on the initial path.
         assert_eq!(
-            pipe.client.send_on_path(
-                &mut buf,
-                Some(client_addr),
-                Some(server_addr)
-            ),
-            Err(Error::Done)
+            pipe.client.next_timeout_reason(),
+            Some(TimeoutReason::Keepalive)
         );
 
-        // Client should send padded PATH_CHALLENGE.
-        let (sent, si) = pipe
-            .client
-            .send_on_path(&mut buf, Some(client_addr_2), Some(server_addr))
-            .expect("No error");
-        assert_eq!(sent, MIN_CLIENT_INITIAL_LEN);
-        assert_eq!(si.from, client_addr_2);
-        assert_eq!(si.to, server_addr);
+        std::thread::sleep(timer.saturating_duration_since(time::Instant::now()));
 
-        let ri = RecvInfo {
-            to: si.to,
-            from: si.from,
-        };
-        assert_eq!(pipe.server.recv(&mut buf[..sent], ri), Ok(sent));
+        pipe.client.on_timeout();
+        assert_eq!(pipe.client.keepalive_timer, None);
 
-        let stats = pipe.server.stats();
-        assert_eq!(stats.path_challenge_rx_count, 1);
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
 
-        // A non-existing 4-tuple raises an InvalidState.
-        let client_addr_3 = "127.0.0.1:9012".parse().unwrap();
-        let server_addr_2 = "127.0.0.1:9876".parse().unwrap();
-        assert_eq!(
-            pipe.client.send_on_path(
-                &mut buf,
-                Some(client_addr_3),
-                Some(server_addr)
-            ),
-            Err(Error::InvalidState)
-        );
-        assert_eq!(
-            pipe.client.send_on_path(
-                &mut buf,
-                Some(client_addr),
-                Some(server_addr_2)
-            ),
-            Err(Error::InvalidState)
-        );
+        let frames =
+            testing::decode_pkt(&mut pipe.server, &mut buf[..len]).unwrap();
 
-        // Let's introduce some additional path challenges and data exchange.
-        assert_eq!(pipe.client.probe_path(client_addr, server_addr_2), Ok(2));
-        assert_eq!(pipe.client.probe_path(client_addr_3, server_addr), Ok(3));
-        // Just to fit in two packets.
-        assert_eq!(pipe.client.stream_send(0, &buf[..1201], true), Ok(1201));
-
-        // PATH_CHALLENGE
-        let (sent, si) = pipe
-            .client
-            .send_on_path(&mut buf, Some(client_addr), None)
-            .expect("No error");
-        assert_eq!(sent, MIN_CLIENT_INITIAL_LEN);
-        assert_eq!(si.from, client_addr);
-        assert_eq!(si.to, server_addr_2);
-
-        let ri = RecvInfo {
-            to: si.to,
-            from: si.from,
-        };
-        assert_eq!(pipe.server.recv(&mut buf[..sent], ri), Ok(sent));
+        assert!(frames
+            .iter()
+            .any(|f| matches!(f, frame::Frame::Ping { .. })));
 
-        let stats = pipe.server.stats();
-        assert_eq!(stats.path_challenge_rx_count, 2);
+        // The timer is restarted once the PING is sent.
+        assert!(pipe.client.keepalive_timer.is_some());
+    }
 
-        // STREAM frame on active path.
-        let (sent, si) = pipe
-            .client
-            .send_on_path(&mut buf, Some(client_addr), None)
-            .expect("No error");
-        assert_eq!(si.from, client_addr);
-        assert_eq!(si.to, server_addr);
+    #[test]
+    fn delayed_ack() {
+        let mut buf = [0; 65535];
 
-        let ri = RecvInfo {
-            to: si.to,
-            from: si.from,
-        };
-        assert_eq!(pipe.server.recv(&mut buf[..sent], ri), Ok(sent));
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_streams_bidi(3);
+        config.set_ack_eliciting_threshold(2);
+        config.verify_peer(false);
 
-        let stats = pipe.server.stats();
-        assert_eq!(stats.path_challenge_rx_count, 2);
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        // PATH_CHALLENGE
-        let (sent, si) = pipe
-            .client
-            .send_on_path(&mut buf, None, Some(server_addr))
-            .expect("No error");
-        assert_eq!(sent, MIN_CLIENT_INITIAL_LEN);
-        assert_eq!(si.from, client_addr_3);
-        assert_eq!(si.to, server_addr);
+        // The first packet is not acknowledged right away.
+        assert_eq!(pipe.client.stream_send(0, b"a", false), Ok(1));
+        let flight = testing::emit_flight(&mut pipe.client).unwrap();
+        testing::process_flight(&mut pipe.server, flight).unwrap();
 
-        let ri = RecvInfo {
-            to: si.to,
-            from: si.from,
-        };
-        assert_eq!(pipe.server.recv(&mut buf[..sent], ri), Ok(sent));
+        assert_eq!(pipe.server.send(&mut buf), Err(Error::Done));
+        assert!(pipe.server.pkt_num_spaces[packet::Epoch::Application]
+            .ack_timer
+            .is_some());
 
-        let stats = pipe.server.stats();
-        assert_eq!(stats.path_challenge_rx_count, 3);
+        // The second one reaches the threshold.
+        assert_eq!(pipe.client.stream_send(0, b"b", false), Ok(1));
+        let flight = testing::emit_flight(&mut pipe.client).unwrap();
+        testing::process_flight(&mut pipe.server, flight).unwrap();
 
-        // STREAM frame on active path.
-        let (sent, si) = pipe
-            .client
-            .send_on_path(&mut buf, None, Some(server_addr))
-            .expect("No error");
-        assert_eq!(si.from, client_addr);
-        assert_eq!(si.to, server_addr);
+        let (len, _) = pipe.server.send(&mut buf).unwrap();
 
-        let ri = RecvInfo {
-            to: si.to,
-            from: si.from,
-        };
-        assert_eq!(pipe.server.recv(&mut buf[..sent], ri), Ok(sent));
+        let frames =
+            testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
 
-        // No more data to exchange leads to Error::Done.
-        assert_eq!(
-            pipe.client.send_on_path(&mut buf, Some(client_addr), None),
-            Err(Error::Done)
-        );
+        assert!(frames.iter().any(|f| matches!(f, frame::Frame::ACK { .. })));
         assert_eq!(
-            pipe.client.send_on_path(&mut buf, None, Some(server_addr)),
-            Err(Error::Done)
+            pipe.server.pkt_num_spaces[packet::Epoch::Application].ack_timer,
+            None
         );
 
-        assert_eq!(pipe.advance(), Ok(()));
-
-        let mut v1 = pipe.client.paths_iter(client_addr).collect::<Vec<_>>();
-        let mut v2 = vec![server_addr, server_addr_2];
-
-        v1.sort();
-        v2.sort();
-
-        assert_eq!(v1, v2);
+        // A single packet is acknowledged once the ACK delay expires.
+        assert_eq!(pipe.client.stream_send(0, b"c", false), Ok(1));
+        let flight = testing::emit_flight(&mut pipe.client).unwrap();
+        testing::process_flight(&mut pipe.server, flight).unwrap();
 
-        let mut v1 = pipe.client.paths_iter(client_addr_2).collect::<Vec<_>>();
-        let mut v2 = vec![server_addr];
+        assert_eq!(pipe.server.send(&mut buf), Err(Error::Done));
 
-        v1.sort();
-        v2.sort();
+        let timer = pipe.server.pkt_num_spaces[packet::Epoch::Application]
+            .ack_timer
+            .unwrap();
 
-        assert_eq!(v1, v2);
+        std::thread::sleep(timer.saturating_duration_since(time::Instant::now()));
 
-        let mut v1 = pipe.client.paths_iter(client_addr_3).collect::<Vec<_>>();
-        let mut v2 = vec![server_addr];
+        pipe.server.on_timeout();
 
-        v1.sort();
-        v2.sort();
+        let (len, _) = pipe.server.send(&mut buf).unwrap();
 
-        assert_eq!(v1, v2);
+        let frames =
+            testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
 
-        let stats = pipe.server.stats();
-        assert_eq!(stats.path_challenge_rx_count, 3);
+        assert!(frames.iter().any(|f| matches!(f, frame::Frame::ACK { .. })));
     }
 
     #[test]
-    fn connection_migration() {
+    fn delayed_ack_reordering() {
+        let mut buf = [0; 65535];
+
         let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
         config
             .load_cert_chain_from_pem_file("examples/cert.crt")
@@ -15649,205 +22611,3837 @@ mod tests {
         config
             .set_application_protos(&[b"proto1", b"proto2"])
             .unwrap();
-        config.verify_peer(false);
-        config.set_active_connection_id_limit(3);
         config.set_initial_max_data(30);
         config.set_initial_max_stream_data_bidi_local(15);
         config.set_initial_max_stream_data_bidi_remote(15);
-        config.set_initial_max_stream_data_uni(10);
         config.set_initial_max_streams_bidi(3);
+        config.set_ack_eliciting_threshold(10);
+        config.verify_peer(false);
 
-        let mut pipe = pipe_with_exchanged_cids(&mut config, 16, 16, 2);
+        for ack_on_reordering in [true, false] {
+            config.enable_ack_on_reordering(ack_on_reordering);
 
-        let server_addr = testing::Pipe::server_addr();
-        let client_addr_2 = "127.0.0.1:5678".parse().unwrap();
-        let client_addr_3 = "127.0.0.1:9012".parse().unwrap();
-        let client_addr_4 = "127.0.0.1:8908".parse().unwrap();
+            let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+            assert_eq!(pipe.handshake(), Ok(()));
+            assert_eq!(pipe.advance(), Ok(()));
 
-        // Case 1: the client first probes the new address, the server too, and
-        // then migrates.
-        assert_eq!(pipe.client.probe_path(client_addr_2, server_addr), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
-        assert_eq!(
-            pipe.client.path_event_next(),
-            Some(PathEvent::Validated(client_addr_2, server_addr))
-        );
-        assert_eq!(pipe.client.path_event_next(), None);
-        assert_eq!(
-            pipe.server.path_event_next(),
-            Some(PathEvent::New(server_addr, client_addr_2))
-        );
-        assert_eq!(
-            pipe.server.path_event_next(),
-            Some(PathEvent::Validated(server_addr, client_addr_2))
-        );
-        assert_eq!(
-            pipe.client.is_path_validated(client_addr_2, server_addr),
-            Ok(true)
-        );
-        assert_eq!(
-            pipe.server.is_path_validated(server_addr, client_addr_2),
-            Ok(true)
-        );
-        // The server can never initiates the connection migration.
-        assert_eq!(
-            pipe.server.migrate(server_addr, client_addr_2),
-            Err(Error::InvalidState)
-        );
-        assert_eq!(pipe.client.migrate(client_addr_2, server_addr), Ok(1));
-        assert_eq!(pipe.client.stream_send(0, b"data", true), Ok(4));
+            // The first packet is lost, so the second one leaves a gap.
+            assert_eq!(pipe.client.stream_send(0, b"a", false), Ok(1));
+            assert!(pipe.client.send(&mut buf).is_ok());
+
+            assert_eq!(pipe.client.stream_send(0, b"b", false), Ok(1));
+            let flight = testing::emit_flight(&mut pipe.client).unwrap();
+            testing::process_flight(&mut pipe.server, flight).unwrap();
+
+            assert_eq!(pipe.server.send(&mut buf).is_ok(), ack_on_reordering);
+        }
+    }
+
+    #[test]
+    /// Tests that old data is retransmitted on PTO.
+    fn early_retransmit() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        // Client sends stream data.
+        assert_eq!(pipe.client.stream_send(0, b"a", false), Ok(1));
         assert_eq!(pipe.advance(), Ok(()));
+
+        // Client sends more stream data, but packet is lost
+        assert_eq!(pipe.client.stream_send(4, b"b", false), Ok(1));
+        assert!(pipe.client.send(&mut buf).is_ok());
+
+        // Wait until PTO expires. Since the RTT is very low, wait a bit more.
+        let timer = pipe.client.timeout().unwrap();
+        std::thread::sleep(timer + time::Duration::from_millis(1));
+
+        pipe.client.on_timeout();
+
+        let epoch = packet::Epoch::Application;
         assert_eq!(
             pipe.client
                 .paths
                 .get_active()
                 .expect("no active")
-                .local_addr(),
-            client_addr_2
+                .recovery
+                .loss_probes[epoch],
+            1,
         );
+
+        // Client retransmits stream data in PTO probe.
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
         assert_eq!(
             pipe.client
                 .paths
                 .get_active()
                 .expect("no active")
-                .peer_addr(),
-            server_addr
+                .recovery
+                .loss_probes[epoch],
+            0,
         );
+
+        let frames =
+            testing::decode_pkt(&mut pipe.server, &mut buf[..len]).unwrap();
+
+        let mut iter = frames.iter();
+
+        // Skip ACK frame.
+        iter.next();
+
         assert_eq!(
-            pipe.server.path_event_next(),
-            Some(PathEvent::PeerMigrated(server_addr, client_addr_2))
+            iter.next(),
+            Some(&frame::Frame::Stream {
+                stream_id: 4,
+                data: stream::RangeBuf::from(b"b", 0, false),
+            })
         );
-        assert_eq!(pipe.server.path_event_next(), None);
+        assert_eq!(pipe.client.stats().retrans, 1);
+    }
+
+    #[test]
+    /// Tests that PTO probe packets are not coalesced together.
+    fn dont_coalesce_probes() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::new().unwrap();
+
+        // Client sends Initial packet.
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(len, 1200);
+
+        // Wait for PTO to expire.
+        let timer = pipe.client.timeout().unwrap();
+        std::thread::sleep(timer + time::Duration::from_millis(1));
+
+        pipe.client.on_timeout();
+
+        let epoch = packet::Epoch::Initial;
         assert_eq!(
-            pipe.server
+            pipe.client
                 .paths
                 .get_active()
                 .expect("no active")
-                .local_addr(),
-            server_addr
-        );
-        assert_eq!(
-            pipe.server
-                .paths
-                .get_active()
-                .expect("no active")
-                .peer_addr(),
-            client_addr_2
+                .recovery
+                .loss_probes[epoch],
+            1,
         );
 
-        // Case 2: the client migrates on a path that was not previously
-        // validated, and has spare SCIDs/DCIDs to do so.
-        assert_eq!(pipe.client.migrate(client_addr_3, server_addr), Ok(2));
-        assert_eq!(pipe.client.stream_send(4, b"data", true), Ok(4));
-        assert_eq!(pipe.advance(), Ok(()));
+        // Client sends PTO probe.
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(len, 1200);
         assert_eq!(
             pipe.client
                 .paths
                 .get_active()
                 .expect("no active")
-                .local_addr(),
-            client_addr_3
+                .recovery
+                .loss_probes[epoch],
+            0,
         );
+
+        // Wait for PTO to expire.
+        let timer = pipe.client.timeout().unwrap();
+        std::thread::sleep(timer + time::Duration::from_millis(1));
+
+        pipe.client.on_timeout();
+
         assert_eq!(
             pipe.client
                 .paths
                 .get_active()
                 .expect("no active")
-                .peer_addr(),
-            server_addr
-        );
-        assert_eq!(
-            pipe.server.path_event_next(),
-            Some(PathEvent::New(server_addr, client_addr_3))
-        );
-        assert_eq!(
-            pipe.server.path_event_next(),
-            Some(PathEvent::Validated(server_addr, client_addr_3))
-        );
-        assert_eq!(
-            pipe.server.path_event_next(),
-            Some(PathEvent::PeerMigrated(server_addr, client_addr_3))
-        );
-        assert_eq!(pipe.server.path_event_next(), None);
-        assert_eq!(
-            pipe.server
-                .paths
-                .get_active()
-                .expect("no active")
-                .local_addr(),
-            server_addr
-        );
-        assert_eq!(
-            pipe.server
-                .paths
-                .get_active()
-                .expect("no active")
-                .peer_addr(),
-            client_addr_3
+                .recovery
+                .loss_probes[epoch],
+            2,
         );
 
-        // Case 3: the client tries to migrate on the current active path.
-        // This is not an error, but it triggers nothing.
-        assert_eq!(pipe.client.migrate(client_addr_3, server_addr), Ok(2));
-        assert_eq!(pipe.client.stream_send(8, b"data", true), Ok(4));
-        assert_eq!(pipe.advance(), Ok(()));
-        assert_eq!(pipe.client.path_event_next(), None);
+        // Client sends first PTO probe.
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(len, 1200);
         assert_eq!(
             pipe.client
                 .paths
                 .get_active()
                 .expect("no active")
-                .local_addr(),
-            client_addr_3
+                .recovery
+                .loss_probes[epoch],
+            1,
         );
+
+        // Client sends second PTO probe.
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(len, 1200);
         assert_eq!(
             pipe.client
                 .paths
                 .get_active()
                 .expect("no active")
-                .peer_addr(),
-            server_addr
-        );
-        assert_eq!(pipe.server.path_event_next(), None);
-        assert_eq!(
-            pipe.server
-                .paths
-                .get_active()
-                .expect("no active")
-                .local_addr(),
-            server_addr
-        );
-        assert_eq!(
-            pipe.server
-                .paths
-                .get_active()
-                .expect("no active")
-                .peer_addr(),
-            client_addr_3
+                .recovery
+                .loss_probes[epoch],
+            0,
         );
+    }
+
+    #[test]
+    /// Tests that the connection is closed after too many consecutive PTOs.
+    fn max_pto_count() {
+        let mut buf = [0; 65535];
+
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.verify_peer(false);
+        config.set_initial_rtt(10);
+        config.set_max_pto_count(2);
+
+        let mut pipe = testing::Pipe::with_client_config(&mut config).unwrap();
+
+        // Client sends Initial packet, but it is lost.
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(len, 1200);
+
+        // Wait for 1st PTO to expire.
+        let timer = pipe.client.timeout().unwrap();
+        std::thread::sleep(timer + time::Duration::from_millis(1));
+
+        pipe.client.on_timeout();
+        assert!(!pipe.client.is_closed());
+
+        // Client sends PTO probe, which is also lost.
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(len, 1200);
+
+        // Wait for 2nd PTO to expire.
+        let timer = pipe.client.timeout().unwrap();
+        std::thread::sleep(timer + time::Duration::from_millis(1));
+
+        pipe.client.on_timeout();
+        assert!(pipe.client.is_closed());
+        assert!(pipe.client.is_timed_out());
+
+        let stats = pipe.client.stats();
+        assert_eq!(stats.pto_count_initial, 2);
+        assert_eq!(stats.pto_count_handshake, 0);
+        assert_eq!(stats.pto_count_application, 0);
+        assert_eq!(stats.longest_pto_backoff, 2);
+    }
+
+    #[test]
+    fn coalesce_padding_short() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::new().unwrap();
+
+        // Client sends first flight.
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(len, MIN_CLIENT_INITIAL_LEN);
+        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+
+        // Server sends first flight.
+        let (len, _) = pipe.server.send(&mut buf).unwrap();
+        assert_eq!(len, MIN_CLIENT_INITIAL_LEN);
+        assert_eq!(pipe.client_recv(&mut buf[..len]), Ok(len));
+
+        let (len, _) = pipe.server.send(&mut buf).unwrap();
+        assert_eq!(pipe.client_recv(&mut buf[..len]), Ok(len));
+
+        // Client sends stream data.
+        assert!(pipe.client.is_established());
+        assert_eq!(pipe.client.stream_send(4, b"hello", true), Ok(5));
+
+        // Client sends second flight.
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(len, MIN_CLIENT_INITIAL_LEN);
+        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+
+        // None of the sent packets should have been dropped.
+        assert_eq!(pipe.client.sent_count, pipe.server.recv_count);
+        assert_eq!(pipe.server.sent_count, pipe.client.recv_count);
+    }
+
+    #[test]
+    /// Tests that client avoids handshake deadlock by arming PTO.
+    fn handshake_anti_deadlock() {
+        let mut buf = [0; 65535];
+
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert-big.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+
+        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
+
+        assert!(!pipe.client.handshake_status().has_handshake_keys);
+        assert!(!pipe.client.handshake_status().peer_verified_address);
+        assert!(!pipe.server.handshake_status().has_handshake_keys);
+        assert!(pipe.server.handshake_status().peer_verified_address);
+
+        // Client sends padded Initial.
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(len, 1200);
+
+        // Server receives client's Initial and sends own Initial and Handshake
+        // until it's blocked by the anti-amplification limit.
+        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+        let flight = testing::emit_flight(&mut pipe.server).unwrap();
+
+        assert!(!pipe.client.handshake_status().has_handshake_keys);
+        assert!(!pipe.client.handshake_status().peer_verified_address);
+        assert!(pipe.server.handshake_status().has_handshake_keys);
+        assert!(pipe.server.handshake_status().peer_verified_address);
+
+        // Client receives the server flight and sends Handshake ACK, but it is
+        // lost.
+        testing::process_flight(&mut pipe.client, flight).unwrap();
+        testing::emit_flight(&mut pipe.client).unwrap();
+
+        assert!(pipe.client.handshake_status().has_handshake_keys);
+        assert!(!pipe.client.handshake_status().peer_verified_address);
+        assert!(pipe.server.handshake_status().has_handshake_keys);
+        assert!(pipe.server.handshake_status().peer_verified_address);
+
+        // Make sure client's PTO timer is armed.
+        assert!(pipe.client.timeout().is_some());
+    }
+
+    #[test]
+    /// Tests that packets with corrupted type (from Handshake to Initial) are
+    /// properly ignored.
+    fn handshake_packet_type_corruption() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::new().unwrap();
+
+        // Client sends padded Initial.
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(len, 1200);
+
+        // Server receives client's Initial and sends own Initial and Handshake.
+        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+
+        let flight = testing::emit_flight(&mut pipe.server).unwrap();
+        testing::process_flight(&mut pipe.client, flight).unwrap();
+
+        // Client sends Initial packet with ACK.
+        let active_pid =
+            pipe.client.paths.get_active_path_id().expect("no active");
+        let (ty, len) = pipe
+            .client
+            .send_single(&mut buf, active_pid, false, time::Instant::now())
+            .unwrap();
+        assert_eq!(ty, Type::Initial);
+
+        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+
+        // Client sends Handshake packet.
+        let (ty, len) = pipe
+            .client
+            .send_single(&mut buf, active_pid, false, time::Instant::now())
+            .unwrap();
+        assert_eq!(ty, Type::Handshake);
+
+        // Packet type is corrupted to Initial.
+        buf[0] &= !(0x20);
+
+        let hdr = Header::from_slice(&mut buf[..len], 0).unwrap();
+        assert_eq!(hdr.ty, Type::Initial);
+
+        // Server receives corrupted packet without returning an error.
+        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+    }
+
+    #[test]
+    fn grease_quic_bit() {
+        let mut buf = [0; 65535];
+
+        // Sends 32 packets from the client, and returns how many had the
+        // QUIC bit cleared.
+        fn send_packets(pipe: &mut testing::Pipe, buf: &mut [u8]) -> usize {
+            let mut cleared = 0;
+
+            for _ in 0..32 {
+                pipe.client.send_ack_eliciting().unwrap();
+
+                let (len, _) = pipe.client.send(buf).unwrap();
+
+                if !Header::quic_bit(buf[0]) {
+                    cleared += 1;
+                }
+
+                assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+            }
+
+            cleared
+        }
+
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.verify_peer(false);
+        config.enable_grease_quic_bit(true);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert!(pipe.client.peer_transport_params.grease_quic_bit);
+        assert!(pipe.server.peer_transport_params.grease_quic_bit);
+
+        // The QUIC bit is randomized, and all packets are accepted.
+        let recv = pipe.server.stats().recv;
+
+        assert!(send_packets(&mut pipe, &mut buf) > 0);
+        assert_eq!(pipe.server.stats().recv, recv + 32);
+
+        // Packets with the QUIC bit cleared are dropped by a server that
+        // didn't send the transport parameter.
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert!(!pipe.client.peer_transport_params.grease_quic_bit);
+        pipe.client.peer_transport_params.grease_quic_bit = true;
+
+        let recv = pipe.server.stats().recv;
+
+        let cleared = send_packets(&mut pipe, &mut buf);
+        assert!(cleared > 0);
+        assert_eq!(pipe.server.stats().recv, recv + 32 - cleared);
+    }
+
+    #[test]
+    fn dgram_send_fails_invalidstate() {
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(
+            pipe.client.dgram_send(b"hello, world"),
+            Err(Error::InvalidState)
+        );
+    }
+
+    #[test]
+    fn dgram_send_app_limited() {
+        let mut buf = [0; 65535];
+        let send_buf = [0xcf; 1000];
+
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_stream_data_uni(10);
+        config.set_initial_max_streams_bidi(3);
+        config.set_initial_max_streams_uni(3);
+        config.enable_dgram(true, 1000, 1000);
+        config.set_max_recv_udp_payload_size(1200);
+        config.verify_peer(false);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        for _ in 0..1000 {
+            assert_eq!(pipe.client.dgram_send(&send_buf), Ok(()));
+        }
+
+        assert!(!pipe
+            .client
+            .paths
+            .get_active()
+            .expect("no active")
+            .recovery
+            .app_limited());
+        assert_eq!(pipe.client.dgram_send_queue.byte_size(), 1_000_000);
+
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+
+        assert_ne!(pipe.client.dgram_send_queue.byte_size(), 0);
+        assert_ne!(pipe.client.dgram_send_queue.byte_size(), 1_000_000);
+        assert!(!pipe
+            .client
+            .paths
+            .get_active()
+            .expect("no active")
+            .recovery
+            .app_limited());
+
+        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+
+        let flight = testing::emit_flight(&mut pipe.client).unwrap();
+        testing::process_flight(&mut pipe.server, flight).unwrap();
+
+        let flight = testing::emit_flight(&mut pipe.server).unwrap();
+        testing::process_flight(&mut pipe.client, flight).unwrap();
+
+        assert_ne!(pipe.client.dgram_send_queue.byte_size(), 0);
+        assert_ne!(pipe.client.dgram_send_queue.byte_size(), 1_000_000);
+
+        assert!(!pipe
+            .client
+            .paths
+            .get_active()
+            .expect("no active")
+            .recovery
+            .app_limited());
+    }
+
+    #[test]
+    fn dgram_single_datagram() {
+        let mut buf = [0; 65535];
+
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_stream_data_uni(10);
+        config.set_initial_max_streams_bidi(3);
+        config.set_initial_max_streams_uni(3);
+        config.enable_dgram(true, 10, 10);
+        config.verify_peer(false);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(pipe.client.dgram_send(b"hello, world"), Ok(()));
+
+        assert_eq!(pipe.advance(), Ok(()));
+
+        let result1 = pipe.server.dgram_recv(&mut buf);
+        assert_eq!(result1, Ok(12));
+
+        let result2 = pipe.server.dgram_recv(&mut buf);
+        assert_eq!(result2, Err(Error::Done));
+    }
+
+    #[test]
+    fn dgram_send_with_urgency() {
+        let mut buf = [0; 65535];
+
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.enable_dgram(true, 10, 10);
+        config.verify_peer(false);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(pipe.client.dgram_send(b"default"), Ok(()));
+        assert_eq!(pipe.client.dgram_send_with_ttl(b"low", 200, None), Ok(()));
+        assert_eq!(pipe.client.dgram_send_with_ttl(b"high", 0, None), Ok(()));
+        assert_eq!(pipe.client.dgram_send_with_ttl(b"high2", 0, None), Ok(()));
+
+        assert_eq!(pipe.client.stats().dgram_send_queue_peak_len, 4);
+
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.server.dgram_recv(&mut buf), Ok(4));
+        assert_eq!(&buf[..4], b"high");
+
+        assert_eq!(pipe.server.dgram_recv(&mut buf), Ok(5));
+        assert_eq!(&buf[..5], b"high2");
+
+        assert_eq!(pipe.server.dgram_recv(&mut buf), Ok(7));
+        assert_eq!(&buf[..7], b"default");
+
+        assert_eq!(pipe.server.dgram_recv(&mut buf), Ok(3));
+        assert_eq!(&buf[..3], b"low");
+
+        assert_eq!(pipe.server.stats().dgram_recv_queue_peak_len, 4);
+    }
+
+    #[test]
+    fn dgram_send_with_ttl_expired() {
+        let mut buf = [0; 65535];
+
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.enable_dgram(true, 10, 10);
+        config.verify_peer(false);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let ttl = time::Duration::from_millis(10);
+
+        assert_eq!(
+            pipe.client.dgram_send_with_ttl(b"stale", 0, Some(ttl)),
+            Ok(())
+        );
+        assert_eq!(pipe.client.dgram_send(b"fresh"), Ok(()));
+        assert_eq!(pipe.client.dgram_send_queue_len(), 2);
+
+        std::thread::sleep(ttl);
+
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.server.dgram_recv(&mut buf), Ok(5));
+        assert_eq!(&buf[..5], b"fresh");
+        assert_eq!(pipe.server.dgram_recv(&mut buf), Err(Error::Done));
+
+        assert_eq!(pipe.client.dgram_send_queue_len(), 0);
+        assert_eq!(pipe.client.stats().dgram_send_expired, 1);
+    }
+
+    #[test]
+    fn dgram_multiple_datagrams() {
+        let mut buf = [0; 65535];
+
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_stream_data_uni(10);
+        config.set_initial_max_streams_bidi(3);
+        config.set_initial_max_streams_uni(3);
+        config.enable_dgram(true, 2, 3);
+        config.verify_peer(false);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(pipe.client.dgram_send_queue_len(), 0);
+        assert_eq!(pipe.client.dgram_send_queue_byte_size(), 0);
+
+        assert_eq!(pipe.client.dgram_send(b"hello, world"), Ok(()));
+        assert_eq!(pipe.client.dgram_send(b"ciao, mondo"), Ok(()));
+        assert_eq!(pipe.client.dgram_send(b"hola, mundo"), Ok(()));
+        assert!(pipe.client.is_dgram_send_queue_full());
+
+        assert_eq!(pipe.client.dgram_send_queue_byte_size(), 34);
+
+        pipe.client
+            .dgram_purge_outgoing(|d: &[u8]| -> bool { d[0] == b'c' });
+
+        assert_eq!(pipe.client.dgram_send_queue_len(), 2);
+        assert_eq!(pipe.client.dgram_send_queue_byte_size(), 23);
+        assert!(!pipe.client.is_dgram_send_queue_full());
+
+        // Before packets exchanged, no dgrams on server receive side.
+        assert_eq!(pipe.server.dgram_recv_queue_len(), 0);
+
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // After packets exchanged, no dgrams on client send side.
+        assert_eq!(pipe.client.dgram_send_queue_len(), 0);
+        assert_eq!(pipe.client.dgram_send_queue_byte_size(), 0);
+
+        assert_eq!(pipe.server.dgram_recv_queue_len(), 2);
+        assert_eq!(pipe.server.dgram_recv_queue_byte_size(), 23);
+        assert!(pipe.server.is_dgram_recv_queue_full());
+
+        let result1 = pipe.server.dgram_recv(&mut buf);
+        assert_eq!(result1, Ok(12));
+        assert_eq!(buf[0], b'h');
+        assert_eq!(buf[1], b'e');
+        assert!(!pipe.server.is_dgram_recv_queue_full());
+
+        let result2 = pipe.server.dgram_recv(&mut buf);
+        assert_eq!(result2, Ok(11));
+        assert_eq!(buf[0], b'h');
+        assert_eq!(buf[1], b'o');
+
+        let result3 = pipe.server.dgram_recv(&mut buf);
+        assert_eq!(result3, Err(Error::Done));
+
+        assert_eq!(pipe.server.dgram_recv_queue_len(), 0);
+        assert_eq!(pipe.server.dgram_recv_queue_byte_size(), 0);
+    }
+
+    #[test]
+    fn dgram_send_queue_overflow() {
+        let mut buf = [0; 65535];
+
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_stream_data_uni(10);
+        config.set_initial_max_streams_bidi(3);
+        config.set_initial_max_streams_uni(3);
+        config.enable_dgram(true, 10, 2);
+        config.verify_peer(false);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(pipe.client.dgram_send(b"hello, world"), Ok(()));
+        assert_eq!(pipe.client.dgram_send(b"ciao, mondo"), Ok(()));
+        assert_eq!(pipe.client.dgram_send(b"hola, mundo"), Err(Error::Done));
+
+        assert_eq!(pipe.advance(), Ok(()));
+
+        let result1 = pipe.server.dgram_recv(&mut buf);
+        assert_eq!(result1, Ok(12));
+        assert_eq!(buf[0], b'h');
+        assert_eq!(buf[1], b'e');
+
+        let result2 = pipe.server.dgram_recv(&mut buf);
+        assert_eq!(result2, Ok(11));
+        assert_eq!(buf[0], b'c');
+        assert_eq!(buf[1], b'i');
+
+        let result3 = pipe.server.dgram_recv(&mut buf);
+        assert_eq!(result3, Err(Error::Done));
+    }
+
+    #[test]
+    fn dgram_recv_queue_overflow() {
+        let mut buf = [0; 65535];
+
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_stream_data_uni(10);
+        config.set_initial_max_streams_bidi(3);
+        config.set_initial_max_streams_uni(3);
+        config.enable_dgram(true, 2, 10);
+        config.set_max_recv_udp_payload_size(1200);
+        config.verify_peer(false);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(pipe.client.dgram_send(b"hello, world"), Ok(()));
+        assert_eq!(pipe.client.dgram_send(b"ciao, mondo"), Ok(()));
+        assert_eq!(pipe.client.dgram_send(b"hola, mundo"), Ok(()));
+
+        assert_eq!(pipe.advance(), Ok(()));
+
+        let result1 = pipe.server.dgram_recv(&mut buf);
+        assert_eq!(result1, Ok(11));
+        assert_eq!(buf[0], b'c');
+        assert_eq!(buf[1], b'i');
+
+        let result2 = pipe.server.dgram_recv(&mut buf);
+        assert_eq!(result2, Ok(11));
+        assert_eq!(buf[0], b'h');
+        assert_eq!(buf[1], b'o');
+
+        let result3 = pipe.server.dgram_recv(&mut buf);
+        assert_eq!(result3, Err(Error::Done));
+    }
+
+    #[test]
+    fn dgram_send_max_size() {
+        let mut buf = [0; MAX_DGRAM_FRAME_SIZE as usize];
+
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_stream_data_uni(10);
+        config.set_initial_max_streams_bidi(3);
+        config.set_initial_max_streams_uni(3);
+        config.enable_dgram(true, 10, 10);
+        config.set_max_recv_udp_payload_size(1452);
+        config.verify_peer(false);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+
+        // Before handshake (before peer settings) we don't know max dgram size
+        assert_eq!(pipe.client.dgram_max_writable_len(), None);
+
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let max_dgram_size = pipe.client.dgram_max_writable_len().unwrap();
+
+        // Tests use a 16-byte connection ID, so the max datagram frame payload
+        // size is (1200 byte-long packet - 40 bytes overhead)
+        assert_eq!(max_dgram_size, 1160);
+
+        let dgram_packet: Vec<u8> = vec![42; max_dgram_size];
+
+        assert_eq!(pipe.client.dgram_send(&dgram_packet), Ok(()));
+
+        assert_eq!(pipe.advance(), Ok(()));
+
+        let result1 = pipe.server.dgram_recv(&mut buf);
+        assert_eq!(result1, Ok(max_dgram_size));
+
+        let result2 = pipe.server.dgram_recv(&mut buf);
+        assert_eq!(result2, Err(Error::Done));
+    }
+
+    #[test]
+    /// Tests is_readable check.
+    fn is_readable() {
+        let mut buf = [0; 65535];
+
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_stream_data_uni(10);
+        config.set_initial_max_streams_bidi(3);
+        config.set_initial_max_streams_uni(3);
+        config.enable_dgram(true, 10, 10);
+        config.set_max_recv_udp_payload_size(1452);
+        config.verify_peer(false);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        // No readable data.
+        assert!(!pipe.client.is_readable());
+        assert!(!pipe.server.is_readable());
+
+        assert_eq!(pipe.client.stream_send(4, b"aaaaa", false), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // Server received stream.
+        assert!(!pipe.client.is_readable());
+        assert!(pipe.server.is_readable());
+
+        assert_eq!(
+            pipe.server.stream_send(4, b"aaaaaaaaaaaaaaa", false),
+            Ok(15)
+        );
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // Client received stream.
+        assert!(pipe.client.is_readable());
+        assert!(pipe.server.is_readable());
+
+        // Client drains stream.
+        let mut b = [0; 15];
+        pipe.client.stream_recv(4, &mut b).unwrap();
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert!(!pipe.client.is_readable());
+        assert!(pipe.server.is_readable());
+
+        // Server shuts down stream.
+        assert_eq!(pipe.server.stream_shutdown(4, Shutdown::Read, 0), Ok(()));
+        assert!(!pipe.server.is_readable());
+
+        // Server received dgram.
+        assert_eq!(pipe.client.dgram_send(b"dddddddddddddd"), Ok(()));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert!(!pipe.client.is_readable());
+        assert!(pipe.server.is_readable());
+
+        // Client received dgram.
+        assert_eq!(pipe.server.dgram_send(b"dddddddddddddd"), Ok(()));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert!(pipe.client.is_readable());
+        assert!(pipe.server.is_readable());
+
+        // Drain the dgram queues.
+        let r = pipe.server.dgram_recv(&mut buf);
+        assert_eq!(r, Ok(14));
+        assert!(!pipe.server.is_readable());
+
+        let r = pipe.client.dgram_recv(&mut buf);
+        assert_eq!(r, Ok(14));
+        assert!(!pipe.client.is_readable());
+    }
+
+    #[test]
+    fn close() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(pipe.client.close(false, 0x1234, b"hello?"), Ok(()));
+
+        assert_eq!(
+            pipe.client.close(false, 0x4321, b"hello?"),
+            Err(Error::Done)
+        );
+
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+
+        let frames =
+            testing::decode_pkt(&mut pipe.server, &mut buf[..len]).unwrap();
+
+        assert_eq!(
+            frames.first(),
+            Some(&frame::Frame::ConnectionClose {
+                error_code: 0x1234,
+                frame_type: 0,
+                reason: b"hello?".to_vec(),
+            })
+        );
+    }
+
+    #[test]
+    fn app_close_by_client() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(pipe.client.close(true, 0x1234, b"hello!"), Ok(()));
+
+        assert_eq!(pipe.client.close(true, 0x4321, b"hello!"), Err(Error::Done));
+
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+
+        let frames =
+            testing::decode_pkt(&mut pipe.server, &mut buf[..len]).unwrap();
+
+        assert_eq!(
+            frames.first(),
+            Some(&frame::Frame::ApplicationClose {
+                error_code: 0x1234,
+                reason: b"hello!".to_vec(),
+            })
+        );
+    }
+
+    #[test]
+    fn close_without_drain() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        // Client sends some data, that the server doesn't acknowledge yet.
+        assert_eq!(pipe.client.stream_send(0, b"hello", true), Ok(5));
+
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+
+        assert_eq!(
+            pipe.server.close_with_drain(true, 0x1234, b"bye", false),
+            Ok(())
+        );
+        assert!(pipe.server.is_closing());
+        assert!(!pipe.server.is_draining());
+
+        let (len, _) = pipe.server.send(&mut buf).unwrap();
+
+        // The connection is closed right away.
+        assert!(pipe.server.is_closed());
+        assert!(!pipe.server.is_closing());
+        assert_eq!(pipe.server.draining_deadline(), None);
+        assert_eq!(pipe.server.send(&mut buf), Err(Error::Done));
+
+        // Pending ACKs are flushed together with the close frame.
+        let frames =
+            testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
+
+        assert!(frames.iter().any(|f| matches!(f, frame::Frame::ACK { .. })));
+
+        assert!(frames.contains(&frame::Frame::ApplicationClose {
+            error_code: 0x1234,
+            reason: b"bye".to_vec(),
+        }));
+    }
+
+    #[test]
+    fn closing_resends_close() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(pipe.client.close(true, 0x1234, b"hello!"), Ok(()));
+        assert!(pipe.client.is_closing());
+        assert_eq!(pipe.client.draining_deadline(), None);
+
+        // The close frame is lost.
+        assert!(pipe.client.send(&mut buf).is_ok());
+        assert!(pipe.client.is_draining());
+        assert_eq!(pipe.client.send(&mut buf), Err(Error::Done));
+
+        let deadline = pipe.client.draining_deadline();
+        assert!(deadline.is_some());
+
+        // The close frame is sent again in response to the 1st, 2nd and 4th
+        // packets received from the server.
+        for resend in [true, true, false, true] {
+            assert_eq!(pipe.server.send_ack_eliciting(), Ok(()));
+
+            let (len, _) = pipe.server.send(&mut buf).unwrap();
+            assert_eq!(pipe.client_recv(&mut buf[..len]), Ok(len));
+
+            if !resend {
+                assert_eq!(pipe.client.send(&mut buf), Err(Error::Done));
+                continue;
+            }
+
+            let (len, _) = pipe.client.send(&mut buf).unwrap();
+
+            let frames =
+                testing::decode_pkt(&mut pipe.server, &mut buf[..len]).unwrap();
+
+            assert!(frames.contains(&frame::Frame::ApplicationClose {
+                error_code: 0x1234,
+                reason: b"hello!".to_vec(),
+            }));
+
+            assert_eq!(pipe.client.send(&mut buf), Err(Error::Done));
+        }
+
+        // Sending the close frame again doesn't extend the closing period.
+        assert_eq!(pipe.client.draining_deadline(), deadline);
+    }
+
+    #[test]
+    #[cfg(not(feature = "rustls"))]
+    fn app_close_by_server_during_handshake_private_key_failure() {
+        let mut pipe = testing::Pipe::new().unwrap();
+        pipe.server.handshake.set_failing_private_key_method();
+
+        // Client sends initial flight.
+        let flight = testing::emit_flight(&mut pipe.client).unwrap();
+        assert_eq!(
+            testing::process_flight(&mut pipe.server, flight),
+            Err(Error::TlsFail)
+        );
+
+        let flight = testing::emit_flight(&mut pipe.server).unwrap();
+
+        // Both connections are not established.
+        assert!(!pipe.server.is_established());
+        assert!(!pipe.client.is_established());
+
+        // Connection should already be closed due the failure during key signing.
+        assert_eq!(
+            pipe.server.close(true, 123, b"fail whale"),
+            Err(Error::Done)
+        );
+
+        testing::process_flight(&mut pipe.client, flight).unwrap();
+
+        // Connection should already be closed due the failure during key signing.
+        assert_eq!(
+            pipe.client.close(true, 123, b"fail whale"),
+            Err(Error::Done)
+        );
+
+        // Connection is not established on the server / client (and never
+        // will be)
+        assert!(!pipe.server.is_established());
+        assert!(!pipe.client.is_established());
+
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(
+            pipe.server.local_error(),
+            Some(&ConnectionError {
+                is_app: false,
+                error_code: 0x01,
+                reason: vec![],
+            })
+        );
+        assert_eq!(
+            pipe.client.peer_error(),
+            Some(&ConnectionError {
+                is_app: false,
+                error_code: 0x01,
+                reason: vec![],
+            })
+        );
+    }
+
+    #[test]
+    fn app_close_by_server_during_handshake_not_established() {
+        let mut pipe = testing::Pipe::new().unwrap();
+
+        // Client sends initial flight.
+        let flight = testing::emit_flight(&mut pipe.client).unwrap();
+        testing::process_flight(&mut pipe.server, flight).unwrap();
+
+        let flight = testing::emit_flight(&mut pipe.server).unwrap();
+
+        // Both connections are not established.
+        assert!(!pipe.client.is_established() && !pipe.server.is_established());
+
+        // Server closes before connection is established.
+        pipe.server.close(true, 123, b"fail whale").unwrap();
+
+        testing::process_flight(&mut pipe.client, flight).unwrap();
+
+        // Connection is established on the client.
+        assert!(pipe.client.is_established());
+
+        // Client sends after connection is established.
+        pipe.client.stream_send(0, b"badauthtoken", true).unwrap();
+
+        let flight = testing::emit_flight(&mut pipe.client).unwrap();
+        testing::process_flight(&mut pipe.server, flight).unwrap();
+
+        // Connection is not established on the server (and never will be)
+        assert!(!pipe.server.is_established());
+
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(
+            pipe.server.local_error(),
+            Some(&ConnectionError {
+                is_app: false,
+                error_code: 0x0c,
+                reason: vec![],
+            })
+        );
+        assert_eq!(
+            pipe.client.peer_error(),
+            Some(&ConnectionError {
+                is_app: false,
+                error_code: 0x0c,
+                reason: vec![],
+            })
+        );
+    }
+
+    #[test]
+    fn app_close_by_server_during_handshake_established() {
+        let mut pipe = testing::Pipe::new().unwrap();
+
+        // Client sends initial flight.
+        let flight = testing::emit_flight(&mut pipe.client).unwrap();
+        testing::process_flight(&mut pipe.server, flight).unwrap();
+
+        let flight = testing::emit_flight(&mut pipe.server).unwrap();
+
+        // Both connections are not established.
+        assert!(!pipe.client.is_established() && !pipe.server.is_established());
+
+        testing::process_flight(&mut pipe.client, flight).unwrap();
+
+        // Connection is established on the client.
+        assert!(pipe.client.is_established());
+
+        // Client sends after connection is established.
+        pipe.client.stream_send(0, b"badauthtoken", true).unwrap();
+
+        let flight = testing::emit_flight(&mut pipe.client).unwrap();
+        testing::process_flight(&mut pipe.server, flight).unwrap();
+
+        // Connection is established on the server but the Handshake ACK has not
+        // been sent yet.
+        assert!(pipe.server.is_established());
+
+        // Server closes after connection is established.
+        pipe.server
+            .close(true, 123, b"Invalid authentication")
+            .unwrap();
+
+        // Server sends Handshake ACK and then 1RTT CONNECTION_CLOSE.
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(
+            pipe.server.local_error(),
+            Some(&ConnectionError {
+                is_app: true,
+                error_code: 123,
+                reason: b"Invalid authentication".to_vec()
+            })
+        );
+        assert_eq!(
+            pipe.client.peer_error(),
+            Some(&ConnectionError {
+                is_app: true,
+                error_code: 123,
+                reason: b"Invalid authentication".to_vec()
+            })
+        );
+    }
+
+    #[test]
+    fn peer_error() {
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(pipe.server.close(false, 0x1234, b"hello?"), Ok(()));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(
+            pipe.client.peer_error(),
+            Some(&ConnectionError {
+                is_app: false,
+                error_code: 0x1234u64,
+                reason: b"hello?".to_vec()
+            })
+        );
+    }
+
+    #[test]
+    fn connection_error_codes() {
+        let mut err = ConnectionError {
+            is_app: false,
+            error_code: 0x3,
+            reason: vec![],
+        };
+
+        assert_eq!(err.wire_error_code(), Some(WireErrorCode::FlowControlError));
+        assert_eq!(err.tls_alert(), None);
+
+        // CRYPTO_ERROR carrying a bad_certificate alert.
+        err.error_code = 0x12a;
+        assert_eq!(err.wire_error_code(), None);
+        assert_eq!(err.tls_alert(), Some(42));
+
+        // Application errors have no transport meaning.
+        err.is_app = true;
+        err.error_code = 0x3;
+        assert_eq!(err.wire_error_code(), None);
+        assert_eq!(err.tls_alert(), None);
+    }
+
+    #[test]
+    fn app_peer_error() {
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(pipe.server.close(true, 0x1234, b"hello!"), Ok(()));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(
+            pipe.client.peer_error(),
+            Some(&ConnectionError {
+                is_app: true,
+                error_code: 0x1234u64,
+                reason: b"hello!".to_vec()
+            })
+        );
+    }
+
+    #[test]
+    fn local_error() {
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(pipe.server.local_error(), None);
+
+        assert_eq!(pipe.server.close(true, 0x1234, b"hello!"), Ok(()));
+
+        assert_eq!(
+            pipe.server.local_error(),
+            Some(&ConnectionError {
+                is_app: true,
+                error_code: 0x1234u64,
+                reason: b"hello!".to_vec()
+            })
+        );
+    }
+
+    #[test]
+    fn update_max_datagram_size() {
+        let mut client_scid = [0; 16];
+        rand::rand_bytes(&mut client_scid[..]);
+        let client_scid = ConnectionId::from_ref(&client_scid);
+        let client_addr = "127.0.0.1:1234".parse().unwrap();
+
+        let mut server_scid = [0; 16];
+        rand::rand_bytes(&mut server_scid[..]);
+        let server_scid = ConnectionId::from_ref(&server_scid);
+        let server_addr = "127.0.0.1:4321".parse().unwrap();
+
+        let mut client_config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        client_config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        client_config.set_max_recv_udp_payload_size(1200);
+
+        let mut server_config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        server_config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        server_config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        server_config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        server_config.verify_peer(false);
+        server_config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        // Larger than the client
+        server_config.set_max_send_udp_payload_size(1500);
+
+        let mut pipe = testing::Pipe {
+            client: connect(
+                Some("quic.tech"),
+                &client_scid,
+                client_addr,
+                server_addr,
+                &mut client_config,
+            )
+            .unwrap(),
+            server: accept(
+                &server_scid,
+                None,
+                server_addr,
+                client_addr,
+                &mut server_config,
+            )
+            .unwrap(),
+            client_link: Default::default(),
+            server_link: Default::default(),
+        };
+
+        // Before handshake
+        assert_eq!(
+            pipe.server
+                .paths
+                .get_active()
+                .expect("no active")
+                .recovery
+                .max_datagram_size(),
+            1500,
+        );
+
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        // After handshake, max_datagram_size should match to client's
+        // max_recv_udp_payload_size which is smaller
+        assert_eq!(
+            pipe.server
+                .paths
+                .get_active()
+                .expect("no active")
+                .recovery
+                .max_datagram_size(),
+            1200,
+        );
+        assert_eq!(
+            pipe.server
+                .paths
+                .get_active()
+                .expect("no active")
+                .recovery
+                .cwnd(),
+            12000,
+        );
+    }
+
+    #[test]
+    fn send_recv_batch() {
+        let mut buf = [0; 65535];
+
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.set_initial_max_data(100000);
+        config.set_initial_max_stream_data_bidi_local(10000);
+        config.set_initial_max_stream_data_bidi_remote(10000);
+        config.set_initial_max_streams_bidi(10);
+        config.verify_peer(false);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(
+            pipe.client.send_batch(&mut buf, 0),
+            Err(Error::BufferTooShort)
+        );
+
+        assert_eq!(pipe.client.stream_send(0, &[0xba; 5000], true), Ok(5000));
+
+        let segment_size = pipe.client.max_send_udp_payload_size();
+
+        let (len, info) = pipe.client.send_batch(&mut buf, segment_size).unwrap();
+        assert!(len > segment_size * 4);
+        assert_eq!(info.from, testing::Pipe::client_addr());
+        assert_eq!(info.to, testing::Pipe::server_addr());
+
+        let recv_info = RecvInfo {
+            from: info.from,
+            to: info.to,
+        };
+
+        assert_eq!(
+            pipe.server
+                .recv_batch(&mut buf[..len], segment_size, recv_info),
+            Ok(len)
+        );
+
+        let mut b = [0; 5000];
+        assert_eq!(pipe.server.stream_recv(0, &mut b), Ok((5000, true)));
+        assert_eq!(b, [0xba; 5000]);
+    }
+
+    #[test]
+    fn send_batch_quantum() {
+        let mut buf = [0; 65535];
+
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.set_initial_max_data(1000000);
+        config.set_initial_max_stream_data_bidi_local(1000000);
+        config.set_initial_max_stream_data_bidi_remote(1000000);
+        config.set_initial_max_streams_bidi(10);
+        config.verify_peer(false);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(
+            pipe.client.stream_send(0, &[0xba; 100000], false),
+            Ok(100000)
+        );
+
+        let segment_size = pipe.client.max_send_udp_payload_size();
+
+        let mut total = 0;
+
+        loop {
+            let quantum = pipe.client.send_budget();
+            assert!(quantum >= segment_size);
+
+            match pipe.client.send_batch(&mut buf, segment_size) {
+                Ok((len, _)) => {
+                    assert!(len <= quantum / segment_size * segment_size);
+
+                    total += len;
+                },
+
+                Err(Error::Done) => break,
+
+                Err(e) => panic!("{:?}", e),
+            }
+        }
+
+        // The congestion window is full, but a single datagram can still be
+        // sent, e.g. to carry ACKs.
+        let path = pipe.client.paths.get_active().unwrap();
+        assert!(total >= path.recovery.cwnd() - segment_size);
+        assert!(path.recovery.cwnd_available() < segment_size);
+        assert_eq!(pipe.client.send_budget(), segment_size);
+        assert_eq!(pipe.client.send_quantum(), path.recovery.send_quantum());
+    }
+
+    #[test]
+    fn send_ready_at() {
+        let mut buf = [0; 65535];
+
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.set_initial_max_data(1000000);
+        config.set_initial_max_stream_data_bidi_local(1000000);
+        config.set_initial_max_stream_data_bidi_remote(1000000);
+        config.set_initial_max_streams_bidi(10);
+        config.verify_peer(false);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let path = pipe.client.paths.get_active().unwrap();
+        assert_eq!(
+            pipe.client.send_ready_at(),
+            Some(path.recovery.get_packet_send_time())
+        );
+
+        assert_eq!(
+            pipe.client.stream_send(0, &[0xba; 100000], false),
+            Ok(100000)
+        );
+
+        loop {
+            match pipe.client.send(&mut buf) {
+                Ok(_) => (),
+
+                Err(Error::Done) => break,
+
+                Err(e) => panic!("{:?}", e),
+            }
+        }
+
+        // The congestion window is full, so sending is blocked until ACKs
+        // are received.
+        assert_eq!(pipe.client.send_ready_at(), None);
+    }
+
+    #[derive(Default)]
+    struct RingProtector {
+        calls: std::sync::atomic::AtomicUsize,
+        packets: std::sync::atomic::AtomicUsize,
+    }
+
+    impl PacketProtector for RingProtector {
+        fn protect(
+            &self, keys: &PacketKeys, packets: &mut [UnprotectedPacket],
+        ) -> Result<()> {
+            use ring::aead;
+
+            use std::sync::atomic::Ordering;
+
+            let (alg, hp_alg) = match keys.cipher_suite {
+                CipherSuite::Aes128GcmSha256 =>
+                    (&aead::AES_128_GCM, &aead::quic::AES_128),
+
+                CipherSuite::Aes256GcmSha384 =>
+                    (&aead::AES_256_GCM, &aead::quic::AES_256),
+
+                CipherSuite::ChaCha20Poly1305Sha256 =>
+                    (&aead::CHACHA20_POLY1305, &aead::quic::CHACHA20),
+            };
+
+            let key = aead::LessSafeKey::new(
+                aead::UnboundKey::new(alg, keys.key).unwrap(),
+            );
+            let hpk = aead::quic::HeaderProtectionKey::new(hp_alg, keys.hp_key)
+                .unwrap();
+
+            for p in packets.iter_mut() {
+                let mut nonce = [0; aead::NONCE_LEN];
+                nonce.copy_from_slice(keys.iv);
+
+                for (n, b) in nonce[4..].iter_mut().zip(p.pkt_num.to_be_bytes()) {
+                    *n ^= b;
+                }
+
+                let tag_off = p.payload.len() - alg.tag_len();
+
+                let tag = key
+                    .seal_in_place_separate_tag(
+                        aead::Nonce::assume_unique_for_key(nonce),
+                        aead::Aad::from(&p.header[..]),
+                        &mut p.payload[..tag_off],
+                    )
+                    .map_err(|_| Error::CryptoFail)?;
+
+                p.payload[tag_off..].copy_from_slice(tag.as_ref());
+
+                let sample_off = 4 - p.pkt_num_len;
+                let mask = hpk
+                    .new_mask(&p.payload[sample_off..sample_off + 16])
+                    .map_err(|_| Error::CryptoFail)?;
+
+                p.header[0] ^= mask[0] & 0x1f;
+
+                let pn_off = p.header.len() - p.pkt_num_len;
+                for i in 0..p.pkt_num_len {
+                    p.header[pn_off + i] ^= mask[i + 1];
+                }
+            }
+
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            self.packets.fetch_add(packets.len(), Ordering::Relaxed);
+
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn send_batch_packet_protector() {
+        use std::sync::atomic::Ordering;
+
+        let mut buf = [0; 65535];
+
+        let protector = Arc::new(RingProtector::default());
+
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.set_initial_max_data(100000);
+        config.set_initial_max_stream_data_bidi_local(10000);
+        config.set_initial_max_stream_data_bidi_remote(10000);
+        config.set_initial_max_streams_bidi(10);
+        config.verify_peer(false);
+        config.set_packet_protector(protector.clone());
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        // 1-RTT packets sent during the handshake are protected one by one.
+        let calls = protector.calls.load(Ordering::Relaxed);
+        assert!(calls > 0);
+        assert_eq!(protector.packets.load(Ordering::Relaxed), calls);
+
+        assert_eq!(pipe.client.stream_send(0, &[0xba; 5000], true), Ok(5000));
+
+        let segment_size = pipe.client.max_send_udp_payload_size();
+
+        let (len, info) = pipe.client.send_batch(&mut buf, segment_size).unwrap();
+        assert!(len > segment_size * 4);
+
+        // All the packets of the batch are protected in a single call.
+        assert_eq!(protector.calls.load(Ordering::Relaxed), calls + 1);
+        assert_eq!(
+            protector.packets.load(Ordering::Relaxed),
+            calls + (len + segment_size - 1) / segment_size
+        );
+
+        let recv_info = RecvInfo {
+            from: info.from,
+            to: info.to,
+        };
+
+        assert_eq!(
+            pipe.server
+                .recv_batch(&mut buf[..len], segment_size, recv_info),
+            Ok(len)
+        );
+
+        let mut b = [0; 5000];
+        assert_eq!(pipe.server.stream_recv(0, &mut b), Ok((5000, true)));
+        assert_eq!(b, [0xba; 5000]);
+    }
+
+    #[test]
+    /// Tests that connection-level send capacity decreases as more stream data
+    /// is buffered.
+    fn send_capacity() {
+        let mut buf = [0; 65535];
+
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.set_initial_max_data(100000);
+        config.set_initial_max_stream_data_bidi_local(10000);
+        config.set_initial_max_stream_data_bidi_remote(10000);
+        config.set_initial_max_streams_bidi(10);
+        config.verify_peer(false);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(pipe.client.stream_send(0, b"hello!", true), Ok(6));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.client.stream_send(4, b"hello!", true), Ok(6));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.client.stream_send(8, b"hello!", true), Ok(6));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.client.stream_send(12, b"hello!", true), Ok(6));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        let mut r = pipe.server.readable().collect::<Vec<u64>>();
+        assert_eq!(r.len(), 4);
+
+        r.sort();
+
+        assert_eq!(r, [0, 4, 8, 12]);
+
+        assert_eq!(pipe.server.stream_recv(0, &mut buf), Ok((6, true)));
+        assert_eq!(pipe.server.stream_recv(4, &mut buf), Ok((6, true)));
+        assert_eq!(pipe.server.stream_recv(8, &mut buf), Ok((6, true)));
+        assert_eq!(pipe.server.stream_recv(12, &mut buf), Ok((6, true)));
+
+        assert_eq!(pipe.server.tx_cap, 12000);
+
+        assert_eq!(pipe.server.stream_send(0, &buf[..5000], false), Ok(5000));
+        assert_eq!(pipe.server.stream_send(4, &buf[..5000], false), Ok(5000));
+        assert_eq!(pipe.server.stream_send(8, &buf[..5000], false), Ok(2000));
+
+        // No more connection send capacity.
+        assert_eq!(
+            pipe.server.stream_send(12, &buf[..5000], false),
+            Err(Error::Done)
+        );
+        assert_eq!(pipe.server.tx_cap, 0);
+
+        assert_eq!(pipe.advance(), Ok(()));
+    }
+
+    #[cfg(feature = "boringssl-boring-crate")]
+    #[test]
+    fn user_provided_boring_ctx() -> Result<()> {
+        // Manually construct boring ssl ctx for server
+        let mut server_tls_ctx_builder =
+            boring::ssl::SslContextBuilder::new(boring::ssl::SslMethod::tls())
+                .unwrap();
+        server_tls_ctx_builder
+            .set_certificate_chain_file("examples/cert.crt")
+            .unwrap();
+        server_tls_ctx_builder
+            .set_private_key_file(
+                "examples/cert.key",
+                boring::ssl::SslFiletype::PEM,
+            )
+            .unwrap();
+
+        let mut server_config = Config::with_boring_ssl_ctx_builder(
+            crate::PROTOCOL_VERSION,
+            server_tls_ctx_builder,
+        )?;
+        let mut client_config = Config::new(crate::PROTOCOL_VERSION)?;
+        client_config.load_cert_chain_from_pem_file("examples/cert.crt")?;
+        client_config.load_priv_key_from_pem_file("examples/cert.key")?;
+
+        for config in [&mut client_config, &mut server_config] {
+            config.set_application_protos(&[b"proto1", b"proto2"])?;
+            config.set_initial_max_data(30);
+            config.set_initial_max_stream_data_bidi_local(15);
+            config.set_initial_max_stream_data_bidi_remote(15);
+            config.set_initial_max_stream_data_uni(10);
+            config.set_initial_max_streams_bidi(3);
+            config.set_initial_max_streams_uni(3);
+            config.set_max_idle_timeout(180_000);
+            config.verify_peer(false);
+            config.set_ack_delay_exponent(8);
+        }
+
+        let mut client_scid = [0; 16];
+        rand::rand_bytes(&mut client_scid[..]);
+        let client_scid = ConnectionId::from_ref(&client_scid);
+        let client_addr = "127.0.0.1:1234".parse().unwrap();
+
+        let mut server_scid = [0; 16];
+        rand::rand_bytes(&mut server_scid[..]);
+        let server_scid = ConnectionId::from_ref(&server_scid);
+        let server_addr = "127.0.0.1:4321".parse().unwrap();
+
+        let mut pipe = testing::Pipe {
+            client: connect(
+                Some("quic.tech"),
+                &client_scid,
+                client_addr,
+                server_addr,
+                &mut client_config,
+            )?,
+            server: accept(
+                &server_scid,
+                None,
+                server_addr,
+                client_addr,
+                &mut server_config,
+            )?,
+            client_link: Default::default(),
+            server_link: Default::default(),
+        };
+
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that resetting a stream restores flow control for unsent data.
+    fn last_tx_data_larger_than_tx_data() {
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.set_initial_max_data(12000);
+        config.set_initial_max_stream_data_bidi_local(20000);
+        config.set_initial_max_stream_data_bidi_remote(20000);
+        config.set_max_recv_udp_payload_size(1200);
+        config.verify_peer(false);
+
+        let mut pipe = testing::Pipe::with_client_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        // Client opens stream 4 and 8.
+        assert_eq!(pipe.client.stream_send(4, b"a", true), Ok(1));
+        assert_eq!(pipe.client.stream_send(8, b"b", true), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // Server reads stream data.
+        let mut b = [0; 15];
+        pipe.server.stream_recv(4, &mut b).unwrap();
+
+        // Server sends stream data close to cwnd (12000).
+        let buf = [0; 10000];
+        assert_eq!(pipe.server.stream_send(4, &buf, false), Ok(10000));
+
+        testing::emit_flight(&mut pipe.server).unwrap();
+
+        // Server buffers some data, until send capacity limit reached.
+        let mut buf = [0; 1200];
+        assert_eq!(pipe.server.stream_send(4, &buf, false), Ok(1200));
+        assert_eq!(pipe.server.stream_send(8, &buf, false), Ok(800));
+        assert_eq!(pipe.server.stream_send(4, &buf, false), Err(Error::Done));
+
+        // Wait for PTO to expire.
+        let timer = pipe.server.timeout().unwrap();
+        std::thread::sleep(timer + time::Duration::from_millis(1));
+
+        pipe.server.on_timeout();
+
+        // Server sends PTO probe (not limited to cwnd),
+        // to update last_tx_data.
+        let (len, _) = pipe.server.send(&mut buf).unwrap();
+        assert_eq!(len, 1200);
+
+        // Client sends STOP_SENDING to decrease tx_data
+        // by unsent data. It will make last_tx_data > tx_data
+        // and trigger #1232 bug.
+        let frames = [frame::Frame::StopSending {
+            stream_id: 4,
+            error_code: 42,
+        }];
+
+        let pkt_type = packet::Type::Short;
+        pipe.send_pkt_to_server(pkt_type, &frames, &mut buf)
+            .unwrap();
+    }
+
+    /// Tests that when the client provides a new ConnectionId, it eventually
+    /// reaches the server and notifies the application.
+    #[test]
+    fn send_connection_ids() {
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.verify_peer(false);
+        config.set_active_connection_id_limit(3);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        // So far, there should not have any QUIC event.
+        assert_eq!(pipe.client.path_event_next(), None);
+        assert_eq!(pipe.server.path_event_next(), None);
+        assert_eq!(pipe.client.scids_left(), 2);
+
+        let (scid, reset_token) = testing::create_cid_and_reset_token(16);
+        assert_eq!(pipe.client.new_scid(&scid, reset_token, false), Ok(1));
+
+        // Let exchange packets over the connection.
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // At this point, the server should be notified that it has a new CID.
+        assert_eq!(pipe.server.available_dcids(), 1);
+        assert_eq!(pipe.server.path_event_next(), None);
+        assert_eq!(pipe.client.path_event_next(), None);
+        assert_eq!(pipe.client.scids_left(), 1);
+
+        // Now, a second CID can be provided.
+        let (scid, reset_token) = testing::create_cid_and_reset_token(16);
+        assert_eq!(pipe.client.new_scid(&scid, reset_token, false), Ok(2));
+
+        // Let exchange packets over the connection.
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // At this point, the server should be notified that it has a new CID.
+        assert_eq!(pipe.server.available_dcids(), 2);
+        assert_eq!(pipe.server.path_event_next(), None);
+        assert_eq!(pipe.client.path_event_next(), None);
+        assert_eq!(pipe.client.scids_left(), 0);
+
+        // If now the client tries to send another CID, it reports an error
+        // since it exceeds the limit of active CIDs.
+        let (scid, reset_token) = testing::create_cid_and_reset_token(16);
+        assert_eq!(
+            pipe.client.new_scid(&scid, reset_token, false),
+            Err(Error::IdLimit),
+        );
+    }
+
+    #[test]
+    /// Tests that NEW_CONNECTION_ID with zero-length CID are rejected.
+    fn connection_id_zero() {
+        let mut buf = [0; 65535];
+
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.verify_peer(false);
+        config.set_active_connection_id_limit(2);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let mut frames = Vec::new();
+
+        // Client adds a CID that is too short.
+        let (scid, reset_token) = testing::create_cid_and_reset_token(0);
+
+        frames.push(frame::Frame::NewConnectionId {
+            seq_num: 1,
+            retire_prior_to: 0,
+            conn_id: scid.to_vec(),
+            reset_token: reset_token.to_be_bytes(),
+        });
+
+        let pkt_type = packet::Type::Short;
+
+        let written =
+            testing::encode_pkt(&mut pipe.client, pkt_type, &frames, &mut buf)
+                .unwrap();
+
+        let active_path = pipe.server.paths.get_active().unwrap();
+        let info = RecvInfo {
+            to: active_path.local_addr(),
+            from: active_path.peer_addr(),
+        };
+
+        assert_eq!(
+            pipe.server.recv(&mut buf[..written], info),
+            Err(Error::InvalidFrame)
+        );
+
+        let err = pipe.server.local_error().unwrap();
+        assert_eq!(
+            pipe.server.local_error_frame().map(|(ty, _)| ty),
+            Some(0x18)
+        );
+        assert_eq!(
+            err.wire_error_code(),
+            Some(WireErrorCode::FrameEncodingError)
+        );
+
+        let written = match pipe.server.send(&mut buf) {
+            Ok((write, _)) => write,
+
+            Err(_) => unreachable!(),
+        };
+
+        let frames =
+            testing::decode_pkt(&mut pipe.client, &mut buf[..written]).unwrap();
+        let mut iter = frames.iter();
+
+        assert_eq!(
+            iter.next(),
+            Some(&frame::Frame::ConnectionClose {
+                error_code: 0x7,
+                frame_type: 0x18,
+                reason: Vec::new(),
+            })
+        );
+    }
+
+    #[test]
+    /// Tests that NEW_CONNECTION_ID with too long CID are rejected.
+    fn connection_id_invalid_max_len() {
+        let mut buf = [0; 65535];
+
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.verify_peer(false);
+        config.set_active_connection_id_limit(2);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let mut frames = Vec::new();
+
+        // Client adds a CID that is too long.
+        let (scid, reset_token) =
+            testing::create_cid_and_reset_token(MAX_CONN_ID_LEN + 1);
+
+        frames.push(frame::Frame::NewConnectionId {
+            seq_num: 1,
+            retire_prior_to: 0,
+            conn_id: scid.to_vec(),
+            reset_token: reset_token.to_be_bytes(),
+        });
+
+        let pkt_type = packet::Type::Short;
+
+        let written =
+            testing::encode_pkt(&mut pipe.client, pkt_type, &frames, &mut buf)
+                .unwrap();
+
+        let active_path = pipe.server.paths.get_active().unwrap();
+        let info = RecvInfo {
+            to: active_path.local_addr(),
+            from: active_path.peer_addr(),
+        };
+
+        assert_eq!(
+            pipe.server.recv(&mut buf[..written], info),
+            Err(Error::InvalidFrame)
+        );
+
+        let written = match pipe.server.send(&mut buf) {
+            Ok((write, _)) => write,
+
+            Err(_) => unreachable!(),
+        };
+
+        let frames =
+            testing::decode_pkt(&mut pipe.client, &mut buf[..written]).unwrap();
+        let mut iter = frames.iter();
+
+        assert_eq!(
+            iter.next(),
+            Some(&frame::Frame::ConnectionClose {
+                error_code: 0x7,
+                frame_type: 0x18,
+                reason: Vec::new(),
+            })
+        );
+    }
+
+    #[test]
+    /// Exercises the handling of NEW_CONNECTION_ID and RETIRE_CONNECTION_ID
+    /// frames.
+    fn connection_id_handling() {
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.verify_peer(false);
+        config.set_active_connection_id_limit(2);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        // So far, there should not have any QUIC event.
+        assert_eq!(pipe.client.path_event_next(), None);
+        assert_eq!(pipe.server.path_event_next(), None);
+        assert_eq!(pipe.client.scids_left(), 1);
+
+        let scid = pipe.client.source_id().into_owned();
+
+        let (scid_1, reset_token_1) = testing::create_cid_and_reset_token(16);
+        assert_eq!(pipe.client.new_scid(&scid_1, reset_token_1, false), Ok(1));
+
+        // Let exchange packets over the connection.
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // At this point, the server should be notified that it has a new CID.
+        assert_eq!(pipe.server.available_dcids(), 1);
+        assert_eq!(pipe.server.path_event_next(), None);
+        assert_eq!(pipe.client.path_event_next(), None);
+        assert_eq!(pipe.client.scids_left(), 0);
+
+        // Now we assume that the client wants to advertise more source
+        // Connection IDs than the advertised limit. This is valid if it
+        // requests its peer to retire enough Connection IDs to fit within the
+        // limits.
+
+        let (scid_2, reset_token_2) = testing::create_cid_and_reset_token(16);
+        assert_eq!(pipe.client.new_scid(&scid_2, reset_token_2, true), Ok(2));
+
+        // Let exchange packets over the connection.
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // At this point, the server still have a spare DCID.
+        assert_eq!(pipe.server.available_dcids(), 1);
+        assert_eq!(pipe.server.path_event_next(), None);
+
+        // Client should have received a retired notification.
+        assert_eq!(pipe.client.retired_scid_next(), Some(scid));
+        assert_eq!(pipe.client.retired_scid_next(), None);
+
+        assert_eq!(pipe.client.path_event_next(), None);
+        assert_eq!(pipe.client.scids_left(), 0);
+
+        // The active Destination Connection ID of the server should now be the
+        // one with sequence number 1.
+        assert_eq!(pipe.server.destination_id(), scid_1);
+
+        // Now tries to experience CID retirement. If the server tries to remove
+        // non-existing DCIDs, it fails.
+        assert_eq!(pipe.server.retire_dcid(0), Err(Error::InvalidState));
+        assert_eq!(pipe.server.retire_dcid(3), Err(Error::InvalidState));
+
+        // Now it removes DCID with sequence 1.
+        assert_eq!(pipe.server.retire_dcid(1), Ok(()));
+
+        // Let exchange packets over the connection.
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.server.path_event_next(), None);
+        assert_eq!(pipe.client.retired_scid_next(), Some(scid_1));
+        assert_eq!(pipe.client.retired_scid_next(), None);
+
+        assert_eq!(pipe.server.destination_id(), scid_2);
+        assert_eq!(pipe.server.available_dcids(), 0);
+
+        // Trying to remove the last DCID triggers an error.
+        assert_eq!(pipe.server.retire_dcid(2), Err(Error::OutOfIdentifiers));
+    }
+
+    #[test]
+    fn next_timeout_reason() {
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_streams_bidi(3);
+        config.set_max_idle_timeout(180_000);
+        config.verify_peer(false);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(pipe.client.stream_send(0, b"hello", true), Ok(5));
+
+        // Packets are sent, but never received.
+        testing::emit_flight(&mut pipe.client).unwrap();
+
+        assert_eq!(
+            pipe.client.next_timeout_reason(),
+            Some(TimeoutReason::LossDetection)
+        );
+
+        let path = pipe.client.paths.get_active().unwrap();
+        assert_eq!(
+            pipe.client.timeout_instant(),
+            path.recovery.loss_detection_timer()
+        );
+
+        // Once everything is acknowledged only the idle timer is left.
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.server.next_timeout_reason(), Some(TimeoutReason::Idle));
+        assert_eq!(pipe.server.timeout_instant(), pipe.server.idle_timer);
+
+        assert_eq!(pipe.server.close(false, 0x1234, b"hello?"), Ok(()));
+        testing::emit_flight(&mut pipe.server).unwrap();
+
+        assert_eq!(
+            pipe.server.next_timeout_reason(),
+            Some(TimeoutReason::Draining)
+        );
+        assert_eq!(pipe.server.timeout_instant(), pipe.server.draining_timer);
+    }
+
+    #[test]
+    fn lost_connection_id_frames() {
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.verify_peer(false);
+        config.set_active_connection_id_limit(2);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let scid = pipe.client.source_id().into_owned();
+
+        let (scid_1, reset_token_1) = testing::create_cid_and_reset_token(16);
+        assert_eq!(pipe.client.new_scid(&scid_1, reset_token_1, false), Ok(1));
+
+        // Packets are sent, but never received.
+        testing::emit_flight(&mut pipe.client).unwrap();
+
+        // Wait until timer expires. Since the RTT is very low, wait a bit more.
+        let timer = pipe.client.timeout().unwrap();
+        std::thread::sleep(timer + time::Duration::from_millis(1));
+
+        pipe.client.on_timeout();
+
+        // Let exchange packets over the connection.
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // At this point, the server should be notified that it has a new CID.
+        assert_eq!(pipe.server.available_dcids(), 1);
+
+        // Now the server retires the first Destination CID.
+        assert_eq!(pipe.server.retire_dcid(0), Ok(()));
+
+        // But the packet never reaches the client.
+        testing::emit_flight(&mut pipe.server).unwrap();
+
+        // Wait until timer expires. Since the RTT is very low, wait a bit more.
+        let timer = pipe.server.timeout().unwrap();
+        std::thread::sleep(timer + time::Duration::from_millis(1));
+
+        pipe.server.on_timeout();
+
+        // Let exchange packets over the connection.
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.client.retired_scid_next(), Some(scid));
+        assert_eq!(pipe.client.retired_scid_next(), None);
+    }
+
+    #[test]
+    fn sending_duplicate_scids() {
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.verify_peer(false);
+        config.set_active_connection_id_limit(3);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let (scid_1, reset_token_1) = testing::create_cid_and_reset_token(16);
+        assert_eq!(pipe.client.new_scid(&scid_1, reset_token_1, false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // Trying to send the same CID with a different reset token raises an
+        // InvalidState error.
+        let reset_token_2 = reset_token_1.wrapping_add(1);
+        assert_eq!(
+            pipe.client.new_scid(&scid_1, reset_token_2, false),
+            Err(Error::InvalidState),
+        );
+
+        // Retrying to send the exact same CID with the same token returns the
+        // previously assigned CID seq, but without sending anything.
+        assert_eq!(pipe.client.new_scid(&scid_1, reset_token_1, false), Ok(1));
+        assert!(!pipe.client.ids.has_new_scids());
+
+        // Now retire this new CID.
+        assert_eq!(pipe.server.retire_dcid(1), Ok(()));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // It is up to the application to ensure that a given SCID is not reused
+        // later.
+        assert_eq!(pipe.client.new_scid(&scid_1, reset_token_1, false), Ok(2));
+    }
+
+    // Utility function.
+    fn pipe_with_exchanged_cids(
+        config: &mut Config, client_scid_len: usize, server_scid_len: usize,
+        additional_cids: usize,
+    ) -> testing::Pipe {
+        let mut pipe = testing::Pipe::with_config_and_scid_lengths(
+            config,
+            client_scid_len,
+            server_scid_len,
+        )
+        .unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let mut c_cids = Vec::new();
+        let mut c_reset_tokens = Vec::new();
+        let mut s_cids = Vec::new();
+        let mut s_reset_tokens = Vec::new();
+
+        for i in 0..additional_cids {
+            if client_scid_len > 0 {
+                let (c_cid, c_reset_token) =
+                    testing::create_cid_and_reset_token(client_scid_len);
+                c_cids.push(c_cid);
+                c_reset_tokens.push(c_reset_token);
+
+                assert_eq!(
+                    pipe.client.new_scid(&c_cids[i], c_reset_tokens[i], true),
+                    Ok(i as u64 + 1)
+                );
+            }
+
+            if server_scid_len > 0 {
+                let (s_cid, s_reset_token) =
+                    testing::create_cid_and_reset_token(server_scid_len);
+                s_cids.push(s_cid);
+                s_reset_tokens.push(s_reset_token);
+                assert_eq!(
+                    pipe.server.new_scid(&s_cids[i], s_reset_tokens[i], true),
+                    Ok(i as u64 + 1)
+                );
+            }
+        }
+
+        // Let exchange packets over the connection.
+        assert_eq!(pipe.advance(), Ok(()));
+
+        if client_scid_len > 0 {
+            assert_eq!(pipe.server.available_dcids(), additional_cids);
+        }
+
+        if server_scid_len > 0 {
+            assert_eq!(pipe.client.available_dcids(), additional_cids);
+        }
+
+        assert_eq!(pipe.server.path_event_next(), None);
+        assert_eq!(pipe.client.path_event_next(), None);
+
+        pipe
+    }
+
+    #[test]
+    fn path_validation() {
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.verify_peer(false);
+        config.set_active_connection_id_limit(2);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let server_addr = testing::Pipe::server_addr();
+        let client_addr_2 = "127.0.0.1:5678".parse().unwrap();
+
+        // We cannot probe a new path if there are not enough identifiers.
+        assert_eq!(
+            pipe.client.probe_path(client_addr_2, server_addr),
+            Err(Error::OutOfIdentifiers)
+        );
+
+        let (c_cid, c_reset_token) = testing::create_cid_and_reset_token(16);
+
+        assert_eq!(pipe.client.new_scid(&c_cid, c_reset_token, true), Ok(1));
+
+        let (s_cid, s_reset_token) = testing::create_cid_and_reset_token(16);
+        assert_eq!(pipe.server.new_scid(&s_cid, s_reset_token, true), Ok(1));
+
+        // We need to exchange the CIDs first.
+        assert_eq!(
+            pipe.client.probe_path(client_addr_2, server_addr),
+            Err(Error::OutOfIdentifiers)
+        );
+
+        // Let exchange packets over the connection.
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.server.available_dcids(), 1);
+        assert_eq!(pipe.server.path_event_next(), None);
+        assert_eq!(pipe.client.available_dcids(), 1);
+        assert_eq!(pipe.client.path_event_next(), None);
+
+        // Now the path probing can work.
+        assert_eq!(pipe.client.probe_path(client_addr_2, server_addr), Ok(1));
+
+        // But the server cannot probe a yet-unseen path.
+        assert_eq!(
+            pipe.server.probe_path(server_addr, client_addr_2),
+            Err(Error::InvalidState),
+        );
+
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // The path should be validated at some point.
+        assert_eq!(
+            pipe.client.path_event_next(),
+            Some(PathEvent::Validated(client_addr_2, server_addr)),
+        );
+        assert_eq!(pipe.client.path_event_next(), None);
+
+        // The server should be notified of this new path.
+        assert_eq!(
+            pipe.server.path_event_next(),
+            Some(PathEvent::New(server_addr, client_addr_2)),
+        );
+        assert_eq!(
+            pipe.server.path_event_next(),
+            Some(PathEvent::Validated(server_addr, client_addr_2)),
+        );
+        assert_eq!(pipe.server.path_event_next(), None);
+
+        // The server can later probe the path again.
+        assert_eq!(pipe.server.probe_path(server_addr, client_addr_2), Ok(1));
+
+        // This should not trigger any event at client side.
+        assert_eq!(pipe.client.path_event_next(), None);
+        assert_eq!(pipe.server.path_event_next(), None);
+    }
+
+    #[test]
+    fn losing_probing_packets() {
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.verify_peer(false);
+        config.set_active_connection_id_limit(2);
+
+        let mut pipe = pipe_with_exchanged_cids(&mut config, 16, 16, 1);
+
+        let server_addr = testing::Pipe::server_addr();
+        let client_addr_2 = "127.0.0.1:5678".parse().unwrap();
+        assert_eq!(pipe.client.probe_path(client_addr_2, server_addr), Ok(1));
+
+        // The client creates the PATH CHALLENGE, but it is lost.
+        testing::emit_flight(&mut pipe.client).unwrap();
+
+        // Wait until probing timer expires. Since the RTT is very low,
+        // wait a bit more.
+        let probed_pid = pipe
+            .client
+            .paths
+            .path_id_from_addrs(&(client_addr_2, server_addr))
+            .unwrap();
+        let probe_instant = pipe
+            .client
+            .paths
+            .get(probed_pid)
+            .unwrap()
+            .recovery
+            .loss_detection_timer()
+            .unwrap();
+        let timer = probe_instant.duration_since(time::Instant::now());
+        std::thread::sleep(timer + time::Duration::from_millis(1));
+
+        pipe.client.on_timeout();
+
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // The path should be validated at some point.
+        assert_eq!(
+            pipe.client.path_event_next(),
+            Some(PathEvent::Validated(client_addr_2, server_addr))
+        );
+        assert_eq!(pipe.client.path_event_next(), None);
+
+        assert_eq!(
+            pipe.server.path_event_next(),
+            Some(PathEvent::New(server_addr, client_addr_2))
+        );
+        // The path should be validated at some point.
+        assert_eq!(
+            pipe.server.path_event_next(),
+            Some(PathEvent::Validated(server_addr, client_addr_2))
+        );
+        assert_eq!(pipe.server.path_event_next(), None);
+    }
+
+    #[test]
+    fn failed_path_validation() {
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.verify_peer(false);
+        config.set_active_connection_id_limit(2);
+
+        let mut pipe = pipe_with_exchanged_cids(&mut config, 16, 16, 1);
+
+        let server_addr = testing::Pipe::server_addr();
+        let client_addr_2 = "127.0.0.1:5678".parse().unwrap();
+        assert_eq!(pipe.client.probe_path(client_addr_2, server_addr), Ok(1));
+
+        for _ in 0..MAX_PROBING_TIMEOUTS {
+            // The client creates the PATH CHALLENGE, but it is always lost.
+            testing::emit_flight(&mut pipe.client).unwrap();
+
+            // Wait until probing timer expires. Since the RTT is very low,
+            // wait a bit more.
+            let probed_pid = pipe
+                .client
+                .paths
+                .path_id_from_addrs(&(client_addr_2, server_addr))
+                .unwrap();
+            let probe_instant = pipe
+                .client
+                .paths
+                .get(probed_pid)
+                .unwrap()
+                .recovery
+                .loss_detection_timer()
+                .unwrap();
+            let timer = probe_instant.duration_since(time::Instant::now());
+            std::thread::sleep(timer + time::Duration::from_millis(1));
+
+            pipe.client.on_timeout();
+        }
+
+        assert_eq!(
+            pipe.client.path_event_next(),
+            Some(PathEvent::FailedValidation(client_addr_2, server_addr)),
+        );
+    }
+
+    #[test]
+    fn client_discard_unknown_address() {
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.verify_peer(false);
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_uni(10);
+        config.set_initial_max_streams_uni(3);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        // Server sends stream data.
+        assert_eq!(pipe.server.stream_send(3, b"a", true), Ok(1));
+
+        let mut flight =
+            testing::emit_flight(&mut pipe.server).expect("no packet");
+        // Let's change the address info.
+        flight
+            .iter_mut()
+            .for_each(|(_, si)| si.from = "127.0.0.1:9292".parse().unwrap());
+        assert_eq!(testing::process_flight(&mut pipe.client, flight), Ok(()));
+        assert_eq!(pipe.client.paths.len(), 1);
+    }
+
+    #[test]
+    fn path_validation_limited_mtu() {
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.verify_peer(false);
+        config.set_active_connection_id_limit(2);
+
+        let mut pipe = pipe_with_exchanged_cids(&mut config, 16, 16, 1);
+
+        let server_addr = testing::Pipe::server_addr();
+        let client_addr_2 = "127.0.0.1:5678".parse().unwrap();
+        assert_eq!(pipe.client.probe_path(client_addr_2, server_addr), Ok(1));
+        // Limited MTU of 1199 bytes for some reason.
+        testing::process_flight(
+            &mut pipe.server,
+            testing::emit_flight_with_max_buffer(
+                &mut pipe.client,
+                1199,
+                None,
+                None,
+            )
+            .expect("no packet"),
+        )
+        .expect("error when processing client packets");
+        testing::process_flight(
+            &mut pipe.client,
+            testing::emit_flight(&mut pipe.server).expect("no packet"),
+        )
+        .expect("error when processing client packets");
+        let probed_pid = pipe
+            .client
+            .paths
+            .path_id_from_addrs(&(client_addr_2, server_addr))
+            .unwrap();
+        assert!(!pipe.client.paths.get(probed_pid).unwrap().validated(),);
+        assert_eq!(pipe.client.path_event_next(), None);
+        // Now let the client probe at its MTU.
+        assert_eq!(pipe.advance(), Ok(()));
+        assert!(pipe.client.paths.get(probed_pid).unwrap().validated());
+        assert_eq!(
+            pipe.client.path_event_next(),
+            Some(PathEvent::Validated(client_addr_2, server_addr))
+        );
+    }
+
+    #[test]
+    fn probe_path_quality() {
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.verify_peer(false);
+        config.set_active_connection_id_limit(2);
+
+        let mut pipe = pipe_with_exchanged_cids(&mut config, 16, 16, 1);
+
+        let client_addr = testing::Pipe::client_addr();
+        let server_addr = testing::Pipe::server_addr();
+        let client_addr_2 = "127.0.0.1:5678".parse().unwrap();
+
+        // Unknown paths can't be probed.
+        assert_eq!(
+            pipe.client.probe_path_quality(client_addr_2, server_addr),
+            Err(Error::InvalidState)
+        );
+
+        assert_eq!(pipe.client.probe_path(client_addr_2, server_addr), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // Probe both the active and the standby path.
+        assert_eq!(
+            pipe.client.probe_path_quality(client_addr, server_addr),
+            Ok(())
+        );
+        assert_eq!(
+            pipe.client.probe_path_quality(client_addr_2, server_addr),
+            Ok(())
+        );
+        assert_eq!(pipe.advance(), Ok(()));
+
+        for addr in [client_addr, client_addr_2] {
+            let stats = pipe
+                .client
+                .path_stats()
+                .find(|s| s.local_addr == addr)
+                .unwrap();
+
+            assert_eq!(stats.quality_probes_sent, 1);
+            assert_eq!(stats.quality_probes_acked, 1);
+            assert_eq!(stats.quality_probes_lost, 0);
+            assert!(stats.quality_probe_rtt.is_some());
+        }
+
+        // The standby path didn't become active.
+        let active = pipe.server.paths.get_active().expect("no active");
+        assert_eq!(active.peer_addr(), client_addr);
+
+        // Answered probes don't trigger validation events.
+        assert_eq!(
+            pipe.client.path_event_next(),
+            Some(PathEvent::Validated(client_addr_2, server_addr))
+        );
+        assert_eq!(pipe.client.path_event_next(), None);
+    }
+
+    #[test]
+    fn freeze_and_thaw() {
+        let mut buf = [0; 65535];
+
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_streams_bidi(3);
+        config.set_max_idle_timeout(180_000);
+        config.verify_peer(false);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.client.stream_send(0, b"hello", false), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // Unread stream data prevents freezing.
+        assert_eq!(pipe.server.freeze(), Err(Error::InvalidState));
+        assert!(!pipe.server.is_closed());
+
+        assert_eq!(pipe.server.stream_recv(0, &mut buf), Ok((5, false)));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        let state = pipe.server.freeze().unwrap();
+        assert!(pipe.server.is_closed());
+
+        pipe.server = Connection::thaw(
+            &state,
+            testing::Pipe::server_addr(),
+            testing::Pipe::client_addr(),
+            &mut config,
+        )
+        .unwrap();
+
+        assert!(pipe.server.is_established());
+        assert_eq!(pipe.server.application_proto(), b"proto1");
+
+        assert_eq!(pipe.client.stream_send(0, b"again", true), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.server.stream_recv(0, &mut buf), Ok((5, true)));
+        assert_eq!(&buf[..5], b"again");
+
+        assert_eq!(pipe.server.stream_send(0, b"bye", true), Ok(3));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.client.stream_recv(0, &mut buf), Ok((3, true)));
+        assert_eq!(&buf[..3], b"bye");
+    }
+
+    #[test]
+    fn pipe_link_impairments() {
+        let mut buf = [0; 65535];
+
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.set_initial_max_data(1_000_000);
+        config.set_initial_max_stream_data_bidi_local(1_000_000);
+        config.set_initial_max_stream_data_bidi_remote(1_000_000);
+        config.set_initial_max_streams_bidi(3);
+        config.verify_peer(false);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        // All packets are dropped.
+        pipe.client_link.set_loss(100);
+
+        assert_eq!(pipe.client.stream_send(0, b"hello", true), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert!(pipe.client_link.dropped() > 0);
+        assert_eq!(pipe.server.readable().len(), 0);
+
+        // Delayed and reordered packets are all delivered.
+        pipe.client_link.set_loss(0);
+        pipe.client_link.set_reorder(50);
+        pipe.client_link.set_delay(time::Duration::from_millis(5));
+        pipe.client_link.set_seed(42);
+
+        let data = [42; 20000];
+        assert_eq!(pipe.client.stream_send(4, &data, true), Ok(20000));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert!(pipe.client_link.reordered() > 0);
+        assert!(!pipe.client_link.has_pending());
+
+        let mut recv = 0;
+
+        while let Ok((len, _)) = pipe.server.stream_recv(4, &mut buf) {
+            recv += len;
+        }
+
+        assert_eq!(recv, 20000);
+    }
+
+    #[test]
+    fn pipe_link_model() {
+        struct DropFirst(bool);
+
+        impl testing::LinkModel for DropFirst {
+            fn on_packet(
+                &mut self, _len: usize, now: time::Instant,
+            ) -> Option<time::Instant> {
+                if !self.0 {
+                    self.0 = true;
+                    return None;
+                }
+
+                Some(now)
+            }
+        }
+
+        let mut buf = [0; 65535];
+
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.set_initial_max_data(1_000_000);
+        config.set_initial_max_stream_data_bidi_local(1_000_000);
+        config.set_initial_max_stream_data_bidi_remote(1_000_000);
+        config.set_initial_max_streams_bidi(3);
+        config.verify_peer(false);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        pipe.client_link.set_model(Box::new(DropFirst(false)));
+
+        let mut link = testing::EmulatedLink::new(7);
+        link.set_bandwidth(10_000_000);
+        link.set_delay(time::Duration::from_millis(2));
+        link.set_jitter(time::Duration::from_millis(5));
+        pipe.server_link.set_model(Box::new(link));
+
+        assert_eq!(pipe.client.stream_send(0, b"hello", true), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.client_link.dropped(), 1);
+        assert_eq!(pipe.server.readable().len(), 0);
+
+        let data = [42; 20000];
+        assert_eq!(pipe.client.stream_send(4, &data, true), Ok(20000));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        let mut recv = 0;
+
+        while let Ok((len, _)) = pipe.server.stream_recv(4, &mut buf) {
+            recv += len;
+        }
+
+        assert_eq!(recv, 20000);
+
+        assert_eq!(pipe.server.stream_send(4, &data, true), Ok(20000));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        recv = 0;
+
+        while let Ok((len, _)) = pipe.client.stream_recv(4, &mut buf) {
+            recv += len;
+        }
+
+        assert_eq!(recv, 20000);
+        assert!(!pipe.server_link.has_pending());
+    }
+
+    #[test]
+    fn path_probing_dos() {
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.verify_peer(false);
+        config.set_active_connection_id_limit(2);
+
+        let mut pipe = pipe_with_exchanged_cids(&mut config, 16, 16, 1);
+
+        let server_addr = testing::Pipe::server_addr();
+        let client_addr_2 = "127.0.0.1:5678".parse().unwrap();
+        assert_eq!(pipe.client.probe_path(client_addr_2, server_addr), Ok(1));
+
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // The path should be validated at some point.
+        assert_eq!(
+            pipe.client.path_event_next(),
+            Some(PathEvent::Validated(client_addr_2, server_addr))
+        );
+        assert_eq!(pipe.client.path_event_next(), None);
+
+        // The server should be notified of this new path.
+        assert_eq!(
+            pipe.server.path_event_next(),
+            Some(PathEvent::New(server_addr, client_addr_2))
+        );
+        assert_eq!(
+            pipe.server.path_event_next(),
+            Some(PathEvent::Validated(server_addr, client_addr_2))
+        );
+        assert_eq!(pipe.server.path_event_next(), None);
+
+        assert_eq!(pipe.server.paths.len(), 2);
+
+        // Now forge a packet reusing the unverified path's CID over another
+        // 4-tuple.
+        assert_eq!(pipe.client.probe_path(client_addr_2, server_addr), Ok(1));
+        let client_addr_3 = "127.0.0.1:9012".parse().unwrap();
+        let mut flight =
+            testing::emit_flight(&mut pipe.client).expect("no generated packet");
+        flight
+            .iter_mut()
+            .for_each(|(_, si)| si.from = client_addr_3);
+        testing::process_flight(&mut pipe.server, flight)
+            .expect("failed to process");
+        assert_eq!(pipe.server.paths.len(), 2);
+        assert_eq!(
+            pipe.server.path_event_next(),
+            Some(PathEvent::ReusedSourceConnectionId(
+                1,
+                (server_addr, client_addr_2),
+                (server_addr, client_addr_3)
+            ))
+        );
+        assert_eq!(pipe.server.path_event_next(), None);
+    }
+
+    #[test]
+    fn retiring_active_path_dcid() {
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.verify_peer(false);
+        config.set_active_connection_id_limit(2);
+
+        let mut pipe = pipe_with_exchanged_cids(&mut config, 16, 16, 1);
+        let server_addr = testing::Pipe::server_addr();
+        let client_addr_2 = "127.0.0.1:5678".parse().unwrap();
+        assert_eq!(pipe.client.probe_path(client_addr_2, server_addr), Ok(1));
+
+        assert_eq!(pipe.client.retire_dcid(0), Err(Error::OutOfIdentifiers));
+    }
+
+    #[test]
+    fn send_on_path_test() {
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.verify_peer(false);
+        config.set_initial_max_data(100000);
+        config.set_initial_max_stream_data_bidi_local(100000);
+        config.set_initial_max_stream_data_bidi_remote(100000);
+        config.set_initial_max_streams_bidi(2);
+        config.set_active_connection_id_limit(4);
+
+        let mut pipe = pipe_with_exchanged_cids(&mut config, 16, 16, 3);
+
+        let server_addr = testing::Pipe::server_addr();
+        let client_addr = testing::Pipe::client_addr();
+        let client_addr_2 = "127.0.0.1:5678".parse().unwrap();
+        assert_eq!(pipe.client.probe_path(client_addr_2, server_addr), Ok(1));
+
+        let mut buf = [0; 65535];
+        // There is nothing to send on the initial path.
+        assert_eq!(
+            pipe.client.send_on_path(
+                &mut buf,
+                Some(client_addr),
+                Some(server_addr)
+            ),
+            Err(Error::Done)
+        );
+
+        // Client should send padded PATH_CHALLENGE.
+        let (sent, si) = pipe
+            .client
+            .send_on_path(&mut buf, Some(client_addr_2), Some(server_addr))
+            .expect("No error");
+        assert_eq!(sent, MIN_CLIENT_INITIAL_LEN);
+        assert_eq!(si.from, client_addr_2);
+        assert_eq!(si.to, server_addr);
+
+        let ri = RecvInfo {
+            to: si.to,
+            from: si.from,
+        };
+        assert_eq!(pipe.server.recv(&mut buf[..sent], ri), Ok(sent));
+
+        let stats = pipe.server.stats();
+        assert_eq!(stats.path_challenge_rx_count, 1);
+
+        // A non-existing 4-tuple raises an InvalidState.
+        let client_addr_3 = "127.0.0.1:9012".parse().unwrap();
+        let server_addr_2 = "127.0.0.1:9876".parse().unwrap();
+        assert_eq!(
+            pipe.client.send_on_path(
+                &mut buf,
+                Some(client_addr_3),
+                Some(server_addr)
+            ),
+            Err(Error::InvalidState)
+        );
+        assert_eq!(
+            pipe.client.send_on_path(
+                &mut buf,
+                Some(client_addr),
+                Some(server_addr_2)
+            ),
+            Err(Error::InvalidState)
+        );
+
+        // Let's introduce some additional path challenges and data exchange.
+        assert_eq!(pipe.client.probe_path(client_addr, server_addr_2), Ok(2));
+        assert_eq!(pipe.client.probe_path(client_addr_3, server_addr), Ok(3));
+        // Just to fit in two packets.
+        assert_eq!(pipe.client.stream_send(0, &buf[..1201], true), Ok(1201));
+
+        // PATH_CHALLENGE
+        let (sent, si) = pipe
+            .client
+            .send_on_path(&mut buf, Some(client_addr), None)
+            .expect("No error");
+        assert_eq!(sent, MIN_CLIENT_INITIAL_LEN);
+        assert_eq!(si.from, client_addr);
+        assert_eq!(si.to, server_addr_2);
+
+        let ri = RecvInfo {
+            to: si.to,
+            from: si.from,
+        };
+        assert_eq!(pipe.server.recv(&mut buf[..sent], ri), Ok(sent));
+
+        let stats = pipe.server.stats();
+        assert_eq!(stats.path_challenge_rx_count, 2);
+
+        // STREAM frame on active path.
+        let (sent, si) = pipe
+            .client
+            .send_on_path(&mut buf, Some(client_addr), None)
+            .expect("No error");
+        assert_eq!(si.from, client_addr);
+        assert_eq!(si.to, server_addr);
+
+        let ri = RecvInfo {
+            to: si.to,
+            from: si.from,
+        };
+        assert_eq!(pipe.server.recv(&mut buf[..sent], ri), Ok(sent));
+
+        let stats = pipe.server.stats();
+        assert_eq!(stats.path_challenge_rx_count, 2);
+
+        // PATH_CHALLENGE
+        let (sent, si) = pipe
+            .client
+            .send_on_path(&mut buf, None, Some(server_addr))
+            .expect("No error");
+        assert_eq!(sent, MIN_CLIENT_INITIAL_LEN);
+        assert_eq!(si.from, client_addr_3);
+        assert_eq!(si.to, server_addr);
+
+        let ri = RecvInfo {
+            to: si.to,
+            from: si.from,
+        };
+        assert_eq!(pipe.server.recv(&mut buf[..sent], ri), Ok(sent));
+
+        let stats = pipe.server.stats();
+        assert_eq!(stats.path_challenge_rx_count, 3);
+
+        // STREAM frame on active path.
+        let (sent, si) = pipe
+            .client
+            .send_on_path(&mut buf, None, Some(server_addr))
+            .expect("No error");
+        assert_eq!(si.from, client_addr);
+        assert_eq!(si.to, server_addr);
+
+        let ri = RecvInfo {
+            to: si.to,
+            from: si.from,
+        };
+        assert_eq!(pipe.server.recv(&mut buf[..sent], ri), Ok(sent));
+
+        // No more data to exchange leads to Error::Done.
+        assert_eq!(
+            pipe.client.send_on_path(&mut buf, Some(client_addr), None),
+            Err(Error::Done)
+        );
+        assert_eq!(
+            pipe.client.send_on_path(&mut buf, None, Some(server_addr)),
+            Err(Error::Done)
+        );
+
+        assert_eq!(pipe.advance(), Ok(()));
+
+        let mut v1 = pipe.client.paths_iter(client_addr).collect::<Vec<_>>();
+        let mut v2 = vec![server_addr, server_addr_2];
+
+        v1.sort();
+        v2.sort();
+
+        assert_eq!(v1, v2);
+
+        let mut v1 = pipe.client.paths_iter(client_addr_2).collect::<Vec<_>>();
+        let mut v2 = vec![server_addr];
+
+        v1.sort();
+        v2.sort();
+
+        assert_eq!(v1, v2);
+
+        let mut v1 = pipe.client.paths_iter(client_addr_3).collect::<Vec<_>>();
+        let mut v2 = vec![server_addr];
+
+        v1.sort();
+        v2.sort();
+
+        assert_eq!(v1, v2);
+
+        let stats = pipe.server.stats();
+        assert_eq!(stats.path_challenge_rx_count, 3);
+    }
+
+    #[test]
+    fn connection_migration() {
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.verify_peer(false);
+        config.set_active_connection_id_limit(3);
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_stream_data_uni(10);
+        config.set_initial_max_streams_bidi(3);
+
+        let mut pipe = pipe_with_exchanged_cids(&mut config, 16, 16, 2);
+
+        let server_addr = testing::Pipe::server_addr();
+        let client_addr_2 = "127.0.0.1:5678".parse().unwrap();
+        let client_addr_3 = "127.0.0.1:9012".parse().unwrap();
+        let client_addr_4 = "127.0.0.1:8908".parse().unwrap();
+
+        // Case 1: the client first probes the new address, the server too, and
+        // then migrates.
+        assert_eq!(pipe.client.probe_path(client_addr_2, server_addr), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(
+            pipe.client.path_event_next(),
+            Some(PathEvent::Validated(client_addr_2, server_addr))
+        );
+        assert_eq!(pipe.client.path_event_next(), None);
+        assert_eq!(
+            pipe.server.path_event_next(),
+            Some(PathEvent::New(server_addr, client_addr_2))
+        );
+        assert_eq!(
+            pipe.server.path_event_next(),
+            Some(PathEvent::Validated(server_addr, client_addr_2))
+        );
+        assert_eq!(
+            pipe.client.is_path_validated(client_addr_2, server_addr),
+            Ok(true)
+        );
+        assert_eq!(
+            pipe.server.is_path_validated(server_addr, client_addr_2),
+            Ok(true)
+        );
+        // The server can never initiates the connection migration.
+        assert_eq!(
+            pipe.server.migrate(server_addr, client_addr_2),
+            Err(Error::InvalidState)
+        );
+        assert_eq!(pipe.client.migrate(client_addr_2, server_addr), Ok(1));
+        assert_eq!(pipe.client.stream_send(0, b"data", true), Ok(4));
+        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(
+            pipe.client
+                .paths
+                .get_active()
+                .expect("no active")
+                .local_addr(),
+            client_addr_2
+        );
+        assert_eq!(
+            pipe.client
+                .paths
+                .get_active()
+                .expect("no active")
+                .peer_addr(),
+            server_addr
+        );
+        assert_eq!(
+            pipe.server.path_event_next(),
+            Some(PathEvent::PeerMigrated(server_addr, client_addr_2))
+        );
+        assert_eq!(pipe.server.path_event_next(), None);
+        assert_eq!(
+            pipe.server
+                .paths
+                .get_active()
+                .expect("no active")
+                .local_addr(),
+            server_addr
+        );
+        assert_eq!(
+            pipe.server
+                .paths
+                .get_active()
+                .expect("no active")
+                .peer_addr(),
+            client_addr_2
+        );
+
+        // Case 2: the client migrates on a path that was not previously
+        // validated, and has spare SCIDs/DCIDs to do so.
+        assert_eq!(pipe.client.migrate(client_addr_3, server_addr), Ok(2));
+        assert_eq!(pipe.client.stream_send(4, b"data", true), Ok(4));
+        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(
+            pipe.client
+                .paths
+                .get_active()
+                .expect("no active")
+                .local_addr(),
+            client_addr_3
+        );
+        assert_eq!(
+            pipe.client
+                .paths
+                .get_active()
+                .expect("no active")
+                .peer_addr(),
+            server_addr
+        );
+        assert_eq!(
+            pipe.server.path_event_next(),
+            Some(PathEvent::New(server_addr, client_addr_3))
+        );
+        assert_eq!(
+            pipe.server.path_event_next(),
+            Some(PathEvent::Validated(server_addr, client_addr_3))
+        );
+        assert_eq!(
+            pipe.server.path_event_next(),
+            Some(PathEvent::PeerMigrated(server_addr, client_addr_3))
+        );
+        assert_eq!(pipe.server.path_event_next(), None);
+        assert_eq!(
+            pipe.server
+                .paths
+                .get_active()
+                .expect("no active")
+                .local_addr(),
+            server_addr
+        );
+        assert_eq!(
+            pipe.server
+                .paths
+                .get_active()
+                .expect("no active")
+                .peer_addr(),
+            client_addr_3
+        );
+
+        // Case 3: the client tries to migrate on the current active path.
+        // This is not an error, but it triggers nothing.
+        assert_eq!(pipe.client.migrate(client_addr_3, server_addr), Ok(2));
+        assert_eq!(pipe.client.stream_send(8, b"data", true), Ok(4));
+        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(pipe.client.path_event_next(), None);
+        assert_eq!(
+            pipe.client
+                .paths
+                .get_active()
+                .expect("no active")
+                .local_addr(),
+            client_addr_3
+        );
+        assert_eq!(
+            pipe.client
+                .paths
+                .get_active()
+                .expect("no active")
+                .peer_addr(),
+            server_addr
+        );
+        assert_eq!(pipe.server.path_event_next(), None);
+        assert_eq!(
+            pipe.server
+                .paths
+                .get_active()
+                .expect("no active")
+                .local_addr(),
+            server_addr
+        );
+        assert_eq!(
+            pipe.server
+                .paths
+                .get_active()
+                .expect("no active")
+                .peer_addr(),
+            client_addr_3
+        );
+
+        // Case 4: the client tries to migrate on a path that was not previously
+        // validated, and has no spare SCIDs/DCIDs. Prevent active migration.
+        assert_eq!(
+            pipe.client.migrate(client_addr_4, server_addr),
+            Err(Error::OutOfIdentifiers)
+        );
+        assert_eq!(
+            pipe.client
+                .paths
+                .get_active()
+                .expect("no active")
+                .local_addr(),
+            client_addr_3
+        );
+        assert_eq!(
+            pipe.client
+                .paths
+                .get_active()
+                .expect("no active")
+                .peer_addr(),
+            server_addr
+        );
+    }
+
+    #[test]
+    fn connection_migration_after_validation() {
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.verify_peer(false);
+        config.set_active_connection_id_limit(3);
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_stream_data_uni(10);
+        config.set_initial_max_streams_bidi(3);
+
+        let mut pipe = pipe_with_exchanged_cids(&mut config, 16, 16, 2);
+
+        let server_addr = testing::Pipe::server_addr();
+        let client_addr = testing::Pipe::client_addr();
+        let client_addr_2 = "127.0.0.1:5678".parse().unwrap();
+
+        assert_eq!(
+            pipe.server
+                .migrate_after_validation(server_addr, client_addr_2),
+            Err(Error::InvalidState)
+        );
+
+        assert_eq!(
+            pipe.client
+                .migrate_after_validation(client_addr_2, server_addr),
+            Ok(1)
+        );
+
+        // The active path doesn't change until the new one is validated.
+        let active = pipe.client.paths.get_active().expect("no active");
+        assert_eq!(active.local_addr(), client_addr);
+        assert_eq!(pipe.server.retired_scids(), 0);
+
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(
+            pipe.client.path_event_next(),
+            Some(PathEvent::Validated(client_addr_2, server_addr))
+        );
+
+        let active = pipe.client.paths.get_active().expect("no active");
+        assert_eq!(active.local_addr(), client_addr_2);
+        assert_eq!(active.active_dcid_seq, Some(1));
+
+        // The Destination CID of the previous path was retired.
+        assert_eq!(pipe.client.stream_send(0, b"data", true), Ok(4));
+        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(pipe.server.retired_scids(), 1);
+    }
+
+    #[test]
+    fn connection_migration_make_before_break() {
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.verify_peer(false);
+        config.set_active_connection_id_limit(3);
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_stream_data_uni(10);
+        config.set_initial_max_streams_bidi(3);
+        config.set_migration_policy(MigrationPolicy::MakeBeforeBreak);
+
+        let mut pipe = pipe_with_exchanged_cids(&mut config, 16, 16, 2);
+
+        let server_addr = testing::Pipe::server_addr();
+        let client_addr = testing::Pipe::client_addr();
+        let client_addr_2 = "127.0.0.1:5678".parse().unwrap();
+
+        // There is no record of the new path yet.
+        assert_eq!(
+            pipe.client.compare_path_rtt(client_addr_2, server_addr),
+            Err(Error::InvalidState)
+        );
+
+        assert_eq!(
+            pipe.client.migrate_with_policy(client_addr_2, server_addr),
+            Ok(1)
+        );
+
+        // The active path doesn't change until the new one is validated.
+        let active = pipe.client.paths.get_active().expect("no active");
+        assert_eq!(active.local_addr(), client_addr);
+
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(
+            pipe.client.path_event_next(),
+            Some(PathEvent::Validated(client_addr_2, server_addr))
+        );
+
+        let active = pipe.client.paths.get_active().expect("no active");
+        assert_eq!(active.local_addr(), client_addr_2);
+        assert_eq!(active.active_dcid_seq, Some(1));
+
+        // The previous path is kept.
+        assert_eq!(pipe.client.stream_send(0, b"data", true), Ok(4));
+        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(pipe.server.retired_scids(), 0);
+
+        let cmp = pipe.client.compare_path_rtt(client_addr, server_addr);
+        assert!(cmp.unwrap().candidate_rtt.is_some());
+
+        // The connection can fall back to the previous path.
+        assert_eq!(pipe.client.migrate(client_addr, server_addr), Ok(0));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        let active = pipe.client.paths.get_active().expect("no active");
+        assert_eq!(active.local_addr(), client_addr);
+    }
+
+    #[test]
+    fn preferred_address_migration() {
+        let mut buf = [0; 65535];
+
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.verify_peer(false);
+        config.set_active_connection_id_limit(3);
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_streams_bidi(3);
+
+        let preferred_addr: SocketAddrV4 = "127.0.0.1:4322".parse().unwrap();
+        config.set_preferred_address(Some(preferred_addr), None);
+
+        let mut pipe =
+            testing::Pipe::with_config_and_scid_lengths(&mut config, 16, 16)
+                .unwrap();
+
+        let client_addr = testing::Pipe::client_addr();
+        let preferred_addr = SocketAddr::V4(preferred_addr);
+
+        let (s_cid, s_reset_token) = testing::create_cid_and_reset_token(16);
+        assert_eq!(
+            pipe.client.set_preferred_address_cid(&s_cid, s_reset_token),
+            Err(Error::InvalidState)
+        );
+        assert_eq!(
+            pipe.server.set_preferred_address_cid(&s_cid, s_reset_token),
+            Ok(())
+        );
+
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let pa = pipe.client.peer_transport_params.preferred_address.as_ref();
+        assert_eq!(pa.unwrap().connection_id, s_cid);
+
+        // The client needs a spare Source Connection ID to migrate.
+        let (c_cid, c_reset_token) = testing::create_cid_and_reset_token(16);
+        assert_eq!(pipe.client.new_scid(&c_cid, c_reset_token, true), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(
+            pipe.client.path_event_next(),
+            Some(PathEvent::Validated(client_addr, preferred_addr))
+        );
+
+        let active = pipe.client.paths.get_active().expect("no active");
+        assert_eq!(active.peer_addr(), preferred_addr);
+        assert_eq!(active.active_dcid_seq, Some(1));
+
+        // Data now flows over the preferred address.
+        assert_eq!(pipe.client.stream_send(0, b"data", true), Ok(4));
+        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(pipe.server.stream_recv(0, &mut buf), Ok((4, true)));
+
+        let active = pipe.server.paths.get_active().expect("no active");
+        assert_eq!(active.local_addr(), preferred_addr);
+    }
+
+    #[test]
+    fn rotate_dcid() {
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.verify_peer(false);
+        config.set_active_connection_id_limit(2);
+
+        let mut pipe = pipe_with_exchanged_cids(&mut config, 16, 16, 1);
+
+        assert_eq!(pipe.client.rotate_dcid(), Ok(1));
+        assert_eq!(pipe.client.stats().dcid_rotated, 1);
+
+        // No spare Destination Connection ID is left.
+        assert_eq!(pipe.client.rotate_dcid(), Err(Error::OutOfIdentifiers));
+
+        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(pipe.server.retired_scids(), 1);
+    }
+
+    #[test]
+    fn rotate_dcid_after_bytes() {
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.verify_peer(false);
+        config.set_dcid_rotation_bytes(1);
+
+        let mut pipe =
+            testing::Pipe::with_config_and_scid_lengths(&mut config, 16, 16)
+                .unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        // Rotation is skipped while there are no spare Connection IDs.
+        assert_eq!(pipe.client.stats().dcid_rotated, 0);
+
+        let (s_cid, s_reset_token) = testing::create_cid_and_reset_token(16);
+        assert_eq!(pipe.server.new_scid(&s_cid, s_reset_token, true), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.client.stats().dcid_rotated, 1);
+
+        let active = pipe.client.paths.get_active().expect("no active");
+        assert_eq!(active.active_dcid_seq, Some(1));
+
+        assert_eq!(pipe.server.retired_scids(), 1);
+    }
+
+    #[test]
+    fn cid_rotation_after_bytes() {
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.verify_peer(false);
+        config.set_active_connection_id_limit(2);
+        config.set_cid_rotation(CidRotation::Bytes(1));
+
+        let mut pipe =
+            testing::Pipe::with_config_and_scid_lengths(&mut config, 16, 16)
+                .unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        // Rotation is skipped while there are no spare Connection IDs.
+        assert_eq!(pipe.client.stats().dcid_rotated, 0);
+
+        let (c_cid, c_reset_token) = testing::create_cid_and_reset_token(16);
+        assert_eq!(pipe.client.new_scid(&c_cid, c_reset_token, true), Ok(1));
+
+        let (s_cid, s_reset_token) = testing::create_cid_and_reset_token(16);
+        assert_eq!(pipe.server.new_scid(&s_cid, s_reset_token, true), Ok(1));
+
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // Both endpoints switched to the new Connection IDs, and stopped
+        // using the initial ones.
+        let active = pipe.client.paths.get_active().expect("no active");
+        assert_eq!(active.active_dcid_seq, Some(1));
+
+        let active = pipe.server.paths.get_active().expect("no active");
+        assert_eq!(active.active_dcid_seq, Some(1));
+
+        assert_eq!(pipe.client.retired_scids(), 1);
+        assert_eq!(pipe.server.retired_scids(), 1);
+    }
+
+    #[test]
+    fn provide_scids() {
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let mut generator =
+            quic_lb::Generator::plaintext(0, &[0x12, 0x34], 8).unwrap();
+
+        let scids = pipe.server.provide_scids(&mut generator).unwrap();
+        assert_eq!(scids.len(), 1);
+        assert_eq!(pipe.server.scids_left(), 0);
+        assert_eq!(
+            generator.decode_server_id(&scids[0]),
+            Some(vec![0x12, 0x34])
+        );
+
+        // The peer's limit was reached.
+        assert_eq!(pipe.server.provide_scids(&mut generator), Ok(vec![]));
+
+        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(pipe.client.available_dcids(), 1);
+    }
+
+    fn new_path_policy_pipe(policy: NewPathPolicy) -> testing::Pipe {
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.verify_peer(false);
+        config.set_active_connection_id_limit(3);
+        config.set_new_path_policy(policy);
+
+        pipe_with_exchanged_cids(&mut config, 16, 16, 1)
+    }
+
+    #[test]
+    fn new_path_policy_defer() {
+        let mut pipe = new_path_policy_pipe(NewPathPolicy::Defer);
+
+        let server_addr = testing::Pipe::server_addr();
+        let client_addr_2 = "127.0.0.1:5678".parse().unwrap();
+
+        assert_eq!(pipe.client.probe_path(client_addr_2, server_addr), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        // Case 4: the client tries to migrate on a path that was not previously
-        // validated, and has no spare SCIDs/DCIDs. Prevent active migration.
+        // The client's probe is answered, but the server doesn't validate the
+        // new path by itself.
         assert_eq!(
-            pipe.client.migrate(client_addr_4, server_addr),
-            Err(Error::OutOfIdentifiers)
+            pipe.client.path_event_next(),
+            Some(PathEvent::Validated(client_addr_2, server_addr))
         );
         assert_eq!(
-            pipe.client
-                .paths
-                .get_active()
-                .expect("no active")
-                .local_addr(),
-            client_addr_3
+            pipe.server.path_event_next(),
+            Some(PathEvent::New(server_addr, client_addr_2))
         );
+        assert_eq!(pipe.server.path_event_next(), None);
         assert_eq!(
-            pipe.client
-                .paths
-                .get_active()
-                .expect("no active")
-                .peer_addr(),
-            server_addr
+            pipe.server.is_path_validated(server_addr, client_addr_2),
+            Ok(false)
+        );
+
+        assert_eq!(pipe.server.probe_path(server_addr, client_addr_2), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(
+            pipe.server.path_event_next(),
+            Some(PathEvent::Validated(server_addr, client_addr_2))
         );
+        assert_eq!(pipe.server.stats().path_validated_count, 1);
+    }
+
+    #[test]
+    fn new_path_policy_drop() {
+        let mut pipe = new_path_policy_pipe(NewPathPolicy::Drop);
+
+        let server_addr = testing::Pipe::server_addr();
+        let client_addr_2 = "127.0.0.1:5678".parse().unwrap();
+
+        assert_eq!(pipe.client.probe_path(client_addr_2, server_addr), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.client.path_event_next(), None);
+        assert_eq!(pipe.server.path_event_next(), None);
+
+        let stats = pipe.server.stats();
+        assert!(stats.new_path_dropped > 0);
+        assert_eq!(stats.paths_count, 1);
     }
 
     #[test]
@@ -16128,7 +26722,7 @@ mod tests {
 
         // Client sends a bunch of PING frames, causing server to ACK (ACKs aren't
         // ack-eliciting)
-        let frames = [frame::Frame::Ping];
+        let frames = [frame::Frame::Ping { mtu_probe: None }];
         let pkt_type = packet::Type::Short;
         for _ in 0..24 {
             let len = pipe
@@ -16157,7 +26751,7 @@ mod tests {
         assert!(
             frames
                 .iter()
-                .any(|frame| matches!(frame, frame::Frame::Ping)),
+                .any(|frame| matches!(frame, frame::Frame::Ping { .. })),
             "found a PING"
         );
     }
@@ -16179,7 +26773,7 @@ mod tests {
             testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
         let mut iter = frames.iter();
 
-        assert_eq!(iter.next(), Some(&frame::Frame::Ping));
+        assert_eq!(iter.next(), Some(&frame::Frame::Ping { mtu_probe: None }));
     }
 
     #[test]
@@ -16213,6 +26807,316 @@ mod tests {
         assert!(iter.next().is_none());
     }
 
+    #[test]
+    fn probe_bandwidth_fills_cwnd() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        // Nothing to send.
+        assert_eq!(pipe.server.send(&mut buf), Err(Error::Done));
+
+        pipe.server.probe_bandwidth(time::Duration::from_secs(10));
+        assert!(pipe.server.is_probing_bandwidth());
+
+        // Server sends PING and PADDING only packets.
+        let (len, _) = pipe.server.send(&mut buf).unwrap();
+
+        let frames =
+            testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
+
+        assert!(frames
+            .iter()
+            .any(|f| matches!(f, frame::Frame::Ping { .. })));
+        assert!(frames
+            .iter()
+            .any(|f| matches!(f, frame::Frame::Padding { .. })));
+
+        // Keep sending until the congestion window is full.
+        while pipe.server.send(&mut buf).is_ok() {}
+
+        let recovery = &pipe.server.paths.get_active().unwrap().recovery;
+        assert!(recovery.cwnd_available() < recovery.max_datagram_size());
+        assert_eq!(
+            pipe.server.send_blocked_reason(),
+            Some(SendBlockedReason::CongestionWindow)
+        );
+
+        // Stopping the probe doesn't generate any more packets.
+        pipe.server.probe_bandwidth(time::Duration::ZERO);
+        assert!(!pipe.server.is_probing_bandwidth());
+
+        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(pipe.server.send(&mut buf), Err(Error::Done));
+    }
+
+    #[test]
+    fn fec_recovers_lost_packet() {
+        let mut buf = [0; 65535];
+
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.set_initial_max_data(30000);
+        config.set_initial_max_stream_data_bidi_local(15000);
+        config.set_initial_max_stream_data_bidi_remote(15000);
+        config.set_initial_max_streams_bidi(3);
+        config.enable_fec(true);
+        config.set_fec_group_size(2);
+        config.verify_peer(false);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert!(pipe.client.peer_transport_params.enable_fec);
+        assert!(pipe.server.peer_transport_params.enable_fec);
+
+        assert_eq!(pipe.client.stream_send(0, &[42; 3000], true), Ok(3000));
+
+        let mut flight = testing::emit_flight(&mut pipe.client).unwrap();
+
+        // The first packet is lost.
+        flight.remove(0);
+
+        testing::process_flight(&mut pipe.server, flight).unwrap();
+
+        assert!(pipe.client.stats().fec_repair_sent > 0);
+        assert_eq!(pipe.server.stats().fec_recovered, 1);
+
+        // All the data is available without waiting for a retransmission.
+        assert_eq!(pipe.server.stream_recv(0, &mut buf), Ok((3000, true)));
+
+        // The recovered packet is acknowledged, so nothing is declared lost.
+        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(pipe.client.stats().lost, 0);
+    }
+
+    #[test]
+    fn fec_repair_not_negotiated() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let frames = [frame::Frame::Repair {
+            first_pkt_num: 0,
+            count: 2,
+            data: vec![0; 10],
+        }];
+
+        let pkt_type = packet::Type::Short;
+        assert_eq!(
+            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
+            Err(Error::InvalidFrame),
+        );
+
+        // The connection is closed with a FRAME_ENCODING_ERROR.
+        let err = pipe.server.local_error().unwrap();
+        assert!(!err.is_app);
+        assert_eq!(err.error_code, 0x07);
+    }
+
+    #[test]
+    fn path_interval_stats() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let stats = pipe.client.path_interval_stats().next().unwrap();
+        assert!(stats.sent > 0);
+        assert!(stats.min_rtt.is_some());
+
+        pipe.client.reset_path_stats();
+
+        let stats = pipe.client.path_interval_stats().next().unwrap();
+        assert_eq!(stats.sent, 0);
+        assert_eq!(stats.recv, 0);
+        assert_eq!(stats.sent_bytes, 0);
+        assert_eq!(stats.loss_rate(), 0.0);
+        assert_eq!(stats.min_rtt, None);
+        assert_eq!(stats.max_rtt, None);
+
+        assert_eq!(pipe.client.stream_send(0, b"hello", true), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(pipe.server.stream_recv(0, &mut buf), Ok((5, true)));
+
+        let stats = pipe.client.path_interval_stats().next().unwrap();
+        assert!(stats.sent > 0);
+        assert!(stats.recv > 0);
+        assert_eq!(stats.lost, 0);
+        assert!(stats.min_rtt.is_some());
+        assert!(stats.min_rtt <= stats.max_rtt);
+
+        // Cumulative statistics are not affected.
+        let cumulative = pipe.client.path_stats().next().unwrap();
+        assert!(cumulative.sent > stats.sent);
+    }
+
+    #[test]
+    fn packet_events() {
+        let mut buf = [0; 65535];
+
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_streams_bidi(3);
+        config.enable_packet_events(true);
+        config.verify_peer(false);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        // Discard the events generated during the handshake.
+        while pipe.client.next_transport_event().is_some() {}
+
+        assert_eq!(pipe.client.stream_send(0, b"hello", true), Ok(5));
+
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+
+        let sent_pn = match pipe.client.next_transport_event() {
+            Some(TransportEvent::PacketSent { pkt_num, size, .. }) => {
+                assert_eq!(size, len);
+                pkt_num
+            },
+
+            ev => panic!("unexpected event {:?}", ev),
+        };
+
+        assert_eq!(pipe.client.next_transport_event(), None);
+
+        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        let acked = std::iter::from_fn(|| pipe.client.next_transport_event())
+            .any(|ev| {
+                matches!(ev, TransportEvent::PacketAcked { pkt_num, .. }
+                    if pkt_num == sent_pn)
+            });
+        assert!(acked);
+    }
+
+    #[test]
+    fn packet_events_disabled() {
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(pipe.client.next_transport_event(), None);
+        assert_eq!(pipe.server.next_transport_event(), None);
+    }
+
+    #[test]
+    fn pmtud_probe() {
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_streams_bidi(3);
+        config.set_max_send_udp_payload_size(1350);
+        config.discover_pmtu(true);
+        config.verify_peer(false);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // The probe of the largest allowed size was acknowledged.
+        let stats = pipe.client.path_stats().next().unwrap();
+        assert_eq!(stats.pmtu, 1350);
+
+        let pmtud = pipe.client.paths.get_active().unwrap().pmtud.as_ref();
+        assert!(!pmtud.unwrap().should_probe());
+    }
+
+    #[test]
+    fn path_max_send_udp_payload_size() {
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.set_max_send_udp_payload_size(1350);
+        config.discover_pmtu(true);
+        config.verify_peer(false);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        let stats = pipe.client.path_stats().next().unwrap();
+        assert_eq!(stats.pmtu, 1350);
+
+        let client_addr = testing::Pipe::client_addr();
+        let server_addr = testing::Pipe::server_addr();
+
+        assert_eq!(
+            pipe.client.set_path_max_send_udp_payload_size(
+                server_addr,
+                client_addr,
+                1250
+            ),
+            Err(Error::InvalidState)
+        );
+
+        assert_eq!(
+            pipe.client.set_path_max_send_udp_payload_size(
+                client_addr,
+                server_addr,
+                1250
+            ),
+            Ok(())
+        );
+
+        let stats = pipe.client.path_stats().next().unwrap();
+        assert_eq!(stats.pmtu, 1250);
+
+        // The size can't be lower than the minimum.
+        assert_eq!(
+            pipe.client.set_path_max_send_udp_payload_size(
+                client_addr,
+                server_addr,
+                1000
+            ),
+            Ok(())
+        );
+
+        let stats = pipe.client.path_stats().next().unwrap();
+        assert_eq!(stats.pmtu, 1200);
+        assert_eq!(pipe.client.max_send_udp_payload_size(), 1200);
+    }
+
     /// Tests that streams do not keep being "writable" after being collected
     /// on reset.
     #[test]
@@ -16506,31 +27410,91 @@ mod tests {
     }
 }
 
+pub use crate::cid::CidRotation;
+pub use crate::cid::ConnectionIdGenerator;
+
 pub use crate::packet::ConnectionId;
 pub use crate::packet::Header;
 pub use crate::packet::Type;
 
+pub use crate::path::MigrationPolicy;
+pub use crate::path::NewPathPolicy;
 pub use crate::path::PathEvent;
+pub use crate::path::PathIntervalStats;
+pub use crate::path::PathRttComparison;
 pub use crate::path::PathStats;
 pub use crate::path::SocketAddrIter;
 
+#[cfg(feature = "qlog")]
+pub use crate::recovery::replay::replay_seq;
+#[cfg(feature = "qlog")]
+pub use crate::recovery::replay::Replay;
+#[cfg(feature = "qlog")]
+pub use crate::recovery::replay::ReplaySnapshot;
+#[cfg(feature = "qlog")]
+pub use crate::recovery::replay::ReplayTrigger;
 pub use crate::recovery::CongestionControlAlgorithm;
-
+pub use crate::recovery::PacingMode;
+pub use crate::recovery::PtoRetransmitPolicy;
+pub use crate::recovery::TransportEvent;
+
+pub use crate::offload::PacketKeys;
+pub use crate::offload::PacketProtector;
+pub use crate::offload::UnprotectedPacket;
+
+pub use crate::stream::MaxStreamsPolicy;
+pub use crate::stream::ReassemblyPressure;
+pub use crate::stream::StreamBuf;
+pub use crate::stream::StreamCapacityIter;
 pub use crate::stream::StreamIter;
+pub use crate::stream::StreamRecvOffsets;
+pub use crate::stream::StreamsBlocked;
+
+pub use crate::token::AeadTokenProvider;
+pub use crate::token::TokenProvider;
+pub use crate::token::ValidatedToken;
 
 mod cid;
 mod crypto;
 mod dgram;
+mod fec;
 #[cfg(feature = "ffi")]
 mod ffi;
 mod flowcontrol;
+#[cfg(feature = "internal")]
+#[doc(hidden)]
+pub mod frame;
+#[cfg(not(feature = "internal"))]
 mod frame;
 pub mod h3;
+pub mod metrics;
 mod minmax;
+mod offload;
+#[cfg(feature = "internal")]
+#[doc(hidden)]
+pub mod packet;
+#[cfg(not(feature = "internal"))]
 mod packet;
 mod path;
+mod pmtud;
+pub mod quic_lb;
 mod rand;
+#[cfg(feature = "internal")]
+#[doc(hidden)]
+pub mod ranges;
+#[cfg(not(feature = "internal"))]
 mod ranges;
+#[cfg(feature = "internal")]
+#[doc(hidden)]
+pub mod recovery;
+#[cfg(not(feature = "internal"))]
 mod recovery;
+pub mod server;
 mod stream;
 mod tls;
+mod token;
+#[cfg(feature = "tokio")]
+pub mod tokio;
+#[cfg(feature = "internal")]
+#[doc(hidden)]
+pub mod wire;
@@ -0,0 +1,400 @@
+// Copyright (C) 2023, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Connection metrics registry.
+//!
+//! A [`Registry`] aggregates counters and gauges across all the connections
+//! it is installed on, with [`set_metrics_registry()`] on the configuration
+//! or on individual connections. A single registry can be shared by all the
+//! connections of a server, or different registries can be used for
+//! different groups of connections.
+//!
+//! Metrics are read with [`Registry::snapshot()`], or rendered in the
+//! Prometheus text exposition format with [`Registry::to_prometheus()`], so
+//! they can be served by a metrics endpoint without parsing qlog.
+//!
+//! ## Examples:
+//!
+//! ```no_run
+//! use std::sync::Arc;
+//!
+//! let registry = Arc::new(quiche::metrics::Registry::new());
+//!
+//! let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION)?;
+//! config.set_metrics_registry(registry.clone());
+//!
+//! // Later, when the metrics endpoint is scraped.
+//! let body = registry.to_prometheus();
+//! # Ok::<(), quiche::Error>(())
+//! ```
+//!
+//! [`set_metrics_registry()`]: ../struct.Config.html#method.set_metrics_registry
+//! [`Registry::snapshot()`]: struct.Registry.html#method.snapshot
+//! [`Registry::to_prometheus()`]: struct.Registry.html#method.to_prometheus
+
+use std::fmt::Write;
+
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+/// A set of metrics shared by multiple connections.
+#[derive(Debug, Default)]
+pub struct Registry {
+    handshakes_completed: AtomicU64,
+
+    packets_sent: AtomicU64,
+
+    packets_received: AtomicU64,
+
+    packets_lost: AtomicU64,
+
+    bytes_sent: AtomicU64,
+
+    bytes_lost: AtomicU64,
+
+    connections_active: AtomicU64,
+
+    congestion_window: AtomicU64,
+}
+
+/// A point-in-time copy of the metrics of a [`Registry`].
+///
+/// [`Registry`]: struct.Registry.html
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Snapshot {
+    /// The number of completed handshakes.
+    pub handshakes_completed: u64,
+
+    /// The number of QUIC packets sent.
+    pub packets_sent: u64,
+
+    /// The number of QUIC packets received.
+    pub packets_received: u64,
+
+    /// The number of QUIC packets that were declared lost.
+    pub packets_lost: u64,
+
+    /// The number of bytes sent.
+    pub bytes_sent: u64,
+
+    /// The number of bytes that were declared lost.
+    pub bytes_lost: u64,
+
+    /// The number of connections currently using the registry.
+    pub connections_active: u64,
+
+    /// The sum of the congestion windows of the active paths of those
+    /// connections, in bytes.
+    pub congestion_window: u64,
+}
+
+impl Registry {
+    /// Creates an empty registry.
+    pub fn new() -> Registry {
+        Registry::default()
+    }
+
+    /// Returns the current value of all metrics.
+    ///
+    /// Metrics are updated independently, so the values of different metrics
+    /// might not reflect exactly the same point in time.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            handshakes_completed: self
+                .handshakes_completed
+                .load(Ordering::Relaxed),
+            packets_sent: self.packets_sent.load(Ordering::Relaxed),
+            packets_received: self.packets_received.load(Ordering::Relaxed),
+            packets_lost: self.packets_lost.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_lost: self.bytes_lost.load(Ordering::Relaxed),
+            connections_active: self.connections_active.load(Ordering::Relaxed),
+            congestion_window: self.congestion_window.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Renders all metrics in the Prometheus text exposition format.
+    pub fn to_prometheus(&self) -> String {
+        let s = self.snapshot();
+
+        let metrics = [
+            (
+                "quiche_handshakes_completed_total",
+                "counter",
+                "Number of completed handshakes.",
+                s.handshakes_completed,
+            ),
+            (
+                "quiche_packets_sent_total",
+                "counter",
+                "Number of QUIC packets sent.",
+                s.packets_sent,
+            ),
+            (
+                "quiche_packets_received_total",
+                "counter",
+                "Number of QUIC packets received.",
+                s.packets_received,
+            ),
+            (
+                "quiche_packets_lost_total",
+                "counter",
+                "Number of QUIC packets declared lost.",
+                s.packets_lost,
+            ),
+            (
+                "quiche_sent_bytes_total",
+                "counter",
+                "Number of bytes sent.",
+                s.bytes_sent,
+            ),
+            (
+                "quiche_lost_bytes_total",
+                "counter",
+                "Number of bytes declared lost.",
+                s.bytes_lost,
+            ),
+            (
+                "quiche_connections_active",
+                "gauge",
+                "Number of active connections.",
+                s.connections_active,
+            ),
+            (
+                "quiche_congestion_window_bytes",
+                "gauge",
+                "Sum of the congestion windows of active connections.",
+                s.congestion_window,
+            ),
+        ];
+
+        let mut out = String::new();
+
+        for (name, ty, help, value) in metrics {
+            // Writing to a String can't fail.
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} {ty}");
+            let _ = writeln!(out, "{name} {value}");
+        }
+
+        out
+    }
+}
+
+/// Tracks the contribution of a single connection to a registry.
+///
+/// Counters are updated with the difference from the values that were last
+/// reported, so the connection doesn't need to touch the shared registry on
+/// every event. The contribution to gauges is removed when it's dropped.
+pub(crate) struct ConnectionMetrics {
+    registry: Arc<Registry>,
+
+    handshake_completed: bool,
+
+    packets_sent: u64,
+
+    packets_received: u64,
+
+    packets_lost: u64,
+
+    bytes_sent: u64,
+
+    bytes_lost: u64,
+
+    congestion_window: u64,
+}
+
+/// The connection state reported to a [`ConnectionMetrics`].
+#[derive(Default)]
+pub(crate) struct ConnectionState {
+    pub handshake_completed: bool,
+
+    pub packets_sent: u64,
+
+    pub packets_received: u64,
+
+    pub packets_lost: u64,
+
+    pub bytes_sent: u64,
+
+    pub bytes_lost: u64,
+
+    pub congestion_window: u64,
+}
+
+impl ConnectionMetrics {
+    pub fn new(registry: Arc<Registry>) -> ConnectionMetrics {
+        registry.connections_active.fetch_add(1, Ordering::Relaxed);
+
+        ConnectionMetrics {
+            registry,
+
+            handshake_completed: false,
+
+            packets_sent: 0,
+
+            packets_received: 0,
+
+            packets_lost: 0,
+
+            bytes_sent: 0,
+
+            bytes_lost: 0,
+
+            congestion_window: 0,
+        }
+    }
+
+    /// Reports the current state of the connection to the registry.
+    pub fn update(&mut self, state: &ConnectionState) {
+        let r = &self.registry;
+
+        if state.handshake_completed && !self.handshake_completed {
+            r.handshakes_completed.fetch_add(1, Ordering::Relaxed);
+            self.handshake_completed = true;
+        }
+
+        add_delta(&r.packets_sent, &mut self.packets_sent, state.packets_sent);
+        add_delta(
+            &r.packets_received,
+            &mut self.packets_received,
+            state.packets_received,
+        );
+        add_delta(&r.packets_lost, &mut self.packets_lost, state.packets_lost);
+        add_delta(&r.bytes_sent, &mut self.bytes_sent, state.bytes_sent);
+        add_delta(&r.bytes_lost, &mut self.bytes_lost, state.bytes_lost);
+
+        if state.congestion_window >= self.congestion_window {
+            r.congestion_window.fetch_add(
+                state.congestion_window - self.congestion_window,
+                Ordering::Relaxed,
+            );
+        } else {
+            r.congestion_window.fetch_sub(
+                self.congestion_window - state.congestion_window,
+                Ordering::Relaxed,
+            );
+        }
+
+        self.congestion_window = state.congestion_window;
+    }
+}
+
+impl Drop for ConnectionMetrics {
+    fn drop(&mut self) {
+        let r = &self.registry;
+
+        r.connections_active.fetch_sub(1, Ordering::Relaxed);
+        r.congestion_window
+            .fetch_sub(self.congestion_window, Ordering::Relaxed);
+    }
+}
+
+impl std::fmt::Debug for ConnectionMetrics {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "registry={:?}", self.registry)
+    }
+}
+
+/// Adds to `metric` the increase of a counter since it was last reported.
+fn add_delta(metric: &AtomicU64, last: &mut u64, value: u64) {
+    if value > *last {
+        metric.fetch_add(value - *last, Ordering::Relaxed);
+        *last = value;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connection_updates() {
+        let registry = Arc::new(Registry::new());
+
+        let mut a = ConnectionMetrics::new(registry.clone());
+        let mut b = ConnectionMetrics::new(registry.clone());
+
+        a.update(&ConnectionState {
+            handshake_completed: true,
+            packets_sent: 10,
+            bytes_sent: 1000,
+            congestion_window: 12000,
+            ..Default::default()
+        });
+
+        b.update(&ConnectionState {
+            packets_sent: 5,
+            congestion_window: 6000,
+            ..Default::default()
+        });
+
+        a.update(&ConnectionState {
+            handshake_completed: true,
+            packets_sent: 12,
+            packets_lost: 1,
+            bytes_sent: 1200,
+            congestion_window: 8000,
+            ..Default::default()
+        });
+
+        assert_eq!(registry.snapshot(), Snapshot {
+            handshakes_completed: 1,
+            packets_sent: 17,
+            packets_received: 0,
+            packets_lost: 1,
+            bytes_sent: 1200,
+            bytes_lost: 0,
+            connections_active: 2,
+            congestion_window: 14000,
+        });
+
+        // Gauges only account for live connections.
+        drop(a);
+
+        let s = registry.snapshot();
+        assert_eq!(s.packets_sent, 17);
+        assert_eq!(s.connections_active, 1);
+        assert_eq!(s.congestion_window, 6000);
+    }
+
+    #[test]
+    fn prometheus() {
+        let registry = Registry::new();
+
+        registry.packets_sent.store(42, Ordering::Relaxed);
+
+        let out = registry.to_prometheus();
+
+        assert!(out.contains(
+            "# HELP quiche_packets_sent_total Number of QUIC packets sent.\n\
+             # TYPE quiche_packets_sent_total counter\n\
+             quiche_packets_sent_total 42\n"
+        ));
+        assert!(out.contains("quiche_connections_active 0\n"));
+    }
+}
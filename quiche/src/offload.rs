@@ -0,0 +1,93 @@
+// Copyright (C) 2023, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Offloading of 1-RTT packet protection (RFC 9001 Section 5).
+//!
+//! When packets are written with [`send_batch()`], the AEAD and header
+//! protection of all the 1-RTT packets in the batch is deferred until the
+//! whole batch has been built, and then applied in a single pass. This lets
+//! the work be handed to a [`PacketProtector`], e.g. a hardware crypto
+//! engine, or an implementation that interleaves the encryption of multiple
+//! packets.
+//!
+//! [`send_batch()`]: ../struct.Connection.html#method.send_batch
+//! [`PacketProtector`]: trait.PacketProtector.html
+
+use crate::CipherSuite;
+use crate::Result;
+
+/// The keys used to protect 1-RTT packets.
+///
+/// The packet keys change after every key update, while the header
+/// protection key stays the same for the whole connection.
+#[derive(Debug)]
+pub struct PacketKeys<'a> {
+    /// The negotiated cipher suite.
+    pub cipher_suite: CipherSuite,
+
+    /// The AEAD key.
+    pub key: &'a [u8],
+
+    /// The AEAD IV, which is combined with the packet number to form the
+    /// nonce (RFC 9001 Section 5.3).
+    pub iv: &'a [u8],
+
+    /// The header protection key (RFC 9001 Section 5.4).
+    pub hp_key: &'a [u8],
+}
+
+/// A 1-RTT packet waiting to be protected.
+#[derive(Debug)]
+pub struct UnprotectedPacket<'a> {
+    /// The full packet number.
+    pub pkt_num: u64,
+
+    /// The length of the encoded packet number, which is at the end of the
+    /// header.
+    pub pkt_num_len: usize,
+
+    /// The packet header, used as the AEAD associated data.
+    pub header: &'a mut [u8],
+
+    /// The plaintext payload, followed by room for the AEAD tag.
+    pub payload: &'a mut [u8],
+}
+
+/// Applies packet protection to batches of outgoing 1-RTT packets.
+///
+/// Implementations must encrypt the payload of each packet in place and
+/// append the authentication tag, and then apply header protection using a
+/// sample of the resulting ciphertext, exactly as described in RFC 9001
+/// Section 5.
+pub trait PacketProtector: Send + Sync {
+    /// Protects all the `packets` using `keys`.
+    ///
+    /// An error is returned to the application by the call that was
+    /// sending the packets, and the packets must not be sent.
+    fn protect(
+        &self, keys: &PacketKeys, packets: &mut [UnprotectedPacket],
+    ) -> Result<()>;
+}
@@ -36,6 +36,7 @@ use crate::Error;
 use crate::Result;
 
 use crate::crypto;
+use crate::offload;
 use crate::rand;
 use crate::ranges;
 use crate::stream;
@@ -371,7 +372,16 @@ impl<'a> Header<'a> {
         let ty = if version == 0 {
             Type::VersionNegotiation
         } else {
-            match (first & TYPE_MASK) >> 4 {
+            let ty = (first & TYPE_MASK) >> 4;
+
+            // QUIC v2 shifts the long header packet types by one.
+            let ty = if version == crate::PROTOCOL_VERSION_V2 {
+                ty.wrapping_sub(1) & 0x03
+            } else {
+                ty
+            };
+
+            match ty {
                 0x00 => Type::Initial,
                 0x01 => Type::ZeroRTT,
                 0x02 => Type::Handshake,
@@ -440,7 +450,18 @@ impl<'a> Header<'a> {
     }
 
     pub(crate) fn to_bytes(&self, out: &mut octets::OctetsMut) -> Result<()> {
-        let mut first = 0;
+        self.to_bytes_with_quic_bit(out, true)
+    }
+
+    /// Encodes the header, setting the QUIC bit (aka fixed bit) to
+    /// `quic_bit`.
+    ///
+    /// The bit can only be cleared when the peer advertised the
+    /// `grease_quic_bit` transport parameter, as per RFC 9287.
+    pub(crate) fn to_bytes_with_quic_bit(
+        &self, out: &mut octets::OctetsMut, quic_bit: bool,
+    ) -> Result<()> {
+        let mut first = if quic_bit { FIXED_BIT } else { 0 };
 
         // Encode pkt num length.
         first |= self.pkt_num_len.saturating_sub(1) as u8;
@@ -450,9 +471,6 @@ impl<'a> Header<'a> {
             // Unset form bit for short header.
             first &= !FORM_BIT;
 
-            // Set fixed bit.
-            first |= FIXED_BIT;
-
             // Set key phase bit.
             if self.key_phase {
                 first |= KEY_PHASE_BIT;
@@ -475,7 +493,13 @@ impl<'a> Header<'a> {
             _ => return Err(Error::InvalidPacket),
         };
 
-        first |= FORM_BIT | FIXED_BIT | (ty << 4);
+        let ty = if self.version == crate::PROTOCOL_VERSION_V2 {
+            (ty + 1) & 0x03
+        } else {
+            ty
+        };
+
+        first |= FORM_BIT | (ty << 4);
 
         out.put_u8(first)?;
 
@@ -520,6 +544,13 @@ impl<'a> Header<'a> {
     fn is_long(b: u8) -> bool {
         b & FORM_BIT != 0
     }
+
+    /// Returns true if the QUIC bit (aka fixed bit) of the header is set.
+    ///
+    /// The `b` parameter represents the first byte of the QUIC header.
+    pub(crate) fn quic_bit(b: u8) -> bool {
+        b & FIXED_BIT != 0
+    }
 }
 
 impl<'a> std::fmt::Debug for Header<'a> {
@@ -713,6 +744,82 @@ pub fn encrypt_pkt(
     Ok(payload_offset + ciphertext_len)
 }
 
+/// A packet written to an output buffer whose protection was deferred.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PendingSeal {
+    /// The offset of the packet in the output buffer.
+    pub offset: usize,
+
+    pub pn: u64,
+
+    pub pn_len: usize,
+
+    pub payload_offset: usize,
+
+    pub payload_len: usize,
+}
+
+/// Protects the packets written to `out`, either with `protector`, or by
+/// sealing all the payloads first and then applying header protection.
+///
+/// The packets must be sorted by offset and must not overlap.
+pub fn encrypt_pkts(
+    out: &mut [u8], pkts: &[PendingSeal], aead: &crypto::Seal,
+    protector: Option<&dyn offload::PacketProtector>,
+) -> Result<()> {
+    let tag_len = aead.alg().tag_len();
+
+    let mut unprotected = Vec::with_capacity(pkts.len());
+
+    let mut rest = out;
+    let mut rest_off = 0;
+
+    for p in pkts {
+        let pkt_len = p.payload_offset + p.payload_len + tag_len;
+
+        if p.offset < rest_off || p.offset + pkt_len > rest_off + rest.len() {
+            return Err(Error::BufferTooShort);
+        }
+
+        let (_, pkt) =
+            std::mem::take(&mut rest).split_at_mut(p.offset - rest_off);
+        let (pkt, tail) = pkt.split_at_mut(pkt_len);
+        let (header, payload) = pkt.split_at_mut(p.payload_offset);
+
+        unprotected.push(offload::UnprotectedPacket {
+            pkt_num: p.pn,
+            pkt_num_len: p.pn_len,
+            header,
+            payload,
+        });
+
+        rest = tail;
+        rest_off = p.offset + pkt_len;
+    }
+
+    if let Some(protector) = protector {
+        return protector.protect(&aead.packet_keys(), &mut unprotected);
+    }
+
+    for (p, u) in pkts.iter().zip(unprotected.iter_mut()) {
+        aead.seal_with_u64_counter(
+            p.pn,
+            u.header,
+            u.payload,
+            p.payload_len,
+            None,
+        )?;
+    }
+
+    for u in unprotected {
+        let mut header = octets::OctetsMut::with_slice(u.header);
+
+        encrypt_hdr(&mut header, u.pkt_num_len, u.payload, aead)?;
+    }
+
+    Ok(())
+}
+
 pub fn encode_pkt_num(pn: u64, b: &mut octets::OctetsMut) -> Result<()> {
     let len = pkt_num_len(pn)?;
 
@@ -807,10 +914,22 @@ fn compute_retry_integrity_tag(
         0x46, 0x15, 0x99, 0xd3, 0x5d, 0x63, 0x2b, 0xf2, 0x23, 0x98, 0x25, 0xbb,
     ];
 
+    const RETRY_INTEGRITY_KEY_V2: [u8; 16] = [
+        0x8f, 0xb4, 0xb0, 0x1b, 0x56, 0xac, 0x48, 0xe2, 0x60, 0xfb, 0xcb, 0xce,
+        0xad, 0x7c, 0xcc, 0x92,
+    ];
+
+    const RETRY_INTEGRITY_NONCE_V2: [u8; aead::NONCE_LEN] = [
+        0xd8, 0x69, 0x69, 0xbc, 0x2d, 0x7c, 0x6d, 0x99, 0x90, 0xef, 0xb0, 0x4a,
+    ];
+
     let (key, nonce) = match version {
         crate::PROTOCOL_VERSION_V1 =>
             (&RETRY_INTEGRITY_KEY_V1, RETRY_INTEGRITY_NONCE_V1),
 
+        crate::PROTOCOL_VERSION_V2 =>
+            (&RETRY_INTEGRITY_KEY_V2, RETRY_INTEGRITY_NONCE_V2),
+
         _ => (&RETRY_INTEGRITY_KEY_V1, RETRY_INTEGRITY_NONCE_V1),
     };
 
@@ -868,17 +987,30 @@ pub struct PktNumSpace {
 
     pub ack_elicited: bool,
 
+    /// The number of ack-eliciting packets received since the last ACK.
+    pub ack_eliciting_unacked: usize,
+
+    /// When a delayed ACK needs to be sent.
+    pub ack_timer: Option<time::Instant>,
+
     pub key_update: Option<KeyUpdate>,
 
     pub crypto_open: Option<crypto::Open>,
     pub crypto_seal: Option<crypto::Seal>,
 
     pub crypto_0rtt_open: Option<crypto::Open>,
+    #[cfg_attr(feature = "rustls", allow(dead_code))]
     pub crypto_0rtt_seal: Option<crypto::Seal>,
 
     pub crypto_stream: stream::Stream,
 }
 
+impl Default for PktNumSpace {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl PktNumSpace {
     pub fn new() -> PktNumSpace {
         PktNumSpace {
@@ -896,6 +1028,10 @@ impl PktNumSpace {
 
             ack_elicited: false,
 
+            ack_eliciting_unacked: 0,
+
+            ack_timer: None,
+
             key_update: None,
 
             crypto_open: None,
@@ -926,6 +1062,14 @@ impl PktNumSpace {
         );
 
         self.ack_elicited = false;
+        self.ack_eliciting_unacked = 0;
+        self.ack_timer = None;
+    }
+
+    /// Returns true if the given received packet number is out of order,
+    /// that is lower than the largest received one, or leaving a gap.
+    pub fn is_reordered(&self, pn: u64) -> bool {
+        pn < self.largest_rx_pkt_num || pn > self.largest_rx_pkt_num + 1
     }
 
     pub fn crypto_overhead(&self) -> Option<usize> {
@@ -948,6 +1092,15 @@ pub struct PktNumWindow {
 }
 
 impl PktNumWindow {
+    /// Creates a window where all packet numbers up to `largest` are
+    /// considered as already received.
+    pub fn from_largest(largest: u64) -> PktNumWindow {
+        PktNumWindow {
+            lower: largest.saturating_add(1),
+            window: 0,
+        }
+    }
+
     pub fn insert(&mut self, seq: u64) {
         // Packet is on the left end of the window.
         if seq < self.lower {
@@ -1045,6 +1198,69 @@ mod tests {
         assert_eq!(Header::from_bytes(&mut b, 9).unwrap(), hdr);
     }
 
+    #[test]
+    fn long_header_types_v2() {
+        let types = [
+            (Type::Initial, 0x01),
+            (Type::ZeroRTT, 0x02),
+            (Type::Handshake, 0x03),
+        ];
+
+        for (ty, bits) in types {
+            let hdr = Header {
+                ty,
+                version: crate::PROTOCOL_VERSION_V2,
+                dcid: vec![0xba; 9].into(),
+                scid: vec![0xbb; 7].into(),
+                pkt_num: 0,
+                pkt_num_len: 0,
+                token: if ty == Type::Initial {
+                    Some(vec![])
+                } else {
+                    None
+                },
+                versions: None,
+                key_phase: false,
+            };
+
+            let mut d = [0; 50];
+
+            let mut b = octets::OctetsMut::with_slice(&mut d);
+            assert!(hdr.to_bytes(&mut b).is_ok());
+            assert_eq!((d[0] & TYPE_MASK) >> 4, bits);
+
+            let mut b = octets::OctetsMut::with_slice(&mut d);
+            assert_eq!(Header::from_bytes(&mut b, 9).unwrap(), hdr);
+        }
+    }
+
+    #[test]
+    fn retry_integrity_v2() {
+        let mut pkt = [
+            0xcf, 0x6b, 0x33, 0x43, 0xcf, 0x00, 0x08, 0xf0, 0x67, 0xa5, 0x50,
+            0x2a, 0x42, 0x62, 0xb5, 0x74, 0x6f, 0x6b, 0x65, 0x6e, 0xc8, 0x64,
+            0x6c, 0xe8, 0xbf, 0xe3, 0x39, 0x52, 0xd9, 0x55, 0x54, 0x36, 0x65,
+            0xdc, 0xc7, 0xb6,
+        ];
+
+        let odcid = [0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08];
+
+        let mut b = octets::OctetsMut::with_slice(&mut pkt);
+
+        let hdr = Header::from_bytes(&mut b, 0).unwrap();
+        assert_eq!(hdr.ty, Type::Retry);
+        assert_eq!(hdr.version, crate::PROTOCOL_VERSION_V2);
+        assert_eq!(hdr.token, Some(b"token".to_vec()));
+
+        assert_eq!(verify_retry_integrity(&b, &odcid, hdr.version), Ok(()));
+
+        // The same packet doesn't verify as QUIC v1.
+        assert_eq!(
+            verify_retry_integrity(&b, &odcid, crate::PROTOCOL_VERSION_V1),
+            Err(Error::CryptoFail)
+        );
+    }
+
     #[test]
     fn initial_v1_dcid_too_long() {
         let hdr = Header {
@@ -1176,6 +1392,54 @@ mod tests {
         assert_eq!(Header::from_bytes(&mut b, 9).unwrap(), hdr);
     }
 
+    #[test]
+    fn greased_quic_bit() {
+        let hdr = Header {
+            ty: Type::Handshake,
+            version: crate::PROTOCOL_VERSION,
+            dcid: vec![0xba; 9].into(),
+            scid: vec![0xbb; 7].into(),
+            pkt_num: 0,
+            pkt_num_len: 0,
+            token: None,
+            versions: None,
+            key_phase: false,
+        };
+
+        let mut d = [0; 50];
+
+        let mut b = octets::OctetsMut::with_slice(&mut d);
+        assert!(hdr.to_bytes(&mut b).is_ok());
+        assert!(Header::quic_bit(d[0]));
+
+        let mut b = octets::OctetsMut::with_slice(&mut d);
+        assert!(hdr.to_bytes_with_quic_bit(&mut b, false).is_ok());
+        assert!(!Header::quic_bit(d[0]));
+
+        // The rest of the header is unchanged.
+        let mut b = octets::OctetsMut::with_slice(&mut d);
+        assert_eq!(Header::from_bytes(&mut b, 9).unwrap(), hdr);
+
+        let hdr = Header {
+            ty: Type::Short,
+            version: 0,
+            dcid: vec![0xba; 9].into(),
+            scid: ConnectionId::default(),
+            pkt_num: 0,
+            pkt_num_len: 0,
+            token: None,
+            versions: None,
+            key_phase: true,
+        };
+
+        let mut b = octets::OctetsMut::with_slice(&mut d);
+        assert!(hdr.to_bytes_with_quic_bit(&mut b, false).is_ok());
+        assert!(!Header::quic_bit(d[0]));
+
+        let mut b = octets::OctetsMut::with_slice(&mut d);
+        assert_eq!(Header::from_bytes(&mut b, 9).unwrap().ty, Type::Short);
+    }
+
     #[test]
     fn pkt_num_decode() {
         let pn = decode_pkt_num(0xa82f30ea, 0x9b32, 2);
@@ -1514,7 +1778,12 @@ mod tests {
 
         let alg = crypto::Algorithm::ChaCha20_Poly1305;
 
-        let aead = crypto::Open::from_secret(alg, secret.into()).unwrap();
+        let aead = crypto::Open::from_secret(
+            alg,
+            secret.into(),
+            crate::PROTOCOL_VERSION,
+        )
+        .unwrap();
 
         let mut hdr = Header::from_bytes(&mut b, 0).unwrap();
         assert_eq!(hdr.ty, Type::Short);
@@ -1882,7 +2151,12 @@ mod tests {
 
         let alg = crypto::Algorithm::ChaCha20_Poly1305;
 
-        let aead = crypto::Seal::from_secret(alg, secret.into()).unwrap();
+        let aead = crypto::Seal::from_secret(
+            alg,
+            secret.into(),
+            crate::PROTOCOL_VERSION,
+        )
+        .unwrap();
 
         let pn = 654_360_564;
         let pn_len = 3;
@@ -1910,6 +2184,62 @@ mod tests {
         assert_eq!(&out[..written], &expected_pkt[..]);
     }
 
+    #[test]
+    fn encrypt_batch() {
+        let secret = [
+            0x9a, 0xc3, 0x12, 0xa7, 0xf8, 0x77, 0x46, 0x8e, 0xbe, 0x69, 0x42,
+            0x27, 0x48, 0xad, 0x00, 0xa1, 0x54, 0x43, 0xf1, 0x82, 0x03, 0xa0,
+            0x7d, 0x60, 0x60, 0xf6, 0x88, 0xf3, 0x0f, 0x21, 0x63, 0x2b,
+        ];
+
+        let header = [0x42, 0x00, 0xbf, 0xf4];
+
+        let expected_pkt = [
+            0x4c, 0xfe, 0x41, 0x89, 0x65, 0x5e, 0x5c, 0xd5, 0x5c, 0x41, 0xf6,
+            0x90, 0x80, 0x57, 0x5d, 0x79, 0x99, 0xc2, 0x5a, 0x5b, 0xfb,
+        ];
+
+        let alg = crypto::Algorithm::ChaCha20_Poly1305;
+
+        let aead = crypto::Seal::from_secret(
+            alg,
+            secret.into(),
+            crate::PROTOCOL_VERSION,
+        )
+        .unwrap();
+
+        // The same packet is written twice, with a gap in between.
+        let mut out = vec![0; 64];
+
+        let mut pkts = Vec::new();
+
+        for offset in [0, 30] {
+            out[offset..offset + 4].copy_from_slice(&header);
+            out[offset + 4] = 0x01;
+
+            pkts.push(PendingSeal {
+                offset,
+                pn: 654_360_564,
+                pn_len: 3,
+                payload_offset: 4,
+                payload_len: 1,
+            });
+        }
+
+        assert_eq!(encrypt_pkts(&mut out, &pkts, &aead, None), Ok(()));
+
+        assert_eq!(&out[..21], &expected_pkt[..]);
+        assert_eq!(&out[21..30], &[0; 9]);
+        assert_eq!(&out[30..51], &expected_pkt[..]);
+
+        // Packets that don't fit are rejected.
+        pkts[1].offset = 50;
+        assert_eq!(
+            encrypt_pkts(&mut out, &pkts, &aead, None),
+            Err(Error::BufferTooShort)
+        );
+    }
+
     #[test]
     fn decrypt_pkt_underflow() {
         let mut buf = [0; 65535];
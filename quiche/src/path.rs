@@ -37,6 +37,7 @@ use slab::Slab;
 use crate::Error;
 use crate::Result;
 
+use crate::pmtud;
 use crate::recovery;
 use crate::recovery::HandshakeStatus;
 
@@ -111,6 +112,60 @@ pub enum PathEvent {
     PeerMigrated(SocketAddr, SocketAddr),
 }
 
+/// How a server handles packets received from a new peer address.
+///
+/// This enum is set with [`set_new_path_policy()`].
+///
+/// [`set_new_path_policy()`]: struct.Config.html#method.set_new_path_policy
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(C)]
+pub enum NewPathPolicy {
+    /// A new path is created and its validation is started immediately
+    /// (default).
+    Validate = 0,
+
+    /// A new path is created, but it is only validated once the application
+    /// requests it with [`probe_path()`], or once the peer migrates to it.
+    ///
+    /// [`probe_path()`]: struct.Connection.html#method.probe_path
+    Defer    = 1,
+
+    /// Packets from new peer addresses are dropped, so the peer can't migrate.
+    Drop     = 2,
+}
+
+/// How a client switches to a new path when migrating.
+///
+/// This enum is set with [`set_migration_policy()`], and applies to
+/// migrations started with [`migrate_with_policy()`].
+///
+/// [`set_migration_policy()`]: struct.Config.html#method.set_migration_policy
+/// [`migrate_with_policy()`]: struct.Connection.html#method.migrate_with_policy
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(C)]
+pub enum MigrationPolicy {
+    /// The connection switches to the new path right away, like
+    /// [`migrate()`] does (default).
+    ///
+    /// [`migrate()`]: struct.Connection.html#method.migrate
+    Immediate       = 0,
+
+    /// The connection keeps using the current path until the new one is
+    /// validated, like [`migrate_after_validation()`] does. The previous
+    /// path is abandoned once the switch happens.
+    ///
+    /// [`migrate_after_validation()`]: struct.Connection.html#method.migrate_after_validation
+    ValidateFirst   = 1,
+
+    /// Like [`ValidateFirst`], but the previous path is kept usable after
+    /// the switch, so that the connection can fall back to it with
+    /// [`migrate()`] if the new path turns out to be worse.
+    ///
+    /// [`ValidateFirst`]: enum.MigrationPolicy.html#variant.ValidateFirst
+    /// [`migrate()`]: struct.Connection.html#method.migrate
+    MakeBeforeBreak = 2,
+}
+
 /// A network path on which QUIC packets can be sent.
 #[derive(Debug)]
 pub struct Path {
@@ -193,8 +248,40 @@ pub struct Path {
     /// to be validated.
     migrating: bool,
 
+    /// Whether the connection should migrate to this path as soon as it is
+    /// validated.
+    pub migrate_on_validation: bool,
+
+    /// Whether the previously active path should be kept usable when the
+    /// connection migrates to this path.
+    pub keep_previous_path: bool,
+
     /// Whether or not we should force eliciting of an ACK (e.g. via PING frame)
     pub needs_ack_eliciting: bool,
+
+    /// Whether a path quality probe should be sent.
+    quality_probe_requested: bool,
+
+    /// The PATH_CHALLENGE data of in-flight path quality probes, along with
+    /// the time they were sent.
+    quality_probes: VecDeque<([u8; 8], time::Instant)>,
+
+    /// The number of path quality probes sent, answered and lost.
+    quality_probes_sent: usize,
+    quality_probes_acked: usize,
+    quality_probes_lost: usize,
+
+    /// The round-trip time measured by the latest answered quality probe.
+    quality_probe_rtt: Option<time::Duration>,
+
+    /// When the current statistics interval started.
+    interval_start: time::Instant,
+
+    /// The cumulative statistics at the start of the current interval.
+    interval_base: Option<PathStats>,
+
+    /// PMTU discovery state, if enabled.
+    pub pmtud: Option<pmtud::Pmtud>,
 }
 
 impl Path {
@@ -239,7 +326,20 @@ impl Path {
             challenge_requested: false,
             failure_notified: false,
             migrating: false,
+            migrate_on_validation: false,
+            keep_previous_path: false,
             needs_ack_eliciting: false,
+            quality_probe_requested: false,
+            quality_probes: VecDeque::new(),
+            quality_probes_sent: 0,
+            quality_probes_acked: 0,
+            quality_probes_lost: 0,
+            quality_probe_rtt: None,
+            interval_start: time::Instant::now(),
+            interval_base: None,
+            pmtud: recovery_config.pmtud_max_datagram_size.map(|max| {
+                pmtud::Pmtud::new(crate::MAX_SEND_UDP_PAYLOAD_SIZE, max)
+            }),
         }
     }
 
@@ -285,7 +385,9 @@ impl Path {
     /// Returns whether the path requires sending a probing packet.
     #[inline]
     pub fn probing_required(&self) -> bool {
-        !self.received_challenges.is_empty() || self.validation_requested()
+        !self.received_challenges.is_empty() ||
+            self.validation_requested() ||
+            self.quality_probe_requested
     }
 
     /// Promotes the path to the provided state only if the new state is greater
@@ -340,6 +442,73 @@ impl Path {
             .push_back((data, pkt_size, sent_time));
     }
 
+    /// Requests a path quality probe.
+    #[inline]
+    pub fn request_quality_probe(&mut self) {
+        self.quality_probe_requested = true;
+    }
+
+    /// Returns whether a path quality probe is requested.
+    #[inline]
+    pub fn quality_probe_requested(&self) -> bool {
+        self.quality_probe_requested
+    }
+
+    /// Handles the sending of a PATH_CHALLENGE used as a quality probe.
+    pub fn on_quality_probe_sent(&mut self, data: [u8; 8], now: time::Instant) {
+        self.quality_probe_requested = false;
+        self.quality_probes.push_back((data, now));
+        self.quality_probes_sent += 1;
+    }
+
+    /// Handles a PATH_RESPONSE answering a quality probe.
+    ///
+    /// Returns whether `data` matched an in-flight quality probe.
+    pub fn on_quality_probe_response(
+        &mut self, data: [u8; 8], now: time::Instant,
+    ) -> bool {
+        let pos = match self.quality_probes.iter().position(|(d, _)| *d == data) {
+            Some(v) => v,
+
+            None => return false,
+        };
+
+        if let Some((_, sent_time)) = self.quality_probes.remove(pos) {
+            self.quality_probe_rtt =
+                Some(now.saturating_duration_since(sent_time));
+            self.quality_probes_acked += 1;
+        }
+
+        true
+    }
+
+    /// Declares lost the quality probes that were not answered within three
+    /// probe timeouts.
+    pub fn detect_lost_quality_probes(&mut self, now: time::Instant) {
+        let threshold = self.recovery.pto() * 3;
+
+        let len = self.quality_probes.len();
+
+        self.quality_probes
+            .retain(|(_, sent_time)| now < *sent_time + threshold);
+
+        self.quality_probes_lost += len - self.quality_probes.len();
+    }
+
+    /// Lowers the maximum size of datagrams sent on the path, which also
+    /// bounds PMTU discovery.
+    pub fn clamp_max_datagram_size(&mut self, max: usize) {
+        let max = std::cmp::max(max, crate::MAX_SEND_UDP_PAYLOAD_SIZE);
+
+        if let Some(pmtud) = &mut self.pmtud {
+            pmtud.clamp(max);
+        }
+
+        if max < self.recovery.max_datagram_size() {
+            self.recovery.update_max_datagram_size(max);
+        }
+    }
+
     pub fn on_challenge_received(&mut self, data: [u8; 8]) {
         // Discard challenges that would cause us to queue more than we want.
         if self.received_challenges.len() == self.received_challenges_max_len {
@@ -399,15 +568,20 @@ impl Path {
         self.received_challenges.pop_front()
     }
 
-    pub fn on_loss_detection_timeout(
+    pub fn on_loss_detection_timeout<F>(
         &mut self, handshake_status: HandshakeStatus, now: time::Instant,
-        is_server: bool, trace_id: &str,
-    ) -> (usize, usize) {
-        let (lost_packets, lost_bytes) = self.recovery.on_loss_detection_timeout(
-            handshake_status,
-            now,
-            trace_id,
-        );
+        is_server: bool, trace_id: &str, stream_urgency: F,
+    ) -> (usize, usize)
+    where
+        F: Fn(u64) -> u8,
+    {
+        let (lost_packets, lost_bytes) =
+            self.recovery.on_loss_detection_timeout_with_priority(
+                handshake_status,
+                now,
+                trace_id,
+                stream_urgency,
+            );
 
         let mut lost_probe_time = None;
         self.in_flight_challenges.retain(|(_, _, sent_time)| {
@@ -454,6 +628,18 @@ impl Path {
         (lost_packets, lost_bytes)
     }
 
+    /// Returns the latest round-trip time estimate of the path.
+    ///
+    /// This is the smoothed RTT once packets were acknowledged on the path,
+    /// or the RTT measured by the latest answered quality probe otherwise.
+    pub fn rtt_sample(&self) -> Option<time::Duration> {
+        if self.recovery.min_rtt().is_some() {
+            return Some(self.recovery.rtt());
+        }
+
+        self.quality_probe_rtt
+    }
+
     pub fn stats(&self) -> PathStats {
         PathStats {
             local_addr: self.local_addr,
@@ -464,6 +650,9 @@ impl Path {
             sent: self.sent_count,
             lost: self.recovery.lost_count,
             retrans: self.retrans_count,
+            persistent_congestion_count: self
+                .recovery
+                .persistent_congestion_count,
             rtt: self.recovery.rtt(),
             min_rtt: self.recovery.min_rtt(),
             rttvar: self.recovery.rttvar(),
@@ -474,6 +663,69 @@ impl Path {
             stream_retrans_bytes: self.stream_retrans_bytes,
             pmtu: self.recovery.max_datagram_size(),
             delivery_rate: self.recovery.delivery_rate(),
+            quality_probes_sent: self.quality_probes_sent,
+            quality_probes_acked: self.quality_probes_acked,
+            quality_probes_lost: self.quality_probes_lost,
+            quality_probe_rtt: self.quality_probe_rtt,
+        }
+    }
+
+    /// Starts a new statistics interval.
+    pub fn reset_interval_stats(&mut self, now: time::Instant) {
+        self.interval_base = Some(self.stats());
+        self.interval_start = now;
+
+        self.recovery.reset_interval_rtt();
+    }
+
+    /// Returns the statistics collected during the current interval.
+    pub fn interval_stats(&self, now: time::Instant) -> PathIntervalStats {
+        let stats = self.stats();
+
+        let (recv, sent, lost, retrans) = match &self.interval_base {
+            Some(base) => (
+                stats.recv - base.recv,
+                stats.sent - base.sent,
+                stats.lost - base.lost,
+                stats.retrans - base.retrans,
+            ),
+
+            None => (stats.recv, stats.sent, stats.lost, stats.retrans),
+        };
+
+        let (sent_bytes, recv_bytes, lost_bytes, stream_retrans_bytes) =
+            match &self.interval_base {
+                Some(base) => (
+                    stats.sent_bytes - base.sent_bytes,
+                    stats.recv_bytes - base.recv_bytes,
+                    stats.lost_bytes - base.lost_bytes,
+                    stats.stream_retrans_bytes - base.stream_retrans_bytes,
+                ),
+
+                None => (
+                    stats.sent_bytes,
+                    stats.recv_bytes,
+                    stats.lost_bytes,
+                    stats.stream_retrans_bytes,
+                ),
+            };
+
+        PathIntervalStats {
+            local_addr: self.local_addr,
+            peer_addr: self.peer_addr,
+            duration: now.saturating_duration_since(self.interval_start),
+            recv,
+            sent,
+            lost,
+            retrans,
+            sent_bytes,
+            recv_bytes,
+            lost_bytes,
+            stream_retrans_bytes,
+            min_rtt: self.recovery.interval_min_rtt,
+            max_rtt: self.recovery.interval_max_rtt,
+            rtt: stats.rtt,
+            cwnd: stats.cwnd,
         }
     }
 }
@@ -521,6 +773,12 @@ pub struct PathMap {
 
     /// Whether this manager serves a connection as a server.
     is_server: bool,
+
+    /// The number of paths that were successfully validated.
+    validated_count: u64,
+
+    /// The number of paths that failed to be validated.
+    failed_validation_count: u64,
 }
 
 impl PathMap {
@@ -547,6 +805,8 @@ impl PathMap {
             addrs_to_paths,
             events: VecDeque::new(),
             is_server,
+            validated_count: 0,
+            failed_validation_count: 0,
         }
     }
 
@@ -714,9 +974,21 @@ impl PathMap {
             ));
 
             p.failure_notified = true;
+
+            self.failed_validation_count += 1;
         }
     }
 
+    /// Returns the number of paths that were successfully validated.
+    pub fn validated_count(&self) -> u64 {
+        self.validated_count
+    }
+
+    /// Returns the number of paths that failed to be validated.
+    pub fn failed_validation_count(&self) -> u64 {
+        self.failed_validation_count
+    }
+
     /// Finds a path candidate to be active and returns its identifier.
     pub fn find_candidate_path(&self) -> Option<usize> {
         // TODO: also consider unvalidated paths if there are no more validated.
@@ -726,6 +998,16 @@ impl PathMap {
             .map(|(pid, _)| pid)
     }
 
+    /// Handles incoming PATH_RESPONSE data answering a path quality probe.
+    ///
+    /// Returns whether `data` matched an in-flight quality probe on any path.
+    pub fn on_quality_probe_response(
+        &mut self, data: [u8; 8], now: time::Instant,
+    ) -> bool {
+        self.iter_mut()
+            .any(|(_, p)| p.on_quality_probe_response(data, now))
+    }
+
     /// Handles incoming PATH_RESPONSE data.
     pub fn on_response_received(&mut self, data: [u8; 8]) -> Result<()> {
         let active_pid = self.get_active_path_id()?;
@@ -741,6 +1023,8 @@ impl PathMap {
 
                 p.migrating = false;
 
+                self.validated_count += 1;
+
                 // Notifies the application.
                 self.notify_event(PathEvent::Validated(local_addr, peer_addr));
 
@@ -827,6 +1111,10 @@ pub struct PathStats {
     /// The number of sent QUIC packets with retransmitted data.
     pub retrans: usize,
 
+    /// The number of times persistent congestion was established, collapsing
+    /// the congestion window to its minimum size.
+    pub persistent_congestion_count: usize,
+
     /// The estimated round-trip time of the connection.
     pub rtt: time::Duration,
 
@@ -853,6 +1141,9 @@ pub struct PathStats {
     pub stream_retrans_bytes: u64,
 
     /// The current PMTU for the connection.
+    ///
+    /// This is also the datagram size congestion control uses to convert
+    /// between bytes and packets.
     pub pmtu: usize,
 
     /// The most recent data delivery rate estimate in bytes/s.
@@ -864,6 +1155,18 @@ pub struct PathStats {
     /// [`SendInfo.at`]: struct.SendInfo.html#structfield.at
     /// [Pacing]: index.html#pacing
     pub delivery_rate: u64,
+
+    /// The number of path quality probes sent.
+    pub quality_probes_sent: usize,
+
+    /// The number of path quality probes answered by the peer.
+    pub quality_probes_acked: usize,
+
+    /// The number of path quality probes that were not answered in time.
+    pub quality_probes_lost: usize,
+
+    /// The round-trip time measured by the latest answered path quality probe.
+    pub quality_probe_rtt: Option<time::Duration>,
 }
 
 impl std::fmt::Debug for PathStats {
@@ -899,6 +1202,103 @@ impl std::fmt::Debug for PathStats {
     }
 }
 
+/// A comparison of the round-trip times of the active path and a candidate
+/// path.
+///
+/// It is returned by the [`compare_path_rtt()`] method.
+///
+/// [`compare_path_rtt()`]: struct.Connection.html#method.compare_path_rtt
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PathRttComparison {
+    /// The estimated round-trip time of the active path.
+    pub active_rtt: time::Duration,
+
+    /// The estimated round-trip time of the candidate path, if it was
+    /// measured yet.
+    pub candidate_rtt: Option<time::Duration>,
+}
+
+impl PathRttComparison {
+    /// Returns whether the candidate path was measured to be faster than the
+    /// active path.
+    pub fn candidate_is_faster(&self) -> bool {
+        self.candidate_rtt
+            .map_or(false, |rtt| rtt < self.active_rtt)
+    }
+}
+
+/// Statistics about the path of a connection, collected over an interval.
+///
+/// The interval starts when the path is created, and is restarted by the
+/// [`reset_path_stats()`] method. Counters only account for events that
+/// happened during the interval, while other values reflect the state of the
+/// path at the time the statistics are collected.
+///
+/// It is returned by the [`path_interval_stats()`] method.
+///
+/// [`reset_path_stats()`]: struct.Connection.html#method.reset_path_stats
+/// [`path_interval_stats()`]: struct.Connection.html#method.path_interval_stats
+#[derive(Clone, Debug)]
+pub struct PathIntervalStats {
+    /// The local address of the path.
+    pub local_addr: SocketAddr,
+
+    /// The peer address of the path.
+    pub peer_addr: SocketAddr,
+
+    /// The time elapsed since the start of the interval.
+    pub duration: time::Duration,
+
+    /// The number of QUIC packets received during the interval.
+    pub recv: usize,
+
+    /// The number of QUIC packets sent during the interval.
+    pub sent: usize,
+
+    /// The number of QUIC packets declared lost during the interval.
+    pub lost: usize,
+
+    /// The number of QUIC packets with retransmitted data sent during the
+    /// interval.
+    pub retrans: usize,
+
+    /// The number of bytes sent during the interval.
+    pub sent_bytes: u64,
+
+    /// The number of bytes received during the interval.
+    pub recv_bytes: u64,
+
+    /// The number of bytes declared lost during the interval.
+    pub lost_bytes: u64,
+
+    /// The number of stream bytes retransmitted during the interval.
+    pub stream_retrans_bytes: u64,
+
+    /// The minimum RTT sample taken during the interval, if any.
+    pub min_rtt: Option<time::Duration>,
+
+    /// The maximum RTT sample taken during the interval, if any.
+    pub max_rtt: Option<time::Duration>,
+
+    /// The current estimated round-trip time of the path.
+    pub rtt: time::Duration,
+
+    /// The current size of the congestion window in bytes.
+    pub cwnd: usize,
+}
+
+impl PathIntervalStats {
+    /// Returns the fraction of packets sent during the interval that were
+    /// declared lost.
+    pub fn loss_rate(&self) -> f64 {
+        if self.sent == 0 {
+            return 0.0;
+        }
+
+        self.lost as f64 / self.sent as f64
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::rand;
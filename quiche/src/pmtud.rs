@@ -0,0 +1,245 @@
+// Copyright (C) 2023, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Datagram Packetization Layer PMTU Discovery (RFC 8899).
+//!
+//! The search starts optimistically by probing the largest allowed size, and
+//! then performs a binary search between the largest size that was confirmed
+//! and the smallest size that repeatedly failed.
+
+use std::cmp;
+
+/// The number of consecutive losses of a probe of a given size after which
+/// the size is considered too large (RFC 8899 Section 5.1.2).
+const MAX_PROBES: usize = 3;
+
+/// The search stops once the candidate range is smaller than this.
+const SEARCH_GRANULARITY: usize = 16;
+
+/// Per-path PMTU discovery state.
+#[derive(Debug)]
+pub struct Pmtud {
+    /// The largest datagram size confirmed to work on the path.
+    current: usize,
+
+    /// The largest datagram size that could still work on the path.
+    max: usize,
+
+    /// The size of the next probe.
+    probe_size: usize,
+
+    /// Whether a probe is currently in flight.
+    in_flight: bool,
+
+    /// The number of consecutive losses of probes of the current size.
+    failures: usize,
+}
+
+impl Pmtud {
+    pub fn new(base: usize, max: usize) -> Pmtud {
+        let max = cmp::max(base, max);
+
+        Pmtud {
+            current: base,
+
+            max,
+
+            probe_size: max,
+
+            in_flight: false,
+
+            failures: 0,
+        }
+    }
+
+    /// Lowers the largest size that is allowed to be probed, e.g. after the
+    /// peer's `max_udp_payload_size` transport parameter is received.
+    pub fn set_max(&mut self, max: usize) {
+        self.max = cmp::max(self.current, cmp::min(self.max, max));
+        self.probe_size = cmp::min(self.probe_size, self.max);
+    }
+
+    /// Lowers the largest size allowed on the path, even below the current
+    /// PMTU, e.g. when the application restricts the size of packets.
+    pub fn clamp(&mut self, max: usize) {
+        self.current = cmp::min(self.current, max);
+        self.max = cmp::min(self.max, max);
+        self.probe_size = cmp::min(self.probe_size, self.max);
+    }
+
+    /// Returns the size of the next probe.
+    pub fn probe_size(&self) -> usize {
+        self.probe_size
+    }
+
+    /// Returns true if a probe should be sent.
+    pub fn should_probe(&self) -> bool {
+        !self.in_flight && self.probe_size > self.current
+    }
+
+    pub fn on_probe_sent(&mut self) {
+        self.in_flight = true;
+    }
+
+    /// Processes the acknowledgement of a probe of the given size.
+    ///
+    /// Returns the new PMTU if it increased.
+    pub fn on_probe_acked(&mut self, size: usize) -> Option<usize> {
+        self.in_flight = false;
+        self.failures = 0;
+
+        let size = cmp::min(size, self.max);
+
+        if size <= self.current {
+            return None;
+        }
+
+        self.current = size;
+        self.update_probe_size();
+
+        Some(self.current)
+    }
+
+    /// Processes the loss of a probe of the given size.
+    pub fn on_probe_lost(&mut self, size: usize) {
+        self.in_flight = false;
+
+        // A stale probe, nothing to learn from it.
+        if size != self.probe_size {
+            return;
+        }
+
+        self.failures += 1;
+
+        if self.failures < MAX_PROBES {
+            return;
+        }
+
+        // Persistent loss, so the probe size is too large for the path.
+        self.failures = 0;
+        self.max = cmp::max(self.current, size - 1);
+        self.update_probe_size();
+    }
+
+    fn update_probe_size(&mut self) {
+        self.probe_size = if self.max - self.current < SEARCH_GRANULARITY {
+            // Search is complete.
+            self.current
+        } else {
+            self.current + (self.max - self.current + 1) / 2
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn probe_max_first() {
+        let mut pmtud = Pmtud::new(1200, 1500);
+
+        assert!(pmtud.should_probe());
+        assert_eq!(pmtud.probe_size(), 1500);
+
+        pmtud.on_probe_sent();
+        assert!(!pmtud.should_probe());
+
+        assert_eq!(pmtud.on_probe_acked(1500), Some(1500));
+        assert_eq!(pmtud.current, 1500);
+
+        // Nothing left to search.
+        assert!(!pmtud.should_probe());
+    }
+
+    #[test]
+    fn binary_search() {
+        let mut pmtud = Pmtud::new(1200, 9000);
+
+        // The largest size is lost repeatedly.
+        for _ in 0..MAX_PROBES {
+            assert_eq!(pmtud.probe_size(), 9000);
+
+            pmtud.on_probe_sent();
+            pmtud.on_probe_lost(9000);
+        }
+
+        // The search continues halfway.
+        assert_eq!(pmtud.probe_size(), 5100);
+        assert_eq!(pmtud.current, 1200);
+
+        pmtud.on_probe_sent();
+        assert_eq!(pmtud.on_probe_acked(5100), Some(5100));
+        assert_eq!(pmtud.probe_size(), 7050);
+
+        // Keep searching until the range is small enough.
+        while pmtud.should_probe() {
+            let size = pmtud.probe_size();
+
+            pmtud.on_probe_sent();
+
+            if size <= 8000 {
+                pmtud.on_probe_acked(size);
+            } else {
+                for _ in 0..MAX_PROBES {
+                    pmtud.on_probe_lost(size);
+                }
+            }
+        }
+
+        assert!(pmtud.current <= 8000);
+        assert!(pmtud.current > 8000 - SEARCH_GRANULARITY);
+    }
+
+    #[test]
+    fn set_max() {
+        let mut pmtud = Pmtud::new(1200, 9000);
+
+        pmtud.set_max(1350);
+        assert_eq!(pmtud.probe_size(), 1350);
+
+        // The max can't be lower than the base size.
+        pmtud.set_max(1000);
+        assert_eq!(pmtud.probe_size(), 1200);
+        assert!(!pmtud.should_probe());
+    }
+
+    #[test]
+    fn clamp() {
+        let mut pmtud = Pmtud::new(1200, 9000);
+
+        assert_eq!(pmtud.on_probe_acked(9000), Some(9000));
+        assert!(!pmtud.should_probe());
+
+        // Clamping also lowers the current PMTU.
+        pmtud.clamp(1500);
+        assert_eq!(pmtud.probe_size(), 1500);
+        assert!(!pmtud.should_probe());
+
+        // Larger probes are not acknowledged past the clamped size.
+        assert_eq!(pmtud.on_probe_acked(9000), None);
+    }
+}
@@ -0,0 +1,554 @@
+// Copyright (C) 2023, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! QUIC-LB compatible Connection IDs.
+//!
+//! This module implements the Connection ID formats described in
+//! [draft-ietf-quic-load-balancers], which encode a server ID into every
+//! Connection ID issued by a server. A load balancer sharing the same
+//! configuration can then extract the server ID and route packets to the right
+//! server, even after the client migrates to a new address or the load
+//! balancer itself loses its flow state.
+//!
+//! Connection IDs are laid out as follows:
+//!
+//! ```text
+//! +-----------+-------------+-----------------+
+//! | first     | server ID   | nonce           |
+//! | octet     | (1-15)      | (4-18)          |
+//! +-----------+-------------+-----------------+
+//! ```
+//!
+//! The three most significant bits of the first octet carry the config ID,
+//! and the remaining ones either carry the length of the Connection ID or
+//! random bits. The server ID and nonce are either sent in the clear, or
+//! encrypted with AES-128, using a single block encryption when they add up to
+//! exactly 16 bytes and a four-pass Feistel network otherwise.
+//!
+//! ## Examples:
+//!
+//! ```no_run
+//! use quiche::ConnectionIdGenerator;
+//!
+//! let key = [0x42; 16];
+//! let mut generator =
+//!     quiche::quic_lb::Generator::encrypted(0, &[0x12, 0x34], 6, &key)?;
+//!
+//! let cid = generator.generate();
+//! assert_eq!(generator.decode_server_id(&cid), Some(vec![0x12, 0x34]));
+//! # Ok::<(), quiche::Error>(())
+//! ```
+//!
+//! [draft-ietf-quic-load-balancers]:
+//!     https://datatracker.ietf.org/doc/draft-ietf-quic-load-balancers/
+
+use crate::crypto;
+use crate::rand;
+
+use crate::ConnectionId;
+use crate::ConnectionIdGenerator;
+use crate::Error;
+use crate::Result;
+
+/// The config ID reserved for Connection IDs that are not routable.
+const UNROUTABLE_CONFIG_ID: u8 = 0b111;
+
+/// The maximum length of a server ID.
+pub const MAX_SERVER_ID_LEN: usize = 15;
+
+/// The minimum length of a nonce.
+pub const MIN_NONCE_LEN: usize = 4;
+
+/// The maximum length of a nonce.
+pub const MAX_NONCE_LEN: usize = 18;
+
+/// The maximum combined length of the server ID and nonce.
+const MAX_PLAINTEXT_LEN: usize = 19;
+
+/// Generates Connection IDs that encode a routable server ID.
+pub struct Generator {
+    config_id: u8,
+
+    server_id: Vec<u8>,
+
+    nonce_len: usize,
+
+    cipher: Option<crypto::Aes128Ecb>,
+
+    encode_len: bool,
+
+    nonce: u128,
+}
+
+impl Generator {
+    /// Creates a generator that encodes `server_id` in the clear.
+    ///
+    /// The `config_id` must be lower than 7, the length of `server_id` between
+    /// 1 and [`MAX_SERVER_ID_LEN`] bytes, and `nonce_len` between
+    /// [`MIN_NONCE_LEN`] and [`MAX_NONCE_LEN`] bytes, with the server ID and
+    /// nonce not exceeding 19 bytes combined. Otherwise an [`InvalidState`] is
+    /// returned.
+    ///
+    /// [`MAX_SERVER_ID_LEN`]: constant.MAX_SERVER_ID_LEN.html
+    /// [`MIN_NONCE_LEN`]: constant.MIN_NONCE_LEN.html
+    /// [`MAX_NONCE_LEN`]: constant.MAX_NONCE_LEN.html
+    /// [`InvalidState`]: ../enum.Error.html#variant.InvalidState
+    pub fn plaintext(
+        config_id: u8, server_id: &[u8], nonce_len: usize,
+    ) -> Result<Generator> {
+        Generator::new(config_id, server_id, nonce_len, None)
+    }
+
+    /// Creates a generator that encrypts `server_id` with the given AES-128
+    /// `key`.
+    ///
+    /// The same requirements as [`plaintext()`] apply.
+    ///
+    /// [`plaintext()`]: struct.Generator.html#method.plaintext
+    pub fn encrypted(
+        config_id: u8, server_id: &[u8], nonce_len: usize, key: &[u8; 16],
+    ) -> Result<Generator> {
+        let cipher = crypto::Aes128Ecb::new(key)?;
+
+        Generator::new(config_id, server_id, nonce_len, Some(cipher))
+    }
+
+    fn new(
+        config_id: u8, server_id: &[u8], nonce_len: usize,
+        cipher: Option<crypto::Aes128Ecb>,
+    ) -> Result<Generator> {
+        if config_id >= UNROUTABLE_CONFIG_ID ||
+            server_id.is_empty() ||
+            server_id.len() > MAX_SERVER_ID_LEN ||
+            !(MIN_NONCE_LEN..=MAX_NONCE_LEN).contains(&nonce_len) ||
+            server_id.len() + nonce_len > MAX_PLAINTEXT_LEN
+        {
+            return Err(Error::InvalidState);
+        }
+
+        let mut nonce = [0; 16];
+        rand::rand_bytes(&mut nonce);
+
+        Ok(Generator {
+            config_id,
+            server_id: server_id.to_vec(),
+            nonce_len,
+            cipher,
+            encode_len: false,
+            nonce: u128::from_be_bytes(nonce),
+        })
+    }
+
+    /// Configures whether the length of the Connection ID is encoded in the
+    /// first octet, as some load balancers require.
+    ///
+    /// The default value is `false`, meaning that random bits are used.
+    pub fn encode_length(&mut self, v: bool) {
+        self.encode_len = v;
+    }
+
+    /// Returns the length of the generated Connection IDs.
+    pub fn cid_len(&self) -> usize {
+        1 + self.server_id.len() + self.nonce_len
+    }
+
+    /// Extracts the server ID from a Connection ID generated with the same
+    /// configuration.
+    ///
+    /// Returns `None` if the Connection ID has the wrong length or config ID.
+    pub fn decode_server_id(&self, cid: &[u8]) -> Option<Vec<u8>> {
+        if cid.len() != self.cid_len() || cid[0] >> 5 != self.config_id {
+            return None;
+        }
+
+        let body = &cid[1..];
+
+        let plaintext = match &self.cipher {
+            None => body.to_vec(),
+
+            Some(cipher) if body.len() == 16 => {
+                let mut block = [0; 16];
+                block.copy_from_slice(body);
+
+                cipher.decrypt_block(&block).to_vec()
+            },
+
+            Some(cipher) => four_pass_decrypt(cipher, body),
+        };
+
+        Some(plaintext[..self.server_id.len()].to_vec())
+    }
+
+    fn next_nonce(&mut self) -> Vec<u8> {
+        // Counters don't repeat, unlike random nonces, which matters when the
+        // nonce is encrypted together with the server ID.
+        self.nonce = self.nonce.wrapping_add(1);
+
+        let counter = self.nonce.to_be_bytes();
+
+        let mut nonce = vec![0; self.nonce_len];
+
+        let counter_len = std::cmp::min(self.nonce_len, counter.len());
+        let prefix_len = self.nonce_len - counter_len;
+
+        rand::rand_bytes(&mut nonce[..prefix_len]);
+        nonce[prefix_len..].copy_from_slice(&counter[16 - counter_len..]);
+
+        nonce
+    }
+}
+
+impl ConnectionIdGenerator for Generator {
+    fn generate(&mut self) -> ConnectionId<'static> {
+        let mut plaintext = self.server_id.clone();
+        plaintext.extend_from_slice(&self.next_nonce());
+
+        let body = match &self.cipher {
+            None => plaintext,
+
+            Some(cipher) if plaintext.len() == 16 => {
+                let mut block = [0; 16];
+                block.copy_from_slice(&plaintext);
+
+                cipher.encrypt_block(&block).to_vec()
+            },
+
+            Some(cipher) => four_pass_encrypt(cipher, &plaintext),
+        };
+
+        let low_bits = if self.encode_len {
+            body.len() as u8
+        } else {
+            rand::rand_u8()
+        };
+
+        let mut cid = Vec::with_capacity(1 + body.len());
+        cid.push((self.config_id << 5) | (low_bits & 0x1f));
+        cid.extend_from_slice(&body);
+
+        cid.into()
+    }
+}
+
+/// Pads one half of the plaintext to a full AES block, together with the
+/// plaintext length and the pass index.
+fn expand(half: &[u8], len: usize, index: u8) -> [u8; 16] {
+    let mut block = [0; 16];
+
+    block[..half.len()].copy_from_slice(half);
+    block[14] = len as u8;
+    block[15] = index;
+
+    block
+}
+
+fn half_len(len: usize) -> usize {
+    (len + 1) / 2
+}
+
+/// Returns the left half of `block`, clearing the trailing nibble when the
+/// length is odd.
+fn truncate_left(block: &[u8], len: usize) -> Vec<u8> {
+    let mut half = block[..half_len(len)].to_vec();
+
+    if len % 2 == 1 {
+        if let Some(b) = half.last_mut() {
+            *b &= 0xf0;
+        }
+    }
+
+    half
+}
+
+/// Returns the right half of `block`, clearing the leading nibble when the
+/// length is odd.
+fn truncate_right(block: &[u8], len: usize) -> Vec<u8> {
+    let mut half = block[block.len() - half_len(len)..].to_vec();
+
+    if len % 2 == 1 {
+        half[0] &= 0x0f;
+    }
+
+    half
+}
+
+/// Returns the bytes of an encrypted `block` that are XORed with the right
+/// half, clearing the leading nibble when the length is odd.
+fn truncate_mask_right(block: &[u8], len: usize) -> Vec<u8> {
+    let mut half = block[..half_len(len)].to_vec();
+
+    if len % 2 == 1 {
+        half[0] &= 0x0f;
+    }
+
+    half
+}
+
+fn merge(left: &[u8], right: &[u8], len: usize) -> Vec<u8> {
+    let mut out = left.to_vec();
+
+    if len % 2 == 1 {
+        if let Some(b) = out.last_mut() {
+            *b |= right[0];
+        }
+
+        out.extend_from_slice(&right[1..]);
+    } else {
+        out.extend_from_slice(right);
+    }
+
+    out
+}
+
+fn xor(dst: &mut [u8], src: &[u8]) {
+    for (d, s) in dst.iter_mut().zip(src) {
+        *d ^= s;
+    }
+}
+
+fn four_pass_encrypt(cipher: &crypto::Aes128Ecb, plaintext: &[u8]) -> Vec<u8> {
+    let len = plaintext.len();
+
+    let mut left = truncate_left(plaintext, len);
+    let mut right = truncate_right(plaintext, len);
+
+    let mask = cipher.encrypt_block(&expand(&left, len, 1));
+    xor(&mut right, &truncate_mask_right(&mask, len));
+
+    let mask = cipher.encrypt_block(&expand(&right, len, 2));
+    xor(&mut left, &truncate_left(&mask, len));
+
+    let mask = cipher.encrypt_block(&expand(&left, len, 3));
+    xor(&mut right, &truncate_mask_right(&mask, len));
+
+    let mask = cipher.encrypt_block(&expand(&right, len, 4));
+    xor(&mut left, &truncate_left(&mask, len));
+
+    merge(&left, &right, len)
+}
+
+fn four_pass_decrypt(cipher: &crypto::Aes128Ecb, ciphertext: &[u8]) -> Vec<u8> {
+    let len = ciphertext.len();
+
+    let mut left = truncate_left(ciphertext, len);
+    let mut right = truncate_right(ciphertext, len);
+
+    let mask = cipher.encrypt_block(&expand(&right, len, 4));
+    xor(&mut left, &truncate_left(&mask, len));
+
+    let mask = cipher.encrypt_block(&expand(&left, len, 3));
+    xor(&mut right, &truncate_mask_right(&mask, len));
+
+    let mask = cipher.encrypt_block(&expand(&right, len, 2));
+    xor(&mut left, &truncate_left(&mask, len));
+
+    let mask = cipher.encrypt_block(&expand(&left, len, 1));
+    xor(&mut right, &truncate_mask_right(&mask, len));
+
+    merge(&left, &right, len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plaintext() {
+        let mut generator =
+            Generator::plaintext(1, &[0xab, 0xcd, 0xef], 5).unwrap();
+        generator.encode_length(true);
+
+        let cid = generator.generate();
+        assert_eq!(cid.len(), generator.cid_len());
+        assert_eq!(cid[0], (1 << 5) | 8);
+        assert_eq!(&cid[1..4], &[0xab, 0xcd, 0xef]);
+
+        assert_eq!(
+            generator.decode_server_id(&cid),
+            Some(vec![0xab, 0xcd, 0xef])
+        );
+
+        // Nonces don't repeat.
+        assert_ne!(generator.generate(), cid);
+    }
+
+    #[test]
+    fn encrypted() {
+        let key = [0x8f; 16];
+
+        // Single pass (server ID and nonce add up to 16 bytes), and four
+        // passes with even and odd lengths.
+        for (server_id_len, nonce_len) in [(4, 12), (3, 5), (3, 6), (1, 18)] {
+            let server_id: Vec<u8> = (1..=server_id_len).collect();
+
+            let mut generator =
+                Generator::encrypted(2, &server_id, nonce_len as usize, &key)
+                    .unwrap();
+
+            let cid = generator.generate();
+            assert_eq!(cid.len(), generator.cid_len());
+            assert_eq!(cid[0] >> 5, 2);
+            assert_ne!(&cid[1..1 + server_id.len()], &server_id[..]);
+
+            assert_eq!(generator.decode_server_id(&cid), Some(server_id));
+        }
+    }
+
+    /// A config ID, server ID and nonce, and the resulting Connection ID.
+    type Vector = (u8, &'static [u8], &'static [u8], &'static [u8]);
+
+    /// Creates a generator whose next Connection ID uses the given nonce.
+    fn generator_with_nonce(
+        config_id: u8, server_id: &[u8], nonce: &[u8], key: Option<&[u8; 16]>,
+    ) -> Generator {
+        let mut generator = match key {
+            Some(key) =>
+                Generator::encrypted(config_id, server_id, nonce.len(), key),
+
+            None => Generator::plaintext(config_id, server_id, nonce.len()),
+        }
+        .unwrap();
+
+        generator.encode_length(true);
+
+        let mut counter = [0; 16];
+        counter[16 - nonce.len()..].copy_from_slice(nonce);
+
+        generator.nonce = u128::from_be_bytes(counter).wrapping_sub(1);
+
+        generator
+    }
+
+    #[test]
+    fn plaintext_vectors() {
+        // Odd and even lengths.
+        let vectors: [Vector; 2] = [
+            (0, &[0xc4, 0x60, 0x5e], &[0x45, 0x04, 0xcc, 0x4f], &[
+                0x07, 0xc4, 0x60, 0x5e, 0x45, 0x04, 0xcc, 0x4f,
+            ]),
+            (
+                1,
+                &[0x35, 0x0d, 0x28, 0xb4, 0x20],
+                &[0x34, 0x87, 0xd9, 0x70, 0xb2],
+                &[
+                    0x2a, 0x35, 0x0d, 0x28, 0xb4, 0x20, 0x34, 0x87, 0xd9, 0x70,
+                    0xb2,
+                ],
+            ),
+        ];
+
+        for (config_id, server_id, nonce, cid) in vectors {
+            let server_id = server_id.to_vec();
+
+            let mut generator =
+                generator_with_nonce(config_id, &server_id, nonce, None);
+
+            let generated = generator.generate();
+            assert_eq!(&generated[..], cid);
+
+            assert_eq!(generator.decode_server_id(&generated), Some(server_id));
+        }
+    }
+
+    #[test]
+    fn encrypted_vectors() {
+        // Test vectors from draft-ietf-quic-load-balancers, appendix B.2.
+        let key = [
+            0x8f, 0x95, 0xf0, 0x92, 0x45, 0x76, 0x5f, 0x80, 0x25, 0x69, 0x34,
+            0xe5, 0x0c, 0x66, 0x20, 0x7f,
+        ];
+
+        let vectors: [Vector; 4] = [
+            // Four passes, odd length.
+            (0, &[0xed, 0x79, 0x3a], &[0xee, 0x08, 0x0d, 0xbf], &[
+                0x07, 0x20, 0xb1, 0xd0, 0x7b, 0x35, 0x9d, 0x3c,
+            ]),
+            // Four passes, odd length, with a server ID longer than the nonce.
+            (
+                1,
+                &[0xed, 0x79, 0x3a, 0x51, 0xd4, 0x9b, 0x8f, 0x5f, 0xab, 0x65],
+                &[0xee, 0x08, 0x0d, 0xbf, 0x48],
+                &[
+                    0x2f, 0xcc, 0x38, 0x1b, 0xc7, 0x4c, 0xb4, 0xfb, 0xad, 0x28,
+                    0x23, 0xa3, 0xd1, 0xf8, 0xfe, 0xd2,
+                ],
+            ),
+            // Single pass.
+            (
+                2,
+                &[0xed, 0x79, 0x3a, 0x51, 0xd4, 0x9b, 0x8f, 0x5f],
+                &[0xee, 0x08, 0x0d, 0xbf, 0x48, 0xc0, 0xd1, 0xe5],
+                &[
+                    0x50, 0x4d, 0xd2, 0xd0, 0x5a, 0x7b, 0x0d, 0xe9, 0xb2, 0xb9,
+                    0x90, 0x7a, 0xfb, 0x5e, 0xcf, 0x8c, 0xc3,
+                ],
+            ),
+            // Four passes, even length. The draft has no vector for this
+            // case, so this one was computed with the draft's algorithm.
+            (
+                3,
+                &[0xed, 0x79, 0x3a, 0x51],
+                &[0xee, 0x08, 0x0d, 0xbf, 0x48, 0xc0],
+                &[
+                    0x6a, 0x0a, 0xc3, 0x6e, 0xde, 0x5d, 0xec, 0x66, 0x5f, 0x16,
+                    0xde,
+                ],
+            ),
+        ];
+
+        for (config_id, server_id, nonce, cid) in vectors {
+            let server_id = server_id.to_vec();
+
+            let mut generator =
+                generator_with_nonce(config_id, &server_id, nonce, Some(&key));
+
+            let generated = generator.generate();
+            assert_eq!(&generated[..], cid);
+
+            assert_eq!(generator.decode_server_id(&generated), Some(server_id));
+        }
+    }
+
+    #[test]
+    fn decode_mismatch() {
+        let mut generator = Generator::plaintext(0, &[0x01, 0x02], 4).unwrap();
+
+        let mut cid = generator.generate().to_vec();
+
+        assert_eq!(generator.decode_server_id(&cid[..6]), None);
+
+        cid[0] |= 0x20;
+        assert_eq!(generator.decode_server_id(&cid), None);
+    }
+
+    #[test]
+    fn invalid_config() {
+        assert!(Generator::plaintext(UNROUTABLE_CONFIG_ID, &[1], 4).is_err());
+        assert!(Generator::plaintext(0, &[], 4).is_err());
+        assert!(Generator::plaintext(0, &[1], 3).is_err());
+        assert!(Generator::plaintext(0, &[1; 10], 10).is_err());
+    }
+}
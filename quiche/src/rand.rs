@@ -24,12 +24,22 @@
 // NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
 // SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
 
+#[cfg(not(feature = "rustls"))]
 pub fn rand_bytes(buf: &mut [u8]) {
     unsafe {
         RAND_bytes(buf.as_mut_ptr(), buf.len());
     }
 }
 
+#[cfg(feature = "rustls")]
+pub fn rand_bytes(buf: &mut [u8]) {
+    use ring::rand::SecureRandom;
+
+    // Like RAND_bytes(), this can't fail short of the OS refusing to provide
+    // randomness, in which case there is no way to continue safely.
+    ring::rand::SystemRandom::new().fill(buf).unwrap();
+}
+
 pub fn rand_u8() -> u8 {
     let mut buf = [0; 1];
 
@@ -59,6 +69,7 @@ pub fn rand_u64_uniform(max: u64) -> u64 {
     r / chunk_size
 }
 
+#[cfg(not(feature = "rustls"))]
 extern {
     fn RAND_bytes(buf: *mut u8, len: libc::size_t) -> libc::c_int;
 }
@@ -79,6 +79,11 @@ impl RangeSet {
         }
     }
 
+    /// Returns `true` if this [`RangeSet`] contains no ranges.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
     /// Converts the inner representation from a BTree to Inline and vice versa
     /// when the proper conditions are met. Keeps the stored data intact.
     #[inline(always)]
@@ -26,7 +26,6 @@
 
 use super::*;
 use crate::rand;
-use crate::recovery;
 
 use std::cmp;
 use std::time::Instant;
@@ -711,7 +710,7 @@ fn bbr2_modulate_cwnd_for_recovery(r: &mut Recovery) {
         r.congestion_window = r
             .congestion_window
             .saturating_sub(lost_bytes)
-            .max(r.max_datagram_size * recovery::MINIMUM_WINDOW_PACKETS);
+            .max(r.max_datagram_size * r.minimum_congestion_window_packets);
     }
 
     if r.bbr2_state.packet_conservation {
@@ -26,8 +26,8 @@
 
 //! CUBIC Congestion Control
 //!
-//! This implementation is based on the following draft:
-//! <https://tools.ietf.org/html/draft-ietf-tcpm-rfc8312bis-02>
+//! This implementation is based on the following RFC:
+//! <https://www.rfc-editor.org/rfc/rfc9438>
 //!
 //! Note that Slow Start can use HyStart++ when enabled.
 
@@ -37,7 +37,6 @@ use std::time::Duration;
 use std::time::Instant;
 
 use crate::packet;
-use crate::recovery;
 use crate::recovery::reno;
 
 use crate::recovery::Acked;
@@ -60,7 +59,7 @@ pub static CUBIC: CongestionControlOps = CongestionControlOps {
 
 /// CUBIC Constants.
 ///
-/// These are recommended value in RFC8312.
+/// These are recommended value in RFC9438.
 const BETA_CUBIC: f64 = 0.7;
 
 const C: f64 = 0.4;
@@ -78,7 +77,7 @@ const ALPHA_AIMD: f64 = 3.0 * (1.0 - BETA_CUBIC) / (1.0 + BETA_CUBIC);
 /// CUBIC State Variables.
 ///
 /// We need to keep those variables across the connection.
-/// k, w_max, w_est are described in the RFC.
+/// k, w_max, w_est and cwnd_prior are described in the RFC.
 #[derive(Debug, Default)]
 pub struct State {
     k: f64,
@@ -87,6 +86,10 @@ pub struct State {
 
     w_est: f64,
 
+    // The cwnd right before the most recent congestion event, used to decide
+    // when W_est stops growing at the reduced AIMD rate.
+    cwnd_prior: f64,
+
     alpha_aimd: f64,
 
     // Used in CUBIC fix (see on_packet_sent())
@@ -101,7 +104,7 @@ pub struct State {
 
 /// Stores the CUBIC state from before the last congestion event.
 ///
-/// <https://tools.ietf.org/id/draft-ietf-tcpm-rfc8312bis-00.html#section-4.9>
+/// <https://www.rfc-editor.org/rfc/rfc9438#section-4.9>
 #[derive(Debug, Default)]
 struct PriorState {
     congestion_window: usize,
@@ -123,6 +126,17 @@ struct PriorState {
 /// not packets.
 /// Unit of t (duration) and RTT are based on seconds (f64).
 impl State {
+    /// Returns the window size just before the last reduction, in bytes.
+    pub fn w_max(&self) -> f64 {
+        self.w_max
+    }
+
+    /// Returns the time period in seconds it takes to increase the congestion
+    /// window back to `w_max()`.
+    pub fn k(&self) -> f64 {
+        self.k
+    }
+
     // K = cubic_root ((w_max - cwnd) / C) (Eq. 2)
     fn cubic_k(&self, cwnd: usize, max_datagram_size: usize) -> f64 {
         let w_max = self.w_max / max_datagram_size as f64;
@@ -139,7 +153,7 @@ impl State {
             max_datagram_size as f64
     }
 
-    // W_est = W_est + alpha_aimd * (segments_acked / cwnd)  (Eq. 4)
+    // W_est = W_est + alpha_cubic * (segments_acked / cwnd)  (Eq. 4)
     fn w_est_inc(
         &self, acked: usize, cwnd: usize, max_datagram_size: usize,
     ) -> f64 {
@@ -159,12 +173,13 @@ fn collapse_cwnd(r: &mut Recovery) {
     r.congestion_recovery_start_time = None;
 
     cubic.w_max = r.congestion_window as f64;
+    cubic.cwnd_prior = r.congestion_window as f64;
 
-    // 4.7 Timeout - reduce ssthresh based on BETA_CUBIC
+    // 4.8 Timeout - reduce ssthresh based on BETA_CUBIC
     r.ssthresh = (r.congestion_window as f64 * BETA_CUBIC) as usize;
     r.ssthresh = cmp::max(
         r.ssthresh,
-        r.max_datagram_size * recovery::MINIMUM_WINDOW_PACKETS,
+        r.max_datagram_size * r.minimum_congestion_window_packets,
     );
 
     cubic.cwnd_inc = 0;
@@ -229,7 +244,7 @@ fn on_packet_acked(
     }
 
     // Detecting spurious congestion events.
-    // <https://tools.ietf.org/id/draft-ietf-tcpm-rfc8312bis-00.html#section-4.9>
+    // <https://www.rfc-editor.org/rfc/rfc9438#section-4.9>
     //
     // When the recovery episode ends with recovering
     // a few packets (less than cwnd / mss * ROLLBACK_THRESHOLD_PERCENT(%)), it's
@@ -284,6 +299,7 @@ fn on_packet_acked(
                 r.cubic_state.k = 0.0;
 
                 r.cubic_state.w_est = r.congestion_window as f64;
+                r.cubic_state.cwnd_prior = r.congestion_window as f64;
                 r.cubic_state.alpha_aimd = ALPHA_AIMD;
             }
         } else {
@@ -299,6 +315,7 @@ fn on_packet_acked(
                     r.cubic_state.k = 0.0;
 
                     r.cubic_state.w_est = r.congestion_window as f64;
+                    r.cubic_state.cwnd_prior = r.congestion_window as f64;
                     r.cubic_state.alpha_aimd = ALPHA_AIMD;
                 },
             }
@@ -313,7 +330,7 @@ fn on_packet_acked(
         let target = f64::max(target, r.congestion_window as f64);
         let target = f64::min(target, r.congestion_window as f64 * 1.5);
 
-        // Update w_est.
+        // Update w_est (4.3 Reno-Friendly Region).
         let w_est_inc = r.cubic_state.w_est_inc(
             packet.size,
             r.congestion_window,
@@ -321,7 +338,9 @@ fn on_packet_acked(
         );
         r.cubic_state.w_est += w_est_inc;
 
-        if r.cubic_state.w_est >= r.cubic_state.w_max {
+        // Once W_est reaches the cwnd from before the last reduction, it
+        // grows at the same rate as Reno.
+        if r.cubic_state.w_est >= r.cubic_state.cwnd_prior {
             r.cubic_state.alpha_aimd = 1.0;
         }
 
@@ -360,8 +379,12 @@ fn congestion_event(
     if !in_congestion_recovery {
         r.congestion_recovery_start_time = Some(now);
 
-        // Fast convergence
-        if (r.congestion_window as f64) < r.cubic_state.w_max {
+        r.cubic_state.cwnd_prior = r.congestion_window as f64;
+
+        // 4.7 Fast Convergence
+        if r.cubic_fast_convergence &&
+            (r.congestion_window as f64) < r.cubic_state.w_max
+        {
             r.cubic_state.w_max =
                 r.congestion_window as f64 * (1.0 + BETA_CUBIC) / 2.0;
         } else {
@@ -371,7 +394,7 @@ fn congestion_event(
         r.ssthresh = (r.congestion_window as f64 * BETA_CUBIC) as usize;
         r.ssthresh = cmp::max(
             r.ssthresh,
-            r.max_datagram_size * recovery::MINIMUM_WINDOW_PACKETS,
+            r.max_datagram_size * r.minimum_congestion_window_packets,
         );
         r.congestion_window = r.ssthresh;
 
@@ -439,6 +462,7 @@ fn debug_fmt(r: &Recovery, f: &mut std::fmt::Formatter) -> std::fmt::Result {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::recovery;
     use crate::recovery::hystart;
 
     use smallvec::smallvec;
@@ -754,7 +778,7 @@ mod tests {
         r.collapse_cwnd();
         assert_eq!(
             r.cwnd(),
-            r.max_datagram_size * recovery::MINIMUM_WINDOW_PACKETS
+            r.max_datagram_size * r.minimum_congestion_window_packets
         );
 
         let mut acked = vec![Acked {
@@ -776,7 +800,7 @@ mod tests {
         // Slow start again - cwnd will be increased by 1 MSS
         assert_eq!(
             r.cwnd(),
-            r.max_datagram_size * (recovery::MINIMUM_WINDOW_PACKETS + 1)
+            r.max_datagram_size * (r.minimum_congestion_window_packets + 1)
         );
     }
 
@@ -1337,4 +1361,170 @@ mod tests {
             prev_cwnd as f64 * (1.0 + BETA_CUBIC) / 2.0
         );
     }
+
+    #[test]
+    fn cubic_fast_convergence_disabled() {
+        let mut cfg = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+        cfg.set_cc_algorithm(recovery::CongestionControlAlgorithm::CUBIC);
+        cfg.enable_cubic_fast_convergence(false);
+
+        let mut r = Recovery::new(&cfg);
+        let mut now = Instant::now();
+        let prev_cwnd = r.cwnd();
+
+        for _ in 0..r.initial_congestion_window_packets {
+            r.on_packet_sent_cc(r.max_datagram_size, now);
+        }
+
+        let mut p = recovery::Sent {
+            pkt_num: 0,
+            frames: smallvec![],
+            time_sent: now,
+            time_acked: None,
+            time_lost: None,
+            size: r.max_datagram_size,
+            ack_eliciting: true,
+            in_flight: true,
+            delivered: 0,
+            delivered_time: now,
+            first_sent_time: now,
+            is_app_limited: false,
+            has_data: false,
+            tx_in_flight: 0,
+            lost: 0,
+        };
+
+        r.congestion_event(
+            r.max_datagram_size,
+            &p,
+            packet::Epoch::Application,
+            now,
+        );
+
+        let cur_cwnd = (prev_cwnd as f64 * BETA_CUBIC) as usize;
+        assert_eq!(r.cwnd(), cur_cwnd);
+        assert_eq!(r.cubic_state.w_max(), prev_cwnd as f64);
+
+        // A 2nd congestion event happens before cwnd recovers to w_max.
+        now += Duration::from_millis(100);
+        p.time_sent = now;
+
+        r.congestion_event(
+            r.max_datagram_size,
+            &p,
+            packet::Epoch::Application,
+            now,
+        );
+
+        assert_eq!(r.cwnd(), (cur_cwnd as f64 * BETA_CUBIC) as usize);
+
+        // Without fast convergence w_max is not further reduced.
+        assert_eq!(r.cubic_state.w_max(), cur_cwnd as f64);
+    }
+
+    #[test]
+    fn cubic_reno_friendly_switch_at_cwnd_prior() {
+        let mut cfg = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+        cfg.set_cc_algorithm(recovery::CongestionControlAlgorithm::CUBIC);
+
+        let mut r = Recovery::new(&cfg);
+        let mut now = Instant::now();
+
+        for _ in 0..r.initial_congestion_window_packets {
+            r.on_packet_sent_cc(r.max_datagram_size, now);
+        }
+
+        let mut p = recovery::Sent {
+            pkt_num: 0,
+            frames: smallvec![],
+            time_sent: now,
+            time_acked: None,
+            time_lost: None,
+            size: r.max_datagram_size,
+            ack_eliciting: true,
+            in_flight: true,
+            delivered: 0,
+            delivered_time: now,
+            first_sent_time: now,
+            is_app_limited: false,
+            has_data: false,
+            tx_in_flight: 0,
+            lost: 0,
+        };
+
+        r.congestion_event(
+            r.max_datagram_size,
+            &p,
+            packet::Epoch::Application,
+            now,
+        );
+
+        // A 2nd congestion event happens before cwnd recovers, so fast
+        // convergence reduces w_max below the cwnd prior to the reduction.
+        now += Duration::from_millis(100);
+        p.time_sent = now;
+
+        let prev_cwnd = r.cwnd();
+
+        r.congestion_event(
+            r.max_datagram_size,
+            &p,
+            packet::Epoch::Application,
+            now,
+        );
+
+        let cwnd_prior = r.cubic_state.cwnd_prior;
+        let w_max = r.cubic_state.w_max();
+
+        assert_eq!(cwnd_prior, prev_cwnd as f64);
+        assert!(w_max < cwnd_prior);
+        assert_eq!(r.cubic_state.w_est, r.cwnd() as f64);
+        assert_eq!(r.cubic_state.alpha_aimd, ALPHA_AIMD);
+
+        // Exit from the recovery.
+        let rtt = Duration::from_millis(100);
+        r.update_rtt(rtt, Duration::from_millis(0), now);
+        now += rtt;
+
+        // To avoid rollback
+        r.lost_count += MIN_ROLLBACK_THRESHOLD;
+
+        let mut passed_w_max = false;
+
+        // W_est grows at the AIMD rate until it reaches cwnd_prior, and at
+        // the Reno rate from then on.
+        for _ in 0..100 {
+            let mut acked = vec![Acked {
+                pkt_num: 0,
+                time_sent: now,
+                size: r.max_datagram_size,
+                delivered: 0,
+                delivered_time: now,
+                first_sent_time: now,
+                is_app_limited: false,
+                tx_in_flight: 0,
+                lost: 0,
+                rtt: Duration::ZERO,
+            }];
+
+            r.on_packets_acked(&mut acked, packet::Epoch::Application, now);
+            now += Duration::from_millis(10);
+
+            if r.cubic_state.w_est >= cwnd_prior {
+                break;
+            }
+
+            if r.cubic_state.w_est >= w_max {
+                passed_w_max = true;
+            }
+
+            assert_eq!(r.cubic_state.alpha_aimd, ALPHA_AIMD);
+        }
+
+        // W_est went past w_max without switching to the Reno rate.
+        assert!(passed_w_max);
+
+        assert!(r.cubic_state.w_est >= cwnd_prior);
+        assert_eq!(r.cubic_state.alpha_aimd, 1.0);
+    }
 }
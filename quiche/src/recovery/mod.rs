@@ -41,6 +41,10 @@ use crate::minmax;
 use crate::packet;
 use crate::ranges;
 
+#[cfg(feature = "qlog")]
+use qlog::events::quic::CongestionStateUpdated;
+#[cfg(feature = "qlog")]
+use qlog::events::quic::CongestionStateUpdatedTrigger;
 #[cfg(feature = "qlog")]
 use qlog::events::EventData;
 
@@ -55,7 +59,7 @@ const INITIAL_TIME_THRESHOLD: f64 = 9.0 / 8.0;
 
 const GRANULARITY: Duration = Duration::from_millis(1);
 
-const INITIAL_RTT: Duration = Duration::from_millis(333);
+pub(crate) const INITIAL_RTT: Duration = Duration::from_millis(333);
 
 const PERSISTENT_CONGESTION_THRESHOLD: u32 = 3;
 
@@ -63,12 +67,19 @@ const RTT_WINDOW: Duration = Duration::from_secs(300);
 
 const MAX_PTO_PROBES_COUNT: usize = 2;
 
-const MINIMUM_WINDOW_PACKETS: usize = 2;
-
 const LOSS_REDUCTION_FACTOR: f64 = 0.5;
 
 const PACING_MULTIPLIER: f64 = 1.25;
 
+// The maximum number of packet events buffered before the oldest ones are
+// dropped.
+const MAX_PACKET_EVENTS: usize = 4096;
+
+// The maximum number of ranges of already processed acknowledgements tracked
+// per packet number space. Older ranges are forgotten, which only makes
+// processing ACK frames that repeat them slower.
+const MAX_ACKED_RANGES: usize = 1024;
+
 // How many non ACK eliciting packets we send before including a PING to solicit
 // an ACK.
 pub(super) const MAX_OUTSTANDING_NON_ACK_ELICITING: usize = 24;
@@ -78,6 +89,27 @@ pub struct Recovery {
 
     pto_count: u32,
 
+    /// The number of PTOs that expired in each packet number space.
+    pub pto_expirations: [usize; packet::Epoch::count()],
+
+    /// The largest number of consecutive PTOs.
+    pub longest_pto_backoff: u32,
+
+    // The maximum PTO backoff exponent, if any.
+    max_pto_backoff: Option<u32>,
+
+    // Whether PTO probes retransmit the most recently sent data.
+    tail_loss_probe: bool,
+
+    // Which frames are rescheduled when a PTO fires.
+    pto_retransmit_policy: PtoRetransmitPolicy,
+
+    // Queued packet events, if enabled.
+    packet_events: Option<VecDeque<TransportEvent>>,
+
+    // The RTT used before any RTT sample is taken.
+    initial_rtt: Duration,
+
     time_of_last_sent_ack_eliciting_pkt:
         [Option<Instant>; packet::Epoch::count()],
 
@@ -87,6 +119,12 @@ pub struct Recovery {
 
     latest_rtt: Duration,
 
+    /// The minimum RTT sample since the last statistics reset.
+    pub interval_min_rtt: Option<Duration>,
+
+    /// The maximum RTT sample since the last statistics reset.
+    pub interval_max_rtt: Option<Duration>,
+
     smoothed_rtt: Option<Duration>,
 
     rttvar: Duration,
@@ -95,12 +133,25 @@ pub struct Recovery {
 
     min_rtt: Duration,
 
+    /// The time the first RTT sample was taken, used to exclude packets sent
+    /// before it from persistent congestion detection.
+    first_rtt_sample: Option<Instant>,
+
     pub max_ack_delay: Duration,
 
     loss_time: [Option<Instant>; packet::Epoch::count()],
 
     sent: [VecDeque<Sent>; packet::Epoch::count()],
 
+    // Packet numbers already processed as acknowledged, so that ranges
+    // repeated by later ACK frames can be skipped without walking the sent
+    // packets they cover.
+    acked_ranges: [ranges::RangeSet; packet::Epoch::count()],
+
+    // The lowest packet number that may still be neither acknowledged nor
+    // lost, from which loss detection starts.
+    loss_scan_start: [u64; packet::Epoch::count()],
+
     pub lost: [Vec<frame::Frame>; packet::Epoch::count()],
 
     pub acked: [Vec<frame::Frame>; packet::Epoch::count()],
@@ -109,6 +160,9 @@ pub struct Recovery {
 
     pub lost_spurious_count: usize,
 
+    /// How many times persistent congestion was established.
+    pub persistent_congestion_count: usize,
+
     pub loss_probes: [usize; packet::Epoch::count()],
 
     in_flight_count: [usize; packet::Epoch::count()],
@@ -144,18 +198,28 @@ pub struct Recovery {
 
     cubic_state: cubic::State,
 
+    // Whether CUBIC fast convergence is enabled.
+    cubic_fast_convergence: bool,
+
     // HyStart++.
     hystart: hystart::Hystart,
 
     // Pacing.
     pub pacer: pacer::Pacer,
 
+    pacing_mode: PacingMode,
+
     // RFC6937 PRR.
     prr: prr::PRR,
 
     #[cfg(feature = "qlog")]
     qlog_metrics: QlogMetrics,
 
+    // Whether persistent congestion was established since the last qlog
+    // congestion state event.
+    #[cfg(feature = "qlog")]
+    qlog_persistent_congestion: bool,
+
     // The maximum size of a data aggregate scheduled and
     // transmitted together.
     send_quantum: usize,
@@ -171,6 +235,16 @@ pub struct Recovery {
 
     /// Initial congestion window size in terms of packet count.
     initial_congestion_window_packets: usize,
+
+    /// Minimum congestion window size in terms of packet count.
+    minimum_congestion_window_packets: usize,
+
+    /// Frame lists of packets removed from `sent`, kept so that their heap
+    /// allocation can be reused by packets sent later.
+    frames_pool: Vec<SmallVec<[frame::Frame; 1]>>,
+
+    /// The maximum number of frame lists kept in `frames_pool`.
+    frames_pool_size: usize,
 }
 
 pub struct RecoveryConfig {
@@ -178,22 +252,56 @@ pub struct RecoveryConfig {
     pub max_ack_delay: Duration,
     cc_ops: &'static CongestionControlOps,
     hystart: bool,
+    cubic_fast_convergence: bool,
     pacing: bool,
+    pacing_mode: PacingMode,
     max_pacing_rate: Option<u64>,
     initial_congestion_window_packets: usize,
+    minimum_congestion_window_packets: usize,
+    initial_rtt: Duration,
+    max_pto_backoff: Option<u32>,
+    tail_loss_probe: bool,
+    pto_retransmit_policy: PtoRetransmitPolicy,
+    packet_events: bool,
+    pub pmtud_max_datagram_size: Option<usize>,
+    frames_pool_size: usize,
 }
 
 impl RecoveryConfig {
     pub fn from_config(config: &Config) -> Self {
         Self {
-            max_send_udp_payload_size: config.max_send_udp_payload_size,
+            // With PMTU discovery, start from the minimum size and let probes
+            // find the actual limit.
+            max_send_udp_payload_size: if config.pmtud {
+                crate::MAX_SEND_UDP_PAYLOAD_SIZE
+            } else {
+                config.max_send_udp_payload_size
+            },
             max_ack_delay: Duration::ZERO,
             cc_ops: config.cc_algorithm.into(),
             hystart: config.hystart,
-            pacing: config.pacing,
+            cubic_fast_convergence: config.cubic_fast_convergence,
+            pacing: config.pacing && config.pacing_mode != PacingMode::Disabled,
+            pacing_mode: config.pacing_mode,
             max_pacing_rate: config.max_pacing_rate,
-            initial_congestion_window_packets: config
-                .initial_congestion_window_packets,
+            // The initial window can't be smaller than the minimum one.
+            initial_congestion_window_packets: cmp::max(
+                config.initial_congestion_window_packets,
+                config.minimum_congestion_window_packets,
+            ),
+            minimum_congestion_window_packets: config
+                .minimum_congestion_window_packets,
+            initial_rtt: config.initial_rtt,
+            max_pto_backoff: config.max_pto_backoff,
+            tail_loss_probe: config.tail_loss_probe,
+            pto_retransmit_policy: config.pto_retransmit_policy,
+            packet_events: config.packet_events,
+            frames_pool_size: config.packet_pool_size,
+            pmtud_max_datagram_size: if config.pmtud {
+                Some(config.max_send_udp_payload_size)
+            } else {
+                None
+            },
         }
     }
 }
@@ -208,6 +316,24 @@ impl Recovery {
 
             pto_count: 0,
 
+            pto_expirations: [0; packet::Epoch::count()],
+
+            longest_pto_backoff: 0,
+
+            max_pto_backoff: recovery_config.max_pto_backoff,
+
+            tail_loss_probe: recovery_config.tail_loss_probe,
+
+            pto_retransmit_policy: recovery_config.pto_retransmit_policy,
+
+            packet_events: if recovery_config.packet_events {
+                Some(VecDeque::new())
+            } else {
+                None
+            },
+
+            initial_rtt: recovery_config.initial_rtt,
+
             time_of_last_sent_ack_eliciting_pkt: [None; packet::Epoch::count()],
 
             largest_acked_pkt: [u64::MAX; packet::Epoch::count()],
@@ -216,7 +342,11 @@ impl Recovery {
 
             latest_rtt: Duration::ZERO,
 
-            // This field should be initialized to `INITIAL_RTT` for the initial
+            interval_min_rtt: None,
+
+            interval_max_rtt: None,
+
+            // This field should be initialized to `initial_rtt` for the initial
             // PTO calculation, but it also needs to be an `Option` to track
             // whether any RTT sample was received, so the initial value is
             // handled by the `rtt()` method instead.
@@ -226,7 +356,9 @@ impl Recovery {
 
             min_rtt: Duration::ZERO,
 
-            rttvar: INITIAL_RTT / 2,
+            first_rtt_sample: None,
+
+            rttvar: recovery_config.initial_rtt / 2,
 
             max_ack_delay: recovery_config.max_ack_delay,
 
@@ -234,6 +366,14 @@ impl Recovery {
 
             sent: [VecDeque::new(), VecDeque::new(), VecDeque::new()],
 
+            acked_ranges: [
+                ranges::RangeSet::new(MAX_ACKED_RANGES),
+                ranges::RangeSet::new(MAX_ACKED_RANGES),
+                ranges::RangeSet::new(MAX_ACKED_RANGES),
+            ],
+
+            loss_scan_start: [0; packet::Epoch::count()],
+
             lost: [Vec::new(), Vec::new(), Vec::new()],
 
             acked: [Vec::new(), Vec::new(), Vec::new()],
@@ -241,6 +381,8 @@ impl Recovery {
             lost_count: 0,
             lost_spurious_count: 0,
 
+            persistent_congestion_count: 0,
+
             loss_probes: [0; packet::Epoch::count()],
 
             in_flight_count: [0; packet::Epoch::count()],
@@ -273,6 +415,8 @@ impl Recovery {
 
             cubic_state: cubic::State::default(),
 
+            cubic_fast_convergence: recovery_config.cubic_fast_convergence,
+
             app_limited: false,
 
             hystart: hystart::Hystart::new(recovery_config.hystart),
@@ -285,6 +429,8 @@ impl Recovery {
                 recovery_config.max_pacing_rate,
             ),
 
+            pacing_mode: recovery_config.pacing_mode,
+
             prr: prr::PRR::default(),
 
             send_quantum: initial_congestion_window,
@@ -292,6 +438,9 @@ impl Recovery {
             #[cfg(feature = "qlog")]
             qlog_metrics: QlogMetrics::default(),
 
+            #[cfg(feature = "qlog")]
+            qlog_persistent_congestion: false,
+
             bbr_state: bbr::State::new(),
 
             bbr2_state: bbr2::State::new(),
@@ -300,6 +449,13 @@ impl Recovery {
 
             initial_congestion_window_packets: recovery_config
                 .initial_congestion_window_packets,
+
+            minimum_congestion_window_packets: recovery_config
+                .minimum_congestion_window_packets,
+
+            frames_pool: Vec::new(),
+
+            frames_pool_size: recovery_config.frames_pool_size,
         }
     }
 
@@ -374,19 +530,40 @@ impl Recovery {
             self.hystart.start_round(pkt_num);
         }
 
-        // Pacing: Set the pacing rate if CC doesn't do its own.
-        if !(self.cc_ops.has_custom_pacing)() {
-            if let Some(srtt) = self.smoothed_rtt {
-                let rate = PACING_MULTIPLIER * self.congestion_window as f64 /
-                    srtt.as_secs_f64();
-                self.set_pacing_rate(rate as u64, now);
-            }
+        // Pacing: Set the pacing rate if CC doesn't do its own, or if a
+        // different pacing mode is configured.
+        let rate = match self.pacing_mode {
+            PacingMode::Auto if !(self.cc_ops.has_custom_pacing)() =>
+                self.cwnd_pacing_rate(),
+
+            PacingMode::CongestionWindow => self.cwnd_pacing_rate(),
+
+            PacingMode::DeliveryRate =>
+                match self.delivery_rate.sample_delivery_rate() {
+                    0 => self.cwnd_pacing_rate(),
+
+                    v => Some((PACING_MULTIPLIER * v as f64) as u64),
+                },
+
+            PacingMode::Auto | PacingMode::Disabled => None,
+        };
+
+        if let Some(rate) = rate {
+            self.pacer.update(self.send_quantum, rate, now);
         }
 
         self.schedule_next_packet(epoch, now, sent_bytes);
 
         pkt.time_sent = self.get_packet_send_time();
 
+        if epoch == packet::Epoch::Application {
+            self.push_packet_event(TransportEvent::PacketSent {
+                pkt_num,
+                size: sent_bytes,
+                time: now,
+            });
+        }
+
         // bytes_in_flight is already updated. Use previous value.
         self.delivery_rate.on_packet_sent(
             &mut pkt,
@@ -404,14 +581,39 @@ impl Recovery {
         (self.cc_ops.on_packet_sent)(self, sent_bytes, now);
     }
 
+    /// Sets the pacing rate computed by the congestion control algorithm.
+    ///
+    /// This is ignored when a pacing mode that doesn't rely on the congestion
+    /// control algorithm is configured.
     pub fn set_pacing_rate(&mut self, rate: u64, now: Instant) {
+        if self.pacing_mode != PacingMode::Auto {
+            return;
+        }
+
         self.pacer.update(self.send_quantum, rate, now);
     }
 
+    /// Returns the pacing rate derived from the congestion window and the
+    /// smoothed RTT, if an RTT sample was taken.
+    fn cwnd_pacing_rate(&self) -> Option<u64> {
+        let srtt = self.smoothed_rtt?;
+
+        let rate = PACING_MULTIPLIER * self.congestion_window as f64 /
+            srtt.as_secs_f64();
+
+        Some(rate as u64)
+    }
+
     pub fn get_packet_send_time(&self) -> Instant {
         self.pacer.next_time()
     }
 
+    /// Returns the time at which the next packet will be released by the
+    /// pacer.
+    pub fn pacing_release_time(&self, now: Instant) -> Instant {
+        self.pacer.release_time(now)
+    }
+
     fn schedule_next_packet(
         &mut self, epoch: packet::Epoch, now: Instant, packet_size: usize,
     ) {
@@ -467,9 +669,19 @@ impl Recovery {
 
         let sent = &mut self.sent[epoch];
 
+        // Only walk the parts of the ranges that weren't acknowledged by
+        // previous ACK frames.
+        let new_ranges = unprocessed_ranges(ranges, &self.acked_ranges[epoch]);
+
+        // Size the list of newly acked packets once for the whole ACK frame,
+        // rather than growing it while walking large ranges.
+        let new_acked_count: u64 =
+            new_ranges.iter().map(|r| r.end - r.start).sum();
+        newly_acked.reserve(cmp::min(new_acked_count as usize, sent.len()));
+
         // Detect and mark acked packets, without removing them from the sent
         // packets list.
-        for r in ranges.iter() {
+        for r in new_ranges.iter() {
             let lowest_acked_in_block = r.start;
             let largest_acked_in_block = r.end - 1;
 
@@ -497,6 +709,17 @@ impl Recovery {
             for unacked in unacked_iter {
                 unacked.time_acked = Some(now);
 
+                if let Some(events) = &mut self.packet_events {
+                    if epoch == packet::Epoch::Application {
+                        push_bounded(events, TransportEvent::PacketAcked {
+                            pkt_num: unacked.pkt_num,
+                            size: unacked.size,
+                            time_sent: unacked.time_sent,
+                            time: now,
+                        });
+                    }
+                }
+
                 // Check if acked packet was already declared lost.
                 if unacked.time_lost.is_some() {
                     // Calculate new packet reordering threshold.
@@ -564,6 +787,8 @@ impl Recovery {
 
                 trace!("{} packet newly acked {}", trace_id, unacked.pkt_num);
             }
+
+            self.acked_ranges[epoch].insert(r.clone());
         }
 
         // Undo congestion window update.
@@ -613,6 +838,26 @@ impl Recovery {
         &mut self, handshake_status: HandshakeStatus, now: Instant,
         trace_id: &str,
     ) -> (usize, usize) {
+        self.on_loss_detection_timeout_with_priority(
+            handshake_status,
+            now,
+            trace_id,
+            |_| 0,
+        )
+    }
+
+    /// Handles the expiration of the loss detection timer.
+    ///
+    /// `stream_urgency` returns the urgency of a stream, and is used to pick
+    /// which stream data to retransmit first with the
+    /// [`PtoRetransmitPolicy::Prioritized`] policy.
+    pub fn on_loss_detection_timeout_with_priority<F>(
+        &mut self, handshake_status: HandshakeStatus, now: Instant,
+        trace_id: &str, stream_urgency: F,
+    ) -> (usize, usize)
+    where
+        F: Fn(u64) -> u8,
+    {
         let (earliest_loss_time, epoch) = self.loss_time_and_space();
 
         if earliest_loss_time.is_some() {
@@ -645,17 +890,30 @@ impl Recovery {
 
         self.pto_count += 1;
 
+        #[cfg(feature = "tracing")]
+        tracing::debug!(
+            pto_count = self.pto_count,
+            ?epoch,
+            bytes_in_flight = self.bytes_in_flight,
+            "probe timeout"
+        );
+
+        self.pto_expirations[epoch] += 1;
+        self.longest_pto_backoff =
+            cmp::max(self.longest_pto_backoff, self.pto_count);
+
         self.loss_probes[epoch] =
             cmp::min(self.pto_count as usize, MAX_PTO_PROBES_COUNT);
 
-        let unacked_iter = self.sent[epoch]
-            .iter_mut()
-            // Skip packets that have already been acked or lost, and packets
-            // that don't contain either CRYPTO or STREAM frames.
-            .filter(|p| p.has_data && p.time_acked.is_none() && p.time_lost.is_none())
-            // Only return as many packets as the number of probe packets that
-            // will be sent.
-            .take(self.loss_probes[epoch]);
+        // Skip packets that have already been acked or lost, and packets
+        // that don't contain either CRYPTO or STREAM frames.
+        let is_unacked_data = |p: &&Sent| {
+            p.has_data && p.time_acked.is_none() && p.time_lost.is_none()
+        };
+
+        // Only return as many packets as the number of probe packets that
+        // will be sent.
+        let probes = self.loss_probes[epoch];
 
         // Retransmit the frames from the oldest sent packets on PTO. However
         // the packets are not actually declared lost (so there is no effect to
@@ -664,8 +922,30 @@ impl Recovery {
         // This will also trigger sending an ACK and retransmitting frames like
         // HANDSHAKE_DONE and MAX_DATA / MAX_STREAM_DATA as well, in addition
         // to CRYPTO and STREAM, if the original packet carried them.
-        for unacked in unacked_iter {
-            self.lost[epoch].extend_from_slice(&unacked.frames);
+        //
+        // With tail loss probes, the most recently sent packets are
+        // retransmitted instead, as they are the ones most likely to have been
+        // lost at the tail of a flight (RFC 8985 Section 7.3).
+        //
+        // With the prioritized policy, frames are picked from all the
+        // unacknowledged packets by their importance instead.
+        if self.pto_retransmit_policy == PtoRetransmitPolicy::Prioritized {
+            self.schedule_prioritized_probe_frames(epoch, probes, stream_urgency);
+        } else if self.tail_loss_probe && epoch == packet::Epoch::Application {
+            for unacked in self.sent[epoch]
+                .iter()
+                .rev()
+                .filter(is_unacked_data)
+                .take(probes)
+            {
+                self.lost[epoch].extend_from_slice(&unacked.frames);
+            }
+        } else {
+            for unacked in
+                self.sent[epoch].iter().filter(is_unacked_data).take(probes)
+            {
+                self.lost[epoch].extend_from_slice(&unacked.frames);
+            }
         }
 
         self.set_loss_detection_timer(handshake_status, now);
@@ -675,6 +955,66 @@ impl Recovery {
         (0, 0)
     }
 
+    /// Reschedules the frames of all unacknowledged packets of `epoch` in
+    /// priority order, until the data of `probes` packets is queued.
+    ///
+    /// Control frames are always rescheduled, followed by CRYPTO data, and
+    /// then stream data in increasing order of urgency.
+    fn schedule_prioritized_probe_frames<F>(
+        &mut self, epoch: packet::Epoch, probes: usize, stream_urgency: F,
+    ) where
+        F: Fn(u64) -> u8,
+    {
+        let mut crypto = Vec::new();
+        let mut streams = Vec::new();
+
+        for unacked in self.sent[epoch].iter().filter(|p| {
+            p.ack_eliciting && p.time_acked.is_none() && p.time_lost.is_none()
+        }) {
+            for frame in &unacked.frames {
+                match frame {
+                    frame::Frame::CryptoHeader { length, .. } =>
+                        crypto.push((*length, frame.clone())),
+
+                    frame::Frame::StreamHeader {
+                        stream_id, length, ..
+                    } => streams.push((
+                        stream_urgency(*stream_id),
+                        *length,
+                        frame.clone(),
+                    )),
+
+                    // Probes don't carry padding, and rescheduling PING
+                    // frames would declare PMTU probes lost.
+                    frame::Frame::Padding { .. } | frame::Frame::Ping { .. } =>
+                        (),
+
+                    _ => self.lost[epoch].push(frame.clone()),
+                }
+            }
+        }
+
+        // The sort is stable, so data of the same urgency is still rescheduled
+        // from the oldest packets first.
+        streams.sort_by_key(|(urgency, ..)| *urgency);
+
+        let mut budget = probes * self.max_datagram_size;
+
+        let data = crypto
+            .into_iter()
+            .chain(streams.into_iter().map(|(_, len, frame)| (len, frame)));
+
+        for (len, frame) in data {
+            if budget == 0 {
+                break;
+            }
+
+            budget = budget.saturating_sub(len);
+
+            self.lost[epoch].push(frame);
+        }
+    }
+
     pub fn on_pkt_num_space_discarded(
         &mut self, epoch: packet::Epoch, handshake_status: HandshakeStatus,
         now: Instant,
@@ -692,6 +1032,9 @@ impl Recovery {
         self.lost[epoch].clear();
         self.acked[epoch].clear();
 
+        self.acked_ranges[epoch] = ranges::RangeSet::new(MAX_ACKED_RANGES);
+        self.loss_scan_start[epoch] = 0;
+
         self.time_of_last_sent_ack_eliciting_pkt[epoch] = None;
         self.loss_time[epoch] = None;
         self.loss_probes[epoch] = 0;
@@ -706,6 +1049,54 @@ impl Recovery {
         self.detect_lost_packets(epoch, now, trace_id)
     }
 
+    /// Drops all the 0-RTT packets in flight after the peer rejected early
+    /// data, so their frames are retransmitted in 1-RTT packets.
+    ///
+    /// The peer never processed these packets, so they are not treated as a
+    /// congestion signal. Returns the number of rejected packets and bytes,
+    /// which callers shouldn't count as lost.
+    pub fn on_early_data_rejected(
+        &mut self, now: Instant, trace_id: &str,
+    ) -> (usize, usize) {
+        let epoch = packet::Epoch::Application;
+
+        let mut lost_packets = 0;
+        let mut lost_bytes = 0;
+
+        for unacked in self.sent[epoch].iter_mut() {
+            if unacked.time_acked.is_some() || unacked.time_lost.is_some() {
+                continue;
+            }
+
+            self.lost[epoch].extend(unacked.frames.drain(..));
+
+            unacked.time_lost = Some(now);
+
+            if unacked.in_flight {
+                lost_bytes += unacked.size;
+
+                self.bytes_in_flight =
+                    self.bytes_in_flight.saturating_sub(unacked.size);
+
+                self.in_flight_count[epoch] =
+                    self.in_flight_count[epoch].saturating_sub(1);
+            }
+
+            trace!(
+                "{} 0-RTT packet {} rejected by peer",
+                trace_id,
+                unacked.pkt_num
+            );
+
+            lost_packets += 1;
+        }
+
+        self.loss_time[epoch] = None;
+        self.loss_probes[epoch] = 0;
+
+        (lost_packets, lost_bytes)
+    }
+
     pub fn loss_detection_timer(&self) -> Option<Instant> {
         self.loss_detection_timer
     }
@@ -714,6 +1105,10 @@ impl Recovery {
         self.congestion_window
     }
 
+    pub fn bytes_in_flight(&self) -> usize {
+        self.bytes_in_flight
+    }
+
     pub fn cwnd_available(&self) -> usize {
         // Ignore cwnd when sending probe packets.
         if self.loss_probes.iter().any(|&x| x > 0) {
@@ -726,7 +1121,7 @@ impl Recovery {
     }
 
     pub fn rtt(&self) -> Duration {
-        self.smoothed_rtt.unwrap_or(INITIAL_RTT)
+        self.smoothed_rtt.unwrap_or(self.initial_rtt)
     }
 
     pub fn min_rtt(&self) -> Option<Duration> {
@@ -745,6 +1140,21 @@ impl Recovery {
         self.rtt() + cmp::max(self.rttvar * 4, GRANULARITY)
     }
 
+    pub fn pto_count(&self) -> u32 {
+        self.pto_count
+    }
+
+    // Returns the multiplier applied to the PTO for exponential backoff.
+    fn pto_backoff(&self) -> u32 {
+        let exp = match self.max_pto_backoff {
+            Some(max) => cmp::min(self.pto_count, max),
+
+            None => self.pto_count,
+        };
+
+        2_u32.pow(exp)
+    }
+
     pub fn delivery_rate(&self) -> u64 {
         self.delivery_rate.sample_delivery_rate()
     }
@@ -776,16 +1186,62 @@ impl Recovery {
         self.max_datagram_size = max_datagram_size;
     }
 
+    /// Raises the maximum datagram size after a larger PMTU was discovered.
+    pub fn pmtud_update_max_datagram_size(&mut self, max_datagram_size: usize) {
+        self.pacer = pacer::Pacer::new(
+            self.pacer.enabled(),
+            self.congestion_window,
+            0,
+            max_datagram_size,
+            self.pacer.max_pacing_rate(),
+        );
+
+        self.max_datagram_size = max_datagram_size;
+    }
+
+    fn push_packet_event(&mut self, ev: TransportEvent) {
+        if let Some(events) = &mut self.packet_events {
+            push_bounded(events, ev);
+        }
+    }
+
+    /// Returns the oldest queued packet event, without removing it.
+    pub fn peek_packet_event(&self) -> Option<&TransportEvent> {
+        self.packet_events.as_ref()?.front()
+    }
+
+    /// Removes and returns the oldest queued packet event.
+    pub fn pop_packet_event(&mut self) -> Option<TransportEvent> {
+        self.packet_events.as_mut()?.pop_front()
+    }
+
+    /// Forgets the RTT samples collected for interval statistics.
+    pub fn reset_interval_rtt(&mut self) {
+        self.interval_min_rtt = None;
+        self.interval_max_rtt = None;
+    }
+
     fn update_rtt(
         &mut self, latest_rtt: Duration, ack_delay: Duration, now: Instant,
     ) {
         self.latest_rtt = latest_rtt;
 
+        self.interval_min_rtt = Some(
+            self.interval_min_rtt
+                .map_or(latest_rtt, |v| cmp::min(v, latest_rtt)),
+        );
+        self.interval_max_rtt = Some(
+            self.interval_max_rtt
+                .map_or(latest_rtt, |v| cmp::max(v, latest_rtt)),
+        );
+
         match self.smoothed_rtt {
             // First RTT sample.
             None => {
                 self.min_rtt = self.minmax_filter.reset(now, latest_rtt);
 
+                self.first_rtt_sample = Some(now);
+
                 self.smoothed_rtt = Some(latest_rtt);
 
                 self.rttvar = latest_rtt / 2;
@@ -836,7 +1292,7 @@ impl Recovery {
     fn pto_time_and_space(
         &self, handshake_status: HandshakeStatus, now: Instant,
     ) -> (Option<Instant>, packet::Epoch) {
-        let mut duration = self.pto() * 2_u32.pow(self.pto_count);
+        let mut duration = self.pto() * self.pto_backoff();
 
         // Arm PTO from now when there are no inflight packets.
         if self.bytes_in_flight == 0 {
@@ -865,7 +1321,7 @@ impl Recovery {
                 }
 
                 // Include max_ack_delay and backoff for Application Data.
-                duration += self.max_ack_delay * 2_u32.pow(self.pto_count);
+                duration += self.max_ack_delay * self.pto_backoff();
             }
 
             let new_time =
@@ -922,22 +1378,87 @@ impl Recovery {
 
         let mut largest_lost_pkt = None;
 
+        // Persistent congestion is established when the lost ack-eliciting
+        // packets span more than the congestion period, without any packet in
+        // between being acknowledged (RFC 9002 Section 7.6.2).
+        let congestion_period = self.persistent_congestion_period();
+
+        let mut pc_start_time: Option<Instant> = None;
+        let mut persistent_congestion = false;
+
+        // Packets before the start of the scan were already acked or declared
+        // lost, so they can't be lost now, and acked ones would anyway reset
+        // the persistent congestion period.
+        let scan_start = self.sent[epoch]
+            .binary_search_by_key(&self.loss_scan_start[epoch], |p| p.pkt_num)
+            .unwrap_or_else(|i| i);
+
+        let mut next_scan_start = self.loss_scan_start[epoch];
+
         let unacked_iter = self.sent[epoch]
-            .iter_mut()
+            .range_mut(scan_start..)
             // Skip packets that follow the largest acked packet.
-            .take_while(|p| p.pkt_num <= largest_acked)
-            // Skip packets that have already been acked or lost.
-            .filter(|p| p.time_acked.is_none() && p.time_lost.is_none());
+            .take_while(|p| p.pkt_num <= largest_acked);
 
         for unacked in unacked_iter {
+            next_scan_start = unacked.pkt_num + 1;
+
+            // Acknowledged packets interrupt a persistent congestion period.
+            if unacked.time_acked.is_some() {
+                pc_start_time = None;
+                continue;
+            }
+
+            // Skip packets that have already been declared lost.
+            if unacked.time_lost.is_some() {
+                continue;
+            }
+
             // Mark packet as lost, or set time when it should be marked.
             if unacked.time_sent <= lost_send_time ||
                 largest_acked >= unacked.pkt_num + self.pkt_thresh
             {
+                let pmtu_probe = unacked.frames.iter().any(|f| {
+                    matches!(f, frame::Frame::Ping { mtu_probe: Some(_) })
+                });
+
                 self.lost[epoch].extend(unacked.frames.drain(..));
 
                 unacked.time_lost = Some(now);
 
+                if let Some(events) = &mut self.packet_events {
+                    if epoch == packet::Epoch::Application {
+                        push_bounded(events, TransportEvent::PacketLost {
+                            pkt_num: unacked.pkt_num,
+                            size: unacked.size,
+                            time_sent: unacked.time_sent,
+                            time: now,
+                        });
+                    }
+                }
+
+                // Lost PMTU probes are not a congestion signal (RFC 8899
+                // Section 4.4), so they are only removed from the bytes in
+                // flight.
+                if pmtu_probe {
+                    if unacked.in_flight {
+                        self.bytes_in_flight =
+                            self.bytes_in_flight.saturating_sub(unacked.size);
+
+                        self.in_flight_count[epoch] =
+                            self.in_flight_count[epoch].saturating_sub(1);
+                    }
+
+                    trace!(
+                        "{} pmtu probe {} lost on epoch {}",
+                        trace_id,
+                        unacked.pkt_num,
+                        epoch
+                    );
+
+                    continue;
+                }
+
                 if unacked.in_flight {
                     lost_bytes += unacked.size;
 
@@ -956,6 +1477,23 @@ impl Recovery {
                     );
                 }
 
+                // Only packets sent after the first RTT sample are considered
+                // for persistent congestion.
+                let after_rtt_sample = self
+                    .first_rtt_sample
+                    .map(|t| unacked.time_sent > t)
+                    .unwrap_or(false);
+
+                if unacked.ack_eliciting && after_rtt_sample {
+                    let start = *pc_start_time.get_or_insert(unacked.time_sent);
+
+                    if unacked.time_sent.saturating_duration_since(start) >
+                        congestion_period
+                    {
+                        persistent_congestion = true;
+                    }
+                }
+
                 lost_packets += 1;
                 self.lost_count += 1;
             } else {
@@ -967,14 +1505,24 @@ impl Recovery {
                 };
 
                 self.loss_time[epoch] = Some(loss_time);
+
+                next_scan_start = unacked.pkt_num;
                 break;
             }
         }
 
+        self.loss_scan_start[epoch] = next_scan_start;
+
         self.bytes_lost += lost_bytes as u64;
 
         if let Some(pkt) = largest_lost_pkt {
-            self.on_packets_lost(lost_bytes, &pkt, epoch, now);
+            self.on_packets_lost(
+                lost_bytes,
+                &pkt,
+                persistent_congestion,
+                epoch,
+                now,
+            );
         }
 
         self.drain_packets(epoch, now);
@@ -1009,8 +1557,31 @@ impl Recovery {
             }
         }
 
-        // Then remove elements up to the previously found index.
-        self.sent[epoch].drain(..lowest_non_expired_pkt_index);
+        // Then remove elements up to the previously found index, recycling
+        // the frame lists that spilled on the heap.
+        for pkt in self.sent[epoch].drain(..lowest_non_expired_pkt_index) {
+            if pkt.frames.spilled() &&
+                self.frames_pool.len() < self.frames_pool_size
+            {
+                let mut frames = pkt.frames;
+                frames.clear();
+
+                self.frames_pool.push(frames);
+            }
+        }
+
+        // Acknowledgements for removed packets don't need to be tracked.
+        if let Some(pkt_num) = self.sent[epoch].front().map(|p| p.pkt_num) {
+            if pkt_num > 0 {
+                self.acked_ranges[epoch].remove_until(pkt_num - 1);
+            }
+        }
+    }
+
+    /// Returns an empty frame list for a new packet, reusing the allocation
+    /// of a previously sent packet if possible.
+    pub fn frames_buf(&mut self) -> SmallVec<[frame::Frame; 1]> {
+        self.frames_pool.pop().unwrap_or_default()
     }
 
     fn on_packets_acked(
@@ -1037,33 +1608,52 @@ impl Recovery {
         }
     }
 
-    fn in_persistent_congestion(&mut self, _largest_lost_pkt_num: u64) -> bool {
-        let _congestion_period = self.pto() * PERSISTENT_CONGESTION_THRESHOLD;
-
-        // TODO: properly detect persistent congestion
-        false
+    /// Returns the duration that lost ack-eliciting packets need to span for
+    /// persistent congestion to be established.
+    pub fn persistent_congestion_period(&self) -> Duration {
+        (self.pto() + self.max_ack_delay) * PERSISTENT_CONGESTION_THRESHOLD
     }
 
     fn on_packets_lost(
         &mut self, lost_bytes: usize, largest_lost_pkt: &Sent,
-        epoch: packet::Epoch, now: Instant,
+        persistent_congestion: bool, epoch: packet::Epoch, now: Instant,
     ) {
         self.bytes_in_flight = self.bytes_in_flight.saturating_sub(lost_bytes);
 
         self.congestion_event(lost_bytes, largest_lost_pkt, epoch, now);
 
-        if self.in_persistent_congestion(largest_lost_pkt.pkt_num) {
-            self.collapse_cwnd();
+        if persistent_congestion {
+            self.on_persistent_congestion();
         }
     }
 
+    fn on_persistent_congestion(&mut self) {
+        self.persistent_congestion_count += 1;
+
+        #[cfg(feature = "qlog")]
+        {
+            self.qlog_persistent_congestion = true;
+        }
+
+        self.collapse_cwnd();
+
+        #[cfg(feature = "tracing")]
+        tracing::debug!(
+            congestion_window = self.congestion_window,
+            count = self.persistent_congestion_count,
+            "persistent congestion"
+        );
+    }
+
     fn congestion_event(
         &mut self, lost_bytes: usize, largest_lost_pkt: &Sent,
         epoch: packet::Epoch, now: Instant,
     ) {
         let time_sent = largest_lost_pkt.time_sent;
 
-        if !self.in_congestion_recovery(time_sent) {
+        let in_recovery = self.in_congestion_recovery(time_sent);
+
+        if !in_recovery {
             (self.cc_ops.checkpoint)(self);
         }
 
@@ -1074,6 +1664,17 @@ impl Recovery {
             epoch,
             now,
         );
+
+        #[cfg(feature = "tracing")]
+        if !in_recovery {
+            tracing::debug!(
+                lost_bytes,
+                congestion_window = self.congestion_window,
+                ssthresh = self.ssthresh,
+                bytes_in_flight = self.bytes_in_flight,
+                "congestion event"
+            );
+        }
     }
 
     fn collapse_cwnd(&mut self) {
@@ -1094,6 +1695,16 @@ impl Recovery {
 
     #[cfg(feature = "qlog")]
     pub fn maybe_qlog(&mut self) -> Option<EventData> {
+        // Only CUBIC keeps track of W_max and K.
+        let (cubic_w_max, cubic_k) = if std::ptr::eq(self.cc_ops, &cubic::CUBIC) {
+            (
+                Some(self.cubic_state.w_max() as u64),
+                Some(Duration::from_secs_f64(self.cubic_state.k().max(0.0))),
+            )
+        } else {
+            (None, None)
+        };
+
         let qlog_metrics = QlogMetrics {
             min_rtt: self.min_rtt,
             smoothed_rtt: self.rtt(),
@@ -1103,23 +1714,209 @@ impl Recovery {
             bytes_in_flight: self.bytes_in_flight as u64,
             ssthresh: self.ssthresh as u64,
             pacing_rate: self.pacer.rate(),
+            cubic_w_max,
+            cubic_k,
         };
 
         self.qlog_metrics.maybe_update(qlog_metrics)
     }
 
+    #[cfg(feature = "qlog")]
+    pub fn maybe_qlog_persistent_congestion(&mut self) -> Option<EventData> {
+        if !self.qlog_persistent_congestion {
+            return None;
+        }
+
+        self.qlog_persistent_congestion = false;
+
+        Some(EventData::CongestionStateUpdated(CongestionStateUpdated {
+            old: None,
+            new: "persistent_congestion".to_string(),
+            trigger: Some(CongestionStateUpdatedTrigger::PersistentCongestion),
+        }))
+    }
+
     pub fn send_quantum(&self) -> usize {
         self.send_quantum
     }
+
+    /// Returns how many bytes can be sent in the next burst, based on both
+    /// the send quantum and the available congestion window.
+    ///
+    /// This is never smaller than a single datagram, so that packets that are
+    /// not subject to congestion control (e.g. ACKs) can still be sent.
+    pub fn send_budget(&self) -> usize {
+        let budget = cmp::min(self.send_quantum, self.cwnd_available());
+
+        cmp::max(budget, self.max_datagram_size)
+    }
 }
 
-/// Available congestion control algorithms.
+/// A packet-level transport event.
 ///
-/// This enum provides currently available list of congestion control
-/// algorithms.
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
-#[repr(C)]
-pub enum CongestionControlAlgorithm {
+/// Events are only generated for packets of the application data packet
+/// number space, whose packet numbers are unique across all paths.
+///
+/// `size` is the number of bytes the packet counted towards the bytes in
+/// flight, which is zero for packets that are not ack-eliciting.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransportEvent {
+    /// A packet was sent.
+    PacketSent {
+        /// The packet number.
+        pkt_num: u64,
+
+        /// The size of the packet in bytes.
+        size: usize,
+
+        /// The time the packet was sent.
+        time: Instant,
+    },
+
+    /// A packet was acknowledged by the peer.
+    PacketAcked {
+        /// The packet number.
+        pkt_num: u64,
+
+        /// The size of the packet in bytes.
+        size: usize,
+
+        /// The time the packet was sent.
+        time_sent: Instant,
+
+        /// The time the acknowledgement was processed.
+        time: Instant,
+    },
+
+    /// A packet was declared lost.
+    PacketLost {
+        /// The packet number.
+        pkt_num: u64,
+
+        /// The size of the packet in bytes.
+        size: usize,
+
+        /// The time the packet was sent.
+        time_sent: Instant,
+
+        /// The time the packet was declared lost.
+        time: Instant,
+    },
+}
+
+impl TransportEvent {
+    /// Returns the time at which the event occurred.
+    pub fn time(&self) -> Instant {
+        match self {
+            TransportEvent::PacketSent { time, .. } |
+            TransportEvent::PacketAcked { time, .. } |
+            TransportEvent::PacketLost { time, .. } => *time,
+        }
+    }
+}
+
+/// Returns the parts of the `ranges` that are not covered by `processed`.
+fn unprocessed_ranges(
+    ranges: &ranges::RangeSet, processed: &ranges::RangeSet,
+) -> SmallVec<[std::ops::Range<u64>; 4]> {
+    let mut out = SmallVec::new();
+
+    let mut processed = processed.iter().peekable();
+
+    for r in ranges.iter() {
+        let mut start = r.start;
+
+        while let Some(p) = processed.peek() {
+            if p.end <= start {
+                processed.next();
+                continue;
+            }
+
+            if p.start >= r.end {
+                break;
+            }
+
+            if p.start > start {
+                out.push(start..p.start);
+            }
+
+            start = cmp::max(start, p.end);
+
+            // The processed range might also overlap the next range.
+            if p.end >= r.end {
+                break;
+            }
+
+            processed.next();
+        }
+
+        if start < r.end {
+            out.push(start..r.end);
+        }
+    }
+
+    out
+}
+
+fn push_bounded(events: &mut VecDeque<TransportEvent>, ev: TransportEvent) {
+    if events.len() >= MAX_PACKET_EVENTS {
+        events.pop_front();
+    }
+
+    events.push_back(ev);
+}
+
+/// How the pacing rate is computed.
+///
+/// This enum is set with [`set_pacing_mode()`].
+///
+/// [`set_pacing_mode()`]: struct.Config.html#method.set_pacing_mode
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(C)]
+pub enum PacingMode {
+    /// Packets are not paced.
+    Disabled         = 0,
+
+    /// The rate computed by the congestion control algorithm is used if it
+    /// provides one, as BBR does, or the congestion window divided by the
+    /// smoothed RTT otherwise (default).
+    Auto             = 1,
+
+    /// The congestion window divided by the smoothed RTT is used, whatever
+    /// the congestion control algorithm.
+    CongestionWindow = 2,
+
+    /// The latest delivery rate sample is used, whatever the congestion
+    /// control algorithm. Until a sample is taken, the congestion window
+    /// divided by the smoothed RTT is used instead.
+    DeliveryRate     = 3,
+}
+
+/// Which frames are retransmitted by the probes sent when a PTO fires.
+///
+/// This enum is set with [`set_pto_retransmit_policy()`].
+///
+/// [`set_pto_retransmit_policy()`]: struct.Config.html#method.set_pto_retransmit_policy
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(C)]
+pub enum PtoRetransmitPolicy {
+    /// The data of the oldest unacknowledged packets is retransmitted, or of
+    /// the most recent ones with tail loss probes (default).
+    Oldest      = 0,
+
+    /// Control frames and CRYPTO data of all unacknowledged packets are
+    /// retransmitted first, followed by the stream data with the lowest
+    /// urgency value, regardless of which packets it was sent in.
+    Prioritized = 1,
+}
+
+/// Available congestion control algorithms.
+///
+/// This enum provides currently available list of congestion control
+/// algorithms.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(C)]
+pub enum CongestionControlAlgorithm {
     /// Reno congestion control algorithm. `reno` in a string form.
     Reno  = 0,
     /// CUBIC congestion control algorithm (default). `cubic` in a string form.
@@ -1359,6 +2156,8 @@ struct QlogMetrics {
     bytes_in_flight: u64,
     ssthresh: u64,
     pacing_rate: u64,
+    cubic_w_max: Option<u64>,
+    cubic_k: Option<Duration>,
 }
 
 #[cfg(feature = "qlog")]
@@ -1436,6 +2235,22 @@ impl QlogMetrics {
             None
         };
 
+        let new_cubic_w_max = if self.cubic_w_max != latest.cubic_w_max {
+            self.cubic_w_max = latest.cubic_w_max;
+            emit_event = true;
+            latest.cubic_w_max
+        } else {
+            None
+        };
+
+        let new_cubic_k = if self.cubic_k != latest.cubic_k {
+            self.cubic_k = latest.cubic_k;
+            emit_event = true;
+            latest.cubic_k.map(|k| k.as_secs_f32() * 1000.0)
+        } else {
+            None
+        };
+
         if emit_event {
             // QVis can't use all these fields and they can be large.
             return Some(EventData::MetricsUpdated(
@@ -1450,6 +2265,8 @@ impl QlogMetrics {
                     ssthresh: new_ssthresh,
                     packets_in_flight: None,
                     pacing_rate: new_pacing_rate,
+                    cubic_w_max: new_cubic_w_max,
+                    cubic_k: new_cubic_k,
                 },
             ));
         }
@@ -1477,6 +2294,39 @@ mod tests {
         );
     }
 
+    #[test]
+    #[cfg(feature = "qlog")]
+    fn qlog_cubic_metrics() {
+        let mut cfg = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+
+        cfg.set_cc_algorithm(CongestionControlAlgorithm::CUBIC);
+
+        let mut r = Recovery::new(&cfg);
+
+        match r.maybe_qlog() {
+            Some(EventData::MetricsUpdated(m)) => {
+                assert_eq!(m.cubic_w_max, Some(0));
+                assert_eq!(m.cubic_k, Some(0.0));
+            },
+
+            _ => panic!("no metrics"),
+        }
+
+        // Other congestion controllers don't report the CUBIC state.
+        cfg.set_cc_algorithm(CongestionControlAlgorithm::Reno);
+
+        let mut r = Recovery::new(&cfg);
+
+        match r.maybe_qlog() {
+            Some(EventData::MetricsUpdated(m)) => {
+                assert_eq!(m.cubic_w_max, None);
+                assert_eq!(m.cubic_k, None);
+            },
+
+            _ => panic!("no metrics"),
+        }
+    }
+
     #[test]
     fn collapse_cwnd() {
         let mut cfg = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
@@ -1486,7 +2336,214 @@ mod tests {
 
         // cwnd will be reset.
         r.collapse_cwnd();
-        assert_eq!(r.cwnd(), r.max_datagram_size * MINIMUM_WINDOW_PACKETS);
+        assert_eq!(
+            r.cwnd(),
+            r.max_datagram_size * r.minimum_congestion_window_packets
+        );
+    }
+
+    #[test]
+    fn configured_congestion_windows() {
+        let mut cfg = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+        cfg.set_cc_algorithm(CongestionControlAlgorithm::Reno);
+        cfg.set_initial_congestion_window_packets(26);
+        assert_eq!(
+            cfg.set_minimum_congestion_window_packets(0),
+            Err(crate::Error::CongestionControl)
+        );
+        assert_eq!(cfg.set_minimum_congestion_window_packets(4), Ok(()));
+
+        let mut r = Recovery::new(&cfg);
+        assert_eq!(r.cwnd(), r.max_datagram_size * 26);
+
+        r.collapse_cwnd();
+        assert_eq!(r.cwnd(), r.max_datagram_size * 4);
+
+        // The initial window is never smaller than the minimum one.
+        cfg.set_initial_congestion_window_packets(1);
+
+        let r = Recovery::new(&cfg);
+        assert_eq!(r.cwnd(), r.max_datagram_size * 4);
+    }
+
+    #[test]
+    fn unprocessed_ranges() {
+        let mut ranges = ranges::RangeSet::default();
+        ranges.insert(0..10);
+        ranges.insert(20..30);
+
+        let mut processed = ranges::RangeSet::default();
+        assert_eq!(
+            super::unprocessed_ranges(&ranges, &processed).as_slice(),
+            &[0..10, 20..30]
+        );
+
+        processed.insert(2..5);
+        processed.insert(8..22);
+        processed.insert(25..26);
+        assert_eq!(
+            super::unprocessed_ranges(&ranges, &processed).as_slice(),
+            &[0..2, 5..8, 22..25, 26..30]
+        );
+
+        processed.insert(0..30);
+        assert!(super::unprocessed_ranges(&ranges, &processed).is_empty());
+    }
+
+    #[test]
+    fn repeated_ack_ranges() {
+        let cfg = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+
+        let mut r = Recovery::new(&cfg);
+
+        let mut now = Instant::now();
+
+        for pkt_num in 0..10 {
+            let p = Sent {
+                pkt_num,
+                frames: smallvec![],
+                time_sent: now,
+                time_acked: None,
+                time_lost: None,
+                size: 1000,
+                ack_eliciting: true,
+                in_flight: true,
+                delivered: 0,
+                delivered_time: now,
+                first_sent_time: now,
+                is_app_limited: false,
+                tx_in_flight: 0,
+                lost: 0,
+                has_data: false,
+            };
+
+            r.on_packet_sent(
+                p,
+                packet::Epoch::Application,
+                HandshakeStatus::default(),
+                now,
+                "",
+            );
+        }
+
+        now += Duration::from_millis(10);
+
+        let mut acked = ranges::RangeSet::default();
+        acked.insert(2..10);
+
+        let mut newly_acked = Vec::new();
+
+        // Packets 0 and 1 are lost due to the packet reordering threshold.
+        assert_eq!(
+            r.on_ack_received(
+                &acked,
+                25,
+                packet::Epoch::Application,
+                HandshakeStatus::default(),
+                now,
+                "",
+                &mut newly_acked,
+            ),
+            Ok((2, 2000))
+        );
+        assert_eq!(newly_acked.len(), 8);
+        assert_eq!(r.acked_ranges[packet::Epoch::Application], 2..10);
+        assert_eq!(r.loss_scan_start[packet::Epoch::Application], 10);
+
+        // Lost packets are kept around for an RTT, so the acked ones can't be
+        // removed yet.
+        assert_eq!(r.sent[packet::Epoch::Application].len(), 10);
+
+        now += Duration::from_millis(1);
+
+        // The same ranges are acknowledged again, nothing new is acked.
+        newly_acked.clear();
+        assert_eq!(
+            r.on_ack_received(
+                &acked,
+                25,
+                packet::Epoch::Application,
+                HandshakeStatus::default(),
+                now,
+                "",
+                &mut newly_acked,
+            ),
+            Ok((0, 0))
+        );
+        assert!(newly_acked.is_empty());
+    }
+
+    #[test]
+    fn frames_pool() {
+        let mut cfg = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+        cfg.set_packet_pool_size(1);
+
+        let mut r = Recovery::new(&cfg);
+
+        let mut now = Instant::now();
+
+        for pkt_num in 0..2 {
+            let mut frames = r.frames_buf();
+            assert!(!frames.spilled());
+
+            frames.push(frame::Frame::Ping { mtu_probe: None });
+            frames.push(frame::Frame::Padding { len: 10 });
+
+            let p = Sent {
+                pkt_num,
+                frames,
+                time_sent: now,
+                time_acked: None,
+                time_lost: None,
+                size: 1000,
+                ack_eliciting: true,
+                in_flight: true,
+                delivered: 0,
+                delivered_time: now,
+                first_sent_time: now,
+                is_app_limited: false,
+                tx_in_flight: 0,
+                lost: 0,
+                has_data: false,
+            };
+
+            r.on_packet_sent(
+                p,
+                packet::Epoch::Application,
+                HandshakeStatus::default(),
+                now,
+                "",
+            );
+        }
+
+        now += Duration::from_millis(10);
+
+        let mut acked = ranges::RangeSet::default();
+        acked.insert(0..2);
+
+        assert_eq!(
+            r.on_ack_received(
+                &acked,
+                25,
+                packet::Epoch::Application,
+                HandshakeStatus::default(),
+                now,
+                "",
+                &mut Vec::new(),
+            ),
+            Ok((0, 0))
+        );
+
+        assert_eq!(r.sent[packet::Epoch::Application].len(), 0);
+
+        // Only one frame list is kept, as configured.
+        assert_eq!(r.frames_pool.len(), 1);
+
+        let frames = r.frames_buf();
+        assert!(frames.spilled());
+        assert!(frames.is_empty());
+
+        assert!(!r.frames_buf().spilled());
     }
 
     #[test]
@@ -1736,6 +2793,233 @@ mod tests {
         assert_eq!(r.sent[packet::Epoch::Application].len(), 0);
     }
 
+    #[test]
+    fn pto_backoff_limit() {
+        let mut cfg = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+        cfg.set_cc_algorithm(CongestionControlAlgorithm::Reno);
+        cfg.set_initial_rtt(100);
+        cfg.set_max_pto_backoff(1);
+
+        let mut r = Recovery::new(&cfg);
+
+        let start = Instant::now();
+
+        // The initial PTO is 3 times the initial RTT.
+        assert_eq!(r.pto(), Duration::from_millis(300));
+
+        let p = Sent {
+            pkt_num: 0,
+            frames: smallvec![],
+            time_sent: start,
+            time_acked: None,
+            time_lost: None,
+            size: 1000,
+            ack_eliciting: true,
+            in_flight: true,
+            delivered: 0,
+            delivered_time: start,
+            first_sent_time: start,
+            is_app_limited: false,
+            tx_in_flight: 0,
+            lost: 0,
+            has_data: false,
+        };
+
+        r.on_packet_sent(
+            p,
+            packet::Epoch::Application,
+            HandshakeStatus::default(),
+            start,
+            "",
+        );
+
+        assert_eq!(
+            r.loss_detection_timer(),
+            Some(start + Duration::from_millis(300))
+        );
+
+        // 1st PTO doubles the timeout.
+        let now = r.loss_detection_timer().unwrap();
+        r.on_loss_detection_timeout(HandshakeStatus::default(), now, "");
+        assert_eq!(r.pto_count(), 1);
+        assert_eq!(
+            r.loss_detection_timer(),
+            Some(start + Duration::from_millis(600))
+        );
+
+        // 2nd PTO doesn't back off any further.
+        let now = r.loss_detection_timer().unwrap();
+        r.on_loss_detection_timeout(HandshakeStatus::default(), now, "");
+        assert_eq!(r.pto_count(), 2);
+        assert_eq!(
+            r.loss_detection_timer(),
+            Some(start + Duration::from_millis(600))
+        );
+
+        assert_eq!(r.pto_expirations[packet::Epoch::Initial], 0);
+        assert_eq!(r.pto_expirations[packet::Epoch::Handshake], 0);
+        assert_eq!(r.pto_expirations[packet::Epoch::Application], 2);
+        assert_eq!(r.longest_pto_backoff, 2);
+
+        // Receiving an ACK resets the PTO count but not the statistics.
+        let mut acked = ranges::RangeSet::default();
+        acked.insert(0..1);
+
+        assert_eq!(
+            r.on_ack_received(
+                &acked,
+                25,
+                packet::Epoch::Application,
+                HandshakeStatus::default(),
+                now,
+                "",
+                &mut Vec::new(),
+            ),
+            Ok((0, 0))
+        );
+
+        assert_eq!(r.pto_count(), 0);
+        assert_eq!(r.longest_pto_backoff, 2);
+    }
+
+    #[test]
+    fn tail_loss_probe() {
+        let mut cfg = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+        cfg.set_cc_algorithm(CongestionControlAlgorithm::Reno);
+        cfg.enable_tail_loss_probe(true);
+
+        let mut r = Recovery::new(&cfg);
+
+        let now = Instant::now();
+
+        for pn in 0..3 {
+            let p = Sent {
+                pkt_num: pn,
+                frames: smallvec![frame::Frame::StreamHeader {
+                    stream_id: 0,
+                    offset: pn * 1000,
+                    length: 1000,
+                    fin: false,
+                }],
+                time_sent: now,
+                time_acked: None,
+                time_lost: None,
+                size: 1000,
+                ack_eliciting: true,
+                in_flight: true,
+                delivered: 0,
+                delivered_time: now,
+                first_sent_time: now,
+                is_app_limited: false,
+                tx_in_flight: 0,
+                lost: 0,
+                has_data: true,
+            };
+
+            r.on_packet_sent(
+                p,
+                packet::Epoch::Application,
+                HandshakeStatus::default(),
+                now,
+                "",
+            );
+        }
+
+        // The first PTO sends a single probe, carrying the data of the most
+        // recently sent packet.
+        let now = r.loss_detection_timer().unwrap();
+        assert_eq!(
+            r.on_loss_detection_timeout(HandshakeStatus::default(), now, ""),
+            (0, 0)
+        );
+
+        assert_eq!(r.loss_probes[packet::Epoch::Application], 1);
+        assert_eq!(r.lost[packet::Epoch::Application], vec![
+            frame::Frame::StreamHeader {
+                stream_id: 0,
+                offset: 2000,
+                length: 1000,
+                fin: false,
+            }
+        ]);
+
+        // Nothing was declared lost.
+        assert_eq!(r.bytes_in_flight, 3000);
+        assert_eq!(r.lost_count, 0);
+    }
+
+    #[test]
+    fn pto_retransmit_policy_prioritized() {
+        let mut cfg = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+        cfg.set_cc_algorithm(CongestionControlAlgorithm::Reno);
+        cfg.set_pto_retransmit_policy(PtoRetransmitPolicy::Prioritized);
+
+        let mut r = Recovery::new(&cfg);
+
+        let now = Instant::now();
+
+        let stream_frame = |stream_id| frame::Frame::StreamHeader {
+            stream_id,
+            offset: 0,
+            length: 1000,
+            fin: false,
+        };
+
+        let frames: Vec<SmallVec<[frame::Frame; 1]>> = vec![
+            smallvec![stream_frame(8)],
+            smallvec![frame::Frame::MaxData { max: 100 }, stream_frame(4)],
+            smallvec![stream_frame(0)],
+        ];
+
+        for (pn, frames) in frames.into_iter().enumerate() {
+            let p = Sent {
+                pkt_num: pn as u64,
+                frames,
+                time_sent: now,
+                time_acked: None,
+                time_lost: None,
+                size: 1000,
+                ack_eliciting: true,
+                in_flight: true,
+                delivered: 0,
+                delivered_time: now,
+                first_sent_time: now,
+                is_app_limited: false,
+                tx_in_flight: 0,
+                lost: 0,
+                has_data: true,
+            };
+
+            r.on_packet_sent(
+                p,
+                packet::Epoch::Application,
+                HandshakeStatus::default(),
+                now,
+                "",
+            );
+        }
+
+        // The control frame is rescheduled first, then the stream data with
+        // the lowest urgency value, until the probe's budget is exhausted.
+        let now = r.loss_detection_timer().unwrap();
+        assert_eq!(
+            r.on_loss_detection_timeout_with_priority(
+                HandshakeStatus::default(),
+                now,
+                "",
+                |stream_id| stream_id as u8,
+            ),
+            (0, 0)
+        );
+
+        assert_eq!(r.loss_probes[packet::Epoch::Application], 1);
+        assert_eq!(r.lost[packet::Epoch::Application], vec![
+            frame::Frame::MaxData { max: 100 },
+            stream_frame(0),
+            stream_frame(4),
+        ]);
+    }
+
     #[test]
     fn loss_on_timer() {
         let mut cfg = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
@@ -1905,6 +3189,204 @@ mod tests {
         assert_eq!(r.sent[packet::Epoch::Application].len(), 0);
     }
 
+    #[test]
+    fn persistent_congestion() {
+        let mut cfg = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+        cfg.set_cc_algorithm(CongestionControlAlgorithm::Reno);
+
+        let mut r = Recovery::new(&cfg);
+
+        let mut now = Instant::now();
+
+        let send_pkt = |r: &mut Recovery, pkt_num: u64, now: Instant| {
+            let p = Sent {
+                pkt_num,
+                frames: smallvec![],
+                time_sent: now,
+                time_acked: None,
+                time_lost: None,
+                size: 1000,
+                ack_eliciting: true,
+                in_flight: true,
+                delivered: 0,
+                delivered_time: now,
+                first_sent_time: now,
+                is_app_limited: false,
+                tx_in_flight: 0,
+                lost: 0,
+                has_data: false,
+            };
+
+            r.on_packet_sent(
+                p,
+                packet::Epoch::Application,
+                HandshakeStatus::default(),
+                now,
+                "",
+            );
+        };
+
+        // Get a first RTT sample.
+        send_pkt(&mut r, 0, now);
+
+        now += Duration::from_millis(50);
+
+        let mut acked = ranges::RangeSet::default();
+        acked.insert(0..1);
+
+        assert_eq!(
+            r.on_ack_received(
+                &acked,
+                0,
+                packet::Epoch::Application,
+                HandshakeStatus::default(),
+                now,
+                "",
+                &mut Vec::new(),
+            ),
+            Ok((0, 0))
+        );
+
+        assert_eq!(r.persistent_congestion_count, 0);
+
+        now += Duration::from_millis(10);
+
+        // Send packets spanning more than the persistent congestion period.
+        send_pkt(&mut r, 1, now);
+        send_pkt(&mut r, 2, now);
+
+        now += r.persistent_congestion_period() + Duration::from_millis(1);
+
+        send_pkt(&mut r, 3, now);
+
+        now += Duration::from_millis(100);
+
+        send_pkt(&mut r, 4, now);
+
+        now += Duration::from_millis(50);
+
+        // Only the last packet is acked, all the others are lost.
+        let mut acked = ranges::RangeSet::default();
+        acked.insert(4..5);
+
+        assert_eq!(
+            r.on_ack_received(
+                &acked,
+                0,
+                packet::Epoch::Application,
+                HandshakeStatus::default(),
+                now,
+                "",
+                &mut Vec::new(),
+            ),
+            Ok((3, 3000))
+        );
+
+        assert_eq!(r.persistent_congestion_count, 1);
+        assert_eq!(
+            r.cwnd(),
+            r.max_datagram_size * r.minimum_congestion_window_packets
+        );
+    }
+
+    #[test]
+    fn persistent_congestion_interrupted_by_ack() {
+        let mut cfg = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+        cfg.set_cc_algorithm(CongestionControlAlgorithm::Reno);
+
+        let mut r = Recovery::new(&cfg);
+
+        let mut now = Instant::now();
+
+        let send_pkt = |r: &mut Recovery, pkt_num: u64, now: Instant| {
+            let p = Sent {
+                pkt_num,
+                frames: smallvec![],
+                time_sent: now,
+                time_acked: None,
+                time_lost: None,
+                size: 1000,
+                ack_eliciting: true,
+                in_flight: true,
+                delivered: 0,
+                delivered_time: now,
+                first_sent_time: now,
+                is_app_limited: false,
+                tx_in_flight: 0,
+                lost: 0,
+                has_data: false,
+            };
+
+            r.on_packet_sent(
+                p,
+                packet::Epoch::Application,
+                HandshakeStatus::default(),
+                now,
+                "",
+            );
+        };
+
+        // Get a first RTT sample.
+        send_pkt(&mut r, 0, now);
+
+        now += Duration::from_millis(50);
+
+        let mut acked = ranges::RangeSet::default();
+        acked.insert(0..1);
+
+        assert_eq!(
+            r.on_ack_received(
+                &acked,
+                0,
+                packet::Epoch::Application,
+                HandshakeStatus::default(),
+                now,
+                "",
+                &mut Vec::new(),
+            ),
+            Ok((0, 0))
+        );
+
+        now += Duration::from_millis(10);
+
+        send_pkt(&mut r, 1, now);
+        send_pkt(&mut r, 2, now);
+
+        now += r.persistent_congestion_period() + Duration::from_millis(1);
+
+        send_pkt(&mut r, 3, now);
+
+        now += Duration::from_millis(100);
+
+        send_pkt(&mut r, 4, now);
+
+        now += Duration::from_millis(50);
+
+        // Packet 2 is acked, so the lost packets don't form a contiguous
+        // period.
+        let mut acked = ranges::RangeSet::default();
+        acked.insert(2..3);
+        acked.insert(4..5);
+
+        assert_eq!(
+            r.on_ack_received(
+                &acked,
+                0,
+                packet::Epoch::Application,
+                HandshakeStatus::default(),
+                now,
+                "",
+                &mut Vec::new(),
+            ),
+            Ok((2, 2000))
+        );
+
+        assert_eq!(r.persistent_congestion_count, 0);
+        assert!(
+            r.cwnd() > r.max_datagram_size * r.minimum_congestion_window_packets
+        );
+    }
+
     #[test]
     fn loss_on_reordering() {
         let mut cfg = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
@@ -2262,6 +3744,60 @@ mod tests {
             now + Duration::from_secs_f64(12000.0 / pacing_rate as f64)
         );
     }
+
+    #[test]
+    fn pacing_mode() {
+        let mut cfg = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+        cfg.set_pacing_mode(PacingMode::Disabled);
+
+        let r = Recovery::new(&cfg);
+        assert!(!r.pacer.enabled());
+
+        let mut cfg = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+        cfg.set_cc_algorithm(CongestionControlAlgorithm::BBR);
+        cfg.set_pacing_mode(PacingMode::CongestionWindow);
+
+        let mut r = Recovery::new(&cfg);
+        assert!(r.pacer.enabled());
+
+        let now = Instant::now();
+
+        // The rate computed by the congestion control algorithm is ignored.
+        r.set_pacing_rate(1_000_000, now);
+        assert_eq!(r.pacer.rate(), 0);
+
+        r.smoothed_rtt = Some(Duration::from_millis(50));
+
+        let p = Sent {
+            pkt_num: 0,
+            frames: smallvec![],
+            time_sent: now,
+            time_acked: None,
+            time_lost: None,
+            size: 1000,
+            ack_eliciting: true,
+            in_flight: true,
+            delivered: 0,
+            delivered_time: now,
+            first_sent_time: now,
+            is_app_limited: false,
+            tx_in_flight: 0,
+            lost: 0,
+            has_data: false,
+        };
+
+        r.on_packet_sent(
+            p,
+            packet::Epoch::Application,
+            HandshakeStatus::default(),
+            now,
+            "",
+        );
+
+        let pacing_rate =
+            (r.congestion_window as f64 * PACING_MULTIPLIER / 0.05) as u64;
+        assert_eq!(r.pacer.rate(), pacing_rate);
+    }
 }
 
 mod bbr;
@@ -2272,3 +3808,5 @@ mod hystart;
 mod pacer;
 mod prr;
 mod reno;
+#[cfg(feature = "qlog")]
+pub mod replay;
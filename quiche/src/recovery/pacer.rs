@@ -205,6 +205,16 @@ impl Pacer {
     pub fn next_time(&self) -> Instant {
         self.next_time
     }
+
+    /// Returns the timestamp the next packet will get if it's sent at `now`,
+    /// including the interval of the burst that was just completed.
+    pub fn release_time(&self, now: Instant) -> Instant {
+        if self.iv.is_zero() {
+            return self.next_time;
+        }
+
+        self.next_time.max(now) + self.iv
+    }
 }
 
 #[cfg(test)]
@@ -242,6 +252,33 @@ mod tests {
         assert_eq!(p.next_time() - now, Duration::from_secs_f64(interval));
     }
 
+    #[test]
+    fn pacer_release_time() {
+        let datagram_size = 1200;
+        let max_burst = datagram_size * 10;
+        let pacing_rate = 100_000;
+
+        let mut p = Pacer::new(true, max_burst, pacing_rate, datagram_size, None);
+
+        let now = Instant::now();
+
+        assert_eq!(p.release_time(now), p.next_time());
+
+        // Fill max_burst. The next packet is released after the burst's
+        // interval.
+        p.send(6000, now);
+        p.send(6000, now);
+
+        let interval = max_burst as f64 / pacing_rate as f64;
+        let release = now + Duration::from_secs_f64(interval);
+
+        assert_eq!(p.release_time(now), release);
+
+        p.send(1000, now);
+
+        assert_eq!(p.next_time(), release);
+    }
+
     #[test]
     /// Same as pacer_update() but adds some idle time between transfers to
     /// trigger a reset.
@@ -125,7 +125,7 @@ fn congestion_event(
 
         r.congestion_window = cmp::max(
             r.congestion_window,
-            r.max_datagram_size * recovery::MINIMUM_WINDOW_PACKETS,
+            r.max_datagram_size * r.minimum_congestion_window_packets,
         );
 
         r.bytes_acked_ca = (r.congestion_window as f64 *
@@ -140,7 +140,8 @@ fn congestion_event(
 }
 
 pub fn collapse_cwnd(r: &mut Recovery) {
-    r.congestion_window = r.max_datagram_size * recovery::MINIMUM_WINDOW_PACKETS;
+    r.congestion_window =
+        r.max_datagram_size * r.minimum_congestion_window_packets;
     r.bytes_acked_sl = 0;
     r.bytes_acked_ca = 0;
 
@@ -0,0 +1,642 @@
+// Copyright (C) 2023, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Offline replay of a qlog trace through the recovery state machine.
+//!
+//! The `packet_sent` and `packet_received` events of a trace are fed to a
+//! fresh [`Recovery`] instance, using the event timestamps as the clock, so
+//! that congestion control decisions can be reproduced and inspected without
+//! the original connection. Only the ACK frames of received packets are
+//! used, as that's all the recovery state depends on.
+//!
+//! [`Recovery`]: ../struct.Recovery.html
+
+use std::io::BufRead;
+
+use std::time::Duration;
+use std::time::Instant;
+
+use qlog::events::connectivity::TransportOwner;
+use qlog::events::quic::AckedRanges;
+use qlog::events::quic::PacketHeader;
+use qlog::events::quic::PacketType;
+use qlog::events::quic::QuicFrame;
+use qlog::events::Event;
+use qlog::events::EventData;
+use qlog::reader::QlogSeqReader;
+use qlog::VantagePointType;
+
+use smallvec::SmallVec;
+
+use crate::packet;
+use crate::ranges;
+use crate::Config;
+
+use super::Acked;
+use super::HandshakeStatus;
+use super::Recovery;
+use super::Sent;
+
+/// The `max_ack_delay` transport parameter default value.
+const DEFAULT_MAX_ACK_DELAY: Duration = Duration::from_millis(25);
+
+/// The `ack_delay_exponent` transport parameter default value.
+const DEFAULT_ACK_DELAY_EXPONENT: u64 = 3;
+
+/// What caused a [`ReplaySnapshot`] to be taken.
+///
+/// [`ReplaySnapshot`]: struct.ReplaySnapshot.html
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReplayTrigger {
+    /// A packet was sent.
+    PacketSent,
+
+    /// An ACK frame was received.
+    AckReceived,
+
+    /// The loss detection timer expired.
+    LossDetectionTimeout,
+}
+
+/// The state of the replayed recovery after processing an event.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReplaySnapshot {
+    /// The time of the event, relative to the start of the trace.
+    pub time: Duration,
+
+    /// What caused the snapshot to be taken.
+    pub trigger: ReplayTrigger,
+
+    /// The packet number of the sent packet, or the largest acknowledged
+    /// one.
+    pub pkt_num: Option<u64>,
+
+    /// The replayed congestion window.
+    pub congestion_window: usize,
+
+    /// The replayed number of bytes in flight.
+    pub bytes_in_flight: usize,
+
+    /// The replayed slow start threshold, if it was set.
+    pub ssthresh: Option<usize>,
+
+    /// The replayed smoothed RTT.
+    pub rtt: Duration,
+
+    /// The replayed number of consecutive probe timeouts.
+    pub pto_count: u32,
+
+    /// The total number of packets declared lost so far.
+    pub lost_count: usize,
+
+    /// The congestion window reported by the trace's last `metrics_updated`
+    /// event, if any, to compare the replayed value with.
+    pub recorded_congestion_window: Option<u64>,
+}
+
+/// Replays qlog events through a [`Recovery`] instance.
+///
+/// [`Recovery`]: ../struct.Recovery.html
+pub struct Replay {
+    recovery: Recovery,
+
+    is_server: bool,
+
+    start: Instant,
+
+    now: Instant,
+
+    ack_delay_exponent: u64,
+
+    handshake_status: HandshakeStatus,
+
+    initial_discarded: bool,
+
+    handshake_discarded: bool,
+
+    recorded_congestion_window: Option<u64>,
+
+    newly_acked: Vec<Acked>,
+
+    snapshots: Vec<ReplaySnapshot>,
+}
+
+impl Replay {
+    /// Creates a replay of the trace of a client or server endpoint, using
+    /// the recovery settings of `config`.
+    pub fn new(config: &Config, is_server: bool) -> Replay {
+        let mut recovery = Recovery::new(config);
+        recovery.max_ack_delay = DEFAULT_MAX_ACK_DELAY;
+
+        let now = Instant::now();
+
+        Replay {
+            recovery,
+
+            is_server,
+
+            start: now,
+
+            now,
+
+            ack_delay_exponent: DEFAULT_ACK_DELAY_EXPONENT,
+
+            handshake_status: HandshakeStatus {
+                has_handshake_keys: false,
+                peer_verified_address: false,
+                completed: false,
+            },
+
+            initial_discarded: false,
+
+            handshake_discarded: false,
+
+            recorded_congestion_window: None,
+
+            newly_acked: Vec::new(),
+
+            snapshots: Vec::new(),
+        }
+    }
+
+    /// Sets the peer's `max_ack_delay`.
+    ///
+    /// This is normally taken from the trace's remote `parameters_set` event.
+    pub fn set_max_ack_delay(&mut self, v: Duration) {
+        self.recovery.max_ack_delay = v;
+    }
+
+    /// Sets the peer's `ack_delay_exponent`.
+    ///
+    /// This is normally taken from the trace's remote `parameters_set` event.
+    pub fn set_ack_delay_exponent(&mut self, v: u64) {
+        self.ack_delay_exponent = v;
+    }
+
+    /// Processes a single qlog event.
+    ///
+    /// Events must be processed in the order they were logged. Events that
+    /// don't affect recovery are ignored.
+    pub fn on_event(&mut self, ev: &Event) {
+        let time = Duration::from_secs_f64(f64::from(ev.time.max(0.0)) / 1000.0);
+
+        // Don't let the clock go backwards.
+        self.now = std::cmp::max(self.now, self.start + time);
+
+        self.on_timeout();
+
+        match &ev.data {
+            EventData::TransportParametersSet(params)
+                if params.owner == Some(TransportOwner::Remote) =>
+            {
+                if let Some(v) = params.max_ack_delay {
+                    self.set_max_ack_delay(Duration::from_millis(v.into()));
+                }
+
+                if let Some(v) = params.ack_delay_exponent {
+                    self.set_ack_delay_exponent(v.into());
+                }
+            },
+
+            EventData::MetricsUpdated(metrics)
+                if metrics.congestion_window.is_some() =>
+                self.recorded_congestion_window = metrics.congestion_window,
+
+            EventData::PacketSent(pkt) => {
+                let frames = pkt.frames.as_deref().unwrap_or(&[]);
+
+                let size = pkt
+                    .raw
+                    .as_ref()
+                    .and_then(|raw| raw.length)
+                    .or_else(|| pkt.header.length.map(u64::from))
+                    .unwrap_or(0);
+
+                self.on_packet_sent(&pkt.header, frames, size as usize);
+            },
+
+            EventData::PacketReceived(pkt) => {
+                let frames = pkt.frames.as_deref().unwrap_or(&[]);
+
+                self.on_packet_received(&pkt.header, frames);
+            },
+
+            _ => (),
+        }
+    }
+
+    /// Returns the snapshots taken so far.
+    pub fn snapshots(&self) -> &[ReplaySnapshot] {
+        &self.snapshots
+    }
+
+    fn on_packet_sent(
+        &mut self, hdr: &PacketHeader, frames: &[QuicFrame], size: usize,
+    ) {
+        let (epoch, pkt_num) = match (epoch_of(hdr), hdr.packet_number) {
+            (Some(epoch), Some(pkt_num)) => (epoch, pkt_num),
+
+            _ => return,
+        };
+
+        if epoch == packet::Epoch::Handshake {
+            self.handshake_status.has_handshake_keys = true;
+
+            // Clients drop Initial keys when they first send a Handshake
+            // packet.
+            if !self.is_server {
+                self.discard_epoch(packet::Epoch::Initial);
+            }
+        }
+
+        let ack_eliciting = frames.iter().any(|f| {
+            !matches!(
+                f,
+                QuicFrame::Padding |
+                    QuicFrame::Ack { .. } |
+                    QuicFrame::ConnectionClose { .. }
+            )
+        });
+
+        let has_data = frames.iter().any(|f| {
+            matches!(f, QuicFrame::Crypto { .. } | QuicFrame::Stream { .. })
+        });
+
+        let in_flight = ack_eliciting || frames.contains(&QuicFrame::Padding);
+
+        let pkt = Sent {
+            pkt_num,
+            frames: SmallVec::new(),
+            time_sent: self.now,
+            time_acked: None,
+            time_lost: None,
+            size: if in_flight { size } else { 0 },
+            ack_eliciting,
+            in_flight,
+            delivered: 0,
+            delivered_time: self.now,
+            first_sent_time: self.now,
+            is_app_limited: false,
+            tx_in_flight: 0,
+            lost: 0,
+            has_data,
+        };
+
+        self.recovery.on_packet_sent(
+            pkt,
+            epoch,
+            self.handshake_status,
+            self.now,
+            "",
+        );
+
+        // Servers confirm the handshake when they send HANDSHAKE_DONE.
+        if self.is_server && frames.contains(&QuicFrame::HandshakeDone) {
+            self.on_handshake_confirmed();
+        }
+
+        self.snapshot(ReplayTrigger::PacketSent, Some(pkt_num));
+    }
+
+    fn on_packet_received(&mut self, hdr: &PacketHeader, frames: &[QuicFrame]) {
+        let epoch = match epoch_of(hdr) {
+            Some(v) => v,
+
+            None => return,
+        };
+
+        if epoch == packet::Epoch::Handshake {
+            self.handshake_status.has_handshake_keys = true;
+            self.handshake_status.peer_verified_address = true;
+
+            // Servers drop Initial keys when they first receive a Handshake
+            // packet.
+            if self.is_server {
+                self.discard_epoch(packet::Epoch::Initial);
+            }
+        }
+
+        for f in frames {
+            match f {
+                QuicFrame::Ack {
+                    ack_delay,
+                    acked_ranges: Some(acked_ranges),
+                    ..
+                } => self.on_ack_received(
+                    epoch,
+                    ack_delay.unwrap_or(0.0),
+                    acked_ranges,
+                ),
+
+                // Clients confirm the handshake when they receive
+                // HANDSHAKE_DONE.
+                QuicFrame::HandshakeDone if !self.is_server =>
+                    self.on_handshake_confirmed(),
+
+                _ => (),
+            }
+        }
+    }
+
+    fn on_ack_received(
+        &mut self, epoch: packet::Epoch, ack_delay: f32,
+        acked_ranges: &AckedRanges,
+    ) {
+        // Dropped packet number spaces can't be acknowledged anymore.
+        if (epoch == packet::Epoch::Initial && self.initial_discarded) ||
+            (epoch == packet::Epoch::Handshake && self.handshake_discarded)
+        {
+            return;
+        }
+
+        let mut ranges = ranges::RangeSet::default();
+
+        match acked_ranges {
+            AckedRanges::Single(r) =>
+                for r in r.iter().filter(|r| !r.is_empty()) {
+                    ranges.insert(r[0]..r[r.len() - 1] + 1);
+                },
+
+            AckedRanges::Double(r) =>
+                for (start, end) in r {
+                    ranges.insert(*start..*end + 1);
+                },
+        }
+
+        let largest_acked = match ranges.last() {
+            Some(v) => v,
+
+            None => return,
+        };
+
+        // quiche logs the encoded ACK Delay field, scaled down by 1000.
+        let ack_delay = ((f64::from(ack_delay) * 1000.0).round() as u64)
+            .saturating_mul(2_u64.pow(self.ack_delay_exponent as u32));
+
+        let res = self.recovery.on_ack_received(
+            &ranges,
+            ack_delay,
+            epoch,
+            self.handshake_status,
+            self.now,
+            "",
+            &mut self.newly_acked,
+        );
+
+        self.newly_acked.clear();
+
+        if res.is_ok() {
+            self.snapshot(ReplayTrigger::AckReceived, Some(largest_acked));
+        }
+    }
+
+    fn on_timeout(&mut self) {
+        while let Some(timer) = self.recovery.loss_detection_timer() {
+            if timer > self.now {
+                break;
+            }
+
+            self.recovery.on_loss_detection_timeout(
+                self.handshake_status,
+                timer,
+                "",
+            );
+
+            let time = self.now;
+            self.now = timer;
+            self.snapshot(ReplayTrigger::LossDetectionTimeout, None);
+            self.now = time;
+
+            // Avoid looping forever if the timer didn't move.
+            if self.recovery.loss_detection_timer() == Some(timer) {
+                break;
+            }
+        }
+    }
+
+    fn on_handshake_confirmed(&mut self) {
+        self.handshake_status.completed = true;
+        self.handshake_status.peer_verified_address = true;
+
+        self.discard_epoch(packet::Epoch::Initial);
+        self.discard_epoch(packet::Epoch::Handshake);
+    }
+
+    fn discard_epoch(&mut self, epoch: packet::Epoch) {
+        let discarded = match epoch {
+            packet::Epoch::Initial => &mut self.initial_discarded,
+
+            packet::Epoch::Handshake => &mut self.handshake_discarded,
+
+            packet::Epoch::Application => return,
+        };
+
+        if *discarded {
+            return;
+        }
+
+        *discarded = true;
+
+        self.recovery.on_pkt_num_space_discarded(
+            epoch,
+            self.handshake_status,
+            self.now,
+        );
+    }
+
+    fn snapshot(&mut self, trigger: ReplayTrigger, pkt_num: Option<u64>) {
+        let ssthresh = if self.recovery.ssthresh == usize::MAX {
+            None
+        } else {
+            Some(self.recovery.ssthresh)
+        };
+
+        self.snapshots.push(ReplaySnapshot {
+            time: self.now.duration_since(self.start),
+            trigger,
+            pkt_num,
+            congestion_window: self.recovery.cwnd(),
+            bytes_in_flight: self.recovery.bytes_in_flight,
+            ssthresh,
+            rtt: self.recovery.rtt(),
+            pto_count: self.recovery.pto_count(),
+            lost_count: self.recovery.lost_count,
+            recorded_congestion_window: self.recorded_congestion_window,
+        });
+    }
+}
+
+/// Replays a JSON-SEQ qlog trace, as written by quiche, and returns the
+/// resulting snapshots.
+///
+/// Whether the trace was recorded by a client or a server is taken from the
+/// trace's vantage point.
+pub fn replay_seq(
+    reader: Box<dyn BufRead + Send + Sync>, config: &Config,
+) -> std::result::Result<Vec<ReplaySnapshot>, Box<dyn std::error::Error>> {
+    let reader = QlogSeqReader::new(reader)?;
+
+    let is_server =
+        reader.qlog.trace.vantage_point.ty == VantagePointType::Server;
+
+    let mut replay = Replay::new(config, is_server);
+
+    for ev in reader {
+        if let qlog::reader::Event::Qlog(ev) = ev {
+            replay.on_event(&ev);
+        }
+    }
+
+    Ok(replay.snapshots)
+}
+
+fn epoch_of(hdr: &PacketHeader) -> Option<packet::Epoch> {
+    match hdr.packet_type {
+        PacketType::Initial => Some(packet::Epoch::Initial),
+
+        PacketType::Handshake => Some(packet::Epoch::Handshake),
+
+        PacketType::ZeroRtt | PacketType::OneRtt =>
+            Some(packet::Epoch::Application),
+
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use qlog::events::quic::PacketReceived;
+    use qlog::events::quic::PacketSent;
+    use qlog::events::RawInfo;
+
+    fn sent(time: f32, pn: u64) -> Event {
+        Event::with_time(
+            time,
+            EventData::PacketSent(PacketSent {
+                header: PacketHeader::new(
+                    PacketType::OneRtt,
+                    Some(pn),
+                    None,
+                    None,
+                    None,
+                    None,
+                    None,
+                    None,
+                ),
+                is_coalesced: None,
+                retry_token: None,
+                stateless_reset_token: None,
+                supported_versions: None,
+                raw: Some(RawInfo {
+                    length: Some(1200),
+                    payload_length: None,
+                    data: None,
+                }),
+                datagram_id: None,
+                trigger: None,
+                send_at_time: None,
+                frames: Some(smallvec::smallvec![QuicFrame::Stream {
+                    stream_id: 0,
+                    offset: pn * 1000,
+                    length: 1000,
+                    fin: None,
+                    raw: None,
+                }]),
+            }),
+        )
+    }
+
+    fn ack(time: f32, ranges: Vec<(u64, u64)>) -> Event {
+        Event::with_time(
+            time,
+            EventData::PacketReceived(PacketReceived {
+                header: PacketHeader::new(
+                    PacketType::OneRtt,
+                    Some(0),
+                    None,
+                    None,
+                    None,
+                    None,
+                    None,
+                    None,
+                ),
+                is_coalesced: None,
+                retry_token: None,
+                stateless_reset_token: None,
+                supported_versions: None,
+                raw: None,
+                datagram_id: None,
+                trigger: None,
+                frames: Some(vec![QuicFrame::Ack {
+                    ack_delay: Some(0.0),
+                    acked_ranges: Some(AckedRanges::Double(ranges)),
+                    ect1: None,
+                    ect0: None,
+                    ce: None,
+                }]),
+            }),
+        )
+    }
+
+    #[test]
+    fn replay_ack_and_loss() {
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config.set_cc_algorithm(crate::CongestionControlAlgorithm::Reno);
+
+        let mut replay = Replay::new(&config, true);
+        replay.on_handshake_confirmed();
+
+        for pn in 0..5 {
+            replay.on_event(&sent(pn as f32, pn));
+        }
+
+        let initial_cwnd = replay.snapshots()[0].congestion_window;
+        assert_eq!(replay.snapshots()[4].bytes_in_flight, 6000);
+
+        // Packet 0 is lost, the others are acknowledged.
+        replay.on_event(&ack(50.0, vec![(1, 4)]));
+
+        let last = replay.snapshots().last().unwrap();
+        assert_eq!(last.trigger, ReplayTrigger::AckReceived);
+        assert_eq!(last.pkt_num, Some(4));
+        assert_eq!(last.bytes_in_flight, 0);
+        assert_eq!(last.lost_count, 1);
+        assert!(last.congestion_window < initial_cwnd);
+        assert!(last.ssthresh.is_some());
+
+        // A packet that is never acknowledged triggers a probe timeout.
+        replay.on_event(&sent(60.0, 5));
+        replay.on_event(&sent(10_000.0, 6));
+
+        assert!(replay
+            .snapshots()
+            .iter()
+            .any(|s| s.trigger == ReplayTrigger::LossDetectionTimeout &&
+                s.pto_count > 0));
+    }
+}
@@ -0,0 +1,1169 @@
+// Copyright (C) 2023, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Server-side connection management.
+//!
+//! A [`Listener`] owns all the connections of a server bound to a single
+//! address, and takes care of the packet handling that happens before a
+//! connection exists or after it is gone:
+//!
+//! * routing incoming packets to connections based on their Destination
+//!   Connection ID, including the ones issued after the handshake;
+//!
+//! * creating connections for new clients, optionally validating their address
+//!   with a Retry first, or deciding per client with an [`InitialPolicy`];
+//!
+//! * negotiating the version with clients using an unsupported one;
+//!
+//! * sending a Stateless Reset in response to packets for unknown connections,
+//!   for example after the server restarted.
+//!
+//! The listener doesn't do any I/O itself. The application reads datagrams
+//! from its socket and passes them to [`recv()`], sending back any response
+//! it produces, and sends the packets generated by the connections with
+//! [`send()`]:
+//!
+//! ```no_run
+//! # let config = quiche::Config::new(quiche::PROTOCOL_VERSION)?;
+//! # let socket = std::net::UdpSocket::bind("0.0.0.0:4433").unwrap();
+//! # let local = socket.local_addr().unwrap();
+//! let mut listener = quiche::server::Listener::new(config);
+//!
+//! let mut buf = [0; 65535];
+//! let mut out = [0; 1350];
+//!
+//! loop {
+//!     let (len, from) = socket.recv_from(&mut buf).unwrap();
+//!     let info = quiche::RecvInfo { from, to: local };
+//!
+//!     match listener.recv(&mut buf[..len], info, &mut out)? {
+//!         quiche::server::Received::Respond(len) => {
+//!             socket.send_to(&out[..len], from).unwrap();
+//!         },
+//!
+//!         quiche::server::Received::Accepted(handle) |
+//!         quiche::server::Received::Delivered(handle) => {
+//!             let conn = listener.get_mut(handle).unwrap();
+//!             // Process readable streams...
+//!         },
+//!
+//!         quiche::server::Received::Dropped => (),
+//!     }
+//!
+//!     while let Ok((len, send_info)) = listener.send(&mut out) {
+//!         socket.send_to(&out[..len], send_info.to).unwrap();
+//!     }
+//!
+//!     listener.collect();
+//! }
+//! # Ok::<(), quiche::Error>(())
+//! ```
+//!
+//! [`Listener`]: struct.Listener.html
+//! [`InitialPolicy`]: trait.InitialPolicy.html
+//! [`recv()`]: struct.Listener.html#method.recv
+//! [`send()`]: struct.Listener.html#method.send
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time;
+
+use ring::hmac;
+
+use crate::packet;
+use crate::rand;
+
+use crate::Config;
+use crate::Connection;
+use crate::ConnectionId;
+use crate::ConnectionIdGenerator;
+use crate::Error;
+use crate::RecvInfo;
+use crate::Result;
+use crate::SendInfo;
+use crate::ValidatedToken;
+
+/// The length of a stateless reset token.
+const RESET_TOKEN_LEN: usize = 16;
+
+/// The smallest Stateless Reset that can be sent, as a short header packet
+/// with a 4 bytes unpredictable payload, followed by the token.
+const MIN_STATELESS_RESET_LEN: usize = 5 + RESET_TOKEN_LEN;
+
+/// The size of the Stateless Resets sent in response to large packets. They
+/// only need to look like a short header packet, not to match the size of
+/// the packet that triggered them.
+const MAX_STATELESS_RESET_LEN: usize = 43;
+
+/// The outcome of processing an incoming datagram with [`Listener::recv()`].
+///
+/// [`Listener::recv()`]: struct.Listener.html#method.recv
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Received {
+    /// The datagram was processed by an existing connection.
+    Delivered(usize),
+
+    /// A new connection was created and processed the datagram.
+    Accepted(usize),
+
+    /// The datagram was not processed, but a packet of the given length was
+    /// written to the output buffer, and needs to be sent back to the peer.
+    /// This is either a Version Negotiation, a Retry or a Stateless Reset.
+    Respond(usize),
+
+    /// The datagram was dropped.
+    Dropped,
+}
+
+/// What to do with an Initial packet that would create a new connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InitialAction {
+    /// Create the connection.
+    Accept,
+
+    /// Validate the client's address with a Retry first.
+    Retry,
+
+    /// Drop the packet without creating any state.
+    Drop,
+}
+
+/// The signals an [`InitialPolicy`] can base its decision on.
+///
+/// [`InitialPolicy`]: trait.InitialPolicy.html
+#[derive(Clone, Copy, Debug)]
+pub struct InitialInfo {
+    /// The address the Initial was received from.
+    pub from: SocketAddr,
+
+    /// The address the Initial was received on.
+    pub to: SocketAddr,
+
+    /// Whether the client presented a valid address validation token, either
+    /// from a Retry or from a NEW_TOKEN frame.
+    pub address_validated: bool,
+
+    /// The number of connections of the listener whose handshake is still in
+    /// progress.
+    pub pending_handshakes: usize,
+
+    /// The total number of connections of the listener.
+    pub connections: usize,
+}
+
+/// Decides how the [`Listener`] answers Initial packets from new clients.
+///
+/// The policy is consulted for every Initial that would create a connection,
+/// after the client's token (if any) was validated. Retry is only possible
+/// for clients whose address isn't validated yet, so it is handled as
+/// [`Accept`] otherwise.
+///
+/// [`RateLimitPolicy`] is a default implementation.
+///
+/// [`Listener`]: struct.Listener.html
+/// [`Accept`]: enum.InitialAction.html#variant.Accept
+/// [`RateLimitPolicy`]: struct.RateLimitPolicy.html
+pub trait InitialPolicy: Send {
+    /// Returns the action to take for the Initial described by `info`.
+    fn on_initial(&mut self, info: &InitialInfo) -> InitialAction;
+}
+
+/// The number of source addresses a [`RateLimitPolicy`] tracks before it
+/// forgets the ones whose window expired.
+///
+/// [`RateLimitPolicy`]: struct.RateLimitPolicy.html
+const MAX_TRACKED_SOURCES: usize = 65536;
+
+/// An [`InitialPolicy`] based on the rate of new connections from each
+/// source address, and on the number of pending handshakes.
+///
+/// Clients are sent a Retry when they open too many connections within the
+/// rate window, or when the listener has too many pending handshakes, and
+/// are dropped entirely above the higher drop thresholds. All limits are
+/// disabled by default, in which case every client is accepted.
+///
+/// [`InitialPolicy`]: trait.InitialPolicy.html
+pub struct RateLimitPolicy {
+    window: time::Duration,
+
+    retry_source_rate: Option<u32>,
+
+    drop_source_rate: Option<u32>,
+
+    retry_handshakes: Option<usize>,
+
+    drop_handshakes: Option<usize>,
+
+    sources: HashMap<IpAddr, (time::Instant, u32)>,
+}
+
+impl RateLimitPolicy {
+    /// Creates a policy with no limits, and a 1 second rate window.
+    pub fn new() -> RateLimitPolicy {
+        RateLimitPolicy {
+            window: time::Duration::from_secs(1),
+            retry_source_rate: None,
+            drop_source_rate: None,
+            retry_handshakes: None,
+            drop_handshakes: None,
+            sources: HashMap::new(),
+        }
+    }
+
+    /// Sets the window the number of Initials per source address is counted
+    /// over.
+    ///
+    /// The default value is 1 second.
+    pub fn set_window(&mut self, v: time::Duration) {
+        self.window = v;
+    }
+
+    /// Sets the number of Initials per source address and window over which
+    /// clients are sent a Retry.
+    pub fn set_retry_source_rate(&mut self, v: Option<u32>) {
+        self.retry_source_rate = v;
+    }
+
+    /// Sets the number of Initials per source address and window over which
+    /// they are dropped.
+    pub fn set_drop_source_rate(&mut self, v: Option<u32>) {
+        self.drop_source_rate = v;
+    }
+
+    /// Sets the number of pending handshakes from which new clients are sent
+    /// a Retry.
+    pub fn set_retry_handshakes(&mut self, v: Option<usize>) {
+        self.retry_handshakes = v;
+    }
+
+    /// Sets the number of pending handshakes from which Initials from new
+    /// clients are dropped.
+    pub fn set_drop_handshakes(&mut self, v: Option<usize>) {
+        self.drop_handshakes = v;
+    }
+
+    /// Counts an Initial from `ip`, and returns the number of Initials from
+    /// it in the current window.
+    fn count(&mut self, ip: IpAddr, now: time::Instant) -> u32 {
+        let window = self.window;
+
+        if self.sources.len() >= MAX_TRACKED_SOURCES {
+            self.sources.retain(|_, (start, _)| {
+                now.saturating_duration_since(*start) < window
+            });
+        }
+
+        let (start, count) = self.sources.entry(ip).or_insert((now, 0));
+
+        if now.saturating_duration_since(*start) >= window {
+            *start = now;
+            *count = 0;
+        }
+
+        *count += 1;
+
+        *count
+    }
+}
+
+impl Default for RateLimitPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl InitialPolicy for RateLimitPolicy {
+    fn on_initial(&mut self, info: &InitialInfo) -> InitialAction {
+        let rate = self.count(info.from.ip(), time::Instant::now());
+
+        let above = |limit: Option<u32>| limit.map_or(false, |l| rate > l);
+
+        let loaded = |limit: Option<usize>| {
+            limit.map_or(false, |l| info.pending_handshakes >= l)
+        };
+
+        if above(self.drop_source_rate) || loaded(self.drop_handshakes) {
+            return InitialAction::Drop;
+        }
+
+        if above(self.retry_source_rate) || loaded(self.retry_handshakes) {
+            return InitialAction::Retry;
+        }
+
+        InitialAction::Accept
+    }
+}
+
+/// Counters of the decisions taken by a [`Listener`] for new clients.
+///
+/// [`Listener`]: struct.Listener.html
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ListenerStats {
+    /// The number of connections created.
+    pub accepted: u64,
+
+    /// The number of Retry packets sent.
+    pub retried: u64,
+
+    /// The number of Initials dropped by the [`InitialPolicy`].
+    ///
+    /// [`InitialPolicy`]: trait.InitialPolicy.html
+    pub dropped: u64,
+}
+
+/// Generates random Connection IDs of the maximum length.
+struct RandomConnectionIds;
+
+impl ConnectionIdGenerator for RandomConnectionIds {
+    fn generate(&mut self) -> ConnectionId<'static> {
+        let mut cid = vec![0; crate::MAX_CONN_ID_LEN];
+        rand::rand_bytes(&mut cid);
+
+        ConnectionId::from_vec(cid)
+    }
+}
+
+/// A table of server connections, see the [module-level documentation].
+///
+/// Connections are identified by an opaque handle, returned when they are
+/// created, that stays valid until they are collected.
+///
+/// [module-level documentation]: index.html
+pub struct Listener {
+    config: Config,
+
+    conns: slab::Slab<Connection>,
+
+    /// Maps the Connection IDs issued by the server, and the original
+    /// Destination Connection ID chosen by clients, to connection handles.
+    ids: HashMap<ConnectionId<'static>, usize>,
+
+    /// The Destination Connection ID chosen by clients whose handshake isn't
+    /// confirmed yet, by connection handle.
+    client_dcids: HashMap<usize, ConnectionId<'static>>,
+
+    cid_generator: Box<dyn ConnectionIdGenerator>,
+
+    cid_len: usize,
+
+    reset_key: hmac::Key,
+
+    retry: bool,
+
+    initial_policy: Option<Box<dyn InitialPolicy>>,
+
+    stats: ListenerStats,
+
+    send_cursor: usize,
+}
+
+impl Listener {
+    /// Creates a listener accepting connections with the given configuration.
+    ///
+    /// Stateless reset tokens are derived from a random secret, so that they
+    /// can't be used after a restart; use [`set_stateless_reset_secret()`] to
+    /// keep resetting connections established before a restart.
+    ///
+    /// [`set_stateless_reset_secret()`]: struct.Listener.html#method.set_stateless_reset_secret
+    pub fn new(config: Config) -> Listener {
+        let mut secret = [0; 32];
+        rand::rand_bytes(&mut secret);
+
+        Listener {
+            config,
+            conns: slab::Slab::new(),
+            ids: HashMap::new(),
+            client_dcids: HashMap::new(),
+            cid_generator: Box::new(RandomConnectionIds),
+            cid_len: crate::MAX_CONN_ID_LEN,
+            reset_key: hmac::Key::new(hmac::HMAC_SHA256, &secret),
+            retry: false,
+            initial_policy: None,
+            stats: ListenerStats::default(),
+            send_cursor: 0,
+        }
+    }
+
+    /// Returns the configuration used for new connections.
+    pub fn config_mut(&mut self) -> &mut Config {
+        &mut self.config
+    }
+
+    /// Sets the generator of the Connection IDs issued by the server.
+    ///
+    /// All the generated Connection IDs must have the same length. Random
+    /// Connection IDs of the maximum length are used by default.
+    pub fn set_cid_generator(
+        &mut self, mut generator: Box<dyn ConnectionIdGenerator>,
+    ) {
+        self.cid_len = generator.generate().len();
+        self.cid_generator = generator;
+    }
+
+    /// Sets the secret stateless reset tokens are derived from.
+    ///
+    /// It needs to be shared by all the servers that can receive packets for
+    /// the same connections.
+    pub fn set_stateless_reset_secret(&mut self, secret: &[u8]) {
+        self.reset_key = hmac::Key::new(hmac::HMAC_SHA256, secret);
+    }
+
+    /// Configures whether to validate client addresses with a Retry before
+    /// creating connections.
+    ///
+    /// This requires a token provider to be set on the configuration with
+    /// [`set_token_provider()`], otherwise [`InvalidState`] is returned.
+    /// Clients presenting a token received in a NEW_TOKEN frame on a previous
+    /// connection skip the Retry.
+    ///
+    /// The default value is `false`.
+    ///
+    /// [`set_token_provider()`]: ../struct.Config.html#method.set_token_provider
+    /// [`InvalidState`]: ../enum.Error.html#variant.InvalidState
+    pub fn enable_retry(&mut self, v: bool) -> Result<()> {
+        if v && self.config.token_provider.is_none() {
+            return Err(Error::InvalidState);
+        }
+
+        self.retry = v;
+
+        Ok(())
+    }
+
+    /// Sets the policy deciding whether to accept, retry or drop each new
+    /// client.
+    ///
+    /// When set, it replaces the setting of [`enable_retry()`]. Retry still
+    /// requires a token provider to be set on the configuration: clients the
+    /// policy decides to retry are dropped if there is none.
+    ///
+    /// [`enable_retry()`]: struct.Listener.html#method.enable_retry
+    pub fn set_initial_policy(&mut self, policy: Box<dyn InitialPolicy>) {
+        self.initial_policy = Some(policy);
+    }
+
+    /// Returns the counters of the decisions taken for new clients.
+    pub fn stats(&self) -> ListenerStats {
+        self.stats
+    }
+
+    /// Returns the connection with the given handle.
+    pub fn get(&self, handle: usize) -> Option<&Connection> {
+        self.conns.get(handle)
+    }
+
+    /// Returns the connection with the given handle.
+    pub fn get_mut(&mut self, handle: usize) -> Option<&mut Connection> {
+        self.conns.get_mut(handle)
+    }
+
+    /// Returns an iterator over all the connections and their handles.
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (usize, &mut Connection)> {
+        self.conns.iter_mut()
+    }
+
+    /// Returns the number of connections.
+    pub fn len(&self) -> usize {
+        self.conns.len()
+    }
+
+    /// Returns true if there are no connections.
+    pub fn is_empty(&self) -> bool {
+        self.conns.is_empty()
+    }
+
+    /// Processes a datagram received on the listener's socket.
+    ///
+    /// Packets for unknown connections might require a response, which is
+    /// written to `out` (see [`Received::Respond`]). Errors returned by an
+    /// existing connection while processing the datagram are ignored, as it
+    /// is closed if needed.
+    ///
+    /// [`Received::Respond`]: enum.Received.html#variant.Respond
+    pub fn recv(
+        &mut self, buf: &mut [u8], info: RecvInfo, out: &mut [u8],
+    ) -> Result<Received> {
+        let hdr = match packet::Header::from_slice(buf, self.cid_len) {
+            Ok(v) => v,
+
+            Err(_) => return Ok(Received::Dropped),
+        };
+
+        if let Some(&handle) = self.ids.get(&hdr.dcid) {
+            self.deliver(handle, buf, info);
+
+            return Ok(Received::Delivered(handle));
+        }
+
+        if hdr.ty == packet::Type::Short {
+            return self.stateless_reset(&hdr.dcid, buf.len(), out);
+        }
+
+        // Only Initial packets can create new connections, and they need to
+        // be padded, so that responses don't amplify attacks.
+        if hdr.ty != packet::Type::Initial ||
+            buf.len() < crate::MIN_CLIENT_INITIAL_LEN
+        {
+            return Ok(Received::Dropped);
+        }
+
+        if !self.config.is_version_supported(hdr.version) {
+            let len = crate::negotiate_version(&hdr.scid, &hdr.dcid, out)?;
+
+            return Ok(Received::Respond(len));
+        }
+
+        let dcid = hdr.dcid.into_owned();
+        let client_scid = hdr.scid.into_owned();
+        let token = hdr.token.unwrap_or_default();
+        let version = hdr.version;
+
+        let validated = match &self.config.token_provider {
+            Some(provider) if !token.is_empty() =>
+                provider.validate_token(&info.from, &dcid, &token),
+
+            _ => None,
+        };
+
+        let action = match self.initial_policy.as_mut() {
+            Some(policy) => {
+                let info = InitialInfo {
+                    from: info.from,
+                    to: info.to,
+                    address_validated: validated.is_some(),
+                    pending_handshakes: self
+                        .conns
+                        .iter()
+                        .filter(|(_, c)| !c.is_established() && !c.is_closed())
+                        .count(),
+                    connections: self.conns.len(),
+                };
+
+                policy.on_initial(&info)
+            },
+
+            None if self.retry => InitialAction::Retry,
+
+            None => InitialAction::Accept,
+        };
+
+        if action == InitialAction::Drop {
+            self.stats.dropped += 1;
+
+            return Ok(Received::Dropped);
+        }
+
+        let (scid, odcid) = match validated {
+            // Reuse the Connection ID sent in the Retry, which the client now
+            // uses as Destination Connection ID.
+            Some(ValidatedToken::Retry(odcid)) if dcid.len() == self.cid_len =>
+                (dcid.clone(), Some(odcid)),
+
+            Some(ValidatedToken::Retry(_)) => return Ok(Received::Dropped),
+
+            Some(ValidatedToken::NewToken) =>
+                (self.cid_generator.generate(), None),
+
+            None if action == InitialAction::Retry => {
+                let provider = match &self.config.token_provider {
+                    Some(v) => Arc::clone(v),
+
+                    None => {
+                        self.stats.dropped += 1;
+
+                        return Ok(Received::Dropped);
+                    },
+                };
+
+                let new_scid = self.cid_generator.generate();
+                let token = provider.mint_retry_token(&info.from, &dcid);
+
+                let len = crate::retry(
+                    &client_scid,
+                    &dcid,
+                    &new_scid,
+                    &token,
+                    version,
+                    out,
+                )?;
+
+                self.stats.retried += 1;
+
+                return Ok(Received::Respond(len));
+            },
+
+            None => (self.cid_generator.generate(), None),
+        };
+
+        self.config
+            .set_stateless_reset_token(Some(reset_token(&self.reset_key, &scid)));
+
+        let conn = crate::accept(
+            &scid,
+            odcid.as_ref(),
+            info.to,
+            info.from,
+            &mut self.config,
+        )?;
+
+        let handle = self.conns.insert(conn);
+
+        // Until the client learns the server's Connection ID, it keeps using
+        // the one it picked. After a Retry both are the same.
+        if dcid != scid {
+            self.ids.insert(dcid.clone(), handle);
+            self.client_dcids.insert(handle, dcid);
+        }
+
+        self.ids.insert(scid, handle);
+
+        self.stats.accepted += 1;
+
+        self.deliver(handle, buf, info);
+
+        Ok(Received::Accepted(handle))
+    }
+
+    /// Writes a single QUIC packet to be sent to the peer of one of the
+    /// connections, going through them in turn.
+    ///
+    /// [`Done`] is returned when no connection has anything to send. See
+    /// [`Connection::send()`] for details.
+    ///
+    /// [`Done`]: ../enum.Error.html#variant.Done
+    /// [`Connection::send()`]: ../struct.Connection.html#method.send
+    pub fn send(&mut self, out: &mut [u8]) -> Result<(usize, SendInfo)> {
+        let handles: Vec<usize> = self.conns.iter().map(|(h, _)| h).collect();
+
+        if handles.is_empty() {
+            return Err(Error::Done);
+        }
+
+        let start = self.send_cursor % handles.len();
+
+        for i in 0..handles.len() {
+            let idx = (start + i) % handles.len();
+            let conn = &mut self.conns[handles[idx]];
+
+            match conn.send(out) {
+                Ok(v) => {
+                    self.send_cursor = idx + 1;
+
+                    return Ok(v);
+                },
+
+                Err(Error::Done) => (),
+
+                Err(_) => {
+                    conn.close(false, 0x1, b"fail").ok();
+                },
+            }
+        }
+
+        Err(Error::Done)
+    }
+
+    /// Returns the amount of time until the next timeout event of any
+    /// connection.
+    ///
+    /// See [`Connection::timeout()`].
+    ///
+    /// [`Connection::timeout()`]: ../struct.Connection.html#method.timeout
+    pub fn timeout(&self) -> Option<std::time::Duration> {
+        self.conns.iter().filter_map(|(_, c)| c.timeout()).min()
+    }
+
+    /// Processes timeout events of all connections whose timer expired.
+    pub fn on_timeout(&mut self) {
+        for (_, conn) in self.conns.iter_mut() {
+            if conn.timeout() == Some(std::time::Duration::ZERO) {
+                conn.on_timeout();
+            }
+        }
+    }
+
+    /// Removes the connection with the given handle and returns it, whether
+    /// it is closed or not.
+    ///
+    /// This can be used instead of [`collect()`] to keep closed connections
+    /// until the application is done with them.
+    ///
+    /// [`collect()`]: struct.Listener.html#method.collect
+    pub fn remove(&mut self, handle: usize) -> Option<Connection> {
+        if !self.conns.contains(handle) {
+            return None;
+        }
+
+        self.ids.retain(|_, h| *h != handle);
+        self.client_dcids.remove(&handle);
+
+        Some(self.conns.remove(handle))
+    }
+
+    /// Removes closed connections and returns them, along with their handle.
+    pub fn collect(&mut self) -> Vec<(usize, Connection)> {
+        let closed: Vec<usize> = self
+            .conns
+            .iter()
+            .filter(|(_, c)| c.is_closed())
+            .map(|(h, _)| h)
+            .collect();
+
+        if closed.is_empty() {
+            return Vec::new();
+        }
+
+        self.ids.retain(|_, h| !closed.contains(h));
+        self.client_dcids.retain(|h, _| !closed.contains(h));
+
+        closed
+            .into_iter()
+            .map(|h| (h, self.conns.remove(h)))
+            .collect()
+    }
+
+    fn deliver(&mut self, handle: usize, buf: &mut [u8], info: RecvInfo) {
+        let conn = &mut self.conns[handle];
+
+        if let Err(e) = conn.recv(buf, info) {
+            trace!("{} recv failed: {:?}", conn.trace_id(), e);
+        }
+
+        // Once the handshake is confirmed the client doesn't use the
+        // Destination Connection ID it picked anymore.
+        if conn.handshake_confirmed {
+            if let Some(dcid) = self.client_dcids.remove(&handle) {
+                if self.ids.get(&dcid) == Some(&handle) {
+                    self.ids.remove(&dcid);
+                }
+            }
+        }
+
+        // Forget retired Connection IDs, and issue new ones.
+        while let Some(scid) = conn.retired_scid_next() {
+            self.ids.remove(&scid);
+        }
+
+        while conn.scids_left() > 0 {
+            let scid = self.cid_generator.generate();
+            let reset_token = reset_token(&self.reset_key, &scid);
+
+            if conn.new_scid(&scid, reset_token, false).is_err() {
+                break;
+            }
+
+            self.ids.insert(scid, handle);
+        }
+    }
+
+    /// Writes a Stateless Reset for a packet of `len` bytes sent to `dcid`.
+    ///
+    /// Resets are always smaller than the packet that triggered them, so
+    /// that two endpoints can't keep resetting each other.
+    fn stateless_reset(
+        &self, dcid: &[u8], len: usize, out: &mut [u8],
+    ) -> Result<Received> {
+        let reset_len = std::cmp::min(len - 1, MAX_STATELESS_RESET_LEN);
+
+        if len <= MIN_STATELESS_RESET_LEN || out.len() < reset_len {
+            return Ok(Received::Dropped);
+        }
+
+        let out = &mut out[..reset_len];
+
+        rand::rand_bytes(out);
+
+        // Look like a short header packet, with the fixed bit set.
+        out[0] = (out[0] & 0x3f) | 0x40;
+
+        let token = reset_token(&self.reset_key, dcid).to_be_bytes();
+        out[reset_len - RESET_TOKEN_LEN..].copy_from_slice(&token);
+
+        Ok(Received::Respond(reset_len))
+    }
+}
+
+/// Derives the stateless reset token of the given Connection ID.
+fn reset_token(key: &hmac::Key, cid: &[u8]) -> u128 {
+    let tag = hmac::sign(key, cid);
+
+    let mut token = [0; RESET_TOKEN_LEN];
+    token.copy_from_slice(&tag.as_ref()[..RESET_TOKEN_LEN]);
+
+    u128::from_be_bytes(token)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing;
+    use crate::AeadTokenProvider;
+
+    fn server_config() -> Config {
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_streams_bidi(3);
+        config.set_active_connection_id_limit(4);
+        config
+    }
+
+    fn client() -> Connection {
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.set_active_connection_id_limit(4);
+        config.verify_peer(false);
+
+        let mut scid = [0; 16];
+        rand::rand_bytes(&mut scid);
+
+        crate::connect(
+            Some("quic.tech"),
+            &ConnectionId::from_ref(&scid),
+            testing::Pipe::client_addr(),
+            testing::Pipe::server_addr(),
+            &mut config,
+        )
+        .unwrap()
+    }
+
+    /// Exchanges packets between the client and the listener, until neither
+    /// has anything to send. Returns the responses sent by the listener.
+    fn advance(client: &mut Connection, listener: &mut Listener) -> usize {
+        let mut responses = 0;
+
+        loop {
+            let mut done = true;
+
+            while let Ok(flight) = testing::emit_flight(client) {
+                for (mut pkt, si) in flight {
+                    let info = RecvInfo {
+                        from: si.from,
+                        to: si.to,
+                    };
+
+                    let mut out = [0; 1350];
+
+                    if let Received::Respond(len) =
+                        listener.recv(&mut pkt, info, &mut out).unwrap()
+                    {
+                        let info = RecvInfo {
+                            from: si.to,
+                            to: si.from,
+                        };
+
+                        client.recv(&mut out[..len], info).ok();
+                        responses += 1;
+                    }
+                }
+
+                done = false;
+            }
+
+            let mut out = [0; 65535];
+
+            while let Ok((len, si)) = listener.send(&mut out) {
+                let info = RecvInfo {
+                    from: si.from,
+                    to: si.to,
+                };
+
+                client.recv(&mut out[..len], info).unwrap();
+
+                done = false;
+            }
+
+            if done {
+                return responses;
+            }
+        }
+    }
+
+    #[test]
+    fn accept() {
+        let mut listener = Listener::new(server_config());
+        let mut client = client();
+
+        let client_dcid = client.destination_id().into_owned();
+
+        assert_eq!(advance(&mut client, &mut listener), 0);
+
+        assert!(client.is_established());
+        assert_eq!(listener.len(), 1);
+
+        let (handle, conn) = listener.iter_mut().next().unwrap();
+        assert!(conn.is_established());
+
+        // The listener issued more Connection IDs, and routes packets sent to
+        // them.
+        assert!(client.available_dcids() > 0);
+        assert!(listener.ids.len() > 2);
+
+        // The Connection ID picked by the client was forgotten once the
+        // handshake was confirmed.
+        assert!(!listener.ids.contains_key(&client_dcid));
+        assert!(listener.client_dcids.is_empty());
+
+        client.stream_send(0, b"hello", true).unwrap();
+        advance(&mut client, &mut listener);
+
+        let conn = listener.get_mut(handle).unwrap();
+        let mut buf = [0; 5];
+        assert_eq!(conn.stream_recv(0, &mut buf), Ok((5, true)));
+
+        client.close(true, 0x0, b"").unwrap();
+        advance(&mut client, &mut listener);
+
+        assert!(listener.get(handle).unwrap().is_draining());
+    }
+
+    #[test]
+    fn remove() {
+        let mut listener = Listener::new(server_config());
+        let mut client = client();
+
+        advance(&mut client, &mut listener);
+
+        let (handle, _) = listener.iter_mut().next().unwrap();
+
+        // Connections can be removed before they are closed.
+        assert!(listener.remove(handle).is_some());
+        assert!(listener.remove(handle).is_none());
+
+        assert!(listener.is_empty());
+        assert!(listener.ids.is_empty());
+    }
+
+    #[test]
+    fn retry() {
+        let mut config = server_config();
+        config.set_token_provider(Arc::new(AeadTokenProvider::new(&[0; 32])));
+
+        let mut listener = Listener::new(config);
+        listener.enable_retry(true).unwrap();
+
+        let mut client = client();
+
+        assert_eq!(advance(&mut client, &mut listener), 1);
+
+        assert!(client.is_established());
+        assert_eq!(listener.len(), 1);
+    }
+
+    #[test]
+    fn retry_without_token_provider() {
+        let mut listener = Listener::new(server_config());
+        assert_eq!(listener.enable_retry(true), Err(Error::InvalidState));
+
+        // Clients are accepted without a Retry.
+        let mut client = client();
+
+        assert_eq!(advance(&mut client, &mut listener), 0);
+        assert!(client.is_established());
+    }
+
+    #[test]
+    fn initial_policy_handshake_load() {
+        let mut config = server_config();
+        config.set_token_provider(Arc::new(AeadTokenProvider::new(&[0; 32])));
+
+        let mut policy = RateLimitPolicy::new();
+        policy.set_retry_handshakes(Some(1));
+
+        let mut listener = Listener::new(config);
+        listener.set_initial_policy(Box::new(policy));
+
+        // The first client is accepted, and its handshake left pending.
+        let mut first = client();
+
+        let (mut pkt, si) = testing::emit_flight(&mut first).unwrap().remove(0);
+        let info = RecvInfo {
+            from: si.from,
+            to: si.to,
+        };
+
+        let mut out = [0; 1350];
+        assert_eq!(
+            listener.recv(&mut pkt, info, &mut out),
+            Ok(Received::Accepted(0))
+        );
+
+        let mut out = [0; 65535];
+
+        while let Ok((len, si)) = listener.send(&mut out) {
+            let info = RecvInfo {
+                from: si.from,
+                to: si.to,
+            };
+
+            first.recv(&mut out[..len], info).unwrap();
+        }
+
+        // The second one needs to go through a Retry.
+        let mut second = client();
+
+        assert_eq!(advance(&mut second, &mut listener), 1);
+        assert!(second.is_established());
+
+        assert_eq!(listener.stats(), ListenerStats {
+            accepted: 2,
+            retried: 1,
+            dropped: 0,
+        });
+    }
+
+    #[test]
+    fn initial_policy_source_rate() {
+        let mut policy = RateLimitPolicy::new();
+        policy.set_window(time::Duration::from_secs(3600));
+        policy.set_drop_source_rate(Some(1));
+
+        let mut listener = Listener::new(server_config());
+        listener.set_initial_policy(Box::new(policy));
+
+        let mut first = client();
+
+        assert_eq!(advance(&mut first, &mut listener), 0);
+        assert!(first.is_established());
+
+        // Both clients use the same address.
+        let mut second = client();
+
+        assert_eq!(advance(&mut second, &mut listener), 0);
+        assert!(!second.is_established());
+
+        assert_eq!(listener.len(), 1);
+        assert_eq!(listener.stats(), ListenerStats {
+            accepted: 1,
+            retried: 0,
+            dropped: 1,
+        });
+    }
+
+    #[test]
+    fn version_negotiation() {
+        let mut listener = Listener::new(server_config());
+
+        // An Initial packet with an unknown version, padded with zeros.
+        let mut pkt = vec![0xc0, 0xba, 0xba, 0xba, 0xba, 16];
+        pkt.extend_from_slice(&[0xba; 16]);
+        pkt.push(8);
+        pkt.extend_from_slice(&[0xbb; 8]);
+        pkt.resize(crate::MIN_CLIENT_INITIAL_LEN, 0);
+
+        let info = RecvInfo {
+            from: testing::Pipe::client_addr(),
+            to: testing::Pipe::server_addr(),
+        };
+
+        let mut out = [0; 1350];
+        let len = match listener.recv(&mut pkt, info, &mut out) {
+            Ok(Received::Respond(len)) => len,
+
+            r => panic!("unexpected result {:?}", r),
+        };
+
+        let hdr = packet::Header::from_slice(&mut out[..len], 8).unwrap();
+        assert_eq!(hdr.ty, packet::Type::VersionNegotiation);
+        assert_eq!(listener.len(), 0);
+
+        // Small Initials are dropped without a response.
+        let info = RecvInfo {
+            from: testing::Pipe::client_addr(),
+            to: testing::Pipe::server_addr(),
+        };
+
+        assert_eq!(
+            listener.recv(&mut pkt[..100], info, &mut out),
+            Ok(Received::Dropped)
+        );
+    }
+
+    #[test]
+    fn stateless_reset() {
+        let mut listener = Listener::new(server_config());
+        listener.set_stateless_reset_secret(b"secret");
+
+        let mut client = client();
+        advance(&mut client, &mut listener);
+
+        let (handle, conn) = listener.iter_mut().next().unwrap();
+        let scid = conn.source_id().into_owned();
+
+        // The server restarts, and loses all its state.
+        let mut listener = Listener::new(server_config());
+        listener.set_stateless_reset_secret(b"secret");
+        assert!(listener.get(handle).is_none());
+
+        let mut pkt = vec![0x40; 100];
+        pkt[1..1 + scid.len()].copy_from_slice(&scid);
+
+        let info = RecvInfo {
+            from: testing::Pipe::client_addr(),
+            to: testing::Pipe::server_addr(),
+        };
+
+        let mut out = [0; 1350];
+        let len = match listener.recv(&mut pkt, info, &mut out) {
+            Ok(Received::Respond(len)) => len,
+
+            r => panic!("unexpected result {:?}", r),
+        };
+
+        assert_eq!(len, MAX_STATELESS_RESET_LEN);
+        assert_eq!(out[0] & 0xc0, 0x40);
+
+        // The client recognizes the reset and closes the connection.
+        let info = RecvInfo {
+            from: testing::Pipe::server_addr(),
+            to: testing::Pipe::client_addr(),
+        };
+
+        client.recv(&mut out[..len], info).ok();
+        assert!(client.is_draining() || client.is_closed());
+
+        // Packets too small to be reset safely are dropped.
+        assert_eq!(
+            listener.recv(&mut pkt[..MIN_STATELESS_RESET_LEN], info, &mut out),
+            Ok(Received::Dropped)
+        );
+    }
+}
@@ -0,0 +1,170 @@
+// Copyright (C) 2023, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use std::cmp;
+use std::fs;
+use std::io;
+
+/// A region of a file that is lazily written to a stream.
+///
+/// Data is read from the file with positional reads, only as the stream's
+/// send capacity allows, so the whole region never needs to be buffered in
+/// memory at once.
+pub struct FileSource {
+    file: fs::File,
+
+    /// The file offset of the next byte to read.
+    off: u64,
+
+    /// The file offset right after the last byte of the region.
+    end: u64,
+
+    /// Whether the stream should be finished once the region is sent.
+    fin: bool,
+}
+
+impl FileSource {
+    pub fn new(file: fs::File, off: u64, len: u64, fin: bool) -> FileSource {
+        FileSource {
+            file,
+            off,
+            end: off.saturating_add(len),
+            fin,
+        }
+    }
+
+    /// Returns the number of bytes of the region left to send.
+    pub fn remaining(&self) -> u64 {
+        self.end - self.off
+    }
+
+    /// Returns whether the stream should be finished with the region.
+    pub fn fin(&self) -> bool {
+        self.fin
+    }
+
+    /// Reads up to `max` bytes from the current offset, without advancing it.
+    ///
+    /// Reaching the end of the file before the end of the region is
+    /// reported as an `UnexpectedEof` error.
+    pub fn read(&self, max: usize) -> io::Result<Vec<u8>> {
+        let len = cmp::min(self.remaining(), max as u64) as usize;
+
+        let mut buf = vec![0; len];
+        let mut done = 0;
+
+        while done < len {
+            match read_at(&self.file, &mut buf[done..], self.off + done as u64) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+
+                Ok(v) => done += v,
+
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(buf)
+    }
+
+    /// Marks `len` bytes as sent.
+    pub fn advance(&mut self, len: usize) {
+        self.off = cmp::min(self.off + len as u64, self.end);
+    }
+}
+
+#[cfg(unix)]
+fn read_at(file: &fs::File, buf: &mut [u8], off: u64) -> io::Result<usize> {
+    use std::os::unix::fs::FileExt;
+
+    file.read_at(buf, off)
+}
+
+#[cfg(windows)]
+fn read_at(file: &fs::File, buf: &mut [u8], off: u64) -> io::Result<usize> {
+    use std::os::windows::fs::FileExt;
+
+    file.seek_read(buf, off)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn read_at(file: &fs::File, buf: &mut [u8], off: u64) -> io::Result<usize> {
+    use std::io::Read;
+    use std::io::Seek;
+
+    let mut file = file;
+
+    file.seek(io::SeekFrom::Start(off))?;
+    file.read(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_region() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/examples/cert.crt");
+        let data = std::fs::read(path).unwrap();
+
+        let file = fs::File::open(path).unwrap();
+        let mut src = FileSource::new(file, 10, 100, true);
+
+        assert_eq!(src.remaining(), 100);
+        assert!(src.fin());
+
+        let buf = src.read(60).unwrap();
+        assert_eq!(&buf[..], &data[10..70]);
+
+        // Reading doesn't advance the offset.
+        assert_eq!(src.remaining(), 100);
+
+        src.advance(60);
+        assert_eq!(src.remaining(), 40);
+
+        let buf = src.read(60).unwrap();
+        assert_eq!(&buf[..], &data[70..110]);
+
+        src.advance(40);
+        assert_eq!(src.remaining(), 0);
+        assert!(src.read(60).unwrap().is_empty());
+    }
+
+    #[test]
+    fn read_past_eof() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/examples/cert.crt");
+        let len = std::fs::metadata(path).unwrap().len();
+
+        let file = fs::File::open(path).unwrap();
+        let src = FileSource::new(file, len - 10, 20, false);
+
+        assert_eq!(
+            src.read(20).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+}
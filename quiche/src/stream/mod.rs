@@ -27,10 +27,13 @@
 use std::cmp;
 
 use std::sync::Arc;
+use std::time;
 
 use std::collections::hash_map;
+use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::collections::VecDeque;
 
 use intrusive_collections::intrusive_adapter;
 use intrusive_collections::KeyAdapter;
@@ -44,12 +47,81 @@ use crate::Result;
 
 const DEFAULT_URGENCY: u8 = 127;
 
+/// The group streams belong to, unless assigned to a different one.
+pub const DEFAULT_GROUP: u64 = 0;
+
+/// The default scheduling weight of stream groups.
+pub const DEFAULT_GROUP_WEIGHT: u8 = 16;
+
+// The maximum number of STREAMS_BLOCKED events waiting to be reported.
+const MAX_BLOCKED_EVENTS: usize = 8;
+
+// The maximum number of reassembly pressure events waiting to be reported.
+const MAX_REASSEMBLY_EVENTS: usize = 8;
+
+/// How credit for new streams is given back to the peer.
+///
+/// As streams opened by the peer complete, the local stream count limits can
+/// be raised, which is advertised to the peer with MAX_STREAMS frames.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MaxStreamsPolicy {
+    /// The limits are raised as soon as any stream completes.
+    Immediate,
+
+    /// The limits are raised once the peer used up half of the available
+    /// credit (default).
+    #[default]
+    Batched,
+
+    /// The limits are raised at most once per the given interval, unless the
+    /// peer reports being blocked.
+    RateLimited(time::Duration),
+}
+
+/// The peer reported that it can't open new streams, because the local stream
+/// count limit was reached.
+///
+/// It is returned by [`streams_blocked_next()`].
+///
+/// [`streams_blocked_next()`]: struct.Connection.html#method.streams_blocked_next
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StreamsBlocked {
+    /// Whether the limit applies to bidirectional streams.
+    pub bidi: bool,
+
+    /// The stream count limit the peer is blocked at.
+    pub limit: u64,
+}
+
+/// A stream buffers too much out-of-order data, which pushed the connection
+/// over its reassembly memory limit.
+///
+/// It is returned by [`reassembly_pressure_next()`].
+///
+/// [`reassembly_pressure_next()`]: struct.Connection.html#method.reassembly_pressure_next
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReassemblyPressure {
+    /// The stream ID.
+    pub stream_id: u64,
+
+    /// The number of bytes the stream buffers out of order.
+    pub buffered: u64,
+
+    /// Whether the stream was shut down with a STOP_SENDING frame as a
+    /// result.
+    pub stopped: bool,
+}
+
 // The default size of the receiver stream flow control window.
 const DEFAULT_STREAM_WINDOW: u64 = 32 * 1024;
 
 /// The maximum size of the receiver stream flow control window.
 pub const MAX_STREAM_WINDOW: u64 = 16 * 1024 * 1024;
 
+// Scales the virtual time of stream groups, so weights are accounted for even
+// when little data is sent.
+const GROUP_VTIME_SCALE: u64 = 256;
+
 /// A simple no-op hasher for Stream IDs.
 ///
 /// The QUIC protocol and quiche library guarantees stream ID uniqueness, so
@@ -122,10 +194,14 @@ pub struct StreamMap {
     /// The total number of unidirectional streams opened by the local endpoint.
     local_opened_streams_uni: u64,
 
-    /// Queue of stream IDs corresponding to streams that have buffered data
-    /// ready to be sent to the peer. This also implies that the stream has
-    /// enough flow control credits to send at least some of that data.
-    flushable: RBTree<StreamFlushablePriorityAdapter>,
+    /// Map of stream groups indexed by group ID. Each group holds the queue of
+    /// its streams that have buffered data ready to be sent to the peer. This
+    /// also implies that the stream has enough flow control credits to send
+    /// at least some of that data.
+    groups: BTreeMap<u64, StreamGroup>,
+
+    /// The virtual time of the group that was last scheduled.
+    groups_vtime: u64,
 
     /// Set of stream IDs corresponding to streams that have outstanding data
     /// to read. This is used to generate a `StreamIter` of streams without
@@ -150,9 +226,15 @@ pub struct StreamMap {
     blocked: StreamIdHashMap<u64>,
 
     /// Set of stream IDs corresponding to streams that are reset. The value
-    /// of the map elements is a tuple of the error code and final size values
-    /// to include in the RESET_STREAM frame.
-    reset: StreamIdHashMap<(u64, u64)>,
+    /// of the map elements is a tuple of the error code, final size and
+    /// reliable size values to include in the RESET_STREAM frame (or in the
+    /// RESET_STREAM_AT frame, when the reliable size is not zero).
+    reset: StreamIdHashMap<(u64, u64, u64)>,
+
+    /// Set of stream IDs corresponding to streams that have a deadline. The
+    /// value of the map elements is a tuple of the deadline, and the reliable
+    /// size and error code to reset the stream with when it expires.
+    deadlines: StreamIdHashMap<(time::Instant, u64, u64)>,
 
     /// Set of stream IDs corresponding to streams that are shutdown on the
     /// receive side, and need to send a STOP_SENDING frame. The value of the
@@ -161,6 +243,53 @@ pub struct StreamMap {
 
     /// The maximum size of a stream window.
     max_stream_window: u64,
+
+    /// The low send watermark of new streams.
+    send_lowat: usize,
+
+    /// The maximum amount of unsent data buffered by new streams, if any.
+    send_max_buffered: Option<usize>,
+
+    /// How stream count credit is given back to the peer.
+    max_streams_policy: MaxStreamsPolicy,
+
+    /// When the local maximum bidirectional stream count limit was last
+    /// updated.
+    max_streams_bidi_updated: Option<time::Instant>,
+
+    /// When the local maximum unidirectional stream count limit was last
+    /// updated.
+    max_streams_uni_updated: Option<time::Instant>,
+
+    /// Whether the peer reported being blocked by the current local
+    /// bidirectional stream count limit.
+    peer_blocked_bidi: bool,
+
+    /// Whether the peer reported being blocked by the current local
+    /// unidirectional stream count limit.
+    peer_blocked_uni: bool,
+
+    /// Queue of STREAMS_BLOCKED events to report to the application.
+    blocked_events: VecDeque<StreamsBlocked>,
+
+    /// Map of stream IDs to the number of bytes they buffer out of order, for
+    /// streams that buffer any.
+    reassembly: StreamIdHashMap<u64>,
+
+    /// The maximum number of bytes buffered out of order across all streams,
+    /// if any.
+    max_reassembly: Option<u64>,
+
+    /// The error code to stop streams with when they exceed the reassembly
+    /// limit, if any.
+    reassembly_stop_error: Option<u64>,
+
+    /// Set of stream IDs that were already reported for exceeding the
+    /// reassembly limit.
+    reassembly_reported: StreamIdHashSet,
+
+    /// Queue of reassembly pressure events to report to the application.
+    reassembly_events: VecDeque<ReassemblyPressure>,
 }
 
 impl StreamMap {
@@ -176,10 +305,18 @@ impl StreamMap {
 
             max_stream_window,
 
+            send_lowat: 1,
+
             ..StreamMap::default()
         }
     }
 
+    /// Sets the send watermarks applied to new streams.
+    pub fn set_send_watermarks(&mut self, low: usize, high: Option<usize>) {
+        self.send_lowat = low;
+        self.send_max_buffered = high;
+    }
+
     /// Returns the stream with the given ID if it exists.
     pub fn get(&self, id: u64) -> Option<&Stream> {
         self.streams.get(&id)
@@ -298,7 +435,7 @@ impl StreamMap {
                     },
                 };
 
-                let s = Stream::new(
+                let mut s = Stream::new(
                     id,
                     max_rx_data,
                     max_tx_data,
@@ -307,6 +444,9 @@ impl StreamMap {
                     self.max_stream_window,
                 );
 
+                s.send_lowat = std::cmp::max(1, self.send_lowat);
+                s.send.set_max_buffered(self.send_max_buffered);
+
                 let is_writable = s.is_writable();
 
                 (v.insert(s), is_writable)
@@ -380,9 +520,19 @@ impl StreamMap {
     ///
     /// If the stream was already in the list, this does nothing.
     pub fn insert_flushable(&mut self, priority_key: &Arc<StreamPriorityKey>) {
-        if !priority_key.flushable.is_linked() {
-            self.flushable.insert(Arc::clone(priority_key));
+        if priority_key.flushable.is_linked() {
+            return;
         }
+
+        let group = self.groups.entry(priority_key.group).or_default();
+
+        // A group that becomes active again doesn't get credit for the time
+        // it was idle.
+        if group.flushable.is_empty() {
+            group.vtime = cmp::max(group.vtime, self.groups_vtime);
+        }
+
+        group.flushable.insert(Arc::clone(priority_key));
     }
 
     /// Removes the stream ID from the flushable streams set.
@@ -391,16 +541,84 @@ impl StreamMap {
             return;
         }
 
+        let group = match self.groups.get_mut(&priority_key.group) {
+            Some(v) => v,
+
+            None => return,
+        };
+
         let mut c = {
             let ptr = Arc::as_ptr(priority_key);
-            unsafe { self.flushable.cursor_mut_from_ptr(ptr) }
+            unsafe { group.flushable.cursor_mut_from_ptr(ptr) }
         };
 
         c.remove();
     }
 
+    /// Returns the next stream to send data from.
+    ///
+    /// Groups are scheduled using weighted fair queuing: the group with the
+    /// lowest virtual time is picked, and within that group streams are
+    /// ordered by priority.
     pub fn peek_flushable(&self) -> Option<Arc<StreamPriorityKey>> {
-        self.flushable.front().clone_pointer()
+        self.groups
+            .values()
+            .filter(|g| !g.flushable.is_empty())
+            .min_by_key(|g| g.vtime)
+            .and_then(|g| g.flushable.front().clone_pointer())
+    }
+
+    /// Charges `len` bytes sent on the wire to the given group.
+    pub fn on_group_data_sent(&mut self, group_id: u64, len: usize) {
+        if let Some(group) = self.groups.get_mut(&group_id) {
+            self.groups_vtime = group.vtime;
+
+            group.vtime += len as u64 * GROUP_VTIME_SCALE / group.weight as u64;
+        }
+    }
+
+    /// Charges `len` bytes written by the application to the given group's
+    /// send budget.
+    pub fn on_group_data_written(&mut self, group_id: u64, len: usize) {
+        self.groups.entry(group_id).or_default().tx_data += len as u64;
+    }
+
+    /// Sets the scheduling weight and send budget of a group.
+    pub fn set_group_params(
+        &mut self, group_id: u64, weight: u8, max_data: Option<u64>,
+    ) {
+        let group = self.groups.entry(group_id).or_default();
+
+        group.weight = cmp::max(weight, 1);
+        group.max_data = max_data;
+
+        if group.cap() == 0 {
+            return;
+        }
+
+        // Streams that were blocked by the group's budget can be written to
+        // again.
+        let keys: Vec<_> = self
+            .streams
+            .values()
+            .filter(|s| s.priority_key.group == group_id && s.is_writable())
+            .map(|s| Arc::clone(&s.priority_key))
+            .collect();
+
+        for priority_key in keys {
+            self.insert_writable(&priority_key);
+        }
+    }
+
+    /// Returns how much more data can be written to streams in the group of
+    /// the given stream.
+    pub fn group_cap(&self, stream_id: u64) -> usize {
+        let group_id = self
+            .streams
+            .get(&stream_id)
+            .map_or(DEFAULT_GROUP, |s| s.priority_key.group);
+
+        self.groups.get(&group_id).map_or(usize::MAX, |g| g.cap())
     }
 
     /// Updates the priorities of a stream.
@@ -419,7 +637,7 @@ impl StreamMap {
 
         if old.flushable.is_linked() {
             self.remove_flushable(old);
-            self.flushable.insert(Arc::clone(new));
+            self.insert_flushable(new);
         }
     }
 
@@ -449,13 +667,15 @@ impl StreamMap {
     }
 
     /// Adds the stream ID to the reset streams set with the
-    /// given error code and final size values.
+    /// given error code, final size and reliable size values.
     ///
     /// If the stream was already in the list, this does nothing.
     pub fn insert_reset(
         &mut self, stream_id: u64, error_code: u64, final_size: u64,
+        reliable_size: u64,
     ) {
-        self.reset.insert(stream_id, (error_code, final_size));
+        self.reset
+            .insert(stream_id, (error_code, final_size, reliable_size));
     }
 
     /// Removes the stream ID from the reset streams set.
@@ -487,8 +707,10 @@ impl StreamMap {
     }
 
     /// Commits the new max_streams_bidi limit.
-    pub fn update_max_streams_bidi(&mut self) {
+    pub fn update_max_streams_bidi(&mut self, now: time::Instant) {
         self.local_max_streams_bidi = self.local_max_streams_bidi_next;
+        self.max_streams_bidi_updated = Some(now);
+        self.peer_blocked_bidi = false;
     }
 
     /// Returns the current max_streams_bidi limit.
@@ -502,8 +724,10 @@ impl StreamMap {
     }
 
     /// Commits the new max_streams_uni limit.
-    pub fn update_max_streams_uni(&mut self) {
+    pub fn update_max_streams_uni(&mut self, now: time::Instant) {
         self.local_max_streams_uni = self.local_max_streams_uni_next;
+        self.max_streams_uni_updated = Some(now);
+        self.peer_blocked_uni = false;
     }
 
     /// Returns the new max_streams_uni limit.
@@ -523,6 +747,140 @@ impl StreamMap {
         self.peer_max_streams_uni - self.local_opened_streams_uni
     }
 
+    /// Returns the number of bidirectional streams the peer can create before
+    /// the local stream count limit is reached.
+    pub fn local_streams_left_bidi(&self) -> u64 {
+        self.local_max_streams_bidi - self.peer_opened_streams_bidi
+    }
+
+    /// Returns the number of unidirectional streams the peer can create
+    /// before the local stream count limit is reached.
+    pub fn local_streams_left_uni(&self) -> u64 {
+        self.local_max_streams_uni - self.peer_opened_streams_uni
+    }
+
+    /// Sets how stream count credit is given back to the peer.
+    pub fn set_max_streams_policy(&mut self, policy: MaxStreamsPolicy) {
+        self.max_streams_policy = policy;
+    }
+
+    /// Sets the reassembly memory limit, and the error code to stop streams
+    /// exceeding it with.
+    pub fn set_reassembly_limit(
+        &mut self, max: Option<u64>, stop_error: Option<u64>,
+    ) {
+        self.max_reassembly = max;
+        self.reassembly_stop_error = stop_error;
+    }
+
+    /// Returns the number of bytes buffered out of order across all streams.
+    pub fn reassembly_len(&self) -> u64 {
+        self.reassembly.values().sum()
+    }
+
+    /// Records the number of bytes the given stream buffers out of order.
+    ///
+    /// When this pushes the connection over the reassembly limit, the stream
+    /// is reported to the application, and the error code to stop it with is
+    /// returned, if any.
+    pub fn update_reassembly(&mut self, stream_id: u64, len: u64) -> Option<u64> {
+        if len == 0 {
+            self.reassembly.remove(&stream_id);
+            self.reassembly_reported.remove(&stream_id);
+
+            return None;
+        }
+
+        self.reassembly.insert(stream_id, len);
+
+        let max = self.max_reassembly?;
+
+        if self.reassembly_len() <= max {
+            return None;
+        }
+
+        let stop_error = self.reassembly_stop_error;
+
+        if self.reassembly_reported.insert(stream_id) {
+            if self.reassembly_events.len() >= MAX_REASSEMBLY_EVENTS {
+                self.reassembly_events.pop_front();
+            }
+
+            self.reassembly_events.push_back(ReassemblyPressure {
+                stream_id,
+                buffered: len,
+                stopped: stop_error.is_some(),
+            });
+        }
+
+        stop_error
+    }
+
+    /// Returns the next reassembly pressure event to report to the
+    /// application.
+    pub fn pop_reassembly_event(&mut self) -> Option<ReassemblyPressure> {
+        self.reassembly_events.pop_front()
+    }
+
+    /// Records that the peer is blocked by the given stream count limit.
+    ///
+    /// Limits lower than the current local limit are stale and ignored.
+    pub fn on_peer_streams_blocked(&mut self, bidi: bool, limit: u64) {
+        let (max, blocked) = if bidi {
+            (self.local_max_streams_bidi, &mut self.peer_blocked_bidi)
+        } else {
+            (self.local_max_streams_uni, &mut self.peer_blocked_uni)
+        };
+
+        if limit < max || *blocked {
+            return;
+        }
+
+        *blocked = true;
+
+        if self.blocked_events.len() >= MAX_BLOCKED_EVENTS {
+            self.blocked_events.pop_front();
+        }
+
+        self.blocked_events
+            .push_back(StreamsBlocked { bidi, limit });
+    }
+
+    /// Returns the next STREAMS_BLOCKED event to report to the application.
+    pub fn pop_blocked_event(&mut self) -> Option<StreamsBlocked> {
+        self.blocked_events.pop_front()
+    }
+
+    /// Returns when the local stream count limits need to be updated, if the
+    /// update is being delayed.
+    pub fn max_streams_timer(&self) -> Option<time::Instant> {
+        let interval = match self.max_streams_policy {
+            MaxStreamsPolicy::RateLimited(v) => v,
+
+            _ => return None,
+        };
+
+        let bidi = self
+            .max_streams_bidi_updated
+            .filter(|_| {
+                self.local_max_streams_bidi_next != self.local_max_streams_bidi
+            })
+            .map(|t| t + interval);
+
+        let uni = self
+            .max_streams_uni_updated
+            .filter(|_| {
+                self.local_max_streams_uni_next != self.local_max_streams_uni
+            })
+            .map(|t| t + interval);
+
+        match (bidi, uni) {
+            (Some(b), Some(u)) => Some(cmp::min(b, u)),
+
+            (b, u) => b.or(u),
+        }
+    }
+
     /// Drops completed stream.
     ///
     /// This should only be called when Stream::is_complete() returns true for
@@ -548,6 +906,10 @@ impl StreamMap {
 
         self.remove_flushable(&s.priority_key);
 
+        self.remove_deadline(stream_id);
+
+        self.update_reassembly(stream_id, 0);
+
         self.collected.insert(stream_id);
     }
 
@@ -567,6 +929,44 @@ impl StreamMap {
         }
     }
 
+    /// Creates an iterator over streams that can be written to, along with how
+    /// much data each of them can accept, up to `conn_cap`.
+    ///
+    /// Streams that can't accept at least their low send watermark are
+    /// skipped, except for stopped streams, which are reported with a
+    /// capacity of zero so the application can learn about it by writing.
+    pub fn writable_capacity(&self, conn_cap: usize) -> StreamCapacityIter {
+        let streams = self
+            .writable
+            .iter()
+            .filter_map(|s| {
+                let stream = self.streams.get(&s.id)?;
+
+                let cap = match stream.send.cap() {
+                    Ok(v) => v,
+
+                    Err(_) => return Some((s.id, 0)),
+                };
+
+                let group_cap =
+                    self.groups.get(&s.group).map_or(usize::MAX, |g| g.cap());
+
+                let cap = cap
+                    .min(stream.send.buffer_cap())
+                    .min(group_cap)
+                    .min(conn_cap);
+
+                if cap < stream.send_lowat {
+                    return None;
+                }
+
+                Some((s.id, cap))
+            })
+            .collect();
+
+        StreamCapacityIter { streams, index: 0 }
+    }
+
     /// Creates an iterator over streams that need to send MAX_STREAM_DATA.
     pub fn almost_full(&self) -> StreamIter {
         StreamIter::from(&self.almost_full)
@@ -578,10 +978,53 @@ impl StreamMap {
     }
 
     /// Creates an iterator over streams that need to send RESET_STREAM.
-    pub fn reset(&self) -> hash_map::Iter<u64, (u64, u64)> {
+    pub fn reset(&self) -> hash_map::Iter<u64, (u64, u64, u64)> {
         self.reset.iter()
     }
 
+    /// Sets the deadline of the given stream, replacing any previous one.
+    pub fn insert_deadline(
+        &mut self, stream_id: u64, deadline: time::Instant, reliable_size: u64,
+        error_code: u64,
+    ) {
+        self.deadlines
+            .insert(stream_id, (deadline, reliable_size, error_code));
+    }
+
+    /// Removes the deadline of the given stream.
+    pub fn remove_deadline(&mut self, stream_id: u64) {
+        self.deadlines.remove(&stream_id);
+    }
+
+    /// Returns the earliest stream deadline, if any.
+    pub fn next_deadline(&self) -> Option<time::Instant> {
+        self.deadlines
+            .values()
+            .map(|&(deadline, ..)| deadline)
+            .min()
+    }
+
+    /// Removes the deadlines that expired at `now`, and returns the
+    /// corresponding stream IDs along with their reliable size and error code.
+    pub fn expired_deadlines(
+        &mut self, now: time::Instant,
+    ) -> Vec<(u64, u64, u64)> {
+        let expired: Vec<(u64, u64, u64)> = self
+            .deadlines
+            .iter()
+            .filter(|(_, &(deadline, ..))| deadline <= now)
+            .map(|(&id, &(_, reliable_size, error_code))| {
+                (id, reliable_size, error_code)
+            })
+            .collect();
+
+        for (id, ..) in &expired {
+            self.deadlines.remove(id);
+        }
+
+        expired
+    }
+
     /// Creates an iterator over streams that need to send STOP_SENDING.
     pub fn stopped(&self) -> hash_map::Iter<u64, u64> {
         self.stopped.iter()
@@ -594,7 +1037,7 @@ impl StreamMap {
 
     /// Returns true if there are any streams that have data to write.
     pub fn has_flushable(&self) -> bool {
-        !self.flushable.is_empty()
+        self.groups.values().any(|g| !g.flushable.is_empty())
     }
 
     /// Returns true if there are any streams that have data to read.
@@ -625,18 +1068,152 @@ impl StreamMap {
 
     /// Returns true if the max bidirectional streams count needs to be updated
     /// by sending a MAX_STREAMS frame to the peer.
-    pub fn should_update_max_streams_bidi(&self) -> bool {
-        self.local_max_streams_bidi_next != self.local_max_streams_bidi &&
-            self.local_max_streams_bidi_next / 2 >
-                self.local_max_streams_bidi - self.peer_opened_streams_bidi
+    pub fn should_update_max_streams_bidi(&self, now: time::Instant) -> bool {
+        self.should_update_max_streams(
+            self.local_max_streams_bidi,
+            self.local_max_streams_bidi_next,
+            self.peer_opened_streams_bidi,
+            self.max_streams_bidi_updated,
+            self.peer_blocked_bidi,
+            now,
+        )
     }
 
     /// Returns true if the max unidirectional streams count needs to be updated
     /// by sending a MAX_STREAMS frame to the peer.
-    pub fn should_update_max_streams_uni(&self) -> bool {
-        self.local_max_streams_uni_next != self.local_max_streams_uni &&
-            self.local_max_streams_uni_next / 2 >
-                self.local_max_streams_uni - self.peer_opened_streams_uni
+    pub fn should_update_max_streams_uni(&self, now: time::Instant) -> bool {
+        self.should_update_max_streams(
+            self.local_max_streams_uni,
+            self.local_max_streams_uni_next,
+            self.peer_opened_streams_uni,
+            self.max_streams_uni_updated,
+            self.peer_blocked_uni,
+            now,
+        )
+    }
+
+    fn should_update_max_streams(
+        &self, max: u64, next: u64, opened: u64, updated: Option<time::Instant>,
+        peer_blocked: bool, now: time::Instant,
+    ) -> bool {
+        if next == max {
+            return false;
+        }
+
+        match self.max_streams_policy {
+            MaxStreamsPolicy::Immediate => true,
+
+            MaxStreamsPolicy::Batched => next / 2 > max - opened,
+
+            MaxStreamsPolicy::RateLimited(interval) =>
+                peer_blocked || updated.map_or(true, |t| now >= t + interval),
+        }
+    }
+
+    /// Serializes the stream limits and the state of all streams, for
+    /// freezing the connection.
+    ///
+    /// If any stream has data or frames pending, [`InvalidState`] is
+    /// returned.
+    ///
+    /// [`InvalidState`]: ../enum.Error.html#variant.InvalidState
+    pub fn freeze(&self, b: &mut octets::OctetsMut) -> Result<()> {
+        if self.has_flushable() ||
+            self.has_readable() ||
+            self.has_almost_full() ||
+            self.has_blocked() ||
+            self.has_reset() ||
+            self.has_stopped() ||
+            !self.deadlines.is_empty()
+        {
+            return Err(Error::InvalidState);
+        }
+
+        b.put_varint(self.peer_max_streams_bidi)?;
+        b.put_varint(self.peer_max_streams_uni)?;
+        b.put_varint(self.peer_opened_streams_bidi)?;
+        b.put_varint(self.peer_opened_streams_uni)?;
+        b.put_varint(self.local_max_streams_bidi)?;
+        b.put_varint(self.local_max_streams_bidi_next)?;
+        b.put_varint(self.local_max_streams_uni)?;
+        b.put_varint(self.local_max_streams_uni_next)?;
+        b.put_varint(self.local_opened_streams_bidi)?;
+        b.put_varint(self.local_opened_streams_uni)?;
+
+        b.put_varint(self.collected.len() as u64)?;
+
+        for id in &self.collected {
+            b.put_varint(*id)?;
+        }
+
+        b.put_varint(self.streams.len() as u64)?;
+
+        for (id, stream) in &self.streams {
+            b.put_varint(*id)?;
+            b.put_u8(stream.local as u8)?;
+            b.put_u8(stream.urgency)?;
+            b.put_u8(stream.incremental as u8)?;
+            b.put_varint(stream.priority_key.group)?;
+
+            stream.recv.freeze(b)?;
+            stream.send.freeze(b)?;
+        }
+
+        Ok(())
+    }
+
+    /// Restores the stream limits and streams serialized by [`freeze()`].
+    ///
+    /// This must only be called on a newly created map.
+    ///
+    /// [`freeze()`]: struct.StreamMap.html#method.freeze
+    pub fn thaw(&mut self, b: &mut octets::Octets) -> Result<()> {
+        self.peer_max_streams_bidi = b.get_varint()?;
+        self.peer_max_streams_uni = b.get_varint()?;
+        self.peer_opened_streams_bidi = b.get_varint()?;
+        self.peer_opened_streams_uni = b.get_varint()?;
+        self.local_max_streams_bidi = b.get_varint()?;
+        self.local_max_streams_bidi_next = b.get_varint()?;
+        self.local_max_streams_uni = b.get_varint()?;
+        self.local_max_streams_uni_next = b.get_varint()?;
+        self.local_opened_streams_bidi = b.get_varint()?;
+        self.local_opened_streams_uni = b.get_varint()?;
+
+        for _ in 0..b.get_varint()? {
+            self.collected.insert(b.get_varint()?);
+        }
+
+        for _ in 0..b.get_varint()? {
+            let id = b.get_varint()?;
+            let local = b.get_u8()? != 0;
+
+            let mut s =
+                Stream::new(id, 0, 0, is_bidi(id), local, self.max_stream_window);
+
+            s.urgency = b.get_u8()?;
+            s.incremental = b.get_u8()? != 0;
+            s.priority_key = Arc::new(StreamPriorityKey {
+                urgency: s.urgency,
+                incremental: s.incremental,
+                group: b.get_varint()?,
+                id,
+                ..Default::default()
+            });
+
+            s.recv.thaw(b, self.max_stream_window)?;
+            s.send.thaw(b)?;
+
+            s.send_lowat = cmp::max(1, self.send_lowat);
+            s.send.set_max_buffered(self.send_max_buffered);
+
+            if s.is_writable() {
+                self.writable.insert(Arc::clone(&s.priority_key));
+            }
+
+            self.streams.insert(id, s);
+        }
+
+        Ok(())
     }
 
     /// Returns the number of active streams in the map.
@@ -705,7 +1282,8 @@ impl Stream {
         !self.send.is_shutdown() &&
             !self.send.is_fin() &&
             (self.send.off_back() + self.send_lowat as u64) <
-                self.send.max_off()
+                self.send.max_off() &&
+            self.send.buffer_cap() >= self.send_lowat
     }
 
     /// Returns true if the stream has data to send and is allowed to send at
@@ -754,10 +1332,56 @@ pub fn is_bidi(stream_id: u64) -> bool {
     (stream_id & 0x2) == 0
 }
 
+/// A group of streams, scheduled against other groups based on its weight and
+/// sharing a send budget.
+struct StreamGroup {
+    /// The group's share of the connection, relative to other groups.
+    weight: u8,
+
+    /// The maximum amount of data that can be written to the group's
+    /// streams, if any.
+    max_data: Option<u64>,
+
+    /// The amount of data written to the group's streams.
+    tx_data: u64,
+
+    /// The virtual time at which the group's next data will be sent.
+    vtime: u64,
+
+    /// Queue of the group's streams that have data to send.
+    flushable: RBTree<StreamFlushablePriorityAdapter>,
+}
+
+impl Default for StreamGroup {
+    fn default() -> Self {
+        StreamGroup {
+            weight: DEFAULT_GROUP_WEIGHT,
+            max_data: None,
+            tx_data: 0,
+            vtime: 0,
+            flushable: Default::default(),
+        }
+    }
+}
+
+impl StreamGroup {
+    /// Returns how much more data can be written to the group's streams.
+    fn cap(&self) -> usize {
+        match self.max_data {
+            Some(max) =>
+                cmp::min(max.saturating_sub(self.tx_data), usize::MAX as u64)
+                    as usize,
+
+            None => usize::MAX,
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct StreamPriorityKey {
     pub urgency: u8,
     pub incremental: bool,
+    pub group: u64,
     pub id: u64,
 
     pub readable: RBTreeAtomicLink,
@@ -770,6 +1394,7 @@ impl Default for StreamPriorityKey {
         Self {
             urgency: DEFAULT_URGENCY,
             incremental: true,
+            group: DEFAULT_GROUP,
             id: Default::default(),
             readable: Default::default(),
             writable: Default::default(),
@@ -893,6 +1518,34 @@ impl ExactSizeIterator for StreamIter {
     }
 }
 
+/// An iterator over writable QUIC streams and their send capacity.
+///
+/// Each item is a stream ID, along with the number of bytes that can be
+/// written to the stream.
+#[derive(Default)]
+pub struct StreamCapacityIter {
+    streams: SmallVec<[(u64, usize); 8]>,
+    index: usize,
+}
+
+impl Iterator for StreamCapacityIter {
+    type Item = (u64, usize);
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        let v = self.streams.get(self.index)?;
+        self.index += 1;
+        Some(*v)
+    }
+}
+
+impl ExactSizeIterator for StreamCapacityIter {
+    #[inline]
+    fn len(&self) -> usize {
+        self.streams.len() - self.index
+    }
+}
+
 /// Buffer holding data at a specific offset.
 ///
 /// The data is stored in a `Vec<u8>` in such a way that it can be shared
@@ -945,6 +1598,36 @@ impl RangeBuf {
         }
     }
 
+    /// Creates a new `RangeBuf` that takes ownership of the given vector.
+    pub fn from_vec(buf: Vec<u8>) -> RangeBuf {
+        RangeBuf::from_shared(Arc::new(buf))
+    }
+
+    /// Creates a new `RangeBuf` that shares the given vector's memory.
+    pub fn from_shared(buf: Arc<Vec<u8>>) -> RangeBuf {
+        RangeBuf {
+            len: buf.len(),
+            data: buf,
+            start: 0,
+            pos: 0,
+            off: 0,
+            fin: false,
+        }
+    }
+
+    /// Returns a `RangeBuf` sharing the memory of the remaining data of
+    /// `self`, at the given stream offset.
+    pub fn rebase(&self, off: u64, fin: bool) -> RangeBuf {
+        RangeBuf {
+            data: self.data.clone(),
+            start: self.pos,
+            pos: self.pos,
+            len: self.len(),
+            off,
+            fin,
+        }
+    }
+
     /// Returns whether `self` holds the final offset in the stream.
     pub fn fin(&self) -> bool {
         self.fin
@@ -952,7 +1635,7 @@ impl RangeBuf {
 
     /// Returns the starting offset of `self`.
     pub fn off(&self) -> u64 {
-        (self.off - self.start as u64) + self.pos as u64
+        self.off + (self.pos - self.start) as u64
     }
 
     /// Returns the final offset of `self`.
@@ -1001,6 +1684,139 @@ impl RangeBuf {
     }
 }
 
+/// A pool of buffers whose memory is reused to hold stream and crypto data.
+///
+/// Buffers are taken from the pool when data is received from the peer or
+/// queued by TLS, and given back once the data was read or acknowledged, so
+/// that the steady state doesn't need to allocate.
+#[derive(Default)]
+pub struct BufPool {
+    /// The buffers available for reuse.
+    bufs: Vec<Vec<u8>>,
+
+    /// The maximum number of buffers kept in the pool.
+    capacity: usize,
+}
+
+impl BufPool {
+    /// Creates a pool keeping up to `capacity` buffers.
+    pub fn new(capacity: usize) -> BufPool {
+        BufPool {
+            bufs: Vec::new(),
+            capacity,
+        }
+    }
+
+    /// Creates a `RangeBuf` holding a copy of `data`, reusing the memory of a
+    /// pooled buffer if there is one.
+    pub fn range_buf(&mut self, data: &[u8], off: u64, fin: bool) -> RangeBuf {
+        let mut buf = self.bufs.pop().unwrap_or_default();
+        buf.extend_from_slice(data);
+
+        RangeBuf {
+            len: buf.len(),
+            data: Arc::new(buf),
+            start: 0,
+            pos: 0,
+            off,
+            fin,
+        }
+    }
+
+    /// Gives the memory of `buf` back to the pool, unless it is still shared
+    /// with another `RangeBuf`.
+    pub fn recycle(&mut self, buf: RangeBuf) {
+        if self.bufs.len() >= self.capacity {
+            return;
+        }
+
+        if let Ok(mut data) = Arc::try_unwrap(buf.data) {
+            data.clear();
+
+            self.bufs.push(data);
+        }
+    }
+
+    /// Returns the number of buffers available for reuse.
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.bufs.len()
+    }
+}
+
+/// A chunk of stream data read without copying.
+///
+/// It is returned by [`stream_recv_buf()`], and shares its memory with the
+/// connection's receive buffers.
+///
+/// [`stream_recv_buf()`]: struct.Connection.html#method.stream_recv_buf
+#[derive(Clone, Debug)]
+pub struct StreamBuf(RangeBuf);
+
+impl StreamBuf {
+    /// Returns the offset of the data within the stream.
+    pub fn off(&self) -> u64 {
+        self.0.off()
+    }
+
+    /// Returns a buffer sharing the memory of the first `len` bytes.
+    pub(crate) fn slice(&self, len: usize) -> RangeBuf {
+        let mut buf = self.0.rebase(0, false);
+        buf.split_off(len);
+
+        buf
+    }
+
+    /// Consumes the first `count` bytes.
+    pub(crate) fn consume(&mut self, count: usize) {
+        self.0.consume(count);
+    }
+}
+
+impl From<Vec<u8>> for StreamBuf {
+    fn from(buf: Vec<u8>) -> Self {
+        StreamBuf(RangeBuf::from_vec(buf))
+    }
+}
+
+impl From<Arc<Vec<u8>>> for StreamBuf {
+    fn from(buf: Arc<Vec<u8>>) -> Self {
+        StreamBuf(RangeBuf::from_shared(buf))
+    }
+}
+
+impl From<RangeBuf> for StreamBuf {
+    fn from(buf: RangeBuf) -> Self {
+        StreamBuf(buf)
+    }
+}
+
+/// The receive-side offsets of a stream.
+///
+/// It is returned by [`stream_recv_offsets()`].
+///
+/// [`stream_recv_offsets()`]: struct.Connection.html#method.stream_recv_offsets
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StreamRecvOffsets {
+    /// The offset up to which all data has been received.
+    pub contiguous: u64,
+
+    /// The largest offset of the data received.
+    pub highest: u64,
+
+    /// The ranges of data between `contiguous` and `highest` that haven't been
+    /// received yet, in ascending order.
+    pub gaps: Vec<std::ops::Range<u64>>,
+}
+
+impl std::ops::Deref for StreamBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
 impl std::ops::Deref for RangeBuf {
     type Target = [u8];
 
@@ -2215,7 +3031,174 @@ mod tests {
             prioritized_writable.iter().map(|s| s.id).collect();
         assert_eq!(walk_2, vec![0, 0, 4, 4, 8, 8, 12, 12]);
     }
+
+    #[test]
+    fn max_streams_policy() {
+        let local_tp = crate::TransportParams::default();
+        let peer_tp = crate::TransportParams::default();
+
+        let now = time::Instant::now();
+
+        let mut streams = StreamMap::new(10, 10, 100);
+
+        for id in [0, 4] {
+            streams
+                .get_or_create(id, &local_tp, &peer_tp, false, true)
+                .unwrap();
+        }
+
+        assert_eq!(streams.local_streams_left_bidi(), 8);
+        assert_eq!(streams.local_streams_left_uni(), 10);
+
+        streams.collect(0, false);
+
+        // Not enough credit was used to send a batched update.
+        assert!(!streams.should_update_max_streams_bidi(now));
+
+        streams.set_max_streams_policy(MaxStreamsPolicy::Immediate);
+        assert!(streams.should_update_max_streams_bidi(now));
+
+        let interval = time::Duration::from_secs(1);
+        streams.set_max_streams_policy(MaxStreamsPolicy::RateLimited(interval));
+        assert!(streams.should_update_max_streams_bidi(now));
+        assert_eq!(streams.max_streams_timer(), None);
+
+        streams.update_max_streams_bidi(now);
+        assert_eq!(streams.local_streams_left_bidi(), 9);
+        assert!(!streams.should_update_max_streams_bidi(now));
+
+        // More credit is available, but the update is delayed.
+        streams.collect(4, false);
+        assert!(!streams.should_update_max_streams_bidi(now));
+        assert_eq!(streams.max_streams_timer(), Some(now + interval));
+        assert!(streams.should_update_max_streams_bidi(now + interval));
+
+        // Stale limits are ignored.
+        streams.on_peer_streams_blocked(true, 10);
+        assert_eq!(streams.pop_blocked_event(), None);
+        assert!(!streams.should_update_max_streams_bidi(now));
+
+        // The peer being blocked bypasses the rate limit.
+        streams.on_peer_streams_blocked(true, 11);
+        streams.on_peer_streams_blocked(true, 11);
+        assert!(streams.should_update_max_streams_bidi(now));
+
+        assert_eq!(
+            streams.pop_blocked_event(),
+            Some(StreamsBlocked {
+                bidi: true,
+                limit: 11
+            })
+        );
+        assert_eq!(streams.pop_blocked_event(), None);
+
+        streams.update_max_streams_bidi(now);
+        assert_eq!(streams.local_streams_left_bidi(), 10);
+        assert_eq!(streams.max_streams_timer(), None);
+    }
+
+    #[test]
+    fn flushable_groups_weighted() {
+        let local_tp = crate::TransportParams::default();
+        let peer_tp = crate::TransportParams {
+            initial_max_stream_data_bidi_local: 100,
+            initial_max_stream_data_uni: 100,
+            ..Default::default()
+        };
+
+        let mut streams = StreamMap::new(100, 100, 100);
+
+        streams.set_group_params(1, 1, None);
+        streams.set_group_params(2, 4, None);
+
+        for (id, group) in [(0, 1), (4, 2)] {
+            let stream = streams
+                .get_or_create(id, &local_tp, &peer_tp, false, true)
+                .unwrap();
+
+            let new_priority_key = Arc::new(StreamPriorityKey {
+                group,
+                id,
+                ..Default::default()
+            });
+
+            let old_priority_key = std::mem::replace(
+                &mut stream.priority_key,
+                new_priority_key.clone(),
+            );
+
+            streams.update_priority(&old_priority_key, &new_priority_key);
+            streams.insert_flushable(&new_priority_key);
+        }
+
+        let mut sent = [0; 2];
+
+        for _ in 0..50 {
+            let priority_key = streams.peek_flushable().unwrap();
+
+            sent[priority_key.group as usize - 1] += 1;
+
+            streams.on_group_data_sent(priority_key.group, 1000);
+        }
+
+        // Group 2 gets four times the share of group 1.
+        assert_eq!(sent, [10, 40]);
+
+        // Once group 2 has nothing left to send, group 1 gets everything.
+        let priority_key = streams.get(4).unwrap().priority_key.clone();
+        streams.remove_flushable(&priority_key);
+
+        assert_eq!(streams.peek_flushable().unwrap().id, 0);
+
+        // The group doesn't get credit for the time it was idle.
+        for _ in 0..10 {
+            streams.on_group_data_sent(1, 1000);
+        }
+
+        streams.insert_flushable(&priority_key);
+
+        let mut burst = 0;
+
+        while streams.peek_flushable().unwrap().id == 4 {
+            streams.on_group_data_sent(2, 1000);
+            burst += 1;
+        }
+
+        assert_eq!(burst, 4);
+    }
+
+    #[test]
+    fn buf_pool() {
+        let mut pool = BufPool::new(1);
+
+        let mut buf = pool.range_buf(b"helloworld", 5, true);
+        assert_eq!(&buf[..], b"helloworld");
+        assert_eq!(buf.off(), 5);
+        assert!(buf.fin());
+
+        // Buffers sharing their memory with others aren't recycled.
+        let second = buf.split_off(5);
+
+        pool.recycle(buf);
+        assert_eq!(pool.len(), 0);
+
+        pool.recycle(second);
+        assert_eq!(pool.len(), 1);
+
+        // The pooled memory is reused.
+        let buf = pool.range_buf(b"hello", 0, false);
+        assert_eq!(&buf[..], b"hello");
+        assert_eq!(pool.len(), 0);
+
+        // No more than `capacity` buffers are kept.
+        pool.recycle(buf);
+        pool.recycle(RangeBuf::from(b"world", 0, false));
+        assert_eq!(pool.len(), 1);
+    }
 }
 
+pub use file::FileSource;
+
+mod file;
 mod recv_buf;
 mod send_buf;
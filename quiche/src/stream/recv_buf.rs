@@ -35,6 +35,7 @@ use crate::Result;
 
 use crate::flowcontrol;
 
+use super::BufPool;
 use super::RangeBuf;
 use super::DEFAULT_STREAM_WINDOW;
 
@@ -64,6 +65,10 @@ pub struct RecvBuf {
     /// The error code received via RESET_STREAM.
     error: Option<u64>,
 
+    /// The error code and reliable size received via RESET_STREAM_AT, until
+    /// the application has read all the data up to the reliable size.
+    reset_at: Option<(u64, u64)>,
+
     /// Whether incoming data is validated but not buffered.
     drain: bool,
 }
@@ -86,7 +91,7 @@ impl RecvBuf {
     /// This also takes care of enforcing stream flow control limits, as well
     /// as handling incoming data that overlaps data that is already in the
     /// buffer.
-    pub fn write(&mut self, buf: RangeBuf) -> Result<()> {
+    pub fn write(&mut self, mut buf: RangeBuf) -> Result<()> {
         if buf.max_off() > self.max_data() {
             return Err(Error::FlowControl);
         }
@@ -118,6 +123,18 @@ impl RecvBuf {
             self.fin_off = Some(buf.max_off());
         }
 
+        // Data past the reliable size of a RESET_STREAM_AT frame is not
+        // delivered to the application.
+        if let Some((_, reliable_size)) = self.reset_at {
+            if buf.off() >= reliable_size {
+                return Ok(());
+            }
+
+            if buf.max_off() > reliable_size {
+                buf.split_off((reliable_size - buf.off()) as usize);
+            }
+        }
+
         // No need to store empty buffer that doesn't carry the fin flag.
         if !buf.fin() && buf.is_empty() {
             return Ok(());
@@ -202,6 +219,17 @@ impl RecvBuf {
     /// On success the amount of data read, and a flag indicating if there is
     /// no more data in the buffer, are returned as a tuple.
     pub fn emit(&mut self, out: &mut [u8]) -> Result<(usize, bool)> {
+        self.emit_pooled(out, &mut BufPool::default())
+    }
+
+    /// Writes data from the receive buffer into the given output buffer, like
+    /// [`emit()`], giving the memory of the buffers that were fully read back
+    /// to `pool`.
+    ///
+    /// [`emit()`]: struct.RecvBuf.html#method.emit
+    pub fn emit_pooled(
+        &mut self, out: &mut [u8], pool: &mut BufPool,
+    ) -> Result<(usize, bool)> {
         let mut len = 0;
         let mut cap = out.len();
 
@@ -211,7 +239,7 @@ impl RecvBuf {
 
         // The stream was reset, so clear its data and return the error code
         // instead.
-        if let Some(e) = self.error {
+        if let Some(e) = self.reset_error() {
             self.data.clear();
             return Err(Error::StreamReset(e));
         }
@@ -240,7 +268,7 @@ impl RecvBuf {
                 break;
             }
 
-            entry.remove();
+            pool.recycle(entry.remove());
         }
 
         // Update consumed bytes for flow control.
@@ -249,6 +277,56 @@ impl RecvBuf {
         Ok((len, self.is_fin()))
     }
 
+    /// Takes the next chunk of contiguous data out of the receive buffer.
+    ///
+    /// Unlike [`emit()`], the data is not copied: the returned buffer shares
+    /// its memory with the one created when the data was received. At most
+    /// `max_len` bytes are returned, any remaining data is left in the receive
+    /// buffer. If there is no data at the expected read offset, the `Done`
+    /// error is returned.
+    ///
+    /// On success the buffer, and a flag indicating if there is no more data
+    /// in the buffer, are returned as a tuple.
+    ///
+    /// [`emit()`]: struct.RecvBuf.html#method.emit
+    pub fn emit_buf(&mut self, max_len: usize) -> Result<(RangeBuf, bool)> {
+        if !self.ready() {
+            return Err(Error::Done);
+        }
+
+        // The stream was reset, so clear its data and return the error code
+        // instead.
+        if let Some(e) = self.reset_error() {
+            self.data.clear();
+            return Err(Error::StreamReset(e));
+        }
+
+        let mut entry = match self.data.first_entry() {
+            Some(entry) => entry,
+            None => return Err(Error::Done),
+        };
+
+        let buf = if entry.get().len() > max_len {
+            let buf = entry.get_mut();
+
+            let mut out = buf.clone();
+            out.split_off(out.pos - out.start + max_len);
+
+            buf.consume(max_len);
+
+            out
+        } else {
+            entry.remove()
+        };
+
+        self.off += buf.len() as u64;
+
+        // Update consumed bytes for flow control.
+        self.flow_control.add_consumed(buf.len() as u64);
+
+        Ok((buf, self.is_fin()))
+    }
+
     /// Resets the stream at the given offset.
     pub fn reset(&mut self, error_code: u64, final_size: u64) -> Result<usize> {
         // Stream's size is already known, forbid changing it.
@@ -273,6 +351,12 @@ impl RecvBuf {
 
         self.error = Some(error_code);
 
+        // The final size was already recorded when RESET_STREAM_AT was
+        // received, but the application still needs to be notified below.
+        if self.reset_at.take().is_some() {
+            self.fin_off = None;
+        }
+
         // Clear all data already buffered.
         self.off = final_size;
 
@@ -286,6 +370,83 @@ impl RecvBuf {
         Ok(max_data_delta as usize)
     }
 
+    /// Resets the stream at the given offset, but keeps delivering data up to
+    /// `reliable_size` to the application.
+    pub fn reset_at(
+        &mut self, error_code: u64, final_size: u64, reliable_size: u64,
+    ) -> Result<usize> {
+        // The reliable size can only be reduced by later frames.
+        let reliable_size = match self.reset_at {
+            Some((_, v)) => cmp::min(v, reliable_size),
+
+            None => reliable_size,
+        };
+
+        // All the reliable data was already read (or discarded), so there is
+        // nothing left to deliver.
+        if self.drain || reliable_size <= self.off {
+            return self.reset(error_code, final_size);
+        }
+
+        // Stream's size is already known, forbid changing it.
+        if let Some(fin_off) = self.fin_off {
+            if fin_off != final_size {
+                return Err(Error::FinalSize);
+            }
+        }
+
+        // Stream's known size is lower than data already received.
+        if final_size < self.len {
+            return Err(Error::FinalSize);
+        }
+
+        // Calculate how many bytes need to be removed from the connection flow
+        // control.
+        let max_data_delta = final_size - self.len;
+
+        if self.error.is_some() {
+            return Ok(max_data_delta as usize);
+        }
+
+        self.reset_at = Some((error_code, reliable_size));
+
+        self.fin_off = Some(final_size);
+        self.len = final_size;
+
+        // Drop data already buffered past the reliable size.
+        let mut dropped = self.data.split_off(&(reliable_size + 1));
+
+        if let Some((_, mut buf)) = dropped.pop_first() {
+            if buf.off() < reliable_size {
+                buf.split_off(
+                    buf.pos - buf.start + (reliable_size - buf.off()) as usize,
+                );
+
+                self.data.insert(buf.max_off(), buf);
+            }
+        }
+
+        Ok(max_data_delta as usize)
+    }
+
+    /// Returns the error code the stream was reset with, if any.
+    ///
+    /// When the stream was reset with RESET_STREAM_AT, the error is only
+    /// returned once the application has read all the data up to the reliable
+    /// size.
+    fn reset_error(&mut self) -> Option<u64> {
+        if let Some((error_code, reliable_size)) = self.reset_at {
+            if self.off >= reliable_size {
+                self.reset_at = None;
+
+                self.error = Some(error_code);
+                self.off = self.len;
+            }
+        }
+
+        self.error
+    }
+
     /// Commits the new max_data limit.
     pub fn update_max_data(&mut self, now: time::Instant) {
         self.flow_control.update_max_data(now);
@@ -311,6 +472,18 @@ impl RecvBuf {
         self.flow_control.autotune_window(now, rtt);
     }
 
+    /// Autotune the window size based on the consumption rate.
+    pub fn autotune_window_rate(
+        &mut self, now: time::Instant, rtt: time::Duration,
+    ) {
+        self.flow_control.autotune_window_rate(now, rtt);
+    }
+
+    /// Return the measured consumption rate.
+    pub fn consumption_rate(&self) -> u64 {
+        self.flow_control.consumption_rate()
+    }
+
     /// Shuts down receiving data.
     pub fn shutdown(&mut self) -> Result<()> {
         if self.drain {
@@ -321,6 +494,8 @@ impl RecvBuf {
 
         self.data.clear();
 
+        self.reset_at = None;
+
         self.off = self.max_off();
 
         Ok(())
@@ -341,11 +516,62 @@ impl RecvBuf {
         self.len
     }
 
+    /// Returns the number of bytes buffered after the first gap in the
+    /// received data, which can't be read until the gap is filled.
+    pub fn reassembly_len(&self) -> u64 {
+        let mut contiguous = Some(self.off);
+        let mut len = 0;
+
+        for buf in self.data.values() {
+            match contiguous {
+                Some(off) if buf.off() <= off => {
+                    contiguous = Some(cmp::max(off, buf.max_off()));
+                },
+
+                _ => {
+                    contiguous = None;
+                    len += buf.len() as u64;
+                },
+            }
+        }
+
+        len
+    }
+
+    /// Returns the offsets of the data received so far, and the gaps in it.
+    pub fn offsets(&self) -> super::StreamRecvOffsets {
+        let mut contiguous = self.off;
+        let mut gaps = Vec::new();
+
+        for buf in self.data.values() {
+            if buf.is_empty() {
+                continue;
+            }
+
+            if buf.off() > contiguous {
+                gaps.push(contiguous..buf.off());
+            }
+
+            contiguous = cmp::max(contiguous, buf.max_off());
+        }
+
+        super::StreamRecvOffsets {
+            contiguous: gaps.first().map_or(contiguous, |g| g.start),
+            highest: self.len,
+            gaps,
+        }
+    }
+
     /// Returns true if the receive-side of the stream is complete.
     ///
     /// This happens when the stream's receive final size is known, and the
     /// application has read all data from the stream.
     pub fn is_fin(&self) -> bool {
+        // The stream was reset, but the application wasn't notified yet.
+        if self.reset_at.is_some() {
+            return false;
+        }
+
         if self.fin_off == Some(self.off) {
             return true;
         }
@@ -353,6 +579,53 @@ impl RecvBuf {
         false
     }
 
+    /// Serializes the buffer's offsets and flow control state, for freezing
+    /// the connection.
+    ///
+    /// Only buffers whose data was fully read can be serialized, otherwise
+    /// [`InvalidState`] is returned.
+    ///
+    /// [`InvalidState`]: ../enum.Error.html#variant.InvalidState
+    pub fn freeze(&self, b: &mut octets::OctetsMut) -> Result<()> {
+        if !self.data.is_empty() ||
+            self.len != self.off ||
+            self.fin_off.is_some() ||
+            self.error.is_some() ||
+            self.reset_at.is_some() ||
+            self.drain
+        {
+            return Err(Error::InvalidState);
+        }
+
+        b.put_varint(self.off)?;
+        b.put_varint(self.flow_control.consumed())?;
+        b.put_varint(self.flow_control.max_data())?;
+        b.put_varint(self.flow_control.window())?;
+
+        Ok(())
+    }
+
+    /// Restores the buffer's offsets and flow control state serialized by
+    /// [`freeze()`].
+    ///
+    /// [`freeze()`]: struct.RecvBuf.html#method.freeze
+    pub fn thaw(
+        &mut self, b: &mut octets::Octets, max_window: u64,
+    ) -> Result<()> {
+        self.off = b.get_varint()?;
+        self.len = self.off;
+
+        let consumed = b.get_varint()?;
+        let max_data = b.get_varint()?;
+        let window = b.get_varint()?;
+
+        self.flow_control =
+            flowcontrol::FlowControl::new(max_data, window, max_window);
+        self.flow_control.add_consumed(consumed);
+
+        Ok(())
+    }
+
     /// Returns true if the stream is not storing incoming data.
     pub fn is_draining(&self) -> bool {
         self.drain
@@ -360,6 +633,14 @@ impl RecvBuf {
 
     /// Returns true if the stream has data to be read.
     pub fn ready(&self) -> bool {
+        // Once all the reliable data was read, the application still needs to
+        // be notified that the stream was reset.
+        if let Some((_, reliable_size)) = self.reset_at {
+            if self.off >= reliable_size {
+                return true;
+            }
+        }
+
         let (_, buf) = match self.data.first_key_value() {
             Some(v) => v,
             None => return false,
@@ -439,6 +720,144 @@ mod tests {
         assert_eq!(recv.emit(&mut buf), Ok((0, true)));
     }
 
+    #[test]
+    fn reassembly_len() {
+        let mut recv = RecvBuf::new(u64::MAX, DEFAULT_STREAM_WINDOW);
+        let mut buf = [0; 32];
+
+        assert!(recv.write(RangeBuf::from(b"world", 10, false)).is_ok());
+        assert!(recv.write(RangeBuf::from(b"something", 20, false)).is_ok());
+        assert_eq!(recv.reassembly_len(), 14);
+
+        assert!(recv.write(RangeBuf::from(b"hello", 0, false)).is_ok());
+        assert_eq!(recv.reassembly_len(), 14);
+
+        assert!(recv.write(RangeBuf::from(b"stuff", 5, false)).is_ok());
+        assert_eq!(recv.reassembly_len(), 9);
+
+        // Reading in-order data doesn't change it.
+        assert_eq!(recv.emit(&mut buf), Ok((15, false)));
+        assert_eq!(recv.reassembly_len(), 9);
+
+        assert!(recv.shutdown().is_ok());
+        assert_eq!(recv.reassembly_len(), 0);
+    }
+
+    #[test]
+    fn offsets() {
+        let mut recv = RecvBuf::new(u64::MAX, DEFAULT_STREAM_WINDOW);
+        let mut buf = [0; 32];
+
+        let offsets = recv.offsets();
+        assert_eq!(offsets.contiguous, 0);
+        assert_eq!(offsets.highest, 0);
+        assert!(offsets.gaps.is_empty());
+
+        let first = RangeBuf::from(b"hello", 0, false);
+        let second = RangeBuf::from(b"world", 10, false);
+        let third = RangeBuf::from(b"something", 20, false);
+
+        assert!(recv.write(third).is_ok());
+        assert!(recv.write(second).is_ok());
+
+        let offsets = recv.offsets();
+        assert_eq!(offsets.contiguous, 0);
+        assert_eq!(offsets.highest, 29);
+        assert_eq!(offsets.gaps, vec![0..10, 15..20]);
+
+        assert!(recv.write(first).is_ok());
+
+        let offsets = recv.offsets();
+        assert_eq!(offsets.contiguous, 5);
+        assert_eq!(offsets.gaps, vec![5..10, 15..20]);
+
+        // Reading data doesn't change the offsets.
+        assert_eq!(recv.emit(&mut buf), Ok((5, false)));
+        assert_eq!(recv.offsets(), offsets);
+
+        let fourth = RangeBuf::from(b"stuff", 5, false);
+        assert!(recv.write(fourth).is_ok());
+
+        let offsets = recv.offsets();
+        assert_eq!(offsets.contiguous, 15);
+        assert_eq!(offsets.highest, 29);
+        assert_eq!(offsets.gaps, vec![15..20]);
+    }
+
+    #[test]
+    fn reset_at() {
+        let mut recv = RecvBuf::new(u64::MAX, DEFAULT_STREAM_WINDOW);
+        let mut buf = [0; 32];
+
+        let first = RangeBuf::from(b"hello", 0, false);
+        let second = RangeBuf::from(b"world", 10, false);
+
+        assert!(recv.write(first).is_ok());
+        assert!(recv.write(second).is_ok());
+
+        assert_eq!(recv.emit(&mut buf[..2]), Ok((2, false)));
+
+        assert_eq!(recv.reset_at(42, 20, 12), Ok(5));
+        assert_eq!(recv.max_off(), 20);
+
+        // The final size can't change.
+        assert_eq!(recv.reset_at(42, 21, 12), Err(Error::FinalSize));
+
+        // A larger reliable size has no effect.
+        assert_eq!(recv.reset_at(42, 20, 15), Ok(0));
+
+        // Data past the reliable size is dropped.
+        let third = RangeBuf::from(b"something", 5, false);
+        assert!(recv.write(third).is_ok());
+
+        assert_eq!(recv.emit(&mut buf), Ok((10, false)));
+        assert_eq!(&buf[..10], b"llosometwo");
+        assert!(!recv.is_fin());
+
+        let fourth = RangeBuf::from(b"hello", 12, false);
+        assert!(recv.write(fourth).is_ok());
+
+        // Once the reliable data was read the error is returned.
+        assert!(recv.ready());
+        assert_eq!(recv.emit(&mut buf), Err(Error::StreamReset(42)));
+        assert!(recv.is_fin());
+        assert!(!recv.ready());
+    }
+
+    #[test]
+    fn reset_at_lower_reliable_size() {
+        let mut recv = RecvBuf::new(u64::MAX, DEFAULT_STREAM_WINDOW);
+        let mut buf = [0; 32];
+
+        let first = RangeBuf::from(b"hello", 0, false);
+        assert!(recv.write(first).is_ok());
+
+        assert_eq!(recv.reset_at(42, 5, 5), Ok(0));
+
+        // The end of the reliable data is not the end of the stream.
+        assert_eq!(recv.emit(&mut buf), Ok((5, false)));
+        assert!(!recv.is_fin());
+
+        assert_eq!(recv.emit(&mut buf), Err(Error::StreamReset(42)));
+        assert!(recv.is_fin());
+
+        let mut recv = RecvBuf::new(u64::MAX, DEFAULT_STREAM_WINDOW);
+
+        let first = RangeBuf::from(b"hello", 0, false);
+        assert!(recv.write(first).is_ok());
+
+        assert_eq!(recv.reset_at(42, 10, 8), Ok(5));
+
+        assert_eq!(recv.emit(&mut buf), Ok((5, false)));
+
+        // A lower reliable size that was already read resets the stream.
+        assert_eq!(recv.reset_at(42, 10, 3), Ok(0));
+
+        assert!(recv.ready());
+        assert_eq!(recv.emit(&mut buf), Err(Error::StreamReset(42)));
+        assert!(recv.is_fin());
+    }
+
     #[test]
     fn ordered_read() {
         let mut recv = RecvBuf::new(u64::MAX, DEFAULT_STREAM_WINDOW);
@@ -516,6 +935,46 @@ mod tests {
         assert_eq!(recv.off, 19);
     }
 
+    #[test]
+    fn split_read_buf() {
+        let mut recv = RecvBuf::new(u64::MAX, DEFAULT_STREAM_WINDOW);
+
+        let mut buf = [0; 32];
+
+        let first = RangeBuf::from(b"something", 0, false);
+        let second = RangeBuf::from(b"helloworld", 9, true);
+
+        assert!(recv.write(first).is_ok());
+        assert!(recv.write(second).is_ok());
+
+        let (len, fin) = recv.emit(&mut buf[..4]).unwrap();
+        assert_eq!(len, 4);
+        assert!(!fin);
+        assert_eq!(&buf[..len], b"some");
+
+        // Data partially read with `emit()` is not returned again.
+        let (data, fin) = recv.emit_buf(3).unwrap();
+        assert_eq!(&data[..], b"thi");
+        assert_eq!(data.off(), 4);
+        assert!(!data.fin());
+        assert!(!fin);
+        assert_eq!(recv.off, 7);
+
+        let (data, fin) = recv.emit_buf(32).unwrap();
+        assert_eq!(&data[..], b"ng");
+        assert_eq!(data.off(), 7);
+        assert!(!fin);
+        assert_eq!(recv.off, 9);
+
+        let (data, fin) = recv.emit_buf(32).unwrap();
+        assert_eq!(&data[..], b"helloworld");
+        assert!(data.fin());
+        assert!(fin);
+        assert_eq!(recv.off, 19);
+
+        assert_eq!(recv.emit_buf(32), Err(Error::Done));
+    }
+
     #[test]
     fn incomplete_read() {
         let mut recv = RecvBuf::new(u64::MAX, DEFAULT_STREAM_WINDOW);
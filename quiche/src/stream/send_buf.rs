@@ -33,6 +33,7 @@ use crate::Result;
 
 use crate::ranges;
 
+use super::BufPool;
 use super::RangeBuf;
 
 #[cfg(test)]
@@ -85,6 +86,13 @@ pub struct SendBuf {
 
     /// The error code received via STOP_SENDING.
     error: Option<u64>,
+
+    /// The maximum amount of unsent data that can be buffered, if any.
+    max_buffered: Option<usize>,
+
+    /// Whether data that was never sent takes precedence over data that needs
+    /// to be retransmitted.
+    new_data_first: bool,
 }
 
 impl SendBuf {
@@ -101,21 +109,96 @@ impl SendBuf {
     /// The number of bytes that were actually stored in the buffer is returned
     /// (this may be lower than the size of the input buffer, in case of partial
     /// writes).
-    pub fn write(&mut self, mut data: &[u8], mut fin: bool) -> Result<usize> {
-        let max_off = self.off + data.len() as u64;
+    pub fn write(&mut self, data: &[u8], fin: bool) -> Result<usize> {
+        self.write_pooled(data, fin, &mut BufPool::default())
+    }
+
+    /// Inserts the given slice of data at the end of the buffer, like
+    /// [`write()`], copying it into buffers taken from `pool`.
+    ///
+    /// [`write()`]: struct.SendBuf.html#method.write
+    pub fn write_pooled(
+        &mut self, data: &[u8], fin: bool, pool: &mut BufPool,
+    ) -> Result<usize> {
+        let (len, fin) = match self.prepare_write(data.len(), fin)? {
+            (len, fin, true) => (len, fin),
+
+            (len, ..) => return Ok(len),
+        };
+
+        let data = &data[..len];
+
+        let mut len = 0;
+
+        // Split the remaining input data into consistently-sized buffers to
+        // avoid fragmentation.
+        for chunk in data.chunks(SEND_BUFFER_SIZE) {
+            len += chunk.len();
+
+            let fin = len == data.len() && fin;
+
+            let buf = pool.range_buf(chunk, self.off, fin);
+
+            // The new data can simply be appended at the end of the send buffer.
+            self.data.push_back(buf);
+
+            self.off += chunk.len() as u64;
+            self.len += chunk.len() as u64;
+        }
+
+        Ok(len)
+    }
+
+    /// Inserts the data of the given buffer at the end of the send buffer,
+    /// without copying it.
+    ///
+    /// The number of bytes that were actually stored in the buffer is returned,
+    /// like with [`write()`].
+    ///
+    /// [`write()`]: struct.SendBuf.html#method.write
+    pub fn write_owned(&mut self, data: &RangeBuf, fin: bool) -> Result<usize> {
+        let (len, fin) = match self.prepare_write(data.len(), fin)? {
+            (len, fin, true) => (len, fin),
+
+            (len, ..) => return Ok(len),
+        };
+
+        let mut buf = data.rebase(self.off, fin);
+
+        if len < buf.len() {
+            buf.split_off(len);
+        }
+
+        self.data.push_back(buf);
+
+        self.off += len as u64;
+        self.len += len as u64;
+
+        Ok(len)
+    }
+
+    /// Validates a write of `len` bytes, and records the final offset.
+    ///
+    /// Returns how many bytes can be buffered, whether they include the final
+    /// offset, and whether they need to be queued at all.
+    fn prepare_write(
+        &mut self, len: usize, mut fin: bool,
+    ) -> Result<(usize, bool, bool)> {
+        let max_off = self.off + len as u64;
 
         // Get the stream send capacity. This will return an error if the stream
         // was stopped.
         let capacity = self.cap()?;
 
-        if data.len() > capacity {
-            // Truncate the input buffer according to the stream's capacity.
-            let len = capacity;
-            data = &data[..len];
-
+        let len = if len > capacity {
             // We are not buffering the full input, so clear the fin flag.
             fin = false;
-        }
+
+            // Truncate the input buffer according to the stream's capacity.
+            capacity
+        } else {
+            len
+        };
 
         if let Some(fin_off) = self.fin_off {
             // Can't write past final offset.
@@ -135,45 +218,30 @@ impl SendBuf {
 
         // Don't queue data that was already fully acked.
         if self.ack_off() >= max_off {
-            return Ok(data.len());
+            return Ok((len, fin, false));
         }
 
         // We already recorded the final offset, so we can just discard the
         // empty buffer now.
-        if data.is_empty() {
-            return Ok(data.len());
+        if len == 0 {
+            return Ok((len, fin, false));
         }
 
-        let mut len = 0;
-
-        // Split the remaining input data into consistently-sized buffers to
-        // avoid fragmentation.
-        for chunk in data.chunks(SEND_BUFFER_SIZE) {
-            len += chunk.len();
-
-            let fin = len == data.len() && fin;
-
-            let buf = RangeBuf::from(chunk, self.off, fin);
-
-            // The new data can simply be appended at the end of the send buffer.
-            self.data.push_back(buf);
-
-            self.off += chunk.len() as u64;
-            self.len += chunk.len() as u64;
-        }
-
-        Ok(len)
+        Ok((len, fin, true))
     }
 
     /// Writes data from the send buffer into the given output buffer.
     pub fn emit(&mut self, out: &mut [u8]) -> Result<(usize, bool)> {
+        let start_pos = self.next_pos();
+        let mut pos = start_pos;
+
         let mut out_len = out.len();
-        let out_off = self.off_front();
+        let out_off = self.off_front_from(pos);
 
         let mut next_off = out_off;
 
         while out_len > 0 {
-            let off_front = self.off_front();
+            let off_front = self.off_front_from(pos);
 
             if self.is_empty() ||
                 off_front >= self.off ||
@@ -183,14 +251,14 @@ impl SendBuf {
                 break;
             }
 
-            let buf = match self.data.get_mut(self.pos) {
+            let buf = match self.data.get_mut(pos) {
                 Some(v) => v,
 
                 None => break,
             };
 
             if buf.is_empty() {
-                self.pos += 1;
+                pos += 1;
                 continue;
             }
 
@@ -214,7 +282,13 @@ impl SendBuf {
                 break;
             }
 
-            self.pos += 1;
+            pos += 1;
+        }
+
+        // When data that needs to be retransmitted was skipped, the position
+        // needs to stay where it is, so that data is sent later.
+        if start_pos == self.pos {
+            self.pos = pos;
         }
 
         // Override the `fin` flag set for the output buffer by matching the
@@ -253,6 +327,16 @@ impl SendBuf {
     }
 
     pub fn ack_and_drop(&mut self, off: u64, len: usize) {
+        self.ack_and_drop_pooled(off, len, &mut BufPool::default());
+    }
+
+    /// Acknowledges data like [`ack_and_drop()`], giving the memory of the
+    /// dropped buffers back to `pool`.
+    ///
+    /// [`ack_and_drop()`]: struct.SendBuf.html#method.ack_and_drop
+    pub fn ack_and_drop_pooled(
+        &mut self, off: u64, len: usize, pool: &mut BufPool,
+    ) {
         self.ack(off, len);
 
         let ack_off = self.ack_off();
@@ -286,7 +370,9 @@ impl SendBuf {
         }
 
         if let Some(drop) = drop_until {
-            self.data.drain(..=drop);
+            for buf in self.data.drain(..=drop) {
+                pool.recycle(buf);
+            }
 
             // When a buffer is marked for retransmission, but then acked before
             // it could be retransmitted, we might end up decreasing the SendBuf
@@ -392,6 +478,57 @@ impl SendBuf {
         Ok(self.reset())
     }
 
+    /// Shuts down sending data, but keeps the data below `reliable_size` so
+    /// that it is still delivered to the peer.
+    ///
+    /// The reliable size is capped to the amount of data written. Data past it
+    /// is dropped, and won't be retransmitted if lost.
+    pub fn reset_at(&mut self, reliable_size: u64) -> Result<(u64, u64)> {
+        if self.shutdown || self.error.is_some() {
+            return Err(Error::Done);
+        }
+
+        self.shutdown = true;
+
+        let reliable_size = cmp::min(reliable_size, self.off);
+
+        let final_size =
+            cmp::max(cmp::max(self.off_front(), self.emit_off), reliable_size);
+        let unsent_len = self.off - final_size;
+
+        self.fin_off = Some(final_size);
+
+        // Drop buffered data past the reliable size.
+        while let Some(buf) = self.data.back_mut() {
+            if buf.off >= reliable_size {
+                self.data.pop_back();
+                continue;
+            }
+
+            if buf.max_off() > reliable_size {
+                buf.split_off((reliable_size - buf.off) as usize);
+            }
+
+            break;
+        }
+
+        self.pos = cmp::min(self.pos, self.data.len());
+        self.len = self.data.iter().map(|b| b.len() as u64).sum();
+
+        // Data past the reliable size doesn't need to be acked anymore.
+        if final_size > reliable_size {
+            self.ack(reliable_size, (final_size - reliable_size) as usize);
+        }
+
+        self.off = final_size;
+
+        // The final size can't change anymore, even if the stream is stopped
+        // later on.
+        self.emit_off = final_size;
+
+        Ok((final_size, unsent_len))
+    }
+
     /// Returns the largest offset of data buffered.
     pub fn off_back(&self) -> u64 {
         self.off
@@ -399,8 +536,22 @@ impl SendBuf {
 
     /// Returns the lowest offset of data buffered.
     pub fn off_front(&self) -> u64 {
-        let mut pos = self.pos;
+        self.off_front_from(self.next_pos())
+    }
 
+    /// Returns the index of the buffer that needs to be sent next.
+    fn next_pos(&self) -> usize {
+        // Skip data that needs to be retransmitted, and resume from the first
+        // byte that was never sent.
+        if self.new_data_first && self.emit_off < self.off {
+            return self.data.partition_point(|b| b.max_off() <= self.emit_off);
+        }
+
+        self.pos
+    }
+
+    /// Returns the lowest offset of data buffered from the given position.
+    fn off_front_from(&self, mut pos: usize) -> u64 {
         // Skip empty buffers from the start of the queue.
         while let Some(b) = self.data.get(pos) {
             if !b.is_empty() {
@@ -470,6 +621,43 @@ impl SendBuf {
         }
     }
 
+    /// Serializes the buffer's offsets, for freezing the connection.
+    ///
+    /// Only buffers whose data was fully acked can be serialized, otherwise
+    /// [`InvalidState`] is returned.
+    ///
+    /// [`InvalidState`]: ../enum.Error.html#variant.InvalidState
+    pub fn freeze(&self, b: &mut octets::OctetsMut) -> Result<()> {
+        if !self.is_empty() ||
+            self.ack_off() != self.off ||
+            self.fin_off.is_some() ||
+            self.error.is_some() ||
+            self.shutdown
+        {
+            return Err(Error::InvalidState);
+        }
+
+        b.put_varint(self.off)?;
+        b.put_varint(self.max_data)?;
+
+        Ok(())
+    }
+
+    /// Restores the buffer's offsets serialized by [`freeze()`].
+    ///
+    /// [`freeze()`]: struct.SendBuf.html#method.freeze
+    pub fn thaw(&mut self, b: &mut octets::Octets) -> Result<()> {
+        self.off = b.get_varint()?;
+        self.emit_off = self.off;
+        self.max_data = b.get_varint()?;
+
+        if self.off > 0 {
+            self.acked.insert(0..self.off);
+        }
+
+        Ok(())
+    }
+
     /// Returns the outgoing flow control capacity.
     pub fn cap(&self) -> Result<usize> {
         // The stream was stopped, so return the error code instead.
@@ -480,6 +668,31 @@ impl SendBuf {
         Ok((self.max_data - self.off) as usize)
     }
 
+    /// Sets the maximum amount of unsent data that can be buffered.
+    pub fn set_max_buffered(&mut self, v: Option<usize>) {
+        self.max_buffered = v;
+    }
+
+    /// Sets whether data that was never sent is sent before data that needs
+    /// to be retransmitted.
+    pub fn set_new_data_first(&mut self, v: bool) {
+        self.new_data_first = v;
+    }
+
+    /// Returns how much more data can be buffered before reaching the
+    /// maximum amount of unsent data.
+    ///
+    /// Unlike [`cap()`], this doesn't take flow control into account.
+    ///
+    /// [`cap()`]: struct.SendBuf.html#method.cap
+    pub fn buffer_cap(&self) -> usize {
+        match self.max_buffered {
+            Some(max) => max.saturating_sub(self.len as usize),
+
+            None => usize::MAX,
+        }
+    }
+
     /// Returns the number of separate buffers stored.
     #[allow(dead_code)]
     pub fn bufs_count(&self) -> usize {
@@ -491,6 +704,8 @@ impl SendBuf {
 mod tests {
     use super::*;
 
+    use std::sync::Arc;
+
     #[test]
     fn empty_write() {
         let mut buf = [0; 5];
@@ -569,6 +784,44 @@ mod tests {
         assert_eq!(send.off_front(), 19);
     }
 
+    #[test]
+    fn write_owned() {
+        let mut buf = [0; 128];
+
+        let mut send = SendBuf::new(15);
+
+        let data = Arc::new(b"helloworld".to_vec());
+
+        assert_eq!(send.write(b"hey", false), Ok(3));
+
+        // The buffer's memory is shared, not copied.
+        let owned = RangeBuf::from_shared(data.clone());
+        assert_eq!(send.write_owned(&owned, false), Ok(10));
+        assert_eq!(send.len, 13);
+
+        drop(owned);
+        assert_eq!(Arc::strong_count(&data), 2);
+
+        // Only part of the buffer fits in the stream's capacity.
+        let mut owned = RangeBuf::from_shared(data.clone());
+        owned.consume(5);
+        assert_eq!(send.write_owned(&owned, true), Ok(2));
+        assert_eq!(send.len, 15);
+        assert!(!send.is_fin());
+
+        drop(owned);
+        assert_eq!(Arc::strong_count(&data), 3);
+
+        let (written, fin) = send.emit(&mut buf).unwrap();
+        assert_eq!(written, 15);
+        assert!(!fin);
+        assert_eq!(&buf[..written], b"heyhelloworldwo");
+
+        // The memory is released once the data is acked.
+        send.ack_and_drop(0, 15);
+        assert_eq!(Arc::strong_count(&data), 1);
+    }
+
     #[test]
     fn resend() {
         let mut buf = [0; 15];
@@ -754,6 +1007,138 @@ mod tests {
         assert_eq!(send.off_front(), 3);
     }
 
+    #[test]
+    fn new_data_first() {
+        let mut buf = [0; 15];
+
+        let mut send = SendBuf::new(u64::MAX);
+        send.set_new_data_first(true);
+
+        assert_eq!(send.write(b"aaaaabbbbb", false), Ok(10));
+
+        let (written, fin) = send.emit(&mut buf).unwrap();
+        assert_eq!(written, 10);
+        assert!(!fin);
+
+        assert_eq!(send.write(b"ccccc", true), Ok(5));
+
+        send.retransmit(0, 5);
+        assert_eq!(send.len, 10);
+
+        // New data is sent before lost data.
+        assert_eq!(send.off_front(), 10);
+
+        let (written, fin) = send.emit(&mut buf).unwrap();
+        assert_eq!(written, 5);
+        assert!(fin);
+        assert_eq!(&buf[..written], b"ccccc");
+
+        // Once there's no new data left, lost data is sent.
+        assert_eq!(send.off_front(), 0);
+
+        let (written, fin) = send.emit(&mut buf).unwrap();
+        assert_eq!(written, 5);
+        assert!(!fin);
+        assert_eq!(&buf[..written], b"aaaaa");
+
+        assert_eq!(send.len, 0);
+        assert_eq!(send.off_front(), 15);
+    }
+
+    #[test]
+    fn reset_at() {
+        let mut buf = [0; 30];
+
+        let mut send = SendBuf::new(u64::MAX);
+
+        assert_eq!(send.write(b"hello", false), Ok(5));
+        assert_eq!(send.write(b"world", false), Ok(5));
+        assert_eq!(send.write(b"something", false), Ok(9));
+
+        let (written, fin) = send.emit(&mut buf[..8]).unwrap();
+        assert_eq!(written, 8);
+        assert!(!fin);
+
+        // Data up to the reliable size is kept, even if it wasn't sent yet.
+        assert_eq!(send.reset_at(12), Ok((12, 7)));
+        assert_eq!(send.reset_at(12), Err(Error::Done));
+
+        assert!(send.is_fin());
+        assert!(!send.is_complete());
+
+        let (written, fin) = send.emit(&mut buf).unwrap();
+        assert_eq!(written, 4);
+        assert!(fin);
+        assert_eq!(&buf[..4], b"ldso");
+
+        // Lost data is retransmitted only up to the reliable size.
+        send.retransmit(6, 6);
+
+        let (written, fin) = send.emit(&mut buf).unwrap();
+        assert_eq!(written, 6);
+        assert!(fin);
+        assert_eq!(&buf[..6], b"orldso");
+
+        send.ack_and_drop(0, 12);
+        assert!(send.is_complete());
+
+        // The final size doesn't change when the stream is stopped.
+        assert_eq!(send.stop(0), Ok((12, 0)));
+    }
+
+    #[test]
+    fn reset_at_sent_data() {
+        let mut buf = [0; 30];
+
+        let mut send = SendBuf::new(u64::MAX);
+
+        assert_eq!(send.write(b"helloworld", false), Ok(10));
+
+        let (written, fin) = send.emit(&mut buf[..8]).unwrap();
+        assert_eq!(written, 8);
+        assert!(!fin);
+
+        // Data that was already sent past the reliable size is abandoned.
+        assert_eq!(send.reset_at(3), Ok((8, 2)));
+
+        send.retransmit(0, 8);
+
+        let (written, fin) = send.emit(&mut buf).unwrap();
+        assert_eq!(written, 3);
+        assert!(!fin);
+        assert_eq!(&buf[..3], b"hel");
+
+        send.ack_and_drop(0, 3);
+        assert!(send.is_complete());
+    }
+
+    #[test]
+    fn max_buffered() {
+        let mut buf = [0; 15];
+
+        let mut send = SendBuf::new(u64::MAX);
+        assert_eq!(send.buffer_cap(), usize::MAX);
+
+        send.set_max_buffered(Some(10));
+        assert_eq!(send.buffer_cap(), 10);
+
+        assert_eq!(send.write(b"something", false), Ok(9));
+        assert_eq!(send.buffer_cap(), 1);
+
+        // Sending data makes room in the buffer.
+        let (written, fin) = send.emit(&mut buf[..4]).unwrap();
+        assert_eq!(written, 4);
+        assert!(!fin);
+        assert_eq!(send.buffer_cap(), 5);
+
+        // Data that needs to be retransmitted counts again.
+        send.retransmit(0, 4);
+        assert_eq!(send.buffer_cap(), 1);
+
+        // Flow control is tracked separately.
+        assert_eq!(send.cap(), Ok(usize::MAX - 9));
+    }
+
     #[test]
     fn send_buf_final_size_retransmit() {
         let mut buf = [0; 50];
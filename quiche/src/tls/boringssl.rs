@@ -47,9 +47,13 @@ use crate::ConnectionError;
 use crate::crypto;
 use crate::packet;
 
+use super::ExData;
+use super::TlsContext;
+use super::TlsHandshake;
+use super::INTERNAL_ERROR;
+use super::TLS_ALERT_ERROR;
+
 const TLS1_3_VERSION: u16 = 0x0304;
-const TLS_ALERT_ERROR: u64 = 0x100;
-const INTERNAL_ERROR: u64 = 0x01;
 
 #[allow(non_camel_case_types)]
 #[repr(transparent)]
@@ -102,7 +106,6 @@ struct X509_STORE_CTX {
 
 #[allow(non_camel_case_types)]
 #[repr(transparent)]
-#[cfg(windows)]
 struct X509 {
     _unused: c_void,
 }
@@ -158,6 +161,16 @@ struct SSL_QUIC_METHOD {
     >,
 }
 
+#[repr(C)]
+#[allow(non_camel_case_types)]
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+enum ssl_compliance_policy_t {
+    ssl_compliance_policy_none,
+    ssl_compliance_policy_fips_202205,
+    ssl_compliance_policy_wpa3_192_202304,
+}
+
 #[cfg(test)]
 #[repr(C)]
 #[allow(non_camel_case_types)]
@@ -249,51 +262,6 @@ impl Context {
         ctx
     }
 
-    pub fn new_handshake(&mut self) -> Result<Handshake> {
-        unsafe {
-            let ssl = SSL_new(self.as_mut_ptr());
-            Ok(Handshake::new(ssl))
-        }
-    }
-
-    pub fn load_verify_locations_from_file(&mut self, file: &str) -> Result<()> {
-        let file = ffi::CString::new(file).map_err(|_| Error::TlsFail)?;
-        map_result(unsafe {
-            SSL_CTX_load_verify_locations(
-                self.as_mut_ptr(),
-                file.as_ptr(),
-                std::ptr::null(),
-            )
-        })
-    }
-
-    pub fn load_verify_locations_from_directory(
-        &mut self, path: &str,
-    ) -> Result<()> {
-        let path = ffi::CString::new(path).map_err(|_| Error::TlsFail)?;
-        map_result(unsafe {
-            SSL_CTX_load_verify_locations(
-                self.as_mut_ptr(),
-                std::ptr::null(),
-                path.as_ptr(),
-            )
-        })
-    }
-
-    pub fn use_certificate_chain_file(&mut self, file: &str) -> Result<()> {
-        let cstr = ffi::CString::new(file).map_err(|_| Error::TlsFail)?;
-        map_result(unsafe {
-            SSL_CTX_use_certificate_chain_file(self.as_mut_ptr(), cstr.as_ptr())
-        })
-    }
-
-    pub fn use_privkey_file(&mut self, file: &str) -> Result<()> {
-        let cstr = ffi::CString::new(file).map_err(|_| Error::TlsFail)?;
-        map_result(unsafe {
-            SSL_CTX_use_PrivateKey_file(self.as_mut_ptr(), cstr.as_ptr(), 1)
-        })
-    }
-
     #[cfg(not(windows))]
     fn load_ca_certs(&mut self) -> Result<()> {
         unsafe { map_result(SSL_CTX_set_default_verify_paths(self.as_mut_ptr())) }
@@ -361,23 +329,111 @@ impl Context {
         };
     }
 
-    pub fn set_verify(&mut self, verify: bool) {
+    fn set_verify_mode(&mut self, mode: c_int) {
+        unsafe {
+            // Keep the verification callback, if any.
+            let cb = SSL_CTX_get_verify_callback(self.as_mut_ptr());
+
+            SSL_CTX_set_verify(self.as_mut_ptr(), mode, cb);
+        }
+    }
+
+    fn as_mut_ptr(&mut self) -> *mut SSL_CTX {
+        self.0
+    }
+}
+
+impl TlsContext for Context {
+    type Handshake = Handshake;
+
+    fn new_handshake(&mut self) -> Result<Handshake> {
+        unsafe {
+            let ssl = SSL_new(self.as_mut_ptr());
+            Ok(Handshake::new(ssl))
+        }
+    }
+
+    fn load_verify_locations_from_file(&mut self, file: &str) -> Result<()> {
+        let file = ffi::CString::new(file).map_err(|_| Error::TlsFail)?;
+        map_result(unsafe {
+            SSL_CTX_load_verify_locations(
+                self.as_mut_ptr(),
+                file.as_ptr(),
+                std::ptr::null(),
+            )
+        })
+    }
+
+    fn set_sigalgs_list(&mut self, sigalgs: &str) -> Result<()> {
+        let sigalgs = ffi::CString::new(sigalgs).map_err(|_| Error::TlsFail)?;
+        map_result(unsafe {
+            SSL_CTX_set1_sigalgs_list(self.as_mut_ptr(), sigalgs.as_ptr())
+        })
+    }
+
+    fn set_groups_list(&mut self, groups: &str) -> Result<()> {
+        let groups = ffi::CString::new(groups).map_err(|_| Error::TlsFail)?;
+        map_result(unsafe {
+            SSL_CTX_set1_groups_list(self.as_mut_ptr(), groups.as_ptr())
+        })
+    }
+
+    fn load_verify_locations_from_directory(&mut self, path: &str) -> Result<()> {
+        let path = ffi::CString::new(path).map_err(|_| Error::TlsFail)?;
+        map_result(unsafe {
+            SSL_CTX_load_verify_locations(
+                self.as_mut_ptr(),
+                std::ptr::null(),
+                path.as_ptr(),
+            )
+        })
+    }
+
+    fn use_certificate_chain_file(&mut self, file: &str) -> Result<()> {
+        let cstr = ffi::CString::new(file).map_err(|_| Error::TlsFail)?;
+        map_result(unsafe {
+            SSL_CTX_use_certificate_chain_file(self.as_mut_ptr(), cstr.as_ptr())
+        })
+    }
+
+    fn use_privkey_file(&mut self, file: &str) -> Result<()> {
+        let cstr = ffi::CString::new(file).map_err(|_| Error::TlsFail)?;
+        map_result(unsafe {
+            SSL_CTX_use_PrivateKey_file(self.as_mut_ptr(), cstr.as_ptr(), 1)
+        })
+    }
+
+    fn set_verify(&mut self, verify: bool) {
         // true  -> 0x01 SSL_VERIFY_PEER
         // false -> 0x00 SSL_VERIFY_NONE
         let mode = i32::from(verify);
 
+        self.set_verify_mode(mode);
+    }
+
+    fn set_verify_required(&mut self, required: bool) {
+        // true  -> 0x03 SSL_VERIFY_PEER | SSL_VERIFY_FAIL_IF_NO_PEER_CERT
+        // false -> 0x01 SSL_VERIFY_PEER
+        let mode = if required { 0x03 } else { 0x01 };
+
+        self.set_verify_mode(mode);
+    }
+
+    fn enable_verify_callback(&mut self) {
         unsafe {
-            SSL_CTX_set_verify(self.as_mut_ptr(), mode, None);
+            let mode = SSL_CTX_get_verify_mode(self.as_mut_ptr());
+
+            SSL_CTX_set_verify(self.as_mut_ptr(), mode, Some(verify_peer));
         }
     }
 
-    pub fn enable_keylog(&mut self) {
+    fn enable_keylog(&mut self) {
         unsafe {
             SSL_CTX_set_keylog_callback(self.as_mut_ptr(), Some(keylog));
         }
     }
 
-    pub fn set_alpn(&mut self, v: &[&[u8]]) -> Result<()> {
+    fn set_alpn(&mut self, v: &[&[u8]]) -> Result<()> {
         let mut protos: Vec<u8> = Vec::new();
 
         for proto in v {
@@ -404,7 +460,7 @@ impl Context {
         })
     }
 
-    pub fn set_ticket_key(&mut self, key: &[u8]) -> Result<()> {
+    fn set_ticket_key(&mut self, key: &[u8]) -> Result<()> {
         map_result(unsafe {
             SSL_CTX_set_tlsext_ticket_keys(
                 self.as_mut_ptr(),
@@ -414,17 +470,13 @@ impl Context {
         })
     }
 
-    pub fn set_early_data_enabled(&mut self, enabled: bool) {
+    fn set_early_data_enabled(&mut self, enabled: bool) {
         let enabled = i32::from(enabled);
 
         unsafe {
             SSL_CTX_set_early_data_enabled(self.as_mut_ptr(), enabled);
         }
     }
-
-    fn as_mut_ptr(&mut self) -> *mut SSL_CTX {
-        self.0
-    }
 }
 
 // NOTE: These traits are not automatically implemented for Context due to the
@@ -447,6 +499,8 @@ pub struct Handshake {
     /// SSL_process_quic_post_handshake should be called when whenever
     /// SSL_provide_quic_data is called to process the provided data.
     provided_data_outstanding: bool,
+    /// Whether the peer rejected the early data sent by the client.
+    early_data_rejected: bool,
 }
 
 impl Handshake {
@@ -459,6 +513,7 @@ impl Handshake {
         Handshake {
             ptr,
             provided_data_outstanding: false,
+            early_data_rejected: false,
         }
     }
 
@@ -466,32 +521,6 @@ impl Handshake {
         unsafe { SSL_get_error(self.as_ptr(), ret_code) }
     }
 
-    pub fn init(&mut self, is_server: bool) -> Result<()> {
-        self.set_state(is_server);
-
-        self.set_min_proto_version(TLS1_3_VERSION)?;
-        self.set_max_proto_version(TLS1_3_VERSION)?;
-
-        self.set_quic_method()?;
-
-        // TODO: the early data context should include transport parameters and
-        // HTTP/3 SETTINGS in wire format.
-        self.set_quic_early_data_context(b"quiche")?;
-
-        self.set_quiet_shutdown(true);
-
-        Ok(())
-    }
-
-    pub fn use_legacy_codepoint(&mut self, use_legacy: bool) {
-        unsafe {
-            SSL_set_quic_use_legacy_codepoint(
-                self.as_mut_ptr(),
-                use_legacy as c_int,
-            );
-        }
-    }
-
     pub fn set_state(&mut self, is_server: bool) {
         unsafe {
             if is_server {
@@ -541,7 +570,169 @@ impl Handshake {
         unsafe { SSL_set_quiet_shutdown(self.as_mut_ptr(), i32::from(mode)) }
     }
 
-    pub fn set_host_name(&mut self, name: &str) -> Result<()> {
+    pub fn reset_early_data_reject(&mut self) {
+        unsafe { SSL_reset_early_data_reject(self.as_mut_ptr()) };
+    }
+
+    #[cfg(test)]
+    pub fn set_options(&mut self, opts: u32) {
+        unsafe {
+            SSL_set_options(self.as_mut_ptr(), opts);
+        }
+    }
+
+    // Only used for testing handling of failure during key signing.
+    #[cfg(test)]
+    pub fn set_failing_private_key_method(&mut self) {
+        extern fn failing_sign(
+            _ssl: *mut SSL, _out: *mut u8, _out_len: *mut usize, _max_out: usize,
+            _signature_algorithm: u16, _in: *const u8, _in_len: usize,
+        ) -> ssl_private_key_result_t {
+            ssl_private_key_result_t::ssl_private_key_failure
+        }
+
+        extern fn failing_decrypt(
+            _ssl: *mut SSL, _out: *mut u8, _out_len: *mut usize, _max_out: usize,
+            _in: *const u8, _in_len: usize,
+        ) -> ssl_private_key_result_t {
+            ssl_private_key_result_t::ssl_private_key_failure
+        }
+
+        extern fn failing_complete(
+            _ssl: *mut SSL, _out: *mut u8, _out_len: *mut usize, _max_out: usize,
+        ) -> ssl_private_key_result_t {
+            ssl_private_key_result_t::ssl_private_key_failure
+        }
+
+        static QUICHE_PRIVATE_KEY_METHOD: SSL_PRIVATE_KEY_METHOD =
+            SSL_PRIVATE_KEY_METHOD {
+                decrypt: Some(failing_decrypt),
+                sign: Some(failing_sign),
+                complete: Some(failing_complete),
+            };
+
+        unsafe {
+            SSL_set_private_key_method(
+                self.as_mut_ptr(),
+                &QUICHE_PRIVATE_KEY_METHOD,
+            );
+        }
+    }
+
+    fn as_ptr(&self) -> *const SSL {
+        self.ptr
+    }
+
+    fn as_mut_ptr(&mut self) -> *mut SSL {
+        self.ptr
+    }
+
+    fn map_result_ssl(&mut self, bssl_result: c_int) -> Result<()> {
+        match bssl_result {
+            1 => Ok(()),
+
+            _ => {
+                let ssl_err = self.get_error(bssl_result);
+                match ssl_err {
+                    // SSL_ERROR_SSL
+                    1 => {
+                        log_ssl_error();
+
+                        Err(Error::TlsFail)
+                    },
+
+                    // SSL_ERROR_WANT_READ
+                    2 => Err(Error::Done),
+
+                    // SSL_ERROR_WANT_WRITE
+                    3 => Err(Error::Done),
+
+                    // SSL_ERROR_WANT_X509_LOOKUP
+                    4 => Err(Error::Done),
+
+                    // SSL_ERROR_SYSCALL
+                    5 => Err(Error::TlsFail),
+
+                    // SSL_ERROR_PENDING_SESSION
+                    11 => Err(Error::Done),
+
+                    // SSL_ERROR_PENDING_CERTIFICATE
+                    12 => Err(Error::Done),
+
+                    // SSL_ERROR_WANT_PRIVATE_KEY_OPERATION
+                    13 => Err(Error::Done),
+
+                    // SSL_ERROR_PENDING_TICKET
+                    14 => Err(Error::Done),
+
+                    // SSL_ERROR_EARLY_DATA_REJECTED
+                    15 => {
+                        self.early_data_rejected = true;
+                        self.reset_early_data_reject();
+                        Err(Error::Done)
+                    },
+
+                    // SSL_ERROR_WANT_CERTIFICATE_VERIFY
+                    16 => Err(Error::Done),
+
+                    _ => Err(Error::TlsFail),
+                }
+            },
+        }
+    }
+
+    fn set_transport_error(&mut self, ex_data: &mut ExData, bssl_result: c_int) {
+        // SSL_ERROR_SSL
+        if self.get_error(bssl_result) == 1 {
+            // SSL_ERROR_SSL can't be recovered so ensure we set a
+            // local_error so the connection is closed.
+            // See https://www.openssl.org/docs/man1.1.1/man3/SSL_get_error.html
+            if ex_data.local_error.is_none() {
+                *ex_data.local_error = Some(ConnectionError {
+                    is_app: false,
+                    error_code: INTERNAL_ERROR,
+                    reason: Vec::new(),
+                })
+            }
+        }
+    }
+
+    #[cfg(feature = "boringssl-boring-crate")]
+    pub(crate) fn ssl_mut(&mut self) -> &mut boring::ssl::SslRef {
+        use foreign_types_shared::ForeignTypeRef;
+
+        unsafe { boring::ssl::SslRef::from_ptr_mut(self.as_mut_ptr() as _) }
+    }
+}
+
+impl TlsHandshake for Handshake {
+    fn init(&mut self, is_server: bool) -> Result<()> {
+        self.set_state(is_server);
+
+        self.set_min_proto_version(TLS1_3_VERSION)?;
+        self.set_max_proto_version(TLS1_3_VERSION)?;
+
+        self.set_quic_method()?;
+
+        // TODO: the early data context should include transport parameters and
+        // HTTP/3 SETTINGS in wire format.
+        self.set_quic_early_data_context(b"quiche")?;
+
+        self.set_quiet_shutdown(true);
+
+        Ok(())
+    }
+
+    fn use_legacy_codepoint(&mut self, use_legacy: bool) {
+        unsafe {
+            SSL_set_quic_use_legacy_codepoint(
+                self.as_mut_ptr(),
+                use_legacy as c_int,
+            );
+        }
+    }
+
+    fn set_host_name(&mut self, name: &str) -> Result<()> {
         let cstr = ffi::CString::new(name).map_err(|_| Error::TlsFail)?;
         let rc =
             unsafe { SSL_set_tlsext_host_name(self.as_mut_ptr(), cstr.as_ptr()) };
@@ -554,7 +745,7 @@ impl Handshake {
         })
     }
 
-    pub fn set_quic_transport_params(&mut self, buf: &[u8]) -> Result<()> {
+    fn set_quic_transport_params(&mut self, buf: &[u8]) -> Result<()> {
         let rc = unsafe {
             SSL_set_quic_transport_params(
                 self.as_mut_ptr(),
@@ -565,7 +756,55 @@ impl Handshake {
         self.map_result_ssl(rc)
     }
 
-    pub fn quic_transport_params(&self) -> &[u8] {
+    fn add_application_settings(
+        &mut self, proto: &[u8], settings: &[u8],
+    ) -> Result<()> {
+        let rc = unsafe {
+            SSL_add_application_settings(
+                self.as_mut_ptr(),
+                proto.as_ptr(),
+                proto.len(),
+                settings.as_ptr(),
+                settings.len(),
+            )
+        };
+        self.map_result_ssl(rc)
+    }
+
+    fn set_cipher_suites(&mut self, suites: &[crypto::Algorithm]) -> Result<()> {
+        let policy = match compliance_policy(suites)? {
+            Some(v) => v,
+
+            None => return Ok(()),
+        };
+
+        let rc = unsafe { SSL_set_compliance_policy(self.as_mut_ptr(), policy) };
+        self.map_result_ssl(rc)?;
+
+        // Compliance policies also enable TLS 1.2, which QUIC doesn't allow.
+        self.set_min_proto_version(TLS1_3_VERSION)
+    }
+
+    fn peer_application_settings(&self) -> Option<&[u8]> {
+        if unsafe { SSL_has_application_settings(self.as_ptr()) } != 1 {
+            return None;
+        }
+
+        let mut ptr: *const u8 = ptr::null();
+        let mut len: usize = 0;
+
+        unsafe {
+            SSL_get0_peer_application_settings(self.as_ptr(), &mut ptr, &mut len);
+        }
+
+        if len == 0 {
+            return Some(&[]);
+        }
+
+        Some(unsafe { slice::from_raw_parts(ptr, len) })
+    }
+
+    fn quic_transport_params(&self) -> &[u8] {
         let mut ptr: *const u8 = ptr::null();
         let mut len: usize = 0;
 
@@ -580,7 +819,30 @@ impl Handshake {
         unsafe { slice::from_raw_parts(ptr, len) }
     }
 
-    pub fn alpn_protocol(&self) -> &[u8] {
+    fn export_keying_material(
+        &self, label: &[u8], context: &[u8], out: &mut [u8],
+    ) -> Result<()> {
+        let rc = unsafe {
+            SSL_export_keying_material(
+                self.as_ptr(),
+                out.as_mut_ptr(),
+                out.len(),
+                label.as_ptr(),
+                label.len(),
+                context.as_ptr(),
+                context.len(),
+                1,
+            )
+        };
+
+        if rc != 1 {
+            return Err(Error::TlsFail);
+        }
+
+        Ok(())
+    }
+
+    fn alpn_protocol(&self) -> &[u8] {
         let mut ptr: *const u8 = ptr::null();
         let mut len: u32 = 0;
 
@@ -595,7 +857,7 @@ impl Handshake {
         unsafe { slice::from_raw_parts(ptr, len as usize) }
     }
 
-    pub fn server_name(&self) -> Option<&str> {
+    fn server_name(&self) -> Option<&str> {
         let s = unsafe {
             let ptr = SSL_get_servername(
                 self.as_ptr(),
@@ -612,7 +874,7 @@ impl Handshake {
         s.to_str().ok()
     }
 
-    pub fn set_session(&mut self, session: &[u8]) -> Result<()> {
+    fn set_session(&mut self, session: &[u8]) -> Result<()> {
         unsafe {
             let ctx = SSL_get_SSL_CTX(self.as_ptr());
 
@@ -634,9 +896,7 @@ impl Handshake {
         }
     }
 
-    pub fn provide_data(
-        &mut self, level: crypto::Level, buf: &[u8],
-    ) -> Result<()> {
+    fn provide_data(&mut self, level: crypto::Level, buf: &[u8]) -> Result<()> {
         self.provided_data_outstanding = true;
         let rc = unsafe {
             SSL_provide_quic_data(
@@ -649,7 +909,7 @@ impl Handshake {
         self.map_result_ssl(rc)
     }
 
-    pub fn do_handshake(&mut self, ex_data: &mut ExData) -> Result<()> {
+    fn do_handshake(&mut self, ex_data: &mut ExData) -> Result<()> {
         self.set_ex_data(*QUICHE_EX_DATA_INDEX, ex_data)?;
         let rc = unsafe { SSL_do_handshake(self.as_mut_ptr()) };
         self.set_ex_data::<Connection>(*QUICHE_EX_DATA_INDEX, std::ptr::null())?;
@@ -658,7 +918,7 @@ impl Handshake {
         self.map_result_ssl(rc)
     }
 
-    pub fn process_post_handshake(&mut self, ex_data: &mut ExData) -> Result<()> {
+    fn process_post_handshake(&mut self, ex_data: &mut ExData) -> Result<()> {
         // If SSL_provide_quic_data hasn't been called since we last called
         // SSL_process_quic_post_handshake, then there's nothing to do.
         if !self.provided_data_outstanding {
@@ -674,22 +934,28 @@ impl Handshake {
         self.map_result_ssl(rc)
     }
 
-    pub fn reset_early_data_reject(&mut self) {
-        unsafe { SSL_reset_early_data_reject(self.as_mut_ptr()) };
+    fn set_early_data_enabled(&mut self, enabled: bool) {
+        let enabled = i32::from(enabled);
+
+        unsafe { SSL_set_early_data_enabled(self.as_mut_ptr(), enabled) };
     }
 
-    pub fn write_level(&self) -> crypto::Level {
+    fn is_early_data_rejected(&self) -> bool {
+        self.early_data_rejected
+    }
+
+    fn write_level(&self) -> crypto::Level {
         unsafe { SSL_quic_write_level(self.as_ptr()) }
     }
 
-    pub fn cipher(&self) -> Option<crypto::Algorithm> {
+    fn cipher(&self) -> Option<crypto::Algorithm> {
         let cipher =
             map_result_ptr(unsafe { SSL_get_current_cipher(self.as_ptr()) });
 
         get_cipher_from_ptr(cipher.ok()?).ok()
     }
 
-    pub fn curve(&self) -> Option<String> {
+    fn curve(&self) -> Option<String> {
         let curve = unsafe {
             let curve_id = SSL_get_curve_id(self.as_ptr());
             if curve_id == 0 {
@@ -707,7 +973,7 @@ impl Handshake {
         Some(curve.to_string())
     }
 
-    pub fn sigalg(&self) -> Option<String> {
+    fn sigalg(&self) -> Option<String> {
         let sigalg = unsafe {
             let sigalg_id = SSL_get_peer_signature_algorithm(self.as_ptr());
             if sigalg_id == 0 {
@@ -725,7 +991,7 @@ impl Handshake {
         Some(sigalg.to_string())
     }
 
-    pub fn peer_cert_chain(&self) -> Option<Vec<&[u8]>> {
+    fn peer_cert_chain(&self) -> Option<Vec<&[u8]>> {
         let cert_chain = unsafe {
             let chain =
                 map_result_ptr(SSL_get0_peer_certificates(self.as_ptr())).ok()?;
@@ -758,7 +1024,7 @@ impl Handshake {
         Some(cert_chain)
     }
 
-    pub fn peer_cert(&self) -> Option<&[u8]> {
+    fn peer_cert(&self) -> Option<&[u8]> {
         let peer_cert = unsafe {
             let chain =
                 map_result_ptr(SSL_get0_peer_certificates(self.as_ptr())).ok()?;
@@ -782,151 +1048,55 @@ impl Handshake {
         Some(peer_cert)
     }
 
-    #[cfg(test)]
-    pub fn set_options(&mut self, opts: u32) {
-        unsafe {
-            SSL_set_options(self.as_mut_ptr(), opts);
-        }
-    }
-
-    // Only used for testing handling of failure during key signing.
-    #[cfg(test)]
-    pub fn set_failing_private_key_method(&mut self) {
-        extern fn failing_sign(
-            _ssl: *mut SSL, _out: *mut u8, _out_len: *mut usize, _max_out: usize,
-            _signature_algorithm: u16, _in: *const u8, _in_len: usize,
-        ) -> ssl_private_key_result_t {
-            ssl_private_key_result_t::ssl_private_key_failure
-        }
+    fn peer_cert_covers_host(&self, host: &str) -> bool {
+        let peer_cert = match self.peer_cert() {
+            Some(v) => v,
 
-        extern fn failing_decrypt(
-            _ssl: *mut SSL, _out: *mut u8, _out_len: *mut usize, _max_out: usize,
-            _in: *const u8, _in_len: usize,
-        ) -> ssl_private_key_result_t {
-            ssl_private_key_result_t::ssl_private_key_failure
-        }
+            None => return false,
+        };
 
-        extern fn failing_complete(
-            _ssl: *mut SSL, _out: *mut u8, _out_len: *mut usize, _max_out: usize,
-        ) -> ssl_private_key_result_t {
-            ssl_private_key_result_t::ssl_private_key_failure
-        }
+        unsafe {
+            let in_p = peer_cert.as_ptr();
 
-        static QUICHE_PRIVATE_KEY_METHOD: SSL_PRIVATE_KEY_METHOD =
-            SSL_PRIVATE_KEY_METHOD {
-                decrypt: Some(failing_decrypt),
-                sign: Some(failing_sign),
-                complete: Some(failing_complete),
-            };
+            let cert = d2i_X509(ptr::null_mut(), &in_p, peer_cert.len() as c_int);
+            if cert.is_null() {
+                return false;
+            }
 
-        unsafe {
-            SSL_set_private_key_method(
-                self.as_mut_ptr(),
-                &QUICHE_PRIVATE_KEY_METHOD,
+            let rc = X509_check_host(
+                cert,
+                host.as_ptr() as *const c_char,
+                host.len(),
+                0,
+                ptr::null_mut(),
             );
+
+            X509_free(cert);
+
+            rc == 1
         }
     }
 
-    pub fn is_completed(&self) -> bool {
+    fn is_completed(&self) -> bool {
         unsafe { SSL_in_init(self.as_ptr()) == 0 }
     }
 
-    pub fn is_resumed(&self) -> bool {
+    fn is_resumed(&self) -> bool {
         unsafe { SSL_session_reused(self.as_ptr()) == 1 }
     }
 
-    pub fn is_in_early_data(&self) -> bool {
+    fn used_hello_retry_request(&self) -> bool {
+        unsafe { SSL_used_hello_retry_request(self.as_ptr()) == 1 }
+    }
+
+    fn is_in_early_data(&self) -> bool {
         unsafe { SSL_in_early_data(self.as_ptr()) == 1 }
     }
 
-    pub fn clear(&mut self) -> Result<()> {
+    fn clear(&mut self) -> Result<()> {
         let rc = unsafe { SSL_clear(self.as_mut_ptr()) };
         self.map_result_ssl(rc)
     }
-
-    fn as_ptr(&self) -> *const SSL {
-        self.ptr
-    }
-
-    fn as_mut_ptr(&mut self) -> *mut SSL {
-        self.ptr
-    }
-
-    fn map_result_ssl(&mut self, bssl_result: c_int) -> Result<()> {
-        match bssl_result {
-            1 => Ok(()),
-
-            _ => {
-                let ssl_err = self.get_error(bssl_result);
-                match ssl_err {
-                    // SSL_ERROR_SSL
-                    1 => {
-                        log_ssl_error();
-
-                        Err(Error::TlsFail)
-                    },
-
-                    // SSL_ERROR_WANT_READ
-                    2 => Err(Error::Done),
-
-                    // SSL_ERROR_WANT_WRITE
-                    3 => Err(Error::Done),
-
-                    // SSL_ERROR_WANT_X509_LOOKUP
-                    4 => Err(Error::Done),
-
-                    // SSL_ERROR_SYSCALL
-                    5 => Err(Error::TlsFail),
-
-                    // SSL_ERROR_PENDING_SESSION
-                    11 => Err(Error::Done),
-
-                    // SSL_ERROR_PENDING_CERTIFICATE
-                    12 => Err(Error::Done),
-
-                    // SSL_ERROR_WANT_PRIVATE_KEY_OPERATION
-                    13 => Err(Error::Done),
-
-                    // SSL_ERROR_PENDING_TICKET
-                    14 => Err(Error::Done),
-
-                    // SSL_ERROR_EARLY_DATA_REJECTED
-                    15 => {
-                        self.reset_early_data_reject();
-                        Err(Error::Done)
-                    },
-
-                    // SSL_ERROR_WANT_CERTIFICATE_VERIFY
-                    16 => Err(Error::Done),
-
-                    _ => Err(Error::TlsFail),
-                }
-            },
-        }
-    }
-
-    fn set_transport_error(&mut self, ex_data: &mut ExData, bssl_result: c_int) {
-        // SSL_ERROR_SSL
-        if self.get_error(bssl_result) == 1 {
-            // SSL_ERROR_SSL can't be recovered so ensure we set a
-            // local_error so the connection is closed.
-            // See https://www.openssl.org/docs/man1.1.1/man3/SSL_get_error.html
-            if ex_data.local_error.is_none() {
-                *ex_data.local_error = Some(ConnectionError {
-                    is_app: false,
-                    error_code: INTERNAL_ERROR,
-                    reason: Vec::new(),
-                })
-            }
-        }
-    }
-
-    #[cfg(feature = "boringssl-boring-crate")]
-    pub(crate) fn ssl_mut(&mut self) -> &mut boring::ssl::SslRef {
-        use foreign_types_shared::ForeignTypeRef;
-
-        unsafe { boring::ssl::SslRef::from_ptr_mut(self.as_mut_ptr() as _) }
-    }
 }
 
 // NOTE: These traits are not automatically implemented for Handshake due to the
@@ -943,22 +1113,6 @@ impl Drop for Handshake {
     }
 }
 
-pub struct ExData<'a> {
-    pub application_protos: &'a Vec<Vec<u8>>,
-
-    pub pkt_num_spaces: &'a mut [packet::PktNumSpace; packet::Epoch::count()],
-
-    pub session: &'a mut Option<Vec<u8>>,
-
-    pub local_error: &'a mut Option<super::ConnectionError>,
-
-    pub keylog: Option<&'a mut Box<dyn std::io::Write + Send + Sync>>,
-
-    pub trace_id: &'a str,
-
-    pub is_server: bool,
-}
-
 fn get_ex_data_from_ptr<'a, T>(ptr: *const SSL, idx: c_int) -> Option<&'a mut T> {
     unsafe {
         let data = SSL_get_ex_data(ptr, idx) as *mut T;
@@ -966,6 +1120,38 @@ fn get_ex_data_from_ptr<'a, T>(ptr: *const SSL, idx: c_int) -> Option<&'a mut T>
     }
 }
 
+/// Checks that the TLS 1.3 cipher suites can be restricted to `suites`.
+pub fn check_cipher_suites(suites: &[crypto::Algorithm]) -> Result<()> {
+    compliance_policy(suites).map(|_| ())
+}
+
+/// Returns the compliance policy that restricts the TLS 1.3 cipher suites to
+/// `suites`, if they need to be restricted.
+///
+/// BoringSSL doesn't allow configuring TLS 1.3 suites directly, so only the
+/// sets of suites matching one of its compliance policies are supported.
+fn compliance_policy(
+    suites: &[crypto::Algorithm],
+) -> Result<Option<ssl_compliance_policy_t>> {
+    let aes128 = suites.contains(&crypto::Algorithm::AES128_GCM);
+    let aes256 = suites.contains(&crypto::Algorithm::AES256_GCM);
+    let chacha = suites.contains(&crypto::Algorithm::ChaCha20_Poly1305);
+
+    match (aes128, aes256, chacha) {
+        (false, false, false) | (true, true, true) => Ok(None),
+
+        (true, true, false) => Ok(Some(
+            ssl_compliance_policy_t::ssl_compliance_policy_fips_202205,
+        )),
+
+        (false, true, false) => Ok(Some(
+            ssl_compliance_policy_t::ssl_compliance_policy_wpa3_192_202304,
+        )),
+
+        _ => Err(Error::TlsFail),
+    }
+}
+
 fn get_cipher_from_ptr(cipher: *const SSL_CIPHER) -> Result<crypto::Algorithm> {
     let cipher_id = unsafe { SSL_CIPHER_get_id(cipher) };
 
@@ -1013,7 +1199,11 @@ extern fn set_read_secret(
     if level != crypto::Level::ZeroRTT || ex_data.is_server {
         let secret = unsafe { slice::from_raw_parts(secret, secret_len) };
 
-        let open = match crypto::Open::from_secret(aead, secret.to_vec()) {
+        let open = match crypto::Open::from_secret(
+            aead,
+            secret.to_vec(),
+            *ex_data.version,
+        ) {
             Ok(v) => v,
 
             Err(_) => return 0,
@@ -1064,7 +1254,11 @@ extern fn set_write_secret(
     if level != crypto::Level::ZeroRTT || !ex_data.is_server {
         let secret = unsafe { slice::from_raw_parts(secret, secret_len) };
 
-        let seal = match crypto::Seal::from_secret(aead, secret.to_vec()) {
+        let seal = match crypto::Seal::from_secret(
+            aead,
+            secret.to_vec(),
+            *ex_data.version,
+        ) {
             Ok(v) => v,
 
             Err(_) => return 0,
@@ -1105,7 +1299,12 @@ extern fn add_handshake_data(
             &mut ex_data.pkt_num_spaces[packet::Epoch::Application],
     };
 
-    if space.crypto_stream.send.write(buf, false).is_err() {
+    if space
+        .crypto_stream
+        .send
+        .write_pooled(buf, false, ex_data.buf_pool)
+        .is_err()
+    {
         return 0;
     }
 
@@ -1175,6 +1374,8 @@ extern fn select_alpn(
         None => return 3, // SSL_TLSEXT_ERR_NOACK
     };
 
+    select_compatible_version(ssl, ex_data);
+
     if ex_data.application_protos.is_empty() {
         return 3; // SSL_TLSEXT_ERR_NOACK
     }
@@ -1213,6 +1414,163 @@ extern fn select_alpn(
     3 // SSL_TLSEXT_ERR_NOACK
 }
 
+/// Upgrades the connection to a version compatible with the client's, as per
+/// RFC 9368.
+///
+/// This runs as part of ALPN selection, which happens after the ClientHello
+/// extensions are parsed, but before any handshake keys are derived or the
+/// server's transport parameters are written.
+fn select_compatible_version(ssl: *mut SSL, ex_data: &mut ExData) {
+    if !ex_data.is_server || ex_data.compatible_versions.is_empty() {
+        return;
+    }
+
+    let mut ptr: *const u8 = ptr::null();
+    let mut len: usize = 0;
+
+    unsafe {
+        SSL_get_peer_quic_transport_params(ssl, &mut ptr, &mut len);
+    }
+
+    if len == 0 {
+        return;
+    }
+
+    let raw_params = unsafe { slice::from_raw_parts(ptr, len) };
+
+    // Only consider the versions offered by the client if it is using the
+    // version it claims to have chosen. Otherwise the handshake fails later
+    // when validating the transport parameters.
+    let client_versions = match crate::TransportParams::decode(raw_params, true) {
+        Ok(crate::TransportParams {
+            version_information: Some(info),
+            ..
+        }) if info.chosen_version == *ex_data.version => info.available_versions,
+
+        _ => return,
+    };
+
+    // Follow the server's order of preference.
+    let version = match ex_data
+        .compatible_versions
+        .iter()
+        .find(|v| client_versions.contains(v))
+    {
+        Some(&v) => v,
+
+        None => return,
+    };
+
+    if version == *ex_data.version {
+        return;
+    }
+
+    let mut local_params = ex_data.local_transport_params.clone();
+
+    // Keep the previously advertised versions, including any GREASE one.
+    let available_versions = match local_params.version_information.take() {
+        Some(info) => info.available_versions,
+
+        None => ex_data.compatible_versions.to_vec(),
+    };
+
+    local_params.version_information = Some(crate::VersionInformation {
+        chosen_version: version,
+        available_versions,
+    });
+
+    let mut raw_params = vec![0; local_params.max_encoded_len()];
+
+    let mut len = match crate::TransportParams::encode(
+        &local_params,
+        true,
+        &mut raw_params,
+    ) {
+        Ok(v) => v.len(),
+
+        Err(_) => return,
+    };
+
+    if ex_data.grease {
+        len += match crate::TransportParams::encode_grease(&mut raw_params[len..])
+        {
+            Ok(v) => v,
+
+            Err(_) => return,
+        };
+    }
+
+    let rc =
+        unsafe { SSL_set_quic_transport_params(ssl, raw_params.as_ptr(), len) };
+
+    if rc != 1 {
+        return;
+    }
+
+    trace!(
+        "{} upgrading to compatible version {:x}",
+        ex_data.trace_id,
+        version
+    );
+
+    *ex_data.version = version;
+    *ex_data.local_transport_params = local_params;
+}
+
+extern fn verify_peer(ok: c_int, store_ctx: *mut X509_STORE_CTX) -> c_int {
+    // The callback is invoked for each certificate in the chain, so only check
+    // the whole chain once, after the leaf certificate was verified.
+    if ok == 0 || unsafe { X509_STORE_CTX_get_error_depth(store_ctx) } != 0 {
+        return ok;
+    }
+
+    let ssl = unsafe {
+        X509_STORE_CTX_get_ex_data(
+            store_ctx,
+            SSL_get_ex_data_X509_STORE_CTX_idx(),
+        ) as *mut SSL
+    };
+
+    let ex_data = match get_ex_data_from_ptr::<ExData>(ssl, *QUICHE_EX_DATA_INDEX)
+    {
+        Some(v) => v,
+
+        None => return ok,
+    };
+
+    let verify_callback = match ex_data.verify_callback {
+        Some(v) => v,
+
+        None => return ok,
+    };
+
+    let handshake = Handshake::new(ssl);
+
+    let accepted = handshake
+        .peer_cert_chain()
+        .map_or(false, |chain| verify_callback(&chain));
+
+    // Prevent handshake from being freed, as we still need it.
+    std::mem::forget(handshake);
+
+    if !accepted {
+        trace!(
+            "{} peer certificate rejected by application",
+            ex_data.trace_id
+        );
+
+        unsafe {
+            X509_STORE_CTX_set_error(
+                store_ctx, 50, // X509_V_ERR_APPLICATION_VERIFICATION
+            );
+        }
+
+        return 0;
+    }
+
+    ok
+}
+
 extern fn new_session(ssl: *mut SSL, session: *mut SSL_SESSION) -> c_int {
     let ex_data = match get_ex_data_from_ptr::<ExData>(ssl, *QUICHE_EX_DATA_INDEX)
     {
@@ -1266,6 +1624,12 @@ extern fn new_session(ssl: *mut SSL, session: *mut SSL_SESSION) -> c_int {
         return 0;
     }
 
+    if let (Some(store), Some(server_name)) =
+        (ex_data.session_store, handshake.server_name())
+    {
+        store.put(server_name, &buffer);
+    }
+
     *ex_data.session = Some(buffer);
 
     // Prevent handshake from being freed, as we still need it.
@@ -1322,6 +1686,14 @@ extern {
         ctx: *mut SSL_CTX, file: *const c_char, ty: c_int,
     ) -> c_int;
 
+    fn SSL_CTX_set1_sigalgs_list(
+        ctx: *mut SSL_CTX, sigalgs: *const c_char,
+    ) -> c_int;
+
+    fn SSL_CTX_set1_groups_list(
+        ctx: *mut SSL_CTX, groups: *const c_char,
+    ) -> c_int;
+
     fn SSL_CTX_load_verify_locations(
         ctx: *mut SSL_CTX, file: *const c_char, path: *const c_char,
     ) -> c_int;
@@ -1339,6 +1711,14 @@ extern {
         >,
     );
 
+    fn SSL_CTX_get_verify_mode(ctx: *const SSL_CTX) -> c_int;
+
+    fn SSL_CTX_get_verify_callback(
+        ctx: *const SSL_CTX,
+    ) -> Option<
+        unsafe extern fn(ok: c_int, store_ctx: *mut X509_STORE_CTX) -> c_int,
+    >;
+
     fn SSL_CTX_set_keylog_callback(
         ctx: *mut SSL_CTX,
         cb: Option<unsafe extern fn(ssl: *const SSL, line: *const c_char)>,
@@ -1396,9 +1776,13 @@ extern {
     fn SSL_set_ex_data(ssl: *mut SSL, idx: c_int, ptr: *mut c_void) -> c_int;
     fn SSL_get_ex_data(ssl: *const SSL, idx: c_int) -> *mut c_void;
 
+    fn SSL_get_ex_data_X509_STORE_CTX_idx() -> c_int;
+
     fn SSL_get_current_cipher(ssl: *const SSL) -> *const SSL_CIPHER;
 
     fn SSL_get_curve_id(ssl: *const SSL) -> u16;
+
+    fn SSL_used_hello_retry_request(ssl: *const SSL) -> c_int;
     fn SSL_get_curve_name(curve: u16) -> *const c_char;
 
     fn SSL_get_peer_signature_algorithm(ssl: *const SSL) -> u16;
@@ -1449,6 +1833,27 @@ extern {
         ssl: *const SSL, out: *mut *const u8, out_len: *mut u32,
     );
 
+    fn SSL_set_compliance_policy(
+        ssl: *mut SSL, policy: ssl_compliance_policy_t,
+    ) -> c_int;
+
+    fn SSL_add_application_settings(
+        ssl: *mut SSL, proto: *const u8, proto_len: usize, settings: *const u8,
+        settings_len: usize,
+    ) -> c_int;
+
+    fn SSL_has_application_settings(ssl: *const SSL) -> c_int;
+
+    fn SSL_get0_peer_application_settings(
+        ssl: *const SSL, out: *mut *const u8, out_len: *mut usize,
+    );
+
+    fn SSL_export_keying_material(
+        ssl: *const SSL, out: *mut u8, out_len: usize, label: *const u8,
+        label_len: usize, context: *const u8, context_len: usize,
+        use_context: c_int,
+    ) -> c_int;
+
     fn SSL_get_servername(ssl: *const SSL, ty: c_int) -> *const c_char;
 
     fn SSL_provide_quic_data(
@@ -1459,6 +1864,8 @@ extern {
 
     fn SSL_reset_early_data_reject(ssl: *mut SSL);
 
+    fn SSL_set_early_data_enabled(ssl: *mut SSL, enabled: i32);
+
     fn SSL_do_handshake(ssl: *mut SSL) -> c_int;
 
     fn SSL_quic_write_level(ssl: *const SSL) -> crypto::Level;
@@ -1492,16 +1899,28 @@ extern {
         param: *mut X509_VERIFY_PARAM, name: *const c_char, namelen: usize,
     ) -> c_int;
 
+    // X509_STORE_CTX
+    fn X509_STORE_CTX_get_ex_data(
+        ctx: *const X509_STORE_CTX, idx: c_int,
+    ) -> *mut c_void;
+
+    fn X509_STORE_CTX_get_error_depth(ctx: *const X509_STORE_CTX) -> c_int;
+
+    fn X509_STORE_CTX_set_error(ctx: *mut X509_STORE_CTX, err: c_int);
+
     // X509_STORE
     #[cfg(windows)]
     fn X509_STORE_add_cert(ctx: *mut X509_STORE, x: *mut X509) -> c_int;
 
     // X509
-    #[cfg(windows)]
     fn X509_free(x: *mut X509);
-    #[cfg(windows)]
     fn d2i_X509(px: *mut X509, input: *const *const u8, len: c_int) -> *mut X509;
 
+    fn X509_check_host(
+        x: *const X509, chk: *const c_char, chklen: usize, flags: c_uint,
+        peername: *mut *mut c_char,
+    ) -> c_int;
+
     // STACK_OF
     fn sk_num(stack: *const STACK_OF) -> usize;
     fn sk_value(stack: *const STACK_OF, idx: usize) -> *mut c_void;
@@ -0,0 +1,197 @@
+// Copyright (C) 2023, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! TLS backends.
+//!
+//! The handshake is driven through the [`TlsContext`] and [`TlsHandshake`]
+//! traits, which are implemented by BoringSSL by default, or by rustls when
+//! the `rustls` feature is enabled.
+
+use crate::Result;
+
+use crate::crypto;
+use crate::packet;
+
+#[cfg(not(feature = "rustls"))]
+mod boringssl;
+
+#[cfg(not(feature = "rustls"))]
+pub use self::boringssl::*;
+
+#[cfg(feature = "rustls")]
+mod rustls;
+
+#[cfg(feature = "rustls")]
+pub use self::rustls::*;
+
+const TLS_ALERT_ERROR: u64 = 0x100;
+const INTERNAL_ERROR: u64 = 0x01;
+
+/// TLS configuration shared by all the connections created from a `Config`.
+pub trait TlsContext {
+    /// The per-connection handshake state created from this context.
+    type Handshake: TlsHandshake;
+
+    fn new_handshake(&mut self) -> Result<Self::Handshake>;
+
+    fn load_verify_locations_from_file(&mut self, file: &str) -> Result<()>;
+
+    fn set_sigalgs_list(&mut self, sigalgs: &str) -> Result<()>;
+
+    fn set_groups_list(&mut self, groups: &str) -> Result<()>;
+
+    fn load_verify_locations_from_directory(&mut self, path: &str) -> Result<()>;
+
+    fn use_certificate_chain_file(&mut self, file: &str) -> Result<()>;
+
+    fn use_privkey_file(&mut self, file: &str) -> Result<()>;
+
+    fn set_verify(&mut self, verify: bool);
+
+    fn set_verify_required(&mut self, required: bool);
+
+    fn enable_verify_callback(&mut self);
+
+    fn enable_keylog(&mut self);
+
+    fn set_alpn(&mut self, v: &[&[u8]]) -> Result<()>;
+
+    fn set_ticket_key(&mut self, key: &[u8]) -> Result<()>;
+
+    fn set_early_data_enabled(&mut self, enabled: bool);
+}
+
+/// The TLS state of a single connection.
+///
+/// Handshake messages received in CRYPTO frames are passed to
+/// [`provide_data()`], and processed by [`do_handshake()`], which writes the
+/// outgoing messages and installs new packet protection keys through the
+/// given [`ExData`].
+///
+/// [`provide_data()`]: TlsHandshake::provide_data
+/// [`do_handshake()`]: TlsHandshake::do_handshake
+pub trait TlsHandshake {
+    fn init(&mut self, is_server: bool) -> Result<()>;
+
+    fn use_legacy_codepoint(&mut self, use_legacy: bool);
+
+    fn set_host_name(&mut self, name: &str) -> Result<()>;
+
+    fn set_quic_transport_params(&mut self, buf: &[u8]) -> Result<()>;
+
+    fn add_application_settings(
+        &mut self, proto: &[u8], settings: &[u8],
+    ) -> Result<()>;
+
+    fn set_cipher_suites(&mut self, suites: &[crypto::Algorithm]) -> Result<()>;
+
+    fn peer_application_settings(&self) -> Option<&[u8]>;
+
+    fn quic_transport_params(&self) -> &[u8];
+
+    fn export_keying_material(
+        &self, label: &[u8], context: &[u8], out: &mut [u8],
+    ) -> Result<()>;
+
+    fn alpn_protocol(&self) -> &[u8];
+
+    fn server_name(&self) -> Option<&str>;
+
+    fn set_session(&mut self, session: &[u8]) -> Result<()>;
+
+    fn provide_data(&mut self, level: crypto::Level, buf: &[u8]) -> Result<()>;
+
+    /// Advances the handshake, returning `Err(Error::Done)` while it is still
+    /// in progress.
+    fn do_handshake(&mut self, ex_data: &mut ExData) -> Result<()>;
+
+    fn process_post_handshake(&mut self, ex_data: &mut ExData) -> Result<()>;
+
+    fn set_early_data_enabled(&mut self, enabled: bool);
+
+    fn is_early_data_rejected(&self) -> bool;
+
+    fn write_level(&self) -> crypto::Level;
+
+    fn cipher(&self) -> Option<crypto::Algorithm>;
+
+    fn curve(&self) -> Option<String>;
+
+    fn sigalg(&self) -> Option<String>;
+
+    fn peer_cert_chain(&self) -> Option<Vec<&[u8]>>;
+
+    fn peer_cert(&self) -> Option<&[u8]>;
+
+    /// Returns whether the peer's leaf certificate is valid for `host`.
+    fn peer_cert_covers_host(&self, host: &str) -> bool;
+
+    fn is_completed(&self) -> bool;
+
+    fn is_resumed(&self) -> bool;
+
+    fn used_hello_retry_request(&self) -> bool;
+
+    fn is_in_early_data(&self) -> bool;
+
+    /// Resets the handshake, e.g. to restart it after Version Negotiation.
+    fn clear(&mut self) -> Result<()>;
+}
+
+/// The connection state the TLS backend can access while processing
+/// handshake messages.
+///
+/// Not every backend supports the features that use all of these.
+#[cfg_attr(feature = "rustls", allow(dead_code))]
+pub struct ExData<'a> {
+    pub application_protos: &'a Vec<Vec<u8>>,
+
+    pub pkt_num_spaces: &'a mut [packet::PktNumSpace; packet::Epoch::count()],
+
+    pub session: &'a mut Option<Vec<u8>>,
+
+    pub session_store: Option<&'a dyn super::SessionStore>,
+
+    pub verify_callback: Option<&'a super::VerifyCallback>,
+
+    pub local_error: &'a mut Option<super::ConnectionError>,
+
+    pub buf_pool: &'a mut crate::stream::BufPool,
+
+    pub keylog: Option<&'a mut Box<dyn std::io::Write + Send + Sync>>,
+
+    pub trace_id: &'a str,
+
+    pub is_server: bool,
+
+    pub version: &'a mut u32,
+
+    pub compatible_versions: &'a [u32],
+
+    pub grease: bool,
+
+    pub local_transport_params: &'a mut super::TransportParams,
+}
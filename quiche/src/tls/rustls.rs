@@ -0,0 +1,999 @@
+// Copyright (C) 2023, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! TLS backend based on rustls.
+//!
+//! rustls doesn't expose the QUIC traffic secrets directly, so they are
+//! captured through its key log interface and turned into packet protection
+//! keys with the `crypto` module, same as with BoringSSL.
+//!
+//! Session resumption, 0-RTT, application settings (ALPS), compatible version
+//! negotiation, session ticket keys and signature algorithm preferences are
+//! not supported, and the system CA store is not loaded by default.
+
+use std::convert::TryFrom;
+
+use std::io::Write;
+
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use ::rustls::client::danger::HandshakeSignatureValid;
+use ::rustls::client::danger::ServerCertVerified;
+use ::rustls::client::danger::ServerCertVerifier;
+use ::rustls::crypto::CryptoProvider;
+use ::rustls::crypto::SupportedKxGroup;
+use ::rustls::pki_types::pem::PemObject;
+use ::rustls::pki_types::CertificateDer;
+use ::rustls::pki_types::PrivateKeyDer;
+use ::rustls::pki_types::ServerName;
+use ::rustls::pki_types::UnixTime;
+use ::rustls::quic;
+use ::rustls::server::ParsedCertificate;
+use ::rustls::server::WebPkiClientVerifier;
+use ::rustls::AlertDescription;
+use ::rustls::CipherSuite;
+use ::rustls::ClientConfig;
+use ::rustls::DigitallySignedStruct;
+use ::rustls::HandshakeKind;
+use ::rustls::KeyLog;
+use ::rustls::NamedGroup;
+use ::rustls::RootCertStore;
+use ::rustls::ServerConfig;
+use ::rustls::SignatureScheme;
+use ::rustls::SupportedCipherSuite;
+
+use crate::Error;
+use crate::Result;
+
+use crate::ConnectionError;
+
+use crate::crypto;
+use crate::packet;
+
+use super::ExData;
+use super::TlsContext;
+use super::TlsHandshake;
+use super::INTERNAL_ERROR;
+use super::TLS_ALERT_ERROR;
+
+struct Settings {
+    certs: Vec<CertificateDer<'static>>,
+
+    key: Option<PrivateKeyDer<'static>>,
+
+    roots: RootCertStore,
+
+    kx_groups: Option<Vec<&'static dyn SupportedKxGroup>>,
+
+    alpn: Vec<Vec<u8>>,
+
+    verify: bool,
+
+    verify_required: bool,
+
+    keylog: bool,
+}
+
+impl Clone for Settings {
+    fn clone(&self) -> Self {
+        Settings {
+            certs: self.certs.clone(),
+            key: self.key.as_ref().map(|k| k.clone_key()),
+            roots: self.roots.clone(),
+            kx_groups: self.kx_groups.clone(),
+            alpn: self.alpn.clone(),
+            verify: self.verify,
+            verify_required: self.verify_required,
+            keylog: self.keylog,
+        }
+    }
+}
+
+pub struct Context {
+    settings: Arc<Settings>,
+}
+
+impl Context {
+    pub fn new() -> Result<Context> {
+        Ok(Context {
+            settings: Arc::new(Settings {
+                certs: Vec::new(),
+                key: None,
+                roots: RootCertStore::empty(),
+                kx_groups: None,
+                alpn: Vec::new(),
+                verify: false,
+                verify_required: false,
+                keylog: false,
+            }),
+        })
+    }
+
+    fn settings_mut(&mut self) -> &mut Settings {
+        Arc::make_mut(&mut self.settings)
+    }
+
+    fn add_verify_locations(&mut self, file: &std::path::Path) -> Result<()> {
+        let certs = CertificateDer::pem_file_iter(file)
+            .map_err(|_| Error::TlsFail)?
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|_| Error::TlsFail)?;
+
+        let (added, _) =
+            self.settings_mut().roots.add_parsable_certificates(certs);
+
+        if added == 0 {
+            return Err(Error::TlsFail);
+        }
+
+        Ok(())
+    }
+}
+
+impl TlsContext for Context {
+    type Handshake = Handshake;
+
+    fn new_handshake(&mut self) -> Result<Handshake> {
+        Ok(Handshake::new(self.settings.clone()))
+    }
+
+    fn load_verify_locations_from_file(&mut self, file: &str) -> Result<()> {
+        self.add_verify_locations(file.as_ref())
+    }
+
+    fn set_sigalgs_list(&mut self, _sigalgs: &str) -> Result<()> {
+        Err(Error::TlsFail)
+    }
+
+    fn set_groups_list(&mut self, groups: &str) -> Result<()> {
+        use ::rustls::crypto::ring::kx_group;
+
+        let groups = groups
+            .split(':')
+            .map(|name| match name {
+                "X25519" | "x25519" => Ok(kx_group::X25519),
+
+                "P-256" | "prime256v1" | "secp256r1" => Ok(kx_group::SECP256R1),
+
+                "P-384" | "secp384r1" => Ok(kx_group::SECP384R1),
+
+                _ => Err(Error::TlsFail),
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        self.settings_mut().kx_groups = Some(groups);
+
+        Ok(())
+    }
+
+    fn load_verify_locations_from_directory(&mut self, path: &str) -> Result<()> {
+        let dir = std::fs::read_dir(path).map_err(|_| Error::TlsFail)?;
+
+        // Like OpenSSL's hashed directory lookup, skip any file that doesn't
+        // contain certificates.
+        for entry in dir.flatten() {
+            self.add_verify_locations(&entry.path()).ok();
+        }
+
+        Ok(())
+    }
+
+    fn use_certificate_chain_file(&mut self, file: &str) -> Result<()> {
+        let certs = CertificateDer::pem_file_iter(file)
+            .map_err(|_| Error::TlsFail)?
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|_| Error::TlsFail)?;
+
+        if certs.is_empty() {
+            return Err(Error::TlsFail);
+        }
+
+        self.settings_mut().certs = certs;
+
+        Ok(())
+    }
+
+    fn use_privkey_file(&mut self, file: &str) -> Result<()> {
+        let key =
+            PrivateKeyDer::from_pem_file(file).map_err(|_| Error::TlsFail)?;
+
+        self.settings_mut().key = Some(key);
+
+        Ok(())
+    }
+
+    fn set_verify(&mut self, verify: bool) {
+        let settings = self.settings_mut();
+
+        settings.verify = verify;
+        settings.verify_required = false;
+    }
+
+    fn set_verify_required(&mut self, required: bool) {
+        let settings = self.settings_mut();
+
+        settings.verify = true;
+        settings.verify_required = required;
+    }
+
+    fn enable_verify_callback(&mut self) {
+        // The callback itself is passed with the ExData, and invoked once the
+        // peer's certificates are received.
+    }
+
+    fn enable_keylog(&mut self) {
+        self.settings_mut().keylog = true;
+    }
+
+    fn set_alpn(&mut self, v: &[&[u8]]) -> Result<()> {
+        self.settings_mut().alpn = v.iter().map(|p| p.to_vec()).collect();
+
+        Ok(())
+    }
+
+    fn set_ticket_key(&mut self, _key: &[u8]) -> Result<()> {
+        Err(Error::TlsFail)
+    }
+
+    fn set_early_data_enabled(&mut self, _enabled: bool) {}
+}
+
+pub struct Handshake {
+    settings: Arc<Settings>,
+
+    /// The rustls connection, created on the first call to do_handshake(),
+    /// once all the parameters it needs are known.
+    conn: Option<quic::Connection>,
+
+    secrets: Arc<SecretLog>,
+
+    is_server: bool,
+
+    host_name: Option<String>,
+
+    transport_params: Vec<u8>,
+
+    cipher_suites: Vec<crypto::Algorithm>,
+
+    use_legacy_codepoint: bool,
+
+    /// Handshake messages received since the last call to do_handshake(),
+    /// one entry per CRYPTO frame batch, as rustls expects.
+    pending_data: Vec<Vec<u8>>,
+
+    write_level: crypto::Level,
+
+    /// The server's 1-RTT read key, which is only installed once the
+    /// handshake completes, as per RFC 9001 Section 5.7.
+    pending_open: Option<crypto::Open>,
+
+    peer_verified: bool,
+}
+
+impl Handshake {
+    fn new(settings: Arc<Settings>) -> Handshake {
+        Handshake {
+            settings,
+            conn: None,
+            secrets: Arc::new(SecretLog::default()),
+            is_server: false,
+            host_name: None,
+            transport_params: Vec::new(),
+            cipher_suites: Vec::new(),
+            use_legacy_codepoint: false,
+            pending_data: Vec::new(),
+            write_level: crypto::Level::Initial,
+            pending_open: None,
+            peer_verified: false,
+        }
+    }
+
+    fn provider(&self) -> CryptoProvider {
+        let mut provider = ::rustls::crypto::ring::default_provider();
+
+        if !self.cipher_suites.is_empty() {
+            provider.cipher_suites.retain(|suite| {
+                suite_algorithm(*suite)
+                    .map_or(false, |alg| self.cipher_suites.contains(&alg))
+            });
+        }
+
+        if let Some(groups) = &self.settings.kx_groups {
+            provider.kx_groups = groups.clone();
+        }
+
+        provider
+    }
+
+    fn start(&mut self, ex_data: &ExData) -> Result<quic::Connection> {
+        let provider = Arc::new(self.provider());
+
+        let version = if self.use_legacy_codepoint {
+            quic::Version::V1Draft
+        } else if *ex_data.version == crate::PROTOCOL_VERSION_V2 {
+            quic::Version::V2
+        } else {
+            quic::Version::V1
+        };
+
+        let params = self.transport_params.clone();
+
+        let conn = if self.is_server {
+            let config = self.server_config(provider, ex_data)?;
+
+            quic::ServerConnection::new(config, version, params)
+                .map(quic::Connection::from)
+        } else {
+            let (config, name) = self.client_config(provider)?;
+
+            quic::ClientConnection::new(config, version, name, params)
+                .map(quic::Connection::from)
+        };
+
+        conn.map_err(|e| {
+            trace!("{} tls error: {}", ex_data.trace_id, e);
+            Error::TlsFail
+        })
+    }
+
+    fn client_config(
+        &self, provider: Arc<CryptoProvider>,
+    ) -> Result<(Arc<ClientConfig>, ServerName<'static>)> {
+        let builder = ClientConfig::builder_with_provider(provider.clone())
+            .with_protocol_versions(&[&::rustls::version::TLS13])
+            .map_err(|_| Error::TlsFail)?
+            .with_root_certificates(self.settings.roots.clone());
+
+        let mut config = match &self.settings.key {
+            Some(key) => builder
+                .with_client_auth_cert(
+                    self.settings.certs.clone(),
+                    key.clone_key(),
+                )
+                .map_err(|_| Error::TlsFail)?,
+
+            None => builder.with_no_client_auth(),
+        };
+
+        if !self.settings.verify {
+            config.dangerous().set_certificate_verifier(Arc::new(
+                NoServerVerification(provider),
+            ));
+        }
+
+        // Without a server name there is nothing to send in SNI, or to verify
+        // the server's certificate against.
+        let name = match &self.host_name {
+            Some(host) =>
+                ServerName::try_from(host.clone()).map_err(|_| Error::TlsFail)?,
+
+            None if !self.settings.verify => {
+                config.enable_sni = false;
+
+                ServerName::IpAddress(std::net::IpAddr::from([0, 0, 0, 0]).into())
+            },
+
+            None => return Err(Error::TlsFail),
+        };
+
+        config.alpn_protocols = self.settings.alpn.clone();
+        config.key_log = self.secrets.clone();
+
+        Ok((Arc::new(config), name))
+    }
+
+    fn server_config(
+        &self, provider: Arc<CryptoProvider>, ex_data: &ExData,
+    ) -> Result<Arc<ServerConfig>> {
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_protocol_versions(&[&::rustls::version::TLS13])
+            .map_err(|_| Error::TlsFail)?;
+
+        let builder = if self.settings.verify {
+            let mut verifier = WebPkiClientVerifier::builder_with_provider(
+                Arc::new(self.settings.roots.clone()),
+                provider,
+            );
+
+            if !self.settings.verify_required {
+                verifier = verifier.allow_unauthenticated();
+            }
+
+            builder.with_client_cert_verifier(
+                verifier.build().map_err(|_| Error::TlsFail)?,
+            )
+        } else {
+            builder.with_no_client_auth()
+        };
+
+        let key = match &self.settings.key {
+            Some(v) => v.clone_key(),
+
+            None => return Err(Error::TlsFail),
+        };
+
+        let mut config = builder
+            .with_single_cert(self.settings.certs.clone(), key)
+            .map_err(|_| Error::TlsFail)?;
+
+        config.alpn_protocols = ex_data.application_protos.clone();
+        config.key_log = self.secrets.clone();
+
+        // Tickets can't be used to resume sessions, so don't send any.
+        config.send_tls13_tickets = 0;
+
+        Ok(Arc::new(config))
+    }
+
+    fn process(
+        &mut self, conn: &mut quic::Connection, ex_data: &mut ExData,
+    ) -> Result<()> {
+        for data in std::mem::take(&mut self.pending_data) {
+            if let Err(e) = conn.read_hs(&data) {
+                trace!("{} tls error: {}", ex_data.trace_id, e);
+
+                set_transport_error(conn, ex_data);
+
+                return Err(Error::TlsFail);
+            }
+
+            self.install_secrets(conn, ex_data)?;
+
+            self.verify_peer(conn, ex_data)?;
+        }
+
+        loop {
+            let mut buf = Vec::new();
+
+            let key_change = conn.write_hs(&mut buf);
+
+            if !buf.is_empty() {
+                trace!(
+                    "{} write message lvl={:?} len={}",
+                    ex_data.trace_id,
+                    self.write_level,
+                    buf.len()
+                );
+
+                ex_data.pkt_num_spaces[level_epoch(self.write_level)]
+                    .crypto_stream
+                    .send
+                    .write_pooled(&buf, false, ex_data.buf_pool)?;
+            }
+
+            self.install_secrets(conn, ex_data)?;
+
+            match key_change {
+                Some(quic::KeyChange::Handshake { .. }) =>
+                    self.write_level = crypto::Level::Handshake,
+
+                Some(quic::KeyChange::OneRtt { .. }) =>
+                    self.write_level = crypto::Level::OneRTT,
+
+                None if buf.is_empty() => break,
+
+                None => (),
+            }
+        }
+
+        if !conn.is_handshaking() {
+            if let Some(open) = self.pending_open.take() {
+                trace!("{} set read secret lvl=OneRTT", ex_data.trace_id);
+
+                ex_data.pkt_num_spaces[packet::Epoch::Application].crypto_open =
+                    Some(open);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Turns the traffic secrets logged by rustls into packet protection keys.
+    fn install_secrets(
+        &mut self, conn: &quic::Connection, ex_data: &mut ExData,
+    ) -> Result<()> {
+        let secrets = std::mem::take(&mut *self.secrets.0.lock().unwrap());
+
+        for secret in secrets {
+            if self.settings.keylog {
+                if let Some(keylog) = &mut ex_data.keylog {
+                    keylog.write_all(&secret.keylog_line()).ok();
+                    keylog.flush().ok();
+                }
+            }
+
+            let (level, is_client) = match secret.label.as_str() {
+                "CLIENT_HANDSHAKE_TRAFFIC_SECRET" =>
+                    (crypto::Level::Handshake, true),
+
+                "SERVER_HANDSHAKE_TRAFFIC_SECRET" =>
+                    (crypto::Level::Handshake, false),
+
+                "CLIENT_TRAFFIC_SECRET_0" => (crypto::Level::OneRTT, true),
+
+                "SERVER_TRAFFIC_SECRET_0" => (crypto::Level::OneRTT, false),
+
+                _ => continue,
+            };
+
+            let aead = conn
+                .negotiated_cipher_suite()
+                .and_then(suite_algorithm)
+                .ok_or(Error::TlsFail)?;
+
+            let space = &mut ex_data.pkt_num_spaces[level_epoch(level)];
+
+            if is_client != ex_data.is_server {
+                trace!("{} set write secret lvl={:?}", ex_data.trace_id, level);
+
+                space.crypto_seal = Some(crypto::Seal::from_secret(
+                    aead,
+                    secret.secret,
+                    *ex_data.version,
+                )?);
+            } else {
+                let open = crypto::Open::from_secret(
+                    aead,
+                    secret.secret,
+                    *ex_data.version,
+                )?;
+
+                if ex_data.is_server && level == crypto::Level::OneRTT {
+                    self.pending_open = Some(open);
+                    continue;
+                }
+
+                trace!("{} set read secret lvl={:?}", ex_data.trace_id, level);
+
+                space.crypto_open = Some(open);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Runs the application's verify callback on the peer's certificates,
+    /// once they are received.
+    fn verify_peer(
+        &mut self, conn: &quic::Connection, ex_data: &mut ExData,
+    ) -> Result<()> {
+        if self.peer_verified || !self.settings.verify {
+            return Ok(());
+        }
+
+        let verify_callback = match ex_data.verify_callback {
+            Some(v) => v,
+
+            None => return Ok(()),
+        };
+
+        let chain: Vec<&[u8]> = match conn.peer_certificates() {
+            Some(certs) => certs.iter().map(|c| c.as_ref()).collect(),
+
+            None => return Ok(()),
+        };
+
+        self.peer_verified = true;
+
+        if !verify_callback(&chain) {
+            trace!(
+                "{} peer certificate rejected by application",
+                ex_data.trace_id
+            );
+
+            let alert = u8::from(AlertDescription::BadCertificate);
+
+            *ex_data.local_error = Some(ConnectionError {
+                is_app: false,
+                error_code: TLS_ALERT_ERROR + u64::from(alert),
+                reason: Vec::new(),
+            });
+
+            return Err(Error::TlsFail);
+        }
+
+        Ok(())
+    }
+}
+
+impl TlsHandshake for Handshake {
+    fn init(&mut self, is_server: bool) -> Result<()> {
+        self.is_server = is_server;
+
+        Ok(())
+    }
+
+    fn use_legacy_codepoint(&mut self, use_legacy: bool) {
+        self.use_legacy_codepoint = use_legacy;
+    }
+
+    fn set_host_name(&mut self, name: &str) -> Result<()> {
+        self.host_name = Some(name.to_string());
+
+        Ok(())
+    }
+
+    fn set_quic_transport_params(&mut self, buf: &[u8]) -> Result<()> {
+        self.transport_params = buf.to_vec();
+
+        Ok(())
+    }
+
+    fn add_application_settings(
+        &mut self, _proto: &[u8], _settings: &[u8],
+    ) -> Result<()> {
+        Err(Error::TlsFail)
+    }
+
+    fn set_cipher_suites(&mut self, suites: &[crypto::Algorithm]) -> Result<()> {
+        self.cipher_suites = suites.to_vec();
+
+        Ok(())
+    }
+
+    fn peer_application_settings(&self) -> Option<&[u8]> {
+        None
+    }
+
+    fn quic_transport_params(&self) -> &[u8] {
+        self.conn
+            .as_ref()
+            .and_then(|conn| conn.quic_transport_parameters())
+            .unwrap_or(&[])
+    }
+
+    fn export_keying_material(
+        &self, label: &[u8], context: &[u8], out: &mut [u8],
+    ) -> Result<()> {
+        let conn = self.conn.as_ref().ok_or(Error::TlsFail)?;
+
+        conn.export_keying_material(out, label, Some(context))
+            .map_err(|_| Error::TlsFail)?;
+
+        Ok(())
+    }
+
+    fn alpn_protocol(&self) -> &[u8] {
+        self.conn
+            .as_ref()
+            .and_then(|conn| conn.alpn_protocol())
+            .unwrap_or(&[])
+    }
+
+    fn server_name(&self) -> Option<&str> {
+        match &self.conn {
+            Some(quic::Connection::Server(conn)) => conn.server_name(),
+
+            _ => self.host_name.as_deref(),
+        }
+    }
+
+    fn set_session(&mut self, _session: &[u8]) -> Result<()> {
+        Err(Error::TlsFail)
+    }
+
+    fn provide_data(&mut self, _level: crypto::Level, buf: &[u8]) -> Result<()> {
+        self.pending_data.push(buf.to_vec());
+
+        Ok(())
+    }
+
+    fn do_handshake(&mut self, ex_data: &mut ExData) -> Result<()> {
+        let mut conn = match self.conn.take() {
+            Some(v) => v,
+
+            None => self.start(ex_data)?,
+        };
+
+        let res = self.process(&mut conn, ex_data);
+
+        let in_progress = conn.is_handshaking();
+
+        self.conn = Some(conn);
+
+        res?;
+
+        if in_progress {
+            return Err(Error::Done);
+        }
+
+        Ok(())
+    }
+
+    fn process_post_handshake(&mut self, ex_data: &mut ExData) -> Result<()> {
+        let mut conn = match self.conn.take() {
+            Some(v) => v,
+
+            None => return Ok(()),
+        };
+
+        let res = self.process(&mut conn, ex_data);
+
+        self.conn = Some(conn);
+
+        res
+    }
+
+    fn set_early_data_enabled(&mut self, _enabled: bool) {}
+
+    fn is_early_data_rejected(&self) -> bool {
+        false
+    }
+
+    fn write_level(&self) -> crypto::Level {
+        self.write_level
+    }
+
+    fn cipher(&self) -> Option<crypto::Algorithm> {
+        self.conn
+            .as_ref()?
+            .negotiated_cipher_suite()
+            .and_then(suite_algorithm)
+    }
+
+    fn curve(&self) -> Option<String> {
+        let group = self.conn.as_ref()?.negotiated_key_exchange_group()?;
+
+        let name = match group.name() {
+            NamedGroup::X25519 => "X25519".to_string(),
+
+            NamedGroup::secp256r1 => "P-256".to_string(),
+
+            NamedGroup::secp384r1 => "P-384".to_string(),
+
+            name => format!("{name:?}"),
+        };
+
+        Some(name)
+    }
+
+    fn sigalg(&self) -> Option<String> {
+        // rustls doesn't expose the peer's signature algorithm.
+        None
+    }
+
+    fn peer_cert_chain(&self) -> Option<Vec<&[u8]>> {
+        let certs = self.conn.as_ref()?.peer_certificates()?;
+
+        if certs.is_empty() {
+            return None;
+        }
+
+        Some(certs.iter().map(|c| c.as_ref()).collect())
+    }
+
+    fn peer_cert(&self) -> Option<&[u8]> {
+        let certs = self.conn.as_ref()?.peer_certificates()?;
+
+        certs.first().map(|c| c.as_ref())
+    }
+
+    fn peer_cert_covers_host(&self, host: &str) -> bool {
+        let peer_cert = match self.peer_cert() {
+            Some(v) => CertificateDer::from(v),
+
+            None => return false,
+        };
+
+        let cert = match ParsedCertificate::try_from(&peer_cert) {
+            Ok(v) => v,
+
+            Err(_) => return false,
+        };
+
+        let name = match ServerName::try_from(host) {
+            Ok(v) => v,
+
+            Err(_) => return false,
+        };
+
+        ::rustls::client::verify_server_name(&cert, &name).is_ok()
+    }
+
+    fn is_completed(&self) -> bool {
+        self.conn
+            .as_ref()
+            .map_or(false, |conn| !conn.is_handshaking())
+    }
+
+    fn is_resumed(&self) -> bool {
+        self.handshake_kind() == Some(HandshakeKind::Resumed)
+    }
+
+    fn used_hello_retry_request(&self) -> bool {
+        self.handshake_kind() == Some(HandshakeKind::FullWithHelloRetryRequest)
+    }
+
+    fn is_in_early_data(&self) -> bool {
+        false
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        self.conn = None;
+        self.pending_data.clear();
+        self.write_level = crypto::Level::Initial;
+        self.pending_open = None;
+        self.peer_verified = false;
+
+        self.secrets.0.lock().unwrap().clear();
+
+        Ok(())
+    }
+}
+
+impl Handshake {
+    fn handshake_kind(&self) -> Option<HandshakeKind> {
+        self.conn.as_ref()?.handshake_kind()
+    }
+}
+
+/// A traffic secret logged by rustls.
+#[derive(Debug)]
+struct Secret {
+    label: String,
+
+    client_random: Vec<u8>,
+
+    secret: Vec<u8>,
+}
+
+impl Secret {
+    /// Formats the secret as a line in the NSS key log format.
+    fn keylog_line(&self) -> Vec<u8> {
+        let mut line = self.label.clone().into_bytes();
+
+        line.push(b' ');
+        write_hex(&mut line, &self.client_random);
+
+        line.push(b' ');
+        write_hex(&mut line, &self.secret);
+
+        line.push(b'\n');
+
+        line
+    }
+}
+
+/// Collects the secrets derived by rustls while processing handshake
+/// messages.
+#[derive(Debug, Default)]
+struct SecretLog(Mutex<Vec<Secret>>);
+
+impl KeyLog for SecretLog {
+    fn log(&self, label: &str, client_random: &[u8], secret: &[u8]) {
+        self.0.lock().unwrap().push(Secret {
+            label: label.to_string(),
+            client_random: client_random.to_vec(),
+            secret: secret.to_vec(),
+        });
+    }
+}
+
+/// Accepts any server certificate, for when peer verification is disabled.
+///
+/// The handshake signature is still checked, so that the server proves it
+/// owns the certificate it sent.
+#[derive(Debug)]
+struct NoServerVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoServerVerification {
+    fn verify_server_cert(
+        &self, _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>], _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8], _now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, ::rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self, _message: &[u8], _cert: &CertificateDer<'_>,
+        _dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, ::rustls::Error> {
+        // Only TLS 1.3 is ever negotiated.
+        Err(::rustls::Error::PeerIncompatible(
+            ::rustls::PeerIncompatible::Tls12NotOffered,
+        ))
+    }
+
+    fn verify_tls13_signature(
+        &self, message: &[u8], cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, ::rustls::Error> {
+        ::rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// Checks that the TLS 1.3 cipher suites can be restricted to `suites`.
+///
+/// Unlike BoringSSL, rustls can be restricted to any set of suites.
+pub fn check_cipher_suites(_suites: &[crypto::Algorithm]) -> Result<()> {
+    Ok(())
+}
+
+fn suite_algorithm(suite: SupportedCipherSuite) -> Option<crypto::Algorithm> {
+    match suite.suite() {
+        CipherSuite::TLS13_AES_128_GCM_SHA256 =>
+            Some(crypto::Algorithm::AES128_GCM),
+
+        CipherSuite::TLS13_AES_256_GCM_SHA384 =>
+            Some(crypto::Algorithm::AES256_GCM),
+
+        CipherSuite::TLS13_CHACHA20_POLY1305_SHA256 =>
+            Some(crypto::Algorithm::ChaCha20_Poly1305),
+
+        _ => None,
+    }
+}
+
+fn level_epoch(level: crypto::Level) -> packet::Epoch {
+    match level {
+        crypto::Level::Initial => packet::Epoch::Initial,
+
+        crypto::Level::ZeroRTT => unreachable!(),
+
+        crypto::Level::Handshake => packet::Epoch::Handshake,
+
+        crypto::Level::OneRTT => packet::Epoch::Application,
+    }
+}
+
+fn set_transport_error(conn: &quic::Connection, ex_data: &mut ExData) {
+    match conn.alert() {
+        Some(alert) => {
+            *ex_data.local_error = Some(ConnectionError {
+                is_app: false,
+                error_code: TLS_ALERT_ERROR + u64::from(u8::from(alert)),
+                reason: Vec::new(),
+            });
+        },
+
+        None if ex_data.local_error.is_none() => {
+            *ex_data.local_error = Some(ConnectionError {
+                is_app: false,
+                error_code: INTERNAL_ERROR,
+                reason: Vec::new(),
+            });
+        },
+
+        None => (),
+    }
+}
+
+fn write_hex(out: &mut Vec<u8>, bytes: &[u8]) {
+    for b in bytes {
+        write!(out, "{b:02x}").ok();
+    }
+}
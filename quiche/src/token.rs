@@ -0,0 +1,402 @@
+// Copyright (C) 2023, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Address validation tokens (RFC 9000 Section 8.1).
+
+use std::convert::TryInto;
+
+use std::collections::HashMap;
+
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time;
+
+use ring::aead;
+use ring::hkdf;
+
+use crate::rand;
+use crate::ConnectionId;
+
+/// The default lifetime of tokens sent in Retry packets.
+const RETRY_TOKEN_LIFETIME: time::Duration = time::Duration::from_secs(10);
+
+/// The default lifetime of tokens sent in NEW_TOKEN frames.
+const NEW_TOKEN_LIFETIME: time::Duration = time::Duration::from_secs(24 * 3600);
+
+/// The default maximum number of unexpired NEW_TOKEN tokens remembered to
+/// detect replays.
+const MAX_REDEEMED_TOKENS: usize = 100_000;
+
+/// Used NEW_TOKEN tokens by nonce, with their expiry time and the Destination
+/// Connection ID they were used with.
+type RedeemedTokens = HashMap<[u8; aead::NONCE_LEN], (u64, Vec<u8>)>;
+
+const TOKEN_TYPE_RETRY: u8 = 0;
+const TOKEN_TYPE_NEW_TOKEN: u8 = 1;
+
+/// The result of a successful token validation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ValidatedToken {
+    /// The token was sent in a Retry packet, and carries the original
+    /// Destination Connection ID chosen by the client, to be passed to
+    /// [`accept()`].
+    ///
+    /// [`accept()`]: fn.accept.html
+    Retry(ConnectionId<'static>),
+
+    /// The token was sent in a NEW_TOKEN frame on a previous connection.
+    NewToken,
+}
+
+/// Mints and validates address validation tokens on the server.
+///
+/// The same provider is used both for the tokens sent in Retry packets, and
+/// for the ones sent in NEW_TOKEN frames after the handshake, which clients
+/// can use on later connections to skip address validation. Tokens must be
+/// authenticated, so that clients can't forge them.
+///
+/// [`AeadTokenProvider`] is a default implementation.
+///
+/// [`AeadTokenProvider`]: struct.AeadTokenProvider.html
+pub trait TokenProvider: Send + Sync {
+    /// Mints a token to send in a Retry packet to the client at `peer`,
+    /// whose original Destination Connection ID was `odcid`.
+    fn mint_retry_token(&self, peer: &SocketAddr, odcid: &[u8]) -> Vec<u8>;
+
+    /// Mints a token to send in a NEW_TOKEN frame to the client at `peer`.
+    fn mint_new_token(&self, peer: &SocketAddr) -> Vec<u8>;
+
+    /// Validates a token received from the client at `peer` in an Initial
+    /// packet with the Destination Connection ID `dcid`.
+    ///
+    /// `None` is returned if the token is invalid or expired. Tokens sent in
+    /// NEW_TOKEN frames lift the amplification limit, so they should only be
+    /// accepted once: the same token may be validated again only for the same
+    /// connection attempt, as identified by `dcid`.
+    fn validate_token(
+        &self, peer: &SocketAddr, dcid: &[u8], token: &[u8],
+    ) -> Option<ValidatedToken>;
+}
+
+/// A [`TokenProvider`] that encrypts tokens with AES-256-GCM.
+///
+/// Tokens are bound to the client's IP address and carry their creation
+/// time, so they can't be used from a different address, or after they
+/// expire. The encryption key is derived from a secret, which needs to be
+/// shared by all the servers that can receive the client's packets.
+///
+/// Tokens sent in NEW_TOKEN frames are remembered until they expire once
+/// they are used, so that they can't be replayed on a different connection
+/// attempt. Replays are only detected by the provider that saw the token
+/// first, so servers sharing the secret don't share this state.
+///
+/// [`TokenProvider`]: trait.TokenProvider.html
+pub struct AeadTokenProvider {
+    key: aead::LessSafeKey,
+
+    new_token_lifetime: time::Duration,
+
+    redeemed: Mutex<RedeemedTokens>,
+
+    max_redeemed: usize,
+}
+
+impl AeadTokenProvider {
+    /// Creates a provider with a key derived from `secret`.
+    pub fn new(secret: &[u8]) -> AeadTokenProvider {
+        let info: [&[u8]; 1] = [b"key"];
+
+        let prk =
+            hkdf::Salt::new(hkdf::HKDF_SHA256, b"quiche token").extract(secret);
+        let okm = prk.expand(&info, &aead::AES_256_GCM).unwrap();
+
+        AeadTokenProvider {
+            key: aead::LessSafeKey::new(aead::UnboundKey::from(okm)),
+
+            new_token_lifetime: NEW_TOKEN_LIFETIME,
+
+            redeemed: Mutex::new(HashMap::new()),
+
+            max_redeemed: MAX_REDEEMED_TOKENS,
+        }
+    }
+
+    /// Sets how long tokens sent in NEW_TOKEN frames can be used for.
+    ///
+    /// Used tokens are remembered for that long, so a shorter lifetime also
+    /// reduces the memory needed to detect replays.
+    ///
+    /// The default value is 24 hours.
+    pub fn set_new_token_lifetime(&mut self, v: time::Duration) {
+        self.new_token_lifetime = v;
+    }
+
+    /// Sets the maximum number of used NEW_TOKEN tokens remembered to detect
+    /// replays.
+    ///
+    /// Once this many unexpired tokens were used, further NEW_TOKEN tokens
+    /// are rejected until some expire, and clients fall back to regular
+    /// address validation.
+    ///
+    /// The default value is 100000.
+    pub fn set_max_redeemed_tokens(&mut self, v: usize) {
+        self.max_redeemed = v;
+    }
+
+    /// Records that the token with the given nonce was used for the
+    /// connection attempt with the given Destination Connection ID.
+    ///
+    /// Returns `false` if the token was already used for a different one.
+    fn redeem(
+        &self, nonce: [u8; aead::NONCE_LEN], expiry: u64, dcid: &[u8],
+    ) -> bool {
+        let mut redeemed = self.redeemed.lock().unwrap();
+
+        if let Some((_, first_dcid)) = redeemed.get(&nonce) {
+            return first_dcid.as_slice() == dcid;
+        }
+
+        if redeemed.len() >= self.max_redeemed {
+            let now = unix_time();
+
+            redeemed.retain(|_, (expiry, _)| *expiry >= now);
+
+            if redeemed.len() >= self.max_redeemed {
+                return false;
+            }
+        }
+
+        redeemed.insert(nonce, (expiry, dcid.to_vec()));
+
+        true
+    }
+
+    fn mint(
+        &self, ty: u8, peer: &SocketAddr, odcid: &[u8], created: u64,
+    ) -> Vec<u8> {
+        let mut nonce = [0; aead::NONCE_LEN];
+        rand::rand_bytes(&mut nonce);
+
+        let mut token = nonce.to_vec();
+
+        let mut payload = Vec::with_capacity(9 + odcid.len());
+        payload.push(ty);
+        payload.extend_from_slice(&created.to_be_bytes());
+        payload.extend_from_slice(odcid);
+
+        self.key
+            .seal_in_place_append_tag(
+                aead::Nonce::assume_unique_for_key(nonce),
+                aead::Aad::from(addr_octets(peer)),
+                &mut payload,
+            )
+            .unwrap();
+
+        token.extend_from_slice(&payload);
+
+        token
+    }
+}
+
+impl TokenProvider for AeadTokenProvider {
+    fn mint_retry_token(&self, peer: &SocketAddr, odcid: &[u8]) -> Vec<u8> {
+        self.mint(TOKEN_TYPE_RETRY, peer, odcid, unix_time())
+    }
+
+    fn mint_new_token(&self, peer: &SocketAddr) -> Vec<u8> {
+        self.mint(TOKEN_TYPE_NEW_TOKEN, peer, &[], unix_time())
+    }
+
+    fn validate_token(
+        &self, peer: &SocketAddr, dcid: &[u8], token: &[u8],
+    ) -> Option<ValidatedToken> {
+        if token.len() < aead::NONCE_LEN {
+            return None;
+        }
+
+        let (nonce, payload) = token.split_at(aead::NONCE_LEN);
+
+        let nonce: [u8; aead::NONCE_LEN] = nonce.try_into().ok()?;
+
+        let mut payload = payload.to_vec();
+
+        let payload = self
+            .key
+            .open_in_place(
+                aead::Nonce::assume_unique_for_key(nonce),
+                aead::Aad::from(addr_octets(peer)),
+                &mut payload,
+            )
+            .ok()?;
+
+        if payload.len() < 9 {
+            return None;
+        }
+
+        let ty = payload[0];
+        let created = u64::from_be_bytes(payload[1..9].try_into().ok()?);
+        let odcid = &payload[9..];
+
+        let lifetime = match ty {
+            TOKEN_TYPE_RETRY => RETRY_TOKEN_LIFETIME,
+
+            TOKEN_TYPE_NEW_TOKEN => self.new_token_lifetime,
+
+            _ => return None,
+        };
+
+        if unix_time().saturating_sub(created) > lifetime.as_secs() {
+            return None;
+        }
+
+        match ty {
+            TOKEN_TYPE_RETRY => Some(ValidatedToken::Retry(
+                ConnectionId::from_vec(odcid.to_vec()),
+            )),
+
+            _ if self.redeem(nonce, created + lifetime.as_secs(), dcid) =>
+                Some(ValidatedToken::NewToken),
+
+            _ => None,
+        }
+    }
+}
+
+fn addr_octets(addr: &SocketAddr) -> Vec<u8> {
+    match addr.ip() {
+        IpAddr::V4(a) => a.octets().to_vec(),
+
+        IpAddr::V6(a) => a.octets().to_vec(),
+    }
+}
+
+fn unix_time() -> u64 {
+    time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_token() {
+        let provider = AeadTokenProvider::new(b"secret");
+
+        let peer = "127.0.0.1:1234".parse().unwrap();
+        let odcid = [0xba; 16];
+        let dcid = [0xbb; 16];
+
+        let token = provider.mint_retry_token(&peer, &odcid);
+
+        assert_eq!(
+            provider.validate_token(&peer, &dcid, &token),
+            Some(ValidatedToken::Retry(ConnectionId::from_vec(
+                odcid.to_vec()
+            )))
+        );
+
+        // The port is not part of the token.
+        let rebound = "127.0.0.1:4321".parse().unwrap();
+        assert!(provider.validate_token(&rebound, &dcid, &token).is_some());
+
+        // Tokens can't be used from a different address.
+        let other = "127.0.0.2:1234".parse().unwrap();
+        assert_eq!(provider.validate_token(&other, &dcid, &token), None);
+    }
+
+    #[test]
+    fn new_token() {
+        let mut provider = AeadTokenProvider::new(b"secret");
+
+        let peer = "[::1]:1234".parse().unwrap();
+        let dcid = [0xba; 16];
+
+        let token = provider.mint_new_token(&peer);
+
+        assert_eq!(
+            provider.validate_token(&peer, &dcid, &token),
+            Some(ValidatedToken::NewToken)
+        );
+
+        // Tokens minted with a different secret are rejected.
+        let other = AeadTokenProvider::new(b"other secret");
+        assert_eq!(other.validate_token(&peer, &dcid, &token), None);
+
+        // Tampered tokens are rejected.
+        let mut tampered = token.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert_eq!(provider.validate_token(&peer, &dcid, &tampered), None);
+
+        assert_eq!(provider.validate_token(&peer, &dcid, &token[..10]), None);
+
+        // Expired tokens are rejected.
+        let created = unix_time() - 3600;
+
+        let old = provider.mint(TOKEN_TYPE_NEW_TOKEN, &peer, &[], created);
+        assert_eq!(
+            provider.validate_token(&peer, &dcid, &old),
+            Some(ValidatedToken::NewToken)
+        );
+
+        provider.set_new_token_lifetime(time::Duration::from_secs(60));
+        assert_eq!(provider.validate_token(&peer, &dcid, &old), None);
+
+        let old = provider.mint(TOKEN_TYPE_RETRY, &peer, &[0xba; 8], created);
+        assert_eq!(provider.validate_token(&peer, &dcid, &old), None);
+    }
+
+    #[test]
+    fn new_token_replay() {
+        let mut provider = AeadTokenProvider::new(b"secret");
+
+        let peer = "127.0.0.1:1234".parse().unwrap();
+
+        let token = provider.mint_new_token(&peer);
+
+        assert_eq!(
+            provider.validate_token(&peer, &[0xba; 16], &token),
+            Some(ValidatedToken::NewToken)
+        );
+
+        // The same connection attempt can validate the token again.
+        assert_eq!(
+            provider.validate_token(&peer, &[0xba; 16], &token),
+            Some(ValidatedToken::NewToken)
+        );
+
+        // The token can't be used for another connection attempt.
+        assert_eq!(provider.validate_token(&peer, &[0xbb; 16], &token), None);
+
+        // Fresh tokens are rejected once too many are remembered.
+        provider.set_max_redeemed_tokens(1);
+
+        let token = provider.mint_new_token(&peer);
+        assert_eq!(provider.validate_token(&peer, &[0xbc; 16], &token), None);
+    }
+}
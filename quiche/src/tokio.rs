@@ -0,0 +1,1260 @@
+// Copyright (C) 2023, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Async integration with the [Tokio] runtime.
+//!
+//! This module takes care of the I/O loop that applications otherwise need to
+//! write themselves: each [`Connection`] is driven by a background task that
+//! reads the UDP socket, feeds it incoming packets, handles timers and sends
+//! outgoing packets whenever the application makes progress.
+//!
+//! Clients use [`connect()`], which resolves once the handshake is complete:
+//!
+//! ```no_run
+//! # async fn example() -> std::io::Result<()> {
+//! use tokio::io::AsyncReadExt;
+//! use tokio::io::AsyncWriteExt;
+//!
+//! let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION).unwrap();
+//! config.set_application_protos(&[b"example-proto"]).unwrap();
+//! config.set_initial_max_data(1_000_000);
+//! config.set_initial_max_stream_data_bidi_local(1_000_000);
+//!
+//! let peer = "127.0.0.1:4433".parse().unwrap();
+//! let conn =
+//!     quiche::tokio::connect(Some("quic.tech"), peer, &mut config).await?;
+//!
+//! let mut stream = conn.open_bidi();
+//! stream.write_all(b"hello").await?;
+//! stream.shutdown().await?;
+//!
+//! let mut reply = Vec::new();
+//! stream.read_to_end(&mut reply).await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Servers bind a [`Listener`] with [`listen()`], and get established
+//! connections from [`accept()`]. Streams opened by the peer are returned by
+//! [`Connection::accept_bidi()`]. All the connections of a server are driven
+//! by a single task, using a [`server::Listener`] to route packets to them;
+//! [`listen_with()`] takes one that was configured by the application, for
+//! example to send Retry packets.
+//!
+//! Streams implement [`AsyncRead`] and [`AsyncWrite`]. Applications speaking
+//! HTTP/3 can instead use [`Connection::send_request()`] on the client side,
+//! and [`Connection::accept_request()`] and [`Connection::send_response()`]
+//! on the server side.
+//!
+//! The underlying [`crate::Connection`] remains accessible through
+//! [`Connection::with()`] for anything not covered by this module.
+//!
+//! [Tokio]: https://tokio.rs/
+//! [`connect()`]: fn.connect.html
+//! [`listen()`]: fn.listen.html
+//! [`listen_with()`]: fn.listen_with.html
+//! [`server::Listener`]: ../server/struct.Listener.html
+//! [`accept()`]: struct.Listener.html#method.accept
+//! [`AsyncRead`]: https://docs.rs/tokio/latest/tokio/io/trait.AsyncRead.html
+//! [`AsyncWrite`]: https://docs.rs/tokio/latest/tokio/io/trait.AsyncWrite.html
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::task::Context;
+use std::task::Poll;
+use std::task::Waker;
+
+use ::tokio::io::AsyncRead;
+use ::tokio::io::AsyncWrite;
+use ::tokio::io::ReadBuf;
+use ::tokio::net::ToSocketAddrs;
+use ::tokio::net::UdpSocket;
+use ::tokio::sync::mpsc;
+use ::tokio::sync::Notify;
+
+use crate::h3;
+use crate::server;
+use crate::Config;
+use crate::ConnectionId;
+use crate::RecvInfo;
+
+const MAX_DATAGRAM_SIZE: usize = 1350;
+
+/// A received UDP datagram, routed to the connection it belongs to.
+type Datagram = (Vec<u8>, RecvInfo);
+
+/// An HTTP/3 message received on a request stream.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Message {
+    /// The ID of the request stream.
+    pub stream_id: u64,
+
+    /// The message's header list.
+    pub headers: Vec<h3::Header>,
+
+    /// The message's body.
+    pub body: Vec<u8>,
+}
+
+/// An HTTP/3 message still being received.
+#[derive(Default)]
+struct PartialMessage {
+    headers: Option<Vec<h3::Header>>,
+    body: Vec<u8>,
+    finished: bool,
+    reset: Option<u64>,
+}
+
+/// Connection state kept by this module, alongside the QUIC connection.
+struct AppState {
+    /// Tasks waiting for the connection to make progress.
+    wakers: Vec<Waker>,
+
+    /// The next locally-initiated bidirectional stream ID.
+    next_bidi: u64,
+
+    /// Peer-initiated streams already handed out to the application.
+    accepted: HashSet<u64>,
+
+    /// Streams whose fin has been read.
+    fin_read: HashSet<u64>,
+
+    h3: Option<h3::Connection>,
+
+    /// HTTP/3 messages being received, by stream ID.
+    messages: HashMap<u64, PartialMessage>,
+
+    /// Peer-initiated HTTP/3 requests not yet handed out to the application.
+    requests: VecDeque<u64>,
+
+    /// Whether the connection was handed out to the application.
+    handed_out: bool,
+
+    /// Whether the application dropped all its handles to the connection.
+    dropped: bool,
+}
+
+impl AppState {
+    fn new(is_server: bool) -> AppState {
+        AppState {
+            wakers: Vec::new(),
+            next_bidi: if is_server { 1 } else { 0 },
+            accepted: HashSet::new(),
+            fin_read: HashSet::new(),
+            h3: None,
+            messages: HashMap::new(),
+            requests: VecDeque::new(),
+            handed_out: false,
+            dropped: false,
+        }
+    }
+}
+
+/// A QUIC connection along with the state kept for it.
+struct State<'a> {
+    conn: &'a mut crate::Connection,
+
+    app: &'a mut AppState,
+}
+
+impl State<'_> {
+    fn wake(&mut self) {
+        for waker in self.app.wakers.drain(..) {
+            waker.wake();
+        }
+    }
+
+    /// Returns the HTTP/3 connection, which must be initialized, along with
+    /// the QUIC one.
+    fn h3(&mut self) -> (&mut h3::Connection, &mut crate::Connection) {
+        (self.app.h3.as_mut().unwrap(), self.conn)
+    }
+
+    /// Processes pending HTTP/3 events, if HTTP/3 is in use.
+    fn poll_h3(&mut self) {
+        let app = &mut *self.app;
+
+        let h3_conn = match app.h3.as_mut() {
+            Some(v) => v,
+
+            None => return,
+        };
+
+        let mut buf = [0; 65535];
+
+        loop {
+            match h3_conn.poll(self.conn) {
+                Ok((stream_id, h3::Event::Headers { list, .. })) => {
+                    let msg = app.messages.entry(stream_id).or_default();
+
+                    // Trailers are not exposed.
+                    if msg.headers.is_none() {
+                        msg.headers = Some(list);
+
+                        if self.conn.is_server() {
+                            app.requests.push_back(stream_id);
+                        }
+                    }
+                },
+
+                Ok((stream_id, h3::Event::Data)) => {
+                    let msg = app.messages.entry(stream_id).or_default();
+
+                    while let Ok(read) =
+                        h3_conn.recv_body(self.conn, stream_id, &mut buf)
+                    {
+                        msg.body.extend_from_slice(&buf[..read]);
+                    }
+                },
+
+                Ok((stream_id, h3::Event::Finished)) =>
+                    app.messages.entry(stream_id).or_default().finished = true,
+
+                Ok((stream_id, h3::Event::Reset(e))) =>
+                    app.messages.entry(stream_id).or_default().reset = Some(e),
+
+                Ok((stream_id, h3::Event::MessageError(_))) => {
+                    app.messages.remove(&stream_id);
+                },
+
+                Ok((stream_id, h3::Event::RequestCancelled)) =>
+                    app.messages.entry(stream_id).or_default().reset =
+                        Some(h3::WireErrorCode::RequestCancelled.into()),
+
+                Ok(_) => (),
+
+                // Connection errors close the connection already.
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+/// The connections driven by a background task.
+enum Conns {
+    /// A client connection, driven by its own task.
+    Client(Box<crate::Connection>, Box<AppState>),
+
+    /// The connections of a server, all driven by the listener's task.
+    Server(Box<ServerConns>),
+}
+
+struct ServerConns {
+    listener: server::Listener,
+
+    /// The state kept for each connection, by listener handle.
+    apps: HashMap<usize, AppState>,
+}
+
+impl Conns {
+    /// Returns the connection with the given handle.
+    ///
+    /// Server connections are only removed from the listener once the
+    /// application dropped all its handles to them, so this can't fail for
+    /// a handle held by the application.
+    fn state(&mut self, handle: usize) -> State<'_> {
+        match self {
+            Conns::Client(conn, app) => State { conn, app },
+
+            Conns::Server(server) => State {
+                conn: server.listener.get_mut(handle).unwrap(),
+                app: server.apps.get_mut(&handle).unwrap(),
+            },
+        }
+    }
+}
+
+struct Shared {
+    conns: Mutex<Conns>,
+
+    /// Wakes up the driver task when the application made progress.
+    notify: Notify,
+}
+
+/// A handle to a connection, shared by all clones of a [`Connection`].
+///
+/// [`Connection`]: struct.Connection.html
+struct Handle {
+    shared: Arc<Shared>,
+
+    id: usize,
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        self.shared.conns.lock().unwrap().state(self.id).app.dropped = true;
+
+        // Let the driver task close the connection.
+        self.shared.notify.notify_one();
+    }
+}
+
+/// An established QUIC connection, driven by a background task.
+///
+/// Handles can be cloned cheaply. The connection is closed once all handles,
+/// including the ones held by [`Stream`]s, are dropped.
+pub struct Connection {
+    handle: Arc<Handle>,
+}
+
+impl Clone for Connection {
+    fn clone(&self) -> Self {
+        Connection {
+            handle: Arc::clone(&self.handle),
+        }
+    }
+}
+
+impl Connection {
+    fn new(shared: Arc<Shared>, id: usize) -> Connection {
+        Connection {
+            handle: Arc::new(Handle { shared, id }),
+        }
+    }
+
+    /// Spawns the driver task of a client connection.
+    fn spawn(
+        conn: crate::Connection, socket: Arc<UdpSocket>,
+        incoming: mpsc::UnboundedReceiver<Datagram>,
+    ) -> Connection {
+        let mut app = AppState::new(false);
+        app.handed_out = true;
+
+        let shared = Arc::new(Shared {
+            conns: Mutex::new(Conns::Client(Box::new(conn), Box::new(app))),
+            notify: Notify::new(),
+        });
+
+        ::tokio::spawn(drive(Arc::clone(&shared), socket, incoming));
+
+        Connection::new(shared, 0)
+    }
+
+    fn shared(&self) -> &Shared {
+        &self.handle.shared
+    }
+
+    /// Calls `f` with the connection's state, and wakes up the driver task
+    /// afterwards, so that any packet generated as a result is sent.
+    fn with_state<R>(&self, f: impl FnOnce(&mut State) -> R) -> R {
+        let r = f(&mut self.shared().conns.lock().unwrap().state(self.handle.id));
+
+        self.shared().notify.notify_one();
+
+        r
+    }
+
+    /// Calls `f` with the underlying connection.
+    ///
+    /// The driver task is woken up afterwards, so that any packet generated
+    /// as a result is sent.
+    pub fn with<R>(&self, f: impl FnOnce(&mut crate::Connection) -> R) -> R {
+        self.with_state(|state| f(state.conn))
+    }
+
+    /// Waits until `f` returns a value, calling it every time the connection
+    /// makes progress.
+    ///
+    /// Fails with [`io::ErrorKind::NotConnected`] if the connection is closed
+    /// in the meantime.
+    async fn wait<R>(
+        &self, mut f: impl FnMut(&mut State) -> Option<io::Result<R>>,
+    ) -> io::Result<R> {
+        std::future::poll_fn(|cx| self.poll_state(cx, &mut f)).await
+    }
+
+    fn poll_state<R>(
+        &self, cx: &mut Context,
+        f: &mut impl FnMut(&mut State) -> Option<io::Result<R>>,
+    ) -> Poll<io::Result<R>> {
+        let mut conns = self.shared().conns.lock().unwrap();
+        let mut state = conns.state(self.handle.id);
+
+        if let Some(r) = f(&mut state) {
+            drop(conns);
+
+            self.shared().notify.notify_one();
+
+            return Poll::Ready(r);
+        }
+
+        if state.conn.is_closed() {
+            return Poll::Ready(Err(io::ErrorKind::NotConnected.into()));
+        }
+
+        state.app.wakers.push(cx.waker().clone());
+
+        Poll::Pending
+    }
+
+    async fn established(&self) -> io::Result<()> {
+        self.wait(|state| state.conn.is_established().then_some(Ok(())))
+            .await
+    }
+
+    /// Waits until the connection is closed.
+    pub async fn closed(&self) {
+        self.wait(|_| None::<io::Result<()>>).await.ok();
+    }
+
+    /// Closes the connection with the given error and reason.
+    ///
+    /// See [`crate::Connection::close()`].
+    pub fn close(&self, app: bool, err: u64, reason: &[u8]) {
+        self.with(|conn| conn.close(app, err, reason).ok());
+    }
+
+    /// Returns the address of the peer.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.with(|conn| conn.path_stats().next().map(|p| p.peer_addr))
+    }
+
+    /// Opens a new bidirectional stream.
+    ///
+    /// The stream is only created on the wire once data is written to it.
+    pub fn open_bidi(&self) -> Stream {
+        let stream_id = self.with_state(|state| {
+            let stream_id = state.app.next_bidi;
+            state.app.next_bidi += 4;
+
+            stream_id
+        });
+
+        Stream {
+            conn: self.clone(),
+            stream_id,
+        }
+    }
+
+    /// Waits for the peer to open a bidirectional stream.
+    ///
+    /// Returns `None` once the connection is closed.
+    pub async fn accept_bidi(&self) -> Option<Stream> {
+        let stream_id = self
+            .wait(|state| {
+                let is_server = state.conn.is_server();
+
+                let stream_id = state.conn.readable().find(|&s| {
+                    // Bidirectional streams initiated by the peer.
+                    s & 0x3 == u64::from(!is_server) &&
+                        !state.app.accepted.contains(&s)
+                })?;
+
+                state.app.accepted.insert(stream_id);
+
+                Some(Ok(stream_id))
+            })
+            .await
+            .ok()?;
+
+        Some(Stream {
+            conn: self.clone(),
+            stream_id,
+        })
+    }
+
+    /// Starts using HTTP/3 on the connection, with the given configuration.
+    ///
+    /// This is done implicitly, with the default configuration, by the other
+    /// HTTP/3 methods.
+    pub fn h3_init(&self, config: &h3::Config) -> io::Result<()> {
+        self.with_state(|state| {
+            if state.app.h3.is_none() {
+                let h3_conn = h3::Connection::with_transport(state.conn, config)
+                    .map_err(to_io_error)?;
+
+                state.app.h3 = Some(h3_conn);
+            }
+
+            Ok(())
+        })
+    }
+
+    fn h3_init_default(&self) -> io::Result<()> {
+        if self.with_state(|state| state.app.h3.is_some()) {
+            return Ok(());
+        }
+
+        self.h3_init(&h3::Config::new().map_err(to_io_error)?)
+    }
+
+    /// Sends an HTTP/3 request and waits for the complete response.
+    pub async fn send_request(
+        &self, headers: &[h3::Header], body: &[u8],
+    ) -> io::Result<Message> {
+        self.h3_init_default()?;
+
+        let stream_id = self
+            .wait(|state| {
+                let (h3_conn, conn) = state.h3();
+
+                match h3_conn.send_request(conn, headers, body.is_empty()) {
+                    Ok(v) => Some(Ok(v)),
+
+                    Err(h3::Error::StreamBlocked) |
+                    Err(h3::Error::TransportError(crate::Error::StreamLimit)) =>
+                        None,
+
+                    Err(e) => Some(Err(to_io_error(e))),
+                }
+            })
+            .await?;
+
+        self.send_body(stream_id, body).await?;
+
+        self.recv_message(stream_id).await
+    }
+
+    /// Waits for the next HTTP/3 request from the client, including its body.
+    ///
+    /// Returns `None` once the connection is closed.
+    pub async fn accept_request(&self) -> Option<Message> {
+        self.h3_init_default().ok()?;
+
+        let stream_id = self
+            .wait(|state| state.app.requests.pop_front().map(Ok))
+            .await
+            .ok()?;
+
+        self.recv_message(stream_id).await.ok()
+    }
+
+    /// Sends an HTTP/3 response on the given request stream.
+    pub async fn send_response(
+        &self, stream_id: u64, headers: &[h3::Header], body: &[u8],
+    ) -> io::Result<()> {
+        self.h3_init_default()?;
+
+        self.wait(|state| {
+            let (h3_conn, conn) = state.h3();
+
+            match h3_conn.send_response(conn, stream_id, headers, body.is_empty())
+            {
+                Ok(()) => Some(Ok(())),
+
+                Err(h3::Error::StreamBlocked) => None,
+
+                Err(e) => Some(Err(to_io_error(e))),
+            }
+        })
+        .await?;
+
+        self.send_body(stream_id, body).await
+    }
+
+    async fn send_body(&self, stream_id: u64, body: &[u8]) -> io::Result<()> {
+        let mut written = 0;
+
+        while written < body.len() {
+            written += self
+                .wait(|state| {
+                    let (h3_conn, conn) = state.h3();
+
+                    match h3_conn.send_body(
+                        conn,
+                        stream_id,
+                        &body[written..],
+                        true,
+                    ) {
+                        Ok(v) => Some(Ok(v)),
+
+                        Err(h3::Error::Done) => None,
+
+                        Err(e) => Some(Err(to_io_error(e))),
+                    }
+                })
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn recv_message(&self, stream_id: u64) -> io::Result<Message> {
+        self.wait(|state| {
+            let msg = state.app.messages.get(&stream_id)?;
+
+            if let Some(e) = msg.reset {
+                state.app.messages.remove(&stream_id);
+
+                return Some(Err(io::Error::new(
+                    io::ErrorKind::ConnectionReset,
+                    format!("stream reset with error {e}"),
+                )));
+            }
+
+            if !msg.finished {
+                return None;
+            }
+
+            let msg = state.app.messages.remove(&stream_id)?;
+
+            Some(Ok(Message {
+                stream_id,
+                headers: msg.headers.unwrap_or_default(),
+                body: msg.body,
+            }))
+        })
+        .await
+    }
+}
+
+/// A bidirectional QUIC stream.
+///
+/// Shutting down the write half sends a fin to the peer.
+pub struct Stream {
+    conn: Connection,
+    stream_id: u64,
+}
+
+impl Stream {
+    /// Returns the stream's ID.
+    pub fn id(&self) -> u64 {
+        self.stream_id
+    }
+
+    /// Returns the connection the stream belongs to.
+    pub fn connection(&self) -> &Connection {
+        &self.conn
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let stream_id = self.stream_id;
+
+        let r = self.conn.poll_state(cx, &mut |state| {
+            if state.app.fin_read.contains(&stream_id) {
+                return Some(Ok(()));
+            }
+
+            match state.conn.stream_recv(stream_id, buf.initialize_unfilled()) {
+                Ok((read, fin)) => {
+                    buf.advance(read);
+
+                    if fin {
+                        state.app.fin_read.insert(stream_id);
+                    }
+
+                    Some(Ok(()))
+                },
+
+                Err(crate::Error::Done) => None,
+
+                Err(e) => Some(Err(to_io_error(e))),
+            }
+        });
+
+        // Reading from a closed connection is the same as reaching the end of
+        // the stream.
+        match r {
+            Poll::Ready(Err(e)) if e.kind() == io::ErrorKind::NotConnected =>
+                Poll::Ready(Ok(())),
+
+            r => r,
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let stream_id = self.stream_id;
+
+        self.conn.poll_state(cx, &mut |state| match state
+            .conn
+            .stream_send(stream_id, buf, false)
+        {
+            Ok(v) => Some(Ok(v)),
+
+            Err(crate::Error::Done) | Err(crate::Error::StreamLimit) => None,
+
+            Err(e) => Some(Err(to_io_error(e))),
+        })
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>, _cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>, cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        let stream_id = self.stream_id;
+
+        self.conn.poll_state(cx, &mut |state| match state
+            .conn
+            .stream_send(stream_id, b"", true)
+        {
+            Ok(_) => Some(Ok(())),
+
+            Err(crate::Error::Done) | Err(crate::Error::StreamLimit) => None,
+
+            Err(e) => Some(Err(to_io_error(e))),
+        })
+    }
+}
+
+/// Connects to `peer_addr` and waits for the handshake to complete.
+///
+/// A new UDP socket bound to an ephemeral port is created for the connection.
+/// See [`crate::connect()`] for the meaning of `server_name`.
+pub async fn connect(
+    server_name: Option<&str>, peer_addr: SocketAddr, config: &mut Config,
+) -> io::Result<Connection> {
+    let bind_addr: SocketAddr = match peer_addr {
+        SocketAddr::V4(_) => "0.0.0.0:0".parse().unwrap(),
+
+        SocketAddr::V6(_) => "[::]:0".parse().unwrap(),
+    };
+
+    let socket = Arc::new(UdpSocket::bind(bind_addr).await?);
+    let local_addr = socket.local_addr()?;
+
+    let mut scid = [0; crate::MAX_CONN_ID_LEN];
+    crate::rand::rand_bytes(&mut scid);
+
+    let scid = ConnectionId::from_ref(&scid);
+
+    let conn = crate::connect(server_name, &scid, local_addr, peer_addr, config)
+        .map_err(to_io_error)?;
+
+    let (tx, rx) = mpsc::unbounded_channel();
+
+    ::tokio::spawn(read_socket(Arc::clone(&socket), tx));
+
+    let conn = Connection::spawn(conn, socket, rx);
+
+    conn.established().await?;
+
+    Ok(conn)
+}
+
+/// Reads datagrams from a client socket until the connection goes away.
+async fn read_socket(
+    socket: Arc<UdpSocket>, tx: mpsc::UnboundedSender<Datagram>,
+) {
+    let mut buf = [0; 65535];
+
+    let local_addr = match socket.local_addr() {
+        Ok(v) => v,
+
+        Err(_) => return,
+    };
+
+    loop {
+        let (len, from) = ::tokio::select! {
+            r = socket.recv_from(&mut buf) => match r {
+                Ok(v) => v,
+
+                Err(_) => continue,
+            },
+
+            _ = tx.closed() => break,
+        };
+
+        let recv_info = RecvInfo {
+            from,
+            to: local_addr,
+        };
+
+        if tx.send((buf[..len].to_vec(), recv_info)).is_err() {
+            break;
+        }
+    }
+}
+
+/// A server accepting QUIC connections on a UDP socket.
+pub struct Listener {
+    local_addr: SocketAddr,
+    incoming: mpsc::UnboundedReceiver<Connection>,
+}
+
+impl Listener {
+    /// Waits for the next connection to complete its handshake.
+    ///
+    /// Returns `None` if the socket failed.
+    pub async fn accept(&mut self) -> Option<Connection> {
+        self.incoming.recv().await
+    }
+
+    /// Returns the address the listener is bound to.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+/// Binds a UDP socket to `addr`, and starts accepting connections on it
+/// using `config`.
+///
+/// No address validation is performed, so connections are accepted without
+/// sending a Retry first. Use [`listen_with()`] to configure this.
+///
+/// [`listen_with()`]: fn.listen_with.html
+pub async fn listen(
+    addr: impl ToSocketAddrs, config: Config,
+) -> io::Result<Listener> {
+    listen_with(addr, server::Listener::new(config)).await
+}
+
+/// Binds a UDP socket to `addr`, and starts accepting connections on it
+/// using `listener`.
+///
+/// The [`server::Listener`] routes packets to connections, and can be
+/// configured to validate client addresses with a Retry, to issue Connection
+/// IDs with a custom generator, or to derive stateless reset tokens from a
+/// shared secret.
+///
+/// Closed connections are removed from `listener` once the application
+/// dropped all its handles to them.
+///
+/// [`server::Listener`]: ../server/struct.Listener.html
+pub async fn listen_with(
+    addr: impl ToSocketAddrs, listener: server::Listener,
+) -> io::Result<Listener> {
+    let socket = Arc::new(UdpSocket::bind(addr).await?);
+    let local_addr = socket.local_addr()?;
+
+    let shared = Arc::new(Shared {
+        conns: Mutex::new(Conns::Server(Box::new(ServerConns {
+            listener,
+            apps: HashMap::new(),
+        }))),
+        notify: Notify::new(),
+    });
+
+    let (tx, incoming) = mpsc::unbounded_channel();
+
+    ::tokio::spawn(serve(shared, socket, tx));
+
+    Ok(Listener {
+        local_addr,
+        incoming,
+    })
+}
+
+/// Drives the connections of a server: feeds incoming datagrams to its
+/// listener, handles timers and sends the packets generated by connections,
+/// until the [`Listener`] is dropped and no connection is left.
+///
+/// [`Listener`]: struct.Listener.html
+async fn serve(
+    shared: Arc<Shared>, socket: Arc<UdpSocket>,
+    accepted: mpsc::UnboundedSender<Connection>,
+) {
+    let mut buf = [0; 65535];
+    let mut out = [0; MAX_DATAGRAM_SIZE];
+
+    let local_addr = match socket.local_addr() {
+        Ok(v) => v,
+
+        Err(_) => return,
+    };
+
+    loop {
+        let mut pkts = Vec::new();
+        let mut established = Vec::new();
+
+        let (timeout, done) = {
+            let mut conns = shared.conns.lock().unwrap();
+
+            let server = match &mut *conns {
+                Conns::Server(v) => v,
+
+                Conns::Client(..) => return,
+            };
+
+            let mut gone = Vec::new();
+
+            for (handle, conn) in server.listener.iter_mut() {
+                let app = server
+                    .apps
+                    .entry(handle)
+                    .or_insert_with(|| AppState::new(true));
+
+                let mut state = State { conn, app };
+
+                // Close the connection once the application can't use it
+                // anymore.
+                if state.app.dropped {
+                    state.conn.close(true, 0x0, b"").ok();
+                }
+
+                state.poll_h3();
+
+                if state.conn.is_established() &&
+                    !state.conn.is_closed() &&
+                    !state.app.handed_out
+                {
+                    state.app.handed_out = true;
+                    established.push(handle);
+                }
+            }
+
+            while let Ok((len, send_info)) = server.listener.send(&mut out) {
+                pkts.push((out[..len].to_vec(), send_info.to));
+            }
+
+            for (handle, conn) in server.listener.iter_mut() {
+                let app = server.apps.get_mut(&handle).unwrap();
+
+                State { conn, app }.wake();
+
+                if conn.is_closed() && (app.dropped || !app.handed_out) {
+                    gone.push(handle);
+                }
+            }
+
+            for handle in gone {
+                server.listener.remove(handle);
+                server.apps.remove(&handle);
+            }
+
+            (
+                server.listener.timeout(),
+                accepted.is_closed() && server.listener.is_empty(),
+            )
+        };
+
+        // The lock needs to be released before handing out connections, as
+        // they lock it again when dropped.
+        for handle in established {
+            accepted
+                .send(Connection::new(Arc::clone(&shared), handle))
+                .ok();
+        }
+
+        for (pkt, to) in pkts {
+            socket.send_to(&pkt, to).await.ok();
+        }
+
+        if done {
+            break;
+        }
+
+        let sleep = async {
+            match timeout {
+                Some(v) => ::tokio::time::sleep(v).await,
+
+                None => std::future::pending().await,
+            }
+        };
+
+        let recvd = ::tokio::select! {
+            r = socket.recv_from(&mut buf) => r.ok(),
+
+            _ = sleep => {
+                if let Conns::Server(server) = &mut *shared.conns.lock().unwrap()
+                {
+                    server.listener.on_timeout();
+                }
+
+                None
+            },
+
+            _ = shared.notify.notified() => None,
+
+            _ = accepted.closed(), if !accepted.is_closed() => None,
+        };
+
+        let (len, from) = match recvd {
+            Some(v) => v,
+
+            None => continue,
+        };
+
+        let recv_info = RecvInfo {
+            from,
+            to: local_addr,
+        };
+
+        let respond = match &mut *shared.conns.lock().unwrap() {
+            Conns::Server(server) =>
+                match server.listener.recv(&mut buf[..len], recv_info, &mut out) {
+                    Ok(server::Received::Respond(len)) => Some(len),
+
+                    _ => None,
+                },
+
+            Conns::Client(..) => None,
+        };
+
+        // Version Negotiation, Retry and Stateless Reset packets.
+        if let Some(len) = respond {
+            socket.send_to(&out[..len], from).await.ok();
+        }
+    }
+}
+
+/// Drives a client connection: feeds it incoming datagrams, handles its
+/// timers and sends the packets it generates, until it is closed.
+async fn drive(
+    shared: Arc<Shared>, socket: Arc<UdpSocket>,
+    mut incoming: mpsc::UnboundedReceiver<Datagram>,
+) {
+    let mut out = [0; MAX_DATAGRAM_SIZE];
+
+    loop {
+        let mut pkts = Vec::new();
+
+        let (timeout, closed) = {
+            let mut conns = shared.conns.lock().unwrap();
+            let mut state = conns.state(0);
+
+            // Close the connection once the application can't use it anymore.
+            if state.app.dropped {
+                state.conn.close(true, 0x0, b"").ok();
+            }
+
+            state.poll_h3();
+
+            loop {
+                match state.conn.send(&mut out) {
+                    Ok((len, send_info)) =>
+                        pkts.push((out[..len].to_vec(), send_info.to)),
+
+                    Err(crate::Error::Done) => break,
+
+                    Err(_) => {
+                        state.conn.close(false, 0x1, b"fail").ok();
+                        break;
+                    },
+                }
+            }
+
+            state.wake();
+
+            (state.conn.timeout(), state.conn.is_closed())
+        };
+
+        for (pkt, to) in pkts {
+            socket.send_to(&pkt, to).await.ok();
+        }
+
+        if closed {
+            break;
+        }
+
+        let sleep = async {
+            match timeout {
+                Some(v) => ::tokio::time::sleep(v).await,
+
+                None => std::future::pending().await,
+            }
+        };
+
+        ::tokio::select! {
+            dgram = incoming.recv() => {
+                let mut conns = shared.conns.lock().unwrap();
+                let state = conns.state(0);
+
+                let mut dgram = match dgram {
+                    Some(v) => Some(v),
+
+                    // The socket is gone, nothing more can be received.
+                    None => {
+                        state.conn.close(false, 0x1, b"fail").ok();
+                        None
+                    },
+                };
+
+                while let Some((mut pkt, recv_info)) = dgram {
+                    state.conn.recv(&mut pkt, recv_info).ok();
+
+                    dgram = incoming.try_recv().ok();
+                }
+            },
+
+            _ = sleep => shared.conns.lock().unwrap().state(0).conn.on_timeout(),
+
+            _ = shared.notify.notified() => (),
+        }
+    }
+}
+
+fn to_io_error<E: std::error::Error + Send + Sync + 'static>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ::tokio::io::AsyncReadExt;
+    use ::tokio::io::AsyncWriteExt;
+
+    fn config() -> Config {
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_streams_bidi(3);
+        config.set_initial_max_streams_uni(3);
+        config.verify_peer(false);
+        config
+    }
+
+    #[::tokio::test]
+    async fn echo() {
+        let mut listener = listen("127.0.0.1:0", config()).await.unwrap();
+        let addr = listener.local_addr();
+
+        ::tokio::spawn(async move {
+            let conn = listener.accept().await.unwrap();
+            let mut stream = conn.accept_bidi().await.unwrap();
+
+            let mut buf = Vec::new();
+            stream.read_to_end(&mut buf).await.unwrap();
+
+            stream.write_all(&buf).await.unwrap();
+            stream.shutdown().await.unwrap();
+
+            conn.closed().await;
+        });
+
+        let conn = connect(Some("quic.tech"), addr, &mut config())
+            .await
+            .unwrap();
+
+        let stream = conn.open_bidi();
+        assert_eq!(stream.id(), 0);
+
+        let data = b"hello, world! this is more than the stream window";
+
+        let (mut r, mut w) = ::tokio::io::split(stream);
+
+        let write = async {
+            w.write_all(data).await.unwrap();
+            w.shutdown().await.unwrap();
+        };
+
+        let read = async {
+            let mut buf = Vec::new();
+            r.read_to_end(&mut buf).await.unwrap();
+            buf
+        };
+
+        let (_, buf) = ::tokio::join!(write, read);
+
+        assert_eq!(&buf[..], &data[..]);
+    }
+
+    #[::tokio::test]
+    async fn retry() {
+        let mut config = config();
+        config.set_token_provider(Arc::new(crate::AeadTokenProvider::new(
+            &[0; 32],
+        )));
+
+        let mut server = server::Listener::new(config);
+        server.enable_retry(true).unwrap();
+
+        let mut listener = listen_with("127.0.0.1:0", server).await.unwrap();
+        let addr = listener.local_addr();
+
+        ::tokio::spawn(async move {
+            let conn = listener.accept().await.unwrap();
+
+            conn.closed().await;
+        });
+
+        let conn = connect(Some("quic.tech"), addr, &mut self::config())
+            .await
+            .unwrap();
+
+        assert!(conn.with(|c| c.did_retry));
+    }
+
+    #[::tokio::test]
+    async fn h3_request() {
+        let mut config = config();
+        config
+            .set_application_protos(h3::APPLICATION_PROTOCOL)
+            .unwrap();
+        config.set_initial_max_data(10000);
+        config.set_initial_max_stream_data_bidi_local(1000);
+        config.set_initial_max_stream_data_bidi_remote(1000);
+        config.set_initial_max_stream_data_uni(1000);
+
+        let mut listener = listen("127.0.0.1:0", config).await.unwrap();
+        let addr = listener.local_addr();
+
+        ::tokio::spawn(async move {
+            let conn = listener.accept().await.unwrap();
+            let req = conn.accept_request().await.unwrap();
+
+            let resp = vec![h3::Header::new(b":status", b"200")];
+
+            conn.send_response(req.stream_id, &resp, &req.body)
+                .await
+                .unwrap();
+
+            conn.closed().await;
+        });
+
+        let mut config = self::config();
+        config
+            .set_application_protos(h3::APPLICATION_PROTOCOL)
+            .unwrap();
+        config.set_initial_max_data(10000);
+        config.set_initial_max_stream_data_bidi_local(1000);
+        config.set_initial_max_stream_data_bidi_remote(1000);
+        config.set_initial_max_stream_data_uni(1000);
+
+        let conn = connect(Some("quic.tech"), addr, &mut config).await.unwrap();
+
+        let req = vec![
+            h3::Header::new(b":method", b"POST"),
+            h3::Header::new(b":scheme", b"https"),
+            h3::Header::new(b":authority", b"quic.tech"),
+            h3::Header::new(b":path", b"/echo"),
+        ];
+
+        let resp = conn.send_request(&req, b"ping").await.unwrap();
+
+        assert_eq!(resp.headers, vec![h3::Header::new(b":status", b"200")]);
+        assert_eq!(resp.body, b"ping");
+    }
+}
@@ -0,0 +1,153 @@
+// Copyright (C) 2023, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Wire encoding primitives.
+//!
+//! This module exposes the variable-length integer and frame encoding logic
+//! used internally by quiche, so that external tools (e.g. packet generators,
+//! fuzzers or dissectors) can produce and parse exactly the same bytes.
+//!
+//! The API provided here has no stability guarantees across versions.
+
+use crate::Result;
+
+pub use crate::frame::Frame;
+pub use crate::packet::Type as PacketType;
+pub use crate::ranges::RangeSet;
+pub use crate::stream::RangeBuf;
+
+pub use octets::varint_len;
+pub use octets::varint_parse_len;
+
+/// The largest value that can be encoded as a variable-length integer.
+pub const MAX_VARINT: u64 = (1 << 62) - 1;
+
+/// Encodes `v` as a variable-length integer into `buf`.
+///
+/// On success the number of bytes written is returned. If `buf` is too short
+/// [`BufferTooShort`] is returned.
+///
+/// [`BufferTooShort`]: ../enum.Error.html#variant.BufferTooShort
+pub fn encode_varint(v: u64, buf: &mut [u8]) -> Result<usize> {
+    if v > MAX_VARINT {
+        return Err(crate::Error::InvalidState);
+    }
+
+    let mut b = octets::OctetsMut::with_slice(buf);
+
+    Ok(b.put_varint(v)?.len())
+}
+
+/// Decodes a variable-length integer from the start of `buf`.
+///
+/// On success the decoded value and the number of bytes read are returned.
+pub fn decode_varint(buf: &[u8]) -> Result<(u64, usize)> {
+    let mut b = octets::Octets::with_slice(buf);
+
+    let v = b.get_varint()?;
+
+    Ok((v, b.off()))
+}
+
+/// Encodes `frame` into `buf`.
+///
+/// On success the number of bytes written is returned.
+pub fn encode_frame(frame: &Frame, buf: &mut [u8]) -> Result<usize> {
+    let mut b = octets::OctetsMut::with_slice(buf);
+
+    frame.to_bytes(&mut b)
+}
+
+/// Decodes a single frame from the start of `buf`, as if it was received in
+/// a packet of type `pkt_type`.
+///
+/// On success the decoded frame and the number of bytes read are returned.
+/// Frames that are not allowed in the given packet type are rejected with
+/// [`InvalidPacket`].
+///
+/// [`InvalidPacket`]: ../enum.Error.html#variant.InvalidPacket
+pub fn decode_frame(buf: &[u8], pkt_type: PacketType) -> Result<(Frame, usize)> {
+    let mut b = octets::Octets::with_slice(buf);
+
+    let frame = Frame::from_bytes(&mut b, pkt_type)?;
+
+    Ok((frame, b.off()))
+}
+
+/// Returns the number of bytes `frame` takes on the wire.
+pub fn frame_wire_len(frame: &Frame) -> usize {
+    frame.wire_len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn varint_roundtrip() {
+        let mut buf = [0; 8];
+
+        for &v in &[0, 37, 15293, 494878333, 151288809941952652] {
+            let written = encode_varint(v, &mut buf).unwrap();
+            assert_eq!(written, varint_len(v));
+            assert_eq!(varint_parse_len(buf[0]), written);
+
+            assert_eq!(decode_varint(&buf[..written]), Ok((v, written)));
+        }
+
+        assert_eq!(
+            encode_varint(MAX_VARINT + 1, &mut buf),
+            Err(crate::Error::InvalidState)
+        );
+        assert_eq!(
+            encode_varint(494878333, &mut buf[..2]),
+            Err(crate::Error::BufferTooShort)
+        );
+    }
+
+    #[test]
+    fn frame_roundtrip() {
+        let mut buf = [0; 128];
+
+        let mut ranges = RangeSet::default();
+        ranges.insert(4..7);
+        ranges.insert(9..12);
+
+        let frame = Frame::ACK {
+            ack_delay: 874_656_534,
+            ranges,
+            ecn_counts: None,
+        };
+
+        let written = encode_frame(&frame, &mut buf).unwrap();
+        assert_eq!(written, frame_wire_len(&frame));
+
+        assert_eq!(
+            decode_frame(&buf[..written], PacketType::Short),
+            Ok((frame, written))
+        );
+    }
+}
@@ -329,7 +329,24 @@ pub fn run(
                         }
                     },
 
-                    Ok((stream_id, quiche::h3::Event::Reset(e))) => {
+                    Ok((stream_id, quiche::h3::Event::MessageError(cause))) => {
+                        return Err(Http3TestError::Other(format!(
+                            "malformed response on stream {stream_id}: {cause:?}"
+                        )));
+                    },
+
+                    Ok((
+                        stream_id,
+                        ev @ (quiche::h3::Event::Reset(_) |
+                        quiche::h3::Event::RequestCancelled),
+                    )) => {
+                        let e = match ev {
+                            quiche::h3::Event::Reset(e) => e,
+
+                            _ => quiche::h3::WireErrorCode::RequestCancelled
+                                as u64,
+                        };
+
                         reqs_complete += 1;
 
                         info!("request was reset by peer with {}", e);
@@ -364,6 +381,14 @@ pub fn run(
 
                     Ok((_goaway_id, quiche::h3::Event::GoAway)) => (),
 
+                    Ok((_, quiche::h3::Event::RequestRejected)) => (),
+
+                    Ok((_, quiche::h3::Event::Drained)) => (),
+
+                    Ok((_, quiche::h3::Event::InterimHeaders { .. })) => (),
+
+                    Ok((_, quiche::h3::Event::Trailers { .. })) => (),
+
                     Err(quiche::h3::Error::Done) => {
                         break;
                     },